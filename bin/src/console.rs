@@ -0,0 +1,319 @@
+//! Admin console for inspecting and controlling a running node with line commands, over stdin or a unix socket.
+//!
+//! Each line is one command, answers and events are printed back to the session which sent the command. Neighbour
+//! changes and data of channels which are subscribed from the console are printed to all sessions.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io,
+    net::SocketAddr,
+    path::Path,
+};
+
+use atm0s_sdn::{
+    features::{
+        alias::{self, FoundLocation},
+        dht_kv::{self, Map},
+        neighbours,
+        pubsub::{self, ChannelControl, ChannelEvent, ChannelId},
+        FeaturesControl, FeaturesEvent,
+    },
+    ConnId, NodeAddr, NodeId, SdnMetrics, ServiceBroadcastLevel,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::UnixListener,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
+
+const HELP: &str = "commands:
+  neighbours                     list connections to neighbours
+  connect <addr>                 connect to a node address
+  disconnect <node>              disconnect from a neighbour
+  kv get <map>                   get all keys of a dht_kv map
+  pubsub list                    list channels which are subscribed from the console and relay stats
+  pubsub sub <channel> <source>  subscribe a channel from a source node, data is printed
+  pubsub unsub <channel> <source>
+  alias resolve <alias>          find the node which registered the alias
+  help";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    Neighbours,
+    Connect(NodeAddr),
+    Disconnect(NodeId),
+    KvGet(Map),
+    PubsubList,
+    PubsubSub(ChannelId, NodeId),
+    PubsubUnsub(ChannelId, NodeId),
+    AliasResolve(u64),
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        match parts.as_slice() {
+            ["help"] => Ok(Self::Help),
+            ["neighbours"] => Ok(Self::Neighbours),
+            ["connect", addr] => addr.parse().map(Self::Connect).map_err(|e| format!("invalid node address: {e}")),
+            ["disconnect", node] => parse_num(node).map(Self::Disconnect),
+            ["kv", "get", map] => parse_num(map).map(|map: u64| Self::KvGet(map.into())),
+            ["pubsub", "list"] => Ok(Self::PubsubList),
+            ["pubsub", "sub", channel, source] => Ok(Self::PubsubSub(parse_num::<u64>(channel)?.into(), parse_num(source)?)),
+            ["pubsub", "unsub", channel, source] => Ok(Self::PubsubUnsub(parse_num::<u64>(channel)?.into(), parse_num(source)?)),
+            ["alias", "resolve", alias] => parse_num(alias).map(Self::AliasResolve),
+            _ => Err(format!("unknown command '{line}', type help for the list of commands")),
+        }
+    }
+}
+
+fn parse_num<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid number '{value}'"))
+}
+
+/// Commands from console sessions, each with the sender of lines which are printed back to the session
+pub type ConsoleRequests = UnboundedSender<(Command, UnboundedSender<String>)>;
+
+/// Read commands from stdin and print to stdout, the node keeps running when stdin is closed
+pub async fn run_stdin(requests: ConsoleRequests) -> io::Result<()> {
+    log::info!("[Console] reading commands from stdin");
+    handle_session(tokio::io::stdin(), tokio::io::stdout(), requests).await
+}
+
+/// Accept console sessions on a unix socket, for example with `socat - UNIX-CONNECT:<path>`
+pub async fn run_unix(path: &Path, requests: ConsoleRequests) -> io::Result<()> {
+    // socket file of the previous run is left after a crash
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    log::info!("[Console] listen on {}", path.display());
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let requests = requests.clone();
+        tokio::spawn(async move {
+            let (reader, writer) = socket.into_split();
+            if let Err(e) = handle_session(reader, writer, requests).await {
+                log::warn!("[Console] session error {e}");
+            }
+        });
+    }
+}
+
+async fn handle_session<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(reader: R, mut writer: W, requests: ConsoleRequests) -> io::Result<()> {
+    let (out_tx, mut out_rx) = unbounded_channel::<String>();
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let Some(line) = line? else {
+                    return Ok(());
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match Command::parse(&line) {
+                    Ok(Command::Help) => writer.write_all(format!("{HELP}\n").as_bytes()).await?,
+                    Ok(cmd) => {
+                        if requests.send((cmd, out_tx.clone())).is_err() {
+                            return Ok(());
+                        }
+                    }
+                    Err(e) => writer.write_all(format!("error: {e}\n").as_bytes()).await?,
+                }
+            }
+            Some(out) = out_rx.recv() => {
+                writer.write_all(format!("{out}\n").as_bytes()).await?;
+            }
+        }
+        writer.flush().await?;
+    }
+}
+
+/// State of the console in the controller loop, which maps commands to feature controls and events to printed lines
+pub struct Console {
+    node_id: NodeId,
+    metrics: std::sync::Arc<SdnMetrics>,
+    alias_service: u8,
+    requests: UnboundedReceiver<(Command, UnboundedSender<String>)>,
+    sessions: Vec<UnboundedSender<String>>,
+    neighbours: BTreeMap<ConnId, (NodeId, SocketAddr)>,
+    channels: BTreeSet<(ChannelId, NodeId)>,
+    wait_kv: HashMap<Map, Vec<UnboundedSender<String>>>,
+    wait_alias: HashMap<u64, Vec<UnboundedSender<String>>>,
+}
+
+impl Console {
+    pub fn new(node_id: NodeId, metrics: std::sync::Arc<SdnMetrics>, alias_service: u8) -> (Self, ConsoleRequests) {
+        let (tx, rx) = unbounded_channel();
+        let console = Self {
+            node_id,
+            metrics,
+            alias_service,
+            requests: rx,
+            sessions: vec![],
+            neighbours: BTreeMap::new(),
+            channels: BTreeSet::new(),
+            wait_kv: HashMap::new(),
+            wait_alias: HashMap::new(),
+        };
+        (console, tx)
+    }
+
+    /// Neighbours are tracked from the start, because the feature doesn't answer with existing connections
+    pub fn init_control(&self) -> FeaturesControl {
+        neighbours::Control::Sub.into()
+    }
+
+    /// Handle a pending command, then return the control which must be sent to the controller
+    pub fn pop_control(&mut self) -> Option<FeaturesControl> {
+        while let Ok((cmd, out)) = self.requests.try_recv() {
+            if !self.sessions.iter().any(|s| s.same_channel(&out)) {
+                self.sessions.push(out.clone());
+            }
+            if let Some(control) = self.on_command(cmd, out) {
+                return Some(control);
+            }
+        }
+        None
+    }
+
+    fn on_command(&mut self, cmd: Command, out: UnboundedSender<String>) -> Option<FeaturesControl> {
+        match cmd {
+            Command::Help => None,
+            Command::Neighbours => {
+                let _ = out.send(format!("{} connections", self.neighbours.len()));
+                for (conn, (node, remote)) in &self.neighbours {
+                    let _ = out.send(format!("  node {node} conn {conn} remote {remote}"));
+                }
+                None
+            }
+            Command::Connect(addr) => {
+                let _ = out.send(format!("connecting to {addr}"));
+                Some(neighbours::Control::ConnectTo(addr).into())
+            }
+            Command::Disconnect(node) => {
+                let _ = out.send(format!("disconnecting from {node}"));
+                Some(neighbours::Control::DisconnectFrom(node).into())
+            }
+            Command::KvGet(map) => {
+                self.wait_kv.entry(map).or_default().push(out);
+                Some(dht_kv::Control::MapGet(map).into())
+            }
+            Command::PubsubList => {
+                let _ = out.send(format!("{} channels subscribed from console", self.channels.len()));
+                for (channel, source) in &self.channels {
+                    let _ = out.send(format!("  channel {} source {source}", **channel));
+                }
+                if let Some(metrics) = self.metrics.latest() {
+                    let _ = out.send(format!("relayed to remote nodes: {} channels, fanout {}", metrics.pubsub_remote_relays, metrics.pubsub_relay_fanout));
+                }
+                None
+            }
+            Command::PubsubSub(channel, source) => {
+                if !self.channels.insert((channel, source)) {
+                    let _ = out.send(format!("channel {} source {source} is already subscribed", *channel));
+                    return None;
+                }
+                Some(pubsub::Control(channel, ChannelControl::SubSource(source)).into())
+            }
+            Command::PubsubUnsub(channel, source) => {
+                if !self.channels.remove(&(channel, source)) {
+                    let _ = out.send(format!("channel {} source {source} is not subscribed", *channel));
+                    return None;
+                }
+                Some(pubsub::Control(channel, ChannelControl::UnsubSource(source)).into())
+            }
+            Command::AliasResolve(alias) => {
+                self.wait_alias.entry(alias).or_default().push(out);
+                Some(
+                    alias::Control::Query {
+                        alias,
+                        service: self.alias_service,
+                        level: ServiceBroadcastLevel::Global,
+                    }
+                    .into(),
+                )
+            }
+        }
+    }
+
+    pub fn on_event(&mut self, event: &FeaturesEvent) {
+        match event {
+            FeaturesEvent::Neighbours(event) => match event {
+                neighbours::Event::Connected(node, conn, remote) => {
+                    self.neighbours.insert(*conn, (*node, *remote));
+                    self.broadcast(format!("neighbour {node} connected over {remote}"));
+                }
+                neighbours::Event::Disconnected(node, conn) => {
+                    self.neighbours.remove(conn);
+                    self.broadcast(format!("neighbour {node} disconnected"));
+                }
+                neighbours::Event::ConnectFailed(addr, reason) => {
+                    self.broadcast(format!("connect to {addr} failed: {reason:?}"));
+                }
+                _ => {}
+            },
+            FeaturesEvent::DhtKv(dht_kv::Event::MapGetRes(map, res)) => {
+                let lines = match res {
+                    Ok(values) => {
+                        let mut lines = vec![format!("map {} has {} keys", **map, values.len())];
+                        for (key, session, version, value) in values {
+                            lines.push(format!("  key {} from node {} version {}: {}", **key, session.0, **version, String::from_utf8_lossy(value)));
+                        }
+                        lines
+                    }
+                    Err(e) => vec![format!("get map {} error {e:?}", **map)],
+                };
+                for out in self.wait_kv.remove(map).unwrap_or_default() {
+                    for line in &lines {
+                        let _ = out.send(line.clone());
+                    }
+                }
+            }
+            FeaturesEvent::PubSub(pubsub::Event(channel, event)) => match event {
+                ChannelEvent::SourceData(source, data) if self.channels.contains(&(*channel, *source)) => {
+                    self.broadcast(format!("channel {} from {source}: {}", **channel, String::from_utf8_lossy(data)));
+                }
+                ChannelEvent::RouteChanged(source) | ChannelEvent::SubRejected(source) if self.channels.contains(&(*channel, *source)) => {
+                    self.broadcast(format!("channel {} source {source}: {event:?}", **channel));
+                }
+                _ => {}
+            },
+            FeaturesEvent::Alias(alias::Event::QueryResult(alias, location)) => {
+                let line = match location {
+                    Some(FoundLocation::Local) => format!("alias {alias} is registered by this node {}", self.node_id),
+                    Some(FoundLocation::Notify(node) | FoundLocation::CachedHint(node) | FoundLocation::RemoteHint(node) | FoundLocation::RemoteScan(node)) => {
+                        format!("alias {alias} is registered by node {node} ({location:?})")
+                    }
+                    None => format!("alias {alias} not found"),
+                };
+                for out in self.wait_alias.remove(alias).unwrap_or_default() {
+                    let _ = out.send(line.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Closed sessions are removed here
+    fn broadcast(&mut self, line: String) {
+        self.sessions.retain(|s| s.send(line.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Command;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("neighbours"), Ok(Command::Neighbours));
+        assert_eq!(Command::parse("  kv   get 1000 "), Ok(Command::KvGet(1000.into())));
+        assert_eq!(Command::parse("pubsub sub 1000 2"), Ok(Command::PubsubSub(1000.into(), 2)));
+        assert_eq!(Command::parse("alias resolve 42"), Ok(Command::AliasResolve(42)));
+        assert!(matches!(Command::parse("connect 1@/ip4/127.0.0.1/udp/10000"), Ok(Command::Connect(_))));
+        assert!(Command::parse("connect abc").is_err());
+        assert!(Command::parse("kv get abc").is_err());
+        assert!(Command::parse("unknown").is_err());
+    }
+}
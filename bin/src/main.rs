@@ -1,21 +1,24 @@
 #![allow(clippy::bool_assert_comparison)]
 
-use atm0s_sdn::features::{router_sync, FeaturesEvent};
+use atm0s_sdn::features::{alias, router_sync, FeaturesEvent};
 use atm0s_sdn::secure::StaticKeyAuthorization;
 use atm0s_sdn::services::visualization;
 use atm0s_sdn::{
     sans_io_runtime::backend::{PollBackend, PollingBackend},
     services::visualization::ConnectionInfo,
 };
-use atm0s_sdn::{NodeAddr, NodeId, SdnControllerUtils};
-use atm0s_sdn::{SdnBuilder, SdnExtOut, SdnOwner};
+use atm0s_sdn::{sdn_services_enum, LatencyProfile, LinkProfile, NodeAddr, NodeId, SdnControllerUtils, ServiceBroadcastLevel, VirtualNetwork};
+use atm0s_sdn::{
+    BootstrapConfig, DnsSeed, FileEventSink, LocalDiscoveryConfig, PcapWriter, ReplicationCfg, SdnBuilder, SdnExtOut, SdnMetrics, SdnOwner, SessionFile, SyslogEventSink, TapFilter, WatchdogConfig,
+    LOCAL_DISCOVERY_PORT, PROMETHEUS_CONTENT_TYPE, SESSION_MAX_AGE_MS,
+};
 use clap::{Parser, ValueEnum};
 use futures_util::{SinkExt, StreamExt};
 #[cfg(not(feature = "embed"))]
 use poem::endpoint::StaticFilesEndpoint;
 #[cfg(feature = "embed")]
 use poem::endpoint::{EmbeddedFileEndpoint, EmbeddedFilesEndpoint};
-use poem::web::Json;
+use poem::web::{Json, Path, Query};
 use poem::{
     get, handler,
     listener::TcpListener,
@@ -42,6 +45,9 @@ use std::{
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 use tokio::sync::{oneshot, Mutex};
 
+mod console;
+mod socks5;
+
 #[cfg(feature = "embed")]
 #[derive(RustEmbed)]
 #[folder = "public"]
@@ -69,6 +75,26 @@ struct Args {
     #[arg(env, short, long)]
     seeds: Vec<NodeAddr>,
 
+    /// Dial known-good peers, seeds and DNS seed lists again with exponential backoff when all connections are lost
+    #[arg(env, long, default_value_t = false)]
+    bootstrap: bool,
+
+    /// File for known-good peers of the bootstrap service, they are dialed after a restart when the seeds are unreachable
+    #[arg(env, long)]
+    bootstrap_peers_path: Option<String>,
+
+    /// DNS seed lists of the bootstrap service, like srv:_sdn._udp.example.com or txt:seeds.example.com
+    #[arg(env, long)]
+    dns_seeds: Vec<DnsSeed>,
+
+    /// Announce this node with udp broadcast and connect to other announced nodes on the same LAN
+    #[arg(env, long, default_value_t = false)]
+    local_discovery: bool,
+
+    /// Udp port of local discovery beacons, it must be the same on all nodes
+    #[arg(env, long, default_value_t = LOCAL_DISCOVERY_PORT)]
+    local_discovery_port: u16,
+
     /// Password for the network
     #[arg(env, short, long, default_value = "password")]
     password: String,
@@ -89,6 +115,10 @@ struct Args {
     #[arg(env, long)]
     custom_addrs: Vec<SocketAddr>,
 
+    /// STUN servers for discovering public addresses, which are appended to the node address
+    #[arg(env, long)]
+    stun_servers: Vec<SocketAddr>,
+
     /// Local tags
     #[arg(env, long)]
     local_tags: Vec<String>,
@@ -104,14 +134,115 @@ struct Args {
     /// Collector node, which will have UI for monitoring network structure
     #[arg(env, long)]
     collector: bool,
+
+    /// Observer node, which joins for monitoring but is never selected as a relay, next hop or dht server
+    #[arg(env, long)]
+    observer: bool,
+
+    /// Optimize for sub-50ms end-to-end latency, with smaller queues and dropping of stale messages
+    #[arg(env, long)]
+    bounded_latency: bool,
+
+    /// Mtu of a constrained link like LoRa, enables small frames and control traffic repair with neighbours
+    #[arg(env, long)]
+    link_mtu: Option<u16>,
+
+    /// OpenTelemetry collector endpoint for pushing metrics, like http://localhost:4318
+    #[arg(env, long)]
+    otlp_endpoint: Option<String>,
+
+    /// Interval in seconds for pushing metrics to the OpenTelemetry collector
+    #[arg(env, long, default_value_t = 10)]
+    otlp_interval: u64,
+
+    /// File for storing dht_kv maps which this node serves, so they survive restarts
+    #[arg(env, long)]
+    kv_storage_path: Option<String>,
+
+    /// File for persisting the node session, so a restart within --session-max-age-ms is treated as the same node by peers
+    #[arg(env, long)]
+    session_path: Option<String>,
+
+    /// Max time in milliseconds between the last refresh of the session file and the restart for reusing the session
+    #[arg(env, long, default_value_t = SESSION_MAX_AGE_MS)]
+    session_max_age_ms: u64,
+
+    /// File for the controller state, which is saved on shutdown and restored on start, so a fast restart rejoins without a full resync
+    #[arg(env, long)]
+    state_path: Option<String>,
+
+    /// File which each entry of the controller event journal is appended to, for post-incident analysis
+    #[arg(env, long)]
+    event_log_path: Option<String>,
+
+    /// Send each entry of the controller event journal to the local syslog daemon over /dev/log
+    #[arg(env, long, default_value_t = false)]
+    event_log_syslog: bool,
+
+    /// Delete the session file before starting, then the node starts with a clean identity
+    #[arg(env, long)]
+    session_reset: bool,
+
+    /// Address for serving Prometheus metrics at /metrics, like 0.0.0.0:9100
+    #[arg(env, long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Alert when the controller doesn't process ticks for this time in milliseconds
+    #[arg(env, long)]
+    watchdog_stall_ms: Option<u64>,
+
+    /// Restart the controller after a watchdog alert, connections are established again
+    #[arg(env, long)]
+    watchdog_restart: bool,
+
+    /// Run a standby controller in the second worker, which takes over with existing connections when the first one stalls
+    #[arg(env, long)]
+    standby_controller: bool,
+
+    /// Local address of a SOCKS5 proxy which tunnels connections to node-<node_id>.sdn or alias-<alias>.sdn, like 127.0.0.1:1080
+    #[arg(env, long)]
+    socks5_addr: Option<SocketAddr>,
+
+    /// Accept SOCKS5 tunnels from other nodes and connect them to local ports of this node
+    #[arg(env, long)]
+    socks5_exit: bool,
+
+    /// Aliases which are registered for this node, so it can be reached as alias-<alias>.sdn
+    #[arg(env, long)]
+    aliases: Vec<u64>,
+
+    /// File which messages of all features are mirrored to in pcap format, for debugging protocols
+    #[arg(env, long)]
+    tap_pcap: Option<String>,
+
+    /// Read admin console commands from stdin, like neighbours, kv get <map> or alias resolve <alias>
+    #[arg(env, long)]
+    console: bool,
+
+    /// Unix socket for admin console sessions, for example with socat - UNIX-CONNECT:<path>
+    #[arg(env, long)]
+    console_socket: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct VisualNodeInfo {
     uptime: u32,
 }
-type SC = visualization::Control<VisualNodeInfo>;
-type SE = visualization::Event<VisualNodeInfo>;
+
+sdn_services_enum! {
+    #[derive(Debug, Clone)]
+    enum SC {
+        Visualization(visualization::Control<VisualNodeInfo>) = visualization::SERVICE_ID,
+    }
+}
+
+sdn_services_enum! {
+    #[derive(Debug, Clone)]
+    enum SE {
+        Visualization(visualization::Event<VisualNodeInfo>) = visualization::SERVICE_ID,
+    }
+}
+
 type TC = ();
 type TW = ();
 
@@ -242,9 +373,35 @@ fn ws(ws: WebSocket, ctx: Data<&Arc<Mutex<WebsocketCtx>>>) -> impl IntoResponse
 }
 
 #[handler]
-async fn dump_router(ctx: Data<&UnboundedSender<oneshot::Sender<serde_json::Value>>>) -> impl IntoResponse {
+async fn resync_router(Path(node): Path<NodeId>, ctx: Data<&UnboundedSender<(NodeId, oneshot::Sender<serde_json::Value>)>>) -> impl IntoResponse {
     let (tx, rx) = oneshot::channel();
-    ctx.0.send(tx).expect("should send");
+    ctx.0.send((node, tx)).expect("should send");
+    match tokio::time::timeout(Duration::from_millis(router_sync::RESYNC_TIMEOUT_MS + 1000), rx).await {
+        Ok(Ok(v)) => Json(serde_json::json!({
+            "status": true,
+            "data": v
+        })),
+        Ok(Err(e)) => Json(serde_json::json!({
+            "status": false,
+            "error": e.to_string()
+        })),
+        Err(_e) => Json(serde_json::json!({
+            "status": false,
+            "error": "timeout"
+        })),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DumpRouterQuery {
+    /// Version of the last dump, only entries which changed after it are returned
+    since: Option<u64>,
+}
+
+#[handler]
+async fn dump_router(Query(query): Query<DumpRouterQuery>, ctx: Data<&UnboundedSender<(u64, oneshot::Sender<serde_json::Value>)>>) -> impl IntoResponse {
+    let (tx, rx) = oneshot::channel();
+    ctx.0.send((query.since.unwrap_or(0), tx)).expect("should send");
     match tokio::time::timeout(Duration::from_millis(1000), rx).await {
         Ok(Ok(v)) => Json(serde_json::json!({
             "status": true,
@@ -261,6 +418,11 @@ async fn dump_router(ctx: Data<&UnboundedSender<oneshot::Sender<serde_json::Valu
     }
 }
 
+#[handler]
+fn prometheus_metrics(metrics: Data<&Arc<SdnMetrics>>) -> impl IntoResponse {
+    metrics.0.prometheus().with_content_type(PROMETHEUS_CONTENT_TYPE)
+}
+
 #[tokio::main]
 async fn main() {
     if std::env::var_os("RUST_LOG").is_none() {
@@ -285,6 +447,10 @@ async fn main() {
         .collect::<Vec<_>>();
     let mut builder = SdnBuilder::<(), SC, SE, TC, TW, VisualNodeInfo>::new(args.node_id, &addrs, args.custom_addrs);
 
+    if !args.stun_servers.is_empty() {
+        builder.discover_public_addrs(&args.stun_servers, Duration::from_secs(2));
+    }
+
     builder.set_authorization(StaticKeyAuthorization::new(&args.password));
     builder.set_manual_discovery(args.local_tags, args.connect_tags);
 
@@ -293,25 +459,159 @@ async fn main() {
     }
 
     builder.set_visualization_collector(args.collector);
+    builder.set_observer(args.observer);
+
+    if args.bounded_latency {
+        builder.set_latency_profile(LatencyProfile::BoundedLatency);
+    }
+
+    if let Some(mtu) = args.link_mtu {
+        builder.set_link_profile(LinkProfile::constrained(mtu));
+    }
+
+    if let Some(endpoint) = &args.otlp_endpoint {
+        builder.enable_otlp_metrics(endpoint, Duration::from_secs(args.otlp_interval)).expect("Should have valid otlp endpoint");
+    }
+
+    if let Some(path) = &args.kv_storage_path {
+        builder.enable_dht_kv_file_storage(path).expect("Should open dht_kv storage file");
+    }
+
+    if let Some(path) = &args.event_log_path {
+        builder.add_event_sink(Arc::new(FileEventSink::open(path).expect("Should open event log file")));
+    }
+
+    if args.event_log_syslog {
+        builder.add_event_sink(Arc::new(SyslogEventSink::connect("/dev/log", "atm0s-sdn").expect("Should connect to syslog")));
+    }
+
+    if let Some(path) = &args.session_path {
+        if args.session_reset {
+            SessionFile::invalidate(path).expect("Should delete session file");
+        }
+        let restored = builder.enable_session_file(path, Duration::from_millis(args.session_max_age_ms)).expect("Should open session file");
+        log::info!("Node session restored from previous run: {restored}");
+    }
+
+    if let Some(path) = &args.state_path {
+        match std::fs::read(path) {
+            Ok(state) => match builder.with_restored_state(&state) {
+                Ok(()) => log::info!("Controller state restored from {path}"),
+                Err(e) => log::warn!("Ignore controller state in {path}: {e}"),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => panic!("Should read state file {path}: {e}"),
+        }
+    }
 
     for seed in args.seeds {
         builder.add_seed(seed);
     }
 
+    if args.bootstrap {
+        builder.enable_bootstrap(BootstrapConfig {
+            peers_file: args.bootstrap_peers_path.map(Into::into),
+            dns_seeds: args.dns_seeds,
+            ..Default::default()
+        });
+    }
+
+    if args.local_discovery {
+        builder.enable_local_discovery(LocalDiscoveryConfig {
+            port: args.local_discovery_port,
+            ..Default::default()
+        });
+    }
+
+    if let Some(stall_ms) = args.watchdog_stall_ms {
+        builder.enable_watchdog(WatchdogConfig::new(Duration::from_millis(stall_ms), args.watchdog_restart));
+    }
+
+    if args.standby_controller {
+        builder.enable_standby_controller(ReplicationCfg::default());
+    }
+
+    if let Some(metrics_addr) = args.metrics_addr {
+        let route = Route::new().at("/metrics", get(prometheus_metrics).data(builder.metrics()));
+        tokio::spawn(async move { Server::new(TcpListener::bind(metrics_addr)).run(route).await });
+    }
+
+    let metrics = builder.metrics();
     let node_info = VisualNodeInfo { uptime: 0 };
     let mut controller = match args.backend {
         BackendType::Poll => builder.build::<PollBackend<SdnOwner, 128, 128>>(args.workers, node_info),
         BackendType::Polling => builder.build::<PollingBackend<SdnOwner, 128, 128>>(args.workers, node_info),
     };
 
-    let (dump_tx, mut dump_rx) = unbounded_channel::<oneshot::Sender<serde_json::Value>>();
+    let mut tap_writer = args.tap_pcap.as_ref().map(|path| {
+        controller.tap(Some(TapFilter { payload: true, ..Default::default() }));
+        PcapWriter::new(std::io::BufWriter::new(std::fs::File::create(path).expect("Should create tap pcap file"))).expect("Should write pcap header")
+    });
+
+    let (mut vnet, vnet_handle) = VirtualNetwork::new();
+    let (alias_tx, mut alias_rx) = unbounded_channel::<(u64, oneshot::Sender<Option<NodeId>>)>();
+    for alias in &args.aliases {
+        controller.feature_control(
+            (),
+            alias::Control::Register {
+                alias: *alias,
+                service: socks5::ALIAS_SERVICE,
+                level: ServiceBroadcastLevel::Global,
+            }
+            .into(),
+        );
+    }
+    if let Some(addr) = args.socks5_addr {
+        let vnet_handle = vnet_handle.clone();
+        tokio::spawn(async move {
+            if let Err(e) = socks5::run_proxy(addr, vnet_handle, alias_tx).await {
+                log::error!("Socks5 proxy error {e}");
+            }
+        });
+    }
+    if args.socks5_exit {
+        tokio::spawn(async move {
+            if let Err(e) = socks5::run_exit(vnet_handle).await {
+                log::error!("Socks5 exit error {e}");
+            }
+        });
+    }
+
+    let mut console = if args.console || args.console_socket.is_some() {
+        let (console, requests) = console::Console::new(args.node_id, metrics, socks5::ALIAS_SERVICE);
+        controller.feature_control((), console.init_control());
+        if args.console {
+            let requests = requests.clone();
+            tokio::spawn(async move {
+                if let Err(e) = console::run_stdin(requests).await {
+                    log::error!("Console stdin error {e}");
+                }
+            });
+        }
+        if let Some(path) = args.console_socket.clone() {
+            tokio::spawn(async move {
+                if let Err(e) = console::run_unix(std::path::Path::new(&path), requests).await {
+                    log::error!("Console socket error {e}");
+                }
+            });
+        }
+        Some(console)
+    } else {
+        None
+    };
+
+    let (dump_tx, mut dump_rx) = unbounded_channel::<(u64, oneshot::Sender<serde_json::Value>)>();
+    let (resync_tx, mut resync_rx) = unbounded_channel::<(NodeId, oneshot::Sender<serde_json::Value>)>();
     let ctx = Arc::new(Mutex::new(WebsocketCtx::new()));
 
     if args.collector {
-        controller.service_control(visualization::SERVICE_ID.into(), (), visualization::Control::Subscribe);
+        controller.service_control_auto((), visualization::Control::Subscribe.into());
         let ctx_c = ctx.clone();
         tokio::spawn(async move {
-            let route = Route::new().at("/dump_router", get(dump_router).data(dump_tx)).at("/ws", get(ws.data(ctx_c)));
+            let route = Route::new()
+                .at("/dump_router", get(dump_router).data(dump_tx))
+                .at("/resync/:node", get(resync_router).data(resync_tx))
+                .at("/ws", get(ws.data(ctx_c)));
 
             #[cfg(not(feature = "embed"))]
             let route = route.nest("/", StaticFilesEndpoint::new("./public/").index_file("index.html"));
@@ -327,64 +627,188 @@ async fn main() {
 
     let started_at = Instant::now();
     let mut count = 0;
-    let mut wait_dump_router = vec![];
+    let mut wait_dump_router: HashMap<u64, Vec<oneshot::Sender<serde_json::Value>>> = HashMap::new();
+    let mut wait_resync: HashMap<NodeId, Vec<oneshot::Sender<serde_json::Value>>> = HashMap::new();
+    let mut wait_alias: HashMap<u64, Vec<oneshot::Sender<Option<NodeId>>>> = HashMap::new();
     while controller.process().is_some() {
+        let now_ms = started_at.elapsed().as_millis() as u64;
         if term.load(Ordering::Relaxed) {
             if shutdown_wait == 200 {
                 log::warn!("Force shutdown");
                 break;
             }
+            if shutdown_wait == 0 {
+                if let Some(path) = &args.state_path {
+                    let state = controller.snapshot_state();
+                    if let Err(e) = std::fs::write(path, &state) {
+                        log::error!("Save controller state to {path} error {e}");
+                    }
+                }
+            }
             shutdown_wait += 1;
             controller.shutdown();
         }
         if count % 500 == 0 {
             //each 5 seconds
-            controller.service_control(
-                visualization::SERVICE_ID.into(),
+            controller.service_control_auto(
                 (),
                 visualization::Control::UpdateInfo(VisualNodeInfo {
                     uptime: started_at.elapsed().as_secs() as u32,
-                }),
+                })
+                .into(),
+            );
+        }
+
+        while let Ok((since, v)) = dump_rx.try_recv() {
+            controller.feature_control((), router_sync::Control::DumpRouterDiff(since).into());
+            wait_dump_router.entry(since).or_default().push(v);
+        }
+
+        while let Ok((node, v)) = resync_rx.try_recv() {
+            controller.feature_control((), router_sync::Control::Resync(node).into());
+            wait_resync.entry(node).or_default().push(v);
+        }
+
+        while let Ok((alias, v)) = alias_rx.try_recv() {
+            controller.feature_control(
+                (),
+                alias::Control::Query {
+                    alias,
+                    service: socks5::ALIAS_SERVICE,
+                    level: ServiceBroadcastLevel::Global,
+                }
+                .into(),
             );
+            wait_alias.entry(alias).or_default().push(v);
+        }
+
+        if let Some(console) = &mut console {
+            while let Some(control) = console.pop_control() {
+                controller.feature_control((), control);
+            }
         }
 
-        while let Ok(v) = dump_rx.try_recv() {
-            controller.feature_control((), router_sync::Control::DumpRouter.into());
-            wait_dump_router.push(v);
+        vnet.on_tick(now_ms);
+        while let Some(control) = vnet.pop_control() {
+            controller.feature_control((), control);
         }
+        let mut visualization_ack = false;
         while let Some(event) = controller.pop_event() {
             match event {
-                SdnExtOut::ServicesEvent(_service, (), event) => match event {
+                SdnExtOut::ServicesEvent(_service, (), SE::Visualization(event)) => match event {
                     visualization::Event::GotAll(all) => {
                         log::info!("Got all: {:?}", all);
                         ctx.lock().await.set_snapshot(all);
+                        visualization_ack = true;
                     }
                     visualization::Event::NodeChanged(node, info, changed) => {
                         log::debug!("Node changed: {:?} {:?}", node, changed);
                         ctx.lock().await.set_node((node, info, changed));
+                        visualization_ack = true;
                     }
                     visualization::Event::NodeRemoved(node) => {
                         log::info!("Node removed: {:?}", node);
                         ctx.lock().await.del_node(node);
+                        visualization_ack = true;
+                    }
+                    visualization::Event::Evicted => {
+                        log::warn!("Visualization subscriber is evicted, subscribe again");
+                        controller.service_control_auto((), visualization::Control::Subscribe.into());
+                    }
+                    visualization::Event::Stats(stats) => {
+                        log::debug!("Stats of {} nodes", stats.len());
                     }
                 },
                 SdnExtOut::FeaturesEvent(_, event) => {
+                    if vnet.on_event(now_ms, &event) {
+                        continue;
+                    }
+                    if let Some(console) = &mut console {
+                        console.on_event(&event);
+                    }
+                    if let FeaturesEvent::Alias(alias::Event::QueryResult(alias, location)) = &event {
+                        let node = location.as_ref().map(|location| match location {
+                            alias::FoundLocation::Local => args.node_id,
+                            alias::FoundLocation::Notify(node) | alias::FoundLocation::CachedHint(node) | alias::FoundLocation::RemoteHint(node) | alias::FoundLocation::RemoteScan(node) => *node,
+                        });
+                        for v in wait_alias.remove(alias).unwrap_or_default() {
+                            let _ = v.send(node);
+                        }
+                    }
                     if let FeaturesEvent::RouterSync(event) = event {
                         match event {
                             router_sync::Event::DumpRouter(value) => {
-                                let json = serde_json::to_value(value).expect("should convert json");
-                                while let Some(v) = wait_dump_router.pop() {
+                                log::debug!("Router dump of {}", value.node_id());
+                            }
+                            router_sync::Event::DumpRouterDiff(dump) => {
+                                let json = serde_json::to_value(&dump).expect("should convert json");
+                                for v in wait_dump_router.remove(&dump.since).unwrap_or_default() {
+                                    let _ = v.send(json.clone());
+                                }
+                            }
+                            router_sync::Event::DumpNetwork(token, snapshots) => {
+                                log::info!("Network dump {token} with {} nodes", snapshots.len());
+                            }
+                            router_sync::Event::PinState(dest, active) => {
+                                log::info!("Pinned route to {dest} active {active}");
+                            }
+                            router_sync::Event::Resynced(node, hashes) => {
+                                log::info!("Resync with {node} finished, table hashes {:?}", hashes);
+                                let json = match hashes {
+                                    Some((before, after)) => serde_json::json!({ "node": node, "before": before, "after": after }),
+                                    None => serde_json::json!({ "node": node, "error": "not connected or no answer" }),
+                                };
+                                for v in wait_resync.remove(&node).unwrap_or_default() {
                                     let _ = v.send(json.clone());
                                 }
                             }
                         }
                     }
                 }
+                SdnExtOut::InterfaceEvent(event) => {
+                    log::info!("Interface event: {:?}", event);
+                }
+                SdnExtOut::DecommissionEvent(event) => {
+                    log::info!("Decommission event: {:?}", event);
+                }
+                SdnExtOut::WatchdogAlert(alert) => {
+                    log::error!("Watchdog alert: {:?}", alert);
+                }
+                SdnExtOut::CapabilitySkew(skew) => {
+                    log::warn!("Version skew: {skew}");
+                }
+                SdnExtOut::ServiceReady(service) => {
+                    log::info!("Service {service} is ready");
+                }
+                SdnExtOut::ControllerTakeover(takeover) => {
+                    log::warn!("Standby controller took over: {:?}", takeover);
+                }
+                SdnExtOut::EventLog(entries) => {
+                    for entry in entries {
+                        log::info!("Event log: {entry}");
+                    }
+                }
+                SdnExtOut::Tap(record) => {
+                    if let Some(writer) = &mut tap_writer {
+                        if let Err(e) = writer.write(&record) {
+                            log::error!("Write tap record error {e}");
+                        }
+                    }
+                }
             }
         }
+        if visualization_ack {
+            // the collector keeps consuming, otherwise it is marked slow and evicted
+            controller.service_control_auto((), visualization::Control::Ack.into());
+        }
         tokio::time::sleep(Duration::from_millis(10)).await;
         count += 1;
     }
 
+    if let Some(mut writer) = tap_writer {
+        if let Err(e) = writer.flush() {
+            log::error!("Flush tap pcap file error {e}");
+        }
+    }
     log::info!("Server shutdown");
 }
@@ -0,0 +1,213 @@
+//! SOCKS5 proxy which tunnels tcp connections to nodes of the network over virtual streams.
+//!
+//! Only the CONNECT command without authentication is supported, and the host must be a domain inside the `.sdn` zone:
+//! `node-<node_id>.sdn` connects to a node directly and `alias-<alias>.sdn` looks up the node which registered the alias.
+//! The exit node reads the target port from the first 2 bytes of the stream, connects to that port on localhost and
+//! answers with one status byte, which is the SOCKS5 reply code, before the data is piped in both directions.
+
+use std::{io, net::SocketAddr, time::Duration};
+
+use atm0s_sdn::{services::manual_discovery, NodeId, VirtualNetworkHandle, VirtualStream};
+use tokio::{
+    io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc::UnboundedSender, oneshot},
+};
+
+/// Virtual stream port where exit nodes accept tunnels
+pub const SOCKS5_EXIT_PORT: u16 = 1080;
+pub const ALIAS_RESOLVE_TIMEOUT_MS: u64 = 10000;
+/// Aliases are registered and scanned with the manual discovery service, which runs and is discoverable on every standalone node
+pub const ALIAS_SERVICE: u8 = manual_discovery::SERVICE_ID;
+
+const SOCKS_VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xFF;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+const REPLY_SUCCEEDED: u8 = 0;
+const REPLY_GENERAL_FAILURE: u8 = 1;
+const REPLY_HOST_UNREACHABLE: u8 = 4;
+const REPLY_CONNECTION_REFUSED: u8 = 5;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+/// Requests for resolving an alias to the node which registered it, answered by the controller loop
+pub type AliasResolver = UnboundedSender<(u64, oneshot::Sender<Option<NodeId>>)>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SdnHost {
+    Node(NodeId),
+    Alias(u64),
+}
+
+impl SdnHost {
+    /// Parse `node-<node_id>.sdn` or `alias-<alias>.sdn`, case insensitive like dns
+    pub fn parse(host: &str) -> Option<Self> {
+        let host = host.to_ascii_lowercase();
+        let name = host.strip_suffix(".sdn")?;
+        if let Some(node) = name.strip_prefix("node-") {
+            node.parse().ok().map(Self::Node)
+        } else if let Some(alias) = name.strip_prefix("alias-") {
+            alias.parse().ok().map(Self::Alias)
+        } else {
+            None
+        }
+    }
+}
+
+/// Accept SOCKS5 clients on the local address
+pub async fn run_proxy(addr: SocketAddr, vnet: VirtualNetworkHandle, resolver: AliasResolver) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("[Socks5] proxy listen on {addr}");
+    loop {
+        let (socket, remote) = listener.accept().await?;
+        let vnet = vnet.clone();
+        let resolver = resolver.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(socket, vnet, resolver).await {
+                log::warn!("[Socks5] client {remote} error {e}");
+            }
+        });
+    }
+}
+
+/// Accept tunnels from other nodes and connect them to local ports
+pub async fn run_exit(vnet: VirtualNetworkHandle) -> io::Result<()> {
+    let listener = vnet.listen(SOCKS5_EXIT_PORT).await?;
+    log::info!("[Socks5] exit listen on virtual port {SOCKS5_EXIT_PORT}");
+    loop {
+        let stream = listener.accept().await?;
+        tokio::spawn(async move {
+            let (node, port) = stream.remote();
+            if let Err(e) = handle_exit(stream).await {
+                log::warn!("[Socks5] tunnel from {node}:{port} error {e}");
+            }
+        });
+    }
+}
+
+async fn handle_client(mut socket: TcpStream, vnet: VirtualNetworkHandle, resolver: AliasResolver) -> io::Result<()> {
+    let mut header = [0; 2];
+    socket.read_exact(&mut header).await?;
+    if header[0] != SOCKS_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported version {}", header[0])));
+    }
+    let mut methods = vec![0; header[1] as usize];
+    socket.read_exact(&mut methods).await?;
+    if !methods.contains(&METHOD_NO_AUTH) {
+        socket.write_all(&[SOCKS_VERSION, METHOD_NONE_ACCEPTABLE]).await?;
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "client requires authentication"));
+    }
+    socket.write_all(&[SOCKS_VERSION, METHOD_NO_AUTH]).await?;
+
+    let mut request = [0; 4];
+    socket.read_exact(&mut request).await?;
+    let host = match request[3] {
+        ATYP_DOMAIN => {
+            let len = socket.read_u8().await? as usize;
+            let mut domain = vec![0; len];
+            socket.read_exact(&mut domain).await?;
+            Some(String::from_utf8_lossy(&domain).to_string())
+        }
+        ATYP_IPV4 => {
+            socket.read_exact(&mut [0; 4]).await?;
+            None
+        }
+        ATYP_IPV6 => {
+            socket.read_exact(&mut [0; 16]).await?;
+            None
+        }
+        atyp => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid address type {atyp}"))),
+    };
+    let port = socket.read_u16().await?;
+    if request[1] != CMD_CONNECT {
+        return reply(&mut socket, REPLY_COMMAND_NOT_SUPPORTED).await;
+    }
+    let host = match host.as_deref().and_then(SdnHost::parse) {
+        Some(host) => host,
+        None => {
+            log::warn!("[Socks5] reject host {host:?}, only node-<id>.sdn and alias-<alias>.sdn are supported");
+            return reply(&mut socket, REPLY_ADDRESS_NOT_SUPPORTED).await;
+        }
+    };
+    let node = match host {
+        SdnHost::Node(node) => Some(node),
+        SdnHost::Alias(alias) => resolve_alias(&resolver, alias).await,
+    };
+    let node = match node {
+        Some(node) => node,
+        None => {
+            log::warn!("[Socks5] cannot resolve {host:?}");
+            return reply(&mut socket, REPLY_HOST_UNREACHABLE).await;
+        }
+    };
+
+    log::info!("[Socks5] tunnel to {node} port {port}");
+    let mut stream = match vnet.connect(node, SOCKS5_EXIT_PORT).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::warn!("[Socks5] connect to exit of {node} error {e}");
+            let code = match e.kind() {
+                io::ErrorKind::TimedOut => REPLY_HOST_UNREACHABLE,
+                _ => REPLY_CONNECTION_REFUSED,
+            };
+            return reply(&mut socket, code).await;
+        }
+    };
+    stream.write_u16(port).await?;
+    let status = stream.read_u8().await.unwrap_or(REPLY_GENERAL_FAILURE);
+    reply(&mut socket, status).await?;
+    if status != REPLY_SUCCEEDED {
+        return Ok(());
+    }
+    copy_bidirectional(&mut socket, &mut stream).await?;
+    Ok(())
+}
+
+async fn handle_exit(mut stream: VirtualStream) -> io::Result<()> {
+    let port = stream.read_u16().await?;
+    let mut socket = match TcpStream::connect(("127.0.0.1", port)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::warn!("[Socks5] exit connect to local port {port} error {e}");
+            let code = match e.kind() {
+                io::ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+                _ => REPLY_GENERAL_FAILURE,
+            };
+            stream.write_u8(code).await?;
+            return stream.shutdown().await;
+        }
+    };
+    stream.write_u8(REPLY_SUCCEEDED).await?;
+    copy_bidirectional(&mut stream, &mut socket).await?;
+    Ok(())
+}
+
+async fn resolve_alias(resolver: &AliasResolver, alias: u64) -> Option<NodeId> {
+    let (tx, rx) = oneshot::channel();
+    resolver.send((alias, tx)).ok()?;
+    tokio::time::timeout(Duration::from_millis(ALIAS_RESOLVE_TIMEOUT_MS), rx).await.ok()?.ok()?
+}
+
+/// Reply with an unspecified bound address, clients only need the code
+async fn reply(socket: &mut TcpStream, code: u8) -> io::Result<()> {
+    socket.write_all(&[SOCKS_VERSION, code, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SdnHost;
+
+    #[test]
+    fn parse_sdn_host() {
+        assert_eq!(SdnHost::parse("node-42.sdn"), Some(SdnHost::Node(42)));
+        assert_eq!(SdnHost::parse("Alias-1234.SDN"), Some(SdnHost::Alias(1234)));
+        assert_eq!(SdnHost::parse("alias-1234.sdn.com"), None);
+        assert_eq!(SdnHost::parse("node-abc.sdn"), None);
+        assert_eq!(SdnHost::parse("example.com"), None);
+    }
+}
@@ -48,6 +48,16 @@ impl ClusterLogic {
         match input {
             Input::Pubsub(pubsub::Event(channel, event)) => match event {
                 pubsub::ChannelEvent::RouteChanged(_) => None,
+                pubsub::ChannelEvent::NoSourceFound => None,
+                pubsub::ChannelEvent::SourceFound(_) => None,
+                pubsub::ChannelEvent::LoopbackStats(_) => None,
+                pubsub::ChannelEvent::RelayCreated(_) | pubsub::ChannelEvent::RelayIdle(_, _) | pubsub::ChannelEvent::RelayDestroyed(_, _) => None,
+                pubsub::ChannelEvent::ReplayData(_, _) => None,
+                pubsub::ChannelEvent::RangeMatched(_) | pubsub::ChannelEvent::RangeUnmatched(_) | pubsub::ChannelEvent::RangeRejected => None,
+                pubsub::ChannelEvent::SubRejected(source) => {
+                    log::warn!("Subscribe to channel {} from source {} is rejected", channel, source);
+                    None
+                }
                 pubsub::ChannelEvent::SourceData(_, data) => {
                     let pkt = TrackMedia::from_buffer(&data);
                     let channel = self.channels.get(&channel)?;
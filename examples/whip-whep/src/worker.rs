@@ -269,9 +269,21 @@ impl RunnerWorker {
                     }
                 }
                 SdnExtOut::ServicesEvent(..) => {}
+                SdnExtOut::InterfaceEvent(..) => {}
+                SdnExtOut::DecommissionEvent(..) => {}
+                SdnExtOut::WatchdogAlert(alert) => log::error!("Watchdog alert: {:?}", alert),
+                SdnExtOut::CapabilitySkew(skew) => log::warn!("Version skew: {skew}"),
+                SdnExtOut::ServiceReady(service) => log::info!("Service {service} is ready"),
+                SdnExtOut::ControllerTakeover(takeover) => log::warn!("Standby controller took over: {:?}", takeover),
+                SdnExtOut::EventLog(entries) => {
+                    for entry in entries {
+                        log::info!("Event log: {entry}");
+                    }
+                }
+                SdnExtOut::Tap(..) => {}
             },
             SdnWorkerOutput::Net(out) => match out {
-                NetOutput::UdpPacket(remote, data) => self.queue.push_back(WorkerInnerOutput::Net(
+                NetOutput::UdpPacket(remote, data, _) => self.queue.push_back(WorkerInnerOutput::Net(
                     RunnerOwner::Sdn(SdnOwner),
                     BackendOutgoing::UdpPacket {
                         slot: self.sdn_backend_slot,
@@ -279,7 +291,7 @@ impl RunnerWorker {
                         data,
                     },
                 )),
-                NetOutput::UdpPackets(remotes, data) => self.queue.push_back(WorkerInnerOutput::Net(
+                NetOutput::UdpPackets(remotes, data, _) => self.queue.push_back(WorkerInnerOutput::Net(
                     RunnerOwner::Sdn(SdnOwner),
                     BackendOutgoing::UdpPackets {
                         slot: self.sdn_backend_slot,
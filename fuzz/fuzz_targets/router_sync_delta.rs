@@ -0,0 +1,8 @@
+#![no_main]
+
+use atm0s_sdn_network::_fuzz_export::router_sync::RouterSyncFuzzer;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    RouterSyncFuzzer::run(data);
+});
@@ -0,0 +1,18 @@
+//! Write seed inputs of stateful fuzz targets, usage: `cargo run --bin gen_corpus [corpus dir]`
+
+use std::{fs, io, path::PathBuf};
+
+use atm0s_sdn_network::_fuzz_export::{neighbours, router_sync};
+
+fn main() -> io::Result<()> {
+    let dir = PathBuf::from(std::env::args().nth(1).unwrap_or_else(|| "corpus".to_string()));
+    for (target, inputs) in [("neighbours_handshake", neighbours::corpus()), ("router_sync_delta", router_sync::corpus())] {
+        let target_dir = dir.join(target);
+        fs::create_dir_all(&target_dir)?;
+        for (index, input) in inputs.iter().enumerate() {
+            fs::write(target_dir.join(format!("seed_{index}")), input)?;
+        }
+        println!("wrote {} seeds to {}", inputs.len(), target_dir.display());
+    }
+    Ok(())
+}
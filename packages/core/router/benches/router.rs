@@ -1,7 +1,7 @@
 use std::vec;
 
 use atm0s_sdn_identity::ConnId;
-use atm0s_sdn_router::core::{Metric, RegistrySync, Router, RouterSync};
+use atm0s_sdn_router::core::{Metric, RegistrySync, Router, RouterSync, UNLIMITED_BANDWIDTH};
 use criterion::{criterion_group, criterion_main, Criterion};
 
 criterion_group!(benches, benchmark_empty, benchmark_single, benchmark_full);
@@ -43,7 +43,7 @@ fn benchmark_single(c: &mut Criterion) {
     router.apply_sync(
         ConnId::from_in(0, 0),
         Metric::new(1, vec![1], 100000),
-        RouterSync(RegistrySync(vec![(0, Metric::new(1, vec![], 100000))]), [None, None, None, None]),
+        RouterSync(RegistrySync(vec![(0, Metric::new(1, vec![], 100000))]), [None, None, None, None], 0, vec![], UNLIMITED_BANDWIDTH),
     );
     group.bench_function("next_service", |b| {
         b.iter(|| router.service_next(1, &[]));
@@ -71,7 +71,11 @@ fn benchmark_full(c: &mut Criterion) {
         services.push((s, Metric::new(1, vec![1], 100000)));
     }
     router.set_direct(ConnId::from_in(0, 0), Metric::new(1, vec![1], 100000));
-    router.apply_sync(ConnId::from_in(0, 0), Metric::new(1, vec![], 100000), RouterSync(RegistrySync(services), [None, None, None, None]));
+    router.apply_sync(
+        ConnId::from_in(0, 0),
+        Metric::new(1, vec![], 100000),
+        RouterSync(RegistrySync(services), [None, None, None, None], 0, vec![], UNLIMITED_BANDWIDTH),
+    );
     group.bench_function("next_service", |b| {
         b.iter(|| router.service_next(1, &[]));
     });
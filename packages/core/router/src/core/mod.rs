@@ -6,7 +6,7 @@ mod table;
 
 pub use self::registry::{RegisterDestDump, RegisterDump, Registry, RegistryDelta, RegistryDestDelta, RegistrySync};
 pub use self::router::{Router, RouterDelta, RouterDump, RouterSync};
-pub use self::table::{DestDelta, DestDump, Metric, Path, TableDelta, TableDump, TableSync, BANDWIDTH_LIMIT};
+pub use self::table::{DestDelta, DestDump, Metric, Path, RoutingPolicy, TableDelta, TableDump, TableSync, BANDWIDTH_LIMIT, UNLIMITED_BANDWIDTH};
 
 #[derive(PartialEq, Debug)]
 pub enum ServiceDestination {
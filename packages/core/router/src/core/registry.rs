@@ -7,6 +7,8 @@ mod dest;
 
 pub use self::dest::{RegisterDestDump, RegistryDestDelta};
 
+use crate::ServicePlacement;
+
 use super::{registry::dest::RegistryDest, Metric, Path, ServiceDestination};
 
 pub const REGISTRY_LOCAL_BW: u32 = 1000000; //1Gbps
@@ -16,21 +18,50 @@ pub enum RegistryDelta {
     ServiceRemote(u8, RegistryDestDelta),
     SetServiceLocal(u8),
     DelServiceLocal(u8),
+    SetServicePlacement(u8, ServicePlacement),
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct RegistrySync(pub Vec<(u8, Metric)>);
 
-#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+impl RegistrySync {
+    /// Load of each synced service, it is not serialized with the sync and only sent to neighbours which support it
+    pub fn loads(&self) -> Vec<u8> {
+        self.0.iter().map(|(_, metric)| metric.load).collect()
+    }
+
+    /// Set loads which are received together with the sync, in the same order as services
+    pub fn set_loads(&mut self, loads: &[u8]) {
+        for ((_, metric), load) in self.0.iter_mut().zip(loads) {
+            metric.load = *load;
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct RegisterDump {
     local: Vec<u8>,
     remotes: HashMap<u8, RegisterDestDump>,
 }
 
+impl RegisterDump {
+    /// Services which are registered by this node
+    pub fn local(&self) -> &[u8] {
+        &self.local
+    }
+
+    /// Paths to remote instances of each service
+    pub fn remotes(&self) -> &HashMap<u8, RegisterDestDump> {
+        &self.remotes
+    }
+}
+
 pub struct Registry {
     node_id: NodeId,
     local_destinations: [bool; 256],
     remote_destinations: [RegistryDest; 256],
+    placements: [ServicePlacement; 256],
+    loads: [u8; 256],
     deltas: VecDeque<RegistryDelta>,
 }
 
@@ -40,6 +71,8 @@ impl Registry {
             node_id,
             local_destinations: [false; 256],
             remote_destinations: std::array::from_fn(|_| RegistryDest::default()),
+            placements: [ServicePlacement::Any; 256],
+            loads: [0; 256],
             deltas: VecDeque::new(),
         }
     }
@@ -73,6 +106,27 @@ impl Registry {
         self.deltas.push_back(RegistryDelta::DelServiceLocal(service_id));
     }
 
+    /// Restrict which instances of a service can be selected as destination
+    pub fn set_placement(&mut self, service_id: u8, placement: ServicePlacement) {
+        if self.placements[service_id as usize] != placement {
+            self.placements[service_id as usize] = placement;
+            self.deltas.push_back(RegistryDelta::SetServicePlacement(service_id, placement));
+        }
+    }
+
+    /// Set load of local service instance, it will be sent to other nodes with next sync
+    pub fn set_load(&mut self, service_id: u8, load: u8) {
+        self.loads[service_id as usize] = load;
+    }
+
+    pub fn placement(&self, service_id: u8) -> ServicePlacement {
+        self.placements[service_id as usize]
+    }
+
+    fn is_local_allowed(&self, service_id: u8) -> bool {
+        self.local_destinations[service_id as usize] && self.placements[service_id as usize].allowed(self.node_id)
+    }
+
     pub fn del_direct(&mut self, conn: ConnId) {
         for i in 0..=255 {
             let pre_empty = self.remote_destinations[i as usize].is_empty();
@@ -87,10 +141,11 @@ impl Registry {
     }
 
     pub fn next(&self, service_id: u8, excepts: &[NodeId]) -> Option<ServiceDestination> {
-        if self.local_destinations[service_id as usize] {
+        if self.is_local_allowed(service_id) {
             Some(ServiceDestination::Local)
         } else {
-            self.remote_destinations[service_id as usize].next(excepts).map(|(c, n)| ServiceDestination::Remote(c, n))
+            let placement = self.placements[service_id as usize];
+            self.remote_destinations[service_id as usize].next(excepts, placement).map(|(c, n)| ServiceDestination::Remote(c, n))
         }
     }
 
@@ -130,12 +185,12 @@ impl Registry {
     pub fn sync_for(&self, node: NodeId) -> RegistrySync {
         let mut res = vec![];
         for i in 0..=255 {
-            if self.local_destinations[i as usize] {
-                res.push((i, Metric::new(0, vec![], REGISTRY_LOCAL_BW)));
+            if self.is_local_allowed(i) {
+                res.push((i, Metric::new(0, vec![], REGISTRY_LOCAL_BW).with_load(self.loads[i as usize])));
             } else {
                 let dest: &RegistryDest = &self.remote_destinations[i as usize];
                 if !dest.is_empty() {
-                    if let Some(Path(_over, metric)) = dest.best_for(node) {
+                    if let Some(Path(_over, metric)) = dest.best_for(node, self.placements[i as usize]) {
                         res.push((i, metric));
                     }
                 }
@@ -155,7 +210,7 @@ impl Registry {
         let mut slots = vec![];
         for (index, dest) in self.remote_destinations.iter().enumerate() {
             if !dest.is_empty() {
-                slots.push((index, dest.next(&[]).map(|(_c, n)| n)));
+                slots.push((index, dest.next(&[], self.placements[index]).map(|(_c, n)| n)));
             }
         }
         log::debug!("[Registry {}] local services: {:?} remote services: {:?}", self.node_id, local_services, slots);
@@ -172,7 +227,7 @@ impl Registry {
         let mut slots = vec![];
         for (index, dest) in self.remote_destinations.iter().enumerate() {
             if !dest.is_empty() {
-                slots.push((index, dest.next(&[]).map(|(_c, n)| n)));
+                slots.push((index, dest.next(&[], self.placements[index]).map(|(_c, n)| n)));
             }
         }
         println!("[Registry {}] local services: {:?} remote services: {:?}", self.node_id, local_services, slots);
@@ -188,6 +243,7 @@ mod tests {
         table::BANDWIDTH_LIMIT,
         Metric, Registry, RegistryDelta, RegistrySync, ServiceDestination,
     };
+    use crate::ServicePlacement;
 
     #[test]
     fn create_manual() {
@@ -219,7 +275,7 @@ mod tests {
 
         assert_eq!(registry.next(1, &[]), None);
         registry.apply_sync(conn1, Metric::new(1, vec![1], BANDWIDTH_LIMIT), RegistrySync(vec![(1, Metric::new(1, vec![], BANDWIDTH_LIMIT))]));
-        assert_eq!(registry.pop_delta(), Some(RegistryDelta::ServiceRemote(1, RegistryDestDelta::SetServicePath(conn1, 1, 12))));
+        assert_eq!(registry.pop_delta(), Some(RegistryDelta::ServiceRemote(1, RegistryDestDelta::SetServicePath(conn1, 1, 12, 0))));
         assert_eq!(registry.pop_delta(), None);
 
         assert_eq!(registry.next(1, &[]), Some(ServiceDestination::Remote(conn1, node1)));
@@ -242,8 +298,8 @@ mod tests {
 
         let sync = vec![(2, Metric::new(1, vec![], BANDWIDTH_LIMIT)), (3, Metric::new(1, vec![], BANDWIDTH_LIMIT))];
         registry.apply_sync(conn1, Metric::new(1, vec![node1], BANDWIDTH_LIMIT), RegistrySync(sync));
-        assert_eq!(registry.pop_delta(), Some(RegistryDelta::ServiceRemote(2, RegistryDestDelta::SetServicePath(conn1, node1, 12))));
-        assert_eq!(registry.pop_delta(), Some(RegistryDelta::ServiceRemote(3, RegistryDestDelta::SetServicePath(conn1, node1, 12))));
+        assert_eq!(registry.pop_delta(), Some(RegistryDelta::ServiceRemote(2, RegistryDestDelta::SetServicePath(conn1, node1, 12, 0))));
+        assert_eq!(registry.pop_delta(), Some(RegistryDelta::ServiceRemote(3, RegistryDestDelta::SetServicePath(conn1, node1, 12, 0))));
         assert_eq!(registry.pop_delta(), None);
 
         assert_eq!(registry.next(1, &[]), None);
@@ -280,5 +336,72 @@ mod tests {
         assert_eq!(registry.sync_for(node4), RegistrySync(vec![(2, Metric::new(2, vec![node3, node2, node1], BANDWIDTH_LIMIT))]));
     }
 
+    #[test]
+    fn sync_load() {
+        let node0: NodeId = 0x0;
+        let mut registry = Registry::new(node0);
+        let conn1: ConnId = ConnId::from_out(0, 0x1);
+        let node1: NodeId = 0x1;
+        let node2: NodeId = 0x2;
+
+        registry.add_service(1);
+        registry.set_load(1, 100);
+        let sync = registry.sync_for(node2);
+        assert_eq!(sync.0.len(), 1);
+        assert_eq!(sync.0[0].1.load, 100);
+
+        // load is not serialized with the sync, it is carried separately
+        let mut decoded: RegistrySync = bincode::deserialize(&bincode::serialize(&sync).expect("should serialize")).expect("should deserialize");
+        assert_eq!(decoded.loads(), vec![0]);
+        decoded.set_loads(&sync.loads());
+        assert_eq!(decoded.loads(), vec![100]);
+
+        let mut registry2 = Registry::new(node2);
+        registry2.apply_sync(
+            conn1,
+            Metric::new(1, vec![node1], BANDWIDTH_LIMIT),
+            RegistrySync(vec![(1, Metric::new(1, vec![], BANDWIDTH_LIMIT).with_load(50))]),
+        );
+        assert_eq!(registry2.pop_delta(), Some(RegistryDelta::ServiceRemote(1, RegistryDestDelta::SetServicePath(conn1, node1, 12, 50))));
+
+        // only load changed should generate delta
+        registry2.apply_sync(
+            conn1,
+            Metric::new(1, vec![node1], BANDWIDTH_LIMIT),
+            RegistrySync(vec![(1, Metric::new(1, vec![], BANDWIDTH_LIMIT).with_load(60))]),
+        );
+        assert_eq!(registry2.pop_delta(), Some(RegistryDelta::ServiceRemote(1, RegistryDestDelta::SetServicePath(conn1, node1, 12, 60))));
+        assert_eq!(registry2.pop_delta(), None);
+    }
+
+    #[test]
+    fn placement_filter() {
+        let node0: NodeId = 0x01000000;
+        let mut registry = Registry::new(node0);
+        let conn1: ConnId = ConnId::from_out(0, 0x1);
+        let node1: NodeId = 0x02000001;
+        let node2: NodeId = 0x02000002;
+
+        registry.add_service(1);
+        registry.apply_sync(conn1, Metric::new(1, vec![node1], BANDWIDTH_LIMIT), RegistrySync(vec![(1, Metric::new(1, vec![], BANDWIDTH_LIMIT))]));
+        while registry.pop_delta().is_some() {}
+        assert_eq!(registry.next(1, &[]), Some(ServiceDestination::Local));
+
+        registry.set_placement(1, ServicePlacement::Geo1(2));
+        assert_eq!(registry.pop_delta(), Some(RegistryDelta::SetServicePlacement(1, ServicePlacement::Geo1(2))));
+        assert_eq!(registry.pop_delta(), None);
+        assert_eq!(registry.next(1, &[]), Some(ServiceDestination::Remote(conn1, node1)));
+        assert_eq!(registry.sync_for(node2), RegistrySync(vec![(1, Metric::new(2, vec![node1], BANDWIDTH_LIMIT))]));
+
+        registry.set_placement(1, ServicePlacement::Geo1(3));
+        assert_eq!(registry.next(1, &[]), None);
+        assert_eq!(registry.sync_for(node2), RegistrySync(vec![]));
+
+        // same placement should not generate delta
+        registry.pop_delta();
+        registry.set_placement(1, ServicePlacement::Geo1(3));
+        assert_eq!(registry.pop_delta(), None);
+    }
+
     //TODO test multi connections with same node
 }
@@ -1,22 +1,37 @@
 use std::collections::{HashMap, VecDeque};
 
 use atm0s_sdn_identity::{ConnId, NodeId};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+
+use crate::ServicePlacement;
 
 use super::{Metric, Path};
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum RegistryDestDelta {
-    SetServicePath(ConnId, NodeId, u32),
+    /// conn, dest, score, load
+    SetServicePath(ConnId, NodeId, u32, u8),
     DelServicePath(ConnId),
 }
 
-#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct RegisterDestDump {
     next: Option<NodeId>,
     paths: HashMap<NodeId, Metric>,
 }
 
+impl RegisterDestDump {
+    /// Next hop of the best path without placement
+    pub fn next(&self) -> Option<NodeId> {
+        self.next
+    }
+
+    /// Paths by over node
+    pub fn paths(&self) -> &HashMap<NodeId, Metric> {
+        &self.paths
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct RegistryDest {
     paths: Vec<Path>,
@@ -26,7 +41,7 @@ pub struct RegistryDest {
 impl RegistryDest {
     pub fn dump(&self) -> RegisterDestDump {
         RegisterDestDump {
-            next: self.next(&[]).map(|p| p.1),
+            next: self.next(&[], ServicePlacement::Any).map(|p| p.1),
             paths: self.paths.iter().map(|p| (p.1.over_node(), p.1.clone())).collect(),
         }
     }
@@ -35,13 +50,13 @@ impl RegistryDest {
         match self.index_of(over) {
             Some(index) => {
                 let slot = &mut self.paths[index];
-                if slot.1.score() != metric.score() || slot.1.dest_node() != metric.dest_node() {
-                    self.deltas.push_back(RegistryDestDelta::SetServicePath(over, metric.dest_node(), metric.score()));
+                if slot.1.score() != metric.score() || slot.1.dest_node() != metric.dest_node() || slot.1.load != metric.load {
+                    self.deltas.push_back(RegistryDestDelta::SetServicePath(over, metric.dest_node(), metric.score(), metric.load));
                 }
                 slot.1 = metric;
             }
             None => {
-                self.deltas.push_back(RegistryDestDelta::SetServicePath(over, metric.dest_node(), metric.score()));
+                self.deltas.push_back(RegistryDestDelta::SetServicePath(over, metric.dest_node(), metric.score(), metric.load));
                 self.paths.push(Path(over, metric));
             }
         }
@@ -71,19 +86,19 @@ impl RegistryDest {
         self.paths.is_empty()
     }
 
-    /// get next node to dest but not in excepts
-    pub fn next(&self, excepts: &[NodeId]) -> Option<(ConnId, NodeId)> {
+    /// get next node to dest but not in excepts, only dest which allowed by placement is accepted
+    pub fn next(&self, excepts: &[NodeId], placement: ServicePlacement) -> Option<(ConnId, NodeId)> {
         for path in self.paths.iter() {
-            if !excepts.contains(&path.1.over_node()) {
+            if !excepts.contains(&path.1.over_node()) && placement.allowed(path.1.dest_node()) {
                 return Some((path.0, path.1.over_node()));
             }
         }
         None
     }
 
-    pub fn best_for(&self, neighbour_id: NodeId) -> Option<Path> {
+    pub fn best_for(&self, neighbour_id: NodeId, placement: ServicePlacement) -> Option<Path> {
         for path in self.paths.iter() {
-            if !path.1.contain_in_hops(neighbour_id) {
+            if !path.1.contain_in_hops(neighbour_id) && placement.allowed(path.1.dest_node()) {
                 return Some(path.clone());
             }
         }
@@ -134,17 +149,17 @@ mod tests {
 
         let mut dest = RegistryDest::default();
         dest.set_path(conn1, Metric::new(1, vec![4, 1], BANDWIDTH_LIMIT)); //directed connection
-        assert_eq!(dest.pop_delta(), Some(RegistryDestDelta::SetServicePath(conn1, node4, 21)));
+        assert_eq!(dest.pop_delta(), Some(RegistryDestDelta::SetServicePath(conn1, node4, 21, 0)));
         assert_eq!(dest.pop_delta(), None);
         dest.set_path(conn2, Metric::new(2, vec![4, 2], BANDWIDTH_LIMIT));
-        assert_eq!(dest.pop_delta(), Some(RegistryDestDelta::SetServicePath(conn2, node4, 22)));
+        assert_eq!(dest.pop_delta(), Some(RegistryDestDelta::SetServicePath(conn2, node4, 22, 0)));
         assert_eq!(dest.pop_delta(), None);
 
-        assert_eq!(dest.next(&[]), Some((conn1, node1)));
+        assert_eq!(dest.next(&[], ServicePlacement::Any), Some((conn1, node1)));
         assert_eq!(dest.next_path(&[node1]), Some(Path(conn2, Metric::new(2, vec![4, 2], BANDWIDTH_LIMIT))));
         assert_eq!(dest.next_path(&[node2]), Some(Path(conn1, Metric::new(1, vec![4, 1], BANDWIDTH_LIMIT))));
         assert_eq!(dest.next_path(&[node3]), Some(Path(conn1, Metric::new(1, vec![4, 1], BANDWIDTH_LIMIT))));
-        assert_eq!(dest.next(&[node1, node2]), None);
+        assert_eq!(dest.next(&[node1, node2], ServicePlacement::Any), None);
         assert_eq!(dest.next_path(&[node1, node2]), None);
     }
 
@@ -163,17 +178,17 @@ mod tests {
 
         let mut dest = RegistryDest::default();
         dest.set_path(conn1, Metric::new(1, vec![4, 1], BANDWIDTH_LIMIT));
-        assert_eq!(dest.pop_delta(), Some(RegistryDestDelta::SetServicePath(conn1, node4, 21)));
+        assert_eq!(dest.pop_delta(), Some(RegistryDestDelta::SetServicePath(conn1, node4, 21, 0)));
         dest.set_path(conn2, Metric::new(2, vec![4, 6, 2], BANDWIDTH_LIMIT));
-        assert_eq!(dest.pop_delta(), Some(RegistryDestDelta::SetServicePath(conn2, node4, 32)));
+        assert_eq!(dest.pop_delta(), Some(RegistryDestDelta::SetServicePath(conn2, node4, 32, 0)));
         dest.set_path(conn3, Metric::new(3, vec![4, 6, 2, 3], BANDWIDTH_LIMIT));
-        assert_eq!(dest.pop_delta(), Some(RegistryDestDelta::SetServicePath(conn3, node4, 43)));
+        assert_eq!(dest.pop_delta(), Some(RegistryDestDelta::SetServicePath(conn3, node4, 43, 0)));
         assert_eq!(dest.pop_delta(), None);
 
         dest.del_path(conn1);
         assert_eq!(dest.pop_delta(), Some(RegistryDestDelta::DelServicePath(conn1)));
 
-        assert_eq!(dest.next(&[]), Some((conn2, node2)));
+        assert_eq!(dest.next(&[], ServicePlacement::Any), Some((conn2, node2)));
         assert_eq!(dest.next_path(&[node1]), Some(Path(conn2, Metric::new(2, vec![4, 6, 2], BANDWIDTH_LIMIT))));
         assert_eq!(dest.next_path(&[node2]), Some(Path(conn3, Metric::new(3, vec![4, 6, 2, 3], BANDWIDTH_LIMIT))));
         assert_eq!(dest.next_path(&[node3]), Some(Path(conn2, Metric::new(2, vec![4, 6, 2], BANDWIDTH_LIMIT))));
@@ -197,8 +212,32 @@ mod tests {
         //this path from 3 => 2 => 1
         dest.set_path(conn1, Metric::new(1, vec![3, 2, 1], BANDWIDTH_LIMIT));
 
-        assert_eq!(dest.best_for(node4), Some(Path(conn1, Metric::new(1, vec![3, 2, 1], BANDWIDTH_LIMIT))));
-        assert_eq!(dest.best_for(node1), None);
-        assert_eq!(dest.best_for(node2), None);
+        assert_eq!(dest.best_for(node4, ServicePlacement::Any), Some(Path(conn1, Metric::new(1, vec![3, 2, 1], BANDWIDTH_LIMIT))));
+        assert_eq!(dest.best_for(node1, ServicePlacement::Any), None);
+        assert_eq!(dest.best_for(node2, ServicePlacement::Any), None);
+    }
+
+    #[test]
+    fn with_placement() {
+        let conn1: ConnId = ConnId::from_out(0, 0x1);
+        let node1: NodeId = 0x1;
+
+        let conn2: ConnId = ConnId::from_out(0, 0x2);
+        let node2: NodeId = 0x2;
+
+        let dest_geo1: NodeId = 0x01000004;
+        let dest_geo2: NodeId = 0x02000004;
+
+        let mut dest = RegistryDest::default();
+        dest.set_path(conn1, Metric::new(1, vec![dest_geo1, node1], BANDWIDTH_LIMIT));
+        dest.set_path(conn2, Metric::new(5, vec![dest_geo2, node2], BANDWIDTH_LIMIT));
+
+        assert_eq!(dest.next(&[], ServicePlacement::Any), Some((conn1, node1)));
+        assert_eq!(dest.next(&[], ServicePlacement::Geo1(2)), Some((conn2, node2)));
+        assert_eq!(dest.next(&[], ServicePlacement::Geo1(3)), None);
+        assert_eq!(
+            dest.best_for(0x5, ServicePlacement::Geo1(2)),
+            Some(Path(conn2, Metric::new(5, vec![dest_geo2, node2], BANDWIDTH_LIMIT)))
+        );
     }
 }
@@ -1,36 +1,67 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::hash::{DefaultHasher, Hasher};
+
 use atm0s_sdn_identity::{ConnId, NodeId, NodeIdType};
 use serde::{Deserialize, Serialize};
 
-use crate::core::{Metric, Path};
+use crate::core::{Metric, Path, RoutingPolicy};
 use crate::core::{Registry, RegistrySync};
 
 use super::registry::{RegisterDump, RegistryDelta};
 use super::table::{NodeIndex, Table, TableDelta, TableDump, TableSync};
 use super::ServiceDestination;
+use crate::ServicePlacement;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum RouterDelta {
     Table(u8, TableDelta),
     Registry(RegistryDelta),
+    /// Node is marked or unmarked as an observer, which must not be selected as key destination
+    Observer(NodeId, bool),
 }
 
 /// Which layer in node id space, in this case is 0 -> 3
 pub type Layer = u8;
 
+/// Registry sync, tables sync, load of sender node as a relay, observer nodes which are known by sender and advertised bandwidth of sender in kbps.
+/// Relay load is not serialized with the sync, it is only sent to neighbours which support it
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
-pub struct RouterSync(pub RegistrySync, pub [Option<TableSync>; 4]);
+pub struct RouterSync(pub RegistrySync, pub [Option<TableSync>; 4], #[serde(skip)] pub u8, pub Vec<NodeId>, pub u32);
 
-#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct RouterDump {
     node_id: NodeId,
     services: RegisterDump,
     layers: [TableDump; 4],
 }
 
+impl RouterDump {
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    pub fn services(&self) -> &RegisterDump {
+        &self.services
+    }
+
+    pub fn layers(&self) -> &[TableDump; 4] {
+        &self.layers
+    }
+}
+
 pub struct Router {
     node_id: NodeId,
     tables: [Table; 4],
     service_registry: Registry,
+    relay_load: u8,
+    observer: bool,
+    /// Observers which are reported by each neighbour
+    remote_observers: HashMap<ConnId, Vec<NodeId>>,
+    observers: BTreeSet<NodeId>,
+    policy: RoutingPolicy,
+    /// Bandwidth which is advertised by each neighbour
+    remote_bandwidth: HashMap<ConnId, u32>,
+    deltas: VecDeque<RouterDelta>,
 }
 
 impl Router {
@@ -41,6 +72,13 @@ impl Router {
             node_id: local_node_id,
             tables,
             service_registry: Registry::new(local_node_id),
+            relay_load: 0,
+            observer: false,
+            remote_observers: HashMap::new(),
+            observers: BTreeSet::new(),
+            policy: RoutingPolicy::default(),
+            remote_bandwidth: HashMap::new(),
+            deltas: VecDeque::new(),
         }
     }
 
@@ -64,15 +102,89 @@ impl Router {
         size
     }
 
+    /// Hash of the best path to each destination, used for checking if two snapshots of the table are same
+    pub fn table_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for table in &self.tables {
+            table.hash_best_paths(&mut hasher);
+        }
+        hasher.finish()
+    }
+
     pub fn register_service(&mut self, service_id: u8) {
         self.service_registry.add_service(service_id);
     }
 
+    pub fn set_service_placement(&mut self, service_id: u8, placement: ServicePlacement) {
+        self.service_registry.set_placement(service_id, placement);
+    }
+
+    pub fn set_service_load(&mut self, service_id: u8, load: u8) {
+        self.service_registry.set_load(service_id, load);
+    }
+
+    /// Set load of this node as a relay, which is sent to neighbours for pubsub relay selection
+    pub fn set_relay_load(&mut self, load: u8) {
+        self.relay_load = load;
+    }
+
+    /// Observer only advertises its local services, so it is never used as a next hop, and it is never selected as key destination.
+    /// Key lookups from an observer are always forwarded to other nodes
+    pub fn set_observer(&mut self, observer: bool) {
+        if self.observer != observer {
+            self.observer = observer;
+            self.deltas.push_back(RouterDelta::Observer(self.node_id, observer));
+        }
+    }
+
+    /// Set weights of path score, it is applied to direct paths which are set after, and paths which are synced over them.
+    /// Advertised bandwidth is sent to neighbours with next syncs
+    pub fn set_policy(&mut self, policy: RoutingPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn policy(&self) -> RoutingPolicy {
+        self.policy
+    }
+
+    /// Direct metric with local policy, bandwidth is limited by advertised bandwidth of both sides
+    fn local_metric(&self, over: ConnId, mut metric: Metric) -> Metric {
+        let remote_bandwidth = self.remote_bandwidth.get(&over).copied().unwrap_or(u32::MAX);
+        metric.bandwidth = metric.bandwidth.min(self.policy.advertised_bandwidth).min(remote_bandwidth);
+        metric.with_policy(self.policy)
+    }
+
+    pub fn is_observer(&self) -> bool {
+        self.observer
+    }
+
+    pub fn is_observer_node(&self, node: NodeId) -> bool {
+        if node == self.node_id {
+            self.observer
+        } else {
+            self.observers.contains(&node)
+        }
+    }
+
+    fn update_observers(&mut self) {
+        let observers = self.remote_observers.values().flatten().filter(|n| **n != self.node_id).copied().collect::<BTreeSet<_>>();
+        for node in self.observers.difference(&observers) {
+            log::info!("[Router {}] node {} is not an observer anymore", self.node_id, node);
+            self.deltas.push_back(RouterDelta::Observer(*node, false));
+        }
+        for node in observers.difference(&self.observers) {
+            log::info!("[Router {}] node {} is an observer", self.node_id, node);
+            self.deltas.push_back(RouterDelta::Observer(*node, true));
+        }
+        self.observers = observers;
+    }
+
     pub fn service_next(&self, service_id: u8, excepts: &[NodeId]) -> Option<ServiceDestination> {
         self.service_registry.next(service_id, excepts)
     }
 
     pub fn set_direct(&mut self, over: ConnId, metric: Metric) {
+        let metric = self.local_metric(over, metric);
         let over_node = metric.over_node();
         let eq_util_layer = self.node_id.eq_util_layer(&over_node) as usize;
         log::debug!("[Router {}] set_direct {}/{} with metric {:?}, eq_util_layer {}", self.node_id, over, over_node, metric, eq_util_layer);
@@ -89,6 +201,10 @@ impl Router {
             table.del_direct(over);
         }
         self.service_registry.del_direct(over);
+        self.remote_bandwidth.remove(&over);
+        if self.remote_observers.remove(&over).is_some() {
+            self.update_observers();
+        }
     }
 
     pub fn next(&self, dest: NodeId, excepts: &[NodeId]) -> Option<(ConnId, NodeId)> {
@@ -111,14 +227,32 @@ impl Router {
         }
     }
 
+    /// All paths to the destination over different neighbours, sorted from the best one
+    pub fn paths(&self, dest: NodeId) -> &[Path] {
+        let eq_util_layer = self.node_id.eq_util_layer(&dest) as usize;
+        debug_assert!(eq_util_layer <= 4);
+        match eq_util_layer {
+            0 => &[],
+            layer => self.tables.get(layer - 1).map(|table| table.paths(dest)).unwrap_or(&[]),
+        }
+    }
+
     pub fn closest_node(&self, key: NodeId, excepts: &[NodeId]) -> Option<(ConnId, NodeId, Layer, NodeIndex)> {
+        // observers in same zone are skipped, each of them is a single slot in layer 0
+        let observer_slots = self.observers.iter().filter(|n| self.node_id.eq_util_layer(n) == 1).map(|n| n.layer(0)).collect::<Vec<_>>();
         for i in [3, 2, 1, 0] {
             let index = key.layer(i);
-            if let Some((next_index, next_conn, next_node)) = self.tables[i as usize].closest_for(index, excepts) {
+            let skips = if i == 0 {
+                observer_slots.as_slice()
+            } else {
+                &[]
+            };
+            if let Some((next_index, next_conn, next_node)) = self.tables[i as usize].closest_for(index, excepts, skips) {
                 let next_distance = next_index ^ index;
                 let current_index = self.node_id.layer(i);
                 let current_distance = index ^ current_index;
-                if current_distance > next_distance {
+                // observer can not be a key destination, then it always forwards to closest node in its zone
+                if current_distance > next_distance || (i == 0 && self.observer) {
                     return Some((next_conn, next_node, i, next_index));
                 }
             } else {
@@ -131,7 +265,12 @@ impl Router {
     }
 
     pub fn create_sync(&self, for_node: NodeId) -> RouterSync {
-        RouterSync(
+        // only reachable observers are forwarded, so a left observer is forgotten when its paths are expired
+        let mut observers = self.observers.iter().filter(|n| **n != for_node && self.next(**n, &[]).is_some()).copied().collect::<Vec<_>>();
+        if self.observer {
+            observers.push(self.node_id);
+        }
+        let mut sync = RouterSync(
             self.service_registry.sync_for(for_node),
             [
                 self.tables[0].sync_for(for_node),
@@ -139,10 +278,27 @@ impl Router {
                 self.tables[2].sync_for(for_node),
                 self.tables[3].sync_for(for_node),
             ],
-        )
+            self.relay_load,
+            observers,
+            self.policy.advertised_bandwidth,
+        );
+        if self.observer {
+            sync.0 .0.retain(|(_, metric)| metric.hops.is_empty());
+            sync.1 = sync.1.map(|table| table.map(|_| TableSync(vec![])));
+        }
+        sync
     }
 
     pub fn apply_sync(&mut self, conn: ConnId, metric: Metric, sync: RouterSync) {
+        if self.remote_bandwidth.insert(conn, sync.4) != Some(sync.4) {
+            // direct path is limited by the new advertised bandwidth
+            self.set_direct(conn, metric.clone());
+        }
+        let metric = self.local_metric(conn, metric).with_relay_load(sync.2);
+        if self.remote_observers.get(&conn) != Some(&sync.3) {
+            self.remote_observers.insert(conn, sync.3);
+            self.update_observers();
+        }
         self.service_registry.apply_sync(conn, metric.clone(), sync.0);
         for (index, table_sync) in sync.1.into_iter().enumerate() {
             if let Some(table_sync) = table_sync {
@@ -152,6 +308,9 @@ impl Router {
     }
 
     pub fn pop_delta(&mut self) -> Option<RouterDelta> {
+        if let Some(delta) = self.deltas.pop_front() {
+            return Some(delta);
+        }
         if let Some(delta) = self.service_registry.pop_delta() {
             return Some(RouterDelta::Registry(delta));
         }
@@ -189,7 +348,7 @@ mod tests {
     use atm0s_sdn_identity::{ConnId, NodeId, NodeIdType};
 
     use crate::core::registry::REGISTRY_LOCAL_BW;
-    use crate::core::{table::TableSync, Metric, Path, Router, RouterSync};
+    use crate::core::{table::TableSync, Metric, Path, Router, RouterDelta, RouterSync, RoutingPolicy, UNLIMITED_BANDWIDTH};
     use crate::core::{RegistrySync, ServiceDestination};
 
     #[test]
@@ -223,6 +382,22 @@ mod tests {
         assert_eq!(router.next(z_node2, &[]), Some((z_node1_conn, z_node1)));
     }
 
+    #[test]
+    fn table_hash_changes_with_best_paths() {
+        let mut router = Router::new(0);
+        let empty = router.table_hash();
+        router.set_direct(ConnId::from_out(0, 1), Metric::new(1, vec![1], 1));
+        let direct = router.table_hash();
+        assert_ne!(direct, empty);
+
+        // latency doesn't affect the hash
+        router.set_direct(ConnId::from_out(0, 1), Metric::new(20, vec![1], 1));
+        assert_eq!(router.table_hash(), direct);
+
+        router.del_direct(ConnId::from_out(0, 1));
+        assert_eq!(router.table_hash(), empty);
+    }
+
     fn create_router(node_id: NodeId) -> (NodeId, ConnId, Router) {
         (node_id, ConnId::from_out(0, node_id as u64), Router::new(node_id))
     }
@@ -237,11 +412,84 @@ mod tests {
         router2.apply_sync(
             ConnId::from_in(0, 0),
             Metric::new(0, vec![1], 0),
-            RouterSync(RegistrySync(vec![]), [Some(TableSync(vec![(3, Metric::new(0, vec![3], 0))])), None, None, None]),
+            RouterSync(
+                RegistrySync(vec![]),
+                [Some(TableSync(vec![(3, Metric::new(0, vec![3], 0))])), None, None, None],
+                0,
+                vec![],
+                UNLIMITED_BANDWIDTH,
+            ),
         );
         assert_eq!(router2.tables[0].slots(), vec![1, 3]);
     }
 
+    #[test]
+    fn relay_load_is_kept_in_paths() {
+        // 1 - 2 - 4
+        // 1 - 3 - 4
+        let (_node1, _conn1, mut router1) = create_router(1);
+        let conn2 = ConnId::from_out(0, 2);
+        let conn3 = ConnId::from_out(0, 3);
+        router1.set_direct(conn2, Metric::new(1, vec![2], 10000));
+        router1.set_direct(conn3, Metric::new(2, vec![3], 10000));
+
+        let sync = |load| {
+            RouterSync(
+                RegistrySync(vec![]),
+                [Some(TableSync(vec![(4, Metric::new(1, vec![4], 10000))])), None, None, None],
+                load,
+                vec![],
+                UNLIMITED_BANDWIDTH,
+            )
+        };
+        router1.apply_sync(conn2, Metric::new(1, vec![2], 10000), sync(200));
+        router1.apply_sync(conn3, Metric::new(2, vec![3], 10000), sync(10));
+        // best path is still selected by score, relay load is only reported for pubsub
+        assert_eq!(router1.next(4, &[]), Some((conn2, 2)));
+        let loads = router1.paths(4).iter().map(|path| (path.0, path.1.relay_load)).collect::<Vec<_>>();
+        assert_eq!(loads, vec![(conn2, 200), (conn3, 10)]);
+    }
+
+    #[test]
+    fn route_with_policy_and_advertised_bandwidth() {
+        // 1 - 2 - 4 over fast but narrow links
+        // 1 - 3 - 4 over slow links
+        let (_node1, _conn1, mut router1) = create_router(1);
+        let conn2 = ConnId::from_out(0, 2);
+        let conn3 = ConnId::from_out(0, 3);
+        let sync = |bandwidth| {
+            RouterSync(
+                RegistrySync(vec![]),
+                [Some(TableSync(vec![(4, Metric::new(1, vec![4], UNLIMITED_BANDWIDTH))])), None, None, None],
+                0,
+                vec![],
+                bandwidth,
+            )
+        };
+        router1.set_direct(conn2, Metric::new(1, vec![2], UNLIMITED_BANDWIDTH));
+        router1.set_direct(conn3, Metric::new(30, vec![3], UNLIMITED_BANDWIDTH));
+        router1.apply_sync(conn2, Metric::new(1, vec![2], UNLIMITED_BANDWIDTH), sync(UNLIMITED_BANDWIDTH));
+        router1.apply_sync(conn3, Metric::new(30, vec![3], UNLIMITED_BANDWIDTH), sync(UNLIMITED_BANDWIDTH));
+        assert_eq!(router1.next(4, &[]), Some((conn2, 2)));
+
+        // node 2 advertises 1Mbps, which is penalized with default policy
+        router1.apply_sync(conn2, Metric::new(1, vec![2], UNLIMITED_BANDWIDTH), sync(1000));
+        assert_eq!(router1.next(4, &[]), Some((conn3, 3)));
+        assert_eq!(router1.next_path(2, &[]).map(|path| path.1.bandwidth), Some(1000));
+
+        // policy which only cares about latency
+        router1.set_policy(RoutingPolicy {
+            bandwidth_weight: 0,
+            ..Default::default()
+        });
+        router1.set_direct(conn2, Metric::new(1, vec![2], UNLIMITED_BANDWIDTH));
+        router1.set_direct(conn3, Metric::new(30, vec![3], UNLIMITED_BANDWIDTH));
+        router1.apply_sync(conn2, Metric::new(1, vec![2], UNLIMITED_BANDWIDTH), sync(1000));
+        router1.apply_sync(conn3, Metric::new(30, vec![3], UNLIMITED_BANDWIDTH), sync(UNLIMITED_BANDWIDTH));
+        assert_eq!(router1.next(4, &[]), Some((conn2, 2)));
+        assert_eq!(router1.create_sync(2).4, UNLIMITED_BANDWIDTH);
+    }
+
     #[test]
     fn complex_sync_same_zone() {
         // A -1- B -1- C -1- F
@@ -288,7 +536,10 @@ mod tests {
                     Some(empty_sync.clone()),
                     Some(empty_sync.clone()),
                     Some(empty_sync.clone())
-                ]
+                ],
+                0,
+                vec![],
+                UNLIMITED_BANDWIDTH
             )
         );
 
@@ -454,6 +705,38 @@ mod tests {
         assert_eq!(router_a.closest_node(NodeId::build(2, 6, 0, 4), &[]), None);
     }
 
+    #[test]
+    fn observer_is_not_key_destination() {
+        let (node_a, _conn_a, mut router_a) = create_router(NodeId::build(0, 0, 0, 1));
+        let (node_o, conn_o, mut router_o) = create_router(NodeId::build(0, 0, 0, 2));
+        let (_node_n, conn_n, _router_n) = create_router(NodeId::build(0, 0, 0, 3));
+        router_o.set_observer(true);
+        assert_eq!(router_o.pop_delta(), Some(RouterDelta::Observer(node_o, true)));
+
+        router_a.set_direct(conn_o, Metric::new(1, vec![node_o], 1));
+        router_a.set_direct(conn_n, Metric::new(1, vec![3], 1));
+        router_o.set_direct(_conn_a, Metric::new(1, vec![node_a], 1));
+        router_o.set_direct(conn_n, Metric::new(1, vec![3], 1));
+
+        // observer doesn't advertise routes over it
+        let sync = router_o.create_sync(node_a);
+        assert_eq!(sync.1[0], Some(TableSync(vec![])));
+        assert_eq!(sync.3, vec![node_o]);
+
+        assert_eq!(router_a.closest_node(NodeId::build(0, 0, 0, 2), &[]), Some((conn_o, node_o, 0, 2)));
+        router_a.apply_sync(conn_o, Metric::new(1, vec![node_o], 1), sync);
+        assert!(router_a.is_observer_node(node_o));
+        assert_eq!(router_a.closest_node(NodeId::build(0, 0, 0, 2), &[]), Some((conn_n, 3, 0, 3)));
+        // direct path to observer is still kept
+        assert_eq!(router_a.next(node_o, &[]), Some((conn_o, node_o)));
+
+        // observer forwards key lookups even if it is the closest node
+        assert_eq!(router_o.closest_node(NodeId::build(0, 0, 0, 2), &[]), Some((conn_n, 3, 0, 3)));
+
+        router_a.del_direct(conn_o);
+        assert!(!router_a.is_observer_node(node_o));
+    }
+
     #[test]
     fn random_test_closest() {
         //TODO
@@ -1,10 +1,11 @@
 use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
 
 use atm0s_sdn_identity::{ConnId, NodeId, NodeIdType};
 use serde::{Deserialize, Serialize};
 
 pub use dest::{Dest, DestDelta, DestDump};
-pub use metric::{Metric, BANDWIDTH_LIMIT};
+pub use metric::{Metric, RoutingPolicy, BANDWIDTH_LIMIT, UNLIMITED_BANDWIDTH};
 pub use path::Path;
 
 mod dest;
@@ -20,12 +21,23 @@ pub struct TableDelta(pub u8, pub DestDelta);
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct TableSync(pub Vec<(u8, Metric)>);
 
-#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct TableDump {
     layer: u8,
     dests: HashMap<u8, DestDump>,
 }
 
+impl TableDump {
+    pub fn layer(&self) -> u8 {
+        self.layer
+    }
+
+    /// Destinations by index in this layer, only non-empty ones
+    pub fn dests(&self) -> &HashMap<u8, DestDump> {
+        &self.dests
+    }
+}
+
 pub struct Table {
     node_id: NodeId,
     layer: u8,
@@ -67,6 +79,16 @@ impl Table {
         size
     }
 
+    /// Feed the hops of best path of each destination into the hasher, latency is skipped because it changes with each ping
+    pub fn hash_best_paths<H: Hasher>(&self, state: &mut H) {
+        for (index, dest) in self.dests.iter().enumerate() {
+            if let Some(path) = dest.next_path(&[]) {
+                (self.layer, index as u8).hash(state);
+                path.1.hops.hash(state);
+            }
+        }
+    }
+
     pub fn add_direct(&mut self, conn: ConnId, metric: Metric) {
         let index = metric.over_node().layer(self.layer);
         if self.dests[index as usize].is_empty() {
@@ -104,9 +126,15 @@ impl Table {
         self.dests[index as usize].next_path(excepts)
     }
 
-    pub fn closest_for(&self, key: u8, excepts: &[NodeId]) -> Option<(NodeIndex, ConnId, NodeId)> {
+    pub fn paths(&self, dest: NodeId) -> &[Path] {
+        let index = dest.layer(self.layer);
+        self.dests[index as usize].paths()
+    }
+
+    /// Find closest slot for the key, slots in skips are ignored
+    pub fn closest_for(&self, key: u8, excepts: &[NodeId], skips: &[NodeIndex]) -> Option<(NodeIndex, ConnId, NodeId)> {
         let mut closest_distance: Option<(u8, ConnId, u32, u8)> = None;
-        for slot in &self.slots {
+        for slot in self.slots.iter().filter(|s| !skips.contains(s)) {
             let distance = *slot ^ key;
             if closest_distance.is_none() || distance < closest_distance.expect("").3 {
                 if let Some((conn, node)) = self.dests[*slot as usize].next(excepts) {
@@ -361,8 +389,8 @@ mod tests {
         let node0: NodeId = 0x0;
         let mut table = Table::new(node0, 0);
 
-        assert_eq!(table.closest_for(0, &[]), None);
-        assert_eq!(table.closest_for(100, &[]), None);
+        assert_eq!(table.closest_for(0, &[], &[]), None);
+        assert_eq!(table.closest_for(100, &[], &[]), None);
 
         let conn1 = ConnId::from_out(0, 1);
         let conn5 = ConnId::from_out(0, 5);
@@ -372,15 +400,16 @@ mod tests {
         table.add_direct(conn5, Metric::new(1, vec![5], 1));
         table.add_direct(conn40, Metric::new(1, vec![40], 1));
 
-        assert_eq!(table.closest_for(0, &[]), Some((1, conn1, 1)));
-        assert_eq!(table.closest_for(3, &[]), Some((1, conn1, 1)));
-        assert_eq!(table.closest_for(3, &[1]), Some((5, conn5, 5)));
+        assert_eq!(table.closest_for(0, &[], &[]), Some((1, conn1, 1)));
+        assert_eq!(table.closest_for(3, &[], &[]), Some((1, conn1, 1)));
+        assert_eq!(table.closest_for(3, &[1], &[]), Some((5, conn5, 5)));
+        assert_eq!(table.closest_for(3, &[], &[1]), Some((5, conn5, 5)));
 
-        assert_eq!(table.closest_for(4, &[]), Some((5, conn5, 5)));
-        assert_eq!(table.closest_for(20, &[]), Some((5, conn5, 5)));
-        assert_eq!(table.closest_for(40, &[]), Some((40, conn40, 40)));
-        assert_eq!(table.closest_for(41, &[]), Some((40, conn40, 40)));
+        assert_eq!(table.closest_for(4, &[], &[]), Some((5, conn5, 5)));
+        assert_eq!(table.closest_for(20, &[], &[]), Some((5, conn5, 5)));
+        assert_eq!(table.closest_for(40, &[], &[]), Some((40, conn40, 40)));
+        assert_eq!(table.closest_for(41, &[], &[]), Some((40, conn40, 40)));
 
-        assert_eq!(table.closest_for(254, &[]), Some((40, conn40, 40)));
+        assert_eq!(table.closest_for(254, &[], &[]), Some((40, conn40, 40)));
     }
 }
@@ -1,7 +1,7 @@
 use std::collections::{HashMap, VecDeque};
 
 use atm0s_sdn_identity::{ConnId, NodeId};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use super::{Metric, Path};
 
@@ -11,9 +11,16 @@ pub enum DestDelta {
     DelBestPath,
 }
 
-#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct DestDump(HashMap<NodeId, Metric>);
 
+impl DestDump {
+    /// Paths by over node
+    pub fn paths(&self) -> &HashMap<NodeId, Metric> {
+        &self.0
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Dest {
     paths: Vec<Path>,
@@ -92,6 +99,11 @@ impl Dest {
         None
     }
 
+    /// All paths, sorted from the best one
+    pub fn paths(&self) -> &[Path] {
+        &self.paths
+    }
+
     pub fn next_path(&self, excepts: &[NodeId]) -> Option<Path> {
         for path in self.paths.iter() {
             if !excepts.contains(&path.1.over_node()) {
@@ -6,6 +6,35 @@ use serde::{Deserialize, Serialize};
 pub const BANDWIDTH_LIMIT: u32 = 10000; //10Mbps
 const BANDWIDTH_SCORE_PENALTY: u32 = 1000; //1s
 const HOP_PLUS_RTT: u16 = 10; //10ms each hops
+pub const UNLIMITED_BANDWIDTH: u32 = 100_000_000; //100Gbps, advertised by nodes which don't limit their bandwidth
+
+/// Weights of path score, the path with the lowest score is preferred.
+/// Default weights make each hop as costly as 10ms of latency and add 1s to paths under 10Mbps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RoutingPolicy {
+    /// Score of each millisecond of latency
+    pub latency_weight: u32,
+    /// Score of each hop
+    pub hop_weight: u32,
+    /// Score which is added to paths with bandwidth under `min_bandwidth`
+    pub bandwidth_weight: u32,
+    /// In kbps
+    pub min_bandwidth: u32,
+    /// Bandwidth of this node in kbps which is advertised to neighbours, each link is limited by the lower side
+    pub advertised_bandwidth: u32,
+}
+
+impl Default for RoutingPolicy {
+    fn default() -> Self {
+        Self {
+            latency_weight: 1,
+            hop_weight: HOP_PLUS_RTT as u32,
+            bandwidth_weight: BANDWIDTH_SCORE_PENALTY,
+            min_bandwidth: BANDWIDTH_LIMIT,
+            advertised_bandwidth: UNLIMITED_BANDWIDTH,
+        }
+    }
+}
 
 /// Concatenate two hops array, with condition that the last hop of `a` is the first hop of `b`, if not return None
 pub fn concat_hops(a: &[NodeId], b: &[NodeId]) -> Vec<NodeId> {
@@ -23,13 +52,43 @@ pub struct Metric {
     pub latency: u16,      //in milliseconds
     pub hops: Vec<NodeId>, //in hops, from 1 (direct)
     pub bandwidth: u32,    //in kbps
+    #[serde(skip)]
+    pub load: u8, //load of destination service instance, only used in service registry and synced by RegistrySync
+    #[serde(skip)]
+    pub relay_load: u8, //load of next hop node as a relay, synced by RouterSync and only used by pubsub for selecting relays
+    #[serde(skip)]
+    pub policy: RoutingPolicy, //weights of local router, synced metrics take it from the direct metric which they are added to
                            // pub lost: f32,
                            // pub jitter: u16,
 }
 
 impl Metric {
     pub fn new(latency: u16, hops: Vec<NodeId>, bandwidth: u32) -> Self {
-        Metric { latency, hops, bandwidth }
+        Metric {
+            latency,
+            hops,
+            bandwidth,
+            load: 0,
+            relay_load: 0,
+            policy: RoutingPolicy::default(),
+        }
+    }
+
+    /// Set load of destination, which is reported by service instance with 0 is idle and 255 is fully loaded
+    pub fn with_load(mut self, load: u8) -> Self {
+        self.load = load;
+        self
+    }
+
+    /// Set load of next hop node as a relay, with 0 is idle and 255 is fully loaded
+    pub fn with_relay_load(mut self, relay_load: u8) -> Self {
+        self.relay_load = relay_load;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: RoutingPolicy) -> Self {
+        self.policy = policy;
+        self
     }
 
     pub fn contain_in_hops(&self, node_id: NodeId) -> bool {
@@ -41,15 +100,21 @@ impl Metric {
             latency: self.latency + other.latency,
             hops: concat_hops(&self.hops, &other.hops),
             bandwidth: std::cmp::min(self.bandwidth, other.bandwidth),
+            load: self.load,
+            relay_load: other.relay_load,
+            policy: other.policy,
         }
     }
 
     pub fn score(&self) -> u32 {
-        let based_score = self.latency as u32 + (self.hops.len() as u32 * HOP_PLUS_RTT as u32);
-        if self.bandwidth >= BANDWIDTH_LIMIT {
+        let policy = &self.policy;
+        let based_score = (self.latency as u32)
+            .saturating_mul(policy.latency_weight)
+            .saturating_add((self.hops.len() as u32).saturating_mul(policy.hop_weight));
+        if self.bandwidth >= policy.min_bandwidth {
             based_score
         } else {
-            based_score + BANDWIDTH_SCORE_PENALTY
+            based_score.saturating_add(policy.bandwidth_weight)
         }
     }
 
@@ -84,7 +149,7 @@ impl PartialEq<Self> for Metric {
 
 #[cfg(test)]
 mod tests {
-    use super::Metric;
+    use super::{Metric, RoutingPolicy};
 
     #[test]
     fn eq() {
@@ -123,6 +188,47 @@ mod tests {
         assert!(m2 == m4);
     }
 
+    #[test]
+    fn relay_load_not_compared() {
+        let m1 = Metric::new(1, vec![1], 10000).with_relay_load(100);
+        let m2 = Metric::new(3, vec![2], 10000);
+
+        // relay load is only used by pubsub, the table is ordered by score
+        assert!(m1 < m2);
+        assert_eq!(m1, Metric::new(1, vec![1], 10000));
+    }
+
+    #[test]
+    fn compare_with_policy() {
+        // fast path over 3 hops vs slow direct path
+        let fast = Metric::new(10, vec![1, 2, 3], 10000);
+        let slow = Metric::new(40, vec![1], 10000);
+        assert!(fast < slow);
+
+        let hops_first = RoutingPolicy {
+            hop_weight: 100,
+            ..Default::default()
+        };
+        assert!(fast.clone().with_policy(hops_first) > slow.clone().with_policy(hops_first));
+
+        // low bandwidth is ignored without weight
+        let narrow = Metric::new(10, vec![1], 1000);
+        assert!(narrow > slow);
+        let latency_only = RoutingPolicy {
+            bandwidth_weight: 0,
+            ..Default::default()
+        };
+        assert!(narrow.with_policy(latency_only) < slow.with_policy(latency_only));
+    }
+
+    #[test]
+    fn add_take_local_policy() {
+        let policy = RoutingPolicy { hop_weight: 0, ..Default::default() };
+        let remote = Metric::new(1, vec![1, 2], 10000);
+        let direct = Metric::new(2, vec![3], 20000).with_policy(policy);
+        assert_eq!(remote.add(&direct).policy, policy);
+    }
+
     #[test]
     fn add() {
         let m1 = Metric::new(1, vec![1, 2], 10000);
@@ -1,6 +1,7 @@
 #![allow(clippy::bool_assert_comparison)]
 
 use atm0s_sdn_identity::{NodeId, NodeIdType};
+use serde::{Deserialize, Serialize};
 pub mod core;
 pub mod shadow;
 
@@ -45,6 +46,28 @@ impl From<u8> for ServiceBroadcastLevel {
     }
 }
 
+/// Restrict which instances of a service are allowed to handle ToService and ToServices traffic,
+/// based on the geo location encoded in their node id.
+/// This is useful for compliance-restricted workloads which must keep traffic inside a region
+/// even if a closer instance exists elsewhere.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ServicePlacement {
+    #[default]
+    Any,
+    Geo1(u8),
+    Geo2(u8, u8),
+}
+
+impl ServicePlacement {
+    pub fn allowed(&self, node: NodeId) -> bool {
+        match self {
+            ServicePlacement::Any => true,
+            ServicePlacement::Geo1(geo1) => node.geo1() == *geo1,
+            ServicePlacement::Geo2(geo1, geo2) => node.geo1() == *geo1 && node.geo2() == *geo2,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum RouteRule {
     Direct,
@@ -53,6 +76,9 @@ pub enum RouteRule {
     /// First is service id, second is the level, and third is seq of message
     ToServices(u8, ServiceBroadcastLevel, u16),
     ToKey(NodeId),
+    /// Same as ToService but prefer instances in the same level as the sender, falling back to the global best instance.
+    /// First is service id, second is the level
+    ToServiceInGroup(u8, ServiceBroadcastLevel),
 }
 
 /// Determine the destination of an action/message
@@ -93,6 +119,13 @@ pub trait RouterTable<Remote> {
     fn path_to_key(&self, key: NodeId) -> RouteAction<Remote>;
     /// Determine the next action for the given service
     fn path_to_service(&self, service_id: u8) -> RouteAction<Remote>;
+    /// Determine the next action for the given service at the node which originates the message.
+    /// The instance is selected with weight by load once here and pinned by the flow of source and service,
+    /// then relay nodes forward it with [`RouterTable::path_to_service`] to avoid bouncing between instances
+    fn path_to_service_from(&self, service_id: u8, source: NodeId) -> RouteAction<Remote>;
+    /// Determine the next action for the given service, prefer instances which are in same level with source.
+    /// If source is not set, the current node is used instead
+    fn path_to_service_in_group(&self, service_id: u8, level: ServiceBroadcastLevel, source: Option<NodeId>) -> RouteAction<Remote>;
     /// Determine the next action if we need broadcast to all node running a service.
     /// If relay_from is set, it should not sending back for avoiding loop
     fn path_to_services(&self, service_id: u8, seq: u16, level: ServiceBroadcastLevel, source: Option<NodeId>, relay_from: Option<NodeId>) -> RouteAction<Remote>;
@@ -103,8 +136,12 @@ pub trait RouterTable<Remote> {
             RouteRule::Direct => RouteAction::Local,
             RouteRule::ToNode(dest) => self.path_to_node(*dest),
             RouteRule::ToKey(key) => self.path_to_key(*key),
-            RouteRule::ToService(service) => self.path_to_service(*service),
+            RouteRule::ToService(service) => match (source, relay_from) {
+                (Some(source), None) => self.path_to_service_from(*service, source),
+                _ => self.path_to_service(*service),
+            },
             RouteRule::ToServices(service, level, seq) => self.path_to_services(*service, *seq, *level, source, relay_from),
+            RouteRule::ToServiceInGroup(service, level) => self.path_to_service_in_group(*service, *level, source),
         }
     }
 }
@@ -114,6 +151,19 @@ mod tests {
     use atm0s_sdn_identity::ConnId;
     type RouteAction = super::RouteAction<ConnId>;
 
+    #[test]
+    fn test_service_placement() {
+        use super::ServicePlacement;
+        use atm0s_sdn_identity::{NodeId, NodeIdType};
+
+        let node = NodeId::build(1, 2, 3, 4);
+        assert!(ServicePlacement::Any.allowed(node));
+        assert!(ServicePlacement::Geo1(1).allowed(node));
+        assert!(!ServicePlacement::Geo1(2).allowed(node));
+        assert!(ServicePlacement::Geo2(1, 2).allowed(node));
+        assert!(!ServicePlacement::Geo2(1, 3).allowed(node));
+    }
+
     #[test]
     fn test_is_local() {
         let local = RouteAction::Local;
@@ -1,8 +1,8 @@
-use std::{fmt::Debug, hash::Hash, sync::Arc};
+use std::{collections::HashMap, fmt::Debug, hash::Hash, sync::Arc};
 
 use atm0s_sdn_identity::{NodeId, NodeIdType};
 
-use crate::{RouteAction, RouterTable, ServiceBroadcastLevel};
+use crate::{RouteAction, RouterTable, ServiceBroadcastLevel, ServicePlacement};
 
 use self::{service::Service, table::ShadowTable};
 
@@ -17,23 +17,80 @@ pub trait ShadowRouterHistory: Send + Sync {
 
     /// For set current time ms
     fn set_ts(&self, now: u64);
+
+    /// Counters of the history, histories which don't track them return zeros
+    fn stats(&self) -> ShadowRouterHistoryStats {
+        ShadowRouterHistoryStats::default()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShadowRouterHistoryStats {
+    /// Messages which are currently remembered
+    pub entries: usize,
+    /// Entries which are removed after the timeout since started
+    pub expired: u64,
+    /// Entries which are removed before the timeout because of the memory cap since started
+    pub evicted: u64,
+    /// Duplicated messages which are accepted again because their entries were evicted, this is an estimate
+    pub false_negatives: u64,
 }
 
 #[derive(Debug, Clone)]
 pub enum ShadowRouterDelta<Remote> {
-    SetTable { layer: u8, index: u8, next: Remote },
-    DelTable { layer: u8, index: u8 },
-    SetServiceRemote { service: u8, conn: Remote, next: NodeId, dest: NodeId, score: u32 },
-    DelServiceRemote { service: u8, conn: Remote },
-    SetServiceLocal { service: u8 },
-    DelServiceLocal { service: u8 },
+    SetTable {
+        layer: u8,
+        index: u8,
+        next: Remote,
+    },
+    DelTable {
+        layer: u8,
+        index: u8,
+    },
+    SetServiceRemote {
+        service: u8,
+        conn: Remote,
+        next: NodeId,
+        dest: NodeId,
+        score: u32,
+        load: u8,
+    },
+    DelServiceRemote {
+        service: u8,
+        conn: Remote,
+    },
+    SetServiceLocal {
+        service: u8,
+    },
+    DelServiceLocal {
+        service: u8,
+    },
+    SetServicePlacement {
+        service: u8,
+        placement: ServicePlacement,
+    },
+    SetObserver {
+        node: NodeId,
+        observer: bool,
+    },
+    /// Messages to the destination node are always sent over the remote, instead of the best path of the table
+    SetPin {
+        dest: NodeId,
+        next: Remote,
+    },
+    DelPin {
+        dest: NodeId,
+    },
 }
 
 pub struct ShadowRouter<Remote: Debug + Hash + Eq + Clone + Copy> {
     node_id: NodeId,
     local_registries: [bool; 256],
     remote_registry: [Service<Remote>; 256],
+    placements: [ServicePlacement; 256],
     tables: [ShadowTable<Remote>; 4],
+    observer: bool,
+    pins: HashMap<NodeId, Remote>,
     cached: Arc<dyn ShadowRouterHistory>,
 }
 
@@ -43,7 +100,10 @@ impl<Remote: Debug + Hash + Eq + Clone + Copy> ShadowRouter<Remote> {
             node_id,
             local_registries: [false; 256],
             remote_registry: std::array::from_fn(|_| Service::new()),
+            placements: [ServicePlacement::Any; 256],
             tables: [ShadowTable::new(0), ShadowTable::new(1), ShadowTable::new(2), ShadowTable::new(3)],
+            observer: false,
+            pins: HashMap::new(),
             cached,
         }
     }
@@ -56,8 +116,15 @@ impl<Remote: Debug + Hash + Eq + Clone + Copy> ShadowRouter<Remote> {
             ShadowRouterDelta::DelTable { layer, index } => {
                 self.tables[layer as usize].del(index);
             }
-            ShadowRouterDelta::SetServiceRemote { service, conn, next, dest, score } => {
-                self.remote_registry[service as usize].set_conn(conn, next, dest, score);
+            ShadowRouterDelta::SetServiceRemote {
+                service,
+                conn,
+                next,
+                dest,
+                score,
+                load,
+            } => {
+                self.remote_registry[service as usize].set_conn(conn, next, dest, score, load);
             }
             ShadowRouterDelta::DelServiceRemote { service, conn } => {
                 self.remote_registry[service as usize].del_conn(conn);
@@ -68,6 +135,23 @@ impl<Remote: Debug + Hash + Eq + Clone + Copy> ShadowRouter<Remote> {
             ShadowRouterDelta::DelServiceLocal { service } => {
                 self.local_registries[service as usize] = false;
             }
+            ShadowRouterDelta::SetServicePlacement { service, placement } => {
+                self.placements[service as usize] = placement;
+            }
+            ShadowRouterDelta::SetObserver { node, observer } => {
+                if node == self.node_id {
+                    self.observer = observer;
+                } else if self.node_id.eq_util_layer(&node) == 1 {
+                    // only observers in same zone are skipped, other zones are still reachable over their other nodes
+                    self.tables[0].set_skip(node.layer(0), observer);
+                }
+            }
+            ShadowRouterDelta::SetPin { dest, next } => {
+                self.pins.insert(dest, next);
+            }
+            ShadowRouterDelta::DelPin { dest } => {
+                self.pins.remove(&dest);
+            }
         }
     }
 }
@@ -89,7 +173,7 @@ impl<Remote: Debug + Hash + Eq + Clone + Copy> RouterTable<Remote> for ShadowRou
             if let Some((remote, _next_index, next_distance)) = self.tables[i as usize].closest_for(key_index) {
                 let current_index = self.node_id.layer(i);
                 let current_distance = key_index ^ current_index;
-                if current_distance > next_distance {
+                if current_distance > next_distance || (i == 0 && self.observer) {
                     return Some(remote);
                 }
             } else {
@@ -111,6 +195,9 @@ impl<Remote: Debug + Hash + Eq + Clone + Copy> RouterTable<Remote> for ShadowRou
         if dest == self.node_id {
             return RouteAction::Local;
         }
+        if let Some(remote) = self.pins.get(&dest) {
+            return RouteAction::Next(*remote);
+        }
         match self.next(dest) {
             Some(remote) => RouteAction::Next(remote),
             None => RouteAction::Reject,
@@ -118,19 +205,55 @@ impl<Remote: Debug + Hash + Eq + Clone + Copy> RouterTable<Remote> for ShadowRou
     }
 
     fn path_to_service(&self, service_id: u8) -> RouteAction<Remote> {
-        if self.local_registries[service_id as usize] {
+        let placement = self.placements[service_id as usize];
+        if self.local_registries[service_id as usize] && placement.allowed(self.node_id) {
+            RouteAction::Local
+        } else {
+            self.remote_registry[service_id as usize].best_conn(placement).map(RouteAction::Next).unwrap_or(RouteAction::Reject)
+        }
+    }
+
+    fn path_to_service_from(&self, service_id: u8, source: NodeId) -> RouteAction<Remote> {
+        let placement = self.placements[service_id as usize];
+        if self.local_registries[service_id as usize] && placement.allowed(self.node_id) {
             RouteAction::Local
         } else {
-            self.remote_registry[service_id as usize].best_conn().map(RouteAction::Next).unwrap_or(RouteAction::Reject)
+            let flow = ((source as u64) << 8) | service_id as u64;
+            self.remote_registry[service_id as usize]
+                .weighted_conn(placement, flow)
+                .map(RouteAction::Next)
+                .unwrap_or(RouteAction::Reject)
+        }
+    }
+
+    fn path_to_service_in_group(&self, service_id: u8, level: ServiceBroadcastLevel, source: Option<NodeId>) -> RouteAction<Remote> {
+        let origin = source.unwrap_or(self.node_id);
+        let placement = self.placements[service_id as usize];
+        let local = self.local_registries[service_id as usize] && placement.allowed(self.node_id);
+        if local && level.same_level(origin, self.node_id) {
+            return RouteAction::Local;
+        }
+        if let Some(remote) = self.remote_registry[service_id as usize].best_conn_in_group(origin, level, placement) {
+            return RouteAction::Next(remote);
         }
+        self.path_to_service(service_id)
     }
 
     fn path_to_services(&self, service_id: u8, seq: u16, level: ServiceBroadcastLevel, source: Option<NodeId>, relay_from: Option<NodeId>) -> RouteAction<Remote> {
         if self.cached.already_received_broadcast(source, service_id, seq) {
             return RouteAction::Reject;
         }
-        let local = self.local_registries[service_id as usize];
-        if let Some(nexts) = self.remote_registry[service_id as usize].broadcast_dests(self.node_id, level, relay_from) {
+        let placement = self.placements[service_id as usize];
+        let local = self.local_registries[service_id as usize] && placement.allowed(self.node_id);
+        if self.observer && relay_from.is_some() {
+            // observer doesn't relay broadcast messages of other nodes
+            return if local {
+                RouteAction::Local
+            } else {
+                RouteAction::Reject
+            };
+        }
+        if let Some(nexts) = self.remote_registry[service_id as usize].broadcast_dests(self.node_id, level, placement, relay_from) {
             RouteAction::Broadcast(local, nexts)
         } else if local {
             RouteAction::Local
@@ -144,10 +267,27 @@ impl<Remote: Debug + Hash + Eq + Clone + Copy> RouterTable<Remote> for ShadowRou
 mod tests {
     use std::sync::Arc;
 
-    use crate::{shadow::MockShadowRouterHistory, RouteAction, RouterTable, ServiceBroadcastLevel};
+    use atm0s_sdn_identity::{NodeId, NodeIdType};
+
+    use crate::{shadow::MockShadowRouterHistory, RouteAction, RouteRule, RouterTable, ServiceBroadcastLevel, ServicePlacement};
 
     use super::{ShadowRouter, ShadowRouterDelta};
 
+    #[test]
+    fn should_route_to_pinned_next() {
+        let history = MockShadowRouterHistory::new();
+        let mut router = ShadowRouter::<u64>::new(0x01, Arc::new(history));
+        router.apply_delta(ShadowRouterDelta::SetTable { layer: 0, index: 3, next: 2 });
+        assert_eq!(router.path_to_node(0x03), RouteAction::Next(2));
+
+        router.apply_delta(ShadowRouterDelta::SetPin { dest: 0x03, next: 5 });
+        assert_eq!(router.path_to_node(0x03), RouteAction::Next(5));
+        assert_eq!(router.path_to_node(0x01), RouteAction::Local);
+
+        router.apply_delta(ShadowRouterDelta::DelPin { dest: 0x03 });
+        assert_eq!(router.path_to_node(0x03), RouteAction::Next(2));
+    }
+
     #[test]
     fn should_route_to_next_service_local() {
         let history = MockShadowRouterHistory::new();
@@ -168,12 +308,53 @@ mod tests {
             next: 2,
             dest: 3,
             score: 4,
+            load: 0,
         });
 
         assert_eq!(router.path_to_service(0), RouteAction::Reject);
         assert_eq!(router.path_to_service(1), RouteAction::Next(2));
     }
 
+    #[test]
+    fn should_route_to_service_in_group() {
+        let history = MockShadowRouterHistory::new();
+        let node = NodeId::build(1, 1, 1, 1);
+        let mut router = ShadowRouter::<u64>::new(node, Arc::new(history));
+        router.apply_delta(ShadowRouterDelta::SetServiceRemote {
+            service: 1,
+            conn: 2,
+            next: 2,
+            dest: NodeId::build(2, 1, 1, 1),
+            score: 1,
+            load: 0,
+        });
+        router.apply_delta(ShadowRouterDelta::SetServiceRemote {
+            service: 1,
+            conn: 3,
+            next: 3,
+            dest: NodeId::build(1, 2, 1, 1),
+            score: 10,
+            load: 0,
+        });
+
+        // global best is in other geo1, but same geo1 is preferred
+        assert_eq!(router.path_to_service(1), RouteAction::Next(2));
+        assert_eq!(router.path_to_service_in_group(1, ServiceBroadcastLevel::Geo1, None), RouteAction::Next(3));
+        // no instance in same geo2 => fallback to global
+        assert_eq!(router.path_to_service_in_group(1, ServiceBroadcastLevel::Geo2, None), RouteAction::Next(2));
+        // level is relative to the source node
+        assert_eq!(router.path_to_service_in_group(1, ServiceBroadcastLevel::Geo1, Some(NodeId::build(2, 5, 5, 5))), RouteAction::Next(2));
+        assert_eq!(router.path_to_service_in_group(0, ServiceBroadcastLevel::Geo1, None), RouteAction::Reject);
+
+        router.apply_delta(ShadowRouterDelta::SetServiceLocal { service: 1 });
+        assert_eq!(router.path_to_service_in_group(1, ServiceBroadcastLevel::Group, None), RouteAction::Local);
+        assert_eq!(router.path_to_service_in_group(1, ServiceBroadcastLevel::Geo1, Some(NodeId::build(2, 5, 5, 5))), RouteAction::Next(2));
+        assert_eq!(
+            router.derive_action(&RouteRule::ToServiceInGroup(1, ServiceBroadcastLevel::Geo1), Some(NodeId::build(1, 3, 3, 3)), None),
+            RouteAction::Local
+        );
+    }
+
     #[test]
     fn should_broadcast_to_next_service_local() {
         let mut history = MockShadowRouterHistory::new();
@@ -196,6 +377,7 @@ mod tests {
             next: 2,
             dest: 3,
             score: 4,
+            load: 0,
         });
         router.apply_delta(ShadowRouterDelta::SetServiceRemote {
             service: 1,
@@ -203,6 +385,7 @@ mod tests {
             next: 3,
             dest: 6,
             score: 2,
+            load: 0,
         });
         router.apply_delta(ShadowRouterDelta::SetServiceRemote {
             service: 1,
@@ -210,6 +393,7 @@ mod tests {
             next: 4,
             dest: 3,
             score: 1,
+            load: 0,
         });
 
         assert_eq!(router.path_to_services(1, 1, ServiceBroadcastLevel::Global, None, None), RouteAction::Broadcast(false, vec![4, 3]));
@@ -223,10 +407,150 @@ mod tests {
             next: 4,
             dest: 5,
             score: 1,
+            load: 0,
         });
         assert_eq!(router.path_to_services(1, 3, ServiceBroadcastLevel::Global, None, Some(4)), RouteAction::Broadcast(true, vec![3, 2]));
     }
 
+    #[test]
+    fn should_route_with_service_placement() {
+        let mut history = MockShadowRouterHistory::new();
+        history.expect_already_received_broadcast().return_const(false);
+
+        let mut router = ShadowRouter::<u64>::new(0x01000001, Arc::new(history));
+        router.apply_delta(ShadowRouterDelta::SetServiceLocal { service: 1 });
+        router.apply_delta(ShadowRouterDelta::SetServiceRemote {
+            service: 1,
+            conn: 2,
+            next: 0x01000002,
+            dest: 0x01000002,
+            score: 1,
+            load: 0,
+        });
+        router.apply_delta(ShadowRouterDelta::SetServiceRemote {
+            service: 1,
+            conn: 3,
+            next: 0x02000003,
+            dest: 0x02000003,
+            score: 2,
+            load: 0,
+        });
+
+        assert_eq!(router.path_to_service(1), RouteAction::Local);
+        assert_eq!(router.path_to_services(1, 1, ServiceBroadcastLevel::Global, None, None), RouteAction::Broadcast(true, vec![2, 3]));
+
+        router.apply_delta(ShadowRouterDelta::SetServicePlacement {
+            service: 1,
+            placement: ServicePlacement::Geo1(2),
+        });
+        assert_eq!(router.path_to_service(1), RouteAction::Next(3));
+        assert_eq!(router.path_to_services(1, 2, ServiceBroadcastLevel::Global, None, None), RouteAction::Broadcast(false, vec![3]));
+
+        router.apply_delta(ShadowRouterDelta::SetServicePlacement {
+            service: 1,
+            placement: ServicePlacement::Geo2(1, 0),
+        });
+        assert_eq!(router.path_to_service(1), RouteAction::Local);
+        assert_eq!(router.path_to_services(1, 3, ServiceBroadcastLevel::Global, None, None), RouteAction::Broadcast(true, vec![2]));
+    }
+
+    #[test]
+    fn should_route_weighted_by_load() {
+        let history = MockShadowRouterHistory::new();
+        let mut router = ShadowRouter::<u64>::new(1, Arc::new(history));
+        router.apply_delta(ShadowRouterDelta::SetServiceRemote {
+            service: 1,
+            conn: 2,
+            next: 2,
+            dest: 2,
+            score: 10,
+            load: 0,
+        });
+        router.apply_delta(ShadowRouterDelta::SetServiceRemote {
+            service: 1,
+            conn: 3,
+            next: 3,
+            dest: 3,
+            score: 10,
+            load: 0,
+        });
+
+        // without load report, always select best path
+        for source in 10..110 {
+            assert_eq!(router.path_to_service_from(1, source), RouteAction::Next(2));
+        }
+
+        router.apply_delta(ShadowRouterDelta::SetServiceRemote {
+            service: 1,
+            conn: 2,
+            next: 2,
+            dest: 2,
+            score: 10,
+            load: 240,
+        });
+
+        let mut selected = [0; 2];
+        for source in 10..1010 {
+            let action = router.path_to_service_from(1, source);
+            // a flow is pinned to the selected instance
+            assert_eq!(router.path_to_service_from(1, source), action);
+            match action {
+                RouteAction::Next(2) => selected[0] += 1,
+                RouteAction::Next(3) => selected[1] += 1,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        // weights are 16 and 256, so lower loaded instance should receive most of flows but not all
+        assert!(selected[0] > 0);
+        assert!(selected[1] > selected[0] * 5);
+
+        // relayed messages always follow the best path
+        for source in 10..110 {
+            assert_eq!(router.derive_action(&RouteRule::ToService(1), Some(source), Some(5)), RouteAction::Next(2));
+        }
+    }
+
+    #[test]
+    fn should_skip_observer_for_key() {
+        let history = MockShadowRouterHistory::new();
+        let mut router = ShadowRouter::<u64>::new(1, Arc::new(history));
+        router.apply_delta(ShadowRouterDelta::SetTable { layer: 0, index: 2, next: 2 });
+        router.apply_delta(ShadowRouterDelta::SetTable { layer: 0, index: 3, next: 3 });
+
+        assert_eq!(router.path_to_key(2), RouteAction::Next(2));
+        router.apply_delta(ShadowRouterDelta::SetObserver { node: 2, observer: true });
+        assert_eq!(router.path_to_key(2), RouteAction::Next(3));
+        // observer is still reachable as a node
+        assert_eq!(router.path_to_node(2), RouteAction::Next(2));
+        router.apply_delta(ShadowRouterDelta::SetObserver { node: 2, observer: false });
+        assert_eq!(router.path_to_key(2), RouteAction::Next(2));
+
+        // local observer always forwards
+        assert_eq!(router.path_to_key(1), RouteAction::Local);
+        router.apply_delta(ShadowRouterDelta::SetObserver { node: 1, observer: true });
+        assert_eq!(router.path_to_key(1), RouteAction::Next(3));
+    }
+
+    #[test]
+    fn observer_should_not_relay_broadcast() {
+        let mut history = MockShadowRouterHistory::new();
+        history.expect_already_received_broadcast().return_const(false);
+        let mut router = ShadowRouter::<u64>::new(1, Arc::new(history));
+        router.apply_delta(ShadowRouterDelta::SetServiceLocal { service: 1 });
+        router.apply_delta(ShadowRouterDelta::SetServiceRemote {
+            service: 1,
+            conn: 2,
+            next: 2,
+            dest: 3,
+            score: 4,
+            load: 0,
+        });
+        router.apply_delta(ShadowRouterDelta::SetObserver { node: 1, observer: true });
+
+        assert_eq!(router.path_to_services(1, 1, ServiceBroadcastLevel::Global, None, None), RouteAction::Broadcast(true, vec![2]));
+        assert_eq!(router.path_to_services(1, 2, ServiceBroadcastLevel::Global, Some(4), Some(4)), RouteAction::Local);
+    }
+
     #[test]
     fn reject_received_broadcast_message() {
         let mut history = MockShadowRouterHistory::new();
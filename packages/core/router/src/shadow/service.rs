@@ -2,7 +2,7 @@ use std::{collections::HashMap, fmt::Debug, hash::Hash};
 
 use atm0s_sdn_identity::NodeId;
 
-use crate::ServiceBroadcastLevel;
+use crate::{ServiceBroadcastLevel, ServicePlacement};
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct ServiceConn<Remote> {
@@ -10,6 +10,7 @@ pub struct ServiceConn<Remote> {
     pub(crate) next: NodeId,
     pub(crate) dest: NodeId,
     pub(crate) score: u32,
+    pub(crate) load: u8,
 }
 
 impl<Remote: Eq + PartialEq> Ord for ServiceConn<Remote> {
@@ -24,6 +25,9 @@ impl<Remote: Eq + PartialEq> PartialOrd for ServiceConn<Remote> {
     }
 }
 
+/// Score offset which is added before calculating weight, for avoiding too big weight with very close instances
+const WEIGHT_SCORE_OFFSET: u64 = 10;
+
 pub struct Service<Remote> {
     dests: Vec<ServiceConn<Remote>>,
 }
@@ -34,12 +38,12 @@ impl<Remote: Debug + Hash + Copy + Eq + PartialEq> Service<Remote> {
     }
 
     /// Add a new destination to the service, if Remote already exists, it will be replaced
-    pub fn set_conn(&mut self, conn: Remote, next: NodeId, dest: NodeId, score: u32) {
+    pub fn set_conn(&mut self, conn: Remote, next: NodeId, dest: NodeId, score: u32, load: u8) {
         let index = self.dests.iter().position(|x| x.conn == conn);
         if let Some(index) = index {
-            self.dests[index] = ServiceConn { conn, next, dest, score };
+            self.dests[index] = ServiceConn { conn, next, dest, score, load };
         } else {
-            self.dests.push(ServiceConn { conn, next, dest, score });
+            self.dests.push(ServiceConn { conn, next, dest, score, load });
         }
         self.dests.sort();
     }
@@ -49,20 +53,61 @@ impl<Remote: Debug + Hash + Copy + Eq + PartialEq> Service<Remote> {
         self.dests.retain(|x| x.conn != conn);
     }
 
-    pub fn best_conn(&self) -> Option<Remote> {
-        self.dests.first().map(|x| x.conn)
+    /// Get best connection which destination is allowed by placement
+    pub fn best_conn(&self, placement: ServicePlacement) -> Option<Remote> {
+        self.dests.iter().find(|x| placement.allowed(x.dest)).map(|x| x.conn)
+    }
+
+    /// Same as best_conn but only consider destinations which are in same level with the given node
+    pub fn best_conn_in_group(&self, node_id: NodeId, level: ServiceBroadcastLevel, placement: ServicePlacement) -> Option<Remote> {
+        self.dests.iter().find(|x| placement.allowed(x.dest) && level.same_level(node_id, x.dest)).map(|x| x.conn)
     }
 
-    /// Get all unique destinations
+    /// Get connection of the instance which is selected for the flow, destination must be allowed by placement.
+    ///
+    /// If some instances are reporting load, an instance is selected between best paths of each instance with weight,
+    /// which is higher with lower load and lower path score. The flow key is hashed for the selecting point, so a flow is kept
+    /// on the same instance while weights are unchanged. Otherwise the best path is selected.
+    pub fn weighted_conn(&self, placement: ServicePlacement, flow: u64) -> Option<Remote> {
+        let mut dests = HashMap::new();
+        let mut candidates = vec![];
+        let mut has_load = false;
+        for dest in self.dests.iter().filter(|x| placement.allowed(x.dest)) {
+            if dests.insert(dest.dest, ()).is_none() {
+                has_load |= dest.load > 0;
+                candidates.push(dest);
+            }
+        }
+
+        if !has_load || candidates.len() == 1 {
+            return candidates.first().map(|x| x.conn);
+        }
+
+        let weights = candidates
+            .iter()
+            .map(|x| (256 - x.load as u64) * 1_000_000 / (x.score as u64 + WEIGHT_SCORE_OFFSET))
+            .collect::<Vec<_>>();
+        let total: u64 = weights.iter().sum();
+        let mut point = flow_hash(flow) % total.max(1);
+        for (candidate, weight) in candidates.iter().zip(weights) {
+            if point < weight {
+                return Some(candidate.conn);
+            }
+            point -= weight;
+        }
+        candidates.last().map(|x| x.conn)
+    }
+
+    /// Get all unique destinations which allowed by placement
     /// If relay_from is Some, it will not return the relay_from node connection
-    pub fn broadcast_dests(&self, node_id: NodeId, level: ServiceBroadcastLevel, relay_from: Option<NodeId>) -> Option<Vec<Remote>> {
+    pub fn broadcast_dests(&self, node_id: NodeId, level: ServiceBroadcastLevel, placement: ServicePlacement, relay_from: Option<NodeId>) -> Option<Vec<Remote>> {
         if self.dests.is_empty() {
             return None;
         }
         let mut remotes = vec![];
         let mut dests = HashMap::new();
         for dest in &self.dests {
-            if dests.contains_key(&dest.dest) || !level.same_level(node_id, dest.dest) {
+            if dests.contains_key(&dest.dest) || !level.same_level(node_id, dest.dest) || !placement.allowed(dest.dest) {
                 continue;
             }
             if let Some(relay_from) = &relay_from {
@@ -76,3 +121,11 @@ impl<Remote: Debug + Hash + Copy + Eq + PartialEq> Service<Remote> {
         Some(remotes)
     }
 }
+
+/// Finalizer of splitmix64, which spreads close flow keys over the whole range
+fn flow_hash(flow: u64) -> u64 {
+    let mut x = flow.wrapping_add(0x9E3779B97F4A7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D049BB133111EB);
+    x ^ (x >> 31)
+}
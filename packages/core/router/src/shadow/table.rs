@@ -4,11 +4,17 @@ use atm0s_sdn_identity::{NodeId, NodeIdType};
 pub struct ShadowTable<Remote> {
     layer: u8,
     dests: [Option<Remote>; 256],
+    /// Slots which are not selected as key destination
+    skips: [bool; 256],
 }
 
 impl<Remote: Copy> ShadowTable<Remote> {
     pub fn new(layer: u8) -> Self {
-        Self { layer, dests: [None; 256] }
+        Self {
+            layer,
+            dests: [None; 256],
+            skips: [false; 256],
+        }
     }
 
     pub fn set(&mut self, index: u8, remote: Remote) {
@@ -19,6 +25,10 @@ impl<Remote: Copy> ShadowTable<Remote> {
         self.dests[index as usize] = None;
     }
 
+    pub fn set_skip(&mut self, index: u8, skip: bool) {
+        self.skips[index as usize] = skip;
+    }
+
     pub fn next(&self, dest: NodeId) -> Option<Remote> {
         let index = dest.layer(self.layer);
         self.dests[index as usize]
@@ -29,6 +39,9 @@ impl<Remote: Copy> ShadowTable<Remote> {
     pub fn closest_for(&self, key_index: u8) -> Option<(Remote, u8, u8)> {
         let mut closest_distance: Option<(Remote, u8, u8)> = None;
         for i in 0..=255 {
+            if self.skips[i as usize] {
+                continue;
+            }
             if let Some(remote) = self.dests[i as usize] {
                 let distance = i ^ key_index;
                 if closest_distance.is_none() || distance < closest_distance.expect("").2 {
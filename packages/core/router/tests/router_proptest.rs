@@ -0,0 +1,226 @@
+//! Property-based tests for core router tables, driven by random operation sequences.
+//!
+//! The local router is connected to some neighbours, each neighbour is a full router which also has random directs and
+//! syncs with other neighbours. After each operation, deltas from the local router are applied to a shadow router like the
+//! router_sync feature does, then invariants are checked.
+
+use std::{collections::HashMap, sync::Arc};
+
+use atm0s_sdn_identity::{ConnId, NodeId};
+use atm0s_sdn_router::{
+    core::{DestDelta, Metric, Router, RouterDelta, TableDelta},
+    shadow::{MockShadowRouterHistory, ShadowRouter, ShadowRouterDelta},
+    RouterTable,
+};
+use proptest::prelude::*;
+
+const LOCAL_NODE: NodeId = 0;
+const BANDWIDTH: u32 = 10_000_000;
+const NODES: usize = 16;
+
+/// Nodes which each layer byte in 0..3, spread over all layers and except local node.
+/// The set is kept small for random operations to hit the same nodes often.
+fn nodes() -> Vec<NodeId> {
+    (0..NODES as u32)
+        .map(|k| {
+            let i = 1 + k * 5;
+            (i % 3) | ((i / 3 % 3) << 8) | ((i / 9 % 3) << 16) | ((i / 27 % 3) << 24)
+        })
+        .collect()
+}
+
+fn conn_for(node: NodeId) -> ConnId {
+    ConnId::from_out(0, node as u64)
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    /// Local node connects to a node with latency
+    SetDirect(usize, u16),
+    /// Local node disconnects from a node
+    DelDirect(usize),
+    /// Local node receives sync from a connected node
+    Sync(usize),
+    /// Remote node connects to another node with latency
+    RemoteSetDirect(usize, usize, u16),
+    /// Remote node disconnects from another node
+    RemoteDelDirect(usize, usize),
+    /// Remote node receives sync from another connected node
+    RemoteSync(usize, usize),
+}
+
+fn op_strategy(nodes: usize) -> impl Strategy<Value = Op> {
+    prop_oneof![
+        1 => (0..nodes, 1..100_u16).prop_map(|(n, l)| Op::SetDirect(n, l)),
+        1 => (0..nodes).prop_map(Op::DelDirect),
+        3 => (0..nodes).prop_map(Op::Sync),
+        2 => (0..nodes, 0..nodes, 1..100_u16).prop_map(|(n, m, l)| Op::RemoteSetDirect(n, m, l)),
+        1 => (0..nodes, 0..nodes).prop_map(|(n, m)| Op::RemoteDelDirect(n, m)),
+        2 => (0..nodes, 0..nodes).prop_map(|(n, m)| Op::RemoteSync(n, m)),
+    ]
+}
+
+struct Network {
+    nodes: Vec<NodeId>,
+    local: Router,
+    shadow: ShadowRouter<ConnId>,
+    /// Directs of local node: conn => (node, metric)
+    local_directs: HashMap<ConnId, (NodeId, Metric)>,
+    remotes: Vec<Router>,
+    /// Directs of each remote node: node => metric
+    remote_directs: Vec<HashMap<NodeId, Metric>>,
+}
+
+impl Network {
+    fn new() -> Self {
+        let nodes = nodes();
+        let mut history = MockShadowRouterHistory::new();
+        history.expect_already_received_broadcast().returning(|_, _, _| false);
+        Self {
+            local: Router::new(LOCAL_NODE),
+            shadow: ShadowRouter::new(LOCAL_NODE, Arc::new(history)),
+            local_directs: HashMap::new(),
+            remotes: nodes.iter().map(|n| Router::new(*n)).collect(),
+            remote_directs: nodes.iter().map(|_| HashMap::new()).collect(),
+            nodes,
+        }
+    }
+
+    fn apply(&mut self, op: Op) {
+        match op {
+            Op::SetDirect(n, latency) => {
+                let node = self.nodes[n];
+                let metric = Metric::new(latency, vec![node], BANDWIDTH);
+                self.local.set_direct(conn_for(node), metric.clone());
+                self.local_directs.insert(conn_for(node), (node, metric));
+            }
+            Op::DelDirect(n) => {
+                let conn = conn_for(self.nodes[n]);
+                if self.local_directs.remove(&conn).is_some() {
+                    self.local.del_direct(conn);
+                }
+            }
+            Op::Sync(n) => {
+                let conn = conn_for(self.nodes[n]);
+                if let Some((_, metric)) = self.local_directs.get(&conn) {
+                    let sync = self.remotes[n].create_sync(LOCAL_NODE);
+                    self.local.apply_sync(conn, metric.clone(), sync);
+                }
+            }
+            Op::RemoteSetDirect(n, m, latency) => {
+                if n != m {
+                    let node = self.nodes[m];
+                    let metric = Metric::new(latency, vec![node], BANDWIDTH);
+                    self.remotes[n].set_direct(conn_for(node), metric.clone());
+                    self.remote_directs[n].insert(node, metric);
+                }
+            }
+            Op::RemoteDelDirect(n, m) => {
+                if self.remote_directs[n].remove(&self.nodes[m]).is_some() {
+                    self.remotes[n].del_direct(conn_for(self.nodes[m]));
+                }
+            }
+            Op::RemoteSync(n, m) => {
+                if let Some(metric) = self.remote_directs[n].get(&self.nodes[m]).cloned() {
+                    let sync = self.remotes[m].create_sync(self.nodes[n]);
+                    self.remotes[n].apply_sync(conn_for(self.nodes[m]), metric, sync);
+                }
+            }
+        }
+        self.drain_deltas();
+    }
+
+    /// Drain deltas from the local router into the shadow router, returning number of deltas
+    fn drain_deltas(&mut self) -> usize {
+        let mut count = 0;
+        while let Some(delta) = self.local.pop_delta() {
+            count += 1;
+            match delta {
+                RouterDelta::Table(layer, TableDelta(index, DestDelta::SetBestPath(conn))) => self.shadow.apply_delta(ShadowRouterDelta::SetTable { layer, index, next: conn }),
+                RouterDelta::Table(layer, TableDelta(index, DestDelta::DelBestPath)) => self.shadow.apply_delta(ShadowRouterDelta::DelTable { layer, index }),
+                RouterDelta::Observer(node, observer) => self.shadow.apply_delta(ShadowRouterDelta::SetObserver { node, observer }),
+                RouterDelta::Registry(_) => {}
+            }
+        }
+        for remote in self.remotes.iter_mut() {
+            while remote.pop_delta().is_some() {}
+        }
+        count
+    }
+
+    fn check_lookup_consistency(&self) -> Result<(), TestCaseError> {
+        for dest in &self.nodes {
+            let next = self.local.next(*dest, &[]);
+            prop_assert_eq!(next.map(|n| n.0), self.shadow.next(*dest), "shadow mismatch for dest {}", dest);
+        }
+        Ok(())
+    }
+
+    fn check_no_phantom(&self) -> Result<(), TestCaseError> {
+        for dest in &self.nodes {
+            if let Some((conn, next_node)) = self.local.next(*dest, &[]) {
+                let direct = self.local_directs.get(&conn);
+                prop_assert!(direct.is_some(), "dest {} routed over disconnected conn {}", dest, conn);
+                prop_assert_eq!(direct.map(|d| d.0), Some(next_node), "dest {} routed over wrong node", dest);
+            }
+        }
+        Ok(())
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn apply_delta_then_lookup_consistency(ops in prop::collection::vec(op_strategy(NODES), 1..200)) {
+        let mut net = Network::new();
+        for op in ops {
+            net.apply(op);
+            net.check_lookup_consistency()?;
+        }
+    }
+
+    #[test]
+    fn no_phantom_routes_after_churn(ops in prop::collection::vec(op_strategy(NODES), 1..200)) {
+        let mut net = Network::new();
+        for op in ops {
+            net.apply(op);
+            net.check_no_phantom()?;
+        }
+
+        let conns: Vec<ConnId> = net.local_directs.keys().cloned().collect();
+        for conn in conns {
+            net.local_directs.remove(&conn);
+            net.local.del_direct(conn);
+            net.drain_deltas();
+            net.check_no_phantom()?;
+            net.check_lookup_consistency()?;
+        }
+
+        prop_assert_eq!(net.local.size(), 0);
+        for dest in &net.nodes {
+            prop_assert_eq!(net.shadow.next(*dest), None);
+        }
+    }
+
+    #[test]
+    fn sync_idempotency(ops in prop::collection::vec(op_strategy(NODES), 1..200), target in 0..NODES) {
+        let mut net = Network::new();
+        for op in ops {
+            net.apply(op);
+        }
+
+        let conn = conn_for(net.nodes[target]);
+        if let Some((_, metric)) = net.local_directs.get(&conn).cloned() {
+            let sync = net.remotes[target].create_sync(LOCAL_NODE);
+            net.local.apply_sync(conn, metric.clone(), sync.clone());
+            net.drain_deltas();
+            let dump = net.local.dump();
+
+            net.local.apply_sync(conn, metric, sync);
+            prop_assert_eq!(net.drain_deltas(), 0);
+            prop_assert_eq!(net.local.dump(), dump);
+            net.check_lookup_consistency()?;
+        }
+    }
+}
@@ -4,5 +4,6 @@ pub mod error_handle;
 pub mod hash;
 pub mod init_array;
 pub mod init_vec;
+pub mod log_sampling;
 pub mod option_handle;
 pub mod types;
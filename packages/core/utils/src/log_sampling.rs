@@ -0,0 +1,165 @@
+//! Rate-limited logging for hot paths.
+//!
+//! Some log lines are triggered by remote packets (e.g. rejecting an unsecure message) and can flood the log under attack.
+//! [`log_sampled!`](crate::log_sampled) emits at most `max_per_window` lines per call-site in each window, and the first
+//! line after a window which had suppressed lines is followed by a summary with the suppressed count.
+//!
+//! ```
+//! use atm0s_sdn_utils::log_sampled;
+//!
+//! let remote = 1;
+//! log_sampled!(log::Level::Warn, "[Feature] reject unsecure message from {remote}");
+//! ```
+
+use std::{
+    sync::{Mutex, OnceLock},
+    time::Instant,
+};
+
+/// Default number of lines emitted per call-site in each window
+pub const DEFAULT_MAX_PER_WINDOW: u32 = 10;
+/// Default window length
+pub const DEFAULT_WINDOW_MS: u64 = 10_000;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LogSample {
+    /// The line should be emitted
+    Emit,
+    /// The line should be emitted, after a summary of lines suppressed in the previous window: (suppressed count, window ms)
+    EmitWithSummary(u64, u64),
+    /// The line should be dropped
+    Suppress,
+}
+
+/// Sampling state of a single call-site, driven by an external clock
+#[derive(Debug)]
+pub struct LogSampler {
+    max_per_window: u32,
+    window_ms: u64,
+    window_started: Option<u64>,
+    emitted: u32,
+    suppressed: u64,
+}
+
+impl LogSampler {
+    pub const fn new(max_per_window: u32, window_ms: u64) -> Self {
+        Self {
+            max_per_window,
+            window_ms,
+            window_started: None,
+            emitted: 0,
+            suppressed: 0,
+        }
+    }
+
+    /// Total lines suppressed in the current window
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed
+    }
+
+    pub fn sample(&mut self, now_ms: u64) -> LogSample {
+        match self.window_started {
+            Some(started) if now_ms < started + self.window_ms => {
+                if self.emitted < self.max_per_window {
+                    self.emitted += 1;
+                    LogSample::Emit
+                } else {
+                    self.suppressed += 1;
+                    LogSample::Suppress
+                }
+            }
+            _ => {
+                let suppressed = std::mem::replace(&mut self.suppressed, 0);
+                self.window_started = Some(now_ms);
+                self.emitted = 1;
+                if suppressed > 0 {
+                    LogSample::EmitWithSummary(suppressed, self.window_ms)
+                } else {
+                    LogSample::Emit
+                }
+            }
+        }
+    }
+}
+
+/// Thread-safe sampler with a process-wide clock, which is used as per call-site static by [`log_sampled!`](crate::log_sampled)
+#[derive(Debug)]
+pub struct SharedLogSampler {
+    inner: Mutex<LogSampler>,
+}
+
+impl SharedLogSampler {
+    pub const fn new(max_per_window: u32, window_ms: u64) -> Self {
+        Self {
+            inner: Mutex::new(LogSampler::new(max_per_window, window_ms)),
+        }
+    }
+
+    pub fn sample(&self) -> LogSample {
+        static STARTED: OnceLock<Instant> = OnceLock::new();
+        let now_ms = STARTED.get_or_init(Instant::now).elapsed().as_millis() as u64;
+        match self.inner.lock() {
+            Ok(mut sampler) => sampler.sample(now_ms),
+            Err(_) => LogSample::Emit,
+        }
+    }
+}
+
+/// Same as `log::log!` but rate-limited per call-site, see [`log_sampling`](crate::log_sampling).
+#[macro_export]
+macro_rules! log_sampled {
+    ($lvl:expr, $($arg:tt)+) => {{
+        let lvl = $lvl;
+        if $crate::log_sampling::log::log_enabled!(lvl) {
+            static SAMPLER: $crate::log_sampling::SharedLogSampler = $crate::log_sampling::SharedLogSampler::new($crate::log_sampling::DEFAULT_MAX_PER_WINDOW, $crate::log_sampling::DEFAULT_WINDOW_MS);
+            match SAMPLER.sample() {
+                $crate::log_sampling::LogSample::Emit => $crate::log_sampling::log::log!(lvl, $($arg)+),
+                $crate::log_sampling::LogSample::EmitWithSummary(suppressed, window_ms) => {
+                    $crate::log_sampling::log::log!(lvl, "[{}:{}] suppressed {} repeated logs in last {} ms", file!(), line!(), suppressed, window_ms);
+                    $crate::log_sampling::log::log!(lvl, $($arg)+);
+                }
+                $crate::log_sampling::LogSample::Suppress => {}
+            }
+        }
+    }};
+}
+
+#[doc(hidden)]
+pub use log;
+
+#[cfg(test)]
+mod tests {
+    use super::{LogSample, LogSampler};
+
+    #[test]
+    fn sample_in_window() {
+        let mut sampler = LogSampler::new(2, 1000);
+        assert_eq!(sampler.sample(0), LogSample::Emit);
+        assert_eq!(sampler.sample(100), LogSample::Emit);
+        assert_eq!(sampler.sample(200), LogSample::Suppress);
+        assert_eq!(sampler.sample(999), LogSample::Suppress);
+        assert_eq!(sampler.suppressed(), 2);
+
+        assert_eq!(sampler.sample(1000), LogSample::EmitWithSummary(2, 1000));
+        assert_eq!(sampler.suppressed(), 0);
+        assert_eq!(sampler.sample(1500), LogSample::Emit);
+        assert_eq!(sampler.sample(1999), LogSample::Suppress);
+    }
+
+    #[test]
+    fn no_summary_without_suppressed() {
+        let mut sampler = LogSampler::new(1, 1000);
+        assert_eq!(sampler.sample(0), LogSample::Emit);
+        assert_eq!(sampler.sample(5000), LogSample::Emit);
+        assert_eq!(sampler.sample(5001), LogSample::Suppress);
+        assert_eq!(sampler.sample(10000), LogSample::EmitWithSummary(1, 1000));
+    }
+
+    #[test]
+    fn macro_compiles() {
+        let value = 1;
+        for _ in 0..20 {
+            log_sampled!(log::Level::Warn, "sampled {value}");
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use atm0s_sdn_network::data_plane::{
+    fragment::{FragmentConfig, Fragmenter},
+    pool::{BufferPool, DEFAULT_MAX_IDLE},
+};
+use criterion::{criterion_group, criterion_main, Criterion};
+
+criterion_group!(benches, benchmark_pool, benchmark_reassembly);
+criterion_main!(benches);
+
+const PAYLOAD: [u8; 1200] = [1; 1200];
+
+fn benchmark_pool(c: &mut Criterion) {
+    let mut group = c.benchmark_group("buffer");
+    group.throughput(criterion::Throughput::Elements(1));
+    group.bench_function("vec_alloc", |b| {
+        b.iter(|| {
+            let mut buf = Vec::with_capacity(1400);
+            buf.extend_from_slice(&PAYLOAD);
+            criterion::black_box(buf);
+        });
+    });
+
+    let pool = BufferPool::new(1400, DEFAULT_MAX_IDLE);
+    group.bench_function("pool_take", |b| {
+        b.iter(|| {
+            criterion::black_box(pool.copy_from(&PAYLOAD));
+        });
+    });
+}
+
+fn benchmark_reassembly(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reassembly");
+    let cfg = FragmentConfig::default();
+    let mut sender = Fragmenter::new(cfg, BufferPool::new(cfg.mtu, DEFAULT_MAX_IDLE));
+    sender.set_enabled(true);
+    let first = sender.send(vec![1; 16 * 1024].into()).expect("Should have first fragment");
+    let mut fragments = vec![first];
+    while let Some(fragment) = sender.pop_fragment() {
+        fragments.push(fragment);
+    }
+
+    group.throughput(criterion::Throughput::Bytes(16 * 1024));
+    let pool = BufferPool::new(cfg.mtu, DEFAULT_MAX_IDLE);
+    let mut receiver = Fragmenter::new(cfg, pool.clone());
+    group.bench_function("16k", |b| {
+        b.iter(|| {
+            let mut out = None;
+            for fragment in &fragments {
+                out = receiver.on_fragment(0, fragment);
+            }
+            criterion::black_box(out.expect("Should reassemble"));
+        });
+    });
+    let stats = pool.stats();
+    println!("reassembly pool: allocated {} reused {}", stats.allocated, stats.reused);
+}
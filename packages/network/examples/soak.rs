@@ -0,0 +1,612 @@
+//!
+//! Long-running soak test for the whole network stack.
+//!
+//! It creates an in-process mesh with the deterministic simulation module, then continuously churns nodes
+//! (join, graceful leave and crash) while exercising data routing, dht_kv, pubsub and alias. After each round it checks some
+//! invariants and at the end it prints a report. The process exits with code 1 if any invariant is violated.
+//!
+//! Example: cargo run --release -p atm0s-sdn-network --example soak -- --nodes 30 --rounds 500
+//!
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use atm0s_sdn_identity::{NodeAddr, NodeId};
+use atm0s_sdn_network::{
+    base::{
+        NetOutgoingMeta, Service, ServiceBuilder, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput, ServiceWorker, ServiceWorkerCtx, ServiceWorkerInput, ServiceWorkerOutput, Ttl,
+        DEFAULT_MSG_TTL,
+    },
+    features::{
+        alias, data,
+        dht_kv::{self, Key, Map, MapControl, MapEvent},
+        pubsub::{self, ChannelControl, ChannelEvent, ChannelId},
+        FeaturesControl, FeaturesEvent,
+    },
+    simulation::Simulation,
+    ExtIn, ExtOut,
+};
+use atm0s_sdn_router::{RouteRule, ServiceBroadcastLevel};
+use clap::Parser;
+use rand::{rngs::StdRng, seq::IteratorRandom, Rng, SeedableRng};
+
+const SOAK_SERVICE: u8 = 0;
+const SOAK_DATA_PORT: u16 = 1000;
+const STEP_MS: u64 = 100;
+
+struct CountingAlloc;
+
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            LIVE_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        LIVE_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Soak test for atm0s-sdn network, which runs an in-process mesh with continuous churn
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Number of nodes which are created at start
+    #[arg(long, default_value_t = 20)]
+    nodes: usize,
+
+    /// Number of nodes which never churn, all other nodes connect to at least one of them so the mesh stays connected
+    #[arg(long, default_value_t = 3)]
+    stable: usize,
+
+    /// Minimum number of alive nodes
+    #[arg(long, default_value_t = 10)]
+    min_nodes: usize,
+
+    /// Maximum number of alive nodes
+    #[arg(long, default_value_t = 40)]
+    max_nodes: usize,
+
+    /// Number of rounds, each round churns some nodes then checks all invariants
+    #[arg(long, default_value_t = 100)]
+    rounds: u64,
+
+    /// Number of churn actions (join, leave or crash) in each round
+    #[arg(long, default_value_t = 2)]
+    churn: usize,
+
+    /// Number of extra random neighbours a new node connects to
+    #[arg(long, default_value_t = 2)]
+    neighbours: usize,
+
+    /// Simulated time for the mesh to converge after churn, in milliseconds
+    #[arg(long, default_value_t = 15000)]
+    settle_ms: u64,
+
+    /// Number of node pairs which are checked for routing in each round
+    #[arg(long, default_value_t = 20)]
+    pairs: usize,
+
+    /// Allowed growth of memory per node compared with the first round
+    #[arg(long, default_value_t = 3.0)]
+    max_mem_growth: f64,
+
+    /// Seed for churn, links and randomness of nodes, the same seed produces the same scenario
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Print all network logs, which is very verbose
+    #[arg(long)]
+    verbose: bool,
+}
+
+#[derive(Default)]
+struct SoakService {
+    shutdown: bool,
+}
+
+impl Service<(), FeaturesControl, FeaturesEvent, (), (), (), ()> for SoakService {
+    fn is_service_empty(&self) -> bool {
+        self.shutdown
+    }
+
+    fn service_id(&self) -> u8 {
+        SOAK_SERVICE
+    }
+
+    fn service_name(&self) -> &str {
+        "soak"
+    }
+
+    fn on_input(&mut self, _ctx: &ServiceCtx, _now: u64, _input: ServiceInput<(), FeaturesEvent, (), ()>) {}
+
+    fn on_shared_input<'a>(&mut self, _ctx: &ServiceCtx, _now: u64, _input: ServiceSharedInput) {}
+
+    fn on_shutdown(&mut self, _ctx: &ServiceCtx, _now: u64) {
+        self.shutdown = true;
+    }
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceOutput<(), FeaturesControl, (), ()>> {
+        None
+    }
+}
+
+#[derive(Default)]
+struct SoakServiceWorker {
+    shutdown: bool,
+}
+
+impl ServiceWorker<(), FeaturesControl, FeaturesEvent, (), (), (), ()> for SoakServiceWorker {
+    fn is_service_empty(&self) -> bool {
+        self.shutdown
+    }
+
+    fn service_id(&self) -> u8 {
+        SOAK_SERVICE
+    }
+
+    fn service_name(&self) -> &str {
+        "soak"
+    }
+
+    fn on_tick(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, _tick_count: u64) {}
+
+    fn on_input(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, _input: ServiceWorkerInput<(), FeaturesEvent, (), ()>) {}
+
+    fn on_shutdown(&mut self, _ctx: &ServiceWorkerCtx, _now: u64) {
+        self.shutdown = true;
+    }
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceWorkerOutput<(), FeaturesControl, FeaturesEvent, (), (), ()>> {
+        None
+    }
+}
+
+struct SoakServiceBuilder;
+
+impl ServiceBuilder<(), FeaturesControl, FeaturesEvent, (), (), (), ()> for SoakServiceBuilder {
+    fn service_id(&self) -> u8 {
+        SOAK_SERVICE
+    }
+
+    fn service_name(&self) -> &str {
+        "soak"
+    }
+
+    fn create(&self) -> Box<dyn Service<(), FeaturesControl, FeaturesEvent, (), (), (), ()>> {
+        Box::<SoakService>::default()
+    }
+
+    fn create_worker(&self) -> Box<dyn ServiceWorker<(), FeaturesControl, FeaturesEvent, (), (), (), ()>> {
+        Box::<SoakServiceWorker>::default()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counter {
+    ok: u64,
+    failed: u64,
+}
+
+impl Counter {
+    fn record(&mut self, ok: bool) {
+        if ok {
+            self.ok += 1;
+        } else {
+            self.failed += 1;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Report {
+    joins: u64,
+    leaves: u64,
+    crashes: u64,
+    stuck_shutdowns: u64,
+    routing: Counter,
+    routing_loops: u64,
+    pubsub_delivery: Counter,
+    pubsub_stuck: u64,
+    dht_kv: Counter,
+    alias: Counter,
+    mem_baseline_per_node: usize,
+    mem_peak_per_node: usize,
+    mem_violations: u64,
+    violations: Vec<String>,
+}
+
+impl Report {
+    fn violation(&mut self, round: u64, msg: String) {
+        log::error!("[Soak] round {round}: {msg}");
+        if self.violations.len() < 100 {
+            self.violations.push(format!("round {round}: {msg}"));
+        }
+    }
+
+    fn print(&self, rounds: u64, alive: usize, sim_ms: u64, elapsed_s: f64) {
+        println!("=========== Soak report ===========");
+        println!("rounds: {rounds}, alive nodes: {alive}, simulated: {} s, wall time: {elapsed_s:.1} s", sim_ms / 1000);
+        println!(
+            "churn: joins {}, leaves {}, crashes {}, stuck shutdowns {}",
+            self.joins, self.leaves, self.crashes, self.stuck_shutdowns
+        );
+        println!("routing: ok {}, failed {}, loops {}", self.routing.ok, self.routing.failed, self.routing_loops);
+        println!(
+            "pubsub: delivered {}, missed {}, stuck subscriptions {}",
+            self.pubsub_delivery.ok, self.pubsub_delivery.failed, self.pubsub_stuck
+        );
+        println!("dht_kv: ok {}, failed {}", self.dht_kv.ok, self.dht_kv.failed);
+        println!("alias: ok {}, failed {}", self.alias.ok, self.alias.failed);
+        println!(
+            "memory per node: baseline {} KiB, peak {} KiB, violations {}",
+            self.mem_baseline_per_node / 1024,
+            self.mem_peak_per_node / 1024,
+            self.mem_violations
+        );
+        if self.violations.is_empty() {
+            println!("result: PASSED");
+        } else {
+            println!("result: FAILED with {} violations", self.violations.len());
+            for violation in &self.violations {
+                println!("  - {violation}");
+            }
+        }
+    }
+}
+
+type Sim = Simulation<(), (), (), ()>;
+
+struct Soak {
+    args: Args,
+    sim: Sim,
+    rng: StdRng,
+    addrs: HashMap<NodeId, NodeAddr>,
+    stable: Vec<NodeId>,
+    alive: Vec<NodeId>,
+    leaving: Vec<NodeId>,
+    next_node: NodeId,
+    report: Report,
+}
+
+impl Soak {
+    fn new(args: Args) -> Self {
+        let rng = StdRng::seed_from_u64(args.seed);
+        let sim = Sim::new(args.seed);
+        Self {
+            args,
+            sim,
+            rng,
+            addrs: HashMap::new(),
+            stable: vec![],
+            alive: vec![],
+            leaving: vec![],
+            next_node: 1,
+            report: Report::default(),
+        }
+    }
+
+    fn control(&mut self, node: NodeId, control: impl Into<FeaturesControl>) {
+        self.sim.control(node, ExtIn::FeaturesControl((), control.into()));
+    }
+
+    /// Run the simulator for `duration` ms and collect all feature events
+    fn run(&mut self, duration: u64) -> Vec<(NodeId, FeaturesEvent)> {
+        let mut events = vec![];
+        let mut elapsed = 0;
+        while elapsed < duration {
+            let step = STEP_MS.min(duration - elapsed);
+            self.sim.advance(step);
+            elapsed += step;
+            while let Some((node, out)) = self.sim.pop_output() {
+                if let ExtOut::FeaturesEvent((), event) = out {
+                    events.push((node, event));
+                }
+            }
+        }
+        events
+    }
+
+    fn join(&mut self) {
+        let node = self.next_node;
+        self.next_node += 1;
+        let mut cfg = Sim::node_cfg(node, node as u64, vec![Arc::new(SoakServiceBuilder)]);
+        if let Some(controller) = &mut cfg.controller {
+            controller.random = Box::new(StdRng::seed_from_u64(self.args.seed ^ node as u64));
+        }
+        let addr = self.sim.add_node(cfg);
+        self.addrs.insert(node, addr);
+        // only new node dials to others, which avoids both sides connecting to each other at the same time
+        let mut targets = HashSet::new();
+        if self.stable.len() < self.args.stable {
+            targets.extend(self.stable.iter().copied());
+            self.stable.push(node);
+        } else {
+            if let Some(stable) = self.stable.iter().choose(&mut self.rng) {
+                targets.insert(*stable);
+            }
+            for _ in 0..self.args.neighbours {
+                if let Some(other) = self.alive.iter().choose(&mut self.rng) {
+                    targets.insert(*other);
+                }
+            }
+        }
+        for target in targets {
+            self.sim.control(node, ExtIn::ConnectTo(self.addrs[&target].clone()));
+        }
+        self.alive.push(node);
+        self.control(node, data::Control::DataListen(SOAK_DATA_PORT));
+        self.report.joins += 1;
+    }
+
+    fn pick_churnable(&mut self) -> Option<NodeId> {
+        let index = self.alive.iter().enumerate().filter(|(_, n)| !self.stable.contains(n)).map(|(i, _)| i).choose(&mut self.rng)?;
+        Some(self.alive.swap_remove(index))
+    }
+
+    fn churn(&mut self) {
+        for _ in 0..self.args.churn {
+            let action = self.rng.gen_range(0..3);
+            let can_remove = self.alive.len() > self.args.min_nodes.max(self.stable.len());
+            let can_add = self.alive.len() < self.args.max_nodes;
+            match action {
+                0 if can_add => self.join(),
+                1 if can_remove => {
+                    if let Some(node) = self.pick_churnable() {
+                        log::info!("[Soak] node {node} leave");
+                        self.sim.leave_node(node);
+                        self.leaving.push(node);
+                        self.report.leaves += 1;
+                    }
+                }
+                2 if can_remove => {
+                    if let Some(node) = self.pick_churnable() {
+                        log::info!("[Soak] node {node} crash");
+                        self.sim.remove_node(node);
+                        self.report.crashes += 1;
+                    }
+                }
+                _ if can_add => self.join(),
+                _ => {}
+            }
+        }
+    }
+
+    fn check_shutdowns(&mut self, round: u64) {
+        for node in std::mem::take(&mut self.leaving) {
+            if self.sim.has_node(node) {
+                self.report.stuck_shutdowns += 1;
+                self.report.violation(round, format!("node {node} did not finish graceful shutdown"));
+                self.sim.remove_node(node);
+            }
+        }
+    }
+
+    fn pick_pair(&mut self) -> (NodeId, NodeId) {
+        let pair = self.alive.iter().copied().choose_multiple(&mut self.rng, 2);
+        (pair[0], pair[1])
+    }
+
+    /// Each sampled pair must be reachable and the number of hops must not exceed number of alive nodes, otherwise it is a loop
+    fn check_routing(&mut self, round: u64) {
+        let mut waits = HashMap::new();
+        for seq in 0..self.args.pairs as u32 {
+            let (from, to) = self.pick_pair();
+            waits.insert(seq, (from, to));
+            let meta = NetOutgoingMeta::new(true, Ttl::default(), 0, true);
+            self.control(from, data::Control::DataSendRule(SOAK_DATA_PORT, RouteRule::ToNode(to), meta, seq.to_be_bytes().to_vec()));
+        }
+
+        for (node, event) in self.run(1000) {
+            if let FeaturesEvent::Data(data::Event::Recv(SOAK_DATA_PORT, meta, buf)) = event {
+                let seq = u32::from_be_bytes(buf.try_into().unwrap_or_default());
+                if let Some((from, to)) = waits.remove(&seq) {
+                    let hops = DEFAULT_MSG_TTL - *meta.ttl;
+                    if to != node {
+                        self.report.routing.record(false);
+                        self.report.violation(round, format!("data from {from} to {to} delivered to wrong node {node}"));
+                    } else if hops as usize > self.alive.len() {
+                        self.report.routing_loops += 1;
+                        self.report.violation(round, format!("data from {from} to {to} took {hops} hops with {} alive nodes", self.alive.len()));
+                    } else {
+                        self.report.routing.record(true);
+                    }
+                }
+            }
+        }
+
+        for (_, (from, to)) in waits {
+            self.report.routing.record(false);
+            self.report.violation(round, format!("data from {from} to {to} is not delivered"));
+        }
+    }
+
+    /// All subscribers must receive published data, and after unsubscribing they must not receive anything
+    fn check_pubsub(&mut self, round: u64) {
+        let channel = ChannelId(round);
+        let publisher = *self.alive.iter().choose(&mut self.rng).expect("should have alive node");
+        let subscribers: Vec<NodeId> = self.alive.iter().copied().filter(|n| *n != publisher).choose_multiple(&mut self.rng, 3);
+
+        self.control(publisher, pubsub::Control(channel, ChannelControl::PubStart));
+        for sub in &subscribers {
+            self.control(*sub, pubsub::Control(channel, ChannelControl::SubAuto));
+        }
+        self.run(2000);
+
+        let value = round.to_be_bytes().to_vec();
+        self.control(publisher, pubsub::Control(channel, ChannelControl::PubData(value.clone())));
+        let mut received = HashSet::new();
+        for (node, event) in self.run(500) {
+            if let FeaturesEvent::PubSub(pubsub::Event(c, ChannelEvent::SourceData(source, data))) = event {
+                if c == channel && source == publisher && data == value {
+                    received.insert(node);
+                }
+            }
+        }
+        for sub in &subscribers {
+            let ok = received.contains(sub);
+            self.report.pubsub_delivery.record(ok);
+            if !ok {
+                self.report.violation(round, format!("pubsub channel {} subscriber {sub} missed data from {publisher}", *channel));
+            }
+        }
+
+        for sub in &subscribers {
+            self.control(*sub, pubsub::Control(channel, ChannelControl::UnsubAuto));
+        }
+        self.run(2000);
+        self.control(publisher, pubsub::Control(channel, ChannelControl::PubData(value)));
+        for (node, event) in self.run(500) {
+            if let FeaturesEvent::PubSub(pubsub::Event(c, ChannelEvent::SourceData(..))) = event {
+                if c == channel {
+                    self.report.pubsub_stuck += 1;
+                    self.report.violation(round, format!("pubsub channel {} node {node} still receives data after unsubscribed", *channel));
+                }
+            }
+        }
+        self.control(publisher, pubsub::Control(channel, ChannelControl::PubStop));
+    }
+
+    /// Value which is set from one node must be notified to another subscribed node
+    fn check_dht_kv(&mut self, round: u64) {
+        let map = Map(round);
+        let key = Key(round);
+        let value = round.to_be_bytes().to_vec();
+        let (setter, subscriber) = self.pick_pair();
+
+        self.control(subscriber, dht_kv::Control::MapCmd(map, MapControl::Sub));
+        self.control(setter, dht_kv::Control::MapCmd(map, MapControl::Set(key, value.clone())));
+        let ok = self
+            .run(2000)
+            .into_iter()
+            .any(|(node, event)| node == subscriber && event == FeaturesEvent::DhtKv(dht_kv::Event::MapEvent(map, MapEvent::OnSet(key, setter, value.clone()))));
+        self.report.dht_kv.record(ok);
+        if !ok {
+            self.report.violation(round, format!("dht_kv map {} set from {setter} is not notified to {subscriber}", *map));
+        }
+
+        self.control(setter, dht_kv::Control::MapCmd(map, MapControl::Del(key)));
+        self.control(subscriber, dht_kv::Control::MapCmd(map, MapControl::Unsub));
+    }
+
+    /// Alias which is registered in one node must be found from another node
+    fn check_alias(&mut self, round: u64) {
+        let alias_v = round;
+        let level = ServiceBroadcastLevel::Global;
+        let (owner, querier) = self.pick_pair();
+
+        self.control(
+            owner,
+            alias::Control::Register {
+                alias: alias_v,
+                service: SOAK_SERVICE,
+                level,
+            },
+        );
+        self.run(1000);
+        self.control(
+            querier,
+            alias::Control::Query {
+                alias: alias_v,
+                service: SOAK_SERVICE,
+                level,
+            },
+        );
+        let mut found = None;
+        for (node, event) in self.run(alias::HINT_TIMEOUT_MS + alias::SCAN_TIMEOUT_MS + 1000) {
+            if let FeaturesEvent::Alias(alias::Event::QueryResult(a, res)) = event {
+                if node == querier && a == alias_v {
+                    found = Some(res);
+                }
+            }
+        }
+        let ok = matches!(
+            found,
+            Some(Some(
+                alias::FoundLocation::Notify(n) | alias::FoundLocation::CachedHint(n) | alias::FoundLocation::RemoteHint(n) | alias::FoundLocation::RemoteScan(n)
+            )) if n == owner
+        );
+        self.report.alias.record(ok);
+        if !ok {
+            self.report
+                .violation(round, format!("alias {alias_v} registered at {owner} is not found from {querier}, result {found:?}"));
+        }
+
+        self.control(owner, alias::Control::Unregister { alias: alias_v });
+    }
+
+    fn check_memory(&mut self, round: u64) {
+        let per_node = LIVE_BYTES.load(Ordering::Relaxed) / self.alive.len().max(1);
+        self.report.mem_peak_per_node = self.report.mem_peak_per_node.max(per_node);
+        if self.report.mem_baseline_per_node == 0 {
+            self.report.mem_baseline_per_node = per_node;
+        } else if per_node as f64 > self.report.mem_baseline_per_node as f64 * self.args.max_mem_growth {
+            self.report.mem_violations += 1;
+            self.report.violation(
+                round,
+                format!("memory per node {} KiB exceeds baseline {} KiB", per_node / 1024, self.report.mem_baseline_per_node / 1024),
+            );
+        }
+    }
+
+    fn start(&mut self) {
+        for _ in 0..self.args.nodes.max(self.args.stable).max(2) {
+            self.join();
+        }
+        self.run(self.args.settle_ms);
+    }
+
+    fn round(&mut self, round: u64) {
+        self.churn();
+        self.run(self.args.settle_ms);
+        self.check_shutdowns(round);
+        self.check_routing(round);
+        self.check_pubsub(round);
+        self.check_dht_kv(round);
+        self.check_alias(round);
+        self.check_memory(round);
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+    let started = Instant::now();
+    let rounds = args.rounds;
+    let verbose = args.verbose;
+
+    if verbose {
+        env_logger::builder().filter_level(log::LevelFilter::Info).init();
+    }
+    let mut soak = Soak::new(args);
+    soak.start();
+    for round in 1..=rounds {
+        soak.round(round);
+        println!(
+            "[Soak] round {round}/{rounds} alive {} violations {} elapsed {:.1} s",
+            soak.alive.len(),
+            soak.report.violations.len(),
+            started.elapsed().as_secs_f64()
+        );
+    }
+
+    soak.report.print(rounds, soak.alive.len(), soak.sim.now_ms(), started.elapsed().as_secs_f64());
+    if !soak.report.violations.is_empty() {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,479 @@
+//! Stateful fuzzer of the neighbours handshake state machine.
+//!
+//! Two neighbours managers exchange controls over a simulated link which the input can drop, duplicate, reorder or corrupt.
+//! The input can also inject raw packets, and controls which are signed with the shared key, so commands of an authorized but
+//! broken peer reach the connection state machine. After each op the state of both managers must stay bounded, and after a long
+//! idle time all connections must be cleaned up.
+
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use atm0s_sdn_identity::{ConnId, NodeAddr, NodeAddrBuilder, NodeId, Protocol};
+use rand::rngs::mock::StepRng;
+use sans_io_runtime::TaskSwitcherChild;
+
+use crate::{
+    base::{Capabilities, ConnectionEvent, LinkProfile, NeighboursControl, NeighboursControlCmds, PeerCapabilities},
+    controller_plane::neighbours::{Input, NeighboursManager, Output},
+    data_plane::NetPair,
+    secure::{HandshakeBuilderXDA, StaticKeyAuthorization},
+};
+
+use super::FuzzInput;
+
+/// Ports of attacker addresses, which bounds the connections an attacker can open
+pub const ATTACKER_PORTS: u16 = 16;
+/// Controls in flight over the link, older ones are dropped
+pub const MAX_IN_FLIGHT: usize = 256;
+/// Max number of ops in one input
+pub const MAX_OPS: usize = 1024;
+/// Max state entries of a manager, each address can have a connection, a neighbour, a dialing and a restarting entry
+pub const MAX_STATE: usize = 4 * (ATTACKER_PORTS as usize + 1);
+const KEY: &str = "fuzz-key";
+const IDLE_STEP_MS: u64 = 1000;
+const IDLE_MS: u64 = 120_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    A,
+    B,
+}
+
+impl Side {
+    fn from_u8(value: u8) -> Self {
+        if value % 2 == 0 {
+            Side::A
+        } else {
+            Side::B
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Side::A => 0,
+            Side::B => 1,
+        }
+    }
+
+    fn index(self) -> usize {
+        self.to_u8() as usize
+    }
+
+    fn other(self) -> Self {
+        match self {
+            Side::A => Side::B,
+            Side::B => Side::A,
+        }
+    }
+
+    fn node(self) -> NodeId {
+        self.index() as NodeId + 1
+    }
+
+    fn addr(self) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1001 + self.index() as u16)
+    }
+}
+
+fn attacker_addr(port: u8) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 2000 + (port as u16 % ATTACKER_PORTS))
+}
+
+fn node_addr(side: Side) -> NodeAddr {
+    let mut builder = NodeAddrBuilder::new(side.node());
+    builder.add_protocol(Protocol::Ip4(Ipv4Addr::LOCALHOST));
+    builder.add_protocol(Protocol::Udp(side.addr().port()));
+    builder.addr()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Side connects to the other side
+    ConnectTo(Side),
+    /// Deliver the oldest control in flight
+    Deliver,
+    Drop,
+    Duplicate,
+    /// Move the oldest control to the end
+    Reorder,
+    /// Change a byte of the oldest control, it is dropped if it can't be parsed anymore
+    Corrupt {
+        pos: u16,
+        value: u8,
+    },
+    /// Raw packet to a side from an attacker address
+    Inject {
+        to: Side,
+        port: u8,
+        data: Vec<u8>,
+    },
+    /// Command which is signed with the shared key, from an attacker address
+    Forge {
+        to: Side,
+        port: u8,
+        from: NodeId,
+        cmd: Vec<u8>,
+    },
+    /// Control which was delivered before, replayed from an attacker address
+    Replay {
+        to: Side,
+        port: u8,
+        index: u8,
+    },
+    Tick {
+        ms: u16,
+    },
+    Disconnect(Side),
+    Restart(Side),
+    Shutdown(Side),
+}
+
+impl Op {
+    pub fn read(input: &mut FuzzInput) -> Option<Self> {
+        let op = match input.u8()? % 13 {
+            0 => Op::ConnectTo(Side::from_u8(input.u8()?)),
+            1 => Op::Deliver,
+            2 => Op::Drop,
+            3 => Op::Duplicate,
+            4 => Op::Reorder,
+            5 => Op::Corrupt {
+                pos: input.u16()?,
+                value: input.u8()?,
+            },
+            6 => {
+                let to = Side::from_u8(input.u8()?);
+                let port = input.u8()?;
+                let len = input.u16()? as usize;
+                Op::Inject {
+                    to,
+                    port,
+                    data: input.bytes(len)?.to_vec(),
+                }
+            }
+            7 => {
+                let to = Side::from_u8(input.u8()?);
+                let port = input.u8()?;
+                let from = input.u32()?;
+                let len = input.u16()? as usize;
+                Op::Forge {
+                    to,
+                    port,
+                    from,
+                    cmd: input.bytes(len)?.to_vec(),
+                }
+            }
+            8 => Op::Replay {
+                to: Side::from_u8(input.u8()?),
+                port: input.u8()?,
+                index: input.u8()?,
+            },
+            9 => Op::Tick { ms: input.u16()? },
+            10 => Op::Disconnect(Side::from_u8(input.u8()?)),
+            11 => Op::Restart(Side::from_u8(input.u8()?)),
+            _ => Op::Shutdown(Side::from_u8(input.u8()?)),
+        };
+        Some(op)
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Op::ConnectTo(side) => out.extend([0, side.to_u8()]),
+            Op::Deliver => out.push(1),
+            Op::Drop => out.push(2),
+            Op::Duplicate => out.push(3),
+            Op::Reorder => out.push(4),
+            Op::Corrupt { pos, value } => {
+                out.push(5);
+                out.extend(pos.to_be_bytes());
+                out.push(*value);
+            }
+            Op::Inject { to, port, data } => {
+                out.extend([6, to.to_u8(), *port]);
+                out.extend((data.len() as u16).to_be_bytes());
+                out.extend(data);
+            }
+            Op::Forge { to, port, from, cmd } => {
+                out.extend([7, to.to_u8(), *port]);
+                out.extend(from.to_be_bytes());
+                out.extend((cmd.len() as u16).to_be_bytes());
+                out.extend(cmd);
+            }
+            Op::Replay { to, port, index } => out.extend([8, to.to_u8(), *port, *index]),
+            Op::Tick { ms } => {
+                out.push(9);
+                out.extend(ms.to_be_bytes());
+            }
+            Op::Disconnect(side) => out.extend([10, side.to_u8()]),
+            Op::Restart(side) => out.extend([11, side.to_u8()]),
+            Op::Shutdown(side) => out.extend([12, side.to_u8()]),
+        }
+    }
+}
+
+pub fn encode(ops: &[Op]) -> Vec<u8> {
+    let mut out = vec![];
+    for op in ops {
+        op.write(&mut out);
+    }
+    out
+}
+
+/// Inputs which cover handshake, lossy links, restart, disconnect and attacks, for seeding the corpus
+pub fn corpus() -> Vec<Vec<u8>> {
+    let deliver_all = || vec![Op::Deliver; 16];
+    let handshake = || [vec![Op::ConnectTo(Side::A)], deliver_all(), vec![Op::Tick { ms: 1000 }], deliver_all()].concat();
+    let ping_cmd = bincode::serialize(&NeighboursControlCmds::Ping { session: 0, seq: 1, sent_ms: 0 }).expect("Should serialize");
+    let request_cmd = bincode::serialize(&NeighboursControlCmds::ConnectRequest {
+        to: Side::A.node(),
+        session: 1,
+        handshake: vec![1, 2, 3],
+    })
+    .expect("Should serialize");
+    vec![
+        encode(&handshake()),
+        encode(&[handshake(), vec![Op::Restart(Side::A)], deliver_all(), vec![Op::Tick { ms: 10_000 }], deliver_all()].concat()),
+        encode(&[handshake(), vec![Op::Disconnect(Side::B)], deliver_all(), vec![Op::Tick { ms: 10_000 }], deliver_all()].concat()),
+        encode(&[handshake(), vec![Op::Shutdown(Side::A), Op::Shutdown(Side::B)], deliver_all()].concat()),
+        encode(&[
+            Op::ConnectTo(Side::A),
+            Op::ConnectTo(Side::B),
+            Op::Duplicate,
+            Op::Reorder,
+            Op::Deliver,
+            Op::Drop,
+            Op::Deliver,
+            Op::Corrupt { pos: 10, value: 0xff },
+            Op::Deliver,
+            Op::Tick { ms: 40_000 },
+            Op::Deliver,
+        ]),
+        encode(&[handshake(), (0..4).map(|i| Op::Replay { to: Side::B, port: i, index: i }).collect()].concat()),
+        encode(&[
+            Op::Forge {
+                to: Side::A,
+                port: 1,
+                from: 3,
+                cmd: request_cmd,
+            },
+            Op::Forge {
+                to: Side::A,
+                port: 1,
+                from: 3,
+                cmd: ping_cmd,
+            },
+            Op::Inject {
+                to: Side::B,
+                port: 2,
+                data: vec![255, 0, 0, 0],
+            },
+            Op::Tick { ms: 1000 },
+        ]),
+    ]
+}
+
+pub struct NeighboursFuzzer {
+    now_ms: u64,
+    managers: [NeighboursManager; 2],
+    /// Controls in flight to a side, with the pair which is seen by the receiver
+    in_flight: VecDeque<(Side, NetPair, NeighboursControl)>,
+    /// Controls which were delivered, for replaying
+    delivered: Vec<NeighboursControl>,
+    /// Established connections of each side
+    conns: [Vec<ConnId>; 2],
+    auth: Arc<StaticKeyAuthorization>,
+}
+
+impl Default for NeighboursFuzzer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NeighboursFuzzer {
+    pub fn new() -> Self {
+        let auth = Arc::new(StaticKeyAuthorization::new(KEY));
+        let build = |side: Side| {
+            NeighboursManager::new(
+                side.node(),
+                vec![side.addr()],
+                auth.clone(),
+                Arc::new(HandshakeBuilderXDA),
+                LinkProfile::Standard,
+                PeerCapabilities::local(Capabilities::SUPPORTED),
+                Box::new(StepRng::new(side.node() as u64 * 1000, 5)),
+            )
+        };
+        Self {
+            now_ms: 0,
+            managers: [build(Side::A), build(Side::B)],
+            in_flight: VecDeque::new(),
+            delivered: vec![],
+            conns: [vec![], vec![]],
+            auth,
+        }
+    }
+
+    /// Run all ops of the input, then check that connections are cleaned up after idle
+    pub fn run(data: &[u8]) {
+        let mut fuzzer = Self::new();
+        let mut input = FuzzInput::new(data);
+        let mut ops = 0;
+        while let Some(op) = Op::read(&mut input) {
+            fuzzer.apply(op);
+            ops += 1;
+            if ops >= MAX_OPS {
+                break;
+            }
+        }
+        fuzzer.finish();
+    }
+
+    pub fn apply(&mut self, op: Op) {
+        match op {
+            Op::ConnectTo(side) => self.input(side, Input::ConnectTo(node_addr(side.other()))),
+            Op::Deliver => {
+                if let Some((to, pair, control)) = self.in_flight.pop_front() {
+                    self.delivered.push(control.clone());
+                    self.input(to, Input::Control(pair, control));
+                }
+            }
+            Op::Drop => {
+                self.in_flight.pop_front();
+            }
+            Op::Duplicate => {
+                if let Some(front) = self.in_flight.front().cloned() {
+                    self.push_in_flight(front);
+                }
+            }
+            Op::Reorder => {
+                if let Some(front) = self.in_flight.pop_front() {
+                    self.in_flight.push_back(front);
+                }
+            }
+            Op::Corrupt { pos, value } => {
+                if let Some((to, pair, control)) = self.in_flight.pop_front() {
+                    let mut buf: Vec<u8> = (&control).try_into().expect("Should serialize control");
+                    let len = buf.len();
+                    buf[pos as usize % len] = value;
+                    if let Ok(control) = NeighboursControl::try_from(buf.as_slice()) {
+                        self.in_flight.push_front((to, pair, control));
+                    }
+                }
+            }
+            Op::Inject { to, port, data } => {
+                if let Ok(control) = NeighboursControl::try_from(data.as_slice()) {
+                    self.input(to, Input::Control(NetPair::new(to.addr(), attacker_addr(port)), control));
+                }
+            }
+            Op::Forge { to, port, from, cmd } => {
+                if let Ok(cmd) = bincode::deserialize::<NeighboursControlCmds>(&cmd) {
+                    let control = NeighboursControl::build(self.now_ms, from, cmd, &*self.auth);
+                    self.input(to, Input::Control(NetPair::new(to.addr(), attacker_addr(port)), control));
+                }
+            }
+            Op::Replay { to, port, index } => {
+                if !self.delivered.is_empty() {
+                    let control = self.delivered[index as usize % self.delivered.len()].clone();
+                    self.input(to, Input::Control(NetPair::new(to.addr(), attacker_addr(port)), control));
+                }
+            }
+            Op::Tick { ms } => self.tick(ms as u64),
+            Op::Disconnect(side) => self.input(side, Input::DisconnectFrom(side.other().node())),
+            Op::Restart(side) => {
+                if let Some(conn) = self.conns[side.index()].first().copied() {
+                    self.input(side, Input::Restart(conn));
+                }
+            }
+            Op::Shutdown(side) => {
+                self.managers[side.index()].on_shutdown(self.now_ms);
+                self.pump(side);
+            }
+        }
+        for side in [Side::A, Side::B] {
+            let size = self.managers[side.index()].state_size();
+            assert!(size <= MAX_STATE, "state of {side:?} is {size} entries");
+        }
+    }
+
+    /// Without traffic all connections are timed out, so the state of both managers must be empty
+    pub fn finish(&mut self) {
+        self.in_flight.clear();
+        let mut idle_ms = 0;
+        while idle_ms < IDLE_MS {
+            self.tick(IDLE_STEP_MS);
+            self.in_flight.clear();
+            idle_ms += IDLE_STEP_MS;
+        }
+        for side in [Side::A, Side::B] {
+            let size = self.managers[side.index()].state_size();
+            assert_eq!(size, 0, "state of {side:?} is not cleaned up after idle");
+        }
+    }
+
+    fn tick(&mut self, ms: u64) {
+        self.now_ms += ms;
+        for side in [Side::A, Side::B] {
+            self.managers[side.index()].on_tick(self.now_ms, 0);
+            self.pump(side);
+        }
+    }
+
+    fn input(&mut self, side: Side, input: Input) {
+        self.managers[side.index()].on_input(self.now_ms, input);
+        self.pump(side);
+    }
+
+    fn push_in_flight(&mut self, control: (Side, NetPair, NeighboursControl)) {
+        if self.in_flight.len() >= MAX_IN_FLIGHT {
+            self.in_flight.pop_front();
+        }
+        self.in_flight.push_back(control);
+    }
+
+    fn pump(&mut self, side: Side) {
+        while let Some(out) = self.managers[side.index()].pop_output(self.now_ms) {
+            match out {
+                Output::Control(pair, control) => {
+                    // controls to attacker addresses are dropped
+                    if pair.remote == side.other().addr() {
+                        self.push_in_flight((side.other(), NetPair::new(pair.remote, pair.local), control));
+                    }
+                }
+                Output::Event(ConnectionEvent::Connected(ctx, _)) => self.conns[side.index()].push(ctx.conn),
+                Output::Event(ConnectionEvent::Disconnected(ctx)) => self.conns[side.index()].retain(|conn| *conn != ctx.conn),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{corpus, NeighboursFuzzer};
+
+    #[test]
+    fn run_corpus() {
+        for input in corpus() {
+            NeighboursFuzzer::run(&input);
+        }
+    }
+
+    #[test]
+    fn run_pseudo_random_inputs() {
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        for _ in 0..64 {
+            let input = (0..512)
+                .map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    seed as u8
+                })
+                .collect::<Vec<_>>();
+            NeighboursFuzzer::run(&input);
+        }
+    }
+}
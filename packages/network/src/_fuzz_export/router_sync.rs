@@ -0,0 +1,316 @@
+//! Stateful fuzzer of router sync.
+//!
+//! A router gets direct connections and sync messages from the input, same as the router sync feature, and its deltas are applied
+//! to a shadow router as they are sent to workers. After each op the dump of the router must stay within a budget, which depends
+//! on the number of connections and the largest sync but not on the number of ops, and the shadow router must select the same
+//! next hop as the router for all seen destinations.
+
+use std::{collections::HashMap, sync::Arc};
+
+use atm0s_sdn_identity::{ConnId, NodeId};
+use atm0s_sdn_router::{
+    core::{DestDelta, Metric, RegistryDelta, RegistryDestDelta, Router, RouterDelta, RouterSync, TableDelta},
+    shadow::{ShadowRouter, ShadowRouterDelta, ShadowRouterHistory},
+    RouterTable, ServicePlacement,
+};
+
+use super::FuzzInput;
+
+/// Node of the fuzzed router, other nodes are drawn from the input
+pub const LOCAL_NODE: NodeId = 0x0102_0304;
+/// Number of connections, which bounds the paths of each destination
+pub const MAX_CONNS: u8 = 16;
+/// Max number of ops in one input
+pub const MAX_OPS: usize = 1024;
+/// Dump bytes of a path without hops, a path is kept for each table slot and each service over each connection
+const PATH_BYTES: usize = 32;
+/// Dump bytes of the router without paths
+const BASE_BYTES: usize = 4096;
+
+struct NoHistory;
+
+impl ShadowRouterHistory for NoHistory {
+    fn already_received_broadcast(&self, _from: Option<NodeId>, _service: u8, _seq: u16) -> bool {
+        false
+    }
+
+    fn set_ts(&self, _now: u64) {}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Connection to a node is connected or its metric is changed
+    SetDirect {
+        conn: u8,
+        node: NodeId,
+        latency: u16,
+        bandwidth: u32,
+    },
+    DelDirect {
+        conn: u8,
+    },
+    /// Sync message over a connection, which is decoded same as in router sync feature
+    ApplySync {
+        conn: u8,
+        sync: Vec<u8>,
+    },
+    RegisterService(u8),
+    SetPlacement {
+        service: u8,
+        placement: ServicePlacement,
+    },
+}
+
+impl Op {
+    pub fn read(input: &mut FuzzInput) -> Option<Self> {
+        let op = match input.u8()? % 5 {
+            0 => Op::SetDirect {
+                conn: input.u8()?,
+                node: input.u32()?,
+                latency: input.u16()?,
+                bandwidth: input.u32()?,
+            },
+            1 => Op::DelDirect { conn: input.u8()? },
+            2 => {
+                let conn = input.u8()?;
+                let len = input.u16()? as usize;
+                Op::ApplySync {
+                    conn,
+                    sync: input.bytes(len)?.to_vec(),
+                }
+            }
+            3 => Op::RegisterService(input.u8()?),
+            _ => {
+                let service = input.u8()?;
+                let placement = match input.u8()? % 3 {
+                    0 => ServicePlacement::Any,
+                    1 => ServicePlacement::Geo1(input.u8()?),
+                    _ => ServicePlacement::Geo2(input.u8()?, input.u8()?),
+                };
+                Op::SetPlacement { service, placement }
+            }
+        };
+        Some(op)
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Op::SetDirect { conn, node, latency, bandwidth } => {
+                out.extend([0, *conn]);
+                out.extend(node.to_be_bytes());
+                out.extend(latency.to_be_bytes());
+                out.extend(bandwidth.to_be_bytes());
+            }
+            Op::DelDirect { conn } => out.extend([1, *conn]),
+            Op::ApplySync { conn, sync } => {
+                out.extend([2, *conn]);
+                out.extend((sync.len() as u16).to_be_bytes());
+                out.extend(sync);
+            }
+            Op::RegisterService(service) => out.extend([3, *service]),
+            Op::SetPlacement { service, placement } => {
+                out.extend([4, *service]);
+                match placement {
+                    ServicePlacement::Any => out.push(0),
+                    ServicePlacement::Geo1(zone) => out.extend([1, *zone]),
+                    ServicePlacement::Geo2(zone, sub) => out.extend([2, *zone, *sub]),
+                }
+            }
+        }
+    }
+}
+
+pub fn encode(ops: &[Op]) -> Vec<u8> {
+    let mut out = vec![];
+    for op in ops {
+        op.write(&mut out);
+    }
+    out
+}
+
+/// Inputs with syncs of real routers in each layer, for seeding the corpus
+pub fn corpus() -> Vec<Vec<u8>> {
+    // neighbours in layer 0, 1, 2 and 3 of the local node, each of them knows some other nodes
+    let neighbours = [0x0102_0305, 0x0102_0404, 0x0103_0304, 0x0202_0304];
+    let mut ops = vec![
+        Op::RegisterService(1),
+        Op::SetPlacement {
+            service: 1,
+            placement: ServicePlacement::Geo1(1),
+        },
+    ];
+    for (index, node) in neighbours.iter().enumerate() {
+        let mut remote = Router::new(*node);
+        remote.register_service(2);
+        remote.set_direct(ConnId::from_in(0, 100), Metric::new(5, vec![node ^ 0x10], 10000));
+        remote.set_direct(ConnId::from_in(0, 101), Metric::new(5, vec![node ^ 0x1000], 10000));
+        remote.set_direct(ConnId::from_in(0, 102), Metric::new(5, vec![LOCAL_NODE], 10000));
+        while remote.pop_delta().is_some() {}
+        let conn = index as u8;
+        ops.push(Op::SetDirect {
+            conn,
+            node: *node,
+            latency: 10,
+            bandwidth: 10000,
+        });
+        ops.push(Op::ApplySync {
+            conn,
+            sync: bincode::serialize(&remote.create_sync(LOCAL_NODE)).expect("Should serialize sync"),
+        });
+    }
+    let full = encode(&ops);
+    ops.push(Op::DelDirect { conn: 1 });
+    ops.push(Op::SetDirect {
+        conn: 2,
+        node: neighbours[2],
+        latency: 500,
+        bandwidth: 10,
+    });
+    let changed = encode(&ops);
+    vec![full, changed, encode(&[Op::ApplySync { conn: 0, sync: vec![0; 32] }])]
+}
+
+pub struct RouterSyncFuzzer {
+    router: Router,
+    shadow: ShadowRouter<ConnId>,
+    /// Node of each connection, same as the connections of router sync feature
+    conns: HashMap<ConnId, NodeId>,
+    dests: Vec<NodeId>,
+    /// Largest sync which is applied, paths of a connection are replaced by each sync so they are not larger than it
+    max_sync: usize,
+}
+
+impl Default for RouterSyncFuzzer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RouterSyncFuzzer {
+    pub fn new() -> Self {
+        Self {
+            router: Router::new(LOCAL_NODE),
+            shadow: ShadowRouter::new(LOCAL_NODE, Arc::new(NoHistory)),
+            conns: HashMap::new(),
+            dests: vec![],
+            max_sync: 0,
+        }
+    }
+
+    pub fn run(data: &[u8]) {
+        let mut fuzzer = Self::new();
+        let mut input = FuzzInput::new(data);
+        let mut ops = 0;
+        while let Some(op) = Op::read(&mut input) {
+            fuzzer.apply(op);
+            ops += 1;
+            if ops >= MAX_OPS {
+                break;
+            }
+        }
+    }
+
+    pub fn apply(&mut self, op: Op) {
+        match op {
+            Op::SetDirect { conn, node, latency, bandwidth } => {
+                // local node can't be a neighbour, router sync feature never connects to itself
+                if node != LOCAL_NODE {
+                    let conn = ConnId::from_out(0, (conn % MAX_CONNS) as u64);
+                    if self.conns.insert(conn, node).is_some_and(|old| old != node) {
+                        // connection is re-used for other node, which is the same as disconnect then connect
+                        self.router.del_direct(conn);
+                    }
+                    self.router.set_direct(conn, Metric::new(latency, vec![node], bandwidth));
+                    self.dests.push(node);
+                }
+            }
+            Op::DelDirect { conn } => {
+                let conn = ConnId::from_out(0, (conn % MAX_CONNS) as u64);
+                if self.conns.remove(&conn).is_some() {
+                    self.router.del_direct(conn);
+                }
+            }
+            Op::ApplySync { conn, sync } => {
+                let conn = ConnId::from_out(0, (conn % MAX_CONNS) as u64);
+                if let (Some(node), Ok(decoded)) = (self.conns.get(&conn), bincode::deserialize::<RouterSync>(&sync)) {
+                    self.max_sync = self.max_sync.max(sync.len());
+                    let sync = decoded;
+                    for table in sync.1.iter().flatten() {
+                        self.dests.extend(table.0.iter().flat_map(|(_, metric)| metric.hops.first().copied()));
+                    }
+                    self.router.apply_sync(conn, Metric::new(10, vec![*node], 10000), sync);
+                }
+            }
+            Op::RegisterService(service) => self.router.register_service(service),
+            Op::SetPlacement { service, placement } => self.router.set_service_placement(service, placement),
+        }
+        self.sync_shadow();
+        self.check();
+    }
+
+    /// Apply deltas to the shadow router, with the same conversion as router sync feature
+    fn sync_shadow(&mut self) {
+        while let Some(delta) = self.router.pop_delta() {
+            let delta = match delta {
+                RouterDelta::Table(layer, TableDelta(index, DestDelta::SetBestPath(conn))) => ShadowRouterDelta::SetTable { layer, index, next: conn },
+                RouterDelta::Table(layer, TableDelta(index, DestDelta::DelBestPath)) => ShadowRouterDelta::DelTable { layer, index },
+                RouterDelta::Registry(RegistryDelta::SetServiceLocal(service)) => ShadowRouterDelta::SetServiceLocal { service },
+                RouterDelta::Registry(RegistryDelta::DelServiceLocal(service)) => ShadowRouterDelta::DelServiceLocal { service },
+                RouterDelta::Registry(RegistryDelta::SetServicePlacement(service, placement)) => ShadowRouterDelta::SetServicePlacement { service, placement },
+                RouterDelta::Registry(RegistryDelta::ServiceRemote(service, RegistryDestDelta::SetServicePath(conn, dest, score, load))) => ShadowRouterDelta::SetServiceRemote {
+                    service,
+                    conn,
+                    next: *self.conns.get(&conn).expect("Delta should be of a known connection"),
+                    dest,
+                    score,
+                    load,
+                },
+                RouterDelta::Registry(RegistryDelta::ServiceRemote(service, RegistryDestDelta::DelServicePath(conn))) => ShadowRouterDelta::DelServiceRemote { service, conn },
+                RouterDelta::Observer(node, observer) => ShadowRouterDelta::SetObserver { node, observer },
+            };
+            self.shadow.apply_delta(delta);
+        }
+    }
+
+    fn check(&mut self) {
+        assert!(self.router.size() <= 4 * 256, "router has more slots than its tables");
+        // each synced hop is stored with one more hop of the connection
+        let budget = BASE_BYTES + MAX_CONNS as usize * (5 * 256 * PATH_BYTES + 2 * self.max_sync);
+        let dump = bincode::serialized_size(&self.router.dump()).expect("Should serialize dump") as usize;
+        assert!(dump <= budget, "router dump {dump} bytes is over budget {budget} bytes");
+        self.dests.sort_unstable();
+        self.dests.dedup();
+        for dest in &self.dests {
+            let expected = self.router.next(*dest, &[]).map(|(conn, _)| conn);
+            assert_eq!(self.shadow.next(*dest), expected, "shadow router is out of sync for {dest}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{corpus, RouterSyncFuzzer};
+
+    #[test]
+    fn run_corpus() {
+        for input in corpus() {
+            RouterSyncFuzzer::run(&input);
+        }
+    }
+
+    #[test]
+    fn run_pseudo_random_inputs() {
+        let mut seed = 0x9e37_79b9_7f4a_7c15u64;
+        for _ in 0..64 {
+            let input = (0..512)
+                .map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    seed as u8
+                })
+                .collect::<Vec<_>>();
+            RouterSyncFuzzer::run(&input);
+        }
+    }
+}
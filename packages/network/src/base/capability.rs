@@ -0,0 +1,263 @@
+//! Capability flags which are negotiated with each neighbour after connected.
+//!
+//! During a rolling upgrade nodes with different builds are connected together. Instead of sending messages which the
+//! other side cannot deserialize, each side advertises its protocol version and capability flags, and new behaviours
+//! are only used with neighbours which support them. Neighbours which never answer are treated as [`PeerCapabilities::LEGACY`].
+//!
+//! Only extensions which are exchanged with a neighbour are negotiated, because capabilities are known per connection.
+//! Messages which are routed over several hops to a remote node (dht_kv ttl, batch, filtered subscriptions and acl,
+//! pubsub replay and channel range, alias reverse index, rpc) are not advertised, bits 1, 2, 4 and 6..=10 were used for
+//! them by earlier builds and stay reserved. Those extensions need all nodes upgraded, a remote node of an older build
+//! drops them as undecodable messages.
+//!
+//! Data planes of the node report their capabilities to the controller when started, the controller reports a skew and
+//! only hands capabilities which all workers support to the data planes.
+
+use std::fmt::Display;
+
+use atm0s_sdn_identity::NodeId;
+use serde::{Deserialize, Serialize};
+
+/// Protocol version of this build, it is increased when capabilities are added
+pub const PROTOCOL_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    pub const EMPTY: Self = Self(0);
+    /// Constrained link framing, negotiated with `LinkProfile`
+    pub const LINK_FRAMING: Self = Self(1 << 0);
+    /// pubsub XOR FEC groups, relays send plain data without parity to neighbours which don't support it
+    pub const PUBSUB_FEC: Self = Self(1 << 3);
+    /// nat_traversal feature
+    pub const NAT_TRAVERSAL: Self = Self(1 << 5);
+    /// Decoding of compressed feature payloads with the built-in codec
+    pub const PAYLOAD_COMPRESSION: Self = Self(1 << 11);
+    /// Periodic session rekeying with `Rekey`, `RekeyAck` and `RekeyDone`
+    pub const REKEY: Self = Self(1 << 12);
+    /// Sequenced encrypted packets which are validated against a replay window
+    pub const REPLAY_PROTECTION: Self = Self(1 << 13);
+    /// membership feature
+    pub const MEMBERSHIP: Self = Self(1 << 14);
+    /// Reassembly of large messages which are fragmented on standard links
+    pub const FRAGMENTATION: Self = Self(1 << 15);
+    /// pubsub `SubAuth` with the token of the subscriber, plain `Sub` is sent to neighbours which don't support it
+    pub const PUBSUB_SUB_AUTH: Self = Self(1 << 16);
+    /// Loads of service instances which are appended to router sync for weighted anycast
+    pub const SERVICE_LOAD: Self = Self(1 << 17);
+    /// Load of the sender as a pubsub relay which is appended to router sync
+    pub const RELAY_LOAD: Self = Self(1 << 18);
+    /// All capabilities which are supported by this build
+    pub const SUPPORTED: Self = Self(0b111_1111_1000_0010_1001);
+
+    const NAMES: [(Self, &'static str); 11] = [
+        (Self::LINK_FRAMING, "link_framing"),
+        (Self::PUBSUB_FEC, "pubsub_fec"),
+        (Self::NAT_TRAVERSAL, "nat_traversal"),
+        (Self::PAYLOAD_COMPRESSION, "payload_compression"),
+        (Self::REKEY, "rekey"),
+        (Self::REPLAY_PROTECTION, "replay_protection"),
+        (Self::MEMBERSHIP, "membership"),
+        (Self::FRAGMENTATION, "fragmentation"),
+        (Self::PUBSUB_SUB_AUTH, "pubsub_sub_auth"),
+        (Self::SERVICE_LOAD, "service_load"),
+        (Self::RELAY_LOAD, "relay_load"),
+    ];
+
+    /// Unknown bits from newer builds are kept, so they can be reported
+    pub fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn intersection(&self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// Capabilities of self which are missing in other
+    pub fn difference(&self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    /// Names of known flags, unknown bits are shown as `bit<N>`
+    pub fn names(&self) -> Vec<String> {
+        let mut names = vec![];
+        for bit in 0..64 {
+            let flag = Self(1 << bit);
+            if !self.contains(flag) {
+                continue;
+            }
+            match Self::NAMES.iter().find(|(known, _)| *known == flag) {
+                Some((_, name)) => names.push(name.to_string()),
+                None => names.push(format!("bit{bit}")),
+            }
+        }
+        names
+    }
+}
+
+impl Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            write!(f, "-")
+        } else {
+            write!(f, "{}", self.names().join(","))
+        }
+    }
+}
+
+/// Protocol version and capabilities which a node advertises
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCapabilities {
+    pub version: u16,
+    pub capabilities: Capabilities,
+}
+
+impl PeerCapabilities {
+    /// Neighbour which doesn't negotiate capabilities, only the original protocol is used with it
+    pub const LEGACY: Self = Self {
+        version: 0,
+        capabilities: Capabilities::EMPTY,
+    };
+
+    /// Capabilities of this build, reduced by the configured ones
+    pub fn local(capabilities: Capabilities) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities: capabilities.intersection(Capabilities::SUPPORTED),
+        }
+    }
+
+    /// Capabilities which both sides can use
+    pub fn agreed(&self, remote: &Self) -> Capabilities {
+        self.capabilities.intersection(remote.capabilities)
+    }
+}
+
+/// Report of a neighbour which runs a different version or a different capability set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapabilitySkew {
+    pub node: NodeId,
+    /// Set when the skew is between the controller and a worker of the local node
+    pub worker: Option<u16>,
+    pub local_version: u16,
+    pub remote_version: u16,
+    /// Local capabilities which are disabled with this neighbour
+    pub disabled: Capabilities,
+    /// Remote capabilities which this node doesn't use
+    pub remote_only: Capabilities,
+}
+
+impl CapabilitySkew {
+    pub fn detect(node: NodeId, local: &PeerCapabilities, remote: &PeerCapabilities) -> Option<Self> {
+        if local == remote {
+            return None;
+        }
+        Some(Self {
+            node,
+            worker: None,
+            local_version: local.version,
+            remote_version: remote.version,
+            disabled: local.capabilities.difference(remote.capabilities),
+            remote_only: remote.capabilities.difference(local.capabilities),
+        })
+    }
+
+    /// Worker of the local node which doesn't support all capabilities of the controller.
+    /// A worker with more capabilities is not a skew, extra ones are never agreed with neighbours.
+    pub fn detect_worker(node: NodeId, worker: u16, controller: &PeerCapabilities, reported: &PeerCapabilities) -> Option<Self> {
+        if controller.version == reported.version && reported.capabilities.contains(controller.capabilities) {
+            return None;
+        }
+        Some(Self {
+            worker: Some(worker),
+            ..Self::detect(node, controller, reported)?
+        })
+    }
+}
+
+impl Display for CapabilitySkew {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.worker {
+            Some(worker) => write!(
+                f,
+                "worker {} runs protocol v{} (controller v{}), disabled with it: {}, worker only: {}",
+                worker, self.remote_version, self.local_version, self.disabled, self.remote_only
+            ),
+            None => write!(
+                f,
+                "node {} runs protocol v{} (local v{}), disabled with it: {}, remote only: {}",
+                self.node, self.remote_version, self.local_version, self.disabled, self.remote_only
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Capabilities, CapabilitySkew, PeerCapabilities, PROTOCOL_VERSION};
+
+    #[test]
+    fn capability_names_with_unknown_bits() {
+        let caps = Capabilities::PUBSUB_FEC.intersection(Capabilities::SUPPORTED);
+        assert_eq!(caps.names(), vec!["pubsub_fec".to_string()]);
+        // reserved bits of retired capabilities are never advertised
+        assert_eq!(Capabilities::from_bits(1 << 1).intersection(Capabilities::SUPPORTED), Capabilities::EMPTY);
+        let newer = Capabilities::from_bits(Capabilities::LINK_FRAMING.bits() | 1 << 40);
+        assert_eq!(newer.to_string(), "link_framing,bit40");
+        assert_eq!(Capabilities::EMPTY.to_string(), "-");
+    }
+
+    #[test]
+    fn detect_skew_with_legacy_and_newer_nodes() {
+        let local = PeerCapabilities::local(Capabilities::SUPPORTED);
+        assert_eq!(CapabilitySkew::detect(2, &local, &local), None);
+
+        let skew = CapabilitySkew::detect(2, &local, &PeerCapabilities::LEGACY).expect("Should have skew");
+        assert_eq!(skew.remote_version, 0);
+        assert_eq!(skew.disabled, Capabilities::SUPPORTED);
+        assert_eq!(skew.remote_only, Capabilities::EMPTY);
+
+        let newer = PeerCapabilities {
+            version: PROTOCOL_VERSION + 1,
+            capabilities: Capabilities::from_bits(1 << 40 | Capabilities::LINK_FRAMING.bits()),
+        };
+        let skew = CapabilitySkew::detect(3, &local, &newer).expect("Should have skew");
+        assert_eq!(local.agreed(&newer), Capabilities::LINK_FRAMING);
+        assert_eq!(skew.remote_only.to_string(), "bit40");
+        assert_eq!(
+            skew.to_string(),
+            format!(
+                "node 3 runs protocol v{} (local v{PROTOCOL_VERSION}), disabled with it: pubsub_fec,nat_traversal,payload_compression,rekey,replay_protection,membership,fragmentation,pubsub_sub_auth,service_load,relay_load, remote only: bit40",
+                PROTOCOL_VERSION + 1
+            )
+        );
+    }
+
+    #[test]
+    fn detect_skew_with_worker() {
+        let controller = PeerCapabilities::local(Capabilities::SUPPORTED);
+        assert_eq!(CapabilitySkew::detect_worker(1, 0, &controller, &controller), None);
+
+        // worker with more capabilities than the controller is not a skew
+        let restricted = PeerCapabilities::local(Capabilities::LINK_FRAMING);
+        assert_eq!(CapabilitySkew::detect_worker(1, 0, &restricted, &controller), None);
+
+        let skew = CapabilitySkew::detect_worker(1, 2, &controller, &restricted).expect("Should have skew");
+        assert_eq!(skew.worker, Some(2));
+        assert_eq!(skew.disabled, Capabilities::SUPPORTED.difference(Capabilities::LINK_FRAMING));
+        assert!(skew.to_string().starts_with("worker 2 runs protocol"));
+    }
+}
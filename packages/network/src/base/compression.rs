@@ -0,0 +1,399 @@
+//! Payload compression which features can opt into for their own messages.
+//!
+//! Compression is applied to the serialized payload of a feature, before it is handed to the router, so relays forward
+//! compressed bytes without touching them. A compressed payload is wrapped in a small envelope:
+//!
+//! ```text
+//! [MAGIC 0xC7][codec id][original len u32 LE][compressed data]
+//! ```
+//!
+//! Payloads are only compressed when they are bigger than the threshold, when the destination is known to decode envelopes
+//! and when the compressed form saves at least 1/8 of the size, so payloads which are already compressed (media, images) are sent raw.
+//! A destination supports envelopes when it is a neighbour which advertises [`Capabilities::PAYLOAD_COMPRESSION`], when it
+//! already sent an envelope to this node, or when the config assumes that all nodes support it.
+//!
+//! Features must only use it for payloads which never start with [`COMPRESSED_MAGIC`], like bincode enums with
+//! less than 199 variants, because raw payloads are sent without any header.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashSet},
+    fmt::Debug,
+    sync::Arc,
+};
+
+use atm0s_sdn_identity::NodeId;
+use parking_lot::Mutex;
+
+use super::Capabilities;
+
+/// First byte of a compressed payload
+pub const COMPRESSED_MAGIC: u8 = 0xC7;
+/// Payloads which are smaller than this are always sent raw
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 256;
+/// Envelopes which claim a bigger original size are dropped, which protects against decompression bombs
+pub const MAX_DECOMPRESSED_LEN: usize = 4 * 1024 * 1024;
+
+const ENVELOPE_HEADER_LEN: usize = 6;
+/// Envelope must save at least 1/MIN_SAVING_DIV of the payload, otherwise decoding isn't worth the few saved bytes
+const MIN_SAVING_DIV: usize = 8;
+
+/// Compression algorithm of the payload. Each codec has an unique id, which is written in the envelope, so the receiver
+/// can decode it. The built-in [`LzCodec`] is always available for decoding, custom codecs must be configured in all nodes.
+pub trait PayloadCodec: Send + Sync {
+    fn id(&self) -> u8;
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+    /// Return None if the data is invalid or isn't decoded to exactly `len` bytes
+    fn decompress(&self, data: &[u8], len: usize) -> Option<Vec<u8>>;
+}
+
+/// Dependency-free LZ77 codec which is fast enough for control messages.
+///
+/// The stream is a list of tokens: a control byte `0x00..=0x7F` is followed by 1..=128 literal bytes, a control byte
+/// `0x80 | (len - 4)` is a match of 4..=131 bytes which starts at the u16 LE offset before the current position.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LzCodec;
+
+impl LzCodec {
+    pub const ID: u8 = 1;
+
+    const MIN_MATCH: usize = 4;
+    const MAX_MATCH: usize = 131;
+    const MAX_LITERALS: usize = 128;
+    const HASH_BITS: u32 = 12;
+
+    fn hash(bytes: &[u8]) -> usize {
+        let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        (value.wrapping_mul(2654435761) >> (32 - Self::HASH_BITS)) as usize
+    }
+
+    fn push_literals(out: &mut Vec<u8>, literals: &[u8]) {
+        for chunk in literals.chunks(Self::MAX_LITERALS) {
+            out.push((chunk.len() - 1) as u8);
+            out.extend_from_slice(chunk);
+        }
+    }
+}
+
+impl PayloadCodec for LzCodec {
+    fn id(&self) -> u8 {
+        Self::ID
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        let mut table = vec![usize::MAX; 1 << Self::HASH_BITS];
+        let mut pos = 0;
+        let mut literal_start = 0;
+        while pos + Self::MIN_MATCH <= data.len() {
+            let slot = &mut table[Self::hash(&data[pos..])];
+            let candidate = *slot;
+            *slot = pos;
+            if candidate != usize::MAX && pos - candidate <= u16::MAX as usize && data[candidate..candidate + Self::MIN_MATCH] == data[pos..pos + Self::MIN_MATCH] {
+                let mut len = Self::MIN_MATCH;
+                while len < Self::MAX_MATCH && pos + len < data.len() && data[candidate + len] == data[pos + len] {
+                    len += 1;
+                }
+                Self::push_literals(&mut out, &data[literal_start..pos]);
+                out.push(0x80 | (len - Self::MIN_MATCH) as u8);
+                out.extend_from_slice(&((pos - candidate) as u16).to_le_bytes());
+                pos += len;
+                literal_start = pos;
+            } else {
+                pos += 1;
+            }
+        }
+        Self::push_literals(&mut out, &data[literal_start..]);
+        out
+    }
+
+    fn decompress(&self, data: &[u8], len: usize) -> Option<Vec<u8>> {
+        let mut out = Vec::with_capacity(len);
+        let mut pos = 0;
+        while pos < data.len() {
+            let control = data[pos] as usize;
+            pos += 1;
+            if control < 0x80 {
+                let literals = data.get(pos..pos + control + 1)?;
+                if out.len() + literals.len() > len {
+                    return None;
+                }
+                out.extend_from_slice(literals);
+                pos += literals.len();
+            } else {
+                let match_len = (control & 0x7F) + Self::MIN_MATCH;
+                let offset = u16::from_le_bytes(data.get(pos..pos + 2)?.try_into().ok()?) as usize;
+                pos += 2;
+                if offset == 0 || offset > out.len() || out.len() + match_len > len {
+                    return None;
+                }
+                // match can overlap with itself, so it is copied byte by byte
+                let start = out.len() - offset;
+                for i in 0..match_len {
+                    out.push(out[start + i]);
+                }
+            }
+        }
+        (out.len() == len).then_some(out)
+    }
+}
+
+/// Compression counters of a feature
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    /// Sent payloads which are compressed
+    pub compressed_msgs: u64,
+    /// Original bytes of compressed payloads
+    pub raw_bytes: u64,
+    /// Bytes of envelopes which are sent instead
+    pub compressed_bytes: u64,
+    /// Payloads over the threshold which are sent raw, because they are incompressible or the destination doesn't support it
+    pub skipped_msgs: u64,
+    /// Received envelopes which are decoded
+    pub decompressed_msgs: u64,
+}
+
+impl CompressionStats {
+    /// Compressed bytes over original bytes, lower is better. None if nothing is compressed yet
+    pub fn ratio(&self) -> Option<f64> {
+        (self.raw_bytes > 0).then(|| self.compressed_bytes as f64 / self.raw_bytes as f64)
+    }
+}
+
+/// Compression settings which are shared by all features of a node. Stats are collected per feature name.
+#[derive(Clone)]
+pub struct CompressionConfig {
+    codec: Arc<dyn PayloadCodec>,
+    threshold: usize,
+    assume_supported: bool,
+    stats: Arc<Mutex<BTreeMap<&'static str, CompressionStats>>>,
+}
+
+impl CompressionConfig {
+    pub fn new(codec: Arc<dyn PayloadCodec>, threshold: usize) -> Self {
+        Self {
+            codec,
+            threshold,
+            assume_supported: false,
+            stats: Default::default(),
+        }
+    }
+
+    /// Compress payloads to destinations which are not negotiated yet, like keys, services or broadcasts.
+    /// It should be enabled only when all nodes of the network run a build which supports compression
+    pub fn with_assume_supported(mut self, assume_supported: bool) -> Self {
+        self.assume_supported = assume_supported;
+        self
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    /// Snapshot of counters of all features which use this config
+    pub fn stats(&self) -> BTreeMap<&'static str, CompressionStats> {
+        self.stats.lock().clone()
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self::new(Arc::new(LzCodec), DEFAULT_COMPRESSION_THRESHOLD)
+    }
+}
+
+impl Debug for CompressionConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CompressionConfig")
+            .field("codec", &self.codec.id())
+            .field("threshold", &self.threshold)
+            .field("assume_supported", &self.assume_supported)
+            .finish()
+    }
+}
+
+/// Per-feature state of payload compression, which tracks the destinations that can decode envelopes.
+/// Without config, payloads are always sent raw but received envelopes of the built-in codec are still decoded.
+pub struct PayloadCompressor {
+    feature: &'static str,
+    cfg: Option<CompressionConfig>,
+    peers: HashSet<NodeId>,
+}
+
+impl PayloadCompressor {
+    pub fn new(feature: &'static str, cfg: Option<CompressionConfig>) -> Self {
+        Self { feature, cfg, peers: HashSet::new() }
+    }
+
+    /// Update the support of a neighbour from its negotiated capabilities
+    pub fn on_peer_capabilities(&mut self, node: NodeId, capabilities: Capabilities) {
+        if capabilities.contains(Capabilities::PAYLOAD_COMPRESSION) {
+            self.peers.insert(node);
+        } else {
+            self.peers.remove(&node);
+        }
+    }
+
+    /// Destination can decode envelopes, None is a destination which is not a single known node
+    pub fn supports(&self, dest: Option<NodeId>) -> bool {
+        match &self.cfg {
+            Some(cfg) => cfg.assume_supported || dest.is_some_and(|node| self.peers.contains(&node)),
+            None => false,
+        }
+    }
+
+    /// Wrap the payload in a compressed envelope if it is worth it, otherwise return it unchanged
+    pub fn encode(&mut self, dest: Option<NodeId>, buf: Vec<u8>) -> Vec<u8> {
+        let cfg = match &self.cfg {
+            Some(cfg) if buf.len() >= cfg.threshold && buf.len() <= MAX_DECOMPRESSED_LEN => cfg,
+            _ => return buf,
+        };
+        debug_assert_ne!(buf.first(), Some(&COMPRESSED_MAGIC), "raw payload must not start with compression magic");
+        let supported = cfg.assume_supported || dest.is_some_and(|node| self.peers.contains(&node));
+        let compressed = supported
+            .then(|| cfg.codec.compress(&buf))
+            .filter(|c| c.len() + ENVELOPE_HEADER_LEN <= buf.len() - buf.len() / MIN_SAVING_DIV);
+        let mut stats = cfg.stats.lock();
+        let stats = stats.entry(self.feature).or_default();
+        match compressed {
+            Some(compressed) => {
+                let mut out = Vec::with_capacity(compressed.len() + ENVELOPE_HEADER_LEN);
+                out.push(COMPRESSED_MAGIC);
+                out.push(cfg.codec.id());
+                out.extend_from_slice(&(buf.len() as u32).to_le_bytes());
+                out.extend_from_slice(&compressed);
+                stats.compressed_msgs += 1;
+                stats.raw_bytes += buf.len() as u64;
+                stats.compressed_bytes += out.len() as u64;
+                out
+            }
+            None => {
+                stats.skipped_msgs += 1;
+                buf
+            }
+        }
+    }
+
+    /// Unwrap a received payload, raw payloads are returned as is. None if the envelope can't be decoded.
+    /// The sender of an envelope is remembered as supporting compression
+    pub fn decode<'a>(&mut self, from: Option<NodeId>, buf: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        if buf.first() != Some(&COMPRESSED_MAGIC) {
+            return Some(Cow::Borrowed(buf));
+        }
+        if buf.len() < ENVELOPE_HEADER_LEN {
+            log::warn!("[PayloadCompressor {}] envelope too short {}", self.feature, buf.len());
+            return None;
+        }
+        let codec_id = buf[1];
+        let len = u32::from_le_bytes([buf[2], buf[3], buf[4], buf[5]]) as usize;
+        if len > MAX_DECOMPRESSED_LEN {
+            log::warn!("[PayloadCompressor {}] reject envelope with too big len {len}", self.feature);
+            return None;
+        }
+        let data = &buf[ENVELOPE_HEADER_LEN..];
+        let decoded = match &self.cfg {
+            Some(cfg) if cfg.codec.id() == codec_id => cfg.codec.decompress(data, len),
+            _ if codec_id == LzCodec::ID => LzCodec.decompress(data, len),
+            _ => {
+                log::warn!("[PayloadCompressor {}] unknown codec {codec_id}", self.feature);
+                return None;
+            }
+        };
+        let Some(decoded) = decoded else {
+            log::warn!("[PayloadCompressor {}] invalid envelope of codec {codec_id}", self.feature);
+            return None;
+        };
+        if let Some(node) = from {
+            self.peers.insert(node);
+        }
+        if let Some(cfg) = &self.cfg {
+            cfg.stats.lock().entry(self.feature).or_default().decompressed_msgs += 1;
+        }
+        Some(Cow::Owned(decoded))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::base::Capabilities;
+
+    use super::{CompressionConfig, LzCodec, PayloadCodec, PayloadCompressor, COMPRESSED_MAGIC};
+
+    fn compressible(len: usize) -> Vec<u8> {
+        (0..len).map(|i| b"atm0s-sdn payload "[i % 18]).collect()
+    }
+
+    fn incompressible(len: usize) -> Vec<u8> {
+        let mut state = 0x1234_5678u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lz_codec_roundtrip() {
+        for data in [vec![], vec![1], compressible(5), compressible(10_000), incompressible(1000), vec![7; 1000]] {
+            let compressed = LzCodec.compress(&data);
+            assert_eq!(LzCodec.decompress(&compressed, data.len()), Some(data.clone()));
+        }
+        assert!(LzCodec.compress(&compressible(10_000)).len() < 1000);
+        // wrong len or truncated data is rejected
+        let compressed = LzCodec.compress(&compressible(1000));
+        assert_eq!(LzCodec.decompress(&compressed, 999), None);
+        assert_eq!(LzCodec.decompress(&compressed[..compressed.len() - 1], 1000), None);
+        assert_eq!(LzCodec.decompress(&[0x80, 1, 0], 4), None);
+    }
+
+    #[test]
+    fn compress_only_supported_and_worth_it() {
+        let cfg = CompressionConfig::new(Arc::new(LzCodec), 100);
+        let mut sender = PayloadCompressor::new("test", Some(cfg.clone()));
+        let mut receiver = PayloadCompressor::new("test", None);
+
+        // small payload and unknown destination are sent raw
+        assert_eq!(sender.encode(Some(2), compressible(50)), compressible(50));
+        assert_eq!(sender.encode(Some(2), compressible(500)), compressible(500));
+
+        sender.on_peer_capabilities(2, Capabilities::SUPPORTED);
+        let envelope = sender.encode(Some(2), compressible(500));
+        assert_eq!(envelope[0], COMPRESSED_MAGIC);
+        assert!(envelope.len() < 100);
+        assert_eq!(receiver.decode(Some(1), &envelope).expect("Should decode").as_ref(), compressible(500).as_slice());
+        // already compressed payload is skipped
+        assert_eq!(sender.encode(Some(2), incompressible(500)), incompressible(500));
+
+        let stats = cfg.stats()["test"];
+        assert_eq!(stats.compressed_msgs, 1);
+        assert_eq!(stats.raw_bytes, 500);
+        assert_eq!(stats.compressed_bytes, envelope.len() as u64);
+        assert_eq!(stats.skipped_msgs, 2);
+        assert!(stats.ratio().expect("Should have ratio") < 0.2);
+
+        sender.on_peer_capabilities(2, Capabilities::EMPTY);
+        assert!(!sender.supports(Some(2)));
+    }
+
+    #[test]
+    fn learn_support_from_received_envelope() {
+        let mut node1 = PayloadCompressor::new("test", Some(CompressionConfig::new(Arc::new(LzCodec), 100).with_assume_supported(true)));
+        let mut node2 = PayloadCompressor::new("test", Some(CompressionConfig::new(Arc::new(LzCodec), 100)));
+        assert!(!node2.supports(Some(1)));
+
+        let envelope = node1.encode(None, compressible(500));
+        assert_eq!(node2.decode(Some(1), &envelope).expect("Should decode").as_ref(), compressible(500).as_slice());
+        assert!(node2.supports(Some(1)));
+
+        // raw payload is passed through, invalid envelopes are dropped
+        assert_eq!(node2.decode(Some(1), &[1, 2, 3]).expect("Should decode").as_ref(), &[1, 2, 3]);
+        assert_eq!(node2.decode(Some(1), &[COMPRESSED_MAGIC, 1]), None);
+        assert_eq!(node2.decode(Some(1), &[COMPRESSED_MAGIC, 99, 4, 0, 0, 0, 3, 1, 2, 3, 4]), None);
+        assert_eq!(node2.decode(Some(1), &[COMPRESSED_MAGIC, 1, 0, 0, 0, 0x10, 0]), None);
+    }
+}
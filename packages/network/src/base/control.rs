@@ -12,6 +12,8 @@ pub enum NeighboursConnectError {
     InvalidSignature,
     InvalidData,
     InvalidState,
+    /// Connection is not admitted by the neighbour policy
+    Rejected,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
@@ -22,12 +24,92 @@ pub enum NeighboursDisconnectReason {
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum NeighboursControlCmds {
-    ConnectRequest { to: NodeId, session: u64, handshake: Vec<u8> },
-    ConnectResponse { session: u64, result: Result<Vec<u8>, NeighboursConnectError> },
-    Ping { session: u64, seq: u64, sent_ms: u64 },
-    Pong { session: u64, seq: u64, sent_ms: u64 },
-    DisconnectRequest { session: u64, reason: NeighboursDisconnectReason },
-    DisconnectResponse { session: u64 },
+    ConnectRequest {
+        to: NodeId,
+        session: u64,
+        handshake: Vec<u8>,
+    },
+    ConnectResponse {
+        session: u64,
+        result: Result<Vec<u8>, NeighboursConnectError>,
+    },
+    Ping {
+        session: u64,
+        seq: u64,
+        sent_ms: u64,
+    },
+    Pong {
+        session: u64,
+        seq: u64,
+        sent_ms: u64,
+    },
+    DisconnectRequest {
+        session: u64,
+        reason: NeighboursDisconnectReason,
+    },
+    DisconnectResponse {
+        session: u64,
+    },
+    /// Request constrained link framing with the max frame size of sender, it is sent after connected
+    LinkProfile {
+        session: u64,
+        mtu: u16,
+    },
+    /// Agreed max frame size, which is the smaller one of two sides
+    LinkProfileAck {
+        session: u64,
+        mtu: u16,
+    },
+    /// Protocol version and capability flags of sender, it is sent after connected
+    Capabilities {
+        session: u64,
+        version: u16,
+        flags: u64,
+    },
+    /// Answer of Capabilities with the version and flags of the responder
+    CapabilitiesAck {
+        session: u64,
+        version: u16,
+        flags: u64,
+    },
+    /// New ephemeral handshake request of the outgoing side, which starts the key epoch
+    Rekey {
+        session: u64,
+        epoch: u32,
+        handshake: Vec<u8>,
+    },
+    /// Handshake response of a rekey, the responder already decrypts with the new keys but still encrypts with the old ones
+    RekeyAck {
+        session: u64,
+        epoch: u32,
+        handshake: Vec<u8>,
+    },
+    /// Requester switched to the new keys, the responder can encrypt with them now
+    RekeyDone {
+        session: u64,
+        epoch: u32,
+    },
+}
+
+impl NeighboursControlCmds {
+    /// Session of the connection which the command belongs to
+    pub fn session(&self) -> u64 {
+        match self {
+            Self::ConnectRequest { session, .. }
+            | Self::ConnectResponse { session, .. }
+            | Self::Ping { session, .. }
+            | Self::Pong { session, .. }
+            | Self::DisconnectRequest { session, .. }
+            | Self::DisconnectResponse { session }
+            | Self::LinkProfile { session, .. }
+            | Self::LinkProfileAck { session, .. }
+            | Self::Capabilities { session, .. }
+            | Self::CapabilitiesAck { session, .. }
+            | Self::Rekey { session, .. }
+            | Self::RekeyAck { session, .. }
+            | Self::RekeyDone { session, .. } => *session,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -1,10 +1,43 @@
+use std::collections::HashMap;
+
 use atm0s_sdn_identity::{ConnId, NodeAddr, NodeId};
 use atm0s_sdn_router::{shadow::ShadowRouter, RouteRule};
 use sans_io_runtime::TaskSwitcherChild;
 
 use crate::data_plane::NetPair;
 
-use super::{Buffer, ConnectionCtx, ConnectionEvent, ServiceId, TransportMsgHeader, Ttl};
+use super::{Buffer, Capabilities, ConnectionCtx, ConnectionEvent, ServiceId, TransportMsgHeader, Ttl, DEFAULT_MSG_TTL};
+
+/// How an incoming message reached this node, which is only collected when it is enabled in data plane config
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IncomingRoute {
+    /// Connection which the message is received from
+    pub conn: ConnId,
+    /// Local and remote addresses of the connection, the remote address can be used for geo lookup
+    pub pair: NetPair,
+    /// Links which the message went through, it is estimated with the default ttl so it is not correct if the sender changed ttl
+    pub hops: u8,
+    /// Delivered over the service broadcast tree
+    pub broadcast: bool,
+    /// Forwarded by other nodes instead of received directly from the source
+    pub relayed: bool,
+}
+
+impl IncomingRoute {
+    pub fn new(conn: ConnId, pair: NetPair, remote: NodeId, header: &TransportMsgHeader, broadcast: bool) -> Self {
+        let hops = DEFAULT_MSG_TTL.saturating_sub(header.ttl).saturating_add(1);
+        Self {
+            conn,
+            pair,
+            hops,
+            broadcast,
+            relayed: match header.from_node {
+                Some(source) => source != remote,
+                None => hops > 1,
+            },
+        }
+    }
+}
 
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct NetIncomingMeta {
@@ -12,11 +45,19 @@ pub struct NetIncomingMeta {
     pub ttl: Ttl,
     pub meta: u8,
     pub secure: bool,
+    /// Only set for messages from the network when incoming route is enabled
+    pub route: Option<IncomingRoute>,
 }
 
 impl NetIncomingMeta {
     pub fn new(source: Option<NodeId>, ttl: Ttl, meta: u8, secure: bool) -> Self {
-        Self { source, ttl, meta, secure }
+        Self {
+            source,
+            ttl,
+            meta,
+            secure,
+            route: None,
+        }
     }
 }
 
@@ -27,21 +68,46 @@ impl From<&TransportMsgHeader> for NetIncomingMeta {
             ttl: Ttl(value.ttl),
             meta: value.meta,
             secure: value.encrypt,
+            route: None,
         }
     }
 }
 
+/// Priority class of an outgoing message, it only takes effect on connections with a bandwidth scheduler.
+/// Control messages are never queued, other classes are queued separately and released in strict order.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MsgPriority {
+    Control,
+    High,
+    #[default]
+    Normal,
+    Bulk,
+}
+
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct NetOutgoingMeta {
     pub source: bool,
     pub ttl: Ttl,
     pub meta: u8,
     pub secure: bool,
+    /// Optional dedup token, the destination drops messages with the same (feature, source, token) received again in a short time.
+    /// This is useful with ToKey routing, which can deliver a message twice via different closest nodes while the network is converging.
+    /// Setting it implies source, because the token is scoped by sender.
+    pub dedup: Option<u32>,
+    /// Priority class in the bandwidth scheduler of the first hop, relayed messages are always Normal
+    pub priority: MsgPriority,
 }
 
 impl NetOutgoingMeta {
     pub fn new(source: bool, ttl: Ttl, meta: u8, secure: bool) -> Self {
-        Self { source, ttl, meta, secure }
+        Self {
+            source,
+            ttl,
+            meta,
+            secure,
+            dedup: None,
+            priority: MsgPriority::Normal,
+        }
     }
 
     pub fn secure() -> Self {
@@ -50,9 +116,22 @@ impl NetOutgoingMeta {
             ttl: Ttl::default(),
             meta: 0,
             secure: true,
+            dedup: None,
+            priority: MsgPriority::Normal,
         }
     }
 
+    pub fn with_dedup(mut self, token: u32) -> Self {
+        self.source = true;
+        self.dedup = Some(token);
+        self
+    }
+
+    pub fn with_priority(mut self, priority: MsgPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
     pub fn to_header(&self, feature: u8, rule: RouteRule, node_id: NodeId) -> TransportMsgHeader {
         TransportMsgHeader::build(feature, self.meta, rule)
             .set_ttl(*self.ttl)
@@ -62,6 +141,7 @@ impl NetOutgoingMeta {
                 None
             })
             .set_encrypt(self.secure)
+            .set_dedup(self.dedup)
     }
 
     pub fn to_incoming(&self, node_id: NodeId) -> NetIncomingMeta {
@@ -74,10 +154,27 @@ impl NetOutgoingMeta {
             ttl: self.ttl,
             meta: self.meta,
             secure: self.secure,
+            route: None,
         }
     }
 }
 
+/// Common error kinds which are reported by features in their events.
+/// This allows applications to implement generic retry or alarm handling without knowing each feature's event.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum FeatureError {
+    /// Destination cannot be found, like no source for a channel or no owner for an alias
+    Unreachable,
+    /// Request did not complete in time
+    Timeout,
+    /// Request is not allowed, like binding a used port or controlling a resource owned by other actor
+    Rejected,
+    /// Data cannot be encoded or decoded
+    Serialization,
+    /// Resource is exhausted, like a full queue
+    Overload,
+}
+
 #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
 pub enum FeatureControlActor<UserData> {
     Controller(UserData),
@@ -121,6 +218,9 @@ pub enum FeatureOutput<UserData, Event, ToWorker> {
     SendRoute(RouteRule, NetOutgoingMeta, Buffer),
     NeighboursConnectTo(NodeAddr),
     NeighboursDisconnectFrom(NodeId),
+    NeighboursRestart(ConnId),
+    /// Load of this node as a relay (0 is idle, 255 is fully loaded), which is advertised to neighbours by router_sync
+    RouterSyncRelayLoad(u8),
     OnResourceEmpty,
 }
 
@@ -138,6 +238,8 @@ impl<UserData, Event, ToWorker> FeatureOutput<UserData, Event, ToWorker> {
             FeatureOutput::SendRoute(rule, ttl, buf) => FeatureOutput::SendRoute(rule, ttl, buf),
             FeatureOutput::NeighboursConnectTo(addr) => FeatureOutput::NeighboursConnectTo(addr),
             FeatureOutput::NeighboursDisconnectFrom(id) => FeatureOutput::NeighboursDisconnectFrom(id),
+            FeatureOutput::NeighboursRestart(conn) => FeatureOutput::NeighboursRestart(conn),
+            FeatureOutput::RouterSyncRelayLoad(load) => FeatureOutput::RouterSyncRelayLoad(load),
             FeatureOutput::OnResourceEmpty => FeatureOutput::OnResourceEmpty,
         }
     }
@@ -212,14 +314,19 @@ impl<UserData, Control, Event, ToController> FeatureWorkerOutput<UserData, Contr
 pub struct FeatureWorkerContext {
     pub node_id: NodeId,
     pub router: ShadowRouter<NetPair>,
+    /// Capabilities which are agreed with the neighbour of each pinned connection, missing until negotiated
+    pub peer_caps: HashMap<NetPair, Capabilities>,
 }
 
 pub trait FeatureWorker<UserData, SdkControl, SdkEvent, ToController, ToWorker>: TaskSwitcherChild<FeatureWorkerOutput<UserData, SdkControl, SdkEvent, ToController>> {
     fn on_tick(&mut self, _ctx: &mut FeatureWorkerContext, _now: u64, _tick_count: u64) {}
-    fn on_network_raw(&mut self, ctx: &mut FeatureWorkerContext, now: u64, conn: ConnId, _pair: NetPair, header: TransportMsgHeader, mut buf: Buffer) {
+    #[allow(clippy::too_many_arguments)]
+    fn on_network_raw(&mut self, ctx: &mut FeatureWorkerContext, now: u64, conn: ConnId, _pair: NetPair, header: TransportMsgHeader, route: Option<IncomingRoute>, mut buf: Buffer) {
         let header_len = header.serialize_size();
         buf.move_front_right(header_len).expect("Buffer should bigger or equal header");
-        self.on_input(ctx, now, FeatureWorkerInput::Network(conn, (&header).into(), buf));
+        let mut meta: NetIncomingMeta = (&header).into();
+        meta.route = route;
+        self.on_input(ctx, now, FeatureWorkerInput::Network(conn, meta, buf));
     }
     fn on_input(&mut self, _ctx: &mut FeatureWorkerContext, _now: u64, input: FeatureWorkerInput<UserData, SdkControl, ToWorker>);
     fn on_shutdown(&mut self, _ctx: &mut FeatureWorkerContext, _now: u64);
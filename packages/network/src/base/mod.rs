@@ -1,34 +1,76 @@
+mod capability;
+mod compression;
 mod control;
 mod feature;
 mod msg;
+mod namespace;
+mod profile;
 mod secure;
 mod service;
 
-use atm0s_sdn_identity::{ConnId, NodeId};
+use std::net::SocketAddr;
+
+use atm0s_sdn_identity::{ConnId, NodeAddr, NodeId};
+pub use capability::*;
+pub use compression::*;
 pub use control::*;
 pub use feature::*;
 pub use msg::*;
+pub use namespace::*;
+pub use profile::*;
 pub use sans_io_runtime::Buffer;
 pub use secure::*;
 pub use service::*;
 
-use crate::data_plane::NetPair;
+use crate::{data_plane::NetPair, metrics::FeatureTraffic};
 
 #[derive(Debug, Clone)]
 pub struct ConnectionCtx {
     pub conn: ConnId,
     pub node: NodeId,
     pub pair: NetPair,
+    /// Negotiated handshake and cipher suite, it is unknown until connected
+    pub secure: SecureInfo,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ConnectionStats {
     pub rtt_ms: u32,
+    /// Traffic over the connection since connected, it is summed from data plane workers
+    pub traffic: FeatureTraffic,
 }
 
 #[derive(Debug, Clone)]
 pub enum ConnectionEvent {
     Connected(ConnectionCtx, SecureContext),
     Stats(ConnectionCtx, ConnectionStats),
+    /// Capabilities of the neighbour are negotiated, or it is detected as legacy
+    Capabilities(ConnectionCtx, PeerCapabilities),
     Disconnected(ConnectionCtx),
+    /// Connecting to a node which is requested by `ConnectTo` failed over all of its addresses
+    ConnectFailed(NodeAddr, ConnectFailReason),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectFailReason {
+    Timeout,
+    Error(NeighboursConnectError),
+}
+
+/// Local network interface state, which is detected by the runner from bind results and periodic checks of bound addresses.
+/// An address is reported down only when no worker has a socket on it.
+/// Embedders can use it for reconnecting app-level sessions when the node is rebinding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterfaceEvent {
+    Up(SocketAddr),
+    Down(SocketAddr),
+}
+
+impl InterfaceEvent {
+    pub fn addr(&self) -> SocketAddr {
+        match self {
+            InterfaceEvent::Up(addr) => *addr,
+            InterfaceEvent::Down(addr) => *addr,
+        }
+    }
 }
@@ -13,6 +13,7 @@ const ROUTE_RULE_TO_NODE: u8 = 1;
 const ROUTE_RULE_TO_SERVICE: u8 = 2;
 const ROUTE_RULE_TO_SERVICES: u8 = 3;
 const ROUTE_RULE_TO_KEY: u8 = 4;
+const ROUTE_RULE_TO_SERVICE_IN_GROUP: u8 = 5;
 
 simple_pub_type!(Ttl, u8);
 
@@ -35,12 +36,14 @@ pub enum TransportMsgHeaderError {
 ///     0                   1                   2                   3
 ///     0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
 ///    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-///    |V=0|E|N|   R   |      TTL      |  Feature       |     Meta     |
+///    |V=0|E|N|D|  R  |      TTL      |  Feature       |     Meta     |
 ///    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
 ///    |                         Route destination (Opt)               |
 ///    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
 ///    |                         FromNodeId (Opt)                      |
 ///    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///    |                         Dedup token (Opt)                     |
+///    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
 /// ```
 ///
 /// In there
@@ -48,7 +51,8 @@ pub enum TransportMsgHeaderError {
 /// - Version (V) : 2 bits (now is 0)
 /// - Encrypt (E): 1 bits, If this bit is set, this msg should be encrypted
 /// - From Node (N)    : 1 bits, If this bit is set, from node_id will occupy 32 bits in header
-/// - Route Type (R): 4 bits
+/// - Dedup (D): 1 bits, If this bit is set, dedup token will occupy 32 bits in header
+/// - Route Type (R): 3 bits
 ///
 ///     - 0: Direct : which node received this msg will handle it, no route destination
 ///     - 1: ToNode : which node received this msg will route it to node_id
@@ -66,6 +70,8 @@ pub enum TransportMsgHeaderError {
 ///     - If route type is ToKey, this field is 32bit key
 ///
 /// - From Node Id: 32 bits (optional if N bit is set)
+/// - Dedup token: 32 bits (optional if D bit is set), the destination drops messages with same (feature, from node, token)
+///   which are received again in a short time, which happen with ToKey routing while the network is converging
 ///
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -78,6 +84,7 @@ pub struct TransportMsgHeader {
     pub meta: u8,
     /// Which can be anonymous or specific node
     pub from_node: Option<NodeId>,
+    pub dedup: Option<u32>,
 }
 
 impl Default for TransportMsgHeader {
@@ -101,6 +108,7 @@ impl TransportMsgHeader {
             feature: 0,
             meta: 0,
             from_node: None,
+            dedup: None,
         }
     }
 
@@ -113,6 +121,7 @@ impl TransportMsgHeader {
             feature,
             meta,
             from_node: None,
+            dedup: None,
         }
     }
 
@@ -134,6 +143,12 @@ impl TransportMsgHeader {
         self
     }
 
+    /// Set dedup token
+    pub fn set_dedup(mut self, dedup: Option<u32>) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
     /// Set to feature
     pub fn set_feature(mut self, feature: u8) -> Self {
         self.feature = feature;
@@ -178,15 +193,22 @@ impl TransportMsgHeader {
             0
         };
 
+        let d_bit = if self.dedup.is_some() {
+            1 << 3
+        } else {
+            0
+        };
+
         let route_type = match self.route {
             RouteRule::Direct => ROUTE_RULE_DIRECT,
             RouteRule::ToNode(_) => ROUTE_RULE_TO_NODE,
             RouteRule::ToService(_) => ROUTE_RULE_TO_SERVICE,
             RouteRule::ToServices(_, _, _) => ROUTE_RULE_TO_SERVICES,
             RouteRule::ToKey(_) => ROUTE_RULE_TO_KEY,
+            RouteRule::ToServiceInGroup(_, _) => ROUTE_RULE_TO_SERVICE_IN_GROUP,
         };
 
-        output[0] = (self.version << 6) | e_bit | n_bit | (route_type & 15);
+        output[0] = (self.version << 6) | e_bit | n_bit | d_bit | (route_type & 7);
         output[1] = self.ttl;
         output[2] = self.feature;
         output[3] = self.meta;
@@ -213,23 +235,23 @@ impl TransportMsgHeader {
                 output[ptr..ptr + 4].copy_from_slice(&key.to_be_bytes());
                 ptr += 4;
             }
+            RouteRule::ToServiceInGroup(service, level) => {
+                output[ptr] = service;
+                output[ptr + 1] = level.into();
+                output[ptr + 2..ptr + 4].copy_from_slice(&[0, 0]);
+                ptr += 4;
+            }
         }
         if let Some(from_node) = self.from_node {
             output[ptr..ptr + 4].copy_from_slice(&from_node.to_be_bytes());
             ptr += 4;
         }
+        if let Some(dedup) = self.dedup {
+            output[ptr..ptr + 4].copy_from_slice(&dedup.to_be_bytes());
+            ptr += 4;
+        }
 
-        Some(
-            4 + if self.from_node.is_some() {
-                4
-            } else {
-                0
-            } + if self.route == RouteRule::Direct {
-                0
-            } else {
-                4
-            },
-        )
+        Some(ptr)
     }
 
     /// Rewrite the ttl in the given buffer with the new ttl.
@@ -282,6 +304,10 @@ impl TransportMsgHeader {
             0
         } else {
             4
+        } + if self.dedup.is_some() {
+            4
+        } else {
+            0
         }
     }
 }
@@ -444,7 +470,8 @@ impl TryFrom<&[u8]> for TransportMsgHeader {
         let version = bytes[0] >> 6; //2 bits
         let e_bit = (bytes[0] >> 5) & 1 == 1; //1 bit
         let n_bit = (bytes[0] >> 4) & 1 == 1; //1 bit
-        let route_type = bytes[0] & 15; //4 bits
+        let d_bit = (bytes[0] >> 3) & 1 == 1; //1 bit
+        let route_type = bytes[0] & 7; //3 bits
 
         if version != 0 {
             return Err(TransportMsgHeaderError::InvalidVersion);
@@ -490,6 +517,14 @@ impl TryFrom<&[u8]> for TransportMsgHeader {
                 ptr += 4;
                 rr
             }
+            ROUTE_RULE_TO_SERVICE_IN_GROUP => {
+                if bytes.len() < ptr + 4 {
+                    return Err(TransportMsgHeaderError::TooSmall);
+                }
+                let rr = RouteRule::ToServiceInGroup(bytes[ptr], ServiceBroadcastLevel::from(bytes[ptr + 1]));
+                ptr += 4;
+                rr
+            }
             _ => return Err(TransportMsgHeaderError::InvalidRoute),
         };
 
@@ -504,6 +539,17 @@ impl TryFrom<&[u8]> for TransportMsgHeader {
             None
         };
 
+        let dedup = if d_bit {
+            if bytes.len() < ptr + 4 {
+                return Err(TransportMsgHeaderError::TooSmall);
+            }
+            let dedup = u32::from_be_bytes([bytes[ptr], bytes[ptr + 1], bytes[ptr + 2], bytes[ptr + 3]]);
+            ptr += 4;
+            Some(dedup)
+        } else {
+            None
+        };
+
         Ok(Self {
             version,
             encrypt: e_bit,
@@ -512,6 +558,7 @@ impl TryFrom<&[u8]> for TransportMsgHeader {
             feature,
             meta,
             from_node,
+            dedup,
         })
     }
 }
@@ -532,6 +579,7 @@ mod tests {
             route: RouteRule::Direct,
             encrypt: true,
             from_node: None,
+            dedup: None,
         };
         let size = header.to_bytes(&mut buf).expect("should serialize");
         assert_eq!(header.serialize_size(), 4);
@@ -558,6 +606,7 @@ mod tests {
             route: RouteRule::ToNode(4),
             encrypt: true,
             from_node: None,
+            dedup: None,
         };
         let size = header.to_bytes(&mut buf).expect("should serialize");
         assert_eq!(header.serialize_size(), 8);
@@ -582,6 +631,7 @@ mod tests {
             route: RouteRule::ToServices(4, ServiceBroadcastLevel::Geo2, 1000),
             encrypt: true,
             from_node: None,
+            dedup: None,
         };
         let size = header.to_bytes(&mut buf).expect("should serialize");
         assert_eq!(header.serialize_size(), 8);
@@ -606,6 +656,7 @@ mod tests {
             route: RouteRule::ToService(4),
             encrypt: true,
             from_node: Some(5),
+            dedup: None,
         };
         let size = header.to_bytes(&mut buf).expect("should serialize");
         assert_eq!(header.serialize_size(), 12);
@@ -618,6 +669,34 @@ mod tests {
         assert_eq!(header.from_node, Some(5));
     }
 
+    #[test]
+    fn test_header_with_dedup() {
+        let mut buf = [0; 16];
+        let header = TransportMsgHeader::build(2, 3, RouteRule::ToKey(4)).set_from_node(Some(5)).set_dedup(Some(6));
+        let size = header.to_bytes(&mut buf).expect("should serialize");
+        assert_eq!(size, 16);
+        assert_eq!(header.serialize_size(), 16);
+        let header2 = TransportMsgHeader::try_from(&buf[0..size]).expect("");
+        assert_eq!(header2, header);
+        assert_eq!(header2.dedup, Some(6));
+
+        assert_eq!(TransportMsgHeader::try_from(&buf[0..size - 1]), Err(TransportMsgHeaderError::TooSmall));
+    }
+
+    #[test]
+    fn test_header_to_service_in_group() {
+        let mut buf = [0; 16];
+        let header = TransportMsgHeader::build(2, 3, RouteRule::ToServiceInGroup(4, ServiceBroadcastLevel::Geo1)).set_from_node(Some(5));
+        let size = header.to_bytes(&mut buf).expect("should serialize");
+        assert_eq!(size, 12);
+        assert_eq!(header.serialize_size(), 12);
+        let header2 = TransportMsgHeader::try_from(&buf[0..size]).expect("");
+        assert_eq!(header2, header);
+        assert_eq!(header2.route, RouteRule::ToServiceInGroup(4, ServiceBroadcastLevel::Geo1));
+
+        assert_eq!(TransportMsgHeader::try_from(&buf[0..7]), Err(TransportMsgHeaderError::TooSmall));
+    }
+
     /// test with invalid version
     #[test]
     fn test_with_invalid_version() {
@@ -630,6 +709,7 @@ mod tests {
             route: RouteRule::ToNode(4),
             encrypt: true,
             from_node: Some(5),
+            dedup: None,
         };
         let size = header.to_bytes(&mut buf).expect("should serialize");
         let err = TransportMsgHeader::try_from(&buf[0..size]).unwrap_err();
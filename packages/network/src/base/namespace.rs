@@ -0,0 +1,157 @@
+//! Derivation of ids from `(namespace, name)` pairs.
+//!
+//! Independent subsystems of an application often pick ids of pubsub channels and dht_kv maps by hand, and two of them can
+//! end up on the same id without noticing. Deriving ids from a namespace (usually the subsystem) and a name keeps them apart.
+//!
+//! The derivation is stable over versions, platforms and languages, so other implementations can compute the same ids:
+//!
+//! ```text
+//! id = u64 BE of first 8 bytes of SHA-256("atm0s-sdn/id/v1" | u32 BE len(namespace) | namespace | name)
+//! ```
+//!
+//! In debug builds every derived id is recorded with its names, so collisions between different names of a same kind are
+//! logged and listed by [`id_collisions`], and [`known_name`] resolves ids back to names for debugging.
+
+use sha2::{Digest, Sha256};
+
+const DERIVE_DOMAIN: &[u8] = b"atm0s-sdn/id/v1";
+
+/// Kind of derived id, ids of different kinds never collide with each other
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum IdKind {
+    Channel,
+    Map,
+}
+
+/// Two different names which are derived into the same id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdCollision {
+    pub kind: IdKind,
+    pub id: u64,
+    /// (namespace, name) which derived the id first
+    pub existing: (String, String),
+    /// (namespace, name) which derived the id later
+    pub new: (String, String),
+}
+
+/// Derive an id from namespace and name, the namespace is length prefixed so ("ab", "c") and ("a", "bc") are different
+pub fn derive_id(kind: IdKind, namespace: &str, name: &str) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(DERIVE_DOMAIN);
+    hasher.update((namespace.len() as u32).to_be_bytes());
+    hasher.update(namespace.as_bytes());
+    hasher.update(name.as_bytes());
+    let digest = hasher.finalize();
+    let id = u64::from_be_bytes(digest[0..8].try_into().expect("Should have 8 bytes"));
+    registry::record(kind, id, namespace, name);
+    id
+}
+
+/// Names of a derived id, it is always None in release builds
+pub fn known_name(kind: IdKind, id: u64) -> Option<(String, String)> {
+    registry::known_name(kind, id)
+}
+
+/// Collisions which are detected since the process started, it is always empty in release builds
+pub fn id_collisions() -> Vec<IdCollision> {
+    registry::collisions()
+}
+
+#[cfg(debug_assertions)]
+mod registry {
+    use std::collections::HashMap;
+
+    use parking_lot::Mutex;
+
+    use super::{IdCollision, IdKind};
+
+    #[derive(Default)]
+    struct Registry {
+        names: HashMap<(IdKind, u64), (String, String)>,
+        collisions: Vec<IdCollision>,
+    }
+
+    static REGISTRY: Mutex<Option<Registry>> = parking_lot::const_mutex(None);
+
+    pub fn record(kind: IdKind, id: u64, namespace: &str, name: &str) {
+        let mut registry = REGISTRY.lock();
+        let registry = registry.get_or_insert_with(Registry::default);
+        match registry.names.get(&(kind, id)) {
+            Some(existing) if existing.0 == namespace && existing.1 == name => {}
+            Some(existing) => {
+                log::error!("[IdRegistry] {kind:?} id {id} of {namespace}/{name} collides with {}/{}", existing.0, existing.1);
+                let collision = IdCollision {
+                    kind,
+                    id,
+                    existing: existing.clone(),
+                    new: (namespace.to_string(), name.to_string()),
+                };
+                registry.collisions.push(collision);
+            }
+            None => {
+                registry.names.insert((kind, id), (namespace.to_string(), name.to_string()));
+            }
+        }
+    }
+
+    pub fn known_name(kind: IdKind, id: u64) -> Option<(String, String)> {
+        REGISTRY.lock().as_ref()?.names.get(&(kind, id)).cloned()
+    }
+
+    pub fn collisions() -> Vec<IdCollision> {
+        REGISTRY.lock().as_ref().map(|r| r.collisions.clone()).unwrap_or_default()
+    }
+}
+
+#[cfg(not(debug_assertions))]
+mod registry {
+    use super::{IdCollision, IdKind};
+
+    pub fn record(_kind: IdKind, _id: u64, _namespace: &str, _name: &str) {}
+
+    pub fn known_name(_kind: IdKind, _id: u64) -> Option<(String, String)> {
+        None
+    }
+
+    pub fn collisions() -> Vec<IdCollision> {
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derive_is_stable_and_namespaced() {
+        let id = derive_id(IdKind::Channel, "chat", "room-1");
+        assert_eq!(id, derive_id(IdKind::Channel, "chat", "room-1"));
+        assert_eq!(id, derive_id(IdKind::Map, "chat", "room-1"));
+        assert_ne!(id, derive_id(IdKind::Channel, "chat", "room-2"));
+        assert_ne!(derive_id(IdKind::Channel, "ab", "c"), derive_id(IdKind::Channel, "a", "bc"));
+        // test vector for other implementations
+        assert_eq!(id, 9286161235959951860);
+        #[cfg(debug_assertions)]
+        assert_eq!(known_name(IdKind::Channel, id), Some(("chat".to_string(), "room-1".to_string())));
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn registry_reports_collision() {
+        registry::record(IdKind::Map, 42, "billing", "invoices");
+        registry::record(IdKind::Map, 42, "billing", "invoices");
+        registry::record(IdKind::Channel, 42, "media", "video");
+        registry::record(IdKind::Map, 42, "media", "video");
+
+        let collisions: Vec<_> = id_collisions().into_iter().filter(|c| c.id == 42).collect();
+        assert_eq!(
+            collisions,
+            vec![IdCollision {
+                kind: IdKind::Map,
+                id: 42,
+                existing: ("billing".to_string(), "invoices".to_string()),
+                new: ("media".to_string(), "video".to_string()),
+            }]
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use crate::features::{alias, pubsub};
+
+/// Preset of timers and queue limits which is applied across features, selected per node.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LatencyProfile {
+    /// Favor reliability and low overhead
+    #[default]
+    Balanced,
+    /// Target sub-50ms end-to-end latency: fine-grained timers, short sticky paths and small queues,
+    /// messages which would be delivered late are dropped instead
+    BoundedLatency,
+}
+
+impl LatencyProfile {
+    /// Interval of ticks which drive all timers like resend, feedback and timeouts
+    pub fn tick_ms(&self) -> u64 {
+        match self {
+            Self::Balanced => 1000,
+            Self::BoundedLatency => 20,
+        }
+    }
+
+    /// Time a pubsub relay keeps its path before finding a better one
+    pub fn relay_sticky_ms(&self) -> u64 {
+        match self {
+            Self::Balanced => pubsub::RELAY_STICKY_MS,
+            Self::BoundedLatency => 10_000,
+        }
+    }
+
+    /// Max messages parked at an alias root while the owner is offline
+    pub fn alias_max_parked_msgs(&self) -> usize {
+        match self {
+            Self::Balanced => alias::MAX_PARKED_MSGS,
+            Self::BoundedLatency => 8,
+        }
+    }
+
+    /// Max time a message is parked at an alias root, older messages are dropped as expired
+    pub fn alias_max_park_ttl_ms(&self) -> u64 {
+        match self {
+            Self::Balanced => alias::MAX_PARK_TTL_MS,
+            Self::BoundedLatency => 1000,
+        }
+    }
+}
+
+/// Framing profile of links. Each node selects its own profile and it is negotiated per connection,
+/// the more constrained one of two sides is used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LinkProfile {
+    /// Normal UDP link, messages are sent as-is
+    #[default]
+    Standard,
+    /// Small MTU and lossy link like LoRa: headers are compressed, messages are fragmented into frames which fit the mtu,
+    /// and control traffic is acked and repaired
+    Constrained { mtu: u16 },
+}
+
+impl LinkProfile {
+    /// Smallest supported frame size
+    pub const MIN_MTU: u16 = 32;
+
+    pub fn constrained(mtu: u16) -> Self {
+        Self::Constrained { mtu: mtu.max(Self::MIN_MTU) }
+    }
+
+    /// Max frame size, None if the link is not constrained
+    pub fn mtu(&self) -> Option<u16> {
+        match self {
+            Self::Standard => None,
+            Self::Constrained { mtu } => Some(*mtu),
+        }
+    }
+
+    /// Select the profile which both sides can use
+    pub fn negotiate(&self, other: &Self) -> Self {
+        match (self.mtu(), other.mtu()) {
+            (Some(a), Some(b)) => Self::constrained(a.min(b)),
+            (Some(mtu), None) | (None, Some(mtu)) => Self::constrained(mtu),
+            (None, None) => Self::Standard,
+        }
+    }
+}
@@ -4,10 +4,61 @@ use atm0s_sdn_identity::NodeId;
 
 use super::Buffer;
 
+/// Time which the decryptor of previous key epoch is kept after rekeying, for packets which are still in flight
+pub const REKEY_GRACE_MS: u64 = 5000;
+
 #[derive(Debug, Clone)]
 pub struct SecureContext {
     pub(crate) encryptor: Box<dyn Encryptor>,
     pub(crate) decryptor: Box<dyn Decryptor>,
+    /// Decryptor of previous key epoch and the time it is dropped
+    pub(crate) previous: Option<(Box<dyn Decryptor>, u64)>,
+}
+
+impl SecureContext {
+    pub fn new(encryptor: Box<dyn Encryptor>, decryptor: Box<dyn Decryptor>) -> Self {
+        Self { encryptor, decryptor, previous: None }
+    }
+
+    /// Context of a connection whose keys are only kept by the data plane, like connections which are adopted by a standby
+    /// controller. It is only for notifying features and services, it cannot encrypt or decrypt
+    pub(crate) fn detached() -> Self {
+        Self::new(Box::new(DetachedKey), Box::new(DetachedKey))
+    }
+
+    /// Switch to keys of a new epoch, the old decryptor is still used as fallback in [`REKEY_GRACE_MS`]
+    pub fn rekey(&mut self, now_ms: u64, encryptor: Option<Box<dyn Encryptor>>, decryptor: Option<Box<dyn Decryptor>>) {
+        if let Some(encryptor) = encryptor {
+            self.encryptor = encryptor;
+        }
+        if let Some(decryptor) = decryptor {
+            let old = std::mem::replace(&mut self.decryptor, decryptor);
+            self.previous = Some((old, now_ms + REKEY_GRACE_MS));
+        }
+    }
+
+    pub fn encrypt(&mut self, now_ms: u64, data: &mut Buffer) -> Result<(), EncryptionError> {
+        self.encryptor.encrypt(now_ms, data)
+    }
+
+    pub fn decrypt(&mut self, now_ms: u64, data: &mut Buffer) -> Result<(), DecryptionError> {
+        if matches!(self.previous, Some((_, drop_ms)) if drop_ms <= now_ms) {
+            self.previous = None;
+        }
+        let (previous, _) = match &mut self.previous {
+            Some(previous) => previous,
+            None => return self.decryptor.decrypt(now_ms, data),
+        };
+        // failed decrypt modifies the buffer, so we need a copy for trying the old key
+        let backup = data.clone();
+        match self.decryptor.decrypt(now_ms, data) {
+            Ok(()) => Ok(()),
+            Err(_) => {
+                *data = backup;
+                previous.decrypt(now_ms, data)
+            }
+        }
+    }
 }
 
 #[mockall::automock]
@@ -28,8 +79,29 @@ pub trait HandshakeBuilder: Send + Sync {
     fn responder(&self) -> Box<dyn HandshakeResponder>;
 }
 
+/// Handshake and cipher suite which are negotiated for a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SecureInfo {
+    pub handshake: &'static str,
+    pub cipher: &'static str,
+}
+
+impl SecureInfo {
+    pub const UNKNOWN: Self = Self {
+        handshake: "unknown",
+        cipher: "unknown",
+    };
+}
+
+impl Default for SecureInfo {
+    fn default() -> Self {
+        Self::UNKNOWN
+    }
+}
+
 #[mockall::automock]
 pub trait HandshakeRequester {
+    fn secure_info(&self) -> SecureInfo;
     fn create_public_request(&self) -> Result<Vec<u8>, HandshakeError>;
     #[allow(clippy::type_complexity)]
     fn process_public_response(&mut self, response: &[u8]) -> Result<(Box<dyn Encryptor>, Box<dyn Decryptor>), HandshakeError>;
@@ -37,6 +109,7 @@ pub trait HandshakeRequester {
 
 #[mockall::automock]
 pub trait HandshakeResponder {
+    fn secure_info(&self) -> SecureInfo;
     #[allow(clippy::type_complexity)]
     fn process_public_request(&mut self, request: &[u8]) -> Result<(Box<dyn Encryptor>, Box<dyn Decryptor>, Vec<u8>), HandshakeError>;
 }
@@ -63,6 +136,8 @@ pub enum DecryptionError {
     TooSmall,
     TooOld,
     DecryptError,
+    /// Sequence is already received or is behind the replay window
+    Replayed,
 }
 
 #[mockall::automock]
@@ -76,3 +151,26 @@ impl Clone for Box<dyn Decryptor> {
         self.clone_box()
     }
 }
+
+#[derive(Debug, Clone)]
+struct DetachedKey;
+
+impl Encryptor for DetachedKey {
+    fn encrypt(&mut self, _now_ms: u64, _data: &mut Buffer) -> Result<(), EncryptionError> {
+        Err(EncryptionError::EncryptFailed)
+    }
+
+    fn clone_box(&self) -> Box<dyn Encryptor> {
+        Box::new(self.clone())
+    }
+}
+
+impl Decryptor for DetachedKey {
+    fn decrypt(&mut self, _now_ms: u64, _data: &mut Buffer) -> Result<(), DecryptionError> {
+        Err(DecryptionError::DecryptError)
+    }
+
+    fn clone_box(&self) -> Box<dyn Decryptor> {
+        Box::new(self.clone())
+    }
+}
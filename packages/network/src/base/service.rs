@@ -1,11 +1,25 @@
 use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_router::ServicePlacement;
 use atm0s_sdn_utils::simple_pub_type;
 use sans_io_runtime::TaskSwitcherChild;
 
+use crate::features::Features;
+
 use super::ConnectionEvent;
 
 simple_pub_type!(ServiceId, u8);
 
+/// Condition which must be met before the controller starts a service.
+/// Until then the service is not created, its controls are queued and established connections are replayed when it starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceDependency {
+    /// Feature is ready: router_sync when the routing table is converged, dht_kv, pubsub and alias when there is a route
+    /// for selecting relays, other features are always ready
+    Feature(Features),
+    /// Other service of this node is started
+    Service(u8),
+}
+
 /// First part is Service, which is running inside the controller.
 #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
 pub enum ServiceControlActor<UserData> {
@@ -47,6 +61,12 @@ pub trait Service<UserData, FeaturesControl, FeaturesEvent, ServiceControl, Serv
     fn on_input(&mut self, _ctx: &ServiceCtx, _now: u64, input: ServiceInput<UserData, FeaturesEvent, ServiceControl, ToController>);
     fn on_shutdown(&mut self, _ctx: &ServiceCtx, _now: u64);
     fn pop_output2(&mut self, _now: u64) -> Option<ServiceOutput<UserData, FeaturesControl, ServiceEvent, ToWorker>>;
+    /// State which is replicated to a standby controller, None for services which don't keep state or rebuild it from the network
+    fn snapshot_state(&self) -> Option<Vec<u8>> {
+        None
+    }
+    /// Restore state of [`Service::snapshot_state`] when a standby controller takes over, it is called before other inputs
+    fn restore_state(&mut self, _state: &[u8]) {}
 }
 
 impl<UserData, FeaturesControl, FeaturesEvent, ServiceControl, ServiceEvent, ToController, ToWorker> TaskSwitcherChild<ServiceOutput<UserData, FeaturesControl, ServiceEvent, ToWorker>>
@@ -123,6 +143,14 @@ pub trait ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, ServiceContro
     fn discoverable(&self) -> bool {
         true
     }
+    /// Restrict which instances of this service can receive ToService and ToServices traffic
+    fn placement(&self) -> ServicePlacement {
+        ServicePlacement::Any
+    }
+    /// Services and features which must be ready before this service is started, it is started immediately without any
+    fn dependencies(&self) -> Vec<ServiceDependency> {
+        vec![]
+    }
     fn create(&self) -> Box<dyn Service<UserData, FeaturesControl, FeaturesEvent, ServiceControl, ServiceEvent, ToController, ToWorker>>;
     fn create_worker(&self) -> Box<dyn ServiceWorker<UserData, FeaturesControl, FeaturesEvent, ServiceControl, ServiceEvent, ToController, ToWorker>>;
 }
@@ -1,25 +1,58 @@
-use std::{collections::VecDeque, fmt::Debug, hash::Hash, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    fmt::Debug,
+    hash::Hash,
+    net::SocketAddr,
+    sync::Arc,
+};
 
-use atm0s_sdn_identity::NodeId;
-use atm0s_sdn_router::shadow::ShadowRouterHistory;
+use atm0s_sdn_identity::{ConnId, NodeId};
+use atm0s_sdn_router::{
+    core::RoutingPolicy,
+    shadow::{ShadowRouterHistory, ShadowRouterHistoryStats},
+    ServicePlacement,
+};
 use rand::RngCore;
 use sans_io_runtime::{return_if_none, return_if_some, TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     base::{
-        Authorization, ConnectionEvent, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, HandshakeBuilder, ServiceBuilder, ServiceControlActor, ServiceCtx,
-        ServiceInput, ServiceOutput, ServiceSharedInput,
+        Authorization, Capabilities, CapabilitySkew, CompressionConfig, CompressionStats, ConnectionEvent, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput,
+        HandshakeBuilder, InterfaceEvent, LatencyProfile, LinkProfile, PeerCapabilities, SecureContext, ServiceBuilder, ServiceControlActor, ServiceCtx, ServiceInput, ServiceOutput,
+        ServiceSharedInput,
     },
-    features::{FeaturesControl, FeaturesEvent},
-    ExtIn, ExtOut, LogicControl, LogicEvent,
+    features::{dht_kv::KvStorageBackend, pubsub::ChannelAuthorizer, router_sync::RouterSyncSnapshot, FeaturesControl, FeaturesEvent, FeaturesToWorker},
+    metrics::{FeatureTraffic, RttHistogram},
+    DecommissionEvent, ExtIn, ExtOut, LogicControl, LogicEvent, StickyExt,
 };
 
-use self::{features::FeatureManager, neighbours::NeighboursManager, services::ServiceManager};
+use self::{
+    features::{FeatureManager, FeaturesCheckpoint},
+    journal::EventJournal,
+    neighbours::NeighboursManager,
+    services::ServiceManager,
+};
 
 mod features;
-mod neighbours;
+mod journal;
+pub(crate) mod neighbours;
 mod services;
 
+#[cfg(unix)]
+pub use journal::SyslogEventSink;
+pub use journal::{EventLogQuery, EventSink, FileEventSink, JournalEntry, JournalEvent, EVENT_JOURNAL_CAPACITY};
+pub use neighbours::NeighbourPolicy;
+
+/// Max time for waiting neighbours to take over relays before leaving
+pub const DECOMMISSION_DRAIN_TIMEOUT_MS: u64 = 10_000;
+/// Time for features and services to send their leaving messages before closing connections
+pub const DECOMMISSION_LEAVE_MS: u64 = 1_000;
+/// Max time for waiting all connections closed
+pub const DECOMMISSION_DISCONNECT_TIMEOUT_MS: u64 = 5_000;
+/// Version of encoded [`StateCheckpoint`], checkpoints of other versions are rejected
+pub const CHECKPOINT_VERSION: u8 = 1;
+
 #[derive(Debug, Clone, convert_enum::From)]
 pub enum Input<UserData, SC, SE, TC> {
     Ext(ExtIn<UserData, SC>),
@@ -42,6 +75,124 @@ enum TaskType {
     Service = 2,
 }
 
+/// Snapshot of controller counters and gauges, which is used for exporting to external telemetry systems
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ControllerMetrics {
+    /// Current connections, including connecting and disconnecting ones
+    pub connections: usize,
+    /// Total established connections since started
+    pub connections_established: u64,
+    /// Total closed connections since started
+    pub connections_closed: u64,
+    /// Destinations in the routing table
+    pub routes: usize,
+    /// Dht_kv maps which this node is responsible for
+    pub dht_kv_maps: usize,
+    /// Pubsub channels relayed to remote nodes
+    pub pubsub_remote_relays: usize,
+    /// Remote subscribers of all relayed pubsub channels, which is the number of copies of each relayed message
+    pub pubsub_relay_fanout: usize,
+    /// Alias messages parked at this node, waiting for owners
+    pub alias_parked_msgs: usize,
+    /// Rtt of neighbour connections, sampled on each connection stats
+    pub rtt_ms: RttHistogram,
+    /// Payload compression counters of each feature or service which opted in
+    pub compression: BTreeMap<&'static str, CompressionStats>,
+    /// Dedup history of broadcast messages, which is shared with data plane workers
+    pub broadcast_history: ShadowRouterHistoryStats,
+    /// Worker which receives packets of each connection, connections without received packets yet are not included
+    pub conn_workers: BTreeMap<ConnId, u16>,
+}
+
+/// State of a controller which is replicated to a standby controller: established connections, the last router sync of
+/// each neighbour and states of services which support snapshots. Features which keep state of local actors (pubsub,
+/// dht_kv, alias) start empty after a takeover, so actors need to subscribe again
+#[derive(Debug, Clone)]
+pub struct ControllerSnapshot {
+    taken_ms: u64,
+    connections: Vec<neighbours::ConnectionSnapshot>,
+    routes: RouterSyncSnapshot,
+    services: Vec<(u8, Vec<u8>)>,
+}
+
+impl ControllerSnapshot {
+    pub fn taken_ms(&self) -> u64 {
+        self.taken_ms
+    }
+
+    /// Number of established connections in the snapshot
+    pub fn connections(&self) -> usize {
+        self.connections.len()
+    }
+}
+
+/// State of a controller which is kept across restarts of the node: its session, addresses of neighbours, last router
+/// syncs, alias roots, served dht_kv slots and states of services which support snapshots. Connections and subscriptions
+/// of local actors are not kept, they are established again after the restart
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateCheckpoint {
+    node_id: NodeId,
+    session: u64,
+    neighbours: Vec<(NodeId, SocketAddr)>,
+    features: FeaturesCheckpoint,
+    services: Vec<(u8, Vec<u8>)>,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum CheckpointError {
+    #[error("checkpoint is empty or corrupted")]
+    Invalid,
+    #[error("checkpoint version {0} is not supported")]
+    Version(u8),
+    #[error("checkpoint belongs to node {0}")]
+    NodeMismatch(NodeId),
+}
+
+impl StateCheckpoint {
+    /// Version byte followed by the bincode encoded checkpoint
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![CHECKPOINT_VERSION];
+        buf.extend(bincode::serialize(self).expect("Should serialize checkpoint"));
+        buf
+    }
+
+    /// Decode a checkpoint of the node, which is created by [`StateCheckpoint::encode`]
+    pub fn decode(node_id: NodeId, buf: &[u8]) -> Result<Self, CheckpointError> {
+        let (version, data) = buf.split_first().ok_or(CheckpointError::Invalid)?;
+        if *version != CHECKPOINT_VERSION {
+            return Err(CheckpointError::Version(*version));
+        }
+        let checkpoint: Self = bincode::deserialize(data).map_err(|_| CheckpointError::Invalid)?;
+        if checkpoint.node_id != node_id {
+            return Err(CheckpointError::NodeMismatch(checkpoint.node_id));
+        }
+        Ok(checkpoint)
+    }
+
+    pub fn session(&self) -> u64 {
+        self.session
+    }
+
+    /// Node and remote address of each neighbour which was connected when the checkpoint was taken
+    pub fn neighbours(&self) -> &[(NodeId, SocketAddr)] {
+        &self.neighbours
+    }
+}
+
+/// State of a bound address, it is up while any worker has a socket on it
+#[derive(Default)]
+struct InterfaceState {
+    up_workers: BTreeSet<u16>,
+    /// Last state which is reported to the embedder
+    reported: Option<InterfaceEvent>,
+}
+
+enum DecommissionState {
+    Draining { started_at: u64, remains: (usize, usize) },
+    Leaving { started_at: u64 },
+    Disconnecting { started_at: u64, connections: usize },
+}
+
 pub struct ControllerPlaneCfg<UserData, SC, SE, TC, TW> {
     pub session: u64,
     pub bind_addrs: Vec<SocketAddr>,
@@ -51,10 +202,37 @@ pub struct ControllerPlaneCfg<UserData, SC, SE, TC, TW> {
     pub handshake_builder: Arc<dyn HandshakeBuilder>,
     pub random: Box<dyn RngCore + Send + Sync>,
     pub history: Arc<dyn ShadowRouterHistory>,
+    pub profile: LatencyProfile,
+    pub link: LinkProfile,
+    /// Storage backend for dht_kv maps which this node serves, None for memory only
+    pub dht_kv_storage: Option<Arc<dyn KvStorageBackend>>,
+    /// Authorization of pubsub Subs from neighbours, None for allowing all
+    pub channel_authorizer: Option<Arc<dyn ChannelAuthorizer>>,
+    /// Observer node joins the network for monitoring, but it is never selected as a relay, a next hop or a dht server
+    pub observer: bool,
+    /// Capabilities which are advertised to neighbours, reduce it for keeping new nodes compatible during a rolling upgrade
+    pub capabilities: Capabilities,
+    /// Payload compression for features which opt into it (dht_kv), None for sending all payloads raw
+    pub compression: Option<CompressionConfig>,
+    /// Interval for rekeying outgoing connections with neighbours which support it, None for keeping keys of connect handshake
+    pub rekey_interval_ms: Option<u64>,
+    /// Limits of connected neighbours, default is no limit
+    pub neighbour_policy: NeighbourPolicy,
+    /// Pin events of worker actors to one worker by their UserData, None for emitting them on the worker of the actor
+    pub sticky_ext: Option<StickyExt>,
+    /// Weights of path score and bandwidth which is advertised to neighbours
+    pub routing_policy: RoutingPolicy,
+    /// State of a previous run of this node, which is restored with the first tick
+    pub checkpoint: Option<StateCheckpoint>,
+    /// Destinations which receive each entry of the event journal
+    pub event_sinks: Vec<Arc<dyn EventSink>>,
 }
 
 pub struct ControllerPlane<UserData, SC, SE, TC, TW> {
     tick_count: u64,
+    caps: PeerCapabilities,
+    /// Capabilities which all workers support, agreed capabilities are reduced to them
+    worker_caps: Capabilities,
     feature_ctx: FeatureContext,
     service_ctx: ServiceCtx,
     neighbours: TaskSwitcherBranch<NeighboursManager, neighbours::Output>,
@@ -63,8 +241,22 @@ pub struct ControllerPlane<UserData, SC, SE, TC, TW> {
     services: TaskSwitcherBranch<ServiceManager<UserData, SC, SE, TC, TW>, services::Output<UserData, SE, TW>>,
     switcher: TaskSwitcher,
     queue: VecDeque<Output<UserData, SE, TW>>,
+    interfaces: HashMap<SocketAddr, InterfaceState>,
+    decommission: Option<DecommissionState>,
+    connections_established: u64,
+    connections_closed: u64,
+    rtt_ms: RttHistogram,
+    /// Traffic of each connection which is reported by workers, it is attached to connection stats
+    conn_traffic: HashMap<ConnId, FeatureTraffic>,
+    /// Worker which receives packets of each connection. All workers bind the same addresses with SO_REUSEPORT, so the
+    /// kernel selects it by the addresses of the connection, and it is updated from traffic reports of workers
+    conn_workers: HashMap<ConnId, u16>,
     shutdown: bool,
     history: Arc<dyn ShadowRouterHistory>,
+    compression: Option<CompressionConfig>,
+    sticky_ext: Option<StickyExt>,
+    checkpoint: Option<StateCheckpoint>,
+    journal: EventJournal,
 }
 
 impl<UserData, SC, SE, TC, TW> ControllerPlane<UserData, SC, SE, TC, TW>
@@ -84,26 +276,137 @@ where
     pub fn new(node_id: NodeId, cfg: ControllerPlaneCfg<UserData, SC, SE, TC, TW>) -> Self {
         log::info!("Create ControllerPlane for node: {}, running session {}", node_id, cfg.session);
         let service_ids = cfg.services.iter().filter(|s| s.discoverable()).map(|s| s.service_id()).collect();
+        let placements = cfg
+            .services
+            .iter()
+            .filter(|s| s.placement() != ServicePlacement::Any)
+            .map(|s| (s.service_id(), s.placement()))
+            .collect();
 
+        let caps = PeerCapabilities::local(cfg.capabilities);
         Self {
             tick_count: 0,
+            caps,
+            worker_caps: Capabilities::SUPPORTED,
             feature_ctx: FeatureContext { node_id, session: cfg.session },
             service_ctx: ServiceCtx { node_id, session: cfg.session },
             neighbours: TaskSwitcherBranch::new(
-                NeighboursManager::new(node_id, cfg.bind_addrs, cfg.authorization, cfg.handshake_builder, cfg.random),
+                NeighboursManager::new(node_id, cfg.bind_addrs, cfg.authorization, cfg.handshake_builder, cfg.link, caps, cfg.random)
+                    .with_rekey_interval(cfg.rekey_interval_ms)
+                    .with_policy(cfg.neighbour_policy),
                 TaskType::Neighbours,
             ),
-            features: TaskSwitcherBranch::new(FeatureManager::new(node_id, cfg.session, service_ids), TaskType::Feature),
+            features: TaskSwitcherBranch::new(
+                FeatureManager::new(
+                    node_id,
+                    cfg.session,
+                    service_ids,
+                    placements,
+                    cfg.profile,
+                    cfg.dht_kv_storage,
+                    cfg.channel_authorizer,
+                    cfg.observer,
+                    cfg.compression.clone(),
+                    cfg.routing_policy,
+                    caps.capabilities,
+                ),
+                TaskType::Feature,
+            ),
             services: TaskSwitcherBranch::new(ServiceManager::new(cfg.services), TaskType::Service),
             switcher: TaskSwitcher::new(3), //3 types: Neighbours, Feature, Service
             queue: VecDeque::new(),
+            interfaces: HashMap::new(),
+            decommission: None,
+            connections_established: 0,
+            connections_closed: 0,
+            rtt_ms: RttHistogram::default(),
+            conn_traffic: HashMap::new(),
+            conn_workers: HashMap::new(),
             shutdown: false,
             history: cfg.history,
+            compression: cfg.compression,
+            sticky_ext: cfg.sticky_ext,
+            checkpoint: cfg.checkpoint,
+            journal: EventJournal::new(EVENT_JOURNAL_CAPACITY, cfg.event_sinks),
         }
     }
 
+    /// Outputs which are waiting to be popped
+    pub fn queue_len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Take a snapshot of current metrics
+    pub fn metrics(&self) -> ControllerMetrics {
+        let mut metrics = ControllerMetrics {
+            connections: self.neighbours.connections(),
+            connections_established: self.connections_established,
+            connections_closed: self.connections_closed,
+            rtt_ms: self.rtt_ms.clone(),
+            compression: self.compression.as_ref().map(|c| c.stats()).unwrap_or_default(),
+            broadcast_history: self.history.stats(),
+            conn_workers: self.conn_workers.iter().map(|(conn, worker)| (*conn, *worker)).collect(),
+            ..Default::default()
+        };
+        self.features.metrics(&mut metrics);
+        metrics
+    }
+
+    pub fn snapshot(&self, now_ms: u64) -> ControllerSnapshot {
+        ControllerSnapshot {
+            taken_ms: now_ms,
+            connections: self.neighbours.snapshot(),
+            routes: self.features.router_snapshot(),
+            services: self.services.snapshot(),
+        }
+    }
+
+    /// Continue from the snapshot of another controller of this node, which has the same session. Connections are adopted
+    /// without handshakes because their keys are still pinned in data planes, then they are replayed to features and services
+    pub fn restore(&mut self, now_ms: u64, snapshot: ControllerSnapshot) {
+        log::info!(
+            "[ControllerPlane] restore snapshot taken at {} with {} connections, {} service states",
+            snapshot.taken_ms,
+            snapshot.connections.len(),
+            snapshot.services.len()
+        );
+        let adopted = self.neighbours.input(&mut self.switcher).adopt(now_ms, snapshot.connections);
+        for (ctx, remote_caps) in adopted {
+            self.share_connection_event(now_ms, ConnectionEvent::Connected(ctx.clone(), SecureContext::detached()));
+            if let Some(remote_caps) = remote_caps {
+                self.share_connection_event(now_ms, ConnectionEvent::Capabilities(ctx, remote_caps));
+            }
+        }
+        self.features.input(&mut self.switcher).restore_router(snapshot.routes);
+        self.services.input(&mut self.switcher).restore(snapshot.services);
+    }
+
+    pub fn checkpoint(&self, now_ms: u64) -> StateCheckpoint {
+        StateCheckpoint {
+            node_id: self.feature_ctx.node_id,
+            session: self.feature_ctx.session,
+            neighbours: self.neighbours.neighbour_addrs(),
+            features: self.features.checkpoint(now_ms),
+            services: self.services.snapshot(),
+        }
+    }
+
+    fn restore_checkpoint(&mut self, now_ms: u64, checkpoint: StateCheckpoint) {
+        log::info!(
+            "[ControllerPlane] restore checkpoint of session {} with {} neighbours, {} service states",
+            checkpoint.session,
+            checkpoint.neighbours.len(),
+            checkpoint.services.len()
+        );
+        self.features.input(&mut self.switcher).restore_checkpoint(now_ms, checkpoint.features);
+        self.services.input(&mut self.switcher).restore(checkpoint.services);
+    }
+
     pub fn on_tick(&mut self, now_ms: u64) {
         log::trace!("[ControllerPlane] on_tick: {}", now_ms);
+        if let Some(checkpoint) = self.checkpoint.take() {
+            self.restore_checkpoint(now_ms, checkpoint);
+        }
         self.neighbours.input(&mut self.switcher).on_tick(now_ms, self.tick_count);
         self.features
             .input(&mut self.switcher)
@@ -111,8 +414,11 @@ where
         self.services
             .input(&mut self.switcher)
             .on_shared_input(&self.service_ctx, now_ms, ServiceSharedInput::Tick(self.tick_count));
+        let features = &self.features;
+        self.services.input(&mut self.switcher).start_ready(&self.service_ctx, now_ms, |feature| features.is_ready(feature));
         self.tick_count += 1;
         self.history.set_ts(now_ms);
+        self.on_decommission_tick(now_ms);
     }
 
     pub fn on_event(&mut self, now_ms: u64, event: Input<UserData, SC, SE, TC>) {
@@ -136,6 +442,41 @@ where
                     .input(&mut self.switcher)
                     .on_input(&self.service_ctx, now_ms, service, ServiceInput::Control(ServiceControlActor::Controller(userdata), control));
             }
+            Input::Ext(ExtIn::SnapshotState(reply)) => {
+                let checkpoint = self.checkpoint(now_ms).encode();
+                log::info!("[ControllerPlane] snapshot state with {} bytes", checkpoint.len());
+                if reply.try_send(checkpoint).is_err() {
+                    log::warn!("[ControllerPlane] snapshot state requester is gone");
+                }
+            }
+            Input::Ext(ExtIn::ControlQuery(query)) => {
+                let entries = self.journal.query(&query);
+                self.queue.push_back(Output::Ext(ExtOut::EventLog(entries)));
+            }
+            Input::Ext(ExtIn::Tap(filter)) => {
+                log::info!("[ControllerPlane] set tap filter {:?}", filter);
+                self.queue.push_back(Output::Event(LogicEvent::Tap(filter)));
+            }
+            #[cfg(feature = "chaos")]
+            Input::Ext(ExtIn::Chaos(control)) => {
+                log::warn!("[ControllerPlane] chaos {:?}", control);
+                self.queue.push_back(Output::Event(LogicEvent::Chaos(control)));
+            }
+            Input::Ext(ExtIn::Decommission) => {
+                if self.decommission.is_some() || self.shutdown {
+                    log::warn!("[ControllerPlane] Decommission is already in progress or node is shutdown");
+                    return;
+                }
+                let (relays, maps) = self.features.decommission_remains();
+                log::info!("[ControllerPlane] Decommission started with {relays} relays, {maps} maps");
+                self.features.input(&mut self.switcher).decommission(&self.feature_ctx);
+                self.decommission = Some(DecommissionState::Draining {
+                    started_at: now_ms,
+                    remains: (relays, maps),
+                });
+                self.queue.push_back(Output::Ext(ExtOut::DecommissionEvent(DecommissionEvent::Started)));
+                self.queue.push_back(Output::Ext(ExtOut::DecommissionEvent(DecommissionEvent::Draining { relays, maps })));
+            }
             Input::Control(LogicControl::NetNeighbour(pair, control)) => {
                 self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::Control(pair, control));
             }
@@ -168,12 +509,51 @@ where
                     .input(&mut self.switcher)
                     .on_input(&self.feature_ctx, now_ms, control.to_feature(), FeatureInput::Control(actor, control));
             }
+            Input::Control(LogicControl::NetInterface(worker, event)) => {
+                // all workers are binding same addresses, so an address is only down when no worker has a socket on it,
+                // and a worker which is retrying its bind doesn't flap the state while others are still receiving
+                let addr = event.addr();
+                let state = self.interfaces.entry(addr).or_default();
+                match event {
+                    InterfaceEvent::Up(_) => state.up_workers.insert(worker),
+                    InterfaceEvent::Down(_) => state.up_workers.remove(&worker),
+                };
+                let current = if state.up_workers.is_empty() {
+                    InterfaceEvent::Down(addr)
+                } else {
+                    InterfaceEvent::Up(addr)
+                };
+                if state.reported.replace(current) != Some(current) {
+                    log::info!("[ControllerPlane] interface changed {:?} by worker {worker}", current);
+                    self.queue.push_back(Output::Ext(ExtOut::InterfaceEvent(current)));
+                }
+            }
+            Input::Control(LogicControl::ConnTraffic(worker, conn, traffic)) => {
+                if traffic.rx.packets > 0 {
+                    if let Some(old) = self.conn_workers.insert(conn, worker).filter(|old| *old != worker) {
+                        log::info!("[ControllerPlane] connection {conn} moved from worker {old} to worker {worker}");
+                    }
+                }
+                let entry = self.conn_traffic.entry(conn).or_default();
+                entry.rx.merge(&traffic.rx);
+                entry.tx.merge(&traffic.tx);
+            }
+            Input::Control(LogicControl::WorkerCapabilities(worker, reported)) => {
+                if let Some(skew) = CapabilitySkew::detect_worker(self.feature_ctx.node_id, worker, &self.caps, &reported) {
+                    log::warn!("[ControllerPlane] Version skew with worker: {skew}");
+                    self.queue.push_back(Output::Ext(ExtOut::CapabilitySkew(skew)));
+                }
+                self.worker_caps = self.worker_caps.intersection(reported.capabilities);
+            }
             Input::Control(LogicControl::ExtFeaturesEvent(userdata, event)) => {
                 self.queue.push_back(Output::Ext(ExtOut::FeaturesEvent(userdata, event)));
             }
             Input::Control(LogicControl::ExtServicesEvent(service, userdata, event)) => {
                 self.queue.push_back(Output::Ext(ExtOut::ServicesEvent(service, userdata, event)));
             }
+            Input::Control(LogicControl::Tap(record)) => {
+                self.queue.push_back(Output::Ext(ExtOut::Tap(record)));
+            }
         }
     }
 
@@ -188,29 +568,105 @@ where
         self.shutdown = true;
     }
 
+    /// Drive decommission flow: waiting relays handed over, then leaving features and services, then closing all connections
+    fn on_decommission_tick(&mut self, now_ms: u64) {
+        match return_if_none!(self.decommission.as_mut()) {
+            DecommissionState::Draining { started_at, remains } => {
+                let current = self.features.decommission_remains();
+                if *remains != current {
+                    *remains = current;
+                    log::info!("[ControllerPlane] Decommission draining, remain relays {}, maps {}", current.0, current.1);
+                    self.queue
+                        .push_back(Output::Ext(ExtOut::DecommissionEvent(DecommissionEvent::Draining { relays: current.0, maps: current.1 })));
+                }
+                // dht_kv maps only move to other nodes after this node left, so we only wait for relays
+                if current.0 == 0 || now_ms >= *started_at + DECOMMISSION_DRAIN_TIMEOUT_MS {
+                    log::info!("[ControllerPlane] Decommission leaving with {} remain relays", current.0);
+                    self.features.input(&mut self.switcher).on_shutdown(&self.feature_ctx, now_ms);
+                    self.services.input(&mut self.switcher).on_shutdown(&self.service_ctx, now_ms);
+                    self.decommission = Some(DecommissionState::Leaving { started_at: now_ms });
+                    self.queue.push_back(Output::Ext(ExtOut::DecommissionEvent(DecommissionEvent::Leaving)));
+                }
+            }
+            DecommissionState::Leaving { started_at } => {
+                if now_ms >= *started_at + DECOMMISSION_LEAVE_MS {
+                    let connections = self.neighbours.connections();
+                    log::info!("[ControllerPlane] Decommission disconnecting {} connections", connections);
+                    self.neighbours.input(&mut self.switcher).on_shutdown(now_ms);
+                    self.decommission = Some(DecommissionState::Disconnecting { started_at: now_ms, connections });
+                    self.queue.push_back(Output::Ext(ExtOut::DecommissionEvent(DecommissionEvent::Disconnecting { connections })));
+                }
+            }
+            DecommissionState::Disconnecting { started_at, connections } => {
+                let current = self.neighbours.connections();
+                if current != *connections {
+                    *connections = current;
+                    self.queue.push_back(Output::Ext(ExtOut::DecommissionEvent(DecommissionEvent::Disconnecting { connections: current })));
+                }
+                if current == 0 || now_ms >= *started_at + DECOMMISSION_DISCONNECT_TIMEOUT_MS {
+                    log::info!("[ControllerPlane] Decommission finished");
+                    self.decommission = None;
+                    self.shutdown = true;
+                    self.queue.push_back(Output::Ext(ExtOut::DecommissionEvent(DecommissionEvent::Finished)));
+                }
+            }
+        }
+    }
+
     fn pop_neighbours(&mut self, now_ms: u64) {
         let out = return_if_none!(self.neighbours.pop_output(now_ms, &mut self.switcher));
         match out {
             neighbours::Output::Control(remote, control) => self.queue.push_back(Output::Event(LogicEvent::NetNeighbour(remote, control))),
-            neighbours::Output::Event(event) => {
-                self.features
-                    .input(&mut self.switcher)
-                    .on_shared_input(&self.feature_ctx, now_ms, FeatureSharedInput::Connection(event.clone()));
-                self.services
-                    .input(&mut self.switcher)
-                    .on_shared_input(&self.service_ctx, now_ms, ServiceSharedInput::Connection(event.clone()));
+            neighbours::Output::Event(mut event) => {
+                if let ConnectionEvent::Stats(ctx, stats) = &mut event {
+                    stats.traffic = self.conn_traffic.get(&ctx.conn).copied().unwrap_or_default();
+                }
+                self.share_connection_event(now_ms, event.clone());
                 match event {
-                    ConnectionEvent::Connected(ctx, secure) => self.queue.push_back(Output::Event(LogicEvent::Pin(ctx.conn, ctx.node, ctx.pair, secure))),
-                    ConnectionEvent::Stats(_ctx, _stats) => {}
-                    ConnectionEvent::Disconnected(ctx) => self.queue.push_back(Output::Event(LogicEvent::UnPin(ctx.conn))),
+                    ConnectionEvent::Connected(ctx, secure) => {
+                        self.connections_established += 1;
+                        self.queue.push_back(Output::Event(LogicEvent::Pin(ctx.conn, ctx.node, ctx.pair, secure)));
+                    }
+                    ConnectionEvent::Stats(ctx, stats) => {
+                        self.rtt_ms.observe(stats.rtt_ms);
+                        self.queue.push_back(Output::Event(LogicEvent::ConnStats(ctx.conn, stats)));
+                    }
+                    ConnectionEvent::Capabilities(ctx, remote) => {
+                        if let Some(skew) = CapabilitySkew::detect(ctx.node, &self.caps, &remote) {
+                            log::warn!("[ControllerPlane] Version skew over {}: {skew}", ctx.pair);
+                            self.queue.push_back(Output::Ext(ExtOut::CapabilitySkew(skew)));
+                        }
+                        self.queue
+                            .push_back(Output::Event(LogicEvent::Capabilities(ctx.conn, self.caps.agreed(&remote).intersection(self.worker_caps))));
+                    }
+                    ConnectionEvent::Disconnected(ctx) => {
+                        self.connections_closed += 1;
+                        self.conn_traffic.remove(&ctx.conn);
+                        self.conn_workers.remove(&ctx.conn);
+                        self.queue.push_back(Output::Event(LogicEvent::UnPin(ctx.conn)));
+                    }
+                    ConnectionEvent::ConnectFailed(..) => {}
                 }
             }
+            neighbours::Output::LinkProfile(conn, link) => self.queue.push_back(Output::Event(LogicEvent::LinkProfile(conn, link))),
+            neighbours::Output::Rekey(conn, encryptor, decryptor) => self.queue.push_back(Output::Event(LogicEvent::Rekey(conn, encryptor, decryptor))),
+            neighbours::Output::AuthFailed(node, pair) => self.journal.record(now_ms, JournalEvent::AuthFailed { node, remote: pair.remote }),
             neighbours::Output::OnResourceEmpty => {
                 log::info!("[ControllerPlane] Neighbours OnResourceEmpty");
             }
         }
     }
 
+    fn share_connection_event(&mut self, now_ms: u64, event: ConnectionEvent) {
+        self.journal.on_connection_event(now_ms, &event);
+        self.features
+            .input(&mut self.switcher)
+            .on_shared_input(&self.feature_ctx, now_ms, FeatureSharedInput::Connection(event.clone()));
+        self.services
+            .input(&mut self.switcher)
+            .on_shared_input(&self.service_ctx, now_ms, ServiceSharedInput::Connection(event));
+    }
+
     fn pop_features(&mut self, now_ms: u64) {
         let out = return_if_none!(self.features.pop_output(now_ms, &mut self.switcher));
 
@@ -223,12 +679,20 @@ where
         };
 
         match out {
-            FeatureOutput::ToWorker(is_broadcast, to) => self.queue.push_back(Output::Event(LogicEvent::Feature(is_broadcast, to))),
+            FeatureOutput::ToWorker(is_broadcast, to) => {
+                if let FeaturesToWorker::RouterSync(delta) = &to {
+                    self.journal.on_router_delta(now_ms, delta);
+                }
+                self.queue.push_back(Output::Event(LogicEvent::Feature(is_broadcast, to)))
+            }
             FeatureOutput::Event(actor, event) => {
                 log::debug!("[Controller] send FeatureEvent to actor {:?}, event {:?}", actor, event);
                 match actor {
                     FeatureControlActor::Controller(userdata) => self.queue.push_back(Output::Ext(ExtOut::FeaturesEvent(userdata, event))),
-                    FeatureControlActor::Worker(worker, userdata) => self.queue.push_back(Output::Event(LogicEvent::ExtFeaturesEvent(worker, userdata, event))),
+                    FeatureControlActor::Worker(worker, userdata) => {
+                        let worker = StickyExt::route(self.sticky_ext.as_ref(), worker, &userdata);
+                        self.queue.push_back(Output::Event(LogicEvent::ExtFeaturesEvent(worker, userdata, event)))
+                    }
                     FeatureControlActor::Service(service) => {
                         self.services.input(&mut self.switcher).on_input(&self.service_ctx, now_ms, service, ServiceInput::FeatureEvent(event));
                     }
@@ -249,6 +713,12 @@ where
             FeatureOutput::NeighboursDisconnectFrom(node) => {
                 self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::DisconnectFrom(node));
             }
+            FeatureOutput::NeighboursRestart(conn) => {
+                self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::Restart(conn));
+            }
+            FeatureOutput::RouterSyncRelayLoad(load) => {
+                self.features.input(&mut self.switcher).set_relay_load(load);
+            }
             FeatureOutput::OnResourceEmpty => {
                 log::info!("[ControllerPlane] Feature {feature:?} OnResourceEmpty");
             }
@@ -260,6 +730,10 @@ where
 
         let (service, out) = match out {
             services::Output::Output(service, out) => (service, out),
+            services::Output::Ready(service) => {
+                self.queue.push_back(Output::Ext(ExtOut::ServiceReady(service)));
+                return;
+            }
             services::Output::OnResourceEmpty => {
                 log::info!("[ControllerPlane] Services OnResourceEmpty");
                 return;
@@ -274,7 +748,10 @@ where
             }
             ServiceOutput::Event(actor, event) => match actor {
                 ServiceControlActor::Controller(userdata) => self.queue.push_back(Output::Ext(ExtOut::ServicesEvent(service, userdata, event))),
-                ServiceControlActor::Worker(worker, userdata) => self.queue.push_back(Output::Event(LogicEvent::ExtServicesEvent(worker, service, userdata, event))),
+                ServiceControlActor::Worker(worker, userdata) => {
+                    let worker = StickyExt::route(self.sticky_ext.as_ref(), worker, &userdata);
+                    self.queue.push_back(Output::Event(LogicEvent::ExtServicesEvent(worker, service, userdata, event)))
+                }
             },
             ServiceOutput::BroadcastWorkers(to) => self.queue.push_back(Output::Event(LogicEvent::Service(service, to))),
             ServiceOutput::OnResourceEmpty => {
@@ -314,3 +791,29 @@ where
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{CheckpointError, StateCheckpoint, CHECKPOINT_VERSION};
+
+    #[test]
+    fn decode_checkpoint_of_same_node() {
+        let checkpoint = StateCheckpoint {
+            node_id: 1,
+            session: 1000,
+            neighbours: vec![(2, "1.2.3.4:1000".parse().expect("Should parse"))],
+            features: Default::default(),
+            services: vec![(1, vec![1, 2, 3])],
+        };
+        let buf = checkpoint.encode();
+        assert_eq!(buf[0], CHECKPOINT_VERSION);
+        assert_eq!(StateCheckpoint::decode(1, &buf), Ok(checkpoint));
+        assert_eq!(StateCheckpoint::decode(2, &buf), Err(CheckpointError::NodeMismatch(1)));
+        assert_eq!(StateCheckpoint::decode(1, &[]), Err(CheckpointError::Invalid));
+        assert_eq!(StateCheckpoint::decode(1, &buf[..buf.len() - 1]), Err(CheckpointError::Invalid));
+
+        let mut future = buf.clone();
+        future[0] = CHECKPOINT_VERSION + 1;
+        assert_eq!(StateCheckpoint::decode(1, &future), Err(CheckpointError::Version(CHECKPOINT_VERSION + 1)));
+    }
+}
@@ -1,12 +1,20 @@
 use std::fmt::Debug;
 use std::hash::Hash;
+use std::sync::Arc;
 
 use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_router::{
+    core::{RouterSync, RoutingPolicy},
+    ServicePlacement,
+};
 use sans_io_runtime::{TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
+use serde::{Deserialize, Serialize};
 
-use crate::base::{Feature, FeatureContext, FeatureInput, FeatureOutput, FeatureSharedInput};
+use crate::base::{Capabilities, CompressionConfig, Feature, FeatureContext, FeatureInput, FeatureOutput, FeatureSharedInput, LatencyProfile};
 use crate::features::*;
 
+use super::ControllerMetrics;
+
 pub type FeaturesInput<'a, UserData> = FeatureInput<'a, UserData, FeaturesControl, FeaturesToController>;
 pub type FeaturesOutput<UserData> = FeatureOutput<UserData, FeaturesEvent, FeaturesToWorker<UserData>>;
 
@@ -15,6 +23,16 @@ pub enum Output<UserData> {
     Shutdown,
 }
 
+/// Feature states which are kept across restarts. Pubsub relays and rpc calls are short-lived, so they are built again
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeaturesCheckpoint {
+    /// Last sync of each neighbour node
+    routes: Vec<(NodeId, RouterSync)>,
+    aliases: alias::AliasCheckpoint,
+    /// dht_kv slots which are served by this node
+    kv_slots: Vec<dht_kv::StoredSlot>,
+}
+
 ///
 /// FeatureManager is a manager for all features
 /// This will take-care of how to route the input to the correct feature
@@ -30,27 +48,55 @@ pub struct FeatureManager<UserData> {
     pubsub: TaskSwitcherBranch<pubsub::PubSubFeature<UserData>, pubsub::Output<UserData>>,
     alias: TaskSwitcherBranch<alias::AliasFeature<UserData>, alias::Output<UserData>>,
     socket: TaskSwitcherBranch<socket::SocketFeature<UserData>, socket::Output<UserData>>,
+    nat_traversal: TaskSwitcherBranch<nat_traversal::NatTraversalFeature<UserData>, nat_traversal::Output<UserData>>,
+    rpc: TaskSwitcherBranch<rpc::RpcFeature<UserData>, rpc::Output<UserData>>,
+    membership: TaskSwitcherBranch<membership::MembershipFeature<UserData>, membership::Output<UserData>>,
+    diag: TaskSwitcherBranch<diag::DiagFeature<UserData>, diag::Output<UserData>>,
     switcher: TaskSwitcher,
     shutdown: bool,
 }
 
 impl<UserData: 'static + Hash + Eq + Copy + Debug> FeatureManager<UserData> {
-    pub fn new(node: NodeId, session: u64, services: Vec<u8>) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        node: NodeId,
+        session: u64,
+        services: Vec<u8>,
+        placements: Vec<(u8, ServicePlacement)>,
+        profile: LatencyProfile,
+        dht_kv_storage: Option<Arc<dyn dht_kv::KvStorageBackend>>,
+        channel_authorizer: Option<Arc<dyn pubsub::ChannelAuthorizer>>,
+        observer: bool,
+        compression: Option<CompressionConfig>,
+        routing_policy: RoutingPolicy,
+        capabilities: Capabilities,
+    ) -> Self {
         Self {
             neighbours: TaskSwitcherBranch::default(Features::Neighbours as usize),
             data: TaskSwitcherBranch::default(Features::Data as usize),
-            router_sync: TaskSwitcherBranch::new(router_sync::RouterSyncFeature::new(node, services), Features::RouterSync as usize),
+            router_sync: TaskSwitcherBranch::new(
+                router_sync::RouterSyncFeature::new(node, services, placements, observer, routing_policy).with_capabilities(capabilities),
+                Features::RouterSync as usize,
+            ),
             vpn: TaskSwitcherBranch::default(Features::Vpn as usize),
-            dht_kv: TaskSwitcherBranch::new(dht_kv::DhtKvFeature::new(node, session), Features::DhtKv as usize),
-            pubsub: TaskSwitcherBranch::new(pubsub::PubSubFeature::new(), Features::PubSub as usize),
-            alias: TaskSwitcherBranch::default(Features::Alias as usize),
+            dht_kv: TaskSwitcherBranch::new(dht_kv::DhtKvFeature::new(node, session, dht_kv_storage).with_compression(compression), Features::DhtKv as usize),
+            pubsub: TaskSwitcherBranch::new(pubsub::PubSubFeature::new(profile).with_authorizer(channel_authorizer), Features::PubSub as usize),
+            alias: TaskSwitcherBranch::new(alias::AliasFeature::new(profile), Features::Alias as usize),
             socket: TaskSwitcherBranch::default(Features::Socket as usize),
-            switcher: TaskSwitcher::new(8),
+            nat_traversal: TaskSwitcherBranch::default(Features::NatTraversal as usize),
+            rpc: TaskSwitcherBranch::default(Features::Rpc as usize),
+            membership: TaskSwitcherBranch::default(Features::Membership as usize),
+            diag: TaskSwitcherBranch::default(Features::Diag as usize),
+            switcher: TaskSwitcher::new(12),
             shutdown: false,
         }
     }
 
     pub fn on_shared_input(&mut self, ctx: &FeatureContext, now_ms: u64, input: FeatureSharedInput) {
+        if let FeatureSharedInput::Tick(_) = input {
+            let router_sync = &self.router_sync;
+            self.pubsub.input(&mut self.switcher).rebalance(now_ms, |source| router_sync.relay_paths(source));
+        }
         self.data.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
         self.neighbours.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
         self.router_sync.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
@@ -58,7 +104,68 @@ impl<UserData: 'static + Hash + Eq + Copy + Debug> FeatureManager<UserData> {
         self.vpn.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
         self.pubsub.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
         self.alias.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
-        self.socket.input(&mut self.switcher).on_shared_input(ctx, now_ms, input);
+        self.socket.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
+        self.nat_traversal.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
+        self.rpc.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
+        self.membership.input(&mut self.switcher).on_shared_input(ctx, now_ms, input.clone());
+        self.diag.input(&mut self.switcher).on_shared_input(ctx, now_ms, input);
+    }
+
+    pub fn set_relay_load(&mut self, load: u8) {
+        self.router_sync.input(&mut self.switcher).set_relay_load(load);
+    }
+
+    /// Routes of neighbour connections, which are replicated to a standby controller
+    pub fn router_snapshot(&self) -> router_sync::RouterSyncSnapshot {
+        self.router_sync.snapshot()
+    }
+
+    pub fn restore_router(&mut self, snapshot: router_sync::RouterSyncSnapshot) {
+        self.router_sync.input(&mut self.switcher).restore(snapshot);
+    }
+
+    pub fn checkpoint(&self, now_ms: u64) -> FeaturesCheckpoint {
+        FeaturesCheckpoint {
+            routes: self.router_sync.checkpoint(),
+            aliases: self.alias.checkpoint(now_ms),
+            kv_slots: self.dht_kv.checkpoint(),
+        }
+    }
+
+    pub fn restore_checkpoint(&mut self, now_ms: u64, checkpoint: FeaturesCheckpoint) {
+        self.router_sync.input(&mut self.switcher).restore_checkpoint(now_ms, checkpoint.routes);
+        self.alias.input(&mut self.switcher).restore_checkpoint(now_ms, checkpoint.aliases);
+        self.dht_kv.input(&mut self.switcher).restore_checkpoint(now_ms, checkpoint.kv_slots);
+    }
+
+    /// Stop taking new responsibilities and hand over existing relays to neighbours
+    pub fn decommission(&mut self, ctx: &FeatureContext) {
+        self.router_sync.input(&mut self.switcher).decommission();
+        self.dht_kv.input(&mut self.switcher).decommission();
+        self.pubsub.input(&mut self.switcher).decommission(ctx);
+    }
+
+    /// Remaining responsibilities of this node: (remote relayed channels, served dht_kv maps)
+    pub fn decommission_remains(&self) -> (usize, usize) {
+        (self.pubsub.remote_relays(), self.dht_kv.served_maps())
+    }
+
+    /// Readiness of a feature for services which depend on it, see [`crate::base::ServiceDependency::Feature`]
+    pub fn is_ready(&self, feature: Features) -> bool {
+        match feature {
+            Features::RouterSync => self.router_sync.is_converged(),
+            Features::DhtKv | Features::PubSub | Features::Alias => self.router_sync.routes() > 0,
+            _ => true,
+        }
+    }
+
+    /// Fill per-feature gauges
+    pub fn metrics(&self, metrics: &mut ControllerMetrics) {
+        metrics.routes = self.router_sync.routes();
+        metrics.dht_kv_maps = self.dht_kv.served_maps();
+        metrics.pubsub_remote_relays = self.pubsub.remote_relays();
+        metrics.pubsub_relay_fanout = self.pubsub.relay_fanout();
+        metrics.alias_parked_msgs = self.alias.parked_msgs();
     }
 
     pub fn on_input(&mut self, ctx: &FeatureContext, now_ms: u64, feature: Features, input: FeaturesInput<'_, UserData>) {
@@ -72,6 +179,10 @@ impl<UserData: 'static + Hash + Eq + Copy + Debug> FeatureManager<UserData> {
                 FeaturesToController::PubSub(to) => self.pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to)),
                 FeaturesToController::Alias(to) => self.alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to)),
                 FeaturesToController::Socket(to) => self.socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to)),
+                FeaturesToController::NatTraversal(to) => self.nat_traversal.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to)),
+                FeaturesToController::Rpc(to) => self.rpc.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to)),
+                FeaturesToController::Membership(to) => self.membership.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to)),
+                FeaturesToController::Diag(to) => self.diag.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::FromWorker(to)),
             },
             FeatureInput::Control(service, control) => match control {
                 FeaturesControl::Data(control) => self.data.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control)),
@@ -82,6 +193,10 @@ impl<UserData: 'static + Hash + Eq + Copy + Debug> FeatureManager<UserData> {
                 FeaturesControl::PubSub(control) => self.pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control)),
                 FeaturesControl::Alias(control) => self.alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control)),
                 FeaturesControl::Socket(control) => self.socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control)),
+                FeaturesControl::NatTraversal(control) => self.nat_traversal.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control)),
+                FeaturesControl::Rpc(control) => self.rpc.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control)),
+                FeaturesControl::Membership(control) => self.membership.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control)),
+                FeaturesControl::Diag(control) => self.diag.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Control(service, control)),
             },
             FeatureInput::Net(con_ctx, header, buf) => match feature {
                 Features::Data => self.data.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf)),
@@ -92,6 +207,10 @@ impl<UserData: 'static + Hash + Eq + Copy + Debug> FeatureManager<UserData> {
                 Features::PubSub => self.pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf)),
                 Features::Alias => self.alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf)),
                 Features::Socket => self.socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf)),
+                Features::NatTraversal => self.nat_traversal.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf)),
+                Features::Rpc => self.rpc.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf)),
+                Features::Membership => self.membership.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf)),
+                Features::Diag => self.diag.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Net(con_ctx, header, buf)),
             },
             FeatureInput::Local(header, buf) => match feature {
                 Features::Data => self.data.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf)),
@@ -102,6 +221,10 @@ impl<UserData: 'static + Hash + Eq + Copy + Debug> FeatureManager<UserData> {
                 Features::PubSub => self.pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf)),
                 Features::Alias => self.alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf)),
                 Features::Socket => self.socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf)),
+                Features::NatTraversal => self.nat_traversal.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf)),
+                Features::Rpc => self.rpc.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf)),
+                Features::Membership => self.membership.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf)),
+                Features::Diag => self.diag.input(&mut self.switcher).on_input(ctx, now_ms, FeatureInput::Local(header, buf)),
             },
         }
     }
@@ -119,6 +242,10 @@ impl<UserData: 'static + Hash + Eq + Copy + Debug> FeatureManager<UserData> {
         self.pubsub.input(&mut self.switcher).on_shutdown(ctx, now_ms);
         self.alias.input(&mut self.switcher).on_shutdown(ctx, now_ms);
         self.socket.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        self.nat_traversal.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        self.rpc.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        self.membership.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        self.diag.input(&mut self.switcher).on_shutdown(ctx, now_ms);
         self.shutdown = true;
     }
 }
@@ -140,6 +267,10 @@ impl<UserData: Hash + Eq + Copy + Debug> TaskSwitcherChild<Output<UserData>> for
             && self.pubsub.is_empty()
             && self.alias.is_empty()
             && self.socket.is_empty()
+            && self.nat_traversal.is_empty()
+            && self.rpc.is_empty()
+            && self.membership.is_empty()
+            && self.diag.is_empty()
     }
 
     fn pop_output<'a>(&mut self, now: u64) -> Option<Output<UserData>> {
@@ -185,6 +316,26 @@ impl<UserData: Hash + Eq + Copy + Debug> TaskSwitcherChild<Output<UserData>> for
                         return Some(Output::Output(Features::Socket, out.into2()));
                     }
                 }
+                Features::NatTraversal => {
+                    if let Some(out) = self.nat_traversal.pop_output(now, &mut self.switcher) {
+                        return Some(Output::Output(Features::NatTraversal, out.into2()));
+                    }
+                }
+                Features::Rpc => {
+                    if let Some(out) = self.rpc.pop_output(now, &mut self.switcher) {
+                        return Some(Output::Output(Features::Rpc, out.into2()));
+                    }
+                }
+                Features::Membership => {
+                    if let Some(out) = self.membership.pop_output(now, &mut self.switcher) {
+                        return Some(Output::Output(Features::Membership, out.into2()));
+                    }
+                }
+                Features::Diag => {
+                    if let Some(out) = self.diag.pop_output(now, &mut self.switcher) {
+                        return Some(Output::Output(Features::Diag, out.into2()));
+                    }
+                }
             }
         }
     }
@@ -0,0 +1,322 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+};
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_router::shadow::ShadowRouterDelta;
+use parking_lot::Mutex;
+use sans_io_runtime::return_if_none;
+use serde::{Deserialize, Serialize};
+
+use crate::{base::ConnectionEvent, data_plane::NetPair, features::router_sync};
+
+/// Entries which are kept in memory for [`crate::ExtIn::ControlQuery`], the oldest ones are dropped
+pub const EVENT_JOURNAL_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JournalEvent {
+    Connected {
+        node: NodeId,
+        remote: SocketAddr,
+    },
+    Disconnected {
+        node: NodeId,
+        remote: SocketAddr,
+    },
+    /// Neighbour control which failed signature, decoding or freshness check, the node is only claimed by the sender
+    AuthFailed {
+        node: NodeId,
+        remote: SocketAddr,
+    },
+    /// Best path of a routing table slot, None when the slot has no path anymore
+    RouteChanged {
+        layer: u8,
+        index: u8,
+        next: Option<SocketAddr>,
+    },
+    /// Service is registered at a node, None for the local node
+    ServiceRegistered {
+        service: u8,
+        node: Option<NodeId>,
+    },
+    ServiceUnregistered {
+        service: u8,
+        node: Option<NodeId>,
+    },
+}
+
+impl JournalEvent {
+    fn is_warning(&self) -> bool {
+        matches!(self, JournalEvent::AuthFailed { .. })
+    }
+}
+
+impl Display for JournalEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JournalEvent::Connected { node, remote } => write!(f, "connected node={node} remote={remote}"),
+            JournalEvent::Disconnected { node, remote } => write!(f, "disconnected node={node} remote={remote}"),
+            JournalEvent::AuthFailed { node, remote } => write!(f, "auth_failed node={node} remote={remote}"),
+            JournalEvent::RouteChanged { layer, index, next: Some(next) } => write!(f, "route_changed layer={layer} index={index} next={next}"),
+            JournalEvent::RouteChanged { layer, index, next: None } => write!(f, "route_changed layer={layer} index={index} next=none"),
+            JournalEvent::ServiceRegistered { service, node: Some(node) } => write!(f, "service_registered service={service} node={node}"),
+            JournalEvent::ServiceRegistered { service, node: None } => write!(f, "service_registered service={service} node=local"),
+            JournalEvent::ServiceUnregistered { service, node: Some(node) } => write!(f, "service_unregistered service={service} node={node}"),
+            JournalEvent::ServiceUnregistered { service, node: None } => write!(f, "service_unregistered service={service} node=local"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Increasing number of the entry, it continues over dropped entries so a reader can detect gaps
+    pub seq: u64,
+    pub ts_ms: u64,
+    pub event: JournalEvent,
+}
+
+impl Display for JournalEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "seq={} ts_ms={} {}", self.seq, self.ts_ms, self.event)
+    }
+}
+
+/// Select entries of the journal, the answer is [`crate::ExtOut::EventLog`] with entries in order
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EventLogQuery {
+    /// Only entries with seq greater than it, for continue reading after the last received entry
+    pub after_seq: Option<u64>,
+    /// Only entries which are recorded at or after it
+    pub since_ms: u64,
+    /// Max number of entries, the oldest matched ones are returned first. 0 for no limit
+    pub limit: usize,
+}
+
+/// External destination of journal entries, it is called in the controller loop so it should not block for long
+pub trait EventSink: Send + Sync {
+    fn write(&self, entry: &JournalEntry);
+}
+
+/// Append each entry as a line to a file
+pub struct FileEventSink {
+    file: Mutex<File>,
+}
+
+impl FileEventSink {
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file: Mutex::new(file) })
+    }
+}
+
+impl EventSink for FileEventSink {
+    fn write(&self, entry: &JournalEntry) {
+        if let Err(e) = writeln!(self.file.lock(), "{entry}") {
+            log::warn!("[FileEventSink] write entry {} error {e}", entry.seq);
+        }
+    }
+}
+
+/// Send each entry to the local syslog daemon with the daemon facility
+#[cfg(unix)]
+pub struct SyslogEventSink {
+    socket: std::os::unix::net::UnixDatagram,
+    ident: String,
+}
+
+#[cfg(unix)]
+impl SyslogEventSink {
+    const FACILITY_DAEMON: u8 = 3;
+    const SEVERITY_WARNING: u8 = 4;
+    const SEVERITY_INFO: u8 = 6;
+
+    /// Connect to the syslog socket, which is `/dev/log` on most systems
+    pub fn connect<P: AsRef<Path>>(path: P, ident: &str) -> std::io::Result<Self> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, ident: ident.to_string() })
+    }
+}
+
+#[cfg(unix)]
+impl EventSink for SyslogEventSink {
+    fn write(&self, entry: &JournalEntry) {
+        let severity = if entry.event.is_warning() {
+            Self::SEVERITY_WARNING
+        } else {
+            Self::SEVERITY_INFO
+        };
+        let line = format!("<{}>{}: {entry}", Self::FACILITY_DAEMON * 8 + severity, self.ident);
+        if let Err(e) = self.socket.send(line.as_bytes()) {
+            log::warn!("[SyslogEventSink] send entry {} error {e}", entry.seq);
+        }
+    }
+}
+
+/// Bounded journal of network events, each entry is also written to all sinks
+pub struct EventJournal {
+    capacity: usize,
+    next_seq: u64,
+    entries: VecDeque<JournalEntry>,
+    sinks: Vec<Arc<dyn EventSink>>,
+    /// Destination of each remote service path, for journaling a path only when it appears or disappears
+    service_paths: HashMap<(u8, NetPair), NodeId>,
+}
+
+impl EventJournal {
+    pub fn new(capacity: usize, sinks: Vec<Arc<dyn EventSink>>) -> Self {
+        Self {
+            capacity,
+            next_seq: 0,
+            entries: VecDeque::new(),
+            sinks,
+            service_paths: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, now_ms: u64, event: JournalEvent) {
+        let entry = JournalEntry {
+            seq: self.next_seq,
+            ts_ms: now_ms,
+            event,
+        };
+        self.next_seq += 1;
+        for sink in &self.sinks {
+            sink.write(&entry);
+        }
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn on_connection_event(&mut self, now_ms: u64, event: &ConnectionEvent) {
+        match event {
+            ConnectionEvent::Connected(ctx, _) => self.record(
+                now_ms,
+                JournalEvent::Connected {
+                    node: ctx.node,
+                    remote: ctx.pair.remote,
+                },
+            ),
+            ConnectionEvent::Disconnected(ctx) => self.record(
+                now_ms,
+                JournalEvent::Disconnected {
+                    node: ctx.node,
+                    remote: ctx.pair.remote,
+                },
+            ),
+            ConnectionEvent::Stats(..) | ConnectionEvent::Capabilities(..) | ConnectionEvent::ConnectFailed(..) => {}
+        }
+    }
+
+    pub fn on_router_delta(&mut self, now_ms: u64, delta: &router_sync::ToWorker) {
+        let event = match delta {
+            ShadowRouterDelta::SetTable { layer, index, next } => JournalEvent::RouteChanged {
+                layer: *layer,
+                index: *index,
+                next: Some(next.remote),
+            },
+            ShadowRouterDelta::DelTable { layer, index } => JournalEvent::RouteChanged {
+                layer: *layer,
+                index: *index,
+                next: None,
+            },
+            ShadowRouterDelta::SetServiceLocal { service } => JournalEvent::ServiceRegistered { service: *service, node: None },
+            ShadowRouterDelta::DelServiceLocal { service } => JournalEvent::ServiceUnregistered { service: *service, node: None },
+            ShadowRouterDelta::SetServiceRemote { service, conn, dest, .. } => {
+                // score and load of a path are updated often, only a new destination is journaled
+                if self.service_paths.insert((*service, *conn), *dest) == Some(*dest) {
+                    return;
+                }
+                JournalEvent::ServiceRegistered { service: *service, node: Some(*dest) }
+            }
+            ShadowRouterDelta::DelServiceRemote { service, conn } => {
+                let dest = return_if_none!(self.service_paths.remove(&(*service, *conn)));
+                JournalEvent::ServiceUnregistered { service: *service, node: Some(dest) }
+            }
+            _ => return,
+        };
+        self.record(now_ms, event);
+    }
+
+    pub fn query(&self, query: &EventLogQuery) -> Vec<JournalEntry> {
+        let matched = self
+            .entries
+            .iter()
+            .filter(|entry| query.after_seq.map_or(true, |after| entry.seq > after) && entry.ts_ms >= query.since_ms);
+        if query.limit == 0 {
+            matched.cloned().collect()
+        } else {
+            matched.take(query.limit).cloned().collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use atm0s_sdn_router::shadow::ShadowRouterDelta;
+
+    use crate::data_plane::NetPair;
+
+    use super::{EventJournal, EventLogQuery, JournalEvent};
+
+    fn pair(port: u16) -> NetPair {
+        NetPair::new(SocketAddr::from(([127, 0, 0, 1], 10000)), SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    #[test]
+    fn drop_oldest_and_query_after_seq() {
+        let mut journal = EventJournal::new(2, vec![]);
+        for index in 0..3 {
+            journal.record(100 + index as u64, JournalEvent::RouteChanged { layer: 0, index, next: None });
+        }
+
+        let all = journal.query(&EventLogQuery::default());
+        assert_eq!(all.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![1, 2]);
+
+        let after = journal.query(&EventLogQuery {
+            after_seq: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(after.len(), 1);
+        assert_eq!(after[0].ts_ms, 102);
+    }
+
+    #[test]
+    fn journal_service_path_only_when_destination_changes() {
+        let mut journal = EventJournal::new(16, vec![]);
+        let set = |dest, score| ShadowRouterDelta::SetServiceRemote {
+            service: 1,
+            conn: pair(20000),
+            next: 2,
+            dest,
+            score,
+            load: 0,
+        };
+        journal.on_router_delta(100, &set(3, 10));
+        journal.on_router_delta(200, &set(3, 20));
+        journal.on_router_delta(300, &ShadowRouterDelta::DelServiceRemote { service: 1, conn: pair(20000) });
+
+        let events = journal.query(&EventLogQuery::default()).into_iter().map(|e| e.event).collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                JournalEvent::ServiceRegistered { service: 1, node: Some(3) },
+                JournalEvent::ServiceUnregistered { service: 1, node: Some(3) },
+            ]
+        );
+    }
+}
@@ -8,23 +8,36 @@ use atm0s_sdn_identity::{ConnId, NodeAddr, NodeId, Protocol};
 use sans_io_runtime::TaskSwitcherChild;
 
 use crate::{
-    base::{self, Authorization, ConnectionCtx, HandshakeBuilder, NeighboursControl, NeighboursControlCmds, SecureContext},
+    base::{
+        self, Authorization, ConnectFailReason, ConnectionCtx, Decryptor, Encryptor, HandshakeBuilder, LinkProfile, NeighboursConnectError, NeighboursControl, NeighboursControlCmds, PeerCapabilities,
+        SecureContext,
+    },
     data_plane::NetPair,
 };
 
+pub use self::connection::ConnectionSnapshot;
 use self::connection::{ConnectionEvent, NeighbourConnection};
+pub use self::policy::{Admission, NeighbourPolicy};
 
 mod connection;
+mod policy;
 
 pub enum Input {
     ConnectTo(NodeAddr),
     DisconnectFrom(NodeId),
+    Restart(ConnId),
     Control(NetPair, NeighboursControl),
 }
 
 pub enum Output {
     Control(NetPair, NeighboursControl),
     Event(base::ConnectionEvent),
+    /// Negotiated link profile of a connection is changed
+    LinkProfile(ConnId, LinkProfile),
+    /// Keys of a new epoch for a connection, None for the side which is not switched yet
+    Rekey(ConnId, Option<Box<dyn Encryptor>>, Option<Box<dyn Decryptor>>),
+    /// Control from the remote failed validation, the node is only claimed by the sender
+    AuthFailed(NodeId, NetPair),
     OnResourceEmpty,
 }
 
@@ -33,34 +46,147 @@ pub struct NeighboursManager {
     bind_addrs: Vec<SocketAddr>,
     connections: HashMap<NetPair, NeighbourConnection>,
     neighbours: HashMap<ConnId, ConnectionCtx>,
+    /// outgoing connections which are requested by `ConnectTo` and not connected yet, for reporting failures with the address
+    dialing: HashMap<NetPair, NodeAddr>,
+    /// connections which will be re-connected after disconnected
+    restarting: HashMap<NetPair, NodeId>,
+    /// second connection of a pair which is restarted without a backup, it is the replacement until connected then the old one until disconnected
+    replacing: HashMap<NetPair, NeighbourConnection>,
     queue: VecDeque<Output>,
     shutdown: bool,
     authorization: Arc<dyn Authorization>,
     handshake_builder: Arc<dyn HandshakeBuilder>,
+    link: LinkProfile,
+    caps: PeerCapabilities,
+    rekey_interval_ms: Option<u64>,
+    policy: NeighbourPolicy,
     random: Box<dyn rand::RngCore>,
 }
 
 impl NeighboursManager {
-    pub fn new(node_id: NodeId, bind_addrs: Vec<SocketAddr>, authorization: Arc<dyn Authorization>, handshake_builder: Arc<dyn HandshakeBuilder>, random: Box<dyn rand::RngCore>) -> Self {
+    pub fn new(
+        node_id: NodeId,
+        bind_addrs: Vec<SocketAddr>,
+        authorization: Arc<dyn Authorization>,
+        handshake_builder: Arc<dyn HandshakeBuilder>,
+        link: LinkProfile,
+        caps: PeerCapabilities,
+        random: Box<dyn rand::RngCore>,
+    ) -> Self {
         Self {
             node_id,
             bind_addrs,
             connections: HashMap::new(),
             neighbours: HashMap::new(),
+            dialing: HashMap::new(),
+            restarting: HashMap::new(),
+            replacing: HashMap::new(),
             queue: VecDeque::new(),
             shutdown: false,
             authorization,
             handshake_builder,
+            link,
+            caps,
+            rekey_interval_ms: None,
+            policy: NeighbourPolicy::default(),
             random,
         }
     }
 
+    /// Rekey outgoing connections after each interval, None for keeping keys of connect handshake
+    pub fn with_rekey_interval(mut self, interval_ms: Option<u64>) -> Self {
+        self.rekey_interval_ms = interval_ms;
+        self
+    }
+
+    /// Limits of connected nodes, which are checked before connecting to a new node or accepting one
+    pub fn with_policy(mut self, policy: NeighbourPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
     pub fn conn(&self, conn: ConnId) -> Option<&ConnectionCtx> {
         self.neighbours.get(&conn)
     }
 
+    /// Number of connections, including connecting and disconnecting ones
+    pub fn connections(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Entries of all internal maps, for checking that state is bounded in fuzzing
+    #[cfg(feature = "fuzz")]
+    pub(crate) fn state_size(&self) -> usize {
+        self.connections.len() + self.neighbours.len() + self.dialing.len() + self.restarting.len() + self.replacing.len()
+    }
+
+    /// Node and remote address of established connections, for connecting to neighbours again after a restart
+    pub fn neighbour_addrs(&self) -> Vec<(NodeId, SocketAddr)> {
+        self.connections
+            .values()
+            .filter(|conn| conn.is_connected())
+            .map(|conn| (conn.ctx().node, conn.ctx().pair.remote))
+            .collect()
+    }
+
+    /// Nodes with at least one connection, including connecting ones, with their lowest rtt
+    fn connected_nodes(&self) -> Vec<(NodeId, Option<u32>)> {
+        let mut nodes: HashMap<NodeId, Option<u32>> = HashMap::new();
+        for conn in self.connections.values() {
+            let rtt = nodes.entry(conn.dest_node()).or_default();
+            *rtt = match (*rtt, conn.rtt_ms()) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+        }
+        nodes.into_iter().collect()
+    }
+
+    /// Check the policy for a new node, disconnecting the evicted node if needed
+    fn admit(&mut self, now_ms: u64, node: NodeId) -> bool {
+        match self.policy.admit(node, &self.connected_nodes()) {
+            Admission::Accept => true,
+            Admission::Evict(evicted) => {
+                log::info!("[Neighbours] Evict node {evicted} for node {node} by policy");
+                for conn in self.connections.values_mut() {
+                    if conn.dest_node() == evicted {
+                        conn.disconnect(now_ms);
+                    }
+                }
+                true
+            }
+            Admission::Reject => {
+                log::info!("[Neighbours] Reject node {node} by policy");
+                false
+            }
+        }
+    }
+
+    /// Established connections, which are replicated to a standby controller
+    pub fn snapshot(&self) -> Vec<ConnectionSnapshot> {
+        self.connections.values().filter_map(|conn| conn.snapshot()).collect()
+    }
+
+    /// Continue connections of a previous controller, returning their contexts and negotiated capabilities for replaying to features and services
+    pub fn adopt(&mut self, now_ms: u64, snapshots: Vec<ConnectionSnapshot>) -> Vec<(ConnectionCtx, Option<PeerCapabilities>)> {
+        let mut adopted = vec![];
+        for snapshot in snapshots {
+            if self.connections.contains_key(&snapshot.pair()) {
+                continue;
+            }
+            let remote_caps = snapshot.remote_caps();
+            let conn = NeighbourConnection::adopt(self.handshake_builder.clone(), self.caps, self.node_id, self.link, snapshot, now_ms).with_rekey_interval(self.rekey_interval_ms);
+            let ctx = conn.ctx();
+            log::info!("[Neighbours] Adopt connection {} to node {} with {}", ctx.conn, ctx.node, ctx.pair);
+            self.neighbours.insert(ctx.conn, ctx.clone());
+            self.connections.insert(ctx.pair, conn);
+            adopted.push((ctx, remote_caps));
+        }
+        adopted
+    }
+
     pub fn on_tick(&mut self, now_ms: u64, _tick_count: u64) {
-        for conn in self.connections.values_mut() {
+        for conn in self.connections.values_mut().chain(self.replacing.values_mut()) {
             conn.on_tick(now_ms);
         }
     }
@@ -68,8 +194,15 @@ impl NeighboursManager {
     pub fn on_input(&mut self, now_ms: u64, input: Input) {
         match input {
             Input::ConnectTo(addr) => {
+                if self.shutdown {
+                    log::warn!("[Neighbours] Ignore connect to {addr} while shutting down");
+                    return;
+                }
                 let dest_node = addr.node_id();
-                let dests = get_node_addr_dests(addr);
+                if !self.admit(now_ms, dest_node) {
+                    return;
+                }
+                let dests = get_node_addr_dests(addr.clone());
                 for local in &self.bind_addrs {
                     for remote in &dests {
                         if local.is_ipv4() != remote.is_ipv4() {
@@ -82,34 +215,92 @@ impl NeighboursManager {
                         }
                         log::info!("[Neighbours] Sending connect request from {local} to {remote}, dest_node {dest_node}");
                         let session_id = self.random.next_u64();
-                        let conn = NeighbourConnection::new_outgoing(self.handshake_builder.clone(), self.node_id, dest_node, session_id, pair, now_ms);
+                        let conn = NeighbourConnection::new_outgoing(self.handshake_builder.clone(), self.link, self.caps, self.node_id, dest_node, session_id, pair, now_ms)
+                            .with_rekey_interval(self.rekey_interval_ms);
                         self.connections.insert(pair, conn);
+                        self.dialing.insert(pair, addr.clone());
                     }
                 }
             }
             Input::DisconnectFrom(node) => {
-                for conn in self.connections.values_mut() {
+                for conn in self.connections.values_mut().chain(self.replacing.values_mut()) {
                     if conn.dest_node() == node {
                         conn.disconnect(now_ms);
                     }
                 }
             }
+            Input::Restart(conn) => {
+                if self.shutdown {
+                    log::warn!("[Neighbours] Ignore restart {conn} while shutting down");
+                    return;
+                }
+                let ctx = if let Some(ctx) = self.neighbours.get(&conn) {
+                    ctx.clone()
+                } else {
+                    log::warn!("[Neighbours] Restart unknown connection {conn}");
+                    return;
+                };
+                if self.replacing.contains_key(&ctx.pair) {
+                    log::warn!("[Neighbours] Ignore restart {conn}, connection with {} is already restarting", ctx.pair);
+                    return;
+                }
+                // if we have other connections to same node, routing will use them while this connection is restarting
+                let has_backup = self.neighbours.values().any(|other| other.node == ctx.node && other.conn != conn);
+                log::info!("[Neighbours] Restart connection {conn} to node {} with {}, has backup {has_backup}", ctx.node, ctx.pair);
+                if has_backup {
+                    if let Some(connection) = self.connections.get_mut(&ctx.pair) {
+                        self.restarting.insert(ctx.pair, ctx.node);
+                        connection.disconnect(now_ms);
+                    }
+                } else {
+                    // make before break: the old connection is disconnected after the replacement is connected
+                    let session_id = self.random.next_u64();
+                    let replacement = NeighbourConnection::new_outgoing(self.handshake_builder.clone(), self.link, self.caps, self.node_id, ctx.node, session_id, ctx.pair, now_ms)
+                        .with_rekey_interval(self.rekey_interval_ms);
+                    self.replacing.insert(ctx.pair, replacement);
+                }
+            }
             Input::Control(addr, control) => {
                 let cmd: NeighboursControlCmds = match control.validate(now_ms, &*self.authorization) {
                     Ok(cmd) => cmd,
                     Err(_) => {
                         log::warn!("[Neighbours] Invalid control from {:?}", addr);
+                        self.queue.push_back(Output::AuthFailed(control.from, addr));
                         return;
                     }
                 };
 
                 log::debug!("[NeighboursManager] received Control(addr: {:?}, cmd: {:?})", addr, cmd);
-                if let Some(conn) = self.connections.get_mut(&addr) {
+                let session = cmd.session();
+                if let Some(conn) = self.replacing.get_mut(&addr).filter(|conn| conn.ctx().conn.session() == session) {
                     conn.on_input(now_ms, control.from, cmd);
+                } else if let Some(conn) = self.connections.get_mut(&addr) {
+                    match cmd {
+                        NeighboursControlCmds::ConnectRequest { .. } if conn.is_connected() && conn.ctx().conn.session() != session && !self.replacing.contains_key(&addr) => {
+                            // remote restarts the connection, the old one is kept until remote disconnects it
+                            log::info!("[Neighbours] Remote {} restarts connection {} with {addr}", control.from, conn.ctx().conn);
+                            let mut replacement = NeighbourConnection::new_incoming(self.handshake_builder.clone(), self.link, self.caps, self.node_id, control.from, session, addr, now_ms)
+                                .with_rekey_interval(self.rekey_interval_ms);
+                            replacement.on_input(now_ms, control.from, cmd);
+                            self.replacing.insert(addr, replacement);
+                        }
+                        cmd => conn.on_input(now_ms, control.from, cmd),
+                    }
                 } else {
                     match cmd {
+                        NeighboursControlCmds::ConnectRequest { .. } if self.shutdown => {
+                            log::warn!("[Neighbours] Reject connect request from {:?} while shutting down", addr);
+                        }
+                        NeighboursControlCmds::ConnectRequest { session, .. } if !self.admit(now_ms, control.from) => {
+                            let cmd = NeighboursControlCmds::ConnectResponse {
+                                session,
+                                result: Err(NeighboursConnectError::Rejected),
+                            };
+                            self.queue.push_back(Output::Control(addr, NeighboursControl::build(now_ms, self.node_id, cmd, &*self.authorization)));
+                        }
                         NeighboursControlCmds::ConnectRequest { session, .. } => {
-                            let mut conn = NeighbourConnection::new_incoming(self.handshake_builder.clone(), self.node_id, control.from, session, addr, now_ms);
+                            let mut conn = NeighbourConnection::new_incoming(self.handshake_builder.clone(), self.link, self.caps, self.node_id, control.from, session, addr, now_ms)
+                                .with_rekey_interval(self.rekey_interval_ms);
                             conn.on_input(now_ms, control.from, cmd);
                             self.connections.insert(addr, conn);
                         }
@@ -127,7 +318,7 @@ impl NeighboursManager {
             return;
         }
         self.shutdown = true;
-        for conn in self.connections.values_mut() {
+        for conn in self.connections.values_mut().chain(self.replacing.values_mut()) {
             conn.disconnect(now_ms);
         }
     }
@@ -141,16 +332,21 @@ impl TaskSwitcherChild<Output> for NeighboursManager {
     }
 
     fn is_empty(&self) -> bool {
-        self.shutdown && self.connections.is_empty() && self.queue.is_empty()
+        self.shutdown && self.connections.is_empty() && self.replacing.is_empty() && self.queue.is_empty()
     }
 
-    fn pop_output(&mut self, _now: u64) -> Option<Output> {
+    fn pop_output(&mut self, now_ms: u64) -> Option<Output> {
         if let Some(output) = self.queue.pop_front() {
             return Some(output);
         }
 
         let mut to_remove = Vec::new();
-        for (remote, conn) in self.connections.iter_mut() {
+        let mut failed = Vec::new();
+        let mut promoted = Vec::new();
+        let mut replace_failed = Vec::new();
+        let mut replaced = Vec::new();
+        let conns = self.connections.iter_mut().map(|conn| (false, conn)).chain(self.replacing.iter_mut().map(|conn| (true, conn)));
+        for (replacing, (remote, conn)) in conns {
             while let Some(output) = conn.pop_output() {
                 match output {
                     connection::Output::Event(event) => {
@@ -158,24 +354,48 @@ impl TaskSwitcherChild<Output> for NeighboursManager {
                             ConnectionEvent::Connected(encryptor, decryptor) => {
                                 let ctx = conn.ctx();
                                 self.neighbours.insert(ctx.conn, ctx.clone());
-                                Some(base::ConnectionEvent::Connected(ctx, SecureContext { encryptor, decryptor }))
+                                if replacing {
+                                    promoted.push(*remote);
+                                } else {
+                                    self.dialing.remove(remote);
+                                }
+                                Some(base::ConnectionEvent::Connected(ctx, SecureContext::new(encryptor, decryptor)))
                             }
-                            ConnectionEvent::ConnectError(_) => {
+                            ConnectionEvent::ConnectError(_) | ConnectionEvent::ConnectTimeout if replacing => {
+                                replace_failed.push((*remote, conn.ctx().conn.is_outgoing()));
+                                None
+                            }
+                            ConnectionEvent::ConnectError(err) => {
                                 to_remove.push(*remote);
+                                failed.push((*remote, ConnectFailReason::Error(err)));
                                 None
                             }
                             ConnectionEvent::ConnectTimeout => {
                                 to_remove.push(*remote);
+                                failed.push((*remote, ConnectFailReason::Timeout));
                                 None
                             }
                             ConnectionEvent::Stats(stats) => {
                                 let ctx = conn.ctx();
                                 Some(base::ConnectionEvent::Stats(ctx, stats))
                             }
+                            ConnectionEvent::Link(link) => {
+                                self.queue.push_back(Output::LinkProfile(conn.ctx().conn, link));
+                                None
+                            }
+                            ConnectionEvent::Rekey(encryptor, decryptor) => {
+                                self.queue.push_back(Output::Rekey(conn.ctx().conn, encryptor, decryptor));
+                                None
+                            }
+                            ConnectionEvent::Capabilities(caps) => Some(base::ConnectionEvent::Capabilities(conn.ctx(), caps)),
                             ConnectionEvent::Disconnected => {
                                 let ctx = conn.ctx();
                                 self.neighbours.remove(&ctx.conn);
-                                to_remove.push(*remote);
+                                if replacing {
+                                    replaced.push(*remote);
+                                } else {
+                                    to_remove.push(*remote);
+                                }
                                 Some(base::ConnectionEvent::Disconnected(ctx))
                             }
                         };
@@ -191,8 +411,63 @@ impl TaskSwitcherChild<Output> for NeighboursManager {
             }
         }
 
+        for remote in promoted {
+            // the replacement takes over the pair, the old connection is disconnected by the side which restarted it
+            let Some(replacement) = self.replacing.remove(&remote) else {
+                continue;
+            };
+            let restarted_locally = replacement.ctx().conn.is_outgoing();
+            if let Some(mut old) = self.connections.insert(remote, replacement) {
+                if restarted_locally {
+                    old.disconnect(now_ms);
+                }
+                self.replacing.insert(remote, old);
+            }
+        }
+
+        for (remote, restarted_locally) in replace_failed {
+            self.replacing.remove(&remote);
+            // remote doesn't accept the replacement, fall back to re-connecting after the old connection is disconnected
+            if let Some(conn) = self.connections.get_mut(&remote).filter(|conn| restarted_locally && conn.is_connected()) {
+                log::warn!("[Neighbours] Replacement of connection {} with {remote} failed, re-connect after disconnect", conn.ctx().conn);
+                self.restarting.insert(remote, conn.dest_node());
+                conn.disconnect(now_ms);
+            }
+        }
+
+        for remote in replaced {
+            self.replacing.remove(&remote);
+        }
+
+        for (remote, reason) in failed {
+            let Some(addr) = self.dialing.remove(&remote) else {
+                continue;
+            };
+            // other addresses of the node may still connect
+            let node = addr.node_id();
+            let pending = self.dialing.values().any(|other| other.node_id() == node);
+            let connected = self.neighbours.values().any(|ctx| ctx.node == node);
+            if !pending && !connected {
+                log::info!("[Neighbours] Connect to {addr} failed {reason:?}");
+                self.queue.push_back(Output::Event(base::ConnectionEvent::ConnectFailed(addr, reason)));
+            }
+        }
+
         for remote in to_remove {
             self.connections.remove(&remote);
+            self.dialing.remove(&remote);
+            if let Some(other) = self.replacing.remove(&remote) {
+                // the other connection of a restarting pair keeps it
+                self.connections.insert(remote, other);
+            } else if let Some(dest_node) = self.restarting.remove(&remote) {
+                if !self.shutdown {
+                    log::info!("[Neighbours] Re-connect to {dest_node} with {remote} after restart");
+                    let session_id = self.random.next_u64();
+                    let conn = NeighbourConnection::new_outgoing(self.handshake_builder.clone(), self.link, self.caps, self.node_id, dest_node, session_id, remote, now_ms)
+                        .with_rekey_interval(self.rekey_interval_ms);
+                    self.connections.insert(remote, conn);
+                }
+            }
         }
 
         self.queue.pop_front()
@@ -211,7 +486,8 @@ fn get_node_addr_dests(addr: NodeAddr) -> Vec<SocketAddr> {
             Protocol::Ip6(i) => {
                 dest_ip = Some(IpAddr::V6(i));
             }
-            Protocol::Udp(port) => {
+            // tcp is used by stream based transports like websocket, which carry the same packets as udp
+            Protocol::Udp(port) | Protocol::Tcp(port) => {
                 if let Some(ip) = dest_ip {
                     dests.push(SocketAddr::new(ip, port));
                 }
@@ -221,3 +497,153 @@ fn get_node_addr_dests(addr: NodeAddr) -> Vec<SocketAddr> {
     }
     dests
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use atm0s_sdn_identity::NodeAddrBuilder;
+    use rand::rngs::mock::StepRng;
+
+    use crate::{
+        base::Capabilities,
+        secure::{HandshakeBuilderXDA, StaticKeyAuthorization},
+    };
+
+    use super::*;
+
+    fn build_manager(node_id: NodeId) -> NeighboursManager {
+        NeighboursManager::new(
+            node_id,
+            vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), node_id as u16)],
+            Arc::new(StaticKeyAuthorization::new("demo-key")),
+            Arc::new(HandshakeBuilderXDA),
+            LinkProfile::Standard,
+            PeerCapabilities::local(Capabilities::SUPPORTED),
+            Box::new(StepRng::new(1000, 5)),
+        )
+    }
+
+    fn node_addr(node_id: NodeId) -> NodeAddr {
+        let mut builder = NodeAddrBuilder::new(node_id);
+        builder.add_protocol(Protocol::Ip4(Ipv4Addr::LOCALHOST));
+        builder.add_protocol(Protocol::Udp(node_id as u16));
+        builder.addr()
+    }
+
+    #[test]
+    fn ignore_connect_to_while_shutting_down() {
+        let mut manager = build_manager(1);
+        manager.on_input(100, Input::ConnectTo(node_addr(2)));
+        assert!(matches!(manager.pop_output(100), Some(Output::Control(..))));
+        while manager.pop_output(100).is_some() {}
+
+        manager.on_shutdown(200);
+        while manager.pop_output(200).is_some() {}
+        manager.on_input(300, Input::ConnectTo(node_addr(3)));
+        assert!(!manager.connections.keys().any(|pair| pair.remote.port() == 3));
+        assert!(!manager.dialing.keys().any(|pair| pair.remote.port() == 3));
+    }
+
+    #[test]
+    fn reject_connect_request_while_shutting_down() {
+        let mut manager = build_manager(1);
+        manager.on_shutdown(100);
+        assert!(manager.is_empty());
+
+        let auth = StaticKeyAuthorization::new("demo-key");
+        let pair = NetPair::new_str("127.0.0.1:1", "127.0.0.1:2").expect("Should parse");
+        let cmd = NeighboursControlCmds::ConnectRequest {
+            to: 1,
+            session: 1000,
+            handshake: vec![1, 2, 3],
+        };
+        manager.on_input(200, Input::Control(pair, NeighboursControl::build(200, 2, cmd, &auth)));
+        assert!(manager.connections.is_empty());
+        assert!(manager.is_empty());
+        assert!(manager.pop_output(200).is_none());
+    }
+
+    /// Deliver controls between two managers one by one, checking after each step that connected sides still have a connection to each other
+    fn exchange_connected(now_ms: u64, managers: &mut [NeighboursManager; 2], events: &mut Vec<(usize, base::ConnectionEvent)>) {
+        let connected = managers.iter().map(|manager| !manager.neighbours.is_empty()).collect::<Vec<_>>();
+        let mut in_flight = VecDeque::new();
+        loop {
+            for (side, manager) in managers.iter_mut().enumerate() {
+                while let Some(out) = manager.pop_output(now_ms) {
+                    match out {
+                        Output::Control(pair, control) => in_flight.push_back((1 - side, NetPair::new(pair.remote, pair.local), control)),
+                        Output::Event(event @ (base::ConnectionEvent::Connected(..) | base::ConnectionEvent::Disconnected(_))) => events.push((side, event)),
+                        _ => {}
+                    }
+                }
+            }
+            let Some((side, pair, control)) = in_flight.pop_front() else {
+                break;
+            };
+            managers[side].on_input(now_ms, Input::Control(pair, control));
+            for (manager, connected) in managers.iter().zip(&connected) {
+                assert!(!connected || !manager.neighbours.is_empty(), "connection lost while restarting");
+            }
+        }
+    }
+
+    fn event_conns(events: &[(usize, base::ConnectionEvent)], side: usize) -> Vec<(bool, ConnId)> {
+        events
+            .iter()
+            .filter(|(s, _)| *s == side)
+            .filter_map(|(_, event)| match event {
+                base::ConnectionEvent::Connected(ctx, _) => Some((true, ctx.conn)),
+                base::ConnectionEvent::Disconnected(ctx) => Some((false, ctx.conn)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn restart_without_backup_make_before_break() {
+        let mut managers = [build_manager(1), build_manager(2)];
+        managers[0].on_input(100, Input::ConnectTo(node_addr(2)));
+        let mut events = vec![];
+        exchange_connected(100, &mut managers, &mut events);
+        let old = ConnId::from_out(0, 1000);
+        assert_eq!(event_conns(&events, 0), vec![(true, old)]);
+
+        events.clear();
+        managers[0].on_input(200, Input::Restart(old));
+        exchange_connected(200, &mut managers, &mut events);
+
+        // replacement is connected over the same pair before the old connection is closed on both sides
+        let new = ConnId::from_out(0, 1005);
+        assert_eq!(event_conns(&events, 0), vec![(true, new), (false, old)]);
+        assert_eq!(event_conns(&events, 1), vec![(true, ConnId::from_in(0, 1005)), (false, ConnId::from_in(0, 1000))]);
+        for manager in &managers {
+            assert_eq!(manager.neighbours.len(), 1);
+            assert_eq!(manager.connections.len(), 1);
+            assert!(manager.replacing.is_empty());
+        }
+    }
+
+    #[test]
+    fn restart_with_backup_disconnect_first() {
+        let mut managers = [build_manager(1), build_manager(2)];
+        managers[0].bind_addrs.push(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 11));
+        managers[0].on_input(100, Input::ConnectTo(node_addr(2)));
+        let mut events = vec![];
+        exchange_connected(100, &mut managers, &mut events);
+        let conns = event_conns(&events, 0);
+        assert_eq!(conns.len(), 2);
+
+        events.clear();
+        let (_, old) = conns[0];
+        managers[0].on_input(200, Input::Restart(old));
+        assert!(managers[0].replacing.is_empty());
+        exchange_connected(200, &mut managers, &mut events);
+
+        // the other connection keeps the node reachable, so the old one is closed before re-connecting
+        let conns = event_conns(&events, 0);
+        assert_eq!(conns.len(), 2);
+        assert_eq!(conns[0], (false, old));
+        assert!(conns[1].0);
+    }
+}
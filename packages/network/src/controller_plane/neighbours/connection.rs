@@ -3,7 +3,10 @@ use std::{collections::VecDeque, fmt::Debug, sync::Arc};
 use atm0s_sdn_identity::{ConnId, NodeId};
 
 use crate::{
-    base::{ConnectionCtx, ConnectionStats, Decryptor, Encryptor, HandshakeBuilder, HandshakeRequester, NeighboursConnectError, NeighboursControlCmds, NeighboursDisconnectReason},
+    base::{
+        Capabilities, ConnectionCtx, ConnectionStats, Decryptor, Encryptor, HandshakeBuilder, HandshakeRequester, LinkProfile, NeighboursConnectError, NeighboursControlCmds,
+        NeighboursDisconnectReason, PeerCapabilities, SecureInfo,
+    },
     data_plane::NetPair,
 };
 
@@ -11,6 +14,7 @@ const INIT_RTT_MS: u32 = 1000;
 const RETRY_CMD_MS: u64 = 1000;
 const CONNECT_TIMEOUT_MS: u64 = 30000; //we need connect more time
 const CONNECTION_TIMEOUT_MS: u64 = 10000;
+const CAPABILITIES_TIMEOUT_MS: u64 = 5000; //neighbour which doesn't answer capabilities in this time is treated as legacy
 
 enum State {
     OutgoingWait {
@@ -30,6 +34,12 @@ enum State {
         stats: ConnectionStats,
         /// handshake_req, handshake_res, remote_session
         handshake: Option<(Vec<u8>, Vec<u8>, u64)>,
+        /// Negotiated link profile, it is Standard until both sides agreed
+        link: LinkProfile,
+        connected_ms: u64,
+        /// Capabilities of remote, None until negotiated
+        remote_caps: Option<PeerCapabilities>,
+        rekey: RekeyState,
     },
     Disconnecting {
         at_ms: u64,
@@ -37,11 +47,25 @@ enum State {
     Disconnected,
 }
 
+/// Progress of rekeying, epoch 0 is the keys of connect handshake
+enum RekeyState {
+    /// Both sides use keys of epoch since at_ms
+    Idle { epoch: u32, at_ms: u64 },
+    /// Outgoing side waits RekeyAck, the request is resent on tick
+    Requesting { epoch: u32, requester: Box<dyn HandshakeRequester>, handshake: Vec<u8> },
+    /// Incoming side already decrypts with new keys and waits RekeyDone before encrypting with them, the response is resent on tick
+    Responding { epoch: u32, handshake: Vec<u8>, encryptor: Box<dyn Encryptor> },
+}
+
 pub enum ConnectionEvent {
     Connected(Box<dyn Encryptor>, Box<dyn Decryptor>),
+    /// Keys of a new epoch, None for the side which is not switched yet
+    Rekey(Option<Box<dyn Encryptor>>, Option<Box<dyn Decryptor>>),
     ConnectError(NeighboursConnectError),
     ConnectTimeout,
     Stats(ConnectionStats),
+    Link(LinkProfile),
+    Capabilities(PeerCapabilities),
     Disconnected,
 }
 
@@ -49,9 +73,12 @@ impl Debug for ConnectionEvent {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ConnectionEvent::Connected(_, _) => write!(f, "Connected"),
+            ConnectionEvent::Rekey(encryptor, decryptor) => write!(f, "Rekey(encryptor: {}, decryptor: {})", encryptor.is_some(), decryptor.is_some()),
             ConnectionEvent::ConnectError(err) => write!(f, "ConnectError({:?})", err),
             ConnectionEvent::ConnectTimeout => write!(f, "ConnectTimeout"),
             ConnectionEvent::Stats(_) => write!(f, "Stats"),
+            ConnectionEvent::Link(link) => write!(f, "Link({:?})", link),
+            ConnectionEvent::Capabilities(caps) => write!(f, "Capabilities({:?})", caps),
             ConnectionEvent::Disconnected => write!(f, "Disconnected"),
         }
     }
@@ -61,15 +88,42 @@ impl PartialEq for ConnectionEvent {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (ConnectionEvent::Connected(_, _), ConnectionEvent::Connected(_, _)) => true,
+            (ConnectionEvent::Rekey(enc1, dec1), ConnectionEvent::Rekey(enc2, dec2)) => enc1.is_some() == enc2.is_some() && dec1.is_some() == dec2.is_some(),
             (ConnectionEvent::ConnectError(err1), ConnectionEvent::ConnectError(err2)) => err1 == err2,
             (ConnectionEvent::ConnectTimeout, ConnectionEvent::ConnectTimeout) => true,
             (ConnectionEvent::Stats(_), ConnectionEvent::Stats(_)) => true,
+            (ConnectionEvent::Link(link1), ConnectionEvent::Link(link2)) => link1 == link2,
+            (ConnectionEvent::Capabilities(caps1), ConnectionEvent::Capabilities(caps2)) => caps1 == caps2,
             (ConnectionEvent::Disconnected, ConnectionEvent::Disconnected) => true,
             _ => false,
         }
     }
 }
 
+/// Established connection, which is replicated to a standby controller for adopting it without a new handshake
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionSnapshot {
+    conn: ConnId,
+    node: NodeId,
+    pair: NetPair,
+    secure: SecureInfo,
+    rtt_ms: u32,
+    handshake: Option<(Vec<u8>, Vec<u8>, u64)>,
+    link: LinkProfile,
+    remote_caps: Option<PeerCapabilities>,
+    epoch: u32,
+}
+
+impl ConnectionSnapshot {
+    pub fn pair(&self) -> NetPair {
+        self.pair
+    }
+
+    pub fn remote_caps(&self) -> Option<PeerCapabilities> {
+        self.remote_caps
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Output {
     Event(ConnectionEvent),
@@ -84,10 +138,18 @@ pub struct NeighbourConnection {
     state: State,
     output: VecDeque<Output>,
     handshake_builder: Arc<dyn HandshakeBuilder>,
+    secure: SecureInfo,
+    /// Local link profile, which is negotiated with remote after connected
+    link: LinkProfile,
+    /// Local capabilities, which are advertised to remote after connected
+    caps: PeerCapabilities,
+    /// Interval for starting a new key epoch, None for keeping keys of connect handshake
+    rekey_interval_ms: Option<u64>,
 }
 
 impl NeighbourConnection {
-    pub fn new_outgoing(handshake_builder: Arc<dyn HandshakeBuilder>, local: NodeId, node: NodeId, session: u64, pair: NetPair, now_ms: u64) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_outgoing(handshake_builder: Arc<dyn HandshakeBuilder>, link: LinkProfile, caps: PeerCapabilities, local: NodeId, node: NodeId, session: u64, pair: NetPair, now_ms: u64) -> Self {
         let requester = handshake_builder.requester();
         let handshake = requester.create_public_request().expect("Should have handshake");
         let state = State::OutgoingWait { at_ms: now_ms, requester };
@@ -99,10 +161,15 @@ impl NeighbourConnection {
             state,
             output: VecDeque::from([Output::Net(now_ms, pair, NeighboursControlCmds::ConnectRequest { to: node, session, handshake })]),
             handshake_builder,
+            secure: SecureInfo::UNKNOWN,
+            link,
+            caps,
+            rekey_interval_ms: None,
         }
     }
 
-    pub fn new_incoming(handshake_builder: Arc<dyn HandshakeBuilder>, local: NodeId, node: NodeId, session: u64, pair: NetPair, now_ms: u64) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_incoming(handshake_builder: Arc<dyn HandshakeBuilder>, link: LinkProfile, caps: PeerCapabilities, local: NodeId, node: NodeId, session: u64, pair: NetPair, now_ms: u64) -> Self {
         let state: State = State::IncomingWait { at_ms: now_ms };
         Self {
             conn: ConnId::from_in(0, session),
@@ -112,18 +179,100 @@ impl NeighbourConnection {
             state,
             output: VecDeque::new(),
             handshake_builder,
+            secure: SecureInfo::UNKNOWN,
+            link,
+            caps,
+            rekey_interval_ms: None,
         }
     }
 
+    /// Continue a connection which is established by another controller, its keys are already pinned in the data plane.
+    /// A rekey in progress is dropped, the neighbour retries it
+    pub fn adopt(handshake_builder: Arc<dyn HandshakeBuilder>, caps: PeerCapabilities, local: NodeId, local_link: LinkProfile, snapshot: ConnectionSnapshot, now_ms: u64) -> Self {
+        Self {
+            conn: snapshot.conn,
+            local,
+            node: snapshot.node,
+            pair: snapshot.pair,
+            state: State::Connected {
+                last_pong_ms: now_ms,
+                ping_seq: 0,
+                stats: ConnectionStats {
+                    rtt_ms: snapshot.rtt_ms,
+                    traffic: Default::default(),
+                },
+                handshake: snapshot.handshake,
+                link: snapshot.link,
+                connected_ms: now_ms,
+                remote_caps: snapshot.remote_caps,
+                rekey: RekeyState::Idle { epoch: snapshot.epoch, at_ms: now_ms },
+            },
+            output: VecDeque::new(),
+            handshake_builder,
+            secure: snapshot.secure,
+            link: local_link,
+            caps,
+            rekey_interval_ms: None,
+        }
+    }
+
+    /// Snapshot of an established connection, None while connecting or disconnecting
+    pub fn snapshot(&self) -> Option<ConnectionSnapshot> {
+        match &self.state {
+            State::Connected {
+                stats,
+                handshake,
+                link,
+                remote_caps,
+                rekey,
+                ..
+            } => Some(ConnectionSnapshot {
+                conn: self.conn,
+                node: self.node,
+                pair: self.pair,
+                secure: self.secure,
+                rtt_ms: stats.rtt_ms,
+                handshake: handshake.clone(),
+                link: *link,
+                remote_caps: *remote_caps,
+                // pending epoch is not finished, so the adopted connection continues with current keys
+                epoch: match rekey {
+                    RekeyState::Idle { epoch, .. } => *epoch,
+                    RekeyState::Requesting { epoch, .. } | RekeyState::Responding { epoch, .. } => epoch.saturating_sub(1),
+                },
+            }),
+            _ => None,
+        }
+    }
+
+    /// Rekey is started by outgoing side only, incoming side always answers if the REKEY capability is agreed
+    pub fn with_rekey_interval(mut self, interval_ms: Option<u64>) -> Self {
+        self.rekey_interval_ms = interval_ms;
+        self
+    }
+
     pub fn dest_node(&self) -> NodeId {
         self.node
     }
 
+    pub fn is_connected(&self) -> bool {
+        matches!(self.state, State::Connected { .. })
+    }
+
+    /// Latest measured rtt, None if not connected
+    pub fn rtt_ms(&self) -> Option<u32> {
+        match &self.state {
+            State::Connected { stats, .. } => Some(stats.rtt_ms),
+            _ => None,
+        }
+    }
+
     pub fn ctx(&self) -> ConnectionCtx {
         ConnectionCtx {
             conn: self.conn,
             node: self.node,
             pair: self.pair,
+            secure: self.secure,
         }
     }
 
@@ -176,11 +325,40 @@ impl NeighbourConnection {
                     log::warn!("[NeighbourConnection] Connection timeout from {} after {} ms", self.pair, CONNECT_TIMEOUT_MS);
                 }
             }
-            State::Connected { ping_seq, last_pong_ms, .. } => {
+            State::Connected {
+                ping_seq,
+                last_pong_ms,
+                link,
+                connected_ms,
+                remote_caps,
+                ..
+            } => {
                 if now_ms - *last_pong_ms >= CONNECTION_TIMEOUT_MS {
                     log::warn!("[NeighbourConnection] Connection timeout {} after a while not received pong, last {last_pong_ms}", self.pair);
                     self.output.push_back(Output::Event(ConnectionEvent::Disconnected));
                 } else {
+                    if remote_caps.is_none() {
+                        if now_ms >= *connected_ms + CAPABILITIES_TIMEOUT_MS {
+                            log::warn!("[NeighbourConnection] Capabilities not answered by {} after {CAPABILITIES_TIMEOUT_MS} ms => treat as legacy", self.pair);
+                            *remote_caps = Some(PeerCapabilities::LEGACY);
+                            self.output.push_back(Output::Event(ConnectionEvent::Capabilities(PeerCapabilities::LEGACY)));
+                        } else {
+                            log::debug!("[NeighbourConnection] Resend capabilities {}", self.pair);
+                            let cmd = NeighboursControlCmds::Capabilities {
+                                session: self.conn.session(),
+                                version: self.caps.version,
+                                flags: self.caps.capabilities.bits(),
+                            };
+                            self.output.push_back(Output::Net(now_ms, self.pair, cmd));
+                        }
+                    }
+                    // legacy neighbours cannot decode link profile, so we don't keep sending it
+                    let framing = remote_caps.map(|remote| self.caps.agreed(&remote).contains(Capabilities::LINK_FRAMING)).unwrap_or(true);
+                    if let (Some(mtu), LinkProfile::Standard, true) = (self.link.mtu(), link, framing) {
+                        log::debug!("[NeighbourConnection] Resend link profile request {}", self.pair);
+                        let cmd = NeighboursControlCmds::LinkProfile { session: self.conn.session(), mtu };
+                        self.output.push_back(Output::Net(now_ms, self.pair, cmd));
+                    }
                     log::debug!("[NeighbourConnection] Send ping {}", self.pair);
                     *ping_seq += 1;
                     let cmd = NeighboursControlCmds::Ping {
@@ -197,7 +375,7 @@ impl NeighbourConnection {
                     self.output.push_back(Output::Event(ConnectionEvent::Disconnected));
                     log::warn!("[NeighbourConnection] Disconnect request timeout {} after {} ms", self.pair, CONNECTION_TIMEOUT_MS);
                 } else {
-                    *at_ms = now_ms;
+                    // at_ms is kept from the first request, so a remote which never responds is still timed out
                     self.output.push_back(self.generate_control(
                         now_ms,
                         NeighboursControlCmds::DisconnectRequest {
@@ -210,25 +388,35 @@ impl NeighbourConnection {
             }
             _ => {}
         }
+        self.tick_rekey(now_ms);
     }
 
     pub fn on_input(&mut self, now_ms: u64, from: NodeId, cmd: NeighboursControlCmds) {
         match cmd {
             NeighboursControlCmds::ConnectRequest { to, session, handshake } => {
+                let was_connected = matches!(self.state, State::Connected { .. });
                 let result = if self.local == to && self.node == from {
                     match &mut self.state {
                         State::IncomingWait { .. } => {
                             let mut responder = self.handshake_builder.responder();
                             match responder.process_public_request(&handshake) {
                                 Ok((encryptor, decryptor, response)) => {
+                                    self.secure = responder.secure_info();
                                     self.output.push_back(Output::Event(ConnectionEvent::Connected(encryptor, decryptor)));
                                     self.state = State::Connected {
                                         last_pong_ms: now_ms,
                                         ping_seq: 0,
-                                        stats: ConnectionStats { rtt_ms: INIT_RTT_MS },
+                                        stats: ConnectionStats {
+                                            rtt_ms: INIT_RTT_MS,
+                                            traffic: Default::default(),
+                                        },
                                         handshake: Some((handshake, response.clone(), session)),
+                                        link: LinkProfile::Standard,
+                                        connected_ms: now_ms,
+                                        remote_caps: None,
+                                        rekey: RekeyState::Idle { epoch: 0, at_ms: now_ms },
                                     };
-                                    log::info!("[NeighbourConnection] Connected {} as incoming conn", self.pair);
+                                    log::info!("[NeighbourConnection] Connected {} as incoming conn with {:?}", self.pair, self.secure);
                                     Ok(response)
                                 }
                                 Err(_) => {
@@ -251,14 +439,22 @@ impl NeighbourConnection {
                                 let mut responder = self.handshake_builder.responder();
                                 match responder.process_public_request(&handshake) {
                                     Ok((encryptor, decryptor, response)) => {
+                                        self.secure = responder.secure_info();
                                         self.output.push_back(Output::Event(ConnectionEvent::Connected(encryptor, decryptor)));
                                         self.state = State::Connected {
                                             last_pong_ms: now_ms,
                                             ping_seq: 0,
-                                            stats: ConnectionStats { rtt_ms: INIT_RTT_MS },
+                                            stats: ConnectionStats {
+                                                rtt_ms: INIT_RTT_MS,
+                                                traffic: Default::default(),
+                                            },
                                             handshake: Some((handshake, response.clone(), session)),
+                                            link: LinkProfile::Standard,
+                                            connected_ms: now_ms,
+                                            remote_caps: None,
+                                            rekey: RekeyState::Idle { epoch: 0, at_ms: now_ms },
                                         };
-                                        log::info!("[NeighbourConnection] Connected {} as incoming conn", self.pair);
+                                        log::info!("[NeighbourConnection] Connected {} as incoming conn with {:?}", self.pair, self.secure);
                                         Ok(response)
                                     }
                                     Err(_) => {
@@ -312,7 +508,12 @@ impl NeighbourConnection {
                     );
                     Err(NeighboursConnectError::InvalidData)
                 };
+                let connected = !was_connected && result.is_ok();
                 self.output.push_back(self.generate_control(now_ms, NeighboursControlCmds::ConnectResponse { session, result }));
+                self.request_link(now_ms);
+                if connected {
+                    self.request_capabilities(now_ms);
+                }
             }
             NeighboursControlCmds::ConnectResponse { session, result } => {
                 if session == self.conn.session() {
@@ -320,14 +521,24 @@ impl NeighbourConnection {
                         match (requester, result) {
                             (requester, Ok(handshake_res)) => match requester.process_public_response(&handshake_res) {
                                 Ok((encryptor, decryptor)) => {
+                                    self.secure = requester.secure_info();
                                     self.output.push_back(Output::Event(ConnectionEvent::Connected(encryptor, decryptor)));
                                     self.state = State::Connected {
                                         last_pong_ms: now_ms,
                                         ping_seq: 0,
-                                        stats: ConnectionStats { rtt_ms: INIT_RTT_MS },
+                                        stats: ConnectionStats {
+                                            rtt_ms: INIT_RTT_MS,
+                                            traffic: Default::default(),
+                                        },
                                         handshake: None,
+                                        link: LinkProfile::Standard,
+                                        connected_ms: now_ms,
+                                        remote_caps: None,
+                                        rekey: RekeyState::Idle { epoch: 0, at_ms: now_ms },
                                     };
-                                    log::info!("Connected to {} as outgoing conn", self.pair);
+                                    log::info!("Connected to {} as outgoing conn with {:?}", self.pair, self.secure);
+                                    self.request_link(now_ms);
+                                    self.request_capabilities(now_ms);
                                 }
                                 Err(e) => {
                                     log::warn!("Connect response from  {} but handshake error {:?}", self.pair, e);
@@ -376,6 +587,85 @@ impl NeighbourConnection {
                     log::warn!("[NeighbourConnection] Invalid session in ping from {}", self.pair);
                 }
             }
+            NeighboursControlCmds::LinkProfile { session, mtu } => {
+                if session == self.conn.session() {
+                    if let Some(agreed) = self.apply_link(LinkProfile::constrained(mtu)) {
+                        let mtu = agreed.mtu().unwrap_or(mtu);
+                        self.output.push_back(self.generate_control(now_ms, NeighboursControlCmds::LinkProfileAck { session, mtu }));
+                    } else {
+                        log::warn!("[NeighbourConnection] Invalid state, should be Connected for link profile from {}", self.pair);
+                    }
+                } else {
+                    log::warn!("[NeighbourConnection] Invalid session in link profile from {}", self.pair);
+                }
+            }
+            NeighboursControlCmds::LinkProfileAck { session, mtu } => {
+                if session == self.conn.session() {
+                    if self.apply_link(LinkProfile::constrained(mtu)).is_none() {
+                        log::warn!("[NeighbourConnection] Invalid state, should be Connected for link profile ack from {}", self.pair);
+                    }
+                } else {
+                    log::warn!("[NeighbourConnection] Invalid session in link profile ack from {}", self.pair);
+                }
+            }
+            NeighboursControlCmds::Capabilities { session, version, flags } => {
+                if session == self.conn.session() {
+                    if self.apply_capabilities(version, flags) {
+                        let cmd = NeighboursControlCmds::CapabilitiesAck {
+                            session,
+                            version: self.caps.version,
+                            flags: self.caps.capabilities.bits(),
+                        };
+                        self.output.push_back(self.generate_control(now_ms, cmd));
+                    } else {
+                        log::warn!("[NeighbourConnection] Invalid state, should be Connected for capabilities from {}", self.pair);
+                    }
+                } else {
+                    log::warn!("[NeighbourConnection] Invalid session in capabilities from {}", self.pair);
+                }
+            }
+            NeighboursControlCmds::CapabilitiesAck { session, version, flags } => {
+                if session == self.conn.session() {
+                    if !self.apply_capabilities(version, flags) {
+                        log::warn!("[NeighbourConnection] Invalid state, should be Connected for capabilities ack from {}", self.pair);
+                    }
+                } else {
+                    log::warn!("[NeighbourConnection] Invalid session in capabilities ack from {}", self.pair);
+                }
+            }
+            NeighboursControlCmds::Rekey { session, epoch, handshake } => {
+                if session == self.conn.session() {
+                    self.on_rekey_request(now_ms, epoch, handshake);
+                } else {
+                    log::warn!("[NeighbourConnection] Invalid session in rekey from {}", self.pair);
+                }
+            }
+            NeighboursControlCmds::RekeyAck { session, epoch, handshake } => {
+                if session == self.conn.session() {
+                    self.on_rekey_response(now_ms, epoch, handshake);
+                } else {
+                    log::warn!("[NeighbourConnection] Invalid session in rekey ack from {}", self.pair);
+                }
+            }
+            NeighboursControlCmds::RekeyDone { session, epoch } => {
+                if session == self.conn.session() {
+                    if let State::Connected { rekey, .. } = &mut self.state {
+                        match rekey {
+                            RekeyState::Responding { epoch: pending, .. } if *pending == epoch => {
+                                if let RekeyState::Responding { encryptor, .. } = std::mem::replace(rekey, RekeyState::Idle { epoch, at_ms: now_ms }) {
+                                    log::info!("[NeighbourConnection] Rekey epoch {epoch} with {} done", self.pair);
+                                    self.output.push_back(Output::Event(ConnectionEvent::Rekey(Some(encryptor), None)));
+                                }
+                            }
+                            _ => log::debug!("[NeighbourConnection] Ignore rekey done epoch {epoch} from {}", self.pair),
+                        }
+                    } else {
+                        log::warn!("[NeighbourConnection] Invalid state, should be Connected for rekey done from {}", self.pair);
+                    }
+                } else {
+                    log::warn!("[NeighbourConnection] Invalid session in rekey done from {}", self.pair);
+                }
+            }
             NeighboursControlCmds::DisconnectRequest { session, .. } => {
                 if session == self.conn.session() {
                     self.state = State::Disconnected;
@@ -406,6 +696,211 @@ impl NeighbourConnection {
         self.output.pop_front()
     }
 
+    /// Request constrained framing if local link profile is constrained, it is resent on tick until acked
+    fn request_link(&mut self, now_ms: u64) {
+        if let (Some(mtu), State::Connected { link: LinkProfile::Standard, .. }) = (self.link.mtu(), &self.state) {
+            self.output
+                .push_back(self.generate_control(now_ms, NeighboursControlCmds::LinkProfile { session: self.conn.session(), mtu }));
+        }
+    }
+
+    /// Advertise local capabilities, it is resent on tick until remote answers or it is treated as legacy
+    fn request_capabilities(&mut self, now_ms: u64) {
+        if let State::Connected { remote_caps: None, .. } = &self.state {
+            let cmd = NeighboursControlCmds::Capabilities {
+                session: self.conn.session(),
+                version: self.caps.version,
+                flags: self.caps.capabilities.bits(),
+            };
+            self.output.push_back(self.generate_control(now_ms, cmd));
+        }
+    }
+
+    /// Start a new key epoch after the interval, pending request or response is resent until answered
+    fn tick_rekey(&mut self, now_ms: u64) {
+        let (rekey, remote) = match &mut self.state {
+            State::Connected {
+                rekey,
+                remote_caps: Some(remote),
+                last_pong_ms,
+                ..
+            } if now_ms - *last_pong_ms < CONNECTION_TIMEOUT_MS => (rekey, *remote),
+            _ => return,
+        };
+        if !self.caps.agreed(&remote).contains(Capabilities::REKEY) {
+            return;
+        }
+        let session = self.conn.session();
+        match rekey {
+            RekeyState::Idle { epoch, at_ms } => {
+                let interval_ms = match self.rekey_interval_ms {
+                    Some(interval_ms) if self.conn.is_outgoing() => interval_ms,
+                    _ => return,
+                };
+                if now_ms < *at_ms + interval_ms {
+                    return;
+                }
+                let requester = self.handshake_builder.requester();
+                match requester.create_public_request() {
+                    Ok(handshake) => {
+                        let epoch = *epoch + 1;
+                        log::info!("[NeighbourConnection] Start rekey epoch {epoch} with {}", self.pair);
+                        let cmd = NeighboursControlCmds::Rekey {
+                            session,
+                            epoch,
+                            handshake: handshake.clone(),
+                        };
+                        self.output.push_back(Output::Net(now_ms, self.pair, cmd));
+                        *rekey = RekeyState::Requesting { epoch, requester, handshake };
+                    }
+                    Err(e) => {
+                        log::warn!("[NeighbourConnection] Cannot create handshake for rekey with {}: {:?}", self.pair, e);
+                    }
+                }
+            }
+            RekeyState::Requesting { epoch, handshake, .. } => {
+                log::debug!("[NeighbourConnection] Resend rekey epoch {epoch} {}", self.pair);
+                let cmd = NeighboursControlCmds::Rekey {
+                    session,
+                    epoch: *epoch,
+                    handshake: handshake.clone(),
+                };
+                self.output.push_back(Output::Net(now_ms, self.pair, cmd));
+            }
+            RekeyState::Responding { epoch, handshake, .. } => {
+                log::debug!("[NeighbourConnection] Resend rekey ack epoch {epoch} {}", self.pair);
+                let cmd = NeighboursControlCmds::RekeyAck {
+                    session,
+                    epoch: *epoch,
+                    handshake: handshake.clone(),
+                };
+                self.output.push_back(Output::Net(now_ms, self.pair, cmd));
+            }
+        }
+    }
+
+    /// Answer rekey request of remote, new decryptor is applied now and new encryptor after RekeyDone
+    fn on_rekey_request(&mut self, now_ms: u64, epoch: u32, handshake: Vec<u8>) {
+        let rekey = if let State::Connected { rekey, .. } = &mut self.state {
+            rekey
+        } else {
+            log::warn!("[NeighbourConnection] Invalid state, should be Connected for rekey from {}", self.pair);
+            return;
+        };
+        let session = self.conn.session();
+        match rekey {
+            RekeyState::Responding { epoch: pending, handshake, .. } if *pending == epoch => {
+                let cmd = NeighboursControlCmds::RekeyAck {
+                    session,
+                    epoch,
+                    handshake: handshake.clone(),
+                };
+                self.output.push_back(Output::Net(now_ms, self.pair, cmd));
+                return;
+            }
+            RekeyState::Idle { epoch: current, .. } | RekeyState::Responding { epoch: current, .. } if *current >= epoch => {
+                log::debug!("[NeighbourConnection] Ignore old rekey epoch {epoch} from {}", self.pair);
+                return;
+            }
+            RekeyState::Requesting { .. } => {
+                log::warn!("[NeighbourConnection] Ignore rekey from {} while requesting rekey", self.pair);
+                return;
+            }
+            _ => {}
+        }
+
+        let mut responder = self.handshake_builder.responder();
+        let (encryptor, decryptor, response) = match responder.process_public_request(&handshake) {
+            Ok(res) => res,
+            Err(e) => {
+                log::warn!("[NeighbourConnection] Invalid rekey request from {}: {:?}", self.pair, e);
+                return;
+            }
+        };
+        // remote only starts a new epoch after it received our answer, so our RekeyDone of previous epoch was lost
+        let pending = std::mem::replace(
+            rekey,
+            RekeyState::Responding {
+                epoch,
+                handshake: response.clone(),
+                encryptor,
+            },
+        );
+        if let RekeyState::Responding { encryptor, .. } = pending {
+            self.output.push_back(Output::Event(ConnectionEvent::Rekey(Some(encryptor), None)));
+        }
+        log::info!("[NeighbourConnection] Answer rekey epoch {epoch} from {}", self.pair);
+        self.output.push_back(Output::Event(ConnectionEvent::Rekey(None, Some(decryptor))));
+        self.output
+            .push_back(Output::Net(now_ms, self.pair, NeighboursControlCmds::RekeyAck { session, epoch, handshake: response }));
+    }
+
+    /// Switch to new keys of the epoch which is answered by remote
+    fn on_rekey_response(&mut self, now_ms: u64, epoch: u32, handshake: Vec<u8>) {
+        let rekey = if let State::Connected { rekey, .. } = &mut self.state {
+            rekey
+        } else {
+            log::warn!("[NeighbourConnection] Invalid state, should be Connected for rekey ack from {}", self.pair);
+            return;
+        };
+        let session = self.conn.session();
+        match rekey {
+            RekeyState::Requesting { epoch: pending, requester, .. } if *pending == epoch => match requester.process_public_response(&handshake) {
+                Ok((encryptor, decryptor)) => {
+                    log::info!("[NeighbourConnection] Rekey epoch {epoch} with {} done", self.pair);
+                    *rekey = RekeyState::Idle { epoch, at_ms: now_ms };
+                    self.output.push_back(Output::Event(ConnectionEvent::Rekey(Some(encryptor), Some(decryptor))));
+                    self.output.push_back(Output::Net(now_ms, self.pair, NeighboursControlCmds::RekeyDone { session, epoch }));
+                }
+                Err(e) => {
+                    // keep keys of previous epoch and retry after next interval
+                    log::warn!("[NeighbourConnection] Invalid rekey ack from {}: {:?}", self.pair, e);
+                    *rekey = RekeyState::Idle { epoch: epoch - 1, at_ms: now_ms };
+                }
+            },
+            RekeyState::Idle { epoch: current, .. } if *current == epoch => {
+                // our RekeyDone was lost
+                self.output.push_back(Output::Net(now_ms, self.pair, NeighboursControlCmds::RekeyDone { session, epoch }));
+            }
+            _ => {
+                log::debug!("[NeighbourConnection] Ignore rekey ack epoch {epoch} from {}", self.pair);
+            }
+        }
+    }
+
+    /// Store capabilities of remote, return false if not connected
+    fn apply_capabilities(&mut self, version: u16, flags: u64) -> bool {
+        let remote = PeerCapabilities {
+            version,
+            capabilities: Capabilities::from_bits(flags),
+        };
+        if let State::Connected { remote_caps, .. } = &mut self.state {
+            if *remote_caps != Some(remote) {
+                log::info!("[NeighbourConnection] Capabilities of {} => v{} {}", self.pair, remote.version, remote.capabilities);
+                *remote_caps = Some(remote);
+                self.output.push_back(Output::Event(ConnectionEvent::Capabilities(remote)));
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Apply the profile which is agreed with remote, return None if not connected
+    fn apply_link(&mut self, remote: LinkProfile) -> Option<LinkProfile> {
+        let agreed = self.link.negotiate(&remote);
+        if let State::Connected { link, .. } = &mut self.state {
+            if *link != agreed {
+                log::info!("[NeighbourConnection] Link profile with {} changed {:?} => {:?}", self.pair, link, agreed);
+                *link = agreed;
+                self.output.push_back(Output::Event(ConnectionEvent::Link(agreed)));
+            }
+            Some(agreed)
+        } else {
+            None
+        }
+    }
+
     fn generate_control(&self, now_ms: u64, control: NeighboursControlCmds) -> Output {
         Output::Net(now_ms, self.pair, control)
     }
@@ -419,23 +914,39 @@ impl NeighbourConnection {
 
 #[cfg(test)]
 mod tests {
-    use crate::base::{MockDecryptor, MockEncryptor, MockHandshakeBuilder, MockHandshakeRequester, MockHandshakeResponder};
+    use std::ops::Deref;
+
+    use crate::{
+        base::{Buffer, MockDecryptor, MockEncryptor, MockHandshakeBuilder, MockHandshakeRequester, MockHandshakeResponder, SecureContext},
+        secure::HandshakeBuilderXDA,
+    };
 
     use super::*;
 
+    const MOCK_SECURE: SecureInfo = SecureInfo { handshake: "mock", cipher: "none" };
+
+    fn local_caps() -> PeerCapabilities {
+        PeerCapabilities::local(Capabilities::SUPPORTED)
+    }
+
+    fn caps_cmd(version: u16, flags: u64) -> NeighboursControlCmds {
+        NeighboursControlCmds::Capabilities { session: 1000, version, flags }
+    }
+
     #[test]
     fn should_handle_outgoing_connect_correct() {
         let mut client_handshake = MockHandshakeBuilder::default();
         client_handshake.expect_requester().returning(move || {
             let mut requester = MockHandshakeRequester::default();
             requester.expect_create_public_request().return_once(|| Ok(vec![1, 2, 3]));
+            requester.expect_secure_info().return_const(MOCK_SECURE);
             requester
                 .expect_process_public_response()
                 .return_once(move |_| Ok((Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()))));
             Box::new(requester)
         });
         let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
-        let mut client = NeighbourConnection::new_outgoing(Arc::new(client_handshake), 1, 2, 1000, pair, 100);
+        let mut client = NeighbourConnection::new_outgoing(Arc::new(client_handshake), LinkProfile::Standard, local_caps(), 1, 2, 1000, pair, 100);
         assert_eq!(
             client.pop_output(),
             Some(Output::Net(
@@ -449,6 +960,8 @@ mod tests {
             ))
         );
 
+        assert_eq!(client.ctx().secure, SecureInfo::UNKNOWN);
+
         //fake accepted
         client.on_input(
             1100,
@@ -462,6 +975,7 @@ mod tests {
             client.pop_output(),
             Some(Output::Event(ConnectionEvent::Connected(Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()))))
         );
+        assert_eq!(client.ctx().secure, MOCK_SECURE);
     }
 
     #[test]
@@ -469,13 +983,14 @@ mod tests {
         let mut server_handshake = MockHandshakeBuilder::default();
         server_handshake.expect_responder().returning(move || {
             let mut responder = MockHandshakeResponder::default();
+            responder.expect_secure_info().return_const(MOCK_SECURE);
             responder
                 .expect_process_public_request()
                 .return_once(|req| Ok((Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()), req.to_vec())));
             Box::new(responder)
         });
         let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
-        let mut server = NeighbourConnection::new_incoming(Arc::new(server_handshake), 1, 2, 1000, pair, 100);
+        let mut server = NeighbourConnection::new_incoming(Arc::new(server_handshake), LinkProfile::Standard, local_caps(), 1, 2, 1000, pair, 100);
         server.on_input(
             1100,
             2,
@@ -490,6 +1005,7 @@ mod tests {
             server.pop_output(),
             Some(Output::Event(ConnectionEvent::Connected(Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()))))
         );
+        assert_eq!(server.ctx().secure, MOCK_SECURE);
         assert_eq!(
             server.pop_output(),
             Some(Output::Net(
@@ -501,6 +1017,7 @@ mod tests {
                 }
             ))
         );
+        assert_eq!(server.pop_output(), Some(Output::Net(1100, pair, caps_cmd(local_caps().version, Capabilities::SUPPORTED.bits()))));
         assert_eq!(server.pop_output(), None);
 
         // should not response after Connected with wrong handshake
@@ -549,4 +1066,276 @@ mod tests {
         );
         assert_eq!(server.pop_output(), None);
     }
+
+    fn connected_client(link: LinkProfile) -> NeighbourConnection {
+        let mut client_handshake = MockHandshakeBuilder::default();
+        client_handshake.expect_requester().returning(move || {
+            let mut requester = MockHandshakeRequester::default();
+            requester.expect_create_public_request().return_once(|| Ok(vec![1, 2, 3]));
+            requester.expect_secure_info().return_const(MOCK_SECURE);
+            requester
+                .expect_process_public_response()
+                .return_once(move |_| Ok((Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()))));
+            Box::new(requester)
+        });
+        let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
+        let mut client = NeighbourConnection::new_outgoing(Arc::new(client_handshake), link, local_caps(), 1, 2, 1000, pair, 100);
+        client.on_input(
+            100,
+            2,
+            NeighboursControlCmds::ConnectResponse {
+                session: 1000,
+                result: Ok(vec![2, 3, 4]),
+            },
+        );
+        while client.pop_output().is_some() {}
+        client
+    }
+
+    fn outputs(conn: &mut NeighbourConnection) -> Vec<Output> {
+        let mut outputs = vec![];
+        while let Some(out) = conn.pop_output() {
+            outputs.push(out);
+        }
+        outputs
+    }
+
+    #[test]
+    fn should_negotiate_capabilities() {
+        let mut client = connected_client(LinkProfile::Standard);
+        let pair = client.ctx().pair;
+        let remote = PeerCapabilities {
+            version: 2,
+            capabilities: Capabilities::from_bits(Capabilities::PUBSUB_FEC.bits() | 1 << 40),
+        };
+
+        client.on_input(200, 2, caps_cmd(remote.version, remote.capabilities.bits()));
+        let ack = NeighboursControlCmds::CapabilitiesAck {
+            session: 1000,
+            version: local_caps().version,
+            flags: Capabilities::SUPPORTED.bits(),
+        };
+        assert_eq!(outputs(&mut client), vec![Output::Event(ConnectionEvent::Capabilities(remote)), Output::Net(200, pair, ack.clone())]);
+
+        //resent capabilities is answered again without new event
+        client.on_input(300, 2, caps_cmd(remote.version, remote.capabilities.bits()));
+        assert_eq!(outputs(&mut client), vec![Output::Net(300, pair, ack)]);
+
+        //not resent after negotiated
+        client.on_tick(1300);
+        assert!(!outputs(&mut client).iter().any(|out| matches!(out, Output::Net(_, _, NeighboursControlCmds::Capabilities { .. }))));
+    }
+
+    #[test]
+    fn should_timeout_disconnect_without_response() {
+        let mut client = connected_client(LinkProfile::Standard);
+        client.disconnect(200);
+        outputs(&mut client);
+
+        //disconnect request is resent on each tick but timeout is counted from the first one
+        for now in (1200..10200).step_by(1000) {
+            client.on_tick(now);
+            assert!(!outputs(&mut client).contains(&Output::Event(ConnectionEvent::Disconnected)));
+        }
+        client.on_tick(10200);
+        assert!(outputs(&mut client).contains(&Output::Event(ConnectionEvent::Disconnected)));
+    }
+
+    #[test]
+    fn should_treat_silent_neighbour_as_legacy() {
+        let mut client = connected_client(LinkProfile::constrained(500));
+
+        //capabilities and link profile are resent until answered
+        client.on_tick(1100);
+        let sent = outputs(&mut client);
+        assert!(sent.iter().any(|out| matches!(out, Output::Net(_, _, NeighboursControlCmds::Capabilities { .. }))));
+        assert!(sent.iter().any(|out| matches!(out, Output::Net(_, _, NeighboursControlCmds::LinkProfile { .. }))));
+
+        client.on_input(4000, 2, NeighboursControlCmds::Pong { session: 1000, seq: 1, sent_ms: 3900 });
+        while client.pop_output().is_some() {}
+        client.on_tick(5100);
+        assert!(outputs(&mut client).contains(&Output::Event(ConnectionEvent::Capabilities(PeerCapabilities::LEGACY))));
+
+        //legacy neighbour cannot decode link profile, so it is not sent anymore
+        client.on_tick(6100);
+        let sent = outputs(&mut client);
+        assert!(!sent
+            .iter()
+            .any(|out| matches!(out, Output::Net(_, _, NeighboursControlCmds::Capabilities { .. } | NeighboursControlCmds::LinkProfile { .. }))));
+    }
+
+    /// Move control messages between both sides until they are quiet, return (client events, server events)
+    fn exchange(now_ms: u64, client: &mut NeighbourConnection, server: &mut NeighbourConnection) -> (Vec<ConnectionEvent>, Vec<ConnectionEvent>) {
+        let mut events = (vec![], vec![]);
+        loop {
+            let mut moved = false;
+            while let Some(out) = client.pop_output() {
+                moved = true;
+                match out {
+                    Output::Event(event) => events.0.push(event),
+                    Output::Net(_, _, cmd) => server.on_input(now_ms, 1, cmd),
+                }
+            }
+            while let Some(out) = server.pop_output() {
+                moved = true;
+                match out {
+                    Output::Event(event) => events.1.push(event),
+                    Output::Net(_, _, cmd) => client.on_input(now_ms, 2, cmd),
+                }
+            }
+            if !moved {
+                return events;
+            }
+        }
+    }
+
+    fn xda_pair(server_caps: Capabilities) -> (NeighbourConnection, NeighbourConnection) {
+        let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
+        let builder = Arc::new(HandshakeBuilderXDA);
+        let client = NeighbourConnection::new_outgoing(builder.clone(), LinkProfile::Standard, local_caps(), 1, 2, 1000, pair, 100).with_rekey_interval(Some(1000));
+        let server = NeighbourConnection::new_incoming(builder, LinkProfile::Standard, PeerCapabilities::local(server_caps), 2, 1, 1000, pair, 100).with_rekey_interval(Some(1000));
+        (client, server)
+    }
+
+    fn take_secure(events: &mut Vec<ConnectionEvent>) -> SecureContext {
+        let index = events.iter().position(|e| matches!(e, ConnectionEvent::Connected(..))).expect("Should connected");
+        match events.remove(index) {
+            ConnectionEvent::Connected(encryptor, decryptor) => SecureContext::new(encryptor, decryptor),
+            _ => panic!("Should be connected event"),
+        }
+    }
+
+    fn rekey_events(events: Vec<ConnectionEvent>) -> Vec<ConnectionEvent> {
+        events.into_iter().filter(|e| matches!(e, ConnectionEvent::Rekey(..))).collect()
+    }
+
+    fn send(now_ms: u64, from: &mut SecureContext, to: &mut SecureContext, msg: &[u8]) -> bool {
+        let mut buf = Buffer::build(msg, 0, 1000);
+        from.encrypt(now_ms, &mut buf).expect("Should encrypt");
+        to.decrypt(now_ms, &mut buf).is_ok() && buf.deref() == msg
+    }
+
+    #[test]
+    fn should_rekey_after_interval() {
+        let (mut client, mut server) = xda_pair(Capabilities::SUPPORTED);
+        let (mut client_events, mut server_events) = exchange(100, &mut client, &mut server);
+        let mut client_secure = take_secure(&mut client_events);
+        let mut server_secure = take_secure(&mut server_events);
+
+        //only outgoing side starts rekey after the interval
+        client.on_tick(600);
+        server.on_tick(1200);
+        let (client_events, server_events) = exchange(1200, &mut client, &mut server);
+        assert_eq!(rekey_events(client_events), vec![]);
+        assert_eq!(rekey_events(server_events), vec![]);
+
+        client.on_tick(1200);
+        client.pop_output().expect("Should have ping");
+        let rekey = client.pop_output().expect("Should have rekey");
+        assert!(matches!(rekey, Output::Net(_, _, NeighboursControlCmds::Rekey { session: 1000, epoch: 1, .. })));
+
+        //server already decrypts with new keys but still encrypts with old ones until rekey done
+        let old_packet = {
+            let mut buf = Buffer::build(&[1, 2, 3], 0, 1000);
+            server_secure.encrypt(1200, &mut buf).expect("Should encrypt");
+            buf
+        };
+        if let Output::Net(_, _, cmd) = rekey {
+            server.on_input(1200, 1, cmd);
+        }
+        let mut server_rekey = rekey_events(
+            outputs(&mut server)
+                .into_iter()
+                .filter_map(|o| {
+                    if let Output::Event(e) = o {
+                        Some(e)
+                    } else {
+                        None
+                    }
+                })
+                .collect(),
+        );
+        assert_eq!(server_rekey, vec![ConnectionEvent::Rekey(None, Some(Box::new(MockDecryptor::default())))]);
+        if let Some(ConnectionEvent::Rekey(encryptor, decryptor)) = server_rekey.pop() {
+            server_secure.rekey(1200, encryptor, decryptor);
+        }
+        assert!(send(1200, &mut client_secure, &mut server_secure, &[4, 5, 6]), "old keys are accepted in grace period");
+
+        //server resends ack on tick, client answers it with done
+        server.on_tick(1300);
+        let (mut client_events, mut server_events) = exchange(1300, &mut client, &mut server);
+        client_events.retain(|e| matches!(e, ConnectionEvent::Rekey(..)));
+        server_events.retain(|e| matches!(e, ConnectionEvent::Rekey(..)));
+        assert_eq!(
+            client_events,
+            vec![ConnectionEvent::Rekey(Some(Box::new(MockEncryptor::default())), Some(Box::new(MockDecryptor::default())))]
+        );
+        assert_eq!(server_events, vec![ConnectionEvent::Rekey(Some(Box::new(MockEncryptor::default())), None)]);
+        for event in client_events {
+            if let ConnectionEvent::Rekey(encryptor, decryptor) = event {
+                client_secure.rekey(1300, encryptor, decryptor);
+            }
+        }
+        for event in server_events {
+            if let ConnectionEvent::Rekey(encryptor, decryptor) = event {
+                server_secure.rekey(1300, encryptor, decryptor);
+            }
+        }
+
+        let mut old_packet = old_packet;
+        assert!(client_secure.decrypt(1300, &mut old_packet).is_ok(), "in flight packet with old keys is accepted");
+        assert!(send(1300, &mut client_secure, &mut server_secure, &[7, 8]));
+        assert!(send(1300, &mut server_secure, &mut client_secure, &[9, 10]));
+
+        //next epoch starts after another interval
+        client.on_tick(2300);
+        let (client_events, server_events) = exchange(2300, &mut client, &mut server);
+        assert_eq!(rekey_events(client_events).len(), 1);
+        assert_eq!(rekey_events(server_events).len(), 2);
+    }
+
+    #[test]
+    fn should_not_rekey_legacy_neighbour() {
+        let (mut client, mut server) = xda_pair(Capabilities::from_bits(Capabilities::SUPPORTED.bits() & !Capabilities::REKEY.bits()));
+        exchange(100, &mut client, &mut server);
+
+        client.on_tick(1200);
+        let sent = outputs(&mut client);
+        assert!(!sent.iter().any(|out| matches!(out, Output::Net(_, _, NeighboursControlCmds::Rekey { .. }))));
+    }
+
+    #[test]
+    fn should_adopt_connection_snapshot() {
+        let (mut client, mut server) = xda_pair(Capabilities::SUPPORTED);
+        assert_eq!(client.snapshot(), None, "connecting state is not snapshotted");
+        exchange(100, &mut client, &mut server);
+
+        let snapshot = client.snapshot().expect("Should have snapshot");
+        let mut adopted = NeighbourConnection::adopt(Arc::new(HandshakeBuilderXDA), local_caps(), 1, LinkProfile::Standard, snapshot.clone(), 5000);
+        assert_eq!((adopted.ctx().conn, adopted.ctx().node, adopted.ctx().pair), (client.ctx().conn, client.ctx().node, client.ctx().pair));
+        assert_eq!(adopted.snapshot(), Some(snapshot));
+
+        //adopted connection continues the session without new handshake
+        adopted.on_input(
+            5000,
+            2,
+            NeighboursControlCmds::Ping {
+                session: 1000,
+                seq: 10,
+                sent_ms: 4990,
+            },
+        );
+        assert_eq!(
+            outputs(&mut adopted),
+            vec![Output::Net(
+                5000,
+                client.ctx().pair,
+                NeighboursControlCmds::Pong {
+                    session: 1000,
+                    seq: 10,
+                    sent_ms: 4990
+                }
+            )]
+        );
+    }
 }
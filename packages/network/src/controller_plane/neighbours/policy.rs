@@ -0,0 +1,120 @@
+use atm0s_sdn_identity::{NodeId, NodeIdType};
+
+/// Limits of neighbour connections, so large meshes don't degrade into full meshes. Limits are counted by node, connections
+/// to one node over different addresses count once. The default policy has no limit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NeighbourPolicy {
+    /// Max connected nodes, 0 for no limit
+    pub max_connections: usize,
+    /// Layer of node ids which groups nodes, 3 is the first geo byte, see [`NodeIdType::layer`]
+    pub group_layer: u8,
+    /// Max connected nodes of each group, 0 for no limit
+    pub group_quota: usize,
+    /// Quotas of some groups, they override `group_quota`
+    pub group_quotas: Vec<(u8, usize)>,
+    /// Nodes which are admitted over others when a limit is hit, and are evicted last
+    pub preferred: Vec<NodeId>,
+}
+
+impl Default for NeighbourPolicy {
+    fn default() -> Self {
+        Self {
+            max_connections: 0,
+            group_layer: 3,
+            group_quota: 0,
+            group_quotas: vec![],
+            preferred: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Accept,
+    /// Accept after disconnecting the node with the lowest value link
+    Evict(NodeId),
+    Reject,
+}
+
+impl NeighbourPolicy {
+    fn group(&self, node: NodeId) -> u8 {
+        node.layer(self.group_layer.min(3))
+    }
+
+    fn quota(&self, group: u8) -> usize {
+        self.group_quotas.iter().find(|(g, _)| *g == group).map(|(_, quota)| *quota).unwrap_or(self.group_quota)
+    }
+
+    /// Value of a link, preferred nodes first then lower rtt. Nodes which are not connected yet have the lowest rtt value,
+    /// so only preferred ones evict others, which avoids churn between equal nodes
+    fn score(&self, node: NodeId, rtt_ms: Option<u32>) -> u64 {
+        let preferred = if self.preferred.contains(&node) {
+            1 << 32
+        } else {
+            0
+        };
+        preferred + (u32::MAX - rtt_ms.unwrap_or(u32::MAX)) as u64
+    }
+
+    /// Decide if a connection to the candidate is admitted, connected is each connected node with its best rtt
+    pub fn admit(&self, candidate: NodeId, connected: &[(NodeId, Option<u32>)]) -> Admission {
+        if connected.iter().any(|(node, _)| *node == candidate) {
+            return Admission::Accept;
+        }
+        let group = self.group(candidate);
+        let quota = self.quota(group);
+        if quota > 0 {
+            let members = connected.iter().filter(|(node, _)| self.group(*node) == group);
+            if members.clone().count() >= quota {
+                return self.evict_lowest(candidate, members);
+            }
+        }
+        if self.max_connections > 0 && connected.len() >= self.max_connections {
+            return self.evict_lowest(candidate, connected.iter());
+        }
+        Admission::Accept
+    }
+
+    fn evict_lowest<'a>(&self, candidate: NodeId, nodes: impl Iterator<Item = &'a (NodeId, Option<u32>)>) -> Admission {
+        let lowest = nodes.map(|(node, rtt)| (self.score(*node, *rtt), *node)).min();
+        match lowest {
+            Some((score, node)) if self.score(candidate, None) > score => Admission::Evict(node),
+            _ => Admission::Reject,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_identity::{NodeId, NodeIdType};
+
+    use super::{Admission, NeighbourPolicy};
+
+    #[test]
+    fn reject_over_limit_and_evict_for_preferred() {
+        let policy = NeighbourPolicy {
+            max_connections: 2,
+            preferred: vec![3],
+            ..Default::default()
+        };
+        let connected = [(1, Some(10)), (2, Some(50))];
+        assert_eq!(policy.admit(1, &connected), Admission::Accept);
+        assert_eq!(policy.admit(4, &connected), Admission::Reject);
+        // the link with the highest rtt has the lowest value
+        assert_eq!(policy.admit(3, &connected), Admission::Evict(2));
+    }
+
+    #[test]
+    fn quota_of_group() {
+        let policy = NeighbourPolicy {
+            group_quota: 1,
+            group_quotas: vec![(2, 2)],
+            ..Default::default()
+        };
+        let node = |geo: u8, index: u8| NodeId::build(geo, 0, 0, index);
+        let connected = [(node(1, 1), Some(10)), (node(2, 1), Some(10))];
+        assert_eq!(policy.admit(node(1, 2), &connected), Admission::Reject);
+        assert_eq!(policy.admit(node(2, 2), &connected), Admission::Accept);
+        assert_eq!(policy.admit(node(3, 1), &connected), Admission::Accept);
+    }
+}
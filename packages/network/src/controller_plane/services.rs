@@ -1,17 +1,34 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
 
+use atm0s_sdn_identity::ConnId;
+use atm0s_sdn_utils::log_sampled;
 use sans_io_runtime::{TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
 
-use crate::base::Service;
-use crate::base::{ServiceBuilder, ServiceCtx, ServiceId, ServiceInput, ServiceOutput, ServiceSharedInput};
-use crate::features::{FeaturesControl, FeaturesEvent};
+use crate::base::{ConnectionEvent, Service};
+use crate::base::{ServiceBuilder, ServiceCtx, ServiceDependency, ServiceId, ServiceInput, ServiceOutput, ServiceSharedInput};
+use crate::features::{Features, FeaturesControl, FeaturesEvent};
 
+/// Inputs which are queued for each service which is waiting for its dependencies, the oldest ones are dropped
+const PENDING_INPUTS_LIMIT: usize = 128;
+
+#[allow(clippy::enum_variant_names)]
 pub enum Output<UserData, ServiceEvent, ToWorker> {
     Output(ServiceId, ServiceOutput<UserData, FeaturesControl, ServiceEvent, ToWorker>),
+    /// Service is started after its dependencies are ready
+    Ready(ServiceId),
     OnResourceEmpty,
 }
 
+type ServiceBuilderArc<UserData, ServiceControl, ServiceEvent, ToController, ToWorker> =
+    Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, ServiceControl, ServiceEvent, ToController, ToWorker>>;
+
+struct PendingService<UserData, ServiceControl, ServiceEvent, ToController, ToWorker> {
+    builder: ServiceBuilderArc<UserData, ServiceControl, ServiceEvent, ToController, ToWorker>,
+    dependencies: Vec<ServiceDependency>,
+    inputs: VecDeque<ServiceInput<UserData, FeaturesEvent, ServiceControl, ToController>>,
+}
+
 type ServiceBox<UserData, ServiceControl, ServiceEvent, ToController, ToWorker> = Box<dyn Service<UserData, FeaturesControl, FeaturesEvent, ServiceControl, ServiceEvent, ToController, ToWorker>>;
 type ServiceBoxOutput<UserData, ServiceEvent, ToWorker> = ServiceOutput<UserData, FeaturesControl, ServiceEvent, ToWorker>;
 type ServiceSwitcher<UserData, ServiceControl, ServiceEvent, ToController, ToWorker> =
@@ -27,6 +44,13 @@ pub struct ServiceManager<UserData, ServiceControl, ServiceEvent, ToController,
     #[allow(clippy::type_complexity)]
     services: [Option<ServiceSlot<UserData, ServiceControl, ServiceEvent, ToController, ToWorker>>; 256],
     services_count: usize,
+    #[allow(clippy::type_complexity)]
+    pending: Vec<PendingService<UserData, ServiceControl, ServiceEvent, ToController, ToWorker>>,
+    /// Connected and capabilities events of established connections, which are replayed to services when they start
+    connections: HashMap<ConnId, Vec<ConnectionEvent>>,
+    ready: VecDeque<ServiceId>,
+    /// Restored states of services which are not started yet, they are applied when the services start
+    restored: HashMap<u8, Vec<u8>>,
     empty_services: HashSet<ServiceId>,
     switcher: TaskSwitcher,
     shutdown: bool,
@@ -36,14 +60,40 @@ pub struct ServiceManager<UserData, ServiceControl, ServiceEvent, ToController,
 impl<UserData, ServiceControl, ServiceEvent, ToController, ToWorker> ServiceManager<UserData, ServiceControl, ServiceEvent, ToController, ToWorker> {
     pub fn new(services: Vec<Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, ServiceControl, ServiceEvent, ToController, ToWorker>>>) -> Self {
         let max_service_id = services.iter().map(|s| s.service_id()).max().unwrap_or(0);
+        let (immediate, delayed): (Vec<_>, Vec<_>) = services.into_iter().partition(|s| s.dependencies().is_empty());
+        let pending = delayed
+            .into_iter()
+            .map(|builder| {
+                let dependencies = builder.dependencies();
+                log::info!("[ControllerPlane] Service {} waits for {:?}", builder.service_name(), dependencies);
+                PendingService {
+                    builder,
+                    dependencies,
+                    inputs: VecDeque::new(),
+                }
+            })
+            .collect::<Vec<_>>();
+        for service in &pending {
+            for dep in &service.dependencies {
+                if let ServiceDependency::Service(id) = dep {
+                    if !immediate.iter().any(|s| s.service_id() == *id) && !pending.iter().any(|s| s.builder.service_id() == *id) {
+                        log::error!("[ControllerPlane] Service {} depends on unknown service {id}, it will never start", service.builder.service_name());
+                    }
+                }
+            }
+        }
         Self {
-            services_count: services.len(),
+            services_count: immediate.len(),
             services: std::array::from_fn(|index| {
-                services.iter().find(|s| s.service_id() == index as u8).map(|s| ServiceSlot {
+                immediate.iter().find(|s| s.service_id() == index as u8).map(|s| ServiceSlot {
                     service: TaskSwitcherBranch::new(s.create(), index),
                     is_empty: false,
                 })
             }),
+            pending,
+            connections: HashMap::new(),
+            ready: VecDeque::new(),
+            restored: HashMap::new(),
             empty_services: HashSet::default(),
             switcher: TaskSwitcher::new(max_service_id as usize + 1),
             shutdown: false,
@@ -51,6 +101,23 @@ impl<UserData, ServiceControl, ServiceEvent, ToController, ToWorker> ServiceMana
     }
 
     pub fn on_shared_input(&mut self, ctx: &ServiceCtx, now: u64, input: ServiceSharedInput) {
+        if let ServiceSharedInput::Connection(event) = &input {
+            match event {
+                ConnectionEvent::Connected(conn, _) => {
+                    self.connections.insert(conn.conn, vec![event.clone()]);
+                }
+                ConnectionEvent::Capabilities(conn, _) => {
+                    if let Some(events) = self.connections.get_mut(&conn.conn) {
+                        events.truncate(1);
+                        events.push(event.clone());
+                    }
+                }
+                ConnectionEvent::Stats(..) | ConnectionEvent::ConnectFailed(..) => {}
+                ConnectionEvent::Disconnected(conn) => {
+                    self.connections.remove(&conn.conn);
+                }
+            }
+        }
         for service in self.services.iter_mut().flatten() {
             service.service.input(&mut self.switcher).on_shared_input(ctx, now, input.clone());
         }
@@ -60,6 +127,69 @@ impl<UserData, ServiceControl, ServiceEvent, ToController, ToWorker> ServiceMana
         if let Some(Some(service)) = self.services.get_mut(*id as usize) {
             self.switcher.flag_task(*id as usize);
             service.service.input(&mut self.switcher).on_input(ctx, now, input);
+        } else if let Some(pending) = self.pending.iter_mut().find(|s| s.builder.service_id() == *id) {
+            if pending.inputs.len() >= PENDING_INPUTS_LIMIT {
+                log_sampled!(
+                    log::Level::Warn,
+                    "[ControllerPlane] Service {} is not started, drop oldest queued input",
+                    pending.builder.service_name()
+                );
+                pending.inputs.pop_front();
+            }
+            pending.inputs.push_back(input);
+        }
+    }
+
+    /// Start services whose dependencies are ready, a chain of services which depend on each other is started at once
+    pub fn start_ready<F: Fn(Features) -> bool>(&mut self, ctx: &ServiceCtx, now: u64, feature_ready: F) {
+        if self.shutdown {
+            return;
+        }
+        loop {
+            let services = &self.services;
+            let ready = self.pending.iter().position(|pending| {
+                pending.dependencies.iter().all(|dep| match dep {
+                    ServiceDependency::Feature(feature) => feature_ready(*feature),
+                    ServiceDependency::Service(id) => services[*id as usize].is_some(),
+                })
+            });
+            let Some(index) = ready else {
+                break;
+            };
+            let pending = self.pending.swap_remove(index);
+            let id = pending.builder.service_id();
+            log::info!("[ControllerPlane] Service {} dependencies are ready => start", pending.builder.service_name());
+            let mut service = TaskSwitcherBranch::new(pending.builder.create(), id as usize);
+            if let Some(state) = self.restored.remove(&id) {
+                service.input(&mut self.switcher).restore_state(&state);
+            }
+            for event in self.connections.values().flatten() {
+                service.input(&mut self.switcher).on_shared_input(ctx, now, ServiceSharedInput::Connection(event.clone()));
+            }
+            for input in pending.inputs {
+                service.input(&mut self.switcher).on_input(ctx, now, input);
+            }
+            self.services[id as usize] = Some(ServiceSlot { service, is_empty: false });
+            self.services_count += 1;
+            self.switcher.flag_task(id as usize);
+            self.ready.push_back(id.into());
+        }
+    }
+
+    /// States of started services which support snapshots
+    pub fn snapshot(&self) -> Vec<(u8, Vec<u8>)> {
+        let started = self.services.iter().flatten().filter_map(|slot| Some((slot.service.service_id(), slot.service.snapshot_state()?)));
+        // restored states of services which are still waiting are kept for the next takeover
+        started.chain(self.restored.iter().map(|(id, state)| (*id, state.clone()))).collect()
+    }
+
+    pub fn restore(&mut self, states: Vec<(u8, Vec<u8>)>) {
+        for (id, state) in states {
+            if let Some(Some(slot)) = self.services.get_mut(id as usize) {
+                slot.service.input(&mut self.switcher).restore_state(&state);
+            } else {
+                self.restored.insert(id, state);
+            }
         }
     }
 
@@ -89,6 +219,9 @@ impl<UserData, ServiceControl, ServiceEvent, ToController, ToWorker> TaskSwitche
     }
 
     fn pop_output(&mut self, now: u64) -> Option<Output<UserData, ServiceEvent, ToWorker>> {
+        if let Some(id) = self.ready.pop_front() {
+            return Some(Output::Ready(id));
+        }
         loop {
             let index = self.switcher.current()?;
             if let Some(Some(slot)) = self.services.get_mut(index) {
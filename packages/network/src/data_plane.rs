@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fmt::{Debug, Display},
     hash::Hash,
     net::{AddrParseError, SocketAddr},
@@ -11,22 +11,46 @@ use atm0s_sdn_router::{
     shadow::{ShadowRouter, ShadowRouterHistory},
     RouteAction, RouteRule, RouterTable,
 };
-use sans_io_runtime::{collections::DynamicDeque, return_if_err, return_if_none, return_if_some, TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
+use atm0s_sdn_utils::log_sampled;
+use sans_io_runtime::{collections::DynamicDeque, return_if_none, return_if_some, TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
 
 use crate::{
     base::{
-        Buffer, FeatureControlActor, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput, NeighboursControl, NetOutgoingMeta, ServiceBuilder, ServiceControlActor, ServiceId,
-        ServiceWorkerCtx, ServiceWorkerInput, ServiceWorkerOutput, TransportMsg, TransportMsgHeader,
+        Buffer, Capabilities, DecryptionError, FeatureControlActor, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput, IncomingRoute, InterfaceEvent, MsgPriority, NeighboursControl,
+        NetOutgoingMeta, PeerCapabilities, ServiceBuilder, ServiceControlActor, ServiceId, ServiceWorkerCtx, ServiceWorkerInput, ServiceWorkerOutput, TransportMsg, TransportMsgHeader,
     },
-    features::{Features, FeaturesControl, FeaturesEvent},
-    ExtIn, ExtOut, LogicControl, LogicEvent,
+    features::{diag, Features, FeaturesControl, FeaturesEvent},
+    metrics::FeatureTraffic,
+    ExtIn, ExtOut, LogicControl, LogicEvent, StickyExt,
 };
 
-use self::{connection::DataPlaneConnection, features::FeatureWorkerManager, services::ServiceWorkerManager};
+use self::{
+    connection::DataPlaneConnection,
+    dedup::DedupCache,
+    features::FeatureWorkerManager,
+    fragment::{FragmentConfig, Fragmenter},
+    multipath::{MultipathPolicy, NodePaths},
+    pool::{BufferPool, PoolStats},
+    scheduler::SchedulerConfig,
+    services::ServiceWorkerManager,
+    shaper::{ServiceShaper, ShapingProfile},
+    tap::{Tap, TapDirection},
+};
 
+#[cfg(feature = "chaos")]
+pub mod chaos;
 mod connection;
+mod dedup;
 mod features;
+pub mod fragment;
+pub mod link;
+pub mod multipath;
+pub mod pool;
+pub mod replay;
+pub mod scheduler;
 mod services;
+pub mod shaper;
+pub mod tap;
 
 /// NetPair is a pair between remote addr and local addr.
 /// This is for solving problems with multi-ip-addresses system.
@@ -58,6 +82,7 @@ impl Display for NetPair {
 #[derive(Debug)]
 pub enum NetInput {
     UdpPacket(NetPair, Buffer),
+    Interface(InterfaceEvent),
     #[cfg(feature = "vpn")]
     TunPacket(Buffer),
 }
@@ -78,8 +103,9 @@ pub enum Input<UserData, SC, SE, TW> {
 
 #[derive(Debug)]
 pub enum NetOutput {
-    UdpPacket(NetPair, Buffer),
-    UdpPackets(Vec<NetPair>, Buffer),
+    /// Packet to a remote with the class of its message, runners can mark it with a DSCP code point of the class
+    UdpPacket(NetPair, Buffer, MsgPriority),
+    UdpPackets(Vec<NetPair>, Buffer, MsgPriority),
     #[cfg(feature = "vpn")]
     TunPacket(Buffer),
 }
@@ -109,6 +135,33 @@ pub struct DataPlaneCfg<UserData, SC, SE, TC, TW> {
     #[allow(clippy::type_complexity)]
     pub services: Vec<Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>,
     pub history: Arc<dyn ShadowRouterHistory>,
+    /// Share capacity of each connection between features, None for sending without limit
+    pub scheduler: Option<SchedulerConfig>,
+    /// Cap throughput of each connection, it overrides capacity of the scheduler or enables one with default weights
+    pub bandwidth_limit_kbps: Option<u32>,
+    /// Shape outgoing traffic of services, services which are not configured are not shaped
+    pub service_shaping: Vec<(ServiceId, ShapingProfile)>,
+    /// Select between many paths to same neighbour, None for always using the path which is selected by router
+    pub multipath: Option<MultipathPolicy>,
+    /// Attach [`IncomingRoute`] to meta of messages which are received from the network, for analytics
+    pub incoming_route: bool,
+    /// Pin events of worker actors to one worker by their UserData, None for emitting them on the worker of the actor
+    pub sticky_ext: Option<StickyExt>,
+    /// Fragment messages which are larger than the mtu on standard links, only with neighbours which support reassembly
+    pub fragment: FragmentConfig,
+}
+
+/// Snapshot of data plane counters of a worker
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DataPlaneMetrics {
+    /// Connections which are pinned to this worker
+    pub connections: usize,
+    /// Traffic of each feature since started, relayed messages are counted as both incoming and outgoing
+    pub features: BTreeMap<Features, FeatureTraffic>,
+    /// Encrypted packets which are dropped as replays since started
+    pub replayed: u64,
+    /// Reused buffers of the worker since started
+    pub pool: PoolStats,
 }
 
 pub struct DataPlane<UserData, SC, SE, TC, TW> {
@@ -121,6 +174,24 @@ pub struct DataPlane<UserData, SC, SE, TC, TW> {
     services: TaskSwitcherBranch<ServiceWorkerManager<UserData, SC, SE, TC, TW>, services::Output<UserData, SC, SE, TC>>,
     conns: HashMap<NetPair, DataPlaneConnection>,
     conns_reverse: HashMap<ConnId, NetPair>,
+    /// Paths to each neighbour, only tracked with multipath policy
+    paths: HashMap<NodeId, NodePaths>,
+    multipath: Option<MultipathPolicy>,
+    incoming_route: bool,
+    sticky_ext: Option<StickyExt>,
+    /// Connections which are using constrained link framing
+    links: Vec<NetPair>,
+    scheduler: Option<SchedulerConfig>,
+    fragment: FragmentConfig,
+    /// Buffers which are shared by connections of this worker
+    pool: BufferPool,
+    shaper: ServiceShaper,
+    dedup: DedupCache,
+    traffic: BTreeMap<Features, FeatureTraffic>,
+    replayed: u64,
+    tap: Tap,
+    #[cfg(feature = "chaos")]
+    chaos: chaos::Chaos,
     queue: DynamicDeque<Output<UserData, SC, SE, TC>, 16>,
     shutdown: bool,
     switcher: TaskSwitcher,
@@ -139,12 +210,32 @@ where
             feature_ctx: FeatureWorkerContext {
                 node_id,
                 router: ShadowRouter::new(node_id, cfg.history),
+                peer_caps: HashMap::new(),
             },
             service_ctx: ServiceWorkerCtx { node_id },
             features: TaskSwitcherBranch::new(FeatureWorkerManager::new(), TaskType::Feature),
             services: TaskSwitcherBranch::new(ServiceWorkerManager::new(cfg.services), TaskType::Service),
             conns: HashMap::new(),
             conns_reverse: HashMap::new(),
+            paths: HashMap::new(),
+            multipath: cfg.multipath,
+            incoming_route: cfg.incoming_route,
+            sticky_ext: cfg.sticky_ext,
+            links: Vec::new(),
+            scheduler: match (cfg.scheduler, cfg.bandwidth_limit_kbps) {
+                (Some(scheduler), Some(limit)) => Some(SchedulerConfig { capacity_kbps: limit, ..scheduler }),
+                (None, Some(limit)) => Some(SchedulerConfig::new(limit)),
+                (scheduler, None) => scheduler,
+            },
+            fragment: cfg.fragment,
+            pool: BufferPool::new(cfg.fragment.mtu, pool::DEFAULT_MAX_IDLE),
+            shaper: ServiceShaper::new(cfg.service_shaping),
+            dedup: DedupCache::default(),
+            traffic: BTreeMap::new(),
+            replayed: 0,
+            tap: Tap::new(cfg.worker_id),
+            #[cfg(feature = "chaos")]
+            chaos: Default::default(),
             queue: DynamicDeque::default(),
             shutdown: false,
             switcher: TaskSwitcher::new(2),
@@ -155,10 +246,42 @@ where
         self.feature_ctx.router.derive_action(&rule, source, relay_from)
     }
 
+    /// Take a snapshot of current metrics
+    pub fn metrics(&self) -> DataPlaneMetrics {
+        DataPlaneMetrics {
+            connections: self.conns.len(),
+            features: self.traffic.clone(),
+            replayed: self.replayed,
+            pool: self.pool.stats(),
+        }
+    }
+
     pub fn on_tick(&mut self, now_ms: u64) {
         log::trace!("[DataPlane] on_tick: {}", now_ms);
+        if self.tick_count == 0 {
+            self.queue
+                .push_back(LogicControl::WorkerCapabilities(self.worker_id, PeerCapabilities::local(Capabilities::SUPPORTED)).into());
+        }
         self.features.input(&mut self.switcher).on_tick(&mut self.feature_ctx, now_ms, self.tick_count);
         self.services.input(&mut self.switcher).on_tick(&self.service_ctx, now_ms, self.tick_count);
+        for pair in &self.links {
+            if let Some(link) = self.conns.get_mut(pair).and_then(|conn| conn.link_mut()) {
+                link.on_tick(now_ms);
+            }
+        }
+        for conn in self.conns.values_mut() {
+            conn.fragmenter_mut().on_tick(now_ms);
+            if let Some(traffic) = conn.take_traffic() {
+                self.queue.push_back(LogicControl::ConnTraffic(self.worker_id, conn.conn(), traffic).into());
+            }
+        }
+        #[cfg(feature = "chaos")]
+        {
+            self.chaos.on_tick();
+            while let Some((pair, buf)) = self.chaos.pop_ready(now_ms) {
+                self.on_net_packet(now_ms, pair, buf);
+            }
+        }
         self.tick_count += 1;
     }
 
@@ -171,6 +294,22 @@ where
                 ExtIn::DisconnectFrom(_node) => {
                     panic!("DisconnectFrom is not supported")
                 }
+                ExtIn::Decommission => {
+                    panic!("Decommission is not supported")
+                }
+                ExtIn::SnapshotState(_) => {
+                    panic!("SnapshotState is not supported")
+                }
+                ExtIn::ControlQuery(_) => {
+                    panic!("ControlQuery is not supported")
+                }
+                ExtIn::Tap(_) => {
+                    panic!("Tap is not supported")
+                }
+                #[cfg(feature = "chaos")]
+                ExtIn::Chaos(_) => {
+                    panic!("Chaos is not supported")
+                }
                 ExtIn::FeaturesControl(userdata, control) => {
                     let feature: Features = control.to_feature();
                     let actor = FeatureControlActor::Worker(self.worker_id, userdata);
@@ -187,16 +326,19 @@ where
             },
             Input::Worker(CrossWorker::Feature(userdata, event)) => self.queue.push_back(Output::Ext(ExtOut::FeaturesEvent(userdata, event))),
             Input::Worker(CrossWorker::Service(service, userdata, event)) => self.queue.push_back(Output::Ext(ExtOut::ServicesEvent(service, userdata, event))),
+            #[cfg(not(feature = "chaos"))]
+            Input::Net(NetInput::UdpPacket(pair, buf)) => self.on_net_packet(now_ms, pair, buf),
+            #[cfg(feature = "chaos")]
             Input::Net(NetInput::UdpPacket(pair, buf)) => {
-                if buf.is_empty() {
-                    return;
-                }
-                if let Ok(control) = NeighboursControl::try_from(&*buf) {
-                    self.queue.push_back(LogicControl::NetNeighbour(pair, control).into());
-                } else {
-                    self.incoming_route(now_ms, pair, buf);
+                let conn = self.conns.get(&pair).map(|conn| (conn.conn(), conn.node()));
+                self.chaos.on_packet(now_ms, pair, conn, buf);
+                while let Some((pair, buf)) = self.chaos.pop_ready(now_ms) {
+                    self.on_net_packet(now_ms, pair, buf);
                 }
             }
+            Input::Net(NetInput::Interface(event)) => {
+                self.queue.push_back(LogicControl::NetInterface(self.worker_id, event).into());
+            }
             #[cfg(feature = "vpn")]
             Input::Net(NetInput::TunPacket(pkt)) => {
                 self.features
@@ -225,26 +367,88 @@ where
             Input::Event(LogicEvent::NetNeighbour(pair, control)) => {
                 let buf: Result<Vec<u8>, ()> = (&control).try_into();
                 if let Ok(buf) = buf {
-                    self.queue.push_back(NetOutput::UdpPacket(pair, buf.into()).into());
+                    self.queue.push_back(NetOutput::UdpPacket(pair, buf.into(), MsgPriority::Control).into());
                 }
             }
             Input::Event(LogicEvent::NetDirect(feature, pair, _conn, meta, buf)) => {
                 let header = meta.to_header(feature as u8, RouteRule::Direct, self.feature_ctx.node_id);
                 let conn = return_if_none!(self.conns.get_mut(&pair));
                 let msg = TransportMsg::build_raw(header, buf);
-                if let Some(pkt) = Self::build_send_to_from_mut(now_ms, conn, pair, msg.take()) {
+                Self::count_traffic(&mut self.traffic, feature as u8, false, 1, msg.get_buf().len());
+                Self::tap_msg(&self.tap, &mut self.queue, now_ms, TapDirection::Outgoing, pair, conn.node(), msg.get_buf());
+                conn.count_tx(msg.get_buf().len());
+                if let Some(pkt) = Self::build_send_to_from_mut(now_ms, conn, pair, meta.priority, msg.take()) {
                     self.queue.push_back(pkt.into());
                 }
             }
             Input::Event(LogicEvent::NetRoute(feature, rule, meta, buf)) => self.outgoing_route(now_ms, feature, rule, meta, buf),
             Input::Event(LogicEvent::Pin(conn, node, pair, secure)) => {
-                self.conns.insert(pair, DataPlaneConnection::new(node, conn, pair, secure));
+                let fragmenter = Fragmenter::new(self.fragment, self.pool.clone());
+                self.conns.insert(
+                    pair,
+                    DataPlaneConnection::new(self.worker_id, node, conn, pair, secure, self.scheduler.clone(), fragmenter, self.tick_count),
+                );
                 self.conns_reverse.insert(conn, pair);
+                if self.multipath.is_some() {
+                    self.paths.entry(node).or_default().add(pair);
+                }
             }
             Input::Event(LogicEvent::UnPin(conn)) => {
-                if let Some(addr) = self.conns_reverse.remove(&conn) {
+                // the pair may be already pinned again by a connection which replaces this one
+                if let Some(addr) = self.conns_reverse.remove(&conn).filter(|addr| self.conns.get(addr).map(|dp_conn| dp_conn.conn()) == Some(conn)) {
                     log::info!("UnPin: conn: {} <--> addr: {}", conn, addr);
-                    self.conns.remove(&addr);
+                    self.feature_ctx.peer_caps.remove(&addr);
+                    if let Some(dp_conn) = self.conns.remove(&addr) {
+                        if self.paths.get_mut(&dp_conn.node()).map(|paths| paths.remove(addr)).unwrap_or(false) {
+                            self.paths.remove(&dp_conn.node());
+                        }
+                    }
+                    self.links.retain(|pair| *pair != addr);
+                }
+                #[cfg(feature = "chaos")]
+                self.chaos.on_unpin(conn);
+            }
+            Input::Event(LogicEvent::ConnStats(conn, stats)) => {
+                let pair = *return_if_none!(self.conns_reverse.get(&conn));
+                let dp_conn = return_if_none!(self.conns.get_mut(&pair));
+                dp_conn.on_stats(self.tick_count, &stats);
+            }
+            Input::Event(LogicEvent::Rekey(conn, encryptor, decryptor)) => {
+                let pair = *return_if_none!(self.conns_reverse.get(&conn));
+                let dp_conn = return_if_none!(self.conns.get_mut(&pair));
+                log::info!("[DataPlane] Rekey conn {conn} with {pair}, encryptor {}, decryptor {}", encryptor.is_some(), decryptor.is_some());
+                dp_conn.rekey(now_ms, encryptor, decryptor);
+            }
+            Input::Event(LogicEvent::Capabilities(conn, caps)) => {
+                let pair = *return_if_none!(self.conns_reverse.get(&conn));
+                let dp_conn = return_if_none!(self.conns.get_mut(&pair));
+                dp_conn.set_capabilities(caps);
+                self.feature_ctx.peer_caps.insert(pair, caps);
+            }
+            Input::Event(LogicEvent::Tap(filter)) => {
+                log::info!("[DataPlane] set tap filter {:?}", filter);
+                self.tap.set_filter(filter);
+            }
+            #[cfg(feature = "chaos")]
+            Input::Event(LogicEvent::Chaos(control)) => {
+                log::warn!("[DataPlane] chaos {:?}", control);
+                match control {
+                    chaos::ChaosControl::Set(config) => self.chaos.set_config(config),
+                    chaos::ChaosControl::Kill(node) => {
+                        for conn in self.conns.values().filter(|conn| conn.node() == node) {
+                            self.chaos.kill(conn.conn());
+                        }
+                    }
+                }
+            }
+            Input::Event(LogicEvent::LinkProfile(conn, link)) => {
+                let pair = *return_if_none!(self.conns_reverse.get(&conn));
+                let dp_conn = return_if_none!(self.conns.get_mut(&pair));
+                log::info!("[DataPlane] Link profile of conn {conn} with {pair} => {:?}", link);
+                dp_conn.set_link(self.feature_ctx.node_id, link);
+                self.links.retain(|p| *p != pair);
+                if link.mtu().is_some() {
+                    self.links.push(pair);
                 }
             }
         }
@@ -260,47 +464,113 @@ where
         self.shutdown = true;
     }
 
+    fn on_net_packet(&mut self, now_ms: u64, pair: NetPair, buf: Buffer) {
+        if buf.is_empty() {
+            return;
+        }
+        if let Ok(control) = NeighboursControl::try_from(&*buf) {
+            self.queue.push_back(LogicControl::NetNeighbour(pair, control).into());
+        } else {
+            self.incoming_route(now_ms, pair, buf);
+        }
+    }
+
     fn incoming_route(&mut self, now_ms: u64, pair: NetPair, mut buf: Buffer) {
-        let conn = return_if_none!(self.conns.get_mut(&pair));
-        if TransportMsgHeader::is_secure(buf[0]) {
-            return_if_none!(conn.decrypt_if_need(now_ms, &mut buf));
+        let conn = if let Some(conn) = self.conns.get_mut(&pair) {
+            conn
+        } else {
+            log_sampled!(log::Level::Warn, "[DataPlane] drop packet from unknown remote {pair}");
+            return;
+        };
+        if fragment::is_fragment(buf[0]) {
+            buf = return_if_none!(conn.fragmenter_mut().on_fragment(now_ms, &buf));
         }
-        let header = return_if_err!(TransportMsgHeader::try_from(&buf as &[u8]));
+        if link::is_frame(buf[0]) {
+            let link = return_if_none!(conn.link_mut());
+            buf = return_if_none!(link.on_frame(now_ms, &buf));
+            if buf.is_empty() {
+                return;
+            }
+        }
+        match conn.decrypt_if_need(now_ms, &mut buf) {
+            Ok(()) => {}
+            Err(DecryptionError::Replayed) => {
+                self.replayed += 1;
+                log_sampled!(log::Level::Warn, "[DataPlane] drop replayed packet from {pair}");
+                return;
+            }
+            Err(_) => {
+                log_sampled!(log::Level::Warn, "[DataPlane] drop packet from {pair} which cannot be decrypted");
+                return;
+            }
+        }
+        let mut buf = return_if_none!(link::decompress_header(buf, conn.node()));
+        let header = match TransportMsgHeader::try_from(&buf as &[u8]) {
+            Ok(header) => header,
+            Err(e) => {
+                log_sampled!(log::Level::Warn, "[DataPlane] drop packet from {pair} with invalid header {:?}", e);
+                return;
+            }
+        };
+        Self::count_traffic(&mut self.traffic, header.feature, true, 1, buf.len());
+        Self::tap_msg(&self.tap, &mut self.queue, now_ms, TapDirection::Incoming, pair, conn.node(), &buf);
+        conn.count_rx(buf.len());
         let action = self.feature_ctx.router.derive_action(&header.route, header.from_node, Some(conn.node()));
         log::debug!("[DataPlane] Incoming rule: {:?} from: {pair}, node {:?} => action {:?}", header.route, header.from_node, action);
         match action {
             RouteAction::Reject => {}
             RouteAction::Local => {
+                if !self.dedup.check_header(now_ms, &header) {
+                    log::debug!("[DataPlane] drop duplicated message {:?} for feature {} from {pair}", header.dedup, header.feature);
+                    return;
+                }
                 let feature = return_if_none!(header.feature.try_into().ok());
                 log::debug!("Incoming message for feature: {feature:?} from: {pair}");
+                let route = self.incoming_route.then(|| IncomingRoute::new(conn.conn(), pair, conn.node(), &header, false));
                 self.features
                     .input(&mut self.switcher)
-                    .on_network_raw(&mut self.feature_ctx, feature, now_ms, conn.conn(), pair, header, buf);
+                    .on_network_raw(&mut self.feature_ctx, feature, now_ms, conn.conn(), pair, header, route, buf);
             }
-            RouteAction::Next(pair) => {
+            RouteAction::Next(next) => {
                 if !TransportMsgHeader::decrease_ttl(&mut buf) {
-                    log::debug!("TTL is 0, drop packet");
+                    // expired diagnostics probes are answered by this relay, which reveals it as a hop of the path
+                    if header.feature == diag::FEATURE_ID {
+                        let route = self.incoming_route.then(|| IncomingRoute::new(conn.conn(), pair, conn.node(), &header, false));
+                        self.features
+                            .input(&mut self.switcher)
+                            .on_network_raw(&mut self.feature_ctx, Features::Diag, now_ms, conn.conn(), pair, header, route, buf);
+                        return;
+                    }
+                    log_sampled!(log::Level::Debug, "[DataPlane] TTL is 0, drop packet from {pair}");
                 }
+                let pair = self.select_path(next);
                 let target_conn = return_if_none!(self.conns.get_mut(&pair));
-                if let Some(out) = Self::build_send_to_from_mut(now_ms, target_conn, pair, buf) {
+                Self::count_traffic(&mut self.traffic, header.feature, false, 1, buf.len());
+                Self::tap_msg(&self.tap, &mut self.queue, now_ms, TapDirection::Outgoing, pair, target_conn.node(), &buf);
+                target_conn.count_tx(buf.len());
+                if let Some(out) = Self::build_send_to_from_mut(now_ms, target_conn, pair, MsgPriority::Normal, buf) {
                     self.queue.push_back(out.into());
                 }
             }
             RouteAction::Broadcast(local, pairs) => {
                 if !TransportMsgHeader::decrease_ttl(&mut buf) {
-                    log::debug!("TTL is 0, drop packet");
+                    log_sampled!(log::Level::Debug, "[DataPlane] TTL is 0, drop packet from {pair}");
                     return;
                 }
-                if local {
+                Self::count_traffic(&mut self.traffic, header.feature, false, pairs.len(), buf.len() * pairs.len());
+                if local && self.dedup.check_header(now_ms, &header) {
                     if let Ok(feature) = header.feature.try_into() {
                         log::debug!("Incoming broadcast feature: {feature:?} from: {pair}");
+                        let route = self.incoming_route.then(|| IncomingRoute::new(conn.conn(), pair, conn.node(), &header, true));
                         self.features
                             .input(&mut self.switcher)
-                            .on_network_raw(&mut self.feature_ctx, feature, now_ms, conn.conn(), pair, header, buf.clone());
+                            .on_network_raw(&mut self.feature_ctx, feature, now_ms, conn.conn(), pair, header, route, buf.clone());
                     }
                 }
+                self.tap_multi(now_ms, &pairs, &buf);
+                Self::count_conns_tx(&mut self.conns, &pairs, buf.len());
                 if !pairs.is_empty() {
-                    if let Some(out) = self.build_send_to_multi_from_mut(now_ms, pairs, buf) {
+                    if let Some(out) = self.build_send_to_multi_from_mut(now_ms, pairs, MsgPriority::Normal, buf) {
                         self.queue.push_back(out.into());
                     }
                 }
@@ -321,11 +591,15 @@ where
                     .on_input(&mut self.feature_ctx, feature, now_ms, FeatureWorkerInput::Local(meta, buf));
             }
             RouteAction::Next(remote) => {
+                let remote = self.select_path(remote);
                 log::debug!("[DataPlane] outgoing route rule {:?} is go with remote {remote}", rule);
                 let header = meta.to_header(feature as u8, rule, self.feature_ctx.node_id);
                 let msg = TransportMsg::build_raw(header, buf);
                 let conn = return_if_none!(self.conns.get_mut(&remote));
-                if let Some(out) = Self::build_send_to_from_mut(now_ms, conn, remote, msg.take()) {
+                Self::count_traffic(&mut self.traffic, feature as u8, false, 1, msg.get_buf().len());
+                Self::tap_msg(&self.tap, &mut self.queue, now_ms, TapDirection::Outgoing, remote, conn.node(), msg.get_buf());
+                conn.count_tx(msg.get_buf().len());
+                if let Some(out) = Self::build_send_to_from_mut(now_ms, conn, remote, meta.priority, msg.take()) {
                     self.queue.push_back(out.into());
                 }
             }
@@ -341,7 +615,10 @@ where
                         .on_input(&mut self.feature_ctx, feature, now_ms, FeatureWorkerInput::Local(meta, buf.clone()));
                 }
                 let msg = TransportMsg::build_raw(header, buf);
-                if let Some(out) = self.build_send_to_multi_from_mut(now_ms, remotes, msg.take()) {
+                Self::count_traffic(&mut self.traffic, feature as u8, false, remotes.len(), msg.get_buf().len() * remotes.len());
+                self.tap_multi(now_ms, &remotes, msg.get_buf());
+                Self::count_conns_tx(&mut self.conns, &remotes, msg.get_buf().len());
+                if let Some(out) = self.build_send_to_multi_from_mut(now_ms, remotes, meta.priority, msg.take()) {
                     self.queue.push_back(out.into());
                 }
             }
@@ -365,6 +642,7 @@ where
             FeatureWorkerOutput::Event(actor, event) => match actor {
                 FeatureControlActor::Controller(userdata) => self.queue.push_back(Output::Control(LogicControl::ExtFeaturesEvent(userdata, event))),
                 FeatureControlActor::Worker(worker, userdata) => {
+                    let worker = StickyExt::route(self.sticky_ext.as_ref(), worker, &userdata);
                     if self.worker_id == worker {
                         self.queue.push_back(Output::Ext(ExtOut::FeaturesEvent(userdata, event)));
                     } else {
@@ -382,7 +660,12 @@ where
                     let conn = self.conns.get_mut(addr).expect("Should have");
                     let header = meta.to_header(feature as u8, RouteRule::Direct, self.feature_ctx.node_id);
                     let msg = TransportMsg::build_raw(header, buf);
-                    self.queue.push_back(Self::build_send_to_from_mut(now_ms, conn, *addr, msg.take()).expect("Should have output").into())
+                    Self::count_traffic(&mut self.traffic, feature as u8, false, 1, msg.get_buf().len());
+                    Self::tap_msg(&self.tap, &mut self.queue, now_ms, TapDirection::Outgoing, *addr, conn.node(), msg.get_buf());
+                    conn.count_tx(msg.get_buf().len());
+                    if let Some(out) = Self::build_send_to_from_mut(now_ms, conn, *addr, meta.priority, msg.take()) {
+                        self.queue.push_back(out.into());
+                    }
                 }
             }
             FeatureWorkerOutput::SendRoute(rule, ttl, buf) => {
@@ -392,20 +675,36 @@ where
             FeatureWorkerOutput::RawDirect(conn, buf) => {
                 if let Some(pair) = self.conns_reverse.get(&conn) {
                     let conn = self.conns.get_mut(pair).expect("Should have conn");
-                    self.queue.push_back(Self::build_send_to(now_ms, conn, *pair, buf).expect("Should ok for convert RawDirect").into());
+                    Self::count_raw_traffic(&mut self.traffic, &buf, 1);
+                    Self::tap_msg(&self.tap, &mut self.queue, now_ms, TapDirection::Outgoing, *pair, conn.node(), &buf);
+                    conn.count_tx(buf.len());
+                    if let Some(out) = Self::build_send_to(now_ms, conn, *pair, buf) {
+                        self.queue.push_back(out.into());
+                    }
                 }
             }
             FeatureWorkerOutput::RawBroadcast(conns, buf) => {
-                let addrs = conns.iter().filter_map(|conn| self.conns_reverse.get(conn)).cloned().collect();
+                let addrs: Vec<_> = conns.iter().filter_map(|conn| self.conns_reverse.get(conn)).cloned().collect();
+                Self::count_raw_traffic(&mut self.traffic, &buf, addrs.len());
+                self.tap_multi(now_ms, &addrs, &buf);
+                Self::count_conns_tx(&mut self.conns, &addrs, buf.len());
                 let out = self.build_send_to_multi(now_ms, addrs, buf).map(|e| e.into()).unwrap_or(Output::Continue);
                 self.queue.push_back(out);
             }
             FeatureWorkerOutput::RawDirect2(pair, buf) => {
                 if let Some(conn) = self.conns.get_mut(&pair) {
-                    self.queue.push_back(Self::build_send_to(now_ms, conn, pair, buf).expect("Should ok for convert RawDirect2").into());
+                    Self::count_raw_traffic(&mut self.traffic, &buf, 1);
+                    Self::tap_msg(&self.tap, &mut self.queue, now_ms, TapDirection::Outgoing, pair, conn.node(), &buf);
+                    conn.count_tx(buf.len());
+                    if let Some(out) = Self::build_send_to(now_ms, conn, pair, buf) {
+                        self.queue.push_back(out.into());
+                    }
                 }
             }
             FeatureWorkerOutput::RawBroadcast2(pairs, buf) => {
+                Self::count_raw_traffic(&mut self.traffic, &buf, pairs.len());
+                self.tap_multi(now_ms, &pairs, &buf);
+                Self::count_conns_tx(&mut self.conns, &pairs, buf.len());
                 let out = self.build_send_to_multi(now_ms, pairs, buf).map(|e| e.into()).unwrap_or(Output::Continue);
                 self.queue.push_back(out);
             }
@@ -431,14 +730,14 @@ where
             ServiceWorkerOutput::ForwardFeatureEventToController(event) => self.queue.push_back(LogicControl::ServiceEvent(service, event).into()),
             ServiceWorkerOutput::ToController(tc) => self.queue.push_back(LogicControl::Service(service, tc).into()),
             ServiceWorkerOutput::FeatureControl(control) => {
-                let feature = control.to_feature();
-                self.features
-                    .input(&mut self.switcher)
-                    .on_input(&mut self.feature_ctx, feature, now_ms, FeatureWorkerInput::Control(FeatureControlActor::Service(service), control));
+                if let Some(control) = self.shaper.send(now_ms, service, control) {
+                    self.send_service_control(now_ms, service, control);
+                }
             }
             ServiceWorkerOutput::Event(actor, event) => match actor {
                 ServiceControlActor::Controller(userdata) => self.queue.push_back(Output::Control(LogicControl::ExtServicesEvent(service, userdata, event))),
                 ServiceControlActor::Worker(worker, userdata) => {
+                    let worker = StickyExt::route(self.sticky_ext.as_ref(), worker, &userdata);
                     if self.worker_id == worker {
                         self.queue.push_back(Output::Ext(ExtOut::ServicesEvent(service, userdata, event)));
                     } else {
@@ -452,46 +751,215 @@ where
         }
     }
 
-    fn build_send_to_from_mut(now: u64, conn: &mut DataPlaneConnection, pair: NetPair, mut buf: Buffer) -> Option<NetOutput> {
+    /// Messages of unknown features are not counted, they are dropped or relayed without a metrics label
+    fn count_traffic(traffic: &mut BTreeMap<Features, FeatureTraffic>, feature: u8, incoming: bool, packets: usize, bytes: usize) {
+        if packets == 0 {
+            return;
+        }
+        let feature = return_if_none!(Features::try_from(feature).ok());
+        let entry = traffic.entry(feature).or_default();
+        if incoming {
+            entry.rx.add(packets, bytes);
+        } else {
+            entry.tx.add(packets, bytes);
+        }
+    }
+
+    /// Raw messages are already built by features, the feature id is read from the header
+    fn count_raw_traffic(traffic: &mut BTreeMap<Features, FeatureTraffic>, buf: &[u8], packets: usize) {
+        let feature = *return_if_none!(buf.get(2));
+        Self::count_traffic(traffic, feature, false, packets, buf.len() * packets);
+    }
+
+    /// Mirrored messages are sent to the controller, which emits them as [`ExtOut::Tap`]
+    fn tap_msg(tap: &Tap, queue: &mut DynamicDeque<Output<UserData, SC, SE, TC>, 16>, now_ms: u64, direction: TapDirection, pair: NetPair, neighbour: NodeId, msg: &[u8]) {
+        if let Some(record) = tap.capture(now_ms, direction, pair, neighbour, msg) {
+            queue.push_back(LogicControl::Tap(record).into());
+        }
+    }
+
+    fn tap_multi(&mut self, now_ms: u64, pairs: &[NetPair], msg: &[u8]) {
+        if !self.tap.is_enabled() {
+            return;
+        }
+        for pair in pairs {
+            if let Some(conn) = self.conns.get(pair) {
+                Self::tap_msg(&self.tap, &mut self.queue, now_ms, TapDirection::Outgoing, *pair, conn.node(), msg);
+            }
+        }
+    }
+
+    /// Traffic of connections is reported to the controller on tick, for per-connection stats
+    fn count_conns_tx(conns: &mut HashMap<NetPair, DataPlaneConnection>, pairs: &[NetPair], bytes: usize) {
+        for pair in pairs {
+            if let Some(conn) = conns.get_mut(pair) {
+                conn.count_tx(bytes);
+            }
+        }
+    }
+
+    /// With bandwidth scheduler, message can be queued and None is returned, it is popped later by [`Self::pop_scheduled`]
+    fn build_send_to_from_mut(now: u64, conn: &mut DataPlaneConnection, pair: NetPair, priority: MsgPriority, buf: Buffer) -> Option<NetOutput> {
+        let buf = match conn.scheduler_mut() {
+            Some(scheduler) => {
+                let feature = *buf.get(2)?;
+                scheduler.send(now, feature, priority, buf)?
+            }
+            None => buf,
+        };
+        Self::encode_send_to(now, conn, pair, priority, buf)
+    }
+
+    /// With constrained link, message is fragmented and only the first frame is returned, other frames are popped later.
+    /// With standard link, message which is larger than the mtu is fragmented the same way after encrypting
+    fn encode_send_to(now: u64, conn: &mut DataPlaneConnection, pair: NetPair, priority: MsgPriority, mut buf: Buffer) -> Option<NetOutput> {
+        conn.set_tx_priority(priority);
+        if let Some(link) = conn.link_mut() {
+            let feature = *buf.get(2)?;
+            let mut buf = link.compress(buf);
+            conn.encrypt_if_need(now, &mut buf)?;
+            let link = conn.link_mut()?;
+            link.send(now, feature, buf);
+            return link.pop_frame().map(|frame| NetOutput::UdpPacket(pair, frame, priority));
+        }
         conn.encrypt_if_need(now, &mut buf)?;
-        Some(NetOutput::UdpPacket(pair, buf))
+        let buf = conn.fragmenter_mut().send(buf)?;
+        Some(NetOutput::UdpPacket(pair, buf, priority))
     }
 
-    fn build_send_to_multi_from_mut(&mut self, now: u64, mut pairs: Vec<NetPair>, mut buf: Buffer) -> Option<NetOutput> {
+    fn build_send_to_multi_from_mut(&mut self, now: u64, mut pairs: Vec<NetPair>, priority: MsgPriority, buf: Buffer) -> Option<NetOutput> {
+        let split = buf.len() > self.fragment.mtu;
+        if !self.links.is_empty() || self.scheduler.is_some() || split {
+            // constrained links need framing, scheduled connections need accounting and large messages need fragmenting per connection
+            pairs.retain(|pair| {
+                if self.scheduler.is_none() && !split && !self.links.contains(pair) {
+                    return true;
+                }
+                if let Some(conn) = self.conns.get_mut(pair) {
+                    if let Some(out) = Self::build_send_to_from_mut(now, conn, *pair, priority, Buffer::build(&buf, 0, 12 + 16)) {
+                        self.queue.push_back(Output::Net(out));
+                    }
+                }
+                false
+            });
+            if pairs.is_empty() {
+                return None;
+            }
+        }
         if TransportMsgHeader::is_secure(buf[0]) {
             let first = pairs.pop()?;
             for pair in pairs {
                 if let Some(conn) = self.conns.get_mut(&pair) {
-                    let mut buf = Buffer::build(&buf, 0, 12 + 16);
-                    if conn.encrypt_if_need(now, &mut buf).is_some() {
-                        let out = NetOutput::UdpPacket(pair, buf);
+                    if let Some(out) = Self::encode_send_to(now, conn, pair, priority, Buffer::build(&buf, 0, 12 + 16)) {
                         self.queue.push_back(Output::Net(out));
                     }
                 }
             }
             let conn = self.conns.get_mut(&first)?;
-            conn.encrypt_if_need(now, &mut buf)?;
-            Some(NetOutput::UdpPacket(first, buf))
+            Self::encode_send_to(now, conn, first, priority, buf)
         } else {
-            Some(NetOutput::UdpPackets(pairs, buf))
+            Some(NetOutput::UdpPackets(pairs, buf, priority))
         }
     }
 
     fn build_send_to_multi(&mut self, now: u64, pairs: Vec<NetPair>, buf: Buffer) -> Option<NetOutput> {
-        if TransportMsgHeader::is_secure(buf[0]) {
+        if TransportMsgHeader::is_secure(buf[0]) || !self.links.is_empty() || self.scheduler.is_some() || buf.len() > self.fragment.mtu {
             let buf = Buffer::build(&buf, 0, 12 + 16);
-            self.build_send_to_multi_from_mut(now, pairs, buf)
+            self.build_send_to_multi_from_mut(now, pairs, MsgPriority::Normal, buf)
         } else {
-            Some(NetOutput::UdpPackets(pairs, buf))
+            Some(NetOutput::UdpPackets(pairs, buf, MsgPriority::Normal))
         }
     }
 
+    /// Pop a remaining fragment of a large message on standard links
+    fn pop_fragment(&mut self) -> Option<Output<UserData, SC, SE, TC>> {
+        for (pair, conn) in self.conns.iter_mut() {
+            if let Some(fragment) = conn.fragmenter_mut().pop_fragment() {
+                return Some(NetOutput::UdpPacket(*pair, fragment, conn.tx_priority()).into());
+            }
+        }
+        None
+    }
+
+    fn pop_link_frame(&mut self) -> Option<Output<UserData, SC, SE, TC>> {
+        for pair in &self.links {
+            let conn = match self.conns.get_mut(pair) {
+                Some(conn) => conn,
+                None => continue,
+            };
+            let priority = conn.tx_priority();
+            if let Some(frame) = conn.link_mut().and_then(|link| link.pop_frame()) {
+                return Some(NetOutput::UdpPacket(*pair, frame, priority).into());
+            }
+        }
+        None
+    }
+
+    fn send_service_control(&mut self, now_ms: u64, service: ServiceId, control: FeaturesControl) {
+        let feature = control.to_feature();
+        self.features
+            .input(&mut self.switcher)
+            .on_input(&mut self.feature_ctx, feature, now_ms, FeatureWorkerInput::Control(FeatureControlActor::Service(service), control));
+    }
+
+    /// Release service controls which are queued by the service shaper to features
+    fn pop_shaped(&mut self, now: u64) {
+        if self.shaper.is_empty() {
+            return;
+        }
+        while let Some((service, control)) = self.shaper.pop(now) {
+            self.send_service_control(now, service, control);
+        }
+    }
+
+    /// Shaping stats of a service, None if the service is not shaped
+    pub fn service_shaping_stats(&self, service: ServiceId) -> Option<scheduler::SchedulerStats> {
+        self.shaper.stats(service)
+    }
+
+    /// Replace the path which is selected by router with another path to same node by the multipath policy.
+    /// The router path is kept if multipath is disabled or no path is alive
+    fn select_path(&mut self, pair: NetPair) -> NetPair {
+        let policy = match self.multipath {
+            Some(policy) => policy,
+            None => return pair,
+        };
+        let node = match self.conns.get(&pair) {
+            Some(conn) => conn.node(),
+            None => return pair,
+        };
+        let paths = match self.paths.get_mut(&node) {
+            Some(paths) => paths,
+            None => return pair,
+        };
+        let conns = &self.conns;
+        paths.select(policy, self.tick_count, |p| conns.get(p).map(|c| c.quality())).unwrap_or(pair)
+    }
+
+    /// Pop a message which is released by the bandwidth scheduler of a connection
+    fn pop_scheduled(&mut self, now: u64) -> Option<Output<UserData, SC, SE, TC>> {
+        self.scheduler.as_ref()?;
+        for (pair, conn) in self.conns.iter_mut() {
+            while let Some((priority, buf)) = conn.scheduler_mut().and_then(|s| s.pop(now)) {
+                if let Some(out) = Self::encode_send_to(now, conn, *pair, priority, buf) {
+                    return Some(out.into());
+                }
+            }
+        }
+        None
+    }
+
+    /// Scheduler stats of a feature in the connection to the remote, None if the connection is not scheduled
+    pub fn scheduler_stats(&self, pair: NetPair, feature: Features) -> Option<scheduler::SchedulerStats> {
+        Some(self.conns.get(&pair)?.scheduler()?.stats(feature))
+    }
+
     fn build_send_to(now: u64, conn: &mut DataPlaneConnection, pair: NetPair, buf: Buffer) -> Option<NetOutput> {
-        if TransportMsgHeader::is_secure(buf[0]) {
+        if TransportMsgHeader::is_secure(buf[0]) || conn.link_mut().is_some() || conn.scheduler().is_some() || conn.fragmenter_mut().need_split(buf.len()) {
             let buf = Buffer::build(&buf, 0, 12 + 16);
-            Self::build_send_to_from_mut(now, conn, pair, buf)
+            Self::build_send_to_from_mut(now, conn, pair, MsgPriority::Normal, buf)
         } else {
-            Some(NetOutput::UdpPacket(pair, buf))
+            Some(NetOutput::UdpPacket(pair, buf, MsgPriority::Normal))
         }
     }
 }
@@ -512,6 +980,9 @@ where
 
     fn pop_output(&mut self, now: u64) -> Option<Output<UserData, SC, SE, TC>> {
         return_if_some!(self.queue.pop_front());
+        return_if_some!(self.pop_link_frame());
+        return_if_some!(self.pop_fragment());
+        self.pop_shaped(now);
 
         while let Some(current) = self.switcher.current() {
             match current.try_into().ok()? {
@@ -520,8 +991,9 @@ where
             }
 
             return_if_some!(self.queue.pop_front());
+            return_if_some!(self.pop_link_frame());
         }
 
-        None
+        self.pop_scheduled(now)
     }
 }
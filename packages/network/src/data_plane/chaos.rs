@@ -0,0 +1,265 @@
+//! Chaos layer for resilience testing, which is only built with the `chaos` feature.
+//!
+//! It is applied to packets which are received by a data plane worker, including neighbour controls, so both data and
+//! connection keep-alive of a link are affected. Packets can be dropped, duplicated, reordered and delayed with probabilities
+//! from [`ChaosConfig`], and all packets of current connections to a node can be dropped for simulating a dead link.
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+
+use atm0s_sdn_identity::{ConnId, NodeId};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::base::Buffer;
+
+use super::NetPair;
+
+/// Distribution of the extra delay of a packet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChaosDelay {
+    Fixed(u64),
+    Uniform {
+        min_ms: u64,
+        max_ms: u64,
+    },
+    /// Long tail delay with the mean, capped at max_ms
+    Exponential {
+        mean_ms: u64,
+        max_ms: u64,
+    },
+}
+
+impl ChaosDelay {
+    fn sample(&self, rng: &mut StdRng) -> u64 {
+        match *self {
+            ChaosDelay::Fixed(delay_ms) => delay_ms,
+            ChaosDelay::Uniform { min_ms, max_ms } => rng.gen_range(min_ms..=max_ms.max(min_ms)),
+            ChaosDelay::Exponential { mean_ms, max_ms } => {
+                let uniform: f64 = rng.gen_range(f64::EPSILON..1.0);
+                ((-uniform.ln() * mean_ms as f64) as u64).min(max_ms)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChaosConfig {
+    /// Probability to drop a packet, from 0.0 to 1.0
+    pub drop: f32,
+    /// Probability to process a packet twice
+    pub duplicate: f32,
+    /// Probability to hold a packet until the next packet is received or the next tick
+    pub reorder: f32,
+    pub delay: Option<ChaosDelay>,
+    /// Neighbours whose packets are affected, empty for all packets including packets of unknown remotes
+    pub nodes: Vec<NodeId>,
+    /// Seed of random decisions, so a scenario makes the same decisions for the same packets
+    pub seed: u64,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        Self {
+            drop: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            delay: None,
+            nodes: vec![],
+            seed: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChaosControl {
+    /// Replace the config of all workers, None for disabling
+    Set(Option<ChaosConfig>),
+    /// Drop all packets of current connections to the node, connections which are established after it are not affected
+    Kill(NodeId),
+}
+
+pub struct Chaos {
+    config: Option<ChaosConfig>,
+    rng: StdRng,
+    killed: HashSet<ConnId>,
+    held: Option<(NetPair, Buffer)>,
+    /// Delayed packets by release time and arrival order
+    delayed: BTreeMap<(u64, u64), (NetPair, Buffer)>,
+    seq: u64,
+    ready: VecDeque<(NetPair, Buffer)>,
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Self {
+            config: None,
+            rng: StdRng::seed_from_u64(0),
+            killed: HashSet::new(),
+            held: None,
+            delayed: BTreeMap::new(),
+            seq: 0,
+            ready: VecDeque::new(),
+        }
+    }
+}
+
+impl Chaos {
+    /// Set new config, held and delayed packets are still released at their time
+    pub fn set_config(&mut self, config: Option<ChaosConfig>) {
+        if let Some(config) = &config {
+            self.rng = StdRng::seed_from_u64(config.seed);
+        }
+        self.config = config;
+    }
+
+    pub fn kill(&mut self, conn: ConnId) {
+        self.killed.insert(conn);
+    }
+
+    /// Forget a killed connection after it is unpinned
+    pub fn on_unpin(&mut self, conn: ConnId) {
+        self.killed.remove(&conn);
+    }
+
+    /// Apply chaos to a received packet, packets which must be processed are returned by [`Self::pop_ready`].
+    /// Conn is the connection of the pair if it is pinned
+    pub fn on_packet(&mut self, now_ms: u64, pair: NetPair, conn: Option<(ConnId, NodeId)>, buf: Buffer) {
+        if conn.map_or(false, |(conn, _)| self.killed.contains(&conn)) {
+            log::debug!("[Chaos] drop packet of killed conn {pair}");
+            return;
+        }
+        let config = match &self.config {
+            Some(config) if config.nodes.is_empty() || conn.map_or(false, |(_, node)| config.nodes.contains(&node)) => config,
+            _ => {
+                self.ready.push_back((pair, buf));
+                return;
+            }
+        };
+        if self.rng.gen::<f32>() < config.drop {
+            log::debug!("[Chaos] drop packet from {pair}");
+            return;
+        }
+        let copies = if self.rng.gen::<f32>() < config.duplicate {
+            2
+        } else {
+            1
+        };
+        let delay_ms = config.delay.map(|delay| delay.sample(&mut self.rng)).unwrap_or(0);
+        let reorder = self.rng.gen::<f32>() < config.reorder;
+        for _ in 0..copies {
+            if delay_ms > 0 {
+                self.seq += 1;
+                self.delayed.insert((now_ms + delay_ms, self.seq), (pair, buf.clone()));
+            } else if reorder && self.held.is_none() {
+                self.held = Some((pair, buf.clone()));
+            } else {
+                self.ready.push_back((pair, buf.clone()));
+                // held packet is released after the packet which is received later
+                if let Some(held) = self.held.take() {
+                    self.ready.push_back(held);
+                }
+            }
+        }
+    }
+
+    /// Release the held packet, it is called on each tick so a packet isn't held forever on an idle link
+    pub fn on_tick(&mut self) {
+        if let Some(held) = self.held.take() {
+            self.ready.push_back(held);
+        }
+    }
+
+    pub fn pop_ready(&mut self, now_ms: u64) -> Option<(NetPair, Buffer)> {
+        if let Some(packet) = self.ready.pop_front() {
+            return Some(packet);
+        }
+        let (at, _) = self.delayed.keys().next()?;
+        if *at > now_ms {
+            return None;
+        }
+        self.delayed.pop_first().map(|(_, packet)| packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_identity::ConnId;
+
+    use crate::data_plane::NetPair;
+
+    use super::{Chaos, ChaosConfig, ChaosDelay};
+
+    fn pair() -> NetPair {
+        NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse")
+    }
+
+    fn ready(chaos: &mut Chaos, now_ms: u64) -> Vec<u8> {
+        let mut res = vec![];
+        while let Some((_, buf)) = chaos.pop_ready(now_ms) {
+            res.push(buf[0]);
+        }
+        res
+    }
+
+    #[test]
+    fn drop_and_duplicate_packets() {
+        let mut chaos = Chaos::default();
+        chaos.set_config(Some(ChaosConfig { drop: 1.0, ..Default::default() }));
+        chaos.on_packet(0, pair(), None, vec![1].into());
+        assert_eq!(ready(&mut chaos, 0), Vec::<u8>::new());
+
+        chaos.set_config(Some(ChaosConfig { duplicate: 1.0, ..Default::default() }));
+        chaos.on_packet(0, pair(), None, vec![2].into());
+        assert_eq!(ready(&mut chaos, 0), vec![2, 2]);
+
+        chaos.set_config(None);
+        chaos.on_packet(0, pair(), None, vec![3].into());
+        assert_eq!(ready(&mut chaos, 0), vec![3]);
+    }
+
+    #[test]
+    fn reorder_and_delay_packets() {
+        let mut chaos = Chaos::default();
+        chaos.set_config(Some(ChaosConfig { reorder: 1.0, ..Default::default() }));
+        chaos.on_packet(0, pair(), None, vec![1].into());
+        assert_eq!(ready(&mut chaos, 0), Vec::<u8>::new());
+        chaos.on_packet(0, pair(), None, vec![2].into());
+        assert_eq!(ready(&mut chaos, 0), vec![2, 1]);
+        chaos.on_packet(0, pair(), None, vec![3].into());
+        chaos.on_tick();
+        assert_eq!(ready(&mut chaos, 0), vec![3]);
+
+        chaos.set_config(Some(ChaosConfig {
+            delay: Some(ChaosDelay::Uniform { min_ms: 10, max_ms: 20 }),
+            ..Default::default()
+        }));
+        chaos.on_packet(0, pair(), None, vec![4].into());
+        assert_eq!(ready(&mut chaos, 9), Vec::<u8>::new());
+        assert_eq!(ready(&mut chaos, 20), vec![4]);
+    }
+
+    #[test]
+    fn filter_nodes_and_kill_conns() {
+        let conn1 = (ConnId::from_out(0, 1), 1);
+        let conn2 = (ConnId::from_out(0, 2), 2);
+        let mut chaos = Chaos::default();
+        chaos.set_config(Some(ChaosConfig {
+            drop: 1.0,
+            nodes: vec![1],
+            ..Default::default()
+        }));
+        chaos.on_packet(0, pair(), Some(conn1), vec![1].into());
+        chaos.on_packet(0, pair(), Some(conn2), vec![2].into());
+        chaos.on_packet(0, pair(), None, vec![3].into());
+        assert_eq!(ready(&mut chaos, 0), vec![2, 3]);
+
+        chaos.set_config(None);
+        chaos.kill(conn2.0);
+        chaos.on_packet(0, pair(), Some(conn1), vec![4].into());
+        chaos.on_packet(0, pair(), Some(conn2), vec![5].into());
+        assert_eq!(ready(&mut chaos, 0), vec![4]);
+
+        chaos.on_unpin(conn2.0);
+        chaos.on_packet(0, pair(), Some(conn2), vec![6].into());
+        assert_eq!(ready(&mut chaos, 0), vec![6]);
+    }
+}
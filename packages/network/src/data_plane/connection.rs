@@ -1,20 +1,132 @@
 use atm0s_sdn_identity::{ConnId, NodeId};
 
-use crate::base::{Buffer, SecureContext, TransportMsgHeader};
+use crate::{
+    base::{Buffer, Capabilities, ConnectionStats, DecryptionError, Decryptor, Encryptor, LinkProfile, MsgPriority, SecureContext, TransportMsgHeader},
+    metrics::FeatureTraffic,
+};
 
-use super::NetPair;
+use super::{
+    fragment::Fragmenter,
+    link::LinkFramer,
+    multipath::PathQuality,
+    replay::{self, ReplayFilter, SequenceGenerator},
+    scheduler::{ConnScheduler, SchedulerConfig},
+    NetPair,
+};
 
 pub struct DataPlaneConnection {
+    worker: u16,
     node: NodeId,
     conn: ConnId,
     #[allow(unused)]
     pair: NetPair,
     secure: SecureContext,
+    /// Framer for constrained link, None with standard link
+    link: Option<LinkFramer>,
+    /// Fragmentation of large messages on standard link
+    fragmenter: Fragmenter,
+    /// Bandwidth scheduler, None if the data plane doesn't limit connections
+    scheduler: Option<ConnScheduler>,
+    /// Class of the last sent message, remaining fragments and link frames are sent with it
+    tx_priority: MsgPriority,
+    /// Quality of this path which is probed by neighbours pings
+    quality: PathQuality,
+    /// Traffic which is not reported to the controller yet
+    traffic: FeatureTraffic,
+    /// Sequences of sent packets, only used after replay protection is agreed with the neighbour
+    tx_seq: Option<SequenceGenerator>,
+    rx_replay: ReplayFilter,
 }
 
 impl DataPlaneConnection {
-    pub fn new(node: NodeId, conn: ConnId, pair: NetPair, secure: SecureContext) -> Self {
-        Self { node, conn, pair, secure }
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(worker: u16, node: NodeId, conn: ConnId, pair: NetPair, secure: SecureContext, scheduler: Option<SchedulerConfig>, fragmenter: Fragmenter, tick: u64) -> Self {
+        Self {
+            worker,
+            node,
+            conn,
+            pair,
+            secure,
+            link: None,
+            fragmenter,
+            scheduler: scheduler.map(ConnScheduler::new),
+            tx_priority: MsgPriority::Normal,
+            quality: PathQuality::new(tick),
+            traffic: FeatureTraffic::default(),
+            tx_seq: None,
+            rx_replay: ReplayFilter::default(),
+        }
+    }
+
+    /// Start sending sequenced packets and fragments when the neighbour supports them
+    pub fn set_capabilities(&mut self, caps: Capabilities) {
+        self.fragmenter.set_enabled(caps.contains(Capabilities::FRAGMENTATION));
+        if !caps.contains(Capabilities::REPLAY_PROTECTION) {
+            self.tx_seq = None;
+        } else if self.tx_seq.is_none() {
+            self.tx_seq = Some(SequenceGenerator::new(self.worker));
+        }
+    }
+
+    pub fn set_link(&mut self, local: NodeId, link: LinkProfile) {
+        match link.mtu() {
+            Some(mtu) if self.link.as_ref().map(|l| l.mtu()) != Some(mtu) => self.link = Some(LinkFramer::new(local, mtu)),
+            Some(_) => {}
+            None => self.link = None,
+        }
+    }
+
+    /// Switch to keys of a new epoch, packets with old keys are still accepted in a grace period
+    pub fn rekey(&mut self, now_ms: u64, encryptor: Option<Box<dyn Encryptor>>, decryptor: Option<Box<dyn Decryptor>>) {
+        self.secure.rekey(now_ms, encryptor, decryptor);
+    }
+
+    pub fn link_mut(&mut self) -> Option<&mut LinkFramer> {
+        self.link.as_mut()
+    }
+
+    pub fn fragmenter_mut(&mut self) -> &mut Fragmenter {
+        &mut self.fragmenter
+    }
+
+    pub fn tx_priority(&self) -> MsgPriority {
+        self.tx_priority
+    }
+
+    pub fn set_tx_priority(&mut self, priority: MsgPriority) {
+        self.tx_priority = priority;
+    }
+
+    pub fn scheduler_mut(&mut self) -> Option<&mut ConnScheduler> {
+        self.scheduler.as_mut()
+    }
+
+    pub fn scheduler(&self) -> Option<&ConnScheduler> {
+        self.scheduler.as_ref()
+    }
+
+    pub fn on_stats(&mut self, tick: u64, stats: &ConnectionStats) {
+        self.quality.on_pong(tick, stats.rtt_ms);
+    }
+
+    pub fn quality(&self) -> PathQuality {
+        self.quality
+    }
+
+    pub fn count_rx(&mut self, bytes: usize) {
+        self.traffic.rx.add(1, bytes);
+    }
+
+    pub fn count_tx(&mut self, bytes: usize) {
+        self.traffic.tx.add(1, bytes);
+    }
+
+    /// Take the traffic since the last call, None if there is no traffic
+    pub fn take_traffic(&mut self) -> Option<FeatureTraffic> {
+        if self.traffic == FeatureTraffic::default() {
+            return None;
+        }
+        Some(std::mem::take(&mut self.traffic))
     }
 
     pub fn node(&self) -> NodeId {
@@ -25,7 +137,8 @@ impl DataPlaneConnection {
         self.conn
     }
 
-    /// This will encrypt without first byte, which is used for TransportMsgHeader meta
+    /// This will encrypt without first byte, which is used for TransportMsgHeader meta.
+    /// With replay protection the first byte is moved into the encrypted part after the sequence, see [`replay`]
     pub fn encrypt_if_need(&mut self, now: u64, buf: &mut Buffer) -> Option<()> {
         if buf.len() < 1 {
             return None;
@@ -34,23 +147,43 @@ impl DataPlaneConnection {
             return Some(());
         }
         buf.ensure_back(12 + 16); //TODO remove magic numbers
+        if let Some(tx_seq) = &mut self.tx_seq {
+            buf.push_front(&tx_seq.next_seq().to_be_bytes());
+            buf.push_front(&[replay::SEQUENCED]);
+        }
         buf.move_front_right(1);
-        self.secure.encryptor.encrypt(now, buf).ok()?;
+        self.secure.encrypt(now, buf).ok()?;
         buf.move_front_left(1);
         Some(())
     }
 
-    /// This will encrypt without first byte, which is used for TransportMsgHeader meta
-    pub fn decrypt_if_need(&mut self, now: u64, buf: &mut Buffer) -> Option<()> {
+    /// This will decrypt without first byte, which is used for TransportMsgHeader meta.
+    /// Sequenced packets are validated against the replay window and restored to the original layout
+    pub fn decrypt_if_need(&mut self, now: u64, buf: &mut Buffer) -> Result<(), DecryptionError> {
         if buf.len() < 1 {
-            return None;
+            return Err(DecryptionError::TooSmall);
         }
         if !TransportMsgHeader::is_secure(buf[0]) {
-            return Some(());
+            return Ok(());
+        }
+        let sequenced = replay::is_sequenced(buf[0]);
+        if !sequenced && !self.rx_replay.accept_unsequenced() {
+            return Err(DecryptionError::Replayed);
         }
         buf.move_front_right(1);
-        self.secure.decryptor.decrypt(now, buf).ok()?;
-        buf.move_front_left(1);
-        Some(())
+        self.secure.decrypt(now, buf)?;
+        if !sequenced {
+            buf.move_front_left(1);
+            return Ok(());
+        }
+        if buf.len() <= replay::SEQUENCE_LEN {
+            return Err(DecryptionError::TooSmall);
+        }
+        let sequence = u64::from_be_bytes(buf[0..replay::SEQUENCE_LEN].try_into().expect("Should have sequence"));
+        if !self.rx_replay.check(sequence) {
+            return Err(DecryptionError::Replayed);
+        }
+        buf.move_front_right(replay::SEQUENCE_LEN);
+        Ok(())
     }
 }
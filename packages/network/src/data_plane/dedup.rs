@@ -0,0 +1,80 @@
+use std::collections::{HashMap, VecDeque};
+
+use atm0s_sdn_identity::NodeId;
+
+use crate::base::TransportMsgHeader;
+
+/// How long a dedup token is remembered
+pub const DEDUP_TTL_MS: u64 = 10_000;
+/// Limit of remembered tokens, oldest ones are evicted first
+const DEDUP_MAX_ENTRIES: usize = 65536;
+
+type DedupKey = (u8, Option<NodeId>, u32);
+
+/// Remember recently received dedup tokens for dropping duplicated messages.
+/// Each data plane has its own cache, so duplicates arriving at different workers are not detected.
+#[derive(Debug, Default)]
+pub struct DedupCache {
+    seen: HashMap<DedupKey, u64>,
+    expires: VecDeque<(u64, DedupKey)>,
+}
+
+impl DedupCache {
+    /// Return true if the token is first seen, false if it is a duplicate
+    pub fn check(&mut self, now_ms: u64, feature: u8, source: Option<NodeId>, token: u32) -> bool {
+        self.clear_expired(now_ms);
+        let key = (feature, source, token);
+        if self.seen.contains_key(&key) {
+            return false;
+        }
+        if self.expires.len() >= DEDUP_MAX_ENTRIES {
+            if let Some((_, key)) = self.expires.pop_front() {
+                self.seen.remove(&key);
+            }
+        }
+        self.seen.insert(key, now_ms);
+        self.expires.push_back((now_ms + DEDUP_TTL_MS, key));
+        true
+    }
+
+    /// Return false if the message carries a dedup token which was already received
+    pub fn check_header(&mut self, now_ms: u64, header: &TransportMsgHeader) -> bool {
+        match header.dedup {
+            Some(token) => self.check(now_ms, header.feature, header.from_node, token),
+            None => true,
+        }
+    }
+
+    fn clear_expired(&mut self, now_ms: u64) {
+        while let Some((expire_at, key)) = self.expires.front() {
+            if *expire_at > now_ms {
+                break;
+            }
+            self.seen.remove(key);
+            self.expires.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DedupCache, DEDUP_TTL_MS};
+
+    #[test]
+    fn drop_duplicated_until_expired() {
+        let mut cache = DedupCache::default();
+        assert!(cache.check(0, 1, Some(2), 100));
+        assert!(!cache.check(10, 1, Some(2), 100));
+        // other feature, source or token are not duplicated
+        assert!(cache.check(10, 2, Some(2), 100));
+        assert!(cache.check(10, 1, Some(3), 100));
+        assert!(cache.check(10, 1, Some(2), 101));
+        assert_eq!(cache.seen.len(), 4);
+
+        assert!(!cache.check(DEDUP_TTL_MS - 1, 1, Some(2), 100));
+        assert!(cache.check(DEDUP_TTL_MS, 1, Some(2), 100));
+        assert_eq!(cache.seen.len(), 4);
+        assert!(cache.check(DEDUP_TTL_MS + 10, 1, Some(2), 102));
+        assert_eq!(cache.seen.len(), 2);
+    }
+}
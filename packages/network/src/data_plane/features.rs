@@ -3,7 +3,7 @@ use std::fmt::Debug;
 use atm0s_sdn_identity::ConnId;
 use sans_io_runtime::{TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
 
-use crate::base::{Buffer, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput, TransportMsgHeader};
+use crate::base::{Buffer, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput, IncomingRoute, TransportMsgHeader};
 use crate::features::*;
 
 use super::NetPair;
@@ -31,6 +31,10 @@ pub struct FeatureWorkerManager<UserData> {
     pubsub: TaskSwitcherBranch<pubsub::PubSubFeatureWorker<UserData>, pubsub::WorkerOutput<UserData>>,
     alias: TaskSwitcherBranch<alias::AliasFeatureWorker<UserData>, alias::WorkerOutput<UserData>>,
     socket: TaskSwitcherBranch<socket::SocketFeatureWorker<UserData>, socket::WorkerOutput<UserData>>,
+    nat_traversal: TaskSwitcherBranch<nat_traversal::NatTraversalFeatureWorker<UserData>, nat_traversal::WorkerOutput<UserData>>,
+    rpc: TaskSwitcherBranch<rpc::RpcFeatureWorker<UserData>, rpc::WorkerOutput<UserData>>,
+    membership: TaskSwitcherBranch<membership::MembershipFeatureWorker<UserData>, membership::WorkerOutput<UserData>>,
+    diag: TaskSwitcherBranch<diag::DiagFeatureWorker<UserData>, diag::WorkerOutput<UserData>>,
     switcher: TaskSwitcher,
     shutdown: bool,
 }
@@ -46,7 +50,11 @@ impl<UserData: Eq + Debug + Copy> FeatureWorkerManager<UserData> {
             pubsub: TaskSwitcherBranch::default(Features::PubSub as usize),
             alias: TaskSwitcherBranch::default(Features::Alias as usize),
             socket: TaskSwitcherBranch::default(Features::Socket as usize),
-            switcher: TaskSwitcher::new(8),
+            nat_traversal: TaskSwitcherBranch::default(Features::NatTraversal as usize),
+            rpc: TaskSwitcherBranch::default(Features::Rpc as usize),
+            membership: TaskSwitcherBranch::default(Features::Membership as usize),
+            diag: TaskSwitcherBranch::default(Features::Diag as usize),
+            switcher: TaskSwitcher::new(12),
             shutdown: false,
         }
     }
@@ -60,19 +68,37 @@ impl<UserData: Eq + Debug + Copy> FeatureWorkerManager<UserData> {
         self.pubsub.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
         self.alias.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
         self.socket.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
+        self.nat_traversal.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
+        self.rpc.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
+        self.membership.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
+        self.diag.input(&mut self.switcher).on_tick(ctx, now_ms, tick_count);
     }
 
     #[allow(clippy::too_many_arguments)]
-    pub fn on_network_raw(&mut self, ctx: &mut FeatureWorkerContext, feature: Features, now_ms: u64, conn: ConnId, pair: NetPair, header: TransportMsgHeader, buf: Buffer) {
+    pub fn on_network_raw(
+        &mut self,
+        ctx: &mut FeatureWorkerContext,
+        feature: Features,
+        now_ms: u64,
+        conn: ConnId,
+        pair: NetPair,
+        header: TransportMsgHeader,
+        route: Option<IncomingRoute>,
+        buf: Buffer,
+    ) {
         match feature {
-            Features::Neighbours => self.neighbours.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf),
-            Features::Data => self.data.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf),
-            Features::RouterSync => self.router_sync.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf),
-            Features::Vpn => self.vpn.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf),
-            Features::DhtKv => self.dht_kv.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf),
-            Features::PubSub => self.pubsub.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf),
-            Features::Alias => self.alias.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf),
-            Features::Socket => self.socket.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, buf),
+            Features::Neighbours => self.neighbours.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, route, buf),
+            Features::Data => self.data.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, route, buf),
+            Features::RouterSync => self.router_sync.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, route, buf),
+            Features::Vpn => self.vpn.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, route, buf),
+            Features::DhtKv => self.dht_kv.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, route, buf),
+            Features::PubSub => self.pubsub.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, route, buf),
+            Features::Alias => self.alias.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, route, buf),
+            Features::Socket => self.socket.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, route, buf),
+            Features::NatTraversal => self.nat_traversal.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, route, buf),
+            Features::Rpc => self.rpc.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, route, buf),
+            Features::Membership => self.membership.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, route, buf),
+            Features::Diag => self.diag.input(&mut self.switcher).on_network_raw(ctx, now_ms, conn, pair, header, route, buf),
         }
     }
 
@@ -87,6 +113,10 @@ impl<UserData: Eq + Debug + Copy> FeatureWorkerManager<UserData> {
                 FeaturesControl::PubSub(control) => self.pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control)),
                 FeaturesControl::Alias(control) => self.alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control)),
                 FeaturesControl::Socket(control) => self.socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control)),
+                FeaturesControl::NatTraversal(control) => self.nat_traversal.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control)),
+                FeaturesControl::Rpc(control) => self.rpc.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control)),
+                FeaturesControl::Membership(control) => self.membership.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control)),
+                FeaturesControl::Diag(control) => self.diag.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Control(actor, control)),
             },
             FeatureWorkerInput::FromController(is_broadcast, to) => match to {
                 FeaturesToWorker::Neighbours(to) => self.neighbours.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to)),
@@ -97,6 +127,10 @@ impl<UserData: Eq + Debug + Copy> FeatureWorkerManager<UserData> {
                 FeaturesToWorker::PubSub(to) => self.pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to)),
                 FeaturesToWorker::Alias(to) => self.alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to)),
                 FeaturesToWorker::Socket(to) => self.socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to)),
+                FeaturesToWorker::NatTraversal(to) => self.nat_traversal.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to)),
+                FeaturesToWorker::Rpc(to) => self.rpc.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to)),
+                FeaturesToWorker::Membership(to) => self.membership.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to)),
+                FeaturesToWorker::Diag(to) => self.diag.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::FromController(is_broadcast, to)),
             },
             FeatureWorkerInput::Network(..) => {
                 panic!("should call above on_network_raw")
@@ -112,6 +146,10 @@ impl<UserData: Eq + Debug + Copy> FeatureWorkerManager<UserData> {
                 Features::PubSub => self.pubsub.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf)),
                 Features::Alias => self.alias.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf)),
                 Features::Socket => self.socket.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf)),
+                Features::NatTraversal => self.nat_traversal.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf)),
+                Features::Rpc => self.rpc.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf)),
+                Features::Membership => self.membership.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf)),
+                Features::Diag => self.diag.input(&mut self.switcher).on_input(ctx, now_ms, FeatureWorkerInput::Local(header, buf)),
             },
         }
     }
@@ -128,6 +166,10 @@ impl<UserData: Eq + Debug + Copy> FeatureWorkerManager<UserData> {
         self.pubsub.input(&mut self.switcher).on_shutdown(ctx, now_ms);
         self.alias.input(&mut self.switcher).on_shutdown(ctx, now_ms);
         self.socket.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        self.nat_traversal.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        self.rpc.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        self.membership.input(&mut self.switcher).on_shutdown(ctx, now_ms);
+        self.diag.input(&mut self.switcher).on_shutdown(ctx, now_ms);
         self.shutdown = true;
     }
 }
@@ -149,6 +191,10 @@ impl<UserData> TaskSwitcherChild<Output<UserData>> for FeatureWorkerManager<User
             && self.pubsub.is_empty()
             && self.alias.is_empty()
             && self.socket.is_empty()
+            && self.nat_traversal.is_empty()
+            && self.rpc.is_empty()
+            && self.membership.is_empty()
+            && self.diag.is_empty()
     }
 
     fn pop_output(&mut self, now: u64) -> Option<Output<UserData>> {
@@ -194,6 +240,26 @@ impl<UserData> TaskSwitcherChild<Output<UserData>> for FeatureWorkerManager<User
                         return Some(Output::Output(Features::Socket, out.into2()));
                     }
                 }
+                Features::NatTraversal => {
+                    if let Some(out) = self.nat_traversal.pop_output(now, &mut self.switcher) {
+                        return Some(Output::Output(Features::NatTraversal, out.into2()));
+                    }
+                }
+                Features::Rpc => {
+                    if let Some(out) = self.rpc.pop_output(now, &mut self.switcher) {
+                        return Some(Output::Output(Features::Rpc, out.into2()));
+                    }
+                }
+                Features::Membership => {
+                    if let Some(out) = self.membership.pop_output(now, &mut self.switcher) {
+                        return Some(Output::Output(Features::Membership, out.into2()));
+                    }
+                }
+                Features::Diag => {
+                    if let Some(out) = self.diag.pop_output(now, &mut self.switcher) {
+                        return Some(Output::Output(Features::Diag, out.into2()));
+                    }
+                }
             }
         }
     }
@@ -0,0 +1,302 @@
+//! Fragmentation for standard links, which is enabled per connection after both sides agree on the fragmentation capability.
+//!
+//! Sockets receive datagrams into buffers of about 1500 bytes, so an encrypted message which is larger than the mtu is split
+//! into fragments and reassembled by the receiver before decrypting. Fragments share the frame namespace of constrained links
+//! (V=2 in the first byte), which is never used by standard links:
+//!
+//! - Fragment: `0x82` + seq (32 bits) + index (16 bits) + count (16 bits) + payload
+//!
+//! Fragments are best-effort like the messages which they carry, a partial message is dropped after the reassembly timeout.
+//! Received fragments are kept in buffers of the worker [`BufferPool`] until the message is completed.
+//! Constrained links already fragment inside their framing, so they don't use this layer.
+
+use std::collections::{HashMap, VecDeque};
+
+use atm0s_sdn_utils::log_sampled;
+
+use crate::base::Buffer;
+
+use super::pool::{BufferPool, PooledBuf};
+
+const FRAG_LARGE: u8 = 0x82;
+const FRAG_HEADER_SIZE: usize = 9;
+
+/// Max size of a datagram, which fits common internet paths after udp and ip headers
+pub const DEFAULT_MTU: usize = 1400;
+/// Messages which are larger than this are dropped by both sender and receiver
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 64 * 1024;
+/// Partial messages are dropped after this time
+pub const DEFAULT_REASSEMBLY_TIMEOUT_MS: u64 = 2000;
+/// Max partial messages of a connection, new ones are dropped when it is full
+const MAX_PARTIALS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentConfig {
+    /// Messages which are larger than the mtu are fragmented, each fragment fits the mtu
+    pub mtu: usize,
+    pub max_message_bytes: usize,
+    pub reassembly_timeout_ms: u64,
+}
+
+impl Default for FragmentConfig {
+    fn default() -> Self {
+        Self {
+            mtu: DEFAULT_MTU,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+            reassembly_timeout_ms: DEFAULT_REASSEMBLY_TIMEOUT_MS,
+        }
+    }
+}
+
+impl FragmentConfig {
+    /// Smallest supported mtu, which leaves room for the fragment header
+    pub const MIN_MTU: usize = 64;
+
+    pub fn new(mtu: usize) -> Self {
+        Self {
+            mtu: mtu.max(Self::MIN_MTU),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_max_message_bytes(mut self, bytes: usize) -> Self {
+        self.max_message_bytes = bytes;
+        self
+    }
+
+    pub fn with_reassembly_timeout_ms(mut self, timeout_ms: u64) -> Self {
+        self.reassembly_timeout_ms = timeout_ms;
+        self
+    }
+}
+
+/// Check if the packet is a fragment of a standard link
+pub fn is_fragment(first_byte: u8) -> bool {
+    first_byte == FRAG_LARGE
+}
+
+struct PartialMsg {
+    fragments: Vec<Option<PooledBuf>>,
+    received: usize,
+    bytes: usize,
+    started_at: u64,
+}
+
+/// Fragments messages of a standard link, it is sans-io: fragments to send are popped with [`Fragmenter::pop_fragment`]
+pub struct Fragmenter {
+    cfg: FragmentConfig,
+    /// Sending fragments is only enabled when the neighbour supports reassembly, receiving is always enabled
+    enabled: bool,
+    seq: u32,
+    partials: HashMap<u32, PartialMsg>,
+    fragments: VecDeque<Buffer>,
+    pool: BufferPool,
+}
+
+impl Fragmenter {
+    pub fn new(cfg: FragmentConfig, pool: BufferPool) -> Self {
+        Self {
+            cfg,
+            enabled: false,
+            seq: 0,
+            partials: HashMap::new(),
+            fragments: VecDeque::new(),
+            pool,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Check if the message will be fragmented
+    pub fn need_split(&self, len: usize) -> bool {
+        self.enabled && len > self.cfg.mtu
+    }
+
+    /// Split a message into fragments and return the first one, other fragments are popped later.
+    /// Messages which fit the mtu are returned as-is, messages which are larger than the max message size are dropped
+    pub fn send(&mut self, buf: Buffer) -> Option<Buffer> {
+        if !self.need_split(buf.len()) {
+            return Some(buf);
+        }
+        if buf.len() > self.cfg.max_message_bytes {
+            log_sampled!(log::Level::Warn, "[Fragmenter] drop message with {} bytes, which is over max {}", buf.len(), self.cfg.max_message_bytes);
+            return None;
+        }
+        let chunk = self.cfg.mtu - FRAG_HEADER_SIZE;
+        let count = buf.len().div_ceil(chunk);
+        if count > u16::MAX as usize {
+            log_sampled!(log::Level::Warn, "[Fragmenter] drop message with {} bytes, which is too big for mtu {}", buf.len(), self.cfg.mtu);
+            return None;
+        }
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        for index in 0..count {
+            let payload = &buf[index * chunk..buf.len().min((index + 1) * chunk)];
+            let mut fragment = Vec::with_capacity(FRAG_HEADER_SIZE + payload.len());
+            fragment.push(FRAG_LARGE);
+            fragment.extend_from_slice(&seq.to_be_bytes());
+            fragment.extend_from_slice(&(index as u16).to_be_bytes());
+            fragment.extend_from_slice(&(count as u16).to_be_bytes());
+            fragment.extend_from_slice(payload);
+            self.fragments.push_back(fragment.into());
+        }
+        self.fragments.pop_front()
+    }
+
+    /// Process a received fragment, returning the message if it is completed
+    pub fn on_fragment(&mut self, now: u64, buf: &[u8]) -> Option<Buffer> {
+        if buf.len() <= FRAG_HEADER_SIZE || !is_fragment(buf[0]) {
+            return None;
+        }
+        let seq = u32::from_be_bytes(buf[1..5].try_into().ok()?);
+        let index = u16::from_be_bytes([buf[5], buf[6]]) as usize;
+        let count = u16::from_be_bytes([buf[7], buf[8]]) as usize;
+        if index >= count {
+            return None;
+        }
+        if !self.partials.contains_key(&seq) && self.partials.len() >= MAX_PARTIALS {
+            log_sampled!(log::Level::Warn, "[Fragmenter] drop fragment of message {seq}, too many partial messages");
+            return None;
+        }
+        let max_message_bytes = self.cfg.max_message_bytes;
+        let partial = self.partials.entry(seq).or_insert_with(|| PartialMsg {
+            fragments: (0..count).map(|_| None).collect(),
+            received: 0,
+            bytes: 0,
+            started_at: now,
+        });
+        if partial.fragments.len() != count {
+            return None;
+        }
+        let payload = &buf[FRAG_HEADER_SIZE..];
+        if partial.fragments[index].is_none() {
+            if partial.bytes + payload.len() > max_message_bytes {
+                log_sampled!(log::Level::Warn, "[Fragmenter] drop message {seq}, which is over max {max_message_bytes} bytes");
+                self.partials.remove(&seq);
+                return None;
+            }
+            partial.fragments[index] = Some(self.pool.copy_from(payload));
+            partial.received += 1;
+            partial.bytes += payload.len();
+        }
+        if partial.received < count {
+            return None;
+        }
+        let partial = self.partials.remove(&seq)?;
+        let mut out = Vec::with_capacity(partial.bytes);
+        for fragment in partial.fragments.into_iter().flatten() {
+            out.extend_from_slice(&fragment);
+        }
+        Some(out.into())
+    }
+
+    /// Drop partial messages which are timed out
+    pub fn on_tick(&mut self, now: u64) {
+        let timeout = self.cfg.reassembly_timeout_ms;
+        self.partials.retain(|seq, partial| {
+            if now >= partial.started_at + timeout {
+                log::debug!("[Fragmenter] drop partial message {seq} after reassembly timeout");
+                return false;
+            }
+            true
+        });
+    }
+
+    pub fn pop_fragment(&mut self) -> Option<Buffer> {
+        self.fragments.pop_front()
+    }
+
+    pub fn has_fragments(&self) -> bool {
+        !self.fragments.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{base::Buffer, data_plane::pool::BufferPool};
+
+    use super::{is_fragment, FragmentConfig, Fragmenter};
+
+    fn new_fragmenter(cfg: FragmentConfig) -> Fragmenter {
+        Fragmenter::new(cfg, BufferPool::new(cfg.mtu, 16))
+    }
+
+    fn pop_all(fragmenter: &mut Fragmenter, first: Buffer) -> Vec<Buffer> {
+        let mut fragments = vec![first];
+        while let Some(fragment) = fragmenter.pop_fragment() {
+            fragments.push(fragment);
+        }
+        fragments
+    }
+
+    fn enabled(cfg: FragmentConfig) -> Fragmenter {
+        let mut fragmenter = new_fragmenter(cfg);
+        fragmenter.set_enabled(true);
+        fragmenter
+    }
+
+    #[test]
+    fn small_message_is_not_fragmented() {
+        let mut sender = enabled(FragmentConfig::new(100));
+        let msg: Buffer = vec![1; 100].into();
+        assert_eq!(sender.send(msg.clone()), Some(msg));
+        assert!(!sender.has_fragments());
+    }
+
+    #[test]
+    fn disabled_sender_does_not_fragment() {
+        let mut sender = new_fragmenter(FragmentConfig::new(100));
+        let msg: Buffer = vec![1; 500].into();
+        assert_eq!(sender.send(msg.clone()), Some(msg));
+    }
+
+    #[test]
+    fn fragment_and_reassemble() {
+        let mut sender = enabled(FragmentConfig::new(100));
+        let mut receiver = new_fragmenter(FragmentConfig::new(100));
+        let msg: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let first = sender.send(msg.clone().into()).expect("Should have first fragment");
+        let fragments = pop_all(&mut sender, first);
+        assert_eq!(fragments.len(), 11);
+        assert!(fragments.iter().all(|f| f.len() <= 100 && is_fragment(f[0])));
+
+        // fragments can arrive out of order and twice
+        for fragment in fragments.iter().rev().skip(1) {
+            assert_eq!(receiver.on_fragment(0, fragment), None);
+        }
+        assert_eq!(receiver.on_fragment(0, &fragments[1]), None);
+        assert_eq!(receiver.on_fragment(0, &fragments[10]).as_deref(), Some(msg.as_slice()));
+    }
+
+    #[test]
+    fn drop_over_max_message_bytes() {
+        let cfg = FragmentConfig::new(100).with_max_message_bytes(500);
+        let mut sender = enabled(cfg);
+        assert_eq!(sender.send(vec![1; 501].into()), None);
+        assert!(!sender.has_fragments());
+
+        // receiver checks the size too, with a sender which allows larger messages
+        let mut sender = enabled(FragmentConfig::new(100));
+        let mut receiver = new_fragmenter(cfg);
+        let first = sender.send(vec![1; 1000].into()).expect("Should have first fragment");
+        for fragment in pop_all(&mut sender, first) {
+            assert_eq!(receiver.on_fragment(0, &fragment), None);
+        }
+    }
+
+    #[test]
+    fn drop_partial_after_timeout() {
+        let mut sender = enabled(FragmentConfig::new(100));
+        let mut receiver = new_fragmenter(FragmentConfig::new(100).with_reassembly_timeout_ms(1000));
+        let first = sender.send(vec![1; 300].into()).expect("Should have first fragment");
+        let fragments = pop_all(&mut sender, first);
+        assert_eq!(receiver.on_fragment(0, &fragments[0]), None);
+        receiver.on_tick(1000);
+        // the first fragment is dropped, so the message is never completed
+        for fragment in &fragments[1..] {
+            assert_eq!(receiver.on_fragment(1000, fragment), None);
+        }
+    }
+}
@@ -0,0 +1,442 @@
+//! Framing for constrained links, which is enabled per connection after the link profile is negotiated.
+//!
+//! Messages are sent in frames which fit the link mtu. Frames use version V=2 in the first byte, so they never collide with
+//! TransportMsgHeader (V=0) or NeighboursControl (first byte 255):
+//!
+//! - Fragment: `|1|0|0|0|0|0|0|R|` + seq (16 bits) + index (8 bits) + count (8 bits) + payload, R is set for reliable messages
+//! - Ack: `0x90` + seq (16 bits), sent when a reliable message is fully received
+//! - Nack: `0x91` + seq (16 bits) + received bitmap (64 bits), sent when a reliable message is stuck, sender repairs missing fragments
+//!
+//! `0x82` is reserved for fragments of standard links, see [`super::fragment`].
+//!
+//! Unreliable messages which fit the mtu are sent as-is without framing overhead.
+//! Messages of control features (neighbours, router sync, dht_kv and alias) are reliable, other features stay best-effort.
+//!
+//! Before framing, the transport header is compressed by removing from_node when it is the sender itself, the receiver
+//! restores it from the connection. Compressed headers are marked with version V=1.
+
+use std::collections::{HashMap, VecDeque};
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_utils::log_sampled;
+
+use crate::{
+    base::{Buffer, TransportMsgHeader},
+    features::Features,
+};
+
+const FRAG_UNRELIABLE: u8 = 0x80;
+const FRAG_RELIABLE: u8 = 0x81;
+const FRAME_ACK: u8 = 0x90;
+const FRAME_NACK: u8 = 0x91;
+const FRAG_HEADER_SIZE: usize = 5;
+
+const COMPRESSED_HEADER: u8 = 0b0100_0000;
+const VERSION_MASK: u8 = 0b1100_0000;
+
+/// Max fragments of a message, which is limited by nack bitmap
+pub const MAX_FRAGMENTS: usize = 64;
+/// Resend reliable messages which are not acked after this time
+pub const REPAIR_TIMEOUT_MS: u64 = 200;
+/// Reliable messages are dropped after this number of resends
+pub const MAX_REPAIRS: u8 = 5;
+/// Send nack for a reliable message which doesn't receive new fragments after this time
+const NACK_AFTER_MS: u64 = 100;
+/// Partial messages are dropped after this time
+const REASSEMBLY_TIMEOUT_MS: u64 = 5000;
+/// Completed reliable messages are remembered for re-acking resent fragments without delivering twice
+const COMPLETED_TTL_MS: u64 = 10_000;
+
+/// Check if the packet is a link frame
+pub fn is_frame(first_byte: u8) -> bool {
+    first_byte & VERSION_MASK == 0b1000_0000
+}
+
+fn is_control(feature: u8) -> bool {
+    matches!(
+        Features::try_from(feature),
+        Ok(Features::Neighbours | Features::RouterSync | Features::DhtKv | Features::Alias | Features::NatTraversal)
+    )
+}
+
+/// Offset of from_node in header, which is after fixed part and route destination
+fn from_node_offset(first_byte: u8) -> usize {
+    if first_byte & 7 == 0 {
+        4
+    } else {
+        4 + 4
+    }
+}
+
+/// Remove from_node from plain header if it is the local node
+pub fn compress_header(buf: Buffer, local: NodeId) -> Buffer {
+    let header = match TransportMsgHeader::try_from(&buf as &[u8]) {
+        Ok(header) => header,
+        Err(_) => return buf,
+    };
+    if header.from_node != Some(local) {
+        return buf;
+    }
+    let offset = from_node_offset(buf[0]);
+    let mut out = Vec::with_capacity(buf.len() - 4);
+    out.push(buf[0] | COMPRESSED_HEADER);
+    out.extend_from_slice(&buf[1..offset]);
+    out.extend_from_slice(&buf[offset + 4..]);
+    out.into()
+}
+
+/// Restore from_node of a compressed plain header with the node of connection
+pub fn decompress_header(buf: Buffer, remote: NodeId) -> Option<Buffer> {
+    if buf.is_empty() || buf[0] & VERSION_MASK != COMPRESSED_HEADER {
+        return Some(buf);
+    }
+    let offset = from_node_offset(buf[0]);
+    if buf.len() < offset {
+        return None;
+    }
+    let mut out = Vec::with_capacity(buf.len() + 4);
+    out.push(buf[0] & !VERSION_MASK);
+    out.extend_from_slice(&buf[1..offset]);
+    out.extend_from_slice(&remote.to_be_bytes());
+    out.extend_from_slice(&buf[offset..]);
+    Some(out.into())
+}
+
+struct PendingMsg {
+    frames: Vec<Buffer>,
+    sent_at: u64,
+    repairs: u8,
+}
+
+struct PartialMsg {
+    reliable: bool,
+    fragments: Vec<Option<Vec<u8>>>,
+    received: u64,
+    started_at: u64,
+    updated_at: u64,
+    nacked_at: u64,
+}
+
+impl PartialMsg {
+    fn is_completed(&self) -> bool {
+        self.fragments.iter().all(|f| f.is_some())
+    }
+}
+
+/// Frames messages for a constrained link, it is sans-io: frames to send are popped with [`LinkFramer::pop_frame`]
+pub struct LinkFramer {
+    local: NodeId,
+    mtu: usize,
+    seq: u16,
+    pending: HashMap<u16, PendingMsg>,
+    partials: HashMap<u16, PartialMsg>,
+    completed: VecDeque<(u16, u64)>,
+    frames: VecDeque<Buffer>,
+}
+
+impl LinkFramer {
+    pub fn new(local: NodeId, mtu: u16) -> Self {
+        Self {
+            local,
+            mtu: mtu as usize,
+            seq: 0,
+            pending: HashMap::new(),
+            partials: HashMap::new(),
+            completed: VecDeque::new(),
+            frames: VecDeque::new(),
+        }
+    }
+
+    pub fn mtu(&self) -> u16 {
+        self.mtu as u16
+    }
+
+    /// Compress header of plain message before encrypting
+    pub fn compress(&self, buf: Buffer) -> Buffer {
+        compress_header(buf, self.local)
+    }
+
+    /// Split a message into frames, the feature is used for selecting reliable mode.
+    /// Messages which need more than [`MAX_FRAGMENTS`] frames are dropped.
+    pub fn send(&mut self, now: u64, feature: u8, buf: Buffer) {
+        let reliable = is_control(feature);
+        if !reliable && buf.len() <= self.mtu {
+            self.frames.push_back(buf);
+            return;
+        }
+        let chunk = self.mtu - FRAG_HEADER_SIZE;
+        let count = buf.len().div_ceil(chunk).max(1);
+        if count > MAX_FRAGMENTS {
+            log_sampled!(log::Level::Warn, "[LinkFramer] drop message with {} bytes, which is too big for mtu {}", buf.len(), self.mtu);
+            return;
+        }
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        let kind = if reliable {
+            FRAG_RELIABLE
+        } else {
+            FRAG_UNRELIABLE
+        };
+        let mut frames = Vec::with_capacity(count);
+        for index in 0..count {
+            let payload = &buf[index * chunk..buf.len().min((index + 1) * chunk)];
+            let mut frame = Vec::with_capacity(FRAG_HEADER_SIZE + payload.len());
+            frame.push(kind);
+            frame.extend_from_slice(&seq.to_be_bytes());
+            frame.push(index as u8);
+            frame.push(count as u8);
+            frame.extend_from_slice(payload);
+            frames.push(Buffer::from(frame));
+        }
+        self.frames.extend(frames.iter().cloned());
+        if reliable {
+            self.pending.insert(seq, PendingMsg { frames, sent_at: now, repairs: 0 });
+        }
+    }
+
+    /// Process a received frame, returning the message if it is completed
+    pub fn on_frame(&mut self, now: u64, buf: &[u8]) -> Option<Buffer> {
+        if buf.len() < 3 {
+            return None;
+        }
+        let seq = u16::from_be_bytes([buf[1], buf[2]]);
+        match buf[0] {
+            FRAME_ACK => {
+                self.pending.remove(&seq);
+                None
+            }
+            FRAME_NACK => {
+                let received = u64::from_be_bytes(buf.get(3..11)?.try_into().ok()?);
+                let pending = self.pending.get_mut(&seq)?;
+                pending.sent_at = now;
+                for (index, frame) in pending.frames.iter().enumerate() {
+                    if received & (1 << index) == 0 {
+                        self.frames.push_back(frame.clone());
+                    }
+                }
+                None
+            }
+            kind @ (FRAG_UNRELIABLE | FRAG_RELIABLE) => {
+                let reliable = kind == FRAG_RELIABLE;
+                let index = *buf.get(3)? as usize;
+                let count = *buf.get(4)? as usize;
+                if count == 0 || count > MAX_FRAGMENTS || index >= count {
+                    return None;
+                }
+                if reliable && self.completed.iter().any(|(s, _)| *s == seq) {
+                    // ack was lost, sender is still resending
+                    self.frames.push_back(Self::ack(seq));
+                    return None;
+                }
+                let partial = self.partials.entry(seq).or_insert_with(|| PartialMsg {
+                    reliable,
+                    fragments: vec![None; count],
+                    received: 0,
+                    started_at: now,
+                    updated_at: now,
+                    nacked_at: now,
+                });
+                if partial.fragments.len() != count || partial.reliable != reliable {
+                    return None;
+                }
+                if partial.fragments[index].is_none() {
+                    partial.fragments[index] = Some(buf[FRAG_HEADER_SIZE..].to_vec());
+                    partial.received |= 1 << index;
+                    partial.updated_at = now;
+                }
+                if !partial.is_completed() {
+                    return None;
+                }
+                let partial = self.partials.remove(&seq)?;
+                if reliable {
+                    self.completed.push_back((seq, now));
+                    self.frames.push_back(Self::ack(seq));
+                }
+                Some(partial.fragments.into_iter().flatten().flatten().collect::<Vec<_>>().into())
+            }
+            _ => None,
+        }
+    }
+
+    /// Resend not acked reliable messages and nack stuck ones
+    pub fn on_tick(&mut self, now: u64) {
+        let frames = &mut self.frames;
+        self.pending.retain(|seq, pending| {
+            if now < pending.sent_at + REPAIR_TIMEOUT_MS {
+                return true;
+            }
+            if pending.repairs >= MAX_REPAIRS {
+                log_sampled!(log::Level::Warn, "[LinkFramer] drop reliable message {seq} after {} repairs", pending.repairs);
+                return false;
+            }
+            pending.repairs += 1;
+            pending.sent_at = now;
+            frames.extend(pending.frames.iter().cloned());
+            true
+        });
+
+        self.partials.retain(|seq, partial| {
+            if now >= partial.started_at + REASSEMBLY_TIMEOUT_MS {
+                log::debug!("[LinkFramer] drop partial message {seq} after reassembly timeout");
+                return false;
+            }
+            if partial.reliable && now >= partial.updated_at + NACK_AFTER_MS && now >= partial.nacked_at + NACK_AFTER_MS {
+                partial.nacked_at = now;
+                let mut frame = Vec::with_capacity(11);
+                frame.push(FRAME_NACK);
+                frame.extend_from_slice(&seq.to_be_bytes());
+                frame.extend_from_slice(&partial.received.to_be_bytes());
+                frames.push_back(frame.into());
+            }
+            true
+        });
+
+        while let Some((_, at)) = self.completed.front() {
+            if now < at + COMPLETED_TTL_MS {
+                break;
+            }
+            self.completed.pop_front();
+        }
+    }
+
+    pub fn pop_frame(&mut self) -> Option<Buffer> {
+        self.frames.pop_front()
+    }
+
+    fn ack(seq: u16) -> Buffer {
+        let mut frame = Vec::with_capacity(3);
+        frame.push(FRAME_ACK);
+        frame.extend_from_slice(&seq.to_be_bytes());
+        frame.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_router::RouteRule;
+
+    use crate::{
+        base::{Buffer, TransportMsg, TransportMsgHeader},
+        features::{data, router_sync},
+    };
+
+    use super::{compress_header, decompress_header, is_frame, LinkFramer, MAX_REPAIRS, REPAIR_TIMEOUT_MS};
+
+    fn pop_all(framer: &mut LinkFramer) -> Vec<Buffer> {
+        let mut frames = vec![];
+        while let Some(frame) = framer.pop_frame() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn compress_from_node() {
+        let header = TransportMsgHeader::build(1, 2, RouteRule::ToNode(3)).set_from_node(Some(10));
+        let msg = TransportMsg::build_raw(header.clone(), vec![1, 2, 3].into()).take();
+        let compressed = compress_header(msg.clone(), 10);
+        assert_eq!(compressed.len(), msg.len() - 4);
+        assert!(!is_frame(compressed[0]));
+        let restored = decompress_header(compressed, 10).expect("Should decompress");
+        assert_eq!(&restored[..], &msg[..]);
+
+        // relayed message from other node is not compressed
+        assert_eq!(&compress_header(msg.clone(), 11)[..], &msg[..]);
+
+        // direct message
+        let header = TransportMsgHeader::build(1, 2, RouteRule::Direct).set_from_node(Some(10)).set_dedup(Some(100));
+        let msg = TransportMsg::build_raw(header, vec![1, 2, 3].into()).take();
+        let compressed = compress_header(msg.clone(), 10);
+        assert_eq!(compressed.len(), msg.len() - 4);
+        assert_eq!(&decompress_header(compressed, 10).expect("Should decompress")[..], &msg[..]);
+    }
+
+    #[test]
+    fn small_unreliable_message_is_not_framed() {
+        let mut framer = LinkFramer::new(1, 64);
+        framer.send(0, data::FEATURE_ID, vec![1; 64].into());
+        let frames = pop_all(&mut framer);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(&frames[0][..], &[1; 64]);
+    }
+
+    #[test]
+    fn fragment_and_reassemble() {
+        let mut sender = LinkFramer::new(1, 64);
+        let mut receiver = LinkFramer::new(2, 64);
+        let msg: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        sender.send(0, data::FEATURE_ID, msg.clone().into());
+        let frames = pop_all(&mut sender);
+        assert_eq!(frames.len(), 4);
+        assert!(frames.iter().all(|f| f.len() <= 64 && is_frame(f[0])));
+
+        assert_eq!(receiver.on_frame(0, &frames[0]), None);
+        assert_eq!(receiver.on_frame(0, &frames[2]), None);
+        assert_eq!(receiver.on_frame(0, &frames[3]), None);
+        assert_eq!(receiver.on_frame(0, &frames[1]).as_deref(), Some(msg.as_slice()));
+        // unreliable message doesn't need ack
+        assert_eq!(receiver.pop_frame(), None);
+        // and is not repaired
+        sender.on_tick(REPAIR_TIMEOUT_MS);
+        assert_eq!(sender.pop_frame(), None);
+    }
+
+    #[test]
+    fn reliable_ack_and_repair() {
+        let mut sender = LinkFramer::new(1, 64);
+        let mut receiver = LinkFramer::new(2, 64);
+        let msg: Vec<u8> = (0..150).map(|i| i as u8).collect();
+        sender.send(0, router_sync::FEATURE_ID, msg.clone().into());
+        let frames = pop_all(&mut sender);
+        assert_eq!(frames.len(), 3);
+
+        // second fragment is lost
+        assert_eq!(receiver.on_frame(0, &frames[0]), None);
+        assert_eq!(receiver.on_frame(0, &frames[2]), None);
+
+        // receiver nacks, sender repairs only missing fragment
+        receiver.on_tick(100);
+        let nack = pop_all(&mut receiver);
+        assert_eq!(nack.len(), 1);
+        assert_eq!(sender.on_frame(100, &nack[0]), None);
+        let repaired = pop_all(&mut sender);
+        assert_eq!(repaired.len(), 1);
+        assert_eq!(&repaired[0][..], &frames[1][..]);
+
+        assert_eq!(receiver.on_frame(110, &repaired[0]).as_deref(), Some(msg.as_slice()));
+        let ack = pop_all(&mut receiver);
+        assert_eq!(ack.len(), 1);
+
+        // resent fragment after completed is acked again but not delivered twice
+        assert_eq!(receiver.on_frame(120, &frames[0]), None);
+        assert_eq!(pop_all(&mut receiver).len(), 1);
+
+        assert_eq!(sender.on_frame(120, &ack[0]), None);
+        sender.on_tick(1000);
+        assert_eq!(sender.pop_frame(), None);
+    }
+
+    #[test]
+    fn reliable_resend_until_max_repairs() {
+        let mut sender = LinkFramer::new(1, 64);
+        sender.send(0, router_sync::FEATURE_ID, vec![1, 2, 3].into());
+        assert_eq!(pop_all(&mut sender).len(), 1);
+
+        let mut now = 0;
+        for _ in 0..MAX_REPAIRS {
+            now += REPAIR_TIMEOUT_MS;
+            sender.on_tick(now);
+            assert_eq!(pop_all(&mut sender).len(), 1);
+        }
+        now += REPAIR_TIMEOUT_MS;
+        sender.on_tick(now);
+        assert_eq!(sender.pop_frame(), None);
+    }
+
+    #[test]
+    fn drop_too_big_message() {
+        let mut sender = LinkFramer::new(1, 32);
+        sender.send(0, data::FEATURE_ID, vec![0; 27 * 64 + 1].into());
+        assert_eq!(sender.pop_frame(), None);
+    }
+}
@@ -0,0 +1,138 @@
+//! Multipath between neighbours.
+//!
+//! A neighbour can be connected over many paths, like udp and tcp or many interfaces, and each path is a separate connection.
+//! The router selects one connection as the next hop, then the data plane can replace it with another path to the same node
+//! by the policy. Paths are probed by neighbours pings, a path which has not received pong for [`PATH_STALE_TICKS`] ticks
+//! is skipped, so traffic fails over long before the connection is timed out.
+
+use super::NetPair;
+
+/// Path which has no pong in this number of ticks is considered as down
+pub const PATH_STALE_TICKS: u64 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MultipathPolicy {
+    /// Use the first established path while it is alive, then the next one
+    Failover,
+    /// Spread messages over all alive paths
+    RoundRobin,
+    /// Use the alive path which has lowest rtt
+    LowestRtt,
+}
+
+/// Quality of a path, which is updated by neighbours pings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathQuality {
+    /// None until the first pong is received
+    pub rtt_ms: Option<u32>,
+    /// Tick of the last pong, or the tick when the path is established
+    pub updated_tick: u64,
+}
+
+impl PathQuality {
+    pub fn new(tick: u64) -> Self {
+        Self { rtt_ms: None, updated_tick: tick }
+    }
+
+    pub fn on_pong(&mut self, tick: u64, rtt_ms: u32) {
+        self.rtt_ms = Some(rtt_ms);
+        self.updated_tick = tick;
+    }
+
+    pub fn is_alive(&self, tick: u64) -> bool {
+        tick <= self.updated_tick + PATH_STALE_TICKS
+    }
+}
+
+/// Paths to a neighbour in established order
+#[derive(Debug, Default)]
+pub struct NodePaths {
+    pairs: Vec<NetPair>,
+    cursor: usize,
+}
+
+impl NodePaths {
+    pub fn add(&mut self, pair: NetPair) {
+        if !self.pairs.contains(&pair) {
+            self.pairs.push(pair);
+        }
+    }
+
+    /// Remove a path, return true if there is no path left
+    pub fn remove(&mut self, pair: NetPair) -> bool {
+        self.pairs.retain(|p| *p != pair);
+        self.pairs.is_empty()
+    }
+
+    /// Select a path by the policy, None if no path is alive
+    pub fn select<F: Fn(&NetPair) -> Option<PathQuality>>(&mut self, policy: MultipathPolicy, tick: u64, quality: F) -> Option<NetPair> {
+        let alive: Vec<(NetPair, PathQuality)> = self.pairs.iter().filter_map(|pair| Some((*pair, quality(pair)?))).filter(|(_, q)| q.is_alive(tick)).collect();
+        match policy {
+            MultipathPolicy::Failover => alive.first().map(|(pair, _)| *pair),
+            MultipathPolicy::RoundRobin => {
+                if alive.is_empty() {
+                    return None;
+                }
+                let pair = alive[self.cursor % alive.len()].0;
+                self.cursor = self.cursor.wrapping_add(1);
+                Some(pair)
+            }
+            MultipathPolicy::LowestRtt => alive.iter().min_by_key(|(_, q)| q.rtt_ms.unwrap_or(u32::MAX)).map(|(pair, _)| *pair),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::data_plane::NetPair;
+
+    use super::{MultipathPolicy, NodePaths, PathQuality};
+
+    fn pair(port: u16) -> NetPair {
+        NetPair::new(format!("127.0.0.1:{port}").parse().expect("Should parse"), "127.0.0.1:2000".parse().expect("Should parse"))
+    }
+
+    fn build(qualities: &[(u16, Option<u32>, u64)]) -> (NodePaths, HashMap<NetPair, PathQuality>) {
+        let mut paths = NodePaths::default();
+        let mut map = HashMap::new();
+        for (port, rtt_ms, updated_tick) in qualities {
+            paths.add(pair(*port));
+            map.insert(
+                pair(*port),
+                PathQuality {
+                    rtt_ms: *rtt_ms,
+                    updated_tick: *updated_tick,
+                },
+            );
+        }
+        (paths, map)
+    }
+
+    #[test]
+    fn failover_to_next_alive_path() {
+        let (mut paths, mut map) = build(&[(1, Some(10), 0), (2, Some(20), 0)]);
+        assert_eq!(paths.select(MultipathPolicy::Failover, 3, |p| map.get(p).copied()), Some(pair(1)));
+        map.get_mut(&pair(2)).expect("Should have path").on_pong(5, 20);
+        assert_eq!(paths.select(MultipathPolicy::Failover, 5, |p| map.get(p).copied()), Some(pair(2)));
+        assert_eq!(paths.select(MultipathPolicy::Failover, 10, |p| map.get(p).copied()), None);
+    }
+
+    #[test]
+    fn round_robin_over_alive_paths() {
+        let (mut paths, map) = build(&[(1, Some(10), 0), (2, Some(20), 10), (3, Some(30), 10)]);
+        let selected: Vec<_> = (0..4).map(|_| paths.select(MultipathPolicy::RoundRobin, 10, |p| map.get(p).copied())).collect();
+        assert_eq!(selected, vec![Some(pair(2)), Some(pair(3)), Some(pair(2)), Some(pair(3))]);
+    }
+
+    #[test]
+    fn lowest_rtt_prefer_measured_path() {
+        let (mut paths, mut map) = build(&[(1, None, 0), (2, Some(50), 0), (3, Some(20), 0)]);
+        assert_eq!(paths.select(MultipathPolicy::LowestRtt, 0, |p| map.get(p).copied()), Some(pair(3)));
+        map.get_mut(&pair(1)).expect("Should have path").on_pong(1, 5);
+        assert_eq!(paths.select(MultipathPolicy::LowestRtt, 1, |p| map.get(p).copied()), Some(pair(1)));
+        assert!(!paths.remove(pair(1)));
+        assert_eq!(paths.select(MultipathPolicy::LowestRtt, 1, |p| map.get(p).copied()), Some(pair(3)));
+    }
+}
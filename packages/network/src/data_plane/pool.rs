@@ -0,0 +1,148 @@
+//! Pool of fixed-size byte buffers which are reused inside the data plane of a worker.
+//!
+//! Buffers which are sent to sockets are owned by the runtime after sending, so they cannot come back. The pool is for
+//! buffers which stay inside the data plane, like fragments which wait for reassembly, so a busy connection reuses same
+//! allocations instead of allocating for every packet. A [`PooledBuf`] holds a reference to the pool and returns its
+//! memory when it is dropped, the pool is shared by all connections of a worker and is not thread-safe.
+
+use std::{
+    cell::RefCell,
+    ops::{Deref, DerefMut},
+    rc::Rc,
+};
+
+/// Max idle buffers which are kept, other buffers are freed when they are returned
+pub const DEFAULT_MAX_IDLE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Buffers which are allocated because the pool is empty
+    pub allocated: u64,
+    /// Buffers which are taken from idle ones
+    pub reused: u64,
+    /// Idle buffers in the pool
+    pub idle: usize,
+}
+
+struct PoolInner {
+    buf_size: usize,
+    max_idle: usize,
+    idle: Vec<Vec<u8>>,
+    stats: PoolStats,
+}
+
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Rc<RefCell<PoolInner>>,
+}
+
+impl BufferPool {
+    /// Create a pool of buffers with capacity of buf_size bytes
+    pub fn new(buf_size: usize, max_idle: usize) -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(PoolInner {
+                buf_size,
+                max_idle,
+                idle: Vec::new(),
+                stats: PoolStats::default(),
+            })),
+        }
+    }
+
+    /// Take an empty buffer, it is returned to the pool when dropped
+    pub fn take(&self) -> PooledBuf {
+        let mut inner = self.inner.borrow_mut();
+        let buf = match inner.idle.pop() {
+            Some(buf) => {
+                inner.stats.reused += 1;
+                buf
+            }
+            None => {
+                inner.stats.allocated += 1;
+                Vec::with_capacity(inner.buf_size)
+            }
+        };
+        PooledBuf { buf, pool: self.inner.clone() }
+    }
+
+    /// Take a buffer with a copy of data
+    pub fn copy_from(&self, data: &[u8]) -> PooledBuf {
+        let mut buf = self.take();
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let inner = self.inner.borrow();
+        PoolStats {
+            idle: inner.idle.len(),
+            ..inner.stats
+        }
+    }
+}
+
+/// Buffer which is borrowed from a [`BufferPool`]
+pub struct PooledBuf {
+    buf: Vec<u8>,
+    pool: Rc<RefCell<PoolInner>>,
+}
+
+impl Deref for PooledBuf {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        let mut pool = self.pool.borrow_mut();
+        // buffers which are grown over the size are not kept, so the pool memory is bounded
+        if pool.idle.len() < pool.max_idle && self.buf.capacity() <= pool.buf_size {
+            let mut buf = std::mem::take(&mut self.buf);
+            buf.clear();
+            pool.idle.push(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BufferPool, PoolStats};
+
+    #[test]
+    fn reuse_returned_buffers() {
+        let pool = BufferPool::new(64, 2);
+        let buf1 = pool.copy_from(&[1, 2, 3]);
+        let buf2 = pool.take();
+        assert_eq!(&buf1[..], &[1, 2, 3]);
+        drop(buf1);
+        drop(buf2);
+        assert_eq!(pool.stats(), PoolStats { allocated: 2, reused: 0, idle: 2 });
+
+        let buf = pool.take();
+        assert!(buf.is_empty());
+        assert_eq!(pool.stats(), PoolStats { allocated: 2, reused: 1, idle: 1 });
+    }
+
+    #[test]
+    fn bounded_idle_buffers() {
+        let pool = BufferPool::new(64, 1);
+        let bufs: Vec<_> = (0..3).map(|_| pool.take()).collect();
+        drop(bufs);
+        assert_eq!(pool.stats().idle, 1);
+
+        // grown buffer is freed
+        let mut buf = pool.take();
+        buf.extend_from_slice(&[0; 100]);
+        drop(buf);
+        assert_eq!(pool.stats().idle, 0);
+    }
+}
@@ -0,0 +1,144 @@
+//! Anti-replay protection of encrypted packets.
+//!
+//! The encryption only rejects packets which are older than a few seconds, so a captured datagram can be replayed into the
+//! mesh while it is still fresh. With neighbours which advertise [`Capabilities::REPLAY_PROTECTION`] each encrypted packet
+//! carries a sequence inside the encrypted part, and the receiver drops sequences which it has already seen or which are
+//! behind its sliding window:
+//!
+//! ```text
+//! | SEQUENCED (1 byte) | encrypted( sequence (8 bytes BE) | original first byte | rest of the message ) |
+//! ```
+//!
+//! Packets of a connection are sent from every worker, so the upper 16 bits of the sequence are the sender worker and each
+//! sender worker has its own window. The receiver accepts packets without sequence until the first sequenced one, because
+//! the sender only switches after the capabilities are agreed, then it requires sequences from the connection.
+//!
+//! [`Capabilities::REPLAY_PROTECTION`]: crate::base::Capabilities::REPLAY_PROTECTION
+
+use std::collections::HashMap;
+
+/// Outer first byte of sequenced packets. Version 3 is not used by other messages, neighbours control messages are 0xFF
+pub const SEQUENCED: u8 = 0b1110_0000;
+/// Size of the sequence which is added to each encrypted packet
+pub const SEQUENCE_LEN: usize = 8;
+/// Number of sequences behind the highest one which are still accepted when they arrive out of order
+pub const REPLAY_WINDOW: u64 = 64;
+
+const COUNTER_BITS: u32 = 48;
+const COUNTER_MASK: u64 = (1 << COUNTER_BITS) - 1;
+
+pub fn is_sequenced(first_byte: u8) -> bool {
+    first_byte == SEQUENCED
+}
+
+/// Sequences of packets which are sent by a worker
+#[derive(Debug)]
+pub struct SequenceGenerator {
+    worker: u16,
+    counter: u64,
+}
+
+impl SequenceGenerator {
+    pub fn new(worker: u16) -> Self {
+        Self { worker, counter: 0 }
+    }
+
+    pub fn next_seq(&mut self) -> u64 {
+        self.counter = (self.counter + 1) & COUNTER_MASK;
+        (self.worker as u64) << COUNTER_BITS | self.counter
+    }
+}
+
+/// Sliding window of a sender worker, bit `i` of `seen` is the sequence `highest - i`
+#[derive(Debug, Default)]
+struct Window {
+    highest: u64,
+    seen: u64,
+}
+
+impl Window {
+    fn check(&mut self, counter: u64) -> bool {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = if shift >= REPLAY_WINDOW {
+                1
+            } else {
+                self.seen << shift | 1
+            };
+            self.highest = counter;
+            return true;
+        }
+        let offset = self.highest - counter;
+        if offset >= REPLAY_WINDOW || self.seen & (1 << offset) != 0 {
+            return false;
+        }
+        self.seen |= 1 << offset;
+        true
+    }
+}
+
+/// Validates sequences of packets which are received from a connection
+#[derive(Debug, Default)]
+pub struct ReplayFilter {
+    windows: HashMap<u16, Window>,
+}
+
+impl ReplayFilter {
+    /// Packets without sequence are only accepted before the remote starts sending sequences
+    pub fn accept_unsequenced(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    /// Return false if the sequence is a duplicate or too old
+    pub fn check(&mut self, sequence: u64) -> bool {
+        let worker = (sequence >> COUNTER_BITS) as u16;
+        let counter = sequence & COUNTER_MASK;
+        if counter == 0 {
+            return false;
+        }
+        self.windows.entry(worker).or_default().check(counter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_duplicated_and_old_sequences() {
+        let mut sender = SequenceGenerator::new(0);
+        let mut filter = ReplayFilter::default();
+        assert!(filter.accept_unsequenced());
+
+        let seqs: Vec<u64> = (0..100).map(|_| sender.next_seq()).collect();
+        assert!(filter.check(seqs[10]));
+        assert!(!filter.accept_unsequenced());
+        assert!(!filter.check(seqs[10]));
+
+        // out of order inside the window is accepted once
+        assert!(filter.check(seqs[5]));
+        assert!(!filter.check(seqs[5]));
+
+        assert!(filter.check(seqs[99]));
+        // 99 - 30 >= window => too old
+        assert!(!filter.check(seqs[30]));
+        assert!(filter.check(seqs[40]));
+        assert!(!filter.check(seqs[40]));
+    }
+
+    #[test]
+    fn windows_are_separated_by_sender_worker() {
+        let mut worker0 = SequenceGenerator::new(0);
+        let mut worker1 = SequenceGenerator::new(1);
+        let mut filter = ReplayFilter::default();
+
+        for _ in 0..100 {
+            worker0.next_seq();
+        }
+        assert!(filter.check(worker0.next_seq()));
+        let seq1 = worker1.next_seq();
+        assert!(filter.check(seq1));
+        assert!(!filter.check(seq1));
+        assert!(filter.check(worker1.next_seq()));
+    }
+}
@@ -0,0 +1,479 @@
+//! Bandwidth scheduler which shares the capacity of a connection between features.
+//!
+//! Messages are sent immediately while the connection has credit and nothing is queued, otherwise they are queued per feature
+//! and released by weighted deficit round robin when credit is refilled, so a bulk feature like vpn cannot starve pubsub
+//! on the same link. Credit is refilled by elapsed time, and queued messages are released on following events and ticks.
+//! Features can also be capped with their own token bucket, a capped feature which runs out of credit is skipped by the round robin
+//! so it doesn't block others. Neighbours control packets are not scheduled, so keepalives are never starved.
+//!
+//! Messages are also classified by [`MsgPriority`] of the sender. Control messages like router sync are never queued,
+//! High, Normal and Bulk messages are queued in separate classes which are released in strict order, and features inside
+//! a class share it by weights. Relayed and raw messages don't carry a priority, they are always Normal.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use atm0s_sdn_utils::log_sampled;
+
+use crate::{
+    base::{Buffer, MsgPriority},
+    features::Features,
+};
+
+/// Weight of features which are not configured
+pub const DEFAULT_WEIGHT: u16 = 1;
+/// Bytes added to the deficit of a feature per weight unit in each round
+const QUANTUM_BYTES: usize = 1500;
+/// Credit can be accumulated up to this duration of capacity, but at least one max packet
+const BURST_MS: u64 = 50;
+const MIN_BURST_BYTES: u64 = 1500;
+/// Max queued bytes per feature and priority class in a connection, newer messages are dropped when it is full
+pub const DEFAULT_MAX_QUEUE_BYTES: usize = 256 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchedulerConfig {
+    /// Capacity of each connection
+    pub capacity_kbps: u32,
+    /// Share weights of features, default is [`DEFAULT_WEIGHT`]
+    pub weights: Vec<(Features, u16)>,
+    /// Max throughput of features in each connection, features which are not configured are only limited by capacity
+    pub limits: Vec<(Features, u32)>,
+    pub max_queue_bytes: usize,
+}
+
+impl SchedulerConfig {
+    pub fn new(capacity_kbps: u32) -> Self {
+        Self {
+            capacity_kbps,
+            weights: vec![],
+            limits: vec![],
+            max_queue_bytes: DEFAULT_MAX_QUEUE_BYTES,
+        }
+    }
+
+    pub fn with_weight(mut self, feature: Features, weight: u16) -> Self {
+        self.weights.retain(|(f, _)| *f != feature);
+        self.weights.push((feature, weight.max(1)));
+        self
+    }
+
+    pub fn with_limit(mut self, feature: Features, limit_kbps: u32) -> Self {
+        self.limits.retain(|(f, _)| *f != feature);
+        self.limits.push((feature, limit_kbps));
+        self
+    }
+
+    pub fn limit(&self, feature: u8) -> Option<u32> {
+        self.limits.iter().find(|(f, _)| *f as u8 == feature).map(|(_, l)| *l)
+    }
+
+    pub fn weight(&self, feature: u8) -> u16 {
+        self.weights.iter().find(|(f, _)| *f as u8 == feature).map(|(_, w)| *w).unwrap_or(DEFAULT_WEIGHT)
+    }
+}
+
+/// Counters of a feature in a connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SchedulerStats {
+    pub sent_bytes: u64,
+    pub queued_bytes: usize,
+    pub dropped_msgs: u64,
+}
+
+/// Credit is refilled by elapsed time with the rate, and can be accumulated up to a burst
+pub(super) struct TokenBucket {
+    rate_kbps: u32,
+    credit: u64,
+    burst: u64,
+    refilled_at: Option<u64>,
+}
+
+impl TokenBucket {
+    pub(super) fn new(rate_kbps: u32) -> Self {
+        Self::with_burst(rate_kbps, rate_kbps as u64 * BURST_MS / 8)
+    }
+
+    pub(super) fn with_burst(rate_kbps: u32, burst: u64) -> Self {
+        let burst = burst.max(MIN_BURST_BYTES);
+        Self {
+            rate_kbps,
+            credit: burst,
+            burst,
+            refilled_at: None,
+        }
+    }
+
+    pub(super) fn refill(&mut self, now: u64) {
+        let last = *self.refilled_at.get_or_insert(now);
+        if now > last {
+            // kbps is equal to bits per ms
+            let bytes = (now - last) * self.rate_kbps as u64 / 8;
+            if bytes > 0 {
+                self.credit = (self.credit + bytes).min(self.burst);
+                self.refilled_at = Some(now);
+            }
+        }
+    }
+
+    pub(super) fn burst(&self) -> usize {
+        self.burst as usize
+    }
+
+    pub(super) fn has(&self, size: usize) -> bool {
+        self.credit >= size as u64
+    }
+
+    /// Control messages are sent without credit, so credit is saturated at zero
+    pub(super) fn consume(&mut self, size: usize) {
+        self.credit = self.credit.saturating_sub(size as u64);
+    }
+}
+
+/// Own limit and counters of a feature, shared by all priority classes
+#[derive(Default)]
+struct FeatureState {
+    /// Own limit of the feature, None if it is only limited by capacity
+    limit: Option<TokenBucket>,
+    stats: SchedulerStats,
+}
+
+impl FeatureState {
+    fn can_send(&self, size: usize) -> bool {
+        self.limit.as_ref().map(|l| l.has(size)).unwrap_or(true)
+    }
+
+    fn on_sent(&mut self, size: usize) {
+        if let Some(limit) = &mut self.limit {
+            limit.consume(size);
+        }
+        self.stats.sent_bytes += size as u64;
+    }
+}
+
+#[derive(Default)]
+struct FeatureQueue {
+    msgs: VecDeque<Buffer>,
+    deficit: usize,
+    bytes: usize,
+}
+
+/// Queues of a priority class, features inside a class share it by weighted deficit round robin
+#[derive(Default)]
+struct ClassQueues {
+    queues: BTreeMap<u8, FeatureQueue>,
+    /// Features which have queued messages, in round robin order
+    active: VecDeque<u8>,
+}
+
+enum ClassPop {
+    Sent(Buffer),
+    /// The connection is out of credit, lower classes must wait too
+    NoCredit,
+    /// The class is empty or all of its features are out of their own limit
+    Blocked,
+}
+
+/// Queued classes in strict order, it must match [`class_index`]
+const QUEUED_CLASSES: [MsgPriority; 3] = [MsgPriority::High, MsgPriority::Normal, MsgPriority::Bulk];
+
+/// Index of queued classes in strict order, Control messages are never queued
+fn class_index(priority: MsgPriority) -> Option<usize> {
+    match priority {
+        MsgPriority::Control => None,
+        MsgPriority::High => Some(0),
+        MsgPriority::Normal => Some(1),
+        MsgPriority::Bulk => Some(2),
+    }
+}
+
+pub struct ConnScheduler {
+    cfg: SchedulerConfig,
+    bucket: TokenBucket,
+    features: BTreeMap<u8, FeatureState>,
+    classes: [ClassQueues; 3],
+}
+
+impl ConnScheduler {
+    pub fn new(cfg: SchedulerConfig) -> Self {
+        Self {
+            bucket: TokenBucket::new(cfg.capacity_kbps),
+            cfg,
+            features: BTreeMap::new(),
+            classes: Default::default(),
+        }
+    }
+
+    pub(super) fn refill(&mut self, now: u64) {
+        self.bucket.refill(now);
+        for state in self.features.values_mut() {
+            if let Some(limit) = &mut state.limit {
+                limit.refill(now);
+            }
+        }
+    }
+
+    /// Return the message if it can be sent now, otherwise it is queued in the class of the priority.
+    /// Control messages are always returned, they only consume credit
+    pub fn send(&mut self, now: u64, feature: u8, priority: MsgPriority, buf: Buffer) -> Option<Buffer> {
+        self.refill(now);
+        let size = buf.len();
+        let limit = self.cfg.limit(feature);
+        let state = self.features.entry(feature).or_insert_with(|| FeatureState {
+            limit: limit.map(TokenBucket::new),
+            ..Default::default()
+        });
+        let class = match class_index(priority) {
+            Some(class) => class,
+            None => {
+                state.on_sent(size);
+                self.bucket.consume(size);
+                return Some(buf);
+            }
+        };
+
+        // a message can bypass the queues only if no class with same or higher priority is waiting
+        let no_backlog = self.classes[..=class].iter().all(|c| c.active.is_empty());
+        if no_backlog && self.bucket.has(size) && state.can_send(size) {
+            state.on_sent(size);
+            self.bucket.consume(size);
+            return Some(buf);
+        }
+
+        let class = &mut self.classes[class];
+        let queue = class.queues.entry(feature).or_default();
+        if queue.bytes + size > self.cfg.max_queue_bytes {
+            log_sampled!(log::Level::Warn, "[ConnScheduler] queue of feature {feature} with priority {priority:?} is full, drop message");
+            state.stats.dropped_msgs += 1;
+            return None;
+        }
+        queue.bytes += size;
+        queue.msgs.push_back(buf);
+        state.stats.queued_bytes += size;
+        if !class.active.contains(&feature) {
+            class.active.push_back(feature);
+        }
+        None
+    }
+
+    /// Pop a queued message which can be sent now with its class, higher classes are always released first
+    pub fn pop(&mut self, now: u64) -> Option<(MsgPriority, Buffer)> {
+        self.refill(now);
+        for (priority, class) in QUEUED_CLASSES.into_iter().zip(self.classes.iter_mut()) {
+            match Self::pop_class(&self.cfg, &mut self.bucket, &mut self.features, class) {
+                ClassPop::Sent(buf) => return Some((priority, buf)),
+                ClassPop::NoCredit => return None,
+                ClassPop::Blocked => continue,
+            }
+        }
+        None
+    }
+
+    fn pop_class(cfg: &SchedulerConfig, bucket: &mut TokenBucket, features: &mut BTreeMap<u8, FeatureState>, class: &mut ClassQueues) -> ClassPop {
+        // number of continuous features which are out of their own limit, all features are blocked when it reaches active count
+        let mut blocked = 0;
+        loop {
+            let feature = match class.active.front() {
+                Some(feature) => *feature,
+                None => return ClassPop::Blocked,
+            };
+            let weight = cfg.weight(feature) as usize;
+            let queue = class.queues.get_mut(&feature).expect("Should have queue of active feature");
+            let state = features.get_mut(&feature).expect("Should have state of active feature");
+            let size = queue.msgs.front().expect("Active queue should not empty").len();
+            if !bucket.has(size) {
+                return ClassPop::NoCredit;
+            }
+            if !state.can_send(size) {
+                blocked += 1;
+                if blocked >= class.active.len() {
+                    return ClassPop::Blocked;
+                }
+                class.active.rotate_left(1);
+                continue;
+            }
+            if queue.deficit < size {
+                queue.deficit += weight * QUANTUM_BYTES;
+                blocked = 0;
+                class.active.rotate_left(1);
+                continue;
+            }
+
+            let buf = queue.msgs.pop_front().expect("Should have message");
+            queue.deficit -= size;
+            queue.bytes -= size;
+            state.stats.queued_bytes -= size;
+            state.on_sent(size);
+            bucket.consume(size);
+            if queue.msgs.is_empty() {
+                queue.deficit = 0;
+                class.active.pop_front();
+            }
+            return ClassPop::Sent(buf);
+        }
+    }
+
+    pub fn has_backlog(&self) -> bool {
+        self.classes.iter().any(|c| !c.active.is_empty())
+    }
+
+    pub fn stats(&self, feature: Features) -> SchedulerStats {
+        self.features.get(&(feature as u8)).map(|q| q.stats).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        base::{Buffer, MsgPriority},
+        features::Features,
+    };
+
+    use super::{ConnScheduler, SchedulerConfig};
+
+    fn msg(size: usize) -> Buffer {
+        vec![0; size].into()
+    }
+
+    #[test]
+    fn pass_through_under_capacity() {
+        let mut scheduler = ConnScheduler::new(SchedulerConfig::new(8000));
+        for i in 0..100 {
+            // 1000 bytes per ms is equal to the capacity
+            assert!(scheduler.send(i, Features::PubSub as u8, MsgPriority::Normal, msg(1000)).is_some());
+        }
+        assert!(!scheduler.has_backlog());
+        assert_eq!(scheduler.stats(Features::PubSub).sent_bytes, 100_000);
+    }
+
+    #[test]
+    fn share_by_weights() {
+        let cfg = SchedulerConfig::new(8000).with_weight(Features::Vpn, 1).with_weight(Features::PubSub, 3);
+        let mut scheduler = ConnScheduler::new(cfg);
+        for now in 0..1000 {
+            // both features send over the capacity
+            for _ in 0..4 {
+                scheduler.send(now, Features::Vpn as u8, MsgPriority::Normal, msg(500));
+                scheduler.send(now, Features::PubSub as u8, MsgPriority::Normal, msg(500));
+            }
+            while scheduler.pop(now).is_some() {}
+        }
+        let vpn = scheduler.stats(Features::Vpn).sent_bytes;
+        let pubsub = scheduler.stats(Features::PubSub).sent_bytes;
+        // total is limited by capacity and burst
+        assert!(vpn + pubsub <= 1000 * 1000 + 50 * 1000);
+        assert!(pubsub > vpn * 27 / 10 && pubsub < vpn * 33 / 10, "pubsub {pubsub} vpn {vpn}");
+        assert!(scheduler.stats(Features::Vpn).dropped_msgs > 0);
+    }
+
+    #[test]
+    fn unused_share_is_given_to_others() {
+        let cfg = SchedulerConfig::new(8000).with_weight(Features::Vpn, 1).with_weight(Features::PubSub, 3);
+        let mut scheduler = ConnScheduler::new(cfg);
+        for now in 0..1000 {
+            for _ in 0..4 {
+                scheduler.send(now, Features::Vpn as u8, MsgPriority::Normal, msg(500));
+            }
+            // pubsub only uses 100 bytes per ms
+            if now % 5 == 0 {
+                scheduler.send(now, Features::PubSub as u8, MsgPriority::Normal, msg(500));
+            }
+            while scheduler.pop(now).is_some() {}
+        }
+        let vpn = scheduler.stats(Features::Vpn).sent_bytes;
+        let pubsub = scheduler.stats(Features::PubSub).sent_bytes;
+        assert_eq!(pubsub, 100 * 1000);
+        assert!(vpn >= 850 * 1000, "vpn {vpn}");
+    }
+
+    #[test]
+    fn feature_limit_caps_throughput() {
+        // vpn is limited to 100 bytes per ms while capacity is 1000 bytes per ms
+        let cfg = SchedulerConfig::new(8000).with_limit(Features::Vpn, 800);
+        let mut scheduler = ConnScheduler::new(cfg);
+        for now in 0..1000 {
+            for _ in 0..4 {
+                scheduler.send(now, Features::Vpn as u8, MsgPriority::Normal, msg(500));
+            }
+            while scheduler.pop(now).is_some() {}
+        }
+        let vpn = scheduler.stats(Features::Vpn).sent_bytes;
+        // limited by rate and burst
+        assert!((95 * 1000..=100 * 1000 + 5000).contains(&vpn), "vpn {vpn}");
+    }
+
+    #[test]
+    fn limited_feature_does_not_block_others() {
+        let cfg = SchedulerConfig::new(8000).with_limit(Features::Vpn, 800);
+        let mut scheduler = ConnScheduler::new(cfg);
+        for now in 0..1000 {
+            for _ in 0..4 {
+                scheduler.send(now, Features::Vpn as u8, MsgPriority::Normal, msg(500));
+                scheduler.send(now, Features::PubSub as u8, MsgPriority::Normal, msg(500));
+            }
+            while scheduler.pop(now).is_some() {}
+        }
+        let vpn = scheduler.stats(Features::Vpn).sent_bytes;
+        let pubsub = scheduler.stats(Features::PubSub).sent_bytes;
+        assert!(vpn <= 100 * 1000 + 5000, "vpn {vpn}");
+        // pubsub takes the rest of capacity
+        assert!(pubsub >= 850 * 1000, "pubsub {pubsub}");
+    }
+
+    #[test]
+    fn queued_msgs_are_released_later() {
+        let mut scheduler = ConnScheduler::new(SchedulerConfig::new(800));
+        // burst is 5000 bytes
+        for _ in 0..5 {
+            assert!(scheduler.send(0, Features::Data as u8, MsgPriority::Normal, msg(1000)).is_some());
+        }
+        assert!(scheduler.send(0, Features::Data as u8, MsgPriority::Normal, msg(1000)).is_none());
+        assert!(scheduler.has_backlog());
+        assert!(scheduler.pop(5).is_none());
+        assert!(scheduler.pop(10).is_some());
+        assert!(!scheduler.has_backlog());
+    }
+
+    #[test]
+    fn control_is_never_queued() {
+        let mut scheduler = ConnScheduler::new(SchedulerConfig::new(800));
+        for _ in 0..5 {
+            assert!(scheduler.send(0, Features::PubSub as u8, MsgPriority::Bulk, msg(1000)).is_some());
+        }
+        assert!(scheduler.send(0, Features::PubSub as u8, MsgPriority::Bulk, msg(1000)).is_none());
+        // out of credit and backlog exists, but control is still sent
+        assert!(scheduler.send(0, Features::RouterSync as u8, MsgPriority::Control, msg(1000)).is_some());
+        assert_eq!(scheduler.stats(Features::RouterSync).sent_bytes, 1000);
+    }
+
+    #[test]
+    fn higher_class_is_released_first() {
+        let mut scheduler = ConnScheduler::new(SchedulerConfig::new(800));
+        for _ in 0..5 {
+            assert!(scheduler.send(0, Features::PubSub as u8, MsgPriority::Bulk, msg(1000)).is_some());
+        }
+        scheduler.send(0, Features::PubSub as u8, MsgPriority::Bulk, vec![3; 1000].into());
+        scheduler.send(0, Features::Data as u8, MsgPriority::Normal, vec![2; 1000].into());
+        scheduler.send(0, Features::Alias as u8, MsgPriority::High, vec![1; 1000].into());
+        assert_eq!(scheduler.pop(10).map(|(p, b)| (p, b[0])), Some((MsgPriority::High, 1)));
+        assert_eq!(scheduler.pop(20).map(|(p, b)| (p, b[0])), Some((MsgPriority::Normal, 2)));
+        assert_eq!(scheduler.pop(30).map(|(p, b)| (p, b[0])), Some((MsgPriority::Bulk, 3)));
+        assert!(!scheduler.has_backlog());
+    }
+
+    #[test]
+    fn bulk_uses_spare_capacity_only() {
+        let mut scheduler = ConnScheduler::new(SchedulerConfig::new(8000));
+        for now in 0..1000 {
+            for _ in 0..4 {
+                scheduler.send(now, Features::PubSub as u8, MsgPriority::Bulk, msg(500));
+            }
+            // data only uses 500 bytes per ms
+            scheduler.send(now, Features::Data as u8, MsgPriority::Normal, msg(500));
+            while scheduler.pop(now).is_some() {}
+        }
+        let data = scheduler.stats(Features::Data);
+        let pubsub = scheduler.stats(Features::PubSub).sent_bytes;
+        assert_eq!(data.dropped_msgs, 0);
+        assert!(data.sent_bytes >= 999 * 500, "data {}", data.sent_bytes);
+        assert!(pubsub >= 450 * 1000, "pubsub {pubsub}");
+    }
+}
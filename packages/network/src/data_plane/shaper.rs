@@ -0,0 +1,184 @@
+//! Outgoing traffic shaping of services.
+//!
+//! A service which has a shaping profile can only emit data at the profile rate, with bursts up to the profile burst.
+//! Feature controls of the service are passed immediately while it has credit and nothing is queued, otherwise they are queued
+//! in order and released when credit is refilled. When many services are waiting, the ones with higher priority are released first.
+//! Controls which don't carry data are free, but they are still queued behind data of the same service to keep the order.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use atm0s_sdn_utils::log_sampled;
+
+use crate::{base::ServiceId, features::FeaturesControl};
+
+use super::scheduler::{SchedulerStats, TokenBucket, DEFAULT_MAX_QUEUE_BYTES};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShapingProfile {
+    pub rate_kbps: u32,
+    pub burst_bytes: u32,
+    /// Queued traffic of services with higher priority is released first
+    pub priority: u8,
+}
+
+impl ShapingProfile {
+    pub fn new(rate_kbps: u32, burst_bytes: u32, priority: u8) -> Self {
+        Self { rate_kbps, burst_bytes, priority }
+    }
+}
+
+struct ServiceQueue {
+    priority: u8,
+    bucket: TokenBucket,
+    controls: VecDeque<(FeaturesControl, usize)>,
+    stats: SchedulerStats,
+}
+
+impl ServiceQueue {
+    /// Data which is bigger than the burst is sent when the bucket is full, otherwise it will never be sent
+    fn cost(&self, size: usize) -> usize {
+        size.min(self.bucket.burst())
+    }
+}
+
+pub struct ServiceShaper {
+    services: BTreeMap<ServiceId, ServiceQueue>,
+    /// Services ordered by priority, higher first
+    order: Vec<ServiceId>,
+}
+
+impl ServiceShaper {
+    pub fn new(profiles: Vec<(ServiceId, ShapingProfile)>) -> Self {
+        let mut services = BTreeMap::new();
+        for (service, profile) in profiles {
+            services.insert(
+                service,
+                ServiceQueue {
+                    priority: profile.priority,
+                    bucket: TokenBucket::with_burst(profile.rate_kbps, profile.burst_bytes as u64),
+                    controls: VecDeque::new(),
+                    stats: SchedulerStats::default(),
+                },
+            );
+        }
+        let mut order: Vec<ServiceId> = services.keys().copied().collect();
+        order.sort_by_key(|service| std::cmp::Reverse(services[service].priority));
+        Self { services, order }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.services.is_empty()
+    }
+
+    /// Return the control if it can be sent now, otherwise it is queued. Services without profile are never shaped
+    pub fn send(&mut self, now: u64, service: ServiceId, control: FeaturesControl) -> Option<FeaturesControl> {
+        let queue = match self.services.get_mut(&service) {
+            Some(queue) => queue,
+            None => return Some(control),
+        };
+        queue.bucket.refill(now);
+        let size = control.payload_len();
+        let cost = queue.cost(size);
+        if queue.controls.is_empty() && queue.bucket.has(cost) {
+            queue.bucket.consume(cost);
+            queue.stats.sent_bytes += size as u64;
+            return Some(control);
+        }
+
+        if queue.stats.queued_bytes + size > DEFAULT_MAX_QUEUE_BYTES {
+            log_sampled!(log::Level::Warn, "[ServiceShaper] queue of service {service} is full, drop control");
+            queue.stats.dropped_msgs += 1;
+            return None;
+        }
+        queue.stats.queued_bytes += size;
+        queue.controls.push_back((control, size));
+        None
+    }
+
+    /// Pop a queued control which can be sent now, with its service
+    pub fn pop(&mut self, now: u64) -> Option<(ServiceId, FeaturesControl)> {
+        for service in &self.order {
+            let queue = self.services.get_mut(service).expect("Should have queue of ordered service");
+            if queue.controls.is_empty() {
+                continue;
+            }
+            queue.bucket.refill(now);
+            let (_, size) = queue.controls.front().expect("Should have control");
+            let (size, cost) = (*size, queue.cost(*size));
+            if !queue.bucket.has(cost) {
+                continue;
+            }
+            let (control, _) = queue.controls.pop_front().expect("Should have control");
+            queue.bucket.consume(cost);
+            queue.stats.queued_bytes -= size;
+            queue.stats.sent_bytes += size as u64;
+            return Some((*service, control));
+        }
+        None
+    }
+
+    pub fn stats(&self, service: ServiceId) -> Option<SchedulerStats> {
+        self.services.get(&service).map(|q| q.stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_router::RouteRule;
+
+    use crate::{
+        base::NetOutgoingMeta,
+        features::{data, FeaturesControl},
+    };
+
+    use super::{ServiceShaper, ShapingProfile};
+
+    fn data_control(len: usize) -> FeaturesControl {
+        FeaturesControl::Data(data::Control::DataSendRule(1, RouteRule::ToNode(2), NetOutgoingMeta::default(), vec![0; len]))
+    }
+
+    #[test]
+    fn unshaped_service_is_not_limited() {
+        let mut shaper = ServiceShaper::new(vec![(1.into(), ShapingProfile::new(8, 1500, 0))]);
+        for _ in 0..100 {
+            assert_eq!(shaper.send(0, 2.into(), data_control(1000)), Some(data_control(1000)));
+        }
+        assert_eq!(shaper.stats(2.into()), None);
+    }
+
+    #[test]
+    fn shaped_service_respects_rate_and_keep_order() {
+        // 80kbps is 10 bytes per ms
+        let mut shaper = ServiceShaper::new(vec![(1.into(), ShapingProfile::new(80, 2000, 0))]);
+        assert_eq!(shaper.send(0, 1.into(), data_control(1000)), Some(data_control(1000)));
+        assert_eq!(shaper.send(0, 1.into(), data_control(1000)), Some(data_control(1000)));
+        assert_eq!(shaper.send(0, 1.into(), data_control(1000)), None);
+        // free control is queued behind data
+        assert_eq!(shaper.send(0, 1.into(), FeaturesControl::Data(data::Control::DataListen(1))), None);
+        assert_eq!(shaper.pop(50), None);
+        assert_eq!(shaper.pop(100), Some((1.into(), data_control(1000))));
+        assert_eq!(shaper.pop(100), Some((1.into(), FeaturesControl::Data(data::Control::DataListen(1)))));
+        assert_eq!(shaper.pop(100), None);
+        assert_eq!(shaper.stats(1.into()).expect("Should have stats").sent_bytes, 3000);
+    }
+
+    #[test]
+    fn higher_priority_service_is_released_first() {
+        let mut shaper = ServiceShaper::new(vec![(1.into(), ShapingProfile::new(80, 1500, 1)), (2.into(), ShapingProfile::new(80, 1500, 10))]);
+        assert!(shaper.send(0, 1.into(), data_control(1500)).is_some());
+        assert!(shaper.send(0, 2.into(), data_control(1500)).is_some());
+        assert_eq!(shaper.send(0, 1.into(), data_control(1000)), None);
+        assert_eq!(shaper.send(0, 2.into(), data_control(1000)), None);
+        assert_eq!(shaper.pop(100), Some((2.into(), data_control(1000))));
+        assert_eq!(shaper.pop(100), Some((1.into(), data_control(1000))));
+    }
+
+    #[test]
+    fn oversized_data_is_sent_with_full_bucket() {
+        let mut shaper = ServiceShaper::new(vec![(1.into(), ShapingProfile::new(80, 1500, 0))]);
+        assert_eq!(shaper.send(0, 1.into(), data_control(4000)), Some(data_control(4000)));
+        assert_eq!(shaper.send(0, 1.into(), data_control(4000)), None);
+        assert_eq!(shaper.pop(100), None);
+        assert_eq!(shaper.pop(150), Some((1.into(), data_control(4000))));
+    }
+}
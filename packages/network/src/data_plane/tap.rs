@@ -0,0 +1,256 @@
+//! Tap of messages which are received and sent by data plane workers, for debugging protocols of features.
+//!
+//! Messages are mirrored after decryption and before encryption, so payloads are plain. Relayed messages are mirrored
+//! both as incoming and outgoing. [`PcapWriter`] writes records as udp packets between the connection addresses, which can be opened with
+//! common capture tools.
+
+use std::{
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+};
+
+use atm0s_sdn_identity::NodeId;
+
+use crate::{base::TransportMsgHeader, data_plane::NetPair, features::Features};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapDirection {
+    Incoming,
+    Outgoing,
+}
+
+/// Messages which are mirrored, empty lists match all
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TapFilter {
+    pub features: Vec<Features>,
+    /// Neighbour which the message is received from or sent to, or source node of the message
+    pub nodes: Vec<NodeId>,
+    /// Mirror payloads too, otherwise only headers
+    pub payload: bool,
+}
+
+impl TapFilter {
+    pub fn is_match(&self, header: &TransportMsgHeader, neighbour: NodeId) -> bool {
+        let feature = self.features.is_empty() || self.features.iter().any(|feature| *feature as u8 == header.feature);
+        let node = self.nodes.is_empty() || self.nodes.contains(&neighbour) || header.from_node.map(|from| self.nodes.contains(&from)).unwrap_or(false);
+        feature && node
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapRecord {
+    pub ts_ms: u64,
+    pub worker: u16,
+    pub direction: TapDirection,
+    pub pair: NetPair,
+    pub neighbour: NodeId,
+    pub header: TransportMsgHeader,
+    /// Size of the whole message
+    pub len: usize,
+    /// Only captured if the filter asks for it
+    pub payload: Option<Vec<u8>>,
+}
+
+impl TapRecord {
+    /// Capture a serialized message if it matches the filter
+    pub(crate) fn capture(filter: &TapFilter, ts_ms: u64, worker: u16, direction: TapDirection, pair: NetPair, neighbour: NodeId, msg: &[u8]) -> Option<Self> {
+        let header = TransportMsgHeader::try_from(msg).ok()?;
+        if !filter.is_match(&header, neighbour) {
+            return None;
+        }
+        let payload = filter.payload.then(|| msg.get(header.serialize_size()..).unwrap_or_default().to_vec());
+        Some(Self {
+            ts_ms,
+            worker,
+            direction,
+            pair,
+            neighbour,
+            header,
+            len: msg.len(),
+            payload,
+        })
+    }
+}
+
+/// Tap of a worker, it captures nothing until a filter is set
+pub(crate) struct Tap {
+    worker: u16,
+    filter: Option<TapFilter>,
+}
+
+impl Tap {
+    pub fn new(worker: u16) -> Self {
+        Self { worker, filter: None }
+    }
+
+    pub fn set_filter(&mut self, filter: Option<TapFilter>) {
+        self.filter = filter;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.filter.is_some()
+    }
+
+    pub fn capture(&self, ts_ms: u64, direction: TapDirection, pair: NetPair, neighbour: NodeId, msg: &[u8]) -> Option<TapRecord> {
+        TapRecord::capture(self.filter.as_ref()?, ts_ms, self.worker, direction, pair, neighbour, msg)
+    }
+}
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+/// Raw ip packets without link layer
+const LINKTYPE_RAW: u32 = 101;
+const PCAP_SNAPLEN: u32 = 65_535;
+const UDP_HEADER_LEN: usize = 8;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+
+/// Write [`TapRecord`]s in pcap format, messages without payload are written as truncated packets
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend(PCAP_MAGIC.to_le_bytes());
+        header.extend(2u16.to_le_bytes());
+        header.extend(4u16.to_le_bytes());
+        header.extend(0i32.to_le_bytes());
+        header.extend(0u32.to_le_bytes());
+        header.extend(PCAP_SNAPLEN.to_le_bytes());
+        header.extend(LINKTYPE_RAW.to_le_bytes());
+        writer.write_all(&header)?;
+        Ok(Self { writer })
+    }
+
+    pub fn write(&mut self, record: &TapRecord) -> io::Result<()> {
+        let (src, dst) = match record.direction {
+            TapDirection::Incoming => (record.pair.remote, record.pair.local),
+            TapDirection::Outgoing => (record.pair.local, record.pair.remote),
+        };
+        let mut msg = vec![0; record.header.serialize_size()];
+        let header_len = record.header.to_bytes(&mut msg).ok_or(io::ErrorKind::InvalidData)?;
+        msg.truncate(header_len);
+        if let Some(payload) = &record.payload {
+            msg.extend_from_slice(payload);
+        }
+        let packet = build_udp_packet(src, dst, record.len, &msg);
+        let orig_len = packet.len() - msg.len() + record.len;
+
+        let mut frame = Vec::with_capacity(16 + packet.len());
+        frame.extend(((record.ts_ms / 1000) as u32).to_le_bytes());
+        frame.extend((((record.ts_ms % 1000) * 1000) as u32).to_le_bytes());
+        frame.extend((packet.len() as u32).to_le_bytes());
+        frame.extend((orig_len as u32).to_le_bytes());
+        frame.extend(packet);
+        self.writer.write_all(&frame)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Ip and udp headers with lengths of the original message, followed by the captured bytes
+fn build_udp_packet(src: SocketAddr, dst: SocketAddr, len: usize, captured: &[u8]) -> Vec<u8> {
+    let udp_len = (UDP_HEADER_LEN + len) as u16;
+    let mut packet = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let mut ip = vec![0x45, 0];
+            ip.extend((IPV4_HEADER_LEN as u16 + udp_len).to_be_bytes());
+            ip.extend([0, 0, 0, 0, 64, 17, 0, 0]);
+            ip.extend(src_ip.octets());
+            ip.extend(dst_ip.octets());
+            let checksum = ipv4_checksum(&ip);
+            ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+            ip
+        }
+        (src_ip, dst_ip) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            let mut ip = vec![0x60, 0, 0, 0];
+            ip.extend(udp_len.to_be_bytes());
+            ip.extend([17, 64]);
+            ip.extend(to_v6(src_ip).octets());
+            ip.extend(to_v6(dst_ip).octets());
+            debug_assert_eq!(ip.len(), IPV6_HEADER_LEN);
+            ip
+        }
+    };
+    packet.extend(src.port().to_be_bytes());
+    packet.extend(dst.port().to_be_bytes());
+    packet.extend(udp_len.to_be_bytes());
+    // zero checksum, the payload may be truncated
+    packet.extend([0, 0]);
+    packet.extend_from_slice(captured);
+    packet
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum = header.chunks(2).map(|word| u16::from_be_bytes([word[0], word[1]]) as u32).sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_router::RouteRule;
+
+    use crate::{
+        base::{TransportMsg, TransportMsgHeader},
+        data_plane::NetPair,
+        features::Features,
+    };
+
+    use super::{PcapWriter, TapDirection, TapFilter, TapRecord};
+
+    fn build_msg(feature: Features, from: u32) -> Vec<u8> {
+        let header = TransportMsgHeader::build(feature as u8, 0, RouteRule::ToNode(3)).set_from_node(Some(from));
+        TransportMsg::build_raw(header, vec![1, 2, 3, 4].into()).get_buf().to_vec()
+    }
+
+    #[test]
+    fn filter_by_feature_and_node() {
+        let pair = NetPair::new_str("127.0.0.1:1000", "127.0.0.1:2000").expect("Should parse");
+        let filter = TapFilter {
+            features: vec![Features::Data],
+            nodes: vec![1],
+            payload: true,
+        };
+        let record = TapRecord::capture(&filter, 0, 0, TapDirection::Incoming, pair, 2, &build_msg(Features::Data, 1)).expect("Should match source node");
+        assert_eq!(record.payload, Some(vec![1, 2, 3, 4]));
+        assert_eq!(record.len, record.header.serialize_size() + 4);
+        assert!(TapRecord::capture(&filter, 0, 0, TapDirection::Incoming, pair, 1, &build_msg(Features::Data, 5)).is_some());
+        assert!(TapRecord::capture(&filter, 0, 0, TapDirection::Incoming, pair, 2, &build_msg(Features::Data, 5)).is_none());
+        assert!(TapRecord::capture(&filter, 0, 0, TapDirection::Incoming, pair, 2, &build_msg(Features::PubSub, 1)).is_none());
+    }
+
+    #[test]
+    fn write_truncated_pcap() {
+        let pair = NetPair::new_str("127.0.0.1:1000", "127.0.0.1:2000").expect("Should parse");
+        let record = TapRecord::capture(&TapFilter::default(), 1500, 0, TapDirection::Outgoing, pair, 2, &build_msg(Features::Data, 1)).expect("Should match all");
+        let mut writer = PcapWriter::new(vec![]).expect("Should write");
+        writer.write(&record).expect("Should write");
+        let buf = writer.into_inner();
+
+        assert_eq!(buf.len(), 24 + 16 + 20 + 8 + record.header.serialize_size());
+        let frame = &buf[24..];
+        assert_eq!(frame[0..4], 1u32.to_le_bytes());
+        assert_eq!(frame[4..8], 500_000u32.to_le_bytes());
+        // captured length excludes the payload, original length includes it
+        assert_eq!(
+            u32::from_le_bytes(frame[12..16].try_into().expect("Should have")) - u32::from_le_bytes(frame[8..12].try_into().expect("Should have")),
+            4
+        );
+        // udp ports of the outgoing direction
+        assert_eq!(frame[16 + 20..16 + 24], [0x03, 0xe8, 0x07, 0xd0]);
+    }
+}
@@ -1,3 +1,12 @@
+//! Alias feature: find and reach the node which registers an alias.
+//!
+//! Each alias has a root node, which is the closest node to the alias key like dht_kv. The root is not stored in dht_kv
+//! because it does more than storing the owner: it tracks owner liveness with refreshes, parks messages for offline
+//! owners and delivers them with acks and receipts. A dht_kv value can't run this logic at the node which stores it.
+//! When a closer node joins, the root is moved together with its parked messages: the old root keeps probing the alias
+//! key, and only the node which the probe is routed to can take over. The new root only accepts parked messages from
+//! nodes which it asked to move.
+
 use std::{
     collections::{HashMap, VecDeque},
     fmt::Debug,
@@ -5,22 +14,72 @@ use std::{
 
 use atm0s_sdn_identity::NodeId;
 use atm0s_sdn_router::{RouteRule, ServiceBroadcastLevel};
+use atm0s_sdn_utils::log_sampled;
 use derivative::Derivative;
 use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
 use serde::{Deserialize, Serialize};
 
-use crate::base::{Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput, NetOutgoingMeta, Ttl};
+use crate::base::{
+    Feature, FeatureContext, FeatureControlActor, FeatureError, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput, LatencyProfile,
+    NetOutgoingMeta, Ttl,
+};
 
 pub const FEATURE_ID: u8 = 6;
 pub const FEATURE_NAME: &str = "alias";
 pub const HINT_TIMEOUT_MS: u64 = 2000;
+/// Query without answer from the alias root and the parallel scan is finished with None after this timeout
 pub const SCAN_TIMEOUT_MS: u64 = 5000;
+/// Previous alias root which is asked to move should hand over its parked messages in this timeout
+pub const ROOT_HANDOVER_TIMEOUT_MS: u64 = 2000;
+/// Owner refreshes its aliases to the root node (closest node to alias key) with this interval
+pub const ROOT_REFRESH_MS: u64 = 2000;
+/// Root node considers owner offline if no refresh after this timeout
+pub const OWNER_TIMEOUT_MS: u64 = 3 * ROOT_REFRESH_MS;
+/// Forwarded message which is not acked by owner after this timeout is parked again and owner is considered offline
+pub const DELIVER_ACK_TIMEOUT_MS: u64 = 2000;
+/// Sender fires Expired receipt by itself if root node doesn't reply after ttl + this timeout
+pub const RECEIPT_TIMEOUT_MS: u64 = 5000;
+/// Max parked messages for each alias at root node
+pub const MAX_PARKED_MSGS: usize = 64;
+/// Max ttl of parked messages
+pub const MAX_PARK_TTL_MS: u64 = 5 * 60 * 1000;
+/// ListByNode and ReverseQuery are answered with None if no reply after this timeout
+pub const LIST_TIMEOUT_MS: u64 = 3000;
+/// Reverse index key of a node is mixed, so the index isn't stored at the node itself (closest node to its own id)
+const INDEX_KEY_SALT: u32 = 0x5bd1_e995;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Control {
-    Register { alias: u64, service: u8, level: ServiceBroadcastLevel },
-    Query { alias: u64, service: u8, level: ServiceBroadcastLevel },
-    Unregister { alias: u64 },
+    Register {
+        alias: u64,
+        service: u8,
+        level: ServiceBroadcastLevel,
+    },
+    /// Find the owner of an alias: local aliases and fresh hints are answered at once, an expired hint is checked at the hint node,
+    /// then the alias root (closest node to alias key) is asked together with a broadcast scan, and the first found owner is used
+    Query {
+        alias: u64,
+        service: u8,
+        level: ServiceBroadcastLevel,
+    },
+    Unregister {
+        alias: u64,
+    },
+    /// Send data to alias owner over the alias root node, the data should fit in a single packet.
+    /// If the owner is offline, data is parked at root node for ttl_ms (0 for no parking) and delivered when the owner registers again.
+    /// Delivery is at-least-once, the sender will receive SendReceipt events with the seq.
+    Send {
+        alias: u64,
+        seq: u64,
+        ttl_ms: u64,
+        data: Vec<u8>,
+    },
+    /// List aliases which are registered by a node, by asking the node directly. Result is [`Event::NodeAliases`]
+    ListByNode(NodeId),
+    /// Lookup aliases of a node from the reverse index, which is stored at the closest node to the mixed node id like dht_kv keys.
+    /// Owners refresh the index with [`ROOT_REFRESH_MS`], so the result is still available a short time after the node is offline.
+    /// Result is [`Event::ReverseResult`]
+    ReverseQuery(NodeId),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -28,13 +87,43 @@ pub enum FoundLocation {
     Local,
     Notify(NodeId),
     CachedHint(NodeId),
+    /// Found by checking an expired hint or by asking the alias root
     RemoteHint(NodeId),
     RemoteScan(NodeId),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SendReceipt {
+    /// Owner is offline, message is parked at root node
+    Queued,
+    Delivered,
+    Expired,
+    QueueFull,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Event {
     QueryResult(u64, Option<FoundLocation>),
+    /// Data sent to a local registered alias: alias, sender node, data
+    Received(u64, NodeId, Vec<u8>),
+    /// Receipt for Send control: alias, seq, receipt
+    SendReceipt(u64, u64, SendReceipt),
+    /// Sorted aliases which are registered by the node, None if the node doesn't reply in [`LIST_TIMEOUT_MS`]
+    NodeAliases(NodeId, Option<Vec<u64>>),
+    /// Sorted aliases of the node in the reverse index, None if the index node doesn't reply in [`LIST_TIMEOUT_MS`]
+    ReverseResult(NodeId, Option<Vec<u64>>),
+}
+
+impl Event {
+    pub fn error(&self) -> Option<FeatureError> {
+        match self {
+            Self::QueryResult(_, None) => Some(FeatureError::Unreachable),
+            Self::SendReceipt(_, _, SendReceipt::Expired) => Some(FeatureError::Timeout),
+            Self::SendReceipt(_, _, SendReceipt::QueueFull) => Some(FeatureError::Overload),
+            Self::NodeAliases(_, None) | Self::ReverseResult(_, None) => Some(FeatureError::Timeout),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -49,11 +138,46 @@ pub enum Message {
     Scan(u64),
     Check(u64),
     Found(u64, bool),
+    RootRegister(u64),
+    RootUnregister(u64),
+    /// alias, seq, ttl_ms, data
+    Send(u64, u64, u64, Vec<u8>),
+    /// alias, sender, seq, data
+    Deliver(u64, NodeId, u64, Vec<u8>),
+    /// alias, sender, seq
+    DeliverAck(u64, NodeId, u64),
+    /// alias, seq, receipt
+    Receipt(u64, u64, SendReceipt),
+    /// All aliases of the sender, which is stored at its reverse index node. Empty list removes the index
+    IndexSync(Vec<u64>),
+    /// req_id
+    ListReq(u64),
+    /// req_id, node
+    ReverseReq(u64, NodeId),
+    /// req_id, aliases
+    ListRes(u64, Vec<u64>),
+    /// Ask the alias root for the current owner
+    RootQuery(u64),
+    /// alias, owner which is registered at the root
+    RootFound(u64, Option<NodeId>),
+    /// Reply of RootRegister, so owner knows which node is its alias root
+    RootAck(u64),
+    /// Sent by alias root which has parked messages, routed by the alias key for finding a closer node which joined after
+    RootProbe(u64),
+    /// Reply of RootProbe by the new alias root: alias, new root. It is also sent by owner after the root changed, which only
+    /// makes the previous root probe, because the root is only moved to the node which a probe is routed to
+    RootMoved(u64, NodeId),
+    /// Parked messages which are moved to the new alias root: alias, (sender, seq, remaining ttl, data)
+    #[allow(clippy::type_complexity)]
+    RootHandover(u64, Vec<(NodeId, u64, u64, Vec<u8>)>),
 }
 
 #[derive(Debug)]
 enum QueryState {
     CheckHint(NodeId, u64),
+    /// Alias root is asked and a scan is running
+    Root(u64),
+    /// Alias root doesn't know the owner, only waiting for the scan
     Scan(u64),
 }
 
@@ -71,6 +195,63 @@ struct HintSlot {
     ts: u64,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ListKind {
+    Node,
+    Reverse,
+}
+
+#[derive(Debug)]
+struct ListSlot<UserData> {
+    actor: FeatureControlActor<UserData>,
+    node: NodeId,
+    kind: ListKind,
+    deadline: u64,
+}
+
+/// Aliases of a node which are stored at its reverse index node
+#[derive(Debug)]
+struct IndexSlot {
+    aliases: Vec<u64>,
+    last_sync: u64,
+}
+
+#[derive(Debug)]
+struct LocalSlot<UserData> {
+    actor: FeatureControlActor<UserData>,
+    last_refresh: u64,
+    /// Alias root which acked the last refresh
+    root: Option<NodeId>,
+}
+
+#[derive(Debug)]
+struct ParkedMsg {
+    sender: NodeId,
+    seq: u64,
+    data: Vec<u8>,
+    deadline: u64,
+    forwarded_at: Option<u64>,
+}
+
+/// State of alias at its root node: current owner with last refresh time and parked messages
+#[derive(Debug, Default)]
+struct RootSlot {
+    owner: Option<(NodeId, u64)>,
+    msgs: VecDeque<ParkedMsg>,
+    /// Last RootProbe, RootMoved is only accepted as a reply of it
+    probed_at: Option<u64>,
+}
+
+/// Alias state which is kept across restarts: owners and parked messages of aliases which are rooted at this node, and reverse
+/// indexes of other nodes. Local aliases belong to actors of the previous run, so apps register them again
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AliasCheckpoint {
+    /// alias, owner, parked messages as (sender, seq, remaining ttl, data)
+    #[allow(clippy::type_complexity)]
+    roots: Vec<(u64, Option<NodeId>, Vec<(NodeId, u64, u64, Vec<u8>)>)>,
+    index: Vec<(NodeId, Vec<u64>)>,
+}
+
 pub type Output<UserData> = FeatureOutput<UserData, Event, ToWorker>;
 pub type WorkerOutput<UserData> = FeatureWorkerOutput<UserData, Control, Event, ToController>;
 
@@ -79,20 +260,101 @@ pub type WorkerOutput<UserData> = FeatureWorkerOutput<UserData, Control, Event,
 pub struct AliasFeature<UserData> {
     queries: HashMap<u64, QuerySlot<UserData>>,
     hint_slots: HashMap<u64, HintSlot>,
-    local_slots: HashMap<u64, u64>,
+    local_slots: HashMap<u64, LocalSlot<UserData>>,
+    root_slots: HashMap<u64, RootSlot>,
+    sending: HashMap<(u64, u64), (FeatureControlActor<UserData>, u64)>,
+    index_slots: HashMap<NodeId, IndexSlot>,
+    /// Local aliases are changed and not synced to the reverse index yet
+    index_dirty: bool,
+    last_index_sync: u64,
+    last_root_probe: u64,
+    /// Previous alias roots which are asked to move, with the deadline of their handover
+    handovers: HashMap<(u64, NodeId), u64>,
+    lists: HashMap<u64, ListSlot<UserData>>,
+    list_seq: u64,
     queue: VecDeque<Output<UserData>>,
     scan_seq: u16,
+    #[derivative(Default(value = "MAX_PARKED_MSGS"))]
+    max_parked_msgs: usize,
+    #[derivative(Default(value = "MAX_PARK_TTL_MS"))]
+    max_park_ttl_ms: u64,
     shutdown: bool,
 }
 
 impl<UserData: Debug + Copy> AliasFeature<UserData> {
+    pub fn new(profile: LatencyProfile) -> Self {
+        Self {
+            max_parked_msgs: profile.alias_max_parked_msgs(),
+            max_park_ttl_ms: profile.alias_max_park_ttl_ms(),
+            ..Default::default()
+        }
+    }
+
+    /// Number of messages parked at this node as alias root, waiting for owners
+    pub fn parked_msgs(&self) -> usize {
+        self.root_slots.values().map(|s| s.msgs.len()).sum()
+    }
+
+    pub fn checkpoint(&self, now_ms: u64) -> AliasCheckpoint {
+        AliasCheckpoint {
+            roots: self
+                .root_slots
+                .iter()
+                .map(|(alias, slot)| {
+                    let msgs = slot.msgs.iter().map(|m| (m.sender, m.seq, m.deadline.saturating_sub(now_ms), m.data.clone())).collect();
+                    (*alias, slot.owner.map(|(owner, _)| owner), msgs)
+                })
+                .collect(),
+            index: self.index_slots.iter().map(|(node, slot)| (*node, slot.aliases.clone())).collect(),
+        }
+    }
+
+    /// Restored owners and indexes are treated as just refreshed, so they are kept until the next refresh from their nodes is late.
+    /// Owners of aliases with parked messages are restored as offline, so messages are delivered again with the next refresh
+    pub fn restore_checkpoint(&mut self, now_ms: u64, checkpoint: AliasCheckpoint) {
+        log::info!(
+            "[AliasFeature] restored {} root aliases and {} reverse indexes from checkpoint",
+            checkpoint.roots.len(),
+            checkpoint.index.len()
+        );
+        for (alias, owner, msgs) in checkpoint.roots {
+            let slot = self.root_slots.entry(alias).or_default();
+            if slot.owner.is_none() && msgs.is_empty() {
+                slot.owner = owner.map(|owner| (owner, now_ms));
+            }
+            for (sender, seq, remain_ms, data) in msgs {
+                if !slot.msgs.iter().any(|m| m.sender == sender && m.seq == seq) {
+                    slot.msgs.push_back(ParkedMsg {
+                        sender,
+                        seq,
+                        data,
+                        deadline: now_ms + remain_ms,
+                        forwarded_at: None,
+                    });
+                }
+            }
+        }
+        for (node, aliases) in checkpoint.index {
+            self.index_slots.entry(node).or_insert(IndexSlot { aliases, last_sync: now_ms });
+        }
+    }
+
     fn process_control(&mut self, now_ms: u64, actor: FeatureControlActor<UserData>, control: Control) {
         match control {
             Control::Register { alias, service, level } => {
                 log::info!("[AliasFeature] Register local alias {} and broadcast hint", alias);
-                self.local_slots.insert(alias, now_ms);
+                self.local_slots.insert(
+                    alias,
+                    LocalSlot {
+                        actor,
+                        last_refresh: now_ms,
+                        root: None,
+                    },
+                );
+                self.index_dirty = true;
                 let seq = Self::gen_seq(&mut self.scan_seq);
                 Self::send_to(&mut self.queue, RouteRule::ToServices(service, level, seq), Message::Notify(alias));
+                Self::send_to(&mut self.queue, Self::root_rule(alias), Message::RootRegister(alias));
             }
             Control::Query { alias, service, level } => {
                 if self.local_slots.contains_key(&alias) {
@@ -119,28 +381,60 @@ impl<UserData: Debug + Copy> AliasFeature<UserData> {
                         Self::send_to(&mut self.queue, RouteRule::ToNode(slot.node), Message::Check(alias));
                     }
                 } else {
-                    log::debug!("[AliasFeature] Alias {alias} is not in query state and has no hint => scan");
+                    log::debug!("[AliasFeature] Alias {alias} is not in query state and has no hint => ask root and scan");
                     self.queries.insert(
                         alias,
                         QuerySlot {
                             waiters: vec![actor],
-                            state: QueryState::Scan(now_ms),
+                            state: QueryState::Root(now_ms),
                             service,
                             level,
                         },
                     );
-                    let seq = Self::gen_seq(&mut self.scan_seq);
-                    Self::send_to(&mut self.queue, RouteRule::ToServices(service, level, seq), Message::Scan(alias));
+                    Self::ask_root_and_scan(&mut self.queue, &mut self.scan_seq, alias, service, level);
                 }
             }
             Control::Unregister { alias } => {
                 log::info!("[AliasFeature] Unregister alias {}", alias);
-                self.local_slots.remove(&alias);
+                if self.local_slots.remove(&alias).is_some() {
+                    self.index_dirty = true;
+                    Self::send_to(&mut self.queue, Self::root_rule(alias), Message::RootUnregister(alias));
+                }
+            }
+            Control::Send { alias, seq, ttl_ms, data } => {
+                let ttl_ms = ttl_ms.min(self.max_park_ttl_ms);
+                log::debug!("[AliasFeature] Send {} bytes to alias {alias} with seq {seq}, ttl {ttl_ms} ms", data.len());
+                self.sending.insert((alias, seq), (actor, now_ms + ttl_ms + RECEIPT_TIMEOUT_MS));
+                Self::send_to(&mut self.queue, Self::root_rule(alias), Message::Send(alias, seq, ttl_ms, data));
+            }
+            Control::ListByNode(node) => {
+                let req_id = self.add_list(now_ms, actor, node, ListKind::Node);
+                Self::send_to(&mut self.queue, RouteRule::ToNode(node), Message::ListReq(req_id));
+            }
+            Control::ReverseQuery(node) => {
+                let req_id = self.add_list(now_ms, actor, node, ListKind::Reverse);
+                Self::send_to(&mut self.queue, Self::index_rule(node), Message::ReverseReq(req_id, node));
             }
         }
     }
 
-    fn process_remote(&mut self, now_ms: u64, from: NodeId, msg: Message) {
+    fn add_list(&mut self, now_ms: u64, actor: FeatureControlActor<UserData>, node: NodeId, kind: ListKind) -> u64 {
+        let req_id = self.list_seq;
+        self.list_seq = self.list_seq.wrapping_add(1);
+        log::debug!("[AliasFeature] List aliases of node {node} with {:?} req {req_id}", kind);
+        self.lists.insert(
+            req_id,
+            ListSlot {
+                actor,
+                node,
+                kind,
+                deadline: now_ms + LIST_TIMEOUT_MS,
+            },
+        );
+        req_id
+    }
+
+    fn process_remote(&mut self, now_ms: u64, node_id: NodeId, from: NodeId, msg: Message) {
         log::debug!("[AliasFeature] Received message from {from}: {:?}", msg);
         match msg {
             Message::Notify(alias) => {
@@ -164,6 +458,199 @@ impl<UserData: Debug + Copy> AliasFeature<UserData> {
                 log::debug!("[AliasFeature] Received Check alias {alias}, found at local: {found}");
                 Self::send_to(&mut self.queue, RouteRule::ToNode(from), Message::Found(alias, found));
             }
+            Message::RootRegister(alias) => {
+                let slot = self.root_slots.entry(alias).or_default();
+                let changed = slot.owner.map(|(owner, _)| owner) != Some(from);
+                slot.owner = Some((from, now_ms));
+                Self::send_to(&mut self.queue, RouteRule::ToNode(from), Message::RootAck(alias));
+                if changed {
+                    log::info!("[AliasFeature] Alias {alias} owner {from} registered at root, forward {} parked msgs", slot.msgs.len());
+                    for msg in slot.msgs.iter_mut() {
+                        msg.forwarded_at = Some(now_ms);
+                        Self::send_to(&mut self.queue, RouteRule::ToNode(from), Message::Deliver(alias, msg.sender, msg.seq, msg.data.clone()));
+                    }
+                }
+            }
+            Message::RootUnregister(alias) => {
+                if let Some(slot) = self.root_slots.get_mut(&alias) {
+                    if slot.owner.map(|(owner, _)| owner) == Some(from) {
+                        log::info!("[AliasFeature] Alias {alias} owner {from} unregistered at root");
+                        Self::set_owner_offline(slot);
+                    }
+                    if slot.owner.is_none() && slot.msgs.is_empty() {
+                        self.root_slots.remove(&alias);
+                    }
+                }
+            }
+            Message::Send(alias, seq, ttl_ms, data) => {
+                let slot = self.root_slots.entry(alias).or_default();
+                let owner = slot.owner.map(|(owner, _)| owner);
+                if slot.msgs.len() >= self.max_parked_msgs {
+                    log::warn!("[AliasFeature] Alias {alias} root queue full => reject msg {seq} from {from}");
+                    Self::send_to(&mut self.queue, RouteRule::ToNode(from), Message::Receipt(alias, seq, SendReceipt::QueueFull));
+                } else if owner.is_none() && ttl_ms == 0 {
+                    Self::send_to(&mut self.queue, RouteRule::ToNode(from), Message::Receipt(alias, seq, SendReceipt::Expired));
+                } else {
+                    let forwarded_at = if let Some(owner) = owner {
+                        Self::send_to(&mut self.queue, RouteRule::ToNode(owner), Message::Deliver(alias, from, seq, data.clone()));
+                        Some(now_ms)
+                    } else {
+                        log::debug!("[AliasFeature] Alias {alias} owner offline => park msg {seq} from {from} in {ttl_ms} ms");
+                        Self::send_to(&mut self.queue, RouteRule::ToNode(from), Message::Receipt(alias, seq, SendReceipt::Queued));
+                        None
+                    };
+                    slot.msgs.push_back(ParkedMsg {
+                        sender: from,
+                        seq,
+                        data,
+                        deadline: now_ms + ttl_ms.min(self.max_park_ttl_ms),
+                        forwarded_at,
+                    });
+                }
+                if slot.owner.is_none() && slot.msgs.is_empty() {
+                    self.root_slots.remove(&alias);
+                }
+            }
+            Message::Deliver(alias, sender, seq, data) => {
+                if let Some(slot) = self.local_slots.get(&alias) {
+                    self.queue.push_back(FeatureOutput::Event(slot.actor, Event::Received(alias, sender, data)));
+                    Self::send_to(&mut self.queue, RouteRule::ToNode(from), Message::DeliverAck(alias, sender, seq));
+                } else {
+                    log::warn!("[AliasFeature] Received Deliver for alias {alias} which is not local");
+                }
+            }
+            Message::DeliverAck(alias, sender, seq) => {
+                if let Some(slot) = self.root_slots.get_mut(&alias) {
+                    if let Some(index) = slot.msgs.iter().position(|m| m.sender == sender && m.seq == seq) {
+                        slot.msgs.remove(index);
+                        Self::send_to(&mut self.queue, RouteRule::ToNode(sender), Message::Receipt(alias, seq, SendReceipt::Delivered));
+                    }
+                }
+            }
+            Message::Receipt(alias, seq, receipt) => {
+                let actor = if receipt == SendReceipt::Queued {
+                    self.sending.get(&(alias, seq)).map(|(actor, _)| *actor)
+                } else {
+                    self.sending.remove(&(alias, seq)).map(|(actor, _)| actor)
+                };
+                if let Some(actor) = actor {
+                    self.queue.push_back(FeatureOutput::Event(actor, Event::SendReceipt(alias, seq, receipt)));
+                }
+            }
+            Message::IndexSync(aliases) => {
+                if aliases.is_empty() {
+                    self.index_slots.remove(&from);
+                } else {
+                    self.index_slots.insert(from, IndexSlot { aliases, last_sync: now_ms });
+                }
+            }
+            Message::ListReq(req_id) => {
+                let mut aliases: Vec<u64> = self.local_slots.keys().copied().collect();
+                aliases.sort_unstable();
+                Self::send_to(&mut self.queue, RouteRule::ToNode(from), Message::ListRes(req_id, aliases));
+            }
+            Message::ReverseReq(req_id, node) => {
+                let aliases = self.index_slots.get(&node).map(|slot| slot.aliases.clone()).unwrap_or_default();
+                Self::send_to(&mut self.queue, RouteRule::ToNode(from), Message::ListRes(req_id, aliases));
+            }
+            Message::ListRes(req_id, aliases) => {
+                if let Some(slot) = self.lists.remove(&req_id) {
+                    let event = match slot.kind {
+                        ListKind::Node => Event::NodeAliases(slot.node, Some(aliases)),
+                        ListKind::Reverse => Event::ReverseResult(slot.node, Some(aliases)),
+                    };
+                    self.queue.push_back(FeatureOutput::Event(slot.actor, event));
+                }
+            }
+            Message::RootQuery(alias) => {
+                let owner = self.root_slots.get(&alias).and_then(|slot| slot.owner.map(|(owner, _)| owner));
+                Self::send_to(&mut self.queue, RouteRule::ToNode(from), Message::RootFound(alias, owner));
+            }
+            Message::RootFound(alias, owner) => {
+                let Some(slot) = self.queries.get_mut(&alias) else {
+                    return;
+                };
+                let QueryState::Root(started_at) = slot.state else {
+                    return;
+                };
+                if let Some(owner) = owner {
+                    log::debug!("[AliasFeature] Found alias {alias} at {owner} from root {from} => notify waiters {:?}", slot.waiters);
+                    self.hint_slots.insert(alias, HintSlot { node: owner, ts: now_ms });
+                    for actor in &slot.waiters {
+                        self.queue.push_back(FeatureOutput::Event(*actor, Event::QueryResult(alias, Some(FoundLocation::RemoteHint(owner)))));
+                    }
+                    self.queries.remove(&alias);
+                } else {
+                    log::debug!("[AliasFeature] Root {from} doesn't know alias {alias} => wait for Scan");
+                    slot.state = QueryState::Scan(started_at);
+                }
+            }
+            Message::RootAck(alias) => {
+                if let Some(slot) = self.local_slots.get_mut(&alias) {
+                    match slot.root.replace(from) {
+                        Some(prev) if prev != from => {
+                            log::info!("[AliasFeature] Alias {alias} root moved from {prev} to {from} => notify previous root");
+                            Self::send_to(&mut self.queue, RouteRule::ToNode(prev), Message::RootMoved(alias, from));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Message::RootProbe(alias) => {
+                if from == node_id {
+                    return;
+                }
+                log::info!("[AliasFeature] Alias {alias} root {from} is not the closest node anymore => take over");
+                self.handovers.insert((alias, from), now_ms + ROOT_HANDOVER_TIMEOUT_MS);
+                Self::send_to(&mut self.queue, RouteRule::ToNode(from), Message::RootMoved(alias, node_id));
+            }
+            Message::RootMoved(alias, root) => {
+                let Some(slot) = self.root_slots.get_mut(&alias) else {
+                    return;
+                };
+                let probing = slot.probed_at.map(|ts| now_ms < ts + ROOT_HANDOVER_TIMEOUT_MS).unwrap_or(false);
+                if root == node_id || root != from || !probing {
+                    if !probing {
+                        log::info!("[AliasFeature] Alias {alias} root moved to {root} by {from} => probe before hand over");
+                        slot.probed_at = Some(now_ms);
+                        Self::send_to(&mut self.queue, Self::root_rule(alias), Message::RootProbe(alias));
+                    }
+                    return;
+                }
+                let slot = self.root_slots.remove(&alias).expect("Should have slot");
+                log::info!("[AliasFeature] Alias {alias} root moved to {root} => hand over {} parked msgs", slot.msgs.len());
+                if !slot.msgs.is_empty() {
+                    let msgs = slot.msgs.into_iter().map(|m| (m.sender, m.seq, m.deadline.saturating_sub(now_ms), m.data)).collect();
+                    Self::send_to(&mut self.queue, RouteRule::ToNode(root), Message::RootHandover(alias, msgs));
+                }
+            }
+            Message::RootHandover(alias, msgs) => {
+                if !self.handovers.remove(&(alias, from)).map(|deadline| now_ms < deadline).unwrap_or(false) {
+                    log::warn!("[AliasFeature] Reject handover of alias {alias} from {from} which is not asked to move");
+                    return;
+                }
+                let slot = self.root_slots.entry(alias).or_default();
+                let owner = slot.owner.map(|(owner, _)| owner);
+                for (sender, seq, remain_ms, data) in msgs {
+                    if slot.msgs.len() >= self.max_parked_msgs || slot.msgs.iter().any(|m| m.sender == sender && m.seq == seq) {
+                        continue;
+                    }
+                    if let Some(owner) = owner {
+                        Self::send_to(&mut self.queue, RouteRule::ToNode(owner), Message::Deliver(alias, sender, seq, data.clone()));
+                    }
+                    slot.msgs.push_back(ParkedMsg {
+                        sender,
+                        seq,
+                        data,
+                        deadline: now_ms + remain_ms.min(self.max_park_ttl_ms),
+                        forwarded_at: owner.map(|_| now_ms),
+                    });
+                }
+                log::info!("[AliasFeature] Alias {alias} handed over from {from}, {} parked msgs", slot.msgs.len());
+                if slot.owner.is_none() && slot.msgs.is_empty() {
+                    self.root_slots.remove(&alias);
+                }
+            }
             Message::Found(alias, found) => {
                 if found {
                     self.hint_slots.insert(alias, HintSlot { node: from, ts: now_ms });
@@ -182,14 +669,12 @@ impl<UserData: Debug + Copy> AliasFeature<UserData> {
                                 }
                                 self.queries.remove(&alias);
                             } else {
-                                log::debug!("[AliasFeature] Not found alias {alias} at hint {node} => switch to Scan");
-                                let seq = self.scan_seq;
-                                self.scan_seq = self.scan_seq.wrapping_add(1);
-                                slot.state = QueryState::Scan(now_ms);
-                                Self::send_to(&mut self.queue, RouteRule::ToServices(slot.service, slot.level, seq), Message::Scan(alias));
+                                log::debug!("[AliasFeature] Not found alias {alias} at hint {node} => ask root and scan");
+                                slot.state = QueryState::Root(now_ms);
+                                Self::ask_root_and_scan(&mut self.queue, &mut self.scan_seq, alias, slot.service, slot.level);
                             }
                         }
-                        QueryState::Scan(_) => {
+                        QueryState::Root(_) | QueryState::Scan(_) => {
                             if !found {
                                 log::warn!("[AliasFeature] Remote should not reply with Found=false for Scan");
                                 return;
@@ -206,6 +691,106 @@ impl<UserData: Debug + Copy> AliasFeature<UserData> {
         }
     }
 
+    /// Scan is started together with the root query, so a query is not delayed when the root is slow or unreachable
+    fn ask_root_and_scan(queue: &mut VecDeque<Output<UserData>>, scan_seq: &mut u16, alias: u64, service: u8, level: ServiceBroadcastLevel) {
+        let seq = Self::gen_seq(scan_seq);
+        Self::send_to(queue, Self::root_rule(alias), Message::RootQuery(alias));
+        Self::send_to(queue, RouteRule::ToServices(service, level, seq), Message::Scan(alias));
+    }
+
+    fn root_rule(alias: u64) -> RouteRule {
+        RouteRule::ToKey(alias as u32)
+    }
+
+    fn index_rule(node: NodeId) -> RouteRule {
+        RouteRule::ToKey((node ^ INDEX_KEY_SALT).wrapping_mul(0x9e37_79b1))
+    }
+
+    fn on_tick_index(&mut self, node_id: NodeId, now: u64) {
+        if self.index_dirty || (!self.local_slots.is_empty() && now >= self.last_index_sync + ROOT_REFRESH_MS) {
+            self.index_dirty = false;
+            self.last_index_sync = now;
+            let mut aliases: Vec<u64> = self.local_slots.keys().copied().collect();
+            aliases.sort_unstable();
+            Self::send_to(&mut self.queue, Self::index_rule(node_id), Message::IndexSync(aliases));
+        }
+        self.index_slots.retain(|_, slot| now < slot.last_sync + OWNER_TIMEOUT_MS);
+
+        let queue = &mut self.queue;
+        self.lists.retain(|req_id, slot| {
+            if now >= slot.deadline {
+                log::warn!("[AliasFeature] List aliases of node {} req {req_id} timeout", slot.node);
+                let event = match slot.kind {
+                    ListKind::Node => Event::NodeAliases(slot.node, None),
+                    ListKind::Reverse => Event::ReverseResult(slot.node, None),
+                };
+                queue.push_back(FeatureOutput::Event(slot.actor, event));
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn set_owner_offline(slot: &mut RootSlot) {
+        slot.owner = None;
+        for msg in slot.msgs.iter_mut() {
+            msg.forwarded_at = None;
+        }
+    }
+
+    fn on_tick_offline_queue(&mut self, now: u64) {
+        for (alias, slot) in self.local_slots.iter_mut() {
+            if now >= slot.last_refresh + ROOT_REFRESH_MS {
+                slot.last_refresh = now;
+                Self::send_to(&mut self.queue, Self::root_rule(*alias), Message::RootRegister(*alias));
+            }
+        }
+
+        for (alias, slot) in self.root_slots.iter_mut() {
+            if let Some((owner, last_refresh)) = slot.owner {
+                let ack_timeout = slot.msgs.iter().any(|m| matches!(m.forwarded_at, Some(ts) if now >= ts + DELIVER_ACK_TIMEOUT_MS));
+                if ack_timeout || now >= last_refresh + OWNER_TIMEOUT_MS {
+                    log::info!("[AliasFeature] Alias {alias} owner {owner} timeout (ack timeout {ack_timeout}) => consider offline");
+                    Self::set_owner_offline(slot);
+                }
+            }
+            let queue = &mut self.queue;
+            slot.msgs.retain(|m| {
+                if m.forwarded_at.is_none() && now >= m.deadline {
+                    log::debug!("[AliasFeature] Alias {alias} parked msg {} from {} expired", m.seq, m.sender);
+                    Self::send_to(queue, RouteRule::ToNode(m.sender), Message::Receipt(*alias, m.seq, SendReceipt::Expired));
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        self.root_slots.retain(|_, slot| slot.owner.is_some() || !slot.msgs.is_empty());
+
+        if now >= self.last_root_probe + ROOT_REFRESH_MS {
+            self.last_root_probe = now;
+            for (alias, slot) in self.root_slots.iter_mut() {
+                if !slot.msgs.is_empty() {
+                    slot.probed_at = Some(now);
+                    Self::send_to(&mut self.queue, Self::root_rule(*alias), Message::RootProbe(*alias));
+                }
+            }
+        }
+        self.handovers.retain(|_, deadline| now < *deadline);
+
+        let queue = &mut self.queue;
+        self.sending.retain(|(alias, seq), (actor, deadline)| {
+            if now >= *deadline {
+                log::warn!("[AliasFeature] Send {seq} to alias {alias} timeout without receipt");
+                queue.push_back(FeatureOutput::Event(*actor, Event::SendReceipt(*alias, *seq, SendReceipt::Expired)));
+                false
+            } else {
+                true
+            }
+        });
+    }
+
     fn send_to(queue: &mut VecDeque<FeatureOutput<UserData, Event, ToWorker>>, rule: RouteRule, msg: Message) {
         let msg = bincode::serialize(&msg).expect("Should to bytes");
         queue.push_back(FeatureOutput::SendRoute(rule, NetOutgoingMeta::new(true, Ttl::default(), 0, true), msg.into()));
@@ -219,22 +804,22 @@ impl<UserData: Debug + Copy> AliasFeature<UserData> {
 }
 
 impl<UserData: Debug + Copy> Feature<UserData, Control, Event, ToController, ToWorker> for AliasFeature<UserData> {
-    fn on_shared_input(&mut self, _ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
+    fn on_shared_input(&mut self, ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
         if let FeatureSharedInput::Tick(_) = input {
+            self.on_tick_offline_queue(now);
+            self.on_tick_index(ctx.node_id, now);
+
             let mut timeout = vec![];
             for (alias, slot) in &mut self.queries {
                 match &slot.state {
                     QueryState::CheckHint(hint, started_at) => {
                         if now >= *started_at + HINT_TIMEOUT_MS {
-                            log::debug!("[AliasFeature] check {alias} hint node {hint} timeout => switch to Scan");
-
-                            let seq = self.scan_seq;
-                            self.scan_seq = self.scan_seq.wrapping_add(1);
-                            slot.state = QueryState::Scan(now);
-                            Self::send_to(&mut self.queue, RouteRule::ToServices(slot.service, slot.level, seq), Message::Scan(*alias));
+                            log::debug!("[AliasFeature] check {alias} hint node {hint} timeout => ask root and scan");
+                            slot.state = QueryState::Root(now);
+                            Self::ask_root_and_scan(&mut self.queue, &mut self.scan_seq, *alias, slot.service, slot.level);
                         }
                     }
-                    QueryState::Scan(started_at) => {
+                    QueryState::Root(started_at) | QueryState::Scan(started_at) => {
                         if now >= *started_at + SCAN_TIMEOUT_MS {
                             timeout.push(*alias);
                         }
@@ -252,16 +837,20 @@ impl<UserData: Debug + Copy> Feature<UserData, Control, Event, ToController, ToW
         }
     }
 
-    fn on_input(&mut self, _ctx: &FeatureContext, now_ms: u64, input: FeatureInput<'_, UserData, Control, ToController>) {
+    fn on_input(&mut self, ctx: &FeatureContext, now_ms: u64, input: FeatureInput<'_, UserData, Control, ToController>) {
         match input {
             FeatureInput::Control(actor, control) => self.process_control(now_ms, actor, control),
             FeatureInput::Local(meta, msg) | FeatureInput::Net(_, meta, msg) => {
                 if !meta.secure {
-                    log::warn!("[AliasFeature] reject unsecure message");
+                    log_sampled!(log::Level::Warn, "[AliasFeature] reject unsecure message");
                     return;
                 }
                 if let (Some(from), Ok(msg)) = (meta.source, bincode::deserialize::<Message>(&msg)) {
-                    self.process_remote(now_ms, from, msg)
+                    if from == ctx.node_id && matches!(msg, Message::RootProbe(_)) {
+                        // probe is routed back, so this node is still the closest one
+                        return;
+                    }
+                    self.process_remote(now_ms, ctx.node_id, from, msg)
                 }
             }
             _ => {}
@@ -338,11 +927,11 @@ mod tests {
     use sans_io_runtime::TaskSwitcherChild;
 
     use crate::{
-        base::{Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput},
-        features::alias::{HintSlot, HINT_TIMEOUT_MS, SCAN_TIMEOUT_MS},
+        base::{Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, LatencyProfile},
+        features::alias::{HintSlot, DELIVER_ACK_TIMEOUT_MS, HINT_TIMEOUT_MS, LIST_TIMEOUT_MS, OWNER_TIMEOUT_MS, ROOT_HANDOVER_TIMEOUT_MS, ROOT_REFRESH_MS, SCAN_TIMEOUT_MS},
     };
 
-    use super::{AliasFeature, Control, Event, FoundLocation, Message, ToWorker};
+    use super::{AliasFeature, Control, Event, FoundLocation, Message, SendReceipt, ToWorker};
 
     fn decode_msg(msg: Option<FeatureOutput<(), Event, ToWorker>>) -> Option<(RouteRule, Message)> {
         match msg? {
@@ -359,6 +948,7 @@ mod tests {
         let level = ServiceBroadcastLevel::Global;
         alias.on_input(&ctx, 0, FeatureInput::Control(FeatureControlActor::Controller(()), Control::Register { alias: 1000, service, level }));
         assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToServices(service, level, 0), Message::Notify(1000))));
+        assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToKey(1000), Message::RootRegister(1000))));
         assert_eq!(alias.pop_output(0), None);
 
         alias.on_input(&ctx, 0, FeatureInput::Control(FeatureControlActor::Controller(()), Control::Query { alias: 1000, service, level }));
@@ -377,13 +967,14 @@ mod tests {
         let level = ServiceBroadcastLevel::Global;
         alias.on_input(&ctx, 0, FeatureInput::Control(FeatureControlActor::Controller(()), Control::Register { alias: 1000, service, level }));
         assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToServices(service, level, 0), Message::Notify(1000))));
+        assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToKey(1000), Message::RootRegister(1000))));
         assert_eq!(alias.pop_output(0), None);
 
-        alias.process_remote(0, 123, Message::Check(1000));
+        alias.process_remote(0, 0, 123, Message::Check(1000));
         assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToNode(123), Message::Found(1000, true))));
         assert_eq!(alias.pop_output(0), None);
 
-        alias.process_remote(0, 123, Message::Check(1001));
+        alias.process_remote(0, 0, 123, Message::Check(1001));
         assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToNode(123), Message::Found(1001, false))));
         assert_eq!(alias.pop_output(0), None);
     }
@@ -396,13 +987,14 @@ mod tests {
         let level = ServiceBroadcastLevel::Global;
         alias.on_input(&ctx, 0, FeatureInput::Control(FeatureControlActor::Controller(()), Control::Register { alias: 1000, service, level }));
         assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToServices(service, level, 0), Message::Notify(1000))));
+        assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToKey(1000), Message::RootRegister(1000))));
         assert_eq!(alias.pop_output(0), None);
 
-        alias.process_remote(0, 123, Message::Scan(1000));
+        alias.process_remote(0, 0, 123, Message::Scan(1000));
         assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToNode(123), Message::Found(1000, true))));
         assert_eq!(alias.pop_output(0), None);
 
-        alias.process_remote(0, 123, Message::Scan(1001));
+        alias.process_remote(0, 0, 123, Message::Scan(1001));
         assert_eq!(alias.pop_output(0), None);
     }
 
@@ -445,7 +1037,7 @@ mod tests {
         assert_eq!(alias.pop_output(10000), None);
 
         //simulate remote found
-        alias.process_remote(10100, 123, Message::Found(1000, true));
+        alias.process_remote(10100, 0, 123, Message::Found(1000, true));
 
         assert_eq!(
             alias.pop_output(10100),
@@ -465,11 +1057,16 @@ mod tests {
         let level = ServiceBroadcastLevel::Global;
 
         alias.on_input(&ctx, 0, FeatureInput::Control(FeatureControlActor::Controller(()), Control::Query { alias: 1000, service, level }));
+        assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToKey(1000), Message::RootQuery(1000))));
         assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToServices(service, level, 0), Message::Scan(1000))));
         assert_eq!(alias.pop_output(0), None);
 
+        //root doesn't know the alias, scan is already running
+        alias.process_remote(50, 0, 100, Message::RootFound(1000, None));
+        assert_eq!(alias.pop_output(50), None);
+
         //simulate scan found
-        alias.process_remote(100, 123, Message::Found(1000, true));
+        alias.process_remote(100, 0, 123, Message::Found(1000, true));
 
         assert_eq!(
             alias.pop_output(100),
@@ -495,14 +1092,17 @@ mod tests {
         assert_eq!(alias.pop_output(10000), None);
 
         //simulate remote not found
-        alias.process_remote(10100, 122, Message::Found(1000, false));
+        alias.process_remote(10100, 0, 122, Message::Found(1000, false));
 
-        // will fallback to scan
+        // will ask root and scan together
+        assert_eq!(decode_msg(alias.pop_output(10100)), Some((RouteRule::ToKey(1000), Message::RootQuery(1000))));
         assert_eq!(decode_msg(alias.pop_output(10100)), Some((RouteRule::ToServices(service, level, 0), Message::Scan(1000))));
         assert_eq!(alias.pop_output(10100), None);
+        alias.process_remote(10100, 0, 100, Message::RootFound(1000, None));
+        assert_eq!(alias.pop_output(10100), None);
 
         //simulate scan found
-        alias.process_remote(10100, 123, Message::Found(1000, true));
+        alias.process_remote(10100, 0, 123, Message::Found(1000, true));
 
         assert_eq!(
             alias.pop_output(10100),
@@ -520,6 +1120,8 @@ mod tests {
         let ctx = FeatureContext { node_id: 0, session: 0 };
         let service = 1;
         let level = ServiceBroadcastLevel::Global;
+        let root_at = 10000 + HINT_TIMEOUT_MS;
+        let scan_at = root_at;
 
         alias.hint_slots.insert(1000, HintSlot { node: 122, ts: 0 });
 
@@ -527,36 +1129,26 @@ mod tests {
         assert_eq!(decode_msg(alias.pop_output(10000)), Some((RouteRule::ToNode(122), Message::Check(1000))));
         assert_eq!(alias.pop_output(10000), None);
 
-        //simulate remote not found
-        alias.on_shared_input(&ctx, 10000 + HINT_TIMEOUT_MS, FeatureSharedInput::Tick(0));
-
-        // will fallback to scan
-        assert_eq!(
-            decode_msg(alias.pop_output(10000 + HINT_TIMEOUT_MS)),
-            Some((RouteRule::ToServices(service, level, 0), Message::Scan(1000)))
-        );
-        assert_eq!(alias.pop_output(10000 + HINT_TIMEOUT_MS), None);
+        //simulate remote not found, root is asked together with scan
+        alias.on_shared_input(&ctx, root_at, FeatureSharedInput::Tick(0));
+        assert_eq!(decode_msg(alias.pop_output(root_at)), Some((RouteRule::ToKey(1000), Message::RootQuery(1000))));
+        assert_eq!(decode_msg(alias.pop_output(root_at)), Some((RouteRule::ToServices(service, level, 0), Message::Scan(1000))));
+        assert_eq!(alias.pop_output(root_at), None);
 
-        //simulate scan found
-        alias.process_remote(10100 + HINT_TIMEOUT_MS, 123, Message::Found(1000, true));
+        //simulate scan found while root doesn't reply
+        alias.process_remote(scan_at + 100, 0, 123, Message::Found(1000, true));
 
         assert_eq!(
-            alias.pop_output(10100 + HINT_TIMEOUT_MS),
+            alias.pop_output(scan_at + 100),
             Some(FeatureOutput::Event(
                 FeatureControlActor::Controller(()),
                 Event::QueryResult(1000, Some(FoundLocation::RemoteScan(123)))
             ))
         );
-        assert_eq!(alias.pop_output(10100 + HINT_TIMEOUT_MS), None);
+        assert_eq!(alias.pop_output(scan_at + 100), None);
 
         //after that hint should be saved
-        assert_eq!(
-            alias.hint_slots.get(&1000),
-            Some(&HintSlot {
-                node: 123,
-                ts: 10100 + HINT_TIMEOUT_MS
-            })
-        );
+        assert_eq!(alias.hint_slots.get(&1000), Some(&HintSlot { node: 123, ts: scan_at + 100 }));
     }
 
     #[test]
@@ -565,6 +1157,7 @@ mod tests {
         let ctx = FeatureContext { node_id: 0, session: 0 };
         let service = 1;
         let level = ServiceBroadcastLevel::Global;
+        let scan_at = 10000 + HINT_TIMEOUT_MS;
 
         alias.hint_slots.insert(1000, HintSlot { node: 122, ts: 0 });
 
@@ -573,29 +1166,391 @@ mod tests {
         assert_eq!(alias.pop_output(10000), None);
 
         //simulate remote not found
-        alias.on_shared_input(&ctx, 10000 + HINT_TIMEOUT_MS, FeatureSharedInput::Tick(0));
+        alias.on_shared_input(&ctx, scan_at, FeatureSharedInput::Tick(0));
+        assert_eq!(decode_msg(alias.pop_output(scan_at)), Some((RouteRule::ToKey(1000), Message::RootQuery(1000))));
+        assert_eq!(decode_msg(alias.pop_output(scan_at)), Some((RouteRule::ToServices(service, level, 0), Message::Scan(1000))));
+        assert_eq!(alias.pop_output(scan_at), None);
+
+        // root doesn't reply before the scan timeout
+        alias.on_shared_input(&ctx, scan_at + SCAN_TIMEOUT_MS - 1, FeatureSharedInput::Tick(1));
+        assert_eq!(alias.pop_output(scan_at + SCAN_TIMEOUT_MS - 1), None);
+
+        //simulate scan timeout
+        alias.on_shared_input(&ctx, scan_at + SCAN_TIMEOUT_MS, FeatureSharedInput::Tick(2));
 
-        // will fallback to scan
         assert_eq!(
-            decode_msg(alias.pop_output(10000 + HINT_TIMEOUT_MS)),
-            Some((RouteRule::ToServices(service, level, 0), Message::Scan(1000)))
+            alias.pop_output(scan_at + SCAN_TIMEOUT_MS),
+            Some(FeatureOutput::Event(FeatureControlActor::Controller(()), Event::QueryResult(1000, None)))
         );
-        assert_eq!(alias.pop_output(10000 + HINT_TIMEOUT_MS), None);
+        assert_eq!(alias.pop_output(scan_at + SCAN_TIMEOUT_MS), None);
+    }
 
-        //simulate scan found
-        alias.on_shared_input(&ctx, 10000 + HINT_TIMEOUT_MS + SCAN_TIMEOUT_MS, FeatureSharedInput::Tick(1));
+    #[test]
+    fn found_remote_with_root() {
+        let mut alias = AliasFeature::default();
+        let ctx = FeatureContext { node_id: 0, session: 0 };
+        let service = 1;
+        let level = ServiceBroadcastLevel::Global;
 
+        alias.on_input(&ctx, 0, FeatureInput::Control(FeatureControlActor::Controller(()), Control::Query { alias: 1000, service, level }));
+        assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToKey(1000), Message::RootQuery(1000))));
+        assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToServices(service, level, 0), Message::Scan(1000))));
+        assert_eq!(alias.pop_output(0), None);
+
+        alias.process_remote(100, 0, 50, Message::RootFound(1000, Some(123)));
         assert_eq!(
-            alias.pop_output(10000 + HINT_TIMEOUT_MS + SCAN_TIMEOUT_MS),
-            Some(FeatureOutput::Event(FeatureControlActor::Controller(()), Event::QueryResult(1000, None)))
+            alias.pop_output(100),
+            Some(FeatureOutput::Event(
+                FeatureControlActor::Controller(()),
+                Event::QueryResult(1000, Some(FoundLocation::RemoteHint(123)))
+            ))
         );
-        assert_eq!(alias.pop_output(10000 + HINT_TIMEOUT_MS + SCAN_TIMEOUT_MS), None);
+        assert_eq!(alias.pop_output(100), None);
+        assert_eq!(alias.hint_slots.get(&1000), Some(&HintSlot { node: 123, ts: 100 }));
+    }
+
+    #[test]
+    fn root_answer_query() {
+        let mut alias = AliasFeature::<()>::default();
+        alias.process_remote(0, 0, 3, Message::RootRegister(1000));
+        assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToNode(3), Message::RootAck(1000))));
+
+        alias.process_remote(10, 0, 5, Message::RootQuery(1000));
+        assert_eq!(decode_msg(alias.pop_output(10)), Some((RouteRule::ToNode(5), Message::RootFound(1000, Some(3)))));
+        alias.process_remote(10, 0, 5, Message::RootQuery(1001));
+        assert_eq!(decode_msg(alias.pop_output(10)), Some((RouteRule::ToNode(5), Message::RootFound(1001, None))));
+        assert_eq!(alias.pop_output(10), None);
+    }
+
+    #[test]
+    fn owner_notify_previous_root_after_moved() {
+        let mut alias = AliasFeature::default();
+        let ctx = FeatureContext { node_id: 0, session: 0 };
+        let service = 1;
+        let level = ServiceBroadcastLevel::Global;
+        alias.on_input(&ctx, 0, FeatureInput::Control(FeatureControlActor::Controller(()), Control::Register { alias: 1000, service, level }));
+        while alias.pop_output(0).is_some() {}
+
+        alias.process_remote(10, 0, 2, Message::RootAck(1000));
+        alias.process_remote(ROOT_REFRESH_MS, 0, 2, Message::RootAck(1000));
+        assert_eq!(alias.pop_output(ROOT_REFRESH_MS), None);
+
+        // a closer node joined, so refresh is acked by the new root
+        alias.process_remote(2 * ROOT_REFRESH_MS, 0, 3, Message::RootAck(1000));
+        assert_eq!(decode_msg(alias.pop_output(2 * ROOT_REFRESH_MS)), Some((RouteRule::ToNode(2), Message::RootMoved(1000, 3))));
+        assert_eq!(alias.pop_output(2 * ROOT_REFRESH_MS), None);
+    }
+
+    #[test]
+    fn root_handover_parked_msgs() {
+        let mut old_root = AliasFeature::<()>::default();
+        old_root.process_remote(0, 0, 2, Message::Send(1000, 1, 10000, vec![1]));
+        assert_eq!(decode_msg(old_root.pop_output(0)), Some((RouteRule::ToNode(2), Message::Receipt(1000, 1, SendReceipt::Queued))));
+
+        // probe is answered by a closer node which joined after the message was parked
+        old_root.on_shared_input(&FeatureContext { node_id: 0, session: 0 }, ROOT_REFRESH_MS, FeatureSharedInput::Tick(0));
+        assert_eq!(decode_msg(old_root.pop_output(ROOT_REFRESH_MS)), Some((RouteRule::ToKey(1000), Message::RootProbe(1000))));
+        let mut new_root = AliasFeature::<()>::default();
+        new_root.process_remote(ROOT_REFRESH_MS, 5, 0, Message::RootProbe(1000));
+        assert_eq!(decode_msg(new_root.pop_output(ROOT_REFRESH_MS)), Some((RouteRule::ToNode(0), Message::RootMoved(1000, 5))));
+
+        old_root.process_remote(ROOT_REFRESH_MS, 0, 5, Message::RootMoved(1000, 5));
+        let handover = Message::RootHandover(1000, vec![(2, 1, 10000 - ROOT_REFRESH_MS, vec![1])]);
+        assert_eq!(decode_msg(old_root.pop_output(ROOT_REFRESH_MS)), Some((RouteRule::ToNode(5), handover)));
+        assert_eq!(old_root.pop_output(ROOT_REFRESH_MS), None);
+        assert!(old_root.root_slots.is_empty());
+
+        // new root already knows the owner, so messages are delivered at once
+        let now = ROOT_REFRESH_MS + 100;
+        new_root.process_remote(now, 5, 4, Message::RootRegister(1000));
+        assert_eq!(decode_msg(new_root.pop_output(now)), Some((RouteRule::ToNode(4), Message::RootAck(1000))));
+        new_root.process_remote(now, 5, 0, Message::RootHandover(1000, vec![(2, 1, 10000 - ROOT_REFRESH_MS, vec![1])]));
+        assert_eq!(decode_msg(new_root.pop_output(now)), Some((RouteRule::ToNode(4), Message::Deliver(1000, 2, 1, vec![1]))));
+        assert_eq!(new_root.pop_output(now), None);
+
+        new_root.process_remote(now + 100, 5, 4, Message::DeliverAck(1000, 2, 1));
+        assert_eq!(
+            decode_msg(new_root.pop_output(now + 100)),
+            Some((RouteRule::ToNode(2), Message::Receipt(1000, 1, SendReceipt::Delivered)))
+        );
+    }
+
+    #[test]
+    fn root_move_only_to_probed_node() {
+        let mut old_root = AliasFeature::<()>::default();
+        old_root.process_remote(0, 0, 2, Message::Send(1000, 1, 10000, vec![1]));
+        while old_root.pop_output(0).is_some() {}
+
+        // unsolicited move, e.g. from the owner or a node which claims the alias, only triggers a probe
+        old_root.process_remote(10, 0, 6, Message::RootMoved(1000, 6));
+        assert_eq!(decode_msg(old_root.pop_output(10)), Some((RouteRule::ToKey(1000), Message::RootProbe(1000))));
+        assert_eq!(old_root.pop_output(10), None);
+        // node 6 is not the node which the probe is routed to
+        old_root.process_remote(20, 0, 6, Message::RootMoved(1000, 5));
+        assert_eq!(old_root.pop_output(20), None);
+        assert_eq!(old_root.parked_msgs(), 1);
+
+        // reply of the probe after it timed out is not accepted
+        let late = 10 + ROOT_HANDOVER_TIMEOUT_MS;
+        old_root.process_remote(late, 0, 5, Message::RootMoved(1000, 5));
+        assert_eq!(decode_msg(old_root.pop_output(late)), Some((RouteRule::ToKey(1000), Message::RootProbe(1000))));
+        old_root.process_remote(late + 10, 0, 5, Message::RootMoved(1000, 5));
+        assert!(matches!(decode_msg(old_root.pop_output(late + 10)), Some((RouteRule::ToNode(5), Message::RootHandover(1000, _)))));
+
+        // new root doesn't accept parked messages from nodes which it didn't ask to move
+        let mut new_root = AliasFeature::<()>::default();
+        new_root.process_remote(0, 5, 7, Message::RootHandover(1000, vec![(2, 1, 10000, vec![1])]));
+        assert_eq!(new_root.parked_msgs(), 0);
+        new_root.process_remote(0, 5, 0, Message::RootProbe(1000));
+        assert_eq!(decode_msg(new_root.pop_output(0)), Some((RouteRule::ToNode(0), Message::RootMoved(1000, 5))));
+        new_root.process_remote(ROOT_HANDOVER_TIMEOUT_MS, 5, 0, Message::RootHandover(1000, vec![(2, 1, 10000, vec![1])]));
+        assert_eq!(new_root.parked_msgs(), 0);
+        new_root.process_remote(0, 5, 0, Message::RootProbe(1000));
+        while new_root.pop_output(0).is_some() {}
+        new_root.process_remote(10, 5, 0, Message::RootHandover(1000, vec![(2, 1, 10000, vec![1])]));
+        assert_eq!(new_root.parked_msgs(), 1);
     }
 
     #[test]
     fn handle_notify_from_remote() {
         let mut alias = AliasFeature::<()>::default();
-        alias.process_remote(100, 123, Message::Notify(1000));
+        alias.process_remote(100, 0, 123, Message::Notify(1000));
         assert_eq!(alias.hint_slots.get(&1000), Some(&HintSlot { node: 123, ts: 100 }));
     }
+
+    #[test]
+    fn root_park_and_deliver_when_owner_register() {
+        let mut alias = AliasFeature::<()>::default();
+
+        alias.process_remote(0, 0, 2, Message::Send(1000, 1, 10000, vec![1, 2, 3]));
+        assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToNode(2), Message::Receipt(1000, 1, SendReceipt::Queued))));
+        assert_eq!(alias.pop_output(0), None);
+
+        alias.process_remote(100, 0, 3, Message::RootRegister(1000));
+        assert_eq!(decode_msg(alias.pop_output(100)), Some((RouteRule::ToNode(3), Message::RootAck(1000))));
+        assert_eq!(decode_msg(alias.pop_output(100)), Some((RouteRule::ToNode(3), Message::Deliver(1000, 2, 1, vec![1, 2, 3]))));
+        assert_eq!(alias.pop_output(100), None);
+
+        alias.process_remote(200, 0, 3, Message::DeliverAck(1000, 2, 1));
+        assert_eq!(decode_msg(alias.pop_output(200)), Some((RouteRule::ToNode(2), Message::Receipt(1000, 1, SendReceipt::Delivered))));
+        assert_eq!(alias.pop_output(200), None);
+        assert!(alias.root_slots.get(&1000).expect("Should have slot").msgs.is_empty());
+    }
+
+    #[test]
+    fn root_expire_parked_msg() {
+        let mut alias = AliasFeature::<()>::default();
+        let ctx = FeatureContext { node_id: 0, session: 0 };
+
+        alias.process_remote(0, 0, 2, Message::Send(1000, 1, 1000, vec![1]));
+        assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToNode(2), Message::Receipt(1000, 1, SendReceipt::Queued))));
+
+        alias.process_remote(0, 0, 2, Message::Send(1000, 2, 0, vec![2]));
+        assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToNode(2), Message::Receipt(1000, 2, SendReceipt::Expired))));
+
+        alias.on_shared_input(&ctx, 1000, FeatureSharedInput::Tick(0));
+        assert_eq!(decode_msg(alias.pop_output(1000)), Some((RouteRule::ToNode(2), Message::Receipt(1000, 1, SendReceipt::Expired))));
+        assert_eq!(alias.pop_output(1000), None);
+        assert!(alias.root_slots.is_empty());
+    }
+
+    #[test]
+    fn root_bounded_latency_profile() {
+        let mut alias = AliasFeature::<()>::new(LatencyProfile::BoundedLatency);
+        let ctx = FeatureContext { node_id: 0, session: 0 };
+        let max_parked = LatencyProfile::BoundedLatency.alias_max_parked_msgs();
+        let max_ttl = LatencyProfile::BoundedLatency.alias_max_park_ttl_ms();
+
+        for seq in 0..max_parked as u64 {
+            alias.process_remote(0, 0, 2, Message::Send(1000, seq, 10000, vec![1]));
+            assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToNode(2), Message::Receipt(1000, seq, SendReceipt::Queued))));
+        }
+
+        let seq = max_parked as u64;
+        alias.process_remote(0, 0, 2, Message::Send(1000, seq, 10000, vec![1]));
+        assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToNode(2), Message::Receipt(1000, seq, SendReceipt::QueueFull))));
+        assert_eq!(alias.pop_output(0), None);
+
+        // stale messages are dropped after the capped ttl instead of the requested one
+        alias.on_shared_input(&ctx, max_ttl, FeatureSharedInput::Tick(0));
+        for seq in 0..max_parked as u64 {
+            assert_eq!(decode_msg(alias.pop_output(max_ttl)), Some((RouteRule::ToNode(2), Message::Receipt(1000, seq, SendReceipt::Expired))));
+        }
+        assert_eq!(alias.pop_output(max_ttl), None);
+    }
+
+    #[test]
+    fn root_park_again_when_owner_not_ack() {
+        let mut alias = AliasFeature::<()>::default();
+        let ctx = FeatureContext { node_id: 0, session: 0 };
+
+        alias.process_remote(0, 0, 3, Message::RootRegister(1000));
+        assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToNode(3), Message::RootAck(1000))));
+        alias.process_remote(0, 0, 2, Message::Send(1000, 1, 10000, vec![1]));
+        assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToNode(3), Message::Deliver(1000, 2, 1, vec![1]))));
+        assert_eq!(alias.pop_output(0), None);
+
+        alias.on_shared_input(&ctx, DELIVER_ACK_TIMEOUT_MS, FeatureSharedInput::Tick(0));
+        assert_eq!(decode_msg(alias.pop_output(DELIVER_ACK_TIMEOUT_MS)), Some((RouteRule::ToKey(1000), Message::RootProbe(1000))));
+        assert_eq!(alias.pop_output(DELIVER_ACK_TIMEOUT_MS), None);
+        assert_eq!(alias.root_slots.get(&1000).expect("Should have slot").owner, None);
+
+        alias.process_remote(DELIVER_ACK_TIMEOUT_MS + 100, 0, 3, Message::RootRegister(1000));
+        assert_eq!(decode_msg(alias.pop_output(DELIVER_ACK_TIMEOUT_MS + 100)), Some((RouteRule::ToNode(3), Message::RootAck(1000))));
+        assert_eq!(
+            decode_msg(alias.pop_output(DELIVER_ACK_TIMEOUT_MS + 100)),
+            Some((RouteRule::ToNode(3), Message::Deliver(1000, 2, 1, vec![1])))
+        );
+    }
+
+    #[test]
+    fn restore_root_from_checkpoint() {
+        let mut alias = AliasFeature::<()>::default();
+        alias.process_remote(0, 0, 3, Message::RootRegister(2000));
+        alias.process_remote(0, 0, 2, Message::Send(1000, 1, 10000, vec![1]));
+        alias.process_remote(0, 0, 4, Message::IndexSync(vec![5, 6]));
+        while alias.pop_output(0).is_some() {}
+        let checkpoint = alias.checkpoint(1000);
+
+        let mut restored = AliasFeature::<()>::default();
+        restored.restore_checkpoint(50000, checkpoint);
+        assert_eq!(restored.root_slots.get(&2000).expect("Should have slot").owner, Some((3, 50000)));
+        restored.process_remote(50000, 0, 5, Message::ReverseReq(1, 4));
+        assert_eq!(decode_msg(restored.pop_output(50000)), Some((RouteRule::ToNode(5), Message::ListRes(1, vec![5, 6]))));
+
+        // parked message keeps its remaining ttl and is delivered when the owner registers
+        restored.process_remote(50100, 0, 3, Message::RootRegister(1000));
+        assert_eq!(decode_msg(restored.pop_output(50100)), Some((RouteRule::ToNode(3), Message::RootAck(1000))));
+        assert_eq!(decode_msg(restored.pop_output(50100)), Some((RouteRule::ToNode(3), Message::Deliver(1000, 2, 1, vec![1]))));
+        let deadline = restored.root_slots.get(&1000).expect("Should have slot").msgs[0].deadline;
+        assert_eq!(deadline, 50000 + 9000);
+    }
+
+    #[test]
+    fn owner_receive_and_ack() {
+        let mut alias = AliasFeature::default();
+        let ctx = FeatureContext { node_id: 0, session: 0 };
+        let service = 1;
+        let level = ServiceBroadcastLevel::Global;
+        alias.on_input(&ctx, 0, FeatureInput::Control(FeatureControlActor::Controller(()), Control::Register { alias: 1000, service, level }));
+        assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToServices(service, level, 0), Message::Notify(1000))));
+        assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToKey(1000), Message::RootRegister(1000))));
+
+        alias.process_remote(100, 0, 5, Message::Deliver(1000, 2, 1, vec![1, 2]));
+        assert_eq!(
+            alias.pop_output(100),
+            Some(FeatureOutput::Event(FeatureControlActor::Controller(()), Event::Received(1000, 2, vec![1, 2])))
+        );
+        assert_eq!(decode_msg(alias.pop_output(100)), Some((RouteRule::ToNode(5), Message::DeliverAck(1000, 2, 1))));
+        assert_eq!(alias.pop_output(100), None);
+    }
+
+    #[test]
+    fn sender_receive_receipts() {
+        let mut alias = AliasFeature::default();
+        let ctx = FeatureContext { node_id: 0, session: 0 };
+        let actor = FeatureControlActor::Controller(());
+        alias.on_input(
+            &ctx,
+            0,
+            FeatureInput::Control(
+                actor,
+                Control::Send {
+                    alias: 1000,
+                    seq: 1,
+                    ttl_ms: 10000,
+                    data: vec![1],
+                },
+            ),
+        );
+        assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToKey(1000), Message::Send(1000, 1, 10000, vec![1]))));
+
+        alias.process_remote(100, 0, 5, Message::Receipt(1000, 1, SendReceipt::Queued));
+        assert_eq!(alias.pop_output(100), Some(FeatureOutput::Event(actor, Event::SendReceipt(1000, 1, SendReceipt::Queued))));
+
+        alias.process_remote(200, 0, 5, Message::Receipt(1000, 1, SendReceipt::Delivered));
+        assert_eq!(alias.pop_output(200), Some(FeatureOutput::Event(actor, Event::SendReceipt(1000, 1, SendReceipt::Delivered))));
+        assert!(alias.sending.is_empty());
+    }
+
+    #[test]
+    fn owner_sync_reverse_index() {
+        let mut alias = AliasFeature::default();
+        let ctx = FeatureContext { node_id: 10, session: 0 };
+        let actor = FeatureControlActor::Controller(());
+        let service = 1;
+        let level = ServiceBroadcastLevel::Global;
+        let index_rule = AliasFeature::<()>::index_rule(10);
+        assert_ne!(index_rule, RouteRule::ToKey(10));
+
+        for alias_v in [1001, 1000] {
+            alias.on_input(&ctx, 0, FeatureInput::Control(actor, Control::Register { alias: alias_v, service, level }));
+        }
+        while alias.pop_output(0).is_some() {}
+
+        alias.on_shared_input(&ctx, 100, FeatureSharedInput::Tick(0));
+        assert_eq!(decode_msg(alias.pop_output(100)), Some((index_rule.clone(), Message::IndexSync(vec![1000, 1001]))));
+        assert_eq!(alias.pop_output(100), None);
+
+        // refreshed with root refresh interval
+        alias.on_shared_input(&ctx, 100 + ROOT_REFRESH_MS, FeatureSharedInput::Tick(0));
+        let mut outputs = vec![];
+        while let Some(out) = decode_msg(alias.pop_output(100 + ROOT_REFRESH_MS)) {
+            outputs.push(out);
+        }
+        assert!(outputs.contains(&(index_rule.clone(), Message::IndexSync(vec![1000, 1001]))));
+
+        // empty list is synced after all aliases are unregistered
+        for alias_v in [1000, 1001] {
+            alias.on_input(&ctx, 200 + ROOT_REFRESH_MS, FeatureInput::Control(actor, Control::Unregister { alias: alias_v }));
+        }
+        while alias.pop_output(200 + ROOT_REFRESH_MS).is_some() {}
+        alias.on_shared_input(&ctx, 300 + ROOT_REFRESH_MS, FeatureSharedInput::Tick(0));
+        assert_eq!(decode_msg(alias.pop_output(300 + ROOT_REFRESH_MS)), Some((index_rule, Message::IndexSync(vec![]))));
+        assert_eq!(alias.pop_output(300 + ROOT_REFRESH_MS), None);
+
+        // answer list request with local aliases
+        alias.process_remote(400 + ROOT_REFRESH_MS, 0, 5, Message::ListReq(7));
+        assert_eq!(decode_msg(alias.pop_output(400 + ROOT_REFRESH_MS)), Some((RouteRule::ToNode(5), Message::ListRes(7, vec![]))));
+    }
+
+    #[test]
+    fn index_node_answer_reverse_query() {
+        let mut alias = AliasFeature::<()>::default();
+        let ctx = FeatureContext { node_id: 0, session: 0 };
+
+        alias.process_remote(0, 0, 3, Message::IndexSync(vec![1000, 1001]));
+        alias.process_remote(10, 0, 5, Message::ReverseReq(1, 3));
+        assert_eq!(decode_msg(alias.pop_output(10)), Some((RouteRule::ToNode(5), Message::ListRes(1, vec![1000, 1001]))));
+        alias.process_remote(10, 0, 5, Message::ReverseReq(2, 4));
+        assert_eq!(decode_msg(alias.pop_output(10)), Some((RouteRule::ToNode(5), Message::ListRes(2, vec![]))));
+
+        // index is expired if the owner doesn't refresh
+        alias.on_shared_input(&ctx, OWNER_TIMEOUT_MS, FeatureSharedInput::Tick(0));
+        alias.process_remote(OWNER_TIMEOUT_MS, 0, 5, Message::ReverseReq(3, 3));
+        assert_eq!(decode_msg(alias.pop_output(OWNER_TIMEOUT_MS)), Some((RouteRule::ToNode(5), Message::ListRes(3, vec![]))));
+    }
+
+    #[test]
+    fn requester_list_and_reverse_query() {
+        let mut alias = AliasFeature::default();
+        let ctx = FeatureContext { node_id: 0, session: 0 };
+        let actor = FeatureControlActor::Controller(());
+
+        alias.on_input(&ctx, 0, FeatureInput::Control(actor, Control::ListByNode(3)));
+        assert_eq!(decode_msg(alias.pop_output(0)), Some((RouteRule::ToNode(3), Message::ListReq(0))));
+        alias.on_input(&ctx, 0, FeatureInput::Control(actor, Control::ReverseQuery(3)));
+        assert_eq!(decode_msg(alias.pop_output(0)), Some((AliasFeature::<()>::index_rule(3), Message::ReverseReq(1, 3))));
+
+        alias.process_remote(100, 0, 3, Message::ListRes(0, vec![1000]));
+        assert_eq!(alias.pop_output(100), Some(FeatureOutput::Event(actor, Event::NodeAliases(3, Some(vec![1000])))));
+
+        alias.on_shared_input(&ctx, LIST_TIMEOUT_MS, FeatureSharedInput::Tick(0));
+        assert_eq!(alias.pop_output(LIST_TIMEOUT_MS), Some(FeatureOutput::Event(actor, Event::ReverseResult(3, None))));
+        assert_eq!(alias.pop_output(LIST_TIMEOUT_MS), None);
+
+        // late reply is ignored
+        alias.process_remote(LIST_TIMEOUT_MS + 100, 0, 7, Message::ListRes(1, vec![1000]));
+        assert_eq!(alias.pop_output(LIST_TIMEOUT_MS + 100), None);
+    }
 }
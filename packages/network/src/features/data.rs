@@ -10,9 +10,16 @@ use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
 use serde::{Deserialize, Serialize};
 
 use crate::base::{
-    Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput, NetIncomingMeta, NetOutgoingMeta,
+    Feature, FeatureContext, FeatureControlActor, FeatureError, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput, NetIncomingMeta,
+    NetOutgoingMeta,
 };
 
+use self::bulk::{BulkMsg, BulkOutput, BulkTransfers};
+
+mod bulk;
+
+pub use bulk::{BulkProgress, BULK_MAX_BYTES, BULK_SEGMENT_SIZE};
+
 pub const FEATURE_ID: u8 = 1;
 pub const FEATURE_NAME: &str = "data_transfer";
 
@@ -22,12 +29,32 @@ pub enum Control {
     DataListen(u16),
     DataUnlisten(u16),
     DataSendRule(u16, RouteRule, NetOutgoingMeta, Vec<u8>),
+    /// Send large data to the port of dest node with a pipelined bulk transfer, the u64 is the transfer id which is used in progress events.
+    /// The receiver gets a single [`Event::BulkRecv`] after all segments are received
+    BulkSend(u16, NodeId, u64, Vec<u8>),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Event {
+    /// Ping result, rtt is None if ping is timeout
     Pong(NodeId, Option<u16>),
     Recv(u16, NetIncomingMeta, Vec<u8>),
+    /// Progress of a bulk transfer, which is sent in each tick while acked data is changed
+    BulkProgress(u64, BulkProgress),
+    BulkSent(u64, BulkProgress),
+    /// Bulk transfer is failed without progress after timeout, or rejected because it is too large
+    BulkFailed(u64, BulkProgress),
+    /// Completed bulk transfer for a listened port, with the sender node
+    BulkRecv(u16, NodeId, Vec<u8>),
+}
+
+impl Event {
+    pub fn error(&self) -> Option<FeatureError> {
+        match self {
+            Self::Pong(_, None) | Self::BulkFailed(_, _) => Some(FeatureError::Timeout),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -41,6 +68,7 @@ enum DataMsg {
     Ping { id: u64, ts: u64, from: NodeId },
     Pong { id: u64, ts: u64 },
     Data(u16, Vec<u8>),
+    Bulk(BulkMsg),
 }
 
 pub type Output<UserData> = FeatureOutput<UserData, Event, ToWorker>;
@@ -51,6 +79,7 @@ pub struct DataFeature<UserData> {
     ping_seq: u64,
     queue: VecDeque<Output<UserData>>,
     data_dest: HashMap<u16, FeatureControlActor<UserData>>,
+    bulk: BulkTransfers<UserData>,
     shutdown: bool,
 }
 
@@ -61,13 +90,35 @@ impl<UserData> Default for DataFeature<UserData> {
             ping_seq: 0,
             queue: VecDeque::new(),
             data_dest: HashMap::new(),
+            bulk: BulkTransfers::default(),
             shutdown: false,
         }
     }
 }
 
+impl<UserData: Copy> DataFeature<UserData> {
+    fn pop_bulk(&mut self) {
+        while let Some(out) = self.bulk.pop_output() {
+            match out {
+                BulkOutput::Send(dest, msg) => {
+                    let msg = bincode::serialize(&DataMsg::Bulk(msg)).expect("should work");
+                    self.queue.push_back(FeatureOutput::SendRoute(RouteRule::ToNode(dest), NetOutgoingMeta::default(), msg.into()));
+                }
+                BulkOutput::Event(actor, event) => self.queue.push_back(FeatureOutput::Event(actor, event)),
+                BulkOutput::Received(port, from, data) => {
+                    if let Some(actor) = self.data_dest.get(&port) {
+                        self.queue.push_back(FeatureOutput::Event(*actor, Event::BulkRecv(port, from, data)));
+                    } else {
+                        log::warn!("[DataFeature] drop bulk transfer from {from} for unlistened port {port}");
+                    }
+                }
+            }
+        }
+    }
+}
+
 impl<UserData: Copy> Feature<UserData, Control, Event, ToController, ToWorker> for DataFeature<UserData> {
-    fn on_shared_input(&mut self, _ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
+    fn on_shared_input(&mut self, ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
         if let FeatureSharedInput::Tick(_) = input {
             //clean timeout ping
             let mut timeout_list = Vec::new();
@@ -81,6 +132,9 @@ impl<UserData: Copy> Feature<UserData, Control, Event, ToController, ToWorker> f
                 let (_, actor, dest) = self.waits.remove(&id).expect("Should have");
                 self.queue.push_back(FeatureOutput::Event(actor, Event::Pong(dest, None)));
             }
+
+            self.bulk.on_tick(now, ctx.node_id);
+            self.pop_bulk();
         }
     }
 
@@ -112,6 +166,10 @@ impl<UserData: Copy> Feature<UserData, Control, Event, ToController, ToWorker> f
                     let msg = bincode::serialize(&data).expect("should work");
                     self.queue.push_back(FeatureOutput::SendRoute(rule, ttl, msg.into()));
                 }
+                Control::BulkSend(port, dest, id, data) => {
+                    self.bulk.send(now_ms, ctx.node_id, ctx.session, actor, id, port, dest, data);
+                    self.pop_bulk();
+                }
             },
             FeatureInput::Net(_, meta, buf) | FeatureInput::Local(meta, buf) => {
                 log::debug!("[DataFeature] on message from {:?} len {}", meta.source, buf.len());
@@ -135,6 +193,10 @@ impl<UserData: Copy> Feature<UserData, Control, Event, ToController, ToWorker> f
                                 self.queue.push_back(FeatureOutput::Event(*actor, Event::Recv(port, meta, data)));
                             }
                         }
+                        DataMsg::Bulk(msg) => {
+                            self.bulk.on_msg(now_ms, ctx.node_id, msg);
+                            self.pop_bulk();
+                        }
                     }
                 }
             }
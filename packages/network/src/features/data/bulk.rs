@@ -0,0 +1,545 @@
+//! Bulk transfer, which pipelines many segments inside a window and uses selective acks (like TCP SACK),
+//! so large transfers over high bandwidth-delay paths are not limited by one round trip per message.
+//!
+//! Sender: data is split in BULK_SEGMENT_SIZE segments, which are sent while in-flight segments are fewer than the window.
+//! The window starts at BULK_INIT_WINDOW and grows by one segment per acked segment (doubles each round trip) up to BULK_MAX_WINDOW,
+//! then halves after a loss. A segment is resent when BULK_DUP_THRESH later segments are acked (fast retransmit),
+//! or when it is not acked after the retransmit timeout, which is checked in each tick.
+//!
+//! Receiver: each ack carries the cumulative ack (all segments before it are received) and up to BULK_MAX_SACKS ranges of received segments after it.
+//! An ack is sent after every BULK_ACK_EVERY new segments, for out of order or duplicated segments, and in each tick while there are unacked segments.
+//! Completed transfers are remembered for BULK_TIMEOUT_MS for answering retransmitted segments.
+
+use std::collections::{HashMap, VecDeque};
+
+use atm0s_sdn_identity::NodeId;
+use serde::{Deserialize, Serialize};
+
+use crate::base::FeatureControlActor;
+
+use super::Event;
+
+pub const BULK_SEGMENT_SIZE: usize = 1000;
+/// Transfers which are larger than this are rejected
+pub const BULK_MAX_BYTES: usize = 64 * 1024 * 1024;
+pub const BULK_INIT_WINDOW: u32 = 16;
+pub const BULK_MIN_WINDOW: u32 = 4;
+pub const BULK_MAX_WINDOW: u32 = 512;
+/// Number of later acked segments after which a missing segment is considered lost
+pub const BULK_DUP_THRESH: u32 = 3;
+pub const BULK_MIN_RTO_MS: u64 = 200;
+pub const BULK_ACK_EVERY: u32 = 4;
+pub const BULK_MAX_SACKS: usize = 16;
+/// A transfer is failed (sender) or dropped (receiver) without any progress in this time
+pub const BULK_TIMEOUT_MS: u64 = 10_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum BulkMsg {
+    Seg {
+        id: u64,
+        from: NodeId,
+        port: u16,
+        total: u32,
+        seq: u32,
+        data: Vec<u8>,
+    },
+    /// Sack ranges are [start, end) of received segments after cum
+    Ack {
+        id: u64,
+        cum: u32,
+        sacks: Vec<(u32, u32)>,
+    },
+}
+
+/// Progress of a bulk transfer in the sender node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BulkProgress {
+    pub acked_bytes: u64,
+    pub total_bytes: u64,
+    /// Current window in segments
+    pub window: u32,
+    pub rtt_ms: u32,
+    pub retransmits: u32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BulkOutput<UserData> {
+    Send(NodeId, BulkMsg),
+    Event(FeatureControlActor<UserData>, Event),
+    /// Completed transfer for a port, with the sender node
+    Received(u16, NodeId, Vec<u8>),
+}
+
+#[derive(Default, Clone, Copy)]
+struct SegState {
+    sent_at: u64,
+    acked: bool,
+    retransmitted: bool,
+}
+
+struct BulkSender<UserData> {
+    actor: FeatureControlActor<UserData>,
+    user_id: u64,
+    dest: NodeId,
+    port: u16,
+    total_bytes: u64,
+    segments: Vec<Vec<u8>>,
+    states: Vec<SegState>,
+    /// All segments before cum are acked
+    cum: u32,
+    /// Next segment which is never sent
+    next: u32,
+    acked: u32,
+    in_flight: u32,
+    window: u32,
+    srtt_ms: Option<u64>,
+    retransmits: u32,
+    last_loss_at: Option<u64>,
+    last_active: u64,
+    reported: u32,
+}
+
+impl<UserData: Copy> BulkSender<UserData> {
+    fn total(&self) -> u32 {
+        self.segments.len() as u32
+    }
+
+    fn progress(&self) -> BulkProgress {
+        let acked_bytes = self.states.iter().zip(self.segments.iter()).filter(|(s, _)| s.acked).map(|(_, d)| d.len() as u64).sum();
+        BulkProgress {
+            acked_bytes,
+            total_bytes: self.total_bytes,
+            window: self.window,
+            rtt_ms: self.srtt_ms.unwrap_or(0) as u32,
+            retransmits: self.retransmits,
+        }
+    }
+
+    fn rto(&self) -> u64 {
+        self.srtt_ms.map(|rtt| (rtt * 2).max(BULK_MIN_RTO_MS)).unwrap_or(1000)
+    }
+
+    fn seg_msg(&self, id: u64, from: NodeId, seq: u32) -> BulkMsg {
+        BulkMsg::Seg {
+            id,
+            from,
+            port: self.port,
+            total: self.total(),
+            seq,
+            data: self.segments[seq as usize].clone(),
+        }
+    }
+
+    /// Halve the window at most once per round trip
+    fn on_loss(&mut self, now: u64) {
+        if self.last_loss_at.map(|at| now >= at + self.srtt_ms.unwrap_or(0)).unwrap_or(true) {
+            self.window = (self.window / 2).max(BULK_MIN_WINDOW);
+            self.last_loss_at = Some(now);
+            log::debug!("[BulkSender] transfer {} to {} lost segment, window {}", self.user_id, self.dest, self.window);
+        }
+    }
+
+    fn resend(&mut self, now: u64, id: u64, from: NodeId, seq: u32, queue: &mut VecDeque<BulkOutput<UserData>>) {
+        let state = &mut self.states[seq as usize];
+        state.sent_at = now;
+        state.retransmitted = true;
+        self.retransmits += 1;
+        queue.push_back(BulkOutput::Send(self.dest, self.seg_msg(id, from, seq)));
+    }
+
+    fn pump(&mut self, now: u64, id: u64, from: NodeId, queue: &mut VecDeque<BulkOutput<UserData>>) {
+        while self.in_flight < self.window && self.next < self.total() {
+            let seq = self.next;
+            self.states[seq as usize].sent_at = now;
+            self.next += 1;
+            self.in_flight += 1;
+            queue.push_back(BulkOutput::Send(self.dest, self.seg_msg(id, from, seq)));
+        }
+    }
+
+    fn mark_acked(&mut self, now: u64, seq: u32) -> bool {
+        let state = &mut self.states[seq as usize];
+        if state.acked {
+            return false;
+        }
+        state.acked = true;
+        self.acked += 1;
+        self.in_flight -= 1;
+        if !state.retransmitted {
+            let sample = now.saturating_sub(state.sent_at);
+            self.srtt_ms = Some(self.srtt_ms.map(|rtt| (rtt * 7 + sample) / 8).unwrap_or(sample));
+        }
+        true
+    }
+
+    fn on_ack(&mut self, now: u64, id: u64, from: NodeId, cum: u32, sacks: Vec<(u32, u32)>, queue: &mut VecDeque<BulkOutput<UserData>>) {
+        let mut newly = 0;
+        for seq in self.cum..cum.min(self.next) {
+            newly += self.mark_acked(now, seq) as u32;
+        }
+        for (start, end) in sacks {
+            for seq in start.max(self.cum)..end.min(self.next) {
+                newly += self.mark_acked(now, seq) as u32;
+            }
+        }
+        while self.cum < self.next && self.states[self.cum as usize].acked {
+            self.cum += 1;
+        }
+        if newly > 0 {
+            self.last_active = now;
+            self.window = (self.window + newly).min(BULK_MAX_WINDOW);
+        }
+
+        let guard = self.srtt_ms.unwrap_or(0);
+        let mut acked_after = 0;
+        let mut lost = vec![];
+        for seq in (self.cum..self.next).rev() {
+            let state = self.states[seq as usize];
+            if state.acked {
+                acked_after += 1;
+            } else if acked_after >= BULK_DUP_THRESH && now >= state.sent_at + guard {
+                lost.push(seq);
+            }
+        }
+        if !lost.is_empty() {
+            self.on_loss(now);
+        }
+        for seq in lost.into_iter().rev() {
+            log::debug!("[BulkSender] transfer {} fast retransmit segment {seq}", self.user_id);
+            self.resend(now, id, from, seq, queue);
+        }
+        self.pump(now, id, from, queue);
+    }
+
+    fn on_tick(&mut self, now: u64, id: u64, from: NodeId, queue: &mut VecDeque<BulkOutput<UserData>>) {
+        let rto = self.rto();
+        let timeout = (self.cum..self.next)
+            .filter(|seq| !self.states[*seq as usize].acked && now >= self.states[*seq as usize].sent_at + rto)
+            .collect::<Vec<_>>();
+        if !timeout.is_empty() {
+            log::debug!("[BulkSender] transfer {} resend {} segments after rto {rto} ms", self.user_id, timeout.len());
+            self.on_loss(now);
+        }
+        for seq in timeout {
+            self.resend(now, id, from, seq, queue);
+        }
+        if self.reported != self.acked {
+            self.reported = self.acked;
+            queue.push_back(BulkOutput::Event(self.actor, Event::BulkProgress(self.user_id, self.progress())));
+        }
+    }
+}
+
+struct BulkReceiver {
+    port: u16,
+    segments: Vec<Option<Vec<u8>>>,
+    received: u32,
+    cum: u32,
+    highest: u32,
+    unacked: u32,
+    last_active: u64,
+}
+
+impl BulkReceiver {
+    fn ack(&mut self, id: u64) -> BulkMsg {
+        self.unacked = 0;
+        let mut sacks = vec![];
+        let mut start = None;
+        for seq in self.cum..=self.highest {
+            match (self.segments[seq as usize].is_some(), start) {
+                (true, None) => start = Some(seq),
+                (false, Some(s)) => {
+                    sacks.push((s, seq));
+                    start = None;
+                    if sacks.len() == BULK_MAX_SACKS {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Some(s) = start.filter(|_| sacks.len() < BULK_MAX_SACKS) {
+            sacks.push((s, self.highest + 1));
+        }
+        BulkMsg::Ack { id, cum: self.cum, sacks }
+    }
+}
+
+pub struct BulkTransfers<UserData> {
+    senders: HashMap<u64, BulkSender<UserData>>,
+    receivers: HashMap<(NodeId, u64), BulkReceiver>,
+    /// Completed transfers with total segments and completed time
+    completed: HashMap<(NodeId, u64), (u32, u64)>,
+    seq: u64,
+    queue: VecDeque<BulkOutput<UserData>>,
+}
+
+impl<UserData> Default for BulkTransfers<UserData> {
+    fn default() -> Self {
+        Self {
+            senders: HashMap::new(),
+            receivers: HashMap::new(),
+            completed: HashMap::new(),
+            seq: 0,
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+impl<UserData: Copy> BulkTransfers<UserData> {
+    /// Start a transfer, the wire id is derived from the node session, so a restarted node doesn't reuse ids of its previous transfers
+    #[allow(clippy::too_many_arguments)]
+    pub fn send(&mut self, now: u64, node_id: NodeId, session: u64, actor: FeatureControlActor<UserData>, user_id: u64, port: u16, dest: NodeId, data: Vec<u8>) {
+        if data.len() > BULK_MAX_BYTES {
+            log::warn!("[BulkTransfers] reject transfer {user_id} to {dest} with size {} larger than {BULK_MAX_BYTES}", data.len());
+            self.queue.push_back(BulkOutput::Event(actor, Event::BulkFailed(user_id, BulkProgress::default())));
+            return;
+        }
+        let id = session.wrapping_add(self.seq);
+        self.seq += 1;
+        let mut segments = data.chunks(BULK_SEGMENT_SIZE).map(|c| c.to_vec()).collect::<Vec<_>>();
+        if segments.is_empty() {
+            segments.push(vec![]);
+        }
+        log::info!(
+            "[BulkTransfers] start transfer {user_id} (wire {id}) to {dest}:{port} with {} bytes in {} segments",
+            data.len(),
+            segments.len()
+        );
+        let mut sender = BulkSender {
+            actor,
+            user_id,
+            dest,
+            port,
+            total_bytes: data.len() as u64,
+            states: vec![SegState::default(); segments.len()],
+            segments,
+            cum: 0,
+            next: 0,
+            acked: 0,
+            in_flight: 0,
+            window: BULK_INIT_WINDOW,
+            srtt_ms: None,
+            retransmits: 0,
+            last_loss_at: None,
+            last_active: now,
+            reported: 0,
+        };
+        sender.pump(now, id, node_id, &mut self.queue);
+        self.senders.insert(id, sender);
+    }
+
+    pub fn on_msg(&mut self, now: u64, node_id: NodeId, msg: BulkMsg) {
+        match msg {
+            BulkMsg::Seg { id, from, port, total, seq, data } => self.on_seg(now, id, from, port, total, seq, data),
+            BulkMsg::Ack { id, cum, sacks } => {
+                let Some(sender) = self.senders.get_mut(&id) else {
+                    log::debug!("[BulkTransfers] ack for unknown transfer {id}");
+                    return;
+                };
+                sender.on_ack(now, id, node_id, cum, sacks, &mut self.queue);
+                if sender.acked == sender.total() {
+                    let sender = self.senders.remove(&id).expect("Should have sender");
+                    log::info!("[BulkTransfers] transfer {} to {} done, retransmits {}", sender.user_id, sender.dest, sender.retransmits);
+                    self.queue.push_back(BulkOutput::Event(sender.actor, Event::BulkSent(sender.user_id, sender.progress())));
+                }
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn on_seg(&mut self, now: u64, id: u64, from: NodeId, port: u16, total: u32, seq: u32, data: Vec<u8>) {
+        let key = (from, id);
+        if let Some((total, _)) = self.completed.get(&key) {
+            self.queue.push_back(BulkOutput::Send(from, BulkMsg::Ack { id, cum: *total, sacks: vec![] }));
+            return;
+        }
+        if total == 0 || total as usize > BULK_MAX_BYTES.div_ceil(BULK_SEGMENT_SIZE) || seq >= total {
+            log::warn!("[BulkTransfers] drop invalid segment {seq}/{total} of transfer {id} from {from}");
+            return;
+        }
+        let receiver = self.receivers.entry(key).or_insert_with(|| BulkReceiver {
+            port,
+            segments: vec![None; total as usize],
+            received: 0,
+            cum: 0,
+            highest: 0,
+            unacked: 0,
+            last_active: now,
+        });
+        if receiver.segments.len() != total as usize {
+            log::warn!("[BulkTransfers] drop segment of transfer {id} from {from} with mismatched total {total}");
+            return;
+        }
+        receiver.last_active = now;
+        if receiver.segments[seq as usize].is_some() {
+            let ack = receiver.ack(id);
+            self.queue.push_back(BulkOutput::Send(from, ack));
+            return;
+        }
+        let in_order = seq == receiver.cum;
+        receiver.segments[seq as usize] = Some(data);
+        receiver.received += 1;
+        receiver.highest = receiver.highest.max(seq);
+        receiver.unacked += 1;
+        while receiver.cum < total && receiver.segments[receiver.cum as usize].is_some() {
+            receiver.cum += 1;
+        }
+        receiver.highest = receiver.highest.max(receiver.cum.saturating_sub(1));
+
+        if receiver.received == total {
+            let receiver = self.receivers.remove(&key).expect("Should have receiver");
+            log::info!("[BulkTransfers] received transfer {id} from {from} with {total} segments");
+            self.completed.insert(key, (total, now));
+            self.queue.push_back(BulkOutput::Send(from, BulkMsg::Ack { id, cum: total, sacks: vec![] }));
+            let data = receiver.segments.into_iter().flatten().flatten().collect::<Vec<_>>();
+            self.queue.push_back(BulkOutput::Received(receiver.port, from, data));
+        } else if !in_order || receiver.unacked >= BULK_ACK_EVERY {
+            let ack = receiver.ack(id);
+            self.queue.push_back(BulkOutput::Send(from, ack));
+        }
+    }
+
+    pub fn on_tick(&mut self, now: u64, node_id: NodeId) {
+        let mut failed = vec![];
+        for (id, sender) in self.senders.iter_mut() {
+            if now >= sender.last_active + BULK_TIMEOUT_MS {
+                failed.push(*id);
+            } else {
+                sender.on_tick(now, *id, node_id, &mut self.queue);
+            }
+        }
+        for id in failed {
+            let sender = self.senders.remove(&id).expect("Should have sender");
+            log::warn!("[BulkTransfers] transfer {} to {} failed without progress in {BULK_TIMEOUT_MS} ms", sender.user_id, sender.dest);
+            self.queue.push_back(BulkOutput::Event(sender.actor, Event::BulkFailed(sender.user_id, sender.progress())));
+        }
+
+        for ((from, id), receiver) in self.receivers.iter_mut() {
+            if receiver.unacked > 0 {
+                let ack = receiver.ack(*id);
+                self.queue.push_back(BulkOutput::Send(*from, ack));
+            }
+        }
+        self.receivers.retain(|(from, id), r| {
+            let alive = now < r.last_active + BULK_TIMEOUT_MS;
+            if !alive {
+                log::warn!("[BulkTransfers] drop incomplete transfer {id} from {from} after timeout");
+            }
+            alive
+        });
+        self.completed.retain(|_, (_, at)| now < *at + BULK_TIMEOUT_MS);
+    }
+
+    pub fn pop_output(&mut self) -> Option<BulkOutput<UserData>> {
+        self.queue.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{base::FeatureControlActor, features::data::Event};
+
+    use super::{BulkMsg, BulkOutput, BulkTransfers, BULK_INIT_WINDOW, BULK_SEGMENT_SIZE, BULK_TIMEOUT_MS};
+
+    fn seg(seq: u32, total: u32, data: Vec<u8>) -> BulkMsg {
+        BulkMsg::Seg {
+            id: 100,
+            from: 1,
+            port: 10,
+            total,
+            seq,
+            data,
+        }
+    }
+
+    #[test]
+    fn sender_pipeline_window() {
+        let mut bulk = BulkTransfers::default();
+        let actor = FeatureControlActor::Controller(());
+        let total = BULK_INIT_WINDOW as usize * 2;
+        bulk.send(0, 1, 100, actor, 1, 10, 2, vec![1; BULK_SEGMENT_SIZE * total]);
+        for seq in 0..BULK_INIT_WINDOW {
+            assert_eq!(bulk.pop_output(), Some(BulkOutput::Send(2, seg(seq, total as u32, vec![1; BULK_SEGMENT_SIZE]))));
+        }
+        assert_eq!(bulk.pop_output(), None);
+
+        //each acked segment opens two more slots
+        bulk.on_msg(10, 1, BulkMsg::Ack { id: 100, cum: 1, sacks: vec![] });
+        assert_eq!(bulk.pop_output(), Some(BulkOutput::Send(2, seg(BULK_INIT_WINDOW, total as u32, vec![1; BULK_SEGMENT_SIZE]))));
+        assert_eq!(bulk.pop_output(), Some(BulkOutput::Send(2, seg(BULK_INIT_WINDOW + 1, total as u32, vec![1; BULK_SEGMENT_SIZE]))));
+        assert_eq!(bulk.pop_output(), None);
+
+        bulk.on_tick(20, 1);
+        match bulk.pop_output() {
+            Some(BulkOutput::Event(_, Event::BulkProgress(1, progress))) => {
+                assert_eq!(progress.acked_bytes, BULK_SEGMENT_SIZE as u64);
+                assert_eq!(progress.window, BULK_INIT_WINDOW + 1);
+                assert_eq!(progress.rtt_ms, 10);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sender_fast_retransmit_with_sacks() {
+        let mut bulk = BulkTransfers::default();
+        let actor = FeatureControlActor::Controller(());
+        bulk.send(0, 1, 100, actor, 1, 10, 2, vec![1; BULK_SEGMENT_SIZE * 5]);
+        while bulk.pop_output().is_some() {}
+
+        //segment 1 is lost, 2..5 are received
+        bulk.on_msg(10, 1, BulkMsg::Ack { id: 100, cum: 1, sacks: vec![(2, 5)] });
+        assert_eq!(bulk.pop_output(), Some(BulkOutput::Send(2, seg(1, 5, vec![1; BULK_SEGMENT_SIZE]))));
+        assert_eq!(bulk.pop_output(), None);
+
+        bulk.on_msg(20, 1, BulkMsg::Ack { id: 100, cum: 5, sacks: vec![] });
+        match bulk.pop_output() {
+            Some(BulkOutput::Event(_, Event::BulkSent(1, progress))) => {
+                assert_eq!(progress.acked_bytes, BULK_SEGMENT_SIZE as u64 * 5);
+                assert_eq!(progress.retransmits, 1);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sender_fail_after_timeout() {
+        let mut bulk = BulkTransfers::default();
+        let actor = FeatureControlActor::Controller(());
+        bulk.send(0, 1, 100, actor, 1, 10, 2, vec![1; 10]);
+        assert_eq!(bulk.pop_output(), Some(BulkOutput::Send(2, seg(0, 1, vec![1; 10]))));
+
+        //resent after rto
+        bulk.on_tick(1000, 1);
+        assert_eq!(bulk.pop_output(), Some(BulkOutput::Send(2, seg(0, 1, vec![1; 10]))));
+        bulk.on_tick(BULK_TIMEOUT_MS, 1);
+        assert!(matches!(bulk.pop_output(), Some(BulkOutput::Event(_, Event::BulkFailed(1, _)))));
+    }
+
+    #[test]
+    fn receiver_sack_and_reassemble() {
+        let mut bulk = BulkTransfers::<()>::default();
+        bulk.on_msg(0, 2, seg(0, 4, vec![1]));
+        assert_eq!(bulk.pop_output(), None);
+        //out of order segment is acked immediately with sack
+        bulk.on_msg(0, 2, seg(2, 4, vec![3]));
+        assert_eq!(bulk.pop_output(), Some(BulkOutput::Send(1, BulkMsg::Ack { id: 100, cum: 1, sacks: vec![(2, 3)] })));
+        //duplicated segment is acked again
+        bulk.on_msg(0, 2, seg(2, 4, vec![3]));
+        assert_eq!(bulk.pop_output(), Some(BulkOutput::Send(1, BulkMsg::Ack { id: 100, cum: 1, sacks: vec![(2, 3)] })));
+        bulk.on_msg(0, 2, seg(1, 4, vec![2]));
+        assert_eq!(bulk.pop_output(), None);
+        bulk.on_tick(100, 2);
+        assert_eq!(bulk.pop_output(), Some(BulkOutput::Send(1, BulkMsg::Ack { id: 100, cum: 3, sacks: vec![] })));
+
+        bulk.on_msg(200, 2, seg(3, 4, vec![4]));
+        assert_eq!(bulk.pop_output(), Some(BulkOutput::Send(1, BulkMsg::Ack { id: 100, cum: 4, sacks: vec![] })));
+        assert_eq!(bulk.pop_output(), Some(BulkOutput::Received(10, 1, vec![1, 2, 3, 4])));
+
+        //completed transfer answers retransmitted segments
+        bulk.on_msg(300, 2, seg(3, 4, vec![4]));
+        assert_eq!(bulk.pop_output(), Some(BulkOutput::Send(1, BulkMsg::Ack { id: 100, cum: 4, sacks: vec![] })));
+        assert_eq!(bulk.pop_output(), None);
+    }
+}
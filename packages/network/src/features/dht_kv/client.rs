@@ -12,7 +12,7 @@ const MAP_GET_TIMEOUT_MS: u64 = 5000;
 
 use super::{
     msg::{ClientCommand, NodeSession, ServerEvent},
-    Control, Event, Map,
+    Control, Event, GetError, Map,
 };
 
 mod map;
@@ -109,6 +109,11 @@ impl<UserData: Eq + Debug + Copy> LocalStorage<UserData> {
                     self.queue.push_back(LocalStorageOutput::Local(actor, Event::MapGetRes(key, Ok(res))));
                 }
             }
+            ServerEvent::GetDenied(key, req_id) => {
+                if let Some((actor, _time_ms)) = self.map_get_waits.remove(&(key, req_id)) {
+                    self.queue.push_back(LocalStorageOutput::Local(actor, Event::MapGetRes(key, Err(GetError::Denied))));
+                }
+            }
         }
     }
 
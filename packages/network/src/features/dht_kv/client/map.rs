@@ -6,8 +6,8 @@ use std::{
 use crate::{
     base::FeatureControlActor,
     features::dht_kv::{
-        msg::{ClientMapCommand, NodeSession, ServerMapEvent, Version},
-        Key, MapControl, MapEvent,
+        msg::{BatchOp, ClientMapCommand, MapAcl, NodeSession, ServerMapEvent, Version},
+        DeniedOp, Key, KeyRange, MapControl, MapEvent,
     },
 };
 
@@ -31,6 +31,7 @@ enum MapSlot {
         version: Version,
         syncing: bool,
         last_sync: u64,
+        expires_at: Option<u64>,
     },
 }
 
@@ -48,33 +49,48 @@ impl MapSlot {
     }
 
     /// This method is called when a new value is set to the map, this will overwrite the old value even if it's not synced or from remote.
-    pub fn set(&mut self, now: u64, new_data: Vec<u8>) -> Option<ClientMapCommand> {
+    /// With ttl_ms the value is expired after that time, each set refreshes the ttl.
+    pub fn set(&mut self, now: u64, new_data: Vec<u8>, ttl_ms: Option<u64>) -> Option<ClientMapCommand> {
+        let key = match self {
+            MapSlot::Unspecific { key } | MapSlot::Remote { key, .. } | MapSlot::Local { key, .. } => *key,
+        };
+        let version = Version(now); //TODO use real version
+        let expires_at = ttl_ms.map(|ttl| now + ttl);
+        *self = MapSlot::Local {
+            key,
+            value: Some(new_data.clone()),
+            version,
+            syncing: true,
+            last_sync: now,
+            expires_at,
+        };
+        Some(Self::set_cmd(now, key, version, new_data, expires_at))
+    }
+
+    fn set_cmd(now: u64, key: Key, version: Version, data: Vec<u8>, expires_at: Option<u64>) -> ClientMapCommand {
+        match expires_at {
+            Some(expires_at) => ClientMapCommand::SetTtl(key, version, data, expires_at.saturating_sub(now)),
+            None => ClientMapCommand::Set(key, version, data),
+        }
+    }
+
+    /// Local value with ttl is expired, after that it is not synced anymore.
+    /// The relay expires it by itself, so we don't need to send Del
+    pub fn expire(&mut self, now: u64) -> bool {
         match self {
-            MapSlot::Unspecific { key } | MapSlot::Remote { key, .. } => {
-                let version = Version(now); //TODO use real version
-                let key = *key;
-                *self = MapSlot::Local {
-                    key,
-                    value: Some(new_data.clone()),
-                    version,
-                    syncing: true,
-                    last_sync: now,
-                };
-                Some(ClientMapCommand::Set(key, version, new_data))
-            }
             MapSlot::Local {
-                key,
-                value,
-                version,
+                value: value @ Some(_),
+                expires_at: Some(expires_at),
                 syncing,
                 last_sync,
-            } => {
-                *value = Some(new_data.clone());
-                *version = Version(now); //TODO use real version
-                *syncing = true;
+                ..
+            } if now >= *expires_at => {
+                *value = None;
+                *syncing = false;
                 *last_sync = now;
-                Some(ClientMapCommand::Set(*key, *version, new_data))
+                true
             }
+            _ => false,
         }
     }
 
@@ -108,11 +124,12 @@ impl MapSlot {
                 version,
                 syncing,
                 last_sync,
+                expires_at,
             } => {
                 if (*syncing && now >= *last_sync + RESEND_MS) || now >= *last_sync + SYNC_MS || force {
                     *last_sync = now;
                     if let Some(value) = value {
-                        Some(ClientMapCommand::Set(*key, *version, value.clone()))
+                        Some(Self::set_cmd(now, *key, *version, value.clone(), *expires_at))
                     } else {
                         Some(ClientMapCommand::Del(*key, *version))
                     }
@@ -123,6 +140,31 @@ impl MapSlot {
         }
     }
 
+    /// Last command is carried by a batch, so the slot doesn't resend it by itself
+    pub fn batched(&mut self) {
+        if let MapSlot::Local { syncing, .. } = self {
+            *syncing = false;
+        }
+    }
+
+    /// Current local state as a batch op, only if it still has the batched version
+    pub fn batch_op(&self, batch_version: Version) -> Option<BatchOp> {
+        match self {
+            MapSlot::Local { key, value, version, .. } if *version == batch_version => match value {
+                Some(value) => Some(BatchOp::Set(*key, *version, value.clone())),
+                None => Some(BatchOp::Del(*key, *version)),
+            },
+            _ => None,
+        }
+    }
+
+    pub fn local_version(&self) -> Option<Version> {
+        match self {
+            MapSlot::Local { version, .. } => Some(*version),
+            _ => None,
+        }
+    }
+
     pub fn set_ok(&mut self, version: Version) {
         match self {
             MapSlot::Unspecific { .. } | MapSlot::Remote { .. } => {}
@@ -215,14 +257,35 @@ enum SubState {
     Unsubscribing { id: u64, started_at: u64, remote: Option<NodeSession>, sent_ts: u64 },
 }
 
+/// Batch which is waiting for BatchOk, ops are rebuilt from current slots when resending
+struct PendingBatch {
+    keys: Vec<(Key, Version)>,
+    last_sent: u64,
+}
+
+/// Acl which this node owns, it is resent in SYNC_MS for restoring it in a new relay
+struct AclState<UserData> {
+    id: u64,
+    acl: MapAcl,
+    actor: FeatureControlActor<UserData>,
+    acked: bool,
+    last_sent: u64,
+}
+
 /// LocalMap manage state of map, which is a collection of MapSlot.
 /// We allow multi-source for a single sub-key with reason for solving conflict between nodes.
 pub struct LocalMap<UserData> {
     session: NodeSession,
     slots: HashMap<(Key, NodeSession), MapSlot>,
-    subscribers: Vec<FeatureControlActor<UserData>>,
+    /// Each subscriber with its key filter, the relay sends union of them
+    subscribers: Vec<(FeatureControlActor<UserData>, Option<KeyRange>)>,
     sub_state: SubState,
     queue: VecDeque<LocalMapOutput<UserData>>,
+    batch_seq: u64,
+    batches: HashMap<u64, PendingBatch>,
+    /// Last actor which changed each local key, it receives denied event from acl
+    writers: HashMap<Key, FeatureControlActor<UserData>>,
+    acl: Option<AclState<UserData>>,
 }
 
 impl<UserData: Eq + Copy + Debug> LocalMap<UserData> {
@@ -233,23 +296,28 @@ impl<UserData: Eq + Copy + Debug> LocalMap<UserData> {
             subscribers: Vec::new(),
             sub_state: SubState::NotSub,
             queue: VecDeque::new(),
+            batch_seq: 0,
+            batches: HashMap::new(),
+            writers: HashMap::new(),
+            acl: None,
         }
     }
 
     pub fn on_tick(&mut self, now: u64) {
+        let filter = self.relay_filter();
         match &mut self.sub_state {
             SubState::NotSub => {}
             SubState::Subscribing { id, sent_ts } => {
                 if now >= *sent_ts + RESEND_MS {
                     log::debug!("[ClientMap] Resend sub command in Subscribing state after {RESEND_MS} ms");
-                    self.queue.push_back(LocalMapOutput::Remote(ClientMapCommand::Sub(*id, None)));
+                    self.queue.push_back(LocalMapOutput::Remote(Self::sub_cmd(*id, None, filter)));
                     *sent_ts = now;
                 }
             }
             SubState::Subscribed { id, remote, sync_ts } => {
                 if now >= *sync_ts + SYNC_MS {
                     log::debug!("[ClientMap] Resend sub command in Subscribed state after {SYNC_MS} ms");
-                    self.queue.push_back(LocalMapOutput::Remote(ClientMapCommand::Sub(*id, Some(*remote))));
+                    self.queue.push_back(LocalMapOutput::Remote(Self::sub_cmd(*id, Some(*remote), filter)));
                     *sync_ts = now;
                 }
             }
@@ -265,12 +333,32 @@ impl<UserData: Eq + Copy + Debug> LocalMap<UserData> {
             }
         }
 
+        let mut expired = vec![];
+        for ((key, _), slot) in self.slots.iter_mut() {
+            if slot.expire(now) {
+                log::debug!("[ClientMap] Local key {} expired", key);
+                expired.push(*key);
+            }
+        }
+        for key in expired {
+            self.fire_event(MapEvent::OnExpired(key, self.session.0));
+        }
+
         self.sync_slots(now, false);
+        self.resend_batches(now);
+        if let Some(acl) = &mut self.acl {
+            if (!acl.acked && now >= acl.last_sent + RESEND_MS) || now >= acl.last_sent + SYNC_MS {
+                log::debug!("[ClientMap] Resend acl {}, acked {}", acl.id, acl.acked);
+                acl.last_sent = now;
+                self.queue.push_back(LocalMapOutput::Remote(ClientMapCommand::SetAcl(acl.id, acl.acl.clone())));
+            }
+        }
 
-        // remove all empty slots
+        // remove all empty slots, deleted slots are kept until their batch is acked
         let mut to_remove = vec![];
         for (key, slot) in self.slots.iter_mut() {
-            if slot.should_cleanup() {
+            let in_batch = key.1 == self.session && self.batches.values().any(|batch| batch.keys.iter().any(|(k, _)| *k == key.0));
+            if slot.should_cleanup() && !in_batch {
                 log::info!("[ClientMap] Remove empty slot for key {}", key.0);
                 to_remove.push(*key);
             }
@@ -279,20 +367,47 @@ impl<UserData: Eq + Copy + Debug> LocalMap<UserData> {
         for key in to_remove {
             self.slots.remove(&key);
         }
+        let session = self.session;
+        let slots = &self.slots;
+        self.writers.retain(|key, _| slots.contains_key(&(*key, session)));
     }
 
     pub fn on_control(&mut self, now: u64, actor: FeatureControlActor<UserData>, control: MapControl) -> Option<ClientMapCommand> {
+        match &control {
+            MapControl::Set(key, _) | MapControl::SetWithTtl(key, _, _) | MapControl::Del(key) => {
+                self.writers.insert(*key, actor);
+            }
+            MapControl::BatchSet(items) => self.writers.extend(items.iter().map(|(key, _)| (*key, actor))),
+            MapControl::BatchDel(keys) => self.writers.extend(keys.iter().map(|key| (*key, actor))),
+            _ => {}
+        }
         match control {
-            MapControl::Set(key, data) => {
-                let slot = self.get_slot(key, self.session, true).expect("Must have slot for set");
-                if let Some(out) = slot.set(now, data.clone()) {
-                    log::debug!("[ClientMap] Set key {} with data len {}", key, data.len());
-                    self.fire_event(MapEvent::OnSet(key, self.session.0, data));
-                    Some(out)
-                } else {
-                    log::warn!("[ClientMap] Set key {} failed", key);
-                    None
+            MapControl::Set(key, data) => self.on_set(now, key, data, None),
+            MapControl::SetWithTtl(key, data, ttl) => self.on_set(now, key, data, Some(ttl.as_millis() as u64)),
+            MapControl::BatchSet(items) => {
+                let mut keys = vec![];
+                for (key, data) in items {
+                    let slot = self.get_slot(key, self.session, true).expect("Must have slot for set");
+                    if slot.set(now, data.clone(), None).is_some() {
+                        slot.batched();
+                        keys.push(key);
+                        self.fire_event(MapEvent::OnSet(key, self.session.0, data));
+                    }
+                }
+                self.send_batch(now, keys)
+            }
+            MapControl::BatchDel(keys) => {
+                let mut deleted = vec![];
+                for key in keys {
+                    if let Some(slot) = self.get_slot(key, self.session, false) {
+                        if slot.del(now).is_some() {
+                            slot.batched();
+                            deleted.push(key);
+                            self.fire_event(MapEvent::OnDel(key, self.session.0));
+                        }
+                    }
                 }
+                self.send_batch(now, deleted)
             }
             MapControl::Del(key) => {
                 let slot = self.get_slot(key, self.session, false)?;
@@ -305,35 +420,30 @@ impl<UserData: Eq + Copy + Debug> LocalMap<UserData> {
                     None
                 }
             }
-            MapControl::Sub => {
-                let send_sub = self.subscribers.is_empty();
-                if self.subscribers.contains(&actor) {
-                    log::warn!("[ClientMap] Actor {:?} already subscribed, Sub failed", actor);
-                    return None;
-                }
-
-                log::debug!("[ClientMap] Actor {:?} subscribe", actor);
-                self.subscribers.push(actor);
-                if send_sub {
-                    log::debug!("[ClientMap] Send sub command");
-                    self.sub_state = SubState::Subscribing { sent_ts: now, id: now };
-
-                    //We need to send all current local data to the new subscriber, because RELAY will not send it to source node.
-                    self.restore_events(actor, true);
-
-                    Some(ClientMapCommand::Sub(now, None))
-                } else {
-                    self.restore_events(actor, false);
-                    None
-                }
+            MapControl::SetAcl(acl) => {
+                log::debug!("[ClientMap] Actor {:?} set acl {:?}", actor, acl);
+                self.acl = Some(AclState {
+                    id: now,
+                    acl: acl.clone(),
+                    actor,
+                    acked: false,
+                    last_sent: now,
+                });
+                Some(ClientMapCommand::SetAcl(now, acl))
             }
+            MapControl::Sub => self.on_sub(now, actor, None),
+            MapControl::SubFiltered(range) => self.on_sub(now, actor, Some(range)),
             MapControl::Unsub => {
-                if !self.subscribers.contains(&actor) || self.subscribers.is_empty() {
+                if !self.is_subscriber(actor) {
                     log::warn!("[ClientMap] Actor {:?} not subscribed, Unsub failed", actor);
                     return None;
                 }
-                self.subscribers.retain(|&x| x != actor);
+                let old_filter = self.relay_filter();
+                self.subscribers.retain(|(sub, _)| *sub != actor);
                 if self.subscribers.is_empty() {
+                    // remote slots are only cached for subscribers, after unsub we will not receive OnDel for them anymore
+                    let session = self.session;
+                    self.slots.retain(|(_, source), _| *source == session);
                     match &self.sub_state {
                         SubState::Subscribed { id, remote, .. } => {
                             log::debug!("[ClientMap] Send unsub command, switch to Unsubscribing state from Subscribed");
@@ -361,7 +471,7 @@ impl<UserData: Eq + Copy + Debug> LocalMap<UserData> {
                     }
                 } else {
                     log::debug!("[ClientMap] Actor {:?} unsubscribed, after that remain {} actors", actor, self.subscribers.len());
-                    None
+                    self.update_relay_filter(old_filter)
                 }
             }
         }
@@ -383,6 +493,62 @@ impl<UserData: Eq + Copy + Debug> LocalMap<UserData> {
                 slot.del_ok(version);
                 None
             }
+            ServerMapEvent::BatchOk(id) => {
+                if self.batches.remove(&id).is_some() {
+                    log::debug!("[ClientMap] BatchOk for batch {}", id);
+                } else {
+                    log::debug!("[ClientMap] BatchOk for unknown or already acked batch {}", id);
+                }
+                None
+            }
+            ServerMapEvent::AclOk(id) => {
+                let acl = self.acl.as_mut().filter(|acl| acl.id == id)?;
+                if !acl.acked {
+                    log::info!("[ClientMap] Acl {} applied by relay {}", id, remote.0);
+                    acl.acked = true;
+                    self.queue.push_back(LocalMapOutput::Local(acl.actor, MapEvent::OnAclApplied));
+                }
+                if acl.acl.is_open() {
+                    // released map doesn't need to be resynced
+                    self.acl = None;
+                }
+                None
+            }
+            ServerMapEvent::AclDenied(id) => {
+                let acl = self.acl.take_if(|acl| acl.id == id)?;
+                log::warn!("[ClientMap] Acl {} denied by relay {}, map is owned by other node", id, remote.0);
+                self.queue.push_back(LocalMapOutput::Local(acl.actor, MapEvent::OnDenied(DeniedOp::Acl)));
+                None
+            }
+            ServerMapEvent::SetDenied(key) => {
+                if matches!(self.slots.get(&(key, self.session)), Some(MapSlot::Local { .. })) {
+                    self.deny_set(key);
+                }
+                None
+            }
+            ServerMapEvent::BatchDenied(id) => {
+                let batch = self.batches.remove(&id)?;
+                for (key, _) in batch.keys {
+                    self.deny_set(key);
+                }
+                None
+            }
+            ServerMapEvent::SubDenied(id) => {
+                match &self.sub_state {
+                    SubState::Subscribing { id: sub_id, .. } | SubState::Subscribed { id: sub_id, .. } if *sub_id == id => {
+                        log::warn!("[ClientMap] Sub {} denied by relay {}, remove all subscribers", id, remote.0);
+                        self.fire_event(MapEvent::OnDenied(DeniedOp::Sub));
+                        self.subscribers.clear();
+                        self.sub_state = SubState::NotSub;
+                        let session = self.session;
+                        self.slots.retain(|(_, source), _| *source == session);
+                    }
+                    _ => {
+                        log::debug!("[ClientMap] Received SubDenied with id {} but not in matched sub state", id);
+                    }
+                }
+                None
+            }
             ServerMapEvent::SubOk(id) => {
                 match &mut self.sub_state {
                     SubState::Subscribing { id: sub_id, .. } => {
@@ -469,15 +635,160 @@ impl<UserData: Eq + Copy + Debug> LocalMap<UserData> {
                 self.fire_event(MapEvent::OnDel(key, source.0));
                 Some(event)
             }
+            ServerMapEvent::OnExpired { key, version, source } => {
+                if !self.accept_event(remote) {
+                    log::warn!("[ClientMap] Received OnExpired {key} but state or remote is not correct");
+                    return None;
+                }
+
+                let slot = self.get_slot(key, source, true).expect("Must have slot for set");
+                let event = slot.on_del(now, key, source, version)?;
+                log::debug!("[ClientMap] Received OnExpired for key {}", key);
+                self.fire_event(MapEvent::OnExpired(key, source.0));
+                Some(event)
+            }
+        }
+    }
+
+    fn on_sub(&mut self, now: u64, actor: FeatureControlActor<UserData>, filter: Option<KeyRange>) -> Option<ClientMapCommand> {
+        if self.is_subscriber(actor) {
+            log::warn!("[ClientMap] Actor {:?} already subscribed, Sub failed", actor);
+            return None;
+        }
+
+        log::debug!("[ClientMap] Actor {:?} subscribe with filter {:?}", actor, filter);
+        let send_sub = self.subscribers.is_empty();
+        let old_filter = self.relay_filter();
+        self.subscribers.push((actor, filter));
+        if send_sub {
+            log::debug!("[ClientMap] Send sub command");
+            self.sub_state = SubState::Subscribing { sent_ts: now, id: now };
+
+            //We need to send all current local data to the new subscriber, because RELAY will not send it to source node.
+            self.restore_events(actor, true);
+
+            Some(Self::sub_cmd(now, None, self.relay_filter()))
+        } else {
+            self.restore_events(actor, false);
+            self.update_relay_filter(old_filter)
+        }
+    }
+
+    fn is_subscriber(&self, actor: FeatureControlActor<UserData>) -> bool {
+        self.subscribers.iter().any(|(sub, _)| *sub == actor)
+    }
+
+    /// Union of subscriber filters, None if any subscriber wants all keys
+    fn relay_filter(&self) -> Option<Vec<KeyRange>> {
+        let mut ranges = vec![];
+        for (_, filter) in self.subscribers.iter() {
+            let range = (*filter)?;
+            if !ranges.contains(&range) {
+                ranges.push(range);
+            }
+        }
+        Some(ranges)
+    }
+
+    fn sub_cmd(id: u64, locked: Option<NodeSession>, filter: Option<Vec<KeyRange>>) -> ClientMapCommand {
+        match filter {
+            Some(ranges) => ClientMapCommand::SubFiltered(id, locked, ranges),
+            None => ClientMapCommand::Sub(id, locked),
+        }
+    }
+
+    /// Send the new filter to relay after subscribers changed, cached remote slots outside of it are dropped
+    /// because the relay will not send OnDel for them anymore
+    fn update_relay_filter(&mut self, old_filter: Option<Vec<KeyRange>>) -> Option<ClientMapCommand> {
+        let filter = self.relay_filter();
+        if filter == old_filter {
+            return None;
+        }
+        if let Some(ranges) = &filter {
+            let session = self.session;
+            self.slots.retain(|(key, source), _| *source == session || ranges.iter().any(|range| range.contains(*key)));
+        }
+        log::debug!("[ClientMap] Relay filter changed to {:?}", filter);
+        match &self.sub_state {
+            SubState::Subscribing { id, .. } => Some(Self::sub_cmd(*id, None, filter)),
+            SubState::Subscribed { id, remote, .. } => Some(Self::sub_cmd(*id, Some(*remote), filter)),
+            _ => None,
+        }
+    }
+
+    /// Local value is dropped, so it is not resent to relay. Writer and subscribers of the key are notified
+    fn deny_set(&mut self, key: Key) {
+        log::warn!("[ClientMap] Set key {} denied by acl, drop local value", key);
+        self.slots.remove(&(key, self.session));
+        let event = MapEvent::OnDenied(DeniedOp::Set(key));
+        let writer = self.writers.remove(&key);
+        if let Some(writer) = writer {
+            self.queue.push_back(LocalMapOutput::Local(writer, event.clone()));
+        }
+        for (sub, filter) in self.subscribers.iter() {
+            if Some(*sub) != writer && filter.map(|range| range.contains(key)).unwrap_or(true) {
+                self.queue.push_back(LocalMapOutput::Local(*sub, event.clone()));
+            }
+        }
+    }
+
+    fn on_set(&mut self, now: u64, key: Key, data: Vec<u8>, ttl_ms: Option<u64>) -> Option<ClientMapCommand> {
+        let slot = self.get_slot(key, self.session, true).expect("Must have slot for set");
+        if let Some(out) = slot.set(now, data.clone(), ttl_ms) {
+            log::debug!("[ClientMap] Set key {} with data len {}, ttl {:?}", key, data.len(), ttl_ms);
+            self.fire_event(MapEvent::OnSet(key, self.session.0, data));
+            Some(out)
+        } else {
+            log::warn!("[ClientMap] Set key {} failed", key);
+            None
+        }
+    }
+
+    fn send_batch(&mut self, now: u64, keys: Vec<Key>) -> Option<ClientMapCommand> {
+        if keys.is_empty() {
+            log::warn!("[ClientMap] Batch without local changes, skip sending");
+            return None;
+        }
+        let keys = keys
+            .into_iter()
+            .filter_map(|key| Some((key, self.slots.get(&(key, self.session))?.local_version()?)))
+            .collect::<Vec<_>>();
+        let id = self.batch_seq;
+        self.batch_seq += 1;
+        let ops = self.batch_ops(&keys);
+        log::debug!("[ClientMap] Send batch {} with {} ops", id, ops.len());
+        self.batches.insert(id, PendingBatch { keys, last_sent: now });
+        Some(ClientMapCommand::Batch(id, ops))
+    }
+
+    /// Resend batches which are not acked in RESEND_MS, ops which are replaced by newer local changes are dropped
+    fn resend_batches(&mut self, now: u64) {
+        let due = self.batches.iter().filter(|(_, batch)| now >= batch.last_sent + RESEND_MS).map(|(id, _)| *id).collect::<Vec<_>>();
+        for id in due {
+            let batch = self.batches.get_mut(&id).expect("Must have batch");
+            batch.last_sent = now;
+            let keys = batch.keys.clone();
+            let ops = self.batch_ops(&keys);
+            if ops.is_empty() {
+                log::debug!("[ClientMap] Drop batch {} after all keys changed", id);
+                self.batches.remove(&id);
+            } else {
+                log::debug!("[ClientMap] Resend batch {} after {RESEND_MS} ms", id);
+                self.queue.push_back(LocalMapOutput::Remote(ClientMapCommand::Batch(id, ops)));
+            }
         }
     }
 
+    fn batch_ops(&self, keys: &[(Key, Version)]) -> Vec<BatchOp> {
+        keys.iter().filter_map(|(key, version)| self.slots.get(&(*key, self.session))?.batch_op(*version)).collect()
+    }
+
     pub fn pop_action(&mut self) -> Option<LocalMapOutput<UserData>> {
         self.queue.pop_front()
     }
 
     pub fn should_cleanup(&self) -> bool {
-        self.slots.is_empty() && self.subscribers.is_empty() && matches!(self.sub_state, SubState::NotSub)
+        self.slots.is_empty() && self.subscribers.is_empty() && self.batches.is_empty() && self.acl.is_none() && matches!(self.sub_state, SubState::NotSub)
     }
 
     fn get_slot(&mut self, key: Key, source: NodeSession, auto_create: bool) -> Option<&mut MapSlot> {
@@ -497,17 +808,26 @@ impl<UserData: Eq + Copy + Debug> LocalMap<UserData> {
     }
 
     fn fire_event(&mut self, event: MapEvent) {
-        for sub in self.subscribers.iter() {
+        for (sub, filter) in self.subscribers.iter() {
+            if let (Some(range), Some(key)) = (filter, event.key()) {
+                if !range.contains(key) {
+                    continue;
+                }
+            }
             log::debug!("[ClientMap] Fire to {:?}, event {:?}", sub, event);
             self.queue.push_back(LocalMapOutput::Local(*sub, event.clone()));
         }
     }
 
     fn restore_events(&mut self, actor: FeatureControlActor<UserData>, only_local: bool) {
+        let filter = self.subscribers.iter().find(|(sub, _)| *sub == actor).and_then(|(_, filter)| *filter);
         for ((key, source), slot) in self.slots.iter() {
             if only_local && self.session != *source {
                 continue;
             }
+            if !filter.map(|range| range.contains(*key)).unwrap_or(true) {
+                continue;
+            }
             if let Some(data) = slot.data() {
                 let event = MapEvent::OnSet(*key, source.0, data.to_vec());
                 log::debug!("[ClientMap] Fire to {:?}, key: {key}, event {:?}", actor, event);
@@ -528,12 +848,14 @@ impl<UserData: Eq + Copy + Debug> LocalMap<UserData> {
 
 #[cfg(test)]
 mod test {
+    use std::time::Duration;
+
     use crate::{
         base::FeatureControlActor,
         features::dht_kv::{
             client::map::{LocalMapOutput, RESEND_MS, SYNC_MS},
-            msg::{ClientMapCommand, Key, NodeSession, ServerMapEvent, Version},
-            MapControl, MapEvent,
+            msg::{BatchOp, ClientMapCommand, Key, MapAcl, NodeSession, ServerMapEvent, Version},
+            DeniedOp, KeyRange, MapControl, MapEvent,
         },
     };
 
@@ -545,7 +867,7 @@ mod test {
         let mut slot = MapSlot::new(key);
 
         //we must output set command with new slot, with Version is now_ms
-        assert_eq!(slot.set(100, vec![1, 2, 3, 4]), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
+        assert_eq!(slot.set(100, vec![1, 2, 3, 4], None), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
 
         //we can delete the slot
         assert_eq!(slot.del(200), Some(ClientMapCommand::Del(key, Version(100))));
@@ -557,7 +879,7 @@ mod test {
         let mut slot = MapSlot::new(key);
 
         //we must output set command with new slot, with Version is now_ms
-        assert_eq!(slot.set(100, vec![1, 2, 3, 4]), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
+        assert_eq!(slot.set(100, vec![1, 2, 3, 4], None), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
 
         assert_eq!(slot.sync(101, false), None);
         assert_eq!(slot.sync(100 + RESEND_MS, false), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
@@ -569,13 +891,29 @@ mod test {
         assert_eq!(slot.sync(100 + RESEND_MS + SYNC_MS, false), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
     }
 
+    #[test]
+    fn map_slot_sync_remaining_ttl() {
+        let key = Key(1);
+        let mut slot = MapSlot::new(key);
+
+        assert_eq!(slot.set(100, vec![1, 2, 3, 4], Some(5000)), Some(ClientMapCommand::SetTtl(key, Version(100), vec![1, 2, 3, 4], 5000)));
+        slot.set_ok(Version(100));
+
+        //resync only sends remaining ttl, it doesn't refresh it
+        assert_eq!(slot.sync(100 + SYNC_MS, false), Some(ClientMapCommand::SetTtl(key, Version(100), vec![1, 2, 3, 4], 5000 - SYNC_MS)));
+        assert!(!slot.expire(5099));
+        assert!(slot.expire(5100));
+        assert_eq!(slot.sync(5100, false), None);
+        assert!(slot.should_cleanup());
+    }
+
     #[test]
     fn map_slot_sync_local_force() {
         let key = Key(1);
         let mut slot = MapSlot::new(key);
 
         //we must output set command with new slot, with Version is now_ms
-        assert_eq!(slot.set(100, vec![1, 2, 3, 4]), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
+        assert_eq!(slot.set(100, vec![1, 2, 3, 4], None), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
 
         assert_eq!(slot.sync(101, false), None);
         assert_eq!(slot.sync(101, true), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
@@ -587,7 +925,7 @@ mod test {
         let mut slot = MapSlot::new(key);
 
         //we must output set command with new slot, with Version is now_ms
-        assert_eq!(slot.set(100, vec![1, 2, 3, 4]), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
+        assert_eq!(slot.set(100, vec![1, 2, 3, 4], None), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
         slot.set_ok(Version(100));
         assert_eq!(slot.sync(100 + RESEND_MS, false), None);
 
@@ -603,7 +941,7 @@ mod test {
         let mut slot = MapSlot::new(key);
 
         //we must output set command with new slot, with Version is now_ms
-        assert_eq!(slot.set(100, vec![1, 2, 3, 4]), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
+        assert_eq!(slot.set(100, vec![1, 2, 3, 4], None), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
 
         //we missing set_ok, but we delete now
         //we must output del command with new slot, with Version is now_ms
@@ -618,7 +956,7 @@ mod test {
         let mut slot = MapSlot::new(key);
 
         //we must output set command with new slot, with Version is now_ms
-        assert_eq!(slot.set(100, vec![1, 2, 3, 4]), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
+        assert_eq!(slot.set(100, vec![1, 2, 3, 4], None), Some(ClientMapCommand::Set(key, Version(100), vec![1, 2, 3, 4])));
         slot.set_ok(Version(101));
         assert_ne!(slot.sync(100 + RESEND_MS, false), None);
 
@@ -647,7 +985,7 @@ mod test {
         let mut slot = MapSlot::new(key);
 
         let version = Version(100);
-        assert_eq!(slot.set(100, vec![1, 2, 3, 4]), Some(ClientMapCommand::Set(key, version, vec![1, 2, 3, 4])));
+        assert_eq!(slot.set(100, vec![1, 2, 3, 4], None), Some(ClientMapCommand::Set(key, version, vec![1, 2, 3, 4])));
 
         let source = NodeSession(1, 2);
         assert_eq!(slot.on_set(100, key, source, version, vec![1, 2, 3, 4]), None);
@@ -696,6 +1034,102 @@ mod test {
         assert_eq!(map.pop_action(), None);
     }
 
+    #[test]
+    fn map_handle_local_ttl_expired() {
+        let session = NodeSession(1, 2);
+        let actor = FeatureControlActor::Controller(());
+        let mut map = LocalMap::new(session);
+
+        let key = Key(1);
+
+        assert_eq!(map.on_control(100, actor, MapControl::Sub), Some(ClientMapCommand::Sub(100, None)));
+        assert_eq!(
+            map.on_control(100, actor, MapControl::SetWithTtl(key, vec![1, 2, 3, 4], Duration::from_millis(1000))),
+            Some(ClientMapCommand::SetTtl(key, Version(100), vec![1, 2, 3, 4], 1000))
+        );
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor, MapEvent::OnSet(key, session.0, vec![1, 2, 3, 4]))));
+
+        //set again will refresh the ttl
+        assert_eq!(
+            map.on_control(900, actor, MapControl::SetWithTtl(key, vec![1, 2, 3, 5], Duration::from_millis(1000))),
+            Some(ClientMapCommand::SetTtl(key, Version(900), vec![1, 2, 3, 5], 1000))
+        );
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor, MapEvent::OnSet(key, session.0, vec![1, 2, 3, 5]))));
+        map.on_server(900, session, ServerMapEvent::SetOk(key, Version(900)));
+
+        let expired = |map: &mut LocalMap<()>| {
+            let mut events = vec![];
+            while let Some(out) = map.pop_action() {
+                match out {
+                    LocalMapOutput::Local(_, event @ MapEvent::OnExpired(..)) => events.push(event),
+                    //expired slot is not synced anymore, relay expires it by itself
+                    LocalMapOutput::Remote(cmd) => assert!(!matches!(cmd, ClientMapCommand::Del(..))),
+                    _ => {}
+                }
+            }
+            events
+        };
+
+        map.on_tick(1100);
+        assert_eq!(expired(&mut map), vec![]);
+
+        map.on_tick(1900);
+        assert_eq!(expired(&mut map), vec![MapEvent::OnExpired(key, session.0)]);
+        assert!(map.slots.is_empty());
+    }
+
+    #[test]
+    fn map_handle_local_batch() {
+        let session = NodeSession(1, 2);
+        let actor = FeatureControlActor::Controller(());
+        let mut map = LocalMap::new(session);
+
+        assert_eq!(
+            map.on_control(100, actor, MapControl::BatchSet(vec![(Key(1), vec![1]), (Key(2), vec![2])])),
+            Some(ClientMapCommand::Batch(
+                0,
+                vec![BatchOp::Set(Key(1), Version(100), vec![1]), BatchOp::Set(Key(2), Version(100), vec![2])]
+            ))
+        );
+        assert_eq!(map.pop_action(), None);
+
+        //batch is resent as a whole, slots don't resend by themselves
+        map.on_tick(100 + RESEND_MS);
+        assert_eq!(
+            map.pop_action(),
+            Some(LocalMapOutput::Remote(ClientMapCommand::Batch(
+                0,
+                vec![BatchOp::Set(Key(1), Version(100), vec![1]), BatchOp::Set(Key(2), Version(100), vec![2])]
+            )))
+        );
+        assert_eq!(map.pop_action(), None);
+
+        assert_eq!(map.on_server(300, session, ServerMapEvent::BatchOk(0)), None);
+        map.on_tick(100 + RESEND_MS * 2);
+        assert_eq!(map.pop_action(), None);
+
+        //deleted slots are kept until the batch is acked, unknown keys are skipped
+        assert_eq!(
+            map.on_control(500, actor, MapControl::BatchDel(vec![Key(1), Key(2), Key(3)])),
+            Some(ClientMapCommand::Batch(1, vec![BatchOp::Del(Key(1), Version(100)), BatchOp::Del(Key(2), Version(100))]))
+        );
+        map.on_tick(500 + RESEND_MS);
+        assert_eq!(
+            map.pop_action(),
+            Some(LocalMapOutput::Remote(ClientMapCommand::Batch(
+                1,
+                vec![BatchOp::Del(Key(1), Version(100)), BatchOp::Del(Key(2), Version(100))]
+            )))
+        );
+        assert_eq!(map.slots.len(), 2);
+
+        assert_eq!(map.on_server(800, session, ServerMapEvent::BatchOk(1)), None);
+        map.on_tick(500 + RESEND_MS * 2);
+        assert!(map.slots.is_empty());
+        assert!(map.should_cleanup());
+        assert_eq!(map.on_control(900, actor, MapControl::BatchDel(vec![Key(1)])), None);
+    }
+
     #[test]
     fn map_handle_sub_with_local_data_correct() {
         let session = NodeSession(1, 2);
@@ -717,6 +1151,117 @@ mod test {
         assert_eq!(map.pop_action(), None); //should not output local event after unsub
     }
 
+    #[test]
+    fn map_handle_filtered_sub() {
+        let session = NodeSession(1, 2);
+        let actor1 = FeatureControlActor::Controller(());
+        let actor2 = FeatureControlActor::Worker(1, ());
+        let mut map = LocalMap::new(session);
+
+        let source = NodeSession(3, 4);
+        let relay = NodeSession(5, 6);
+        let range = KeyRange::prefix(0x1000_0000_0000_0000, 4);
+        assert_eq!(range, KeyRange::new(Key(0x1000_0000_0000_0000), Key(0x1FFF_FFFF_FFFF_FFFF)));
+        let inside = Key(0x1000_0000_0000_0001);
+        let outside = Key(0x2000_0000_0000_0001);
+        let on_set = |key: Key| ServerMapEvent::OnSet {
+            key,
+            source,
+            version: Version(2000),
+            data: vec![1],
+        };
+
+        assert_eq!(map.on_control(102, actor1, MapControl::SubFiltered(range)), Some(ClientMapCommand::SubFiltered(102, None, vec![range])));
+        assert_eq!(map.on_server(103, relay, ServerMapEvent::SubOk(102)), None);
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor1, MapEvent::OnRelaySelected(relay.0))));
+        assert_eq!(map.on_server(103, relay, on_set(inside)), Some(ClientMapCommand::OnSetAck(inside, source, Version(2000))));
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor1, MapEvent::OnSet(inside, source.0, vec![1]))));
+
+        //subscriber without filter widens the relay filter
+        assert_eq!(map.on_control(104, actor2, MapControl::Sub), Some(ClientMapCommand::Sub(102, Some(relay))));
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor2, MapEvent::OnSet(inside, source.0, vec![1]))));
+        assert_eq!(map.on_server(105, relay, on_set(outside)), Some(ClientMapCommand::OnSetAck(outside, source, Version(2000))));
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor2, MapEvent::OnSet(outside, source.0, vec![1]))));
+        assert_eq!(map.pop_action(), None);
+
+        //after that subscriber left, the relay filter is narrowed and slots outside it are dropped
+        assert_eq!(map.on_control(106, actor2, MapControl::Unsub), Some(ClientMapCommand::SubFiltered(102, Some(relay), vec![range])));
+        assert_eq!(map.slots.len(), 1);
+
+        map.on_tick(103 + SYNC_MS);
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Remote(ClientMapCommand::SubFiltered(102, Some(relay), vec![range]))));
+    }
+
+    #[test]
+    fn map_handle_acl_owner() {
+        let session = NodeSession(1, 2);
+        let actor = FeatureControlActor::Controller(());
+        let mut map = LocalMap::new(session);
+        let relay = NodeSession(5, 6);
+
+        let acl = MapAcl {
+            writers: Some(vec![3]),
+            readers: None,
+        };
+        assert_eq!(map.on_control(100, actor, MapControl::SetAcl(acl.clone())), Some(ClientMapCommand::SetAcl(100, acl.clone())));
+        map.on_tick(100 + RESEND_MS);
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Remote(ClientMapCommand::SetAcl(100, acl.clone()))));
+
+        assert_eq!(map.on_server(300, relay, ServerMapEvent::AclOk(100)), None);
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor, MapEvent::OnAclApplied)));
+        assert_eq!(map.on_server(300, relay, ServerMapEvent::AclOk(100)), None);
+        assert_eq!(map.pop_action(), None);
+
+        //acked acl is still resynced for restoring it in new relay
+        map.on_tick(300 + RESEND_MS);
+        assert_eq!(map.pop_action(), None);
+        map.on_tick(300 + SYNC_MS);
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Remote(ClientMapCommand::SetAcl(100, acl))));
+        assert!(!map.should_cleanup());
+
+        //open acl releases the map
+        assert_eq!(
+            map.on_control(2000, actor, MapControl::SetAcl(MapAcl::default())),
+            Some(ClientMapCommand::SetAcl(2000, MapAcl::default()))
+        );
+        assert_eq!(map.on_server(2001, relay, ServerMapEvent::AclOk(2000)), None);
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor, MapEvent::OnAclApplied)));
+        assert!(map.should_cleanup());
+    }
+
+    #[test]
+    fn map_handle_acl_denied() {
+        let session = NodeSession(1, 2);
+        let actor = FeatureControlActor::Controller(());
+        let writer = FeatureControlActor::Worker(1, ());
+        let mut map = LocalMap::new(session);
+        let relay = NodeSession(5, 6);
+        let key = Key(1);
+
+        assert_eq!(
+            map.on_control(100, actor, MapControl::SetAcl(MapAcl::default())),
+            Some(ClientMapCommand::SetAcl(100, MapAcl::default()))
+        );
+        assert_eq!(map.on_server(101, relay, ServerMapEvent::AclDenied(100)), None);
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor, MapEvent::OnDenied(DeniedOp::Acl))));
+
+        assert_eq!(map.on_control(102, actor, MapControl::Sub), Some(ClientMapCommand::Sub(102, None)));
+        assert_eq!(map.on_control(103, writer, MapControl::Set(key, vec![1])), Some(ClientMapCommand::Set(key, Version(103), vec![1])));
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor, MapEvent::OnSet(key, session.0, vec![1]))));
+
+        //writer and subscribers are notified, and the value is not resent anymore
+        assert_eq!(map.on_server(104, relay, ServerMapEvent::SetDenied(key)), None);
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(writer, MapEvent::OnDenied(DeniedOp::Set(key)))));
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor, MapEvent::OnDenied(DeniedOp::Set(key)))));
+        map.on_tick(104 + SYNC_MS);
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Remote(ClientMapCommand::Sub(102, None))));
+        assert_eq!(map.pop_action(), None);
+
+        assert_eq!(map.on_server(105 + SYNC_MS, relay, ServerMapEvent::SubDenied(102)), None);
+        assert_eq!(map.pop_action(), Some(LocalMapOutput::Local(actor, MapEvent::OnDenied(DeniedOp::Sub))));
+        assert!(map.should_cleanup());
+    }
+
     #[test]
     fn map_unsub_ok() {
         let session = NodeSession(1, 2);
@@ -740,6 +1285,39 @@ mod test {
         assert!(map.should_cleanup());
     }
 
+    #[test]
+    fn map_unsub_drop_remote_slots() {
+        let session = NodeSession(1, 2);
+        let actor = FeatureControlActor::Controller(());
+        let mut map = LocalMap::new(session);
+
+        let key = Key(1);
+        let source = NodeSession(3, 4);
+        let relay = NodeSession(5, 6);
+
+        assert_eq!(map.on_control(102, actor, MapControl::Sub), Some(ClientMapCommand::Sub(102, None)));
+        assert_eq!(map.on_server(103, relay, ServerMapEvent::SubOk(102)), None);
+        assert_eq!(
+            map.on_server(
+                103,
+                relay,
+                ServerMapEvent::OnSet {
+                    key,
+                    source,
+                    version: Version(2000),
+                    data: vec![1, 2, 3, 4]
+                }
+            ),
+            Some(ClientMapCommand::OnSetAck(key, source, Version(2000)))
+        );
+
+        //after unsub, the remote slot will never be deleted by OnDel, so it must be dropped for map to be cleaned
+        assert_eq!(map.on_control(104, actor, MapControl::Unsub), Some(ClientMapCommand::Unsub(102)));
+        assert_eq!(map.on_server(105, relay, ServerMapEvent::UnsubOk(102)), None);
+        map.on_tick(106);
+        assert!(map.should_cleanup());
+    }
+
     #[test]
     fn map_handle_auto_resend_sub_unsub() {
         let session = NodeSession(1, 2);
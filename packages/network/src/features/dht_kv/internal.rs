@@ -1,4 +1,4 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, sync::Arc};
 
 use atm0s_sdn_router::RouteRule;
 
@@ -8,6 +8,7 @@ use super::{
     client::{LocalStorage, LocalStorageOutput},
     msg::{NodeSession, RemoteCommand},
     server::RemoteStorage,
+    storage::{KvStorageBackend, StoredSlot},
     Control, Event,
 };
 
@@ -23,11 +24,11 @@ pub struct DhtKvInternal<UserData> {
 }
 
 impl<UserData: Eq + Debug + Copy> DhtKvInternal<UserData> {
-    pub fn new(session: NodeSession) -> Self {
+    pub fn new(session: NodeSession, storage: Option<Arc<dyn KvStorageBackend>>) -> Self {
         Self {
             session,
             local: LocalStorage::new(session),
-            remote: RemoteStorage::new(session),
+            remote: RemoteStorage::new(session, storage),
         }
     }
 
@@ -36,6 +37,22 @@ impl<UserData: Eq + Debug + Copy> DhtKvInternal<UserData> {
         self.remote.on_tick(now);
     }
 
+    pub fn decommission(&mut self) {
+        self.remote.decommission();
+    }
+
+    pub fn served_maps(&self) -> usize {
+        self.remote.maps()
+    }
+
+    pub fn served_slots(&self) -> Vec<StoredSlot> {
+        self.remote.slots()
+    }
+
+    pub fn restore_served(&mut self, now: u64, slots: Vec<StoredSlot>) {
+        self.remote.restore(now, slots);
+    }
+
     pub fn on_local(&mut self, now: u64, actor: FeatureControlActor<UserData>, control: Control) {
         self.local.on_local(now, actor, control);
     }
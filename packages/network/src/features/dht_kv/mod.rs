@@ -6,25 +6,29 @@
 //! For solve conflict, each sub_key will attacked to a locked value, which is a pair (node, lock_session).
 //! In which, node is the node that locked the value, and session is the session of the lock.
 
-use std::fmt::Debug;
+use std::{fmt::Debug, sync::Arc, time::Duration};
 
 use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_router::RouteRule;
+use atm0s_sdn_utils::log_sampled;
 use derivative::Derivative;
 use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
 
-use crate::base::{Feature, FeatureContext, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput, NetOutgoingMeta};
-
-use self::{
-    internal::InternalOutput,
-    msg::{NodeSession, Version},
+use crate::base::{
+    CompressionConfig, ConnectionEvent, Feature, FeatureContext, FeatureError, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput,
+    NetOutgoingMeta, PayloadCompressor,
 };
 
+use self::internal::InternalOutput;
+
 mod client;
 mod internal;
-mod msg;
+pub(crate) mod msg;
 mod server;
+mod storage;
 
-pub use self::msg::{Key, Map};
+pub use self::msg::{Key, KeyRange, Map, MapAcl, NodeSession, Version};
+pub use self::storage::{FileKvStorage, KvStorageBackend, MemoryKvStorage, StoredSlot};
 
 pub const FEATURE_ID: u8 = 4;
 pub const FEATURE_NAME: &str = "dht_kv";
@@ -32,14 +36,29 @@ pub const FEATURE_NAME: &str = "dht_kv";
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MapControl {
     Set(Key, Vec<u8>),
+    /// Set a value which is removed after the ttl, setting the key again refreshes the ttl.
+    /// The expiry is reported to subscribers with [`MapEvent::OnExpired`]
+    SetWithTtl(Key, Vec<u8>, Duration),
+    /// Set many keys with a single message, which is applied atomically by the relay and acked once
+    BatchSet(Vec<(Key, Vec<u8>)>),
+    /// Delete many keys with a single message, which is applied atomically by the relay and acked once
+    BatchDel(Vec<Key>),
     Del(Key),
     Sub,
+    /// Subscribe only to keys inside the range, other keys are filtered by the relay and never sent to this node
+    SubFiltered(KeyRange),
     Unsub,
+    /// Restrict which nodes can write or read the map, the first node which sets the acl becomes the owner of the map
+    /// and is the only one which can change it. Setting an open acl releases the map
+    SetAcl(MapAcl),
 }
 
 impl MapControl {
     pub fn is_creator(&self) -> bool {
-        matches!(self, MapControl::Set(_, _) | MapControl::Sub)
+        matches!(
+            self,
+            MapControl::Set(_, _) | MapControl::SetWithTtl(_, _, _) | MapControl::BatchSet(_) | MapControl::Sub | MapControl::SubFiltered(_) | MapControl::SetAcl(_)
+        )
     }
 }
 
@@ -53,13 +72,51 @@ pub enum Control {
 pub enum GetError {
     Timeout,
     NotFound,
+    /// Map acl doesn't allow this node to read
+    Denied,
+}
+
+impl From<&GetError> for FeatureError {
+    fn from(value: &GetError) -> Self {
+        match value {
+            GetError::Timeout => FeatureError::Timeout,
+            GetError::NotFound => FeatureError::Unreachable,
+            GetError::Denied => FeatureError::Rejected,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MapEvent {
     OnSet(Key, NodeId, Vec<u8>),
     OnDel(Key, NodeId),
+    /// Value which is set with ttl is expired without refresh
+    OnExpired(Key, NodeId),
     OnRelaySelected(NodeId),
+    /// Acl is accepted by the relay, this node is the owner of the map
+    OnAclApplied,
+    /// Operation is rejected by the map acl
+    OnDenied(DeniedOp),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeniedOp {
+    /// Local value of the key is dropped
+    Set(Key),
+    /// Subscription is removed
+    Sub,
+    /// Map is owned by other node
+    Acl,
+}
+
+impl MapEvent {
+    /// Key of the event, None for events which are not related to a key
+    pub fn key(&self) -> Option<Key> {
+        match self {
+            MapEvent::OnSet(key, _, _) | MapEvent::OnDel(key, _) | MapEvent::OnExpired(key, _) | MapEvent::OnDenied(DeniedOp::Set(key)) => Some(*key),
+            MapEvent::OnRelaySelected(_) | MapEvent::OnAclApplied | MapEvent::OnDenied(_) => None,
+        }
+    }
 }
 
 type MapGetRs = Result<Vec<(Key, NodeSession, Version, Vec<u8>)>, GetError>;
@@ -70,6 +127,16 @@ pub enum Event {
     MapGetRes(Map, MapGetRs),
 }
 
+impl Event {
+    pub fn error(&self) -> Option<FeatureError> {
+        match self {
+            Self::MapGetRes(_, Err(err)) => Some(err.into()),
+            Self::MapEvent(_, MapEvent::OnDenied(_)) => Some(FeatureError::Rejected),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ToWorker;
 
@@ -81,22 +148,62 @@ pub type WorkerOutput<UserData> = FeatureWorkerOutput<UserData, Control, Event,
 
 pub struct DhtKvFeature<UserData> {
     internal: internal::DhtKvInternal<UserData>,
+    session: u64,
+    /// Dedup token for ToKey commands, which can be delivered twice while the network is converging.
+    /// It is seeded with the session and the time of the first command, so a node which is restarted with a persisted session
+    /// doesn't repeat tokens of the previous run
+    dedup_seq: Option<u32>,
+    compressor: PayloadCompressor,
     shutdown: bool,
 }
 
 impl<UserData: Eq + Copy + Debug> DhtKvFeature<UserData> {
-    pub fn new(node_id: NodeId, session: u64) -> Self {
+    /// With storage backend, served maps are written through to it and restored when the feature is created
+    pub fn new(node_id: NodeId, session: u64, storage: Option<Arc<dyn KvStorageBackend>>) -> Self {
         Self {
-            internal: internal::DhtKvInternal::new(NodeSession(node_id, session)),
+            internal: internal::DhtKvInternal::new(NodeSession(node_id, session), storage),
+            session,
+            dedup_seq: None,
+            compressor: PayloadCompressor::new(FEATURE_NAME, None),
             shutdown: false,
         }
     }
+
+    /// Compress big remote commands, like sets of big values. Commands to keys are only compressed when the config
+    /// assumes all nodes support it, because the relay of the key is unknown before routing
+    pub fn with_compression(mut self, cfg: Option<CompressionConfig>) -> Self {
+        self.compressor = PayloadCompressor::new(FEATURE_NAME, cfg);
+        self
+    }
+
+    /// Stop accepting new maps from remote nodes. Maps which are already stored here will be re-created
+    /// in the next closest node by their owners after this node left
+    pub fn decommission(&mut self) {
+        log::info!("[DhtKv] decommission => stop accepting new maps");
+        self.internal.decommission();
+    }
+
+    /// Number of maps which this node is responsible for
+    pub fn served_maps(&self) -> usize {
+        self.internal.served_maps()
+    }
+
+    /// Slots which this node serves, so a restarted node keeps answering for them before owners set them again
+    pub fn checkpoint(&self) -> Vec<StoredSlot> {
+        self.internal.served_slots()
+    }
+
+    pub fn restore_checkpoint(&mut self, now: u64, slots: Vec<StoredSlot>) {
+        self.internal.restore_served(now, slots);
+    }
 }
 
 impl<UserData: Eq + Copy + Debug> Feature<UserData, Control, Event, ToController, ToWorker> for DhtKvFeature<UserData> {
     fn on_shared_input(&mut self, _ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
-        if let FeatureSharedInput::Tick(_) = input {
-            self.internal.on_tick(now);
+        match input {
+            FeatureSharedInput::Tick(_) => self.internal.on_tick(now),
+            FeatureSharedInput::Connection(ConnectionEvent::Capabilities(ctx, caps)) => self.compressor.on_peer_capabilities(ctx.node, caps.capabilities),
+            _ => {}
         }
     }
 
@@ -106,18 +213,18 @@ impl<UserData: Eq + Copy + Debug> Feature<UserData, Control, Event, ToController
                 log::debug!("[DhtKv] on ext input: actor={:?}, control={:?}", actor, control);
                 self.internal.on_local(now_ms, actor, control);
             }
-            FeatureInput::Local(_header, buf) => {
-                if let Ok(cmd) = bincode::deserialize(&buf) {
+            FeatureInput::Local(header, buf) => {
+                if let Some(Ok(cmd)) = self.compressor.decode(header.source, &buf).map(|buf| bincode::deserialize(&buf)) {
                     self.internal.on_remote(now_ms, cmd)
                 }
             }
             FeatureInput::Net(_conn, meta, buf) => {
                 if !meta.secure {
                     //only allow secure message
-                    log::warn!("[DhtKv] reject unsecure message");
+                    log_sampled!(log::Level::Warn, "[DhtKv] reject unsecure message");
                     return;
                 }
-                if let Ok(cmd) = bincode::deserialize(&buf) {
+                if let Some(Ok(cmd)) = self.compressor.decode(meta.source, &buf).map(|buf| bincode::deserialize(&buf)) {
                     self.internal.on_remote(now_ms, cmd)
                 }
             }
@@ -142,14 +249,23 @@ impl<UserData: Eq + Copy + Debug> TaskSwitcherChild<FeatureOutput<UserData, Even
         FeatureOutput::OnResourceEmpty
     }
 
-    fn pop_output(&mut self, _now: u64) -> Option<FeatureOutput<UserData, Event, ToWorker>> {
+    fn pop_output(&mut self, now: u64) -> Option<FeatureOutput<UserData, Event, ToWorker>> {
         match self.internal.pop_action()? {
             InternalOutput::Local(service, event) => Some(FeatureOutput::Event(service, event)),
-            InternalOutput::Remote(rule, cmd) => Some(FeatureOutput::SendRoute(
-                rule,
-                NetOutgoingMeta::new(false, Default::default(), 0, true),
-                bincode::serialize(&cmd).expect("Should to bytes").into(),
-            )),
+            InternalOutput::Remote(rule, cmd) => {
+                let mut meta = NetOutgoingMeta::new(false, Default::default(), 0, true);
+                if matches!(rule, RouteRule::ToKey(_)) {
+                    let seq = self.dedup_seq.get_or_insert((self.session ^ now) as u32);
+                    *seq = seq.wrapping_add(1);
+                    meta = meta.with_dedup(*seq);
+                }
+                let dest = match rule {
+                    RouteRule::ToNode(node) => Some(node),
+                    _ => None,
+                };
+                let buf = self.compressor.encode(dest, bincode::serialize(&cmd).expect("Should to bytes"));
+                Some(FeatureOutput::SendRoute(rule, meta, buf.into()))
+            }
         }
     }
 }
@@ -2,14 +2,75 @@ use atm0s_sdn_identity::NodeId;
 use atm0s_sdn_utils::simple_pub_type;
 use serde::{Deserialize, Serialize};
 
+use crate::base::{derive_id, IdKind};
+
 simple_pub_type!(Map, u64);
 simple_pub_type!(Key, u64);
 simple_pub_type!(Version, u64);
 simple_pub_type!(Seq, u64);
 
+impl Map {
+    /// Map id which is derived from namespace and name, see [`derive_id`] for the stable hashing
+    pub fn derive(namespace: &str, name: &str) -> Self {
+        Self(derive_id(IdKind::Map, namespace, name))
+    }
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq)]
 pub struct NodeSession(pub NodeId, pub u64);
 
+/// Inclusive range of keys, which is used for filtered subscriptions
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq)]
+pub struct KeyRange {
+    pub first: Key,
+    pub last: Key,
+}
+
+impl KeyRange {
+    pub fn new(first: Key, last: Key) -> Self {
+        Self { first, last }
+    }
+
+    /// All keys which have same highest `bits` bits with prefix, 0 bits means all keys
+    pub fn prefix(prefix: u64, bits: u8) -> Self {
+        let mask = match bits {
+            0 => 0,
+            bits => u64::MAX << (64 - bits.min(64) as u32),
+        };
+        Self {
+            first: Key(prefix & mask),
+            last: Key(prefix & mask | !mask),
+        }
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.first.0 <= key.0 && key.0 <= self.last.0
+    }
+}
+
+/// Nodes which are allowed to access a map, None allows all nodes. The owner of the map is always allowed
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MapAcl {
+    /// Nodes which can Set or Del keys
+    pub writers: Option<Vec<NodeId>>,
+    /// Nodes which can Sub or Get the map
+    pub readers: Option<Vec<NodeId>>,
+}
+
+impl MapAcl {
+    pub fn is_open(&self) -> bool {
+        self.writers.is_none() && self.readers.is_none()
+    }
+
+    pub fn can_write(&self, node: NodeId) -> bool {
+        self.writers.as_ref().map(|nodes| nodes.contains(&node)).unwrap_or(true)
+    }
+
+    pub fn can_read(&self, node: NodeId) -> bool {
+        self.readers.as_ref().map(|nodes| nodes.contains(&node)).unwrap_or(true)
+    }
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum DelReason {
     Timeout,
@@ -30,13 +91,35 @@ pub enum ClientMapCommand {
     Del(Key, Version),
     Sub(u64, Option<NodeSession>), //
     Unsub(u64),
-    OnSetAck(Key, NodeSession, Version), //Seq from OnHSet
-    OnDelAck(Key, NodeSession, Version), //Seq from OnHDel
+    OnSetAck(Key, NodeSession, Version),                  //Seq from OnHSet
+    OnDelAck(Key, NodeSession, Version),                  //Seq from OnHDel, also used for OnExpired
+    SetTtl(Key, Version, Vec<u8>, u64),                   //Remaining ttl in ms, resync doesn't refresh it
+    Batch(u64, Vec<BatchOp>),                             //Applied all or nothing, acked with BatchOk(id)
+    SubFiltered(u64, Option<NodeSession>, Vec<KeyRange>), //Same as Sub but relay only fires events for keys inside the ranges
+    SetAcl(u64, MapAcl),                                  //Only accepted from owner, first SetAcl claims the map
 }
 
 impl ClientMapCommand {
     pub fn is_creator(&self) -> bool {
-        matches!(self, ClientMapCommand::Set(_, _, _) | ClientMapCommand::Sub(_, _))
+        match self {
+            ClientMapCommand::Set(_, _, _) | ClientMapCommand::SetTtl(_, _, _, _) | ClientMapCommand::Sub(_, _) | ClientMapCommand::SubFiltered(_, _, _) | ClientMapCommand::SetAcl(_, _) => true,
+            ClientMapCommand::Batch(_, ops) => ops.iter().any(|op| matches!(op, BatchOp::Set(_, _, _))),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum BatchOp {
+    Set(Key, Version, Vec<u8>),
+    Del(Key, Version),
+}
+
+impl BatchOp {
+    pub fn key(&self) -> Key {
+        match self {
+            BatchOp::Set(key, _, _) | BatchOp::Del(key, _) => *key,
+        }
     }
 }
 
@@ -56,10 +139,18 @@ pub(crate) enum ServerMapEvent {
     UnsubOk(u64),
     OnSet { key: Key, source: NodeSession, version: Version, data: Vec<u8> },
     OnDel { key: Key, source: NodeSession, version: Version },
+    OnExpired { key: Key, source: NodeSession, version: Version },
+    BatchOk(u64),
+    AclOk(u64),
+    SetDenied(Key),
+    BatchDenied(u64),
+    SubDenied(u64),
+    AclDenied(u64),
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub(crate) enum ServerEvent {
     MapEvent(Map, ServerMapEvent),
     MapGetRes(Map, u64, Vec<(Key, NodeSession, Version, Vec<u8>)>),
+    GetDenied(Map, u64),
 }
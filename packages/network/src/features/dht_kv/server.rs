@@ -1,9 +1,13 @@
-use std::collections::{HashMap, VecDeque};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
 
 use self::map::RemoteMap;
 
 use super::{
-    msg::{ClientCommand, NodeSession, ServerEvent},
+    msg::{ClientCommand, ClientMapCommand, NodeSession, ServerEvent, ServerMapEvent},
+    storage::{KvStorageBackend, StoredSlot},
     Map,
 };
 
@@ -13,21 +17,82 @@ pub struct RemoteStorage {
     session: NodeSession,
     maps: HashMap<Map, RemoteMap>,
     queue: VecDeque<(NodeSession, ServerEvent)>,
+    decommission: bool,
+    storage: Option<Arc<dyn KvStorageBackend>>,
 }
 
 impl RemoteStorage {
-    pub fn new(session: NodeSession) -> Self {
+    pub fn new(session: NodeSession, storage: Option<Arc<dyn KvStorageBackend>>) -> Self {
+        let mut maps = HashMap::new();
+        if let Some(storage) = &storage {
+            let slots = storage.load();
+            log::info!("[DhtKvServer] Restore {} slots from storage", slots.len());
+            for slot in slots {
+                let map = maps.entry(slot.map).or_insert_with(|| RemoteMap::new(session));
+                map.restore(0, slot.key, slot.source, slot.version, slot.data);
+            }
+        }
         Self {
             session,
-            maps: HashMap::new(),
+            maps,
             queue: VecDeque::new(),
+            decommission: false,
+            storage,
         }
     }
 
+    /// Stop creating new maps, existing maps are still served until this node leaves
+    pub fn decommission(&mut self) {
+        self.decommission = true;
+    }
+
+    /// Number of maps which are stored in this node
+    pub fn maps(&self) -> usize {
+        self.maps.len()
+    }
+
+    /// All slots which are served by this node
+    pub fn slots(&self) -> Vec<StoredSlot> {
+        let mut slots = vec![];
+        for (map, remote) in self.maps.iter() {
+            for (key, source, version, data) in remote.dump() {
+                slots.push(StoredSlot {
+                    map: *map,
+                    key,
+                    source,
+                    version,
+                    data,
+                });
+            }
+        }
+        slots
+    }
+
+    /// Restore slots of a checkpoint, slots which are already loaded from the storage backend are kept
+    pub fn restore(&mut self, now: u64, slots: Vec<StoredSlot>) {
+        let mut restored = 0;
+        for slot in slots {
+            let map = self.maps.entry(slot.map).or_insert_with(|| RemoteMap::new(self.session));
+            if map.slot(slot.key, slot.source).is_some() {
+                continue;
+            }
+            if let Some(storage) = &self.storage {
+                storage.set(slot.map, slot.key, slot.source, slot.version, &slot.data);
+            }
+            map.restore(now, slot.key, slot.source, slot.version, slot.data);
+            restored += 1;
+        }
+        log::info!("[DhtKvServer] Restore {restored} slots from checkpoint");
+    }
+
     pub fn on_tick(&mut self, now: u64) {
         let mut to_remove = vec![];
         for (key, map) in self.maps.iter_mut() {
-            map.on_tick(now);
+            for (sub_key, source) in map.on_tick(now) {
+                if let Some(storage) = &self.storage {
+                    storage.del(*key, sub_key, source);
+                }
+            }
             while let Some((session, event)) = map.pop_action() {
                 self.queue.push_back((session, ServerEvent::MapEvent(*key, event)));
             }
@@ -46,6 +111,9 @@ impl RemoteStorage {
             ClientCommand::MapCmd(key, cmd) => {
                 let map = if let Some(map) = self.maps.get_mut(&key) {
                     map
+                } else if cmd.is_creator() && self.decommission {
+                    log::debug!("[DhtKvServer] Reject creating new map {} while decommissioning", key);
+                    return;
                 } else if cmd.is_creator() {
                     log::info!("[DhtKvServer] Creating new map: {}", key);
                     self.maps.insert(key, RemoteMap::new(self.session));
@@ -54,17 +122,47 @@ impl RemoteStorage {
                     return;
                 };
 
+                let batch_keys = match &cmd {
+                    ClientMapCommand::Batch(_, ops) => ops.iter().map(|op| op.key()).collect(),
+                    _ => vec![],
+                };
+
                 if let Some(event) = map.on_client(now, remote, cmd) {
+                    if let Some(storage) = &self.storage {
+                        match &event {
+                            ServerMapEvent::SetOk(sub_key, _) => {
+                                if let Some((version, data)) = map.slot(*sub_key, remote) {
+                                    storage.set(key, *sub_key, remote, version, &data);
+                                }
+                            }
+                            ServerMapEvent::DelOk(sub_key, _) => storage.del(key, *sub_key, remote),
+                            ServerMapEvent::BatchOk(_) => {
+                                for sub_key in batch_keys {
+                                    match map.slot(sub_key, remote) {
+                                        Some((version, data)) => storage.set(key, sub_key, remote, version, &data),
+                                        None => storage.del(key, sub_key, remote),
+                                    }
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
                     self.queue.push_back((remote, ServerEvent::MapEvent(key, event)));
                     while let Some((session, event)) = map.pop_action() {
                         self.queue.push_back((session, ServerEvent::MapEvent(key, event)));
                     }
                 }
             }
-            ClientCommand::MapGet(key, id) => {
-                let values = self.maps.get_mut(&key).map(|map| map.dump()).unwrap_or_default();
-                self.queue.push_back((remote, ServerEvent::MapGetRes(key, id, values)));
-            }
+            ClientCommand::MapGet(key, id) => match self.maps.get(&key) {
+                Some(map) if !map.can_read(remote.0) => {
+                    log::warn!("[DhtKvServer] Get map {} from {} denied by acl", key, remote.0);
+                    self.queue.push_back((remote, ServerEvent::GetDenied(key, id)));
+                }
+                map => {
+                    let values = map.map(|map| map.dump()).unwrap_or_default();
+                    self.queue.push_back((remote, ServerEvent::MapGetRes(key, id, values)));
+                }
+            },
         }
     }
 
@@ -1,13 +1,15 @@
 use std::collections::{HashMap, VecDeque};
 
-use crate::features::dht_kv::msg::{ClientMapCommand, Key, NodeSession, ServerMapEvent, Version};
+use atm0s_sdn_identity::NodeId;
+
+use crate::features::dht_kv::msg::{BatchOp, ClientMapCommand, Key, KeyRange, MapAcl, NodeSession, ServerMapEvent, Version};
 
 const RESEND_MS: u64 = 200; //We will resend set or del command if we don't get ack in this time
 const TIMEOUT_MS: u64 = 10000; //We will remove sub if we don't get any message from it in this time
 
 enum MapSlot {
     Unspecific,
-    Set { data: Vec<u8>, version: Version, live_at: u64 },
+    Set { data: Vec<u8>, version: Version, live_at: u64, expires_at: Option<u64> },
 }
 
 impl MapSlot {
@@ -15,21 +17,24 @@ impl MapSlot {
         Self::Unspecific
     }
 
-    fn set(&mut self, now: u64, new_version: Version, new_data: Vec<u8>) -> bool {
+    fn set(&mut self, now: u64, new_version: Version, new_data: Vec<u8>, ttl_ms: Option<u64>) -> bool {
+        let new_expires_at = ttl_ms.map(|ttl| now + ttl);
         match self {
             MapSlot::Unspecific => {
                 *self = MapSlot::Set {
                     data: new_data,
                     version: new_version,
                     live_at: now,
+                    expires_at: new_expires_at,
                 };
                 true
             }
-            MapSlot::Set { version, data, live_at } => {
+            MapSlot::Set { version, data, live_at, expires_at } => {
                 if version.0 <= new_version.0 {
                     *version = new_version;
                     *data = new_data;
                     *live_at = now;
+                    *expires_at = new_expires_at;
                     true
                 } else {
                     false
@@ -38,6 +43,17 @@ impl MapSlot {
         }
     }
 
+    fn is_expired(&self, now: u64) -> Option<Version> {
+        match self {
+            MapSlot::Set {
+                version,
+                expires_at: Some(expires_at),
+                ..
+            } if now >= *expires_at => Some(*version),
+            _ => None,
+        }
+    }
+
     fn del(&mut self, _now: u64, version: Version) -> Option<Version> {
         match self {
             MapSlot::Unspecific => None,
@@ -71,6 +87,24 @@ struct WaitAcksEvent {
 struct SubSlot {
     id: u64,
     last_ts: u64,
+    /// None for subscribing to all keys
+    filter: Option<Vec<KeyRange>>,
+}
+
+impl SubSlot {
+    fn accept(&self, key: Key) -> bool {
+        match &self.filter {
+            Some(ranges) => ranges.iter().any(|range| range.contains(key)),
+            None => true,
+        }
+    }
+}
+
+/// Acl is resynced by the owner, it is removed if the owner doesn't resync it in TIMEOUT_MS
+struct AclSlot {
+    owner: NodeId,
+    acl: MapAcl,
+    last_ts: u64,
 }
 
 pub struct RemoteMap {
@@ -78,6 +112,7 @@ pub struct RemoteMap {
     slots: HashMap<(Key, NodeSession), MapSlot>,
     slots_event: HashMap<(Key, NodeSession), WaitAcksEvent>,
     subs: HashMap<NodeSession, SubSlot>,
+    acl: Option<AclSlot>,
     queue: VecDeque<(NodeSession, ServerMapEvent)>,
 }
 
@@ -88,11 +123,30 @@ impl RemoteMap {
             slots: HashMap::new(),
             slots_event: HashMap::new(),
             subs: HashMap::new(),
+            acl: None,
             queue: VecDeque::new(),
         }
     }
 
-    pub fn on_tick(&mut self, now: u64) {
+    /// Returns slots which are expired by ttl, they are already removed and fired to subscribers
+    pub fn on_tick(&mut self, now: u64) -> Vec<(Key, NodeSession)> {
+        //clean-up expired slots
+        let expired = self.slots.iter().filter_map(|(key, slot)| slot.is_expired(now).map(|version| (*key, version))).collect::<Vec<_>>();
+        for ((key, source), version) in &expired {
+            log::debug!("[ServerMap] Key {} from {} with version {} expired", key, source.0, version);
+            self.slots.remove(&(*key, *source));
+            self.fire_event(
+                now,
+                *key,
+                *source,
+                ServerMapEvent::OnExpired {
+                    key: *key,
+                    source: *source,
+                    version: *version,
+                },
+            );
+        }
+
         //clean-up timeout subs
         let mut to_remove = vec![];
         for (node, slot) in self.subs.iter() {
@@ -106,6 +160,13 @@ impl RemoteMap {
             self.subs.remove(&node);
         }
 
+        if let Some(acl) = &self.acl {
+            if now >= acl.last_ts + TIMEOUT_MS {
+                log::warn!("[ServerMap] Remove acl from owner {} after timeout {TIMEOUT_MS}", acl.owner);
+                self.acl = None;
+            }
+        }
+
         //resend events
         let mut to_remove = vec![];
         for (key, slot) in self.slots_event.iter_mut() {
@@ -124,6 +185,25 @@ impl RemoteMap {
         for key in to_remove {
             self.slots_event.remove(&key);
         }
+
+        expired.into_iter().map(|(key, _)| key).collect()
+    }
+
+    /// Restore a slot which is loaded from storage backend
+    pub fn restore(&mut self, now: u64, key: Key, source: NodeSession, version: Version, data: Vec<u8>) {
+        self.slots.insert(
+            (key, source),
+            MapSlot::Set {
+                data,
+                version,
+                live_at: now,
+                expires_at: None,
+            },
+        );
+    }
+
+    pub fn slot(&self, key: Key, source: NodeSession) -> Option<(Version, Vec<u8>)> {
+        self.slots.get(&(key, source))?.dump()
     }
 
     pub fn dump(&self) -> Vec<(Key, NodeSession, Version, Vec<u8>)> {
@@ -139,19 +219,23 @@ impl RemoteMap {
             .collect()
     }
 
+    /// Owner and nodes in readers list can Sub and Get the map
+    pub fn can_read(&self, node: NodeId) -> bool {
+        match &self.acl {
+            Some(slot) => slot.owner == node || slot.acl.can_read(node),
+            None => true,
+        }
+    }
+
     pub fn on_client(&mut self, now: u64, remote: NodeSession, cmd: ClientMapCommand) -> Option<ServerMapEvent> {
+        if let Some(denied) = self.check_acl(remote, &cmd) {
+            log::warn!("[ServerMap] Command {:?} from {} denied by acl", cmd, remote.0);
+            return Some(denied);
+        }
         match cmd {
-            ClientMapCommand::Set(key, version, data) => {
-                let slot = self.get_slot(key, remote, true).expect("must have slot with auto_create");
-                if slot.set(now, version, data.clone()) {
-                    log::debug!("[ServerMap] Set key {} from {} with version {}", key, remote.0, version.0);
-                    self.fire_event(now, key, remote, ServerMapEvent::OnSet { key, version, source: remote, data });
-                    Some(ServerMapEvent::SetOk(key, version))
-                } else {
-                    log::warn!("[ServerMap] Set key {} from {} with version {} failed", key, remote.0, version.0);
-                    None
-                }
-            }
+            ClientMapCommand::Set(key, version, data) => self.on_set(now, remote, key, version, data, None),
+            ClientMapCommand::SetTtl(key, version, data, ttl_ms) => self.on_set(now, remote, key, version, data, Some(ttl_ms)),
+            ClientMapCommand::Batch(id, ops) => self.on_batch(now, remote, id, ops),
             ClientMapCommand::Del(key, req_version) => {
                 let slot = self.get_slot(key, remote, false)?;
                 if let Some(version) = slot.del(now, req_version) {
@@ -164,14 +248,9 @@ impl RemoteMap {
                     None
                 }
             }
-            ClientMapCommand::Sub(id, locked_session) => {
-                let old = self.subs.insert(remote, SubSlot { last_ts: now, id });
-                if old.is_none() || locked_session != Some(self.session) {
-                    log::debug!("[ServerMap] New sub from {} with id {}", remote.0, id);
-                    self.fire_sub_events(now, remote);
-                }
-                Some(ServerMapEvent::SubOk(id))
-            }
+            ClientMapCommand::Sub(id, locked_session) => self.on_sub(now, remote, id, locked_session, None),
+            ClientMapCommand::SubFiltered(id, locked_session, ranges) => self.on_sub(now, remote, id, locked_session, Some(ranges)),
+            ClientMapCommand::SetAcl(id, acl) => self.on_set_acl(now, remote, id, acl),
             ClientMapCommand::Unsub(id) => {
                 let sub = self.subs.get(&remote)?;
                 if sub.id == id {
@@ -206,7 +285,7 @@ impl RemoteMap {
             }
             ClientMapCommand::OnDelAck(key, session, acked_version) => {
                 let slot = self.slots_event.get_mut(&(key, session))?;
-                if let ServerMapEvent::OnDel { version, .. } = &slot.event {
+                if let ServerMapEvent::OnDel { version, .. } | ServerMapEvent::OnExpired { version, .. } = &slot.event {
                     if acked_version == *version {
                         log::debug!("[ServerMap] Acked del key {key} from {} with version {acked_version}", remote.0);
                         slot.remotes.retain(|r| *r != remote);
@@ -228,12 +307,109 @@ impl RemoteMap {
         }
     }
 
+    fn on_set(&mut self, now: u64, remote: NodeSession, key: Key, version: Version, data: Vec<u8>, ttl_ms: Option<u64>) -> Option<ServerMapEvent> {
+        let slot = self.get_slot(key, remote, true).expect("must have slot with auto_create");
+        if slot.set(now, version, data.clone(), ttl_ms) {
+            log::debug!("[ServerMap] Set key {} from {} with version {}, ttl {:?}", key, remote.0, version.0, ttl_ms);
+            self.fire_event(now, key, remote, ServerMapEvent::OnSet { key, version, source: remote, data });
+            Some(ServerMapEvent::SetOk(key, version))
+        } else {
+            log::warn!("[ServerMap] Set key {} from {} with version {} failed", key, remote.0, version.0);
+            None
+        }
+    }
+
+    /// Existing values are fired again when the sub is new, the relay is changed or the filter is changed
+    fn on_sub(&mut self, now: u64, remote: NodeSession, id: u64, locked_session: Option<NodeSession>, filter: Option<Vec<KeyRange>>) -> Option<ServerMapEvent> {
+        let filter_changed = self.subs.get(&remote).map(|sub| sub.filter != filter).unwrap_or(false);
+        let old = self.subs.insert(remote, SubSlot { last_ts: now, id, filter });
+        if old.is_none() || locked_session != Some(self.session) || filter_changed {
+            log::debug!("[ServerMap] New sub from {} with id {}, filter changed {}", remote.0, id, filter_changed);
+            self.fire_sub_events(now, remote);
+        }
+        Some(ServerMapEvent::SubOk(id))
+    }
+
+    /// Acks are always accepted, so events which are sent before the acl changed are not resent forever
+    fn check_acl(&self, remote: NodeSession, cmd: &ClientMapCommand) -> Option<ServerMapEvent> {
+        let slot = self.acl.as_ref()?;
+        if slot.owner == remote.0 {
+            return None;
+        }
+        match cmd {
+            ClientMapCommand::Set(key, _, _) | ClientMapCommand::SetTtl(key, _, _, _) | ClientMapCommand::Del(key, _) if !slot.acl.can_write(remote.0) => Some(ServerMapEvent::SetDenied(*key)),
+            ClientMapCommand::Batch(id, _) if !slot.acl.can_write(remote.0) => Some(ServerMapEvent::BatchDenied(*id)),
+            ClientMapCommand::Sub(id, _) | ClientMapCommand::SubFiltered(id, _, _) if !slot.acl.can_read(remote.0) => Some(ServerMapEvent::SubDenied(*id)),
+            ClientMapCommand::SetAcl(id, _) => Some(ServerMapEvent::AclDenied(*id)),
+            _ => None,
+        }
+    }
+
+    /// Existing subs which are not allowed by the new acl are removed with SubDenied.
+    /// Existing values from nodes which are not writers anymore are kept until their source deletes them or times out
+    fn on_set_acl(&mut self, now: u64, remote: NodeSession, id: u64, acl: MapAcl) -> Option<ServerMapEvent> {
+        if acl.is_open() {
+            log::info!("[ServerMap] Acl released by owner {}", remote.0);
+            self.acl = None;
+            return Some(ServerMapEvent::AclOk(id));
+        }
+
+        if self.acl.as_ref().map(|slot| slot.acl != acl).unwrap_or(true) {
+            log::info!("[ServerMap] Acl set by owner {}: {:?}", remote.0, acl);
+        }
+        let denied = self
+            .subs
+            .iter()
+            .filter(|(node, _)| node.0 != remote.0 && !acl.can_read(node.0))
+            .map(|(node, sub)| (*node, sub.id))
+            .collect::<Vec<_>>();
+        for (node, sub_id) in denied {
+            log::info!("[ServerMap] Remove sub from {} which is not allowed by new acl", node.0);
+            self.subs.remove(&node);
+            self.queue.push_back((node, ServerMapEvent::SubDenied(sub_id)));
+        }
+        self.acl = Some(AclSlot { owner: remote.0, acl, last_ts: now });
+        Some(ServerMapEvent::AclOk(id))
+    }
+
+    /// Batch is applied all or nothing, a single stale op rejects the whole batch.
+    /// Deleting a missing key is accepted, so a resent batch which was already applied is still acked
+    fn on_batch(&mut self, now: u64, remote: NodeSession, id: u64, ops: Vec<BatchOp>) -> Option<ServerMapEvent> {
+        let valid = ops.iter().all(|op| match (op, self.slots.get(&(op.key(), remote))) {
+            (BatchOp::Set(_, version, _) | BatchOp::Del(_, version), Some(MapSlot::Set { version: current, .. })) => current.0 <= version.0,
+            _ => true,
+        });
+        if !valid {
+            log::warn!("[ServerMap] Batch {id} from {} with {} ops rejected by stale version", remote.0, ops.len());
+            return None;
+        }
+
+        log::debug!("[ServerMap] Apply batch {id} from {} with {} ops", remote.0, ops.len());
+        for op in ops {
+            match op {
+                BatchOp::Set(key, version, data) => {
+                    let slot = self.get_slot(key, remote, true).expect("must have slot with auto_create");
+                    if slot.set(now, version, data.clone(), None) {
+                        self.fire_event(now, key, remote, ServerMapEvent::OnSet { key, version, source: remote, data });
+                    }
+                }
+                BatchOp::Del(key, req_version) => {
+                    if let Some(version) = self.get_slot(key, remote, false).and_then(|slot| slot.del(now, req_version)) {
+                        self.slots.remove(&(key, remote));
+                        self.fire_event(now, key, remote, ServerMapEvent::OnDel { key, version, source: remote });
+                    }
+                }
+            }
+        }
+        Some(ServerMapEvent::BatchOk(id))
+    }
+
     pub fn pop_action(&mut self) -> Option<(NodeSession, ServerMapEvent)> {
         self.queue.pop_front()
     }
 
     pub fn should_clean(&self) -> bool {
-        self.slots.is_empty() && self.subs.is_empty() && self.slots_event.is_empty()
+        self.slots.is_empty() && self.subs.is_empty() && self.slots_event.is_empty() && self.acl.is_none()
     }
 
     fn get_slot(&mut self, key: Key, source: NodeSession, auto_create: bool) -> Option<&mut MapSlot> {
@@ -250,8 +426,8 @@ impl RemoteMap {
             return;
         }
         let mut remotes = vec![];
-        for remote in self.subs.keys() {
-            if *remote != source {
+        for (remote, sub) in self.subs.iter() {
+            if *remote != source && sub.accept(key) {
                 log::debug!("[ServerMap] Fire event {:?} for key {key} to {}", event, remote.0);
                 remotes.push(*remote);
                 self.queue.push_back((*remote, event.clone()));
@@ -270,8 +446,9 @@ impl RemoteMap {
 
     /// We only send events which not owned by remote
     fn fire_sub_events(&mut self, now: u64, remote: NodeSession) {
+        let sub = self.subs.get(&remote).expect("Must have sub slot");
         for (key, slot) in self.slots.iter() {
-            if key.1 == remote {
+            if key.1 == remote || !sub.accept(key.0) {
                 continue;
             }
             if let Some((version, data)) = slot.dump() {
@@ -301,7 +478,7 @@ impl RemoteMap {
 mod test {
     use super::{MapSlot, RemoteMap};
     use crate::features::dht_kv::{
-        msg::{ClientMapCommand, Key, NodeSession, ServerMapEvent, Version},
+        msg::{BatchOp, ClientMapCommand, Key, KeyRange, MapAcl, NodeSession, ServerMapEvent, Version},
         server::map::{RESEND_MS, TIMEOUT_MS},
     };
 
@@ -326,9 +503,9 @@ mod test {
     fn map_slot_set_del_correct() {
         let mut slot = MapSlot::new();
 
-        assert_eq!(slot.set(0, Version(0), vec![1, 2, 3]), true);
+        assert_eq!(slot.set(0, Version(0), vec![1, 2, 3], None), true);
         assert_eq!(slot.dump(), Some((Version(0), vec![1, 2, 3])));
-        assert_eq!(slot.set(0, Version(1), vec![1, 2, 4]), true);
+        assert_eq!(slot.set(0, Version(1), vec![1, 2, 4], None), true);
         assert_eq!(slot.dump(), Some((Version(1), vec![1, 2, 4])));
         assert_eq!(slot.del(0, Version(1)), Some(Version(1)));
         assert_eq!(slot.dump(), None);
@@ -338,7 +515,7 @@ mod test {
     fn map_slot_set_del_newer_version_correct() {
         let mut slot = MapSlot::new();
 
-        assert_eq!(slot.set(0, Version(0), vec![1, 2, 3]), true);
+        assert_eq!(slot.set(0, Version(0), vec![1, 2, 3], None), true);
         assert_eq!(slot.dump(), Some((Version(0), vec![1, 2, 3])));
         assert_eq!(slot.del(0, Version(100)), Some(Version(0)));
         assert_eq!(slot.dump(), None);
@@ -348,9 +525,9 @@ mod test {
     fn map_slot_set_del_invalid() {
         let mut slot = MapSlot::new();
 
-        assert_eq!(slot.set(0, Version(100), vec![1, 2, 3]), true);
+        assert_eq!(slot.set(0, Version(100), vec![1, 2, 3], None), true);
         assert_eq!(slot.dump(), Some((Version(100), vec![1, 2, 3])));
-        assert_eq!(slot.set(0, Version(1), vec![1, 2, 4]), false);
+        assert_eq!(slot.set(0, Version(1), vec![1, 2, 4], None), false);
         assert_eq!(slot.dump(), Some((Version(100), vec![1, 2, 3])));
         assert_eq!(slot.del(0, Version(1)), None);
         assert_eq!(slot.dump(), Some((Version(100), vec![1, 2, 3])));
@@ -384,6 +561,214 @@ mod test {
         assert_eq!(map.pop_action(), Some((consumer, on_del(1000, 2, source))));
     }
 
+    #[test]
+    fn map_expire_ttl_slot() {
+        let relay = NodeSession(1, 2);
+        let mut map = RemoteMap::new(relay);
+
+        let source = NodeSession(3, 4);
+        let consumer = NodeSession(5, 6);
+
+        assert_eq!(map.on_client(0, consumer, ClientMapCommand::Sub(1, None)), Some(ServerMapEvent::SubOk(1)));
+        assert_eq!(
+            map.on_client(0, source, ClientMapCommand::SetTtl(Key(1000), Version(1), vec![1, 2, 3, 4], 1000)),
+            Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
+        );
+        assert_eq!(map.pop_action(), Some((consumer, on_set(1000, 1, source, vec![1, 2, 3, 4]))));
+        assert_eq!(map.on_client(1, consumer, ClientMapCommand::OnSetAck(Key(1000), source, Version(1))), None);
+
+        //resync with remaining ttl doesn't extend the expiry
+        assert_eq!(
+            map.on_client(500, source, ClientMapCommand::SetTtl(Key(1000), Version(1), vec![1, 2, 3, 4], 500)),
+            Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
+        );
+        assert_eq!(map.pop_action(), Some((consumer, on_set(1000, 1, source, vec![1, 2, 3, 4]))));
+        assert_eq!(map.on_client(501, consumer, ClientMapCommand::OnSetAck(Key(1000), source, Version(1))), None);
+
+        assert_eq!(map.on_tick(999), vec![]);
+        assert_eq!(map.on_tick(1000), vec![(Key(1000), source)]);
+        let expired = ServerMapEvent::OnExpired {
+            key: Key(1000),
+            source,
+            version: Version(1),
+        };
+        assert_eq!(map.pop_action(), Some((consumer, expired)));
+        assert_eq!(map.pop_action(), None);
+        assert_eq!(map.dump(), vec![]);
+
+        //expired event is acked like del
+        assert_eq!(map.on_client(1001, consumer, ClientMapCommand::OnDelAck(Key(1000), source, Version(1))), None);
+        map.on_tick(1000 + RESEND_MS);
+        assert_eq!(map.pop_action(), None);
+        assert!(!map.should_clean());
+    }
+
+    #[test]
+    fn map_apply_batch_with_single_ack() {
+        let relay = NodeSession(1, 2);
+        let mut map = RemoteMap::new(relay);
+
+        let source = NodeSession(3, 4);
+        let consumer = NodeSession(5, 6);
+
+        assert_eq!(map.on_client(0, consumer, ClientMapCommand::Sub(1, None)), Some(ServerMapEvent::SubOk(1)));
+        assert_eq!(
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1])),
+            Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
+        );
+        assert_eq!(map.pop_action(), Some((consumer, on_set(1000, 1, source, vec![1]))));
+
+        let batch = || {
+            ClientMapCommand::Batch(
+                10,
+                vec![
+                    BatchOp::Set(Key(1001), Version(2), vec![2]),
+                    BatchOp::Set(Key(1002), Version(2), vec![3]),
+                    BatchOp::Del(Key(1000), Version(2)),
+                ],
+            )
+        };
+        assert_eq!(map.on_client(2, source, batch()), Some(ServerMapEvent::BatchOk(10)));
+        let mut events = vec![];
+        while let Some(event) = map.pop_action() {
+            events.push(event);
+        }
+        assert_eq!(
+            events,
+            vec![
+                (consumer, on_set(1001, 2, source, vec![2])),
+                (consumer, on_set(1002, 2, source, vec![3])),
+                (consumer, on_del(1000, 1, source)),
+            ]
+        );
+
+        //resent batch is still acked
+        assert_eq!(map.on_client(3, source, batch()), Some(ServerMapEvent::BatchOk(10)));
+        assert_eq!(map.slots.len(), 2);
+    }
+
+    #[test]
+    fn map_reject_batch_with_stale_op() {
+        let relay = NodeSession(1, 2);
+        let mut map = RemoteMap::new(relay);
+
+        let source = NodeSession(3, 4);
+        let consumer = NodeSession(5, 6);
+
+        assert_eq!(map.on_client(0, consumer, ClientMapCommand::Sub(1, None)), Some(ServerMapEvent::SubOk(1)));
+        assert_eq!(
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(5), vec![1])),
+            Some(ServerMapEvent::SetOk(Key(1000), Version(5)))
+        );
+        assert_eq!(map.pop_action(), Some((consumer, on_set(1000, 5, source, vec![1]))));
+
+        //stale set of key 1000 rejects the whole batch
+        let batch = ClientMapCommand::Batch(11, vec![BatchOp::Set(Key(1001), Version(2), vec![2]), BatchOp::Set(Key(1000), Version(2), vec![3])]);
+        assert_eq!(map.on_client(2, source, batch), None);
+        assert_eq!(map.pop_action(), None);
+        assert_eq!(map.dump(), vec![(Key(1000), source, Version(5), vec![1])]);
+    }
+
+    #[test]
+    fn map_filtered_sub_only_fires_matching_keys() {
+        let relay = NodeSession(1, 2);
+        let mut map = RemoteMap::new(relay);
+
+        let source = NodeSession(3, 4);
+        let consumer = NodeSession(5, 6);
+
+        assert_eq!(
+            map.on_client(0, source, ClientMapCommand::Set(Key(1000), Version(1), vec![1])),
+            Some(ServerMapEvent::SetOk(Key(1000), Version(1)))
+        );
+        assert_eq!(
+            map.on_client(0, source, ClientMapCommand::Set(Key(2000), Version(1), vec![2])),
+            Some(ServerMapEvent::SetOk(Key(2000), Version(1)))
+        );
+
+        let ranges = vec![KeyRange::new(Key(1000), Key(1999))];
+        assert_eq!(map.on_client(1, consumer, ClientMapCommand::SubFiltered(1, None, ranges.clone())), Some(ServerMapEvent::SubOk(1)));
+        assert_eq!(map.pop_action(), Some((consumer, on_set(1000, 1, source, vec![1]))));
+        assert_eq!(map.pop_action(), None);
+
+        assert_eq!(
+            map.on_client(2, source, ClientMapCommand::Set(Key(2001), Version(2), vec![3])),
+            Some(ServerMapEvent::SetOk(Key(2001), Version(2)))
+        );
+        assert_eq!(map.pop_action(), None);
+        assert_eq!(
+            map.on_client(2, source, ClientMapCommand::Del(Key(1000), Version(2))),
+            Some(ServerMapEvent::DelOk(Key(1000), Version(1)))
+        );
+        assert_eq!(map.pop_action(), Some((consumer, on_del(1000, 1, source))));
+
+        //same filter from locked relay doesn't fire existing values again
+        assert_eq!(map.on_client(3, consumer, ClientMapCommand::SubFiltered(1, Some(relay), ranges)), Some(ServerMapEvent::SubOk(1)));
+        assert_eq!(map.pop_action(), None);
+
+        //widen the filter fires values which are inside it now
+        assert_eq!(map.on_client(4, consumer, ClientMapCommand::Sub(1, Some(relay))), Some(ServerMapEvent::SubOk(1)));
+        let mut events = vec![];
+        while let Some((remote, event)) = map.pop_action() {
+            events.push((remote, event));
+        }
+        events.sort_by_key(|(_, event)| match event {
+            ServerMapEvent::OnSet { key, .. } => key.0,
+            _ => 0,
+        });
+        assert_eq!(events, vec![(consumer, on_set(2000, 1, source, vec![2])), (consumer, on_set(2001, 2, source, vec![3]))]);
+    }
+
+    #[test]
+    fn map_acl_denies_other_nodes() {
+        let relay = NodeSession(1, 2);
+        let mut map = RemoteMap::new(relay);
+
+        let owner = NodeSession(3, 4);
+        let writer = NodeSession(5, 6);
+        let other = NodeSession(7, 8);
+
+        assert_eq!(map.on_client(0, other, ClientMapCommand::Sub(1, None)), Some(ServerMapEvent::SubOk(1)));
+        let acl = MapAcl {
+            writers: Some(vec![writer.0]),
+            readers: Some(vec![writer.0]),
+        };
+        assert_eq!(map.on_client(1, owner, ClientMapCommand::SetAcl(10, acl.clone())), Some(ServerMapEvent::AclOk(10)));
+        //existing sub which isn't allowed anymore is removed
+        assert_eq!(map.pop_action(), Some((other, ServerMapEvent::SubDenied(1))));
+        assert!(!map.can_read(other.0));
+        assert!(map.can_read(owner.0));
+
+        assert_eq!(map.on_client(2, other, ClientMapCommand::SetAcl(11, MapAcl::default())), Some(ServerMapEvent::AclDenied(11)));
+        assert_eq!(map.on_client(2, other, ClientMapCommand::Set(Key(1), Version(1), vec![1])), Some(ServerMapEvent::SetDenied(Key(1))));
+        assert_eq!(map.on_client(2, other, ClientMapCommand::Del(Key(1), Version(1))), Some(ServerMapEvent::SetDenied(Key(1))));
+        assert_eq!(
+            map.on_client(2, other, ClientMapCommand::Batch(12, vec![BatchOp::Set(Key(1), Version(1), vec![1])])),
+            Some(ServerMapEvent::BatchDenied(12))
+        );
+        assert_eq!(map.on_client(2, other, ClientMapCommand::SubFiltered(2, None, vec![])), Some(ServerMapEvent::SubDenied(2)));
+        assert_eq!(map.slots.len(), 0);
+
+        assert_eq!(map.on_client(3, writer, ClientMapCommand::Sub(3, None)), Some(ServerMapEvent::SubOk(3)));
+        assert_eq!(
+            map.on_client(3, owner, ClientMapCommand::Set(Key(1), Version(1), vec![1])),
+            Some(ServerMapEvent::SetOk(Key(1), Version(1)))
+        );
+        assert_eq!(map.pop_action(), Some((writer, on_set(1, 1, owner, vec![1]))));
+        assert_eq!(
+            map.on_client(3, writer, ClientMapCommand::Set(Key(2), Version(1), vec![2])),
+            Some(ServerMapEvent::SetOk(Key(2), Version(1)))
+        );
+
+        //acl is removed if owner doesn't resync it
+        map.on_tick(1 + TIMEOUT_MS - 1);
+        assert_eq!(map.on_client(1 + TIMEOUT_MS - 1, owner, ClientMapCommand::SetAcl(10, acl)), Some(ServerMapEvent::AclOk(10)));
+        map.on_tick(1 + TIMEOUT_MS);
+        assert!(!map.can_read(other.0));
+        map.on_tick(2 * TIMEOUT_MS);
+        assert!(map.can_read(other.0));
+    }
+
     #[test]
     fn map_correct_sub_after_set_event() {
         let relay = NodeSession(1, 2);
@@ -0,0 +1,203 @@
+//! Storage backends for maps which this node serves.
+//!
+//! By default maps are only kept in memory and a restart wipes them. With a [`KvStorageBackend`], each accepted set or del
+//! is written through to the backend, and stored slots are loaded back when the feature is created.
+//! [`FileKvStorage`] is a dependency-free on-disk backend, other databases can be plugged by implementing the trait.
+//! Backends are called from the controller loop, so they should be fast and handle their own errors.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::Path,
+};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::msg::{Key, Map, NodeSession, Version};
+
+/// A slot of a map which is owned by source node session
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredSlot {
+    pub map: Map,
+    pub key: Key,
+    pub source: NodeSession,
+    pub version: Version,
+    pub data: Vec<u8>,
+}
+
+pub trait KvStorageBackend: Send + Sync {
+    /// Load all stored slots, which is called once when the dht_kv feature is created
+    fn load(&self) -> Vec<StoredSlot>;
+    fn set(&self, map: Map, key: Key, source: NodeSession, version: Version, data: &[u8]);
+    fn del(&self, map: Map, key: Key, source: NodeSession);
+}
+
+/// In-memory backend, which keeps slots across feature instances sharing it, like a node which is restarted in the same process
+#[derive(Debug, Default)]
+pub struct MemoryKvStorage {
+    #[allow(clippy::type_complexity)]
+    slots: Mutex<HashMap<(Map, Key, NodeSession), (Version, Vec<u8>)>>,
+}
+
+impl KvStorageBackend for MemoryKvStorage {
+    fn load(&self) -> Vec<StoredSlot> {
+        self.slots
+            .lock()
+            .iter()
+            .map(|((map, key, source), (version, data))| StoredSlot {
+                map: *map,
+                key: *key,
+                source: *source,
+                version: *version,
+                data: data.clone(),
+            })
+            .collect()
+    }
+
+    fn set(&self, map: Map, key: Key, source: NodeSession, version: Version, data: &[u8]) {
+        self.slots.lock().insert((map, key, source), (version, data.to_vec()));
+    }
+
+    fn del(&self, map: Map, key: Key, source: NodeSession) {
+        self.slots.lock().remove(&(map, key, source));
+    }
+}
+
+/// Record of the on-disk log
+#[derive(Debug, Serialize, Deserialize)]
+enum FileRecord {
+    Set(Map, Key, NodeSession, Version, Vec<u8>),
+    Del(Map, Key, NodeSession),
+}
+
+/// On-disk backend with an append-only log file. Each record is a u32 little-endian length followed by the bincode encoded record.
+/// The log is replayed and compacted when opening, a truncated record at the end (crash while writing) is ignored.
+pub struct FileKvStorage {
+    file: Mutex<File>,
+    memory: MemoryKvStorage,
+}
+
+impl FileKvStorage {
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let memory = MemoryKvStorage::default();
+        if let Ok(mut file) = File::open(path) {
+            let mut buf = vec![];
+            file.read_to_end(&mut buf)?;
+            let mut remain = &buf[..];
+            while remain.len() >= 4 {
+                let len = u32::from_le_bytes([remain[0], remain[1], remain[2], remain[3]]) as usize;
+                if remain.len() < 4 + len {
+                    log::warn!("[FileKvStorage] ignore truncated record at the end of {}", path.display());
+                    break;
+                }
+                match bincode::deserialize::<FileRecord>(&remain[4..4 + len]) {
+                    Ok(FileRecord::Set(map, key, source, version, data)) => memory.set(map, key, source, version, &data),
+                    Ok(FileRecord::Del(map, key, source)) => memory.del(map, key, source),
+                    Err(e) => log::warn!("[FileKvStorage] skip invalid record {e}"),
+                }
+                remain = &remain[4 + len..];
+            }
+        }
+
+        // compact by rewriting current slots to a new file, then replace the old one
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        for slot in memory.load() {
+            file.write_all(&encode(&FileRecord::Set(slot.map, slot.key, slot.source, slot.version, slot.data)))?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Self { file: Mutex::new(file), memory })
+    }
+
+    fn append(&self, record: &FileRecord) {
+        if let Err(e) = self.file.lock().write_all(&encode(record)) {
+            log::error!("[FileKvStorage] write {:?} error {e}", record);
+        }
+    }
+}
+
+fn encode(record: &FileRecord) -> Vec<u8> {
+    let data = bincode::serialize(record).expect("Should serialize");
+    let mut buf = Vec::with_capacity(4 + data.len());
+    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&data);
+    buf
+}
+
+impl KvStorageBackend for FileKvStorage {
+    fn load(&self) -> Vec<StoredSlot> {
+        self.memory.load()
+    }
+
+    fn set(&self, map: Map, key: Key, source: NodeSession, version: Version, data: &[u8]) {
+        self.memory.set(map, key, source, version, data);
+        self.append(&FileRecord::Set(map, key, source, version, data.to_vec()));
+    }
+
+    fn del(&self, map: Map, key: Key, source: NodeSession) {
+        self.memory.del(map, key, source);
+        self.append(&FileRecord::Del(map, key, source));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::{FileKvStorage, KvStorageBackend, MemoryKvStorage, StoredSlot};
+    use crate::features::dht_kv::msg::{Key, Map, NodeSession, Version};
+
+    fn slot(key: u64, version: u64, data: Vec<u8>) -> StoredSlot {
+        StoredSlot {
+            map: Map(1),
+            key: Key(key),
+            source: NodeSession(1, 1000),
+            version: Version(version),
+            data,
+        }
+    }
+
+    fn check_backend(backend: &dyn KvStorageBackend) {
+        let source = NodeSession(1, 1000);
+        backend.set(Map(1), Key(1), source, Version(1), &[1]);
+        backend.set(Map(1), Key(2), source, Version(1), &[2]);
+        backend.set(Map(1), Key(1), source, Version(2), &[3]);
+        backend.del(Map(1), Key(2), source);
+
+        assert_eq!(backend.load(), vec![slot(1, 2, vec![3])]);
+    }
+
+    #[test]
+    fn memory_storage() {
+        check_backend(&MemoryKvStorage::default());
+    }
+
+    #[test]
+    fn file_storage() {
+        let path = std::env::temp_dir().join(format!("atm0s-sdn-kv-test-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let backend = FileKvStorage::open(&path).expect("Should open");
+        check_backend(&backend);
+        drop(backend);
+
+        // reopen for checking persisted data, with a truncated record at the end
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).expect("Should open file");
+        file.write_all(&[100, 0, 0, 0, 1, 2]).expect("Should write");
+        drop(file);
+        let backend = FileKvStorage::open(&path).expect("Should open");
+        assert_eq!(backend.load(), vec![slot(1, 2, vec![3])]);
+        backend.set(Map(2), Key(1), NodeSession(1, 1000), Version(1), &[4]);
+        drop(backend);
+
+        let backend = FileKvStorage::open(&path).expect("Should open");
+        let mut slots = backend.load();
+        slots.sort_by_key(|s| s.map);
+        assert_eq!(slots, vec![slot(1, 2, vec![3]), StoredSlot { map: Map(2), ..slot(1, 1, vec![4]) }]);
+        std::fs::remove_file(path).expect("Should remove test file");
+    }
+}
@@ -0,0 +1,350 @@
+//! Diagnostics of overlay paths.
+//!
+//! Probes are sent with a small ttl: a relay which receives a probe with ttl 0 answers it instead of forwarding it, so
+//! each ttl reveals one hop of the path, like ICMP time exceeded. [`Control::Ping`] sends probes for all ttls at once and
+//! reports the rtt of each hop, [`Control::TraceRoute`] sends them one after another until the destination answers.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+};
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_router::RouteRule;
+use derivative::Derivative;
+use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
+use serde::{Deserialize, Serialize};
+
+use crate::base::{
+    Feature, FeatureContext, FeatureControlActor, FeatureError, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput, NetOutgoingMeta, Ttl,
+};
+
+pub const FEATURE_ID: u8 = 11;
+pub const FEATURE_NAME: &str = "diagnostics";
+
+/// Max number of hops which are probed
+pub const DIAG_MAX_HOPS: u8 = 16;
+/// Time for waiting answers of all probes of a ping
+const PING_TIMEOUT_MS: u64 = 2000;
+/// Time for waiting the answer of each hop of a trace route, the hop is reported as unknown after it
+const HOP_TIMEOUT_MS: u64 = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Control {
+    /// Measure rtt to each hop of the path to the node
+    Ping(NodeId),
+    /// Record nodes of the path to the node, hop by hop
+    TraceRoute(NodeId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hop {
+    pub node: NodeId,
+    pub rtt_ms: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Hops of the path in order, None for hops which didn't answer. The last hop is the destination if it is reached
+    Ping(NodeId, Vec<Option<Hop>>),
+    /// Nodes of the path in order, None for hops which didn't answer. The last node is the destination if it is reached
+    TraceRoute(NodeId, Vec<Option<NodeId>>),
+}
+
+impl Event {
+    pub fn error(&self) -> Option<FeatureError> {
+        let reached = match self {
+            Self::Ping(dest, hops) => matches!(hops.last(), Some(Some(hop)) if hop.node == *dest),
+            Self::TraceRoute(dest, nodes) => nodes.last() == Some(&Some(*dest)),
+        };
+        (!reached).then_some(FeatureError::Timeout)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ToWorker;
+
+#[derive(Debug, Clone)]
+pub struct ToController;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum DiagMsg {
+    Probe {
+        session: u64,
+        ttl: u8,
+        ts: u64,
+        from: NodeId,
+        dest: NodeId,
+    },
+    /// Answer of a relay which the probe is expired at
+    Expired {
+        session: u64,
+        ttl: u8,
+        ts: u64,
+        node: NodeId,
+    },
+    /// Answer of the destination
+    Reached {
+        session: u64,
+        ttl: u8,
+        ts: u64,
+        node: NodeId,
+    },
+}
+
+pub type Output<UserData> = FeatureOutput<UserData, Event, ToWorker>;
+pub type WorkerOutput<UserData> = FeatureWorkerOutput<UserData, Control, Event, ToController>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Ping,
+    TraceRoute,
+}
+
+struct Session<UserData> {
+    actor: FeatureControlActor<UserData>,
+    dest: NodeId,
+    kind: Kind,
+    /// Send time of the last probe, all probes of a ping are sent together
+    sent_ms: u64,
+    /// Ttl of the last sent probe
+    ttl: u8,
+    hops: Vec<Option<Hop>>,
+    /// Lowest ttl which reached the destination
+    reached: Option<u8>,
+}
+
+impl<UserData> Session<UserData> {
+    fn is_finished(&self) -> bool {
+        match (self.kind, self.reached) {
+            (Kind::Ping, Some(reached)) => self.hops[..reached as usize].iter().all(|hop| hop.is_some()),
+            (Kind::TraceRoute, Some(_)) => true,
+            (_, None) => false,
+        }
+    }
+
+    fn into_event(mut self) -> Event {
+        let len = match self.reached {
+            Some(reached) => reached as usize + 1,
+            None => self.hops.iter().rposition(|hop| hop.is_some()).map(|pos| pos + 1).unwrap_or(0),
+        };
+        self.hops.truncate(len);
+        match self.kind {
+            Kind::Ping => Event::Ping(self.dest, self.hops),
+            Kind::TraceRoute => Event::TraceRoute(self.dest, self.hops.into_iter().map(|hop| hop.map(|hop| hop.node)).collect()),
+        }
+    }
+}
+
+#[derive(Derivative)]
+#[derivative(Default(bound = ""))]
+pub struct DiagFeature<UserData> {
+    sessions: HashMap<u64, Session<UserData>>,
+    session_seq: u64,
+    queue: VecDeque<Output<UserData>>,
+    shutdown: bool,
+}
+
+impl<UserData: Copy> DiagFeature<UserData> {
+    fn send_probe(&mut self, node_id: NodeId, session: u64, dest: NodeId, ttl: u8, now_ms: u64) {
+        let msg = bincode::serialize(&DiagMsg::Probe {
+            session,
+            ttl,
+            ts: now_ms,
+            from: node_id,
+            dest,
+        })
+        .expect("should work");
+        let meta = NetOutgoingMeta::new(true, Ttl(ttl), 0, false);
+        self.queue.push_back(FeatureOutput::SendRoute(RouteRule::ToNode(dest), meta, msg.into()));
+    }
+
+    fn reply(&mut self, to: NodeId, msg: DiagMsg) {
+        let msg = bincode::serialize(&msg).expect("should work");
+        self.queue.push_back(FeatureOutput::SendRoute(RouteRule::ToNode(to), NetOutgoingMeta::default(), msg.into()));
+    }
+
+    fn on_answer(&mut self, node_id: NodeId, now_ms: u64, session_id: u64, ttl: u8, answer: Hop, reached: bool) {
+        let Some(session) = self.sessions.get_mut(&session_id) else {
+            log::debug!("[DiagFeature] answer of unknown session {session_id} from {}", answer.node);
+            return;
+        };
+        let Some(hop) = session.hops.get_mut(ttl as usize) else {
+            return;
+        };
+        // a late answer of a trace route hop which is already reported as unknown is ignored
+        if session.kind == Kind::TraceRoute && ttl != session.ttl {
+            return;
+        }
+        *hop = Some(answer);
+        if reached {
+            session.reached = Some(session.reached.map_or(ttl, |reached| reached.min(ttl)));
+        }
+        if session.is_finished() {
+            let session = self.sessions.remove(&session_id).expect("Should have");
+            self.queue.push_back(FeatureOutput::Event(session.actor, session.into_event()));
+        } else if session.kind == Kind::TraceRoute {
+            self.next_hop(node_id, session_id, now_ms);
+        }
+    }
+
+    /// Probe the next hop of a trace route, or finish it after the max hops
+    fn next_hop(&mut self, node_id: NodeId, session_id: u64, now_ms: u64) {
+        let session = self.sessions.get_mut(&session_id).expect("Should have");
+        if session.ttl + 1 >= DIAG_MAX_HOPS {
+            let session = self.sessions.remove(&session_id).expect("Should have");
+            self.queue.push_back(FeatureOutput::Event(session.actor, session.into_event()));
+            return;
+        }
+        session.ttl += 1;
+        session.sent_ms = now_ms;
+        let (dest, ttl) = (session.dest, session.ttl);
+        self.send_probe(node_id, session_id, dest, ttl, now_ms);
+    }
+}
+
+impl<UserData: Copy> Feature<UserData, Control, Event, ToController, ToWorker> for DiagFeature<UserData> {
+    fn on_shared_input(&mut self, ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
+        if let FeatureSharedInput::Tick(_) = input {
+            let mut timeout_list = Vec::new();
+            for (id, session) in self.sessions.iter() {
+                let timeout = match session.kind {
+                    Kind::Ping => PING_TIMEOUT_MS,
+                    Kind::TraceRoute => HOP_TIMEOUT_MS,
+                };
+                if now >= session.sent_ms + timeout {
+                    timeout_list.push((*id, session.kind));
+                }
+            }
+
+            for (id, kind) in timeout_list {
+                match kind {
+                    Kind::Ping => {
+                        let session = self.sessions.remove(&id).expect("Should have");
+                        log::info!("[DiagFeature] ping to {} is timeout", session.dest);
+                        self.queue.push_back(FeatureOutput::Event(session.actor, session.into_event()));
+                    }
+                    Kind::TraceRoute => self.next_hop(ctx.node_id, id, now),
+                }
+            }
+        }
+    }
+
+    fn on_input(&mut self, ctx: &FeatureContext, now_ms: u64, input: FeatureInput<'_, UserData, Control, ToController>) {
+        match input {
+            FeatureInput::Control(actor, control) => {
+                let (dest, kind) = match control {
+                    Control::Ping(dest) => (dest, Kind::Ping),
+                    Control::TraceRoute(dest) => (dest, Kind::TraceRoute),
+                };
+                log::info!("[DiagFeature] start {kind:?} to {dest}");
+                let session_id = self.session_seq;
+                self.session_seq += 1;
+                let session = Session {
+                    actor,
+                    dest,
+                    kind,
+                    sent_ms: now_ms,
+                    ttl: 0,
+                    hops: vec![None; DIAG_MAX_HOPS as usize],
+                    reached: None,
+                };
+                self.sessions.insert(session_id, session);
+                match kind {
+                    Kind::Ping => {
+                        for ttl in 0..DIAG_MAX_HOPS {
+                            self.send_probe(ctx.node_id, session_id, dest, ttl, now_ms);
+                        }
+                    }
+                    Kind::TraceRoute => self.send_probe(ctx.node_id, session_id, dest, 0, now_ms),
+                }
+            }
+            FeatureInput::Net(_, _, buf) | FeatureInput::Local(_, buf) => {
+                let Ok(msg) = bincode::deserialize::<DiagMsg>(&buf) else {
+                    log::warn!("[DiagFeature] invalid message");
+                    return;
+                };
+                match msg {
+                    DiagMsg::Probe { session, ttl, ts, from, dest } => {
+                        let node = ctx.node_id;
+                        if dest == node {
+                            self.reply(from, DiagMsg::Reached { session, ttl, ts, node });
+                        } else {
+                            log::debug!("[DiagFeature] probe from {from} to {dest} is expired");
+                            self.reply(from, DiagMsg::Expired { session, ttl, ts, node });
+                        }
+                    }
+                    DiagMsg::Expired { session, ttl, ts, node } | DiagMsg::Reached { session, ttl, ts, node } => {
+                        let reached = matches!(msg, DiagMsg::Reached { .. });
+                        let rtt_ms = now_ms.saturating_sub(ts) as u32;
+                        self.on_answer(ctx.node_id, now_ms, session, ttl, Hop { node, rtt_ms }, reached);
+                    }
+                }
+            }
+            FeatureInput::FromWorker(_) => {}
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &FeatureContext, _now: u64) {
+        self.shutdown = true;
+    }
+}
+
+impl<UserData> TaskSwitcherChild<Output<UserData>> for DiagFeature<UserData> {
+    type Time = u64;
+
+    fn is_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn empty_event(&self) -> Output<UserData> {
+        Output::OnResourceEmpty
+    }
+
+    fn pop_output(&mut self, _now: u64) -> Option<Output<UserData>> {
+        self.queue.pop_front()
+    }
+}
+
+#[derive(Derivative)]
+#[derivative(Default(bound = ""))]
+pub struct DiagFeatureWorker<UserData> {
+    queue: DynamicDeque<WorkerOutput<UserData>, 1>,
+    shutdown: bool,
+}
+
+impl<UserData> FeatureWorker<UserData, Control, Event, ToController, ToWorker> for DiagFeatureWorker<UserData> {
+    fn on_input(&mut self, _ctx: &mut crate::base::FeatureWorkerContext, _now: u64, input: FeatureWorkerInput<UserData, Control, ToWorker>) {
+        match input {
+            FeatureWorkerInput::Control(actor, control) => self.queue.push_back(FeatureWorkerOutput::ForwardControlToController(actor, control)),
+            FeatureWorkerInput::Network(conn, header, buf) => self.queue.push_back(FeatureWorkerOutput::ForwardNetworkToController(conn, header, buf)),
+            #[cfg(feature = "vpn")]
+            FeatureWorkerInput::TunPkt(..) => {}
+            FeatureWorkerInput::FromController(..) => {
+                log::warn!("No handler for FromController");
+            }
+            FeatureWorkerInput::Local(header, buf) => self.queue.push_back(FeatureWorkerOutput::ForwardLocalToController(header, buf)),
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &mut crate::base::FeatureWorkerContext, _now: u64) {
+        log::info!("[DiagFeatureWorker] Shutdown");
+        self.shutdown = true;
+    }
+}
+
+impl<UserData> TaskSwitcherChild<WorkerOutput<UserData>> for DiagFeatureWorker<UserData> {
+    type Time = u64;
+
+    fn is_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn empty_event(&self) -> WorkerOutput<UserData> {
+        WorkerOutput::OnResourceEmpty
+    }
+
+    fn pop_output(&mut self, _now: u64) -> Option<WorkerOutput<UserData>> {
+        self.queue.pop_front()
+    }
+}
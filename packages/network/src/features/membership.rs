@@ -0,0 +1,645 @@
+//! SWIM-style cluster membership with failure detection.
+//!
+//! Each node keeps a member list with incarnation numbers, which is exchanged with new neighbours after capabilities are
+//! negotiated and then kept up to date with updates which are piggybacked on probe messages. Every [`PROBE_INTERVAL_MS`]
+//! a node pings one member in a shuffled round-robin order; if no ack arrives in [`PROBE_TIMEOUT_MS`] it asks
+//! [`INDIRECT_PROBES`] other members to ping the target on its behalf, and if they don't relay an ack in
+//! [`INDIRECT_TIMEOUT_MS`] the target is suspected. A suspected node refutes by increasing its incarnation, otherwise it is
+//! declared dead after [`SUSPECT_TIMEOUT_MS`]. Dead members are kept for [`DEAD_RETAIN_MS`] so stale updates cannot revive
+//! them, only an alive update with a higher incarnation, which is sent by the node itself, brings a member back.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    hash::Hash,
+};
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_router::RouteRule;
+use atm0s_sdn_utils::log_sampled;
+use derivative::Derivative;
+use rand::seq::SliceRandom;
+use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
+use serde::{Deserialize, Serialize};
+
+use crate::base::{
+    Capabilities, ConnectionEvent, Feature, FeatureContext, FeatureControlActor, FeatureError, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput,
+    MsgPriority, NetOutgoingMeta, Ttl,
+};
+
+pub const FEATURE_ID: u8 = 10;
+pub const FEATURE_NAME: &str = "membership";
+/// A new probe is started at most once per interval
+pub const PROBE_INTERVAL_MS: u64 = 1000;
+/// Direct ping which is not acked in this timeout is retried over other members
+pub const PROBE_TIMEOUT_MS: u64 = 500;
+/// Target is suspected if no indirect ack arrives in this timeout
+pub const INDIRECT_TIMEOUT_MS: u64 = 1000;
+/// Number of members which are asked to ping the target on behalf of this node
+pub const INDIRECT_PROBES: usize = 3;
+/// Suspected member which doesn't refute in this timeout is declared dead
+pub const SUSPECT_TIMEOUT_MS: u64 = 5000;
+/// Dead members are kept for rejecting stale updates before they are removed
+pub const DEAD_RETAIN_MS: u64 = 30000;
+/// Max number of updates which are piggybacked on each message
+pub const MAX_PIGGYBACK: usize = 8;
+/// Each update is sent `RETRANSMIT_MULT * log2(members)` times
+const RETRANSMIT_MULT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MemberState {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Member {
+    pub node: NodeId,
+    pub incarnation: u64,
+    pub state: MemberState,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Control {
+    /// Subscribe state changes of members
+    Sub,
+    UnSub,
+    /// Get the member list including this node, result is [`Event::Members`]
+    Members,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Member joined or refuted a suspicion, with its incarnation
+    Alive(NodeId, u64),
+    Suspect(NodeId, u64),
+    Dead(NodeId, u64),
+    /// Members sorted by node id
+    Members(Vec<Member>),
+}
+
+impl Event {
+    pub fn error(&self) -> Option<FeatureError> {
+        None
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ToWorker;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ToController;
+
+pub type Output<UserData> = FeatureOutput<UserData, Event, ToWorker>;
+pub type WorkerOutput<UserData> = FeatureWorkerOutput<UserData, Control, Event, ToController>;
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum MessageKind {
+    Ping(u64),
+    Ack(u64),
+    /// Ping the node on behalf of the sender and relay the ack with the sequence
+    PingReq(u64, NodeId),
+    /// Full member list for a new neighbour
+    Sync,
+}
+
+/// Every message carries the incarnation of its sender and some member updates
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Message {
+    incarnation: u64,
+    kind: MessageKind,
+    updates: Vec<Member>,
+}
+
+#[derive(Debug)]
+struct MemberSlot {
+    incarnation: u64,
+    state: MemberState,
+    /// Time of the last state change, used for suspect and dead timeouts
+    changed_at: u64,
+}
+
+#[derive(Debug)]
+struct Probe {
+    target: NodeId,
+    seq: u64,
+    started_at: u64,
+    indirect_at: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Relay {
+    requester: NodeId,
+    seq: u64,
+    started_at: u64,
+}
+
+#[derive(Debug, Derivative)]
+#[derivative(Default(bound = ""))]
+pub struct MembershipFeature<UserData> {
+    incarnation: u64,
+    members: HashMap<NodeId, MemberSlot>,
+    subs: Vec<FeatureControlActor<UserData>>,
+    /// Updates which are waiting for piggybacking, with remaining transmit count
+    gossip: Vec<(Member, usize)>,
+    probe_order: Vec<NodeId>,
+    probe: Option<Probe>,
+    last_probe_at: u64,
+    /// Pings which are sent for other members, by own sequence
+    relays: HashMap<u64, Relay>,
+    seq: u64,
+    queue: VecDeque<Output<UserData>>,
+    shutdown: bool,
+}
+
+impl<UserData: Debug + Copy + Eq> MembershipFeature<UserData> {
+    fn members(&self, node_id: NodeId) -> Vec<Member> {
+        let mut members: Vec<Member> = self
+            .members
+            .iter()
+            .map(|(node, slot)| Member {
+                node: *node,
+                incarnation: slot.incarnation,
+                state: slot.state,
+            })
+            .collect();
+        members.push(Member {
+            node: node_id,
+            incarnation: self.incarnation,
+            state: MemberState::Alive,
+        });
+        members.sort_by_key(|member| member.node);
+        members
+    }
+
+    fn retransmit(&self) -> usize {
+        let n = self.members.len() + 1;
+        RETRANSMIT_MULT * (usize::BITS - n.leading_zeros()) as usize
+    }
+
+    fn gossip(&mut self, member: Member) {
+        let count = self.retransmit();
+        self.gossip.retain(|(m, _)| m.node != member.node);
+        self.gossip.push((member, count));
+    }
+
+    /// Take freshest updates for a message, updates which are sent enough times are removed
+    fn piggyback(&mut self) -> Vec<Member> {
+        self.gossip.sort_by(|a, b| b.1.cmp(&a.1));
+        let mut updates = Vec::with_capacity(MAX_PIGGYBACK);
+        for (member, count) in self.gossip.iter_mut().take(MAX_PIGGYBACK) {
+            updates.push(*member);
+            *count -= 1;
+        }
+        self.gossip.retain(|(_, count)| *count > 0);
+        updates
+    }
+
+    fn send(&mut self, dest: NodeId, kind: MessageKind) {
+        let updates = self.piggyback();
+        let msg = Message {
+            incarnation: self.incarnation,
+            kind,
+            updates,
+        };
+        let buf = bincode::serialize(&msg).expect("Should serialize membership message");
+        self.queue.push_back(FeatureOutput::SendRoute(
+            RouteRule::ToNode(dest),
+            NetOutgoingMeta::new(true, Ttl::default(), 0, true).with_priority(MsgPriority::Control),
+            buf.into(),
+        ));
+    }
+
+    fn fire(&mut self, event: Event) {
+        for sub in self.subs.iter() {
+            self.queue.push_back(FeatureOutput::Event(*sub, event.clone()));
+        }
+    }
+
+    /// Merge an update with SWIM rules, changes are gossiped again and fired to subscribers
+    fn apply(&mut self, ctx: &FeatureContext, now: u64, update: Member) {
+        if update.node == ctx.node_id {
+            if update.state != MemberState::Alive && update.incarnation >= self.incarnation {
+                self.incarnation = update.incarnation + 1;
+                log::warn!("[Membership] refute {:?} with incarnation {}", update.state, self.incarnation);
+                self.gossip(Member {
+                    node: ctx.node_id,
+                    incarnation: self.incarnation,
+                    state: MemberState::Alive,
+                });
+            }
+            return;
+        }
+
+        let accepted = match (self.members.get(&update.node), update.state) {
+            (None, MemberState::Dead) => false,
+            (None, _) => true,
+            (Some(slot), MemberState::Alive) => update.incarnation > slot.incarnation,
+            (Some(slot), MemberState::Suspect) => match slot.state {
+                MemberState::Alive => update.incarnation >= slot.incarnation,
+                _ => update.incarnation > slot.incarnation,
+            },
+            (Some(slot), MemberState::Dead) => slot.state != MemberState::Dead && update.incarnation >= slot.incarnation,
+        };
+        if !accepted {
+            return;
+        }
+
+        let prev = self.members.get(&update.node).map(|slot| (slot.state, slot.changed_at));
+        let changed = prev.map(|(state, _)| state) != Some(update.state);
+        // when only incarnation is changed, keep the time of the state change
+        let changed_at = match prev {
+            Some((_, changed_at)) if !changed => changed_at,
+            _ => now,
+        };
+        self.members.insert(
+            update.node,
+            MemberSlot {
+                incarnation: update.incarnation,
+                state: update.state,
+                changed_at,
+            },
+        );
+        self.gossip(update);
+
+        if changed {
+            log::info!("[Membership] node {} is {:?} with incarnation {}", update.node, update.state, update.incarnation);
+            let event = match update.state {
+                MemberState::Alive => Event::Alive(update.node, update.incarnation),
+                MemberState::Suspect => Event::Suspect(update.node, update.incarnation),
+                MemberState::Dead => Event::Dead(update.node, update.incarnation),
+            };
+            self.fire(event);
+        }
+    }
+
+    fn next_target(&mut self) -> Option<NodeId> {
+        loop {
+            if self.probe_order.is_empty() {
+                self.probe_order = self.members.iter().filter(|(_, slot)| slot.state != MemberState::Dead).map(|(node, _)| *node).collect();
+                self.probe_order.shuffle(&mut rand::thread_rng());
+                if self.probe_order.is_empty() {
+                    return None;
+                }
+            }
+            let node = self.probe_order.pop().expect("Should have probe target");
+            if self.members.get(&node).map(|slot| slot.state != MemberState::Dead).unwrap_or(false) {
+                return Some(node);
+            }
+        }
+    }
+
+    fn on_tick(&mut self, ctx: &FeatureContext, now: u64) {
+        let suspects: Vec<(NodeId, u64)> = self
+            .members
+            .iter()
+            .filter(|(_, slot)| slot.state == MemberState::Suspect && now >= slot.changed_at + SUSPECT_TIMEOUT_MS)
+            .map(|(node, slot)| (*node, slot.incarnation))
+            .collect();
+        for (node, incarnation) in suspects {
+            self.apply(
+                ctx,
+                now,
+                Member {
+                    node,
+                    incarnation,
+                    state: MemberState::Dead,
+                },
+            );
+        }
+
+        if let Some(probe) = &mut self.probe {
+            match probe.indirect_at {
+                None if now >= probe.started_at + PROBE_TIMEOUT_MS => {
+                    probe.indirect_at = Some(now);
+                    let (target, seq) = (probe.target, probe.seq);
+                    let mut helpers: Vec<NodeId> = self
+                        .members
+                        .iter()
+                        .filter(|(node, slot)| **node != target && slot.state == MemberState::Alive)
+                        .map(|(node, _)| *node)
+                        .collect();
+                    helpers.shuffle(&mut rand::thread_rng());
+                    helpers.truncate(INDIRECT_PROBES);
+                    log::debug!("[Membership] ping to {target} timeout, ask {:?} for indirect ping", helpers);
+                    for helper in helpers {
+                        self.send(helper, MessageKind::PingReq(seq, target));
+                    }
+                }
+                Some(indirect_at) if now >= indirect_at + INDIRECT_TIMEOUT_MS => {
+                    let target = probe.target;
+                    self.probe = None;
+                    if let Some(slot) = self.members.get(&target) {
+                        if slot.state == MemberState::Alive {
+                            let update = Member {
+                                node: target,
+                                incarnation: slot.incarnation,
+                                state: MemberState::Suspect,
+                            };
+                            self.apply(ctx, now, update);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if self.probe.is_none() && now >= self.last_probe_at + PROBE_INTERVAL_MS {
+            if let Some(target) = self.next_target() {
+                self.seq += 1;
+                self.last_probe_at = now;
+                self.probe = Some(Probe {
+                    target,
+                    seq: self.seq,
+                    started_at: now,
+                    indirect_at: None,
+                });
+                self.send(target, MessageKind::Ping(self.seq));
+            }
+        }
+
+        self.members.retain(|_, slot| slot.state != MemberState::Dead || now < slot.changed_at + DEAD_RETAIN_MS);
+        self.relays.retain(|_, relay| now < relay.started_at + INDIRECT_TIMEOUT_MS);
+    }
+
+    fn on_msg(&mut self, ctx: &FeatureContext, now: u64, from: NodeId, msg: Message) {
+        // a message from the node itself is the best proof that it is alive
+        self.apply(
+            ctx,
+            now,
+            Member {
+                node: from,
+                incarnation: msg.incarnation,
+                state: MemberState::Alive,
+            },
+        );
+        for update in msg.updates {
+            self.apply(ctx, now, update);
+        }
+
+        match msg.kind {
+            MessageKind::Ping(seq) => self.send(from, MessageKind::Ack(seq)),
+            MessageKind::Ack(seq) => {
+                if let Some(relay) = self.relays.remove(&seq) {
+                    self.send(relay.requester, MessageKind::Ack(relay.seq));
+                } else if self.probe.as_ref().map(|probe| probe.seq == seq).unwrap_or(false) {
+                    self.probe = None;
+                }
+            }
+            MessageKind::PingReq(seq, target) => {
+                self.seq += 1;
+                self.relays.insert(
+                    self.seq,
+                    Relay {
+                        requester: from,
+                        seq,
+                        started_at: now,
+                    },
+                );
+                self.send(target, MessageKind::Ping(self.seq));
+            }
+            MessageKind::Sync => {}
+        }
+    }
+}
+
+impl<UserData: Debug + Copy + Hash + Eq> Feature<UserData, Control, Event, ToController, ToWorker> for MembershipFeature<UserData> {
+    fn on_shared_input(&mut self, ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
+        match input {
+            FeatureSharedInput::Tick(_) => self.on_tick(ctx, now),
+            FeatureSharedInput::Connection(ConnectionEvent::Capabilities(conn, caps)) => {
+                if !caps.capabilities.contains(Capabilities::MEMBERSHIP) {
+                    log::info!("[Membership] neighbour {} doesn't support membership, skip sync", conn.node);
+                    return;
+                }
+                let msg = Message {
+                    incarnation: self.incarnation,
+                    kind: MessageKind::Sync,
+                    updates: self.members(ctx.node_id),
+                };
+                let buf = bincode::serialize(&msg).expect("Should serialize membership message");
+                self.queue.push_back(FeatureOutput::SendDirect(
+                    conn.conn,
+                    NetOutgoingMeta::new(true, 1.into(), 0, true).with_priority(MsgPriority::Control),
+                    buf.into(),
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    fn on_input(&mut self, ctx: &FeatureContext, now_ms: u64, input: FeatureInput<'_, UserData, Control, ToController>) {
+        match input {
+            FeatureInput::Control(actor, control) => match control {
+                Control::Sub => {
+                    if !self.subs.contains(&actor) {
+                        self.subs.push(actor);
+                    }
+                }
+                Control::UnSub => {
+                    if let Some(pos) = self.subs.iter().position(|x| *x == actor) {
+                        self.subs.swap_remove(pos);
+                    }
+                }
+                Control::Members => {
+                    let members = self.members(ctx.node_id);
+                    self.queue.push_back(FeatureOutput::Event(actor, Event::Members(members)));
+                }
+            },
+            FeatureInput::Local(meta, buf) | FeatureInput::Net(_, meta, buf) => {
+                if !meta.secure {
+                    log_sampled!(log::Level::Warn, "[Membership] reject unsecure message");
+                    return;
+                }
+                match (meta.source, bincode::deserialize::<Message>(&buf)) {
+                    (Some(from), Ok(msg)) if from != ctx.node_id => self.on_msg(ctx, now_ms, from, msg),
+                    _ => log_sampled!(log::Level::Warn, "[Membership] receive invalid message"),
+                }
+            }
+            FeatureInput::FromWorker(_) => {}
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &FeatureContext, _now: u64) {
+        log::info!("[Membership] Shutdown");
+        self.shutdown = true;
+    }
+}
+
+impl<UserData> TaskSwitcherChild<Output<UserData>> for MembershipFeature<UserData> {
+    type Time = u64;
+
+    fn is_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn empty_event(&self) -> Output<UserData> {
+        Output::OnResourceEmpty
+    }
+
+    fn pop_output(&mut self, _now: u64) -> Option<Output<UserData>> {
+        self.queue.pop_front()
+    }
+}
+
+#[derive(Derivative)]
+#[derivative(Default(bound = ""))]
+pub struct MembershipFeatureWorker<UserData> {
+    queue: DynamicDeque<WorkerOutput<UserData>, 1>,
+    shutdown: bool,
+}
+
+impl<UserData> FeatureWorker<UserData, Control, Event, ToController, ToWorker> for MembershipFeatureWorker<UserData> {
+    fn on_input(&mut self, _ctx: &mut crate::base::FeatureWorkerContext, _now: u64, input: FeatureWorkerInput<UserData, Control, ToWorker>) {
+        match input {
+            FeatureWorkerInput::Control(actor, control) => self.queue.push_back(FeatureWorkerOutput::ForwardControlToController(actor, control)),
+            FeatureWorkerInput::Network(conn, header, buf) => self.queue.push_back(FeatureWorkerOutput::ForwardNetworkToController(conn, header, buf)),
+            #[cfg(feature = "vpn")]
+            FeatureWorkerInput::TunPkt(..) => {}
+            FeatureWorkerInput::FromController(..) => {
+                log::warn!("No handler for FromController");
+            }
+            FeatureWorkerInput::Local(header, buf) => self.queue.push_back(FeatureWorkerOutput::ForwardLocalToController(header, buf)),
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &mut crate::base::FeatureWorkerContext, _now: u64) {
+        self.shutdown = true;
+    }
+}
+
+impl<UserData> TaskSwitcherChild<WorkerOutput<UserData>> for MembershipFeatureWorker<UserData> {
+    type Time = u64;
+
+    fn is_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn empty_event(&self) -> WorkerOutput<UserData> {
+        WorkerOutput::OnResourceEmpty
+    }
+
+    fn pop_output(&mut self, _now: u64) -> Option<WorkerOutput<UserData>> {
+        self.queue.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_router::RouteRule;
+    use sans_io_runtime::TaskSwitcherChild;
+
+    use crate::base::{Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, NetIncomingMeta};
+
+    use super::{Control, Event, Member, MemberState, MembershipFeature, Message, MessageKind, INDIRECT_TIMEOUT_MS, PROBE_INTERVAL_MS, PROBE_TIMEOUT_MS, SUSPECT_TIMEOUT_MS};
+
+    const CTX: FeatureContext = FeatureContext { node_id: 1, session: 0 };
+
+    fn incoming(from: u32, msg: &Message) -> FeatureInput<'static, (), Control, super::ToController> {
+        FeatureInput::Local(NetIncomingMeta::new(Some(from), 1.into(), 0, true), bincode::serialize(msg).expect("Should serialize").into())
+    }
+
+    fn msg(incarnation: u64, kind: MessageKind, updates: Vec<Member>) -> Message {
+        Message { incarnation, kind, updates }
+    }
+
+    fn routed(output: Option<FeatureOutput<(), Event, super::ToWorker>>) -> (RouteRule, Message) {
+        match output {
+            Some(FeatureOutput::SendRoute(rule, _, buf)) => (rule, bincode::deserialize(&buf).expect("Should decode")),
+            out => panic!("Should be SendRoute, got {:?}", out),
+        }
+    }
+
+    fn member(node: u32, incarnation: u64, state: MemberState) -> Member {
+        Member { node, incarnation, state }
+    }
+
+    #[test]
+    fn probe_suspect_then_dead() {
+        let sub = FeatureControlActor::Controller(());
+        let mut membership = MembershipFeature::<()>::default();
+        membership.on_input(&CTX, 0, FeatureInput::Control(sub, Control::Sub));
+        membership.on_input(
+            &CTX,
+            0,
+            incoming(2, &msg(0, MessageKind::Sync, vec![member(2, 0, MemberState::Alive), member(3, 0, MemberState::Alive)])),
+        );
+        let mut events = vec![];
+        while let Some(out) = membership.pop_output(0) {
+            events.push(out);
+        }
+        assert_eq!(events.len(), 2);
+        assert!(events.contains(&FeatureOutput::Event(sub, Event::Alive(2, 0))));
+        assert!(events.contains(&FeatureOutput::Event(sub, Event::Alive(3, 0))));
+
+        membership.on_shared_input(&CTX, PROBE_INTERVAL_MS, FeatureSharedInput::Tick(1));
+        let (rule, ping) = routed(membership.pop_output(0));
+        let (target, seq) = match (rule, ping.kind) {
+            (RouteRule::ToNode(target), MessageKind::Ping(seq)) => (target, seq),
+            out => panic!("Should be ping, got {:?}", out),
+        };
+        let helper = if target == 2 {
+            3
+        } else {
+            2
+        };
+
+        // no direct ack, ask the other member for indirect ping
+        membership.on_shared_input(&CTX, PROBE_INTERVAL_MS + PROBE_TIMEOUT_MS, FeatureSharedInput::Tick(2));
+        let (rule, req) = routed(membership.pop_output(0));
+        assert_eq!(rule, RouteRule::ToNode(helper));
+        assert_eq!(req.kind, MessageKind::PingReq(seq, target));
+        assert_eq!(membership.pop_output(0), None);
+
+        let suspect_at = PROBE_INTERVAL_MS + PROBE_TIMEOUT_MS + INDIRECT_TIMEOUT_MS;
+        membership.on_shared_input(&CTX, suspect_at, FeatureSharedInput::Tick(3));
+        assert_eq!(membership.pop_output(0), Some(FeatureOutput::Event(sub, Event::Suspect(target, 0))));
+        // next probe to the helper carries the suspicion
+        let (rule, ping) = routed(membership.pop_output(0));
+        assert_eq!(rule, RouteRule::ToNode(helper));
+        assert!(ping.updates.contains(&member(target, 0, MemberState::Suspect)));
+        membership.on_input(&CTX, suspect_at, incoming(helper, &msg(0, MessageKind::Ack(seq + 1), vec![])));
+        assert_eq!(membership.pop_output(0), None);
+
+        membership.on_shared_input(&CTX, suspect_at + SUSPECT_TIMEOUT_MS, FeatureSharedInput::Tick(4));
+        assert_eq!(membership.pop_output(0), Some(FeatureOutput::Event(sub, Event::Dead(target, 0))));
+
+        // stale alive is ignored, only higher incarnation revives
+        membership.on_input(&CTX, 0, incoming(helper, &msg(0, MessageKind::Sync, vec![member(target, 0, MemberState::Alive)])));
+        while let Some(out) = membership.pop_output(0) {
+            assert!(!matches!(out, FeatureOutput::Event(..)), "Should not fire event, got {:?}", out);
+        }
+        membership.on_input(&CTX, 0, incoming(target, &msg(1, MessageKind::Sync, vec![])));
+        assert_eq!(membership.pop_output(0), Some(FeatureOutput::Event(sub, Event::Alive(target, 1))));
+    }
+
+    #[test]
+    fn refute_suspicion_and_relay_ack() {
+        let mut membership = MembershipFeature::<()>::default();
+        membership.on_input(&CTX, 0, incoming(2, &msg(0, MessageKind::PingReq(7, 3), vec![member(1, 0, MemberState::Suspect)])));
+        let (rule, ping) = routed(membership.pop_output(0));
+        assert_eq!(rule, RouteRule::ToNode(3));
+        assert_eq!(ping.incarnation, 1);
+        assert!(ping.updates.contains(&member(1, 1, MemberState::Alive)));
+        let seq = match ping.kind {
+            MessageKind::Ping(seq) => seq,
+            kind => panic!("Should be ping, got {:?}", kind),
+        };
+
+        membership.on_input(&CTX, 10, incoming(3, &msg(0, MessageKind::Ack(seq), vec![])));
+        let (rule, ack) = routed(membership.pop_output(0));
+        assert_eq!(rule, RouteRule::ToNode(2));
+        assert_eq!(ack.kind, MessageKind::Ack(7));
+        assert_eq!(membership.pop_output(0), None);
+
+        membership.on_input(&CTX, 10, FeatureInput::Control(FeatureControlActor::Controller(()), Control::Members));
+        assert_eq!(
+            membership.pop_output(0),
+            Some(FeatureOutput::Event(
+                FeatureControlActor::Controller(()),
+                Event::Members(vec![member(1, 1, MemberState::Alive), member(2, 0, MemberState::Alive), member(3, 0, MemberState::Alive)])
+            ))
+        );
+    }
+}
@@ -1,9 +1,15 @@
+use crate::base::FeatureError;
+
 pub mod alias;
 pub mod data;
 pub mod dht_kv;
+pub mod diag;
+pub mod membership;
+pub mod nat_traversal;
 pub mod neighbours;
 pub mod pubsub;
 pub mod router_sync;
+pub mod rpc;
 pub mod socket;
 pub mod vpn;
 
@@ -12,7 +18,7 @@ pub mod vpn;
 /// This is a helper struct to help FeatureManager to manage the features
 ///
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, num_enum::TryFromPrimitive, num_enum::IntoPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, num_enum::TryFromPrimitive, num_enum::IntoPrimitive)]
 #[repr(u8)]
 pub enum Features {
     Neighbours = neighbours::FEATURE_ID,
@@ -23,6 +29,29 @@ pub enum Features {
     PubSub = pubsub::FEATURE_ID,
     Alias = alias::FEATURE_ID,
     Socket = socket::FEATURE_ID,
+    NatTraversal = nat_traversal::FEATURE_ID,
+    Rpc = rpc::FEATURE_ID,
+    Membership = membership::FEATURE_ID,
+    Diag = diag::FEATURE_ID,
+}
+
+impl Features {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Features::Neighbours => neighbours::FEATURE_NAME,
+            Features::Data => data::FEATURE_NAME,
+            Features::RouterSync => router_sync::FEATURE_NAME,
+            Features::Vpn => vpn::FEATURE_NAME,
+            Features::DhtKv => dht_kv::FEATURE_NAME,
+            Features::PubSub => pubsub::FEATURE_NAME,
+            Features::Alias => alias::FEATURE_NAME,
+            Features::Socket => socket::FEATURE_NAME,
+            Features::NatTraversal => nat_traversal::FEATURE_NAME,
+            Features::Rpc => rpc::FEATURE_NAME,
+            Features::Membership => membership::FEATURE_NAME,
+            Features::Diag => diag::FEATURE_NAME,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, convert_enum::From)]
@@ -35,6 +64,10 @@ pub enum FeaturesControl {
     PubSub(pubsub::Control),
     Alias(alias::Control),
     Socket(socket::Control),
+    NatTraversal(nat_traversal::Control),
+    Rpc(rpc::Control),
+    Membership(membership::Control),
+    Diag(diag::Control),
 }
 
 impl FeaturesControl {
@@ -48,6 +81,24 @@ impl FeaturesControl {
             Self::PubSub(_) => Features::PubSub,
             Self::Alias(_) => Features::Alias,
             Self::Socket(_) => Features::Socket,
+            Self::NatTraversal(_) => Features::NatTraversal,
+            Self::Rpc(_) => Features::Rpc,
+            Self::Membership(_) => Features::Membership,
+            Self::Diag(_) => Features::Diag,
+        }
+    }
+
+    /// Size of user data which is carried by the control, zero for controls which don't send data
+    pub fn payload_len(&self) -> usize {
+        match self {
+            Self::Data(data::Control::DataSendRule(_, _, _, data) | data::Control::BulkSend(_, _, _, data)) => data.len(),
+            Self::DhtKv(dht_kv::Control::MapCmd(_, dht_kv::MapControl::Set(_, data) | dht_kv::MapControl::SetWithTtl(_, data, _))) => data.len(),
+            Self::DhtKv(dht_kv::Control::MapCmd(_, dht_kv::MapControl::BatchSet(items))) => items.iter().map(|(_, data)| data.len()).sum(),
+            Self::PubSub(pubsub::Control(_, pubsub::ChannelControl::PubData(data) | pubsub::ChannelControl::PubDataRetained(data))) => data.len(),
+            Self::Alias(alias::Control::Send { data, .. }) => data.len(),
+            Self::Socket(socket::Control::SendTo(_, _, _, buf, _) | socket::Control::Send(_, buf, _)) => buf.len(),
+            Self::Rpc(rpc::Control::Request(_, _, data, _) | rpc::Control::Response(_, data)) => data.len(),
+            _ => 0,
         }
     }
 }
@@ -62,6 +113,47 @@ pub enum FeaturesEvent {
     PubSub(pubsub::Event),
     Alias(alias::Event),
     Socket(socket::Event),
+    NatTraversal(nat_traversal::Event),
+    Rpc(rpc::Event),
+    Membership(membership::Event),
+    Diag(diag::Event),
+}
+
+impl FeaturesEvent {
+    pub fn to_feature(&self) -> Features {
+        match self {
+            Self::Neighbours(_) => Features::Neighbours,
+            Self::Data(_) => Features::Data,
+            Self::RouterSync(_) => Features::RouterSync,
+            Self::Vpn(_) => Features::Vpn,
+            Self::DhtKv(_) => Features::DhtKv,
+            Self::PubSub(_) => Features::PubSub,
+            Self::Alias(_) => Features::Alias,
+            Self::Socket(_) => Features::Socket,
+            Self::NatTraversal(_) => Features::NatTraversal,
+            Self::Rpc(_) => Features::Rpc,
+            Self::Membership(_) => Features::Membership,
+            Self::Diag(_) => Features::Diag,
+        }
+    }
+
+    /// Classify the event as a [`FeatureError`] if it reports a failure
+    pub fn error(&self) -> Option<FeatureError> {
+        match self {
+            Self::Neighbours(event) => event.error(),
+            Self::Data(event) => event.error(),
+            Self::RouterSync(event) => event.error(),
+            Self::Vpn(event) => event.error(),
+            Self::DhtKv(event) => event.error(),
+            Self::PubSub(event) => event.error(),
+            Self::Alias(event) => event.error(),
+            Self::Socket(event) => event.error(),
+            Self::NatTraversal(event) => event.error(),
+            Self::Rpc(event) => event.error(),
+            Self::Membership(event) => event.error(),
+            Self::Diag(event) => event.error(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, convert_enum::From)]
@@ -74,6 +166,10 @@ pub enum FeaturesToController {
     PubSub(pubsub::ToController),
     Alias(alias::ToController),
     Socket(socket::ToController),
+    NatTraversal(nat_traversal::ToController),
+    Rpc(rpc::ToController),
+    Membership(membership::ToController),
+    Diag(diag::ToController),
 }
 
 impl FeaturesToController {
@@ -87,6 +183,10 @@ impl FeaturesToController {
             Self::PubSub(_) => Features::PubSub,
             Self::Alias(_) => Features::Alias,
             Self::Socket(_) => Features::Socket,
+            Self::NatTraversal(_) => Features::NatTraversal,
+            Self::Rpc(_) => Features::Rpc,
+            Self::Membership(_) => Features::Membership,
+            Self::Diag(_) => Features::Diag,
         }
     }
 }
@@ -101,6 +201,10 @@ pub enum FeaturesToWorker<UserData> {
     PubSub(pubsub::ToWorker<UserData>),
     Alias(alias::ToWorker),
     Socket(socket::ToWorker<UserData>),
+    NatTraversal(nat_traversal::ToWorker),
+    Rpc(rpc::ToWorker),
+    Membership(membership::ToWorker),
+    Diag(diag::ToWorker),
 }
 
 impl<UserData> FeaturesToWorker<UserData> {
@@ -114,6 +218,47 @@ impl<UserData> FeaturesToWorker<UserData> {
             Self::PubSub(_) => Features::PubSub,
             Self::Alias(_) => Features::Alias,
             Self::Socket(_) => Features::Socket,
+            Self::NatTraversal(_) => Features::NatTraversal,
+            Self::Rpc(_) => Features::Rpc,
+            Self::Membership(_) => Features::Membership,
+            Self::Diag(_) => Features::Diag,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::base::FeatureError;
+
+    use super::{alias, data, dht_kv, pubsub, rpc, FeaturesEvent};
+
+    #[test]
+    fn classify_error_events() {
+        let timeout: FeaturesEvent = data::Event::Pong(1, None).into();
+        assert_eq!(timeout.error(), Some(FeatureError::Timeout));
+        let pong: FeaturesEvent = data::Event::Pong(1, Some(10)).into();
+        assert_eq!(pong.error(), None);
+
+        let get: FeaturesEvent = dht_kv::Event::MapGetRes(dht_kv::Map(1), Err(dht_kv::GetError::Timeout)).into();
+        assert_eq!(get.error(), Some(FeatureError::Timeout));
+        let get: FeaturesEvent = dht_kv::Event::MapGetRes(dht_kv::Map(1), Ok(vec![])).into();
+        assert_eq!(get.error(), None);
+
+        let no_source: FeaturesEvent = pubsub::Event(pubsub::ChannelId(1), pubsub::ChannelEvent::NoSourceFound).into();
+        assert_eq!(no_source.error(), Some(FeatureError::Unreachable));
+
+        let not_found: FeaturesEvent = alias::Event::QueryResult(1, None).into();
+        assert_eq!(not_found.error(), Some(FeatureError::Unreachable));
+        let full: FeaturesEvent = alias::Event::SendReceipt(1, 2, alias::SendReceipt::QueueFull).into();
+        assert_eq!(full.error(), Some(FeatureError::Overload));
+        let expired: FeaturesEvent = alias::Event::SendReceipt(1, 2, alias::SendReceipt::Expired).into();
+        assert_eq!(expired.error(), Some(FeatureError::Timeout));
+        let delivered: FeaturesEvent = alias::Event::SendReceipt(1, 2, alias::SendReceipt::Delivered).into();
+        assert_eq!(delivered.error(), None);
+
+        let no_handler: FeaturesEvent = rpc::Event::Response(1, Err(rpc::RpcError::NoHandler)).into();
+        assert_eq!(no_handler.error(), Some(FeatureError::Unreachable));
+        let rpc_timeout: FeaturesEvent = rpc::Event::Response(1, Err(rpc::RpcError::Timeout)).into();
+        assert_eq!(rpc_timeout.error(), Some(FeatureError::Timeout));
+    }
+}
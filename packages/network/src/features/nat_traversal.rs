@@ -0,0 +1,382 @@
+//! NAT traversal with udp hole punching.
+//!
+//! Each node tells every new neighbour which address it sees the neighbour from, so a node behind NAT learns its reflexive
+//! (public) addresses. When a node wants a direct connection to a node which is only reachable over relays, it routes a punch
+//! request with its advertised addresses to the target, then the target replies with its own addresses over the same relays.
+//! Both sides connect to each other at the same time, which opens the NAT mappings on both sides, and neighbours resolve the
+//! simultaneous open into a single connection. If no direct connection is established in [`PUNCH_TIMEOUT_MS`],
+//! the punch is fallen back to relay mode: traffic to the node keeps routed over the relays.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    hash::Hash,
+    net::SocketAddr,
+};
+
+use atm0s_sdn_identity::{ConnId, NodeAddr, NodeAddrBuilder, NodeId, Protocol};
+use atm0s_sdn_router::RouteRule;
+use atm0s_sdn_utils::log_sampled;
+use derivative::Derivative;
+use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
+use serde::{Deserialize, Serialize};
+
+use crate::base::{
+    Capabilities, ConnectionEvent, Feature, FeatureContext, FeatureControlActor, FeatureError, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput,
+    NetOutgoingMeta, Ttl,
+};
+
+pub const FEATURE_ID: u8 = 8;
+pub const FEATURE_NAME: &str = "nat_traversal";
+/// Punch which has no direct connection after this timeout is fallen back to relay mode
+pub const PUNCH_TIMEOUT_MS: u64 = 10000;
+/// Max number of addresses which are advertised to the other side of a punch
+pub const MAX_ADVERTISED_ADDRS: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Control {
+    /// Get addresses which are advertised to other nodes: local addresses then reflexive addresses
+    Addrs,
+    /// Punch a direct connection to a node, coordinated over already connected relay nodes
+    Punch(NodeId),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Addrs(Vec<SocketAddr>),
+    /// Direct connection to the node is established
+    Punched(NodeId, ConnId),
+    /// Punching is failed, traffic to the node keeps routed over relay nodes
+    Relayed(NodeId),
+}
+
+impl Event {
+    pub fn error(&self) -> Option<FeatureError> {
+        // relay mode is a fallback, the node is still reachable
+        None
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ToWorker;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ToController;
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum Message {
+    /// Address which the receiver is seen from
+    Observed(SocketAddr),
+    PunchRequest(Vec<SocketAddr>),
+    PunchResponse(Vec<SocketAddr>),
+}
+
+pub type Output<UserData> = FeatureOutput<UserData, Event, ToWorker>;
+pub type WorkerOutput<UserData> = FeatureWorkerOutput<UserData, Control, Event, ToController>;
+
+#[derive(Debug)]
+struct PunchSlot<UserData> {
+    actors: Vec<FeatureControlActor<UserData>>,
+    started_at: u64,
+}
+
+#[derive(Debug, Derivative)]
+#[derivative(Default(bound = ""))]
+pub struct NatTraversalFeature<UserData> {
+    /// Direct connections to neighbours
+    conns: HashMap<ConnId, NodeId>,
+    locals: Vec<SocketAddr>,
+    /// Reflexive addresses, newest first
+    reflexives: Vec<SocketAddr>,
+    punches: HashMap<NodeId, PunchSlot<UserData>>,
+    queue: VecDeque<Output<UserData>>,
+    shutdown: bool,
+}
+
+impl<UserData: Debug + Copy + Hash + Eq> NatTraversalFeature<UserData> {
+    fn advertised(&self) -> Vec<SocketAddr> {
+        let mut addrs: Vec<SocketAddr> = Vec::new();
+        for addr in self.locals.iter().chain(self.reflexives.iter()) {
+            if !addrs.contains(addr) {
+                addrs.push(*addr);
+            }
+        }
+        addrs.truncate(MAX_ADVERTISED_ADDRS);
+        addrs
+    }
+
+    fn send_msg(&mut self, rule: RouteRule, msg: Message) {
+        let buf = bincode::serialize(&msg).expect("Should serialize nat message");
+        self.queue.push_back(FeatureOutput::SendRoute(rule, NetOutgoingMeta::new(true, Ttl::default(), 0, true), buf.into()));
+    }
+
+    fn connect_to(&mut self, node: NodeId, addrs: &[SocketAddr]) {
+        if addrs.is_empty() {
+            log::warn!("[NatTraversal] node {node} has no advertised address, keep relay mode");
+            return;
+        }
+        log::info!("[NatTraversal] punch to node {node} with addrs {:?}", addrs);
+        self.queue.push_back(FeatureOutput::NeighboursConnectTo(build_addr(node, addrs)));
+    }
+
+    fn on_msg(&mut self, from: NodeId, msg: Message) {
+        match msg {
+            Message::Observed(_) => {
+                log_sampled!(log::Level::Warn, "[NatTraversal] observed addr must be sent directly, from {from}");
+            }
+            Message::PunchRequest(addrs) => {
+                let advertised = self.advertised();
+                self.send_msg(RouteRule::ToNode(from), Message::PunchResponse(advertised));
+                self.connect_to(from, &addrs);
+            }
+            Message::PunchResponse(addrs) => {
+                if self.punches.contains_key(&from) {
+                    self.connect_to(from, &addrs);
+                } else {
+                    log::warn!("[NatTraversal] punch response from node {from} without punching");
+                }
+            }
+        }
+    }
+}
+
+impl<UserData: Debug + Copy + Hash + Eq> Feature<UserData, Control, Event, ToController, ToWorker> for NatTraversalFeature<UserData> {
+    fn on_shared_input(&mut self, _ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
+        match input {
+            FeatureSharedInput::Tick(_) => {
+                let timeouts: Vec<NodeId> = self.punches.iter().filter(|(_, slot)| now >= slot.started_at + PUNCH_TIMEOUT_MS).map(|(node, _)| *node).collect();
+                for node in timeouts {
+                    log::warn!("[NatTraversal] punch to node {node} timeout, fallback to relay");
+                    let slot = self.punches.remove(&node).expect("Should have punch slot");
+                    for actor in slot.actors {
+                        self.queue.push_back(FeatureOutput::Event(actor, Event::Relayed(node)));
+                    }
+                }
+            }
+            FeatureSharedInput::Connection(ConnectionEvent::Connected(ctx, _)) => {
+                self.conns.insert(ctx.conn, ctx.node);
+                if !self.locals.contains(&ctx.pair.local) {
+                    self.locals.push(ctx.pair.local);
+                }
+                if let Some(slot) = self.punches.remove(&ctx.node) {
+                    log::info!("[NatTraversal] punched to node {} over {}", ctx.node, ctx.pair);
+                    for actor in slot.actors {
+                        self.queue.push_back(FeatureOutput::Event(actor, Event::Punched(ctx.node, ctx.conn)));
+                    }
+                }
+            }
+            FeatureSharedInput::Connection(ConnectionEvent::Capabilities(ctx, caps)) => {
+                if !caps.capabilities.contains(Capabilities::NAT_TRAVERSAL) {
+                    log::info!("[NatTraversal] neighbour {} doesn't support nat traversal, skip observed address", ctx.node);
+                    return;
+                }
+                // tell the neighbour which address it is seen from
+                let buf = bincode::serialize(&Message::Observed(ctx.pair.remote)).expect("Should serialize nat message");
+                self.queue.push_back(FeatureOutput::SendDirect(ctx.conn, NetOutgoingMeta::new(false, 1.into(), 0, true), buf.into()));
+            }
+            FeatureSharedInput::Connection(ConnectionEvent::Disconnected(ctx)) => {
+                self.conns.remove(&ctx.conn);
+            }
+            _ => {}
+        }
+    }
+
+    fn on_input(&mut self, ctx: &FeatureContext, now_ms: u64, input: FeatureInput<'_, UserData, Control, ToController>) {
+        match input {
+            FeatureInput::Control(actor, control) => match control {
+                Control::Addrs => {
+                    let addrs = self.advertised();
+                    self.queue.push_back(FeatureOutput::Event(actor, Event::Addrs(addrs)));
+                }
+                Control::Punch(node) => {
+                    if node == ctx.node_id {
+                        log::warn!("[NatTraversal] reject punch to self");
+                        return;
+                    }
+                    if let Some((conn, _)) = self.conns.iter().find(|(_, n)| **n == node) {
+                        self.queue.push_back(FeatureOutput::Event(actor, Event::Punched(node, *conn)));
+                        return;
+                    }
+                    if let Some(slot) = self.punches.get_mut(&node) {
+                        if !slot.actors.contains(&actor) {
+                            slot.actors.push(actor);
+                        }
+                        return;
+                    }
+                    log::info!("[NatTraversal] start punching to node {node}");
+                    self.punches.insert(
+                        node,
+                        PunchSlot {
+                            actors: vec![actor],
+                            started_at: now_ms,
+                        },
+                    );
+                    let advertised = self.advertised();
+                    self.send_msg(RouteRule::ToNode(node), Message::PunchRequest(advertised));
+                }
+            },
+            FeatureInput::Net(conn_ctx, meta, buf) => {
+                if !meta.secure {
+                    log_sampled!(log::Level::Warn, "[NatTraversal] reject unsecure message");
+                    return;
+                }
+                match (meta.source, bincode::deserialize::<Message>(&buf)) {
+                    (None, Ok(Message::Observed(addr))) => {
+                        log::debug!("[NatTraversal] node {} sees this node from {addr}", conn_ctx.node);
+                        self.reflexives.retain(|a| *a != addr);
+                        self.reflexives.insert(0, addr);
+                        self.reflexives.truncate(MAX_ADVERTISED_ADDRS);
+                    }
+                    (Some(from), Ok(msg)) => self.on_msg(from, msg),
+                    (_, Err(_)) => log::warn!("[NatTraversal] receive invalid message from {}", conn_ctx.pair),
+                    (None, Ok(_)) => log::warn!("[NatTraversal] punch message must be routed, from {}", conn_ctx.pair),
+                }
+            }
+            FeatureInput::Local(meta, buf) => {
+                if let (true, Some(from), Ok(msg)) = (meta.secure, meta.source, bincode::deserialize::<Message>(&buf)) {
+                    self.on_msg(from, msg);
+                }
+            }
+            FeatureInput::FromWorker(_) => {}
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &FeatureContext, _now: u64) {
+        log::info!("[NatTraversal] Shutdown");
+        self.shutdown = true;
+    }
+}
+
+impl<UserData> TaskSwitcherChild<Output<UserData>> for NatTraversalFeature<UserData> {
+    type Time = u64;
+
+    fn is_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn empty_event(&self) -> Output<UserData> {
+        Output::OnResourceEmpty
+    }
+
+    fn pop_output(&mut self, _now: u64) -> Option<Output<UserData>> {
+        self.queue.pop_front()
+    }
+}
+
+fn build_addr(node: NodeId, addrs: &[SocketAddr]) -> NodeAddr {
+    let mut builder = NodeAddrBuilder::new(node);
+    for addr in addrs {
+        match addr {
+            SocketAddr::V4(addr) => builder.add_protocol(Protocol::Ip4(*addr.ip())),
+            SocketAddr::V6(addr) => builder.add_protocol(Protocol::Ip6(*addr.ip())),
+        }
+        builder.add_protocol(Protocol::Udp(addr.port()));
+    }
+    builder.addr()
+}
+
+#[derive(Derivative)]
+#[derivative(Default(bound = ""))]
+pub struct NatTraversalFeatureWorker<UserData> {
+    queue: DynamicDeque<WorkerOutput<UserData>, 1>,
+    shutdown: bool,
+}
+
+impl<UserData> FeatureWorker<UserData, Control, Event, ToController, ToWorker> for NatTraversalFeatureWorker<UserData> {
+    fn on_input(&mut self, _ctx: &mut crate::base::FeatureWorkerContext, _now: u64, input: FeatureWorkerInput<UserData, Control, ToWorker>) {
+        match input {
+            FeatureWorkerInput::Control(actor, control) => self.queue.push_back(FeatureWorkerOutput::ForwardControlToController(actor, control)),
+            FeatureWorkerInput::Network(conn, header, buf) => self.queue.push_back(FeatureWorkerOutput::ForwardNetworkToController(conn, header, buf)),
+            #[cfg(feature = "vpn")]
+            FeatureWorkerInput::TunPkt(..) => {}
+            FeatureWorkerInput::FromController(..) => {
+                log::warn!("No handler for FromController");
+            }
+            FeatureWorkerInput::Local(header, buf) => self.queue.push_back(FeatureWorkerOutput::ForwardLocalToController(header, buf)),
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &mut crate::base::FeatureWorkerContext, _now: u64) {
+        self.shutdown = true;
+    }
+}
+
+impl<UserData> TaskSwitcherChild<WorkerOutput<UserData>> for NatTraversalFeatureWorker<UserData> {
+    type Time = u64;
+
+    fn is_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn empty_event(&self) -> WorkerOutput<UserData> {
+        WorkerOutput::OnResourceEmpty
+    }
+
+    fn pop_output(&mut self, _now: u64) -> Option<WorkerOutput<UserData>> {
+        self.queue.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_router::RouteRule;
+    use sans_io_runtime::TaskSwitcherChild;
+
+    use crate::base::{Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, NetIncomingMeta};
+
+    use super::{build_addr, Control, Event, Message, NatTraversalFeature, PUNCH_TIMEOUT_MS};
+
+    fn net_msg(msg: &Message) -> Vec<u8> {
+        bincode::serialize(msg).expect("Should serialize")
+    }
+
+    fn routed(output: Option<FeatureOutput<(), Event, super::ToWorker>>) -> (RouteRule, Message) {
+        match output {
+            Some(FeatureOutput::SendRoute(rule, meta, buf)) => {
+                assert!(meta.source);
+                (rule, bincode::deserialize(&buf).expect("Should decode"))
+            }
+            out => panic!("Should be SendRoute, got {:?}", out),
+        }
+    }
+
+    #[test]
+    fn punch_request_reply_and_connect() {
+        let ctx = FeatureContext { node_id: 3, session: 0 };
+        let mut nat = NatTraversalFeature::<()>::default();
+        let addrs = vec!["1.2.3.4:1000".parse().expect("Should parse")];
+        nat.on_input(
+            &ctx,
+            0,
+            FeatureInput::Local(NetIncomingMeta::new(Some(1), 1.into(), 0, true), net_msg(&Message::PunchRequest(addrs.clone())).into()),
+        );
+        assert_eq!(routed(nat.pop_output(0)), (RouteRule::ToNode(1), Message::PunchResponse(vec![])));
+        assert_eq!(nat.pop_output(0), Some(FeatureOutput::NeighboursConnectTo(build_addr(1, &addrs))));
+        assert_eq!(nat.pop_output(0), None);
+
+        // response without punching is ignored
+        nat.on_input(
+            &ctx,
+            0,
+            FeatureInput::Local(NetIncomingMeta::new(Some(2), 1.into(), 0, true), net_msg(&Message::PunchResponse(addrs)).into()),
+        );
+        assert_eq!(nat.pop_output(0), None);
+    }
+
+    #[test]
+    fn punch_fallback_to_relay_after_timeout() {
+        let ctx = FeatureContext { node_id: 1, session: 0 };
+        let mut nat = NatTraversalFeature::<()>::default();
+        nat.on_input(&ctx, 100, FeatureInput::Control(FeatureControlActor::Controller(()), Control::Punch(3)));
+        assert_eq!(routed(nat.pop_output(0)), (RouteRule::ToNode(3), Message::PunchRequest(vec![])));
+        // punch to self is rejected
+        nat.on_input(&ctx, 100, FeatureInput::Control(FeatureControlActor::Controller(()), Control::Punch(1)));
+        assert_eq!(nat.pop_output(0), None);
+
+        nat.on_shared_input(&ctx, PUNCH_TIMEOUT_MS, FeatureSharedInput::Tick(1));
+        assert_eq!(nat.pop_output(0), None);
+        nat.on_shared_input(&ctx, PUNCH_TIMEOUT_MS + 100, FeatureSharedInput::Tick(2));
+        assert_eq!(nat.pop_output(0), Some(FeatureOutput::Event(FeatureControlActor::Controller(()), Event::Relayed(3))));
+        assert_eq!(nat.pop_output(0), None);
+    }
+}
@@ -1,10 +1,21 @@
-use std::{collections::VecDeque, fmt::Debug, hash::Hash};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    hash::Hash,
+    net::SocketAddr,
+};
 
 use atm0s_sdn_identity::{ConnId, NodeAddr, NodeId};
 use derivative::Derivative;
 use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
 
-use crate::base::{ConnectionEvent, Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput};
+use crate::{
+    base::{
+        ConnectFailReason, ConnectionEvent, Feature, FeatureContext, FeatureControlActor, FeatureError, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput,
+        FeatureWorkerOutput, SecureInfo,
+    },
+    data_plane::NetPair,
+};
 
 pub const FEATURE_ID: u8 = 0;
 pub const FEATURE_NAME: &str = "neighbours_api";
@@ -15,12 +26,40 @@ pub enum Control {
     UnSub,
     ConnectTo(NodeAddr),
     DisconnectFrom(NodeId),
+    /// Re-establish a connection over the same remote address, the new one is connected before the old one is closed unless the node has another connection
+    Restart(ConnId),
+    /// Get number of connections for each negotiated handshake and cipher suite
+    SecureStats,
+    /// Get all paths to a neighbour with their probed quality
+    Paths(NodeId),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Event {
-    Connected(NodeId, ConnId),
+    /// A connection to a neighbour is established, with the remote address of the connection
+    Connected(NodeId, ConnId, SocketAddr),
     Disconnected(NodeId, ConnId),
+    /// Connecting to a node by `Control::ConnectTo` failed over all of its addresses, it is sent to all subscribers
+    ConnectFailed(NodeAddr, ConnectFailReason),
+    /// Number of connections for each negotiated handshake and cipher suite, sorted by suite
+    SecureStats(Vec<(SecureInfo, usize)>),
+    /// Paths to a neighbour, sorted by connection id
+    Paths(NodeId, Vec<PathInfo>),
+}
+
+/// A connection to a neighbour, each connection is a separate path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathInfo {
+    pub conn: ConnId,
+    pub pair: NetPair,
+    /// None until the first pong is received
+    pub rtt_ms: Option<u32>,
+}
+
+impl Event {
+    pub fn error(&self) -> Option<FeatureError> {
+        None
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -36,6 +75,8 @@ pub type WorkerOutput<UserData> = FeatureWorkerOutput<UserData, Control, Event,
 #[derivative(Default(bound = ""))]
 pub struct NeighboursFeature<UserData> {
     subs: Vec<FeatureControlActor<UserData>>,
+    suites: HashMap<SecureInfo, usize>,
+    paths: HashMap<NodeId, Vec<PathInfo>>,
     output: VecDeque<Output<UserData>>,
     shutdown: bool,
 }
@@ -45,16 +86,45 @@ impl<UserData: Debug + Copy + Hash + Eq> Feature<UserData, Control, Event, ToCon
         match input {
             FeatureSharedInput::Connection(ConnectionEvent::Connected(ctx, _)) => {
                 log::debug!("[Neighbours] Connected {}, fire event to {:?}", ctx.pair, self.subs);
+                *self.suites.entry(ctx.secure).or_default() += 1;
+                self.paths.entry(ctx.node).or_default().push(PathInfo {
+                    conn: ctx.conn,
+                    pair: ctx.pair,
+                    rtt_ms: None,
+                });
                 for sub in self.subs.iter() {
-                    self.output.push_back(FeatureOutput::Event(*sub, Event::Connected(ctx.node, ctx.conn)));
+                    self.output.push_back(FeatureOutput::Event(*sub, Event::Connected(ctx.node, ctx.conn, ctx.pair.remote)));
                 }
             }
             FeatureSharedInput::Connection(ConnectionEvent::Disconnected(ctx)) => {
                 log::debug!("[Neighbours] Disconnected {}, fire event to {:?}", ctx.pair, self.subs);
+                if let Some(count) = self.suites.get_mut(&ctx.secure) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.suites.remove(&ctx.secure);
+                    }
+                }
+                if let Some(paths) = self.paths.get_mut(&ctx.node) {
+                    paths.retain(|path| path.conn != ctx.conn);
+                    if paths.is_empty() {
+                        self.paths.remove(&ctx.node);
+                    }
+                }
                 for sub in self.subs.iter() {
                     self.output.push_back(FeatureOutput::Event(*sub, Event::Disconnected(ctx.node, ctx.conn)));
                 }
             }
+            FeatureSharedInput::Connection(ConnectionEvent::ConnectFailed(addr, reason)) => {
+                log::debug!("[Neighbours] Connect to {addr} failed {reason:?}, fire event to {:?}", self.subs);
+                for sub in self.subs.iter() {
+                    self.output.push_back(FeatureOutput::Event(*sub, Event::ConnectFailed(addr.clone(), reason)));
+                }
+            }
+            FeatureSharedInput::Connection(ConnectionEvent::Stats(ctx, stats)) => {
+                if let Some(path) = self.paths.get_mut(&ctx.node).and_then(|paths| paths.iter_mut().find(|path| path.conn == ctx.conn)) {
+                    path.rtt_ms = Some(stats.rtt_ms);
+                }
+            }
             _ => {}
         }
     }
@@ -80,6 +150,19 @@ impl<UserData: Debug + Copy + Hash + Eq> Feature<UserData, Control, Event, ToCon
                 Control::DisconnectFrom(node) => {
                     self.output.push_back(FeatureOutput::NeighboursDisconnectFrom(node));
                 }
+                Control::Restart(conn) => {
+                    self.output.push_back(FeatureOutput::NeighboursRestart(conn));
+                }
+                Control::SecureStats => {
+                    let mut stats: Vec<_> = self.suites.iter().map(|(info, count)| (*info, *count)).collect();
+                    stats.sort();
+                    self.output.push_back(FeatureOutput::Event(actor, Event::SecureStats(stats)));
+                }
+                Control::Paths(node) => {
+                    let mut paths = self.paths.get(&node).cloned().unwrap_or_default();
+                    paths.sort_by_key(|path| path.conn);
+                    self.output.push_back(FeatureOutput::Event(actor, Event::Paths(node, paths)));
+                }
             }
         }
     }
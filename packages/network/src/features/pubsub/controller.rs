@@ -1,39 +1,64 @@
 use std::{
     collections::{HashMap, VecDeque},
     fmt::Debug,
+    sync::Arc,
 };
 
+use atm0s_sdn_router::RouteRule;
+
 use crate::{
-    base::{ConnectionEvent, Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput},
+    base::{ConnectionEvent, Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, LatencyProfile, NetOutgoingMeta, Ttl},
     data_plane::NetPair,
 };
 
-use self::source_hint::SourceHintLogic;
+use self::{
+    channel_range::ChannelRanges,
+    retained::{ReplayRequests, RetainedHistory},
+    source_hint::SourceHintLogic,
+};
 
 use super::{
-    msg::{ChannelId, Feedback, RelayControl, RelayId, SourceHint},
-    ChannelControl, ChannelEvent, Control, Event, RelayWorkerControl, ToController, ToWorker,
+    msg::{ChannelId, Feedback, PubsubMessage, RelayControl, RelayId, SourceHint},
+    ChannelAuthorizer, ChannelControl, ChannelEvent, Control, Event, LoopbackStats, RelayStats, RelayWorkerControl, ToController, ToWorker,
 };
 
 pub const RELAY_TIMEOUT: u64 = 10_000;
 pub const RELAY_STICKY_MS: u64 = 5 * 60 * 1000; //sticky route path in 5 minutes
+/// Number of channels relayed to remote nodes at which this node is considered fully loaded as a relay
+pub const RELAY_FULL_LOAD_CHANNELS: usize = 1000;
+/// Channels with at least this number of local subscribers are replicated by a worker, so the controller sends one message
+/// instead of one event per subscriber
+pub const WORKER_FANOUT_MIN_LOCALS: usize = 16;
+/// Interval of comparing paths of bound relays with the best path in the routing table
+pub const REBALANCE_INTERVAL_MS: u64 = 5_000;
+/// A relay is migrated to the best path only if it is faster than the current path by at least this latency, which avoids flapping
+pub const REBALANCE_MIN_IMPROVEMENT_MS: u16 = 20;
+/// Paths to a source which have latency in the same range are equal-distance, the one over the less loaded relay is preferred
+pub const RELAY_EQUAL_LATENCY_MS: u16 = 10;
+/// A relay is migrated to an equal-distance path only if its relay load is lower than the current one by at least this value
+pub const REBALANCE_MIN_LOAD_IMPROVEMENT: u8 = 32;
 
+mod channel_range;
 mod consumers;
 mod feedbacks;
 mod local_relay;
 mod remote_relay;
+mod retained;
 mod source_hint;
 
 use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_utils::log_sampled;
 use local_relay::LocalRelay;
 use remote_relay::RemoteRelay;
-use sans_io_runtime::TaskSwitcherChild;
+use sans_io_runtime::{return_if_none, TaskSwitcherChild};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum GenericRelayOutput<UserData> {
     ToWorker(RelayWorkerControl<UserData>),
     RouteChanged(FeatureControlActor<UserData>),
     Feedback(Vec<FeatureControlActor<UserData>>, Feedback),
+    /// Local subscribers which are removed because the relay is rejected by the next hop
+    Rejected(Vec<FeatureControlActor<UserData>>),
 }
 
 pub trait GenericRelay<UserData> {
@@ -47,46 +72,141 @@ pub trait GenericRelay<UserData> {
     fn conn_disconnected(&mut self, now: u64, remote: NetPair);
     fn should_clear(&self) -> bool;
     fn relay_dests(&self) -> Option<(&[FeatureControlActor<UserData>], bool)>;
+    /// Number of local and remote subscribers
+    fn subscribers(&self) -> (usize, usize);
+    /// Neighbour which the relay receives data from, None for local relays or relays which are not bound
+    fn next(&self) -> Option<NetPair> {
+        None
+    }
+    /// Subscribe over a better neighbour, the current one is released after the new one answers
+    fn migrate(&mut self, _now: u64, _next: NetPair) {}
     fn pop_output(&mut self) -> Option<GenericRelayOutput<UserData>>;
 }
 
+/// Lifecycle state of a relay, which is used for lifecycle events
+struct RelayLifecycle {
+    created_at: u64,
+    idle: bool,
+    peak: usize,
+}
+
 pub struct PubSubFeature<UserData> {
     relays: HashMap<RelayId, Box<dyn GenericRelay<UserData>>>,
+    lifecycles: HashMap<RelayId, RelayLifecycle>,
+    lifecycle_subs: Vec<FeatureControlActor<UserData>>,
     source_hints: HashMap<ChannelId, SourceHintLogic<UserData>>,
+    loopback: HashMap<ChannelId, LoopbackStats>,
+    retained: HashMap<ChannelId, RetainedHistory>,
+    replays: ReplayRequests<UserData>,
+    ranges: ChannelRanges<UserData>,
     queue: VecDeque<FeatureOutput<UserData, Event, ToWorker<UserData>>>,
+    relay_load: u8,
+    relay_sticky_ms: u64,
+    /// Connected neighbours, for mapping relay paths to routing table paths
+    neighbours: HashMap<NetPair, NodeId>,
+    last_rebalance: u64,
+    authorizer: Option<Arc<dyn ChannelAuthorizer>>,
+    decommission: bool,
     shutdown: bool,
 }
 
 impl<UserData: 'static + Eq + Copy + Debug> Default for PubSubFeature<UserData> {
     fn default() -> Self {
-        Self::new()
+        Self::new(LatencyProfile::default())
     }
 }
 
 impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
-    pub fn new() -> Self {
+    pub fn new(profile: LatencyProfile) -> Self {
         Self {
             relays: HashMap::new(),
+            lifecycles: HashMap::new(),
+            lifecycle_subs: Vec::new(),
             source_hints: HashMap::new(),
+            loopback: HashMap::new(),
+            retained: HashMap::new(),
+            replays: ReplayRequests::default(),
+            ranges: ChannelRanges::default(),
             queue: VecDeque::new(),
+            relay_load: 0,
+            relay_sticky_ms: profile.relay_sticky_ms(),
+            neighbours: HashMap::new(),
+            last_rebalance: 0,
+            authorizer: None,
+            decommission: false,
             shutdown: false,
         }
     }
 
-    fn get_relay(&mut self, ctx: &FeatureContext, relay_id: RelayId, auto_create: bool) -> Option<&mut Box<dyn GenericRelay<UserData>>> {
+    /// Check Subs from neighbours with the authorizer, None for allowing all
+    pub fn with_authorizer(mut self, authorizer: Option<Arc<dyn ChannelAuthorizer>>) -> Self {
+        self.authorizer = authorizer;
+        self
+    }
+
+    fn get_relay(&mut self, ctx: &FeatureContext, now: u64, relay_id: RelayId, auto_create: bool) -> Option<&mut Box<dyn GenericRelay<UserData>>> {
         if !self.relays.contains_key(&relay_id) && auto_create {
             let relay: Box<dyn GenericRelay<UserData>> = if ctx.node_id == relay_id.1 {
                 log::info!("[PubSubFeatureController] Creating new LocalRelay: {:?}", relay_id);
                 Box::new(LocalRelay::default())
             } else {
                 log::info!("[PubSubFeatureController] Creating new RemoteRelay: {:?}", relay_id);
-                Box::new(RemoteRelay::new(ctx.session))
+                Box::new(RemoteRelay::new(ctx.session, self.relay_sticky_ms))
             };
             self.relays.insert(relay_id, relay);
+            self.lifecycles.insert(
+                relay_id,
+                RelayLifecycle {
+                    created_at: now,
+                    idle: false,
+                    peak: 0,
+                },
+            );
+            self.fire_lifecycle(relay_id.0, ChannelEvent::RelayCreated(relay_id.1));
         }
         self.relays.get_mut(&relay_id)
     }
 
+    fn fire_lifecycle(&mut self, channel: ChannelId, event: ChannelEvent) {
+        for actor in &self.lifecycle_subs {
+            self.queue.push_back(FeatureOutput::Event(*actor, Event(channel, event.clone())));
+        }
+    }
+
+    fn relay_stats(&self, now: u64, relay_id: RelayId) -> Option<RelayStats> {
+        let (local_subscribers, remote_subscribers) = self.relays.get(&relay_id)?.subscribers();
+        let lifecycle = self.lifecycles.get(&relay_id)?;
+        Some(RelayStats {
+            lifetime_ms: now.saturating_sub(lifecycle.created_at),
+            local_subscribers,
+            remote_subscribers,
+            peak_subscribers: lifecycle.peak.max(local_subscribers + remote_subscribers),
+        })
+    }
+
+    /// Update peak subscribers and fire RelayIdle when the relay becomes idle
+    fn update_lifecycle(&mut self, now: u64, relay_id: RelayId) {
+        let stats = return_if_none!(self.relay_stats(now, relay_id));
+        let lifecycle = return_if_none!(self.lifecycles.get_mut(&relay_id));
+        lifecycle.peak = stats.peak_subscribers;
+        let idle = stats.local_subscribers + stats.remote_subscribers == 0;
+        if idle != lifecycle.idle {
+            lifecycle.idle = idle;
+            if idle {
+                log::info!("[PubSubFeatureController] Relay {:?} is idle, stats {:?}", relay_id, stats);
+                self.fire_lifecycle(relay_id.0, ChannelEvent::RelayIdle(relay_id.1, stats));
+            }
+        }
+    }
+
+    fn remove_relay(&mut self, now: u64, relay_id: RelayId) {
+        let stats = self.relay_stats(now, relay_id).unwrap_or_default();
+        self.relays.remove(&relay_id);
+        self.lifecycles.remove(&relay_id);
+        log::info!("[PubSubFeatureController] Relay {:?} destroyed, stats {:?}", relay_id, stats);
+        self.fire_lifecycle(relay_id.0, ChannelEvent::RelayDestroyed(relay_id.1, stats));
+    }
+
     fn get_source_hint(&mut self, node_id: NodeId, session: u64, channel: ChannelId, auto_create: bool) -> Option<&mut SourceHintLogic<UserData>> {
         if !self.source_hints.contains_key(&channel) && auto_create {
             log::info!("[PubSubFeatureController] Creating new SourceHintLogic: {}", channel);
@@ -95,16 +215,60 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
         self.source_hints.get_mut(&channel)
     }
 
+    /// Replay and channel range messages are routed between nodes with the router table, without relays
+    fn send_route_msg(&mut self, rule: RouteRule, msg: PubsubMessage) {
+        let meta = NetOutgoingMeta::new(true, Ttl::default(), 0, true);
+        let buf = bincode::serialize(&msg).expect("Should serialize routed message");
+        self.queue.push_back(FeatureOutput::SendRoute(rule, meta, buf.into()));
+    }
+
+    /// Request retained messages of the source if the actor subscribed with replay
+    fn request_replay(&mut self, ctx: &FeatureContext, now: u64, actor: FeatureControlActor<UserData>, channel: ChannelId, source: NodeId) {
+        let count = return_if_none!(self.replays.wanted(channel, actor));
+        log::info!("[PubSubFeatureController] request replay {count} msgs of {} from source {source} for {:?}", channel, actor);
+        if source == ctx.node_id {
+            let history = return_if_none!(self.retained.get(&channel));
+            for data in history.last(count as usize) {
+                self.queue.push_back(FeatureOutput::Event(actor, Event(channel, ChannelEvent::ReplayData(source, data.clone()))));
+            }
+        } else {
+            let id = self.replays.create(now, channel, actor);
+            self.send_route_msg(RouteRule::ToNode(source), PubsubMessage::ReplayRequest(RelayId(channel, source), id, count));
+        }
+    }
+
+    fn on_remote_replay_request(&mut self, ctx: &FeatureContext, requester: NodeId, relay_id: RelayId, id: u64, count: u16) {
+        if relay_id.1 != ctx.node_id {
+            log::warn!("[PubSubFeatureController] ReplayRequest for {:?} which is not published by this node", relay_id);
+            return;
+        }
+        let history = return_if_none!(self.retained.get(&relay_id.0));
+        let msgs = history.last(count as usize).cloned().collect::<Vec<_>>();
+        log::info!("[PubSubFeatureController] replay {} msgs of {:?} to {requester}", msgs.len(), relay_id);
+        for data in msgs {
+            self.send_route_msg(RouteRule::ToNode(requester), PubsubMessage::ReplayData(relay_id, id, data));
+        }
+    }
+
     fn on_local(&mut self, ctx: &FeatureContext, now: u64, actor: FeatureControlActor<UserData>, channel: ChannelId, control: ChannelControl) {
         match control {
             ChannelControl::SubAuto => {
-                log::info!("[PubSubFeatureController] SubAuto for {} from {:?}", channel, actor);
+                self.on_local(ctx, now, actor, channel, ChannelControl::SubAutoTimeout(source_hint::DISCOVERY_TIMEOUT_MS));
+            }
+            ChannelControl::SubAutoTimeout(discovery_timeout_ms) => {
+                log::info!("[PubSubFeatureController] SubAuto for {} from {:?} with discovery timeout {discovery_timeout_ms} ms", channel, actor);
                 let sh = self.get_source_hint(ctx.node_id, ctx.session, channel, true).expect("Should create");
-                sh.on_local(now, actor, source_hint::LocalCmd::Subscribe);
+                sh.on_local(now, actor, source_hint::LocalCmd::Subscribe(discovery_timeout_ms));
                 self.pop_single_source_hint(ctx, now, channel);
             }
+            ChannelControl::SubWithReplay(count) => {
+                log::info!("[PubSubFeatureController] SubWithReplay({count}) for {} from {:?}", channel, actor);
+                self.replays.on_sub(channel, actor, count);
+                self.on_local(ctx, now, actor, channel, ChannelControl::SubAuto);
+            }
             ChannelControl::UnsubAuto => {
                 log::info!("[PubSubFeatureController] UnsubAuto for {} from {:?}", channel, actor);
+                self.replays.on_unsub(channel, actor);
                 if let Some(sh) = self.get_source_hint(ctx.node_id, ctx.session, channel, false) {
                     sh.on_local(now, actor, source_hint::LocalCmd::Unsubscribe);
                     self.pop_single_source_hint(ctx, now, channel);
@@ -113,16 +277,21 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
             ChannelControl::PubStart => {
                 log::info!("[PubSubFeatureController] PubStart for {} from {:?}", channel, actor);
                 let relay_id = RelayId(channel, ctx.node_id);
-                let relay = self.get_relay(ctx, relay_id, true).expect("Should create");
+                let relay = self.get_relay(ctx, now, relay_id, true).expect("Should create");
                 relay.on_pub_start(actor);
                 Self::pop_single_relay(relay_id, self.relays.get_mut(&relay_id).expect("Should have"), &mut self.queue);
+                self.update_lifecycle(now, relay_id);
 
                 let sh = self.get_source_hint(ctx.node_id, ctx.session, channel, true).expect("Should create");
                 sh.on_local(now, actor, source_hint::LocalCmd::Register);
                 self.pop_single_source_hint(ctx, now, channel);
+
+                self.ranges.on_pub_start(channel, actor);
+                self.pop_ranges(ctx, now);
             }
             ChannelControl::PubStop => {
                 log::info!("[PubSubFeatureController] PubStop for {} from {:?}", channel, actor);
+                self.retained.remove(&channel);
                 let relay_id = RelayId(channel, ctx.node_id);
                 if let Some(relay) = self.relays.get_mut(&relay_id) {
                     relay.on_pub_stop(actor);
@@ -133,20 +302,24 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
                     sh.on_local(now, actor, source_hint::LocalCmd::Unregister);
                     self.pop_single_source_hint(ctx, now, channel);
                 }
+
+                self.ranges.on_pub_stop(channel, actor);
+                self.pop_ranges(ctx, now);
             }
             ChannelControl::SubSource(source) => {
                 log::info!("[PubSubFeatureController] SubSource(source) for {} from {:?}", channel, actor);
                 let relay_id = RelayId(channel, source);
-                let relay = self.get_relay(ctx, relay_id, true).expect("Should create");
+                let relay = self.get_relay(ctx, now, relay_id, true).expect("Should create");
                 log::debug!("[PubSubFeatureController] Sub for {:?} from {:?}", relay_id, actor);
                 relay.on_local_sub(now, actor);
                 Self::pop_single_relay(relay_id, self.relays.get_mut(&relay_id).expect("Should have"), &mut self.queue);
+                self.update_lifecycle(now, relay_id);
             }
             ChannelControl::FeedbackAuto(fb) => {
                 if let Some(sh) = self.get_source_hint(ctx.node_id, ctx.session, channel, false) {
                     for source in sh.sources() {
                         let relay_id = RelayId(channel, source);
-                        let relay = self.get_relay(ctx, relay_id, true).expect("Should create");
+                        let relay = self.get_relay(ctx, now, relay_id, true).expect("Should create");
                         log::debug!("[PubSubFeatureController] Feedback for {:?} from {:?}", relay_id, actor);
                         relay.on_local_feedback(now, actor, fb);
                         Self::pop_single_relay(relay_id, self.relays.get_mut(&relay_id).expect("Should have"), &mut self.queue);
@@ -161,7 +334,9 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
                     relay.on_local_unsub(now, actor);
                     Self::pop_single_relay(relay_id, relay, &mut self.queue);
                     if relay.should_clear() {
-                        self.relays.remove(&relay_id);
+                        self.remove_relay(now, relay_id);
+                    } else {
+                        self.update_lifecycle(now, relay_id);
                     }
                 } else {
                     log::warn!("[PubSubFeatureController] Unsub for unknown relay {:?}", relay_id);
@@ -171,18 +346,38 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
                 let relay_id = RelayId(channel, ctx.node_id);
                 if let Some(relay) = self.relays.get(&relay_id) {
                     if let Some((locals, has_remote)) = relay.relay_dests() {
+                        let stats = self.loopback.entry(channel).or_default();
                         log::debug!(
-                            "[PubSubFeatureController] Pub for {:?} from {:?} to {:?} locals, has remote {has_remote}",
+                            "[PubSubFeatureController] Pub for {:?} from {:?} to {:?} locals, has remote {has_remote}, loopback {}",
                             relay_id,
                             actor,
-                            locals.len()
+                            locals.len(),
+                            stats.enabled
                         );
-                        for local in locals {
-                            self.queue.push_back(FeatureOutput::Event(*local, Event(channel, ChannelEvent::SourceData(ctx.node_id, data.clone()))));
+                        let fanout = stats.enabled && locals.len() >= WORKER_FANOUT_MIN_LOCALS;
+                        if stats.enabled {
+                            if !fanout {
+                                for local in locals {
+                                    self.queue.push_back(FeatureOutput::Event(*local, Event(channel, ChannelEvent::SourceData(ctx.node_id, data.clone()))));
+                                }
+                            }
+                            stats.loopback_msgs += locals.len() as u64;
+                            stats.loopback_bytes += (locals.len() * data.len()) as u64;
                         }
 
-                        if has_remote {
-                            self.queue.push_back(FeatureOutput::ToWorker(true, ToWorker::RelayData(relay_id, data)));
+                        if fanout {
+                            stats.fanout_msgs += 1;
+                            if has_remote {
+                                stats.serialized_msgs += 1;
+                                stats.serialized_bytes += data.len() as u64;
+                            }
+                            // workers have same subscriber list, so only one of them replicates the data
+                            self.queue.push_back(FeatureOutput::ToWorker(false, ToWorker::RelayFanout(relay_id, data)));
+                        } else if has_remote || (!stats.enabled && !locals.is_empty()) {
+                            stats.serialized_msgs += 1;
+                            stats.serialized_bytes += data.len() as u64;
+                            // only one worker should relay the data, otherwise it will be duplicated
+                            self.queue.push_back(FeatureOutput::ToWorker(false, ToWorker::RelayData(relay_id, data)));
                         }
                     } else {
                         log::debug!("[PubSubFeatureController] No subscribers for {:?}, dropping data from {:?}", relay_id, actor)
@@ -191,20 +386,105 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
                     log::warn!("[PubSubFeatureController] Pub for unknown relay {:?}", relay_id);
                 }
             }
+            ChannelControl::PubDataRetained(data) => {
+                self.retained.entry(channel).or_default().push(data.clone());
+                self.on_local(ctx, now, actor, channel, ChannelControl::PubData(data));
+            }
+            ChannelControl::SetLocalLoopback(enabled) => {
+                log::info!("[PubSubFeatureController] SetLocalLoopback for {} to {enabled} from {:?}", channel, actor);
+                self.loopback.entry(channel).or_default().enabled = enabled;
+                self.queue.push_back(FeatureOutput::ToWorker(true, ToWorker::SetLocalLoopback(channel, enabled)));
+            }
+            ChannelControl::SetFec(config) => {
+                log::info!("[PubSubFeatureController] SetFec for {} to {:?} from {:?}", channel, config, actor);
+                self.queue.push_back(FeatureOutput::ToWorker(true, ToWorker::SetFec(channel, config)));
+            }
+            ChannelControl::LoopbackStats => {
+                let stats = self.loopback.get(&channel).copied().unwrap_or_default();
+                self.queue.push_back(FeatureOutput::Event(actor, Event(channel, ChannelEvent::LoopbackStats(stats))));
+            }
+            ChannelControl::SubRelayLifecycle => {
+                if !self.lifecycle_subs.contains(&actor) {
+                    log::info!("[PubSubFeatureController] SubRelayLifecycle from {:?}", actor);
+                    self.lifecycle_subs.push(actor);
+                }
+            }
+            ChannelControl::UnsubRelayLifecycle => {
+                log::info!("[PubSubFeatureController] UnsubRelayLifecycle from {:?}", actor);
+                self.lifecycle_subs.retain(|a| *a != actor);
+            }
+            ChannelControl::SubRange(from, to) => {
+                log::info!("[PubSubFeatureController] SubRange [{}, {}] from {:?}", from, to, actor);
+                if self.ranges.on_sub(actor, from, to) {
+                    self.pop_ranges(ctx, now);
+                } else {
+                    self.queue.push_back(FeatureOutput::Event(actor, Event(from, ChannelEvent::RangeRejected)));
+                }
+            }
+            ChannelControl::UnsubRange(from, to) => {
+                log::info!("[PubSubFeatureController] UnsubRange [{}, {}] from {:?}", from, to, actor);
+                self.ranges.on_unsub(actor, from, to);
+                self.pop_ranges(ctx, now);
+            }
+            ChannelControl::SetSubToken(token) => {
+                log::info!("[PubSubFeatureController] SetSubToken for {} with token {} from {:?}", channel, token.is_some(), actor);
+                self.queue.push_back(FeatureOutput::ToWorker(true, ToWorker::SetSubToken(channel, token)));
+            }
         }
     }
 
+    /// Subs are handled only if they are allowed by the authorizer, the requester is the neighbour of the connection which the Sub is received from
+    fn authorize_remote_sub(&mut self, now: u64, remote: NetPair, relay_id: RelayId, control: RelayControl) -> Option<RelayControl> {
+        let authorizer = match &self.authorizer {
+            Some(authorizer) => authorizer,
+            None => {
+                return match control {
+                    RelayControl::SubAuth(uuid, _) => Some(RelayControl::Sub(uuid)),
+                    control => Some(control),
+                }
+            }
+        };
+        let (uuid, token) = match &control {
+            RelayControl::SubAuth(uuid, token) => (*uuid, Some(token.as_slice())),
+            RelayControl::Sub(uuid) => (*uuid, None),
+            _ => return Some(control),
+        };
+        let allowed = match self.neighbours.get(&remote) {
+            Some(requester) => authorizer.authorize(relay_id.0, *requester, token),
+            None => false,
+        };
+        if allowed {
+            return Some(RelayControl::Sub(uuid));
+        }
+        log::warn!("[PubSubFeatureController] Sub for {:?} from {remote} is rejected by authorizer", relay_id);
+        self.queue
+            .push_back(FeatureOutput::ToWorker(true, ToWorker::RelayControl(relay_id, RelayWorkerControl::SendSubRejected(uuid, remote))));
+        // a consumer which is already subscribed is removed, like the authorization is revoked
+        if let Some(relay) = self.relays.get_mut(&relay_id) {
+            relay.conn_disconnected(now, remote);
+            Self::pop_single_relay(relay_id, relay, &mut self.queue);
+            self.update_lifecycle(now, relay_id);
+        }
+        None
+    }
+
     fn on_remote_relay_control(&mut self, ctx: &FeatureContext, now: u64, remote: NetPair, relay_id: RelayId, control: RelayControl) {
-        if self.get_relay(ctx, relay_id, control.should_create()).is_some() {
+        let control = return_if_none!(self.authorize_remote_sub(now, remote, relay_id, control));
+        let auto_create = control.should_create() && !(self.decommission && relay_id.1 != ctx.node_id);
+        if self.get_relay(ctx, now, relay_id, auto_create).is_some() {
             let relay: &mut Box<dyn GenericRelay<UserData>> = self.relays.get_mut(&relay_id).expect("Should have relay");
             log::debug!("[PubSubFeatureController] Remote control for {:?} from {:?}: {:?}", relay_id, remote, control);
             relay.on_remote(now, remote, control);
             Self::pop_single_relay(relay_id, relay, &mut self.queue);
             if relay.should_clear() {
-                self.relays.remove(&relay_id);
+                self.remove_relay(now, relay_id);
+            } else {
+                self.update_lifecycle(now, relay_id);
             }
+        } else if self.decommission && control.should_create() {
+            log::debug!("[PubSubFeatureController] Decommissioning, reject new relay {:?} from {:?}", relay_id, remote);
         } else {
-            log::warn!("[PubSubFeatureController] Remote control for unknown relay {:?}", relay_id);
+            log_sampled!(log::Level::Warn, "[PubSubFeatureController] Remote control for unknown relay {:?}", relay_id);
         }
     }
 
@@ -219,7 +499,7 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
                 self.source_hints.remove(&channel);
             }
         } else {
-            log::warn!("[PubSubFeatureController] Remote control for unknown channel {:?}", channel);
+            log_sampled!(log::Level::Warn, "[PubSubFeatureController] Remote control for unknown channel {:?}", channel);
         }
     }
 
@@ -234,6 +514,11 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
                         queue.push_back(FeatureOutput::Event(actor, Event(relay_id.0, ChannelEvent::FeedbackData(fb))));
                     }
                 }
+                GenericRelayOutput::Rejected(actors) => {
+                    for actor in actors {
+                        queue.push_back(FeatureOutput::Event(actor, Event(relay_id.0, ChannelEvent::SubRejected(relay_id.1))));
+                    }
+                }
             };
         }
     }
@@ -253,6 +538,7 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
                 source_hint::Output::SubscribeSource(actors, source) => {
                     for actor in actors {
                         self.on_local(ctx, now, actor, channel, ChannelControl::SubSource(source));
+                        self.request_replay(ctx, now, actor, channel, source);
                     }
                 }
                 source_hint::Output::UnsubscribeSource(actors, source) => {
@@ -260,7 +546,112 @@ impl<UserData: 'static + Eq + Copy + Debug> PubSubFeature<UserData> {
                         self.on_local(ctx, now, actor, channel, ChannelControl::UnsubSource(source));
                     }
                 }
+                source_hint::Output::NoSourceFound(actor) => {
+                    self.queue.push_back(FeatureOutput::Event(actor, Event(channel, ChannelEvent::NoSourceFound)));
+                }
+                source_hint::Output::SourceFound(actors, source) => {
+                    for actor in actors {
+                        self.queue.push_back(FeatureOutput::Event(actor, Event(channel, ChannelEvent::SourceFound(source))));
+                    }
+                }
+            }
+        }
+    }
+
+    fn pop_ranges(&mut self, ctx: &FeatureContext, now: u64) {
+        while let Some(out) = self.ranges.pop_output() {
+            match out {
+                channel_range::Output::ToRoot(family, msg) => self.send_route_msg(RouteRule::ToKey(family), msg),
+                channel_range::Output::ToNode(dest, msg) => self.send_route_msg(RouteRule::ToNode(dest), msg),
+                channel_range::Output::Subscribe(actors, channel, source) => {
+                    for actor in actors {
+                        self.on_local(ctx, now, actor, channel, ChannelControl::SubSource(source));
+                        self.queue.push_back(FeatureOutput::Event(actor, Event(channel, ChannelEvent::RangeMatched(source))));
+                    }
+                }
+                channel_range::Output::Unsubscribe(actors, channel, source) => {
+                    for actor in actors {
+                        self.on_local(ctx, now, actor, channel, ChannelControl::UnsubSource(source));
+                        self.queue.push_back(FeatureOutput::Event(actor, Event(channel, ChannelEvent::RangeUnmatched(source))));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Stop relaying new channels for remote nodes and ask current remote consumers to find another path.
+    /// Channels which are published by this node are kept because there is no other path to them.
+    pub fn decommission(&mut self, ctx: &FeatureContext) {
+        log::info!("[PubSubFeatureController] Decommission with {} remote relayed channels", self.remote_relays());
+        self.decommission = true;
+        for (relay_id, relay) in self.relays.iter() {
+            if relay_id.1 != ctx.node_id && relay.relay_dests().is_some_and(|(_, has_remote)| has_remote) {
+                self.queue.push_back(FeatureOutput::ToWorker(true, ToWorker::RelayControl(*relay_id, RelayWorkerControl::SendHandover)));
+            }
+        }
+    }
+
+    /// Number of channels relayed to remote nodes
+    pub fn remote_relays(&self) -> usize {
+        self.relays.values().filter(|r| r.relay_dests().is_some_and(|(_, has_remote)| has_remote)).count()
+    }
+
+    /// Remote subscribers of all relays, each relayed message is sent once per remote subscriber
+    pub fn relay_fanout(&self) -> usize {
+        self.relays.values().map(|r| r.subscribers().1).sum()
+    }
+
+    /// Migrate bound relays to the best path to the source. It is used when it is faster than the current path by at least [`REBALANCE_MIN_IMPROVEMENT_MS`],
+    /// or when it is equal-distance over a neighbour which is less loaded as a relay by at least [`REBALANCE_MIN_LOAD_IMPROVEMENT`].
+    /// `paths` returns (neighbour, latency, relay load of neighbour) of paths to a source. It runs once per [`REBALANCE_INTERVAL_MS`]
+    pub fn rebalance(&mut self, now: u64, paths: impl Fn(NodeId) -> Vec<(NodeId, u16, u8)>) {
+        if now < self.last_rebalance + REBALANCE_INTERVAL_MS {
+            return;
+        }
+        self.last_rebalance = now;
+        for (relay_id, relay) in self.relays.iter_mut() {
+            let Some(current) = relay.next().and_then(|next| self.neighbours.get(&next).copied()) else {
+                continue;
+            };
+            let paths = paths(relay_id.1);
+            // between equal-distance paths the one over the less loaded relay is preferred
+            let Some((best, best_latency, best_load)) = paths.iter().min_by_key(|(_, latency, load)| (latency / RELAY_EQUAL_LATENCY_MS, *load, *latency)).copied() else {
+                continue;
+            };
+            if best == current {
+                continue;
             }
+            // current path can be removed from the table while the relay is still bound to it
+            let (current_latency, current_load) = paths
+                .iter()
+                .find(|(over, _, _)| *over == current)
+                .map(|(_, latency, load)| (*latency, *load))
+                .unwrap_or((u16::MAX, u8::MAX));
+            let faster = current_latency.saturating_sub(best_latency) >= REBALANCE_MIN_IMPROVEMENT_MS;
+            let less_loaded = best_latency / RELAY_EQUAL_LATENCY_MS <= current_latency / RELAY_EQUAL_LATENCY_MS && current_load.saturating_sub(best_load) >= REBALANCE_MIN_LOAD_IMPROVEMENT;
+            if !faster && !less_loaded {
+                continue;
+            }
+            let Some(next) = self.neighbours.iter().find(|(_, node)| **node == best).map(|(pair, _)| *pair) else {
+                continue;
+            };
+            log::info!(
+                "[PubSubFeatureController] Rebalance relay {:?} from {current} ({current_latency} ms, load {current_load}) to {best} ({best_latency} ms, load {best_load}) over {next}",
+                relay_id
+            );
+            relay.migrate(now, next);
+            Self::pop_single_relay(*relay_id, relay, &mut self.queue);
+        }
+    }
+
+    /// Relay load is advertised to neighbours, then their relays prefer this node only if it is less loaded than equal-distance paths
+    fn update_relay_load(&mut self) {
+        let remote_channels = self.remote_relays();
+        let load = (remote_channels * 255 / RELAY_FULL_LOAD_CHANNELS).min(255) as u8;
+        if load != self.relay_load {
+            log::debug!("[PubSubFeature] relay load changed {} => {} with {} remote relayed channels", self.relay_load, load, remote_channels);
+            self.relay_load = load;
+            self.queue.push_back(FeatureOutput::RouterSyncRelayLoad(load));
         }
     }
 }
@@ -270,17 +661,29 @@ impl<UserData: 'static + Eq + Copy + Debug> Feature<UserData, Control, Event, To
         match input {
             FeatureSharedInput::Tick(_) => {
                 let mut clears = vec![];
+                let mut not_clears = vec![];
                 for (relay_id, relay) in self.relays.iter_mut() {
                     if relay.should_clear() {
                         clears.push(*relay_id);
                     } else {
                         relay.on_tick(now);
                         Self::pop_single_relay(*relay_id, relay, &mut self.queue);
+                        not_clears.push(*relay_id);
                     }
                 }
                 for relay_id in clears {
-                    self.relays.remove(&relay_id);
+                    self.remove_relay(now, relay_id);
+                }
+                for relay_id in not_clears {
+                    self.update_lifecycle(now, relay_id);
                 }
+                // keep stats while the channel is published here, and disabled toggles for publishing later
+                let relays = &self.relays;
+                self.loopback.retain(|channel, stats| !stats.enabled || relays.contains_key(&RelayId(*channel, ctx.node_id)));
+                self.update_relay_load();
+                self.replays.on_tick(now);
+                self.ranges.on_tick(now);
+                self.pop_ranges(ctx, now);
 
                 let mut clears = vec![];
                 let mut not_clears = vec![];
@@ -299,14 +702,19 @@ impl<UserData: 'static + Eq + Copy + Debug> Feature<UserData, Control, Event, To
                     self.pop_single_source_hint(ctx, now, channel);
                 }
             }
-            FeatureSharedInput::Connection(event) => {
-                if let ConnectionEvent::Disconnected(ctx) = event {
+            FeatureSharedInput::Connection(event) => match event {
+                ConnectionEvent::Connected(ctx, _) => {
+                    self.neighbours.insert(ctx.pair, ctx.node);
+                }
+                ConnectionEvent::Disconnected(ctx) => {
+                    self.neighbours.remove(&ctx.pair);
                     for (relay_id, relay) in self.relays.iter_mut() {
                         relay.conn_disconnected(now, ctx.pair);
                         Self::pop_single_relay(*relay_id, relay, &mut self.queue);
                     }
                 }
-            }
+                _ => {}
+            },
         }
     }
 
@@ -318,6 +726,30 @@ impl<UserData: 'static + Eq + Copy + Debug> Feature<UserData, Control, Event, To
             FeatureInput::FromWorker(ToController::SourceHint(remote, channel, control)) => {
                 self.on_remote_source_hint_control(ctx, now_ms, remote, channel, control);
             }
+            FeatureInput::FromWorker(ToController::ReplayRequest(requester, relay_id, id, count)) => {
+                self.on_remote_replay_request(ctx, requester, relay_id, id, count);
+            }
+            FeatureInput::FromWorker(ToController::ReplayData(relay_id, id, data)) => {
+                if let Some(actor) = self.replays.actor_for(relay_id.0, id) {
+                    self.queue.push_back(FeatureOutput::Event(actor, Event(relay_id.0, ChannelEvent::ReplayData(relay_id.1, data))));
+                } else {
+                    log::debug!("[PubSubFeatureController] drop ReplayData of {:?} for unknown or timed out request {id}", relay_id);
+                }
+            }
+            FeatureInput::FromWorker(ToController::FamilyRegister(source, family, channels)) => {
+                self.ranges.on_register(now_ms, source, family, channels);
+            }
+            FeatureInput::FromWorker(ToController::FamilyUnregister(source, family, channels)) => {
+                self.ranges.on_unregister(source, family, channels);
+            }
+            FeatureInput::FromWorker(ToController::RangeQuery(requester, family, id, from, to)) => {
+                self.ranges.on_query(requester, family, id, from, to);
+                self.pop_ranges(ctx, now_ms);
+            }
+            FeatureInput::FromWorker(ToController::RangeSources(id, from, to, sources)) => {
+                self.ranges.on_sources(id, from, to, sources);
+                self.pop_ranges(ctx, now_ms);
+            }
             FeatureInput::Control(actor, Control(channel, control)) => {
                 self.on_local(ctx, now_ms, actor, channel, control);
             }
@@ -346,3 +778,178 @@ impl<UserData> TaskSwitcherChild<FeatureOutput<UserData, Event, ToWorker<UserDat
         self.queue.pop_front()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use atm0s_sdn_identity::{ConnId, NodeId};
+
+    use crate::{
+        base::{ConnectionCtx, ConnectionEvent, Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, SecureContext, SecureInfo},
+        data_plane::NetPair,
+        features::pubsub::{
+            msg::{ChannelId, RelayControl, RelayId},
+            ChannelAuthorizer, ChannelControl, ChannelEvent, Control, Event, RelayWorkerControl, ToController, ToWorker,
+        },
+    };
+    use sans_io_runtime::TaskSwitcherChild;
+
+    use super::{PubSubFeature, REBALANCE_INTERVAL_MS, REBALANCE_MIN_IMPROVEMENT_MS, REBALANCE_MIN_LOAD_IMPROVEMENT, WORKER_FANOUT_MIN_LOCALS};
+
+    struct TokenAuthorizer;
+
+    impl ChannelAuthorizer for TokenAuthorizer {
+        fn authorize(&self, _channel: ChannelId, requester: NodeId, token: Option<&[u8]>) -> bool {
+            requester == 2 && token == Some(b"secret")
+        }
+    }
+
+    fn connected(feature: &mut PubSubFeature<u32>, ctx: &FeatureContext, node: NodeId, pair: NetPair) {
+        let conn = ConnectionCtx {
+            conn: ConnId::from_out(0, node as u64),
+            node,
+            pair,
+            secure: SecureInfo::UNKNOWN,
+        };
+        feature.on_shared_input(ctx, 0, FeatureSharedInput::Connection(ConnectionEvent::Connected(conn, SecureContext::detached())));
+    }
+
+    #[test]
+    fn high_fanout_channel_is_replicated_by_worker() {
+        let ctx = FeatureContext { node_id: 1, session: 0 };
+        let mut feature = PubSubFeature::<u32>::default();
+        let channel = ChannelId(1000);
+        let subscribers = WORKER_FANOUT_MIN_LOCALS as u32;
+        for i in 0..subscribers {
+            feature.on_input(&ctx, 0, FeatureInput::Control(FeatureControlActor::Controller(i), Control(channel, ChannelControl::SubSource(1))));
+        }
+        while feature.pop_output(0).is_some() {}
+
+        feature.on_input(
+            &ctx,
+            0,
+            FeatureInput::Control(FeatureControlActor::Controller(0), Control(channel, ChannelControl::PubData(vec![1, 2, 3]))),
+        );
+        assert!(matches!(feature.pop_output(0), Some(FeatureOutput::ToWorker(false, ToWorker::RelayFanout(relay_id, data))) if relay_id.0 == channel && data == vec![1, 2, 3]));
+        assert!(feature.pop_output(0).is_none());
+
+        // below the threshold, the controller delivers directly
+        feature.on_input(&ctx, 0, FeatureInput::Control(FeatureControlActor::Controller(0), Control(channel, ChannelControl::UnsubSource(1))));
+        while feature.pop_output(0).is_some() {}
+        feature.on_input(&ctx, 0, FeatureInput::Control(FeatureControlActor::Controller(1), Control(channel, ChannelControl::PubData(vec![1]))));
+        let expected = Event(channel, ChannelEvent::SourceData(1, vec![1]));
+        let mut actors = vec![];
+        while let Some(out) = feature.pop_output(0) {
+            match out {
+                FeatureOutput::Event(FeatureControlActor::Controller(actor), event) if event == expected => actors.push(actor),
+                _ => panic!("Unexpected output {:?}", out),
+            }
+        }
+        actors.sort();
+        assert_eq!(actors, (1..subscribers).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn remote_sub_checked_by_authorizer() {
+        let ctx = FeatureContext { node_id: 1, session: 0 };
+        let mut feature = PubSubFeature::<u32>::default().with_authorizer(Some(Arc::new(TokenAuthorizer)));
+        let relay_id = RelayId(ChannelId(1000), 1);
+        let remote = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let unknown = NetPair::new_str("1.1.1.1:1000", "3.3.3.3:3000").expect("Should parse pair");
+        connected(&mut feature, &ctx, 2, remote);
+        feature.on_input(&ctx, 0, FeatureInput::Control(FeatureControlActor::Controller(0), Control(relay_id.0, ChannelControl::PubStart)));
+        while feature.pop_output(0).is_some() {}
+
+        for (pair, control) in [
+            (remote, RelayControl::SubAuth(2000, b"wrong".to_vec())),
+            (remote, RelayControl::Sub(2000)),
+            (unknown, RelayControl::SubAuth(2000, b"secret".to_vec())),
+        ] {
+            feature.on_input(&ctx, 0, FeatureInput::FromWorker(ToController::RelayControl(pair, relay_id, control)));
+            assert!(matches!(
+                feature.pop_output(0),
+                Some(FeatureOutput::ToWorker(true, ToWorker::RelayControl(id, RelayWorkerControl::SendSubRejected(2000, rejected)))) if id == relay_id && rejected == pair
+            ));
+            assert!(feature.pop_output(0).is_none());
+        }
+
+        feature.on_input(
+            &ctx,
+            0,
+            FeatureInput::FromWorker(ToController::RelayControl(remote, relay_id, RelayControl::SubAuth(2000, b"secret".to_vec()))),
+        );
+        assert!(matches!(
+            feature.pop_output(0),
+            Some(FeatureOutput::ToWorker(true, ToWorker::RelayControl(id, RelayWorkerControl::SendSubOk(2000, pair)))) if id == relay_id && pair == remote
+        ));
+        assert_eq!(feature.relay_fanout(), 1);
+    }
+
+    #[test]
+    fn rebalance_relay_to_faster_neighbour() {
+        let ctx = FeatureContext { node_id: 1, session: 0 };
+        let mut feature = PubSubFeature::<u32>::default();
+        let relay_id = RelayId(ChannelId(1000), 3);
+        let pair2 = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let pair3 = NetPair::new_str("1.1.1.1:1000", "3.3.3.3:3000").expect("Should parse pair");
+        connected(&mut feature, &ctx, 2, pair2);
+        connected(&mut feature, &ctx, 3, pair3);
+
+        feature.on_input(&ctx, 0, FeatureInput::Control(FeatureControlActor::Controller(0), Control(relay_id.0, ChannelControl::SubSource(3))));
+        while feature.pop_output(0).is_some() {}
+        feature.on_input(&ctx, 0, FeatureInput::FromWorker(ToController::RelayControl(pair2, relay_id, RelayControl::SubOK(0))));
+        while feature.pop_output(0).is_some() {}
+
+        // improvement under the threshold is ignored
+        let now = REBALANCE_INTERVAL_MS;
+        feature.rebalance(now, |_| vec![(3, 50, 0), (2, 50 + REBALANCE_MIN_IMPROVEMENT_MS - 1, 0)]);
+        assert!(feature.pop_output(now).is_none());
+
+        // rebalance is limited by interval
+        feature.rebalance(now + 1, |_| vec![(3, 10, 0), (2, 100, 0)]);
+        assert!(feature.pop_output(now).is_none());
+
+        let now = 2 * REBALANCE_INTERVAL_MS;
+        feature.rebalance(now, |_| vec![(3, 10, 0), (2, 100, 0)]);
+        assert!(matches!(
+            feature.pop_output(now),
+            Some(FeatureOutput::ToWorker(true, ToWorker::RelayControl(id, RelayWorkerControl::SendSub(0, Some(pair))))) if id == relay_id && pair == pair3
+        ));
+        assert!(feature.pop_output(now).is_none());
+    }
+
+    #[test]
+    fn rebalance_relay_to_less_loaded_neighbour() {
+        let ctx = FeatureContext { node_id: 1, session: 0 };
+        let mut feature = PubSubFeature::<u32>::default();
+        let relay_id = RelayId(ChannelId(1000), 4);
+        let pair2 = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let pair3 = NetPair::new_str("1.1.1.1:1000", "3.3.3.3:3000").expect("Should parse pair");
+        connected(&mut feature, &ctx, 2, pair2);
+        connected(&mut feature, &ctx, 3, pair3);
+
+        feature.on_input(&ctx, 0, FeatureInput::Control(FeatureControlActor::Controller(0), Control(relay_id.0, ChannelControl::SubSource(4))));
+        while feature.pop_output(0).is_some() {}
+        feature.on_input(&ctx, 0, FeatureInput::FromWorker(ToController::RelayControl(pair2, relay_id, RelayControl::SubOK(0))));
+        while feature.pop_output(0).is_some() {}
+
+        // small load difference is ignored
+        let now = REBALANCE_INTERVAL_MS;
+        feature.rebalance(now, |_| vec![(2, 50, 100), (3, 55, 100 - REBALANCE_MIN_LOAD_IMPROVEMENT + 1)]);
+        assert!(feature.pop_output(now).is_none());
+
+        // less loaded relay which is not equal-distance is ignored
+        let now = 2 * REBALANCE_INTERVAL_MS;
+        feature.rebalance(now, |_| vec![(2, 50, 200), (3, 65, 0)]);
+        assert!(feature.pop_output(now).is_none());
+
+        let now = 3 * REBALANCE_INTERVAL_MS;
+        feature.rebalance(now, |_| vec![(2, 50, 200), (3, 55, 0)]);
+        assert!(matches!(
+            feature.pop_output(now),
+            Some(FeatureOutput::ToWorker(true, ToWorker::RelayControl(id, RelayWorkerControl::SendSub(0, Some(pair))))) if id == relay_id && pair == pair3
+        ));
+        assert!(feature.pop_output(now).is_none());
+    }
+}
@@ -0,0 +1,356 @@
+//! Range subscriptions, which let monitoring tools observe a family of channels without subscribing each channel.
+//!
+//! Term: channels with the same high 32 bits of ChannelId are in the same family, and the family root is the node which is closest
+//! to the family key in XOR distance.
+//!
+//! Register: each publisher node sends its channels of a family to the root in each tick for keep alive, and Unregister when a channel is stopped.
+//! The root removes channels which are not resent after FAMILY_TIMEOUT_MS.
+//!
+//! Query: a node with range subscribers sends RangeQuery(family, id, from, to) to the root in each tick, and the root answers with published channels
+//! inside the range. An answer has at most RANGE_MSG_MAX_ITEMS channels, a truncated answer only covers [from, last channel] and the next page is queried immediately.
+//! The subscriber node compares each answer with matched channels inside the covered range, then subscribes new (channel, source) pairs
+//! and unsubscribes removed pairs, same as a manual subscriber with SubSource and UnsubSource.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+use atm0s_sdn_identity::NodeId;
+use sans_io_runtime::return_if_none;
+
+use crate::{
+    base::FeatureControlActor,
+    features::pubsub::msg::{ChannelId, PubsubMessage},
+};
+
+/// Registered channels are removed from the root if the publisher node doesn't resend them in this time
+pub const FAMILY_TIMEOUT_MS: u64 = 10_000;
+/// Max channels in a single FamilyRegister or RangeSources message, which keeps the message in a single packet
+pub const RANGE_MSG_MAX_ITEMS: usize = 64;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Output<UserData> {
+    /// Routed to the root of the family
+    ToRoot(u32, PubsubMessage),
+    /// Routed to a requester node
+    ToNode(NodeId, PubsubMessage),
+    Subscribe(Vec<FeatureControlActor<UserData>>, ChannelId, NodeId),
+    Unsubscribe(Vec<FeatureControlActor<UserData>>, ChannelId, NodeId),
+}
+
+struct RangeSub<UserData> {
+    id: u64,
+    from: ChannelId,
+    to: ChannelId,
+    /// Start of the next queried page
+    cursor: ChannelId,
+    actors: Vec<FeatureControlActor<UserData>>,
+    matched: BTreeSet<(ChannelId, NodeId)>,
+}
+
+pub struct ChannelRanges<UserData> {
+    /// Channels which are published by this node, with their local publishers
+    published: BTreeMap<ChannelId, Vec<FeatureControlActor<UserData>>>,
+    /// Channels which are registered to this node as the family root, with last register time
+    registry: HashMap<u32, BTreeMap<(ChannelId, NodeId), u64>>,
+    subs: Vec<RangeSub<UserData>>,
+    next_id: u64,
+    queue: VecDeque<Output<UserData>>,
+}
+
+impl<UserData> Default for ChannelRanges<UserData> {
+    fn default() -> Self {
+        Self {
+            published: BTreeMap::new(),
+            registry: HashMap::new(),
+            subs: Vec::new(),
+            next_id: 0,
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+impl<UserData: Eq + Copy> ChannelRanges<UserData> {
+    pub fn on_pub_start(&mut self, channel: ChannelId, actor: FeatureControlActor<UserData>) {
+        let actors = self.published.entry(channel).or_default();
+        if actors.contains(&actor) {
+            return;
+        }
+        actors.push(actor);
+        if actors.len() == 1 {
+            log::info!("[ChannelRanges] Register {} to family {} root", channel, channel.family());
+            self.queue.push_back(Output::ToRoot(channel.family(), PubsubMessage::FamilyRegister(channel.family(), vec![channel])));
+        }
+    }
+
+    pub fn on_pub_stop(&mut self, channel: ChannelId, actor: FeatureControlActor<UserData>) {
+        let actors = return_if_none!(self.published.get_mut(&channel));
+        actors.retain(|a| *a != actor);
+        if actors.is_empty() {
+            log::info!("[ChannelRanges] Unregister {} from family {} root", channel, channel.family());
+            self.published.remove(&channel);
+            self.queue.push_back(Output::ToRoot(channel.family(), PubsubMessage::FamilyUnregister(channel.family(), vec![channel])));
+        }
+    }
+
+    /// Subscribe channels inside [from, to], the range must be inside a single family. Returns false if the range is invalid
+    pub fn on_sub(&mut self, actor: FeatureControlActor<UserData>, from: ChannelId, to: ChannelId) -> bool {
+        if from > to || from.family() != to.family() {
+            log::warn!("[ChannelRanges] Reject range [{}, {}] which is empty or not inside a single family", from, to);
+            return false;
+        }
+        if let Some(sub) = self.subs.iter_mut().find(|s| s.from == from && s.to == to) {
+            if !sub.actors.contains(&actor) {
+                sub.actors.push(actor);
+                for (channel, source) in &sub.matched {
+                    self.queue.push_back(Output::Subscribe(vec![actor], *channel, *source));
+                }
+            }
+        } else {
+            let id = self.next_id;
+            self.next_id += 1;
+            log::info!("[ChannelRanges] Create range sub {id} for [{}, {}]", from, to);
+            self.subs.push(RangeSub {
+                id,
+                from,
+                to,
+                cursor: from,
+                actors: vec![actor],
+                matched: BTreeSet::new(),
+            });
+            self.queue.push_back(Output::ToRoot(from.family(), PubsubMessage::RangeQuery(from.family(), id, from, to)));
+        }
+        true
+    }
+
+    pub fn on_unsub(&mut self, actor: FeatureControlActor<UserData>, from: ChannelId, to: ChannelId) {
+        let index = return_if_none!(self.subs.iter().position(|s| s.from == from && s.to == to));
+        let sub = &mut self.subs[index];
+        if let Some(pos) = sub.actors.iter().position(|a| *a == actor) {
+            sub.actors.swap_remove(pos);
+            for (channel, source) in &sub.matched {
+                self.queue.push_back(Output::Unsubscribe(vec![actor], *channel, *source));
+            }
+        }
+        if sub.actors.is_empty() {
+            log::info!("[ChannelRanges] Remove range sub {} for [{}, {}]", sub.id, from, to);
+            self.subs.swap_remove(index);
+        }
+    }
+
+    pub fn on_tick(&mut self, now: u64) {
+        let mut families: BTreeMap<u32, Vec<ChannelId>> = BTreeMap::new();
+        for channel in self.published.keys() {
+            families.entry(channel.family()).or_default().push(*channel);
+        }
+        for (family, channels) in families {
+            for chunk in channels.chunks(RANGE_MSG_MAX_ITEMS) {
+                self.queue.push_back(Output::ToRoot(family, PubsubMessage::FamilyRegister(family, chunk.to_vec())));
+            }
+        }
+
+        for (family, entries) in self.registry.iter_mut() {
+            entries.retain(|(channel, source), last_ts| {
+                let alive = now < *last_ts + FAMILY_TIMEOUT_MS;
+                if !alive {
+                    log::warn!("[ChannelRanges] Remove {} of source {source} from family {family} because timeout", channel);
+                }
+                alive
+            });
+        }
+        self.registry.retain(|_, entries| !entries.is_empty());
+
+        for sub in &self.subs {
+            self.queue
+                .push_back(Output::ToRoot(sub.from.family(), PubsubMessage::RangeQuery(sub.from.family(), sub.id, sub.cursor, sub.to)));
+        }
+    }
+
+    pub fn on_register(&mut self, now: u64, source: NodeId, family: u32, channels: Vec<ChannelId>) {
+        let entries = self.registry.entry(family).or_default();
+        for channel in channels.into_iter().filter(|c| c.family() == family) {
+            if entries.insert((channel, source), now).is_none() {
+                log::info!("[ChannelRanges] Source {source} registered {} to family {family}", channel);
+            }
+        }
+    }
+
+    pub fn on_unregister(&mut self, source: NodeId, family: u32, channels: Vec<ChannelId>) {
+        let entries = return_if_none!(self.registry.get_mut(&family));
+        for channel in channels {
+            if entries.remove(&(channel, source)).is_some() {
+                log::info!("[ChannelRanges] Source {source} unregistered {} from family {family}", channel);
+            }
+        }
+        if entries.is_empty() {
+            self.registry.remove(&family);
+        }
+    }
+
+    /// Answer a query with registered channels inside [from, to], sources of a single channel are never split between pages
+    pub fn on_query(&mut self, requester: NodeId, family: u32, id: u64, from: ChannelId, to: ChannelId) {
+        if from > to {
+            return;
+        }
+        let mut page: Vec<(ChannelId, NodeId)> = vec![];
+        let mut covered = to;
+        if let Some(entries) = self.registry.get(&family) {
+            for (channel, source) in entries.range((from, NodeId::MIN)..=(to, NodeId::MAX)).map(|(k, _)| *k) {
+                if let Some((last, _)) = page.last().filter(|(last, _)| page.len() >= RANGE_MSG_MAX_ITEMS && *last != channel) {
+                    covered = *last;
+                    break;
+                }
+                page.push((channel, source));
+            }
+        }
+        log::debug!(
+            "[ChannelRanges] Answer query {id} of {requester} in family {family} with {} channels in [{}, {}]",
+            page.len(),
+            from,
+            covered
+        );
+        self.queue.push_back(Output::ToNode(requester, PubsubMessage::RangeSources(id, from, covered, page)));
+    }
+
+    pub fn on_sources(&mut self, id: u64, from: ChannelId, to: ChannelId, sources: Vec<(ChannelId, NodeId)>) {
+        let sub = return_if_none!(self.subs.iter_mut().find(|s| s.id == id));
+        if from < sub.from || to > sub.to || from > to {
+            log::warn!("[ChannelRanges] Range sub {id} received answer [{}, {}] outside of [{}, {}]", from, to, sub.from, sub.to);
+            return;
+        }
+        let found: BTreeSet<(ChannelId, NodeId)> = sources.into_iter().filter(|(c, _)| from <= *c && *c <= to).collect();
+        let removed: Vec<_> = sub.matched.range((from, NodeId::MIN)..=(to, NodeId::MAX)).filter(|pair| !found.contains(pair)).cloned().collect();
+        for (channel, source) in removed {
+            log::info!("[ChannelRanges] Range sub {id} unmatched {} from source {source}", channel);
+            sub.matched.remove(&(channel, source));
+            self.queue.push_back(Output::Unsubscribe(sub.actors.clone(), channel, source));
+        }
+        for (channel, source) in found {
+            if sub.matched.insert((channel, source)) {
+                log::info!("[ChannelRanges] Range sub {id} matched {} from source {source}", channel);
+                self.queue.push_back(Output::Subscribe(sub.actors.clone(), channel, source));
+            }
+        }
+
+        if to < sub.to {
+            sub.cursor = ChannelId(*to + 1);
+            self.queue
+                .push_back(Output::ToRoot(sub.from.family(), PubsubMessage::RangeQuery(sub.from.family(), id, sub.cursor, sub.to)));
+        } else {
+            sub.cursor = sub.from;
+        }
+    }
+
+    pub fn pop_output(&mut self) -> Option<Output<UserData>> {
+        self.queue.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        base::FeatureControlActor,
+        features::pubsub::msg::{ChannelId, PubsubMessage},
+    };
+
+    use super::{ChannelRanges, Output, FAMILY_TIMEOUT_MS, RANGE_MSG_MAX_ITEMS};
+
+    const FAMILY: u32 = 1;
+
+    fn channel(index: u64) -> ChannelId {
+        ChannelId(((FAMILY as u64) << 32) + index)
+    }
+
+    #[test]
+    fn reject_range_outside_family() {
+        let mut ranges = ChannelRanges::default();
+        let actor = FeatureControlActor::Controller(());
+        assert!(!ranges.on_sub(actor, channel(10), channel(1)));
+        assert!(!ranges.on_sub(actor, channel(1), ChannelId(2 << 32)));
+        assert_eq!(ranges.pop_output(), None);
+    }
+
+    #[test]
+    fn publisher_register_and_unregister() {
+        let mut ranges = ChannelRanges::default();
+        let actor = FeatureControlActor::Controller(());
+        ranges.on_pub_start(channel(1), actor);
+        assert_eq!(ranges.pop_output(), Some(Output::ToRoot(FAMILY, PubsubMessage::FamilyRegister(FAMILY, vec![channel(1)]))));
+        ranges.on_pub_start(channel(1), FeatureControlActor::Worker(1, ()));
+        assert_eq!(ranges.pop_output(), None);
+
+        ranges.on_tick(1000);
+        assert_eq!(ranges.pop_output(), Some(Output::ToRoot(FAMILY, PubsubMessage::FamilyRegister(FAMILY, vec![channel(1)]))));
+        assert_eq!(ranges.pop_output(), None);
+
+        ranges.on_pub_stop(channel(1), actor);
+        assert_eq!(ranges.pop_output(), None);
+        ranges.on_pub_stop(channel(1), FeatureControlActor::Worker(1, ()));
+        assert_eq!(ranges.pop_output(), Some(Output::ToRoot(FAMILY, PubsubMessage::FamilyUnregister(FAMILY, vec![channel(1)]))));
+    }
+
+    #[test]
+    fn root_answer_query_with_pages() {
+        let mut ranges = ChannelRanges::<()>::default();
+        let channels = (0..RANGE_MSG_MAX_ITEMS as u64 + 10).map(channel).collect::<Vec<_>>();
+        ranges.on_register(0, 2, FAMILY, channels.clone());
+        //channel of other family is ignored
+        ranges.on_register(0, 2, FAMILY, vec![ChannelId(5)]);
+        //second source of the last channel in the first page is kept in the same page
+        ranges.on_register(0, 3, FAMILY, vec![channel(RANGE_MSG_MAX_ITEMS as u64 - 1)]);
+
+        ranges.on_query(10, FAMILY, 1, channel(0), channel(1000));
+        let mut first_page = channels[..RANGE_MSG_MAX_ITEMS].iter().map(|c| (*c, 2)).collect::<Vec<_>>();
+        first_page.push((channel(RANGE_MSG_MAX_ITEMS as u64 - 1), 3));
+        assert_eq!(
+            ranges.pop_output(),
+            Some(Output::ToNode(10, PubsubMessage::RangeSources(1, channel(0), channel(RANGE_MSG_MAX_ITEMS as u64 - 1), first_page)))
+        );
+
+        ranges.on_query(10, FAMILY, 1, channel(RANGE_MSG_MAX_ITEMS as u64), channel(1000));
+        let second_page = channels[RANGE_MSG_MAX_ITEMS..].iter().map(|c| (*c, 2)).collect::<Vec<_>>();
+        assert_eq!(
+            ranges.pop_output(),
+            Some(Output::ToNode(10, PubsubMessage::RangeSources(1, channel(RANGE_MSG_MAX_ITEMS as u64), channel(1000), second_page)))
+        );
+
+        //unregister and timeout
+        ranges.on_unregister(2, FAMILY, channels);
+        ranges.on_tick(FAMILY_TIMEOUT_MS);
+        ranges.on_query(10, FAMILY, 1, channel(0), channel(1000));
+        assert_eq!(ranges.pop_output(), Some(Output::ToNode(10, PubsubMessage::RangeSources(1, channel(0), channel(1000), vec![]))));
+    }
+
+    #[test]
+    fn subscriber_match_and_unmatch() {
+        let mut ranges = ChannelRanges::default();
+        let actor1 = FeatureControlActor::Controller(());
+        let actor2 = FeatureControlActor::Worker(1, ());
+        assert!(ranges.on_sub(actor1, channel(0), channel(100)));
+        assert_eq!(ranges.pop_output(), Some(Output::ToRoot(FAMILY, PubsubMessage::RangeQuery(FAMILY, 0, channel(0), channel(100)))));
+
+        ranges.on_sources(0, channel(0), channel(100), vec![(channel(1), 2), (channel(200), 2)]);
+        assert_eq!(ranges.pop_output(), Some(Output::Subscribe(vec![actor1], channel(1), 2)));
+        assert_eq!(ranges.pop_output(), None);
+
+        //second actor of same range receives current matched channels
+        assert!(ranges.on_sub(actor2, channel(0), channel(100)));
+        assert_eq!(ranges.pop_output(), Some(Output::Subscribe(vec![actor2], channel(1), 2)));
+
+        //truncated answer queries next page immediately and only updates the covered range
+        ranges.on_sources(0, channel(0), channel(5), vec![(channel(2), 3)]);
+        assert_eq!(ranges.pop_output(), Some(Output::Unsubscribe(vec![actor1, actor2], channel(1), 2)));
+        assert_eq!(ranges.pop_output(), Some(Output::Subscribe(vec![actor1, actor2], channel(2), 3)));
+        assert_eq!(ranges.pop_output(), Some(Output::ToRoot(FAMILY, PubsubMessage::RangeQuery(FAMILY, 0, channel(6), channel(100)))));
+        ranges.on_sources(0, channel(6), channel(100), vec![]);
+        assert_eq!(ranges.pop_output(), None);
+
+        ranges.on_tick(1000);
+        assert_eq!(ranges.pop_output(), Some(Output::ToRoot(FAMILY, PubsubMessage::RangeQuery(FAMILY, 0, channel(0), channel(100)))));
+
+        ranges.on_unsub(actor1, channel(0), channel(100));
+        assert_eq!(ranges.pop_output(), Some(Output::Unsubscribe(vec![actor1], channel(2), 3)));
+        ranges.on_unsub(actor2, channel(0), channel(100));
+        assert_eq!(ranges.pop_output(), Some(Output::Unsubscribe(vec![actor2], channel(2), 3)));
+        ranges.on_tick(2000);
+        assert_eq!(ranges.pop_output(), None);
+    }
+}
@@ -102,6 +102,22 @@ impl<UserData: Eq + Debug + Copy> RelayConsumers<UserData> {
         }
     }
 
+    /// Remove all consumers after the relay is rejected, remotes are notified with SubRejected. Returns removed local actors
+    pub fn reject_all(&mut self) -> Vec<FeatureControlActor<UserData>> {
+        for (remote, slot) in self.remotes.drain() {
+            self.queue.push_back(RelayWorkerControl::SendSubRejected(slot.uuid, remote));
+            self.queue.push_back(RelayWorkerControl::RouteDelRemote(remote));
+        }
+        for actor in &self.locals {
+            self.queue.push_back(RelayWorkerControl::RouteDelLocal(*actor));
+        }
+        std::mem::take(&mut self.locals)
+    }
+
+    pub fn has_remote(&self, remote: &NetPair) -> bool {
+        self.remotes.contains_key(remote)
+    }
+
     pub fn should_clear(&self) -> bool {
         self.locals.is_empty() && self.remotes.is_empty()
     }
@@ -110,6 +126,11 @@ impl<UserData: Eq + Debug + Copy> RelayConsumers<UserData> {
         (self.locals.as_slice(), !self.remotes.is_empty())
     }
 
+    /// Number of local and remote subscribers
+    pub fn subscribers(&self) -> (usize, usize) {
+        (self.locals.len(), self.remotes.len())
+    }
+
     pub fn pop_output(&mut self) -> Option<RelayWorkerControl<UserData>> {
         self.queue.pop_front()
     }
@@ -70,6 +70,10 @@ impl<UserData: Eq + Debug + Copy> GenericRelay<UserData> for LocalRelay<UserData
         Some(self.consumers.relay_dests())
     }
 
+    fn subscribers(&self) -> (usize, usize) {
+        self.consumers.subscribers()
+    }
+
     fn pop_output(&mut self) -> Option<GenericRelayOutput<UserData>> {
         if let Some(fb) = self.feedbacks.pop_output() {
             log::debug!("[LocalRelay] pop_output feedback {:?}", fb);
@@ -14,7 +14,7 @@ use crate::{
 };
 use std::{collections::VecDeque, fmt::Debug};
 
-use super::{consumers::RelayConsumers, feedbacks::FeedbacksAggerator, GenericRelay, GenericRelayOutput, RELAY_STICKY_MS, RELAY_TIMEOUT};
+use super::{consumers::RelayConsumers, feedbacks::FeedbacksAggerator, GenericRelay, GenericRelayOutput, RELAY_TIMEOUT};
 
 enum RelayState<UserData> {
     New,
@@ -26,7 +26,7 @@ enum RelayState<UserData> {
         consumers: RelayConsumers<UserData>,
         feedbacks: FeedbacksAggerator<UserData>,
         next: NetPair,
-        sticky_session_at: u64,
+        sticky_session_end: u64,
     },
     Unbinding {
         next: NetPair,
@@ -37,15 +37,20 @@ enum RelayState<UserData> {
 
 pub struct RemoteRelay<UserData> {
     uuid: u64,
+    sticky_ms: u64,
     state: RelayState<UserData>,
+    /// Neighbour which is asked by `migrate`, only its SubOK can switch the relay inside sticky session
+    migrate_to: Option<NetPair>,
     queue: VecDeque<GenericRelayOutput<UserData>>,
 }
 
 impl<UserData: Eq + Copy + Debug> RemoteRelay<UserData> {
-    pub fn new(uuid: u64) -> Self {
+    pub fn new(uuid: u64, sticky_ms: u64) -> Self {
         Self {
             uuid,
+            sticky_ms,
             state: RelayState::New,
+            migrate_to: None,
             queue: VecDeque::new(),
         }
     }
@@ -70,10 +75,10 @@ impl<UserData: Eq + Copy + Debug> GenericRelay<UserData> for RemoteRelay<UserDat
                 next,
                 consumers,
                 feedbacks,
-                sticky_session_at,
+                sticky_session_end,
                 ..
             } => {
-                if now >= *sticky_session_at + RELAY_STICKY_MS {
+                if now >= *sticky_session_end {
                     log::info!("[PubSubRemoteRelay] Sticky session end for relay from {next} => trying finding better way");
                     self.queue.push_back(GenericRelayOutput::ToWorker(RelayWorkerControl::SendSub(self.uuid, None)));
                 } else {
@@ -237,15 +242,18 @@ impl<UserData: Eq + Copy + Debug> GenericRelay<UserData> for RemoteRelay<UserDat
                             next: remote,
                             consumers,
                             feedbacks,
-                            sticky_session_at: now,
+                            sticky_session_end: now + self.sticky_ms,
                         };
                     }
                     RelayState::Bound {
-                        next, sticky_session_at, consumers, ..
+                        next, sticky_session_end, consumers, ..
                     } => {
                         if *next == remote {
                             log::debug!("[Relay] SubOK for bound relay {} from same remote {remote} => renew sticky session", self.uuid);
-                            *sticky_session_at = now;
+                            *sticky_session_end = now + self.sticky_ms;
+                        } else if now < *sticky_session_end && self.migrate_to != Some(remote) {
+                            log::warn!("[Relay] SubOK for bound relay {} from other remote {remote} inside sticky session => stale, Unsub it", self.uuid);
+                            self.queue.push_back(GenericRelayOutput::ToWorker(RelayWorkerControl::SendUnsub(self.uuid, remote)));
                         } else {
                             log::warn!("[Relay] SubOK for bound relay {} from other remote {remote} => renew stick session and Unsub older", self.uuid);
                             let (locals, has_remote) = consumers.relay_dests();
@@ -258,6 +266,8 @@ impl<UserData: Eq + Copy + Debug> GenericRelay<UserData> for RemoteRelay<UserDat
                                 self.queue.push_back(GenericRelayOutput::RouteChanged(*actor));
                             }
                             *next = remote;
+                            *sticky_session_end = now + self.sticky_ms;
+                            self.migrate_to = None;
                         }
                     }
                     _ => {}
@@ -288,6 +298,38 @@ impl<UserData: Eq + Copy + Debug> GenericRelay<UserData> for RemoteRelay<UserDat
                     }
                 }
             }
+            RelayControl::Handover(uuid) => {
+                if uuid != self.uuid {
+                    log::warn!("[Relay] Handover for wrong relay session {uuid} vs {}", self.uuid);
+                    return;
+                }
+                if let RelayState::Bound { next, sticky_session_end, .. } = &mut self.state {
+                    if *next == remote {
+                        log::info!("[Relay] Handover for bound relay {} from {remote} => end sticky session and finding new way", self.uuid);
+                        *sticky_session_end = now;
+                        self.queue.push_back(GenericRelayOutput::ToWorker(RelayWorkerControl::SendSub(self.uuid, None)));
+                    }
+                }
+            }
+            RelayControl::SubRejected(uuid) => {
+                if uuid != self.uuid {
+                    log::warn!("[Relay] SubRejected for wrong relay session {uuid} vs {}", self.uuid);
+                    return;
+                }
+                let consumers = match &mut self.state {
+                    RelayState::Binding { consumers, .. } => consumers,
+                    RelayState::Bound { next, consumers, .. } if *next == remote => {
+                        self.queue.push_back(GenericRelayOutput::ToWorker(RelayWorkerControl::RouteDelSource(*next)));
+                        consumers
+                    }
+                    _ => return,
+                };
+                log::warn!("[Relay] SubRejected for relay {} from {remote} => reject all consumers and switched to Unbound", self.uuid);
+                let locals = consumers.reject_all();
+                Self::pop_consumers_out(consumers, &mut self.queue);
+                self.queue.push_back(GenericRelayOutput::Rejected(locals));
+                self.state = RelayState::Unbound;
+            }
             RelayControl::Feedback(fb) => match &mut self.state {
                 RelayState::Binding { feedbacks, .. } => {
                     feedbacks.on_remote_feedback(now, remote, fb);
@@ -346,9 +388,35 @@ impl<UserData: Eq + Copy + Debug> GenericRelay<UserData> for RemoteRelay<UserDat
         }
     }
 
+    fn subscribers(&self) -> (usize, usize) {
+        match &self.state {
+            RelayState::Bound { consumers, .. } | RelayState::Binding { consumers, .. } => consumers.subscribers(),
+            _ => (0, 0),
+        }
+    }
+
     fn should_clear(&self) -> bool {
         matches!(self.state, RelayState::Unbound)
     }
+
+    fn next(&self) -> Option<NetPair> {
+        match &self.state {
+            RelayState::Bound { next, .. } => Some(*next),
+            _ => None,
+        }
+    }
+
+    /// Make before break: Sub is sent to the new neighbour, then its SubOK switches the relay and Unsubs the current one.
+    /// A consumer of this relay is never used, because it receives data over this node
+    fn migrate(&mut self, _now: u64, to: NetPair) {
+        if let RelayState::Bound { next, consumers, .. } = &self.state {
+            if *next != to && !consumers.has_remote(&to) {
+                log::info!("[PubSubRemoteRelay] Migrate relay {} from {next} to {to}", self.uuid);
+                self.migrate_to = Some(to);
+                self.queue.push_back(GenericRelayOutput::ToWorker(RelayWorkerControl::SendSub(self.uuid, Some(to))));
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -366,7 +434,7 @@ mod tests {
     use super::RemoteRelay;
 
     fn create_local_bound_relay(uuid: u64, actor: FeatureControlActor<()>, remote: NetPair) -> RemoteRelay<()> {
-        let mut relay = RemoteRelay::new(uuid);
+        let mut relay = RemoteRelay::new(uuid, RELAY_STICKY_MS);
 
         relay.on_local_sub(0, actor);
 
@@ -383,7 +451,7 @@ mod tests {
 
     #[test]
     fn on_local_sub_unsub() {
-        let mut relay = RemoteRelay::new(1000);
+        let mut relay = RemoteRelay::new(1000, RELAY_STICKY_MS);
 
         relay.on_local_sub(100, FeatureControlActor::Controller(()));
 
@@ -420,7 +488,7 @@ mod tests {
 
     #[test]
     fn on_remote_sub_unsub() {
-        let mut relay = RemoteRelay::<()>::new(1000);
+        let mut relay = RemoteRelay::<()>::new(1000, RELAY_STICKY_MS);
 
         let consumer = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2001").expect("Should parse pair");
 
@@ -455,7 +523,7 @@ mod tests {
 
     #[test]
     fn retry_sending_sub() {
-        let mut relay = RemoteRelay::new(1000);
+        let mut relay = RemoteRelay::new(1000, RELAY_STICKY_MS);
 
         relay.on_local_sub(100, FeatureControlActor::Controller(()));
 
@@ -520,7 +588,7 @@ mod tests {
 
     #[test]
     fn consumer_disconnected_should_unsub_if_empty() {
-        let mut relay = RemoteRelay::<()>::new(1000);
+        let mut relay = RemoteRelay::<()>::new(1000, RELAY_STICKY_MS);
 
         let consumer = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2001").expect("Should parse pair");
 
@@ -593,4 +661,98 @@ mod tests {
         assert_eq!(relay.pop_output(), Some(GenericRelayOutput::RouteChanged(FeatureControlActor::Controller(()))));
         assert_eq!(relay.pop_output(), None);
     }
+
+    #[test]
+    fn handover_from_next_node_finding_new_way() {
+        let remote = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let mut relay = create_local_bound_relay(1000, FeatureControlActor::Controller(()), remote);
+
+        let remote2 = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2001").expect("Should parse pair");
+
+        //handover with wrong session or from other remote should be ignored
+        relay.on_remote(100, remote, RelayControl::Handover(1001));
+        relay.on_remote(100, remote2, RelayControl::Handover(1000));
+        assert_eq!(relay.pop_output(), None);
+
+        relay.on_remote(100, remote, RelayControl::Handover(1000));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::SendSub(1000, None))));
+        assert_eq!(relay.pop_output(), None);
+
+        //sticky session is ended, so tick will keep finding new way until SubOK
+        relay.on_tick(200);
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::SendSub(1000, None))));
+        assert_eq!(relay.pop_output(), None);
+
+        relay.on_remote(300, remote2, RelayControl::SubOK(1000));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::SendUnsub(1000, remote))));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::RouteSetSource(remote2))));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::RouteChanged(FeatureControlActor::Controller(()))));
+        assert_eq!(relay.pop_output(), None);
+    }
+
+    #[test]
+    fn rejected_by_next_node_should_reject_consumers() {
+        let remote = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let mut relay = create_local_bound_relay(1000, FeatureControlActor::Controller(()), remote);
+
+        let consumer = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2001").expect("Should parse pair");
+        relay.on_remote(100, consumer, RelayControl::Sub(2000));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::SendSubOk(2000, consumer))));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::RouteSetRemote(consumer, 2000))));
+        assert_eq!(relay.pop_output(), None);
+
+        //rejected from other remote should be ignored
+        relay.on_remote(200, consumer, RelayControl::SubRejected(1000));
+        assert_eq!(relay.pop_output(), None);
+
+        relay.on_remote(200, remote, RelayControl::SubRejected(1000));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::RouteDelSource(remote))));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::SendSubRejected(2000, consumer))));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::RouteDelRemote(consumer))));
+        assert_eq!(
+            relay.pop_output(),
+            Some(GenericRelayOutput::ToWorker(RelayWorkerControl::RouteDelLocal(FeatureControlActor::Controller(()))))
+        );
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::Rejected(vec![FeatureControlActor::Controller(())])));
+        assert_eq!(relay.pop_output(), None);
+        assert!(relay.should_clear());
+    }
+
+    #[test]
+    fn migrate_make_before_break() {
+        let remote = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let mut relay = create_local_bound_relay(1000, FeatureControlActor::Controller(()), remote);
+        assert_eq!(relay.next(), Some(remote));
+
+        let consumer = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2001").expect("Should parse pair");
+        relay.on_remote(100, consumer, RelayControl::Sub(2000));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::SendSubOk(2000, consumer))));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::RouteSetRemote(consumer, 2000))));
+        assert_eq!(relay.pop_output(), None);
+
+        //migrate to current next or to a consumer should be ignored
+        relay.migrate(200, remote);
+        relay.migrate(200, consumer);
+        assert_eq!(relay.pop_output(), None);
+
+        let remote2 = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2002").expect("Should parse pair");
+        relay.migrate(200, remote2);
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::SendSub(1000, Some(remote2)))));
+        assert_eq!(relay.pop_output(), None);
+        assert_eq!(relay.next(), Some(remote));
+
+        relay.on_remote(300, remote2, RelayControl::SubOK(1000));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::SendUnsub(1000, remote))));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::SendRouteChanged)));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::RouteSetSource(remote2))));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::RouteChanged(FeatureControlActor::Controller(()))));
+        assert_eq!(relay.pop_output(), None);
+        assert_eq!(relay.next(), Some(remote2));
+
+        //late SubOK from the older remote inside sticky session is stale
+        relay.on_remote(400, remote, RelayControl::SubOK(1000));
+        assert_eq!(relay.pop_output(), Some(GenericRelayOutput::ToWorker(RelayWorkerControl::SendUnsub(1000, remote))));
+        assert_eq!(relay.pop_output(), None);
+        assert_eq!(relay.next(), Some(remote2));
+    }
 }
@@ -0,0 +1,150 @@
+//! Retained history of channels which are published by this node, and replay requests of local subscribers.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::{base::FeatureControlActor, features::pubsub::msg::ChannelId};
+
+/// Max number of retained messages per channel
+pub const RETAINED_MAX_MSGS: usize = 64;
+/// Max total size of retained messages per channel, older messages are dropped first
+pub const RETAINED_MAX_BYTES: usize = 256 * 1024;
+/// Replay data which arrives after this timeout is dropped
+pub const REPLAY_TIMEOUT_MS: u64 = 10_000;
+
+#[derive(Default)]
+pub struct RetainedHistory {
+    msgs: VecDeque<Vec<u8>>,
+    bytes: usize,
+}
+
+impl RetainedHistory {
+    pub fn push(&mut self, data: Vec<u8>) {
+        if data.len() > RETAINED_MAX_BYTES {
+            log::warn!("[RetainedHistory] message size {} is larger than history limit, skip retaining", data.len());
+            return;
+        }
+        self.bytes += data.len();
+        self.msgs.push_back(data);
+        while self.msgs.len() > RETAINED_MAX_MSGS || self.bytes > RETAINED_MAX_BYTES {
+            let removed = self.msgs.pop_front().expect("Should have message");
+            self.bytes -= removed.len();
+        }
+    }
+
+    /// Last n messages from oldest to newest
+    pub fn last(&self, n: usize) -> impl Iterator<Item = &Vec<u8>> {
+        self.msgs.iter().skip(self.msgs.len().saturating_sub(n))
+    }
+}
+
+struct ReplayWait<UserData> {
+    channel: ChannelId,
+    actor: FeatureControlActor<UserData>,
+    started_at: u64,
+}
+
+/// Local subscribers which want replay, and their requests which are waiting for data from remote sources
+pub struct ReplayRequests<UserData> {
+    subs: Vec<(ChannelId, FeatureControlActor<UserData>, u16)>,
+    waits: HashMap<u64, ReplayWait<UserData>>,
+    next_id: u64,
+}
+
+impl<UserData> Default for ReplayRequests<UserData> {
+    fn default() -> Self {
+        Self {
+            subs: Vec::new(),
+            waits: HashMap::new(),
+            next_id: 0,
+        }
+    }
+}
+
+impl<UserData: Eq + Copy> ReplayRequests<UserData> {
+    pub fn on_sub(&mut self, channel: ChannelId, actor: FeatureControlActor<UserData>, count: u16) {
+        if let Some(sub) = self.subs.iter_mut().find(|(c, a, _)| *c == channel && *a == actor) {
+            sub.2 = count;
+        } else {
+            self.subs.push((channel, actor, count));
+        }
+    }
+
+    pub fn on_unsub(&mut self, channel: ChannelId, actor: FeatureControlActor<UserData>) {
+        self.subs.retain(|(c, a, _)| !(*c == channel && *a == actor));
+        self.waits.retain(|_, w| !(w.channel == channel && w.actor == actor));
+    }
+
+    /// Number of messages which the actor want to replay from each source of the channel
+    pub fn wanted(&self, channel: ChannelId, actor: FeatureControlActor<UserData>) -> Option<u16> {
+        self.subs.iter().find(|(c, a, _)| *c == channel && *a == actor).map(|(_, _, count)| *count)
+    }
+
+    /// Create a request id for replaying from a remote source
+    pub fn create(&mut self, now: u64, channel: ChannelId, actor: FeatureControlActor<UserData>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.waits.insert(id, ReplayWait { channel, actor, started_at: now });
+        id
+    }
+
+    /// Actor which is waiting for the replay data, None if the request is unknown or timed out
+    pub fn actor_for(&self, channel: ChannelId, id: u64) -> Option<FeatureControlActor<UserData>> {
+        self.waits.get(&id).filter(|w| w.channel == channel).map(|w| w.actor)
+    }
+
+    pub fn on_tick(&mut self, now: u64) {
+        self.waits.retain(|_, w| now < w.started_at + REPLAY_TIMEOUT_MS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{base::FeatureControlActor, features::pubsub::msg::ChannelId};
+
+    use super::{ReplayRequests, RetainedHistory, REPLAY_TIMEOUT_MS, RETAINED_MAX_BYTES, RETAINED_MAX_MSGS};
+
+    #[test]
+    fn history_keep_last_msgs() {
+        let mut history = RetainedHistory::default();
+        for i in 0..(RETAINED_MAX_MSGS + 10) {
+            history.push(vec![i as u8]);
+        }
+        assert_eq!(history.last(usize::MAX).count(), RETAINED_MAX_MSGS);
+        assert_eq!(
+            history.last(2).cloned().collect::<Vec<_>>(),
+            vec![vec![(RETAINED_MAX_MSGS + 8) as u8], vec![(RETAINED_MAX_MSGS + 9) as u8]]
+        );
+    }
+
+    #[test]
+    fn history_limit_bytes() {
+        let mut history = RetainedHistory::default();
+        history.push(vec![1; RETAINED_MAX_BYTES / 2]);
+        history.push(vec![2; RETAINED_MAX_BYTES / 2]);
+        history.push(vec![3; 10]);
+        assert_eq!(history.last(10).map(|m| m[0]).collect::<Vec<_>>(), vec![2, 3]);
+
+        // too large message is not retained
+        history.push(vec![4; RETAINED_MAX_BYTES + 1]);
+        assert_eq!(history.last(10).map(|m| m[0]).collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[test]
+    fn replay_requests_timeout() {
+        let channel = ChannelId(1);
+        let actor = FeatureControlActor::Controller(());
+        let mut requests = ReplayRequests::default();
+        requests.on_sub(channel, actor, 5);
+        assert_eq!(requests.wanted(channel, actor), Some(5));
+
+        let id = requests.create(0, channel, actor);
+        assert_eq!(requests.actor_for(channel, id), Some(actor));
+        assert_eq!(requests.actor_for(ChannelId(2), id), None);
+
+        requests.on_tick(REPLAY_TIMEOUT_MS);
+        assert_eq!(requests.actor_for(channel, id), None);
+
+        requests.on_unsub(channel, actor);
+        assert_eq!(requests.wanted(channel, actor), None);
+    }
+}
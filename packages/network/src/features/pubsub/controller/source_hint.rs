@@ -29,12 +29,14 @@ use derivative::Derivative;
 use crate::{base::FeatureControlActor, data_plane::NetPair, features::pubsub::msg::SourceHint};
 
 const TIMEOUT_MS: u64 = 10_000;
+pub const DISCOVERY_TIMEOUT_MS: u64 = 5_000;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum LocalCmd {
     Register,
     Unregister,
-    Subscribe,
+    /// Subscribe with discovery timeout in milliseconds, after that NoSourceFound is notified if no source is found
+    Subscribe(u64),
     Unsubscribe,
 }
 
@@ -43,6 +45,8 @@ pub enum Output<UserData> {
     SendRemote(Option<NetPair>, SourceHint),
     SubscribeSource(Vec<FeatureControlActor<UserData>>, NodeId),
     UnsubscribeSource(Vec<FeatureControlActor<UserData>>, NodeId),
+    NoSourceFound(FeatureControlActor<UserData>),
+    SourceFound(Vec<FeatureControlActor<UserData>>, NodeId),
 }
 
 #[derive(Derivative)]
@@ -54,6 +58,10 @@ pub struct SourceHintLogic<UserData> {
     remote_subscribers: BTreeMap<NetPair, u64>,
     local_sources: Vec<FeatureControlActor<UserData>>,
     local_subscribers: Vec<FeatureControlActor<UserData>>,
+    /// local subscribers which are waiting for source discovery, with deadline
+    discovering: Vec<(FeatureControlActor<UserData>, u64)>,
+    /// local subscribers which are already notified NoSourceFound
+    not_found: Vec<FeatureControlActor<UserData>>,
     next_hop: Option<NetPair>,
     queue: VecDeque<Output<UserData>>,
 }
@@ -76,7 +84,25 @@ impl<UserData: Eq + Copy + Debug> SourceHintLogic<UserData> {
         }
     }
 
+    /// called when a source is added, for notifying subscribers which are already received NoSourceFound
+    fn on_source_found(&mut self, source: NodeId) {
+        self.discovering.clear();
+        if !self.not_found.is_empty() {
+            log::info!("[SourceHint] Notify source({source}) found to local {:?} actors", self.not_found);
+            self.queue.push_back(Output::SourceFound(std::mem::take(&mut self.not_found), source));
+        }
+    }
+
     pub fn on_tick(&mut self, now_ms: u64) {
+        if self.local_sources.is_empty() && self.remote_sources.is_empty() {
+            while let Some(index) = self.discovering.iter().position(|(_, deadline)| now_ms >= *deadline) {
+                let (actor, _) = self.discovering.swap_remove(index);
+                log::warn!("[SourceHint] Notify no source found to local {:?} actor because discovery timeout", actor);
+                self.not_found.push(actor);
+                self.queue.push_back(Output::NoSourceFound(actor));
+            }
+        }
+
         let mut timeout_subscribes = vec![];
         for (remote, last_tick) in &self.remote_subscribers {
             if now_ms - last_tick >= TIMEOUT_MS {
@@ -121,7 +147,7 @@ impl<UserData: Eq + Copy + Debug> SourceHintLogic<UserData> {
         }
     }
 
-    pub fn on_local(&mut self, _now_ms: u64, actor: FeatureControlActor<UserData>, cmd: LocalCmd) {
+    pub fn on_local(&mut self, now_ms: u64, actor: FeatureControlActor<UserData>, cmd: LocalCmd) {
         match cmd {
             LocalCmd::Register => {
                 if !self.local_sources.contains(&actor) {
@@ -141,6 +167,7 @@ impl<UserData: Eq + Copy + Debug> SourceHintLogic<UserData> {
                             log::info!("[SourceHint] Notify new source({}) to local {:?} actors", self.node_id, self.local_subscribers);
                             self.queue.push_back(Output::SubscribeSource(self.local_subscribers.clone(), self.node_id));
                         }
+                        self.on_source_found(self.node_id);
                     }
                 }
             }
@@ -165,10 +192,13 @@ impl<UserData: Eq + Copy + Debug> SourceHintLogic<UserData> {
                     }
                 }
             }
-            LocalCmd::Subscribe => {
+            LocalCmd::Subscribe(discovery_timeout_ms) => {
                 if !self.local_subscribers.contains(&actor) {
                     log::info!("[SourceHint] Subscribe new local subscriber: {:?}", actor);
                     self.local_subscribers.push(actor);
+                    if self.local_sources.is_empty() && self.remote_sources.is_empty() {
+                        self.discovering.push((actor, now_ms.saturating_add(discovery_timeout_ms)));
+                    }
                     if self.local_subscribers.len() == 1 && self.remote_subscribers.is_empty() {
                         log::info!("[SourceHint] Send Subscribe({}) to root node", self.session_id);
                         self.queue.push_back(Output::SendRemote(None, SourceHint::Subscribe(self.session_id)));
@@ -187,6 +217,8 @@ impl<UserData: Eq + Copy + Debug> SourceHintLogic<UserData> {
                 if let Some(index) = self.local_subscribers.iter().position(|x| x == &actor) {
                     log::info!("[SourceHint] Unsubscribe local subscriber: {:?}", actor);
                     self.local_subscribers.swap_remove(index);
+                    self.discovering.retain(|(a, _)| *a != actor);
+                    self.not_found.retain(|a| *a != actor);
                     // if all subscribers are removed, we need to notify next hop to remove this node from subscriber list
                     if self.local_subscribers.is_empty() && self.remote_subscribers.is_empty() {
                         log::info!("[SourceHint] Send Unsubscribe({}) to next node", self.session_id);
@@ -243,6 +275,7 @@ impl<UserData: Eq + Copy + Debug> SourceHintLogic<UserData> {
                         log::info!("[SourceHint] Notify new source({}) to local {:?} actors", source, self.local_subscribers);
                         self.queue.push_back(Output::SubscribeSource(self.local_subscribers.clone(), source));
                     }
+                    self.on_source_found(source);
                 }
             }
             SourceHint::Unregister { source, to_root } => {
@@ -341,6 +374,7 @@ impl<UserData: Eq + Copy + Debug> SourceHintLogic<UserData> {
                             log::info!("[SourceHint] Notify new source({source}) to local {:?} actors", self.local_subscribers);
                             self.queue.push_back(Output::SubscribeSource(self.local_subscribers.clone(), source));
                         }
+                        self.on_source_found(source);
                     }
                 }
             }
@@ -358,7 +392,11 @@ impl<UserData: Eq + Copy + Debug> SourceHintLogic<UserData> {
 
 #[cfg(test)]
 mod tests {
-    use crate::{base::FeatureControlActor, data_plane::NetPair, features::pubsub::controller::source_hint::TIMEOUT_MS};
+    use crate::{
+        base::FeatureControlActor,
+        data_plane::NetPair,
+        features::pubsub::controller::source_hint::{DISCOVERY_TIMEOUT_MS, TIMEOUT_MS},
+    };
 
     use super::{LocalCmd, Output, SourceHint, SourceHintLogic};
 
@@ -369,15 +407,15 @@ mod tests {
         let mut sh = SourceHintLogic::new(node_id, session_id);
 
         //subscribe should send a subscribe message
-        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe);
+        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe(DISCOVERY_TIMEOUT_MS));
 
         assert_eq!(sh.pop_output(), Some(Output::SendRemote(None, SourceHint::Subscribe(session_id))));
         assert_eq!(sh.pop_output(), None);
 
-        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe);
+        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe(DISCOVERY_TIMEOUT_MS));
         assert_eq!(sh.pop_output(), None);
 
-        sh.on_local(0, FeatureControlActor::Worker(1, ()), LocalCmd::Subscribe);
+        sh.on_local(0, FeatureControlActor::Worker(1, ()), LocalCmd::Subscribe(DISCOVERY_TIMEOUT_MS));
         assert_eq!(sh.pop_output(), None);
 
         //fake a local source should send local source event
@@ -398,7 +436,7 @@ mod tests {
         let mut sh = SourceHintLogic::new(node_id, session_id);
 
         //subscribe should send a subscribe message
-        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe);
+        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe(DISCOVERY_TIMEOUT_MS));
         assert_eq!(sh.pop_output(), Some(Output::SendRemote(None, SourceHint::Subscribe(session_id))));
         assert_eq!(sh.pop_output(), None);
 
@@ -427,7 +465,7 @@ mod tests {
         assert_eq!(sh.pop_output(), None);
 
         //subscribe should send a subscribe message and local source event
-        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe);
+        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe(DISCOVERY_TIMEOUT_MS));
 
         assert_eq!(sh.pop_output(), Some(Output::SendRemote(None, SourceHint::Subscribe(session_id))));
         assert_eq!(sh.pop_output(), Some(Output::SubscribeSource(vec![FeatureControlActor::Controller(())], node_id)));
@@ -488,7 +526,7 @@ mod tests {
         assert_eq!(sh.pop_output(), None);
 
         //subscribe should send a subscribe message and local source event
-        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe);
+        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe(DISCOVERY_TIMEOUT_MS));
 
         assert_eq!(sh.pop_output(), Some(Output::SendRemote(None, SourceHint::Subscribe(session_id))));
         assert_eq!(sh.pop_output(), Some(Output::SubscribeSource(vec![FeatureControlActor::Controller(())], remote_node_id)));
@@ -647,6 +685,57 @@ mod tests {
         assert_eq!(sh.pop_output(), None);
     }
 
+    #[test]
+    fn discovery_timeout_should_notify_no_source() {
+        let node_id = 1;
+        let session_id = 1234;
+        let mut sh = SourceHintLogic::new(node_id, session_id);
+
+        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe(1000));
+        assert_eq!(sh.pop_output(), Some(Output::SendRemote(None, SourceHint::Subscribe(session_id))));
+        assert_eq!(sh.pop_output(), None);
+
+        sh.on_tick(500);
+        assert_eq!(sh.pop_output(), Some(Output::SendRemote(None, SourceHint::Subscribe(session_id))));
+        assert_eq!(sh.pop_output(), None);
+
+        sh.on_tick(1000);
+        assert_eq!(sh.pop_output(), Some(Output::NoSourceFound(FeatureControlActor::Controller(()))));
+        assert_eq!(sh.pop_output(), Some(Output::SendRemote(None, SourceHint::Subscribe(session_id))));
+        assert_eq!(sh.pop_output(), None);
+
+        // should notify only once
+        sh.on_tick(2000);
+        assert_eq!(sh.pop_output(), Some(Output::SendRemote(None, SourceHint::Subscribe(session_id))));
+        assert_eq!(sh.pop_output(), None);
+
+        // source appeared later
+        let remote = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        sh.on_remote(2100, remote, SourceHint::Sources(vec![2]));
+        assert_eq!(sh.pop_output(), Some(Output::SubscribeSource(vec![FeatureControlActor::Controller(())], 2)));
+        assert_eq!(sh.pop_output(), Some(Output::SourceFound(vec![FeatureControlActor::Controller(())], 2)));
+        assert_eq!(sh.pop_output(), None);
+    }
+
+    #[test]
+    fn discovery_should_not_timeout_with_source() {
+        let node_id = 1;
+        let session_id = 1234;
+        let mut sh = SourceHintLogic::new(node_id, session_id);
+
+        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe(1000));
+        assert_eq!(sh.pop_output(), Some(Output::SendRemote(None, SourceHint::Subscribe(session_id))));
+
+        let remote = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        sh.on_remote(100, remote, SourceHint::Sources(vec![2]));
+        assert_eq!(sh.pop_output(), Some(Output::SubscribeSource(vec![FeatureControlActor::Controller(())], 2)));
+        assert_eq!(sh.pop_output(), None);
+
+        sh.on_tick(1000);
+        assert_eq!(sh.pop_output(), Some(Output::SendRemote(None, SourceHint::Subscribe(session_id))));
+        assert_eq!(sh.pop_output(), None);
+    }
+
     #[test]
     fn subscribe_resend_after_tick() {
         let node_id = 1;
@@ -654,7 +743,7 @@ mod tests {
         let mut sh = SourceHintLogic::new(node_id, session_id);
 
         //subscribe should send a subscribe message
-        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe);
+        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe(DISCOVERY_TIMEOUT_MS));
 
         assert_eq!(sh.pop_output(), Some(Output::SendRemote(None, SourceHint::Subscribe(session_id))));
         assert_eq!(sh.pop_output(), None);
@@ -671,7 +760,7 @@ mod tests {
         let mut sh = SourceHintLogic::new(node_id, session_id);
 
         //subscribe should send a subscribe message and local source event
-        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe);
+        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe(DISCOVERY_TIMEOUT_MS));
 
         assert_eq!(sh.pop_output(), Some(Output::SendRemote(None, SourceHint::Subscribe(session_id))));
         assert_eq!(sh.pop_output(), None);
@@ -721,7 +810,7 @@ mod tests {
         let mut sh = SourceHintLogic::new(node_id, session_id);
 
         //subscribe should send a subscribe message and local source event
-        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe);
+        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe(DISCOVERY_TIMEOUT_MS));
 
         assert_eq!(sh.pop_output(), Some(Output::SendRemote(None, SourceHint::Subscribe(session_id))));
         assert_eq!(sh.pop_output(), None);
@@ -788,7 +877,7 @@ mod tests {
         let mut sh = SourceHintLogic::new(node_id, session_id);
 
         //subscribe should send a subscribe message and local source event
-        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe);
+        sh.on_local(0, FeatureControlActor::Controller(()), LocalCmd::Subscribe(DISCOVERY_TIMEOUT_MS));
 
         assert_eq!(sh.pop_output(), Some(Output::SendRemote(None, SourceHint::Subscribe(session_id))));
         assert_eq!(sh.pop_output(), None);
@@ -0,0 +1,311 @@
+//! Forward error correction for data of pubsub channels.
+//!
+//! Data published by the source node is split in groups of `group_size` packets, and each group is followed by one parity
+//! packet which is the xor of its packets. Relays forward fec packets as-is, and nodes with local subscribers recover a
+//! single lost packet per group from the other packets and the parity. Packets are delivered as soon as they arrive,
+//! so a recovered packet can be delivered after later packets of the same group.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+pub const MIN_GROUP_SIZE: u8 = 2;
+pub const MAX_GROUP_SIZE: u8 = 32;
+/// Number of recent groups which are kept for recovering, older groups are dropped
+const DECODER_WINDOW: u32 = 4;
+
+/// Fec config of a channel, which is set by the publisher node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FecConfig {
+    /// Number of data packets protected by one parity packet, the redundancy is 1 / group_size
+    pub group_size: u8,
+}
+
+impl FecConfig {
+    pub fn xor(group_size: u8) -> Self {
+        Self {
+            group_size: group_size.clamp(MIN_GROUP_SIZE, MAX_GROUP_SIZE),
+        }
+    }
+}
+
+/// Position of a packet in a fec group, parity packet has index equal to size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FecHeader {
+    pub group: u32,
+    pub index: u8,
+    pub size: u8,
+}
+
+impl FecHeader {
+    fn is_valid(&self) -> bool {
+        (MIN_GROUP_SIZE..=MAX_GROUP_SIZE).contains(&self.size) && self.index <= self.size
+    }
+}
+
+/// Parity packet of a group, which contains xor of data lengths and xor of data padded with zero
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FecParity {
+    pub header: FecHeader,
+    pub len_xor: u16,
+    pub data: Vec<u8>,
+}
+
+fn xor_into(dest: &mut Vec<u8>, data: &[u8]) {
+    if dest.len() < data.len() {
+        dest.resize(data.len(), 0);
+    }
+    for (d, s) in dest.iter_mut().zip(data) {
+        *d ^= *s;
+    }
+}
+
+pub struct FecEncoder {
+    config: FecConfig,
+    group: u32,
+    index: u8,
+    len_xor: u16,
+    parity: Vec<u8>,
+}
+
+impl FecEncoder {
+    pub fn new(config: FecConfig) -> Self {
+        Self {
+            config,
+            group: 0,
+            index: 0,
+            len_xor: 0,
+            parity: Vec::new(),
+        }
+    }
+
+    /// Return header of the data, and the parity packet if the data completed a group
+    pub fn push(&mut self, data: &[u8]) -> (FecHeader, Option<FecParity>) {
+        let size = self.config.group_size;
+        let header = FecHeader {
+            group: self.group,
+            index: self.index,
+            size,
+        };
+        self.len_xor ^= data.len() as u16;
+        xor_into(&mut self.parity, data);
+        self.index += 1;
+        if self.index < size {
+            return (header, None);
+        }
+
+        let parity = FecParity {
+            header: FecHeader { group: self.group, index: size, size },
+            len_xor: self.len_xor,
+            data: std::mem::take(&mut self.parity),
+        };
+        self.group = self.group.wrapping_add(1);
+        self.index = 0;
+        self.len_xor = 0;
+        (header, Some(parity))
+    }
+}
+
+struct FecGroup {
+    packets: Vec<Option<Vec<u8>>>,
+    parity: Option<(u16, Vec<u8>)>,
+    done: bool,
+}
+
+impl FecGroup {
+    fn new(size: u8) -> Self {
+        Self {
+            packets: vec![None; size as usize],
+            parity: None,
+            done: false,
+        }
+    }
+
+    /// Recover the single missing packet if all other packets and the parity are received
+    fn try_recover(&mut self) -> Option<Vec<u8>> {
+        if self.done {
+            return None;
+        }
+        let missing = self.packets.iter().filter(|p| p.is_none()).count();
+        if missing == 0 {
+            self.done = true;
+            return None;
+        }
+        if missing > 1 {
+            return None;
+        }
+        let (len_xor, parity) = self.parity.as_ref()?;
+        let mut len = *len_xor;
+        let mut data = parity.clone();
+        for packet in self.packets.iter().flatten() {
+            len ^= packet.len() as u16;
+            xor_into(&mut data, packet);
+        }
+        data.truncate(len as usize);
+        self.done = true;
+        Some(data)
+    }
+}
+
+#[derive(Default)]
+pub struct FecDecoder {
+    groups: BTreeMap<u32, FecGroup>,
+    recovered: u64,
+}
+
+impl FecDecoder {
+    /// Number of packets which are recovered by this decoder
+    pub fn recovered(&self) -> u64 {
+        self.recovered
+    }
+
+    /// Return the recovered packet of the group if any
+    pub fn on_data(&mut self, header: FecHeader, data: &[u8]) -> Option<Vec<u8>> {
+        if !header.is_valid() || header.index == header.size {
+            return None;
+        }
+        let group = self.get_group(header)?;
+        group.packets[header.index as usize] = Some(data.to_vec());
+        self.finish(header.group)
+    }
+
+    /// Return the recovered packet of the group if any
+    pub fn on_parity(&mut self, parity: FecParity) -> Option<Vec<u8>> {
+        if !parity.header.is_valid() || parity.header.index != parity.header.size {
+            return None;
+        }
+        let group = self.get_group(parity.header)?;
+        group.parity = Some((parity.len_xor, parity.data));
+        self.finish(parity.header.group)
+    }
+
+    fn get_group(&mut self, header: FecHeader) -> Option<&mut FecGroup> {
+        let latest = self.groups.keys().next_back().copied().unwrap_or(header.group);
+        if header.group.saturating_add(DECODER_WINDOW) <= latest {
+            log::debug!("[FecDecoder] drop packet of old group {}", header.group);
+            return None;
+        }
+        let group = self.groups.entry(header.group).or_insert_with(|| FecGroup::new(header.size));
+        if group.packets.len() != header.size as usize {
+            log::warn!("[FecDecoder] group {} size mismatch {} vs {}", header.group, group.packets.len(), header.size);
+            return None;
+        }
+        Some(group)
+    }
+
+    fn finish(&mut self, group: u32) -> Option<Vec<u8>> {
+        let recovered = self.groups.get_mut(&group)?.try_recover();
+        if recovered.is_some() {
+            self.recovered += 1;
+        }
+        let latest = self.groups.keys().next_back().copied().unwrap_or(group);
+        self.groups.retain(|g, _| g.saturating_add(DECODER_WINDOW) > latest);
+        recovered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FecConfig, FecDecoder, FecEncoder, FecHeader, FecParity};
+
+    fn encode(encoder: &mut FecEncoder, packets: &[Vec<u8>]) -> Vec<(FecHeader, Vec<u8>, Option<FecParity>)> {
+        packets
+            .iter()
+            .map(|p| {
+                let (header, parity) = encoder.push(p);
+                (header, p.clone(), parity)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn encoder_emit_parity_after_group() {
+        let mut encoder = FecEncoder::new(FecConfig::xor(2));
+        let (h1, p1) = encoder.push(&[1, 2, 3]);
+        assert_eq!(h1, FecHeader { group: 0, index: 0, size: 2 });
+        assert_eq!(p1, None);
+        let (h2, p2) = encoder.push(&[4]);
+        assert_eq!(h2, FecHeader { group: 0, index: 1, size: 2 });
+        assert_eq!(
+            p2,
+            Some(FecParity {
+                header: FecHeader { group: 0, index: 2, size: 2 },
+                len_xor: 3 ^ 1,
+                data: vec![1 ^ 4, 2, 3],
+            })
+        );
+        let (h3, _) = encoder.push(&[5]);
+        assert_eq!(h3, FecHeader { group: 1, index: 0, size: 2 });
+    }
+
+    #[test]
+    fn decoder_recover_single_lost() {
+        let packets = vec![vec![1, 2, 3, 4], vec![5, 6], vec![7, 8, 9]];
+        for lost in 0..packets.len() {
+            let mut encoder = FecEncoder::new(FecConfig::xor(3));
+            let mut decoder = FecDecoder::default();
+            let mut recovered = None;
+            for (i, (header, data, parity)) in encode(&mut encoder, &packets).into_iter().enumerate() {
+                if i != lost {
+                    assert_eq!(decoder.on_data(header, &data), None);
+                }
+                if let Some(parity) = parity {
+                    recovered = decoder.on_parity(parity);
+                }
+            }
+            assert_eq!(recovered.as_ref(), Some(&packets[lost]));
+            assert_eq!(decoder.recovered(), 1);
+        }
+    }
+
+    #[test]
+    fn decoder_recover_when_parity_arrive_first() {
+        let packets = vec![vec![1, 2], vec![3, 4, 5]];
+        let mut encoder = FecEncoder::new(FecConfig::xor(2));
+        let encoded = encode(&mut encoder, &packets);
+        let mut decoder = FecDecoder::default();
+        assert_eq!(decoder.on_parity(encoded[1].2.clone().expect("Should have parity")), None);
+        assert_eq!(decoder.on_data(encoded[1].0, &encoded[1].1), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn decoder_cannot_recover_two_lost() {
+        let packets = vec![vec![1], vec![2], vec![3]];
+        let mut encoder = FecEncoder::new(FecConfig::xor(3));
+        let encoded = encode(&mut encoder, &packets);
+        let mut decoder = FecDecoder::default();
+        assert_eq!(decoder.on_data(encoded[0].0, &encoded[0].1), None);
+        assert_eq!(decoder.on_parity(encoded[2].2.clone().expect("Should have parity")), None);
+        assert_eq!(decoder.recovered(), 0);
+    }
+
+    #[test]
+    fn decoder_nothing_to_recover_without_loss() {
+        let packets = vec![vec![1], vec![2]];
+        let mut encoder = FecEncoder::new(FecConfig::xor(2));
+        let mut decoder = FecDecoder::default();
+        for (header, data, parity) in encode(&mut encoder, &packets) {
+            assert_eq!(decoder.on_data(header, &data), None);
+            if let Some(parity) = parity {
+                assert_eq!(decoder.on_parity(parity), None);
+            }
+        }
+        assert_eq!(decoder.recovered(), 0);
+    }
+
+    #[test]
+    fn decoder_drop_old_groups() {
+        let mut encoder = FecEncoder::new(FecConfig::xor(2));
+        let mut decoder = FecDecoder::default();
+        let (old, _) = encoder.push(&[1]);
+        let (_, old_parity) = encoder.push(&[2]);
+        for i in 0..10 {
+            let (header, _) = encoder.push(&[i]);
+            decoder.on_data(header, &[i]);
+        }
+        // group 0 is out of window, so it is not recovered
+        assert_eq!(decoder.on_parity(old_parity.expect("Should have parity")), None);
+        assert_eq!(decoder.on_data(old, &[1]), None);
+        assert!(decoder.groups.len() <= 4);
+    }
+}
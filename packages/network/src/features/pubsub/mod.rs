@@ -1,17 +1,19 @@
 use atm0s_sdn_identity::NodeId;
 
 use crate::{
-    base::{FeatureControlActor, FeatureOutput, FeatureWorkerOutput},
+    base::{FeatureControlActor, FeatureError, FeatureOutput, FeatureWorkerOutput},
     data_plane::NetPair,
 };
 
 use self::msg::{RelayControl, RelayId, SourceHint};
 
 mod controller;
-mod msg;
+pub(crate) mod fec;
+pub(crate) mod msg;
 mod worker;
 
-pub use controller::PubSubFeature;
+pub use controller::{PubSubFeature, REBALANCE_INTERVAL_MS, REBALANCE_MIN_IMPROVEMENT_MS, RELAY_STICKY_MS, WORKER_FANOUT_MIN_LOCALS};
+pub use fec::FecConfig;
 pub use msg::{ChannelId, Feedback};
 pub use worker::PubSubFeatureWorker;
 
@@ -20,14 +22,40 @@ pub const FEATURE_NAME: &str = "pubsub";
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ChannelControl {
+    /// Subscribe with source auto discovery, NoSourceFound is notified if no source is found after default discovery timeout
     SubAuto,
+    /// Same as SubAuto but with custom discovery timeout in milliseconds
+    SubAutoTimeout(u64),
     FeedbackAuto(Feedback),
     UnsubAuto,
     SubSource(NodeId),
     UnsubSource(NodeId),
     PubStart,
     PubData(Vec<u8>),
+    /// Same as PubData, and the data is kept in the retained history of the channel for late subscribers
+    PubDataRetained(Vec<u8>),
     PubStop,
+    /// Enable or disable local loopback for data published by this node, default is enabled.
+    /// With loopback, subscribers in same node receive data directly without serialization,
+    /// otherwise data is serialized and relayed by worker like data for remote nodes
+    SetLocalLoopback(bool),
+    /// Get [`LoopbackStats`] of data published by this node
+    LoopbackStats,
+    /// Subscribe lifecycle events of all relays in this node, the channel of this control is ignored
+    SubRelayLifecycle,
+    UnsubRelayLifecycle,
+    /// Enable or disable fec for data published by this node, default is disabled.
+    /// Parity packets are generated by the publisher node and lost data is recovered by nodes with local subscribers
+    SetFec(Option<FecConfig>),
+    /// Same as SubAuto, and the last n retained messages of each found source are replayed with [`ChannelEvent::ReplayData`]
+    SubWithReplay(u16),
+    /// Subscribe all channels inside [from, to], which must be inside a single family (same high 32 bits).
+    /// Each matched channel is subscribed like SubSource and notified with [`ChannelEvent::RangeMatched`], the channel of this control is ignored
+    SubRange(ChannelId, ChannelId),
+    UnsubRange(ChannelId, ChannelId),
+    /// Set the token which is carried in Subs of this node for the channel, it is checked by the [`ChannelAuthorizer`] of the next hop.
+    /// None for subscribing without token, it is also used by this node when relaying the channel for other nodes
+    SetSubToken(Option<Vec<u8>>),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -38,11 +66,87 @@ pub enum ChannelEvent {
     RouteChanged(NodeId),
     SourceData(NodeId, Vec<u8>),
     FeedbackData(Feedback),
+    /// No source is found after discovery timeout of auto subscribe
+    NoSourceFound,
+    /// A source is found after NoSourceFound is notified
+    SourceFound(NodeId),
+    LoopbackStats(LoopbackStats),
+    /// A relay of this channel from the source is created in this node, lifecycle events are only sent to [`ChannelControl::SubRelayLifecycle`] subscribers
+    RelayCreated(NodeId),
+    /// The relay doesn't have any subscriber, it is sent again after the relay is used then idle again
+    RelayIdle(NodeId, RelayStats),
+    RelayDestroyed(NodeId, RelayStats),
+    /// A retained message of the source which is replayed for [`ChannelControl::SubWithReplay`], it can arrive after live data
+    ReplayData(NodeId, Vec<u8>),
+    /// A source of the channel is matched by a range subscription, data of the channel is delivered with this channel id
+    RangeMatched(NodeId),
+    /// A source of the channel is no longer published inside a subscribed range
+    RangeUnmatched(NodeId),
+    /// The range is empty or not inside a single family, it is sent with the start channel of the range
+    RangeRejected,
+    /// The subscription to the source is rejected by the [`ChannelAuthorizer`] of a node in the path, the actor is unsubscribed from it
+    SubRejected(NodeId),
+}
+
+/// Stats of a relay which are reported with lifecycle events
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RelayStats {
+    /// Time since the relay was created
+    pub lifetime_ms: u64,
+    pub local_subscribers: usize,
+    pub remote_subscribers: usize,
+    /// Max number of local and remote subscribers in the relay lifetime
+    pub peak_subscribers: usize,
+}
+
+/// Counters of data published by this node in a channel, which help to verify that local subscribers
+/// don't pay serialization and relay cost
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopbackStats {
+    pub enabled: bool,
+    /// Data delivered to local subscribers directly
+    pub loopback_msgs: u64,
+    pub loopback_bytes: u64,
+    /// Data serialized by worker for remote subscribers, or for local subscribers when loopback is disabled
+    pub serialized_msgs: u64,
+    pub serialized_bytes: u64,
+    /// Data replicated to local subscribers by a worker instead of the controller, which is also counted in loopback
+    pub fanout_msgs: u64,
+}
+
+impl Default for LoopbackStats {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            loopback_msgs: 0,
+            loopback_bytes: 0,
+            serialized_msgs: 0,
+            serialized_bytes: 0,
+            fanout_msgs: 0,
+        }
+    }
+}
+
+/// Authorization of remote subscribers, which is checked by each node when a neighbour subscribes a channel through it.
+/// The requester is the neighbour which sends the Sub, a relay node subscribes the source with its own id and token.
+/// Local actors are always allowed
+pub trait ChannelAuthorizer: Send + Sync {
+    fn authorize(&self, channel: ChannelId, requester: NodeId, token: Option<&[u8]>) -> bool;
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Event(pub ChannelId, pub ChannelEvent);
 
+impl Event {
+    pub fn error(&self) -> Option<FeatureError> {
+        match self.1 {
+            ChannelEvent::NoSourceFound => Some(FeatureError::Unreachable),
+            ChannelEvent::RangeRejected | ChannelEvent::SubRejected(_) => Some(FeatureError::Rejected),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RelayWorkerControl<UserData> {
     SendSub(u64, Option<NetPair>),
@@ -50,7 +154,9 @@ pub enum RelayWorkerControl<UserData> {
     SendSubOk(u64, NetPair),
     SendUnsubOk(u64, NetPair),
     SendRouteChanged,
+    SendHandover,
     SendFeedback(Feedback, NetPair),
+    SendSubRejected(u64, NetPair),
     RouteSetSource(NetPair),
     RouteDelSource(NetPair),
     RouteSetLocal(FeatureControlActor<UserData>),
@@ -67,7 +173,9 @@ impl<UserData> RelayWorkerControl<UserData> {
                 | RelayWorkerControl::SendUnsub(_, _)
                 | RelayWorkerControl::SendSubOk(_, _)
                 | RelayWorkerControl::SendUnsubOk(_, _)
+                | RelayWorkerControl::SendSubRejected(_, _)
                 | RelayWorkerControl::SendRouteChanged
+                | RelayWorkerControl::SendHandover
         )
     }
 }
@@ -77,12 +185,23 @@ pub enum ToWorker<UserData> {
     RelayControl(RelayId, RelayWorkerControl<UserData>),
     SourceHint(ChannelId, Option<NetPair>, SourceHint),
     RelayData(RelayId, Vec<u8>),
+    /// Data published by this node for a high fan-out channel, which the worker delivers to its local subscribers and relays to remotes
+    RelayFanout(RelayId, Vec<u8>),
+    SetLocalLoopback(ChannelId, bool),
+    SetFec(ChannelId, Option<FecConfig>),
+    SetSubToken(ChannelId, Option<Vec<u8>>),
 }
 
 #[derive(Debug, Clone)]
 pub enum ToController {
     RelayControl(NetPair, RelayId, RelayControl),
     SourceHint(NetPair, ChannelId, SourceHint),
+    ReplayRequest(NodeId, RelayId, u64, u16),
+    ReplayData(RelayId, u64, Vec<u8>),
+    FamilyRegister(NodeId, u32, Vec<ChannelId>),
+    FamilyUnregister(NodeId, u32, Vec<ChannelId>),
+    RangeQuery(NodeId, u32, u64, ChannelId, ChannelId),
+    RangeSources(u64, ChannelId, ChannelId, Vec<(ChannelId, NodeId)>),
 }
 
 pub type Output<UserData> = FeatureOutput<UserData, Event, ToWorker<UserData>>;
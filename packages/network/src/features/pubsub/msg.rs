@@ -4,12 +4,24 @@ use atm0s_sdn_utils::simple_pub_type;
 use sans_io_runtime::Buffer;
 use serde::{Deserialize, Serialize};
 
-use crate::base::{TransportMsg, TransportMsgHeader, TransportMsgHeaderError};
+use crate::base::{derive_id, IdKind, TransportMsg, TransportMsgHeader, TransportMsgHeaderError};
 
-use super::FEATURE_ID;
+use super::{fec::FecHeader, FEATURE_ID};
 
 simple_pub_type!(ChannelId, u64);
 
+impl ChannelId {
+    /// Channels with the same high 32 bits are in the same family, which can be subscribed by range
+    pub fn family(&self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+
+    /// Channel id which is derived from namespace and name, see [`derive_id`] for the stable hashing
+    pub fn derive(namespace: &str, name: &str) -> Self {
+        Self(derive_id(IdKind::Channel, namespace, name))
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct RelayId(pub ChannelId, pub NodeId);
 
@@ -55,7 +67,7 @@ impl std::ops::Add for Feedback {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum RelayControl {
     Sub(u64),
     Unsub(u64),
@@ -63,11 +75,18 @@ pub enum RelayControl {
     UnsubOK(u64),
     RouteChanged(u64),
     Feedback(Feedback),
+    /// Sent by a decommissioning relay to its consumers, which should find another path to the source
+    Handover(u64),
+    /// Sub with the token of the sender for the channel, which is checked by the receiver before it is handled like Sub.
+    /// The sender is the neighbour of the connection it is received from
+    SubAuth(u64, Vec<u8>),
+    /// Sent back to a consumer when its Sub is rejected by the authorizer of this node or of a node closer to the source
+    SubRejected(u64),
 }
 
 impl RelayControl {
     pub fn should_create(&self) -> bool {
-        matches!(self, RelayControl::Sub(_))
+        matches!(self, RelayControl::Sub(_) | RelayControl::SubAuth(..))
     }
 }
 
@@ -104,11 +123,27 @@ pub enum PubsubMessageError {
     DeserializeError,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum PubsubMessage {
     Control(RelayId, RelayControl),
     SourceHint(ChannelId, SourceHint),
     Data(RelayId, Vec<u8>),
+    /// Data of a channel with fec, which is protected by the parity of its group
+    FecData(RelayId, FecHeader, Vec<u8>),
+    /// Parity of a fec group with xor of data lengths
+    FecParity(RelayId, FecHeader, u16, Vec<u8>),
+    /// Request last retained messages from the source with request id and count, which is routed to the source node
+    ReplayRequest(RelayId, u64, u16),
+    /// A retained message for the request id, which is routed back to the requester node
+    ReplayData(RelayId, u64, Vec<u8>),
+    /// Channels of a family which are published by the sender node, which is routed to the family root and kept alive by resending
+    FamilyRegister(u32, Vec<ChannelId>),
+    /// Channels of a family which are no longer published by the sender node
+    FamilyUnregister(u32, Vec<ChannelId>),
+    /// Query published channels inside the range [from, to] of a family with query id, which is routed to the family root
+    RangeQuery(u32, u64, ChannelId, ChannelId),
+    /// Answer for the query id with all published channels inside [from, to], which is routed back to the requester node
+    RangeSources(u64, ChannelId, ChannelId, Vec<(ChannelId, NodeId)>),
 }
 
 impl TryFrom<&[u8]> for PubsubMessage {
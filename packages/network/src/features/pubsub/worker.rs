@@ -1,16 +1,20 @@
-use std::{collections::HashMap, fmt::Debug};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+};
 
-use atm0s_sdn_identity::ConnId;
+use atm0s_sdn_identity::{ConnId, NodeId};
 use atm0s_sdn_router::{RouteAction, RouterTable};
 use sans_io_runtime::{collections::DynamicDeque, return_if_err, return_if_none, TaskSwitcherChild};
 
 use crate::{
-    base::{Buffer, FeatureControlActor, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput, TransportMsgHeader},
+    base::{Buffer, Capabilities, FeatureControlActor, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput, IncomingRoute, TransportMsgHeader},
     data_plane::NetPair,
 };
 
 use super::{
-    msg::{PubsubMessage, RelayControl, RelayId},
+    fec::{FecDecoder, FecEncoder, FecParity},
+    msg::{ChannelId, PubsubMessage, RelayControl, RelayId},
     ChannelControl, ChannelEvent, Control, Event, RelayWorkerControl, ToController, ToWorker,
 };
 
@@ -19,6 +23,8 @@ struct WorkerRelay<UserData> {
     locals: Vec<FeatureControlActor<UserData>>,
     remotes: Vec<NetPair>,
     remotes_uuid: HashMap<NetPair, u64>,
+    /// Created when fec data is received for local subscribers
+    fec: Option<FecDecoder>,
 }
 
 impl<UserData> WorkerRelay<UserData> {
@@ -29,6 +35,12 @@ impl<UserData> WorkerRelay<UserData> {
 
 pub struct PubSubFeatureWorker<UserData> {
     relays: HashMap<RelayId, WorkerRelay<UserData>>,
+    /// Channels which data published by this node is serialized before delivering to local subscribers
+    no_loopback: HashSet<ChannelId>,
+    /// Fec encoders of channels which data is published by this node
+    fec: HashMap<ChannelId, FecEncoder>,
+    /// Tokens which are carried in Subs sent by this node
+    sub_tokens: HashMap<ChannelId, Vec<u8>>,
     queue: DynamicDeque<FeatureWorkerOutput<UserData, Control, Event, ToController>, 16>,
     shutdown: bool,
 }
@@ -37,14 +49,83 @@ impl<UserData> Default for PubSubFeatureWorker<UserData> {
     fn default() -> Self {
         Self {
             relays: HashMap::new(),
+            no_loopback: HashSet::new(),
+            fec: HashMap::new(),
+            sub_tokens: HashMap::new(),
             queue: Default::default(),
             shutdown: false,
         }
     }
 }
 
+impl<UserData: Copy> PubSubFeatureWorker<UserData> {
+    /// Deliver to local subscribers from serialized data, same as data received from network
+    fn deliver_serialized(queue: &mut DynamicDeque<FeatureWorkerOutput<UserData, Control, Event, ToController>, 16>, relay_id: RelayId, locals: &[FeatureControlActor<UserData>], buf: &Buffer) {
+        if let Ok(PubsubMessage::Data(_, data) | PubsubMessage::FecData(_, _, data)) = PubsubMessage::try_from(buf as &[u8]) {
+            for actor in locals {
+                queue.push_back(FeatureWorkerOutput::Event(*actor, Event(relay_id.0, ChannelEvent::SourceData(relay_id.1, data.clone()))));
+            }
+        }
+    }
+
+    /// Serialize data published by this node, with the parity packet if the data completed a fec group
+    fn serialize_pub(fec: &mut HashMap<ChannelId, FecEncoder>, relay_id: RelayId, data: Vec<u8>) -> (Buffer, Option<Buffer>) {
+        if let Some(encoder) = fec.get_mut(&relay_id.0) {
+            let (header, parity) = encoder.push(&data);
+            let parity = parity.map(|p| PubsubMessage::FecParity(relay_id, p.header, p.len_xor, p.data).into());
+            (PubsubMessage::FecData(relay_id, header, data).into(), parity)
+        } else {
+            (PubsubMessage::Data(relay_id, data).into(), None)
+        }
+    }
+
+    /// Channel range messages are routed to the family root, which can be this node
+    fn on_channel_range_msg(&mut self, from_node: Option<NodeId>, msg: PubsubMessage) {
+        let out = match msg {
+            PubsubMessage::FamilyRegister(family, channels) => ToController::FamilyRegister(return_if_none!(from_node), family, channels),
+            PubsubMessage::FamilyUnregister(family, channels) => ToController::FamilyUnregister(return_if_none!(from_node), family, channels),
+            PubsubMessage::RangeQuery(family, id, from, to) => ToController::RangeQuery(return_if_none!(from_node), family, id, from, to),
+            PubsubMessage::RangeSources(id, from, to, sources) => ToController::RangeSources(id, from, to, sources),
+            _ => return,
+        };
+        log::debug!("[PubSubWorker] received channel range msg {:?} from {:?}", out, from_node);
+        self.queue.push_back(FeatureWorkerOutput::ToController(out));
+    }
+
+    /// Split remotes into neighbours which negotiated fec and the ones which only understand plain data
+    fn split_fec(ctx: &FeatureWorkerContext, remotes: &[NetPair]) -> (Vec<NetPair>, Vec<NetPair>) {
+        remotes
+            .iter()
+            .partition(|remote| ctx.peer_caps.get(remote).map(|caps| caps.contains(Capabilities::PUBSUB_FEC)).unwrap_or(false))
+    }
+
+    fn broadcast_pub(&mut self, ctx: &FeatureWorkerContext, relay_id: RelayId, remotes: &[NetPair], buf: Buffer, parity: Option<Buffer>) {
+        let (fec, legacy) = if parity.is_some() || self.fec.contains_key(&relay_id.0) {
+            Self::split_fec(ctx, remotes)
+        } else {
+            (remotes.to_vec(), vec![])
+        };
+        if !legacy.is_empty() {
+            log::debug!("[PubsubWorker] send plain data for {:?} to {} remotes without fec", relay_id, legacy.len());
+            let plain = match PubsubMessage::try_from(&buf as &[u8]) {
+                Ok(PubsubMessage::FecData(_, _, data)) => PubsubMessage::Data(relay_id, data).into(),
+                _ => buf.clone(),
+            };
+            self.queue.push_back(FeatureWorkerOutput::RawBroadcast2(legacy, plain));
+        }
+        if fec.is_empty() {
+            return;
+        }
+        self.queue.push_back(FeatureWorkerOutput::RawBroadcast2(fec.clone(), buf));
+        if let Some(parity) = parity {
+            log::debug!("[PubsubWorker] send fec parity for {:?}", relay_id);
+            self.queue.push_back(FeatureWorkerOutput::RawBroadcast2(fec, parity));
+        }
+    }
+}
+
 impl<UserData: Eq + Copy + Debug> FeatureWorker<UserData, Control, Event, ToController, ToWorker<UserData>> for PubSubFeatureWorker<UserData> {
-    fn on_network_raw(&mut self, _ctx: &mut FeatureWorkerContext, _now: u64, _conn: ConnId, remote: NetPair, _header: TransportMsgHeader, buf: Buffer) {
+    fn on_network_raw(&mut self, ctx: &mut FeatureWorkerContext, _now: u64, _conn: ConnId, remote: NetPair, header: TransportMsgHeader, _route: Option<IncomingRoute>, buf: Buffer) {
         log::debug!("[PubSubWorker] on_network_raw from {}", remote);
         let msg = return_if_err!(PubsubMessage::try_from(&buf as &[u8]));
         match msg {
@@ -75,6 +156,81 @@ impl<UserData: Eq + Copy + Debug> FeatureWorker<UserData, Control, Event, ToCont
                     log::warn!("[PubsubWorker] Relay from untrusted source local {:?} != remote {}", relay.source, remote);
                 }
             }
+            PubsubMessage::FecData(relay_id, header, data) => {
+                log::debug!("[PubSubWorker] received PubsubMessage::FecData({:?}, {:?}, size {})", relay_id, header, data.len());
+                let relay = return_if_none!(self.relays.get_mut(&relay_id));
+                if relay.source != Some(remote) {
+                    log::warn!("[PubsubWorker] Relay from untrusted source local {:?} != remote {}", relay.source, remote);
+                    return;
+                }
+                if !relay.locals.is_empty() {
+                    let recovered = relay.fec.get_or_insert_with(Default::default).on_data(header, &data);
+                    for actor in &relay.locals {
+                        self.queue
+                            .push_back(FeatureWorkerOutput::Event(*actor, Event(relay_id.0, ChannelEvent::SourceData(relay_id.1, data.clone()))));
+                    }
+                    if let Some(recovered) = recovered {
+                        log::debug!(
+                            "[PubsubWorker] recovered fec data for {:?} in group {}, total {}",
+                            relay_id,
+                            header.group,
+                            relay.fec.as_ref().map(|f| f.recovered()).unwrap_or_default()
+                        );
+                        for actor in &relay.locals {
+                            self.queue
+                                .push_back(FeatureWorkerOutput::Event(*actor, Event(relay_id.0, ChannelEvent::SourceData(relay_id.1, recovered.clone()))));
+                        }
+                    }
+                }
+                if !relay.remotes.is_empty() {
+                    // relays forward fec packets as-is, lost data is only recovered at nodes with local subscribers
+                    let (fec, legacy) = Self::split_fec(ctx, &relay.remotes);
+                    if !legacy.is_empty() {
+                        self.queue.push_back(FeatureWorkerOutput::RawBroadcast2(legacy, PubsubMessage::Data(relay_id, data.clone()).into()));
+                    }
+                    if !fec.is_empty() {
+                        self.queue.push_back(FeatureWorkerOutput::RawBroadcast2(fec, PubsubMessage::FecData(relay_id, header, data).into()));
+                    }
+                }
+            }
+            PubsubMessage::FecParity(relay_id, header, len_xor, parity) => {
+                log::debug!("[PubSubWorker] received PubsubMessage::FecParity({:?}, {:?})", relay_id, header);
+                let relay = return_if_none!(self.relays.get_mut(&relay_id));
+                if relay.source != Some(remote) {
+                    log::warn!("[PubsubWorker] Relay from untrusted source local {:?} != remote {}", relay.source, remote);
+                    return;
+                }
+                let (fec, _) = Self::split_fec(ctx, &relay.remotes);
+                if !fec.is_empty() {
+                    let msg = PubsubMessage::FecParity(relay_id, header, len_xor, parity.clone());
+                    self.queue.push_back(FeatureWorkerOutput::RawBroadcast2(fec, msg.into()));
+                }
+                if !relay.locals.is_empty() {
+                    let parity = FecParity { header, len_xor, data: parity };
+                    if let Some(recovered) = relay.fec.get_or_insert_with(Default::default).on_parity(parity) {
+                        log::debug!(
+                            "[PubsubWorker] recovered fec data for {:?} in group {}, total {}",
+                            relay_id,
+                            header.group,
+                            relay.fec.as_ref().map(|f| f.recovered()).unwrap_or_default()
+                        );
+                        for actor in &relay.locals {
+                            self.queue
+                                .push_back(FeatureWorkerOutput::Event(*actor, Event(relay_id.0, ChannelEvent::SourceData(relay_id.1, recovered.clone()))));
+                        }
+                    }
+                }
+            }
+            PubsubMessage::ReplayRequest(relay_id, id, count) => {
+                let requester = return_if_none!(header.from_node);
+                log::debug!("[PubSubWorker] received PubsubMessage::ReplayRequest({:?}, {id}, {count}) from {requester}", relay_id);
+                self.queue.push_back(FeatureWorkerOutput::ToController(ToController::ReplayRequest(requester, relay_id, id, count)));
+            }
+            PubsubMessage::ReplayData(relay_id, id, data) => {
+                log::debug!("[PubSubWorker] received PubsubMessage::ReplayData({:?}, {id}, size {})", relay_id, data.len());
+                self.queue.push_back(FeatureWorkerOutput::ToController(ToController::ReplayData(relay_id, id, data)));
+            }
+            msg => self.on_channel_range_msg(header.from_node, msg),
         }
     }
 
@@ -92,7 +248,12 @@ impl<UserData: Eq + Copy + Debug> FeatureWorker<UserData, Control, Event, ToCont
                         log::warn!("[PubsubWorker] SendSub: no route for {:?}", relay_id);
                         return;
                     };
-                    let control = PubsubMessage::Control(relay_id, RelayControl::Sub(uuid));
+                    let sub_auth = ctx.peer_caps.get(&dest).map(|caps| caps.contains(Capabilities::PUBSUB_SUB_AUTH)).unwrap_or(false);
+                    let control = match self.sub_tokens.get(&relay_id.0) {
+                        Some(token) if sub_auth => RelayControl::SubAuth(uuid, token.clone()),
+                        _ => RelayControl::Sub(uuid),
+                    };
+                    let control = PubsubMessage::Control(relay_id, control);
                     self.queue.push_back(FeatureWorkerOutput::RawDirect2(dest, control.into()));
                 }
                 RelayWorkerControl::SendFeedback(fb, remote) => {
@@ -110,6 +271,11 @@ impl<UserData: Eq + Copy + Debug> FeatureWorker<UserData, Control, Event, ToCont
                     let control = PubsubMessage::Control(relay_id, RelayControl::SubOK(uuid));
                     self.queue.push_back(FeatureWorkerOutput::RawDirect2(remote, control.into()));
                 }
+                RelayWorkerControl::SendSubRejected(uuid, remote) => {
+                    log::debug!("[PubsubWorker] SendSubRejected for {:?} to {:?}", relay_id, remote);
+                    let control = PubsubMessage::Control(relay_id, RelayControl::SubRejected(uuid));
+                    self.queue.push_back(FeatureWorkerOutput::RawDirect2(remote, control.into()));
+                }
                 RelayWorkerControl::SendUnsubOk(uuid, remote) => {
                     log::debug!("[PubsubWorker] SendUnsubOk for {:?} to {:?}", relay_id, remote);
                     let control = PubsubMessage::Control(relay_id, RelayControl::UnsubOK(uuid));
@@ -123,6 +289,14 @@ impl<UserData: Eq + Copy + Debug> FeatureWorker<UserData, Control, Event, ToCont
                         self.queue.push_back(FeatureWorkerOutput::RawDirect2(*addr, control.into()));
                     }
                 }
+                RelayWorkerControl::SendHandover => {
+                    let relay = return_if_none!(self.relays.get(&relay_id));
+                    log::info!("[PubsubWorker] SendHandover for {:?} to remotes {:?}", relay_id, relay.remotes);
+                    for (addr, uuid) in relay.remotes_uuid.iter() {
+                        let control = PubsubMessage::Control(relay_id, RelayControl::Handover(*uuid));
+                        self.queue.push_back(FeatureWorkerOutput::RawDirect2(*addr, control.into()));
+                    }
+                }
                 RelayWorkerControl::RouteSetSource(source) => {
                     log::info!("[PubsubWorker] RouteSetSource for {:?} to {:?}", relay_id, source);
                     let entry: &mut WorkerRelay<UserData> = self.relays.entry(relay_id).or_insert(WorkerRelay {
@@ -130,6 +304,7 @@ impl<UserData: Eq + Copy + Debug> FeatureWorker<UserData, Control, Event, ToCont
                         locals: vec![],
                         remotes: vec![],
                         remotes_uuid: HashMap::new(),
+                        fec: None,
                     });
 
                     entry.source = Some(source);
@@ -153,6 +328,7 @@ impl<UserData: Eq + Copy + Debug> FeatureWorker<UserData, Control, Event, ToCont
                         locals: vec![],
                         remotes: vec![],
                         remotes_uuid: HashMap::new(),
+                        fec: None,
                     });
 
                     entry.locals.push(actor);
@@ -177,6 +353,7 @@ impl<UserData: Eq + Copy + Debug> FeatureWorker<UserData, Control, Event, ToCont
                         locals: vec![],
                         remotes: vec![],
                         remotes_uuid: HashMap::new(),
+                        fec: None,
                     });
 
                     entry.remotes.push(remote);
@@ -210,30 +387,93 @@ impl<UserData: Eq + Copy + Debug> FeatureWorker<UserData, Control, Event, ToCont
             }
             FeatureWorkerInput::FromController(_, ToWorker::RelayData(relay_id, data)) => {
                 let relay = return_if_none!(self.relays.get(&relay_id));
-                if relay.remotes.is_empty() {
+                let loopback = !(relay_id.1 == ctx.node_id && self.no_loopback.contains(&relay_id.0));
+                if relay.remotes.is_empty() && loopback {
                     log::warn!("RelayData: no remote for {:?}", relay_id);
                     return;
                 }
-                let control = PubsubMessage::Data(relay_id, data);
-                self.queue.push_back(FeatureWorkerOutput::RawBroadcast2(relay.remotes.clone(), control.into()));
+                let (buf, parity) = if relay_id.1 == ctx.node_id {
+                    Self::serialize_pub(&mut self.fec, relay_id, data)
+                } else {
+                    (PubsubMessage::Data(relay_id, data).into(), None)
+                };
+                if !loopback {
+                    Self::deliver_serialized(&mut self.queue, relay_id, &relay.locals, &buf);
+                }
+                if !relay.remotes.is_empty() {
+                    let remotes = relay.remotes.clone();
+                    self.broadcast_pub(ctx, relay_id, &remotes, buf, parity);
+                }
+            }
+            FeatureWorkerInput::FromController(_, ToWorker::RelayFanout(relay_id, data)) => {
+                let relay = return_if_none!(self.relays.get(&relay_id));
+                log::debug!("[PubsubWorker] RelayFanout for {:?} to {} locals, {} remotes", relay_id, relay.locals.len(), relay.remotes.len());
+                for actor in &relay.locals {
+                    self.queue
+                        .push_back(FeatureWorkerOutput::Event(*actor, Event(relay_id.0, ChannelEvent::SourceData(relay_id.1, data.clone()))));
+                }
+                if !relay.remotes.is_empty() {
+                    let remotes = relay.remotes.clone();
+                    let (buf, parity) = Self::serialize_pub(&mut self.fec, relay_id, data);
+                    self.broadcast_pub(ctx, relay_id, &remotes, buf, parity);
+                }
+            }
+            FeatureWorkerInput::FromController(_, ToWorker::SetLocalLoopback(channel, enabled)) => {
+                log::info!("[PubsubWorker] SetLocalLoopback for {} to {enabled}", channel);
+                if enabled {
+                    self.no_loopback.remove(&channel);
+                } else {
+                    self.no_loopback.insert(channel);
+                }
+            }
+            FeatureWorkerInput::FromController(_, ToWorker::SetFec(channel, config)) => {
+                log::info!("[PubsubWorker] SetFec for {} to {:?}", channel, config);
+                if let Some(config) = config {
+                    self.fec.insert(channel, FecEncoder::new(config));
+                } else {
+                    self.fec.remove(&channel);
+                }
+            }
+            FeatureWorkerInput::FromController(_, ToWorker::SetSubToken(channel, token)) => {
+                log::info!("[PubsubWorker] SetSubToken for {} with token {}", channel, token.is_some());
+                if let Some(token) = token {
+                    self.sub_tokens.insert(channel, token);
+                } else {
+                    self.sub_tokens.remove(&channel);
+                }
             }
             FeatureWorkerInput::Control(actor, control) => match control {
                 Control(channel, ChannelControl::PubData(data)) => {
                     let relay_id = RelayId(channel, ctx.node_id);
                     let relay = return_if_none!(self.relays.get(&relay_id));
 
+                    if self.no_loopback.contains(&channel) {
+                        let (buf, parity) = Self::serialize_pub(&mut self.fec, relay_id, data);
+                        Self::deliver_serialized(&mut self.queue, relay_id, &relay.locals, &buf);
+                        if !relay.remotes.is_empty() {
+                            let remotes = relay.remotes.clone();
+                            self.broadcast_pub(ctx, relay_id, &remotes, buf, parity);
+                        }
+                        return;
+                    }
+
                     for actor in &relay.locals {
                         self.queue
                             .push_back(FeatureWorkerOutput::Event(*actor, Event(channel, ChannelEvent::SourceData(ctx.node_id, data.clone()))));
                     }
 
                     if !relay.remotes.is_empty() {
-                        let control = PubsubMessage::Data(relay_id, data);
-                        self.queue.push_back(FeatureWorkerOutput::RawBroadcast2(relay.remotes.clone(), control.into()));
+                        let remotes = relay.remotes.clone();
+                        let (buf, parity) = Self::serialize_pub(&mut self.fec, relay_id, data);
+                        self.broadcast_pub(ctx, relay_id, &remotes, buf, parity);
                     }
                 }
                 _ => self.queue.push_back(FeatureWorkerOutput::ForwardControlToController(actor, control)),
             },
+            FeatureWorkerInput::Local(meta, buf) => {
+                let msg = return_if_err!(bincode::deserialize::<PubsubMessage>(&buf));
+                self.on_channel_range_msg(meta.source, msg);
+            }
             _ => {}
         }
     }
@@ -258,3 +498,117 @@ impl<UserData> TaskSwitcherChild<FeatureWorkerOutput<UserData, Control, Event, T
         self.queue.pop_front()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use atm0s_sdn_router::shadow::{MockShadowRouterHistory, ShadowRouter};
+    use sans_io_runtime::TaskSwitcherChild;
+
+    use crate::{
+        base::{Capabilities, FeatureControlActor, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput},
+        data_plane::NetPair,
+        features::pubsub::{
+            fec::FecConfig,
+            msg::{ChannelId, PubsubMessage, RelayControl, RelayId},
+            ChannelEvent, Event, RelayWorkerControl, ToWorker,
+        },
+    };
+
+    use super::PubSubFeatureWorker;
+
+    fn control(worker: &mut PubSubFeatureWorker<u32>, ctx: &mut FeatureWorkerContext, relay_id: RelayId, control: RelayWorkerControl<u32>) {
+        worker.on_input(ctx, 0, FeatureWorkerInput::FromController(true, ToWorker::RelayControl(relay_id, control)));
+    }
+
+    #[test]
+    fn fanout_to_1000_local_subscribers() {
+        let mut ctx = FeatureWorkerContext {
+            node_id: 1,
+            router: ShadowRouter::new(1, Arc::new(MockShadowRouterHistory::new())),
+            peer_caps: HashMap::new(),
+        };
+        let mut worker = PubSubFeatureWorker::<u32>::default();
+        let relay_id = RelayId(ChannelId(1000), 1);
+        let remote = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        for i in 0..1000 {
+            control(&mut worker, &mut ctx, relay_id, RelayWorkerControl::RouteSetLocal(FeatureControlActor::Controller(i)));
+        }
+        control(&mut worker, &mut ctx, relay_id, RelayWorkerControl::RouteSetRemote(remote, 1));
+
+        let data = vec![1, 2, 3, 4];
+        worker.on_input(&mut ctx, 0, FeatureWorkerInput::FromController(false, ToWorker::RelayFanout(relay_id, data.clone())));
+
+        for i in 0..1000 {
+            let expected = Event(relay_id.0, ChannelEvent::SourceData(1, data.clone()));
+            assert!(matches!(worker.pop_output(0), Some(FeatureWorkerOutput::Event(FeatureControlActor::Controller(actor), event)) if actor == i && event == expected));
+        }
+        // remotes receive the data once
+        assert!(matches!(worker.pop_output(0), Some(FeatureWorkerOutput::RawBroadcast2(remotes, _)) if remotes == vec![remote]));
+        assert!(worker.pop_output(0).is_none());
+    }
+
+    #[test]
+    fn sub_carry_token_to_capable_neighbour() {
+        let remote = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let mut ctx = FeatureWorkerContext {
+            node_id: 1,
+            router: ShadowRouter::new(1, Arc::new(MockShadowRouterHistory::new())),
+            peer_caps: HashMap::from([(remote, Capabilities::LINK_FRAMING)]),
+        };
+        let mut worker = PubSubFeatureWorker::<u32>::default();
+        let relay_id = RelayId(ChannelId(1000), 2);
+
+        let send_sub = |worker: &mut PubSubFeatureWorker<u32>, ctx: &mut FeatureWorkerContext| {
+            control(worker, ctx, relay_id, RelayWorkerControl::SendSub(100, Some(remote)));
+            match worker.pop_output(0) {
+                Some(FeatureWorkerOutput::RawDirect2(pair, buf)) if pair == remote => PubsubMessage::try_from(&buf as &[u8]).ok(),
+                _ => None,
+            }
+        };
+        assert_eq!(send_sub(&mut worker, &mut ctx), Some(PubsubMessage::Control(relay_id, RelayControl::Sub(100))));
+
+        // neighbour without SubAuth support only receives plain Sub
+        worker.on_input(&mut ctx, 0, FeatureWorkerInput::FromController(true, ToWorker::SetSubToken(relay_id.0, Some(vec![1, 2]))));
+        assert_eq!(send_sub(&mut worker, &mut ctx), Some(PubsubMessage::Control(relay_id, RelayControl::Sub(100))));
+
+        ctx.peer_caps.insert(remote, Capabilities::PUBSUB_SUB_AUTH);
+        assert_eq!(send_sub(&mut worker, &mut ctx), Some(PubsubMessage::Control(relay_id, RelayControl::SubAuth(100, vec![1, 2]))));
+    }
+
+    #[test]
+    fn fec_only_sent_to_capable_neighbours() {
+        let capable = NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair");
+        let legacy = NetPair::new_str("1.1.1.1:1000", "3.3.3.3:3000").expect("Should parse pair");
+        let mut ctx = FeatureWorkerContext {
+            node_id: 1,
+            router: ShadowRouter::new(1, Arc::new(MockShadowRouterHistory::new())),
+            peer_caps: HashMap::from([(capable, Capabilities::SUPPORTED), (legacy, Capabilities::LINK_FRAMING)]),
+        };
+        let mut worker = PubSubFeatureWorker::<u32>::default();
+        let relay_id = RelayId(ChannelId(1000), 1);
+        worker.on_input(&mut ctx, 0, FeatureWorkerInput::FromController(true, ToWorker::SetFec(relay_id.0, Some(FecConfig::xor(2)))));
+        control(&mut worker, &mut ctx, relay_id, RelayWorkerControl::RouteSetRemote(capable, 1));
+        control(&mut worker, &mut ctx, relay_id, RelayWorkerControl::RouteSetRemote(legacy, 2));
+
+        let mut sent = vec![];
+        for data in [vec![1], vec![2]] {
+            worker.on_input(&mut ctx, 0, FeatureWorkerInput::FromController(false, ToWorker::RelayFanout(relay_id, data)));
+            while let Some(out) = worker.pop_output(0) {
+                if let FeatureWorkerOutput::RawBroadcast2(remotes, buf) = out {
+                    sent.push((remotes, PubsubMessage::try_from(&buf as &[u8]).ok()));
+                }
+            }
+        }
+
+        assert_eq!(sent.len(), 5);
+        for (remotes, msg) in sent {
+            match msg {
+                Some(PubsubMessage::Data(..)) => assert_eq!(remotes, vec![legacy]),
+                Some(PubsubMessage::FecData(..) | PubsubMessage::FecParity(..)) => assert_eq!(remotes, vec![capable]),
+                msg => panic!("Unexpected msg {msg:?}"),
+            }
+        }
+    }
+}
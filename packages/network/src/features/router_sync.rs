@@ -1,32 +1,95 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 
 use atm0s_sdn_identity::{ConnId, NodeId};
 use atm0s_sdn_router::{
-    core::{DestDelta, Metric, RegistryDelta, RegistryDestDelta, Router, RouterDelta, RouterDump, RouterSync, TableDelta},
+    core::{DestDelta, Metric, RegistryDelta, RegistryDestDelta, RegistrySync, Router, RouterDelta, RouterDump, RouterSync, RoutingPolicy, TableDelta, TableSync},
     shadow::ShadowRouterDelta,
+    RouteRule, ServiceBroadcastLevel, ServicePlacement,
 };
+use atm0s_sdn_utils::log_sampled;
 use derivative::Derivative;
 use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    base::{ConnectionEvent, Feature, FeatureContext, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput, NetOutgoingMeta},
+    base::{
+        Capabilities, ConnectionEvent, Feature, FeatureContext, FeatureControlActor, FeatureError, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerContext,
+        FeatureWorkerInput, FeatureWorkerOutput, MsgPriority, NetOutgoingMeta, Ttl,
+    },
     data_plane::NetPair,
 };
 
+use self::dump::DumpTracker;
+pub use self::dump::{DumpDest, DumpPath, DumpService, VersionedRouterDump, ROUTER_DUMP_SCHEMA};
+
+mod dump;
+
 pub const FEATURE_ID: u8 = 2;
 pub const FEATURE_NAME: &str = "router_sync";
 
 const INIT_RTT_MS: u16 = 1000;
 const INIT_BW: u32 = 100_000_000;
+/// Snapshots which arrive after this timeout are not included in the network dump
+pub const NETWORK_DUMP_TIMEOUT_MS: u64 = 2000;
+/// Routing table which is unchanged in this number of ticks is considered as converged
+pub const ROUTER_CONVERGED_TICKS: u32 = 3;
+/// Resync which doesn't receive a sync from the neighbour in this timeout is reported as failed
+pub const RESYNC_TIMEOUT_MS: u64 = 2000;
+/// Syncs which are restored from a checkpoint are dropped if their neighbours don't connect again in this timeout
+pub const RESTORED_SYNC_TIMEOUT_MS: u64 = 10_000;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Control {
     DumpRouter,
+    /// Dump routing table entries which changed after the version, with the stable schema of [`VersionedRouterDump`].
+    /// Version 0 is a full dump, it is answered with [`Event::DumpRouterDiff`]
+    DumpRouterDiff(u64),
+    /// Request router snapshots of all nodes which run the service, tagged with a token.
+    /// Snapshots are collected in [`NETWORK_DUMP_TIMEOUT_MS`] then emitted as [`Event::DumpNetwork`].
+    /// token, service
+    DumpNetwork(u64, u8),
+    SetServicePlacement(u8, ServicePlacement),
+    /// Report load of local service instance, 0 is idle and 255 is fully loaded.
+    /// It is piggybacked on router sync to neighbours which negotiated `service_load` and used for weighted anycast
+    SetServiceLoad(u8, u8),
+    /// Force messages to the destination node over the pinned path instead of the best path of the router.
+    /// When the pinned path is lost, the router path is used until the pinned path comes back, state changes
+    /// are reported with [`Event::PinState`]
+    PinRoute(NodeId, RoutePin),
+    UnpinRoute(NodeId),
+    /// Discard all routes which are learned from the neighbour and request a full sync from it, for recovering from a
+    /// suspected desync without restarting the node. Completion is reported with [`Event::Resynced`]
+    Resync(NodeId),
+}
+
+/// Path which is forced for a destination, only the first hop is pinned: the next nodes route with their own tables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoutePin {
+    /// Send over the direct connection to the neighbour
+    NextHop(NodeId),
+    /// Send toward the relay node, over the direct connection if connected or the best path which doesn't go over the destination
+    Relay(NodeId),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Event {
     DumpRouter(Box<RouterDump>),
+    DumpRouterDiff(Box<VersionedRouterDump>),
+    /// token, snapshots of local node and replied nodes, sorted by node id
+    DumpNetwork(u64, Vec<RouterDump>),
+    /// dest, true if the pinned path is used, false if it fell back to the router
+    PinState(NodeId, bool),
+    /// neighbour, (table hash before, table hash after) or None if it isn't connected or doesn't answer in [`RESYNC_TIMEOUT_MS`]
+    Resynced(NodeId, Option<(u64, u64)>),
+}
+
+impl Event {
+    pub fn error(&self) -> Option<FeatureError> {
+        match self {
+            Self::Resynced(_, None) => Some(FeatureError::Timeout),
+            _ => None,
+        }
+    }
 }
 
 pub type ToWorker = ShadowRouterDelta<NetPair>;
@@ -35,41 +98,327 @@ pub type ToController = ();
 pub type Output<UserData> = FeatureOutput<UserData, Event, ToWorker>;
 pub type WorkerOutput<UserData> = FeatureWorkerOutput<UserData, Control, Event, ToController>;
 
+/// Messages of network dump are routed with source, while router syncs are sent directly without source
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum DumpMessage {
+    Request(u64),
+    Snapshot(u64, Box<RouterDump>),
+    /// Ask the neighbour to send its full sync now
+    ResyncRequest,
+}
+
+struct NetworkDump<UserData> {
+    actor: FeatureControlActor<UserData>,
+    started_at: u64,
+    snapshots: BTreeMap<NodeId, RouterDump>,
+}
+
+struct PendingResync<UserData> {
+    actor: FeatureControlActor<UserData>,
+    started_at: u64,
+    before: u64,
+}
+
+/// Last sync and metric of each neighbour connection, which are replicated to a standby controller.
+/// Applying them again rebuilds the routing table without waiting for neighbours to sync
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RouterSyncSnapshot(Vec<(ConnId, Metric, RouterSync)>);
+
+struct PinnedRoute<UserData> {
+    actor: FeatureControlActor<UserData>,
+    pin: RoutePin,
+    active: Option<NetPair>,
+}
+
 pub struct RouterSyncFeature<UserData> {
-    router: Router,
+    /// Boxed because tables are big arrays, which are copied with each move of the feature manager
+    router: Box<Router>,
     conns: HashMap<ConnId, (NodeId, NetPair, Metric)>,
+    /// Local capabilities, syncs are extended with the ones which are agreed with each neighbour
+    caps: Capabilities,
+    conn_caps: HashMap<ConnId, Capabilities>,
+    /// Last received sync of each connection, only kept for snapshots
+    syncs: HashMap<ConnId, RouterSync>,
+    /// Syncs of a checkpoint which are waiting for their neighbours to connect again, and the time they are dropped
+    restored: (HashMap<NodeId, RouterSync>, u64),
     queue: VecDeque<Output<UserData>>,
     services: Vec<u8>,
+    placements: Vec<(u8, ServicePlacement)>,
+    decommission: bool,
+    /// Observer mode which is applied after workers are ready
+    observer: bool,
+    dumps: HashMap<u64, NetworkDump<UserData>>,
+    dump_seq: u16,
+    /// Versions of table entries for [`Control::DumpRouterDiff`]
+    dump: DumpTracker,
+    pins: HashMap<NodeId, PinnedRoute<UserData>>,
+    resyncs: HashMap<NodeId, PendingResync<UserData>>,
+    /// Table hash of the last tick and number of ticks without change
+    stable: (u64, u32),
     shutdown: bool,
 }
 
-impl<UserData> RouterSyncFeature<UserData> {
-    pub fn new(node: NodeId, services: Vec<u8>) -> Self {
-        log::info!("[RouterSync] started node {} with public services {:?}", node, services);
+impl<UserData: Copy> RouterSyncFeature<UserData> {
+    /// Observer node only advertises its local services, so it is never selected as a relay, a next hop or a dht server
+    /// Paths are scored with weights of the policy, and its advertised bandwidth is sent to neighbours
+    pub fn new(node: NodeId, services: Vec<u8>, placements: Vec<(u8, ServicePlacement)>, observer: bool, policy: RoutingPolicy) -> Self {
+        log::info!(
+            "[RouterSync] started node {} with public services {:?}, placements {:?}, observer {}, policy {:?}",
+            node,
+            services,
+            placements,
+            observer,
+            policy
+        );
 
+        let mut router = Box::new(Router::new(node));
+        router.set_policy(policy);
         Self {
-            router: Router::new(node),
+            router,
             services,
+            placements,
             conns: HashMap::new(),
+            caps: Capabilities::SUPPORTED,
+            conn_caps: HashMap::new(),
+            syncs: HashMap::new(),
+            restored: (HashMap::new(), 0),
             queue: VecDeque::new(),
+            decommission: false,
+            observer,
+            dumps: HashMap::new(),
+            dump_seq: 0,
+            dump: DumpTracker::default(),
+            pins: HashMap::new(),
+            resyncs: HashMap::new(),
+            stable: (0, 0),
             shutdown: false,
         }
     }
 
-    fn send_sync_to(router: &Router, queue: &mut VecDeque<Output<UserData>>, conn: ConnId, node: NodeId) {
-        let sync = router.create_sync(node);
+    /// Capabilities of this node, extensions of router sync are only sent to neighbours which agreed them
+    pub fn with_capabilities(mut self, caps: Capabilities) -> Self {
+        self.caps = caps;
+        self
+    }
+
+    /// Set load of this node as a relay, it will be sent to neighbours with next syncs
+    pub fn set_relay_load(&mut self, load: u8) {
+        log::debug!("[RouterSync] set relay load {}", load);
+        self.router.set_relay_load(load);
+    }
+
+    /// Number of destinations in the routing table
+    pub fn routes(&self) -> usize {
+        self.router.size()
+    }
+
+    /// Latency to the destination and relay load of each neighbour which has a path to it, sorted from the best path
+    pub fn relay_paths(&self, dest: NodeId) -> Vec<(NodeId, u16, u8)> {
+        self.router.paths(dest).iter().map(|path| (path.1.over_node(), path.1.latency, path.1.relay_load)).collect()
+    }
+
+    /// Routing table is not empty and unchanged in [`ROUTER_CONVERGED_TICKS`] ticks
+    pub fn is_converged(&self) -> bool {
+        self.router.size() > 0 && self.stable.1 >= ROUTER_CONVERGED_TICKS
+    }
+
+    pub fn snapshot(&self) -> RouterSyncSnapshot {
+        RouterSyncSnapshot(
+            self.syncs
+                .iter()
+                .filter_map(|(conn, sync)| self.conns.get(conn).map(|(_, _, metric)| (*conn, metric.clone(), sync.clone())))
+                .collect(),
+        )
+    }
+
+    /// Apply syncs of a snapshot, only for connections which are already connected
+    pub fn restore(&mut self, snapshot: RouterSyncSnapshot) {
+        for (conn, metric, sync) in snapshot.0 {
+            let Some(entry) = self.conns.get_mut(&conn) else {
+                log::warn!("[RouterSync] skip restoring routes of unknown connection {conn}");
+                continue;
+            };
+            entry.2 = metric.clone();
+            self.router.set_direct(conn, metric.clone());
+            self.router.apply_sync(conn, metric, sync.clone());
+            self.syncs.insert(conn, sync);
+        }
+        log::info!("[RouterSync] restored routing table with {} routes", self.router.size());
+        self.refresh_pins();
+    }
+
+    /// Last sync of each neighbour node for a checkpoint. Connections are established again after a restart, so syncs are keyed by node
+    pub fn checkpoint(&self) -> Vec<(NodeId, RouterSync)> {
+        let mut syncs = HashMap::new();
+        for (conn, sync) in self.syncs.iter() {
+            if let Some((node, _, _)) = self.conns.get(conn) {
+                syncs.insert(*node, sync.clone());
+            }
+        }
+        syncs.into_iter().collect()
+    }
+
+    /// Keep syncs of a checkpoint, each one is applied when its neighbour connects again, so routes over it are available
+    /// before the next sync round. Syncs of neighbours which don't connect in [`RESTORED_SYNC_TIMEOUT_MS`] are dropped
+    pub fn restore_checkpoint(&mut self, now: u64, syncs: Vec<(NodeId, RouterSync)>) {
+        log::info!("[RouterSync] restored syncs of {} neighbours from checkpoint", syncs.len());
+        self.restored = (syncs.into_iter().collect(), now + RESTORED_SYNC_TIMEOUT_MS);
+    }
+
+    /// Stop advertising routes and services over this node, then neighbours will switch to other paths.
+    /// Only the direct path to this node is still kept by neighbours
+    pub fn decommission(&mut self) {
+        if self.decommission {
+            return;
+        }
+        log::info!("[RouterSync] decommission => stop advertising routes over this node");
+        self.decommission = true;
+        for (conn, (node, _, _)) in self.conns.iter() {
+            let caps = self.conn_caps.get(conn).copied().unwrap_or_default();
+            Self::send_sync_to(&self.router, &mut self.queue, *conn, *node, caps, self.decommission);
+        }
+    }
+
+    fn send_sync_to(router: &Router, queue: &mut VecDeque<Output<UserData>>, conn: ConnId, node: NodeId, caps: Capabilities, decommission: bool) {
+        let mut sync = router.create_sync(node);
+        if decommission {
+            sync.0 = RegistrySync(vec![]);
+            sync.1 = sync.1.map(|table| table.map(|_| TableSync(vec![])));
+        }
         queue.push_back(FeatureOutput::SendDirect(
             conn,
-            NetOutgoingMeta::new(false, 1.into(), 0, true),
-            bincode::serialize(&sync).expect("").into(),
+            NetOutgoingMeta::new(false, 1.into(), 0, true).with_priority(MsgPriority::Control),
+            encode_sync(&sync, caps).into(),
         ));
     }
+
+    fn send_dump_msg(&mut self, rule: RouteRule, msg: DumpMessage) {
+        let buf = bincode::serialize(&msg).expect("Should serialize dump message");
+        self.queue.push_back(FeatureOutput::SendRoute(rule, NetOutgoingMeta::new(true, Ttl::default(), 0, true), buf.into()));
+    }
+
+    fn on_dump_msg(&mut self, from: NodeId, buf: &[u8]) {
+        match bincode::deserialize::<DumpMessage>(buf) {
+            Ok(DumpMessage::Request(token)) => {
+                log::info!("[RouterSync] reply router snapshot for dump {token} to {from}");
+                self.send_dump_msg(RouteRule::ToNode(from), DumpMessage::Snapshot(token, Box::new(self.router.dump())));
+            }
+            Ok(DumpMessage::ResyncRequest) => {
+                log::info!("[RouterSync] {from} requested resync => send full sync");
+                for (conn, (node, _, _)) in self.conns.iter().filter(|(_, (node, _, _))| *node == from) {
+                    let caps = self.conn_caps.get(conn).copied().unwrap_or_default();
+                    Self::send_sync_to(&self.router, &mut self.queue, *conn, *node, caps, self.decommission);
+                }
+            }
+            Ok(DumpMessage::Snapshot(token, snapshot)) => {
+                if let Some(dump) = self.dumps.get_mut(&token) {
+                    log::debug!("[RouterSync] got router snapshot for dump {token} from {from}");
+                    dump.snapshots.insert(from, *snapshot);
+                } else {
+                    log_sampled!(log::Level::Warn, "[RouterSync] got router snapshot for unknown or timeout dump {token} from {from}");
+                }
+            }
+            Err(_) => log::warn!("[RouterSync] Receive invalid dump message from {from}"),
+        }
+    }
+
+    /// Lowest score connection to the neighbour
+    fn direct_pair(&self, node: NodeId) -> Option<NetPair> {
+        self.conns.values().filter(|(n, _, _)| *n == node).min_by_key(|(_, _, metric)| metric.score()).map(|(_, pair, _)| *pair)
+    }
+
+    fn resolve_pin(&self, dest: NodeId, pin: RoutePin) -> Option<NetPair> {
+        match pin {
+            RoutePin::NextHop(node) => self.direct_pair(node),
+            RoutePin::Relay(relay) => match self.direct_pair(relay) {
+                Some(pair) => Some(pair),
+                None => {
+                    let (conn, _) = self.router.next(relay, &[dest])?;
+                    Some(self.conns.get(&conn)?.1)
+                }
+            },
+        }
+    }
+
+    /// Re-resolve pinned paths, then update workers with changed paths and report changed states
+    fn refresh_pins(&mut self) {
+        let resolved = self.pins.iter().map(|(dest, pinned)| (*dest, self.resolve_pin(*dest, pinned.pin))).collect::<Vec<_>>();
+        for (dest, next) in resolved {
+            let pinned = self.pins.get_mut(&dest).expect("Should have pin");
+            if pinned.active == next {
+                continue;
+            }
+            let delta = match next {
+                Some(next) => {
+                    log::info!("[RouterSync] pinned route to {dest} over {next}");
+                    ShadowRouterDelta::SetPin { dest, next }
+                }
+                None => {
+                    log::warn!("[RouterSync] pinned route to {dest} is lost, fallback to router");
+                    ShadowRouterDelta::DelPin { dest }
+                }
+            };
+            self.queue.push_back(FeatureOutput::ToWorker(true, delta));
+            if pinned.active.is_some() != next.is_some() {
+                self.queue.push_back(FeatureOutput::Event(pinned.actor, Event::PinState(dest, next.is_some())));
+            }
+            pinned.active = next;
+        }
+    }
+
+    /// Forget routes which are learned over the neighbour, they are filled again by the next sync from it
+    fn start_resync(&mut self, now: u64, actor: FeatureControlActor<UserData>, node: NodeId) {
+        let conns = self
+            .conns
+            .iter()
+            .filter(|(_, (n, _, _))| *n == node)
+            .map(|(conn, (_, _, metric))| (*conn, metric.clone()))
+            .collect::<Vec<_>>();
+        if conns.is_empty() {
+            log::warn!("[RouterSync] resync with {node} failed: not a neighbour");
+            self.queue.push_back(FeatureOutput::Event(actor, Event::Resynced(node, None)));
+            return;
+        }
+        let before = self.router.table_hash();
+        log::info!("[RouterSync] resync with {node} over {} connections, table hash {before}", conns.len());
+        for (conn, metric) in conns {
+            self.router.del_direct(conn);
+            self.router.set_direct(conn, metric);
+        }
+        self.resyncs.insert(node, PendingResync { actor, started_at: now, before });
+        self.send_dump_msg(RouteRule::ToNode(node), DumpMessage::ResyncRequest);
+    }
+
+    fn on_tick_resyncs(&mut self, now: u64) {
+        let timeout = self.resyncs.iter().filter(|(_, r)| now >= r.started_at + RESYNC_TIMEOUT_MS).map(|(n, _)| *n).collect::<Vec<_>>();
+        for node in timeout {
+            let resync = self.resyncs.remove(&node).expect("Should have resync");
+            log::warn!("[RouterSync] resync with {node} timeout");
+            self.queue.push_back(FeatureOutput::Event(resync.actor, Event::Resynced(node, None)));
+        }
+    }
+
+    fn on_tick_dumps(&mut self, now: u64) {
+        let timeout = self.dumps.iter().filter(|(_, d)| now >= d.started_at + NETWORK_DUMP_TIMEOUT_MS).map(|(t, _)| *t).collect::<Vec<_>>();
+        for token in timeout {
+            let dump = self.dumps.remove(&token).expect("Should have dump");
+            log::info!("[RouterSync] network dump {token} finished with {} snapshots", dump.snapshots.len());
+            self.queue
+                .push_back(FeatureOutput::Event(dump.actor, Event::DumpNetwork(token, dump.snapshots.into_values().collect())));
+        }
+    }
 }
 
-impl<UserData> Feature<UserData, Control, Event, ToController, ToWorker> for RouterSyncFeature<UserData> {
-    fn on_shared_input(&mut self, _ctx: &FeatureContext, _now: u64, input: FeatureSharedInput) {
+impl<UserData: Copy> Feature<UserData, Control, Event, ToController, ToWorker> for RouterSyncFeature<UserData> {
+    fn on_shared_input(&mut self, _ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
         match input {
             FeatureSharedInput::Tick(tick_count) => {
+                self.on_tick_dumps(now);
+                self.on_tick_resyncs(now);
+                if !self.restored.0.is_empty() && now >= self.restored.1 {
+                    log::info!("[RouterSync] drop restored syncs of {} neighbours which didn't connect again", self.restored.0.len());
+                    self.restored.0.clear();
+                }
                 if tick_count < 1 {
                     //we need to wait all workers to be ready
                     return;
@@ -80,17 +429,43 @@ impl<UserData> Feature<UserData, Control, Event, ToController, ToWorker> for Rou
                     self.router.register_service(service);
                 }
 
+                while let Some((service, placement)) = self.placements.pop() {
+                    log::info!("[RouterSync] set service {} placement {:?}", service, placement);
+                    self.router.set_service_placement(service, placement);
+                }
+
+                if self.observer != self.router.is_observer() {
+                    log::info!("[RouterSync] set observer mode {}", self.observer);
+                    self.router.set_observer(self.observer);
+                }
+
                 for (conn, (node, _, _)) in self.conns.iter() {
-                    Self::send_sync_to(&self.router, &mut self.queue, *conn, *node);
+                    let caps = self.conn_caps.get(conn).copied().unwrap_or_default();
+                    Self::send_sync_to(&self.router, &mut self.queue, *conn, *node, caps, self.decommission);
+                }
+                self.refresh_pins();
+
+                let hash = self.router.table_hash();
+                if hash == self.stable.0 {
+                    self.stable.1 = self.stable.1.saturating_add(1);
+                } else {
+                    self.stable = (hash, 0);
                 }
+                self.dump.refresh(now, &self.router.dump());
             }
             FeatureSharedInput::Connection(event) => match event {
                 ConnectionEvent::Connected(ctx, _) => {
                     log::info!("[RouterSync] Connection {} connected", ctx.pair);
                     let metric = Metric::new(INIT_RTT_MS, vec![ctx.node], INIT_BW);
                     self.conns.insert(ctx.conn, (ctx.node, ctx.pair, metric.clone()));
-                    self.router.set_direct(ctx.conn, metric);
-                    Self::send_sync_to(&self.router, &mut self.queue, ctx.conn, ctx.node);
+                    self.router.set_direct(ctx.conn, metric.clone());
+                    if let Some(sync) = self.restored.0.remove(&ctx.node) {
+                        log::info!("[RouterSync] apply restored sync of {} to connection {}", ctx.node, ctx.pair);
+                        self.router.apply_sync(ctx.conn, metric, sync.clone());
+                        self.syncs.insert(ctx.conn, sync);
+                    }
+                    Self::send_sync_to(&self.router, &mut self.queue, ctx.conn, ctx.node, Capabilities::EMPTY, self.decommission);
+                    self.refresh_pins();
                 }
                 ConnectionEvent::Stats(ctx, stats) => {
                     log::debug!("[RouterSync] Connection {} stats rtt_ms {}", ctx.pair, stats.rtt_ms);
@@ -101,36 +476,105 @@ impl<UserData> Feature<UserData, Control, Event, ToController, ToWorker> for Rou
                 ConnectionEvent::Disconnected(ctx) => {
                     log::info!("[RouterSync] Connection {} disconnected", ctx.pair);
                     self.conns.remove(&ctx.conn);
+                    self.conn_caps.remove(&ctx.conn);
+                    self.syncs.remove(&ctx.conn);
                     self.router.del_direct(ctx.conn);
+                    self.refresh_pins();
+                }
+                ConnectionEvent::Capabilities(ctx, remote) => {
+                    let caps = self.caps.intersection(remote.capabilities);
+                    self.conn_caps.insert(ctx.conn, caps);
+                    // sync again, so extensions don't wait for the next tick
+                    Self::send_sync_to(&self.router, &mut self.queue, ctx.conn, ctx.node, caps, self.decommission);
                 }
+                ConnectionEvent::ConnectFailed(..) => {}
             },
         }
     }
 
-    fn on_input(&mut self, _ctx: &FeatureContext, _now_ms: u64, input: FeatureInput<'_, UserData, Control, ToController>) {
+    fn on_input(&mut self, _ctx: &FeatureContext, now_ms: u64, input: FeatureInput<'_, UserData, Control, ToController>) {
         match input {
             FeatureInput::FromWorker(_) => {}
             FeatureInput::Control(actor, control) => match control {
                 Control::DumpRouter => {
                     self.queue.push_back(FeatureOutput::Event(actor, Event::DumpRouter(Box::new(self.router.dump()))));
                 }
+                Control::DumpRouterDiff(since) => {
+                    self.dump.refresh(now_ms, &self.router.dump());
+                    let dump = self.dump.dump(self.router.node_id(), now_ms, since);
+                    log::debug!("[RouterSync] dump router since version {since} => version {}, {} dests", dump.version, dump.dests.len());
+                    self.queue.push_back(FeatureOutput::Event(actor, Event::DumpRouterDiff(Box::new(dump))));
+                }
+                Control::DumpNetwork(token, service) => {
+                    log::info!("[RouterSync] start network dump {token} over service {service}");
+                    let mut snapshots = BTreeMap::new();
+                    snapshots.insert(self.router.node_id(), self.router.dump());
+                    if self.dumps.insert(token, NetworkDump { actor, started_at: now_ms, snapshots }).is_some() {
+                        log::warn!("[RouterSync] network dump {token} is restarted");
+                    }
+                    let seq = self.dump_seq;
+                    self.dump_seq = self.dump_seq.wrapping_add(1);
+                    self.send_dump_msg(RouteRule::ToServices(service, ServiceBroadcastLevel::Global, seq), DumpMessage::Request(token));
+                }
+                Control::SetServicePlacement(service, placement) => {
+                    log::info!("[RouterSync] set service {} placement {:?}", service, placement);
+                    self.router.set_service_placement(service, placement);
+                }
+                Control::SetServiceLoad(service, load) => {
+                    log::debug!("[RouterSync] set service {} load {}", service, load);
+                    self.router.set_service_load(service, load);
+                }
+                Control::PinRoute(dest, pin) => {
+                    log::info!("[RouterSync] pin route to {dest} with {:?}", pin);
+                    // a replaced pin is always reported with its new state
+                    let pinned = PinnedRoute { actor, pin, active: None };
+                    if let Some(PinnedRoute { active: Some(_), .. }) = self.pins.insert(dest, pinned) {
+                        self.queue.push_back(FeatureOutput::ToWorker(true, ShadowRouterDelta::DelPin { dest }));
+                    }
+                    self.refresh_pins();
+                    if let Some(PinnedRoute { active: None, .. }) = self.pins.get(&dest) {
+                        self.queue.push_back(FeatureOutput::Event(actor, Event::PinState(dest, false)));
+                    }
+                }
+                Control::Resync(node) => self.start_resync(now_ms, actor, node),
+                Control::UnpinRoute(dest) => {
+                    if self.pins.remove(&dest).is_some() {
+                        log::info!("[RouterSync] unpin route to {dest}");
+                        self.queue.push_back(FeatureOutput::ToWorker(true, ShadowRouterDelta::DelPin { dest }));
+                    }
+                }
             },
             FeatureInput::Net(ctx, meta, buf) => {
                 if !meta.secure {
-                    log::warn!("[RouterSync] reject unsecure message");
+                    log_sampled!(log::Level::Warn, "[RouterSync] reject unsecure message");
+                    return;
+                }
+                if let Some(from) = meta.source {
+                    self.on_dump_msg(from, &buf);
                     return;
                 }
-                if let Some((_node, _remote, metric)) = self.conns.get(&ctx.conn) {
-                    if let Ok(sync) = bincode::deserialize::<RouterSync>(&buf) {
+                if let Some((node, _remote, metric)) = self.conns.get(&ctx.conn) {
+                    let caps = self.conn_caps.get(&ctx.conn).copied().unwrap_or_default();
+                    if let Some(sync) = decode_sync(&buf, caps) {
+                        self.syncs.insert(ctx.conn, sync.clone());
                         self.router.apply_sync(ctx.conn, metric.clone(), sync);
+                        if let Some(resync) = self.resyncs.remove(node) {
+                            let after = self.router.table_hash();
+                            log::info!("[RouterSync] resync with {node} done, table hash {} => {after}", resync.before);
+                            self.queue.push_back(FeatureOutput::Event(resync.actor, Event::Resynced(*node, Some((resync.before, after)))));
+                        }
                     } else {
                         log::warn!("[RouterSync] Receive invalid sync from {}", ctx.pair);
                     }
                 } else {
-                    log::warn!("[RouterSync] Receive sync from unknown connection {}", ctx.pair);
+                    log_sampled!(log::Level::Warn, "[RouterSync] Receive sync from unknown connection {}", ctx.pair);
+                }
+            }
+            FeatureInput::Local(meta, buf) => {
+                if let (true, Some(from)) = (meta.secure, meta.source) {
+                    self.on_dump_msg(from, &buf);
                 }
             }
-            FeatureInput::Local(..) => {}
         }
     }
 
@@ -163,7 +607,8 @@ impl<UserData> TaskSwitcherChild<Output<UserData>> for RouterSyncFeature<UserDat
                 RouterDelta::Table(layer, TableDelta(index, DestDelta::DelBestPath)) => ShadowRouterDelta::DelTable { layer, index },
                 RouterDelta::Registry(RegistryDelta::SetServiceLocal(service)) => ShadowRouterDelta::SetServiceLocal { service },
                 RouterDelta::Registry(RegistryDelta::DelServiceLocal(service)) => ShadowRouterDelta::DelServiceLocal { service },
-                RouterDelta::Registry(RegistryDelta::ServiceRemote(service, RegistryDestDelta::SetServicePath(conn, dest, score))) => {
+                RouterDelta::Registry(RegistryDelta::SetServicePlacement(service, placement)) => ShadowRouterDelta::SetServicePlacement { service, placement },
+                RouterDelta::Registry(RegistryDelta::ServiceRemote(service, RegistryDestDelta::SetServicePath(conn, dest, score, load))) => {
                     let conn = self.conns.get(&conn)?;
                     ShadowRouterDelta::SetServiceRemote {
                         service,
@@ -171,12 +616,14 @@ impl<UserData> TaskSwitcherChild<Output<UserData>> for RouterSyncFeature<UserDat
                         next: conn.0,
                         dest,
                         score,
+                        load,
                     }
                 }
                 RouterDelta::Registry(RegistryDelta::ServiceRemote(service, RegistryDestDelta::DelServicePath(conn))) => ShadowRouterDelta::DelServiceRemote {
                     service,
                     conn: self.conns.get(&conn)?.1,
                 },
+                RouterDelta::Observer(node, observer) => ShadowRouterDelta::SetObserver { node, observer },
             };
             return Some(FeatureOutput::ToWorker(true, rule));
         }
@@ -200,9 +647,7 @@ impl<UserData> FeatureWorker<UserData, Control, Event, ToController, ToWorker> f
                 log::debug!("[RouterSyncWorker] apply router delta {:?}", delta);
                 ctx.router.apply_delta(delta);
             }
-            FeatureWorkerInput::Local(_header, _msg) => {
-                log::warn!("No handler for local message in {}", FEATURE_NAME);
-            }
+            FeatureWorkerInput::Local(header, msg) => self.queue.push_back(FeatureWorkerOutput::ForwardLocalToController(header, msg)),
             #[cfg(feature = "vpn")]
             FeatureWorkerInput::TunPkt(_buf) => {
                 log::warn!("No handler for tun packet in {}", FEATURE_NAME);
@@ -232,9 +677,103 @@ impl<UserData> TaskSwitcherChild<WorkerOutput<UserData>> for RouterSyncFeatureWo
     }
 }
 
+/// Encode a router sync for a neighbour. Extensions which are agreed with it are appended after the sync,
+/// nodes without them ignore trailing bytes
+pub(crate) fn encode_sync(sync: &RouterSync, caps: Capabilities) -> Vec<u8> {
+    let mut buf = bincode::serialize(sync).expect("Should serialize router sync");
+    if caps.contains(Capabilities::SERVICE_LOAD) {
+        bincode::serialize_into(&mut buf, &sync.0.loads()).expect("Should serialize service loads");
+    }
+    if caps.contains(Capabilities::RELAY_LOAD) {
+        bincode::serialize_into(&mut buf, &sync.2).expect("Should serialize relay load");
+    }
+    buf
+}
+
+/// Decode a router sync from a neighbour, extensions which are missing are left as default because the neighbour
+/// can send a sync before it received the agreed capabilities
+pub(crate) fn decode_sync(buf: &[u8], caps: Capabilities) -> Option<RouterSync> {
+    let mut reader = buf;
+    let mut sync: RouterSync = bincode::deserialize_from(&mut reader).ok()?;
+    if caps.contains(Capabilities::SERVICE_LOAD) && !reader.is_empty() {
+        let loads: Vec<u8> = bincode::deserialize_from(&mut reader).ok()?;
+        sync.0.set_loads(&loads);
+    }
+    if caps.contains(Capabilities::RELAY_LOAD) && !reader.is_empty() {
+        sync.2 = bincode::deserialize_from(&mut reader).ok()?;
+    }
+    Some(sync)
+}
+
 #[cfg(test)]
 mod tests {
-    use atm0s_sdn_router::core::{Metric, RegistrySync, RouterSync, TableSync};
+    use atm0s_sdn_identity::{ConnId, NodeId};
+    use atm0s_sdn_router::core::{Metric, RegistrySync, RouterSync, RoutingPolicy, TableSync, UNLIMITED_BANDWIDTH};
+    use sans_io_runtime::TaskSwitcherChild;
+
+    use crate::{
+        base::{
+            Capabilities, ConnectionCtx, ConnectionEvent, Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, PeerCapabilities, SecureContext, SecureInfo,
+            PROTOCOL_VERSION,
+        },
+        data_plane::NetPair,
+    };
+
+    use super::{decode_sync, Control, RouterSyncFeature};
+
+    const CTX: FeatureContext = FeatureContext { node_id: 1, session: 0 };
+
+    fn conn_ctx(node: NodeId) -> ConnectionCtx {
+        ConnectionCtx {
+            conn: ConnId::from_out(0, node as u64),
+            node,
+            pair: NetPair::new_str("1.1.1.1:1000", &format!("2.2.2.{node}:2000")).expect("Should parse pair"),
+            secure: SecureInfo::UNKNOWN,
+        }
+    }
+
+    fn sent_sync(feature: &mut RouterSyncFeature<u32>, conn: ConnId) -> Vec<u8> {
+        while let Some(out) = feature.pop_output(0) {
+            if let FeatureOutput::SendDirect(to, _, buf) = out {
+                if to == conn {
+                    return buf.to_vec();
+                }
+            }
+        }
+        panic!("Should send sync to {conn}");
+    }
+
+    #[test]
+    fn loads_only_synced_with_agreed_neighbours() {
+        let mut feature = RouterSyncFeature::<u32>::new(1, vec![1], vec![], false, RoutingPolicy::default()).with_capabilities(Capabilities::SUPPORTED);
+        feature.on_shared_input(&CTX, 0, FeatureSharedInput::Tick(1));
+        feature.on_input(&CTX, 0, FeatureInput::Control(FeatureControlActor::Controller(0), Control::SetServiceLoad(1, 100)));
+        feature.set_relay_load(80);
+        while feature.pop_output(0).is_some() {}
+
+        let (new, legacy) = (conn_ctx(2), conn_ctx(3));
+        for ctx in [&new, &legacy] {
+            feature.on_shared_input(&CTX, 0, FeatureSharedInput::Connection(ConnectionEvent::Connected(ctx.clone(), SecureContext::detached())));
+            // capabilities are not negotiated yet, so only the plain sync is sent
+            let buf = sent_sync(&mut feature, ctx.conn);
+            let sync = bincode::deserialize::<RouterSync>(&buf).expect("Should decode");
+            assert_eq!(bincode::serialize(&sync).expect("Should encode"), buf);
+        }
+
+        let remote = PeerCapabilities {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+        };
+        feature.on_shared_input(&CTX, 0, FeatureSharedInput::Connection(ConnectionEvent::Capabilities(new.clone(), remote)));
+        let sync = decode_sync(&sent_sync(&mut feature, new.conn), Capabilities::SUPPORTED).expect("Should decode");
+        assert_eq!(sync.0.loads(), vec![100]);
+        assert_eq!(sync.2, 80);
+
+        feature.on_shared_input(&CTX, 0, FeatureSharedInput::Connection(ConnectionEvent::Capabilities(legacy.clone(), PeerCapabilities::LEGACY)));
+        let buf = sent_sync(&mut feature, legacy.conn);
+        let sync = bincode::deserialize::<RouterSync>(&buf).expect("Should decode");
+        assert_eq!(bincode::serialize(&sync).expect("Should encode"), buf);
+    }
 
     #[test]
     fn router_sync_should_fit_udp() {
@@ -258,7 +797,7 @@ mod tests {
             *i = Some(table);
         }
 
-        let sync = RouterSync(service_sync, table_sync);
+        let sync = RouterSync(service_sync, table_sync, 0, vec![], UNLIMITED_BANDWIDTH);
         let sync_msg_len = bincode::serialize(&sync).expect("").len();
         assert!(sync_msg_len <= MAX_SIZE, "SYNC msg not fit in UDP {} vs {}", sync_msg_len, MAX_SIZE);
     }
@@ -0,0 +1,258 @@
+//! Versioned export of the routing table for external tooling.
+//!
+//! Entries are compared with the previous state on each tick, and changed ones are tagged with a new version and the time of the
+//! change. Pollers keep the version of the last dump and ask only for entries which changed after it.
+
+use std::collections::{BTreeMap, HashMap};
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_router::core::{Metric, RouterDump};
+use serde::{Deserialize, Serialize};
+
+/// Version of the dump format, it is increased with each incompatible change of fields
+pub const ROUTER_DUMP_SCHEMA: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DumpPath {
+    /// Neighbour which the path goes over
+    pub over: NodeId,
+    pub dest: NodeId,
+    /// Nodes from dest to over
+    pub hops: Vec<NodeId>,
+    pub latency_ms: u16,
+    pub bandwidth_kbps: u32,
+    /// Load of the service instance, 0 for table paths
+    pub load: u8,
+    /// Lower is better
+    pub score: u32,
+}
+
+impl DumpPath {
+    fn new(over: NodeId, metric: &Metric) -> Self {
+        Self {
+            over,
+            dest: metric.dest_node(),
+            hops: metric.hops.clone(),
+            latency_ms: metric.latency,
+            bandwidth_kbps: metric.bandwidth,
+            load: metric.load,
+            score: metric.score(),
+        }
+    }
+
+    /// Sorted from the best path, so equal tables give equal dumps
+    fn sorted(paths: &HashMap<NodeId, Metric>) -> Vec<Self> {
+        let mut paths = paths.iter().map(|(over, metric)| Self::new(*over, metric)).collect::<Vec<_>>();
+        paths.sort_by_key(|path| (path.score, path.over));
+        paths
+    }
+}
+
+/// Slot of a layer, which is a destination node in layer 0 or a zone in upper layers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DumpDest {
+    pub layer: u8,
+    pub index: u8,
+    /// Empty if the destination is removed, it is only included in diffs
+    pub paths: Vec<DumpPath>,
+    pub version: u64,
+    pub updated_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DumpService {
+    pub service: u8,
+    /// Registered by this node
+    pub local: bool,
+    /// Paths to remote instances, both fields are empty if the service is removed
+    pub paths: Vec<DumpPath>,
+    pub version: u64,
+    pub updated_ms: u64,
+}
+
+/// Routing table of a node, which is a full dump or a diff since a version
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionedRouterDump {
+    pub schema: u16,
+    pub node: NodeId,
+    /// Version of the latest change, which is used as `since` of the next poll
+    pub version: u64,
+    /// Requested version
+    pub since: u64,
+    /// All entries are included, because `since` is 0 or it is newer than the current version, for example after a restart
+    pub full: bool,
+    pub generated_ms: u64,
+    /// Sorted by layer then index
+    pub dests: Vec<DumpDest>,
+    /// Sorted by service
+    pub services: Vec<DumpService>,
+}
+
+/// Versions of routing table entries, which are refreshed from router dumps
+#[derive(Default)]
+pub struct DumpTracker {
+    version: u64,
+    dests: BTreeMap<(u8, u8), DumpDest>,
+    services: BTreeMap<u8, DumpService>,
+}
+
+impl DumpTracker {
+    /// Compare the dump with the previous state, all changed entries are tagged with one new version
+    pub fn refresh(&mut self, now_ms: u64, dump: &RouterDump) {
+        let next = self.version + 1;
+        let mut changed = false;
+
+        let mut dests = BTreeMap::new();
+        for table in dump.layers() {
+            for (index, dest) in table.dests() {
+                dests.insert((table.layer(), *index), DumpPath::sorted(dest.paths()));
+            }
+        }
+        for (key, entry) in self.dests.iter_mut() {
+            if !entry.paths.is_empty() && !dests.contains_key(key) {
+                entry.paths.clear();
+                (entry.version, entry.updated_ms) = (next, now_ms);
+                changed = true;
+            }
+        }
+        for ((layer, index), paths) in dests {
+            let entry = self.dests.entry((layer, index)).or_insert_with(|| DumpDest {
+                layer,
+                index,
+                paths: vec![],
+                version: 0,
+                updated_ms: 0,
+            });
+            if entry.paths != paths {
+                entry.paths = paths;
+                (entry.version, entry.updated_ms) = (next, now_ms);
+                changed = true;
+            }
+        }
+
+        let mut services: BTreeMap<u8, (bool, Vec<DumpPath>)> = BTreeMap::new();
+        for service in dump.services().local() {
+            services.entry(*service).or_default().0 = true;
+        }
+        for (service, dest) in dump.services().remotes() {
+            services.entry(*service).or_default().1 = DumpPath::sorted(dest.paths());
+        }
+        for (service, entry) in self.services.iter_mut() {
+            if (entry.local || !entry.paths.is_empty()) && !services.contains_key(service) {
+                entry.local = false;
+                entry.paths.clear();
+                (entry.version, entry.updated_ms) = (next, now_ms);
+                changed = true;
+            }
+        }
+        for (service, (local, paths)) in services {
+            let entry = self.services.entry(service).or_insert_with(|| DumpService {
+                service,
+                local: false,
+                paths: vec![],
+                version: 0,
+                updated_ms: 0,
+            });
+            if entry.local != local || entry.paths != paths {
+                (entry.local, entry.paths) = (local, paths);
+                (entry.version, entry.updated_ms) = (next, now_ms);
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.version = next;
+        }
+    }
+
+    /// Entries which changed after `since`, or all current entries if it is a full dump
+    pub fn dump(&self, node: NodeId, now_ms: u64, since: u64) -> VersionedRouterDump {
+        let full = since == 0 || since > self.version;
+        let dests = self
+            .dests
+            .values()
+            .filter(|dest| {
+                if full {
+                    !dest.paths.is_empty()
+                } else {
+                    dest.version > since
+                }
+            })
+            .cloned()
+            .collect();
+        let services = self
+            .services
+            .values()
+            .filter(|service| {
+                if full {
+                    service.local || !service.paths.is_empty()
+                } else {
+                    service.version > since
+                }
+            })
+            .cloned()
+            .collect();
+        VersionedRouterDump {
+            schema: ROUTER_DUMP_SCHEMA,
+            node,
+            version: self.version,
+            since,
+            full,
+            generated_ms: now_ms,
+            dests,
+            services,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_identity::ConnId;
+    use atm0s_sdn_router::core::{Metric, Router};
+
+    use super::DumpTracker;
+
+    #[test]
+    fn diff_since_version() {
+        let mut router = Router::new(0x01_00_00_01);
+        let mut tracker = DumpTracker::default();
+        let version = |tracker: &DumpTracker| tracker.dump(0, 0, 0).version;
+        tracker.refresh(100, &router.dump());
+        assert_eq!(version(&tracker), 0);
+        assert_eq!(tracker.dump(router.node_id(), 100, 0).dests, vec![]);
+
+        router.register_service(1);
+        router.set_direct(ConnId::from_out(0, 1), Metric::new(10, vec![0x01_00_00_02], 1000));
+        tracker.refresh(200, &router.dump());
+        assert_eq!(version(&tracker), 1);
+
+        //unchanged dump doesn't create a new version
+        tracker.refresh(300, &router.dump());
+        assert_eq!(version(&tracker), 1);
+
+        let full = tracker.dump(router.node_id(), 300, 0);
+        assert!(full.full);
+        assert_eq!(full.dests.len(), 1);
+        assert_eq!((full.dests[0].layer, full.dests[0].index, full.dests[0].updated_ms), (0, 2, 200));
+        assert_eq!(full.dests[0].paths[0].over, 0x01_00_00_02);
+        assert_eq!(full.services.len(), 1);
+        assert!(full.services[0].local);
+        assert_eq!(tracker.dump(router.node_id(), 300, 1).dests, vec![]);
+
+        router.set_direct(ConnId::from_out(0, 2), Metric::new(10, vec![0x01_00_00_03], 1000));
+        router.del_direct(ConnId::from_out(0, 1));
+        tracker.refresh(400, &router.dump());
+        assert_eq!(version(&tracker), 2);
+
+        let diff = tracker.dump(router.node_id(), 400, 1);
+        assert!(!diff.full);
+        assert_eq!(diff.services, vec![]);
+        let changes = diff.dests.iter().map(|dest| (dest.index, dest.paths.len(), dest.version)).collect::<Vec<_>>();
+        assert_eq!(changes, vec![(2, 0, 2), (3, 1, 2)]);
+
+        //removed entries are not included in full dump, and unknown version is answered with full dump
+        let full = tracker.dump(router.node_id(), 400, 10);
+        assert!(full.full);
+        assert_eq!(full.dests.iter().map(|dest| dest.index).collect::<Vec<_>>(), vec![3]);
+    }
+}
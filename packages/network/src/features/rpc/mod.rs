@@ -0,0 +1,331 @@
+//! Request/response between nodes with correlation ids and timeouts.
+//!
+//! An actor registers as the handler of a service id on its node, then requests to that service are delivered to it with
+//! [`Event::Request`] and answered with [`Control::Response`]. Requests are routed with `RouteRule::ToNode` to a specific node
+//! or `RouteRule::ToService` to the closest node which runs the service. The requester matches responses with a sequence
+//! which is generated by the feature, so [`RequestId`] only needs to be unique for each actor, and reports
+//! [`RpcError::Timeout`] if no response arrives in the timeout of the request. The request and response should fit in a single packet.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+};
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_router::RouteRule;
+use atm0s_sdn_utils::log_sampled;
+use derivative::Derivative;
+use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
+
+use crate::base::{
+    Feature, FeatureContext, FeatureControlActor, FeatureError, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput, NetOutgoingMeta, Ttl,
+};
+
+use self::msg::RpcMessage;
+
+mod msg;
+
+pub use self::msg::{RequestId, RpcDest, RpcError, RpcRemote};
+
+pub const FEATURE_ID: u8 = 9;
+pub const FEATURE_NAME: &str = "rpc";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Control {
+    /// Handle requests to the service on this node, the previous handler is replaced
+    Register(u8),
+    /// Stop handling requests to the service, only the registered handler can unregister
+    Unregister(u8),
+    /// Send a request with timeout in milliseconds, result is [`Event::Response`] with the same request id
+    Request(RequestId, RpcDest, Vec<u8>, u64),
+    /// Answer a request which is received with [`Event::Request`]
+    Response(RpcRemote, Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Request to a registered service: service, requester, data
+    Request(u8, RpcRemote, Vec<u8>),
+    Response(RequestId, Result<Vec<u8>, RpcError>),
+}
+
+impl Event {
+    pub fn error(&self) -> Option<FeatureError> {
+        match self {
+            Self::Response(_, Err(RpcError::Timeout)) => Some(FeatureError::Timeout),
+            Self::Response(_, Err(RpcError::NoHandler)) => Some(FeatureError::Unreachable),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ToWorker;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ToController;
+
+pub type Output<UserData> = FeatureOutput<UserData, Event, ToWorker>;
+pub type WorkerOutput<UserData> = FeatureWorkerOutput<UserData, Control, Event, ToController>;
+
+#[derive(Debug)]
+struct PendingSlot<UserData> {
+    actor: FeatureControlActor<UserData>,
+    req_id: RequestId,
+    deadline: u64,
+}
+
+#[derive(Debug, Derivative)]
+#[derivative(Default(bound = ""))]
+pub struct RpcFeature<UserData> {
+    handlers: HashMap<u8, FeatureControlActor<UserData>>,
+    /// Requests which are waiting for response, by sequence
+    pending: HashMap<u64, PendingSlot<UserData>>,
+    seq: u64,
+    queue: VecDeque<Output<UserData>>,
+    shutdown: bool,
+}
+
+impl<UserData: Debug + Copy + Eq> RpcFeature<UserData> {
+    fn send_msg(&mut self, rule: RouteRule, msg: RpcMessage) {
+        let buf = bincode::serialize(&msg).expect("Should serialize rpc message");
+        self.queue.push_back(FeatureOutput::SendRoute(rule, NetOutgoingMeta::new(true, Ttl::default(), 0, true), buf.into()));
+    }
+
+    fn on_control(&mut self, now_ms: u64, actor: FeatureControlActor<UserData>, control: Control) {
+        match control {
+            Control::Register(service) => {
+                if let Some(old) = self.handlers.insert(service, actor) {
+                    if old != actor {
+                        log::warn!("[RpcFeature] handler of service {service} is replaced, old {:?}, new {:?}", old, actor);
+                    }
+                }
+            }
+            Control::Unregister(service) => {
+                if self.handlers.get(&service) == Some(&actor) {
+                    self.handlers.remove(&service);
+                } else {
+                    log::warn!("[RpcFeature] {:?} unregister service {service} which is not handled by it", actor);
+                }
+            }
+            Control::Request(req_id, dest, data, timeout_ms) => {
+                self.seq += 1;
+                let seq = self.seq;
+                log::debug!("[RpcFeature] request {req_id} from {:?} to {:?} with seq {seq}", actor, dest);
+                self.pending.insert(
+                    seq,
+                    PendingSlot {
+                        actor,
+                        req_id,
+                        deadline: now_ms + timeout_ms,
+                    },
+                );
+                self.send_msg(dest.rule(), RpcMessage::Request { seq, service: dest.service(), data });
+            }
+            Control::Response(remote, data) => {
+                self.send_msg(RouteRule::ToNode(remote.node), RpcMessage::Response { seq: remote.seq, result: Ok(data) });
+            }
+        }
+    }
+
+    fn on_msg(&mut self, from: NodeId, msg: RpcMessage) {
+        match msg {
+            RpcMessage::Request { seq, service, data } => {
+                if let Some(actor) = self.handlers.get(&service) {
+                    self.queue.push_back(FeatureOutput::Event(*actor, Event::Request(service, RpcRemote { node: from, seq }, data)));
+                } else {
+                    log::warn!("[RpcFeature] request to service {service} from node {from} without handler");
+                    self.send_msg(
+                        RouteRule::ToNode(from),
+                        RpcMessage::Response {
+                            seq,
+                            result: Err(RpcError::NoHandler),
+                        },
+                    );
+                }
+            }
+            RpcMessage::Response { seq, result } => {
+                if let Some(slot) = self.pending.remove(&seq) {
+                    self.queue.push_back(FeatureOutput::Event(slot.actor, Event::Response(slot.req_id, result)));
+                } else {
+                    log::debug!("[RpcFeature] response with seq {seq} from node {from} after timeout");
+                }
+            }
+        }
+    }
+}
+
+impl<UserData: Debug + Copy + Eq> Feature<UserData, Control, Event, ToController, ToWorker> for RpcFeature<UserData> {
+    fn on_shared_input(&mut self, _ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
+        if let FeatureSharedInput::Tick(_) = input {
+            let timeouts: Vec<u64> = self.pending.iter().filter(|(_, slot)| now >= slot.deadline).map(|(seq, _)| *seq).collect();
+            for seq in timeouts {
+                let slot = self.pending.remove(&seq).expect("Should have pending slot");
+                log::warn!("[RpcFeature] request {} of {:?} timeout", slot.req_id, slot.actor);
+                self.queue.push_back(FeatureOutput::Event(slot.actor, Event::Response(slot.req_id, Err(RpcError::Timeout))));
+            }
+        }
+    }
+
+    fn on_input(&mut self, _ctx: &FeatureContext, now_ms: u64, input: FeatureInput<'_, UserData, Control, ToController>) {
+        match input {
+            FeatureInput::Control(actor, control) => self.on_control(now_ms, actor, control),
+            FeatureInput::Local(meta, buf) | FeatureInput::Net(_, meta, buf) => {
+                if !meta.secure {
+                    log_sampled!(log::Level::Warn, "[RpcFeature] reject unsecure message");
+                    return;
+                }
+                match (meta.source, bincode::deserialize::<RpcMessage>(&buf)) {
+                    (Some(from), Ok(msg)) => self.on_msg(from, msg),
+                    _ => log_sampled!(log::Level::Warn, "[RpcFeature] receive invalid message"),
+                }
+            }
+            FeatureInput::FromWorker(_) => {}
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &FeatureContext, _now: u64) {
+        log::info!("[RpcFeature] Shutdown");
+        self.shutdown = true;
+    }
+}
+
+impl<UserData> TaskSwitcherChild<Output<UserData>> for RpcFeature<UserData> {
+    type Time = u64;
+
+    fn is_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn empty_event(&self) -> Output<UserData> {
+        Output::OnResourceEmpty
+    }
+
+    fn pop_output(&mut self, _now: u64) -> Option<Output<UserData>> {
+        self.queue.pop_front()
+    }
+}
+
+#[derive(Derivative)]
+#[derivative(Default(bound = ""))]
+pub struct RpcFeatureWorker<UserData> {
+    queue: DynamicDeque<WorkerOutput<UserData>, 1>,
+    shutdown: bool,
+}
+
+impl<UserData> FeatureWorker<UserData, Control, Event, ToController, ToWorker> for RpcFeatureWorker<UserData> {
+    fn on_input(&mut self, _ctx: &mut crate::base::FeatureWorkerContext, _now: u64, input: FeatureWorkerInput<UserData, Control, ToWorker>) {
+        match input {
+            FeatureWorkerInput::Control(actor, control) => self.queue.push_back(FeatureWorkerOutput::ForwardControlToController(actor, control)),
+            FeatureWorkerInput::Network(conn, header, buf) => self.queue.push_back(FeatureWorkerOutput::ForwardNetworkToController(conn, header, buf)),
+            #[cfg(feature = "vpn")]
+            FeatureWorkerInput::TunPkt(..) => {}
+            FeatureWorkerInput::FromController(..) => {
+                log::warn!("No handler for FromController");
+            }
+            FeatureWorkerInput::Local(header, buf) => self.queue.push_back(FeatureWorkerOutput::ForwardLocalToController(header, buf)),
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &mut crate::base::FeatureWorkerContext, _now: u64) {
+        self.shutdown = true;
+    }
+}
+
+impl<UserData> TaskSwitcherChild<WorkerOutput<UserData>> for RpcFeatureWorker<UserData> {
+    type Time = u64;
+
+    fn is_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn empty_event(&self) -> WorkerOutput<UserData> {
+        WorkerOutput::OnResourceEmpty
+    }
+
+    fn pop_output(&mut self, _now: u64) -> Option<WorkerOutput<UserData>> {
+        self.queue.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_router::RouteRule;
+    use sans_io_runtime::TaskSwitcherChild;
+
+    use crate::base::{Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, NetIncomingMeta};
+
+    use super::{msg::RpcMessage, Control, Event, RpcDest, RpcError, RpcFeature, RpcRemote};
+
+    const CTX: FeatureContext = FeatureContext { node_id: 1, session: 0 };
+
+    fn incoming(from: u32, msg: &RpcMessage) -> FeatureInput<'static, (), Control, super::ToController> {
+        FeatureInput::Local(NetIncomingMeta::new(Some(from), 1.into(), 0, true), bincode::serialize(msg).expect("Should serialize").into())
+    }
+
+    fn routed(output: Option<FeatureOutput<(), Event, super::ToWorker>>) -> (RouteRule, RpcMessage) {
+        match output {
+            Some(FeatureOutput::SendRoute(rule, meta, buf)) => {
+                assert!(meta.source);
+                (rule, bincode::deserialize(&buf).expect("Should decode"))
+            }
+            out => panic!("Should be SendRoute, got {:?}", out),
+        }
+    }
+
+    #[test]
+    fn request_and_match_response() {
+        let actor = FeatureControlActor::Controller(());
+        let mut rpc = RpcFeature::<()>::default();
+        rpc.on_input(&CTX, 0, FeatureInput::Control(actor, Control::Request(100, RpcDest::Node(2, 10), vec![1], 1000)));
+        rpc.on_input(&CTX, 0, FeatureInput::Control(actor, Control::Request(100, RpcDest::Service(10), vec![2], 1000)));
+        assert_eq!(routed(rpc.pop_output(0)), (RouteRule::ToNode(2), RpcMessage::Request { seq: 1, service: 10, data: vec![1] }));
+        assert_eq!(routed(rpc.pop_output(0)), (RouteRule::ToService(10), RpcMessage::Request { seq: 2, service: 10, data: vec![2] }));
+
+        // responses are matched by seq, so same request id from an actor is fine
+        rpc.on_input(&CTX, 10, incoming(3, &RpcMessage::Response { seq: 2, result: Ok(vec![20]) }));
+        assert_eq!(rpc.pop_output(0), Some(FeatureOutput::Event(actor, Event::Response(100, Ok(vec![20])))));
+        // duplicated response is ignored
+        rpc.on_input(&CTX, 10, incoming(3, &RpcMessage::Response { seq: 2, result: Ok(vec![20]) }));
+        assert_eq!(rpc.pop_output(0), None);
+
+        rpc.on_shared_input(&CTX, 999, FeatureSharedInput::Tick(1));
+        assert_eq!(rpc.pop_output(0), None);
+        rpc.on_shared_input(&CTX, 1000, FeatureSharedInput::Tick(2));
+        assert_eq!(rpc.pop_output(0), Some(FeatureOutput::Event(actor, Event::Response(100, Err(RpcError::Timeout)))));
+        assert_eq!(rpc.pop_output(0), None);
+    }
+
+    #[test]
+    fn handle_request_with_registered_handler() {
+        let handler = FeatureControlActor::Service(5.into());
+        let mut rpc = RpcFeature::<()>::default();
+        rpc.on_input(&CTX, 0, FeatureInput::Control(handler, Control::Register(10)));
+
+        rpc.on_input(&CTX, 0, incoming(2, &RpcMessage::Request { seq: 7, service: 10, data: vec![1] }));
+        let remote = RpcRemote { node: 2, seq: 7 };
+        assert_eq!(rpc.pop_output(0), Some(FeatureOutput::Event(handler, Event::Request(10, remote, vec![1]))));
+
+        rpc.on_input(&CTX, 0, FeatureInput::Control(handler, Control::Response(remote, vec![2])));
+        assert_eq!(routed(rpc.pop_output(0)), (RouteRule::ToNode(2), RpcMessage::Response { seq: 7, result: Ok(vec![2]) }));
+
+        // only the handler can unregister
+        rpc.on_input(&CTX, 0, FeatureInput::Control(FeatureControlActor::Controller(()), Control::Unregister(10)));
+        rpc.on_input(&CTX, 0, incoming(2, &RpcMessage::Request { seq: 8, service: 10, data: vec![] }));
+        assert!(matches!(rpc.pop_output(0), Some(FeatureOutput::Event(a, Event::Request(10, _, _))) if a == handler));
+
+        rpc.on_input(&CTX, 0, FeatureInput::Control(handler, Control::Unregister(10)));
+        rpc.on_input(&CTX, 0, incoming(2, &RpcMessage::Request { seq: 9, service: 10, data: vec![] }));
+        assert_eq!(
+            routed(rpc.pop_output(0)),
+            (
+                RouteRule::ToNode(2),
+                RpcMessage::Response {
+                    seq: 9,
+                    result: Err(RpcError::NoHandler)
+                }
+            )
+        );
+        assert_eq!(rpc.pop_output(0), None);
+    }
+}
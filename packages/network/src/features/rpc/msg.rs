@@ -0,0 +1,51 @@
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_router::RouteRule;
+use serde::{Deserialize, Serialize};
+
+/// Id of a request which is chosen by the requester, it only needs to be unique for each actor
+pub type RequestId = u64;
+
+/// Destination of a request, both variants are handled by the handler which is registered for the service id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RpcDest {
+    /// Handler of the service at a specific node
+    Node(NodeId, u8),
+    /// Handler of the service at the closest node which runs the service
+    Service(u8),
+}
+
+impl RpcDest {
+    pub fn service(&self) -> u8 {
+        match self {
+            Self::Node(_, service) | Self::Service(service) => *service,
+        }
+    }
+
+    pub(crate) fn rule(&self) -> RouteRule {
+        match self {
+            Self::Node(node, _) => RouteRule::ToNode(*node),
+            Self::Service(service) => RouteRule::ToService(*service),
+        }
+    }
+}
+
+/// Requester of a received request, which is used for sending back the response
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RpcRemote {
+    pub node: NodeId,
+    pub seq: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RpcError {
+    /// No response in the timeout of the request
+    Timeout,
+    /// The request reached a node which has no handler for the service
+    NoHandler,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum RpcMessage {
+    Request { seq: u64, service: u8, data: Vec<u8> },
+    Response { seq: u64, result: Result<Vec<u8>, RpcError> },
+}
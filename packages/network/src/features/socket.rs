@@ -9,7 +9,7 @@ use atm0s_sdn_router::RouteRule;
 use sans_io_runtime::{collections::DynamicDeque, return_if_none, TaskSwitcherChild};
 
 use crate::base::{
-    Buffer, Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput,
+    Buffer, Feature, FeatureContext, FeatureControlActor, FeatureError, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput,
     NetOutgoingMeta, Ttl,
 };
 
@@ -28,6 +28,17 @@ pub enum Control {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Event {
     RecvFrom(u16, NodeId, u16, Buffer, u8),
+    /// Control of a socket failed: port, error
+    Error(u16, FeatureError),
+}
+
+impl Event {
+    pub fn error(&self) -> Option<FeatureError> {
+        match self {
+            Self::Error(_, err) => Some(*err),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -60,6 +71,10 @@ impl<UserData> SocketFeature<UserData> {
         let meta: NetOutgoingMeta = NetOutgoingMeta::new(true, Default::default(), meta, false);
         self.queue.push_back(FeatureOutput::SendRoute(RouteRule::ToNode(dest_node), meta, data));
     }
+
+    fn error(&mut self, actor: FeatureControlActor<UserData>, port: u16, err: FeatureError) {
+        self.queue.push_back(FeatureOutput::Event(actor, Event::Error(port, err)));
+    }
 }
 
 impl<UserData> Default for SocketFeature<UserData> {
@@ -81,6 +96,7 @@ impl<UserData: Copy + Debug + Eq> Feature<UserData, Control, Event, ToController
                 Control::Bind(port) => {
                     if self.sockets.contains_key(&port) {
                         log::warn!("[SocketFeature] Bind failed, port already in use: {}", port);
+                        self.error(actor, port, FeatureError::Rejected);
                         return;
                     }
                     self.sockets.insert(port, Socket { target: None, actor });
@@ -93,9 +109,11 @@ impl<UserData: Copy + Debug + Eq> Feature<UserData, Control, Event, ToController
                             self.queue.push_back(FeatureOutput::ToWorker(true, ToWorker::ConnectSocket(port, dest_node, dest_port)));
                         } else {
                             log::warn!("[SocketFeature] Connect failed, actor mismatch: {:?} != {:?}", socket.actor, actor);
+                            self.error(actor, port, FeatureError::Rejected);
                         }
                     } else {
                         log::warn!("[SocketFeature] Connect failed, port not found: {}", port);
+                        self.error(actor, port, FeatureError::Rejected);
                     }
                 }
                 Control::SendTo(port, dest_node, dest_port, data, meta) => {
@@ -106,15 +124,18 @@ impl<UserData: Copy + Debug + Eq> Feature<UserData, Control, Event, ToController
                                     self.queue.push_back(FeatureOutput::Event(actor, Event::RecvFrom(dest_port, ctx.node_id, port, data, meta)));
                                 } else {
                                     log::warn!("[SocketFeature] SendTo failed, port not found: {}", dest_port);
+                                    self.error(actor, port, FeatureError::Unreachable);
                                 }
                             } else {
                                 self.send_to(port, dest_node, dest_port, data, meta);
                             }
                         } else {
                             log::warn!("[SocketFeature] SendTo failed, actor mismatch: {:?} != {:?}", socket.actor, actor);
+                            self.error(actor, port, FeatureError::Rejected);
                         }
                     } else {
                         log::warn!("[SocketFeature] SendTo failed, port not found: {}", port);
+                        self.error(actor, port, FeatureError::Rejected);
                     }
                 }
                 Control::Send(port, data, meta) => {
@@ -125,15 +146,18 @@ impl<UserData: Copy + Debug + Eq> Feature<UserData, Control, Event, ToController
                                     self.queue.push_back(FeatureOutput::Event(actor, Event::RecvFrom(dest_port, ctx.node_id, port, data, meta)));
                                 } else {
                                     log::warn!("[SocketFeature] SendTo failed, port not found: {}", dest_port);
+                                    self.error(actor, port, FeatureError::Unreachable);
                                 }
                             } else {
                                 self.send_to(port, dest_node, dest_port, data, meta);
                             }
                         } else {
                             log::warn!("[SocketFeature] Send failed, target not found: {}", port);
+                            self.error(actor, port, FeatureError::Rejected);
                         }
                     } else {
                         log::warn!("[SocketFeature] Send failed, port not found: {}", port);
+                        self.error(actor, port, FeatureError::Rejected);
                     }
                 }
                 Control::Unbind(port) => {
@@ -143,9 +167,11 @@ impl<UserData: Copy + Debug + Eq> Feature<UserData, Control, Event, ToController
                             self.queue.push_back(FeatureOutput::ToWorker(true, ToWorker::UnbindSocket(port)));
                         } else {
                             log::warn!("[SocketFeature] Unbind failed, actor mismatch: {:?} != {:?}", socket.actor, actor);
+                            self.error(actor, port, FeatureError::Rejected);
                         }
                     } else {
                         log::warn!("[SocketFeature] Unbind failed, port not found: {}", port);
+                        self.error(actor, port, FeatureError::Rejected);
                     }
                 }
             },
@@ -266,23 +292,20 @@ impl<UserData: Clone + Copy + Eq> FeatureWorker<UserData, Control, Event, ToCont
             },
             FeatureWorkerInput::Control(actor, control) => {
                 let (port, (dest_node, dest_port), mut data, meta) = match control {
-                    Control::Send(port, data, meta) => {
-                        let socket = return_if_none!(self.sockets.get(&port));
-                        if actor == socket.actor {
-                            let target = return_if_none!(socket.target);
-                            (port, target, data, meta)
-                        } else {
+                    Control::Send(port, data, meta) => match self.sockets.get(&port) {
+                        Some(Socket { target: Some(target), actor: owner }) if *owner == actor => (port, *target, data, meta),
+                        _ => {
+                            self.queue.push_back(FeatureWorkerOutput::Event(actor, Event::Error(port, FeatureError::Rejected)));
                             return;
                         }
-                    }
-                    Control::SendTo(port, dest_node, dest_port, data, meta) => {
-                        let socket = return_if_none!(self.sockets.get(&port));
-                        if actor == socket.actor {
-                            (port, (dest_node, dest_port), data, meta)
-                        } else {
+                    },
+                    Control::SendTo(port, dest_node, dest_port, data, meta) => match self.sockets.get(&port) {
+                        Some(socket) if socket.actor == actor => (port, (dest_node, dest_port), data, meta),
+                        _ => {
+                            self.queue.push_back(FeatureWorkerOutput::Event(actor, Event::Error(port, FeatureError::Rejected)));
                             return;
                         }
-                    }
+                    },
                     _ => {
                         self.queue.push_back(FeatureWorkerOutput::ForwardControlToController(actor, control));
                         return;
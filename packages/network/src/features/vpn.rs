@@ -1,27 +1,153 @@
-use std::marker::PhantomData;
+//! Vpn over the mesh: ip packets which are read from the tun device are sent to the node which owns the destination address.
+//!
+//! Addresses are mapped to nodes by subnets. Without configuration only [`DEFAULT_SUBNET`] is used, where the last byte of
+//! the address is the last byte of the node id, like 10.33.33.4 for node 4 in the same group. Subnets of an existing address
+//! plan are added with [`Control::Configure`], the most specific subnet which contains the destination is used. Routes of
+//! the OS toward the tun device for the extra subnets are not managed here.
+
+use std::net::Ipv4Addr;
 
 #[cfg(feature = "vpn")]
 use crate::base::TransportMsg;
-#[cfg(feature = "vpn")]
-use atm0s_sdn_identity::{NodeId, NodeIdType};
+use atm0s_sdn_identity::NodeId;
 #[cfg(feature = "vpn")]
 use atm0s_sdn_router::{RouteAction, RouteRule, RouterTable};
-use derivative::Derivative;
+#[cfg(feature = "vpn")]
+use atm0s_sdn_utils::log_sampled;
 use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
 
-use crate::base::{Buffer, Feature, FeatureContext, FeatureInput, FeatureOutput, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput};
+use crate::base::{Buffer, Feature, FeatureContext, FeatureControlActor, FeatureError, FeatureInput, FeatureOutput, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput};
 
 pub const FEATURE_ID: u8 = 3;
 pub const FEATURE_NAME: &str = "vpn";
 
+/// Subnet which is used without configuration
+pub const DEFAULT_SUBNET: VpnSubnet = VpnSubnet {
+    subnet: Ipv4Addr::new(10, 33, 33, 0),
+    prefix: 24,
+    mapping: IpMapping::HostBits,
+};
+
+/// How addresses of a subnet are mapped to nodes
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Control {}
+pub enum IpMapping {
+    /// Host bits of the address are the lowest bits of the node id, the other bits of the node id are same as the local node
+    HostBits,
+    /// Address of each node, for address plans which don't follow node ids
+    Static(Vec<(Ipv4Addr, NodeId)>),
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Event {}
+pub struct VpnSubnet {
+    pub subnet: Ipv4Addr,
+    pub prefix: u8,
+    pub mapping: IpMapping,
+}
+
+impl VpnSubnet {
+    /// Host bits of the subnet address are cleared, None if the prefix is longer than 32
+    pub fn new(subnet: Ipv4Addr, prefix: u8, mapping: IpMapping) -> Option<Self> {
+        if prefix > 32 {
+            return None;
+        }
+        let subnet = Ipv4Addr::from(u32::from(subnet) & Self::netmask(prefix));
+        Some(Self { subnet, prefix, mapping })
+    }
+
+    fn netmask(prefix: u8) -> u32 {
+        (u64::from(u32::MAX) << (32 - prefix)) as u32
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        u32::from(ip) & Self::netmask(self.prefix) == u32::from(self.subnet)
+    }
+
+    /// Node which owns the address, None if the address is outside of the subnet or not mapped
+    pub fn node_of(&self, local: NodeId, ip: Ipv4Addr) -> Option<NodeId> {
+        if !self.contains(ip) {
+            return None;
+        }
+        match &self.mapping {
+            IpMapping::HostBits => {
+                let host_mask = !Self::netmask(self.prefix);
+                Some((local & !host_mask) | (u32::from(ip) & host_mask))
+            }
+            IpMapping::Static(table) => table.iter().find(|(addr, _)| *addr == ip).map(|(_, node)| *node),
+        }
+    }
+}
+
+/// Subnets which are sorted from the most specific one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VpnSubnets(Vec<VpnSubnet>);
+
+impl Default for VpnSubnets {
+    fn default() -> Self {
+        Self(vec![DEFAULT_SUBNET])
+    }
+}
+
+impl VpnSubnets {
+    /// Add the subnet or replace the mapping of a same subnet
+    pub fn upsert(&mut self, subnet: VpnSubnet) {
+        match self.0.iter_mut().find(|s| s.subnet == subnet.subnet && s.prefix == subnet.prefix) {
+            Some(slot) => *slot = subnet,
+            None => self.0.push(subnet),
+        }
+        self.0.sort_by(|a, b| b.prefix.cmp(&a.prefix));
+    }
+
+    pub fn remove(&mut self, subnet: Ipv4Addr, prefix: u8) -> bool {
+        let len = self.0.len();
+        self.0.retain(|s| !(s.subnet == subnet && s.prefix == prefix));
+        self.0.len() != len
+    }
+
+    pub fn list(&self) -> &[VpnSubnet] {
+        &self.0
+    }
+
+    /// Node which owns the address with the most specific subnet
+    pub fn node_of(&self, local: NodeId, ip: Ipv4Addr) -> Option<NodeId> {
+        self.0.iter().find(|s| s.contains(ip))?.node_of(local, ip)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Control {
+    /// Add a subnet or replace the mapping of a same subnet, it is applied to all workers
+    Configure {
+        subnet: Ipv4Addr,
+        prefix: u8,
+        mapping: IpMapping,
+    },
+    Remove {
+        subnet: Ipv4Addr,
+        prefix: u8,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// Subnets after a change, from the most specific one
+    Subnets(Vec<VpnSubnet>),
+    /// subnet, prefix of a rejected control, like a prefix longer than 32 or removing an unknown subnet
+    Rejected(Ipv4Addr, u8),
+}
+
+impl Event {
+    pub fn error(&self) -> Option<FeatureError> {
+        match self {
+            Self::Rejected(..) => Some(FeatureError::Rejected),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
-pub struct ToWorker;
+pub enum ToWorker {
+    Subnets(VpnSubnets),
+}
 
 #[derive(Debug, Clone)]
 pub struct ToController;
@@ -29,17 +155,63 @@ pub struct ToController;
 pub type Output<UserData> = FeatureOutput<UserData, Event, ToWorker>;
 pub type WorkerOutput<UserData> = FeatureWorkerOutput<UserData, Control, Event, ToController>;
 
-#[derive(Debug, Derivative)]
-#[derivative(Default(bound = ""))]
 pub struct VpnFeature<UserData> {
-    _tmp: PhantomData<UserData>,
+    subnets: VpnSubnets,
+    queue: DynamicDeque<Output<UserData>, 2>,
     shutdown: bool,
 }
 
-impl<UserData> Feature<UserData, Control, Event, ToController, ToWorker> for VpnFeature<UserData> {
+impl<UserData> Default for VpnFeature<UserData> {
+    fn default() -> Self {
+        Self {
+            subnets: VpnSubnets::default(),
+            queue: DynamicDeque::default(),
+            shutdown: false,
+        }
+    }
+}
+
+impl<UserData: Copy> VpnFeature<UserData> {
+    fn on_control(&mut self, actor: FeatureControlActor<UserData>, control: Control) {
+        let changed = match control {
+            Control::Configure { subnet, prefix, mapping } => match VpnSubnet::new(subnet, prefix, mapping) {
+                Some(subnet) => {
+                    log::info!("[VpnFeature] configure subnet {}/{} with {:?}", subnet.subnet, subnet.prefix, subnet.mapping);
+                    self.subnets.upsert(subnet);
+                    true
+                }
+                None => {
+                    log::warn!("[VpnFeature] reject invalid subnet {subnet}/{prefix}");
+                    self.queue.push_back(FeatureOutput::Event(actor, Event::Rejected(subnet, prefix)));
+                    false
+                }
+            },
+            Control::Remove { subnet, prefix } => {
+                if self.subnets.remove(subnet, prefix) {
+                    log::info!("[VpnFeature] remove subnet {subnet}/{prefix}");
+                    true
+                } else {
+                    log::warn!("[VpnFeature] remove unknown subnet {subnet}/{prefix}");
+                    self.queue.push_back(FeatureOutput::Event(actor, Event::Rejected(subnet, prefix)));
+                    false
+                }
+            }
+        };
+        if changed {
+            self.queue.push_back(FeatureOutput::ToWorker(true, ToWorker::Subnets(self.subnets.clone())));
+            self.queue.push_back(FeatureOutput::Event(actor, Event::Subnets(self.subnets.list().to_vec())));
+        }
+    }
+}
+
+impl<UserData: Copy> Feature<UserData, Control, Event, ToController, ToWorker> for VpnFeature<UserData> {
     fn on_shared_input(&mut self, _ctx: &FeatureContext, _now: u64, _input: crate::base::FeatureSharedInput) {}
 
-    fn on_input(&mut self, _ctx: &FeatureContext, _now_ms: u64, _input: FeatureInput<'_, UserData, Control, ToController>) {}
+    fn on_input(&mut self, _ctx: &FeatureContext, _now_ms: u64, input: FeatureInput<'_, UserData, Control, ToController>) {
+        if let FeatureInput::Control(actor, control) = input {
+            self.on_control(actor, control);
+        }
+    }
 
     fn on_shutdown(&mut self, _ctx: &FeatureContext, _now: u64) {
         self.shutdown = true;
@@ -50,7 +222,7 @@ impl<UserData> TaskSwitcherChild<Output<UserData>> for VpnFeature<UserData> {
     type Time = u64;
 
     fn is_empty(&self) -> bool {
-        self.shutdown
+        self.shutdown && self.queue.is_empty()
     }
 
     fn empty_event(&self) -> Output<UserData> {
@@ -58,25 +230,42 @@ impl<UserData> TaskSwitcherChild<Output<UserData>> for VpnFeature<UserData> {
     }
 
     fn pop_output(&mut self, _now: u64) -> Option<Output<UserData>> {
-        None
+        self.queue.pop_front()
     }
 }
 
-#[derive(Derivative)]
-#[derivative(Default(bound = ""))]
 pub struct VpnFeatureWorker<UserData> {
+    subnets: VpnSubnets,
     queue: DynamicDeque<WorkerOutput<UserData>, 16>,
     shutdown: bool,
 }
 
+impl<UserData> Default for VpnFeatureWorker<UserData> {
+    fn default() -> Self {
+        Self {
+            subnets: VpnSubnets::default(),
+            queue: DynamicDeque::default(),
+            shutdown: false,
+        }
+    }
+}
+
 impl<UserData> VpnFeatureWorker<UserData> {
     #[cfg(feature = "vpn")]
     fn process_tun(&mut self, ctx: &FeatureWorkerContext, mut pkt: Buffer) {
-        #[cfg(any(target_os = "macos", target_os = "ios"))]
-        let to_ip = &pkt[20..24];
-        #[cfg(any(target_os = "linux", target_os = "android"))]
-        let to_ip = &pkt[16..20];
-        let dest = NodeId::build(ctx.node_id.geo1(), ctx.node_id.geo2(), ctx.node_id.group(), to_ip[3]);
+        if pkt.len() < TUN_HEADER_LEN + 20 {
+            log_sampled!(log::Level::Warn, "[VpnFeatureWorker] drop too short tun packet {} bytes", pkt.len());
+            return;
+        }
+        let to_ip = &pkt[TUN_HEADER_LEN + 16..TUN_HEADER_LEN + 20];
+        let to_ip = Ipv4Addr::new(to_ip[0], to_ip[1], to_ip[2], to_ip[3]);
+        let dest = match self.subnets.node_of(ctx.node_id, to_ip) {
+            Some(dest) => dest,
+            None => {
+                log_sampled!(log::Level::Warn, "[VpnFeatureWorker] drop packet to {to_ip} which is not in any subnet");
+                return;
+            }
+        };
         if dest == ctx.node_id {
             //This is for current node, just echo back
             rewrite_tun_pkt(&mut pkt);
@@ -103,6 +292,11 @@ impl<UserData> FeatureWorker<UserData, Control, Event, ToController, ToWorker> f
             #[cfg(feature = "vpn")]
             FeatureWorkerInput::TunPkt(pkt) => self.process_tun(ctx, pkt),
             FeatureWorkerInput::Network(_conn, _header, pkt) => self.process_udp(ctx, pkt),
+            FeatureWorkerInput::Control(actor, control) => self.queue.push_back(FeatureWorkerOutput::ForwardControlToController(actor, control)),
+            FeatureWorkerInput::FromController(_, ToWorker::Subnets(subnets)) => {
+                log::info!("[VpnFeatureWorker] apply subnets {:?}", subnets.list());
+                self.subnets = subnets;
+            }
             _ => {}
         }
     }
@@ -129,6 +323,14 @@ impl<UserData> TaskSwitcherChild<WorkerOutput<UserData>> for VpnFeatureWorker<Us
     }
 }
 
+/// macOS utun devices prefix each packet with a 4 bytes packet information header, other platforms give raw ip packets
+#[cfg(feature = "vpn")]
+const TUN_HEADER_LEN: usize = if cfg!(any(target_os = "macos", target_os = "ios")) {
+    4
+} else {
+    0
+};
+
 #[cfg(feature = "vpn")]
 fn rewrite_tun_pkt(payload: &mut [u8]) {
     #[cfg(any(target_os = "macos", target_os = "ios"))]
@@ -142,3 +344,40 @@ fn rewrite_tun_pkt(payload: &mut [u8]) {
         payload[3] = 0;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::{IpMapping, VpnSubnet, VpnSubnets, DEFAULT_SUBNET};
+
+    #[test]
+    fn default_subnet_maps_last_byte_in_local_group() {
+        let local = 0x01020304;
+        assert_eq!(DEFAULT_SUBNET.node_of(local, Ipv4Addr::new(10, 33, 33, 9)), Some(0x01020309));
+        assert_eq!(DEFAULT_SUBNET.node_of(local, Ipv4Addr::new(10, 33, 34, 9)), None);
+    }
+
+    #[test]
+    fn subnet_host_bits_and_static_mapping() {
+        let wide = VpnSubnet::new(Ipv4Addr::new(172, 16, 5, 5), 16, IpMapping::HostBits).expect("Should be valid");
+        assert_eq!(wide.subnet, Ipv4Addr::new(172, 16, 0, 0));
+        assert_eq!(wide.node_of(0x01020304, Ipv4Addr::new(172, 16, 7, 9)), Some(0x01020709));
+        assert_eq!(VpnSubnet::new(Ipv4Addr::new(172, 16, 0, 0), 33, IpMapping::HostBits), None);
+
+        let mut subnets = VpnSubnets::default();
+        subnets.upsert(wide);
+        let office = VpnSubnet::new(Ipv4Addr::new(172, 16, 9, 0), 24, IpMapping::Static(vec![(Ipv4Addr::new(172, 16, 9, 20), 1000)])).expect("Should be valid");
+        subnets.upsert(office);
+
+        // the most specific subnet is used, even when its mapping doesn't know the address
+        assert_eq!(subnets.node_of(1, Ipv4Addr::new(172, 16, 9, 20)), Some(1000));
+        assert_eq!(subnets.node_of(1, Ipv4Addr::new(172, 16, 9, 21)), None);
+        assert_eq!(subnets.node_of(1, Ipv4Addr::new(172, 16, 8, 21)), Some(0x0815));
+        assert_eq!(subnets.node_of(1, Ipv4Addr::new(10, 33, 33, 2)), Some(2));
+
+        assert!(subnets.remove(Ipv4Addr::new(172, 16, 9, 0), 24));
+        assert!(!subnets.remove(Ipv4Addr::new(172, 16, 9, 0), 24));
+        assert_eq!(subnets.node_of(1, Ipv4Addr::new(172, 16, 9, 21)), Some(0x0915));
+    }
+}
@@ -1,10 +1,23 @@
 #![allow(clippy::bool_assert_comparison)]
 
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::mpsc::SyncSender,
+};
+
 use atm0s_sdn_identity::{ConnId, NodeAddr, NodeId};
 use atm0s_sdn_router::RouteRule;
-use base::{FeatureControlActor, NeighboursControl, NetIncomingMeta, NetOutgoingMeta, SecureContext, ServiceControlActor, ServiceId};
-use data_plane::NetPair;
+use base::{
+    Capabilities, CapabilitySkew, ConnectionStats, Decryptor, Encryptor, FeatureControlActor, InterfaceEvent, LinkProfile, NeighboursControl, NetIncomingMeta, NetOutgoingMeta, PeerCapabilities,
+    SecureContext, ServiceControlActor, ServiceId,
+};
+use controller_plane::{EventLogQuery, JournalEntry};
+use data_plane::{
+    tap::{TapFilter, TapRecord},
+    NetPair,
+};
 use features::{Features, FeaturesControl, FeaturesEvent, FeaturesToController, FeaturesToWorker};
+use metrics::FeatureTraffic;
 use sans_io_runtime::Buffer;
 
 #[cfg(feature = "fuzz")]
@@ -13,8 +26,12 @@ pub mod base;
 pub mod controller_plane;
 pub mod data_plane;
 pub mod features;
+pub mod link_tests;
+pub mod metrics;
 pub mod secure;
 pub mod services;
+pub mod simulation;
+pub mod test_vectors;
 pub mod worker;
 
 #[derive(Debug, Clone)]
@@ -23,12 +40,104 @@ pub enum ExtIn<UserData, ServicesControl> {
     DisconnectFrom(NodeId),
     FeaturesControl(UserData, FeaturesControl),
     ServicesControl(ServiceId, UserData, ServicesControl),
+    /// Gracefully remove this node from the network, progress is reported with [`ExtOut::DecommissionEvent`]
+    Decommission,
+    /// Encode a [`controller_plane::StateCheckpoint`] of the controller and send it to the channel
+    SnapshotState(SyncSender<Vec<u8>>),
+    /// Read entries of the event journal, they are answered with [`ExtOut::EventLog`]
+    ControlQuery(EventLogQuery),
+    /// Mirror messages of data plane workers which match the filter, None for stopping. Records are emitted with [`ExtOut::Tap`]
+    Tap(Option<TapFilter>),
+    /// Inject faults into packets which are received by data plane workers, only for resilience testing
+    #[cfg(feature = "chaos")]
+    Chaos(data_plane::chaos::ChaosControl),
+}
+
+/// Progress of a decommission flow, in order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecommissionEvent {
+    /// Node stopped accepting new dht_kv maps and pubsub relays, and asked neighbours to take over its relays
+    Started,
+    /// Remaining responsibilities, emitted each time they change
+    Draining { relays: usize, maps: usize },
+    /// Features and services are shutting down
+    Leaving,
+    /// All neighbour connections are being closed
+    Disconnecting { connections: usize },
+    /// Node left the network
+    Finished,
+}
+
+/// Alert which is emitted when the controller plane stopped processing ticks for too long
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchdogAlert {
+    /// Time since the last processed tick
+    pub stalled_ms: u64,
+    /// Kind of the last input which was processed before the stall was detected
+    pub last_event: &'static str,
+    /// Outputs which are waiting in the worker queue
+    pub worker_queue: usize,
+    /// Outputs which are waiting in the controller plane queue
+    pub controller_queue: usize,
+    /// Controller was rebuilt, all connections are established again from scratch
+    pub restarted: bool,
+}
+
+/// Standby controller took over after the active controller stopped replicating its state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControllerTakeover {
+    /// Worker which runs the controller now
+    pub worker: u16,
+    /// Age of the restored snapshot, changes in this time are lost
+    pub snapshot_age_ms: u64,
+    /// Connections which are adopted from the snapshot
+    pub connections: usize,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ExtOut<UserData, ServicesEvent> {
     FeaturesEvent(UserData, FeaturesEvent),
     ServicesEvent(ServiceId, UserData, ServicesEvent),
+    InterfaceEvent(InterfaceEvent),
+    DecommissionEvent(DecommissionEvent),
+    WatchdogAlert(WatchdogAlert),
+    /// Neighbour runs a different protocol version or capability set, unsupported capabilities are disabled with it
+    CapabilitySkew(CapabilitySkew),
+    /// Service which declares dependencies is started after they are ready
+    ServiceReady(ServiceId),
+    /// Standby controller took over, actors of pubsub, dht_kv and alias need to subscribe again
+    ControllerTakeover(ControllerTakeover),
+    /// Entries of the event journal which matched a [`ExtIn::ControlQuery`]
+    EventLog(Vec<JournalEntry>),
+    /// Message which is mirrored by a data plane worker, see [`ExtIn::Tap`]
+    Tap(TapRecord),
+}
+
+/// Pin external events of each UserData to one worker.
+///
+/// Without it an event is emitted by the worker which sent the control, so a session which sends controls from many workers
+/// must merge many queues. With it all events of a UserData are emitted in order by the worker which is selected by its hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StickyExt {
+    pub workers: u16,
+}
+
+impl StickyExt {
+    pub fn new(workers: u16) -> Self {
+        assert!(workers > 0, "sticky ext needs at least one worker");
+        Self { workers }
+    }
+
+    pub fn worker_of<UserData: Hash>(&self, userdata: &UserData) -> u16 {
+        let mut hasher = DefaultHasher::new();
+        userdata.hash(&mut hasher);
+        (hasher.finish() % self.workers as u64) as u16
+    }
+
+    /// Worker which emits an event of a worker actor, it is the actor worker when sticky is disabled
+    pub fn route<UserData: Hash>(sticky: Option<&StickyExt>, worker: u16, userdata: &UserData) -> u16 {
+        sticky.map(|sticky| sticky.worker_of(userdata)).unwrap_or(worker)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -38,11 +147,18 @@ pub enum LogicControl<UserData, SC, SE, TC> {
     NetNeighbour(NetPair, NeighboursControl),
     NetRemote(Features, ConnId, NetIncomingMeta, Buffer),
     NetLocal(Features, NetIncomingMeta, Buffer),
+    /// Interface state of a bound address in a worker, first u16 is worker id
+    NetInterface(u16, InterfaceEvent),
+    /// Traffic of a connection which is counted by a worker since its last report, first u16 is worker id
+    ConnTraffic(u16, ConnId, FeatureTraffic),
+    /// Protocol version and capabilities which a worker supports, reported once when it is started
+    WorkerCapabilities(u16, PeerCapabilities),
     FeaturesControl(FeatureControlActor<UserData>, FeaturesControl),
     ServicesControl(ServiceControlActor<UserData>, ServiceId, SC),
     ServiceEvent(ServiceId, FeaturesEvent),
     ExtFeaturesEvent(UserData, FeaturesEvent),
     ExtServicesEvent(ServiceId, UserData, SE),
+    Tap(TapRecord),
 }
 
 #[derive(Debug, Clone)]
@@ -53,6 +169,14 @@ pub enum LogicEvent<UserData, SE, TW> {
 
     Pin(ConnId, NodeId, NetPair, SecureContext),
     UnPin(ConnId),
+    /// Negotiated link profile of a pinned connection
+    LinkProfile(ConnId, LinkProfile),
+    /// Keys of a new epoch for a pinned connection, None for the side which is not switched yet
+    Rekey(ConnId, Option<Box<dyn Encryptor>>, Option<Box<dyn Decryptor>>),
+    /// Capabilities which are agreed with the neighbour of a pinned connection
+    Capabilities(ConnId, Capabilities),
+    /// Probed stats of a pinned connection, used for selecting between paths to same node
+    ConnStats(ConnId, ConnectionStats),
     /// first bool is flag for broadcast or not
     Feature(bool, FeaturesToWorker<UserData>),
     Service(ServiceId, TW),
@@ -60,6 +184,10 @@ pub enum LogicEvent<UserData, SE, TW> {
     ExtFeaturesEvent(u16, UserData, FeaturesEvent),
     /// first u16 is worker id
     ExtServicesEvent(u16, ServiceId, UserData, SE),
+    /// Filter of the tap of all workers
+    Tap(Option<TapFilter>),
+    #[cfg(feature = "chaos")]
+    Chaos(data_plane::chaos::ChaosControl),
 }
 
 pub enum LogicEventDest {
@@ -73,6 +201,13 @@ impl<UserData, SE, TW> LogicEvent<UserData, SE, TW> {
         match self {
             LogicEvent::Pin(..) => LogicEventDest::Broadcast,
             LogicEvent::UnPin(..) => LogicEventDest::Broadcast,
+            LogicEvent::LinkProfile(..) => LogicEventDest::Broadcast,
+            LogicEvent::Rekey(..) => LogicEventDest::Broadcast,
+            LogicEvent::Capabilities(..) => LogicEventDest::Broadcast,
+            LogicEvent::ConnStats(..) => LogicEventDest::Broadcast,
+            LogicEvent::Tap(..) => LogicEventDest::Broadcast,
+            #[cfg(feature = "chaos")]
+            LogicEvent::Chaos(..) => LogicEventDest::Broadcast,
             LogicEvent::Service(..) => LogicEventDest::Broadcast,
             LogicEvent::Feature(true, ..) => LogicEventDest::Broadcast,
             LogicEvent::Feature(false, ..) => LogicEventDest::Any,
@@ -84,3 +219,22 @@ impl<UserData, SE, TW> LogicEvent<UserData, SE, TW> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::StickyExt;
+
+    #[test]
+    fn sticky_ext_pins_userdata_to_one_worker() {
+        let sticky = StickyExt::new(4);
+        for userdata in 0..100u64 {
+            let worker = sticky.worker_of(&userdata);
+            assert!(worker < 4);
+            assert_eq!(StickyExt::route(Some(&sticky), 0, &userdata), worker);
+            assert_eq!(StickyExt::route(Some(&sticky), 3, &userdata), worker);
+        }
+        let used: std::collections::HashSet<u16> = (0..100u64).map(|userdata| sticky.worker_of(&userdata)).collect();
+        assert_eq!(used.len(), 4);
+        assert_eq!(StickyExt::route(None, 3, &10u64), 3);
+    }
+}
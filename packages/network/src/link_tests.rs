@@ -0,0 +1,463 @@
+//! Conformance suite for custom link implementations.
+//!
+//! A link carries the udp-like packets of [`NetOutput`] from one node to [`NetInput`] of another, for example over
+//! LoRa, BLE or a custom tunnel. Integrations implement [`LinkUnderTest`] for a pair of endpoints, then call
+//! [`run_all`] or each check from their own tests:
+//!
+//! - [`ordering`]: packets in each direction are delivered once, in order and with the sender address
+//! - [`liveness`]: the link still delivers after a long idle period
+//! - [`errors`]: oversized packets are rejected and packets to unknown addresses never reach an endpoint
+//! - [`handshake`]: two sdn nodes connect over the link, and a node with an invalid key is rejected
+//!
+//! Time is driven by the suite with `now_ms`, so sans-io links can be tested without real sockets.
+//! The handshake check requires the link to carry neighbour control packets as-is, which are around 200 bytes.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use atm0s_sdn_identity::{NodeAddr, NodeAddrBuilder, NodeId, Protocol};
+use atm0s_sdn_router::shadow::ShadowRouterHistory;
+use parking_lot::Mutex;
+use rand::rngs::mock::StepRng;
+use sans_io_runtime::TaskSwitcherChild;
+
+use crate::{
+    base::{Capabilities, LatencyProfile, LinkProfile},
+    controller_plane::ControllerPlaneCfg,
+    data_plane::{DataPlaneCfg, NetInput, NetOutput, NetPair},
+    secure::{HandshakeBuilderXDA, StaticKeyAuthorization},
+    worker::{SdnWorker, SdnWorkerCfg, SdnWorkerInput, SdnWorkerOutput},
+    ExtIn,
+};
+
+/// Step of simulated time
+const TICK_MS: u64 = 10;
+/// Max time for a packet to be delivered
+pub const DELIVERY_TIMEOUT_MS: u64 = 2000;
+/// Idle time before checking liveness
+pub const IDLE_MS: u64 = 30_000;
+/// Max time for two nodes to connect
+pub const CONNECT_TIMEOUT_MS: u64 = 10_000;
+const ORDERING_PACKETS: u32 = 32;
+
+/// Endpoint of the link under test
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    A,
+    B,
+}
+
+impl Side {
+    fn other(&self) -> Self {
+        match self {
+            Side::A => Side::B,
+            Side::B => Side::A,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum LinkError {
+    #[error("packet is larger than mtu")]
+    TooLarge,
+    #[error("destination is unreachable")]
+    Unreachable,
+    #[error("link is closed")]
+    Closed,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ConformanceError {
+    #[error("send from {0:?} failed: {1}")]
+    SendFailed(Side, LinkError),
+    #[error("packet {seq} to {side:?} is not delivered in time")]
+    Lost { side: Side, seq: u32 },
+    #[error("packet to {side:?} is out of order, expected {expected} got {got}")]
+    Reordered { side: Side, expected: u32, got: u32 },
+    #[error("packet to {side:?} has wrong source {got}, expected {expected}")]
+    WrongSource { side: Side, expected: SocketAddr, got: SocketAddr },
+    #[error("unexpected packet delivered to {0:?}")]
+    Unexpected(Side),
+    #[error("oversized packet is accepted")]
+    OversizedAccepted,
+    #[error("nodes are not connected in time")]
+    NotConnected,
+    #[error("node with invalid key is connected")]
+    UnauthorizedConnected,
+}
+
+/// A pair of endpoints of a link implementation, addresses are the local addresses of each side
+pub trait LinkUnderTest {
+    fn addr(&self, side: Side) -> SocketAddr;
+    /// Max packet size, None if the link doesn't have a limit
+    fn mtu(&self) -> Option<usize> {
+        None
+    }
+    fn send(&mut self, now_ms: u64, side: Side, to: SocketAddr, data: &[u8]) -> Result<(), LinkError>;
+    /// Pop a received packet of the side with the sender address
+    fn recv(&mut self, now_ms: u64, side: Side) -> Option<(SocketAddr, Vec<u8>)>;
+}
+
+/// Run all checks in order
+pub fn run_all<L: LinkUnderTest>(link: &mut L, mut now_ms: u64) -> Result<(), ConformanceError> {
+    now_ms = ordering(link, now_ms)?;
+    now_ms = liveness(link, now_ms)?;
+    now_ms = errors(link, now_ms)?;
+    handshake(link, now_ms)?;
+    Ok(())
+}
+
+/// Check that packets in both directions are delivered once and in order, return the time after the check
+pub fn ordering<L: LinkUnderTest>(link: &mut L, mut now_ms: u64) -> Result<u64, ConformanceError> {
+    for side in [Side::A, Side::B] {
+        let to = link.addr(side.other());
+        for seq in 0..ORDERING_PACKETS {
+            link.send(now_ms, side, to, &test_packet(seq, link.mtu())).map_err(|e| ConformanceError::SendFailed(side, e))?;
+        }
+        for seq in 0..ORDERING_PACKETS {
+            let (from, data, at) = wait_packet(link, now_ms, side.other()).ok_or(ConformanceError::Lost { side: side.other(), seq })?;
+            now_ms = at;
+            check_packet(link, side, seq, from, &data)?;
+        }
+    }
+    Ok(now_ms)
+}
+
+/// Check that the link still delivers after a long idle period, return the time after the check
+pub fn liveness<L: LinkUnderTest>(link: &mut L, mut now_ms: u64) -> Result<u64, ConformanceError> {
+    let idle_end = now_ms + IDLE_MS;
+    while now_ms < idle_end {
+        now_ms += TICK_MS;
+        for side in [Side::A, Side::B] {
+            if link.recv(now_ms, side).is_some() {
+                return Err(ConformanceError::Unexpected(side));
+            }
+        }
+    }
+    for side in [Side::A, Side::B] {
+        link.send(now_ms, side, link.addr(side.other()), &test_packet(0, link.mtu()))
+            .map_err(|e| ConformanceError::SendFailed(side, e))?;
+        let (from, data, at) = wait_packet(link, now_ms, side.other()).ok_or(ConformanceError::Lost { side: side.other(), seq: 0 })?;
+        now_ms = at;
+        check_packet(link, side, 0, from, &data)?;
+    }
+    Ok(now_ms)
+}
+
+/// Check that oversized packets are rejected and packets to unknown addresses are not delivered, return the time after the check
+pub fn errors<L: LinkUnderTest>(link: &mut L, mut now_ms: u64) -> Result<u64, ConformanceError> {
+    if let Some(mtu) = link.mtu() {
+        match link.send(now_ms, Side::A, link.addr(Side::B), &vec![0; mtu + 1]) {
+            Err(LinkError::TooLarge) => {}
+            Ok(_) => return Err(ConformanceError::OversizedAccepted),
+            Err(e) => return Err(ConformanceError::SendFailed(Side::A, e)),
+        }
+    }
+
+    let unknown = unknown_addr(link);
+    match link.send(now_ms, Side::A, unknown, &test_packet(0, link.mtu())) {
+        // link can either reject or silently drop it like udp
+        Ok(_) | Err(LinkError::Unreachable) => {}
+        Err(e) => return Err(ConformanceError::SendFailed(Side::A, e)),
+    }
+    let deadline = now_ms + DELIVERY_TIMEOUT_MS;
+    while now_ms < deadline {
+        now_ms += TICK_MS;
+        for side in [Side::A, Side::B] {
+            if link.recv(now_ms, side).is_some() {
+                return Err(ConformanceError::Unexpected(side));
+            }
+        }
+    }
+    Ok(now_ms)
+}
+
+/// Check that two sdn nodes can connect over the link and a node with invalid key cannot, return the time after the check
+pub fn handshake<L: LinkUnderTest>(link: &mut L, mut now_ms: u64) -> Result<u64, ConformanceError> {
+    let (connected, at) = connect_nodes(link, now_ms, "link-test-key", "link-test-key");
+    now_ms = at;
+    if !connected {
+        return Err(ConformanceError::NotConnected);
+    }
+
+    let (connected, at) = connect_nodes(link, now_ms, "link-test-key", "invalid-key");
+    if connected {
+        return Err(ConformanceError::UnauthorizedConnected);
+    }
+    Ok(at)
+}
+
+fn test_packet(seq: u32, mtu: Option<usize>) -> Vec<u8> {
+    let mut data = seq.to_be_bytes().to_vec();
+    data.resize(mtu.unwrap_or(64).clamp(4, 64), seq as u8);
+    data
+}
+
+fn check_packet<L: LinkUnderTest>(link: &L, from_side: Side, seq: u32, from: SocketAddr, data: &[u8]) -> Result<(), ConformanceError> {
+    let side = from_side.other();
+    let expected = link.addr(from_side);
+    if from != expected {
+        return Err(ConformanceError::WrongSource { side, expected, got: from });
+    }
+    let got = data.get(0..4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]])).ok_or(ConformanceError::Unexpected(side))?;
+    if got != seq {
+        return Err(ConformanceError::Reordered { side, expected: seq, got });
+    }
+    Ok(())
+}
+
+fn wait_packet<L: LinkUnderTest>(link: &mut L, mut now_ms: u64, side: Side) -> Option<(SocketAddr, Vec<u8>, u64)> {
+    let deadline = now_ms + DELIVERY_TIMEOUT_MS;
+    loop {
+        if let Some((from, data)) = link.recv(now_ms, side) {
+            return Some((from, data, now_ms));
+        }
+        if now_ms >= deadline {
+            return None;
+        }
+        now_ms += TICK_MS;
+    }
+}
+
+fn unknown_addr<L: LinkUnderTest>(link: &L) -> SocketAddr {
+    let mut addr = link.addr(Side::B);
+    loop {
+        addr.set_port(addr.port().wrapping_add(1));
+        if addr != link.addr(Side::A) && addr != link.addr(Side::B) {
+            return addr;
+        }
+    }
+}
+
+fn node_addr(node: NodeId, addr: SocketAddr) -> NodeAddr {
+    let mut builder = NodeAddrBuilder::new(node);
+    match addr.ip() {
+        IpAddr::V4(ip) => builder.add_protocol(Protocol::Ip4(ip)),
+        IpAddr::V6(ip) => builder.add_protocol(Protocol::Ip6(ip)),
+    }
+    builder.add_protocol(Protocol::Udp(addr.port()));
+    builder.addr()
+}
+
+type BroadcastKey = (Option<NodeId>, u8, u16);
+
+#[derive(Default)]
+struct History {
+    received: Mutex<(HashSet<BroadcastKey>, VecDeque<BroadcastKey>)>,
+}
+
+impl ShadowRouterHistory for History {
+    fn already_received_broadcast(&self, from: Option<NodeId>, service: u8, seq: u16) -> bool {
+        let mut received = self.received.lock();
+        if !received.0.insert((from, service, seq)) {
+            return true;
+        }
+        received.1.push_back((from, service, seq));
+        if received.1.len() > 1000 {
+            if let Some(old) = received.1.pop_front() {
+                received.0.remove(&old);
+            }
+        }
+        false
+    }
+
+    fn set_ts(&self, _now: u64) {}
+}
+
+type TestWorker = SdnWorker<(), (), (), (), ()>;
+
+fn build_worker(node_id: NodeId, session: u64, addr: SocketAddr, key: &str) -> Box<TestWorker> {
+    let history = Arc::new(History::default());
+    Box::new(SdnWorker::new(SdnWorkerCfg {
+        node_id,
+        tick_ms: 1,
+        controller: Some(ControllerPlaneCfg {
+            session,
+            bind_addrs: vec![addr],
+            services: vec![],
+            authorization: Arc::new(StaticKeyAuthorization::new(key)),
+            handshake_builder: Arc::new(HandshakeBuilderXDA),
+            random: Box::new(StepRng::new(node_id as u64 * 1000, 5)),
+            history: history.clone(),
+            profile: LatencyProfile::default(),
+            link: LinkProfile::Standard,
+            dht_kv_storage: None,
+            channel_authorizer: None,
+            observer: false,
+            capabilities: Capabilities::SUPPORTED,
+            compression: None,
+            rekey_interval_ms: None,
+            neighbour_policy: Default::default(),
+            sticky_ext: None,
+            routing_policy: Default::default(),
+            checkpoint: None,
+            event_sinks: vec![],
+        }),
+        standby: None,
+        replication: None,
+        data: DataPlaneCfg {
+            worker_id: 0,
+            services: vec![],
+            history,
+            scheduler: None,
+            bandwidth_limit_kbps: None,
+            service_shaping: vec![],
+            multipath: None,
+            incoming_route: false,
+            sticky_ext: None,
+            fragment: Default::default(),
+        },
+    }))
+}
+
+/// Pump a worker outputs, packets are sent over the link
+fn pump<L: LinkUnderTest>(link: &mut L, worker: &mut TestWorker, side: Side, now_ms: u64) {
+    while let Some(out) = worker.pop_output(now_ms) {
+        let (pairs, data) = match out {
+            SdnWorkerOutput::Net(NetOutput::UdpPacket(pair, data, _)) => (vec![pair], data),
+            SdnWorkerOutput::Net(NetOutput::UdpPackets(pairs, data, _)) => (pairs, data),
+            SdnWorkerOutput::Bus(bus) => {
+                worker.on_event(now_ms, SdnWorkerInput::Bus(bus));
+                continue;
+            }
+            _ => continue,
+        };
+        for pair in pairs {
+            if let Err(e) = link.send(now_ms, side, pair.remote, &data) {
+                log::warn!("[LinkTests] send {} bytes from {:?} to {} error {e}", data.len(), side, pair.remote);
+            }
+        }
+    }
+}
+
+fn connect_nodes<L: LinkUnderTest>(link: &mut L, mut now_ms: u64, key_a: &str, key_b: &str) -> (bool, u64) {
+    let (addr_a, addr_b) = (link.addr(Side::A), link.addr(Side::B));
+    // new sessions for each run, so nodes are not confused by packets of previous runs
+    let session = now_ms;
+    let mut workers = [(Side::A, build_worker(1, session, addr_a, key_a)), (Side::B, build_worker(2, session + 1, addr_b, key_b))];
+    workers[0].1.on_event(now_ms, SdnWorkerInput::Ext(ExtIn::ConnectTo(node_addr(2, addr_b))));
+
+    let deadline = now_ms + CONNECT_TIMEOUT_MS;
+    let mut connected = false;
+    while now_ms < deadline && !connected {
+        now_ms += TICK_MS;
+        for (side, worker) in workers.iter_mut() {
+            worker.on_tick(now_ms);
+            while let Some((from, data)) = link.recv(now_ms, *side) {
+                let pair = NetPair::new(from, link.addr(*side));
+                worker.on_event(now_ms, SdnWorkerInput::Net(NetInput::UdpPacket(pair, data.into())));
+            }
+            pump(link, worker, *side, now_ms);
+        }
+        connected = workers.iter().all(|(_, w)| w.controller_metrics().map_or(false, |m| m.connections > 0));
+    }
+
+    for (side, worker) in workers.iter_mut() {
+        worker.on_shutdown(now_ms);
+        pump(link, worker, *side, now_ms);
+    }
+    // drain remaining packets, so next checks start with an empty link
+    let drain_end = now_ms + DELIVERY_TIMEOUT_MS;
+    while now_ms < drain_end {
+        now_ms += TICK_MS;
+        for side in [Side::A, Side::B] {
+            while link.recv(now_ms, side).is_some() {}
+        }
+    }
+    (connected, now_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, net::SocketAddr};
+
+    use super::{run_all, ConformanceError, LinkError, LinkUnderTest, Side};
+
+    /// In-memory link with fixed latency, which is the reference implementation for the suite
+    struct MemoryLink {
+        addrs: [SocketAddr; 2],
+        mtu: Option<usize>,
+        latency_ms: u64,
+        queues: [VecDeque<(u64, SocketAddr, Vec<u8>)>; 2],
+        /// Reverse the order of queued packets, for checking the suite detects broken links
+        reorder: bool,
+    }
+
+    impl MemoryLink {
+        fn new(mtu: Option<usize>) -> Self {
+            Self {
+                addrs: ["127.0.0.1:10001".parse().expect("Should parse"), "127.0.0.1:10002".parse().expect("Should parse")],
+                mtu,
+                latency_ms: 20,
+                queues: [VecDeque::new(), VecDeque::new()],
+                reorder: false,
+            }
+        }
+
+        fn index(side: Side) -> usize {
+            match side {
+                Side::A => 0,
+                Side::B => 1,
+            }
+        }
+    }
+
+    impl LinkUnderTest for MemoryLink {
+        fn addr(&self, side: Side) -> SocketAddr {
+            self.addrs[Self::index(side)]
+        }
+
+        fn mtu(&self) -> Option<usize> {
+            self.mtu
+        }
+
+        fn send(&mut self, now_ms: u64, side: Side, to: SocketAddr, data: &[u8]) -> Result<(), LinkError> {
+            if self.mtu.map_or(false, |mtu| data.len() > mtu) {
+                return Err(LinkError::TooLarge);
+            }
+            let dest = self.addrs.iter().position(|a| *a == to).ok_or(LinkError::Unreachable)?;
+            let packet = (now_ms + self.latency_ms, self.addr(side), data.to_vec());
+            if self.reorder {
+                self.queues[dest].push_front(packet);
+            } else {
+                self.queues[dest].push_back(packet);
+            }
+            Ok(())
+        }
+
+        fn recv(&mut self, now_ms: u64, side: Side) -> Option<(SocketAddr, Vec<u8>)> {
+            let queue = &mut self.queues[Self::index(side)];
+            if queue.front()?.0 <= now_ms {
+                queue.pop_front().map(|(_, from, data)| (from, data))
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn memory_link_conformance() {
+        assert_eq!(run_all(&mut MemoryLink::new(None), 0), Ok(()));
+        assert_eq!(run_all(&mut MemoryLink::new(Some(1400)), 0), Ok(()));
+    }
+
+    #[test]
+    fn detect_broken_links() {
+        let mut link = MemoryLink::new(None);
+        link.reorder = true;
+        assert_eq!(
+            super::ordering(&mut link, 0),
+            Err(ConformanceError::Reordered {
+                side: Side::B,
+                expected: 0,
+                got: super::ORDERING_PACKETS - 1
+            })
+        );
+
+        // link which is too small for handshake
+        assert_eq!(super::handshake(&mut MemoryLink::new(Some(32)), 0), Err(ConformanceError::NotConnected));
+    }
+}
@@ -0,0 +1,95 @@
+//! Counters and histograms which are shared by controller and data plane metrics.
+
+/// Upper bounds of rtt buckets in milliseconds, samples which are bigger than the last bound are only counted in total
+pub const RTT_BUCKETS_MS: [u32; 9] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficCounter {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+impl TrafficCounter {
+    pub fn add(&mut self, packets: usize, bytes: usize) {
+        self.packets += packets as u64;
+        self.bytes += bytes as u64;
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.packets += other.packets;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Incoming and outgoing traffic of a feature or a connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeatureTraffic {
+    pub rx: TrafficCounter,
+    pub tx: TrafficCounter,
+}
+
+/// Histogram of rtt samples, which is cumulative since started
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RttHistogram {
+    buckets: [u64; RTT_BUCKETS_MS.len()],
+    count: u64,
+    sum_ms: u64,
+}
+
+impl RttHistogram {
+    pub fn observe(&mut self, rtt_ms: u32) {
+        if let Some(slot) = RTT_BUCKETS_MS.iter().position(|bound| rtt_ms <= *bound) {
+            self.buckets[slot] += 1;
+        }
+        self.count += 1;
+        self.sum_ms += rtt_ms as u64;
+    }
+
+    /// Pairs of (upper bound, samples which are less than or equal the bound), like Prometheus `le` buckets
+    pub fn cumulative_buckets(&self) -> Vec<(u32, u64)> {
+        let mut acc = 0;
+        RTT_BUCKETS_MS
+            .iter()
+            .zip(self.buckets.iter())
+            .map(|(bound, count)| {
+                acc += count;
+                (*bound, acc)
+            })
+            .collect()
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum_ms(&self) -> u64 {
+        self.sum_ms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RttHistogram, TrafficCounter};
+
+    #[test]
+    fn rtt_histogram_cumulative_buckets() {
+        let mut histogram = RttHistogram::default();
+        for rtt in [3, 5, 40, 3000] {
+            histogram.observe(rtt);
+        }
+        let buckets = histogram.cumulative_buckets();
+        assert_eq!(buckets[0], (5, 2));
+        assert_eq!(buckets[3], (50, 3));
+        assert_eq!(buckets.last(), Some(&(2500, 3)));
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.sum_ms(), 3048);
+    }
+
+    #[test]
+    fn merge_traffic_counter() {
+        let mut counter = TrafficCounter::default();
+        counter.add(2, 100);
+        counter.merge(&TrafficCounter { packets: 1, bytes: 20 });
+        assert_eq!(counter, TrafficCounter { packets: 3, bytes: 120 });
+    }
+}
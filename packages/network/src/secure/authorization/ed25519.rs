@@ -0,0 +1,308 @@
+//! Public-key authorization, each node signs control messages with its own Ed25519 keypair.
+//!
+//! The signature of a message is `[public key 32][ed25519 signature 64][certificate 64]`, the certificate is optional.
+//! Receivers verify the signature, then ask a [`CertificateProvider`] whether the public key is bound to the claimed NodeId.
+//! Revoking a node in the provider rejects its next control messages, so existing connections are timed out too.
+//!
+//! The NodeId is bound to the key during the handshake: `ConnectRequest` and `ConnectResponse` carry the ephemeral
+//! handshake keys and are signed like all neighbour control messages, then they are validated against the `from` NodeId.
+//! So a connection is only established with the node whose key is accepted by the provider, and a node can't claim
+//! another NodeId without the key of it.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use atm0s_sdn_identity::NodeId;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use parking_lot::RwLock;
+use rand::{rngs::OsRng, RngCore};
+
+use crate::base::Authorization;
+
+pub const ED25519_PUBLIC_KEY_LEN: usize = 32;
+pub const ED25519_SIGNATURE_LEN: usize = 64;
+
+const CERTIFICATE_CONTEXT: &[u8] = b"atm0s-sdn-node-certificate";
+
+pub type Ed25519PublicKey = [u8; ED25519_PUBLIC_KEY_LEN];
+pub type Ed25519Signature = [u8; ED25519_SIGNATURE_LEN];
+
+fn certificate_msg(node: NodeId, public_key: &Ed25519PublicKey) -> Vec<u8> {
+    [CERTIFICATE_CONTEXT, &node.to_be_bytes(), public_key].concat()
+}
+
+/// Ed25519 keypair (RFC 8032) of a node or of a certificate issuer
+#[derive(Clone)]
+pub struct Ed25519Keypair {
+    key: SigningKey,
+}
+
+impl Ed25519Keypair {
+    /// Restore the keypair from its 32 bytes secret seed
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        Self { key: SigningKey::from_bytes(&seed) }
+    }
+
+    pub fn generate() -> Self {
+        let mut seed = [0; 32];
+        OsRng.fill_bytes(&mut seed);
+        Self::from_seed(seed)
+    }
+
+    /// Secret seed, which should be stored for keeping the identity after restart
+    pub fn seed(&self) -> [u8; 32] {
+        self.key.to_bytes()
+    }
+
+    pub fn public_key(&self) -> Ed25519PublicKey {
+        self.key.verifying_key().to_bytes()
+    }
+
+    pub fn sign(&self, msg: &[u8]) -> Ed25519Signature {
+        self.key.sign(msg).to_bytes()
+    }
+
+    /// Issue a certificate which binds the public key to the node, it is verified by [`CaCertificateProvider`] with the public key of this issuer
+    pub fn issue_certificate(&self, node: NodeId, public_key: &Ed25519PublicKey) -> Ed25519Signature {
+        self.sign(&certificate_msg(node, public_key))
+    }
+}
+
+/// Verify an Ed25519 signature, keys of small order and non-canonical signatures are rejected
+pub fn ed25519_verify(public_key: &Ed25519PublicKey, msg: &[u8], signature: &[u8]) -> bool {
+    let Ok(signature) = Signature::from_slice(signature) else {
+        return false;
+    };
+    let Ok(key) = VerifyingKey::from_bytes(public_key) else {
+        return false;
+    };
+    key.verify_strict(msg, &signature).is_ok()
+}
+
+/// Decide which public keys are allowed to act as which nodes
+pub trait CertificateProvider: Send + Sync {
+    /// `certificate` is attached by the sender if it is configured with one
+    fn verify(&self, node: NodeId, public_key: &Ed25519PublicKey, certificate: Option<&[u8]>) -> bool;
+}
+
+/// Static binding between nodes and their public keys, which is managed by the operator
+#[derive(Default)]
+pub struct AllowlistProvider {
+    nodes: RwLock<HashMap<NodeId, Ed25519PublicKey>>,
+}
+
+impl AllowlistProvider {
+    pub fn new(nodes: impl IntoIterator<Item = (NodeId, Ed25519PublicKey)>) -> Self {
+        Self {
+            nodes: RwLock::new(nodes.into_iter().collect()),
+        }
+    }
+
+    /// Allow the node with the public key, it replaces the previous key of the node
+    pub fn allow(&self, node: NodeId, public_key: Ed25519PublicKey) {
+        self.nodes.write().insert(node, public_key);
+    }
+
+    /// Remove the node, return true if it was allowed
+    pub fn revoke(&self, node: NodeId) -> bool {
+        self.nodes.write().remove(&node).is_some()
+    }
+}
+
+impl CertificateProvider for AllowlistProvider {
+    fn verify(&self, node: NodeId, public_key: &Ed25519PublicKey, _certificate: Option<&[u8]>) -> bool {
+        self.nodes.read().get(&node) == Some(public_key)
+    }
+}
+
+/// Accept nodes with a certificate which is issued by a trusted issuer, new nodes can join without updating other nodes
+pub struct CaCertificateProvider {
+    issuer: Ed25519PublicKey,
+    revoked_nodes: RwLock<HashSet<NodeId>>,
+    revoked_keys: RwLock<HashSet<Ed25519PublicKey>>,
+}
+
+impl CaCertificateProvider {
+    pub fn new(issuer: Ed25519PublicKey) -> Self {
+        Self {
+            issuer,
+            revoked_nodes: Default::default(),
+            revoked_keys: Default::default(),
+        }
+    }
+
+    /// Reject all certificates of the node
+    pub fn revoke_node(&self, node: NodeId) {
+        self.revoked_nodes.write().insert(node);
+    }
+
+    /// Reject a leaked key, the node can join again with a certificate for a new key
+    pub fn revoke_key(&self, public_key: Ed25519PublicKey) {
+        self.revoked_keys.write().insert(public_key);
+    }
+}
+
+impl CertificateProvider for CaCertificateProvider {
+    fn verify(&self, node: NodeId, public_key: &Ed25519PublicKey, certificate: Option<&[u8]>) -> bool {
+        let Some(certificate) = certificate else {
+            return false;
+        };
+        if self.revoked_nodes.read().contains(&node) || self.revoked_keys.read().contains(public_key) {
+            return false;
+        }
+        ed25519_verify(&self.issuer, &certificate_msg(node, public_key), certificate)
+    }
+}
+
+pub struct Ed25519Authorization {
+    keypair: Ed25519Keypair,
+    certificate: Option<Ed25519Signature>,
+    provider: Arc<dyn CertificateProvider>,
+}
+
+impl Ed25519Authorization {
+    pub fn new(keypair: Ed25519Keypair, provider: Arc<dyn CertificateProvider>) -> Self {
+        Self { keypair, certificate: None, provider }
+    }
+
+    /// Attach the certificate of this node to signatures, which is required by [`CaCertificateProvider`]
+    pub fn with_certificate(mut self, certificate: Ed25519Signature) -> Self {
+        self.certificate = Some(certificate);
+        self
+    }
+}
+
+impl Authorization for Ed25519Authorization {
+    /// Generate signature with the public key and the certificate of this node
+    fn sign(&self, msg: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(ED25519_PUBLIC_KEY_LEN + ED25519_SIGNATURE_LEN * 2);
+        out.extend_from_slice(&self.keypair.public_key());
+        out.extend_from_slice(&self.keypair.sign(msg));
+        if let Some(certificate) = &self.certificate {
+            out.extend_from_slice(certificate);
+        }
+        out
+    }
+
+    /// Validate signature, then check that the public key is bound to the node
+    fn validate(&self, node_id: NodeId, msg: &[u8], sign: &[u8]) -> Option<()> {
+        let certificate = match sign.len() {
+            len if len == ED25519_PUBLIC_KEY_LEN + ED25519_SIGNATURE_LEN => None,
+            len if len == ED25519_PUBLIC_KEY_LEN + ED25519_SIGNATURE_LEN * 2 => Some(&sign[ED25519_PUBLIC_KEY_LEN + ED25519_SIGNATURE_LEN..]),
+            _ => return None,
+        };
+        let public_key: Ed25519PublicKey = sign[..ED25519_PUBLIC_KEY_LEN].try_into().ok()?;
+        let signature = &sign[ED25519_PUBLIC_KEY_LEN..ED25519_PUBLIC_KEY_LEN + ED25519_SIGNATURE_LEN];
+        if !ed25519_verify(&public_key, msg, signature) {
+            return None;
+        }
+        self.provider.verify(node_id, &public_key, certificate).then_some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::base::{Authorization, NeighboursControl, NeighboursControlCmds};
+
+    use super::*;
+
+    fn hex<const N: usize>(value: &str) -> [u8; N] {
+        let mut out = [0; N];
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).expect("Should be hex");
+        }
+        out
+    }
+
+    #[test]
+    fn rfc8032_test_vectors() {
+        let keypair = Ed25519Keypair::from_seed(hex("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60"));
+        assert_eq!(keypair.public_key(), hex::<32>("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a"));
+        let signature = keypair.sign(b"");
+        assert_eq!(
+            signature,
+            hex::<64>("e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b")
+        );
+        assert!(ed25519_verify(&keypair.public_key(), b"", &signature));
+
+        let keypair = Ed25519Keypair::from_seed(hex("4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb"));
+        assert_eq!(keypair.public_key(), hex::<32>("3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c"));
+        let signature = keypair.sign(&[0x72]);
+        assert_eq!(
+            signature,
+            hex::<64>("92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00")
+        );
+        assert!(ed25519_verify(&keypair.public_key(), &[0x72], &signature));
+        assert!(!ed25519_verify(&keypair.public_key(), &[0x73], &signature));
+        assert!(!ed25519_verify(&keypair.public_key(), &[0x72], &signature[1..]));
+    }
+
+    #[test]
+    fn allowlist_binds_and_revokes_nodes() {
+        let node1 = Ed25519Keypair::generate();
+        let node2 = Ed25519Keypair::generate();
+        let provider = Arc::new(AllowlistProvider::new([(1, node1.public_key()), (2, node2.public_key())]));
+        let auth1 = Ed25519Authorization::new(node1, provider.clone());
+        let auth2 = Ed25519Authorization::new(node2, provider.clone());
+
+        let msg = b"hello";
+        let sign = auth2.sign(msg);
+        assert_eq!(auth1.validate(2, msg, &sign), Some(()));
+        // key of node2 is not bound to node3 and signature doesn't match other message
+        assert_eq!(auth1.validate(3, msg, &sign), None);
+        assert_eq!(auth1.validate(2, b"other", &sign), None);
+
+        assert!(provider.revoke(2));
+        assert_eq!(auth1.validate(2, msg, &sign), None);
+    }
+
+    #[test]
+    fn ca_certificate_binds_and_revokes_nodes() {
+        let issuer = Ed25519Keypair::generate();
+        let provider = Arc::new(CaCertificateProvider::new(issuer.public_key()));
+        let node1 = Ed25519Keypair::generate();
+        let node2 = Ed25519Keypair::generate();
+        let cert2 = issuer.issue_certificate(2, &node2.public_key());
+        let auth1 = Ed25519Authorization::new(node1, provider.clone());
+        let auth2 = Ed25519Authorization::new(node2.clone(), provider.clone()).with_certificate(cert2);
+
+        let msg = b"hello";
+        let sign = auth2.sign(msg);
+        assert_eq!(auth1.validate(2, msg, &sign), Some(()));
+        assert_eq!(auth1.validate(3, msg, &sign), None);
+        // without certificate or with a self-issued one
+        assert_eq!(auth1.validate(2, msg, &Ed25519Authorization::new(node2.clone(), provider.clone()).sign(msg)), None);
+        let self_issued = Ed25519Authorization::new(node2.clone(), provider.clone()).with_certificate(node2.issue_certificate(2, &node2.public_key()));
+        assert_eq!(auth1.validate(2, msg, &self_issued.sign(msg)), None);
+
+        provider.revoke_key(node2.public_key());
+        assert_eq!(auth1.validate(2, msg, &sign), None);
+    }
+
+    #[test]
+    fn handshake_bound_to_node_key() {
+        let node1 = Ed25519Keypair::generate();
+        let node2 = Ed25519Keypair::generate();
+        let provider = Arc::new(AllowlistProvider::new([(1, node1.public_key()), (2, node2.public_key())]));
+        let auth1 = Ed25519Authorization::new(node1, provider.clone());
+        let auth2 = Ed25519Authorization::new(node2, provider);
+
+        let cmd = NeighboursControlCmds::ConnectRequest {
+            to: 1,
+            session: 1000,
+            handshake: vec![1, 2, 3],
+        };
+        let request = NeighboursControl::build(0, 2, cmd.clone(), &auth2);
+        assert_eq!(request.validate(0, &auth1), Ok(cmd.clone()));
+
+        // node2 can't connect as node3 or take over the handshake of node1
+        assert_eq!(NeighboursControl::build(0, 3, cmd.clone(), &auth2).validate(0, &auth1), Err(()));
+        let mut spoofed = request.clone();
+        spoofed.from = 1;
+        assert_eq!(spoofed.validate(0, &auth1), Err(()));
+    }
+}
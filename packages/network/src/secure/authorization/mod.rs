@@ -1,2 +1,4 @@
+mod ed25519;
 mod static_key;
+pub use ed25519::*;
 pub use static_key::StaticKeyAuthorization;
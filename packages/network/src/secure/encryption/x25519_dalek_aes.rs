@@ -10,9 +10,13 @@ use aes_gcm::{
 use rand::rngs::OsRng;
 use x25519_dalek::{EphemeralSecret, PublicKey};
 
-use crate::base::{Buffer as BufferMut, DecryptionError, Decryptor, EncryptionError, Encryptor, HandshakeBuilder, HandshakeError, HandshakeRequester, HandshakeResponder};
+use crate::base::{Buffer as BufferMut, DecryptionError, Decryptor, EncryptionError, Encryptor, HandshakeBuilder, HandshakeError, HandshakeRequester, HandshakeResponder, SecureInfo};
 
 const MSG_TIMEOUT_MS: u64 = 5000; // after 5 seconds message is considered expired
+const SECURE_INFO: SecureInfo = SecureInfo {
+    handshake: "xda",
+    cipher: "x25519-aes256gcm",
+};
 
 pub struct HandshakeBuilderXDA;
 
@@ -37,6 +41,10 @@ impl Default for HandshakeRequesterXDA {
 }
 
 impl HandshakeRequester for HandshakeRequesterXDA {
+    fn secure_info(&self) -> SecureInfo {
+        SECURE_INFO
+    }
+
     fn create_public_request(&self) -> Result<Vec<u8>, HandshakeError> {
         let key = self.key.as_ref().ok_or(HandshakeError::InvalidState)?;
         Ok(PublicKey::from(key).as_bytes().to_vec())
@@ -61,6 +69,10 @@ impl Default for HandshakeResponderXDA {
 }
 
 impl HandshakeResponder for HandshakeResponderXDA {
+    fn secure_info(&self) -> SecureInfo {
+        SECURE_INFO
+    }
+
     fn process_public_request(&mut self, request: &[u8]) -> Result<(Box<dyn Encryptor>, Box<dyn Decryptor>, Vec<u8>), HandshakeError> {
         let buf: [u8; 32] = request.try_into().map_err(|_| HandshakeError::InvalidPublicKey)?;
         let key = self.key.take().ok_or(HandshakeError::InvalidState)?;
@@ -191,7 +203,7 @@ impl<'a> AsMut<[u8]> for BufferMut2<'a> {
 mod tests {
     use std::ops::Deref;
 
-    use crate::base::{Buffer as BufferMut, HandshakeRequester, HandshakeResponder};
+    use crate::base::{Buffer as BufferMut, HandshakeRequester, HandshakeResponder, SecureContext, REKEY_GRACE_MS};
 
     use super::{HandshakeRequesterXDA, HandshakeResponderXDA};
 
@@ -273,4 +285,34 @@ mod tests {
             assert_eq!(buf.deref(), msg);
         }
     }
+
+    #[test]
+    fn rekey_accepts_old_key_in_grace_period() {
+        let mut client = HandshakeRequesterXDA::default();
+        let mut server = HandshakeResponderXDA::default();
+        let (s_encrypt, _s_decrypt, res) = server.process_public_request(client.create_public_request().expect("").as_slice()).expect("Should ok");
+        let (c_encrypt, c_decrypt) = client.process_public_response(res.as_slice()).expect("Should ok");
+        let mut old_encrypt = s_encrypt.clone_box();
+        let mut c_secure = SecureContext::new(c_encrypt, c_decrypt);
+
+        let mut client = HandshakeRequesterXDA::default();
+        let mut server = HandshakeResponderXDA::default();
+        let (mut s_encrypt, _s_decrypt, res) = server.process_public_request(client.create_public_request().expect("").as_slice()).expect("Should ok");
+        let (c_encrypt, c_decrypt) = client.process_public_response(res.as_slice()).expect("Should ok");
+        c_secure.rekey(1000, Some(c_encrypt), Some(c_decrypt));
+
+        let mut buf1 = BufferMut::build(&[1, 2, 3], 0, 1000);
+        s_encrypt.encrypt(1000, &mut buf1).expect("Should ok");
+        c_secure.decrypt(1000, &mut buf1).expect("Should ok");
+        assert_eq!(buf1.deref(), &[1, 2, 3]);
+
+        let mut buf2 = BufferMut::build(&[4, 5, 6], 0, 1000);
+        old_encrypt.encrypt(1000, &mut buf2).expect("Should ok");
+        c_secure.decrypt(1000, &mut buf2).expect("Should ok");
+        assert_eq!(buf2.deref(), &[4, 5, 6]);
+
+        let mut buf3 = BufferMut::build(&[7, 8, 9], 0, 1000);
+        old_encrypt.encrypt(1000 + REKEY_GRACE_MS, &mut buf3).expect("Should ok");
+        assert!(c_secure.decrypt(1000 + REKEY_GRACE_MS, &mut buf3).is_err());
+    }
 }
@@ -0,0 +1,496 @@
+//! Keep the node connected to the mesh after the startup seeds are dialed.
+//!
+//! The service learns addresses of peers which this node connected to, and persists them with a [`PeerStore`]. When all
+//! connections are lost it rotates through known peers, static seeds and seeds of [`SeedSource`]s (like DNS seed lists),
+//! dialing one candidate per attempt with exponential backoff until a connection is established again.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use atm0s_sdn_identity::{NodeAddr, NodeAddrBuilder, NodeId, Protocol};
+use sans_io_runtime::collections::DynamicDeque;
+
+use crate::{
+    base::{ConnectionEvent, Service, ServiceBuilder, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput, ServiceWorker, ServiceWorkerCtx, ServiceWorkerInput, ServiceWorkerOutput},
+    features::{neighbours::Control as NeighbourControl, FeaturesControl, FeaturesEvent},
+};
+
+pub const SERVICE_ID: u8 = 3;
+pub const SERVICE_NAME: &str = "bootstrap";
+
+/// Delay of the first attempt after all connections are lost, it is doubled after each attempt
+pub const BOOTSTRAP_BACKOFF_MIN_MS: u64 = 1_000;
+pub const BOOTSTRAP_BACKOFF_MAX_MS: u64 = 60_000;
+/// Known-good peers which are kept, the least recently connected ones are dropped
+pub const BOOTSTRAP_MAX_PEERS: usize = 64;
+/// Interval for polling seed sources
+const SOURCE_POLL_MS: u64 = 5_000;
+/// Min interval between two saves of known-good peers
+const SAVE_INTERVAL_MS: u64 = 30_000;
+
+/// Persistent list of known-good peers, it is loaded when the service is created
+pub trait PeerStore: Send + Sync {
+    fn load(&self) -> Vec<NodeAddr>;
+    fn save(&self, peers: &[NodeAddr]);
+}
+
+/// Store peers in a text file, one node address per line
+pub struct FilePeerStore {
+    path: PathBuf,
+}
+
+impl FilePeerStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self { path: path.as_ref().to_path_buf() }
+    }
+}
+
+impl PeerStore for FilePeerStore {
+    fn load(&self) -> Vec<NodeAddr> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) => {
+                log::info!("[FilePeerStore] no peers loaded from {}: {e}", self.path.display());
+                return vec![];
+            }
+        };
+        BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|line| {
+                let addr = line.trim().parse::<NodeAddr>();
+                if addr.is_err() {
+                    log::warn!("[FilePeerStore] skip invalid peer {line}");
+                }
+                addr.ok()
+            })
+            .collect()
+    }
+
+    fn save(&self, peers: &[NodeAddr]) {
+        // write to a temporary file then rename, so a crash while writing keeps the previous list
+        let tmp = self.path.with_extension("tmp");
+        let res = File::create(&tmp)
+            .and_then(|mut file| {
+                for peer in peers {
+                    writeln!(file, "{peer}")?;
+                }
+                file.sync_all()
+            })
+            .and_then(|_| std::fs::rename(&tmp, &self.path));
+        if let Err(e) = res {
+            log::error!("[FilePeerStore] save {} peers to {} error {e}", peers.len(), self.path.display());
+        }
+    }
+}
+
+/// Source of seeds which changes over time, like a DNS seed list which is resolved periodically
+pub trait SeedSource: Send + Sync {
+    /// Current seeds, it is polled in the controller loop so it must not block
+    fn seeds(&self) -> Vec<NodeAddr>;
+}
+
+fn neighbour_control<UserData, SE, TW>(c: NeighbourControl) -> ServiceOutput<UserData, FeaturesControl, SE, TW> {
+    ServiceOutput::FeatureControl(FeaturesControl::Neighbours(c))
+}
+
+/// Address which a peer is reached at, built from the remote address of an outgoing connection
+fn peer_addr(node: NodeId, remote: SocketAddr) -> NodeAddr {
+    let mut builder = NodeAddrBuilder::new(node);
+    match remote.ip() {
+        IpAddr::V4(ip) => builder.add_protocol(Protocol::Ip4(ip)),
+        IpAddr::V6(ip) => builder.add_protocol(Protocol::Ip6(ip)),
+    }
+    builder.add_protocol(Protocol::Udp(remote.port()));
+    builder.addr()
+}
+
+struct Reseed {
+    next_at: u64,
+    backoff: u64,
+    cursor: usize,
+}
+
+pub struct BootstrapService<UserData, SC, SE, TC, TW> {
+    node_id: NodeId,
+    seeds: Vec<NodeAddr>,
+    sources: Vec<Arc<dyn SeedSource>>,
+    source_seeds: Vec<NodeAddr>,
+    last_poll_ms: Option<u64>,
+    store: Option<Arc<dyn PeerStore>>,
+    /// Known-good peers, the most recently connected one is the last
+    peers: VecDeque<NodeAddr>,
+    dirty: bool,
+    last_save_ms: u64,
+    conns: HashMap<NodeId, usize>,
+    /// Some while the node has no connection
+    reseed: Option<Reseed>,
+    queue: VecDeque<ServiceOutput<UserData, FeaturesControl, SE, TW>>,
+    shutdown: bool,
+    _tmp: std::marker::PhantomData<(SC, TC, TW)>,
+}
+
+impl<UserData, SC, SE, TC, TW> BootstrapService<UserData, SC, SE, TC, TW> {
+    pub fn new(node_id: NodeId, seeds: Vec<NodeAddr>, sources: Vec<Arc<dyn SeedSource>>, store: Option<Arc<dyn PeerStore>>) -> Self {
+        let peers: VecDeque<_> = store.as_ref().map(|s| s.load()).unwrap_or_default().into_iter().filter(|p| p.node_id() != node_id).collect();
+        log::info!("[BootstrapService] created with {} seeds, {} sources, {} known peers", seeds.len(), sources.len(), peers.len());
+        Self {
+            node_id,
+            seeds,
+            sources,
+            source_seeds: vec![],
+            last_poll_ms: None,
+            store,
+            peers,
+            dirty: false,
+            last_save_ms: 0,
+            conns: HashMap::new(),
+            reseed: None,
+            queue: VecDeque::new(),
+            shutdown: false,
+            _tmp: std::marker::PhantomData,
+        }
+    }
+
+    /// Known-good peers first with the most recent one first, then static seeds and seeds of sources, one per node
+    fn candidates(&self) -> Vec<NodeAddr> {
+        let mut candidates: Vec<NodeAddr> = vec![];
+        for addr in self.peers.iter().rev().chain(self.seeds.iter()).chain(self.source_seeds.iter()) {
+            if addr.node_id() != self.node_id && !candidates.iter().any(|c| c.node_id() == addr.node_id()) {
+                candidates.push(addr.clone());
+            }
+        }
+        candidates
+    }
+
+    fn on_tick(&mut self, now: u64) {
+        if self.shutdown {
+            return;
+        }
+        if self.reseed.is_none() && self.conns.is_empty() {
+            // startup seeds or reconnects of neighbours get the min backoff before candidates are rotated
+            log::info!("[BootstrapService] no connection, start reseeding");
+            self.reseed = Some(Reseed {
+                next_at: now + BOOTSTRAP_BACKOFF_MIN_MS,
+                backoff: BOOTSTRAP_BACKOFF_MIN_MS,
+                cursor: 0,
+            });
+        }
+
+        if !self.sources.is_empty() && self.last_poll_ms.map_or(true, |last| last + SOURCE_POLL_MS <= now) {
+            self.last_poll_ms = Some(now);
+            self.source_seeds = self.sources.iter().flat_map(|s| s.seeds()).collect();
+        }
+
+        if let Some(reseed) = self.reseed.as_ref().filter(|r| r.next_at <= now) {
+            let candidates = self.candidates();
+            let cursor = reseed.cursor;
+            let backoff = reseed.backoff;
+            if let Some(addr) = candidates.get(cursor % candidates.len().max(1)) {
+                log::info!("[BootstrapService] no connection, dial candidate {addr} then wait {backoff} ms");
+                self.queue.push_back(neighbour_control(NeighbourControl::ConnectTo(addr.clone())));
+            } else {
+                log::warn!("[BootstrapService] no connection and no candidate to dial");
+            }
+            self.reseed = Some(Reseed {
+                next_at: now + backoff,
+                backoff: (backoff * 2).min(BOOTSTRAP_BACKOFF_MAX_MS),
+                cursor: cursor + 1,
+            });
+        }
+
+        if self.dirty && self.last_save_ms + SAVE_INTERVAL_MS <= now {
+            self.save(now);
+        }
+    }
+
+    fn learn(&mut self, addr: NodeAddr) {
+        self.peers.retain(|p| p.node_id() != addr.node_id());
+        self.peers.push_back(addr);
+        while self.peers.len() > BOOTSTRAP_MAX_PEERS {
+            self.peers.pop_front();
+        }
+        self.dirty = true;
+    }
+
+    fn save(&mut self, now: u64) {
+        self.dirty = false;
+        self.last_save_ms = now;
+        if let Some(store) = &self.store {
+            store.save(&self.peers.iter().cloned().collect::<Vec<_>>());
+        }
+    }
+}
+
+impl<UserData, SC, SE, TC: Debug, TW: Debug> Service<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for BootstrapService<UserData, SC, SE, TC, TW> {
+    fn is_service_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn service_id(&self) -> u8 {
+        SERVICE_ID
+    }
+
+    fn service_name(&self) -> &str {
+        SERVICE_NAME
+    }
+
+    fn on_shared_input<'a>(&mut self, _ctx: &ServiceCtx, now: u64, input: ServiceSharedInput) {
+        match input {
+            ServiceSharedInput::Tick(_) => self.on_tick(now),
+            ServiceSharedInput::Connection(ConnectionEvent::Connected(ctx, _)) => {
+                *self.conns.entry(ctx.node).or_default() += 1;
+                if self.reseed.take().is_some() {
+                    log::info!("[BootstrapService] connected to {} over {}, stop reseeding", ctx.node, ctx.pair);
+                }
+                // remote address of an incoming connection may be a NAT mapping, only dialed ones are known-good
+                if ctx.conn.is_outgoing() {
+                    self.learn(peer_addr(ctx.node, ctx.pair.remote));
+                }
+            }
+            ServiceSharedInput::Connection(ConnectionEvent::Disconnected(ctx)) => {
+                if let Some(count) = self.conns.get_mut(&ctx.node) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.conns.remove(&ctx.node);
+                    }
+                }
+                if self.conns.is_empty() {
+                    log::warn!("[BootstrapService] lost all connections");
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_input(&mut self, _ctx: &ServiceCtx, _now: u64, _input: ServiceInput<UserData, FeaturesEvent, SC, TC>) {}
+
+    fn on_shutdown(&mut self, _ctx: &ServiceCtx, now: u64) {
+        log::info!("[BootstrapService] Shutdown");
+        self.shutdown = true;
+        self.reseed = None;
+        if self.dirty {
+            self.save(now);
+        }
+    }
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceOutput<UserData, FeaturesControl, SE, TW>> {
+        self.queue.pop_front()
+    }
+}
+
+pub struct BootstrapServiceWorker<UserData, SC, SE, TC> {
+    queue: DynamicDeque<ServiceWorkerOutput<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC>, 8>,
+    shutdown: bool,
+}
+
+impl<UserData, SC, SE, TC, TW> ServiceWorker<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for BootstrapServiceWorker<UserData, SC, SE, TC> {
+    fn is_service_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn service_id(&self) -> u8 {
+        SERVICE_ID
+    }
+
+    fn service_name(&self) -> &str {
+        SERVICE_NAME
+    }
+
+    fn on_tick(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, _tick_count: u64) {}
+
+    fn on_input(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, input: ServiceWorkerInput<UserData, FeaturesEvent, SC, TW>) {
+        match input {
+            ServiceWorkerInput::Control(actor, control) => self.queue.push_back(ServiceWorkerOutput::ForwardControlToController(actor, control)),
+            ServiceWorkerInput::FeatureEvent(event) => self.queue.push_back(ServiceWorkerOutput::ForwardFeatureEventToController(event)),
+            ServiceWorkerInput::FromController(_) => {}
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &ServiceWorkerCtx, _now: u64) {
+        log::info!("[BootstrapServiceWorker] Shutdown");
+        self.shutdown = true;
+    }
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceWorkerOutput<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC>> {
+        self.queue.pop_front()
+    }
+}
+
+pub struct BootstrapServiceBuilder<UserData, SC, SE, TC, TW> {
+    _tmp: std::marker::PhantomData<(UserData, SC, SE, TC, TW)>,
+    node_id: NodeId,
+    seeds: Vec<NodeAddr>,
+    sources: Vec<Arc<dyn SeedSource>>,
+    store: Option<Arc<dyn PeerStore>>,
+}
+
+impl<UserData, SC, SE, TC, TW> BootstrapServiceBuilder<UserData, SC, SE, TC, TW> {
+    pub fn new(node_id: NodeId, seeds: Vec<NodeAddr>) -> Self {
+        Self {
+            _tmp: std::marker::PhantomData,
+            node_id,
+            seeds,
+            sources: vec![],
+            store: None,
+        }
+    }
+
+    pub fn with_source(mut self, source: Arc<dyn SeedSource>) -> Self {
+        self.sources.push(source);
+        self
+    }
+
+    pub fn with_store(mut self, store: Arc<dyn PeerStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+}
+
+impl<UserData, SC, SE, TC, TW> ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for BootstrapServiceBuilder<UserData, SC, SE, TC, TW>
+where
+    UserData: 'static + Debug + Send + Sync,
+    SC: 'static + Debug + Send + Sync,
+    SE: 'static + Debug + Send + Sync,
+    TC: 'static + Debug + Send + Sync,
+    TW: 'static + Debug + Send + Sync,
+{
+    fn service_id(&self) -> u8 {
+        SERVICE_ID
+    }
+
+    fn service_name(&self) -> &str {
+        SERVICE_NAME
+    }
+
+    fn create(&self) -> Box<dyn Service<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
+        Box::new(BootstrapService::new(self.node_id, self.seeds.clone(), self.sources.clone(), self.store.clone()))
+    }
+
+    fn create_worker(&self) -> Box<dyn ServiceWorker<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
+        Box::new(BootstrapServiceWorker {
+            queue: Default::default(),
+            shutdown: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use atm0s_sdn_identity::{ConnId, NodeAddr, NodeAddrBuilder, Protocol};
+    use parking_lot::Mutex;
+
+    use crate::{
+        base::{ConnectionCtx, ConnectionEvent, SecureContext, SecureInfo, Service, ServiceCtx, ServiceOutput, ServiceSharedInput},
+        data_plane::NetPair,
+        features::{neighbours, FeaturesControl},
+    };
+
+    use super::{BootstrapService, PeerStore, SeedSource, BOOTSTRAP_BACKOFF_MIN_MS};
+
+    fn node_addr(node: u32) -> NodeAddr {
+        let mut builder = NodeAddrBuilder::new(node);
+        builder.add_protocol(Protocol::Ip4([127, 0, 0, 1].into()));
+        builder.add_protocol(Protocol::Udp(node as u16));
+        builder.addr()
+    }
+
+    fn connect_to<SE, TW>(addr: NodeAddr) -> Option<ServiceOutput<(), FeaturesControl, SE, TW>> {
+        Some(ServiceOutput::FeatureControl(FeaturesControl::Neighbours(neighbours::Control::ConnectTo(addr))))
+    }
+
+    #[derive(Default)]
+    struct MemoryStore(Mutex<Vec<NodeAddr>>);
+
+    impl PeerStore for MemoryStore {
+        fn load(&self) -> Vec<NodeAddr> {
+            self.0.lock().clone()
+        }
+
+        fn save(&self, peers: &[NodeAddr]) {
+            *self.0.lock() = peers.to_vec();
+        }
+    }
+
+    struct StaticSource(Vec<NodeAddr>);
+
+    impl SeedSource for StaticSource {
+        fn seeds(&self) -> Vec<NodeAddr> {
+            self.0.clone()
+        }
+    }
+
+    fn conn_ctx(node: u32, conn: ConnId) -> ConnectionCtx {
+        ConnectionCtx {
+            conn,
+            node,
+            pair: NetPair::new_str("127.0.0.1:100", &format!("127.0.0.1:{node}")).expect("Should parse pair"),
+            secure: SecureInfo::UNKNOWN,
+        }
+    }
+
+    fn secure() -> SecureContext {
+        SecureContext::detached()
+    }
+
+    #[test]
+    fn rotate_candidates_with_backoff() {
+        let ctx = ServiceCtx { node_id: 100, session: 0 };
+        let source = Arc::new(StaticSource(vec![node_addr(102)]));
+        let mut service = BootstrapService::<(), (), (), (), ()>::new(100, vec![node_addr(101)], vec![source], None);
+
+        service.on_shared_input(&ctx, 0, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(0), None);
+
+        let mut now = BOOTSTRAP_BACKOFF_MIN_MS;
+        service.on_shared_input(&ctx, now, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(now), connect_to(node_addr(101)));
+
+        // backoff is doubled after each attempt
+        now += BOOTSTRAP_BACKOFF_MIN_MS;
+        service.on_shared_input(&ctx, now, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(now), connect_to(node_addr(102)));
+
+        now += BOOTSTRAP_BACKOFF_MIN_MS;
+        service.on_shared_input(&ctx, now, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(now), None);
+
+        now += BOOTSTRAP_BACKOFF_MIN_MS;
+        service.on_shared_input(&ctx, now, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(now), connect_to(node_addr(101)));
+    }
+
+    #[test]
+    fn learn_peer_and_reseed_after_lost() {
+        let ctx = ServiceCtx { node_id: 100, session: 0 };
+        let store = Arc::new(MemoryStore::default());
+        let mut service = BootstrapService::<(), (), (), (), ()>::new(100, vec![], vec![], Some(store.clone()));
+
+        let conn = conn_ctx(103, ConnId::from_out(0, 1));
+        service.on_shared_input(&ctx, 0, ServiceSharedInput::Tick(0));
+        service.on_shared_input(&ctx, 100, ServiceSharedInput::Connection(ConnectionEvent::Connected(conn.clone(), secure())));
+        service.on_shared_input(&ctx, 5_000, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(5_000), None);
+
+        service.on_shared_input(&ctx, 6_000, ServiceSharedInput::Connection(ConnectionEvent::Disconnected(conn)));
+        service.on_shared_input(&ctx, 6_000, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(6_000), None);
+        service.on_shared_input(&ctx, 6_000 + BOOTSTRAP_BACKOFF_MIN_MS, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(6_000 + BOOTSTRAP_BACKOFF_MIN_MS), connect_to(node_addr(103)));
+
+        // known-good peers are saved on shutdown and loaded by the next run
+        service.on_shutdown(&ctx, 10_000);
+        assert_eq!(store.load(), vec![node_addr(103)]);
+        let service = BootstrapService::<(), (), (), (), ()>::new(100, vec![], vec![], Some(store));
+        assert_eq!(service.candidates(), vec![node_addr(103)]);
+    }
+}
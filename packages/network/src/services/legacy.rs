@@ -0,0 +1,264 @@
+//! Host a behaviour which is written against the legacy `NetworkBehavior` model as a service, so old services can be
+//! migrated one by one instead of all at once.
+//!
+//! Only the parts of the old model which fit the sans-io stack are kept: ticks, neighbour connection events and messages.
+//! Messages are carried by the data feature on the [`legacy_port`] of the service, so a behaviour only talks with the same
+//! behaviour on other nodes, like the old `TransportMsg` which was routed by service id. Per connection handlers and rpc
+//! are not hosted, their logic moves into the behaviour with the node and connection which are given in each callback.
+
+use std::{collections::VecDeque, fmt::Debug, marker::PhantomData, sync::Arc};
+
+use atm0s_sdn_identity::{ConnId, NodeId};
+use atm0s_sdn_router::RouteRule;
+use sans_io_runtime::collections::DynamicDeque;
+
+use crate::{
+    base::{
+        ConnectionEvent, NetOutgoingMeta, Service, ServiceBuilder, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput, ServiceWorker, ServiceWorkerCtx, ServiceWorkerInput,
+        ServiceWorkerOutput,
+    },
+    features::{data, FeaturesControl, FeaturesEvent},
+};
+
+/// Data feature ports from this base are reserved for legacy behaviours, the lower byte is the service id
+pub const LEGACY_PORT_BASE: u16 = 0xFF00;
+
+pub fn legacy_port(service_id: u8) -> u16 {
+    LEGACY_PORT_BASE | service_id as u16
+}
+
+/// Destination of a legacy message, same as the route rules of the old `TransportMsg`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LegacyDest {
+    Node(NodeId),
+    /// Closest node to the key
+    Closest(NodeId),
+    /// Closest node which runs the same service
+    Service,
+}
+
+/// Replacement of the old `BehaviorAgent`: messages are buffered and sent after each callback
+pub struct LegacyAgent {
+    node_id: NodeId,
+    service_id: u8,
+    outgoing: VecDeque<(LegacyDest, Vec<u8>)>,
+}
+
+impl LegacyAgent {
+    pub fn local_node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    pub fn service_id(&self) -> u8 {
+        self.service_id
+    }
+
+    pub fn send_to(&mut self, dest: LegacyDest, msg: Vec<u8>) {
+        self.outgoing.push_back((dest, msg));
+    }
+}
+
+/// Sans-io subset of the old `NetworkBehavior` trait
+pub trait LegacyBehavior: Send + Sync {
+    fn service_id(&self) -> u8;
+    fn service_name(&self) -> &str;
+    fn on_tick(&mut self, agent: &mut LegacyAgent, ts_ms: u64, interval_ms: u64);
+    fn on_connection_connected(&mut self, _agent: &mut LegacyAgent, _node: NodeId, _conn: ConnId) {}
+    fn on_connection_disconnected(&mut self, _agent: &mut LegacyAgent, _node: NodeId, _conn: ConnId) {}
+    /// Message which is sent by the same behaviour on the source node
+    fn on_msg(&mut self, agent: &mut LegacyAgent, from: NodeId, msg: Vec<u8>);
+}
+
+pub struct LegacyService<B, UserData, SE, TW> {
+    behavior: B,
+    agent: LegacyAgent,
+    last_tick_ms: Option<u64>,
+    queue: VecDeque<ServiceOutput<UserData, FeaturesControl, SE, TW>>,
+    shutdown: bool,
+}
+
+impl<B: LegacyBehavior, UserData, SE, TW> LegacyService<B, UserData, SE, TW> {
+    pub fn new(behavior: B) -> Self {
+        let service_id = behavior.service_id();
+        log::info!("[LegacyService] host {} as service {service_id} on data port {}", behavior.service_name(), legacy_port(service_id));
+        Self {
+            agent: LegacyAgent {
+                node_id: 0,
+                service_id,
+                outgoing: VecDeque::new(),
+            },
+            behavior,
+            last_tick_ms: None,
+            queue: VecDeque::from([ServiceOutput::FeatureControl(data::Control::DataListen(legacy_port(service_id)).into())]),
+            shutdown: false,
+        }
+    }
+
+    fn flush(&mut self) {
+        let port = legacy_port(self.agent.service_id);
+        while let Some((dest, msg)) = self.agent.outgoing.pop_front() {
+            let rule = match dest {
+                LegacyDest::Node(node) => RouteRule::ToNode(node),
+                LegacyDest::Closest(key) => RouteRule::ToKey(key),
+                LegacyDest::Service => RouteRule::ToService(self.agent.service_id),
+            };
+            // source is needed for on_msg of the remote behaviour
+            let meta = NetOutgoingMeta::new(true, Default::default(), 0, true);
+            self.queue.push_back(ServiceOutput::FeatureControl(data::Control::DataSendRule(port, rule, meta, msg).into()));
+        }
+    }
+}
+
+impl<B, UserData, SC, SE, TC, TW> Service<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for LegacyService<B, UserData, SE, TW>
+where
+    B: LegacyBehavior,
+    SC: Debug,
+{
+    fn is_service_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn service_id(&self) -> u8 {
+        self.agent.service_id
+    }
+
+    fn service_name(&self) -> &str {
+        self.behavior.service_name()
+    }
+
+    fn on_shared_input<'a>(&mut self, ctx: &ServiceCtx, now: u64, input: ServiceSharedInput) {
+        if self.shutdown {
+            return;
+        }
+        self.agent.node_id = ctx.node_id;
+        match input {
+            ServiceSharedInput::Tick(_) => {
+                let interval_ms = self.last_tick_ms.map(|last| now - last).unwrap_or(0);
+                self.last_tick_ms = Some(now);
+                self.behavior.on_tick(&mut self.agent, now, interval_ms);
+            }
+            ServiceSharedInput::Connection(ConnectionEvent::Connected(ctx, _)) => self.behavior.on_connection_connected(&mut self.agent, ctx.node, ctx.conn),
+            ServiceSharedInput::Connection(ConnectionEvent::Disconnected(ctx)) => self.behavior.on_connection_disconnected(&mut self.agent, ctx.node, ctx.conn),
+            ServiceSharedInput::Connection(_) => {}
+        }
+        self.flush();
+    }
+
+    fn on_input(&mut self, ctx: &ServiceCtx, _now: u64, input: ServiceInput<UserData, FeaturesEvent, SC, TC>) {
+        self.agent.node_id = ctx.node_id;
+        match input {
+            ServiceInput::FeatureEvent(FeaturesEvent::Data(data::Event::Recv(port, meta, msg))) if port == legacy_port(self.agent.service_id) => {
+                if let Some(from) = meta.source {
+                    self.behavior.on_msg(&mut self.agent, from, msg);
+                    self.flush();
+                } else {
+                    log::warn!("[LegacyService] drop message without source on port {port}");
+                }
+            }
+            ServiceInput::Control(_, control) => log::warn!("[LegacyService] legacy behaviour {} does not have controls, drop {control:?}", self.behavior.service_name()),
+            _ => {}
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &ServiceCtx, _now: u64) {
+        log::info!("[LegacyService] shutdown {}", self.behavior.service_name());
+        self.queue
+            .push_back(ServiceOutput::FeatureControl(data::Control::DataUnlisten(legacy_port(self.agent.service_id)).into()));
+        self.shutdown = true;
+    }
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceOutput<UserData, FeaturesControl, SE, TW>> {
+        self.queue.pop_front()
+    }
+}
+
+/// All logic runs in the controller, workers only forward data events to it
+pub struct LegacyServiceWorker<UserData, SC, SE, TC> {
+    service_id: u8,
+    service_name: String,
+    queue: DynamicDeque<ServiceWorkerOutput<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC>, 8>,
+    shutdown: bool,
+}
+
+impl<UserData, SC, SE, TC, TW> ServiceWorker<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for LegacyServiceWorker<UserData, SC, SE, TC> {
+    fn is_service_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn service_id(&self) -> u8 {
+        self.service_id
+    }
+
+    fn service_name(&self) -> &str {
+        &self.service_name
+    }
+
+    fn on_tick(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, _tick_count: u64) {}
+
+    fn on_input(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, input: ServiceWorkerInput<UserData, FeaturesEvent, SC, TW>) {
+        match input {
+            ServiceWorkerInput::Control(actor, control) => self.queue.push_back(ServiceWorkerOutput::ForwardControlToController(actor, control)),
+            ServiceWorkerInput::FeatureEvent(event) => self.queue.push_back(ServiceWorkerOutput::ForwardFeatureEventToController(event)),
+            ServiceWorkerInput::FromController(_) => {}
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &ServiceWorkerCtx, _now: u64) {
+        self.shutdown = true;
+    }
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceWorkerOutput<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC>> {
+        self.queue.pop_front()
+    }
+}
+
+/// Build a [`LegacyService`] with a new behaviour from the factory each time the service is started
+pub struct LegacyServiceBuilder<B, UserData, SC, SE, TC, TW> {
+    service_id: u8,
+    service_name: String,
+    factory: Arc<dyn Fn() -> B + Send + Sync>,
+    _tmp: PhantomData<(UserData, SC, SE, TC, TW)>,
+}
+
+impl<B: LegacyBehavior, UserData, SC, SE, TC, TW> LegacyServiceBuilder<B, UserData, SC, SE, TC, TW> {
+    pub fn new<F: Fn() -> B + Send + Sync + 'static>(factory: F) -> Self {
+        let sample = factory();
+        Self {
+            service_id: sample.service_id(),
+            service_name: sample.service_name().to_string(),
+            factory: Arc::new(factory),
+            _tmp: PhantomData,
+        }
+    }
+}
+
+impl<B, UserData, SC, SE, TC, TW> ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for LegacyServiceBuilder<B, UserData, SC, SE, TC, TW>
+where
+    B: 'static + LegacyBehavior,
+    UserData: 'static + Debug + Send + Sync,
+    SC: 'static + Debug + Send + Sync,
+    SE: 'static + Debug + Send + Sync,
+    TC: 'static + Debug + Send + Sync,
+    TW: 'static + Debug + Send + Sync,
+{
+    fn service_id(&self) -> u8 {
+        self.service_id
+    }
+
+    fn service_name(&self) -> &str {
+        &self.service_name
+    }
+
+    fn create(&self) -> Box<dyn Service<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
+        Box::new(LegacyService::new((self.factory)()))
+    }
+
+    fn create_worker(&self) -> Box<dyn ServiceWorker<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
+        Box::new(LegacyServiceWorker {
+            service_id: self.service_id,
+            service_name: self.service_name.clone(),
+            queue: Default::default(),
+            shutdown: false,
+        })
+    }
+}
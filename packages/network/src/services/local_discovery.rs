@@ -0,0 +1,332 @@
+//! Connect to peers which are discovered on the local network.
+//!
+//! Discovery itself is done by a [`SeedSource`] which is driven outside of the controller, like the udp broadcast announcer
+//! of the runner. The service polls it, filters peers with a [`LocalDiscoveryPolicy`], and connects to allowed ones which
+//! are not connected yet.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Debug,
+    net::IpAddr,
+    sync::Arc,
+};
+
+use atm0s_sdn_identity::{NodeAddr, NodeId, Protocol};
+use sans_io_runtime::collections::DynamicDeque;
+
+use crate::{
+    base::{ConnectionEvent, Service, ServiceBuilder, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput, ServiceWorker, ServiceWorkerCtx, ServiceWorkerInput, ServiceWorkerOutput},
+    features::{neighbours::Control as NeighbourControl, FeaturesControl, FeaturesEvent},
+    services::bootstrap::SeedSource,
+};
+
+pub const SERVICE_ID: u8 = 4;
+pub const SERVICE_NAME: &str = "local_discovery";
+
+/// Interval for polling the discovery source
+const POLL_INTERVAL_MS: u64 = 1_000;
+/// Min time between two dials to the same discovered peer
+const RETRY_CONNECT_MS: u64 = 30_000;
+
+/// Which discovered peers are connected to, empty lists allow all
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LocalDiscoveryPolicy {
+    pub allowed_nodes: Vec<NodeId>,
+    /// Networks as address and prefix length, a peer is allowed if one of its addresses is in one of them
+    pub allowed_networks: Vec<(IpAddr, u8)>,
+    /// Max discovered peers which are connected at the same time, 0 for no limit
+    pub max_peers: usize,
+}
+
+impl LocalDiscoveryPolicy {
+    pub fn allow_all() -> Self {
+        Self::default()
+    }
+
+    pub fn is_allowed(&self, addr: &NodeAddr) -> bool {
+        if !self.allowed_nodes.is_empty() && !self.allowed_nodes.contains(&addr.node_id()) {
+            return false;
+        }
+        if self.allowed_networks.is_empty() {
+            return true;
+        }
+        addr.multiaddr().iter().any(|part| {
+            let ip = match part {
+                Protocol::Ip4(ip) => IpAddr::V4(ip),
+                Protocol::Ip6(ip) => IpAddr::V6(ip),
+                _ => return false,
+            };
+            self.allowed_networks.iter().any(|(network, prefix)| in_network(ip, *network, *prefix))
+        })
+    }
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix.min(32) as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix.min(128) as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+fn neighbour_control<UserData, SE, TW>(c: NeighbourControl) -> ServiceOutput<UserData, FeaturesControl, SE, TW> {
+    ServiceOutput::FeatureControl(FeaturesControl::Neighbours(c))
+}
+
+pub struct LocalDiscoveryService<UserData, SC, SE, TC, TW> {
+    node_id: NodeId,
+    source: Arc<dyn SeedSource>,
+    policy: LocalDiscoveryPolicy,
+    last_poll_ms: Option<u64>,
+    /// Discovered peers which are dialed, with the time of the last dial
+    dialed: HashMap<NodeId, u64>,
+    connected: HashSet<NodeId>,
+    queue: VecDeque<ServiceOutput<UserData, FeaturesControl, SE, TW>>,
+    shutdown: bool,
+    _tmp: std::marker::PhantomData<(SC, TC, TW)>,
+}
+
+impl<UserData, SC, SE, TC, TW> LocalDiscoveryService<UserData, SC, SE, TC, TW> {
+    pub fn new(node_id: NodeId, source: Arc<dyn SeedSource>, policy: LocalDiscoveryPolicy) -> Self {
+        log::info!("[LocalDiscoveryService] created with policy {:?}", policy);
+        Self {
+            node_id,
+            source,
+            policy,
+            last_poll_ms: None,
+            dialed: HashMap::new(),
+            connected: HashSet::new(),
+            queue: VecDeque::new(),
+            shutdown: false,
+            _tmp: std::marker::PhantomData,
+        }
+    }
+
+    fn poll(&mut self, now: u64) {
+        if self.shutdown || self.last_poll_ms.is_some_and(|last| last + POLL_INTERVAL_MS > now) {
+            return;
+        }
+        self.last_poll_ms = Some(now);
+        for addr in self.source.seeds() {
+            let node = addr.node_id();
+            if node == self.node_id || self.connected.contains(&node) || self.dialed.get(&node).is_some_and(|last| last + RETRY_CONNECT_MS > now) {
+                continue;
+            }
+            if !self.policy.is_allowed(&addr) {
+                log::debug!("[LocalDiscoveryService] discovered peer {addr} is not allowed");
+                continue;
+            }
+            let active = self.connected.iter().filter(|n| self.dialed.contains_key(n)).count();
+            if self.policy.max_peers > 0 && active >= self.policy.max_peers {
+                break;
+            }
+            log::info!("[LocalDiscoveryService] discovered peer {addr} => connect");
+            self.dialed.insert(node, now);
+            self.queue.push_back(neighbour_control(NeighbourControl::ConnectTo(addr)));
+        }
+    }
+}
+
+impl<UserData, SC, SE, TC: Debug, TW: Debug> Service<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for LocalDiscoveryService<UserData, SC, SE, TC, TW> {
+    fn is_service_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn service_id(&self) -> u8 {
+        SERVICE_ID
+    }
+
+    fn service_name(&self) -> &str {
+        SERVICE_NAME
+    }
+
+    fn on_shared_input<'a>(&mut self, _ctx: &ServiceCtx, now: u64, input: ServiceSharedInput) {
+        match input {
+            ServiceSharedInput::Tick(_) => self.poll(now),
+            ServiceSharedInput::Connection(ConnectionEvent::Connected(ctx, _)) => {
+                self.connected.insert(ctx.node);
+            }
+            ServiceSharedInput::Connection(ConnectionEvent::Disconnected(ctx)) => {
+                self.connected.remove(&ctx.node);
+            }
+            _ => {}
+        }
+    }
+
+    fn on_input(&mut self, _ctx: &ServiceCtx, _now: u64, _input: ServiceInput<UserData, FeaturesEvent, SC, TC>) {}
+
+    fn on_shutdown(&mut self, _ctx: &ServiceCtx, _now: u64) {
+        log::info!("[LocalDiscoveryService] Shutdown");
+        self.shutdown = true;
+    }
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceOutput<UserData, FeaturesControl, SE, TW>> {
+        self.queue.pop_front()
+    }
+}
+
+pub struct LocalDiscoveryServiceWorker<UserData, SC, SE, TC> {
+    queue: DynamicDeque<ServiceWorkerOutput<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC>, 8>,
+    shutdown: bool,
+}
+
+impl<UserData, SC, SE, TC, TW> ServiceWorker<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for LocalDiscoveryServiceWorker<UserData, SC, SE, TC> {
+    fn is_service_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn service_id(&self) -> u8 {
+        SERVICE_ID
+    }
+
+    fn service_name(&self) -> &str {
+        SERVICE_NAME
+    }
+
+    fn on_tick(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, _tick_count: u64) {}
+
+    fn on_input(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, input: ServiceWorkerInput<UserData, FeaturesEvent, SC, TW>) {
+        match input {
+            ServiceWorkerInput::Control(actor, control) => self.queue.push_back(ServiceWorkerOutput::ForwardControlToController(actor, control)),
+            ServiceWorkerInput::FeatureEvent(event) => self.queue.push_back(ServiceWorkerOutput::ForwardFeatureEventToController(event)),
+            ServiceWorkerInput::FromController(_) => {}
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &ServiceWorkerCtx, _now: u64) {
+        log::info!("[LocalDiscoveryServiceWorker] Shutdown");
+        self.shutdown = true;
+    }
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceWorkerOutput<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC>> {
+        self.queue.pop_front()
+    }
+}
+
+pub struct LocalDiscoveryServiceBuilder<UserData, SC, SE, TC, TW> {
+    _tmp: std::marker::PhantomData<(UserData, SC, SE, TC, TW)>,
+    node_id: NodeId,
+    source: Arc<dyn SeedSource>,
+    policy: LocalDiscoveryPolicy,
+}
+
+impl<UserData, SC, SE, TC, TW> LocalDiscoveryServiceBuilder<UserData, SC, SE, TC, TW> {
+    pub fn new(node_id: NodeId, source: Arc<dyn SeedSource>, policy: LocalDiscoveryPolicy) -> Self {
+        Self {
+            _tmp: std::marker::PhantomData,
+            node_id,
+            source,
+            policy,
+        }
+    }
+}
+
+impl<UserData, SC, SE, TC, TW> ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for LocalDiscoveryServiceBuilder<UserData, SC, SE, TC, TW>
+where
+    UserData: 'static + Debug + Send + Sync,
+    SC: 'static + Debug + Send + Sync,
+    SE: 'static + Debug + Send + Sync,
+    TC: 'static + Debug + Send + Sync,
+    TW: 'static + Debug + Send + Sync,
+{
+    fn service_id(&self) -> u8 {
+        SERVICE_ID
+    }
+
+    fn service_name(&self) -> &str {
+        SERVICE_NAME
+    }
+
+    fn create(&self) -> Box<dyn Service<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
+        Box::new(LocalDiscoveryService::new(self.node_id, self.source.clone(), self.policy.clone()))
+    }
+
+    fn create_worker(&self) -> Box<dyn ServiceWorker<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
+        Box::new(LocalDiscoveryServiceWorker {
+            queue: Default::default(),
+            shutdown: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use atm0s_sdn_identity::{NodeAddr, NodeAddrBuilder, Protocol};
+    use parking_lot::Mutex;
+
+    use crate::{
+        base::{Service, ServiceCtx, ServiceOutput, ServiceSharedInput},
+        features::{neighbours, FeaturesControl},
+        services::bootstrap::SeedSource,
+    };
+
+    use super::{LocalDiscoveryPolicy, LocalDiscoveryService, RETRY_CONNECT_MS};
+
+    fn node_addr(node: u32, ip: [u8; 4]) -> NodeAddr {
+        let mut builder = NodeAddrBuilder::new(node);
+        builder.add_protocol(Protocol::Ip4(ip.into()));
+        builder.add_protocol(Protocol::Udp(10000));
+        builder.addr()
+    }
+
+    fn connect_to<SE, TW>(addr: NodeAddr) -> Option<ServiceOutput<(), FeaturesControl, SE, TW>> {
+        Some(ServiceOutput::FeatureControl(FeaturesControl::Neighbours(neighbours::Control::ConnectTo(addr))))
+    }
+
+    #[derive(Default)]
+    struct Discovered(Mutex<Vec<NodeAddr>>);
+
+    impl SeedSource for Discovered {
+        fn seeds(&self) -> Vec<NodeAddr> {
+            self.0.lock().clone()
+        }
+    }
+
+    #[test]
+    fn policy_allows_nodes_and_networks() {
+        let policy = LocalDiscoveryPolicy {
+            allowed_nodes: vec![],
+            allowed_networks: vec![([192, 168, 1, 0].into(), 24)],
+            max_peers: 0,
+        };
+        assert!(policy.is_allowed(&node_addr(1, [192, 168, 1, 20])));
+        assert!(!policy.is_allowed(&node_addr(1, [192, 168, 2, 20])));
+
+        let policy = LocalDiscoveryPolicy {
+            allowed_nodes: vec![1],
+            ..Default::default()
+        };
+        assert!(policy.is_allowed(&node_addr(1, [10, 0, 0, 1])));
+        assert!(!policy.is_allowed(&node_addr(2, [10, 0, 0, 1])));
+    }
+
+    #[test]
+    fn connect_allowed_peers_and_retry_later() {
+        let ctx = ServiceCtx { node_id: 100, session: 0 };
+        let discovered = Arc::new(Discovered::default());
+        let policy = LocalDiscoveryPolicy {
+            allowed_nodes: vec![100, 101],
+            ..Default::default()
+        };
+        let mut service = LocalDiscoveryService::<(), (), (), (), ()>::new(100, discovered.clone(), policy);
+
+        *discovered.0.lock() = vec![node_addr(100, [10, 0, 0, 100]), node_addr(101, [10, 0, 0, 101]), node_addr(102, [10, 0, 0, 102])];
+        service.on_shared_input(&ctx, 0, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(0), connect_to(node_addr(101, [10, 0, 0, 101])));
+        assert_eq!(service.pop_output2(0), None);
+
+        service.on_shared_input(&ctx, 1_000, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(1_000), None);
+
+        service.on_shared_input(&ctx, RETRY_CONNECT_MS, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(RETRY_CONNECT_MS), connect_to(node_addr(101, [10, 0, 0, 101])));
+    }
+}
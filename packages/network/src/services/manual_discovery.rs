@@ -144,7 +144,7 @@ impl<UserData, SC, SE, TC: Debug, TW: Debug> Service<UserData, FeaturesControl,
                         self.removing_list.remove(&source);
                     }
                 }
-                MapEvent::OnDel(_, source) => {
+                MapEvent::OnDel(_, source) | MapEvent::OnExpired(_, source) => {
                     self.nodes.remove(&source);
                     self.removing_list.entry(source).or_insert_with(|| {
                         log::info!("ManualDiscoveryService node {source} removed tag {map} => push to removing_list");
@@ -154,6 +154,10 @@ impl<UserData, SC, SE, TC: Debug, TW: Debug> Service<UserData, FeaturesControl,
                 MapEvent::OnRelaySelected(node) => {
                     log::info!("ManualDiscoveryService relay {node} selected for tag {map}");
                 }
+                MapEvent::OnAclApplied => {}
+                MapEvent::OnDenied(op) => {
+                    log::warn!("ManualDiscoveryService {:?} denied by acl of tag {map}", op);
+                }
             }
         }
     }
@@ -326,7 +330,11 @@ mod test {
         assert_eq!(service.pop_output2(100), Some(neighbour_cmd(neighbours::Control::ConnectTo(addr2.clone()))));
 
         // fake connected
-        service.on_input(&ctx, 110, neighbour_event(neighbours::Event::Connected(addr2.node_id(), ConnId::from_out(0, 0))));
+        service.on_input(
+            &ctx,
+            110,
+            neighbour_event(neighbours::Event::Connected(addr2.node_id(), ConnId::from_out(0, 0), "127.0.0.1:10000".parse().expect("Should parse"))),
+        );
 
         // remove node
         service.on_shared_input(&ctx, 200, ServiceSharedInput::Tick(0));
@@ -1,2 +1,6 @@
+pub mod bootstrap;
+pub mod legacy;
+pub mod local_discovery;
 pub mod manual_discovery;
+pub mod presence;
 pub mod visualization;
@@ -0,0 +1,499 @@
+//! Presence service: ephemeral room membership built on top of dht_kv.
+//!
+//! Each member joined from this node is stored as a key inside the room map, with the member meta as value.
+//! Members must be refreshed by `Heartbeat`, otherwise they are removed after the configured ttl.
+//! If the whole node is crashed, dht_kv will remove its keys after the server side timeout.
+//!
+//! For avoiding flooding dht_kv and watchers with many small changes, local writes are coalesced per member
+//! and watcher changes are collected per room, both of them are flushed on each tick.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Debug,
+};
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_utils::{hash::hash_str, simple_pub_type};
+use sans_io_runtime::collections::DynamicDeque;
+
+use crate::{
+    base::{Service, ServiceBuilder, ServiceControlActor, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput, ServiceWorker, ServiceWorkerCtx, ServiceWorkerInput, ServiceWorkerOutput},
+    features::{
+        dht_kv::{Control as KvControl, Event as KvEvent, Key, Map, MapControl, MapEvent},
+        FeaturesControl, FeaturesEvent,
+    },
+};
+
+pub const SERVICE_ID: u8 = 2;
+pub const SERVICE_NAME: &str = "presence";
+
+/// Default time a member is kept alive without heartbeat, embedder should send heartbeat with around a half of this interval
+pub const DEFAULT_MEMBER_TTL_MS: u64 = 10_000;
+
+simple_pub_type!(Room, u64);
+
+pub type MemberId = u64;
+
+fn kv_control<UserData, SE, TW>(c: KvControl) -> ServiceOutput<UserData, FeaturesControl, SE, TW> {
+    ServiceOutput::FeatureControl(FeaturesControl::DhtKv(c))
+}
+
+/// Room is mapped to a dht_kv map with a prefix, for avoiding collision with other users of dht_kv
+fn room_map(room: Room) -> Map {
+    Map(hash_str(&format!("presence/{}", room.0)))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub node: NodeId,
+    pub id: MemberId,
+    pub meta: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PresenceChange {
+    /// Member joined or updated its meta
+    Joined(Member),
+    Left(NodeId, MemberId),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Control {
+    /// Join a room with member meta, joining again with same member will update the meta
+    Join(Room, MemberId, Vec<u8>),
+    Heartbeat(Room, MemberId),
+    Leave(Room, MemberId),
+    /// Watch a room, a Snapshot event will be fired after that with all known members
+    Watch(Room),
+    Unwatch(Room),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Snapshot(Room, Vec<Member>),
+    Changed(Room, Vec<PresenceChange>),
+    /// Local member is removed because of missing heartbeat, only fired to the actor which joined it
+    Expired(Room, MemberId),
+}
+
+struct LocalMember<UserData> {
+    owner: ServiceControlActor<UserData>,
+    last_heartbeat: u64,
+}
+
+struct WatchingRoom<UserData> {
+    watchers: Vec<ServiceControlActor<UserData>>,
+    members: BTreeMap<(NodeId, MemberId), Vec<u8>>,
+    changes: Vec<PresenceChange>,
+}
+
+pub struct PresenceService<UserData, SC, SE, TC, TW> {
+    member_ttl_ms: u64,
+    queue: VecDeque<ServiceOutput<UserData, FeaturesControl, SE, TW>>,
+    locals: HashMap<(Room, MemberId), LocalMember<UserData>>,
+    /// Coalesced dht writes, Some is Set and None is Del
+    pending: BTreeMap<(Room, MemberId), Option<Vec<u8>>>,
+    rooms: HashMap<Room, WatchingRoom<UserData>>,
+    maps: HashMap<Map, Room>,
+    shutdown: bool,
+    _tmp: std::marker::PhantomData<(SC, TC)>,
+}
+
+impl<UserData: Copy + Eq + Debug, SC, SE, TC, TW> PresenceService<UserData, SC, SE, TC, TW>
+where
+    SE: From<Event> + TryInto<Event>,
+{
+    pub fn new(member_ttl_ms: u64) -> Self {
+        Self {
+            member_ttl_ms,
+            queue: VecDeque::new(),
+            locals: HashMap::new(),
+            pending: BTreeMap::new(),
+            rooms: HashMap::new(),
+            maps: HashMap::new(),
+            shutdown: false,
+            _tmp: std::marker::PhantomData,
+        }
+    }
+
+    fn on_control(&mut self, now: u64, actor: ServiceControlActor<UserData>, control: Control) {
+        match control {
+            Control::Join(room, member, meta) => {
+                log::info!("[PresenceService] {actor:?} join {room} as member {member}");
+                self.locals.insert((room, member), LocalMember { owner: actor, last_heartbeat: now });
+                self.pending.insert((room, member), Some(meta));
+            }
+            Control::Heartbeat(room, member) => {
+                if let Some(local) = self.locals.get_mut(&(room, member)) {
+                    local.last_heartbeat = now;
+                } else {
+                    log::warn!("[PresenceService] heartbeat for unknown member {member} in {room}");
+                }
+            }
+            Control::Leave(room, member) => {
+                if self.locals.remove(&(room, member)).is_some() {
+                    log::info!("[PresenceService] {actor:?} leave {room} with member {member}");
+                    self.pending.insert((room, member), None);
+                }
+            }
+            Control::Watch(room) => {
+                let map = room_map(room);
+                let state = self.rooms.entry(room).or_insert_with(|| {
+                    log::info!("[PresenceService] start watching {room} with {map}");
+                    self.queue.push_back(kv_control(KvControl::MapCmd(map, MapControl::Sub)));
+                    WatchingRoom {
+                        watchers: vec![],
+                        members: BTreeMap::new(),
+                        changes: vec![],
+                    }
+                });
+                self.maps.insert(map, room);
+                if !state.watchers.contains(&actor) {
+                    state.watchers.push(actor);
+                    let members = state
+                        .members
+                        .iter()
+                        .map(|((node, id), meta)| Member {
+                            node: *node,
+                            id: *id,
+                            meta: meta.clone(),
+                        })
+                        .collect();
+                    self.queue.push_back(ServiceOutput::Event(actor, Event::Snapshot(room, members).into()));
+                }
+            }
+            Control::Unwatch(room) => {
+                if let Some(state) = self.rooms.get_mut(&room) {
+                    state.watchers.retain(|w| *w != actor);
+                    if state.watchers.is_empty() {
+                        let map = room_map(room);
+                        log::info!("[PresenceService] stop watching {room} with {map}");
+                        self.queue.push_back(kv_control(KvControl::MapCmd(map, MapControl::Unsub)));
+                        self.rooms.remove(&room);
+                        self.maps.remove(&map);
+                    }
+                }
+            }
+        }
+    }
+
+    fn on_map_event(&mut self, map: Map, event: MapEvent) {
+        let room = if let Some(room) = self.maps.get(&map) {
+            *room
+        } else {
+            return;
+        };
+        let state = self.rooms.get_mut(&room).expect("Should have room state for watching map");
+        match event {
+            MapEvent::OnSet(key, node, meta) => {
+                state.members.insert((node, *key), meta.clone());
+                state.changes.push(PresenceChange::Joined(Member { node, id: *key, meta }));
+            }
+            MapEvent::OnDel(key, node) | MapEvent::OnExpired(key, node) => {
+                if state.members.remove(&(node, *key)).is_some() {
+                    state.changes.push(PresenceChange::Left(node, *key));
+                }
+            }
+            MapEvent::OnRelaySelected(_) | MapEvent::OnAclApplied => {}
+            MapEvent::OnDenied(op) => {
+                log::warn!("[PresenceService] {:?} denied by acl of room map", op);
+            }
+        }
+    }
+
+    fn on_tick(&mut self, now: u64) {
+        let ttl = self.member_ttl_ms;
+        let expired = self.locals.iter().filter(|(_, m)| now >= m.last_heartbeat + ttl).map(|(k, m)| (*k, m.owner)).collect::<Vec<_>>();
+        for ((room, member), owner) in expired {
+            log::warn!("[PresenceService] member {member} in {room} expired after {ttl} ms without heartbeat");
+            self.locals.remove(&(room, member));
+            self.pending.insert((room, member), None);
+            self.queue.push_back(ServiceOutput::Event(owner, Event::Expired(room, member).into()));
+        }
+
+        while let Some(((room, member), value)) = self.pending.pop_first() {
+            let control = match value {
+                Some(meta) => MapControl::Set(Key(member), meta),
+                None => MapControl::Del(Key(member)),
+            };
+            self.queue.push_back(kv_control(KvControl::MapCmd(room_map(room), control)));
+        }
+
+        for (room, state) in self.rooms.iter_mut() {
+            if state.changes.is_empty() {
+                continue;
+            }
+            let changes = std::mem::take(&mut state.changes);
+            for watcher in state.watchers.iter() {
+                self.queue.push_back(ServiceOutput::Event(*watcher, Event::Changed(*room, changes.clone()).into()));
+            }
+        }
+    }
+}
+
+impl<UserData: Copy + Eq + Debug, SC, SE, TC, TW> Service<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for PresenceService<UserData, SC, SE, TC, TW>
+where
+    SC: From<Control> + TryInto<Control>,
+    SE: From<Event> + TryInto<Event>,
+{
+    fn is_service_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn service_id(&self) -> u8 {
+        SERVICE_ID
+    }
+
+    fn service_name(&self) -> &str {
+        SERVICE_NAME
+    }
+
+    fn on_shared_input<'a>(&mut self, _ctx: &ServiceCtx, now: u64, input: ServiceSharedInput) {
+        if let ServiceSharedInput::Tick(_) = input {
+            self.on_tick(now);
+        }
+    }
+
+    fn on_input(&mut self, _ctx: &ServiceCtx, now: u64, input: ServiceInput<UserData, FeaturesEvent, SC, TC>) {
+        match input {
+            ServiceInput::Control(actor, control) => {
+                if let Ok(control) = control.try_into() {
+                    self.on_control(now, actor, control);
+                }
+            }
+            ServiceInput::FeatureEvent(FeaturesEvent::DhtKv(KvEvent::MapEvent(map, event))) => self.on_map_event(map, event),
+            _ => {}
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &ServiceCtx, _now: u64) {
+        log::info!("[PresenceService] Shutdown");
+        for (room, member) in self.locals.drain().map(|(k, _)| k) {
+            self.pending.insert((room, member), None);
+        }
+        while let Some(((room, member), value)) = self.pending.pop_first() {
+            if value.is_none() {
+                self.queue.push_back(kv_control(KvControl::MapCmd(room_map(room), MapControl::Del(Key(member)))));
+            }
+        }
+        for (room, _) in self.rooms.drain() {
+            self.queue.push_back(kv_control(KvControl::MapCmd(room_map(room), MapControl::Unsub)));
+        }
+        self.maps.clear();
+        self.shutdown = true;
+    }
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceOutput<UserData, FeaturesControl, SE, TW>> {
+        self.queue.pop_front()
+    }
+}
+
+pub struct PresenceServiceWorker<UserData, SC, SE, TC> {
+    queue: DynamicDeque<ServiceWorkerOutput<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC>, 8>,
+    shutdown: bool,
+}
+
+impl<UserData, SC, SE, TC, TW> ServiceWorker<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for PresenceServiceWorker<UserData, SC, SE, TC> {
+    fn is_service_empty(&self) -> bool {
+        self.shutdown && self.queue.is_empty()
+    }
+
+    fn service_id(&self) -> u8 {
+        SERVICE_ID
+    }
+
+    fn service_name(&self) -> &str {
+        SERVICE_NAME
+    }
+
+    fn on_tick(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, _tick_count: u64) {}
+
+    fn on_input(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, input: ServiceWorkerInput<UserData, FeaturesEvent, SC, TW>) {
+        match input {
+            ServiceWorkerInput::Control(actor, control) => self.queue.push_back(ServiceWorkerOutput::ForwardControlToController(actor, control)),
+            ServiceWorkerInput::FeatureEvent(event) => self.queue.push_back(ServiceWorkerOutput::ForwardFeatureEventToController(event)),
+            ServiceWorkerInput::FromController(_) => {}
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &ServiceWorkerCtx, _now: u64) {
+        log::info!("[PresenceServiceWorker] Shutdown");
+        self.shutdown = true;
+    }
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceWorkerOutput<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC>> {
+        self.queue.pop_front()
+    }
+}
+
+pub struct PresenceServiceBuilder<UserData, SC, SE, TC, TW> {
+    _tmp: std::marker::PhantomData<(UserData, SC, SE, TC, TW)>,
+    member_ttl_ms: u64,
+}
+
+impl<UserData, SC, SE, TC, TW> PresenceServiceBuilder<UserData, SC, SE, TC, TW> {
+    pub fn new(member_ttl_ms: u64) -> Self {
+        Self {
+            _tmp: std::marker::PhantomData,
+            member_ttl_ms,
+        }
+    }
+}
+
+impl<UserData, SC, SE, TC, TW> ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for PresenceServiceBuilder<UserData, SC, SE, TC, TW>
+where
+    UserData: 'static + Debug + Send + Sync + Copy + Eq,
+    SC: 'static + Debug + Send + Sync + From<Control> + TryInto<Control>,
+    SE: 'static + Debug + Send + Sync + From<Event> + TryInto<Event>,
+    TC: 'static + Debug + Send + Sync,
+    TW: 'static + Debug + Send + Sync,
+{
+    fn service_id(&self) -> u8 {
+        SERVICE_ID
+    }
+
+    fn service_name(&self) -> &str {
+        SERVICE_NAME
+    }
+
+    fn create(&self) -> Box<dyn Service<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
+        Box::new(PresenceService::new(self.member_ttl_ms))
+    }
+
+    fn create_worker(&self) -> Box<dyn ServiceWorker<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
+        Box::new(PresenceServiceWorker {
+            queue: Default::default(),
+            shutdown: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        base::{Service, ServiceControlActor, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput},
+        features::{
+            dht_kv::{self, Key, Map, MapControl, MapEvent},
+            FeaturesControl, FeaturesEvent,
+        },
+    };
+
+    use super::{room_map, Control, Event, Member, PresenceChange, PresenceService, Room, DEFAULT_MEMBER_TTL_MS};
+
+    type TestService = PresenceService<(), Control, Event, (), ()>;
+
+    const ACTOR: ServiceControlActor<()> = ServiceControlActor::Controller(());
+
+    fn map_cmd(map: Map, control: MapControl) -> ServiceOutput<(), FeaturesControl, Event, ()> {
+        ServiceOutput::FeatureControl(FeaturesControl::DhtKv(dht_kv::Control::MapCmd(map, control)))
+    }
+
+    fn map_event(map: Map, event: MapEvent) -> ServiceInput<(), FeaturesEvent, Control, ()> {
+        ServiceInput::FeatureEvent(FeaturesEvent::DhtKv(dht_kv::Event::MapEvent(map, event)))
+    }
+
+    #[test]
+    fn should_batch_writes_on_tick() {
+        let ctx = ServiceCtx { node_id: 1, session: 0 };
+        let mut service = TestService::new(DEFAULT_MEMBER_TTL_MS);
+        let room = Room(100);
+
+        service.on_input(&ctx, 0, ServiceInput::Control(ACTOR, Control::Join(room, 1, vec![1])));
+        service.on_input(&ctx, 0, ServiceInput::Control(ACTOR, Control::Join(room, 1, vec![2])));
+        service.on_input(&ctx, 0, ServiceInput::Control(ACTOR, Control::Join(room, 2, vec![3])));
+        service.on_input(&ctx, 0, ServiceInput::Control(ACTOR, Control::Leave(room, 2)));
+        assert_eq!(service.pop_output2(0), None);
+
+        service.on_shared_input(&ctx, 100, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(100), Some(map_cmd(room_map(room), MapControl::Set(Key(1), vec![2]))));
+        assert_eq!(service.pop_output2(100), Some(map_cmd(room_map(room), MapControl::Del(Key(2)))));
+        assert_eq!(service.pop_output2(100), None);
+    }
+
+    #[test]
+    fn should_expire_member_without_heartbeat() {
+        let ctx = ServiceCtx { node_id: 1, session: 0 };
+        let mut service = TestService::new(1000);
+        let room = Room(100);
+
+        service.on_input(&ctx, 0, ServiceInput::Control(ACTOR, Control::Join(room, 1, vec![1])));
+        service.on_shared_input(&ctx, 0, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(0), Some(map_cmd(room_map(room), MapControl::Set(Key(1), vec![1]))));
+
+        service.on_input(&ctx, 800, ServiceInput::Control(ACTOR, Control::Heartbeat(room, 1)));
+        service.on_shared_input(&ctx, 1000, ServiceSharedInput::Tick(1));
+        assert_eq!(service.pop_output2(1000), None);
+
+        service.on_shared_input(&ctx, 1800, ServiceSharedInput::Tick(2));
+        assert_eq!(service.pop_output2(1800), Some(ServiceOutput::Event(ACTOR, Event::Expired(room, 1))));
+        assert_eq!(service.pop_output2(1800), Some(map_cmd(room_map(room), MapControl::Del(Key(1)))));
+        assert_eq!(service.pop_output2(1800), None);
+    }
+
+    #[test]
+    fn should_fire_watcher_events() {
+        let ctx = ServiceCtx { node_id: 1, session: 0 };
+        let mut service = TestService::new(DEFAULT_MEMBER_TTL_MS);
+        let room = Room(100);
+        let map = room_map(room);
+
+        service.on_input(&ctx, 0, ServiceInput::Control(ACTOR, Control::Watch(room)));
+        assert_eq!(service.pop_output2(0), Some(map_cmd(map, MapControl::Sub)));
+        assert_eq!(service.pop_output2(0), Some(ServiceOutput::Event(ACTOR, Event::Snapshot(room, vec![]))));
+        assert_eq!(service.pop_output2(0), None);
+
+        service.on_input(&ctx, 100, map_event(map, MapEvent::OnSet(Key(1), 2, vec![1])));
+        service.on_input(&ctx, 100, map_event(map, MapEvent::OnSet(Key(2), 3, vec![2])));
+        service.on_input(&ctx, 100, map_event(map, MapEvent::OnDel(Key(2), 3)));
+        service.on_input(&ctx, 100, map_event(Map(1), MapEvent::OnSet(Key(1), 2, vec![1])));
+        assert_eq!(service.pop_output2(100), None);
+
+        service.on_shared_input(&ctx, 1000, ServiceSharedInput::Tick(0));
+        assert_eq!(
+            service.pop_output2(1000),
+            Some(ServiceOutput::Event(
+                ACTOR,
+                Event::Changed(
+                    room,
+                    vec![
+                        PresenceChange::Joined(Member { node: 2, id: 1, meta: vec![1] }),
+                        PresenceChange::Joined(Member { node: 3, id: 2, meta: vec![2] }),
+                        PresenceChange::Left(3, 2),
+                    ]
+                )
+            ))
+        );
+        assert_eq!(service.pop_output2(1000), None);
+
+        let actor2 = ServiceControlActor::Worker(1, ());
+        service.on_input(&ctx, 1100, ServiceInput::Control(actor2, Control::Watch(room)));
+        assert_eq!(
+            service.pop_output2(1100),
+            Some(ServiceOutput::Event(actor2, Event::Snapshot(room, vec![Member { node: 2, id: 1, meta: vec![1] }])))
+        );
+
+        service.on_input(&ctx, 1200, ServiceInput::Control(ACTOR, Control::Unwatch(room)));
+        assert_eq!(service.pop_output2(1200), None);
+        service.on_input(&ctx, 1200, ServiceInput::Control(actor2, Control::Unwatch(room)));
+        assert_eq!(service.pop_output2(1200), Some(map_cmd(map, MapControl::Unsub)));
+        assert_eq!(service.pop_output2(1200), None);
+    }
+
+    #[test]
+    fn should_cleanup_on_shutdown() {
+        let ctx = ServiceCtx { node_id: 1, session: 0 };
+        let mut service = TestService::new(DEFAULT_MEMBER_TTL_MS);
+        let room = Room(100);
+
+        service.on_input(&ctx, 0, ServiceInput::Control(ACTOR, Control::Join(room, 1, vec![1])));
+        service.on_input(&ctx, 0, ServiceInput::Control(ACTOR, Control::Watch(room)));
+        assert_eq!(service.pop_output2(0), Some(map_cmd(room_map(room), MapControl::Sub)));
+        assert_eq!(service.pop_output2(0), Some(ServiceOutput::Event(ACTOR, Event::Snapshot(room, vec![]))));
+
+        service.on_shutdown(&ctx, 100);
+        assert_eq!(service.pop_output2(100), Some(map_cmd(room_map(room), MapControl::Del(Key(1)))));
+        assert_eq!(service.pop_output2(100), Some(map_cmd(room_map(room), MapControl::Unsub)));
+        assert_eq!(service.pop_output2(100), None);
+        assert!(service.is_service_empty());
+    }
+}
@@ -5,16 +5,17 @@ use std::{
 };
 
 use atm0s_sdn_identity::{ConnId, NodeId};
-use atm0s_sdn_router::{RouteRule, ServiceBroadcastLevel};
+use atm0s_sdn_router::{core::RouterDump, RouteRule, ServiceBroadcastLevel};
+use atm0s_sdn_utils::log_sampled;
 use sans_io_runtime::collections::DynamicDeque;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::{
     base::{
-        ConnectionEvent, NetOutgoingMeta, Service, ServiceBuilder, ServiceControlActor, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput, ServiceWorker, ServiceWorkerCtx,
-        ServiceWorkerInput, ServiceWorkerOutput, Ttl,
+        CompressionConfig, ConnectionCtx, ConnectionEvent, NetOutgoingMeta, PayloadCompressor, Service, ServiceBuilder, ServiceControlActor, ServiceCtx, ServiceInput, ServiceOutput,
+        ServiceSharedInput, ServiceWorker, ServiceWorkerCtx, ServiceWorkerInput, ServiceWorkerOutput, Ttl,
     },
-    features::{data, FeaturesControl, FeaturesEvent},
+    features::{data, router_sync, FeaturesControl, FeaturesEvent},
 };
 
 pub const SERVICE_ID: u8 = 1;
@@ -26,6 +27,13 @@ const NODE_PING_TTL: u8 = 5;
 
 const DATA_PORT: u16 = 0;
 
+/// Subscriber which has this number of events without ack is marked slow, then it stops receiving changes until it acks
+pub const SUBSCRIBER_MAX_PENDING: usize = 256;
+/// Slow subscriber which doesn't ack in this duration is unsubscribed
+pub const SUBSCRIBER_EVICT_MS: u64 = 30000;
+/// Stats subscribers with a smaller interval are clamped to this
+pub const STATS_MIN_INTERVAL_MS: u64 = 1000;
+
 fn data_cmd<UserData, SE, TW>(cmd: data::Control) -> ServiceOutput<UserData, FeaturesControl, SE, TW> {
     ServiceOutput::FeatureControl(FeaturesControl::Data(cmd))
 }
@@ -37,19 +45,57 @@ pub struct ConnectionInfo {
     pub local: SocketAddr,
     pub remote: SocketAddr,
     pub rtt_ms: u32,
+    /// Counters since connected, summed from all workers of the node
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+}
+
+impl ConnectionInfo {
+    fn new(ctx: &ConnectionCtx) -> Self {
+        Self {
+            conn: ctx.conn,
+            dest: ctx.node,
+            local: ctx.pair.local,
+            remote: ctx.pair.remote,
+            rtt_ms: 1000,
+            rx_packets: 0,
+            rx_bytes: 0,
+            tx_packets: 0,
+            tx_bytes: 0,
+        }
+    }
+}
+
+/// Traffic and route table of a node, which are pushed to stats subscribers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeStats {
+    pub node: NodeId,
+    pub conns: Vec<ConnectionInfo>,
+    /// Only reported by nodes which are built with route table snapshots
+    pub routes: Option<RouterDump>,
 }
 
 struct NodeInfo<Info> {
     last_ping_ms: u64,
     info: Info,
     conns: Vec<ConnectionInfo>,
+    routes: Option<RouterDump>,
 }
 
 #[derive(Debug, Clone)]
 pub enum Control<Info> {
     Subscribe,
+    Unsubscribe,
+    /// Confirm that delivered events are consumed. A slow subscriber is resynced with a GotAll snapshot after ack
+    Ack,
+    /// Get all nodes, which also counts as an ack for a subscriber
     GetAll,
     UpdateInfo(Info),
+    /// Receive [`Event::Stats`] of all nodes every interval in milliseconds, subscribing again changes the interval
+    SubscribeStats(u64),
+    UnsubscribeStats,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -57,11 +103,28 @@ pub enum Event<Info> {
     GotAll(Vec<(NodeId, Info, Vec<ConnectionInfo>)>),
     NodeChanged(NodeId, Info, Vec<ConnectionInfo>),
     NodeRemoved(NodeId),
+    /// Subscriber is unsubscribed because it is slow and doesn't ack, it must subscribe again
+    Evicted,
+    /// Latest known traffic and routes of all nodes, for stats subscribers
+    Stats(Vec<NodeStats>),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 enum Message<Info> {
-    Snapshot(NodeId, Info, Vec<ConnectionInfo>),
+    Snapshot(NodeId, Info, Vec<ConnectionInfo>, Option<RouterDump>),
+}
+
+struct Subscriber<UserData> {
+    actor: ServiceControlActor<UserData>,
+    /// Events which are delivered after the last ack
+    pending: usize,
+    slow_since: Option<u64>,
+}
+
+struct StatsSubscriber<UserData> {
+    actor: ServiceControlActor<UserData>,
+    interval_ms: u64,
+    last_ms: u64,
 }
 
 pub struct VisualizationService<UserData, SC, SE, TC, TW, Info> {
@@ -71,12 +134,17 @@ pub struct VisualizationService<UserData, SC, SE, TC, TW, Info> {
     queue: VecDeque<ServiceOutput<UserData, FeaturesControl, SE, TW>>,
     conns: BTreeMap<ConnId, ConnectionInfo>,
     network_nodes: BTreeMap<NodeId, NodeInfo<Info>>,
-    subscribers: Vec<ServiceControlActor<UserData>>,
+    subscribers: Vec<Subscriber<UserData>>,
+    stats_subscribers: Vec<StatsSubscriber<UserData>>,
+    /// Attach router dump to snapshots, the dump is requested at each snapshot and sent with the next one
+    route_table: bool,
+    routes: Option<RouterDump>,
+    compressor: PayloadCompressor,
     shutdown: bool,
     _tmp: std::marker::PhantomData<(SC, TC)>,
 }
 
-impl<UserData: Copy, SC, SE, TC, TW, Info: Clone> VisualizationService<UserData, SC, SE, TC, TW, Info>
+impl<UserData: Copy + Eq, SC, SE, TC, TW, Info: Clone> VisualizationService<UserData, SC, SE, TC, TW, Info>
 where
     SC: From<Control<Info>> + TryInto<Control<Info>>,
     SE: From<Event<Info>> + TryInto<Event<Info>>,
@@ -90,16 +158,87 @@ where
             network_nodes: BTreeMap::new(),
             queue: VecDeque::from([ServiceOutput::FeatureControl(FeaturesControl::Data(data::Control::DataListen(DATA_PORT)))]),
             subscribers: Vec::new(),
+            stats_subscribers: Vec::new(),
+            route_table: false,
+            routes: None,
+            compressor: PayloadCompressor::new(SERVICE_NAME, None),
             shutdown: false,
             _tmp: std::marker::PhantomData,
         }
     }
 
-    fn fire_event(&mut self, event: Event<Info>) {
-        for sub in self.subscribers.iter() {
-            self.queue.push_back(ServiceOutput::Event(*sub, event.clone().into()));
+    pub fn with_route_table(mut self, enabled: bool) -> Self {
+        self.route_table = enabled;
+        self
+    }
+
+    pub fn with_compression(mut self, cfg: Option<CompressionConfig>) -> Self {
+        self.compressor = PayloadCompressor::new(SERVICE_NAME, cfg);
+        self
+    }
+
+    fn fire_event(&mut self, now: u64, event: Event<Info>) {
+        for sub in self.subscribers.iter_mut() {
+            if sub.slow_since.is_some() {
+                continue;
+            }
+            self.queue.push_back(ServiceOutput::Event(sub.actor, event.clone().into()));
+            sub.pending += 1;
+            if sub.pending >= SUBSCRIBER_MAX_PENDING {
+                log::warn!("[Visualization] Subscriber has {} events without ack, mark as slow", sub.pending);
+                sub.slow_since = Some(now);
+            }
         }
     }
+
+    fn push_all(&mut self, actor: ServiceControlActor<UserData>) {
+        let all = self.network_nodes.iter().map(|(k, v)| (*k, v.info.clone(), v.conns.clone())).collect();
+        self.queue.push_back(ServiceOutput::Event(actor, Event::GotAll(all).into()));
+    }
+
+    /// Reset pending events of a subscriber, return true if it was slow
+    fn ack(&mut self, actor: ServiceControlActor<UserData>) -> bool {
+        match self.subscribers.iter_mut().find(|sub| sub.actor == actor) {
+            Some(sub) => {
+                sub.pending = 0;
+                sub.slow_since.take().is_some()
+            }
+            None => false,
+        }
+    }
+
+    fn push_stats(&mut self, now: u64) {
+        if !self.stats_subscribers.iter().any(|sub| now >= sub.last_ms + sub.interval_ms) {
+            return;
+        }
+        let stats: Vec<_> = self
+            .network_nodes
+            .iter()
+            .map(|(node, info)| NodeStats {
+                node: *node,
+                conns: info.conns.clone(),
+                routes: info.routes.clone(),
+            })
+            .collect();
+        for sub in self.stats_subscribers.iter_mut() {
+            if now >= sub.last_ms + sub.interval_ms {
+                sub.last_ms = now;
+                self.queue.push_back(ServiceOutput::Event(sub.actor, Event::Stats(stats.clone()).into()));
+            }
+        }
+    }
+
+    fn evict_slow_subscribers(&mut self, now: u64) {
+        let queue = &mut self.queue;
+        self.subscribers.retain(|sub| match sub.slow_since {
+            Some(since) if now >= since + SUBSCRIBER_EVICT_MS => {
+                log::warn!("[Visualization] Evict slow subscriber without ack after {SUBSCRIBER_EVICT_MS} ms");
+                queue.push_back(ServiceOutput::Event(sub.actor, Event::Evicted.into()));
+                false
+            }
+            _ => true,
+        });
+    }
 }
 
 impl<UserData: Copy + Eq, SC, SE, TC, TW, Info> Service<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for VisualizationService<UserData, SC, SE, TC, TW, Info>
@@ -131,52 +270,47 @@ where
                     }
                 }
                 for node in to_remove {
-                    self.fire_event(Event::NodeRemoved(node));
+                    self.fire_event(now, Event::NodeRemoved(node));
                     self.network_nodes.remove(&node);
                 }
+                self.evict_slow_subscribers(now);
+                self.push_stats(now);
 
                 if now >= self.last_ping + NODE_PING_MS {
                     log::debug!("[Visualization] Sending Snapshot to collector with interval {NODE_PING_MS} ms with {} conns", self.conns.len());
                     self.last_ping = now;
-                    let msg = Message::Snapshot(ctx.node_id, self.info.clone(), self.conns.values().cloned().collect::<Vec<_>>());
+                    let msg = Message::Snapshot(ctx.node_id, self.info.clone(), self.conns.values().cloned().collect::<Vec<_>>(), self.routes.clone());
                     let seq = self.broadcast_seq;
                     self.broadcast_seq = self.broadcast_seq.wrapping_add(1);
                     self.queue.push_back(data_cmd(data::Control::DataSendRule(
                         DATA_PORT,
                         RouteRule::ToServices(SERVICE_ID, ServiceBroadcastLevel::Global, seq),
                         NetOutgoingMeta::new(false, Ttl(NODE_PING_TTL), 0, true),
-                        bincode::serialize(&msg).expect("Should to bytes"),
+                        self.compressor.encode(None, bincode::serialize(&msg).expect("Should to bytes")),
                     )));
+                    if self.route_table {
+                        self.queue.push_back(ServiceOutput::FeatureControl(FeaturesControl::RouterSync(router_sync::Control::DumpRouter)));
+                    }
                 }
             }
             ServiceSharedInput::Connection(ConnectionEvent::Connected(ctx, _)) => {
                 log::info!("[Visualization] New connection from {} to {}, set default rtt_ms to 1000ms", ctx.pair, ctx.node);
-                self.conns.insert(
-                    ctx.conn,
-                    ConnectionInfo {
-                        conn: ctx.conn,
-                        dest: ctx.node,
-                        local: ctx.pair.local,
-                        remote: ctx.pair.remote,
-                        rtt_ms: 1000,
-                    },
-                );
+                self.conns.insert(ctx.conn, ConnectionInfo::new(&ctx));
             }
             ServiceSharedInput::Connection(ConnectionEvent::Stats(ctx, stats)) => {
                 log::debug!("[Visualization] Update rtt_ms for connection from {} to {} to {}ms", ctx.pair, ctx.node, stats.rtt_ms);
-                let entry = self.conns.entry(ctx.conn).or_insert(ConnectionInfo {
-                    conn: ctx.conn,
-                    dest: ctx.node,
-                    local: ctx.pair.local,
-                    remote: ctx.pair.remote,
-                    rtt_ms: 1000,
-                });
+                let entry = self.conns.entry(ctx.conn).or_insert_with(|| ConnectionInfo::new(&ctx));
                 entry.rtt_ms = stats.rtt_ms;
+                entry.rx_packets = stats.traffic.rx.packets;
+                entry.rx_bytes = stats.traffic.rx.bytes;
+                entry.tx_packets = stats.traffic.tx.packets;
+                entry.tx_bytes = stats.traffic.tx.bytes;
             }
             ServiceSharedInput::Connection(ConnectionEvent::Disconnected(ctx)) => {
                 log::info!("[Visualization] Connection from {} to {} is disconnected", ctx.pair, ctx.node);
                 self.conns.remove(&ctx.conn);
             }
+            ServiceSharedInput::Connection(ConnectionEvent::Capabilities(..) | ConnectionEvent::ConnectFailed(..)) => {}
         }
     }
 
@@ -184,39 +318,68 @@ where
         match input {
             ServiceInput::FeatureEvent(FeaturesEvent::Data(data::Event::Recv(_port, meta, buf))) => {
                 if !meta.secure {
-                    log::warn!("[Visualization] reject unsecure message");
+                    log_sampled!(log::Level::Warn, "[Visualization] reject unsecure message");
                     return;
                 }
+                let Some(buf) = self.compressor.decode(meta.source, &buf) else {
+                    return;
+                };
                 if let Ok(msg) = bincode::deserialize::<Message<Info>>(&buf) {
                     match msg {
-                        Message::Snapshot(from, info, conns) => {
+                        Message::Snapshot(from, info, conns, routes) => {
                             log::debug!("[Visualization] Got snapshot from {} with info {:?} {} connections", from, info, conns.len());
-                            self.fire_event(Event::NodeChanged(from, info.clone(), conns.clone()));
-                            self.network_nodes.insert(from, NodeInfo { last_ping_ms: now, info, conns });
+                            self.fire_event(now, Event::NodeChanged(from, info.clone(), conns.clone()));
+                            self.network_nodes.insert(
+                                from,
+                                NodeInfo {
+                                    last_ping_ms: now,
+                                    info,
+                                    conns,
+                                    routes,
+                                },
+                            );
                         }
                     }
                 }
             }
+            ServiceInput::FeatureEvent(FeaturesEvent::RouterSync(router_sync::Event::DumpRouter(dump))) => {
+                self.routes = Some(*dump);
+            }
             ServiceInput::Control(actor, control) => {
-                let mut push_all = || {
-                    let all = self.network_nodes.iter().map(|(k, v)| (*k, v.info.clone(), v.conns.clone())).collect();
-                    self.queue.push_back(ServiceOutput::Event(actor, Event::GotAll(all).into()));
-                };
                 if let Ok(control) = control.try_into() {
                     match control {
                         Control::GetAll => {
-                            push_all();
+                            self.ack(actor);
+                            self.push_all(actor);
                         }
                         Control::Subscribe => {
-                            if !self.subscribers.contains(&actor) {
-                                self.subscribers.push(actor);
+                            if !self.subscribers.iter().any(|sub| sub.actor == actor) {
+                                self.subscribers.push(Subscriber { actor, pending: 0, slow_since: None });
                                 log::info!("[Visualization] New subscriber, sending snapshot with {} nodes", self.network_nodes.len());
-                                push_all();
+                                self.push_all(actor);
+                            }
+                        }
+                        Control::Unsubscribe => {
+                            self.subscribers.retain(|sub| sub.actor != actor);
+                        }
+                        Control::Ack => {
+                            if self.ack(actor) {
+                                log::info!("[Visualization] Slow subscriber acked, resync with snapshot of {} nodes", self.network_nodes.len());
+                                self.push_all(actor);
                             }
                         }
                         Control::UpdateInfo(info) => {
                             self.info = info;
                         }
+                        Control::SubscribeStats(interval_ms) => {
+                            let interval_ms = interval_ms.max(STATS_MIN_INTERVAL_MS);
+                            log::info!("[Visualization] Stats subscriber with interval {interval_ms} ms");
+                            self.stats_subscribers.retain(|sub| sub.actor != actor);
+                            self.stats_subscribers.push(StatsSubscriber { actor, interval_ms, last_ms: now });
+                        }
+                        Control::UnsubscribeStats => {
+                            self.stats_subscribers.retain(|sub| sub.actor != actor);
+                        }
                     }
                 }
             }
@@ -274,6 +437,8 @@ impl<UserData, SC, SE, TC, TW> ServiceWorker<UserData, FeaturesControl, Features
 pub struct VisualizationServiceBuilder<UserData, SC, SE, TC, TW, Info> {
     info: Info,
     collector: bool,
+    route_table: bool,
+    compression: Option<CompressionConfig>,
     _tmp: std::marker::PhantomData<(UserData, SC, SE, TC, TW, Info)>,
 }
 
@@ -283,9 +448,24 @@ impl<UserData, SC, SE, TC, TW, Info> VisualizationServiceBuilder<UserData, SC, S
         Self {
             info,
             collector,
+            route_table: false,
+            compression: None,
             _tmp: std::marker::PhantomData,
         }
     }
+
+    /// Attach route table of this node to snapshots, which makes each snapshot bigger with the size of the mesh
+    pub fn with_route_table(mut self, enabled: bool) -> Self {
+        self.route_table = enabled;
+        self
+    }
+
+    /// Compress snapshots, which are broadcast to collectors. The destination of a broadcast is unknown, so snapshots are
+    /// only compressed when the config assumes all nodes support it. Received compressed snapshots are always decoded
+    pub fn with_compression(mut self, cfg: Option<CompressionConfig>) -> Self {
+        self.compression = cfg;
+        self
+    }
 }
 
 impl<UserData, SC, SE, TC, TW, Info> ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for VisualizationServiceBuilder<UserData, SC, SE, TC, TW, Info>
@@ -310,7 +490,11 @@ where
     }
 
     fn create(&self) -> Box<dyn Service<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
-        Box::new(VisualizationService::new(self.info.clone()))
+        Box::new(
+            VisualizationService::new(self.info.clone())
+                .with_route_table(self.route_table)
+                .with_compression(self.compression.clone()),
+        )
     }
 
     fn create_worker(&self) -> Box<dyn ServiceWorker<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
@@ -323,18 +507,23 @@ where
 
 #[cfg(test)]
 mod test {
+    use std::sync::Arc;
+
     use atm0s_sdn_identity::{ConnId, NodeId};
-    use atm0s_sdn_router::{RouteRule, ServiceBroadcastLevel};
+    use atm0s_sdn_router::{core::Router, RouteRule, ServiceBroadcastLevel};
     use serde::{Deserialize, Serialize};
 
     use crate::{
-        base::{ConnectionCtx, ConnectionEvent, MockDecryptor, MockEncryptor, NetIncomingMeta, NetOutgoingMeta, SecureContext, Service, ServiceCtx, ServiceInput, ServiceSharedInput, Ttl},
+        base::{
+            CompressionConfig, ConnectionCtx, ConnectionEvent, LzCodec, MockDecryptor, MockEncryptor, NetIncomingMeta, NetOutgoingMeta, SecureContext, SecureInfo, Service, ServiceControlActor,
+            ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput, Ttl, COMPRESSED_MAGIC,
+        },
         data_plane::NetPair,
         features::{
             data::{Control as DataControl, Event as DataEvent},
-            FeaturesEvent,
+            router_sync, FeaturesControl, FeaturesEvent,
         },
-        services::visualization::{data_cmd, Message, DATA_PORT, NODE_PING_MS, NODE_PING_TTL, NODE_TIMEOUT_MS},
+        services::visualization::{data_cmd, Message, DATA_PORT, NODE_PING_MS, NODE_PING_TTL, NODE_TIMEOUT_MS, SUBSCRIBER_EVICT_MS, SUBSCRIBER_MAX_PENDING},
     };
 
     use super::{Control, Event, VisualizationService, SERVICE_ID};
@@ -352,11 +541,9 @@ mod test {
                 conn: ConnId::from_in(0, node as u64),
                 node,
                 pair: NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair"),
+                secure: SecureInfo::UNKNOWN,
             },
-            SecureContext {
-                encryptor: Box::new(MockEncryptor::new()),
-                decryptor: Box::new(MockDecryptor::new()),
-            },
+            SecureContext::new(Box::new(MockEncryptor::new()), Box::new(MockDecryptor::new())),
         )
     }
 
@@ -365,6 +552,7 @@ mod test {
             conn: ConnId::from_in(0, node as u64),
             node,
             pair: NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair"),
+            secure: SecureInfo::UNKNOWN,
         })
     }
 
@@ -385,7 +573,7 @@ mod test {
                 DATA_PORT,
                 RouteRule::ToServices(SERVICE_ID, ServiceBroadcastLevel::Global, 0),
                 NetOutgoingMeta::new(false, Ttl(NODE_PING_TTL), 0, true),
-                bincode::serialize(&Message::Snapshot(node_id, node_info.clone(), vec![])).expect("Should to bytes")
+                bincode::serialize(&Message::Snapshot(node_id, node_info.clone(), vec![], None)).expect("Should to bytes")
             )))
         );
 
@@ -396,7 +584,7 @@ mod test {
                 DATA_PORT,
                 RouteRule::ToServices(SERVICE_ID, ServiceBroadcastLevel::Global, 1),
                 NetOutgoingMeta::new(false, Ttl(NODE_PING_TTL), 0, true),
-                bincode::serialize(&Message::Snapshot(node_id, node_info.clone(), vec![])).expect("Should to bytes")
+                bincode::serialize(&Message::Snapshot(node_id, node_info.clone(), vec![], None)).expect("Should to bytes")
             )))
         );
     }
@@ -432,7 +620,7 @@ mod test {
         let node2_info = Info(2);
         let node2 = 2;
 
-        let snapshot = Message::Snapshot(node2, node2_info, vec![]);
+        let snapshot = Message::Snapshot(node2, node2_info, vec![], None);
         let buf = bincode::serialize(&snapshot).expect("Should to bytes");
         service.on_input(&ctx, 100, data_event(DataEvent::Recv(DATA_PORT, NetIncomingMeta::new(None, NODE_PING_TTL.into(), 0, true), buf)));
 
@@ -442,4 +630,114 @@ mod test {
         service.on_shared_input(&ctx, 100 + NODE_TIMEOUT_MS, ServiceSharedInput::Tick(0));
         assert_eq!(service.network_nodes.len(), 0);
     }
+
+    #[test]
+    fn compressed_snapshot_decoded_by_collector() {
+        let ctx = ServiceCtx { node_id: 1, session: 0 };
+        let compression = CompressionConfig::new(Arc::new(LzCodec), 64).with_assume_supported(true);
+        let mut agent = VisualizationService::<(), Control<Info>, Event<Info>, (), (), _>::new(Info(1)).with_compression(Some(compression.clone()));
+        for node in 2..20 {
+            agent.on_shared_input(&ctx, 0, ServiceSharedInput::Connection(connected_event(node)));
+        }
+        assert_eq!(agent.pop_output2(0), Some(data_cmd(DataControl::DataListen(DATA_PORT))));
+        agent.on_shared_input(&ctx, NODE_PING_MS, ServiceSharedInput::Tick(0));
+        let buf = match agent.pop_output2(NODE_PING_MS) {
+            Some(ServiceOutput::FeatureControl(FeaturesControl::Data(DataControl::DataSendRule(_, _, _, buf)))) => buf,
+            out => panic!("Should send snapshot, got {:?}", out),
+        };
+        assert_eq!(buf[0], COMPRESSED_MAGIC);
+        assert_eq!(compression.stats()["visualization"].compressed_msgs, 1);
+
+        // collector without compression config still decodes it
+        let collector_ctx = ServiceCtx { node_id: 100, session: 0 };
+        let mut collector = VisualizationService::<(), Control<Info>, Event<Info>, (), (), _>::new(Info(100));
+        collector.on_input(
+            &collector_ctx,
+            100,
+            data_event(DataEvent::Recv(DATA_PORT, NetIncomingMeta::new(Some(1), NODE_PING_TTL.into(), 0, true), buf)),
+        );
+        assert_eq!(collector.network_nodes.get(&1).map(|n| n.conns.len()), Some(18));
+    }
+
+    #[test]
+    fn collector_mark_and_evict_slow_subscriber() {
+        let ctx = ServiceCtx { node_id: 1, session: 0 };
+        let mut service = VisualizationService::<(), Control<Info>, Event<Info>, (), (), _>::new(Info(1));
+        let actor = ServiceControlActor::Controller(());
+        let snapshot = |node: NodeId| {
+            let buf = bincode::serialize(&Message::Snapshot(node, Info(2), vec![], None)).expect("Should to bytes");
+            data_event(DataEvent::Recv(DATA_PORT, NetIncomingMeta::new(None, NODE_PING_TTL.into(), 0, true), buf))
+        };
+        let events = |service: &mut VisualizationService<(), Control<Info>, Event<Info>, (), (), Info>| {
+            let mut events = vec![];
+            while let Some(out) = service.pop_output2(0) {
+                if let ServiceOutput::Event(_, event) = out {
+                    events.push(event);
+                }
+            }
+            events
+        };
+
+        service.on_input(&ctx, 0, ServiceInput::Control(actor, Control::Subscribe));
+        assert_eq!(events(&mut service), vec![Event::GotAll(vec![])]);
+
+        // changes are dropped after max pending events
+        for i in 0..SUBSCRIBER_MAX_PENDING + 10 {
+            service.on_input(&ctx, 100, snapshot(2 + i as NodeId % 2));
+        }
+        assert_eq!(events(&mut service).len(), SUBSCRIBER_MAX_PENDING);
+
+        // ack resyncs with a snapshot
+        service.on_input(&ctx, 200, ServiceInput::Control(actor, Control::Ack));
+        assert_eq!(events(&mut service), vec![Event::GotAll(vec![(2, Info(2), vec![]), (3, Info(2), vec![])])]);
+        service.on_input(&ctx, 300, snapshot(2));
+        assert_eq!(events(&mut service), vec![Event::NodeChanged(2, Info(2), vec![])]);
+
+        // slow subscriber which never acks is evicted
+        for _ in 0..SUBSCRIBER_MAX_PENDING {
+            service.on_input(&ctx, 400, snapshot(2));
+        }
+        assert_eq!(events(&mut service).len(), SUBSCRIBER_MAX_PENDING - 1);
+        service.on_shared_input(&ctx, 400 + SUBSCRIBER_EVICT_MS - 1, ServiceSharedInput::Tick(0));
+        assert!(!events(&mut service).contains(&Event::Evicted));
+        service.on_shared_input(&ctx, 400 + SUBSCRIBER_EVICT_MS, ServiceSharedInput::Tick(0));
+        assert!(events(&mut service).contains(&Event::Evicted));
+        service.on_input(&ctx, 500 + SUBSCRIBER_EVICT_MS, snapshot(2));
+        assert_eq!(events(&mut service), vec![]);
+    }
+
+    #[test]
+    fn agent_attach_route_table_to_next_snapshot() {
+        let node_info = Info(1);
+        let node_id = 1;
+        let ctx = ServiceCtx { node_id, session: 0 };
+        let mut service = VisualizationService::<(), Control<Info>, Event<Info>, (), (), _>::new(node_info.clone()).with_route_table(true);
+        assert_eq!(service.pop_output2(0), Some(data_cmd(DataControl::DataListen(DATA_PORT))));
+
+        let snapshot = |seq: u16, routes| {
+            data_cmd(DataControl::DataSendRule(
+                DATA_PORT,
+                RouteRule::ToServices(SERVICE_ID, ServiceBroadcastLevel::Global, seq),
+                NetOutgoingMeta::new(false, Ttl(NODE_PING_TTL), 0, true),
+                bincode::serialize(&Message::Snapshot(node_id, node_info.clone(), vec![], routes)).expect("Should to bytes"),
+            ))
+        };
+
+        service.on_shared_input(&ctx, NODE_PING_MS, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(NODE_PING_MS), Some(snapshot(0, None)));
+        assert_eq!(
+            service.pop_output2(NODE_PING_MS),
+            Some(ServiceOutput::FeatureControl(FeaturesControl::RouterSync(router_sync::Control::DumpRouter)))
+        );
+
+        let dump = Router::new(node_id).dump();
+        service.on_input(
+            &ctx,
+            NODE_PING_MS,
+            ServiceInput::FeatureEvent(FeaturesEvent::RouterSync(router_sync::Event::DumpRouter(Box::new(dump.clone())))),
+        );
+
+        service.on_shared_input(&ctx, NODE_PING_MS * 2, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(NODE_PING_MS * 2), Some(snapshot(1, Some(dump))));
+    }
 }
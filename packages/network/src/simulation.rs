@@ -0,0 +1,296 @@
+//! Deterministic simulation of many nodes over an in-memory network.
+//!
+//! Each node is a [`SdnWorker`] with controller and a single data plane. Packets between nodes go through a medium with
+//! per-link latency, jitter and loss, and nodes can be split into partitions. Time is virtual and advanced with
+//! [`Simulation::advance`], and latency, jitter and loss of each link are drawn from the seed, so timing of a scenario is the same in
+//! each run. Nodes still have their own randomness like hash map order, crypto nonces and membership probes, so tests should check
+//! outcomes like routes, delivered messages and measured delays instead of exact packet counts.
+//!
+//! Node addresses are generated from node ids with [`Simulation::node_addr`], which requires node ids below 2^24.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt::Debug,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use atm0s_sdn_identity::{NodeAddr, NodeAddrBuilder, NodeId, Protocol};
+use atm0s_sdn_router::shadow::ShadowRouterHistory;
+use parking_lot::Mutex;
+use rand::rngs::mock::StepRng;
+use sans_io_runtime::{return_if_none, TaskSwitcherChild};
+
+use crate::{
+    base::{Capabilities, LatencyProfile, LinkProfile, ServiceBuilder},
+    controller_plane::{ControllerMetrics, ControllerPlaneCfg},
+    data_plane::{DataPlaneCfg, DataPlaneMetrics, NetInput, NetOutput, NetPair},
+    features::{FeaturesControl, FeaturesEvent},
+    secure::{HandshakeBuilderXDA, StaticKeyAuthorization},
+    worker::{SdnWorker, SdnWorkerCfg, SdnWorkerInput, SdnWorkerOutput},
+    ExtIn, ExtOut,
+};
+
+use self::medium::Medium;
+pub use self::medium::{LinkModel, MediumStats};
+
+mod medium;
+
+/// Interval of node ticks in virtual time
+pub const SIM_TICK_MS: u64 = 10;
+/// Udp port of all generated node addresses
+pub const SIM_PORT: u16 = 10000;
+
+type BroadcastKey = (Option<NodeId>, u8, u16);
+
+#[derive(Default)]
+struct History {
+    received: Mutex<(HashSet<BroadcastKey>, VecDeque<BroadcastKey>)>,
+}
+
+impl ShadowRouterHistory for History {
+    fn already_received_broadcast(&self, from: Option<NodeId>, service: u8, seq: u16) -> bool {
+        let mut received = self.received.lock();
+        if !received.0.insert((from, service, seq)) {
+            return true;
+        }
+        received.1.push_back((from, service, seq));
+        if received.1.len() > 1000 {
+            if let Some(old) = received.1.pop_front() {
+                received.0.remove(&old);
+            }
+        }
+        false
+    }
+
+    fn set_ts(&self, _now: u64) {}
+}
+
+pub struct Simulation<SC, SE, TC, TW> {
+    now_ms: u64,
+    tick_ms: u64,
+    next_tick_ms: u64,
+    /// Ordered by node id, so nodes are processed in the same order in each run. Workers are boxed because they are too large for moving on stack
+    #[allow(clippy::type_complexity)]
+    nodes: BTreeMap<NodeId, Box<SdnWorker<(), SC, SE, TC, TW>>>,
+    addrs: HashMap<SocketAddr, NodeId>,
+    medium: Medium,
+    output: VecDeque<(NodeId, ExtOut<(), SE>)>,
+}
+
+impl<SC: Debug, SE: Debug, TC: Debug, TW: Debug> Simulation<SC, SE, TC, TW> {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            now_ms: 0,
+            tick_ms: SIM_TICK_MS,
+            next_tick_ms: 0,
+            nodes: BTreeMap::new(),
+            addrs: HashMap::new(),
+            medium: Medium::new(seed),
+            output: VecDeque::new(),
+        }
+    }
+
+    pub fn with_tick_ms(mut self, tick_ms: u64) -> Self {
+        assert!(tick_ms > 0, "tick_ms should be greater than 0");
+        self.tick_ms = tick_ms;
+        self
+    }
+
+    /// Address which a node binds in the simulation, like 10.0.0.1:10000 for node 1
+    pub fn node_addr(node: NodeId) -> SocketAddr {
+        assert!(node < 1 << 24, "simulation supports node ids below 2^24");
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::from(0x0a00_0000 | node)), SIM_PORT)
+    }
+
+    /// Config of a node which binds [`Self::node_addr`] and authorizes with a shared key. Fields can be changed before [`Self::add_node`]
+    #[allow(clippy::type_complexity)]
+    pub fn node_cfg(node_id: NodeId, session: u64, services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>) -> SdnWorkerCfg<(), SC, SE, TC, TW> {
+        let history = Arc::new(History::default());
+        SdnWorkerCfg {
+            node_id,
+            tick_ms: 1,
+            controller: Some(ControllerPlaneCfg {
+                session,
+                bind_addrs: vec![Self::node_addr(node_id)],
+                services: services.clone(),
+                authorization: Arc::new(StaticKeyAuthorization::new("simulation")),
+                handshake_builder: Arc::new(HandshakeBuilderXDA),
+                random: Box::new(StepRng::new(node_id as u64 * 1000, 5)),
+                history: history.clone(),
+                profile: LatencyProfile::default(),
+                link: LinkProfile::Standard,
+                dht_kv_storage: None,
+                channel_authorizer: None,
+                observer: false,
+                capabilities: Capabilities::SUPPORTED,
+                compression: None,
+                rekey_interval_ms: None,
+                neighbour_policy: Default::default(),
+                sticky_ext: None,
+                routing_policy: Default::default(),
+                checkpoint: None,
+                event_sinks: vec![],
+            }),
+            standby: None,
+            replication: None,
+            data: DataPlaneCfg {
+                worker_id: 0,
+                services,
+                history,
+                scheduler: None,
+                bandwidth_limit_kbps: None,
+                service_shaping: vec![],
+                multipath: None,
+                incoming_route: false,
+                sticky_ext: None,
+                fragment: Default::default(),
+            },
+        }
+    }
+
+    /// Add a node which runs the controller, return its address for connecting from other nodes
+    pub fn add_node(&mut self, cfg: SdnWorkerCfg<(), SC, SE, TC, TW>) -> NodeAddr {
+        let node_id = cfg.node_id;
+        let controller = cfg.controller.as_ref().expect("Simulation node should have controller");
+        assert!(!self.nodes.contains_key(&node_id), "node {node_id} already exists");
+        let mut builder = NodeAddrBuilder::new(node_id);
+        for addr in &controller.bind_addrs {
+            match addr.ip() {
+                IpAddr::V4(ip) => builder.add_protocol(Protocol::Ip4(ip)),
+                IpAddr::V6(ip) => builder.add_protocol(Protocol::Ip6(ip)),
+            }
+            builder.add_protocol(Protocol::Udp(addr.port()));
+            self.addrs.insert(*addr, node_id);
+        }
+        self.nodes.insert(node_id, Box::new(SdnWorker::new(cfg)));
+        builder.addr()
+    }
+
+    /// Remove a node immediately without notifying its neighbours, which simulates a crash
+    pub fn remove_node(&mut self, node: NodeId) {
+        self.nodes.remove(&node).expect("Node not found");
+        self.addrs.retain(|_, n| *n != node);
+        self.medium.remove_node(node);
+    }
+
+    /// Gracefully shutdown a node, it is removed after all of its resources are released
+    pub fn leave_node(&mut self, node: NodeId) {
+        let worker = self.nodes.get_mut(&node).expect("Node not found");
+        worker.on_shutdown(self.now_ms);
+        self.pump(node);
+    }
+
+    pub fn has_node(&self, node: NodeId) -> bool {
+        self.nodes.contains_key(&node)
+    }
+
+    pub fn now_ms(&self) -> u64 {
+        self.now_ms
+    }
+
+    pub fn stats(&self) -> MediumStats {
+        self.medium.stats()
+    }
+
+    pub fn controller_metrics(&self, node: NodeId) -> Option<ControllerMetrics> {
+        self.nodes.get(&node)?.controller_metrics()
+    }
+
+    pub fn data_metrics(&self, node: NodeId) -> Option<DataPlaneMetrics> {
+        Some(self.nodes.get(&node)?.data_metrics())
+    }
+
+    /// Model of links which are not set with [`Self::set_link`]
+    pub fn set_default_link(&mut self, model: LinkModel) {
+        self.medium.set_default_link(model);
+    }
+
+    pub fn set_link(&mut self, node1: NodeId, node2: NodeId, model: LinkModel) {
+        self.medium.set_link(node1, node2, model);
+    }
+
+    /// Drop packets between nodes of different groups, nodes which are not listed form one more group. It replaces the previous partition
+    pub fn partition(&mut self, groups: &[&[NodeId]]) {
+        log::info!("[Simulation] partition {:?} at {} ms", groups, self.now_ms);
+        self.medium.partition(groups);
+    }
+
+    pub fn heal(&mut self) {
+        log::info!("[Simulation] heal partition at {} ms", self.now_ms);
+        self.medium.heal();
+    }
+
+    /// Send a control to the controller of a node at current time
+    pub fn control(&mut self, node: NodeId, control: ExtIn<(), SC>) {
+        let worker = self.nodes.get_mut(&node).expect("Node not found");
+        worker.on_event(self.now_ms, SdnWorkerInput::Ext(control));
+        self.pump(node);
+    }
+
+    pub fn pop_output(&mut self) -> Option<(NodeId, ExtOut<(), SE>)> {
+        self.output.pop_front()
+    }
+
+    /// Run the simulation until `now_ms + delta_ms`, nodes are ticked each tick interval and packets are delivered at their exact time
+    pub fn advance(&mut self, delta_ms: u64) {
+        let end_ms = self.now_ms + delta_ms;
+        loop {
+            let next_ms = self.medium.next_at().map_or(self.next_tick_ms, |at| at.min(self.next_tick_ms));
+            if next_ms > end_ms {
+                break;
+            }
+            self.now_ms = self.now_ms.max(next_ms);
+            if self.now_ms >= self.next_tick_ms {
+                self.next_tick_ms = self.now_ms + self.tick_ms;
+                let nodes: Vec<_> = self.nodes.keys().copied().collect();
+                for node in nodes {
+                    if let Some(worker) = self.nodes.get_mut(&node) {
+                        worker.on_tick(self.now_ms);
+                    }
+                    self.pump(node);
+                    if self.nodes.get(&node).is_some_and(|worker| worker.is_empty()) {
+                        log::info!("[Simulation] node {node} finished shutdown at {} ms", self.now_ms);
+                        self.remove_node(node);
+                    }
+                }
+            }
+            while let Some(packet) = self.medium.pop_due(self.now_ms) {
+                let worker = match self.nodes.get_mut(&packet.to) {
+                    Some(worker) => worker,
+                    None => continue,
+                };
+                worker.on_event(self.now_ms, SdnWorkerInput::Net(NetInput::UdpPacket(packet.pair, packet.data)));
+                self.pump(packet.to);
+            }
+        }
+        self.now_ms = end_ms;
+    }
+
+    /// Pop all outputs of a node, packets are sent over the medium
+    fn pump(&mut self, node: NodeId) {
+        let now_ms = self.now_ms;
+        let worker = return_if_none!(self.nodes.get_mut(&node));
+        while let Some(out) = worker.pop_output(now_ms) {
+            let (pairs, data) = match out {
+                SdnWorkerOutput::Ext(out) | SdnWorkerOutput::ExtWorker(out) => {
+                    self.output.push_back((node, out));
+                    continue;
+                }
+                SdnWorkerOutput::Net(NetOutput::UdpPacket(pair, data, _)) => (vec![pair], data),
+                SdnWorkerOutput::Net(NetOutput::UdpPackets(pairs, data, _)) => (pairs, data),
+                SdnWorkerOutput::Bus(bus) => {
+                    worker.on_event(now_ms, SdnWorkerInput::Bus(bus));
+                    continue;
+                }
+                _ => continue,
+            };
+            for pair in pairs {
+                match self.addrs.get(&pair.remote) {
+                    Some(to) => self.medium.send(now_ms, node, *to, NetPair::new(pair.remote, pair.local), data.clone()),
+                    None => log::debug!("[Simulation] drop packet from {node} to unknown address {}", pair.remote),
+                }
+            }
+        }
+    }
+}
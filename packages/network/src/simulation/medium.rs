@@ -0,0 +1,248 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+};
+
+use atm0s_sdn_identity::NodeId;
+use sans_io_runtime::Buffer;
+
+use crate::data_plane::NetPair;
+
+/// Behavior of a simulated link, which is applied to each packet in both directions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkModel {
+    pub latency_ms: u64,
+    /// Random extra delay in `0..=jitter_ms`, packets can be reordered by it
+    pub jitter_ms: u64,
+    /// Probability to drop a packet, from 0.0 to 1.0
+    pub loss: f32,
+}
+
+impl LinkModel {
+    pub fn new(latency_ms: u64) -> Self {
+        Self { latency_ms, jitter_ms: 0, loss: 0.0 }
+    }
+
+    pub fn with_jitter(mut self, jitter_ms: u64) -> Self {
+        self.jitter_ms = jitter_ms;
+        self
+    }
+
+    pub fn with_loss(mut self, loss: f32) -> Self {
+        assert!((0.0..=1.0).contains(&loss), "loss should be in 0.0..=1.0");
+        self.loss = loss;
+        self
+    }
+}
+
+impl Default for LinkModel {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+/// Counters of packets which are sent over the medium
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MediumStats {
+    pub sent: u64,
+    pub delivered: u64,
+    /// Dropped by the loss of links
+    pub lost: u64,
+    /// Dropped because nodes are in different partitions
+    pub partitioned: u64,
+}
+
+pub(crate) struct Packet {
+    pub to: NodeId,
+    /// Pair which is seen by the receiver
+    pub pair: NetPair,
+    pub data: Buffer,
+}
+
+/// Packets which are sent over a link in one direction
+#[derive(Default)]
+struct LinkState {
+    seq: u64,
+    last_ms: u64,
+    /// Index of the packet in the last ms
+    index: u64,
+}
+
+/// In-memory medium between nodes, all randomness is derived from the seed.
+///
+/// Nodes can send a different number of packets in each run, for example because of hash map order. So jitter and loss are not
+/// drawn from one random stream, they are hashed from the seed, the link and the send time, then a packet which is sent at the same
+/// time over the same link has the same fate in each run. Jitter is the same for all packets of a link in a ms, which keeps them in order.
+pub(crate) struct Medium {
+    seed: u64,
+    states: HashMap<(NodeId, NodeId), LinkState>,
+    default_link: LinkModel,
+    links: HashMap<(NodeId, NodeId), LinkModel>,
+    /// Group of each node, nodes which are not listed are in group 0
+    partitions: HashMap<NodeId, usize>,
+    /// Packets in flight, ordered by delivery time, receiver, sender then send order of the link
+    queue: BTreeMap<(u64, NodeId, NodeId, u64), Packet>,
+    stats: MediumStats,
+}
+
+impl Medium {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            states: HashMap::new(),
+            default_link: LinkModel::default(),
+            links: HashMap::new(),
+            partitions: HashMap::new(),
+            queue: BTreeMap::new(),
+            stats: MediumStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> MediumStats {
+        self.stats
+    }
+
+    pub fn set_default_link(&mut self, model: LinkModel) {
+        self.default_link = model;
+    }
+
+    pub fn set_link(&mut self, node1: NodeId, node2: NodeId, model: LinkModel) {
+        self.links.insert((node1, node2), model);
+        self.links.insert((node2, node1), model);
+    }
+
+    pub fn partition(&mut self, groups: &[&[NodeId]]) {
+        self.partitions.clear();
+        for (index, group) in groups.iter().enumerate() {
+            for node in group.iter() {
+                self.partitions.insert(*node, index + 1);
+            }
+        }
+    }
+
+    pub fn heal(&mut self) {
+        self.partitions.clear();
+    }
+
+    /// Remove packets in flight to a node, which is removed from the simulation
+    pub fn remove_node(&mut self, node: NodeId) {
+        self.queue.retain(|_, packet| packet.to != node);
+        self.states.retain(|(from, to), _| *from != node && *to != node);
+    }
+
+    pub fn send(&mut self, now_ms: u64, from: NodeId, to: NodeId, pair: NetPair, data: Buffer) {
+        self.stats.sent += 1;
+        if self.partitions.get(&from).unwrap_or(&0) != self.partitions.get(&to).unwrap_or(&0) {
+            log::debug!("[Simulation] drop packet from {from} to {to} by partition");
+            self.stats.partitioned += 1;
+            return;
+        }
+        let model = *self.links.get(&(from, to)).unwrap_or(&self.default_link);
+        let state = self.states.entry((from, to)).or_default();
+        if state.last_ms != now_ms {
+            state.last_ms = now_ms;
+            state.index = 0;
+        }
+        state.index += 1;
+        state.seq += 1;
+        let (index, seq) = (state.index, state.seq);
+        if model.loss > 0.0 && (self.draw(from, to, now_ms, index) % 1_000_000) as f32 / 1_000_000.0 < model.loss {
+            log::debug!("[Simulation] drop packet from {from} to {to} by loss");
+            self.stats.lost += 1;
+            return;
+        }
+        let jitter = if model.jitter_ms > 0 {
+            self.draw(from, to, now_ms, 0) % (model.jitter_ms + 1)
+        } else {
+            0
+        };
+        self.queue.insert((now_ms + model.latency_ms + jitter, to, from, seq), Packet { to, pair, data });
+    }
+
+    /// DefaultHasher::new() has fixed keys, so it gives the same value in each run
+    fn draw(&self, from: NodeId, to: NodeId, now_ms: u64, index: u64) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (self.seed, from, to, now_ms, index).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Delivery time of the next packet in flight
+    pub fn next_at(&self) -> Option<u64> {
+        self.queue.keys().next().map(|(at, ..)| *at)
+    }
+
+    pub fn pop_due(&mut self, now_ms: u64) -> Option<Packet> {
+        if self.next_at()? > now_ms {
+            return None;
+        }
+        let (_, packet) = self.queue.pop_first()?;
+        self.stats.delivered += 1;
+        Some(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data_plane::NetPair;
+
+    use super::{LinkModel, Medium, MediumStats};
+
+    fn pair() -> NetPair {
+        NetPair::new_str("10.0.0.2:10000", "10.0.0.1:10000").expect("Should parse")
+    }
+
+    fn deliveries(medium: &mut Medium, until: u64) -> Vec<(u64, u8)> {
+        let mut res = vec![];
+        for now in 0..=until {
+            while let Some(packet) = medium.pop_due(now) {
+                res.push((now, packet.data[0]));
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn latency_jitter_and_loss_are_deterministic() {
+        let run = |seed: u64| {
+            let mut medium = Medium::new(seed);
+            medium.set_link(1, 2, LinkModel::new(20).with_jitter(10).with_loss(0.3));
+            for i in 0..50u8 {
+                medium.send(0, 1, 2, pair(), vec![i].into());
+            }
+            (deliveries(&mut medium, 100), medium.stats())
+        };
+
+        let (delivered, stats) = run(42);
+        assert_eq!(run(42), (delivered.clone(), stats));
+        assert_ne!(run(43).0, delivered);
+        assert!(delivered.iter().all(|(at, _)| (20..=30).contains(at)));
+        assert_eq!(stats.sent, 50);
+        assert_eq!(stats.delivered as usize, delivered.len());
+        assert_eq!(stats.lost + stats.delivered, 50);
+        assert!(stats.lost > 0);
+    }
+
+    #[test]
+    fn partition_drops_packets_between_groups() {
+        let mut medium = Medium::new(0);
+        medium.partition(&[&[1], &[2, 3]]);
+        medium.send(0, 1, 2, pair(), vec![1].into());
+        medium.send(0, 2, 3, pair(), vec![2].into());
+        // node 4 is not listed, so it is in the same group as other unlisted nodes only
+        medium.send(0, 4, 3, pair(), vec![3].into());
+        medium.heal();
+        medium.send(0, 1, 2, pair(), vec![4].into());
+
+        // packets which are delivered at the same time are ordered by receiver
+        assert_eq!(deliveries(&mut medium, 10), vec![(1, 4), (1, 2)]);
+        assert_eq!(
+            medium.stats(),
+            MediumStats {
+                sent: 4,
+                delivered: 2,
+                lost: 0,
+                partitioned: 2
+            }
+        );
+    }
+}
@@ -0,0 +1,526 @@
+//! Canonical wire examples for validating compatible implementations.
+//!
+//! Each vector is a named message built from fixed inputs and serialized exactly as it is sent over the network:
+//!
+//! - `neighbours/*`: full neighbours control packet (first byte 255, then bincode of [`NeighboursControl`]), signed with `StaticKeyAuthorization::new(STATIC_KEY)`.
+//! - `header/*`: [`TransportMsgHeader`] bytes only.
+//! - `dht_kv/*`: dht_kv remote command, which is the payload after the transport header.
+//! - `pubsub/*`: full pubsub packet, including transport header.
+//! - `router_sync/*`: router sync payload which is sent directly to a neighbour, with extensions of the capabilities in its name.
+//!
+//! Golden bytes are stored in `test_vectors/wire.hex` as `name hex` lines and can be loaded with [`golden`].
+//! Third-party implementations (or FFI ports) should be able to decode each golden packet and produce the same bytes when re-encoding.
+
+use std::fmt::Write;
+
+use atm0s_sdn_router::{
+    core::{Metric, RegistrySync, RouterSync, TableSync},
+    RouteRule, ServiceBroadcastLevel,
+};
+use sans_io_runtime::Buffer;
+
+use crate::{
+    base::{Capabilities, NeighboursConnectError, NeighboursControl, NeighboursControlCmds, NeighboursDisconnectReason, TransportMsgHeader},
+    features::{
+        dht_kv::msg::{BatchOp, ClientCommand, ClientMapCommand, Key, KeyRange, Map, MapAcl, NodeSession, RemoteCommand, ServerEvent, ServerMapEvent, Version},
+        pubsub::{
+            fec::FecHeader,
+            msg::{ChannelId, Feedback, PubsubMessage, RelayControl, RelayId, SourceHint},
+        },
+        router_sync::{decode_sync, encode_sync},
+    },
+    secure::StaticKeyAuthorization,
+};
+
+/// Key used for signing neighbours control vectors
+pub const STATIC_KEY: &str = "demo_key";
+/// Timestamp used for building neighbours control vectors
+pub const NOW_MS: u64 = 1_700_000_000_000;
+
+pub const GOLDEN_FILE: &str = include_str!("../test_vectors/wire.hex");
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestVector {
+    pub name: &'static str,
+    pub bytes: Vec<u8>,
+}
+
+impl TestVector {
+    fn new(name: &'static str, bytes: Vec<u8>) -> Self {
+        Self { name, bytes }
+    }
+}
+
+pub fn neighbours_control() -> Vec<TestVector> {
+    neighbours_cmds()
+        .into_iter()
+        .map(|(name, cmd)| {
+            let auth = StaticKeyAuthorization::new(STATIC_KEY);
+            let control = NeighboursControl::build(NOW_MS, 1, cmd, &auth);
+            TestVector::new(name, (&control).try_into().expect("Should serialize neighbours control"))
+        })
+        .collect()
+}
+
+pub fn transport_headers() -> Vec<TestVector> {
+    headers()
+        .into_iter()
+        .map(|(name, header)| {
+            let mut buf = [0; 16];
+            let len = header.to_bytes(&mut buf).expect("Should serialize header");
+            TestVector::new(name, buf[..len].to_vec())
+        })
+        .collect()
+}
+
+pub fn dht_kv() -> Vec<TestVector> {
+    dht_kv_cmds()
+        .into_iter()
+        .map(|(name, cmd)| TestVector::new(name, bincode::serialize(&cmd).expect("Should serialize dht_kv command")))
+        .collect()
+}
+
+pub fn pubsub() -> Vec<TestVector> {
+    pubsub_msgs()
+        .into_iter()
+        .map(|(name, msg)| {
+            let buf: Buffer = msg.into();
+            TestVector::new(name, buf.to_vec())
+        })
+        .collect()
+}
+
+pub fn router_sync() -> Vec<TestVector> {
+    router_syncs().into_iter().map(|(name, caps, sync)| TestVector::new(name, encode_sync(&sync, caps))).collect()
+}
+
+/// All vectors, in the same order as the golden file
+pub fn all() -> Vec<TestVector> {
+    let mut res = neighbours_control();
+    res.extend(transport_headers());
+    res.extend(dht_kv());
+    res.extend(pubsub());
+    res.extend(router_sync());
+    res
+}
+
+/// Parse golden file, empty lines and lines starting with # are ignored
+pub fn golden() -> Vec<(&'static str, Vec<u8>)> {
+    GOLDEN_FILE
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| {
+            let (name, hex) = l.split_once(' ').expect("Golden line should be `name hex`");
+            (name, from_hex(hex.trim()).expect("Golden bytes should be valid hex"))
+        })
+        .collect()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut out, b| {
+        let _ = write!(out, "{:02x}", b);
+        out
+    })
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn neighbours_cmds() -> Vec<(&'static str, NeighboursControlCmds)> {
+    vec![
+        (
+            "neighbours/connect_request",
+            NeighboursControlCmds::ConnectRequest {
+                to: 2,
+                session: 1000,
+                handshake: vec![1, 2, 3, 4],
+            },
+        ),
+        (
+            "neighbours/connect_response_ok",
+            NeighboursControlCmds::ConnectResponse {
+                session: 1000,
+                result: Ok(vec![5, 6, 7, 8]),
+            },
+        ),
+        (
+            "neighbours/connect_response_err",
+            NeighboursControlCmds::ConnectResponse {
+                session: 1000,
+                result: Err(NeighboursConnectError::InvalidSignature),
+            },
+        ),
+        (
+            "neighbours/ping",
+            NeighboursControlCmds::Ping {
+                session: 1000,
+                seq: 1,
+                sent_ms: NOW_MS,
+            },
+        ),
+        (
+            "neighbours/pong",
+            NeighboursControlCmds::Pong {
+                session: 1000,
+                seq: 1,
+                sent_ms: NOW_MS,
+            },
+        ),
+        (
+            "neighbours/disconnect_request",
+            NeighboursControlCmds::DisconnectRequest {
+                session: 1000,
+                reason: NeighboursDisconnectReason::Shutdown,
+            },
+        ),
+        ("neighbours/disconnect_response", NeighboursControlCmds::DisconnectResponse { session: 1000 }),
+        ("neighbours/link_profile", NeighboursControlCmds::LinkProfile { session: 1000, mtu: 64 }),
+        ("neighbours/link_profile_ack", NeighboursControlCmds::LinkProfileAck { session: 1000, mtu: 64 }),
+    ]
+}
+
+fn headers() -> Vec<(&'static str, TransportMsgHeader)> {
+    vec![
+        ("header/direct", TransportMsgHeader::build(1, 0, RouteRule::Direct)),
+        ("header/to_node", TransportMsgHeader::build(2, 3, RouteRule::ToNode(0x01020304)).set_ttl(10)),
+        ("header/to_service", TransportMsgHeader::build(3, 0, RouteRule::ToService(100))),
+        ("header/to_services", TransportMsgHeader::build(3, 0, RouteRule::ToServices(100, ServiceBroadcastLevel::Geo2, 1000))),
+        ("header/to_key", TransportMsgHeader::build(4, 0, RouteRule::ToKey(0x0a0b0c0d))),
+        (
+            "header/from_node_secure",
+            TransportMsgHeader::build(5, 1, RouteRule::ToNode(2)).set_from_node(Some(1)).set_encrypt(true),
+        ),
+        (
+            "header/to_key_dedup",
+            TransportMsgHeader::build(4, 0, RouteRule::ToKey(0x0a0b0c0d)).set_from_node(Some(1)).set_dedup(Some(0x11223344)),
+        ),
+    ]
+}
+
+fn dht_kv_cmds() -> Vec<(&'static str, RemoteCommand)> {
+    let session = NodeSession(1, 1000);
+    let map = Map(0x1122334455667788);
+    vec![
+        (
+            "dht_kv/client_set",
+            RemoteCommand::Client(session, ClientCommand::MapCmd(map, ClientMapCommand::Set(Key(1), Version(2), vec![1, 2, 3]))),
+        ),
+        (
+            "dht_kv/client_del",
+            RemoteCommand::Client(session, ClientCommand::MapCmd(map, ClientMapCommand::Del(Key(1), Version(3)))),
+        ),
+        (
+            "dht_kv/client_sub",
+            RemoteCommand::Client(session, ClientCommand::MapCmd(map, ClientMapCommand::Sub(10, Some(NodeSession(2, 2000))))),
+        ),
+        ("dht_kv/client_unsub", RemoteCommand::Client(session, ClientCommand::MapCmd(map, ClientMapCommand::Unsub(10)))),
+        (
+            "dht_kv/client_on_set_ack",
+            RemoteCommand::Client(session, ClientCommand::MapCmd(map, ClientMapCommand::OnSetAck(Key(1), NodeSession(2, 2000), Version(2)))),
+        ),
+        (
+            "dht_kv/client_on_del_ack",
+            RemoteCommand::Client(session, ClientCommand::MapCmd(map, ClientMapCommand::OnDelAck(Key(1), NodeSession(2, 2000), Version(3)))),
+        ),
+        (
+            "dht_kv/client_set_ttl",
+            RemoteCommand::Client(session, ClientCommand::MapCmd(map, ClientMapCommand::SetTtl(Key(1), Version(4), vec![1, 2, 3], 5000))),
+        ),
+        (
+            "dht_kv/client_batch",
+            RemoteCommand::Client(
+                session,
+                ClientCommand::MapCmd(
+                    map,
+                    ClientMapCommand::Batch(12, vec![BatchOp::Set(Key(1), Version(5), vec![1, 2, 3]), BatchOp::Del(Key(2), Version(5))]),
+                ),
+            ),
+        ),
+        (
+            "dht_kv/client_sub_filtered",
+            RemoteCommand::Client(
+                session,
+                ClientCommand::MapCmd(map, ClientMapCommand::SubFiltered(10, Some(NodeSession(2, 2000)), vec![KeyRange::new(Key(100), Key(199))])),
+            ),
+        ),
+        (
+            "dht_kv/client_set_acl",
+            RemoteCommand::Client(
+                session,
+                ClientCommand::MapCmd(
+                    map,
+                    ClientMapCommand::SetAcl(
+                        13,
+                        MapAcl {
+                            writers: Some(vec![2, 3]),
+                            readers: None,
+                        },
+                    ),
+                ),
+            ),
+        ),
+        ("dht_kv/client_get", RemoteCommand::Client(session, ClientCommand::MapGet(map, 11))),
+        (
+            "dht_kv/server_set_ok",
+            RemoteCommand::Server(session, ServerEvent::MapEvent(map, ServerMapEvent::SetOk(Key(1), Version(2)))),
+        ),
+        (
+            "dht_kv/server_del_ok",
+            RemoteCommand::Server(session, ServerEvent::MapEvent(map, ServerMapEvent::DelOk(Key(1), Version(3)))),
+        ),
+        ("dht_kv/server_sub_ok", RemoteCommand::Server(session, ServerEvent::MapEvent(map, ServerMapEvent::SubOk(10)))),
+        ("dht_kv/server_unsub_ok", RemoteCommand::Server(session, ServerEvent::MapEvent(map, ServerMapEvent::UnsubOk(10)))),
+        ("dht_kv/server_batch_ok", RemoteCommand::Server(session, ServerEvent::MapEvent(map, ServerMapEvent::BatchOk(12)))),
+        ("dht_kv/server_acl_ok", RemoteCommand::Server(session, ServerEvent::MapEvent(map, ServerMapEvent::AclOk(13)))),
+        (
+            "dht_kv/server_set_denied",
+            RemoteCommand::Server(session, ServerEvent::MapEvent(map, ServerMapEvent::SetDenied(Key(1)))),
+        ),
+        (
+            "dht_kv/server_batch_denied",
+            RemoteCommand::Server(session, ServerEvent::MapEvent(map, ServerMapEvent::BatchDenied(12))),
+        ),
+        ("dht_kv/server_sub_denied", RemoteCommand::Server(session, ServerEvent::MapEvent(map, ServerMapEvent::SubDenied(10)))),
+        ("dht_kv/server_acl_denied", RemoteCommand::Server(session, ServerEvent::MapEvent(map, ServerMapEvent::AclDenied(13)))),
+        (
+            "dht_kv/server_on_set",
+            RemoteCommand::Server(
+                session,
+                ServerEvent::MapEvent(
+                    map,
+                    ServerMapEvent::OnSet {
+                        key: Key(1),
+                        source: NodeSession(2, 2000),
+                        version: Version(2),
+                        data: vec![1, 2, 3],
+                    },
+                ),
+            ),
+        ),
+        (
+            "dht_kv/server_on_del",
+            RemoteCommand::Server(
+                session,
+                ServerEvent::MapEvent(
+                    map,
+                    ServerMapEvent::OnDel {
+                        key: Key(1),
+                        source: NodeSession(2, 2000),
+                        version: Version(3),
+                    },
+                ),
+            ),
+        ),
+        (
+            "dht_kv/server_on_expired",
+            RemoteCommand::Server(
+                session,
+                ServerEvent::MapEvent(
+                    map,
+                    ServerMapEvent::OnExpired {
+                        key: Key(1),
+                        source: NodeSession(2, 2000),
+                        version: Version(4),
+                    },
+                ),
+            ),
+        ),
+        (
+            "dht_kv/server_get_res",
+            RemoteCommand::Server(session, ServerEvent::MapGetRes(map, 11, vec![(Key(1), NodeSession(2, 2000), Version(2), vec![1, 2, 3])])),
+        ),
+        ("dht_kv/server_get_denied", RemoteCommand::Server(session, ServerEvent::GetDenied(map, 11))),
+    ]
+}
+
+fn pubsub_msgs() -> Vec<(&'static str, PubsubMessage)> {
+    let relay = RelayId(ChannelId(0x1122334455667788), 2);
+    vec![
+        ("pubsub/control_sub", PubsubMessage::Control(relay, RelayControl::Sub(1000))),
+        ("pubsub/control_unsub", PubsubMessage::Control(relay, RelayControl::Unsub(1000))),
+        ("pubsub/control_sub_ok", PubsubMessage::Control(relay, RelayControl::SubOK(1000))),
+        ("pubsub/control_unsub_ok", PubsubMessage::Control(relay, RelayControl::UnsubOK(1000))),
+        ("pubsub/control_route_changed", PubsubMessage::Control(relay, RelayControl::RouteChanged(1000))),
+        ("pubsub/control_feedback", PubsubMessage::Control(relay, RelayControl::Feedback(Feedback::simple(1, 100, 1000, 2000)))),
+        ("pubsub/control_handover", PubsubMessage::Control(relay, RelayControl::Handover(1000))),
+        ("pubsub/control_sub_auth", PubsubMessage::Control(relay, RelayControl::SubAuth(1000, vec![1, 2, 3]))),
+        ("pubsub/control_sub_rejected", PubsubMessage::Control(relay, RelayControl::SubRejected(1000))),
+        ("pubsub/source_hint_register", PubsubMessage::SourceHint(relay.0, SourceHint::Register { source: 2, to_root: true })),
+        (
+            "pubsub/source_hint_unregister",
+            PubsubMessage::SourceHint(relay.0, SourceHint::Unregister { source: 2, to_root: false }),
+        ),
+        ("pubsub/source_hint_subscribe", PubsubMessage::SourceHint(relay.0, SourceHint::Subscribe(1000))),
+        ("pubsub/source_hint_subscribe_ok", PubsubMessage::SourceHint(relay.0, SourceHint::SubscribeOk(1000))),
+        ("pubsub/source_hint_unsubscribe", PubsubMessage::SourceHint(relay.0, SourceHint::Unsubscribe(1000))),
+        ("pubsub/source_hint_unsubscribe_ok", PubsubMessage::SourceHint(relay.0, SourceHint::UnsubscribeOk(1000))),
+        ("pubsub/source_hint_sources", PubsubMessage::SourceHint(relay.0, SourceHint::Sources(vec![2, 3]))),
+        ("pubsub/data", PubsubMessage::Data(relay, vec![1, 2, 3, 4])),
+        ("pubsub/fec_data", PubsubMessage::FecData(relay, FecHeader { group: 7, index: 1, size: 4 }, vec![1, 2, 3, 4])),
+        ("pubsub/fec_parity", PubsubMessage::FecParity(relay, FecHeader { group: 7, index: 4, size: 4 }, 4, vec![5, 6, 7, 8])),
+        ("pubsub/replay_request", PubsubMessage::ReplayRequest(relay, 1000, 10)),
+        ("pubsub/replay_data", PubsubMessage::ReplayData(relay, 1000, vec![1, 2, 3, 4])),
+        ("pubsub/family_register", PubsubMessage::FamilyRegister(1, vec![ChannelId(1 << 32 | 1), ChannelId(1 << 32 | 2)])),
+        ("pubsub/family_unregister", PubsubMessage::FamilyUnregister(1, vec![ChannelId(1 << 32 | 1)])),
+        ("pubsub/range_query", PubsubMessage::RangeQuery(1, 1000, ChannelId(1 << 32), ChannelId(1 << 32 | 100))),
+        (
+            "pubsub/range_sources",
+            PubsubMessage::RangeSources(1000, ChannelId(1 << 32), ChannelId(1 << 32 | 100), vec![(ChannelId(1 << 32 | 1), 2)]),
+        ),
+    ]
+}
+
+fn router_syncs() -> Vec<(&'static str, Capabilities, RouterSync)> {
+    let services = RegistrySync(vec![(1, Metric::new(10, vec![3, 2], 1000).with_load(100)), (2, Metric::new(0, vec![], 1000))]);
+    let tables = [Some(TableSync(vec![(4, Metric::new(5, vec![4], 1000))])), None, None, None];
+    let sync = RouterSync(services, tables, 50, vec![5], 1000);
+    vec![
+        ("router_sync/sync", Capabilities::EMPTY, sync.clone()),
+        ("router_sync/sync_service_load", Capabilities::SERVICE_LOAD, sync.clone()),
+        ("router_sync/sync_relay_load", Capabilities::RELAY_LOAD, sync),
+    ]
+}
+
+/// Decode a vector with the real decoder of its kind then encode it again, None if it is not decodable
+pub fn reencode(name: &str, bytes: &[u8]) -> Option<Vec<u8>> {
+    let (kind, _) = name.split_once('/')?;
+    match kind {
+        "neighbours" => {
+            let control = NeighboursControl::try_from(bytes).ok()?;
+            (&control).try_into().ok()
+        }
+        "header" => {
+            let header = TransportMsgHeader::try_from(bytes).ok()?;
+            let mut buf = [0; 16];
+            let len = header.to_bytes(&mut buf)?;
+            Some(buf[..len].to_vec())
+        }
+        "dht_kv" => {
+            let cmd: RemoteCommand = bincode::deserialize(bytes).ok()?;
+            bincode::serialize(&cmd).ok()
+        }
+        "pubsub" => {
+            let msg = PubsubMessage::try_from(bytes).ok()?;
+            let buf: Buffer = msg.into();
+            Some(buf.to_vec())
+        }
+        "router_sync" => {
+            let (_, caps, _) = router_syncs().into_iter().find(|(n, _, _)| *n == name)?;
+            let sync = decode_sync(bytes, caps)?;
+            Some(encode_sync(&sync, caps))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{base::NeighboursControl, secure::StaticKeyAuthorization};
+
+    use super::*;
+
+    #[test]
+    fn golden_match() {
+        let vectors = all();
+        if std::env::var("UPDATE_GOLDEN").is_ok() {
+            let mut content = String::from("# Generated by `UPDATE_GOLDEN=1 cargo test -p atm0s-sdn-network test_vectors`, see src/test_vectors.rs\n");
+            for v in &vectors {
+                content.push_str(&format!("{} {}\n", v.name, to_hex(&v.bytes)));
+            }
+            std::fs::write(concat!(env!("CARGO_MANIFEST_DIR"), "/test_vectors/wire.hex"), content).expect("Should write golden file");
+            return;
+        }
+
+        let golden = golden();
+        assert_eq!(golden.len(), vectors.len());
+        for ((name, bytes), v) in golden.into_iter().zip(vectors) {
+            assert_eq!(name, v.name);
+            assert_eq!(to_hex(&bytes), to_hex(&v.bytes), "golden mismatch for {name}");
+        }
+    }
+
+    #[test]
+    fn golden_roundtrip() {
+        for (name, bytes) in golden() {
+            assert_eq!(reencode(name, &bytes), Some(bytes), "roundtrip mismatch for {name}");
+        }
+    }
+
+    #[test]
+    fn golden_neighbours_decode() {
+        let auth = StaticKeyAuthorization::new(STATIC_KEY);
+        let golden = golden();
+        for (name, cmd) in neighbours_cmds() {
+            let (_, bytes) = golden.iter().find(|(n, _)| *n == name).expect("Should have golden");
+            let control = NeighboursControl::try_from(bytes.as_slice()).expect("Should decode");
+            assert_eq!(control.from, 1);
+            assert_eq!(control.validate(NOW_MS, &auth), Ok(cmd));
+        }
+    }
+
+    #[test]
+    fn golden_headers_decode() {
+        let golden = golden();
+        for (name, header) in headers() {
+            let (_, bytes) = golden.iter().find(|(n, _)| *n == name).expect("Should have golden");
+            assert_eq!(TransportMsgHeader::try_from(bytes.as_slice()), Ok(header));
+        }
+    }
+
+    #[test]
+    fn golden_dht_kv_decode() {
+        let golden = golden();
+        for (name, cmd) in dht_kv_cmds() {
+            let (_, bytes) = golden.iter().find(|(n, _)| *n == name).expect("Should have golden");
+            assert_eq!(bincode::deserialize::<RemoteCommand>(bytes).expect("Should decode"), cmd);
+        }
+    }
+
+    #[test]
+    fn golden_pubsub_decode() {
+        let golden = golden();
+        for (name, msg) in pubsub_msgs() {
+            let (_, bytes) = golden.iter().find(|(n, _)| *n == name).expect("Should have golden");
+            assert_eq!(PubsubMessage::try_from(bytes.as_slice()).ok(), Some(msg));
+        }
+    }
+
+    #[test]
+    fn golden_router_sync_decode() {
+        let golden = golden();
+        for (name, caps, sync) in router_syncs() {
+            let (_, bytes) = golden.iter().find(|(n, _)| *n == name).expect("Should have golden");
+            let decoded = decode_sync(bytes, caps).expect("Should decode");
+            let loads = if caps.contains(Capabilities::SERVICE_LOAD) {
+                sync.0.loads()
+            } else {
+                vec![0; sync.0 .0.len()]
+            };
+            let relay_load = if caps.contains(Capabilities::RELAY_LOAD) {
+                sync.2
+            } else {
+                0
+            };
+            assert_eq!(decoded.0.loads(), loads);
+            assert_eq!(decoded, RouterSync(sync.0.clone(), sync.1.clone(), relay_load, sync.3.clone(), sync.4));
+            // nodes which don't support the extensions decode the same sync without them
+            assert_eq!(
+                bincode::deserialize::<RouterSync>(bytes).expect("Should decode as legacy"),
+                RouterSync(sync.0, sync.1, 0, sync.3, sync.4)
+            );
+        }
+    }
+
+    #[test]
+    fn hex_helpers() {
+        assert_eq!(to_hex(&[0, 1, 254, 255]), "0001feff");
+        assert_eq!(from_hex("0001feff"), Some(vec![0, 1, 254, 255]));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
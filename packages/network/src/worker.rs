@@ -1,19 +1,51 @@
-use std::{fmt::Debug, hash::Hash};
+use std::{collections::VecDeque, fmt::Debug, hash::Hash, sync::Arc};
 
 use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_utils::log_sampled;
 use sans_io_runtime::{TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
 
 use crate::{
-    controller_plane::{self, ControllerPlane, ControllerPlaneCfg},
-    data_plane::{self, CrossWorker, DataPlane, DataPlaneCfg, NetInput, NetOutput},
-    ExtIn, ExtOut, LogicControl, LogicEvent, LogicEventDest,
+    controller_plane::{self, ControllerMetrics, ControllerPlane, ControllerPlaneCfg, ControllerSnapshot},
+    data_plane::{self, CrossWorker, DataPlane, DataPlaneCfg, DataPlaneMetrics, NetInput, NetOutput},
+    ControllerTakeover, ExtIn, ExtOut, LogicControl, LogicEvent, LogicEventDest,
 };
 
+/// Default interval for replicating the active controller to the standby one
+pub const DEFAULT_REPLICATION_INTERVAL_MS: u64 = 1000;
+/// Default time without snapshots before the standby controller takes over
+pub const DEFAULT_TAKEOVER_MS: u64 = 3000;
+
 #[derive(Debug, Clone)]
 pub enum SdnWorkerBusEvent<UserData, SC, SE, TC, TW> {
     Control(LogicControl<UserData, SC, SE, TC>),
     Workers(LogicEvent<UserData, SE, TW>),
     Worker(u16, CrossWorker<UserData, SE>),
+    /// Snapshot of the active controller, which is sent to the standby worker
+    Replicate(Arc<ControllerSnapshot>),
+    /// Worker which runs the controller after a takeover, controllers of other workers are stopped
+    Takeover(u16),
+    /// External input which is received by a worker without controller, it is forwarded to the controller
+    Ext(ExtIn<UserData, SC>),
+}
+
+/// Replication of the active controller to a standby controller in another worker of the same node.
+///
+/// The active controller sends snapshots in each interval, the standby controller takes over when it doesn't receive
+/// snapshots for `takeover_ms`, which happens when the active worker is stalled or crashed. The takeover time should be
+/// shorter than the connection timeout of neighbours (10 seconds), so the adopted connections are still alive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplicationCfg {
+    pub interval_ms: u64,
+    pub takeover_ms: u64,
+}
+
+impl Default for ReplicationCfg {
+    fn default() -> Self {
+        Self {
+            interval_ms: DEFAULT_REPLICATION_INTERVAL_MS,
+            takeover_ms: DEFAULT_TAKEOVER_MS,
+        }
+    }
 }
 
 pub enum SdnWorkerInput<UserData, SC, SE, TC, TW> {
@@ -44,15 +76,31 @@ pub struct SdnWorkerCfg<UserData, SC, SE, TC, TW> {
     pub node_id: NodeId,
     pub tick_ms: u64,
     pub controller: Option<ControllerPlaneCfg<UserData, SC, SE, TC, TW>>,
+    /// Controller which is started when the active controller stops replicating, it must have the same session
+    pub standby: Option<ControllerPlaneCfg<UserData, SC, SE, TC, TW>>,
+    /// Replicate the active controller to the standby one, used by both sides. None for running without standby
+    pub replication: Option<ReplicationCfg>,
     pub data: DataPlaneCfg<UserData, SC, SE, TC, TW>,
 }
 
+struct Standby<UserData, SC, SE, TC, TW> {
+    cfg: ControllerPlaneCfg<UserData, SC, SE, TC, TW>,
+    /// Latest snapshot and the time it was received
+    snapshot: Option<(Arc<ControllerSnapshot>, u64)>,
+}
+
 pub struct SdnWorker<UserData, SC, SE, TC, TW> {
+    node_id: NodeId,
+    worker: u16,
     tick_ms: u64,
     #[allow(clippy::type_complexity)]
     controller: Option<TaskSwitcherBranch<ControllerPlane<UserData, SC, SE, TC, TW>, controller_plane::Output<UserData, SE, TW>>>,
     #[allow(clippy::type_complexity)]
     data: TaskSwitcherBranch<DataPlane<UserData, SC, SE, TC, TW>, data_plane::Output<UserData, SC, SE, TC>>,
+    standby: Option<Standby<UserData, SC, SE, TC, TW>>,
+    replication: Option<ReplicationCfg>,
+    last_replicate: u64,
+    queue: VecDeque<SdnWorkerOutput<UserData, SC, SE, TC, TW>>,
     shutdown: bool,
     switcher: TaskSwitcher,
     last_tick: Option<u64>,
@@ -64,11 +112,17 @@ where
 {
     pub fn new(cfg: SdnWorkerCfg<UserData, SC, SE, TC, TW>) -> Self {
         Self {
+            node_id: cfg.node_id,
+            worker: cfg.data.worker_id,
             tick_ms: cfg.tick_ms,
             controller: cfg
                 .controller
                 .map(|controller| TaskSwitcherBranch::new(ControllerPlane::new(cfg.node_id, controller), TaskType::Controller)),
             data: TaskSwitcherBranch::new(DataPlane::new(cfg.node_id, cfg.data), TaskType::Data),
+            standby: cfg.standby.map(|cfg| Standby { cfg, snapshot: None }),
+            replication: cfg.replication,
+            last_replicate: 0,
+            queue: VecDeque::new(),
             shutdown: false,
             switcher: TaskSwitcher::new(2),
             last_tick: None,
@@ -80,7 +134,27 @@ where
     }
 
     pub fn is_empty(&self) -> bool {
-        self.shutdown && self.controller.as_ref().map_or(true, |c| c.is_empty()) && self.data.is_empty()
+        self.shutdown && self.queue.is_empty() && self.controller.as_ref().map_or(true, |c| c.is_empty()) && self.data.is_empty()
+    }
+
+    /// This worker runs the controller, which changes after a takeover
+    pub fn has_controller(&self) -> bool {
+        self.controller.is_some()
+    }
+
+    /// Outputs which are waiting in the controller plane, None if this worker doesn't run the controller
+    pub fn controller_queue_len(&self) -> Option<usize> {
+        self.controller.as_ref().map(|c| c.queue_len())
+    }
+
+    /// Metrics of the data plane of this worker
+    pub fn data_metrics(&self) -> DataPlaneMetrics {
+        self.data.metrics()
+    }
+
+    /// Metrics of the controller plane, None if this worker doesn't run the controller
+    pub fn controller_metrics(&self) -> Option<ControllerMetrics> {
+        self.controller.as_ref().map(|c| c.metrics())
     }
 
     pub fn on_tick(&mut self, now_ms: u64) {
@@ -94,14 +168,49 @@ where
         self.data.input(&mut self.switcher).on_tick(now_ms);
         if let Some(controller) = &mut self.controller {
             controller.input(&mut self.switcher).on_tick(now_ms);
+            if let Some(replication) = &self.replication {
+                if !self.shutdown && now_ms >= self.last_replicate + replication.interval_ms {
+                    self.last_replicate = now_ms;
+                    let snapshot = controller.snapshot(now_ms);
+                    self.queue.push_back(SdnWorkerOutput::Bus(SdnWorkerBusEvent::Replicate(Arc::new(snapshot))));
+                }
+            }
         }
+        self.check_takeover(now_ms);
+    }
+
+    /// Start the standby controller if the active one stopped replicating. The standby waits for the first snapshot, so
+    /// it never competes with a controller which is still starting
+    fn check_takeover(&mut self, now_ms: u64) {
+        let takeover_ms = match (&self.replication, &self.standby) {
+            (Some(replication), Some(Standby { snapshot: Some((_, received)), .. })) if !self.shutdown && now_ms >= received + replication.takeover_ms => replication.takeover_ms,
+            _ => return,
+        };
+        let standby = self.standby.take().expect("Should have standby");
+        let (snapshot, _) = standby.snapshot.expect("Should have snapshot");
+        let snapshot = Arc::unwrap_or_clone(snapshot);
+        let takeover = ControllerTakeover {
+            worker: self.worker,
+            snapshot_age_ms: now_ms.saturating_sub(snapshot.taken_ms()),
+            connections: snapshot.connections(),
+        };
+        log::warn!("[SdnWorker] worker {} takes over the controller after {takeover_ms} ms without snapshots: {:?}", self.worker, takeover);
+        let mut controller = TaskSwitcherBranch::new(ControllerPlane::new(self.node_id, standby.cfg), TaskType::Controller);
+        controller.input(&mut self.switcher).restore(now_ms, snapshot);
+        self.controller = Some(controller);
+        self.queue.push_back(SdnWorkerOutput::Bus(SdnWorkerBusEvent::Takeover(self.worker)));
+        self.queue.push_back(SdnWorkerOutput::Ext(ExtOut::ControllerTakeover(takeover)));
     }
 
     pub fn on_event(&mut self, now_ms: u64, input: SdnWorkerInput<UserData, SC, SE, TC, TW>) {
         match input {
             SdnWorkerInput::Ext(ext) => {
-                let controller = self.controller.as_mut().expect("Should have controller");
-                controller.input(&mut self.switcher).on_event(now_ms, controller_plane::Input::Ext(ext));
+                if let Some(controller) = &mut self.controller {
+                    controller.input(&mut self.switcher).on_event(now_ms, controller_plane::Input::Ext(ext));
+                } else {
+                    // the controller is moved to another worker after a takeover
+                    self.queue.push_back(SdnWorkerOutput::Bus(SdnWorkerBusEvent::Ext(ext)));
+                }
             }
             SdnWorkerInput::ExtWorker(ext) => {
                 self.data.input(&mut self.switcher).on_event(now_ms, data_plane::Input::Ext(ext));
@@ -111,8 +220,28 @@ where
             }
             SdnWorkerInput::Bus(bus) => match bus {
                 SdnWorkerBusEvent::Control(control) => {
-                    let controller = self.controller.as_mut().expect("Should have controller");
-                    controller.input(&mut self.switcher).on_event(now_ms, controller_plane::Input::Control(control));
+                    if let Some(controller) = &mut self.controller {
+                        controller.input(&mut self.switcher).on_event(now_ms, controller_plane::Input::Control(control));
+                    } else {
+                        log_sampled!(log::Level::Warn, "[SdnWorker] worker {} drop control which arrived after the controller is stopped", self.worker);
+                    }
+                }
+                SdnWorkerBusEvent::Ext(ext) => {
+                    if let Some(controller) = &mut self.controller {
+                        controller.input(&mut self.switcher).on_event(now_ms, controller_plane::Input::Ext(ext));
+                    } else {
+                        log_sampled!(log::Level::Warn, "[SdnWorker] worker {} drop forwarded ext which arrived after the controller is stopped", self.worker);
+                    }
+                }
+                SdnWorkerBusEvent::Replicate(snapshot) => {
+                    if let Some(standby) = &mut self.standby {
+                        standby.snapshot = Some((snapshot, now_ms));
+                    }
+                }
+                SdnWorkerBusEvent::Takeover(worker) => {
+                    if worker != self.worker && self.controller.take().is_some() {
+                        log::warn!("[SdnWorker] worker {} stops its controller because worker {worker} took over", self.worker);
+                    }
                 }
                 SdnWorkerBusEvent::Workers(event) => {
                     self.data.input(&mut self.switcher).on_event(now_ms, data_plane::Input::Event(event));
@@ -137,6 +266,9 @@ where
     }
 
     pub fn pop_output2(&mut self, now: u64) -> Option<SdnWorkerOutput<UserData, SC, SE, TC, TW>> {
+        if let Some(out) = self.queue.pop_front() {
+            return Some(out);
+        }
         loop {
             match self.switcher.current()?.try_into().ok()? {
                 TaskType::Controller => {
@@ -213,7 +345,7 @@ where
     }
 
     fn is_empty(&self) -> bool {
-        self.shutdown && self.controller.as_ref().map_or(true, |c| c.is_empty()) && self.data.is_empty()
+        self.shutdown && self.queue.is_empty() && self.controller.as_ref().map_or(true, |c| c.is_empty()) && self.data.is_empty()
     }
 
     fn pop_output(&mut self, now: u64) -> Option<SdnWorkerOutput<UserData, SC, SE, TC, TW>> {
@@ -0,0 +1,98 @@
+use atm0s_sdn_network::{
+    base::{Capabilities, CapabilitySkew, PROTOCOL_VERSION},
+    features::{socket, FeaturesControl, FeaturesEvent},
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+#[test]
+fn capability_skew_reported_and_connection_works() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    // node2 is an older build which only knows some capabilities
+    let older = Capabilities::from_bits(Capabilities::LINK_FRAMING.bits() | Capabilities::PUBSUB_FEC.bits());
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new_with_capabilities(node2, 1235, vec![], older));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let mut skews = vec![];
+    while let Some((node, out)) = sim.pop_res() {
+        if let ExtOut::CapabilitySkew(skew) = out {
+            skews.push((node, skew));
+        }
+    }
+    skews.sort_by_key(|(node, _)| *node);
+    let disabled = Capabilities::SUPPORTED.difference(older);
+    assert_eq!(
+        skews,
+        vec![
+            (
+                node1,
+                CapabilitySkew {
+                    node: node2,
+                    worker: None,
+                    local_version: PROTOCOL_VERSION,
+                    remote_version: PROTOCOL_VERSION,
+                    disabled,
+                    remote_only: Capabilities::EMPTY,
+                }
+            ),
+            (
+                node2,
+                CapabilitySkew {
+                    node: node1,
+                    worker: None,
+                    local_version: PROTOCOL_VERSION,
+                    remote_version: PROTOCOL_VERSION,
+                    disabled: Capabilities::EMPTY,
+                    remote_only: disabled,
+                }
+            ),
+        ]
+    );
+
+    // the connection keeps working with the agreed capabilities
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::Bind(10000))));
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::Bind(10001))));
+    sim.process(10);
+
+    sim.control(
+        node2,
+        ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::SendTo(10001, node1, 10000, vec![1, 2, 3].into(), 0))),
+    );
+    sim.process(10);
+    assert_eq!(
+        sim.pop_res(),
+        Some((
+            node1,
+            ExtOut::FeaturesEvent((), FeaturesEvent::Socket(socket::Event::RecvFrom(10000, node2, 10001, vec![1, 2, 3].into(), 0)))
+        ))
+    );
+}
+
+#[test]
+fn capability_skew_not_reported_for_same_build() {
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    let _addr1 = sim.add_node(TestNode::new(1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(2, 1235, vec![]));
+
+    sim.control(1, ExtIn::ConnectTo(addr2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    while let Some((node, out)) = sim.pop_res() {
+        assert!(!matches!(out, ExtOut::CapabilitySkew(_)), "Node {node} should not report skew");
+    }
+}
@@ -0,0 +1,107 @@
+#![cfg(feature = "chaos")]
+
+use atm0s_sdn_network::{
+    data_plane::chaos::{ChaosConfig, ChaosControl, ChaosDelay},
+    features::{
+        dht_kv::{Control, Event, Key, Map, MapControl, MapEvent},
+        diag, FeaturesControl, FeaturesEvent,
+    },
+    simulation::{LinkModel, Simulation},
+    ExtIn, ExtOut,
+};
+
+type Sim = Simulation<(), (), (), ()>;
+
+/// Nodes are dead after this time without pong, plus some ticks for router sync
+const REROUTE_BOUND_MS: u64 = 12000;
+
+/// Build a triangle of nodes 1, 2 and 3
+fn build_triangle() -> Sim {
+    let mut sim = Sim::new(0);
+    sim.set_default_link(LinkModel::new(10));
+    let _addr1 = sim.add_node(Sim::node_cfg(1, 1234, vec![]));
+    let addr2 = sim.add_node(Sim::node_cfg(2, 1235, vec![]));
+    let addr3 = sim.add_node(Sim::node_cfg(3, 1236, vec![]));
+
+    sim.control(1, ExtIn::ConnectTo(addr2.clone()));
+    sim.control(1, ExtIn::ConnectTo(addr3.clone()));
+    sim.control(2, ExtIn::ConnectTo(addr3));
+    sim.advance(5000);
+    while sim.pop_output().is_some() {}
+    sim
+}
+
+fn ping(sim: &mut Sim, dest: u32) -> Option<diag::Event> {
+    sim.control(1, ExtIn::FeaturesControl((), FeaturesControl::Diag(diag::Control::Ping(dest))));
+    for _ in 0..50 {
+        sim.advance(100);
+        while let Some((node, out)) = sim.pop_output() {
+            if let (1, ExtOut::FeaturesEvent((), FeaturesEvent::Diag(event))) = (node, out) {
+                return Some(event);
+            }
+        }
+    }
+    None
+}
+
+#[test]
+fn chaos_killed_connection_is_rerouted() {
+    let mut sim = build_triangle();
+    let event = ping(&mut sim, 3).expect("Should have ping result");
+    assert_eq!(event.error(), None);
+    let diag::Event::Ping(3, hops) = event else {
+        panic!("Unexpected event {event:?}");
+    };
+    assert_eq!(hops.len(), 1, "node 3 should be a direct neighbour");
+
+    sim.control(1, ExtIn::Chaos(ChaosControl::Kill(3)));
+    sim.advance(REROUTE_BOUND_MS);
+    while sim.pop_output().is_some() {}
+
+    let event = ping(&mut sim, 3).expect("Should have ping result");
+    assert_eq!(event.error(), None);
+    let diag::Event::Ping(3, hops) = event else {
+        panic!("Unexpected event {event:?}");
+    };
+    assert_eq!(hops.len(), 2, "node 3 should be reached over node 2");
+}
+
+#[test]
+fn chaos_lossy_link_dht_kv() {
+    let mut sim = build_triangle();
+    let chaos = ChaosConfig {
+        drop: 0.2,
+        duplicate: 0.2,
+        reorder: 0.2,
+        delay: Some(ChaosDelay::Exponential { mean_ms: 20, max_ms: 200 }),
+        nodes: vec![],
+        seed: 1,
+    };
+    for node in [1, 2, 3] {
+        sim.control(node, ExtIn::Chaos(ChaosControl::Set(Some(chaos.clone()))));
+    }
+
+    let map = Map(1000);
+    let key = Key(2000);
+    sim.control(1, ExtIn::FeaturesControl((), FeaturesControl::DhtKv(Control::MapCmd(map, MapControl::Sub))));
+    sim.advance(1000);
+    sim.control(3, ExtIn::FeaturesControl((), FeaturesControl::DhtKv(Control::MapCmd(map, MapControl::Set(key, vec![1, 2, 3])))));
+
+    let mut received = false;
+    for _ in 0..100 {
+        sim.advance(100);
+        while let Some((node, out)) = sim.pop_output() {
+            if let (1, ExtOut::FeaturesEvent((), FeaturesEvent::DhtKv(Event::MapEvent(_, MapEvent::OnSet(k, source, value))))) = (node, out) {
+                assert_eq!((k, source, value), (key, 3, vec![1, 2, 3]));
+                received = true;
+            }
+        }
+    }
+    assert!(received, "value should be received over lossy links");
+
+    for node in [1, 2, 3] {
+        sim.control(node, ExtIn::Chaos(ChaosControl::Set(None)));
+    }
+    let event = ping(&mut sim, 3).expect("Should have ping result");
+    assert_eq!(event.error(), None);
+}
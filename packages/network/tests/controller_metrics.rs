@@ -0,0 +1,82 @@
+use atm0s_sdn_network::{
+    controller_plane::ControllerMetrics,
+    features::{
+        pubsub::{ChannelControl, ChannelId, Control},
+        Features, FeaturesControl,
+    },
+    ExtIn,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+#[test]
+fn controller_metrics_connections_and_routes() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    assert_eq!(sim.controller_metrics(node1), ControllerMetrics::default());
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    while sim.pop_res().is_some() {}
+
+    let metrics = sim.controller_metrics(node1);
+    assert_eq!(metrics.connections, 1);
+    assert_eq!(metrics.connections_established, 1);
+    assert_eq!(metrics.connections_closed, 0);
+    assert!(metrics.routes > 0);
+
+    sim.leave_node(node2);
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let metrics = sim.controller_metrics(node1);
+    assert_eq!(metrics.connections, 0);
+    assert_eq!(metrics.connections_established, 1);
+    assert_eq!(metrics.connections_closed, 1);
+}
+
+#[test]
+fn controller_metrics_pubsub_fanout_and_feature_traffic() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    while sim.pop_res().is_some() {}
+    assert!(sim.controller_metrics(node1).rtt_ms.count() > 0);
+    assert_eq!(sim.data_metrics(node1).connections, 1);
+    let conn_workers = sim.controller_metrics(node1).conn_workers;
+    assert_eq!(conn_workers.len(), 1);
+    assert!(conn_workers.iter().all(|(conn, worker)| conn.is_outgoing() && *worker == 0));
+
+    let channel = ChannelId(1000);
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::PubSub(Control(channel, ChannelControl::SubSource(node2)))));
+    sim.process(1);
+    assert_eq!(sim.controller_metrics(node2).pubsub_relay_fanout, 1);
+
+    let before = sim.data_metrics(node1).features.get(&Features::PubSub).copied().unwrap_or_default();
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::PubSub(Control(channel, ChannelControl::PubData(vec![1, 2, 3, 4])))));
+    sim.process(1);
+    while sim.pop_res().is_some() {}
+
+    let sent = sim.data_metrics(node2).features.get(&Features::PubSub).copied().expect("Should have pubsub traffic");
+    let received = sim.data_metrics(node1).features.get(&Features::PubSub).copied().expect("Should have pubsub traffic");
+    assert!(sent.tx.packets > 0);
+    assert!(received.rx.packets > before.rx.packets);
+    assert!(received.rx.bytes > before.rx.bytes + 4);
+}
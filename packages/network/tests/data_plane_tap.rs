@@ -0,0 +1,65 @@
+use atm0s_sdn_network::{
+    data_plane::tap::{TapDirection, TapFilter, TapRecord},
+    features::{diag, Features, FeaturesControl},
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+/// Build a chain 1 - 2 - 3, so messages from node1 to node3 are relayed by node2
+fn build_chain() -> NetworkSimulator<(), (), (), ()> {
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    let _addr1 = sim.add_node(TestNode::new_with_seed(1, 1234, vec![], 1000));
+    let addr2 = sim.add_node(TestNode::new_with_seed(2, 1235, vec![], 2000));
+    let _addr3 = sim.add_node(TestNode::new_with_seed(3, 1236, vec![], 3000));
+
+    sim.control(1, ExtIn::ConnectTo(addr2.clone()));
+    sim.control(3, ExtIn::ConnectTo(addr2));
+    for _i in 0..8 {
+        sim.process(500);
+    }
+    while sim.pop_res().is_some() {}
+    sim
+}
+
+fn collect_records(sim: &mut NetworkSimulator<(), (), (), ()>, steps: usize) -> Vec<TapRecord> {
+    let mut records = vec![];
+    for _ in 0..steps {
+        sim.process(100);
+        while let Some((node, out)) = sim.pop_res() {
+            if let (2, ExtOut::Tap(record)) = (node, out) {
+                records.push(record);
+            }
+        }
+    }
+    records
+}
+
+#[test]
+fn data_plane_tap_relayed_messages() {
+    let mut sim = build_chain();
+
+    sim.control(
+        2,
+        ExtIn::Tap(Some(TapFilter {
+            features: vec![Features::Diag],
+            nodes: vec![],
+            payload: true,
+        })),
+    );
+    sim.process(100);
+    sim.control(1, ExtIn::FeaturesControl((), FeaturesControl::Diag(diag::Control::TraceRoute(3))));
+    let records = collect_records(&mut sim, 30);
+
+    assert!(records.iter().all(|record| record.header.feature == diag::FEATURE_ID && record.payload.is_some()));
+    assert!(records.iter().any(|record| record.direction == TapDirection::Incoming && record.neighbour == 1));
+    // probe which is relayed to node3
+    assert!(records.iter().any(|record| record.direction == TapDirection::Outgoing && record.neighbour == 3));
+
+    sim.control(2, ExtIn::Tap(None));
+    sim.process(100);
+    sim.control(1, ExtIn::FeaturesControl((), FeaturesControl::Diag(diag::Control::TraceRoute(3))));
+    assert_eq!(collect_records(&mut sim, 30), vec![]);
+}
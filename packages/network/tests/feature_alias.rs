@@ -209,13 +209,186 @@ fn feature_alias_three_nodes() {
         sim.process(500);
     }
 
+    // node3 missed the notify, so the owner is found by asking the alias root instead of scanning
     sim.control(node3, ExtIn::FeaturesControl((), FeaturesControl::Alias(alias::Control::Query { alias, service, level })));
     sim.process(10);
     assert_eq!(
         sim.pop_res(),
         Some((
             node3,
-            ExtOut::FeaturesEvent((), FeaturesEvent::Alias(alias::Event::QueryResult(alias, Some(FoundLocation::RemoteScan(node1)))))
+            ExtOut::FeaturesEvent((), FeaturesEvent::Alias(alias::Event::QueryResult(alias, Some(FoundLocation::RemoteHint(node1)))))
         ))
     );
 }
+
+#[test]
+fn feature_alias_offline_queue() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![Arc::new(MockServiceBuilder)]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![Arc::new(MockServiceBuilder)]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let alias_v = 1000;
+    let service = 0;
+    let level = ServiceBroadcastLevel::Global;
+
+    // owner is offline => message is parked at root node
+    let send = alias::Control::Send {
+        alias: alias_v,
+        seq: 1,
+        ttl_ms: 10000,
+        data: vec![1, 2, 3],
+    };
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Alias(send)));
+    sim.process(10);
+    assert_eq!(
+        sim.pop_res(),
+        Some((
+            node2,
+            ExtOut::FeaturesEvent((), FeaturesEvent::Alias(alias::Event::SendReceipt(alias_v, 1, alias::SendReceipt::Queued)))
+        ))
+    );
+
+    // owner comes online => parked message is delivered with receipt
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Alias(alias::Control::Register { alias: alias_v, service, level })));
+    sim.process(10);
+    sim.process(10);
+    assert_eq!(
+        sim.pop_res(),
+        Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::Alias(alias::Event::Received(alias_v, node2, vec![1, 2, 3])))))
+    );
+    assert_eq!(
+        sim.pop_res(),
+        Some((
+            node2,
+            ExtOut::FeaturesEvent((), FeaturesEvent::Alias(alias::Event::SendReceipt(alias_v, 1, alias::SendReceipt::Delivered)))
+        ))
+    );
+    assert_eq!(sim.pop_res(), None);
+}
+
+#[test]
+fn feature_alias_list_and_reverse_query() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![Arc::new(MockServiceBuilder)]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![Arc::new(MockServiceBuilder)]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let service = 0;
+    let level = ServiceBroadcastLevel::Global;
+    for alias in [1001, 1000] {
+        sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Alias(alias::Control::Register { alias, service, level })));
+    }
+    // reverse index is synced on next tick
+    sim.process(10);
+    sim.process(1000);
+    while sim.pop_res().is_some() {}
+
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Alias(alias::Control::ListByNode(node1))));
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Alias(alias::Control::ReverseQuery(node1))));
+    sim.process(10);
+    let mut events = vec![];
+    while let Some((node, out)) = sim.pop_res() {
+        assert_eq!(node, node2);
+        events.push(out);
+    }
+    assert_eq!(
+        events,
+        vec![
+            ExtOut::FeaturesEvent((), FeaturesEvent::Alias(alias::Event::NodeAliases(node1, Some(vec![1000, 1001])))),
+            ExtOut::FeaturesEvent((), FeaturesEvent::Alias(alias::Event::ReverseResult(node1, Some(vec![1000, 1001])))),
+        ]
+    );
+
+    // unknown node doesn't reply
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Alias(alias::Control::ListByNode(3))));
+    sim.process(10);
+    for _i in 0..4 {
+        sim.process(1000);
+    }
+    assert_eq!(sim.pop_res(), Some((node2, ExtOut::FeaturesEvent((), FeaturesEvent::Alias(alias::Event::NodeAliases(3, None))))));
+}
+
+#[test]
+fn feature_alias_root_migration() {
+    // key of the alias is closest to node2, then to node3 after it joins
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![Arc::new(MockServiceBuilder)]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![Arc::new(MockServiceBuilder)]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let alias_v = 3;
+    let service = 0;
+    let level = ServiceBroadcastLevel::Global;
+
+    // owner is offline => message is parked at node2
+    let send = alias::Control::Send {
+        alias: alias_v,
+        seq: 1,
+        ttl_ms: 60000,
+        data: vec![1, 2, 3],
+    };
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Alias(send)));
+    sim.process(10);
+    assert_eq!(
+        sim.pop_res(),
+        Some((
+            node1,
+            ExtOut::FeaturesEvent((), FeaturesEvent::Alias(alias::Event::SendReceipt(alias_v, 1, alias::SendReceipt::Queued)))
+        ))
+    );
+
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![Arc::new(MockServiceBuilder)]));
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+
+    // For sync and root probe
+    for _i in 0..10 {
+        sim.process(500);
+    }
+    assert_eq!(sim.pop_res(), None);
+
+    // owner registers at the new root, which received the parked message from node2
+    sim.control(node3, ExtIn::FeaturesControl((), FeaturesControl::Alias(alias::Control::Register { alias: alias_v, service, level })));
+    sim.process(10);
+    sim.process(10);
+    assert_eq!(
+        sim.pop_res(),
+        Some((node3, ExtOut::FeaturesEvent((), FeaturesEvent::Alias(alias::Event::Received(alias_v, node1, vec![1, 2, 3])))))
+    );
+    assert_eq!(
+        sim.pop_res(),
+        Some((
+            node1,
+            ExtOut::FeaturesEvent((), FeaturesEvent::Alias(alias::Event::SendReceipt(alias_v, 1, alias::SendReceipt::Delivered)))
+        ))
+    );
+    assert_eq!(sim.pop_res(), None);
+}
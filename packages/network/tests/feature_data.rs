@@ -0,0 +1,115 @@
+use std::{cell::Cell, rc::Rc};
+
+use atm0s_sdn_network::{
+    features::{data, FeaturesControl, FeaturesEvent},
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+const PORT: u16 = 1;
+
+fn control(control: data::Control) -> ExtIn<(), ()> {
+    ExtIn::FeaturesControl((), FeaturesControl::Data(control))
+}
+
+/// Collect bulk events until the transfer is done or failed
+fn wait_bulk(sim: &mut NetworkSimulator<(), (), (), ()>, max_steps: usize) -> (Option<data::Event>, Option<Vec<u8>>, usize) {
+    let mut done = None;
+    let mut received = None;
+    let mut progress_events = 0;
+    for _ in 0..max_steps {
+        sim.process(1);
+        while let Some((_node, out)) = sim.pop_res() {
+            match out {
+                ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::BulkProgress(_, _))) => progress_events += 1,
+                ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::BulkRecv(PORT, 1, data))) => received = Some(data),
+                ExtOut::FeaturesEvent((), FeaturesEvent::Data(event @ (data::Event::BulkSent(..) | data::Event::BulkFailed(..)))) => done = Some(event),
+                _ => {}
+            }
+        }
+        if done.is_some() && received.is_some() {
+            break;
+        }
+    }
+    (done, received, progress_events)
+}
+
+#[test]
+fn feature_data_bulk_transfer_with_loss() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    // after armed, drop every 50th full segment from node1 (recovered by sack) and the first last segment (recovered by rto)
+    let drop_armed = Rc::new(Cell::new(false));
+    let drop_armed_c = drop_armed.clone();
+    let counter = Rc::new(Cell::new(0));
+    let last_dropped = Rc::new(Cell::new(false));
+    sim.set_packet_filter(Box::new(move |from, _to, data| {
+        if from != node1 || !drop_armed_c.get() {
+            return true;
+        }
+        if data.len() >= data::BULK_SEGMENT_SIZE {
+            counter.set(counter.get() + 1);
+            return counter.get() % 50 != 0;
+        }
+        if data.len() >= 500 && !last_dropped.get() {
+            last_dropped.set(true);
+            return false;
+        }
+        true
+    }));
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    sim.control(node2, control(data::Control::DataListen(PORT)));
+    sim.process(1);
+
+    let value = (0..300_500).map(|i| i as u8).collect::<Vec<_>>();
+    drop_armed.set(true);
+    sim.control(node1, control(data::Control::BulkSend(PORT, node2, 1000, value.clone())));
+    let (done, received, progress_events) = wait_bulk(&mut sim, 1000);
+
+    match done {
+        Some(data::Event::BulkSent(1000, progress)) => {
+            assert_eq!(progress.acked_bytes, value.len() as u64);
+            assert_eq!(progress.total_bytes, value.len() as u64);
+            assert!(progress.retransmits > 0);
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(received, Some(value));
+    assert!(progress_events > 0);
+}
+
+#[test]
+fn feature_data_bulk_transfer_unreachable() {
+    let node1 = 1;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    sim.add_node(TestNode::new(node1, 1234, vec![]));
+    sim.process(100);
+
+    sim.control(node1, control(data::Control::BulkSend(PORT, 2, 1000, vec![1; 100])));
+    for _ in 0..20 {
+        sim.process(1000);
+    }
+    let mut failed = false;
+    while let Some((_, out)) = sim.pop_res() {
+        if let ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::BulkFailed(1000, progress))) = out {
+            assert_eq!(progress.acked_bytes, 0);
+            failed = true;
+        }
+    }
+    assert!(failed);
+}
@@ -1,6 +1,9 @@
+use std::{sync::Arc, time::Duration};
+
 use atm0s_sdn_network::{
+    base::{CompressionConfig, LinkProfile},
     features::{
-        dht_kv::{Control, Event, Key, Map, MapControl, MapEvent},
+        dht_kv::{Control, DeniedOp, Event, GetError, Key, KeyRange, KvStorageBackend, Map, MapAcl, MapControl, MapEvent, MemoryKvStorage, NodeSession},
         FeaturesControl, FeaturesEvent,
     },
     ExtIn, ExtOut,
@@ -181,3 +184,306 @@ fn feature_dht_kv_move_key_other_relay() {
     assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnSet(sub_key, node2, value2))))));
     assert_eq!(sim.pop_res(), None);
 }
+
+#[test]
+fn feature_dht_kv_restore_after_restart() {
+    let node_id = 1;
+    let storage = Arc::new(MemoryKvStorage::default());
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    sim.add_node(TestNode::new_with(node_id, 1234, vec![], LinkProfile::Standard, Some(storage.clone())));
+
+    sim.process(100);
+
+    let key = Map(1000);
+    let sub_key = Key(2000);
+    let value = vec![1, 2, 3, 4];
+
+    sim.control(node_id, control(Control::MapCmd(key, MapControl::Set(sub_key, value.clone()))));
+    sim.process(100);
+    assert_eq!(storage.load().len(), 1);
+
+    // restart node with a new session, the map is restored from storage
+    sim.crash_node(node_id);
+    sim.add_node(TestNode::new_with(node_id, 1235, vec![], LinkProfile::Standard, Some(storage.clone())));
+    sim.process(100);
+
+    sim.control(node_id, control(Control::MapGet(key)));
+    sim.process(100);
+    match sim.pop_res() {
+        Some((_, ExtOut::FeaturesEvent((), FeaturesEvent::DhtKv(Event::MapGetRes(res_key, Ok(values)))))) => {
+            assert_eq!(res_key, key);
+            assert_eq!(values.len(), 1);
+            assert_eq!((values[0].0, values[0].1, &values[0].3), (sub_key, NodeSession(node_id, 1234), &value));
+        }
+        other => panic!("Should get restored value, got {:?}", other),
+    }
+}
+
+#[test]
+fn feature_dht_kv_two_nodes_ttl_expired() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let key = Map(1);
+    let sub_key = Key(2000);
+    let ttl = Duration::from_millis(3000);
+
+    sim.control(node1, control(Control::MapCmd(key, MapControl::Sub)));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnRelaySelected(node1))))));
+
+    sim.control(node2, control(Control::MapCmd(key, MapControl::SetWithTtl(sub_key, vec![1], ttl))));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnSet(sub_key, node2, vec![1]))))));
+
+    // set again before expired will refresh the ttl
+    for _i in 0..20 {
+        sim.process(100);
+    }
+    sim.control(node2, control(Control::MapCmd(key, MapControl::SetWithTtl(sub_key, vec![2], ttl))));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnSet(sub_key, node2, vec![2]))))));
+
+    // periodic syncs from source don't refresh the ttl
+    for _i in 0..25 {
+        sim.process(100);
+    }
+    assert_eq!(sim.pop_res(), None);
+
+    for _i in 0..10 {
+        sim.process(100);
+    }
+    assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnExpired(sub_key, node2))))));
+    assert_eq!(sim.pop_res(), None);
+
+    sim.control(node1, control(Control::MapGet(key)));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event::MapGetRes(key, Ok(vec![]))))));
+}
+
+#[test]
+fn feature_dht_kv_two_nodes_batch() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let key = Map(1);
+    let items = vec![(Key(2000), vec![1]), (Key(2001), vec![2]), (Key(2002), vec![3])];
+
+    sim.control(node1, control(Control::MapCmd(key, MapControl::Sub)));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnRelaySelected(node1))))));
+
+    sim.control(node2, control(Control::MapCmd(key, MapControl::BatchSet(items.clone()))));
+    sim.process(100);
+    for (sub_key, data) in &items {
+        assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnSet(*sub_key, node2, data.clone()))))));
+    }
+    assert_eq!(sim.pop_res(), None);
+
+    sim.control(node1, control(Control::MapGet(key)));
+    sim.process(100);
+    let values = match sim.pop_res() {
+        Some((_, ExtOut::FeaturesEvent(_, FeaturesEvent::DhtKv(Event::MapGetRes(_, Ok(values)))))) => values,
+        res => panic!("Unexpected {:?}", res),
+    };
+    let mut values = values.into_iter().map(|(sub_key, source, _version, data)| (sub_key, source.0, data)).collect::<Vec<_>>();
+    values.sort();
+    assert_eq!(values, items.iter().map(|(sub_key, data)| (*sub_key, node2, data.clone())).collect::<Vec<_>>());
+
+    sim.control(node2, control(Control::MapCmd(key, MapControl::BatchDel(items.iter().map(|(sub_key, _)| *sub_key).collect()))));
+    sim.process(100);
+    for (sub_key, _) in &items {
+        assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnDel(*sub_key, node2))))));
+    }
+    assert_eq!(sim.pop_res(), None);
+
+    // acked batches are not resent
+    for _i in 0..5 {
+        sim.process(100);
+    }
+    assert_eq!(sim.pop_res(), None);
+}
+
+#[test]
+fn feature_dht_kv_two_nodes_sub_filtered() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let key = Map(1);
+    sim.control(node2, control(Control::MapCmd(key, MapControl::Set(Key(1001), vec![1]))));
+    sim.control(node2, control(Control::MapCmd(key, MapControl::Set(Key(2001), vec![2]))));
+    sim.process(100);
+
+    sim.control(node1, control(Control::MapCmd(key, MapControl::SubFiltered(KeyRange::new(Key(1000), Key(1999))))));
+    sim.process(100);
+    let mut events = vec![];
+    while let Some(res) = sim.pop_res() {
+        events.push(res);
+    }
+    assert_eq!(
+        events,
+        vec![
+            (node1, event(Event::MapEvent(key, MapEvent::OnRelaySelected(node1)))),
+            (node1, event(Event::MapEvent(key, MapEvent::OnSet(Key(1001), node2, vec![1])))),
+        ]
+    );
+
+    // keys outside of the filter are not sent to subscriber
+    sim.control(node2, control(Control::MapCmd(key, MapControl::Set(Key(2002), vec![3]))));
+    sim.control(node2, control(Control::MapCmd(key, MapControl::Set(Key(1002), vec![4]))));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnSet(Key(1002), node2, vec![4]))))));
+    assert_eq!(sim.pop_res(), None);
+
+    sim.control(node2, control(Control::MapCmd(key, MapControl::Del(Key(2001)))));
+    sim.control(node2, control(Control::MapCmd(key, MapControl::Del(Key(1001)))));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnDel(Key(1001), node2))))));
+    assert_eq!(sim.pop_res(), None);
+}
+
+#[test]
+fn feature_dht_kv_two_nodes_acl() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let key = Map(1);
+    let acl = MapAcl {
+        writers: Some(vec![node1]),
+        readers: Some(vec![node1]),
+    };
+    sim.control(node1, control(Control::MapCmd(key, MapControl::SetAcl(acl))));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event::MapEvent(key, MapEvent::OnAclApplied)))));
+
+    sim.control(node2, control(Control::MapCmd(key, MapControl::SetAcl(MapAcl::default()))));
+    sim.control(node2, control(Control::MapCmd(key, MapControl::Set(Key(1000), vec![1]))));
+    sim.control(node2, control(Control::MapCmd(key, MapControl::Sub)));
+    sim.control(node2, control(Control::MapGet(key)));
+    sim.process(100);
+    let mut events = vec![];
+    while let Some(res) = sim.pop_res() {
+        events.push(res);
+    }
+    assert_eq!(
+        events,
+        vec![
+            // local value is fired to local subscriber before the relay denies it
+            (node2, event(Event::MapEvent(key, MapEvent::OnSet(Key(1000), node2, vec![1])))),
+            (node2, event(Event::MapEvent(key, MapEvent::OnDenied(DeniedOp::Acl)))),
+            (node2, event(Event::MapEvent(key, MapEvent::OnDenied(DeniedOp::Set(Key(1000)))))),
+            (node2, event(Event::MapEvent(key, MapEvent::OnDenied(DeniedOp::Sub)))),
+            (node2, event(Event::MapGetRes(key, Err(GetError::Denied)))),
+        ]
+    );
+
+    // owner releases the map, then other nodes can write again
+    sim.control(node1, control(Control::MapCmd(key, MapControl::SetAcl(MapAcl::default()))));
+    sim.control(node1, control(Control::MapCmd(key, MapControl::Sub)));
+    sim.process(100);
+    sim.control(node2, control(Control::MapCmd(key, MapControl::Set(Key(1000), vec![2]))));
+    sim.process(100);
+    let mut events = vec![];
+    while let Some(res) = sim.pop_res() {
+        events.push(res);
+    }
+    assert_eq!(
+        events,
+        vec![
+            (node1, event(Event::MapEvent(key, MapEvent::OnAclApplied))),
+            (node1, event(Event::MapEvent(key, MapEvent::OnRelaySelected(node1)))),
+            (node1, event(Event::MapEvent(key, MapEvent::OnSet(Key(1000), node2, vec![2])))),
+        ]
+    );
+}
+
+#[test]
+fn feature_dht_kv_two_nodes_compressed_values() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    // node2 doesn't compress, but it can decode compressed values
+    let compression = CompressionConfig::default();
+    let _addr1 = sim.add_node(TestNode::new_with_compression(node1, 1234, vec![], compression.clone()));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let key = Map(1);
+    sim.control(node2, control(Control::MapCmd(key, MapControl::Sub)));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node2, event(Event::MapEvent(key, MapEvent::OnRelaySelected(node1))))));
+
+    let value: Vec<u8> = (0..2000).map(|i| (i % 16) as u8).collect();
+    sim.control(node1, control(Control::MapCmd(key, MapControl::Set(Key(1000), value.clone()))));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node2, event(Event::MapEvent(key, MapEvent::OnSet(Key(1000), node1, value))))));
+
+    // already compressed value is sent raw
+    let mut seed = 1u32;
+    let media: Vec<u8> = (0..2000)
+        .map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        })
+        .collect();
+    sim.control(node1, control(Control::MapCmd(key, MapControl::Set(Key(1001), media.clone()))));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node2, event(Event::MapEvent(key, MapEvent::OnSet(Key(1001), node1, media))))));
+    assert_eq!(sim.pop_res(), None);
+
+    let stats = sim.controller_metrics(node1).compression["dht_kv"];
+    assert!(stats.compressed_msgs >= 1);
+    assert!(stats.skipped_msgs >= 1);
+    assert!(stats.ratio().expect("Should have ratio") < 0.5);
+}
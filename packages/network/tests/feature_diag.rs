@@ -0,0 +1,74 @@
+use atm0s_sdn_network::{
+    base::FeatureError,
+    features::{diag, FeaturesControl, FeaturesEvent},
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+fn control(control: diag::Control) -> ExtIn<(), ()> {
+    ExtIn::FeaturesControl((), FeaturesControl::Diag(control))
+}
+
+/// Build a chain 1 - 2 - 3, so paths from node1 to node3 are relayed by node2
+fn build_chain() -> NetworkSimulator<(), (), (), ()> {
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    let _addr1 = sim.add_node(TestNode::new_with_seed(1, 1234, vec![], 1000));
+    let addr2 = sim.add_node(TestNode::new_with_seed(2, 1235, vec![], 2000));
+    let _addr3 = sim.add_node(TestNode::new_with_seed(3, 1236, vec![], 3000));
+
+    sim.control(1, ExtIn::ConnectTo(addr2.clone()));
+    sim.control(3, ExtIn::ConnectTo(addr2));
+    for _i in 0..8 {
+        sim.process(500);
+    }
+    while sim.pop_res().is_some() {}
+    sim
+}
+
+fn wait_event(sim: &mut NetworkSimulator<(), (), (), ()>, steps: usize) -> Option<diag::Event> {
+    for _ in 0..steps {
+        sim.process(100);
+        while let Some((node, out)) = sim.pop_res() {
+            if let (1, ExtOut::FeaturesEvent((), FeaturesEvent::Diag(event))) = (node, out) {
+                return Some(event);
+            }
+        }
+    }
+    None
+}
+
+#[test]
+fn feature_diag_trace_route() {
+    let mut sim = build_chain();
+
+    sim.control(1, control(diag::Control::TraceRoute(3)));
+    let event = wait_event(&mut sim, 50).expect("Should have trace route result");
+    assert_eq!(event, diag::Event::TraceRoute(3, vec![Some(2), Some(3)]));
+    assert_eq!(event.error(), None);
+}
+
+#[test]
+fn feature_diag_ping_hops() {
+    let mut sim = build_chain();
+
+    sim.control(1, control(diag::Control::Ping(3)));
+    let event = wait_event(&mut sim, 50).expect("Should have ping result");
+    let diag::Event::Ping(3, hops) = event else {
+        panic!("Unexpected event {event:?}");
+    };
+    let nodes = hops.iter().map(|hop| hop.map(|hop| hop.node)).collect::<Vec<_>>();
+    assert_eq!(nodes, vec![Some(2), Some(3)]);
+}
+
+#[test]
+fn feature_diag_unreachable() {
+    let mut sim = build_chain();
+
+    sim.control(1, control(diag::Control::Ping(4)));
+    let event = wait_event(&mut sim, 50).expect("Should have ping result");
+    assert_eq!(event, diag::Event::Ping(4, vec![]));
+    assert_eq!(event.error(), Some(FeatureError::Timeout));
+}
@@ -0,0 +1,71 @@
+use atm0s_sdn_network::{
+    features::{
+        membership::{self, Member, MemberState},
+        FeaturesControl, FeaturesEvent,
+    },
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+fn membership_control(control: membership::Control) -> ExtIn<(), ()> {
+    ExtIn::FeaturesControl((), FeaturesControl::Membership(control))
+}
+
+fn membership_event(event: membership::Event) -> ExtOut<(), ()> {
+    ExtOut::FeaturesEvent((), FeaturesEvent::Membership(event))
+}
+
+fn alive(node: u32) -> Member {
+    Member {
+        node,
+        incarnation: 0,
+        state: MemberState::Alive,
+    }
+}
+
+#[test]
+fn feature_membership_detect_crashed_node() {
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    // node1 <-> node2 <-> node3, node1 learns node3 over node2
+    let _addr1 = sim.add_node(TestNode::new_with_seed(1, 1234, vec![], 1000));
+    let addr2 = sim.add_node(TestNode::new_with_seed(2, 1235, vec![], 2000));
+    let _addr3 = sim.add_node(TestNode::new_with_seed(3, 1236, vec![], 3000));
+
+    sim.control(1, ExtIn::ConnectTo(addr2.clone()));
+    sim.control(3, ExtIn::ConnectTo(addr2));
+
+    for _i in 0..6 {
+        sim.process(500);
+    }
+
+    sim.control(1, membership_control(membership::Control::Members));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), Some((1, membership_event(membership::Event::Members(vec![alive(1), alive(2), alive(3)])))));
+
+    sim.control(1, membership_control(membership::Control::Sub));
+    sim.process(10);
+    sim.crash_node(3);
+
+    let mut events = vec![];
+    for _i in 0..20 {
+        sim.process(500);
+        while let Some((node, event)) = sim.pop_res() {
+            assert_eq!(node, 1);
+            events.push(event);
+        }
+    }
+    assert_eq!(events, vec![membership_event(membership::Event::Suspect(3, 0)), membership_event(membership::Event::Dead(3, 0))]);
+
+    sim.control(1, membership_control(membership::Control::Members));
+    sim.process(10);
+    let dead = Member {
+        node: 3,
+        incarnation: 0,
+        state: MemberState::Dead,
+    };
+    assert_eq!(sim.pop_res(), Some((1, membership_event(membership::Event::Members(vec![alive(1), alive(2), dead])))));
+}
@@ -0,0 +1,77 @@
+use std::net::SocketAddr;
+
+use atm0s_sdn_network::{
+    features::{nat_traversal, FeaturesControl, FeaturesEvent},
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{node_to_addr, NetworkSimulator, TestNode};
+
+mod simulator;
+
+fn nat_control(control: nat_traversal::Control) -> ExtIn<(), ()> {
+    ExtIn::FeaturesControl((), FeaturesControl::NatTraversal(control))
+}
+
+fn build_chain(sim: &mut NetworkSimulator<(), (), (), ()>) {
+    // node1 <-> relay2 <-> node3
+    let _addr1 = sim.add_node(TestNode::new_with_seed(1, 1234, vec![], 1000));
+    let addr2 = sim.add_node(TestNode::new_with_seed(2, 1235, vec![], 2000));
+    let _addr3 = sim.add_node(TestNode::new_with_seed(3, 1236, vec![], 3000));
+
+    sim.control(1, ExtIn::ConnectTo(addr2.clone()));
+    sim.control(3, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+}
+
+#[test]
+fn feature_nat_traversal_advertise_reflexive_addr() {
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    build_chain(&mut sim);
+
+    sim.control(1, nat_control(nat_traversal::Control::Addrs));
+    sim.process(10);
+    let addrs: Vec<SocketAddr> = vec![node_to_addr(1)];
+    assert_eq!(sim.pop_res(), Some((1, ExtOut::FeaturesEvent((), FeaturesEvent::NatTraversal(nat_traversal::Event::Addrs(addrs))))));
+}
+
+#[test]
+fn feature_nat_traversal_punch_over_relay() {
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    build_chain(&mut sim);
+
+    sim.control(1, nat_control(nat_traversal::Control::Punch(3)));
+    for _i in 0..4 {
+        sim.process(100);
+    }
+    match sim.pop_res() {
+        Some((1, ExtOut::FeaturesEvent((), FeaturesEvent::NatTraversal(nat_traversal::Event::Punched(3, _))))) => {}
+        res => panic!("Unexpected result {res:?}"),
+    }
+
+    // already connected node is reported immediately
+    sim.control(1, nat_control(nat_traversal::Control::Punch(3)));
+    sim.process(10);
+    match sim.pop_res() {
+        Some((1, ExtOut::FeaturesEvent((), FeaturesEvent::NatTraversal(nat_traversal::Event::Punched(3, _))))) => {}
+        res => panic!("Unexpected result {res:?}"),
+    }
+}
+
+#[test]
+fn feature_nat_traversal_fallback_to_relay() {
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    build_chain(&mut sim);
+
+    // symmetric NAT: direct packets between node1 and node3 never pass
+    sim.set_packet_filter(Box::new(|from, to, _| !matches!((from, to), (1, 3) | (3, 1))));
+    sim.control(1, nat_control(nat_traversal::Control::Punch(3)));
+    for _i in 0..11 {
+        sim.process(1000);
+    }
+    assert_eq!(sim.pop_res(), Some((1, ExtOut::FeaturesEvent((), FeaturesEvent::NatTraversal(nat_traversal::Event::Relayed(3))))));
+}
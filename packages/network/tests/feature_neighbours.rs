@@ -1,10 +1,18 @@
+use std::{cell::RefCell, rc::Rc, sync::Arc};
+
 use atm0s_sdn_identity::ConnId;
 use atm0s_sdn_network::{
-    features::{neighbours, FeaturesControl, FeaturesEvent},
+    base::{ConnectFailReason, NeighboursControl, NeighboursControlCmds, SecureInfo},
+    features::{
+        neighbours,
+        rpc::{self, RpcDest},
+        FeaturesControl, FeaturesEvent,
+    },
+    secure::{AllowlistProvider, Ed25519Authorization, Ed25519Keypair, StaticKeyAuthorization},
     ExtIn, ExtOut,
 };
 
-use crate::simulator::{NetworkSimulator, TestNode};
+use crate::simulator::{build_addr, node_to_addr, NetworkSimulator, TestNode};
 
 mod simulator;
 
@@ -39,12 +47,261 @@ fn feature_neighbours_two_nodes() {
         vec![
             (
                 node1,
-                ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(node2, ConnId::from_out(0, 1000))))
+                ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(node2, ConnId::from_out(0, 1000), node_to_addr(node2))))
+            ),
+            (
+                node2,
+                ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(node1, ConnId::from_in(0, 1000), node_to_addr(node1))))
+            ),
+        ]
+    );
+}
+
+#[test]
+fn feature_neighbours_connect_failed() {
+    let node1 = 1;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+
+    // node3 is not in the network, so the connect request is never answered
+    let addr3 = build_addr(3);
+    sim.control(node1, ExtIn::ConnectTo(addr3.clone()));
+    for _i in 0..32 {
+        sim.process(1000);
+    }
+
+    assert_eq!(
+        sim.pop_res(),
+        Some((
+            node1,
+            ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::ConnectFailed(addr3, ConnectFailReason::Timeout)))
+        ))
+    );
+    assert_eq!(sim.pop_res(), None);
+}
+
+#[test]
+fn feature_neighbours_restart_connection() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    while sim.pop_res().is_some() {}
+
+    let conn = ConnId::from_out(0, 1000);
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Restart(conn))));
+
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let mut out = vec![];
+    while let Some((node, out_event)) = sim.pop_res() {
+        out.push((node, out_event));
+    }
+    // the replacement is connected before the old connection is closed
+    out.sort_by(|a, b| a.0.cmp(&b.0));
+
+    assert_eq!(
+        out,
+        vec![
+            (
+                node1,
+                ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(node2, ConnId::from_out(0, 1005), node_to_addr(node2))))
+            ),
+            (node1, ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Disconnected(node2, conn)))),
+            (
+                node2,
+                ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(node1, ConnId::from_in(0, 1005), node_to_addr(node1))))
+            ),
+            (
+                node2,
+                ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Disconnected(node1, ConnId::from_in(0, 1000))))
+            ),
+        ]
+    );
+}
+
+#[test]
+fn feature_neighbours_secure_stats() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    let stats_control = ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::SecureStats));
+    let stats_event = |stats: Vec<(SecureInfo, usize)>| ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::SecureStats(stats)));
+    let xda = SecureInfo {
+        handshake: "xda",
+        cipher: "x25519-aes256gcm",
+    };
+
+    sim.control(node1, stats_control.clone());
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node1, stats_event(vec![]))));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    sim.control(node1, stats_control.clone());
+    sim.control(node2, stats_control.clone());
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node1, stats_event(vec![(xda, 1)]))));
+    assert_eq!(sim.pop_res(), Some((node2, stats_event(vec![(xda, 1)]))));
+    assert_eq!(sim.pop_res(), None);
+
+    sim.control(node1, ExtIn::DisconnectFrom(node2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    sim.control(node1, stats_control.clone());
+    sim.control(node2, stats_control);
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node1, stats_event(vec![]))));
+    assert_eq!(sim.pop_res(), Some((node2, stats_event(vec![]))));
+    assert_eq!(sim.pop_res(), None);
+}
+
+#[test]
+fn feature_neighbours_ed25519_allowlist() {
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let keys: Vec<_> = (0..3).map(|_| Ed25519Keypair::generate()).collect();
+    // node3 is not in the allowlist of the network
+    let allowlist = Arc::new(AllowlistProvider::new([(node1, keys[0].public_key()), (node2, keys[1].public_key())]));
+    let node3_allowlist = Arc::new(AllowlistProvider::new([(node1, keys[0].public_key()), (node2, keys[1].public_key()), (node3, keys[2].public_key())]));
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new_with_authorization(
+        node1,
+        1234,
+        vec![],
+        Arc::new(Ed25519Authorization::new(keys[0].clone(), allowlist.clone())),
+    ));
+    let addr2 = sim.add_node(TestNode::new_with_authorization(
+        node2,
+        1235,
+        vec![],
+        Arc::new(Ed25519Authorization::new(keys[1].clone(), allowlist.clone())),
+    ));
+    let _addr3 = sim.add_node(TestNode::new_with_authorization(
+        node3,
+        1236,
+        vec![],
+        Arc::new(Ed25519Authorization::new(keys[2].clone(), node3_allowlist)),
+    ));
+
+    for node in [node1, node2, node3] {
+        sim.control(node, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    }
+    sim.control(node1, ExtIn::ConnectTo(addr2.clone()));
+    sim.control(node3, ExtIn::ConnectTo(addr2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let mut out = vec![];
+    while let Some(res) = sim.pop_res() {
+        out.push(res);
+    }
+    out.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        out,
+        vec![
+            (
+                node1,
+                ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(node2, ConnId::from_out(0, 1000), node_to_addr(node2))))
             ),
             (
                 node2,
-                ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(node1, ConnId::from_in(0, 1000))))
+                ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(node1, ConnId::from_in(0, 1000), node_to_addr(node1))))
             ),
         ]
     );
+
+    // revoked node2 is disconnected after the connection timeout, because its control messages are rejected
+    allowlist.revoke(node2);
+    for _i in 0..12 {
+        sim.process(1000);
+    }
+    let mut out = vec![];
+    while let Some(res) = sim.pop_res() {
+        out.push(res);
+    }
+    assert!(out.contains(&(
+        node1,
+        ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Disconnected(node2, ConnId::from_out(0, 1000))))
+    )));
+}
+
+#[test]
+fn feature_neighbours_rekey_without_dropping() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    // epochs which are finished with RekeyDone
+    let epochs = Rc::new(RefCell::new(vec![]));
+    let epochs_c = epochs.clone();
+    let auth = StaticKeyAuthorization::new("demo-key");
+    sim.set_packet_filter(Box::new(move |_from, _to, data| {
+        if let Ok(control) = NeighboursControl::try_from(data) {
+            if let Ok(NeighboursControlCmds::RekeyDone { epoch, .. }) = control.validate(0, &auth) {
+                epochs_c.borrow_mut().push(epoch);
+            }
+        }
+        true
+    }));
+
+    let _addr1 = sim.add_node(TestNode::new_with_rekey(node1, 1234, vec![], 2000));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert!(matches!(
+        sim.pop_res(),
+        Some((1, ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(2, _, _)))))
+    ));
+
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Rpc(rpc::Control::Register(10))));
+    for i in 0..20 {
+        sim.control(
+            node1,
+            ExtIn::FeaturesControl((), FeaturesControl::Rpc(rpc::Control::Request(i, RpcDest::Node(node2, 10), vec![i as u8], 1000))),
+        );
+        sim.process(10);
+        let remote = match sim.pop_res() {
+            Some((2, ExtOut::FeaturesEvent((), FeaturesEvent::Rpc(rpc::Event::Request(10, remote, data))))) if data == vec![i as u8] => remote,
+            res => panic!("Should receive request {i}, got {:?}", res),
+        };
+        sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Rpc(rpc::Control::Response(remote, vec![i as u8]))));
+        sim.process(10);
+        assert_eq!(sim.pop_res(), Some((1, ExtOut::FeaturesEvent((), FeaturesEvent::Rpc(rpc::Event::Response(i, Ok(vec![i as u8])))))));
+        sim.process(480);
+    }
+
+    // 10 seconds with 2 seconds interval, connection is never dropped
+    assert!(epochs.borrow().len() >= 4, "Should rekey multiple times, got {:?}", epochs.borrow());
+    assert_eq!(sim.pop_res(), None);
 }
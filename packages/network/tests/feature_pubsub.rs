@@ -1,8 +1,13 @@
+use std::{cell::Cell, rc::Rc, sync::Arc};
+
+use atm0s_sdn_identity::NodeId;
 use atm0s_sdn_network::{
+    base::FeatureError,
     features::{
-        pubsub::{ChannelControl, ChannelEvent, ChannelId, Control, Event, Feedback},
+        pubsub::{ChannelAuthorizer, ChannelControl, ChannelEvent, ChannelId, Control, Event, FecConfig, Feedback, LoopbackStats, RelayStats},
         FeaturesControl, FeaturesEvent,
     },
+    simulation::{LinkModel, Simulation},
     ExtIn, ExtOut,
 };
 
@@ -36,6 +41,120 @@ fn feature_pubsub_manual_single_node() {
     assert_eq!(sim.pop_res(), None);
 }
 
+#[test]
+fn feature_pubsub_relay_lifecycle() {
+    let node_id = 1;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    sim.add_node(TestNode::new(node_id, 1234, vec![]));
+
+    sim.process(100);
+
+    let channel = ChannelId(1000);
+    sim.control(node_id, control(Control(ChannelId(0), ChannelControl::SubRelayLifecycle)));
+    sim.control(node_id, control(Control(channel, ChannelControl::SubSource(node_id))));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node_id, event(Event(channel, ChannelEvent::RelayCreated(node_id))))));
+    assert_eq!(sim.pop_res(), None);
+
+    // relay is created at 200 ms, then destroyed at 1101 ms
+    sim.process(900);
+    sim.control(node_id, control(Control(channel, ChannelControl::UnsubSource(node_id))));
+    sim.process(1);
+    let stats = RelayStats {
+        lifetime_ms: 901,
+        local_subscribers: 0,
+        remote_subscribers: 0,
+        peak_subscribers: 1,
+    };
+    assert_eq!(sim.pop_res(), Some((node_id, event(Event(channel, ChannelEvent::RelayDestroyed(node_id, stats))))));
+    assert_eq!(sim.pop_res(), None);
+
+    // publisher without subscribers is idle
+    sim.control(node_id, control(Control(channel, ChannelControl::PubStart)));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node_id, event(Event(channel, ChannelEvent::RelayCreated(node_id))))));
+    assert_eq!(sim.pop_res(), Some((node_id, event(Event(channel, ChannelEvent::RelayIdle(node_id, RelayStats::default()))))));
+    assert_eq!(sim.pop_res(), None);
+
+    // no more events after unsubscribed
+    sim.control(node_id, control(Control(ChannelId(0), ChannelControl::UnsubRelayLifecycle)));
+    sim.control(node_id, control(Control(channel, ChannelControl::PubStop)));
+    sim.process(1000);
+    assert_eq!(sim.pop_res(), None);
+}
+
+#[test]
+fn feature_pubsub_local_loopback_toggle() {
+    let node_id = 1;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    sim.add_node(TestNode::new(node_id, 1234, vec![]));
+
+    sim.process(100);
+
+    let channel = ChannelId(1000);
+    let value = vec![1, 2, 3, 4];
+
+    sim.control(node_id, control(Control(channel, ChannelControl::SubSource(node_id))));
+    sim.control(node_id, control(Control(channel, ChannelControl::PubData(value.clone()))));
+    sim.control(node_id, control(Control(channel, ChannelControl::LoopbackStats)));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node_id, event(Event(channel, ChannelEvent::SourceData(node_id, value.clone()))))));
+    let stats = LoopbackStats {
+        enabled: true,
+        loopback_msgs: 1,
+        loopback_bytes: 4,
+        serialized_msgs: 0,
+        serialized_bytes: 0,
+
+        fanout_msgs: 0,
+    };
+    assert_eq!(sim.pop_res(), Some((node_id, event(Event(channel, ChannelEvent::LoopbackStats(stats))))));
+    assert_eq!(sim.pop_res(), None);
+
+    // without loopback, data is still delivered but after serialized by worker
+    sim.control(node_id, control(Control(channel, ChannelControl::SetLocalLoopback(false))));
+    sim.process(1);
+    sim.control(node_id, control(Control(channel, ChannelControl::PubData(value.clone()))));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node_id, event(Event(channel, ChannelEvent::SourceData(node_id, value.clone()))))));
+    assert_eq!(sim.pop_res(), None);
+
+    sim.control(node_id, control(Control(channel, ChannelControl::LoopbackStats)));
+    sim.process(1);
+    let stats = LoopbackStats {
+        enabled: false,
+        loopback_msgs: 1,
+        loopback_bytes: 4,
+        serialized_msgs: 1,
+        serialized_bytes: 4,
+
+        fanout_msgs: 0,
+    };
+    assert_eq!(sim.pop_res(), Some((node_id, event(Event(channel, ChannelEvent::LoopbackStats(stats))))));
+    assert_eq!(sim.pop_res(), None);
+}
+
+#[test]
+fn feature_pubsub_local_loopback_disabled_worker() {
+    let node_id = 1;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    sim.add_node(TestNode::new(node_id, 1234, vec![]));
+
+    sim.process(100);
+
+    let channel = ChannelId(1000);
+    let value = vec![1, 2, 3, 4];
+
+    sim.control(node_id, control(Control(channel, ChannelControl::SetLocalLoopback(false))));
+    sim.control_worker(node_id, control(Control(channel, ChannelControl::SubSource(node_id))));
+    sim.process(1);
+    sim.control_worker(node_id, control(Control(channel, ChannelControl::PubData(value.clone()))));
+    sim.process(1);
+    assert_eq!(sim.pop_res_worker(), Some((node_id, event(Event(channel, ChannelEvent::SourceData(node_id, value))))));
+    assert_eq!(sim.pop_res_worker(), None);
+    assert_eq!(sim.pop_res(), None);
+}
+
 #[test]
 fn feature_pubsub_auto_single_node() {
     let node_id = 1;
@@ -132,6 +251,167 @@ fn feature_pubsub_manual_two_nodes() {
     assert_eq!(sim.pop_res(), None);
 }
 
+#[test]
+fn feature_pubsub_fec_recover_lost_data() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    // drop the first large packet from node2 to node1 after armed
+    let drop_armed = Rc::new(Cell::new(false));
+    let drop_armed_c = drop_armed.clone();
+    sim.set_packet_filter(Box::new(move |from, _to, data| {
+        if from == node2 && data.len() >= 500 && drop_armed_c.get() {
+            drop_armed_c.set(false);
+            return false;
+        }
+        true
+    }));
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let channel = ChannelId(1000);
+    let value1 = vec![1; 500];
+    let value2 = vec![2; 520];
+
+    sim.control(node2, control(Control(channel, ChannelControl::SetFec(Some(FecConfig::xor(2))))));
+    sim.control(node1, control(Control(channel, ChannelControl::SubSource(node2))));
+    sim.process(1);
+
+    drop_armed.set(true);
+    sim.control(node2, control(Control(channel, ChannelControl::PubData(value1.clone()))));
+    sim.process(1);
+    assert!(!drop_armed.get());
+    assert_eq!(sim.pop_res(), None);
+
+    // the lost data is recovered after the parity of its group is received
+    sim.control(node2, control(Control(channel, ChannelControl::PubData(value2.clone()))));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event(channel, ChannelEvent::SourceData(node2, value2))))));
+    assert_eq!(sim.pop_res(), Some((node1, event(Event(channel, ChannelEvent::SourceData(node2, value1))))));
+    assert_eq!(sim.pop_res(), None);
+}
+
+#[test]
+fn feature_pubsub_replay_retained() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let channel = ChannelId(1000);
+    sim.control(node2, control(Control(channel, ChannelControl::PubStart)));
+    sim.control(node2, control(Control(channel, ChannelControl::PubDataRetained(vec![1]))));
+    sim.control(node2, control(Control(channel, ChannelControl::PubDataRetained(vec![2]))));
+    sim.control(node2, control(Control(channel, ChannelControl::PubData(vec![3]))));
+    sim.control(node2, control(Control(channel, ChannelControl::PubDataRetained(vec![4]))));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), None);
+
+    // late subscribers in remote and source nodes receive the last retained messages
+    sim.control(node1, control(Control(channel, ChannelControl::SubWithReplay(2))));
+    sim.control(node2, control(Control(channel, ChannelControl::SubWithReplay(5))));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), Some((node2, event(Event(channel, ChannelEvent::ReplayData(node2, vec![1]))))));
+    assert_eq!(sim.pop_res(), Some((node2, event(Event(channel, ChannelEvent::ReplayData(node2, vec![2]))))));
+    assert_eq!(sim.pop_res(), Some((node2, event(Event(channel, ChannelEvent::ReplayData(node2, vec![4]))))));
+    assert_eq!(sim.pop_res(), Some((node1, event(Event(channel, ChannelEvent::ReplayData(node2, vec![2]))))));
+    assert_eq!(sim.pop_res(), Some((node1, event(Event(channel, ChannelEvent::ReplayData(node2, vec![4]))))));
+    assert_eq!(sim.pop_res(), None);
+
+    // live data is delivered as usual
+    sim.control(node2, control(Control(channel, ChannelControl::PubDataRetained(vec![5]))));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node2, event(Event(channel, ChannelEvent::SourceData(node2, vec![5]))))));
+    assert_eq!(sim.pop_res(), Some((node1, event(Event(channel, ChannelEvent::SourceData(node2, vec![5]))))));
+    assert_eq!(sim.pop_res(), None);
+
+    // history is cleared after the publisher stops
+    sim.control(node1, control(Control(channel, ChannelControl::UnsubAuto)));
+    sim.control(node2, control(Control(channel, ChannelControl::UnsubAuto)));
+    sim.control(node2, control(Control(channel, ChannelControl::PubStop)));
+    sim.process(10);
+    sim.control(node2, control(Control(channel, ChannelControl::PubStart)));
+    sim.control(node1, control(Control(channel, ChannelControl::SubWithReplay(2))));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), None);
+}
+
+#[test]
+fn feature_pubsub_sub_range() {
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let channel1 = ChannelId(1 << 32 | 1);
+    let channel2 = ChannelId(1 << 32 | 2);
+    let other_family = ChannelId(2 << 32 | 1);
+    sim.control(node3, control(Control(channel1, ChannelControl::PubStart)));
+    sim.control(node3, control(Control(channel2, ChannelControl::PubStart)));
+    sim.control(node3, control(Control(other_family, ChannelControl::PubStart)));
+    sim.process(1);
+
+    // range across families is rejected
+    sim.control(node1, control(Control(channel1, ChannelControl::SubRange(channel1, other_family))));
+    sim.control(node1, control(Control(channel1, ChannelControl::SubRange(ChannelId(1 << 32), ChannelId(1 << 32 | 100)))));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event(channel1, ChannelEvent::RangeRejected)))));
+    assert_eq!(sim.pop_res(), Some((node1, event(Event(channel1, ChannelEvent::RangeMatched(node3))))));
+    assert_eq!(sim.pop_res(), Some((node1, event(Event(channel2, ChannelEvent::RangeMatched(node3))))));
+    assert_eq!(sim.pop_res(), None);
+
+    // data of matched channels is delivered with their own channel id
+    sim.control(node3, control(Control(channel2, ChannelControl::PubData(vec![2]))));
+    sim.control(node3, control(Control(other_family, ChannelControl::PubData(vec![3]))));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event(channel2, ChannelEvent::SourceData(node3, vec![2]))))));
+    assert_eq!(sim.pop_res(), None);
+
+    // stopped channel is unmatched after the next query
+    sim.control(node3, control(Control(channel2, ChannelControl::PubStop)));
+    sim.process(1);
+    sim.process(1000);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event(channel2, ChannelEvent::RangeUnmatched(node3))))));
+    assert_eq!(sim.pop_res(), None);
+
+    sim.control(node1, control(Control(channel1, ChannelControl::UnsubRange(ChannelId(1 << 32), ChannelId(1 << 32 | 100)))));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event(channel1, ChannelEvent::RangeUnmatched(node3))))));
+    sim.control(node3, control(Control(channel1, ChannelControl::PubData(vec![1]))));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), None);
+}
+
 #[test]
 fn feature_pubsub_auto_two_nodes() {
     let node1 = 1;
@@ -293,3 +573,94 @@ fn feature_pubsub_auto_three_nodes_sub_after_start() {
     sim.process(1);
     assert_eq!(sim.pop_res(), None);
 }
+
+struct TokenAuthorizer;
+
+impl ChannelAuthorizer for TokenAuthorizer {
+    fn authorize(&self, _channel: ChannelId, _requester: NodeId, token: Option<&[u8]>) -> bool {
+        token == Some(b"secret")
+    }
+}
+
+#[test]
+fn feature_pubsub_authorizer_reject_and_accept() {
+    type Sim = Simulation<(), (), (), ()>;
+    let mut sim = Sim::new(0);
+    // chain 1 - 2 - 3, node2 relays the channel of node3 and both check Subs with the authorizer
+    let _addr1 = sim.add_node(Sim::node_cfg(1, 1234, vec![]));
+    for (node, session) in [(2, 1235), (3, 1236)] {
+        let mut cfg = Sim::node_cfg(node, session, vec![]);
+        cfg.controller.as_mut().expect("Should have controller").channel_authorizer = Some(Arc::new(TokenAuthorizer));
+        let addr = sim.add_node(cfg);
+        sim.control(node - 1, ExtIn::ConnectTo(addr));
+    }
+    sim.advance(5000);
+    while sim.pop_output().is_some() {}
+
+    let channel = ChannelId(1000);
+    sim.control(3, control(Control(channel, ChannelControl::PubStart)));
+
+    // node1 without token is rejected by node2, then node2 without token is rejected by node3
+    for token_node in [None, Some(1)] {
+        if let Some(node) = token_node {
+            sim.control(node, control(Control(channel, ChannelControl::SetSubToken(Some(b"secret".to_vec())))));
+        }
+        sim.control(1, control(Control(channel, ChannelControl::SubSource(3))));
+        sim.advance(100);
+        assert_eq!(sim.pop_output(), Some((1, event(Event(channel, ChannelEvent::SubRejected(3))))));
+        assert_eq!(sim.pop_output(), None);
+        assert_eq!(Event(channel, ChannelEvent::SubRejected(3)).error(), Some(FeatureError::Rejected));
+    }
+
+    sim.control(2, control(Control(channel, ChannelControl::SetSubToken(Some(b"secret".to_vec())))));
+    sim.control(1, control(Control(channel, ChannelControl::SubSource(3))));
+    sim.advance(100);
+    sim.control(3, control(Control(channel, ChannelControl::PubData(vec![1, 2, 3]))));
+    sim.advance(100);
+    assert_eq!(sim.pop_output(), Some((1, event(Event(channel, ChannelEvent::SourceData(3, vec![1, 2, 3]))))));
+    assert_eq!(sim.pop_output(), None);
+}
+
+#[test]
+fn feature_pubsub_rebalance_to_faster_path() {
+    type Sim = Simulation<(), (), (), ()>;
+    let mut sim = Sim::new(0);
+    // triangle 1 - 2 - 3, the direct link 1 - 3 is slow then node1 relays over node2
+    sim.set_default_link(LinkModel::new(5));
+    sim.set_link(1, 3, LinkModel::new(100));
+    let addr1 = sim.add_node(Sim::node_cfg(1, 1234, vec![]));
+    let addr2 = sim.add_node(Sim::node_cfg(2, 1235, vec![]));
+    let _addr3 = sim.add_node(Sim::node_cfg(3, 1236, vec![]));
+    sim.control(2, ExtIn::ConnectTo(addr1.clone()));
+    sim.control(3, ExtIn::ConnectTo(addr1));
+    sim.control(3, ExtIn::ConnectTo(addr2));
+    sim.advance(10_000);
+    while sim.pop_output().is_some() {}
+
+    let channel = ChannelId(1000);
+    sim.control(3, control(Control(channel, ChannelControl::PubStart)));
+    sim.control(1, control(Control(channel, ChannelControl::SubSource(3))));
+    sim.advance(1000);
+    while sim.pop_output().is_some() {}
+
+    // the path over node2 becomes slow, node1 migrates to the direct link without losing data
+    sim.set_link(1, 2, LinkModel::new(300));
+    let mut route_changed = false;
+    for i in 0..10 {
+        sim.control(3, control(Control(channel, ChannelControl::PubData(vec![i]))));
+        sim.advance(1000);
+        while let Some(out) = sim.pop_output() {
+            match out {
+                (1, out) if out == event(Event(channel, ChannelEvent::RouteChanged(3))) => route_changed = true,
+                (1, out) if out == event(Event(channel, ChannelEvent::SourceData(3, vec![i]))) => {}
+                _ => panic!("Unexpected output {:?}", out),
+            }
+        }
+    }
+    assert!(route_changed);
+
+    sim.control(3, control(Control(channel, ChannelControl::PubData(vec![1, 2, 3]))));
+    sim.advance(150);
+    assert_eq!(sim.pop_output(), Some((1, event(Event(channel, ChannelEvent::SourceData(3, vec![1, 2, 3]))))));
+    assert_eq!(sim.pop_output(), None);
+}
@@ -5,7 +5,7 @@ use atm0s_sdn_network::{
         NetIncomingMeta, NetOutgoingMeta, Service, ServiceBuilder, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput, ServiceWorker, ServiceWorkerCtx, ServiceWorkerInput,
         ServiceWorkerOutput,
     },
-    features::{data, FeaturesControl, FeaturesEvent},
+    features::{data, router_sync, Features, FeaturesControl, FeaturesEvent},
     ExtIn, ExtOut,
 };
 use atm0s_sdn_router::RouteRule;
@@ -174,3 +174,145 @@ fn feature_router_sync_three_nodes() {
     sim.process(10);
     assert_eq!(sim.pop_res(), Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Pong(node3, Some(0)))))));
 }
+
+#[test]
+fn feature_router_sync_dump_network() {
+    // node1 <-> node2 <-> node3
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![Arc::new(MockServiceBuilder)]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![Arc::new(MockServiceBuilder)]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![Arc::new(MockServiceBuilder)]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::RouterSync(router_sync::Control::DumpNetwork(1000, 0))));
+    sim.process(10);
+    // snapshots are emitted after the collecting window
+    assert_eq!(sim.pop_res(), None);
+
+    sim.process(router_sync::NETWORK_DUMP_TIMEOUT_MS);
+    match sim.pop_res() {
+        Some((node, ExtOut::FeaturesEvent((), FeaturesEvent::RouterSync(router_sync::Event::DumpNetwork(token, snapshots))))) => {
+            assert_eq!(node, node1);
+            assert_eq!(token, 1000);
+            assert_eq!(snapshots.iter().map(|s| s.node_id()).collect::<Vec<_>>(), vec![node1, node2, node3]);
+        }
+        res => panic!("Unexpected result {res:?}"),
+    }
+    assert_eq!(sim.pop_res(), None);
+}
+
+fn relayed_data(sim: &NetworkSimulator<(), (), (), ()>, node: u32) -> u64 {
+    sim.data_metrics(node).features.get(&Features::Data).map(|t| t.tx.packets).unwrap_or_default()
+}
+
+#[test]
+fn feature_router_sync_pin_route_with_fallback() {
+    // node1 <-> node2 <-> node3, node1 <-> node3
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node2, ExtIn::ConnectTo(addr3.clone()));
+    sim.control(node1, ExtIn::ConnectTo(addr3));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    // direct path is used without pin
+    let relayed = relayed_data(&sim, node2);
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::Ping(node3))));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Pong(node3, Some(0)))))));
+    assert_eq!(relayed_data(&sim, node2), relayed);
+
+    let pin = router_sync::Control::PinRoute(node3, router_sync::RoutePin::NextHop(node2));
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::RouterSync(pin)));
+    sim.process(10);
+    assert_eq!(
+        sim.pop_res(),
+        Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::RouterSync(router_sync::Event::PinState(node3, true)))))
+    );
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::Ping(node3))));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Pong(node3, Some(0)))))));
+    assert!(relayed_data(&sim, node2) > relayed);
+
+    // pinned next hop is gone, then the router path is used
+    sim.leave_node(node2);
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(
+        sim.pop_res(),
+        Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::RouterSync(router_sync::Event::PinState(node3, false)))))
+    );
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::Ping(node3))));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Pong(node3, Some(0)))))));
+}
+
+#[test]
+fn feature_router_sync_resync_with_neighbour() {
+    // node1 <-> node2 <-> node3
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    // routes over node2 are dropped then learned again from its full sync, in the same round trip
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::RouterSync(router_sync::Control::Resync(node2))));
+    sim.process(10);
+    match sim.pop_res() {
+        Some((node, ExtOut::FeaturesEvent((), FeaturesEvent::RouterSync(router_sync::Event::Resynced(neighbour, Some((before, after))))))) => {
+            assert_eq!(node, node1);
+            assert_eq!(neighbour, node2);
+            assert_eq!(before, after);
+        }
+        res => panic!("Unexpected result {res:?}"),
+    }
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::Ping(node3))));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Pong(node3, Some(0)))))));
+
+    // node3 is not a neighbour of node1
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::RouterSync(router_sync::Control::Resync(node3))));
+    sim.process(10);
+    assert_eq!(
+        sim.pop_res(),
+        Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::RouterSync(router_sync::Event::Resynced(node3, None)))))
+    );
+}
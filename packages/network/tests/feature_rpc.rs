@@ -0,0 +1,82 @@
+use atm0s_sdn_network::{
+    features::{
+        rpc::{self, RpcDest, RpcError},
+        FeaturesControl, FeaturesEvent,
+    },
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+const SERVICE: u8 = 10;
+
+fn rpc_control(control: rpc::Control) -> ExtIn<(), ()> {
+    ExtIn::FeaturesControl((), FeaturesControl::Rpc(control))
+}
+
+fn rpc_event(event: rpc::Event) -> ExtOut<(), ()> {
+    ExtOut::FeaturesEvent((), FeaturesEvent::Rpc(event))
+}
+
+fn build_chain(sim: &mut NetworkSimulator<(), (), (), ()>) {
+    // node1 <-> node2 <-> node3
+    let _addr1 = sim.add_node(TestNode::new_with_seed(1, 1234, vec![], 1000));
+    let addr2 = sim.add_node(TestNode::new_with_seed(2, 1235, vec![], 2000));
+    let _addr3 = sim.add_node(TestNode::new_with_seed(3, 1236, vec![], 3000));
+
+    sim.control(1, ExtIn::ConnectTo(addr2.clone()));
+    sim.control(3, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+}
+
+#[test]
+fn feature_rpc_request_response_over_relay() {
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    build_chain(&mut sim);
+
+    sim.control(3, rpc_control(rpc::Control::Register(SERVICE)));
+    sim.control(1, rpc_control(rpc::Control::Request(100, RpcDest::Node(3, SERVICE), vec![1, 2, 3], 1000)));
+    sim.process(10);
+
+    let remote = match sim.pop_res() {
+        Some((3, ExtOut::FeaturesEvent((), FeaturesEvent::Rpc(rpc::Event::Request(SERVICE, remote, data))))) => {
+            assert_eq!(remote.node, 1);
+            assert_eq!(data, vec![1, 2, 3]);
+            remote
+        }
+        res => panic!("Should receive request, got {:?}", res),
+    };
+    assert_eq!(sim.pop_res(), None);
+
+    sim.control(3, rpc_control(rpc::Control::Response(remote, vec![4, 5])));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), Some((1, rpc_event(rpc::Event::Response(100, Ok(vec![4, 5]))))));
+    assert_eq!(sim.pop_res(), None);
+}
+
+#[test]
+fn feature_rpc_no_handler_and_timeout() {
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    build_chain(&mut sim);
+
+    sim.control(1, rpc_control(rpc::Control::Request(100, RpcDest::Node(2, SERVICE), vec![1], 1000)));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), Some((1, rpc_event(rpc::Event::Response(100, Err(RpcError::NoHandler))))));
+
+    // handler which doesn't answer
+    sim.control(3, rpc_control(rpc::Control::Register(SERVICE)));
+    sim.control(1, rpc_control(rpc::Control::Request(101, RpcDest::Node(3, SERVICE), vec![1], 1000)));
+    sim.process(10);
+    assert!(matches!(sim.pop_res(), Some((3, ExtOut::FeaturesEvent((), FeaturesEvent::Rpc(rpc::Event::Request(..)))))));
+
+    sim.process(1000);
+    sim.process(1000);
+    assert_eq!(sim.pop_res(), Some((1, rpc_event(rpc::Event::Response(101, Err(RpcError::Timeout))))));
+    assert_eq!(sim.pop_res(), None);
+}
@@ -1,4 +1,5 @@
 use atm0s_sdn_network::{
+    base::FeatureError,
     features::{socket, FeaturesControl, FeaturesEvent},
     ExtIn, ExtOut,
 };
@@ -104,3 +105,43 @@ fn feature_socket_three_nodes() {
         ))
     );
 }
+
+#[test]
+fn feature_socket_errors() {
+    let node1 = 1;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::Bind(10000))));
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::Bind(10000))));
+    sim.process(10);
+    let event = FeaturesEvent::Socket(socket::Event::Error(10000, FeatureError::Rejected));
+    assert_eq!(event.error(), Some(FeatureError::Rejected));
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::FeaturesEvent((), event))));
+
+    // send to a local port which is not bound
+    sim.control(
+        node1,
+        ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::SendTo(10000, node1, 10001, vec![1, 2, 3, 4].into(), 0))),
+    );
+    sim.process(10);
+    assert_eq!(
+        sim.pop_res(),
+        Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::Socket(socket::Event::Error(10000, FeatureError::Unreachable)))))
+    );
+
+    // send without connecting
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::Send(10000, vec![1, 2, 3, 4].into(), 0))));
+    sim.process(10);
+    assert_eq!(
+        sim.pop_res(),
+        Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::Socket(socket::Event::Error(10000, FeatureError::Rejected)))))
+    );
+    assert_eq!(sim.pop_res(), None);
+}
@@ -0,0 +1,172 @@
+#![cfg(feature = "vpn")]
+
+use std::{cell::RefCell, net::Ipv4Addr, rc::Rc};
+
+use atm0s_sdn_network::{
+    base::{Buffer, TransportMsgHeader},
+    features::{vpn, FeaturesControl, FeaturesEvent},
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+/// Nodes are dead after this time without pong, plus some ticks for router sync
+const REROUTE_BOUND_MS: u64 = 12000;
+
+/// IPv4 packet to 10.33.33.{dest}, same as packets which are read from the tun device
+fn ip_pkt(dest: u8, seq: u8) -> Buffer {
+    ip_pkt_to(Ipv4Addr::new(10, 33, 33, dest), seq)
+}
+
+fn ip_pkt_to(dest: Ipv4Addr, seq: u8) -> Buffer {
+    let [d0, d1, d2, d3] = dest.octets();
+    let mut pkt = vec![0x45, 0, 0, 24, 0, 0, 0, 0, 64, 17, 0, 0, 10, 33, 33, 1, d0, d1, d2, d3, seq, seq, seq, seq];
+    if cfg!(any(target_os = "macos", target_os = "ios")) {
+        // utun packet information header
+        pkt.splice(0..0, [0, 0, 0, 2]);
+    }
+    Buffer::from(pkt)
+}
+
+/// Nodes which relayed vpn packets
+fn vpn_relays(sim: &mut NetworkSimulator<(), (), (), ()>) -> Rc<RefCell<Vec<u32>>> {
+    let relays = Rc::new(RefCell::new(vec![]));
+    let relays_c = relays.clone();
+    sim.set_packet_filter(Box::new(move |from, _to, data| {
+        if let Ok(header) = TransportMsgHeader::try_from(data) {
+            if header.feature == vpn::FEATURE_ID && from != 1 {
+                relays_c.borrow_mut().push(from);
+            }
+        }
+        true
+    }));
+    relays
+}
+
+#[test]
+fn feature_vpn_multi_hop_failover() {
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    let relays = vpn_relays(&mut sim);
+
+    // node1 -> node2 -> node4 and node1 -> node3 -> node4, node1 and node4 are not connected
+    let _addr1 = sim.add_node(TestNode::new_with_seed(1, 1234, vec![], 1000));
+    let addr2 = sim.add_node(TestNode::new_with_seed(2, 1235, vec![], 2000));
+    let addr3 = sim.add_node(TestNode::new_with_seed(3, 1236, vec![], 3000));
+    let addr4 = sim.add_node(TestNode::new_with_seed(4, 1237, vec![], 4000));
+    sim.control(1, ExtIn::ConnectTo(addr2.clone()));
+    sim.control(1, ExtIn::ConnectTo(addr3.clone()));
+    sim.control(2, ExtIn::ConnectTo(addr4.clone()));
+    sim.control(3, ExtIn::ConnectTo(addr4));
+    for _i in 0..6 {
+        sim.process(500);
+    }
+
+    sim.tun_input(1, ip_pkt(4, 1));
+    sim.process(10);
+    let (node, pkt) = sim.pop_tun().expect("Should deliver over the mesh");
+    assert_eq!(node, 4);
+    assert_eq!(pkt.to_vec(), ip_pkt(4, 1).to_vec());
+    assert!(sim.pop_tun().is_none());
+
+    let middle = *relays.borrow().last().expect("Should be relayed");
+    assert!(middle == 2 || middle == 3, "Should relay over a middle node, got {middle}");
+
+    // kill the middle node which carries the path, packets are sent each 100ms until rerouted over the other one
+    sim.crash_node(middle);
+    relays.borrow_mut().clear();
+    let mut reroute_ms = None;
+    for seq in 2..=200u8 {
+        sim.tun_input(1, ip_pkt(4, seq));
+        sim.process(100);
+        if let Some((node, pkt)) = sim.pop_tun() {
+            assert_eq!(node, 4);
+            assert_eq!(pkt.to_vec(), ip_pkt(4, seq).to_vec());
+            reroute_ms = Some((seq as u64 - 1) * 100);
+            break;
+        }
+    }
+    let reroute_ms = reroute_ms.expect("Should reroute over the other middle node");
+    assert!(reroute_ms <= REROUTE_BOUND_MS, "Reroute took {reroute_ms} ms");
+    assert_eq!(*relays.borrow().last().expect("Should be relayed"), 5 - middle);
+
+    // packets to local node are echoed back to the tun device
+    sim.tun_input(1, ip_pkt(1, 0));
+    sim.process(10);
+    assert_eq!(sim.pop_tun().map(|(node, _)| node), Some(1));
+}
+
+#[test]
+fn feature_vpn_configured_subnets() {
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(2, 1235, vec![]));
+    sim.control(1, ExtIn::ConnectTo(addr2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    // outside of the default subnet => dropped
+    sim.tun_input(1, ip_pkt_to(Ipv4Addr::new(172, 16, 0, 2), 1));
+    sim.process(10);
+    assert!(sim.pop_tun().is_none());
+
+    let configure = vpn::Control::Configure {
+        subnet: Ipv4Addr::new(172, 16, 0, 0),
+        prefix: 16,
+        mapping: vpn::IpMapping::HostBits,
+    };
+    sim.control(1, ExtIn::FeaturesControl((), FeaturesControl::Vpn(configure)));
+    let enterprise = vpn::Control::Configure {
+        subnet: Ipv4Addr::new(192, 168, 10, 0),
+        prefix: 24,
+        mapping: vpn::IpMapping::Static(vec![(Ipv4Addr::new(192, 168, 10, 200), 2)]),
+    };
+    sim.control(1, ExtIn::FeaturesControl((), FeaturesControl::Vpn(enterprise)));
+    sim.process(10);
+    match sim.pop_res() {
+        Some((1, ExtOut::FeaturesEvent((), FeaturesEvent::Vpn(vpn::Event::Subnets(subnets))))) => assert_eq!(subnets.len(), 2),
+        res => panic!("Unexpected result {res:?}"),
+    }
+    match sim.pop_res() {
+        Some((1, ExtOut::FeaturesEvent((), FeaturesEvent::Vpn(vpn::Event::Subnets(subnets))))) => {
+            let prefixes = subnets.iter().map(|s| (s.subnet, s.prefix)).collect::<Vec<_>>();
+            assert_eq!(
+                prefixes,
+                vec![(Ipv4Addr::new(10, 33, 33, 0), 24), (Ipv4Addr::new(192, 168, 10, 0), 24), (Ipv4Addr::new(172, 16, 0, 0), 16)]
+            );
+        }
+        res => panic!("Unexpected result {res:?}"),
+    }
+
+    for dest in [Ipv4Addr::new(172, 16, 0, 2), Ipv4Addr::new(192, 168, 10, 200), Ipv4Addr::new(10, 33, 33, 2)] {
+        sim.tun_input(1, ip_pkt_to(dest, 2));
+        sim.process(10);
+        let (node, pkt) = sim.pop_tun().expect("Should deliver over the mesh");
+        assert_eq!(node, 2);
+        assert_eq!(pkt.to_vec(), ip_pkt_to(dest, 2).to_vec());
+    }
+
+    // unknown address in a static subnet
+    sim.tun_input(1, ip_pkt_to(Ipv4Addr::new(192, 168, 10, 2), 3));
+    sim.process(10);
+    assert!(sim.pop_tun().is_none());
+
+    sim.control(
+        1,
+        ExtIn::FeaturesControl(
+            (),
+            FeaturesControl::Vpn(vpn::Control::Remove {
+                subnet: Ipv4Addr::new(10, 0, 0, 0),
+                prefix: 8,
+            }),
+        ),
+    );
+    sim.process(10);
+    assert_eq!(
+        sim.pop_res(),
+        Some((1, ExtOut::FeaturesEvent((), FeaturesEvent::Vpn(vpn::Event::Rejected(Ipv4Addr::new(10, 0, 0, 0), 8)))))
+    );
+}
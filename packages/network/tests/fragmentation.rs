@@ -0,0 +1,94 @@
+use std::{cell::RefCell, rc::Rc};
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_network::{
+    base::Capabilities,
+    data_plane::fragment::{self, DEFAULT_MTU},
+    features::{socket, FeaturesControl, FeaturesEvent},
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+fn socket_control(control: socket::Control) -> ExtIn<(), ()> {
+    ExtIn::FeaturesControl((), FeaturesControl::Socket(control))
+}
+
+/// Connect two nodes and bind sockets, returning (fragments, max packet size) of packets from node1
+fn setup(sim: &mut NetworkSimulator<(), (), (), ()>, node1: TestNode<(), (), (), ()>, node2: TestNode<(), (), (), ()>) -> Rc<RefCell<(usize, usize)>> {
+    let from: NodeId = node1.node_id();
+    let packets = Rc::new(RefCell::new((0, 0)));
+    let packets_c = packets.clone();
+    sim.set_packet_filter(Box::new(move |src, _to, data| {
+        if src == from {
+            let mut packets = packets_c.borrow_mut();
+            if fragment::is_fragment(data[0]) {
+                packets.0 += 1;
+            }
+            packets.1 = packets.1.max(data.len());
+        }
+        true
+    }));
+
+    let node1_id = node1.node_id();
+    let node2_id = node2.node_id();
+    let _addr1 = sim.add_node(node1);
+    let addr2 = sim.add_node(node2);
+    sim.control(node1_id, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    sim.control(node1_id, socket_control(socket::Control::Bind(10000)));
+    sim.control(node2_id, socket_control(socket::Control::Bind(10001)));
+    sim.process(10);
+    *packets.borrow_mut() = (0, 0);
+    packets
+}
+
+#[test]
+fn fragment_large_message() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    let packets = setup(&mut sim, TestNode::new(node1, 1234, vec![]), TestNode::new(node2, 1235, vec![]));
+
+    let payload: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+    sim.control(node1, socket_control(socket::Control::SendTo(10000, node2, 10001, payload.clone().into(), 0)));
+    sim.process(10);
+    assert_eq!(
+        sim.pop_res(),
+        Some((node2, ExtOut::FeaturesEvent((), FeaturesEvent::Socket(socket::Event::RecvFrom(10001, node1, 10000, payload.into(), 0)))))
+    );
+
+    let (fragments, max_size) = *packets.borrow();
+    assert_eq!(fragments, 4);
+    assert!(max_size <= DEFAULT_MTU, "Packet size {max_size} should fit mtu");
+}
+
+#[test]
+fn no_fragment_with_legacy_neighbour() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    let legacy = Capabilities::SUPPORTED.difference(Capabilities::FRAGMENTATION);
+    let packets = setup(&mut sim, TestNode::new(node1, 1234, vec![]), TestNode::new_with_capabilities(node2, 1235, vec![], legacy));
+    assert!(matches!(sim.pop_res(), Some((1, ExtOut::CapabilitySkew(skew))) if skew.disabled == Capabilities::FRAGMENTATION));
+    assert!(matches!(sim.pop_res(), Some((2, ExtOut::CapabilitySkew(skew))) if skew.remote_only == Capabilities::FRAGMENTATION));
+
+    let payload = vec![1; 2000];
+    sim.control(node1, socket_control(socket::Control::SendTo(10000, node2, 10001, payload.clone().into(), 0)));
+    sim.process(10);
+    assert_eq!(
+        sim.pop_res(),
+        Some((node2, ExtOut::FeaturesEvent((), FeaturesEvent::Socket(socket::Event::RecvFrom(10001, node1, 10000, payload.into(), 0)))))
+    );
+
+    let (fragments, max_size) = *packets.borrow();
+    assert_eq!(fragments, 0);
+    assert!(max_size > DEFAULT_MTU);
+}
@@ -0,0 +1,79 @@
+use atm0s_sdn_network::{
+    base::{NetIncomingMeta, NetOutgoingMeta, Ttl},
+    features::{data, FeaturesControl, FeaturesEvent},
+    ExtIn, ExtOut,
+};
+use atm0s_sdn_router::RouteRule;
+
+use crate::simulator::{node_to_addr, NetworkSimulator, TestNode};
+
+mod simulator;
+
+const PORT: u16 = 1;
+
+fn send_data(sim: &mut NetworkSimulator<(), (), (), ()>, from: u32, to: u32) {
+    let meta = NetOutgoingMeta::new(true, Ttl::default(), 0, true);
+    let control = data::Control::DataSendRule(PORT, RouteRule::ToNode(to), meta, vec![1, 2, 3]);
+    sim.control(from, ExtIn::FeaturesControl((), FeaturesControl::Data(control)));
+}
+
+fn pop_recv_meta(sim: &mut NetworkSimulator<(), (), (), ()>) -> Option<(u32, NetIncomingMeta)> {
+    loop {
+        match sim.pop_res()? {
+            (node, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Recv(PORT, meta, _)))) => return Some((node, meta)),
+            _ => continue,
+        }
+    }
+}
+
+#[test]
+fn incoming_route_hops_and_relay() {
+    // node1 <-> node2 <-> node3
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new_with_incoming_route(node3, 1236, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    for node in [node1, node3] {
+        sim.control(node, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::DataListen(PORT))));
+    }
+    sim.process(10);
+
+    send_data(&mut sim, node1, node3);
+    sim.process(10);
+    let (node, meta) = pop_recv_meta(&mut sim).expect("Should receive data");
+    assert_eq!(node, node3);
+    assert_eq!(meta.source, Some(node1));
+    let route = meta.route.expect("Should have incoming route");
+    assert_eq!(route.pair.remote, node_to_addr(node2));
+    assert_eq!(route.hops, 2);
+    assert!(route.relayed);
+    assert!(!route.broadcast);
+
+    send_data(&mut sim, node2, node3);
+    sim.process(10);
+    let (_, meta) = pop_recv_meta(&mut sim).expect("Should receive data");
+    let route = meta.route.expect("Should have incoming route");
+    assert_eq!(route.hops, 1);
+    assert!(!route.relayed);
+
+    // disabled node doesn't collect incoming route
+    send_data(&mut sim, node3, node1);
+    sim.process(10);
+    let (node, meta) = pop_recv_meta(&mut sim).expect("Should receive data");
+    assert_eq!(node, node1);
+    assert_eq!(meta.source, Some(node3));
+    assert_eq!(meta.route, None);
+}
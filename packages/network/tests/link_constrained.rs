@@ -0,0 +1,73 @@
+use std::{cell::RefCell, rc::Rc};
+
+use atm0s_sdn_network::{
+    base::LinkProfile,
+    data_plane::link,
+    features::{socket, FeaturesControl, FeaturesEvent},
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+#[test]
+fn link_constrained_small_mtu() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    // (frames, max frame size) of node1 <-> node2
+    let frames = Rc::new(RefCell::new((0, 0)));
+    let frames_c = frames.clone();
+    sim.set_packet_filter(Box::new(move |_from, _to, data| {
+        if link::is_frame(data[0]) {
+            let mut frames = frames_c.borrow_mut();
+            frames.0 += 1;
+            frames.1 = frames.1.max(data.len());
+        }
+        true
+    }));
+
+    let _addr1 = sim.add_node(TestNode::new_with_link(node1, 1234, vec![], LinkProfile::constrained(64)));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::Bind(10000))));
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::Bind(10001))));
+    sim.process(10);
+
+    let payload = vec![7; 200];
+    sim.control(
+        node2,
+        ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::SendTo(10001, node1, 10000, payload.clone().into(), 0))),
+    );
+    sim.process(10);
+    assert_eq!(
+        sim.pop_res(),
+        Some((
+            node1,
+            ExtOut::FeaturesEvent((), FeaturesEvent::Socket(socket::Event::RecvFrom(10000, node2, 10001, payload.clone().into(), 0)))
+        ))
+    );
+
+    sim.control(
+        node1,
+        ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::SendTo(10000, node2, 10001, payload.clone().into(), 0))),
+    );
+    sim.process(10);
+    assert_eq!(
+        sim.pop_res(),
+        Some((node2, ExtOut::FeaturesEvent((), FeaturesEvent::Socket(socket::Event::RecvFrom(10001, node1, 10000, payload.into(), 0)))))
+    );
+
+    let (count, max_size) = *frames.borrow();
+    assert!(count > 0, "Should send over link frames");
+    assert!(max_size <= 64, "Frame size {max_size} should fit mtu");
+}
@@ -0,0 +1,138 @@
+//! Long-running scenario for catching slow leaks in relays and dht_kv.
+//!
+//! A mini-mesh runs for simulated hours with churn of pubsub subscriptions and kv entries. Allocations are counted
+//! by a global allocator, and live bytes must return to the baseline after the churn is stopped.
+//! This binary contains only one test, so allocations of other tests don't affect the counters.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicIsize, AtomicU64, Ordering},
+};
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_network::{
+    features::{
+        dht_kv::{self, Key, Map, MapControl},
+        pubsub::{self, ChannelControl, ChannelId},
+        FeaturesControl,
+    },
+    ExtIn,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+struct CountingAlloc;
+
+static LIVE_BYTES: AtomicIsize = AtomicIsize::new(0);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LIVE_BYTES.fetch_add(layout.size() as isize, Ordering::Relaxed);
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE_BYTES.fetch_sub(layout.size() as isize, Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        LIVE_BYTES.fetch_add(new_size as isize - layout.size() as isize, Ordering::Relaxed);
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Same as tick interval of the default latency profile
+const STEP_MS: u64 = 1000;
+const CYCLE_MS: u64 = 10_000;
+const CHANNELS: u64 = 16;
+/// Live bytes can grow a bit because of hashmap capacity and log sampling, but not with time
+const TOLERANCE_BYTES: isize = 64 * 1024;
+
+const PUBLISHER: NodeId = 1;
+const KV_OWNER: NodeId = 2;
+const SUBSCRIBER: NodeId = 3;
+
+fn pubsub(channel: u64, control: ChannelControl) -> ExtIn<(), ()> {
+    ExtIn::FeaturesControl((), FeaturesControl::PubSub(pubsub::Control(ChannelId(channel), control)))
+}
+
+fn kv(map: u64, control: MapControl) -> ExtIn<(), ()> {
+    ExtIn::FeaturesControl((), FeaturesControl::DhtKv(dht_kv::Control::MapCmd(Map(map), control)))
+}
+
+fn run(sim: &mut NetworkSimulator<(), (), (), ()>, duration_ms: u64) {
+    for _ in 0..duration_ms / STEP_MS {
+        sim.process(STEP_MS);
+        while sim.pop_res().is_some() {}
+        while sim.pop_res_worker().is_some() {}
+    }
+}
+
+/// One churn cycle: start publishing and subscribing a channel, set a kv entry, then tear everything down
+fn churn_cycle(sim: &mut NetworkSimulator<(), (), (), ()>, cycle: u64) {
+    let channel = cycle % CHANNELS;
+    let map = 1000 + cycle % CHANNELS;
+    sim.control(PUBLISHER, pubsub(channel, ChannelControl::PubStart));
+    sim.control(SUBSCRIBER, pubsub(channel, ChannelControl::SubAuto));
+    sim.control(SUBSCRIBER, kv(map, MapControl::Sub));
+    sim.control(KV_OWNER, kv(map, MapControl::Set(Key(cycle), vec![0; 100])));
+    run(sim, CYCLE_MS / 2);
+
+    sim.control(PUBLISHER, pubsub(channel, ChannelControl::PubData(vec![0; 100])));
+    sim.control(SUBSCRIBER, pubsub(channel, ChannelControl::UnsubAuto));
+    sim.control(PUBLISHER, pubsub(channel, ChannelControl::PubStop));
+    sim.control(KV_OWNER, kv(map, MapControl::Del(Key(cycle))));
+    sim.control(SUBSCRIBER, kv(map, MapControl::Unsub));
+    run(sim, CYCLE_MS / 2);
+}
+
+fn churn(sim: &mut NetworkSimulator<(), (), (), ()>, started_cycle: u64, duration_ms: u64) -> u64 {
+    let cycles = duration_ms / CYCLE_MS;
+    for cycle in started_cycle..started_cycle + cycles {
+        churn_cycle(sim, cycle);
+    }
+    // wait for relays, subs and kv slots to be timed out
+    run(sim, 60_000);
+    started_cycle + cycles
+}
+
+#[test]
+fn long_running_churn_returns_to_baseline() {
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    let _addr1 = sim.add_node(TestNode::new(PUBLISHER, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(KV_OWNER, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(SUBSCRIBER, 1236, vec![]));
+
+    sim.control(PUBLISHER, ExtIn::ConnectTo(addr2));
+    sim.control(KV_OWNER, ExtIn::ConnectTo(addr3));
+    run(&mut sim, 5_000);
+
+    // warm-up with all channels and maps, so that containers reach their steady capacity
+    let cycle = churn(&mut sim, 0, 30 * 60 * 1000);
+    let baseline = LIVE_BYTES.load(Ordering::Relaxed);
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+
+    churn(&mut sim, cycle, 2 * 60 * 60 * 1000);
+    let live = LIVE_BYTES.load(Ordering::Relaxed);
+    let allocated = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    println!("baseline {baseline} bytes, after 2 hours {live} bytes, {allocated} allocations");
+
+    assert!(allocated > 0, "Should run churn");
+    assert!(live - baseline < TOLERANCE_BYTES, "Live bytes grew from {baseline} to {live} after 2 simulated hours");
+
+    for node in [PUBLISHER, KV_OWNER, SUBSCRIBER] {
+        let metrics = sim.controller_metrics(node);
+        assert_eq!(metrics.pubsub_remote_relays, 0, "node {node} should not keep relays");
+        assert_eq!(metrics.dht_kv_maps, 0, "node {node} should not keep kv maps");
+        assert_eq!(metrics.alias_parked_msgs, 0, "node {node} should not keep alias messages");
+    }
+}
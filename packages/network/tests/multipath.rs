@@ -0,0 +1,103 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use atm0s_sdn_network::{
+    base::NetOutgoingMeta,
+    data_plane::multipath::MultipathPolicy,
+    features::{data, neighbours, FeaturesControl, FeaturesEvent},
+    ExtIn, ExtOut,
+};
+use atm0s_sdn_router::RouteRule;
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+const IP1: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
+const IP2: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+
+fn addr(ip: Ipv4Addr, node: u32) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(ip), node as u16)
+}
+
+fn send_data(sim: &mut NetworkSimulator<(), (), (), ()>, from: u32, to: u32, data: Vec<u8>) {
+    let control = data::Control::DataSendRule(1, RouteRule::ToNode(to), NetOutgoingMeta::default(), data);
+    sim.control(from, ExtIn::FeaturesControl((), FeaturesControl::Data(control)));
+}
+
+fn pop_recv(sim: &mut NetworkSimulator<(), (), (), ()>) -> Option<(u32, Vec<u8>)> {
+    loop {
+        match sim.pop_res()? {
+            (node, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Recv(1, _, data)))) => return Some((node, data)),
+            _ => continue,
+        }
+    }
+}
+
+#[test]
+fn multipath_neighbours_paths() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new_multipath(node1, 1234, vec![], &[IP1, IP2], Some(MultipathPolicy::LowestRtt)));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Paths(node2))));
+    sim.process(10);
+    match sim.pop_res() {
+        Some((node, ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Paths(dest, paths))))) => {
+            assert_eq!(node, node1);
+            assert_eq!(dest, node2);
+            let mut locals: Vec<_> = paths.iter().map(|path| path.pair.local).collect();
+            locals.sort();
+            assert_eq!(locals, vec![addr(IP1, node1), addr(IP2, node1)]);
+            assert!(paths.iter().all(|path| path.rtt_ms == Some(0)));
+        }
+        res => panic!("Unexpected result {res:?}"),
+    }
+}
+
+#[test]
+fn multipath_failover_before_connection_timeout() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new_multipath(node1, 1234, vec![], &[IP1, IP2], Some(MultipathPolicy::Failover)));
+    let addr2 = sim.add_node(TestNode::new_multipath(node2, 1235, vec![], &[IP1], Some(MultipathPolicy::Failover)));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    for node in [node1, node2] {
+        sim.control(node, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::DataListen(1))));
+    }
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    // break each path in turn, traffic is moved to the other one after a few missed pings and long before the connection timeout
+    for (index, ip) in [IP1, IP2].into_iter().enumerate() {
+        sim.set_path_blocked(addr(ip, node1), addr(IP1, node2), true);
+        for _i in 0..4 {
+            sim.process(1000);
+        }
+        send_data(&mut sim, node1, node2, vec![index as u8]);
+        send_data(&mut sim, node2, node1, vec![index as u8]);
+        sim.process(10);
+        let mut received = vec![pop_recv(&mut sim), pop_recv(&mut sim)];
+        received.sort();
+        assert_eq!(received, vec![Some((node1, vec![index as u8])), Some((node2, vec![index as u8]))]);
+        sim.set_path_blocked(addr(ip, node1), addr(IP1, node2), false);
+        for _i in 0..2 {
+            sim.process(500);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use atm0s_sdn_network::{base::InterfaceEvent, ExtOut};
+
+use crate::simulator::{node_to_addr, NetworkSimulator, TestNode};
+
+mod simulator;
+
+#[test]
+fn network_interface_events() {
+    let node1 = 1;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr = node_to_addr(node1);
+
+    sim.interface_event(node1, InterfaceEvent::Up(addr));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::InterfaceEvent(InterfaceEvent::Up(addr)))));
+
+    // same state from other workers should not be reported again
+    sim.interface_event(node1, InterfaceEvent::Up(addr));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), None);
+
+    sim.interface_event(node1, InterfaceEvent::Down(addr));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::InterfaceEvent(InterfaceEvent::Down(addr)))));
+
+    sim.interface_event(node1, InterfaceEvent::Up(addr));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::InterfaceEvent(InterfaceEvent::Up(addr)))));
+    assert_eq!(sim.pop_res(), None);
+}
+
+#[test]
+fn network_interface_is_down_when_all_workers_are_down() {
+    let node1 = 1;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr = node_to_addr(node1);
+
+    sim.interface_event(node1, InterfaceEvent::Up(addr));
+    sim.worker_interface_event(node1, 1, InterfaceEvent::Up(addr));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::InterfaceEvent(InterfaceEvent::Up(addr)))));
+    assert_eq!(sim.pop_res(), None);
+
+    // worker 1 lost its socket and keeps retrying the bind, the address is still up in worker 0
+    for _ in 0..3 {
+        sim.worker_interface_event(node1, 1, InterfaceEvent::Down(addr));
+        sim.process(100);
+        assert_eq!(sim.pop_res(), None);
+    }
+
+    sim.interface_event(node1, InterfaceEvent::Down(addr));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::InterfaceEvent(InterfaceEvent::Down(addr)))));
+
+    sim.worker_interface_event(node1, 1, InterfaceEvent::Up(addr));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::InterfaceEvent(InterfaceEvent::Up(addr)))));
+    sim.interface_event(node1, InterfaceEvent::Up(addr));
+    sim.process(100);
+    assert_eq!(sim.pop_res(), None);
+}
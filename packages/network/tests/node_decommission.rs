@@ -0,0 +1,115 @@
+use atm0s_sdn_network::{
+    features::{
+        pubsub::{ChannelControl, ChannelEvent, ChannelId, Control, Event},
+        FeaturesControl, FeaturesEvent,
+    },
+    DecommissionEvent, ExtIn, ExtOut,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+fn control(control: Control) -> ExtIn<(), ()> {
+    ExtIn::FeaturesControl((), FeaturesControl::PubSub(control))
+}
+
+fn event(event: Event) -> ExtOut<(), ()> {
+    ExtOut::FeaturesEvent((), FeaturesEvent::PubSub(event))
+}
+
+/// Process until the node finished decommission, returning all of its decommission events
+fn wait_decommission(sim: &mut NetworkSimulator<(), (), (), ()>, node: u32) -> Vec<DecommissionEvent> {
+    let mut events = vec![];
+    for _ in 0..100 {
+        sim.process(100);
+        while let Some((from, out)) = sim.pop_res() {
+            match out {
+                ExtOut::DecommissionEvent(event) if from == node => events.push(event),
+                _ => {}
+            }
+        }
+        if events.last() == Some(&DecommissionEvent::Finished) {
+            break;
+        }
+    }
+    events
+}
+
+#[test]
+fn node_decommission_single_node() {
+    let node1 = 1;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    sim.add_node(TestNode::new(node1, 1234, vec![]));
+    sim.process(100);
+
+    sim.control(node1, ExtIn::Decommission);
+    assert_eq!(
+        wait_decommission(&mut sim, node1),
+        vec![
+            DecommissionEvent::Started,
+            DecommissionEvent::Draining { relays: 0, maps: 0 },
+            DecommissionEvent::Leaving,
+            DecommissionEvent::Disconnecting { connections: 0 },
+            DecommissionEvent::Finished,
+        ]
+    );
+
+    // a second command is ignored
+    sim.control(node1, ExtIn::Decommission);
+    sim.process(100);
+    assert_eq!(sim.pop_res(), None);
+}
+
+#[test]
+fn node_decommission_handover_pubsub_relay() {
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let node4 = 4;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+    let addr4 = sim.add_node(TestNode::new(node4, 1237, vec![]));
+
+    // only path 1 -> 2 -> 3 exists when subscribing, so node2 must be the relay
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node2, ExtIn::ConnectTo(addr3.clone()));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let channel = ChannelId(1000);
+    let value = vec![1, 2, 3, 4];
+
+    sim.control(node1, control(Control(channel, ChannelControl::SubSource(node3))));
+    sim.process(1);
+    sim.control(node3, control(Control(channel, ChannelControl::PubData(value.clone()))));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event(channel, ChannelEvent::SourceData(node3, value.clone()))))));
+    assert_eq!(sim.pop_res(), None);
+
+    // then alternative path 1 -> 4 -> 3 is added
+    sim.control(node1, ExtIn::ConnectTo(addr4));
+    sim.control(node4, ExtIn::ConnectTo(addr3));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    while sim.pop_res().is_some() {}
+
+    sim.control(node2, ExtIn::Decommission);
+    let events = wait_decommission(&mut sim, node2);
+    assert_eq!(events.first(), Some(&DecommissionEvent::Started));
+    assert_eq!(events.get(1), Some(&DecommissionEvent::Draining { relays: 1, maps: 0 }));
+    assert_eq!(events.get(2), Some(&DecommissionEvent::Draining { relays: 0, maps: 0 }));
+    assert_eq!(events.get(3), Some(&DecommissionEvent::Leaving));
+    assert_eq!(events.last(), Some(&DecommissionEvent::Finished));
+
+    // data is still delivered over the new path
+    sim.control(node3, control(Control(channel, ChannelControl::PubData(value.clone()))));
+    sim.process(1);
+    assert_eq!(sim.pop_res(), Some((node1, event(Event(channel, ChannelEvent::SourceData(node3, value))))));
+    assert_eq!(sim.pop_res(), None);
+}
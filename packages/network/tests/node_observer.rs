@@ -0,0 +1,96 @@
+use atm0s_sdn_network::{
+    base::NetOutgoingMeta,
+    features::{data, FeaturesControl, FeaturesEvent},
+    ExtIn, ExtOut,
+};
+use atm0s_sdn_router::RouteRule;
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+fn data_control(control: data::Control) -> ExtIn<(), ()> {
+    ExtIn::FeaturesControl((), FeaturesControl::Data(control))
+}
+
+fn pong(dest: u32, rtt: Option<u16>) -> ExtOut<(), ()> {
+    ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Pong(dest, rtt)))
+}
+
+#[test]
+fn node_observer_is_not_next_hop() {
+    // node1 <-> observer2 <-> node3
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new_observer(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    // observer can reach both sides, and it is reachable from them
+    sim.control(node2, data_control(data::Control::Ping(node1)));
+    sim.control(node2, data_control(data::Control::Ping(node3)));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), Some((node2, pong(node1, Some(0)))));
+    assert_eq!(sim.pop_res(), Some((node2, pong(node3, Some(0)))));
+    sim.control(node1, data_control(data::Control::Ping(node2)));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), Some((node1, pong(node2, Some(0)))));
+
+    // but traffic between node1 and node3 is never relayed over the observer
+    sim.control(node1, data_control(data::Control::Ping(node3)));
+    for _i in 0..3 {
+        sim.process(1000);
+    }
+    assert_eq!(sim.pop_res(), Some((node1, pong(node3, None))));
+}
+
+#[test]
+fn node_observer_is_not_key_destination() {
+    // full mesh of node1, observer2 and node3
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new_observer(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node1, ExtIn::ConnectTo(addr3.clone()));
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    for node in [node1, node2, node3] {
+        sim.control(node, data_control(data::Control::DataListen(1)));
+    }
+
+    // key 2 is closest to observer2, so it is served by node3 which is the next closest node, from both node1 and the observer itself
+    for node in [node1, node2] {
+        sim.control(node, data_control(data::Control::DataSendRule(1, RouteRule::ToKey(2), NetOutgoingMeta::default(), vec![node as u8])));
+        sim.process(10);
+        match sim.pop_res() {
+            Some((dest, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Recv(1, _, data))))) => {
+                assert_eq!(dest, node3);
+                assert_eq!(data, vec![node as u8]);
+            }
+            res => panic!("Unexpected result {res:?}"),
+        }
+        assert_eq!(sim.pop_res(), None);
+    }
+}
@@ -0,0 +1,168 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use atm0s_sdn_network::{
+    base::{
+        ConnectionEvent, Service, ServiceBuilder, ServiceCtx, ServiceDependency, ServiceInput, ServiceOutput, ServiceSharedInput, ServiceWorker, ServiceWorkerCtx, ServiceWorkerInput,
+        ServiceWorkerOutput,
+    },
+    features::{Features, FeaturesControl, FeaturesEvent},
+    ExtIn, ExtOut,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+/// (connections which are seen by the service, echoed control)
+type Echo = (usize, u32);
+
+/// Echo each control with number of connections which are seen by the service
+struct EchoService {
+    id: u8,
+    connections: usize,
+    outputs: VecDeque<ServiceOutput<(), FeaturesControl, Echo, ()>>,
+    shutdown: bool,
+}
+
+impl Service<(), FeaturesControl, FeaturesEvent, u32, Echo, (), ()> for EchoService {
+    fn is_service_empty(&self) -> bool {
+        self.shutdown && self.outputs.is_empty()
+    }
+
+    fn service_id(&self) -> u8 {
+        self.id
+    }
+
+    fn service_name(&self) -> &str {
+        "echo"
+    }
+
+    fn on_input(&mut self, _ctx: &ServiceCtx, _now: u64, input: ServiceInput<(), FeaturesEvent, u32, ()>) {
+        if let ServiceInput::Control(actor, value) = input {
+            self.outputs.push_back(ServiceOutput::Event(actor, (self.connections, value)));
+        }
+    }
+
+    fn on_shared_input<'a>(&mut self, _ctx: &ServiceCtx, _now: u64, input: ServiceSharedInput) {
+        match input {
+            ServiceSharedInput::Connection(ConnectionEvent::Connected(..)) => self.connections += 1,
+            ServiceSharedInput::Connection(ConnectionEvent::Disconnected(..)) => self.connections -= 1,
+            _ => {}
+        }
+    }
+
+    fn on_shutdown(&mut self, _ctx: &ServiceCtx, _now: u64) {
+        self.shutdown = true;
+    }
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceOutput<(), FeaturesControl, Echo, ()>> {
+        self.outputs.pop_front()
+    }
+}
+
+struct EchoServiceWorker {
+    id: u8,
+    shutdown: bool,
+}
+
+impl ServiceWorker<(), FeaturesControl, FeaturesEvent, u32, Echo, (), ()> for EchoServiceWorker {
+    fn is_service_empty(&self) -> bool {
+        self.shutdown
+    }
+
+    fn service_id(&self) -> u8 {
+        self.id
+    }
+
+    fn service_name(&self) -> &str {
+        "echo"
+    }
+
+    fn on_tick(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, _tick_count: u64) {}
+
+    fn on_input(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, _input: ServiceWorkerInput<(), FeaturesEvent, u32, ()>) {}
+
+    fn on_shutdown(&mut self, _ctx: &ServiceWorkerCtx, _now: u64) {
+        self.shutdown = true;
+    }
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceWorkerOutput<(), FeaturesControl, FeaturesEvent, u32, Echo, ()>> {
+        None
+    }
+}
+
+struct EchoServiceBuilder {
+    id: u8,
+    dependencies: Vec<ServiceDependency>,
+}
+
+impl ServiceBuilder<(), FeaturesControl, FeaturesEvent, u32, Echo, (), ()> for EchoServiceBuilder {
+    fn service_id(&self) -> u8 {
+        self.id
+    }
+
+    fn service_name(&self) -> &str {
+        "echo"
+    }
+
+    fn dependencies(&self) -> Vec<ServiceDependency> {
+        self.dependencies.clone()
+    }
+
+    fn create(&self) -> Box<dyn Service<(), FeaturesControl, FeaturesEvent, u32, Echo, (), ()>> {
+        Box::new(EchoService {
+            id: self.id,
+            connections: 0,
+            outputs: VecDeque::new(),
+            shutdown: false,
+        })
+    }
+
+    fn create_worker(&self) -> Box<dyn ServiceWorker<(), FeaturesControl, FeaturesEvent, u32, Echo, (), ()>> {
+        Box::new(EchoServiceWorker { id: self.id, shutdown: false })
+    }
+}
+
+#[test]
+fn service_starts_after_dependencies_are_ready() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<u32, Echo, (), ()>::new(0);
+
+    let router = Arc::new(EchoServiceBuilder {
+        id: 1,
+        dependencies: vec![ServiceDependency::Feature(Features::RouterSync)],
+    });
+    let chained = Arc::new(EchoServiceBuilder {
+        id: 2,
+        dependencies: vec![ServiceDependency::Service(1)],
+    });
+    let free = Arc::new(EchoServiceBuilder { id: 3, dependencies: vec![] });
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![router, chained, free]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    // service without dependencies works immediately, control to the waiting service is queued
+    sim.control(node1, ExtIn::ServicesControl(3.into(), (), 30));
+    sim.control(node1, ExtIn::ServicesControl(2.into(), (), 20));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::ServicesEvent(3.into(), (), (0, 30)))));
+    // a single node never converges
+    assert_eq!(sim.pop_res(), None);
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    for _i in 0..8 {
+        sim.process(500);
+    }
+
+    // the chain is started in order, then the queued control is handled with the replayed connection
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::ServiceReady(1.into()))));
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::ServiceReady(2.into()))));
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::ServicesEvent(2.into(), (), (1, 20)))));
+    assert_eq!(sim.pop_res(), None);
+
+    sim.control(node1, ExtIn::ServicesControl(1.into(), (), 10));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::ServicesEvent(1.into(), (), (1, 10)))));
+}
@@ -0,0 +1,71 @@
+use std::sync::{Arc, Mutex};
+
+use atm0s_sdn_identity::{ConnId, NodeId};
+use atm0s_sdn_network::{
+    services::legacy::{LegacyAgent, LegacyBehavior, LegacyDest, LegacyServiceBuilder},
+    ExtIn,
+};
+
+use crate::simulator::{NetworkSimulator, TestNode};
+
+mod simulator;
+
+/// Messages which are received by the behaviour of each node: (receiver, sender, message)
+type Received = Arc<Mutex<Vec<(NodeId, NodeId, Vec<u8>)>>>;
+
+/// Behaviour in the old style: ping each connected neighbour and answer pings with pongs
+struct PingBehavior {
+    received: Received,
+}
+
+impl LegacyBehavior for PingBehavior {
+    fn service_id(&self) -> u8 {
+        100
+    }
+
+    fn service_name(&self) -> &str {
+        "legacy_ping"
+    }
+
+    fn on_tick(&mut self, _agent: &mut LegacyAgent, _ts_ms: u64, _interval_ms: u64) {}
+
+    fn on_connection_connected(&mut self, agent: &mut LegacyAgent, node: NodeId, _conn: ConnId) {
+        agent.send_to(LegacyDest::Node(node), b"ping".to_vec());
+    }
+
+    fn on_msg(&mut self, agent: &mut LegacyAgent, from: NodeId, msg: Vec<u8>) {
+        if msg == b"ping" {
+            agent.send_to(LegacyDest::Node(from), b"pong".to_vec());
+        }
+        self.received.lock().expect("Should lock").push((agent.local_node_id(), from, msg));
+    }
+}
+
+#[test]
+fn legacy_behavior_exchanges_messages() {
+    let node1 = 1;
+    let node2 = 2;
+    let received: Received = Default::default();
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let builder = |received: Received| Arc::new(LegacyServiceBuilder::new(move || PingBehavior { received: received.clone() }));
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![builder(received.clone())]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![builder(received.clone())]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let mut received = received.lock().expect("Should lock").clone();
+    received.sort();
+    assert_eq!(
+        received,
+        vec![
+            (node1, node2, b"ping".to_vec()),
+            (node1, node2, b"pong".to_vec()),
+            (node2, node1, b"ping".to_vec()),
+            (node2, node1, b"pong".to_vec()),
+        ]
+    );
+}
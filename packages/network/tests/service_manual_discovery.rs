@@ -7,7 +7,7 @@ use atm0s_sdn_network::{
     ExtIn, ExtOut,
 };
 
-use crate::simulator::{build_addr, NetworkSimulator, TestNode};
+use crate::simulator::{build_addr, node_to_addr, NetworkSimulator, TestNode};
 
 mod simulator;
 
@@ -44,14 +44,14 @@ fn service_manual_discovery_three_nodes() {
         sim.pop_res(),
         Some((
             node1,
-            ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(node2, ConnId::from_out(0, 1000))))
+            ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(node2, ConnId::from_out(0, 1000), node_to_addr(node2))))
         ))
     );
     assert_eq!(
         sim.pop_res(),
         Some((
             node1,
-            ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(node3, ConnId::from_out(0, 1005))))
+            ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(node3, ConnId::from_out(0, 1005), node_to_addr(node3))))
         ))
     );
 }
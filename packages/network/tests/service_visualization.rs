@@ -29,12 +29,33 @@ fn node_changed(node: NodeId, info: NodeInfo, remotes: &[(NodeId, ConnId)]) -> E
                     local: node_to_addr(node),
                     remote: node_to_addr(*n),
                     rtt_ms: 0,
+                    rx_packets: 0,
+                    rx_bytes: 0,
+                    tx_packets: 0,
+                    tx_bytes: 0,
                 })
                 .collect(),
         ),
     )
 }
 
+/// Traffic counters depend on sync messages, so they are checked then cleared before comparing
+fn without_traffic(res: Option<(NodeId, ExtOut<(), Event<NodeInfo>>)>) -> Option<(NodeId, ExtOut<(), Event<NodeInfo>>)> {
+    res.map(|(node, mut out)| {
+        if let ExtOut::ServicesEvent(_, _, Event::NodeChanged(_, _, conns)) = &mut out {
+            for conn in conns.iter_mut() {
+                assert!(conn.rx_packets > 0 && conn.tx_packets > 0, "connection should have traffic {:?}", conn);
+                assert!(conn.rx_bytes > 0 && conn.tx_bytes > 0, "connection should have traffic {:?}", conn);
+                conn.rx_packets = 0;
+                conn.rx_bytes = 0;
+                conn.tx_packets = 0;
+                conn.tx_bytes = 0;
+            }
+        }
+        (node, out)
+    })
+}
+
 #[test]
 fn service_visualization_simple() {
     let node1 = 1;
@@ -69,9 +90,9 @@ fn service_visualization_simple() {
         sim.process(1000);
     }
 
-    assert_eq!(sim.pop_res(), Some((node1, node_changed(node1, node1_info, &[(node2, ConnId::from_out(0, 1000))]))));
+    assert_eq!(without_traffic(sim.pop_res()), Some((node1, node_changed(node1, node1_info, &[(node2, ConnId::from_out(0, 1000))]))));
 
-    assert_eq!(sim.pop_res(), Some((node1, node_changed(node2, node2_info, &[(node1, ConnId::from_in(0, 1000))]))));
+    assert_eq!(without_traffic(sim.pop_res()), Some((node1, node_changed(node2, node2_info, &[(node1, ConnId::from_in(0, 1000))]))));
 }
 
 /// 3 nodes: Master <--> Master <--> Agent
@@ -128,7 +149,7 @@ fn service_visualization_multi_collectors() {
     let mut node1_events = vec![];
     let mut node2_events = vec![];
 
-    while let Some((node, e)) = sim.pop_res() {
+    while let Some((node, e)) = without_traffic(sim.pop_res()) {
         if node == node1 {
             node1_events.push(e);
         } else if node == node2 {
@@ -166,3 +187,46 @@ fn service_visualization_multi_collectors() {
         ]
     );
 }
+
+#[test]
+fn service_visualization_subscribe_stats() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<Control<NodeInfo>, Event<NodeInfo>, (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![Arc::new(VisualizationServiceBuilder::new(NodeInfo(1), true))]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![Arc::new(VisualizationServiceBuilder::new(NodeInfo(2), false).with_route_table(true))]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node1, ExtIn::ServicesControl(visualization::SERVICE_ID.into(), (), Control::SubscribeStats(1000)));
+
+    // node2 requests route dump at the first snapshot and attaches it to the next snapshot
+    for _i in 0..12 {
+        sim.process(1000);
+    }
+
+    let mut last_stats = None;
+    while let Some((node, out)) = sim.pop_res() {
+        if let ExtOut::ServicesEvent(_, (), Event::Stats(stats)) = out {
+            assert_eq!(node, node1);
+            last_stats = Some(stats);
+        }
+    }
+    let stats = last_stats.expect("Should receive stats");
+    assert_eq!(stats.iter().map(|s| s.node).collect::<Vec<_>>(), vec![node1, node2]);
+    assert_eq!(stats[0].routes, None);
+    assert_eq!(stats[1].routes.as_ref().map(|r| r.node_id()), Some(node2));
+    let conn = &stats[1].conns[0];
+    assert_eq!(conn.dest, node1);
+    assert!(conn.rx_bytes > 0 && conn.tx_bytes > 0);
+
+    sim.control(node1, ExtIn::ServicesControl(visualization::SERVICE_ID.into(), (), Control::UnsubscribeStats));
+    sim.process(1);
+    while sim.pop_res().is_some() {}
+    for _i in 0..3 {
+        sim.process(1000);
+    }
+    while let Some((_, out)) = sim.pop_res() {
+        assert!(!matches!(out, ExtOut::ServicesEvent(_, (), Event::Stats(_))));
+    }
+}
@@ -0,0 +1,88 @@
+use atm0s_sdn_network::{
+    features::{diag, FeaturesControl, FeaturesEvent},
+    simulation::{LinkModel, Simulation},
+    ExtIn, ExtOut,
+};
+
+type Sim = Simulation<(), (), (), ()>;
+
+/// Build a chain 1 - 2 - 3, so paths from node1 to node3 are relayed by node2
+fn build_chain(seed: u64, link: LinkModel) -> Sim {
+    let mut sim = Sim::new(seed);
+    sim.set_default_link(link);
+    let _addr1 = sim.add_node(Sim::node_cfg(1, 1234, vec![]));
+    let addr2 = sim.add_node(Sim::node_cfg(2, 1235, vec![]));
+    let _addr3 = sim.add_node(Sim::node_cfg(3, 1236, vec![]));
+
+    sim.control(1, ExtIn::ConnectTo(addr2.clone()));
+    sim.control(3, ExtIn::ConnectTo(addr2));
+    sim.advance(5000);
+    while sim.pop_output().is_some() {}
+    sim
+}
+
+fn ping(sim: &mut Sim, dest: u32) -> Option<diag::Event> {
+    sim.control(1, ExtIn::FeaturesControl((), FeaturesControl::Diag(diag::Control::Ping(dest))));
+    for _ in 0..50 {
+        sim.advance(100);
+        while let Some((node, out)) = sim.pop_output() {
+            if let (1, ExtOut::FeaturesEvent((), FeaturesEvent::Diag(event))) = (node, out) {
+                return Some(event);
+            }
+        }
+    }
+    None
+}
+
+#[test]
+fn simulation_latency_per_hop() {
+    let mut sim = build_chain(0, LinkModel::new(20));
+
+    let event = ping(&mut sim, 3).expect("Should have ping result");
+    assert_eq!(event.error(), None);
+    let diag::Event::Ping(3, hops) = event else {
+        panic!("Unexpected event {event:?}");
+    };
+    let rtts = hops.iter().map(|hop| hop.expect("Should reach hop").rtt_ms).collect::<Vec<_>>();
+    assert_eq!(rtts, vec![40, 80]);
+}
+
+#[test]
+fn simulation_partition_and_heal() {
+    let mut sim = build_chain(0, LinkModel::new(20));
+
+    sim.partition(&[&[1], &[2, 3]]);
+    let event = ping(&mut sim, 3).expect("Should have ping result");
+    assert_eq!(event, diag::Event::Ping(3, vec![]));
+    assert!(sim.stats().partitioned > 0);
+
+    sim.heal();
+    let event = ping(&mut sim, 3).expect("Should have ping result");
+    assert_eq!(event.error(), None);
+}
+
+#[test]
+fn simulation_repeats_results() {
+    let run = |seed: u64| {
+        let mut sim = build_chain(seed, LinkModel::new(20).with_jitter(5).with_loss(0.05));
+        let event = ping(&mut sim, 3);
+        (event, sim.now_ms())
+    };
+
+    let first = run(7);
+    assert!(first.0.as_ref().map_or(false, |event| event.error().is_none()));
+    assert_eq!(run(7), first);
+}
+
+#[test]
+fn simulation_leave_node() {
+    let mut sim = build_chain(0, LinkModel::new(20));
+
+    sim.leave_node(3);
+    assert!(sim.has_node(3));
+    sim.advance(5000);
+    assert!(!sim.has_node(3));
+
+    let event = ping(&mut sim, 2).expect("Should have ping result");
+    assert_eq!(event.error(), None);
+}
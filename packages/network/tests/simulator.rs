@@ -3,20 +3,20 @@
 //! We will create a node with a controller and single worker, which is enough for testing
 //!
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::net::{Ipv4Addr, SocketAddr};
 use std::sync::Arc;
 use std::{collections::VecDeque, net::IpAddr};
 
 use atm0s_sdn_identity::{NodeAddr, NodeAddrBuilder, NodeId, Protocol};
-use atm0s_sdn_network::base::ServiceBuilder;
-use atm0s_sdn_network::controller_plane::ControllerPlaneCfg;
-use atm0s_sdn_network::data_plane::{DataPlaneCfg, NetPair};
-use atm0s_sdn_network::features::{FeaturesControl, FeaturesEvent};
+use atm0s_sdn_network::base::{Authorization, Capabilities, CompressionConfig, InterfaceEvent, LatencyProfile, LinkProfile, ServiceBuilder};
+use atm0s_sdn_network::controller_plane::{ControllerMetrics, ControllerPlaneCfg};
+use atm0s_sdn_network::data_plane::{multipath::MultipathPolicy, scheduler::SchedulerConfig, DataPlaneCfg, DataPlaneMetrics, NetPair};
+use atm0s_sdn_network::features::{dht_kv::KvStorageBackend, FeaturesControl, FeaturesEvent};
 use atm0s_sdn_network::secure::{HandshakeBuilderXDA, StaticKeyAuthorization};
-use atm0s_sdn_network::worker::{SdnWorker, SdnWorkerCfg, SdnWorkerInput, SdnWorkerOutput};
-use atm0s_sdn_network::{base::Buffer, data_plane, ExtIn, ExtOut};
+use atm0s_sdn_network::worker::{SdnWorker, SdnWorkerBusEvent, SdnWorkerCfg, SdnWorkerInput, SdnWorkerOutput};
+use atm0s_sdn_network::{base::Buffer, data_plane, ExtIn, ExtOut, LogicControl};
 use atm0s_sdn_router::shadow::ShadowRouterHistory;
 use log::{LevelFilter, Metadata, Record};
 use parking_lot::Mutex;
@@ -75,6 +75,9 @@ pub enum TestNodeIn<SC> {
     Ext(ExtIn<(), SC>),
     ExtWorker(ExtIn<(), SC>),
     Udp(NetPair, Buffer),
+    /// Interface event which is detected by a worker, events of other workers are reported to the controller through the bus
+    #[allow(dead_code)]
+    Interface(u16, InterfaceEvent),
     #[cfg(feature = "vpn")]
     #[allow(dead_code)]
     Tun(Buffer),
@@ -91,10 +94,17 @@ pub enum TestNodeOut<SE> {
     Continue,
 }
 
+#[allow(dead_code)]
 pub fn build_addr(node_id: NodeId) -> NodeAddr {
+    build_multi_addr(node_id, &[Ipv4Addr::LOCALHOST])
+}
+
+pub fn build_multi_addr(node_id: NodeId, ips: &[Ipv4Addr]) -> NodeAddr {
     let mut builder = NodeAddrBuilder::new(node_id);
-    builder.add_protocol(Protocol::Ip4(Ipv4Addr::LOCALHOST));
-    builder.add_protocol(Protocol::Udp(node_id as u16));
+    for ip in ips {
+        builder.add_protocol(Protocol::Ip4(*ip));
+        builder.add_protocol(Protocol::Udp(node_id as u16));
+    }
     builder.addr()
 }
 
@@ -126,32 +136,313 @@ impl ShadowRouterHistory for SingleThreadDataWorkerHistory {
 
 pub struct TestNode<SC, SE, TC, TW> {
     node_id: NodeId,
+    addr: NodeAddr,
     worker: SdnWorker<(), SC, SE, TC, TW>,
 }
 
 #[allow(clippy::type_complexity)]
 impl<SC: Debug, SE: Debug, TC: Debug, TW: Debug> TestNode<SC, SE, TC, TW> {
+    #[allow(dead_code)]
     pub fn new(node_id: NodeId, session: u64, services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>) -> Self {
+        Self::new_with_link(node_id, session, services, LinkProfile::Standard)
+    }
+
+    #[allow(dead_code)]
+    pub fn new_with_link(node_id: NodeId, session: u64, services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>, link: LinkProfile) -> Self {
+        Self::new_with(node_id, session, services, link, None)
+    }
+
+    #[allow(dead_code)]
+    pub fn new_with(
+        node_id: NodeId,
+        session: u64,
+        services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>,
+        link: LinkProfile,
+        dht_kv_storage: Option<Arc<dyn KvStorageBackend>>,
+    ) -> Self {
+        Self::new_with_scheduler(node_id, session, services, link, dht_kv_storage, None)
+    }
+
+    #[allow(dead_code)]
+    pub fn new_with_scheduler(
+        node_id: NodeId,
+        session: u64,
+        services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>,
+        link: LinkProfile,
+        dht_kv_storage: Option<Arc<dyn KvStorageBackend>>,
+        scheduler: Option<SchedulerConfig>,
+    ) -> Self {
+        Self::build(
+            node_id,
+            session,
+            services,
+            link,
+            dht_kv_storage,
+            scheduler,
+            false,
+            &[Ipv4Addr::LOCALHOST],
+            None,
+            false,
+            Capabilities::SUPPORTED,
+            None,
+            None,
+            None,
+            1000,
+        )
+    }
+
+    #[allow(dead_code)]
+    pub fn new_observer(node_id: NodeId, session: u64, services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>) -> Self {
+        Self::build(
+            node_id,
+            session,
+            services,
+            LinkProfile::Standard,
+            None,
+            None,
+            true,
+            &[Ipv4Addr::LOCALHOST],
+            None,
+            false,
+            Capabilities::SUPPORTED,
+            None,
+            None,
+            None,
+            1000,
+        )
+    }
+
+    /// Node which binds same port on many ips, so it has many paths to each neighbour
+    #[allow(dead_code)]
+    pub fn new_multipath(
+        node_id: NodeId,
+        session: u64,
+        services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>,
+        ips: &[Ipv4Addr],
+        multipath: Option<MultipathPolicy>,
+    ) -> Self {
+        Self::build(
+            node_id,
+            session,
+            services,
+            LinkProfile::Standard,
+            None,
+            None,
+            false,
+            ips,
+            multipath,
+            false,
+            Capabilities::SUPPORTED,
+            None,
+            None,
+            None,
+            1000,
+        )
+    }
+
+    /// Node which attaches incoming route to meta of received messages
+    #[allow(dead_code)]
+    pub fn new_with_incoming_route(node_id: NodeId, session: u64, services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>) -> Self {
+        Self::build(
+            node_id,
+            session,
+            services,
+            LinkProfile::Standard,
+            None,
+            None,
+            false,
+            &[Ipv4Addr::LOCALHOST],
+            None,
+            true,
+            Capabilities::SUPPORTED,
+            None,
+            None,
+            None,
+            1000,
+        )
+    }
+
+    /// Node which advertises only some capabilities, like an older build in a rolling upgrade
+    #[allow(dead_code)]
+    pub fn new_with_capabilities(node_id: NodeId, session: u64, services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>, capabilities: Capabilities) -> Self {
+        Self::build(
+            node_id,
+            session,
+            services,
+            LinkProfile::Standard,
+            None,
+            None,
+            false,
+            &[Ipv4Addr::LOCALHOST],
+            None,
+            false,
+            capabilities,
+            None,
+            None,
+            None,
+            1000,
+        )
+    }
+
+    /// Node which compresses feature payloads
+    #[allow(dead_code)]
+    pub fn new_with_compression(node_id: NodeId, session: u64, services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>, compression: CompressionConfig) -> Self {
+        Self::build(
+            node_id,
+            session,
+            services,
+            LinkProfile::Standard,
+            None,
+            None,
+            false,
+            &[Ipv4Addr::LOCALHOST],
+            None,
+            false,
+            Capabilities::SUPPORTED,
+            Some(compression),
+            None,
+            None,
+            1000,
+        )
+    }
+
+    /// Node which authorizes neighbours with a custom method instead of the shared demo key
+    #[allow(dead_code)]
+    pub fn new_with_authorization(
+        node_id: NodeId,
+        session: u64,
+        services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>,
+        authorization: Arc<dyn Authorization>,
+    ) -> Self {
+        Self::build(
+            node_id,
+            session,
+            services,
+            LinkProfile::Standard,
+            None,
+            None,
+            false,
+            &[Ipv4Addr::LOCALHOST],
+            None,
+            false,
+            Capabilities::SUPPORTED,
+            None,
+            Some(authorization),
+            None,
+            1000,
+        )
+    }
+
+    /// Node which rekeys its outgoing connections after each interval
+    #[allow(dead_code)]
+    pub fn new_with_rekey(node_id: NodeId, session: u64, services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>, rekey_interval_ms: u64) -> Self {
+        Self::build(
+            node_id,
+            session,
+            services,
+            LinkProfile::Standard,
+            None,
+            None,
+            false,
+            &[Ipv4Addr::LOCALHOST],
+            None,
+            false,
+            Capabilities::SUPPORTED,
+            None,
+            None,
+            Some(rekey_interval_ms),
+            1000,
+        )
+    }
+
+    /// Node whose connection sessions start from its own seed. All other nodes start from the same value, so a node which is
+    /// connected from two of them, like a relay, would see the same session twice
+    #[allow(dead_code)]
+    pub fn new_with_seed(node_id: NodeId, session: u64, services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>, seed: u64) -> Self {
+        Self::build(
+            node_id,
+            session,
+            services,
+            LinkProfile::Standard,
+            None,
+            None,
+            false,
+            &[Ipv4Addr::LOCALHOST],
+            None,
+            false,
+            Capabilities::SUPPORTED,
+            None,
+            None,
+            None,
+            seed,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn build(
+        node_id: NodeId,
+        session: u64,
+        services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>,
+        link: LinkProfile,
+        dht_kv_storage: Option<Arc<dyn KvStorageBackend>>,
+        scheduler: Option<SchedulerConfig>,
+        observer: bool,
+        ips: &[Ipv4Addr],
+        multipath: Option<MultipathPolicy>,
+        incoming_route: bool,
+        capabilities: Capabilities,
+        compression: Option<CompressionConfig>,
+        authorization: Option<Arc<dyn Authorization>>,
+        rekey_interval_ms: Option<u64>,
+        random_start: u64,
+    ) -> Self {
         let _log = AutoContext::new(node_id);
-        let authorization: Arc<StaticKeyAuthorization> = Arc::new(StaticKeyAuthorization::new("demo-key"));
+        let authorization = authorization.unwrap_or_else(|| Arc::new(StaticKeyAuthorization::new("demo-key")));
         let handshake_builder = Arc::new(HandshakeBuilderXDA);
-        let random = Box::new(StepRng::new(1000, 5));
+        let random = Box::new(StepRng::new(random_start, 5));
         let history = Arc::new(SingleThreadDataWorkerHistory::default());
         Self {
             node_id,
+            addr: build_multi_addr(node_id, ips),
             worker: SdnWorker::new(SdnWorkerCfg {
                 node_id,
                 tick_ms: 1,
                 controller: Some(ControllerPlaneCfg {
                     session,
-                    bind_addrs: vec![node_to_addr(node_id)],
+                    bind_addrs: ips.iter().map(|ip| SocketAddr::new(IpAddr::V4(*ip), node_id as u16)).collect(),
                     services: services.clone(),
                     authorization,
                     handshake_builder,
                     random,
                     history: history.clone(),
+                    profile: LatencyProfile::default(),
+                    link,
+                    dht_kv_storage,
+                    channel_authorizer: None,
+                    observer,
+                    capabilities,
+                    compression,
+                    rekey_interval_ms,
+                    neighbour_policy: Default::default(),
+                    sticky_ext: None,
+                    routing_policy: Default::default(),
+                    checkpoint: None,
+                    event_sinks: vec![],
                 }),
-                data: DataPlaneCfg { worker_id: 0, services, history },
+                standby: None,
+                replication: None,
+                data: DataPlaneCfg {
+                    worker_id: 0,
+                    services,
+                    history,
+                    scheduler,
+                    bandwidth_limit_kbps: None,
+                    service_shaping: vec![],
+                    multipath,
+                    incoming_route,
+                    sticky_ext: None,
+                    fragment: Default::default(),
+                },
             }),
         }
     }
@@ -161,7 +452,7 @@ impl<SC: Debug, SE: Debug, TC: Debug, TW: Debug> TestNode<SC, SE, TC, TW> {
     }
 
     pub fn addr(&self) -> NodeAddr {
-        build_addr(self.node_id)
+        self.addr.clone()
     }
 
     pub fn tick(&mut self, now: u64) {
@@ -169,12 +460,33 @@ impl<SC: Debug, SE: Debug, TC: Debug, TW: Debug> TestNode<SC, SE, TC, TW> {
         self.worker.on_tick(now);
     }
 
+    pub fn shutdown(&mut self, now: u64) {
+        let _log = AutoContext::new(self.node_id);
+        self.worker.on_shutdown(now);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.worker.is_empty()
+    }
+
+    #[allow(dead_code)]
+    pub fn controller_metrics(&self) -> ControllerMetrics {
+        self.worker.controller_metrics().expect("Should have controller")
+    }
+
+    #[allow(dead_code)]
+    pub fn data_metrics(&self) -> DataPlaneMetrics {
+        self.worker.data_metrics()
+    }
+
     pub fn on_input(&mut self, now: u64, input: TestNodeIn<SC>) {
         let _log = AutoContext::new(self.node_id);
         let input = match input {
             TestNodeIn::Ext(ext_in) => SdnWorkerInput::Ext(ext_in),
             TestNodeIn::ExtWorker(ext_in) => SdnWorkerInput::ExtWorker(ext_in),
             TestNodeIn::Udp(addr, buf) => SdnWorkerInput::Net(data_plane::NetInput::UdpPacket(addr, buf)),
+            TestNodeIn::Interface(0, event) => SdnWorkerInput::Net(data_plane::NetInput::Interface(event)),
+            TestNodeIn::Interface(worker, event) => SdnWorkerInput::Bus(SdnWorkerBusEvent::Control(LogicControl::NetInterface(worker, event))),
             #[cfg(feature = "vpn")]
             TestNodeIn::Tun(buf) => SdnWorkerInput::Net(data_plane::NetInput::TunPacket(buf)),
         };
@@ -191,8 +503,8 @@ impl<SC: Debug, SE: Debug, TC: Debug, TW: Debug> TestNode<SC, SE, TC, TW> {
         match output {
             SdnWorkerOutput::Ext(ext) => TestNodeOut::Ext(ext),
             SdnWorkerOutput::ExtWorker(ext) => TestNodeOut::ExtWorker(ext),
-            SdnWorkerOutput::Net(data_plane::NetOutput::UdpPacket(dest, data)) => TestNodeOut::Udp(vec![dest], data),
-            SdnWorkerOutput::Net(data_plane::NetOutput::UdpPackets(dests, data)) => TestNodeOut::Udp(dests, data),
+            SdnWorkerOutput::Net(data_plane::NetOutput::UdpPacket(dest, data, _)) => TestNodeOut::Udp(vec![dest], data),
+            SdnWorkerOutput::Net(data_plane::NetOutput::UdpPackets(dests, data, _)) => TestNodeOut::Udp(dests, data),
             #[cfg(feature = "vpn")]
             SdnWorkerOutput::Net(data_plane::NetOutput::TunPacket(data)) => TestNodeOut::Tun(data),
             SdnWorkerOutput::Bus(bus) => {
@@ -209,6 +521,7 @@ pub fn addr_to_node(addr: SocketAddr) -> NodeId {
     addr.port() as u32
 }
 
+#[allow(dead_code)]
 pub fn node_to_addr(node: NodeId) -> SocketAddr {
     SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), node as u16)
 }
@@ -222,8 +535,17 @@ pub struct NetworkSimulator<SC, SE, TC: Clone, TW: Clone> {
     nodes: Vec<TestNode<SC, SE, TC, TW>>,
     nodes_index: HashMap<NodeId, usize>,
     switcher: TaskSwitcher,
+    /// Called for each sent udp packet with (from, to, data), packet is dropped if it returns false
+    packet_filter: Option<PacketFilter>,
+    /// Udp paths which drop all packets, in both directions
+    blocked_paths: HashSet<(SocketAddr, SocketAddr)>,
+    /// Packets which are written to the mock tun device of each node
+    #[cfg(feature = "vpn")]
+    tun_output: VecDeque<(NodeId, Buffer)>,
 }
 
+pub type PacketFilter = Box<dyn FnMut(NodeId, NodeId, &[u8]) -> bool>;
+
 impl<SC: Debug, SE: Debug, TC: Debug + Clone, TW: Debug + Clone> NetworkSimulator<SC, SE, TC, TW> {
     pub fn new(started_ms: u64) -> Self {
         Self {
@@ -235,6 +557,10 @@ impl<SC: Debug, SE: Debug, TC: Debug + Clone, TW: Debug + Clone> NetworkSimulato
             nodes: Vec::new(),
             nodes_index: HashMap::new(),
             switcher: TaskSwitcher::new(0),
+            packet_filter: None,
+            blocked_paths: HashSet::new(),
+            #[cfg(feature = "vpn")]
+            tun_output: VecDeque::new(),
         }
     }
 
@@ -244,10 +570,30 @@ impl<SC: Debug, SE: Debug, TC: Debug + Clone, TW: Debug + Clone> NetworkSimulato
         log::set_max_level(level);
     }
 
+    /// Inspect or drop udp packets between nodes, useful for simulating small mtu or lossy links
+    #[allow(unused)]
+    pub fn set_packet_filter(&mut self, filter: PacketFilter) {
+        self.packet_filter = Some(filter);
+    }
+
+    /// Drop all udp packets between two addresses, useful for simulating a broken path between multipath nodes
+    #[allow(dead_code)]
+    pub fn set_path_blocked(&mut self, addr1: SocketAddr, addr2: SocketAddr, blocked: bool) {
+        if blocked {
+            self.blocked_paths.insert((addr1, addr2));
+            self.blocked_paths.insert((addr2, addr1));
+        } else {
+            self.blocked_paths.remove(&(addr1, addr2));
+            self.blocked_paths.remove(&(addr2, addr1));
+        }
+    }
+
+    #[allow(dead_code)]
     pub fn control(&mut self, node: NodeId, control: ExtIn<(), SC>) {
         self.input.push_back((node, control));
     }
 
+    #[allow(dead_code)]
     pub fn pop_res(&mut self) -> Option<(NodeId, ExtOut<(), SE>)> {
         self.output.pop_front()
     }
@@ -271,6 +617,82 @@ impl<SC: Debug, SE: Debug, TC: Debug + Clone, TW: Debug + Clone> NetworkSimulato
         addr
     }
 
+    /// Gracefully shutdown a node, it will be removed after all of its resources are released
+    #[allow(dead_code)]
+    pub fn leave_node(&mut self, node: NodeId) {
+        let node_index = *self.nodes_index.get(&node).expect("Node not found");
+        self.switcher.flag_task(node_index);
+        self.nodes[node_index].shutdown(self.clock_ms);
+    }
+
+    /// Remove a node immediately without notifying its neighbours, which simulates a crash
+    #[allow(dead_code)]
+    pub fn crash_node(&mut self, node: NodeId) {
+        let node_index = self.nodes_index.remove(&node).expect("Node not found");
+        self.nodes.swap_remove(node_index);
+        if let Some(moved) = self.nodes.get(node_index) {
+            self.nodes_index.insert(moved.node_id(), node_index);
+        }
+        self.switcher.set_tasks(self.nodes.len());
+        self.input.retain(|(n, _)| *n != node);
+        self.input_worker.retain(|(n, _)| *n != node);
+    }
+
+    /// Simulate a packet which is read from the mock tun device of a node
+    #[cfg(feature = "vpn")]
+    #[allow(dead_code)]
+    pub fn tun_input(&mut self, node: NodeId, pkt: Buffer) {
+        let node_index = *self.nodes_index.get(&node).expect("Node not found");
+        self.switcher.flag_task(node_index);
+        self.nodes[node_index].on_input(self.clock_ms, TestNodeIn::Tun(pkt));
+    }
+
+    /// Packet which is written to the mock tun device of a node
+    #[cfg(feature = "vpn")]
+    #[allow(dead_code)]
+    pub fn pop_tun(&mut self) -> Option<(NodeId, Buffer)> {
+        self.tun_output.pop_front()
+    }
+
+    /// Deliver a raw udp packet from one node to another, like a datagram which is sent by an attacker
+    #[allow(dead_code)]
+    pub fn inject_udp(&mut self, from: NodeId, to: NodeId, data: Buffer) {
+        let index = *self.nodes_index.get(&to).expect("Node not found");
+        self.switcher.flag_task(index);
+        self.nodes[index].on_input(self.clock_ms, TestNodeIn::Udp(NetPair::new(node_to_addr(to), node_to_addr(from)), data));
+    }
+
+    /// Simulate network interface changes which are detected by the runner
+    #[allow(dead_code)]
+    pub fn interface_event(&mut self, node: NodeId, event: InterfaceEvent) {
+        self.worker_interface_event(node, 0, event);
+    }
+
+    /// Simulate an interface change which is detected by a worker of the node, the node itself runs as worker 0
+    #[allow(dead_code)]
+    pub fn worker_interface_event(&mut self, node: NodeId, worker: u16, event: InterfaceEvent) {
+        let node_index = *self.nodes_index.get(&node).expect("Node not found");
+        self.switcher.flag_task(node_index);
+        self.nodes[node_index].on_input(self.clock_ms, TestNodeIn::Interface(worker, event));
+    }
+
+    #[allow(dead_code)]
+    pub fn controller_metrics(&self, node: NodeId) -> ControllerMetrics {
+        let node_index = *self.nodes_index.get(&node).expect("Node not found");
+        self.nodes[node_index].controller_metrics()
+    }
+
+    #[allow(dead_code)]
+    pub fn data_metrics(&self, node: NodeId) -> DataPlaneMetrics {
+        let node_index = *self.nodes_index.get(&node).expect("Node not found");
+        self.nodes[node_index].data_metrics()
+    }
+
+    #[allow(dead_code)]
+    pub fn has_node(&self, node: NodeId) -> bool {
+        self.nodes_index.contains_key(&node)
+    }
+
     pub fn process(&mut self, delta: u64) {
         self.clock_ms += delta;
         log::debug!("Tick {} ms", self.clock_ms);
@@ -290,6 +712,12 @@ impl<SC: Debug, SE: Debug, TC: Debug + Clone, TW: Debug + Clone> NetworkSimulato
         }
 
         self.pop_outputs(self.clock_ms);
+
+        let removed = self.nodes.iter().filter(|n| n.is_empty()).map(|n| n.node_id()).collect::<Vec<_>>();
+        for node in removed {
+            log::info!("Node {} shutdown finished, removed from network", node);
+            self.crash_node(node);
+        }
     }
 
     fn pop_outputs(&mut self, now: u64) {
@@ -317,14 +745,31 @@ impl<SC: Debug, SE: Debug, TC: Debug + Clone, TW: Debug + Clone> NetworkSimulato
                 for dest in dests {
                     log::debug!("Send UDP packet from {} to {}, buf len {}", dest.local, dest.remote, data.len());
                     let dest_node = addr_to_node(dest.remote);
-                    let dest_index = *self.nodes_index.get(&dest_node).expect("Node not found");
+                    let dest_index = if let Some(index) = self.nodes_index.get(&dest_node) {
+                        *index
+                    } else {
+                        log::debug!("Drop UDP packet to unknown node {}", dest_node);
+                        continue;
+                    };
+                    if self.blocked_paths.contains(&(dest.local, dest.remote)) {
+                        log::debug!("Drop UDP packet from {} to {} by blocked path", dest.local, dest.remote);
+                        continue;
+                    }
+                    if let Some(filter) = &mut self.packet_filter {
+                        if !filter(node, dest_node, &data) {
+                            log::debug!("Drop UDP packet from {} to {} by filter", node, dest_node);
+                            continue;
+                        }
+                    }
                     self.switcher.flag_task(dest_index);
                     let in_pair = NetPair::new(dest.remote, dest.local);
                     self.nodes[dest_index].on_input(now, TestNodeIn::Udp(in_pair, data.clone()));
                 }
             }
             #[cfg(feature = "vpn")]
-            TestNodeOut::Tun(_) => todo!(),
+            TestNodeOut::Tun(pkt) => {
+                self.tun_output.push_back((node, pkt));
+            }
             TestNodeOut::Continue => {}
         }
     }
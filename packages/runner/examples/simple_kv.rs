@@ -18,12 +18,13 @@ use std::{
     time::Duration,
 };
 
-use atm0s_sdn::{SdnBuilder, SdnExtIn, SdnOwner};
+use atm0s_sdn::{BatchBackend, SdnBuilder, SdnExtIn, SdnOwner};
 
 #[derive(Debug, Clone, ValueEnum)]
 enum BackendType {
     Poll,
     Polling,
+    Batch,
 }
 
 /// Simple program to running a node
@@ -89,6 +90,7 @@ fn main() {
     let mut controller = match args.backend {
         BackendType::Poll => builder.build::<PollBackend<SdnOwner, 128, 128>>(args.workers, args.node_id),
         BackendType::Polling => builder.build::<PollingBackend<SdnOwner, 128, 128>>(args.workers, args.node_id),
+        BackendType::Batch => builder.build::<BatchBackend<SdnOwner, 64>>(args.workers, args.node_id),
     };
 
     if args.kv_subscribe {
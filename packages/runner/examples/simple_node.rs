@@ -11,12 +11,13 @@ use std::{
     time::Duration,
 };
 
-use atm0s_sdn::{SdnBuilder, SdnOwner};
+use atm0s_sdn::{BatchBackend, SdnBuilder, SdnOwner};
 
 #[derive(Debug, Clone, ValueEnum)]
 enum BackendType {
     Poll,
     Polling,
+    Batch,
 }
 
 /// Simple program to running a node
@@ -93,6 +94,7 @@ fn main() {
     let mut controller = match args.backend {
         BackendType::Poll => builder.build::<PollBackend<SdnOwner, 128, 128>>(args.workers, args.node_id),
         BackendType::Polling => builder.build::<PollingBackend<SdnOwner, 128, 128>>(args.workers, args.node_id),
+        BackendType::Batch => builder.build::<BatchBackend<SdnOwner, 64>>(args.workers, args.node_id),
     };
 
     while controller.process().is_some() {
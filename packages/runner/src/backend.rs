@@ -0,0 +1,801 @@
+//! Backend which batches udp socket io, for high packet rates.
+//!
+//! The runtime backends use one syscall per datagram. [`BatchBackend`] queues outgoing packets in a worker cycle and flushes
+//! them with `sendmmsg` when the queue reaches the batch size or the cycle is finished, and reads up to the batch size of
+//! datagrams with a single `recvmmsg` when a socket is readable. Other platforms fall back to `send_to` and `recv_from`
+//! with the same queueing.
+//!
+//! On Linux, sockets also use udp segmentation offload when the kernel supports it. Consecutive packets to the same
+//! destination with the same size, like a bulk vpn flow, are sent as one GSO message which the kernel or the nic splits
+//! into datagrams, and with GRO the kernel coalesces received datagrams which are split again here. Datagrams on the wire
+//! are unchanged, so support is detected per socket and doesn't need anything from remote nodes. A route can still reject
+//! segmentation while the socket supports it, like over a nic without offload, then the kernel fails the message with EIO or
+//! EINVAL and only that destination falls back to plain datagrams. The batch size is a const parameter like other sizes of
+//! backends, because the runtime creates backends inside workers with [`Default`].
+//!
+//! Udp sockets are marked with the DSCP code point which the worker requested for the listen, see [`crate::DscpClasses`].
+
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::Debug,
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use polling::{Event, Events, PollMode, Poller};
+use sans_io_runtime::{
+    backend::{Awaker, Backend, BackendIncoming, BackendIncomingInternal, BackendOutgoing, BackendOwner},
+    Buffer,
+};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+
+use crate::dscp;
+
+/// Padding before received data, which is used for appending headers without reallocating
+const RECV_PADDING: usize = 100;
+const RECV_SIZE: usize = 1500;
+/// Coalesced messages are received into large buffers, which are fewer than the batch size to bound memory
+const GRO_BATCH: usize = 8;
+const GRO_RECV_SIZE: usize = 65535;
+
+/// Udp segmentation offload which is supported by a socket
+#[derive(Debug, Clone, Default)]
+struct Offload {
+    gso: bool,
+    gro: bool,
+    /// Destinations whose route rejected a gso message, packets to them are sent as plain datagrams
+    gso_rejected: HashSet<SocketAddr>,
+}
+
+impl Offload {
+    fn gso_to(&self, to: &SocketAddr) -> bool {
+        self.gso && !self.gso_rejected.contains(to)
+    }
+}
+
+enum SocketType<Owner> {
+    Waker,
+    Udp(UdpSocket, Owner, Offload),
+    #[cfg(feature = "vpn")]
+    Tun(sans_io_runtime::backend::tun::TunFd, Owner),
+}
+
+/// Received datagram, or coalesced datagrams of the segment size with gro
+struct Received {
+    len: usize,
+    from: Option<SocketAddr>,
+    segment: Option<usize>,
+}
+
+/// Queued outgoing packet, data is an index in the queued buffers so a packet to many destinations is not copied
+struct QueuedPacket {
+    slot: usize,
+    to: SocketAddr,
+    data: usize,
+}
+
+pub struct BatchBackend<Owner, const BATCH_SIZE: usize> {
+    poll: Arc<Poller>,
+    events: Events,
+    ready: VecDeque<usize>,
+    sockets: Vec<Option<SocketType<Owner>>>,
+    output: VecDeque<BackendIncomingInternal<Owner>>,
+    queued: Vec<QueuedPacket>,
+    queued_data: Vec<Buffer>,
+    /// Spare buffers for receiving, buffers which are not filled by a batch are kept for the next one
+    recv_bufs: Vec<Buffer>,
+    /// Buffers for coalesced messages, received data is copied out so they are always reused
+    gro_bufs: Vec<Vec<u8>>,
+    awaker: Arc<BatchAwaker>,
+    awake_flag: Arc<AtomicBool>,
+}
+
+impl<Owner: Debug + Clone + Copy + PartialEq, const BATCH_SIZE: usize> BatchBackend<Owner, BATCH_SIZE> {
+    fn create_udp(addr: SocketAddr, reuse: bool) -> Result<UdpSocket, io::Error> {
+        let dscp = dscp::take(addr).unwrap_or(0);
+        let domain = match addr {
+            SocketAddr::V4(_) => Domain::IPV4,
+            SocketAddr::V6(_) => Domain::IPV6,
+        };
+        let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+        if reuse {
+            socket.set_reuse_address(true)?;
+            socket.set_reuse_port(true)?;
+        }
+        if dscp != 0 {
+            dscp::mark(SockRef::from(&socket), &addr, dscp)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        Ok(socket.into())
+    }
+
+    fn select_slot(&mut self) -> usize {
+        if let Some(slot) = self.sockets.iter().position(|s| s.is_none()) {
+            return slot;
+        }
+        self.sockets.push(None);
+        self.sockets.len() - 1
+    }
+
+    /// Queue a packet, data is sent with every destination
+    fn queue(&mut self, data: Buffer, dests: impl Iterator<Item = (usize, SocketAddr)>) {
+        let index = self.queued_data.len();
+        self.queued_data.push(data);
+        self.queued.extend(dests.map(|(slot, to)| QueuedPacket { slot, to, data: index }));
+        if self.queued.len() >= BATCH_SIZE {
+            self.flush();
+        }
+    }
+
+    /// Send all queued packets, consecutive packets of the same socket are sent in one batch
+    fn flush(&mut self) {
+        let mut start = 0;
+        while start < self.queued.len() {
+            let slot = self.queued[start].slot;
+            let end = self.queued[start..].iter().position(|p| p.slot != slot).map_or(self.queued.len(), |len| start + len);
+            if let Some(Some(SocketType::Udp(socket, _, offload))) = self.sockets.get_mut(slot) {
+                let packets: Vec<(&[u8], SocketAddr)> = self.queued[start..end].iter().map(|p| (&self.queued_data[p.data][..], p.to)).collect();
+                let mut sent = 0;
+                while sent < packets.len() {
+                    match sys::send_batch(socket, &packets[sent..], offload) {
+                        Ok(0) => break,
+                        Ok(len) => sent += len,
+                        Err(e) if sys::message_len(&packets[sent..], offload) > 1 && sys::is_gso_unsupported(&e) => {
+                            // the nic or the route to the destination doesn't support it, packets are sent again without it
+                            let to = packets[sent].1;
+                            log::warn!("BatchBackend disable gso to {to} on socket {slot} after error {:?}", e);
+                            offload.gso_rejected.insert(to);
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            log::trace!("BatchBackend drop {} packets, socket is busy", packets.len() - sent);
+                            break;
+                        }
+                        Err(e) => {
+                            // the error is for the first message, which is a whole gso group, others are still tried
+                            let dropped = sys::message_len(&packets[sent..], offload);
+                            log::trace!("BatchBackend drop {dropped} packets to {} after error {:?}", packets[sent].1, e);
+                            sent += dropped;
+                        }
+                    }
+                }
+            } else {
+                log::error!("BatchBackend send_to error: no socket for {}", slot);
+            }
+            start = end;
+        }
+        self.queued.clear();
+        self.queued_data.clear();
+    }
+
+    fn recv_udp(&mut self, slot: usize) {
+        let Some(Some(SocketType::Udp(socket, owner, offload))) = self.sockets.get(slot) else {
+            return;
+        };
+        if offload.gro {
+            while self.gro_bufs.len() < GRO_BATCH.min(BATCH_SIZE) {
+                self.gro_bufs.push(vec![0; GRO_RECV_SIZE]);
+            }
+            let mut bufs: Vec<&mut [u8]> = self.gro_bufs.iter_mut().map(|b| b.as_mut_slice()).collect();
+            match sys::recv_batch(socket, &mut bufs) {
+                Ok(received) => {
+                    for (buf, msg) in self.gro_bufs.iter().zip(received) {
+                        let Some(from) = msg.from else {
+                            continue;
+                        };
+                        let segment = msg.segment.unwrap_or(msg.len).max(1);
+                        for chunk in buf[..msg.len].chunks(segment) {
+                            let mut data = Buffer::new(RECV_PADDING, RECV_SIZE.max(chunk.len()));
+                            data.push_back(chunk);
+                            self.output.push_back(BackendIncomingInternal::Event(*owner, BackendIncoming::UdpPacket { slot, from, data }));
+                        }
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => log::trace!("BatchBackend recv_from error {:?}", e),
+            }
+            return;
+        }
+
+        while self.recv_bufs.len() < BATCH_SIZE {
+            self.recv_bufs.push(Buffer::new(RECV_PADDING, RECV_SIZE));
+        }
+        let mut bufs: Vec<&mut [u8]> = self.recv_bufs.iter_mut().map(|b| b.remain_mut()).collect();
+        match sys::recv_batch(socket, &mut bufs) {
+            Ok(received) => {
+                // received buffers are the first ones
+                let rest = self.recv_bufs.split_off(received.len());
+                for (mut buf, msg) in std::mem::replace(&mut self.recv_bufs, rest).into_iter().zip(received) {
+                    let Some(from) = msg.from else {
+                        continue;
+                    };
+                    buf.move_back_right(msg.len).expect("Should not overflow");
+                    self.output.push_back(BackendIncomingInternal::Event(*owner, BackendIncoming::UdpPacket { slot, from, data: buf }));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => log::trace!("BatchBackend recv_from error {:?}", e),
+        }
+    }
+
+    #[cfg(feature = "vpn")]
+    fn recv_tun(&mut self, slot: usize) {
+        use std::io::Read;
+
+        let Some(Some(SocketType::Tun(fd, owner))) = self.sockets.get_mut(slot) else {
+            return;
+        };
+        if !fd.read {
+            return;
+        }
+        for _ in 0..BATCH_SIZE {
+            let mut buf = Buffer::new(RECV_PADDING, RECV_SIZE);
+            match fd.fd.read(buf.remain_mut()) {
+                Ok(size) => {
+                    buf.move_back_right(size).expect("Should not overflow");
+                    self.output.push_back(BackendIncomingInternal::Event(*owner, BackendIncoming::TunPacket { slot, data: buf }));
+                }
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl<Owner, const BATCH_SIZE: usize> Default for BatchBackend<Owner, BATCH_SIZE> {
+    fn default() -> Self {
+        let poll = Arc::new(Poller::new().expect("should create poll"));
+        let awake_flag = Arc::new(AtomicBool::new(false));
+        Self {
+            poll: poll.clone(),
+            events: Events::new(),
+            ready: VecDeque::new(),
+            sockets: vec![Some(SocketType::Waker)],
+            output: VecDeque::new(),
+            queued: Vec::with_capacity(BATCH_SIZE),
+            queued_data: Vec::with_capacity(BATCH_SIZE),
+            recv_bufs: Vec::with_capacity(BATCH_SIZE),
+            gro_bufs: Vec::new(),
+            awake_flag: awake_flag.clone(),
+            awaker: Arc::new(BatchAwaker { poll, awake_flag }),
+        }
+    }
+}
+
+impl<Owner: Debug + Clone + Copy + PartialEq, const BATCH_SIZE: usize> Backend<Owner> for BatchBackend<Owner, BATCH_SIZE> {
+    fn create_awaker(&self) -> Arc<dyn Awaker> {
+        self.awaker.clone()
+    }
+
+    fn poll_incoming(&mut self, timeout: Duration) {
+        // packets which are queued on tick are sent before waiting, so they are not delayed by the poll timeout
+        self.flush();
+        self.events.clear();
+        if let Err(e) = self.poll.wait(&mut self.events, Some(timeout)) {
+            log::error!("BatchBackend poll error {:?}", e);
+            return;
+        }
+
+        if self.awake_flag.swap(false, Ordering::Relaxed) {
+            self.ready.push_back(0);
+        }
+        self.ready.extend(self.events.iter().map(|e| e.key));
+    }
+
+    fn pop_incoming(&mut self) -> Option<BackendIncomingInternal<Owner>> {
+        loop {
+            if let Some(event) = self.output.pop_front() {
+                return Some(event);
+            }
+            let slot = self.ready.pop_front()?;
+            match self.sockets.get(slot) {
+                Some(Some(SocketType::Waker)) => return Some(BackendIncomingInternal::Awake),
+                Some(Some(SocketType::Udp(..))) => self.recv_udp(slot),
+                #[cfg(feature = "vpn")]
+                Some(Some(SocketType::Tun(..))) => self.recv_tun(slot),
+                Some(None) | None => {}
+            }
+        }
+    }
+
+    fn finish_outgoing_cycle(&mut self) {
+        self.flush();
+    }
+
+    fn finish_incoming_cycle(&mut self) {}
+}
+
+impl<Owner: Debug + Clone + Copy + PartialEq, const BATCH_SIZE: usize> BackendOwner<Owner> for BatchBackend<Owner, BATCH_SIZE> {
+    fn on_action(&mut self, owner: Owner, action: BackendOutgoing) {
+        match action {
+            BackendOutgoing::UdpListen { addr, reuse } => {
+                let result = Self::create_udp(addr, reuse).and_then(|socket| {
+                    let local_addr = socket.local_addr()?;
+                    let offload = sys::enable_offload(&socket);
+                    log::info!("BatchBackend: UdpListen {addr}, reuse: {reuse}, gso: {}, gro: {}", offload.gso, offload.gro);
+                    let slot = self.select_slot();
+                    unsafe {
+                        self.poll.add_with_mode(&socket, Event::readable(slot), PollMode::Level)?;
+                    }
+                    self.sockets[slot] = Some(SocketType::Udp(socket, owner, offload));
+                    Ok((local_addr, slot))
+                });
+                if let Err(e) = &result {
+                    log::error!("BatchBackend bind error {:?}", e);
+                }
+                self.output.push_back(BackendIncomingInternal::Event(owner, BackendIncoming::UdpListenResult { bind: addr, result }));
+            }
+            BackendOutgoing::UdpUnlisten { slot } => {
+                // queued packets of the socket are sent before it is closed
+                self.flush();
+                if let Some(slot) = self.sockets.get_mut(slot) {
+                    if let Some(SocketType::Udp(socket, _, _)) = slot {
+                        if let Err(e) = self.poll.delete(&*socket) {
+                            log::error!("BatchBackend deregister error {:?}", e);
+                        }
+                        *slot = None;
+                    }
+                }
+            }
+            BackendOutgoing::UdpPacket { slot, to, data } => {
+                self.queue(data, std::iter::once((slot, to)));
+            }
+            BackendOutgoing::UdpPackets { slot, to, data } => {
+                self.queue(data, to.into_iter().map(|dest| (slot, dest)));
+            }
+            BackendOutgoing::UdpPackets2 { to, data } => {
+                self.queue(data, to.into_iter());
+            }
+            #[cfg(feature = "vpn")]
+            BackendOutgoing::TunBind { fd } => {
+                use std::os::fd::AsRawFd;
+
+                let slot = self.select_slot();
+                if fd.read {
+                    unsafe {
+                        if let Err(e) = self.poll.add_with_mode(fd.fd.as_raw_fd(), Event::readable(slot), PollMode::Level) {
+                            log::error!("BatchBackend register error {:?}", e);
+                            return;
+                        }
+                    }
+                }
+                self.output.push_back(BackendIncomingInternal::Event(owner, BackendIncoming::TunBindResult { result: Ok(slot) }));
+                self.sockets[slot] = Some(SocketType::Tun(fd, owner));
+            }
+            #[cfg(feature = "vpn")]
+            BackendOutgoing::TunUnbind { slot } => {
+                use std::os::fd::{AsRawFd, BorrowedFd};
+
+                if let Some(slot) = self.sockets.get_mut(slot) {
+                    if let Some(SocketType::Tun(fd, _)) = slot {
+                        if fd.read {
+                            let fd = unsafe { BorrowedFd::borrow_raw(fd.fd.as_raw_fd()) };
+                            if let Err(e) = self.poll.delete(fd) {
+                                log::error!("BatchBackend deregister error {:?}", e);
+                            }
+                        }
+                        *slot = None;
+                    }
+                }
+            }
+            #[cfg(feature = "vpn")]
+            BackendOutgoing::TunPacket { slot, data } => {
+                use std::io::Write;
+
+                if let Some(Some(SocketType::Tun(fd, _))) = self.sockets.get_mut(slot) {
+                    if let Err(e) = fd.fd.write_all(&data) {
+                        log::error!("BatchBackend write_all error {:?}", e);
+                    }
+                } else {
+                    log::error!("BatchBackend send_to error: no tun for {}", slot);
+                }
+            }
+        }
+    }
+}
+
+pub struct BatchAwaker {
+    poll: Arc<Poller>,
+    awake_flag: Arc<AtomicBool>,
+}
+
+impl Awaker for BatchAwaker {
+    fn awake(&self) {
+        if self.awake_flag.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        self.poll.notify().expect("Should notify poll");
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::{
+        io, mem,
+        net::{SocketAddr, UdpSocket},
+        os::fd::AsRawFd,
+        ptr,
+    };
+
+    use socket2::SockAddr;
+
+    use super::{Offload, Received};
+
+    /// Linux older than 5.x rejects more segments than this
+    const MAX_GSO_SEGMENTS: usize = 64;
+    /// Gso message must fit an ip packet
+    const MAX_GSO_BYTES: usize = 65000;
+
+    #[repr(C, align(8))]
+    #[derive(Clone, Copy)]
+    struct CmsgBuf([u8; 64]);
+
+    pub fn enable_offload(socket: &UdpSocket) -> Offload {
+        let fd = socket.as_raw_fd();
+        let mut segment: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: the option values point to ints which outlive the calls
+        let gso = unsafe { libc::getsockopt(fd, libc::SOL_UDP, libc::UDP_SEGMENT, &mut segment as *mut libc::c_int as *mut libc::c_void, &mut len) } == 0;
+        let enable: libc::c_int = 1;
+        let gro = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_UDP,
+                libc::UDP_GRO,
+                &enable as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        } == 0;
+        Offload { gso, gro, ..Default::default() }
+    }
+
+    /// Kernel returns these errors when the segmentation is not supported on the route
+    pub fn is_gso_unsupported(e: &io::Error) -> bool {
+        matches!(e.raw_os_error(), Some(libc::EIO) | Some(libc::EINVAL))
+    }
+
+    /// Group consecutive packets which can be sent as one gso message, returning (start, end) of messages
+    fn gso_messages(packets: &[(&[u8], SocketAddr)], offload: &Offload) -> Vec<(usize, usize)> {
+        let mut messages = Vec::with_capacity(packets.len());
+        let mut start = 0;
+        while start < packets.len() {
+            let end = start + message_len(&packets[start..], offload);
+            messages.push((start, end));
+            start = end;
+        }
+        messages
+    }
+
+    /// Number of packets which are sent in the first message, it is more than 1 for a gso message.
+    /// Packets of a message have same destination and size, except the last one which can be smaller
+    pub fn message_len(packets: &[(&[u8], SocketAddr)], offload: &Offload) -> usize {
+        let Some(&(first, to)) = packets.first() else {
+            return 0;
+        };
+        let gso = offload.gso_to(&to);
+        let mut len = 1;
+        let mut bytes = first.len();
+        while gso && !first.is_empty() && len < packets.len() && len < MAX_GSO_SEGMENTS {
+            let (data, dest) = packets[len];
+            if dest != to || data.len() > first.len() || bytes + data.len() > MAX_GSO_BYTES {
+                break;
+            }
+            bytes += data.len();
+            len += 1;
+            if data.len() < first.len() {
+                break;
+            }
+        }
+        len
+    }
+
+    /// Send packets with one sendmmsg, returning the number of sent packets
+    pub fn send_batch(socket: &UdpSocket, packets: &[(&[u8], SocketAddr)], offload: &Offload) -> io::Result<usize> {
+        let messages = gso_messages(packets, offload);
+        let addrs: Vec<SockAddr> = messages.iter().map(|(start, _)| SockAddr::from(packets[*start].1)).collect();
+        let mut iovecs: Vec<libc::iovec> = packets
+            .iter()
+            .map(|(data, _)| libc::iovec {
+                iov_base: data.as_ptr() as *mut libc::c_void,
+                iov_len: data.len(),
+            })
+            .collect();
+        let mut cmsgs = vec![CmsgBuf([0; 64]); messages.len()];
+        let mut msgs: Vec<libc::mmsghdr> = Vec::with_capacity(messages.len());
+        for ((&(start, end), addr), cmsg) in messages.iter().zip(addrs.iter()).zip(cmsgs.iter_mut()) {
+            // SAFETY: msghdr is a plain C struct, zero is a valid value for all fields
+            let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+            hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+            hdr.msg_namelen = addr.len();
+            // SAFETY: start is inside iovecs
+            hdr.msg_iov = unsafe { iovecs.as_mut_ptr().add(start) };
+            hdr.msg_iovlen = (end - start) as _;
+            if end - start > 1 {
+                // SAFETY: the control buffer is aligned and large enough for one u16 cmsg
+                unsafe {
+                    hdr.msg_control = cmsg.0.as_mut_ptr() as *mut libc::c_void;
+                    hdr.msg_controllen = libc::CMSG_SPACE(mem::size_of::<u16>() as u32) as _;
+                    let cm = libc::CMSG_FIRSTHDR(&hdr);
+                    (*cm).cmsg_level = libc::SOL_UDP;
+                    (*cm).cmsg_type = libc::UDP_SEGMENT;
+                    (*cm).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+                    ptr::write_unaligned(libc::CMSG_DATA(cm) as *mut u16, packets[start].0.len() as u16);
+                }
+            }
+            msgs.push(libc::mmsghdr { msg_hdr: hdr, msg_len: 0 });
+        }
+        // SAFETY: all pointers in msgs point to addrs, iovecs, cmsgs and packets, which outlive the call
+        let res = unsafe { libc::sendmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), msgs.len() as _, 0) };
+        if res < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(messages[..res as usize].iter().map(|(start, end)| end - start).sum())
+        }
+    }
+
+    /// Receive datagrams into the buffers with one recvmmsg, returning received messages of the first buffers in order
+    pub fn recv_batch(socket: &UdpSocket, bufs: &mut [&mut [u8]]) -> io::Result<Vec<Received>> {
+        // SAFETY: sockaddr_storage is a plain C struct, zero is a valid value
+        let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; bufs.len()];
+        let mut cmsgs = vec![CmsgBuf([0; 64]); bufs.len()];
+        let mut iovecs: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(addrs.iter_mut())
+            .zip(cmsgs.iter_mut())
+            .map(|((iov, addr), cmsg)| {
+                // SAFETY: msghdr is a plain C struct, zero is a valid value for all fields
+                let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+                hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+                hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                hdr.msg_iov = iov;
+                hdr.msg_iovlen = 1;
+                hdr.msg_control = cmsg.0.as_mut_ptr() as *mut libc::c_void;
+                hdr.msg_controllen = mem::size_of::<CmsgBuf>() as _;
+                libc::mmsghdr { msg_hdr: hdr, msg_len: 0 }
+            })
+            .collect();
+        // SAFETY: all pointers in msgs point to addrs, cmsgs and bufs, which outlive the call
+        let res = unsafe { libc::recvmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), msgs.len() as _, 0, ptr::null_mut()) };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut received = Vec::with_capacity(res as usize);
+        for (msg, addr) in msgs.iter().zip(addrs.iter()).take(res as usize) {
+            // SAFETY: the storage and length are filled by the kernel
+            let from = unsafe { SockAddr::new(*addr, msg.msg_hdr.msg_namelen) }.as_socket();
+            let mut segment = None;
+            // SAFETY: the control buffer is filled by the kernel, headers are walked with the libc macros
+            unsafe {
+                let mut cm = libc::CMSG_FIRSTHDR(&msg.msg_hdr);
+                while !cm.is_null() {
+                    if (*cm).cmsg_level == libc::SOL_UDP && (*cm).cmsg_type == libc::UDP_GRO {
+                        segment = Some(ptr::read_unaligned(libc::CMSG_DATA(cm) as *const libc::c_int) as usize);
+                    }
+                    cm = libc::CMSG_NXTHDR(&msg.msg_hdr, cm);
+                }
+            }
+            received.push(Received {
+                len: msg.msg_len as usize,
+                from,
+                segment,
+            });
+        }
+        Ok(received)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::{
+        io,
+        net::{SocketAddr, UdpSocket},
+    };
+
+    use super::{Offload, Received};
+
+    pub fn enable_offload(_socket: &UdpSocket) -> Offload {
+        Offload::default()
+    }
+
+    pub fn is_gso_unsupported(_e: &io::Error) -> bool {
+        false
+    }
+
+    pub fn message_len(packets: &[(&[u8], SocketAddr)], _offload: &Offload) -> usize {
+        packets.len().min(1)
+    }
+
+    pub fn send_batch(socket: &UdpSocket, packets: &[(&[u8], SocketAddr)], _offload: &Offload) -> io::Result<usize> {
+        for (index, (data, to)) in packets.iter().enumerate() {
+            if let Err(e) = socket.send_to(data, to) {
+                return if index == 0 {
+                    Err(e)
+                } else {
+                    Ok(index)
+                };
+            }
+        }
+        Ok(packets.len())
+    }
+
+    pub fn recv_batch(socket: &UdpSocket, bufs: &mut [&mut [u8]]) -> io::Result<Vec<Received>> {
+        let mut received = Vec::new();
+        for buf in bufs.iter_mut() {
+            match socket.recv_from(buf) {
+                Ok((len, from)) => received.push(Received { len, from: Some(from), segment: None }),
+                Err(e) if received.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use sans_io_runtime::{
+        backend::{Backend, BackendIncoming, BackendIncomingInternal, BackendOutgoing, BackendOwner},
+        group_owner_type, Buffer,
+    };
+
+    use super::{BatchBackend, Offload};
+    use crate::dscp::{self, DSCP_EF};
+
+    group_owner_type!(TestOwner);
+
+    fn listen(backend: &mut BatchBackend<TestOwner, 4>, owner: TestOwner) -> (SocketAddr, usize) {
+        backend.on_action(
+            owner,
+            BackendOutgoing::UdpListen {
+                addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+                reuse: false,
+            },
+        );
+        match backend.pop_incoming() {
+            Some(BackendIncomingInternal::Event(o, BackendIncoming::UdpListenResult { result, .. })) if o == owner => result.expect("Should bind"),
+            _ => panic!("Expected UdpListenResult"),
+        }
+    }
+
+    /// Receive packets until the expected count or the deadline, returning (slot, from, data)
+    fn recv(backend: &mut BatchBackend<TestOwner, 4>, count: usize) -> Vec<(usize, SocketAddr, Vec<u8>)> {
+        let mut received = vec![];
+        for _ in 0..100 {
+            backend.poll_incoming(Duration::from_millis(10));
+            while let Some(event) = backend.pop_incoming() {
+                if let BackendIncomingInternal::Event(_, BackendIncoming::UdpPacket { slot, from, data }) = event {
+                    received.push((slot, from, data.to_vec()));
+                }
+            }
+            if received.len() >= count {
+                break;
+            }
+        }
+        received
+    }
+
+    #[test]
+    fn send_and_recv_in_batches() {
+        let mut backend = BatchBackend::<TestOwner, 4>::default();
+        let (addr1, slot1) = listen(&mut backend, TestOwner(1));
+        let (addr2, slot2) = listen(&mut backend, TestOwner(2));
+
+        // more than a batch, so one is flushed when queueing and the rest at the end of cycle
+        for i in 0..10u8 {
+            backend.on_action(
+                TestOwner(1),
+                BackendOutgoing::UdpPacket {
+                    slot: slot1,
+                    to: addr2,
+                    data: Buffer::from(vec![i; 100]),
+                },
+            );
+        }
+        backend.finish_outgoing_cycle();
+
+        let received = recv(&mut backend, 10);
+        assert_eq!(received.len(), 10);
+        for (i, (slot, from, data)) in received.into_iter().enumerate() {
+            assert_eq!((slot, from), (slot2, addr1));
+            assert_eq!(data, vec![i as u8; 100]);
+        }
+
+        backend.on_action(
+            TestOwner(1),
+            BackendOutgoing::UdpPackets2 {
+                to: vec![(slot1, addr2), (slot2, addr1)],
+                data: Buffer::from(b"hello".to_vec()),
+            },
+        );
+        backend.finish_outgoing_cycle();
+        let mut received = recv(&mut backend, 2);
+        received.sort();
+        assert_eq!(received, vec![(slot1, addr2, b"hello".to_vec()), (slot2, addr1, b"hello".to_vec())]);
+
+        backend.on_action(TestOwner(1), BackendOutgoing::UdpUnlisten { slot: slot1 });
+        backend.on_action(TestOwner(2), BackendOutgoing::UdpUnlisten { slot: slot2 });
+    }
+
+    #[test]
+    fn segmented_packets_are_received_in_order() {
+        let mut backend = BatchBackend::<TestOwner, 4>::default();
+        let (addr1, slot1) = listen(&mut backend, TestOwner(1));
+        let (addr2, slot2) = listen(&mut backend, TestOwner(2));
+
+        // a bulk flow, which is sent as segments of the same size with gso and maybe coalesced with gro
+        let packets: Vec<Vec<u8>> = (0..20u8)
+            .map(|i| {
+                vec![
+                    i;
+                    if i == 19 {
+                        300
+                    } else {
+                        1200
+                    }
+                ]
+            })
+            .collect();
+        for data in &packets {
+            backend.on_action(
+                TestOwner(1),
+                BackendOutgoing::UdpPacket {
+                    slot: slot1,
+                    to: addr2,
+                    data: Buffer::from(data.clone()),
+                },
+            );
+        }
+        backend.finish_outgoing_cycle();
+
+        let received = recv(&mut backend, packets.len());
+        assert_eq!(received, packets.into_iter().map(|data| (slot2, addr1, data)).collect::<Vec<_>>());
+
+        backend.on_action(TestOwner(1), BackendOutgoing::UdpUnlisten { slot: slot1 });
+        backend.on_action(TestOwner(2), BackendOutgoing::UdpUnlisten { slot: slot2 });
+    }
+
+    #[test]
+    fn sockets_are_marked_with_requested_dscp() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        dscp::request(addr, DSCP_EF);
+        let socket = BatchBackend::<TestOwner, 4>::create_udp(addr, false).expect("Should bind");
+        assert_eq!(socket2::SockRef::from(&socket).tos().expect("Should get tos"), (DSCP_EF as u32) << 2);
+        assert_eq!(dscp::take(addr), None);
+
+        let socket = BatchBackend::<TestOwner, 4>::create_udp(addr, false).expect("Should bind");
+        assert_eq!(socket2::SockRef::from(&socket).tos().expect("Should get tos"), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn failed_gso_message_counts_its_segments() {
+        let to = SocketAddr::from(([127, 0, 0, 1], 1000));
+        let other = SocketAddr::from(([127, 0, 0, 1], 1001));
+        let packets: Vec<(&[u8], SocketAddr)> = vec![(&[1; 100], to), (&[2; 100], to), (&[3; 50], to), (&[4; 100], to), (&[5; 100], other)];
+        let mut offload = Offload { gso: true, ..Default::default() };
+        assert_eq!(super::sys::message_len(&packets, &offload), 3);
+        assert_eq!(super::sys::message_len(&packets[3..], &offload), 1);
+        assert_eq!(super::sys::message_len(&[], &offload), 0);
+
+        // destination whose route rejected gso is sent without it, others are still grouped
+        offload.gso_rejected.insert(to);
+        assert_eq!(super::sys::message_len(&packets, &offload), 1);
+        let packets: Vec<(&[u8], SocketAddr)> = vec![(&[1; 100], other), (&[2; 100], other)];
+        assert_eq!(super::sys::message_len(&packets, &offload), 2);
+
+        assert_eq!(super::sys::message_len(&packets, &Offload::default()), 1);
+    }
+}
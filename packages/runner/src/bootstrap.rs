@@ -0,0 +1,331 @@
+//! Config of the bootstrap service and a minimal DNS client (RFC 1035) for resolving its seed lists.
+//!
+//! TXT records contain node addresses in their text form. SRV records point to hosts which are named by their node id,
+//! like `100.seeds.example.com`, the host is resolved with the system resolver and the port of the record is the udp port.
+//! Names are resolved again periodically in a background thread, the last successful result is kept when a query fails.
+
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use atm0s_sdn_identity::{NodeAddr, NodeAddrBuilder, NodeId, Protocol};
+use atm0s_sdn_network::services::bootstrap::SeedSource;
+use parking_lot::Mutex;
+use rand::{thread_rng, Rng};
+
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+const HEADER_LEN: usize = 12;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Max compression pointers which are followed in a name, for rejecting loops
+const MAX_POINTERS: usize = 16;
+/// Default interval for resolving DNS seed lists again
+pub const DNS_SEED_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Bootstrap service which dials known-good peers and seeds again when the node loses all connections
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootstrapConfig {
+    /// File which known-good peers are persisted to, None for keeping them in memory only
+    pub peers_file: Option<PathBuf>,
+    pub dns_seeds: Vec<DnsSeed>,
+    pub dns_interval: Duration,
+    /// Nameserver for DNS seed lists, None for the first one of `/etc/resolv.conf`
+    pub nameserver: Option<SocketAddr>,
+}
+
+impl Default for BootstrapConfig {
+    fn default() -> Self {
+        Self {
+            peers_file: None,
+            dns_seeds: vec![],
+            dns_interval: DNS_SEED_INTERVAL,
+            nameserver: None,
+        }
+    }
+}
+
+/// Name of a DNS seed list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsSeed {
+    Srv(String),
+    Txt(String),
+}
+
+impl FromStr for DnsSeed {
+    type Err = String;
+
+    /// Parse `srv:<name>` or `txt:<name>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("srv", name)) if !name.is_empty() => Ok(Self::Srv(name.to_string())),
+            Some(("txt", name)) if !name.is_empty() => Ok(Self::Txt(name.to_string())),
+            _ => Err(format!("invalid dns seed {s}, expected srv:<name> or txt:<name>")),
+        }
+    }
+}
+
+pub(crate) fn build_query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    buf.extend_from_slice(&id.to_be_bytes());
+    // standard query with recursion desired
+    buf.extend_from_slice(&0x0100u16.to_be_bytes());
+    buf.extend_from_slice(&1u16.to_be_bytes());
+    buf.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf.extend_from_slice(&qtype.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    buf
+}
+
+/// Read a possibly compressed name, returns it with the offset after the name in place
+fn read_name(msg: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = vec![];
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *msg.get(offset)? as usize;
+        if len == 0 {
+            end.get_or_insert(offset + 1);
+            break;
+        }
+        if len & 0xC0 == 0xC0 {
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return None;
+            }
+            end.get_or_insert(offset + 2);
+            offset = ((len & 0x3F) << 8) | *msg.get(offset + 1)? as usize;
+            continue;
+        }
+        labels.push(String::from_utf8_lossy(msg.get(offset + 1..offset + 1 + len)?).to_string());
+        offset += 1 + len;
+    }
+    Some((labels.join("."), end?))
+}
+
+/// Records of an answer which match the query type, TXT strings or SRV (port, target)
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Record {
+    Txt(Vec<String>),
+    Srv(u16, String),
+}
+
+pub(crate) fn parse_response(msg: &[u8], id: u16, qtype: u16) -> Option<Vec<Record>> {
+    if msg.len() < HEADER_LEN || u16::from_be_bytes([msg[0], msg[1]]) != id {
+        return None;
+    }
+    let flags = u16::from_be_bytes([msg[2], msg[3]]);
+    // must be a response without error code
+    if flags & 0x8000 == 0 || flags & 0x000F != 0 {
+        return None;
+    }
+    let questions = u16::from_be_bytes([msg[4], msg[5]]);
+    let answers = u16::from_be_bytes([msg[6], msg[7]]);
+    let mut offset = HEADER_LEN;
+    for _ in 0..questions {
+        offset = read_name(msg, offset)?.1 + 4;
+    }
+    let mut records = vec![];
+    for _ in 0..answers {
+        offset = read_name(msg, offset)?.1;
+        let header = msg.get(offset..offset + 10)?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let rdlen = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let rdata_offset = offset + 10;
+        let rdata = msg.get(rdata_offset..rdata_offset + rdlen)?;
+        offset = rdata_offset + rdlen;
+        if rtype != qtype {
+            continue;
+        }
+        match rtype {
+            TYPE_TXT => {
+                let mut strings = vec![];
+                let mut pos = 0;
+                while pos < rdata.len() {
+                    let len = rdata[pos] as usize;
+                    strings.push(String::from_utf8_lossy(rdata.get(pos + 1..pos + 1 + len)?).to_string());
+                    pos += 1 + len;
+                }
+                records.push(Record::Txt(strings));
+            }
+            TYPE_SRV => {
+                let port = u16::from_be_bytes([*rdata.get(4)?, *rdata.get(5)?]);
+                let (target, _) = read_name(msg, rdata_offset + 6)?;
+                records.push(Record::Srv(port, target));
+            }
+            _ => {}
+        }
+    }
+    Some(records)
+}
+
+fn query(nameserver: SocketAddr, name: &str, qtype: u16) -> io::Result<Vec<Record>> {
+    let socket = UdpSocket::bind(if nameserver.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    let id = thread_rng().gen();
+    socket.send_to(&build_query(id, name, qtype), nameserver)?;
+    let mut buf = [0u8; 4096];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf)?;
+        if from != nameserver {
+            continue;
+        }
+        if let Some(records) = parse_response(&buf[..len], id, qtype) {
+            return Ok(records);
+        }
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid dns response"));
+    }
+}
+
+/// Node addresses of a SRV target which is named by its node id
+fn srv_target_addr(port: u16, target: &str) -> Option<NodeAddr> {
+    let node: NodeId = target.split('.').next()?.parse().ok()?;
+    let mut builder = NodeAddrBuilder::new(node);
+    for addr in (target, port).to_socket_addrs().ok()? {
+        match addr {
+            SocketAddr::V4(addr) => builder.add_protocol(Protocol::Ip4(*addr.ip())),
+            SocketAddr::V6(addr) => builder.add_protocol(Protocol::Ip6(*addr.ip())),
+        }
+        builder.add_protocol(Protocol::Udp(port));
+    }
+    Some(builder.addr())
+}
+
+/// Resolve a seed list, records which can't be converted to node addresses are skipped
+pub fn resolve_dns_seed(nameserver: SocketAddr, seed: &DnsSeed) -> io::Result<Vec<NodeAddr>> {
+    let addrs = match seed {
+        DnsSeed::Txt(name) => query(nameserver, name, TYPE_TXT)?
+            .into_iter()
+            .flat_map(|record| match record {
+                Record::Txt(strings) => strings,
+                Record::Srv(..) => vec![],
+            })
+            .filter_map(|s| s.parse::<NodeAddr>().ok())
+            .collect(),
+        DnsSeed::Srv(name) => query(nameserver, name, TYPE_SRV)?
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Srv(port, target) => srv_target_addr(port, &target),
+                Record::Txt(_) => None,
+            })
+            .collect(),
+    };
+    Ok(addrs)
+}
+
+/// First nameserver of `/etc/resolv.conf`
+pub fn system_nameserver() -> io::Result<SocketAddr> {
+    let conf = std::fs::read_to_string("/etc/resolv.conf")?;
+    conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|ip| ip.trim().parse().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no nameserver in /etc/resolv.conf"))
+}
+
+/// [`SeedSource`] which resolves DNS seed lists in a background thread, the thread stops after the source is dropped
+pub struct DnsSeedSource {
+    seeds: Arc<Mutex<Vec<NodeAddr>>>,
+}
+
+impl DnsSeedSource {
+    pub fn spawn(nameserver: SocketAddr, names: Vec<DnsSeed>, interval: Duration) -> Self {
+        let seeds = Arc::new(Mutex::new(vec![]));
+        let weak: Weak<Mutex<Vec<NodeAddr>>> = Arc::downgrade(&seeds);
+        std::thread::Builder::new()
+            .name("dns-seed".to_string())
+            .spawn(move || loop {
+                let mut resolved = vec![];
+                let mut failed = 0;
+                for name in &names {
+                    match resolve_dns_seed(nameserver, name) {
+                        Ok(addrs) => resolved.extend(addrs),
+                        Err(e) => {
+                            failed += 1;
+                            log::warn!("[DnsSeedSource] resolve {:?} error {e}", name);
+                        }
+                    }
+                }
+                let Some(seeds) = weak.upgrade() else {
+                    break;
+                };
+                if failed < names.len() {
+                    log::info!("[DnsSeedSource] resolved {} seeds", resolved.len());
+                    *seeds.lock() = resolved;
+                }
+                drop(seeds);
+                std::thread::sleep(interval);
+            })
+            .expect("Should spawn dns seed thread");
+        Self { seeds }
+    }
+}
+
+impl SeedSource for DnsSeedSource {
+    fn seeds(&self) -> Vec<NodeAddr> {
+        self.seeds.lock().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_query, parse_response, DnsSeed, Record, TYPE_SRV, TYPE_TXT};
+
+    /// Answer of the query, the name of each record is a pointer to the question
+    fn response(query: &[u8], records: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut buf = query.to_vec();
+        buf[2] = 0x81;
+        buf[3] = 0x80;
+        buf[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+        for (rtype, rdata) in records {
+            buf.extend_from_slice(&[0xC0, 12]);
+            buf.extend_from_slice(&rtype.to_be_bytes());
+            buf.extend_from_slice(&1u16.to_be_bytes());
+            buf.extend_from_slice(&60u32.to_be_bytes());
+            buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            buf.extend_from_slice(rdata);
+        }
+        buf
+    }
+
+    #[test]
+    fn parse_txt_and_srv_answers() {
+        let query = build_query(7, "seeds.example.com", TYPE_TXT);
+        let txt = b"\x0bhello world\x03abc".to_vec();
+        assert_eq!(
+            parse_response(&response(&query, &[(TYPE_TXT, txt)]), 7, TYPE_TXT),
+            Some(vec![Record::Txt(vec!["hello world".into(), "abc".into()])])
+        );
+        assert_eq!(parse_response(&response(&query, &[]), 8, TYPE_TXT), None);
+
+        // target is "100." followed by a pointer to "example.com" of the question
+        let query = build_query(9, "_sdn._udp.example.com", TYPE_SRV);
+        let mut srv = vec![0, 1, 0, 1, 0x27, 0x10];
+        srv.extend_from_slice(b"\x03100\xC0\x16");
+        assert_eq!(
+            parse_response(&response(&query, &[(TYPE_SRV, srv)]), 9, TYPE_SRV),
+            Some(vec![Record::Srv(10000, "100.example.com".into())])
+        );
+    }
+
+    #[test]
+    fn parse_dns_seed() {
+        assert_eq!("srv:_sdn._udp.example.com".parse(), Ok(DnsSeed::Srv("_sdn._udp.example.com".into())));
+        assert_eq!("txt:seeds.example.com".parse(), Ok(DnsSeed::Txt("seeds.example.com".into())));
+        assert!("seeds.example.com".parse::<DnsSeed>().is_err());
+    }
+}
@@ -3,23 +3,45 @@ use std::{
     hash::Hash,
     marker::PhantomData,
     net::{IpAddr, SocketAddr},
+    path::Path,
     sync::Arc,
     time::Duration,
 };
 
 use atm0s_sdn_identity::{NodeAddr, NodeAddrBuilder, NodeId, Protocol};
 use atm0s_sdn_network::{
-    base::{Authorization, HandshakeBuilder, ServiceBuilder},
-    features::{FeaturesControl, FeaturesEvent},
+    base::{Authorization, Capabilities, CompressionConfig, HandshakeBuilder, LatencyProfile, LinkProfile, ServiceBuilder, ServiceId},
+    controller_plane::{CheckpointError, EventSink, NeighbourPolicy, StateCheckpoint},
+    data_plane::{fragment::FragmentConfig, multipath::MultipathPolicy, scheduler::SchedulerConfig, shaper::ShapingProfile},
+    features::{
+        dht_kv::{FileKvStorage, KvStorageBackend},
+        pubsub::ChannelAuthorizer,
+        FeaturesControl, FeaturesEvent,
+    },
     secure::{HandshakeBuilderXDA, StaticKeyAuthorization},
-    services::{manual_discovery, visualization},
+    services::{
+        bootstrap::{BootstrapServiceBuilder, FilePeerStore},
+        local_discovery::LocalDiscoveryServiceBuilder,
+        manual_discovery, visualization,
+    },
+    worker::ReplicationCfg,
 };
+use atm0s_sdn_router::core::RoutingPolicy;
 use rand::{thread_rng, RngCore};
 use sans_io_runtime::backend::Backend;
 use serde::{de::DeserializeOwned, Serialize};
 
+#[cfg(feature = "otlp")]
+use crate::otlp::{OtlpConfig, OtlpError};
 use crate::{
-    history::DataWorkerHistory,
+    bootstrap::{system_nameserver, BootstrapConfig, DnsSeedSource},
+    dscp::{DscpBackend, DscpClasses},
+    history::{DataWorkerHistory, HistoryConfig},
+    local_discovery::{LanAnnouncer, LocalDiscoveryConfig},
+    metrics::SdnMetrics,
+    session::SessionFile,
+    stun,
+    watchdog::WatchdogConfig,
     worker_inner::{ControllerCfg, SdnController, SdnExtIn, SdnInnerCfg, SdnOwner, SdnWorkerInner},
 };
 
@@ -29,10 +51,39 @@ pub struct SdnBuilder<UserData, SC, SE, TC, TW, NodeInfo> {
     node_addr: NodeAddr,
     node_id: NodeId,
     session: u64,
+    session_file: Option<Arc<SessionFile>>,
+    checkpoint: Option<StateCheckpoint>,
     bind_addrs: Vec<SocketAddr>,
     tick_ms: u64,
+    profile: LatencyProfile,
+    link: LinkProfile,
+    scheduler: Option<SchedulerConfig>,
+    bandwidth_limit_kbps: Option<u32>,
+    service_shaping: Vec<(ServiceId, ShapingProfile)>,
+    multipath: Option<MultipathPolicy>,
+    fragment: FragmentConfig,
+    broadcast_history: HistoryConfig,
+    incoming_route: bool,
+    dht_kv_storage: Option<Arc<dyn KvStorageBackend>>,
+    channel_authorizer: Option<Arc<dyn ChannelAuthorizer>>,
+    udp_reuse_port: bool,
+    dscp: DscpClasses,
+    observer: bool,
+    capabilities: Capabilities,
+    compression: Option<CompressionConfig>,
+    rekey_interval_ms: Option<u64>,
+    neighbour_policy: NeighbourPolicy,
+    routing_policy: RoutingPolicy,
+    event_sinks: Vec<Arc<dyn EventSink>>,
     visualization_collector: bool,
     seeds: Vec<NodeAddr>,
+    metrics: Arc<SdnMetrics>,
+    watchdog: Option<WatchdogConfig>,
+    replication: Option<ReplicationCfg>,
+    bootstrap: Option<BootstrapConfig>,
+    local_discovery: Option<LocalDiscoveryConfig>,
+    #[cfg(feature = "otlp")]
+    otlp: Option<OtlpConfig>,
     #[allow(clippy::type_complexity)]
     services: Vec<Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>,
     #[cfg(feature = "vpn")]
@@ -62,11 +113,40 @@ where
             handshake: None,
             node_addr,
             node_id,
-            tick_ms: 1000,
+            tick_ms: LatencyProfile::default().tick_ms(),
+            profile: LatencyProfile::default(),
+            link: LinkProfile::default(),
+            scheduler: None,
+            bandwidth_limit_kbps: None,
+            service_shaping: vec![],
+            multipath: None,
+            fragment: FragmentConfig::default(),
+            broadcast_history: HistoryConfig::default(),
+            incoming_route: false,
+            dht_kv_storage: None,
+            channel_authorizer: None,
+            udp_reuse_port: true,
+            dscp: DscpClasses::default(),
+            observer: false,
+            capabilities: Capabilities::SUPPORTED,
+            compression: None,
+            rekey_interval_ms: None,
+            neighbour_policy: NeighbourPolicy::default(),
+            routing_policy: RoutingPolicy::default(),
+            event_sinks: vec![],
             session: thread_rng().next_u64(),
+            session_file: None,
+            checkpoint: None,
             bind_addrs: bind_addrs.to_vec(),
             visualization_collector: false,
             seeds: vec![],
+            metrics: Arc::new(SdnMetrics::new(node_id)),
+            watchdog: None,
+            replication: None,
+            bootstrap: None,
+            local_discovery: None,
+            #[cfg(feature = "otlp")]
+            otlp: None,
             services: vec![],
             #[cfg(feature = "vpn")]
             vpn_enable: false,
@@ -86,6 +166,39 @@ where
         self.seeds.push(addr);
     }
 
+    /// Discover public addresses of the bind addresses with STUN servers and append them to the node address,
+    /// so nodes behind 1:1 NAT like cloud instances are reachable without custom addresses.
+    /// Servers are tried in order until one answers. The query runs immediately, so it should be called before the node address
+    /// is used, like in [`Self::set_manual_discovery`]. Return the appended addresses.
+    pub fn discover_public_addrs(&mut self, stun_servers: &[SocketAddr], timeout: Duration) -> Vec<SocketAddr> {
+        let mut known = node_addr_dests(&self.node_addr);
+        let mut discovered = vec![];
+        for bind in &self.bind_addrs {
+            if bind.port() == 0 {
+                log::warn!("Skip stun for bind addr {bind} without fixed port");
+                continue;
+            }
+            for server in stun_servers.iter().filter(|s| s.is_ipv4() == bind.is_ipv4()) {
+                match stun::discover_public_addr(*bind, *server, timeout) {
+                    Ok(addr) => {
+                        log::info!("Discovered public addr {addr} of {bind} with stun server {server}");
+                        if !known.contains(&addr) {
+                            known.push(addr);
+                            discovered.push(addr);
+                        }
+                        break;
+                    }
+                    Err(err) => log::warn!("Discover public addr of {bind} with stun server {server} error {err}"),
+                }
+            }
+        }
+        if !discovered.is_empty() {
+            self.node_addr = generate_node_addr(self.node_id, &[], known);
+            log::info!("Updated node addr {}", self.node_addr);
+        }
+        discovered
+    }
+
     /// Setting authorization
     pub fn set_authorization<A: Authorization + 'static>(&mut self, auth: A) {
         self.auth = Some(Arc::new(auth));
@@ -96,6 +209,204 @@ where
         self.handshake = Some(Arc::new(handshake));
     }
 
+    /// Setting latency profile, default is [`LatencyProfile::Balanced`].
+    /// [`LatencyProfile::BoundedLatency`] uses finer ticks, shorter sticky pubsub paths and smaller alias queues for sub-50ms
+    /// end-to-end latency, with the cost of more CPU and control traffic.
+    pub fn set_latency_profile(&mut self, profile: LatencyProfile) {
+        self.profile = profile;
+        self.tick_ms = profile.tick_ms();
+    }
+
+    /// Setting link profile, default is [`LinkProfile::Standard`].
+    /// With [`LinkProfile::Constrained`], connections of this node use small frames, header compression and repair of control traffic,
+    /// which is negotiated with each neighbour.
+    pub fn set_link_profile(&mut self, link: LinkProfile) {
+        self.link = link;
+    }
+
+    /// Setting bandwidth scheduler of connections, default is sending without limit.
+    /// Capacity of each connection is shared between features by weights, so bulk traffic like vpn cannot starve pubsub on the same link.
+    pub fn set_bandwidth_scheduler(&mut self, scheduler: SchedulerConfig) {
+        self.scheduler = Some(scheduler);
+    }
+
+    /// Cap throughput of each connection, default is sending without limit.
+    /// It overrides capacity of the bandwidth scheduler, or enables one with default weights. Neighbours keepalives are not limited.
+    pub fn set_bandwidth_limit_kbps(&mut self, limit_kbps: u32) {
+        self.bandwidth_limit_kbps = Some(limit_kbps);
+    }
+
+    /// Shape outgoing traffic of a service with a rate, burst and priority, default is not shaped.
+    /// Data which the service sends over features is queued when it is out of credit, then released by priority between services.
+    pub fn set_service_shaping(&mut self, service: ServiceId, profile: ShapingProfile) {
+        self.service_shaping.retain(|(s, _)| *s != service);
+        self.service_shaping.push((service, profile));
+    }
+
+    /// Select between many paths to same neighbour by a policy, default is using the path which is selected by router.
+    /// Paths are probed by neighbours pings, and a path which stops answering is skipped before its connection is timed out.
+    pub fn set_multipath_policy(&mut self, policy: MultipathPolicy) {
+        self.multipath = Some(policy);
+    }
+
+    /// Fragment messages which are larger than the mtu, default is [`FragmentConfig::default`] with mtu of 1400 bytes and max message of 64KB.
+    /// Only neighbours which support reassembly receive fragments, messages to older neighbours are sent as-is.
+    pub fn set_fragment_config(&mut self, cfg: FragmentConfig) {
+        self.fragment = cfg;
+    }
+
+    /// Setting dedup history of broadcast messages, default is [`HistoryConfig::default`] with timeout of 2 seconds and 10000 entries.
+    /// Relay nodes with high broadcast rates can raise the cap, evictions before timeout are counted in controller metrics.
+    pub fn set_broadcast_history(&mut self, cfg: HistoryConfig) {
+        self.broadcast_history = cfg;
+    }
+
+    /// Attach [`IncomingRoute`](atm0s_sdn_network::base::IncomingRoute) (ingress connection, hop count and relay info) to meta of messages which are received from the network.
+    /// It is disabled by default, then messages are dispatched without any extra work.
+    pub fn enable_incoming_route(&mut self) {
+        self.incoming_route = true;
+    }
+
+    /// Setting storage backend for dht_kv maps which this node serves, default is memory only.
+    /// Maps are restored from the backend when the node starts, so they survive restarts.
+    pub fn set_dht_kv_storage(&mut self, storage: Arc<dyn KvStorageBackend>) {
+        self.dht_kv_storage = Some(storage);
+    }
+
+    /// Setting authorizer of pubsub channels, which checks each Sub from neighbours with the requester node and its token.
+    /// Rejected subscribers receive [`ChannelEvent::SubRejected`](atm0s_sdn_network::features::pubsub::ChannelEvent::SubRejected), default is allowing all
+    pub fn set_channel_authorizer(&mut self, authorizer: Arc<dyn ChannelAuthorizer>) {
+        self.channel_authorizer = Some(authorizer);
+    }
+
+    /// Store dht_kv maps in a file with [`FileKvStorage`], the file is created if not exists
+    pub fn enable_dht_kv_file_storage<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()> {
+        self.dht_kv_storage = Some(Arc::new(FileKvStorage::open(path)?));
+        Ok(())
+    }
+
+    /// Persist the node session in a file with [`SessionFile`], so peers recognize a restart within `max_age` as the same node instance
+    /// and keep its dht_kv slots and pubsub relays. Returns true if the session of the previous run is restored.
+    /// For starting with a clean identity, delete the file with [`SessionFile::invalidate`] before calling this.
+    pub fn enable_session_file<P: AsRef<Path>>(&mut self, path: P, max_age: Duration) -> std::io::Result<bool> {
+        let file = SessionFile::open(path, self.node_id, max_age)?;
+        self.session = file.session();
+        let restored = file.restored();
+        self.session_file = Some(Arc::new(file));
+        Ok(restored)
+    }
+
+    /// Restore the state which is taken with [`crate::SdnControllerUtils::snapshot_state`] in a previous run of this node. The node
+    /// reuses the session of the checkpoint, connects to its last neighbours, and applies their last router syncs as soon as
+    /// they are connected again, so a fast restart doesn't wait for a full resync. Served dht_kv slots, alias roots and
+    /// service states are restored too, while actors still need to subscribe and register again
+    pub fn with_restored_state(&mut self, state: &[u8]) -> Result<(), CheckpointError> {
+        let checkpoint = StateCheckpoint::decode(self.node_id, state)?;
+        log::info!("Restore state of session {} with {} neighbours", checkpoint.session(), checkpoint.neighbours().len());
+        self.session = checkpoint.session();
+        for (node, addr) in checkpoint.neighbours() {
+            self.add_seed(generate_node_addr(*node, &[*addr], vec![]));
+        }
+        self.checkpoint = Some(checkpoint);
+        Ok(())
+    }
+
+    /// Handle for reading the latest controller and data plane metrics, which are refreshed every second after the node is built
+    pub fn metrics(&self) -> Arc<SdnMetrics> {
+        self.metrics.clone()
+    }
+
+    /// Alert with `SdnExtOut::WatchdogAlert` when the controller stops processing ticks, and optionally restart it
+    pub fn enable_watchdog(&mut self, cfg: WatchdogConfig) {
+        self.watchdog = Some(cfg);
+    }
+
+    /// Run a standby controller in worker 1, which takes over when the controller in worker 0 stops replicating its state.
+    /// Established connections and routes are kept, then `SdnExtOut::ControllerTakeover` is emitted. It needs at least 2 workers,
+    /// and the watchdog doesn't restart the controller while a standby is enabled.
+    pub fn enable_standby_controller(&mut self, cfg: ReplicationCfg) {
+        self.replication = Some(cfg);
+    }
+
+    /// Keep the node connected after startup: known-good peers are learned from outgoing connections, and when all connections
+    /// are lost the node rotates through them, the seeds and DNS seed lists with exponential backoff
+    pub fn enable_bootstrap(&mut self, cfg: BootstrapConfig) {
+        self.bootstrap = Some(cfg);
+    }
+
+    /// Announce this node with udp broadcast on the LAN and connect to announced peers which are allowed by the policy,
+    /// so lab setups on the same network don't need seeds
+    pub fn enable_local_discovery(&mut self, cfg: LocalDiscoveryConfig) {
+        self.local_discovery = Some(cfg);
+    }
+
+    /// Periodically push controller metrics to an OpenTelemetry collector, endpoint is like `http://localhost:4318`
+    #[cfg(feature = "otlp")]
+    pub fn enable_otlp_metrics(&mut self, endpoint: &str, interval: Duration) -> Result<(), OtlpError> {
+        self.otlp = Some(OtlpConfig::new(endpoint, interval)?);
+        Ok(())
+    }
+
+    /// Setting SO_REUSEPORT for udp sockets, default is true.
+    /// With SO_REUSEPORT each worker binds its own socket on the same address and the kernel shards incoming packets between them,
+    /// so it is always enabled when building with more than one worker.
+    pub fn set_udp_reuse_port(&mut self, value: bool) {
+        self.udp_reuse_port = value;
+    }
+
+    /// Mark packets of each traffic class with a DSCP code point for network QoS policies, default is best effort for all.
+    /// Every distinct code point needs its own udp socket on each address, so SO_REUSEPORT is enabled when there are more than one.
+    pub fn set_dscp(&mut self, classes: DscpClasses) {
+        self.dscp = classes;
+    }
+
+    /// Join the network as an observer, default is false.
+    /// Observer node receives router syncs, visualization data, pubsub and dht_kv events, but it is never selected as a relay,
+    /// a next hop or a dht server, so monitoring taps don't affect traffic of other nodes.
+    pub fn set_observer(&mut self, value: bool) {
+        self.observer = value;
+    }
+
+    /// Capabilities which are advertised to neighbours, default is [`Capabilities::SUPPORTED`].
+    /// During a rolling upgrade, upgraded nodes can keep advertising the old set until all nodes are upgraded,
+    /// neighbours with a different version or capability set are reported with `SdnExtOut::CapabilitySkew`.
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    /// Compress big payloads of dht_kv and visualization snapshots, default is sending them raw.
+    /// Payloads are compressed only for destinations which can decode them and only when it saves bytes, ratios are reported
+    /// in `ControllerMetrics::compression`.
+    pub fn set_payload_compression(&mut self, cfg: CompressionConfig) {
+        self.compression = Some(cfg);
+    }
+
+    /// Rekey connections with neighbours after each interval, default is keeping the keys of connect handshake.
+    /// Old keys are still accepted for a short grace period, so traffic is not dropped while rotating. Only neighbours which
+    /// advertise [`Capabilities::REKEY`] are rekeyed.
+    pub fn set_rekey_interval_ms(&mut self, interval_ms: u64) {
+        self.rekey_interval_ms = Some(interval_ms);
+    }
+
+    /// Limits of connected neighbours, default is no limit. When a limit is hit, new nodes are rejected unless they are
+    /// preferred, then the connected node with the highest rtt is evicted. Restarted connections are not checked.
+    pub fn set_neighbour_policy(&mut self, policy: NeighbourPolicy) {
+        self.neighbour_policy = policy;
+    }
+
+    /// Weights of latency, hop count and bandwidth in path score, and bandwidth which this node advertises to neighbours,
+    /// default is [`RoutingPolicy::default`]. Weights are only used for local path selection, so nodes can use different
+    /// policies, but advertised bandwidth limits the paths over this node for all of them.
+    pub fn set_routing_policy(&mut self, policy: RoutingPolicy) {
+        self.routing_policy = policy;
+    }
+
+    /// Stream entries of the controller event journal to a sink, like [`atm0s_sdn_network::controller_plane::FileEventSink`]
+    /// or [`atm0s_sdn_network::controller_plane::SyslogEventSink`]. Entries are always kept in memory for `ExtIn::ControlQuery`
+    pub fn add_event_sink(&mut self, sink: Arc<dyn EventSink>) {
+        self.event_sinks.push(sink);
+    }
+
     /// Setting visualization collector mode
     pub fn set_visualization_collector(&mut self, value: bool) {
         self.visualization_collector = value;
@@ -129,14 +440,25 @@ where
         self.vpn_netmask = Some(netmask);
     }
 
+    /// Build the node with `workers` workers, which run with backend `B`.
+    /// Backends which don't mark udp sockets with the DSCP code points of [`Self::set_dscp`] themselves are wrapped with [`DscpBackend`].
     pub fn build<B: Backend<SdnOwner>>(mut self, workers: usize, info: NodeInfo) -> SdnController<UserData, SC, SE, TC, TW> {
         assert!(workers > 0);
+        if !self.udp_reuse_port && workers > 1 {
+            log::warn!("[SdnBuilder] SO_REUSEPORT is required for sharding udp receive across {workers} workers, enable it");
+            self.udp_reuse_port = true;
+        }
+        if !self.udp_reuse_port && self.dscp.code_points().len() > 1 {
+            log::warn!("[SdnBuilder] SO_REUSEPORT is required for binding a socket per DSCP code point, enable it");
+            self.udp_reuse_port = true;
+        }
+        assert!(self.replication.is_none() || workers > 1, "standby controller needs at least 2 workers");
         #[cfg(feature = "vpn")]
         let (tun_device, mut queue_fds) = {
             if self.vpn_enable {
-                let vpn_ip = self.vpn_ip.unwrap_or((10, 33, 33, self.node_id as u8));
-                let vpn_netmask = self.vpn_netmask.unwrap_or((255, 255, 255, 0));
-                let mut tun_device = sans_io_runtime::backend::tun::create_tun(&format!("utun{}", self.node_id as u8), vpn_ip, vpn_netmask, 1400, workers);
+                let (a, b, c, d) = self.vpn_ip.unwrap_or((10, 33, 33, self.node_id as u8));
+                let (m0, m1, m2, m3) = self.vpn_netmask.unwrap_or((255, 255, 255, 0));
+                let mut tun_device = crate::tun::create(self.node_id, std::net::Ipv4Addr::new(a, b, c, d), std::net::Ipv4Addr::new(m0, m1, m2, m3), workers);
                 let mut queue_fds = std::collections::VecDeque::with_capacity(workers);
                 for i in 0..workers {
                     queue_fds.push_back(tun_device.get_queue_fd(i).expect("Should have tun queue fd"));
@@ -147,28 +469,83 @@ where
             }
         };
 
-        self.add_service(Arc::new(visualization::VisualizationServiceBuilder::<UserData, SC, SE, TC, TW, NodeInfo>::new(
-            info,
-            self.visualization_collector,
-        )));
+        self.add_service(Arc::new(
+            visualization::VisualizationServiceBuilder::<UserData, SC, SE, TC, TW, NodeInfo>::new(info, self.visualization_collector).with_compression(self.compression.clone()),
+        ));
 
-        let history = Arc::new(DataWorkerHistory::default());
+        if let Some(cfg) = self.bootstrap.take() {
+            let mut bootstrap = BootstrapServiceBuilder::new(self.node_id, self.seeds.clone());
+            if let Some(path) = cfg.peers_file {
+                bootstrap = bootstrap.with_store(Arc::new(FilePeerStore::new(path)));
+            }
+            if !cfg.dns_seeds.is_empty() {
+                match cfg.nameserver.map(Ok).unwrap_or_else(system_nameserver) {
+                    Ok(nameserver) => bootstrap = bootstrap.with_source(Arc::new(DnsSeedSource::spawn(nameserver, cfg.dns_seeds, cfg.dns_interval))),
+                    Err(e) => log::error!("[SdnBuilder] DNS seeds are disabled, no nameserver: {e}"),
+                }
+            }
+            self.add_service(Arc::new(bootstrap));
+        }
+
+        if let Some(cfg) = self.local_discovery.take() {
+            match LanAnnouncer::spawn(self.node_addr.clone(), cfg.port, cfg.interval) {
+                Ok(announcer) => self.add_service(Arc::new(LocalDiscoveryServiceBuilder::new(self.node_id, Arc::new(announcer), cfg.policy))),
+                Err(e) => log::error!("[SdnBuilder] local discovery is disabled, bind port {} error {e}", cfg.port),
+            }
+        }
+
+        let history = Arc::new(DataWorkerHistory::new(self.broadcast_history));
+
+        let auth = self.auth.unwrap_or_else(|| Arc::new(StaticKeyAuthorization::new("unsecure")));
+        let handshake = self.handshake.unwrap_or_else(|| Arc::new(HandshakeBuilderXDA));
+        let checkpoint = self.checkpoint.take();
+        let controller_cfg = |session_file| ControllerCfg {
+            session: self.session,
+            session_file,
+            auth: auth.clone(),
+            handshake: handshake.clone(),
+            profile: self.profile,
+            link: self.link,
+            dht_kv_storage: self.dht_kv_storage.clone(),
+            channel_authorizer: self.channel_authorizer.clone(),
+            observer: self.observer,
+            capabilities: self.capabilities,
+            compression: self.compression.clone(),
+            rekey_interval_ms: self.rekey_interval_ms,
+            neighbour_policy: self.neighbour_policy.clone(),
+            routing_policy: self.routing_policy,
+            checkpoint: None,
+            event_sinks: self.event_sinks.clone(),
+            #[cfg(feature = "vpn")]
+            vpn_tun_device: None,
+        };
 
         let mut controller = SdnController::default();
-        controller.add_worker::<SdnOwner, _, SdnWorkerInner<UserData, SC, SE, TC, TW>, B>(
-            Duration::from_millis(1000),
+        controller.add_worker::<SdnOwner, _, SdnWorkerInner<UserData, SC, SE, TC, TW>, DscpBackend<B, SdnOwner>>(
+            Duration::from_millis(self.tick_ms),
             SdnInnerCfg {
                 node_id: self.node_id,
                 tick_ms: self.tick_ms,
                 bind_addrs: self.bind_addrs.to_vec(),
+                udp_reuse_port: self.udp_reuse_port,
+                dscp: self.dscp,
                 services: self.services.clone(),
                 history: history.clone(),
+                scheduler: self.scheduler.clone(),
+                bandwidth_limit_kbps: self.bandwidth_limit_kbps,
+                service_shaping: self.service_shaping.clone(),
+                multipath: self.multipath,
+                fragment: self.fragment,
+                incoming_route: self.incoming_route,
+                metrics: self.metrics.clone(),
+                watchdog: self.watchdog,
+                replication: self.replication,
+                standby: false,
                 controller: Some(ControllerCfg {
-                    session: self.session,
-                    auth: self.auth.unwrap_or_else(|| Arc::new(StaticKeyAuthorization::new("unsecure"))),
-                    handshake: self.handshake.unwrap_or_else(|| Arc::new(HandshakeBuilderXDA)),
+                    checkpoint,
                     #[cfg(feature = "vpn")]
                     vpn_tun_device: tun_device,
+                    ..controller_cfg(self.session_file.clone())
                 }),
                 #[cfg(feature = "vpn")]
                 vpn_tun_fd: queue_fds.pop_front(),
@@ -176,16 +553,29 @@ where
             None,
         );
 
-        for _ in 1..workers {
-            controller.add_worker::<SdnOwner, _, SdnWorkerInner<UserData, SC, SE, TC, TW>, B>(
-                Duration::from_millis(1000),
+        for worker in 1..workers {
+            let standby = worker == 1 && self.replication.is_some();
+            controller.add_worker::<SdnOwner, _, SdnWorkerInner<UserData, SC, SE, TC, TW>, DscpBackend<B, SdnOwner>>(
+                Duration::from_millis(self.tick_ms),
                 SdnInnerCfg {
                     node_id: self.node_id,
                     tick_ms: self.tick_ms,
                     bind_addrs: self.bind_addrs.to_vec(),
+                    udp_reuse_port: self.udp_reuse_port,
+                    dscp: self.dscp,
                     services: self.services.clone(),
                     history: history.clone(),
-                    controller: None,
+                    scheduler: self.scheduler.clone(),
+                    bandwidth_limit_kbps: self.bandwidth_limit_kbps,
+                    service_shaping: self.service_shaping.clone(),
+                    multipath: self.multipath,
+                    fragment: self.fragment,
+                    incoming_route: self.incoming_route,
+                    metrics: self.metrics.clone(),
+                    watchdog: None,
+                    replication: self.replication.filter(|_| standby),
+                    standby,
+                    controller: standby.then(|| controller_cfg(None)),
                     #[cfg(feature = "vpn")]
                     vpn_tun_fd: queue_fds.pop_front(),
                 },
@@ -193,6 +583,11 @@ where
             );
         }
 
+        #[cfg(feature = "otlp")]
+        if let Some(otlp) = self.otlp {
+            crate::otlp::spawn_exporter(otlp, &self.metrics);
+        }
+
         std::thread::sleep(std::time::Duration::from_millis(100));
 
         for seed in self.seeds {
@@ -203,6 +598,21 @@ where
     }
 }
 
+/// Socket addresses in a node address, in order
+fn node_addr_dests(addr: &NodeAddr) -> Vec<SocketAddr> {
+    let mut dests = vec![];
+    let mut ip = None;
+    for part in addr.multiaddr().iter() {
+        match part {
+            Protocol::Ip4(i) => ip = Some(IpAddr::V4(i)),
+            Protocol::Ip6(i) => ip = Some(IpAddr::V6(i)),
+            Protocol::Udp(port) => dests.extend(ip.map(|ip| SocketAddr::new(ip, port))),
+            _ => {}
+        }
+    }
+    dests
+}
+
 pub fn generate_node_addr(node_id: u32, bind_addrs: &[SocketAddr], custom_ips: Vec<SocketAddr>) -> NodeAddr {
     let mut addr_builder = NodeAddrBuilder::new(node_id);
     for bind_addr in bind_addrs {
@@ -0,0 +1,347 @@
+//! DSCP marking of udp sockets for network QoS policies.
+//!
+//! Traffic classes of [`MsgPriority`] are mapped to DSCP code points with [`DscpClasses`], which is set at runtime with
+//! [`crate::SdnBuilder::set_dscp`]. Workers bind one udp socket per distinct code point on every address with SO_REUSEPORT,
+//! and send packets of a class from the socket which is marked with its code point, so the marking is set once with IP_TOS or
+//! IPV6_TCLASS instead of per packet.
+//!
+//! `UdpListen` actions don't carry a code point and the runtime creates backends with [`Default`], so workers queue the code
+//! point of each listen in a thread local before the action reaches the backend in the same worker thread.
+//! [`crate::BatchBackend`] takes it when creating the socket. Other backends, like the poll backends of the runtime, are wrapped
+//! with [`DscpBackend`] by the builder, which finds the udp socket that the inner backend opened for the listen by its bound
+//! address and marks it. Finding sockets is only supported on unix.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+use atm0s_sdn_network::base::MsgPriority;
+use sans_io_runtime::backend::{Awaker, Backend, BackendIncoming, BackendIncomingInternal, BackendOutgoing, BackendOwner};
+use socket2::SockRef;
+
+/// DSCP code points of common QoS classes (RFC 4594), for [`DscpClasses`]
+pub const DSCP_BEST_EFFORT: u8 = 0;
+/// Low priority data like backups, which yields to best effort traffic
+pub const DSCP_CS1: u8 = 8;
+/// Multimedia streaming
+pub const DSCP_AF31: u8 = 26;
+/// Multimedia conferencing
+pub const DSCP_AF41: u8 = 34;
+/// Expedited forwarding for realtime traffic like voice
+pub const DSCP_EF: u8 = 46;
+
+/// DSCP code points of traffic classes, only the lower 6 bits are used. The default keeps all classes best effort
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DscpClasses {
+    /// Neighbours control packets
+    pub control: u8,
+    pub high: u8,
+    /// Also used for relayed and raw packets, and for every class whose socket cannot be bound
+    pub normal: u8,
+    pub bulk: u8,
+}
+
+impl DscpClasses {
+    /// Same code point for all classes, it only needs one socket per address
+    pub fn uniform(dscp: u8) -> Self {
+        Self {
+            control: dscp,
+            high: dscp,
+            normal: dscp,
+            bulk: dscp,
+        }
+    }
+
+    pub fn get(&self, priority: MsgPriority) -> u8 {
+        match priority {
+            MsgPriority::Control => self.control,
+            MsgPriority::High => self.high,
+            MsgPriority::Normal => self.normal,
+            MsgPriority::Bulk => self.bulk,
+        }
+    }
+
+    /// Distinct code points which need a socket, the normal one is first
+    pub fn code_points(&self) -> Vec<u8> {
+        let mut points = vec![self.normal];
+        for point in [self.control, self.high, self.bulk] {
+            if !points.contains(&point) {
+                points.push(point);
+            }
+        }
+        points
+    }
+}
+
+thread_local! {
+    /// Code points of udp listens which are queued by the worker of this thread, by bind address
+    static PENDING: RefCell<HashMap<SocketAddr, VecDeque<u8>>> = RefCell::new(HashMap::new());
+}
+
+/// Queue the code point of a udp listen, which is taken by the backend when the listen is handled
+pub(crate) fn request(addr: SocketAddr, dscp: u8) {
+    PENDING.with(|pending| pending.borrow_mut().entry(addr).or_default().push_back(dscp));
+}
+
+/// Take the code point of a udp listen, None if the worker didn't request one
+pub(crate) fn take(addr: SocketAddr) -> Option<u8> {
+    PENDING.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        let queue = pending.get_mut(&addr)?;
+        let dscp = queue.pop_front();
+        if queue.is_empty() {
+            pending.remove(&addr);
+        }
+        dscp
+    })
+}
+
+fn pending(addr: SocketAddr) -> usize {
+    PENDING.with(|pending| pending.borrow().get(&addr).map_or(0, |queue| queue.len()))
+}
+
+/// Mark a socket with a code point, which is the upper 6 bits of the tos and traffic class fields
+pub(crate) fn mark(socket: SockRef<'_>, addr: &SocketAddr, dscp: u8) -> io::Result<()> {
+    let tos = ((dscp & 0x3f) as u32) << 2;
+    match addr {
+        SocketAddr::V4(_) => socket.set_tos(tos),
+        SocketAddr::V6(_) => sys::set_tclass_v6(socket, tos),
+    }
+}
+
+/// Backend wrapper which marks udp sockets of a backend that doesn't mark them itself
+pub struct DscpBackend<B, Owner> {
+    inner: B,
+    /// Events which are popped from the inner backend while looking for a listen result
+    incoming: VecDeque<BackendIncomingInternal<Owner>>,
+}
+
+impl<B: Default, Owner> Default for DscpBackend<B, Owner> {
+    fn default() -> Self {
+        Self {
+            inner: B::default(),
+            incoming: VecDeque::new(),
+        }
+    }
+}
+
+impl<Owner, B: Backend<Owner>> Backend<Owner> for DscpBackend<B, Owner> {
+    fn create_awaker(&self) -> Arc<dyn Awaker> {
+        self.inner.create_awaker()
+    }
+
+    fn poll_incoming(&mut self, timeout: Duration) {
+        self.inner.poll_incoming(timeout)
+    }
+
+    fn pop_incoming(&mut self) -> Option<BackendIncomingInternal<Owner>> {
+        self.incoming.pop_front().or_else(|| self.inner.pop_incoming())
+    }
+
+    fn finish_outgoing_cycle(&mut self) {
+        self.inner.finish_outgoing_cycle()
+    }
+
+    fn finish_incoming_cycle(&mut self) {
+        self.inner.finish_incoming_cycle()
+    }
+}
+
+impl<Owner, B: Backend<Owner>> BackendOwner<Owner> for DscpBackend<B, Owner> {
+    fn on_action(&mut self, owner: Owner, action: BackendOutgoing) {
+        let addr = match &action {
+            BackendOutgoing::UdpListen { addr, .. } if pending(*addr) > 0 => *addr,
+            _ => return self.inner.on_action(owner, action),
+        };
+        // sockets which are opened by other wrapped workers meanwhile would share the address with SO_REUSEPORT
+        let _guard = sys::LISTEN_LOCK.lock();
+        let before = sys::udp_sockets();
+        let queued = pending(addr);
+        self.inner.on_action(owner, action);
+        if pending(addr) < queued {
+            // the inner backend took the code point and marked the socket
+            return;
+        }
+        let dscp = take(addr).unwrap_or(0);
+        // backends return queued results before reading sockets, so the result is found before any packet of the new socket
+        let mut local = None;
+        while let Some(event) = self.inner.pop_incoming() {
+            if let BackendIncomingInternal::Event(_, BackendIncoming::UdpListenResult { bind, result }) = &event {
+                if *bind == addr {
+                    local = result.as_ref().ok().map(|(local, _)| *local);
+                    self.incoming.push_back(event);
+                    break;
+                }
+            }
+            self.incoming.push_back(event);
+        }
+        let local = match local {
+            Some(local) if dscp != 0 => local,
+            _ => return,
+        };
+        for fd in sys::udp_sockets().difference(&before) {
+            if let Err(e) = sys::mark_if_bound(*fd, &local, dscp) {
+                log::error!("DscpBackend mark socket of {local} error {e}");
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::{
+        collections::HashSet,
+        io,
+        net::SocketAddr,
+        os::fd::{BorrowedFd, RawFd},
+    };
+
+    use parking_lot::Mutex;
+    use socket2::{SockRef, Type};
+
+    /// Serializes listens of wrapped backends across workers
+    pub static LISTEN_LOCK: Mutex<()> = Mutex::new(());
+
+    #[cfg(target_os = "linux")]
+    pub fn set_tclass_v6(socket: SockRef<'_>, tclass: u32) -> io::Result<()> {
+        socket.set_tclass_v6(tclass)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn set_tclass_v6(_socket: SockRef<'_>, _tclass: u32) -> io::Result<()> {
+        log::warn!("DSCP marking of ipv6 sockets is only supported on Linux");
+        Ok(())
+    }
+
+    /// Open udp sockets of the process
+    pub fn udp_sockets() -> HashSet<RawFd> {
+        let entries = match std::fs::read_dir("/dev/fd") {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::error!("DscpBackend list fds error {e}");
+                return HashSet::new();
+            }
+        };
+        entries
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<RawFd>().ok())
+            .filter(|fd| {
+                // SAFETY: the fd is only borrowed for queries, which fail with EBADF if it is closed meanwhile
+                let fd = unsafe { BorrowedFd::borrow_raw(*fd) };
+                SockRef::from(&fd).r#type().map_or(false, |t| t == Type::DGRAM)
+            })
+            .collect()
+    }
+
+    /// Mark the socket if it is bound to the local address of the listen
+    pub fn mark_if_bound(fd: RawFd, addr: &SocketAddr, dscp: u8) -> io::Result<()> {
+        // SAFETY: same as above, the fd is only borrowed while the listen lock is held
+        let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+        let socket = SockRef::from(&fd);
+        if socket.local_addr()?.as_socket().as_ref() != Some(addr) {
+            return Ok(());
+        }
+        super::mark(socket, addr, dscp)
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::{collections::HashSet, io, net::SocketAddr};
+
+    use parking_lot::Mutex;
+    use socket2::SockRef;
+
+    pub static LISTEN_LOCK: Mutex<()> = Mutex::new(());
+
+    pub fn set_tclass_v6(_socket: SockRef<'_>, _tclass: u32) -> io::Result<()> {
+        log::warn!("DSCP marking of ipv6 sockets is only supported on Linux");
+        Ok(())
+    }
+
+    pub fn udp_sockets() -> HashSet<i32> {
+        HashSet::new()
+    }
+
+    pub fn mark_if_bound(_fd: i32, addr: &SocketAddr, _dscp: u8) -> io::Result<()> {
+        log::warn!("DscpBackend cannot find the socket of {addr} on this platform");
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{SocketAddr, UdpSocket},
+        os::fd::BorrowedFd,
+    };
+
+    use atm0s_sdn_network::base::MsgPriority;
+    use sans_io_runtime::{
+        backend::{BackendIncoming, BackendIncomingInternal, BackendOutgoing, BackendOwner, PollBackend},
+        group_owner_type,
+    };
+    use socket2::SockRef;
+
+    use super::{DscpBackend, DscpClasses, DSCP_AF41, DSCP_CS1, DSCP_EF};
+
+    group_owner_type!(TestOwner);
+
+    #[test]
+    fn classes_share_sockets_of_same_code_point() {
+        assert_eq!(DscpClasses::default().code_points(), vec![0]);
+        let classes = DscpClasses {
+            control: DSCP_EF,
+            high: DSCP_EF,
+            normal: DSCP_AF41,
+            bulk: DSCP_CS1,
+        };
+        assert_eq!(classes.code_points(), vec![DSCP_AF41, DSCP_EF, DSCP_CS1]);
+        assert_eq!(classes.get(MsgPriority::High), DSCP_EF);
+        assert_eq!(classes.get(MsgPriority::Bulk), DSCP_CS1);
+    }
+
+    #[test]
+    fn wrapped_backend_sockets_are_marked() {
+        let mut backend = DscpBackend::<PollBackend<TestOwner, 8, 8>, TestOwner>::default();
+        let listen = |backend: &mut DscpBackend<PollBackend<TestOwner, 8, 8>, TestOwner>, dscp: Option<u8>| {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+            if let Some(dscp) = dscp {
+                super::request(addr, dscp);
+            }
+            backend.on_action(TestOwner(1), BackendOutgoing::UdpListen { addr, reuse: false });
+            match sans_io_runtime::backend::Backend::pop_incoming(backend) {
+                Some(BackendIncomingInternal::Event(_, BackendIncoming::UdpListenResult { result, .. })) => result.expect("Should bind"),
+                _ => panic!("Expected UdpListenResult"),
+            }
+        };
+        let tos_of = |addr: SocketAddr| {
+            super::sys::udp_sockets()
+                .into_iter()
+                .find_map(|fd| {
+                    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+                    let socket = SockRef::from(&fd);
+                    (socket.local_addr().ok()?.as_socket()? == addr).then(|| socket.tos().expect("Should get tos"))
+                })
+                .expect("Should find socket")
+        };
+
+        // a socket of this test is open while listening, it must not be marked
+        let other = UdpSocket::bind("127.0.0.1:0").expect("Should bind");
+        let (marked, slot1) = listen(&mut backend, Some(DSCP_EF));
+        assert_eq!(tos_of(marked), (DSCP_EF as u32) << 2);
+        assert_eq!(SockRef::from(&other).tos().expect("Should get tos"), 0);
+
+        let (plain, slot2) = listen(&mut backend, None);
+        assert_eq!(tos_of(plain), 0);
+        assert_eq!(super::take(SocketAddr::from(([127, 0, 0, 1], 0))), None);
+
+        backend.on_action(TestOwner(1), BackendOutgoing::UdpUnlisten { slot: slot1 });
+        backend.on_action(TestOwner(1), BackendOutgoing::UdpUnlisten { slot: slot2 });
+    }
+}
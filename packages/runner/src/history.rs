@@ -1,64 +1,171 @@
+//! Dedup history of broadcast messages, which is shared by the controller and all data plane workers of a node.
+//!
+//! Entries are kept in a time wheel of slots, each slot holds the messages which are received in `timeout_ms / slots`
+//! milliseconds and the oldest slot is dropped when the wheel is turned, so expiring is cheap even at high broadcast rates.
+//! The number of entries is capped, when it is reached the oldest slots are evicted before their timeout. Hashes of
+//! evicted entries are remembered in a fixed size table, for estimating duplicates which are accepted again because of it.
+
 use std::{
-    collections::{HashMap, VecDeque},
-    sync::atomic::AtomicU64,
+    collections::{hash_map::DefaultHasher, HashSet, VecDeque},
+    hash::{Hash, Hasher},
 };
 
 use atm0s_sdn_identity::NodeId;
-use atm0s_sdn_router::shadow::ShadowRouterHistory;
+use atm0s_sdn_router::shadow::{ShadowRouterHistory, ShadowRouterHistoryStats};
 use parking_lot::Mutex;
 
 const HISTORY_TIMEOUT_MS: u64 = 2000;
+const HISTORY_SLOTS: usize = 8;
+const HISTORY_MAX_ENTRIES: usize = 10000;
+const EVICTED_FINGERPRINTS: usize = 4096;
 
-#[derive(Debug, Default)]
-pub struct DataWorkerHistory {
-    now_ms: AtomicU64,
-    #[allow(clippy::type_complexity)]
-    queue: Mutex<VecDeque<(u64, (Option<NodeId>, u8, u16))>>,
-    #[allow(clippy::type_complexity)]
-    map: Mutex<HashMap<(Option<NodeId>, u8, u16), bool>>,
+type HistoryKey = (Option<NodeId>, u8, u16);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryConfig {
+    /// Entries are removed after between `timeout_ms - timeout_ms / slots` and `timeout_ms`
+    pub timeout_ms: u64,
+    pub slots: usize,
+    /// Hard cap of remembered messages, oldest slots are evicted when it is reached
+    pub max_entries: usize,
 }
 
-impl ShadowRouterHistory for DataWorkerHistory {
-    fn already_received_broadcast(&self, from: Option<NodeId>, service: u8, seq: u16) -> bool {
-        let mut map = self.map.lock();
-        let mut queue = self.queue.lock();
-        let now_ms = self.now_ms.load(std::sync::atomic::Ordering::Relaxed);
-        if map.contains_key(&(from, service, seq)) {
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: HISTORY_TIMEOUT_MS,
+            slots: HISTORY_SLOTS,
+            max_entries: HISTORY_MAX_ENTRIES,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TimeWheel {
+    cfg: HistoryConfig,
+    slot_ms: u64,
+    /// Index of the newest slot, which is counted from time 0
+    current: u64,
+    /// From the oldest slot to the newest one
+    slots: VecDeque<HashSet<HistoryKey>>,
+    evicted: Vec<u32>,
+    stats: ShadowRouterHistoryStats,
+}
+
+impl TimeWheel {
+    fn new(cfg: HistoryConfig) -> Self {
+        let slots = cfg.slots.max(1);
+        Self {
+            cfg: HistoryConfig { slots, ..cfg },
+            slot_ms: (cfg.timeout_ms / slots as u64).max(1),
+            current: 0,
+            slots: VecDeque::from([HashSet::new()]),
+            evicted: vec![0; EVICTED_FINGERPRINTS],
+            stats: ShadowRouterHistoryStats::default(),
+        }
+    }
+
+    fn fingerprint(key: &HistoryKey) -> (usize, u32) {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        // 0 is reserved for empty places
+        ((hash as usize) % EVICTED_FINGERPRINTS, (hash >> 32) as u32 | 1)
+    }
+
+    fn check_and_insert(&mut self, key: HistoryKey) -> bool {
+        if self.slots.iter().any(|slot| slot.contains(&key)) {
             return true;
         }
 
-        map.insert((from, service, seq), true);
-        queue.push_back((now_ms, (from, service, seq)));
-        if queue.len() > 10000 {
-            let (_ts, pair) = queue.pop_front().expect("queue should not empty");
-            map.remove(&pair);
+        let (index, fingerprint) = Self::fingerprint(&key);
+        if self.evicted[index] == fingerprint {
+            self.evicted[index] = 0;
+            self.stats.false_negatives += 1;
         }
+
+        while self.stats.entries >= self.cfg.max_entries.max(1) {
+            // newest slot is only evicted when it alone reaches the cap
+            let mut slot = if self.slots.len() > 1 {
+                self.slots.pop_front().expect("should have slot")
+            } else {
+                std::mem::take(self.slots.back_mut().expect("should have slot"))
+            };
+            self.stats.entries -= slot.len();
+            self.stats.evicted += slot.len() as u64;
+            for key in slot.drain() {
+                let (index, fingerprint) = Self::fingerprint(&key);
+                self.evicted[index] = fingerprint;
+            }
+        }
+
+        self.slots.back_mut().expect("should have slot").insert(key);
+        self.stats.entries += 1;
         false
     }
 
-    fn set_ts(&self, now_ms: u64) {
-        self.now_ms.store(now_ms, std::sync::atomic::Ordering::Relaxed);
-        let mut map = self.map.lock();
-        let mut queue = self.queue.lock();
-
-        while let Some((time, key)) = queue.front() {
-            if now_ms >= *time + HISTORY_TIMEOUT_MS {
-                map.remove(key);
-                queue.pop_front();
+    fn turn(&mut self, now_ms: u64) {
+        let current = now_ms / self.slot_ms;
+        if current <= self.current {
+            return;
+        }
+        // a long jump expires all slots, so only the last turns are needed
+        let turns = (current - self.current).min(self.cfg.slots as u64);
+        self.current = current;
+        for _ in 0..turns {
+            let mut slot = if self.slots.len() >= self.cfg.slots {
+                self.slots.pop_front().expect("should have slot")
             } else {
-                break;
-            }
+                HashSet::new()
+            };
+            self.stats.entries -= slot.len();
+            self.stats.expired += slot.len() as u64;
+            slot.clear();
+            self.slots.push_back(slot);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DataWorkerHistory {
+    wheel: Mutex<TimeWheel>,
+}
+
+impl DataWorkerHistory {
+    pub fn new(cfg: HistoryConfig) -> Self {
+        Self {
+            wheel: Mutex::new(TimeWheel::new(cfg)),
         }
     }
 }
 
+impl Default for DataWorkerHistory {
+    fn default() -> Self {
+        Self::new(HistoryConfig::default())
+    }
+}
+
+impl ShadowRouterHistory for DataWorkerHistory {
+    fn already_received_broadcast(&self, from: Option<NodeId>, service: u8, seq: u16) -> bool {
+        self.wheel.lock().check_and_insert((from, service, seq))
+    }
+
+    fn set_ts(&self, now_ms: u64) {
+        self.wheel.lock().turn(now_ms);
+    }
+
+    fn stats(&self) -> ShadowRouterHistoryStats {
+        self.wheel.lock().stats.clone()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use atm0s_sdn_router::shadow::ShadowRouterHistory;
+    use atm0s_sdn_router::shadow::{ShadowRouterHistory, ShadowRouterHistoryStats};
 
     use crate::history::HISTORY_TIMEOUT_MS;
 
-    use super::DataWorkerHistory;
+    use super::{DataWorkerHistory, HistoryConfig};
 
     #[test]
     fn simple_work() {
@@ -71,4 +178,57 @@ mod tests {
         history.set_ts(HISTORY_TIMEOUT_MS);
         assert_eq!(history.already_received_broadcast(Some(1), 1, 1), false);
     }
+
+    #[test]
+    fn expire_by_slots() {
+        let history = DataWorkerHistory::new(HistoryConfig {
+            timeout_ms: 1000,
+            slots: 4,
+            max_entries: 100,
+        });
+        assert_eq!(history.already_received_broadcast(Some(1), 1, 1), false);
+        history.set_ts(500);
+        assert_eq!(history.already_received_broadcast(Some(1), 1, 2), false);
+
+        //first entry is expired with its slot, second one is still remembered
+        history.set_ts(1000);
+        assert_eq!(history.already_received_broadcast(Some(1), 1, 2), true);
+        assert_eq!(history.already_received_broadcast(Some(1), 1, 1), false);
+        assert_eq!(history.stats().expired, 1);
+
+        //long jump expires all
+        history.set_ts(100000);
+        assert_eq!(
+            history.stats(),
+            ShadowRouterHistoryStats {
+                entries: 0,
+                expired: 3,
+                evicted: 0,
+                false_negatives: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn evict_at_memory_cap() {
+        let history = DataWorkerHistory::new(HistoryConfig {
+            timeout_ms: 1000,
+            slots: 4,
+            max_entries: 10,
+        });
+        for seq in 0..5 {
+            assert_eq!(history.already_received_broadcast(None, 1, seq), false);
+        }
+        history.set_ts(250);
+        for seq in 5..100 {
+            assert_eq!(history.already_received_broadcast(None, 1, seq), false);
+            assert!(history.stats().entries <= 10);
+        }
+
+        //evicted entry is accepted again, and it is counted as false negative
+        assert_eq!(history.already_received_broadcast(None, 1, 0), false);
+        let stats = history.stats();
+        assert_eq!(stats.false_negatives, 1);
+        assert_eq!(stats.evicted as usize + stats.entries, 101);
+    }
 }
@@ -1,36 +1,122 @@
 #![allow(clippy::bool_assert_comparison)]
 
-use std::{fmt::Debug, hash::Hash};
+use std::{
+    fmt::Debug,
+    hash::Hash,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 
 pub use atm0s_sdn_identity::{ConnDirection, ConnId, NodeAddr, NodeAddrBuilder, NodeId, NodeIdType, Protocol};
-pub use atm0s_sdn_network::controller_plane::ControllerPlaneCfg;
+#[cfg(unix)]
+pub use atm0s_sdn_network::controller_plane::SyslogEventSink;
+pub use atm0s_sdn_network::controller_plane::{
+    CheckpointError, ControllerMetrics, ControllerPlaneCfg, EventLogQuery, EventSink, FileEventSink, JournalEntry, JournalEvent, NeighbourPolicy, StateCheckpoint,
+};
+#[cfg(feature = "chaos")]
+pub use atm0s_sdn_network::data_plane::chaos::{ChaosConfig, ChaosControl, ChaosDelay};
 pub use atm0s_sdn_network::data_plane::DataPlaneCfg;
-use atm0s_sdn_network::features::FeaturesControl;
+use atm0s_sdn_network::features::{
+    rpc::{self, RequestId, RpcDest},
+    FeaturesControl,
+};
 pub use atm0s_sdn_network::{
     base, features, secure, services,
-    worker::{SdnWorker, SdnWorkerBusEvent, SdnWorkerCfg, SdnWorkerInput, SdnWorkerOutput},
+    worker::{ReplicationCfg, SdnWorker, SdnWorkerBusEvent, SdnWorkerCfg, SdnWorkerInput, SdnWorkerOutput},
+    ControllerTakeover, DecommissionEvent, WatchdogAlert,
 };
 pub use atm0s_sdn_network::{
-    base::ServiceId,
-    data_plane::{NetInput, NetOutput},
+    base::{Capabilities, CapabilitySkew, LatencyProfile, LinkProfile, ServiceId},
+    data_plane::{
+        fragment::FragmentConfig,
+        multipath::MultipathPolicy,
+        scheduler::SchedulerConfig,
+        shaper::ShapingProfile,
+        tap::{PcapWriter, TapDirection, TapFilter, TapRecord},
+        NetInput, NetOutput,
+    },
+};
+pub use atm0s_sdn_router::{
+    core::RoutingPolicy,
+    shadow::{ShadowRouterHistory, ShadowRouterHistoryStats},
+    RouteRule, ServiceBroadcastLevel,
 };
-pub use atm0s_sdn_router::{shadow::ShadowRouterHistory, RouteRule, ServiceBroadcastLevel};
 pub use sans_io_runtime;
 
+mod backend;
+mod bootstrap;
 mod builder;
+mod dscp;
 mod history;
+mod local_discovery;
+mod metrics;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+mod prometheus;
+mod registry;
+mod room;
+mod services_enum;
+mod session;
+mod stream;
+mod stun;
 mod time;
+#[cfg(feature = "vpn")]
+mod tun;
+#[cfg(feature = "tokio")]
+mod vnet;
+mod watchdog;
 mod worker_inner;
 
+pub use backend::BatchBackend;
+pub use bootstrap::{resolve_dns_seed, system_nameserver, BootstrapConfig, DnsSeed, DnsSeedSource, DNS_SEED_INTERVAL};
 pub use builder::{generate_node_addr, SdnBuilder};
-pub use history::DataWorkerHistory;
+pub use dscp::{DscpBackend, DscpClasses, DSCP_AF31, DSCP_AF41, DSCP_BEST_EFFORT, DSCP_CS1, DSCP_EF};
+pub use history::{DataWorkerHistory, HistoryConfig};
+pub use local_discovery::{LanAnnouncer, LocalDiscoveryConfig, LOCAL_DISCOVERY_INTERVAL, LOCAL_DISCOVERY_PORT};
+pub use metrics::SdnMetrics;
+pub use prometheus::PROMETHEUS_CONTENT_TYPE;
+pub use registry::{instance_key, registry_map, RegistryEvent, RegistryOutput, ServiceInstance, ServiceRegistry, REGISTRY_HEARTBEAT_MS, REGISTRY_NAMESPACE, REGISTRY_TTL_MS};
+pub use room::{RoomEvent, RoomOutput, RoomSpec, RoomStep, RoomTransaction, ROOM_STEP_TIMEOUT_MS};
+pub use services_enum::SdnServiceEnum;
+pub use session::{SessionFile, SESSION_MAX_AGE_MS, SESSION_REFRESH_MS};
+pub use stream::{
+    ListenerOutput, StreamEvent, StreamOutput, StreamState, VirtualTcpListener, VirtualTcpStream, STREAM_MAX_RETRIES, STREAM_MAX_RTO_MS, STREAM_MSS, STREAM_RECV_BUFFER, STREAM_RTO_MS,
+    STREAM_SEND_BUFFER,
+};
+pub use stun::discover_public_addr;
 pub use time::{TimePivot, TimeTicker};
+#[cfg(feature = "tokio")]
+pub use vnet::{VirtualListener, VirtualNetwork, VirtualNetworkHandle, VirtualStream, VirtualUdpSocket};
+pub use watchdog::{WatchdogConfig, WATCHDOG_STALL_MS};
 pub use worker_inner::{SdnChannel, SdnController, SdnEvent, SdnExtIn, SdnExtOut, SdnOwner};
 
+/// Request ids which are generated by [`SdnControllerUtils::rpc_request`], unique in the process
+static RPC_REQUEST_SEQ: AtomicU64 = AtomicU64::new(0);
+/// Max time for waiting the controller to answer [`SdnControllerUtils::snapshot_state`]
+pub const SNAPSHOT_STATE_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub trait SdnControllerUtils<UserData, SC> {
     fn connect_to(&mut self, addr: NodeAddr);
+    /// Gracefully leave the network, progress is reported with `SdnExtOut::DecommissionEvent`
+    fn decommission(&mut self);
     fn feature_control(&mut self, userdata: UserData, cmd: FeaturesControl);
     fn service_control(&mut self, service: ServiceId, userdata: UserData, cmd: SC);
+    /// Send a rpc request, the result is reported with `rpc::Event::Response` which has the returned request id
+    fn rpc_request(&mut self, userdata: UserData, dest: RpcDest, payload: Vec<u8>, timeout: Duration) -> RequestId;
+    /// Send control to the service which is detected from the aggregated enum
+    fn service_control_auto(&mut self, userdata: UserData, cmd: SC)
+    where
+        SC: SdnServiceEnum;
+    /// Encode the controller state for [`SdnBuilder::with_restored_state`] of the next run. It blocks until the controller answers,
+    /// and returns an empty buffer if it doesn't answer in [`SNAPSHOT_STATE_TIMEOUT`]
+    fn snapshot_state(&mut self) -> Vec<u8>;
+    /// Read entries of the controller event journal, they are answered with `SdnExtOut::EventLog`
+    fn query_event_log(&mut self, query: EventLogQuery);
+    /// Mirror messages of all data plane workers which match the filter, None for stopping. Records are emitted with `SdnExtOut::Tap`
+    fn tap(&mut self, filter: Option<TapFilter>);
+    /// Inject faults into packets which are received by all data plane workers, only for resilience testing
+    #[cfg(feature = "chaos")]
+    fn chaos(&mut self, control: ChaosControl);
 }
 
 impl<
@@ -44,6 +130,9 @@ impl<
     fn connect_to(&mut self, addr: NodeAddr) {
         self.send_to(0, SdnExtIn::ConnectTo(addr));
     }
+    fn decommission(&mut self) {
+        self.send_to(0, SdnExtIn::Decommission);
+    }
     fn feature_control(&mut self, userdata: UserData, cmd: FeaturesControl) {
         self.send_to(0, SdnExtIn::FeaturesControl(userdata, cmd));
     }
@@ -51,4 +140,39 @@ impl<
     fn service_control(&mut self, service: ServiceId, userdata: UserData, cmd: SC) {
         self.send_to(0, SdnExtIn::ServicesControl(service, userdata, cmd));
     }
+
+    fn rpc_request(&mut self, userdata: UserData, dest: RpcDest, payload: Vec<u8>, timeout: Duration) -> RequestId {
+        let req_id = RPC_REQUEST_SEQ.fetch_add(1, Ordering::Relaxed) + 1;
+        self.feature_control(userdata, rpc::Control::Request(req_id, dest, payload, timeout.as_millis() as u64).into());
+        req_id
+    }
+
+    fn service_control_auto(&mut self, userdata: UserData, cmd: SC)
+    where
+        SC: SdnServiceEnum,
+    {
+        self.service_control(cmd.service_id(), userdata, cmd);
+    }
+
+    fn snapshot_state(&mut self) -> Vec<u8> {
+        let (tx, rx) = std::sync::mpsc::sync_channel(1);
+        self.send_to(0, SdnExtIn::SnapshotState(tx));
+        rx.recv_timeout(SNAPSHOT_STATE_TIMEOUT).unwrap_or_else(|e| {
+            log::error!("[SdnController] snapshot state failed: {e}");
+            vec![]
+        })
+    }
+
+    fn query_event_log(&mut self, query: EventLogQuery) {
+        self.send_to(0, SdnExtIn::ControlQuery(query));
+    }
+
+    fn tap(&mut self, filter: Option<TapFilter>) {
+        self.send_to(0, SdnExtIn::Tap(filter));
+    }
+
+    #[cfg(feature = "chaos")]
+    fn chaos(&mut self, control: ChaosControl) {
+        self.send_to(0, SdnExtIn::Chaos(control));
+    }
 }
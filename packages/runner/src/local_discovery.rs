@@ -0,0 +1,139 @@
+//! Udp broadcast announcer for the local_discovery service.
+//!
+//! Each node broadcasts a beacon with its node address on the discovery port and listens for beacons of other nodes on the
+//! same LAN. Peers which are not heard for a few intervals are forgotten. Beacons are not authenticated, connections to
+//! discovered peers are still authorized by the handshake.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use atm0s_sdn_identity::{NodeAddr, NodeId};
+use atm0s_sdn_network::services::{bootstrap::SeedSource, local_discovery::LocalDiscoveryPolicy};
+use parking_lot::Mutex;
+use socket2::{Domain, Protocol, Socket, Type};
+
+pub const LOCAL_DISCOVERY_PORT: u16 = 10_900;
+pub const LOCAL_DISCOVERY_INTERVAL: Duration = Duration::from_secs(2);
+/// Peers are forgotten after missing this number of beacons
+const PEER_TTL_BEACONS: u32 = 3;
+const BEACON_MAGIC: &[u8; 8] = b"ATM0SLAN";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalDiscoveryConfig {
+    pub port: u16,
+    pub interval: Duration,
+    pub policy: LocalDiscoveryPolicy,
+}
+
+impl Default for LocalDiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            port: LOCAL_DISCOVERY_PORT,
+            interval: LOCAL_DISCOVERY_INTERVAL,
+            policy: LocalDiscoveryPolicy::allow_all(),
+        }
+    }
+}
+
+pub(crate) fn build_beacon(addr: &NodeAddr) -> Vec<u8> {
+    let mut buf = BEACON_MAGIC.to_vec();
+    buf.extend(addr.to_vec());
+    buf
+}
+
+pub(crate) fn parse_beacon(buf: &[u8]) -> Option<NodeAddr> {
+    NodeAddr::from_vec(buf.strip_prefix(BEACON_MAGIC)?)
+}
+
+type Peers = Mutex<HashMap<NodeId, (NodeAddr, Instant)>>;
+
+/// [`SeedSource`] of peers which announce themselves on the LAN, the background thread stops after it is dropped
+pub struct LanAnnouncer {
+    peers: Arc<Peers>,
+    ttl: Duration,
+}
+
+impl LanAnnouncer {
+    pub fn spawn(node_addr: NodeAddr, port: u16, interval: Duration) -> io::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.set_broadcast(true)?;
+        socket.bind(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)).into())?;
+        let socket: UdpSocket = socket.into();
+        socket.set_read_timeout(Some(interval))?;
+
+        let peers = Arc::new(Mutex::new(HashMap::new()));
+        let weak: Weak<Peers> = Arc::downgrade(&peers);
+        let beacon = build_beacon(&node_addr);
+        let broadcast = SocketAddrV4::new(Ipv4Addr::BROADCAST, port);
+        std::thread::Builder::new().name("lan-announcer".to_string()).spawn(move || {
+            let mut buf = [0u8; 1500];
+            let mut next_announce = Instant::now();
+            loop {
+                if next_announce <= Instant::now() {
+                    next_announce = Instant::now() + interval;
+                    if let Err(e) = socket.send_to(&beacon, broadcast) {
+                        log::warn!("[LanAnnouncer] send beacon error {e}");
+                    }
+                }
+                let received = socket.recv_from(&mut buf);
+                let Some(peers) = weak.upgrade() else {
+                    break;
+                };
+                match received {
+                    Ok((len, from)) => match parse_beacon(&buf[..len]) {
+                        Some(addr) if addr.node_id() != node_addr.node_id() => {
+                            log::debug!("[LanAnnouncer] beacon of {addr} from {from}");
+                            peers.lock().insert(addr.node_id(), (addr, Instant::now()));
+                        }
+                        Some(_) => {}
+                        None => log::debug!("[LanAnnouncer] ignore invalid beacon from {from}"),
+                    },
+                    Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+                    Err(e) => {
+                        log::error!("[LanAnnouncer] receive error {e}");
+                        std::thread::sleep(interval);
+                    }
+                }
+            }
+        })?;
+        Ok(Self {
+            peers,
+            ttl: interval * PEER_TTL_BEACONS,
+        })
+    }
+}
+
+impl SeedSource for LanAnnouncer {
+    fn seeds(&self) -> Vec<NodeAddr> {
+        let mut peers = self.peers.lock();
+        peers.retain(|_, (_, last)| last.elapsed() < self.ttl);
+        peers.values().map(|(addr, _)| addr.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_identity::{NodeAddrBuilder, Protocol};
+
+    use super::{build_beacon, parse_beacon};
+
+    #[test]
+    fn beacon_roundtrip() {
+        let mut builder = NodeAddrBuilder::new(100);
+        builder.add_protocol(Protocol::Ip4([192, 168, 1, 10].into()));
+        builder.add_protocol(Protocol::Udp(10000));
+        let addr = builder.addr();
+
+        let beacon = build_beacon(&addr);
+        assert_eq!(parse_beacon(&beacon), Some(addr));
+        assert_eq!(parse_beacon(&beacon[1..]), None);
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+
+use atm0s_sdn_identity::{ConnId, NodeId};
+use atm0s_sdn_network::{controller_plane::ControllerMetrics, data_plane::DataPlaneMetrics};
+use parking_lot::Mutex;
+
+/// Interval for refreshing metrics from the controller and data planes
+pub const METRICS_UPDATE_INTERVAL_MS: u64 = 1000;
+
+/// Latest metrics of a node, which are refreshed by the workers.
+/// This is shared with exporters like the OTLP exporter, or can be read by the embedder directly.
+#[derive(Debug)]
+pub struct SdnMetrics {
+    node_id: NodeId,
+    latest: Mutex<Option<ControllerMetrics>>,
+    data: Mutex<BTreeMap<u16, DataPlaneMetrics>>,
+}
+
+impl SdnMetrics {
+    pub fn new(node_id: NodeId) -> Self {
+        Self {
+            node_id,
+            latest: Mutex::new(None),
+            data: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    /// Latest snapshot, None if the controller is not started yet
+    pub fn latest(&self) -> Option<ControllerMetrics> {
+        self.latest.lock().clone()
+    }
+
+    /// Latest data plane snapshot of each worker, ordered by worker index
+    pub fn data_planes(&self) -> Vec<(u16, DataPlaneMetrics)> {
+        self.data.lock().iter().map(|(worker, metrics)| (*worker, metrics.clone())).collect()
+    }
+
+    /// Worker which receives packets of each connection, from the assignment table of the controller.
+    /// Empty if the controller is not started yet
+    pub fn conn_workers(&self) -> BTreeMap<ConnId, u16> {
+        self.latest.lock().as_ref().map(|metrics| metrics.conn_workers.clone()).unwrap_or_default()
+    }
+
+    /// Number of connections which are pinned to each worker, workers without connections are included with 0
+    pub fn worker_connections(&self) -> BTreeMap<u16, usize> {
+        let mut counts: BTreeMap<u16, usize> = self.data.lock().keys().map(|worker| (*worker, 0)).collect();
+        for worker in self.conn_workers().into_values() {
+            *counts.entry(worker).or_default() += 1;
+        }
+        counts
+    }
+
+    /// Latest snapshots encoded in Prometheus text format, which can be served at `/metrics`
+    pub fn prometheus(&self) -> String {
+        crate::prometheus::encode(self.node_id, self.latest().as_ref(), &self.data_planes())
+    }
+
+    pub(crate) fn update(&self, metrics: ControllerMetrics) {
+        *self.latest.lock() = Some(metrics);
+    }
+
+    pub(crate) fn update_data(&self, worker: u16, metrics: DataPlaneMetrics) {
+        self.data.lock().insert(worker, metrics);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use atm0s_sdn_identity::ConnId;
+    use atm0s_sdn_network::{controller_plane::ControllerMetrics, data_plane::DataPlaneMetrics};
+
+    use super::SdnMetrics;
+
+    #[test]
+    fn worker_assignment_of_connections() {
+        let conn1 = ConnId::from_out(0, 1);
+        let conn2 = ConnId::from_out(0, 2);
+        let conn3 = ConnId::from_in(0, 3);
+        let metrics = SdnMetrics::new(1);
+        for worker in 0..3 {
+            metrics.update_data(worker, DataPlaneMetrics::default());
+        }
+        assert_eq!(metrics.conn_workers(), BTreeMap::new());
+        assert_eq!(metrics.worker_connections(), BTreeMap::from([(0, 0), (1, 0), (2, 0)]));
+
+        let conn_workers = BTreeMap::from([(conn1, 0), (conn2, 1), (conn3, 1)]);
+        metrics.update(ControllerMetrics {
+            conn_workers: conn_workers.clone(),
+            ..Default::default()
+        });
+        assert_eq!(metrics.conn_workers(), conn_workers);
+        assert_eq!(metrics.worker_connections(), BTreeMap::from([(0, 1), (1, 2), (2, 0)]));
+    }
+}
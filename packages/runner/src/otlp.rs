@@ -0,0 +1,281 @@
+//! Push controller metrics to an OpenTelemetry collector with OTLP/HTTP JSON encoding.
+//!
+//! The exporter runs in a background thread and periodically posts the latest [`SdnMetrics`] snapshot to
+//! `{endpoint}/v1/metrics`. Only plain `http://` endpoints are supported, a local collector sidecar is expected
+//! for forwarding to secured backends. The thread stops after the node is dropped.
+
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{Arc, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use atm0s_sdn_network::controller_plane::ControllerMetrics;
+use serde_json::{json, Value};
+
+use crate::metrics::SdnMetrics;
+
+/// Timeout for connecting and sending to the collector
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+const SCOPE_NAME: &str = "atm0s-sdn";
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum OtlpError {
+    #[error("unsupported endpoint {0}, only http:// is supported")]
+    UnsupportedEndpoint(String),
+    #[error("invalid endpoint {0}")]
+    InvalidEndpoint(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OtlpConfig {
+    /// host:port of the collector
+    host: String,
+    /// Path of metrics api, default is /v1/metrics
+    path: String,
+    /// Push interval
+    pub interval: Duration,
+}
+
+impl OtlpConfig {
+    /// Parse an endpoint like `http://localhost:4318`, the path `/v1/metrics` is appended if endpoint doesn't have a path
+    pub fn new(endpoint: &str, interval: Duration) -> Result<Self, OtlpError> {
+        let rest = endpoint.strip_prefix("http://").ok_or_else(|| OtlpError::UnsupportedEndpoint(endpoint.to_string()))?;
+        let (host, path) = match rest.find('/') {
+            Some(pos) if pos + 1 < rest.len() => (&rest[..pos], rest[pos..].to_string()),
+            Some(pos) => (&rest[..pos], "/v1/metrics".to_string()),
+            None => (rest, "/v1/metrics".to_string()),
+        };
+        if host.is_empty() {
+            return Err(OtlpError::InvalidEndpoint(endpoint.to_string()));
+        }
+        let host = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{host}:80")
+        };
+        Ok(Self { host, path, interval })
+    }
+}
+
+/// Spawn the exporter thread, which stops when all strong references of metrics are dropped
+pub fn spawn_exporter(cfg: OtlpConfig, metrics: &Arc<SdnMetrics>) {
+    let metrics = Arc::downgrade(metrics);
+    std::thread::Builder::new()
+        .name("sdn-otlp-exporter".to_string())
+        .spawn(move || run_exporter(cfg, metrics))
+        .expect("Should spawn otlp exporter thread");
+}
+
+fn run_exporter(cfg: OtlpConfig, metrics: Weak<SdnMetrics>) {
+    let started_ns = now_ns();
+    log::info!("[OtlpExporter] started, pushing to http://{}{} every {:?}", cfg.host, cfg.path, cfg.interval);
+    loop {
+        std::thread::sleep(cfg.interval);
+        let (node_id, latest) = match metrics.upgrade() {
+            Some(metrics) => (metrics.node_id(), metrics.latest()),
+            None => break,
+        };
+        if let Some(latest) = latest {
+            let body = encode(node_id, &latest, started_ns, now_ns());
+            match post(&cfg, body.to_string().as_bytes()) {
+                Ok(status) if (200..300).contains(&status) => {}
+                Ok(status) => log::warn!("[OtlpExporter] collector responded status {status}"),
+                Err(err) => log::warn!("[OtlpExporter] push error {err}"),
+            }
+        }
+    }
+    log::info!("[OtlpExporter] stopped");
+}
+
+fn now_ns() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+}
+
+fn attr(key: &str, value: Value) -> Value {
+    json!({ "key": key, "value": value })
+}
+
+/// Encode metrics as an OTLP ExportMetricsServiceRequest in JSON, 64-bit integers are encoded as strings as the spec requires
+fn encode(node_id: u32, metrics: &ControllerMetrics, started_ns: u64, now_ns: u64) -> Value {
+    let node_attrs = json!([attr("sdn.node_id", json!({ "intValue": node_id.to_string() }))]);
+    let gauge = |name: &str, desc: &str, value: usize| {
+        json!({
+            "name": name,
+            "description": desc,
+            "unit": "1",
+            "gauge": { "dataPoints": [{ "attributes": node_attrs, "timeUnixNano": now_ns.to_string(), "asInt": value.to_string() }] }
+        })
+    };
+    let counter = |name: &str, desc: &str, value: u64| {
+        json!({
+            "name": name,
+            "description": desc,
+            "unit": "1",
+            "sum": {
+                "aggregationTemporality": 2,
+                "isMonotonic": true,
+                "dataPoints": [{ "attributes": node_attrs, "startTimeUnixNano": started_ns.to_string(), "timeUnixNano": now_ns.to_string(), "asInt": value.to_string() }]
+            }
+        })
+    };
+
+    json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": [
+                    attr("service.name", json!({ "stringValue": SCOPE_NAME })),
+                    attr("service.instance.id", json!({ "stringValue": node_id.to_string() })),
+                ]
+            },
+            "scopeMetrics": [{
+                "scope": { "name": SCOPE_NAME, "version": env!("CARGO_PKG_VERSION") },
+                "metrics": [
+                    gauge("sdn.connections", "Current connections", metrics.connections),
+                    counter("sdn.connections.established", "Established connections", metrics.connections_established),
+                    counter("sdn.connections.closed", "Closed connections", metrics.connections_closed),
+                    gauge("sdn.router.routes", "Destinations in routing table", metrics.routes),
+                    gauge("sdn.dht_kv.maps", "Served dht_kv maps", metrics.dht_kv_maps),
+                    gauge("sdn.pubsub.remote_relays", "Pubsub channels relayed to remote nodes", metrics.pubsub_remote_relays),
+                    gauge("sdn.pubsub.relay_fanout", "Remote subscribers of relayed pubsub channels", metrics.pubsub_relay_fanout),
+                    gauge("sdn.alias.parked_msgs", "Alias messages parked for offline owners", metrics.alias_parked_msgs),
+                ]
+            }]
+        }]
+    })
+}
+
+/// Post body with a minimal HTTP/1.1 request, returning the response status code
+fn post(cfg: &OtlpConfig, body: &[u8]) -> std::io::Result<u16> {
+    let addr = cfg
+        .host
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("cannot resolve {}", cfg.host)))?;
+    let mut stream = TcpStream::connect_timeout(&addr, HTTP_TIMEOUT)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+    let header = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        cfg.path,
+        cfg.host,
+        body.len()
+    );
+    stream.write_all(header.as_bytes())?;
+    stream.write_all(body)?;
+
+    let mut buf = [0; 64];
+    let mut len = 0;
+    while len < buf.len() {
+        let n = stream.read(&mut buf[len..])?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+        if buf[..len].contains(&b'\n') {
+            break;
+        }
+    }
+    let status_line = String::from_utf8_lossy(&buf[..len]);
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid response {status_line}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        time::Duration,
+    };
+
+    use atm0s_sdn_network::controller_plane::ControllerMetrics;
+    use serde_json::Value;
+
+    use super::{encode, post, OtlpConfig, OtlpError};
+
+    #[test]
+    fn parse_endpoint() {
+        let interval = Duration::from_secs(1);
+        let cfg = OtlpConfig::new("http://localhost:4318", interval).expect("Should parse");
+        assert_eq!((cfg.host.as_str(), cfg.path.as_str()), ("localhost:4318", "/v1/metrics"));
+        let cfg = OtlpConfig::new("http://collector/", interval).expect("Should parse");
+        assert_eq!((cfg.host.as_str(), cfg.path.as_str()), ("collector:80", "/v1/metrics"));
+        let cfg = OtlpConfig::new("http://127.0.0.1:4318/custom/metrics", interval).expect("Should parse");
+        assert_eq!((cfg.host.as_str(), cfg.path.as_str()), ("127.0.0.1:4318", "/custom/metrics"));
+
+        assert_eq!(
+            OtlpConfig::new("https://localhost:4318", interval),
+            Err(OtlpError::UnsupportedEndpoint("https://localhost:4318".to_string()))
+        );
+        assert_eq!(OtlpConfig::new("http:///v1/metrics", interval), Err(OtlpError::InvalidEndpoint("http:///v1/metrics".to_string())));
+    }
+
+    #[test]
+    fn encode_metrics() {
+        let metrics = ControllerMetrics {
+            connections: 3,
+            connections_established: 5,
+            connections_closed: 2,
+            routes: 10,
+            ..Default::default()
+        };
+        let body = encode(1, &metrics, 100, 200);
+        let resource = &body["resourceMetrics"][0];
+        assert_eq!(resource["resource"]["attributes"][1]["value"]["stringValue"], "1");
+
+        let metrics = resource["scopeMetrics"][0]["metrics"].as_array().expect("Should have metrics");
+        let find = |name: &str| metrics.iter().find(|m| m["name"] == name).cloned().expect("Should have metric");
+
+        let connections = &find("sdn.connections")["gauge"]["dataPoints"][0];
+        assert_eq!(connections["asInt"], "3");
+        assert_eq!(connections["timeUnixNano"], "200");
+        assert_eq!(connections["attributes"][0]["key"], "sdn.node_id");
+        assert_eq!(connections["attributes"][0]["value"]["intValue"], "1");
+
+        let established = find("sdn.connections.established");
+        assert_eq!(established["sum"]["isMonotonic"], true);
+        assert_eq!(established["sum"]["dataPoints"][0]["asInt"], "5");
+        assert_eq!(established["sum"]["dataPoints"][0]["startTimeUnixNano"], "100");
+        assert_eq!(find("sdn.router.routes")["gauge"]["dataPoints"][0]["asInt"], "10");
+    }
+
+    #[test]
+    fn post_to_collector() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Should bind");
+        let addr = listener.local_addr().expect("Should have addr");
+        let collector = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("Should accept");
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).expect("Should read");
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).expect("Should read");
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(len) = line.strip_prefix("Content-Length: ") {
+                    content_length = len.trim().parse().expect("Should parse");
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).expect("Should read body");
+            reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").expect("Should write");
+            (request_line, body)
+        });
+
+        let cfg = OtlpConfig::new(&format!("http://{addr}"), Duration::from_secs(1)).expect("Should parse");
+        let body = encode(1, &ControllerMetrics::default(), 0, 0);
+        assert_eq!(post(&cfg, body.to_string().as_bytes()).expect("Should post"), 200);
+
+        let (request_line, received) = collector.join().expect("Should join");
+        assert_eq!(request_line, "POST /v1/metrics HTTP/1.1\r\n");
+        assert_eq!(serde_json::from_slice::<Value>(&received).expect("Should decode"), body);
+    }
+}
@@ -0,0 +1,208 @@
+//! Encode node metrics in the Prometheus text exposition format (version 0.0.4).
+//!
+//! Every sample has a `node` label, data plane samples also have a `worker` label, so the scrape of a multi-worker node
+//! keeps the traffic of each worker apart. Rtt is exported as a cumulative histogram in milliseconds.
+
+use std::fmt::Write;
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_network::{base::CompressionStats, controller_plane::ControllerMetrics, data_plane::DataPlaneMetrics, metrics::FeatureTraffic};
+
+/// Content type which should be used when serving the encoded text
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "{name}{{{labels}}} {value}");
+}
+
+fn traffic(out: &mut String, node: &str, data: &[(u16, DataPlaneMetrics)], name: &str, help: &str, value: fn(&FeatureTraffic) -> u64) {
+    family(out, name, "counter", help);
+    for (worker, metrics) in data {
+        for (feature, traffic) in &metrics.features {
+            sample(out, name, &format!("{node},worker=\"{worker}\",feature=\"{}\"", feature.name()), value(traffic));
+        }
+    }
+}
+
+fn compression(out: &mut String, node: &str, metrics: &ControllerMetrics, name: &str, help: &str, value: fn(&CompressionStats) -> u64) {
+    family(out, name, "counter", help);
+    for (feature, stats) in &metrics.compression {
+        sample(out, name, &format!("{node},feature=\"{feature}\""), value(stats));
+    }
+}
+
+pub(crate) fn encode(node_id: NodeId, controller: Option<&ControllerMetrics>, data: &[(u16, DataPlaneMetrics)]) -> String {
+    let mut out = String::new();
+    let node = format!("node=\"{node_id}\"");
+
+    if let Some(metrics) = controller {
+        let gauges = [
+            ("sdn_connections", "Current neighbour connections", metrics.connections),
+            ("sdn_router_routes", "Destinations in routing table", metrics.routes),
+            ("sdn_dht_kv_maps", "Served dht_kv maps", metrics.dht_kv_maps),
+            ("sdn_pubsub_remote_relays", "Pubsub channels relayed to remote nodes", metrics.pubsub_remote_relays),
+            ("sdn_pubsub_relay_fanout", "Remote subscribers of relayed pubsub channels", metrics.pubsub_relay_fanout),
+            ("sdn_alias_parked_msgs", "Alias messages parked for offline owners", metrics.alias_parked_msgs),
+        ];
+        for (name, help, value) in gauges {
+            family(&mut out, name, "gauge", help);
+            sample(&mut out, name, &node, value);
+        }
+
+        family(&mut out, "sdn_connections_established_total", "counter", "Established neighbour connections");
+        sample(&mut out, "sdn_connections_established_total", &node, metrics.connections_established);
+        family(&mut out, "sdn_connections_closed_total", "counter", "Closed neighbour connections");
+        sample(&mut out, "sdn_connections_closed_total", &node, metrics.connections_closed);
+
+        family(&mut out, "sdn_broadcast_history_entries", "gauge", "Broadcast messages remembered for dedup");
+        sample(&mut out, "sdn_broadcast_history_entries", &node, metrics.broadcast_history.entries);
+        let history = [
+            (
+                "sdn_broadcast_history_expired_total",
+                "Broadcast dedup entries removed after timeout",
+                metrics.broadcast_history.expired,
+            ),
+            (
+                "sdn_broadcast_history_evicted_total",
+                "Broadcast dedup entries evicted before timeout by the memory cap",
+                metrics.broadcast_history.evicted,
+            ),
+            (
+                "sdn_broadcast_history_false_negatives_total",
+                "Duplicated broadcast messages accepted again after eviction, estimated",
+                metrics.broadcast_history.false_negatives,
+            ),
+        ];
+        for (name, help, value) in history {
+            family(&mut out, name, "counter", help);
+            sample(&mut out, name, &node, value);
+        }
+
+        family(&mut out, "sdn_rtt_ms", "histogram", "Rtt of neighbour connections in milliseconds");
+        for (bound, count) in metrics.rtt_ms.cumulative_buckets() {
+            sample(&mut out, "sdn_rtt_ms_bucket", &format!("{node},le=\"{bound}\""), count);
+        }
+        sample(&mut out, "sdn_rtt_ms_bucket", &format!("{node},le=\"+Inf\""), metrics.rtt_ms.count());
+        sample(&mut out, "sdn_rtt_ms_sum", &node, metrics.rtt_ms.sum_ms());
+        sample(&mut out, "sdn_rtt_ms_count", &node, metrics.rtt_ms.count());
+
+        if !metrics.compression.is_empty() {
+            compression(&mut out, &node, metrics, "sdn_compression_raw_bytes_total", "Original bytes of compressed payloads", |s| s.raw_bytes);
+            compression(&mut out, &node, metrics, "sdn_compression_compressed_bytes_total", "Sent bytes of compressed payloads", |s| {
+                s.compressed_bytes
+            });
+            compression(&mut out, &node, metrics, "sdn_compression_compressed_msgs_total", "Compressed payloads", |s| s.compressed_msgs);
+            compression(&mut out, &node, metrics, "sdn_compression_skipped_msgs_total", "Payloads over the threshold which are sent raw", |s| {
+                s.skipped_msgs
+            });
+        }
+    }
+
+    if !data.is_empty() {
+        family(&mut out, "sdn_worker_connections", "gauge", "Neighbour connections pinned to the worker");
+        for (worker, metrics) in data {
+            sample(&mut out, "sdn_worker_connections", &format!("{node},worker=\"{worker}\""), metrics.connections);
+        }
+        family(&mut out, "sdn_worker_replayed_packets_total", "counter", "Encrypted packets dropped as replays by the worker");
+        for (worker, metrics) in data {
+            sample(&mut out, "sdn_worker_replayed_packets_total", &format!("{node},worker=\"{worker}\""), metrics.replayed);
+        }
+        family(&mut out, "sdn_worker_pool_allocated_total", "counter", "Buffers allocated by the worker pool because it is empty");
+        for (worker, metrics) in data {
+            sample(&mut out, "sdn_worker_pool_allocated_total", &format!("{node},worker=\"{worker}\""), metrics.pool.allocated);
+        }
+        family(&mut out, "sdn_worker_pool_reused_total", "counter", "Buffers reused from the worker pool");
+        for (worker, metrics) in data {
+            sample(&mut out, "sdn_worker_pool_reused_total", &format!("{node},worker=\"{worker}\""), metrics.pool.reused);
+        }
+
+        traffic(&mut out, &node, data, "sdn_feature_rx_packets_total", "Messages received by each feature", |t| t.rx.packets);
+        traffic(&mut out, &node, data, "sdn_feature_rx_bytes_total", "Bytes received by each feature", |t| t.rx.bytes);
+        traffic(&mut out, &node, data, "sdn_feature_tx_packets_total", "Messages sent by each feature", |t| t.tx.packets);
+        traffic(&mut out, &node, data, "sdn_feature_tx_bytes_total", "Bytes sent by each feature", |t| t.tx.bytes);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_network::{
+        base::CompressionStats,
+        controller_plane::ControllerMetrics,
+        data_plane::{pool::PoolStats, DataPlaneMetrics},
+        features::Features,
+        metrics::{FeatureTraffic, TrafficCounter},
+    };
+    use atm0s_sdn_router::shadow::ShadowRouterHistoryStats;
+
+    use super::encode;
+
+    #[test]
+    fn encode_controller_and_data_planes() {
+        let mut controller = ControllerMetrics {
+            connections: 2,
+            connections_established: 3,
+            pubsub_relay_fanout: 4,
+            broadcast_history: ShadowRouterHistoryStats {
+                entries: 5,
+                evicted: 6,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        controller.rtt_ms.observe(20);
+        controller.rtt_ms.observe(4000);
+        controller.compression.insert(
+            "dht_kv",
+            CompressionStats {
+                compressed_msgs: 2,
+                raw_bytes: 1000,
+                compressed_bytes: 300,
+                ..Default::default()
+            },
+        );
+        let mut data = DataPlaneMetrics {
+            connections: 2,
+            replayed: 3,
+            pool: PoolStats { allocated: 4, reused: 40, idle: 2 },
+            ..Default::default()
+        };
+        data.features.insert(
+            Features::PubSub,
+            FeatureTraffic {
+                rx: TrafficCounter { packets: 5, bytes: 500 },
+                tx: TrafficCounter { packets: 7, bytes: 700 },
+            },
+        );
+
+        let text = encode(1, Some(&controller), &[(0, data)]);
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.contains(&"# TYPE sdn_connections gauge"));
+        assert!(lines.contains(&"sdn_connections{node=\"1\"} 2"));
+        assert!(lines.contains(&"sdn_connections_established_total{node=\"1\"} 3"));
+        assert!(lines.contains(&"sdn_pubsub_relay_fanout{node=\"1\"} 4"));
+        assert!(lines.contains(&"sdn_broadcast_history_entries{node=\"1\"} 5"));
+        assert!(lines.contains(&"sdn_broadcast_history_evicted_total{node=\"1\"} 6"));
+        assert!(lines.contains(&"sdn_rtt_ms_bucket{node=\"1\",le=\"10\"} 0"));
+        assert!(lines.contains(&"sdn_rtt_ms_bucket{node=\"1\",le=\"25\"} 1"));
+        assert!(lines.contains(&"sdn_rtt_ms_bucket{node=\"1\",le=\"+Inf\"} 2"));
+        assert!(lines.contains(&"sdn_rtt_ms_sum{node=\"1\"} 4020"));
+        assert!(lines.contains(&"sdn_compression_raw_bytes_total{node=\"1\",feature=\"dht_kv\"} 1000"));
+        assert!(lines.contains(&"sdn_compression_compressed_bytes_total{node=\"1\",feature=\"dht_kv\"} 300"));
+        assert!(lines.contains(&"sdn_worker_connections{node=\"1\",worker=\"0\"} 2"));
+        assert!(lines.contains(&"sdn_worker_replayed_packets_total{node=\"1\",worker=\"0\"} 3"));
+        assert!(lines.contains(&"sdn_worker_pool_reused_total{node=\"1\",worker=\"0\"} 40"));
+        assert!(lines.contains(&"sdn_feature_rx_packets_total{node=\"1\",worker=\"0\",feature=\"pubsub\"} 5"));
+        assert!(lines.contains(&"sdn_feature_tx_bytes_total{node=\"1\",worker=\"0\",feature=\"pubsub\"} 700"));
+    }
+
+    #[test]
+    fn encode_before_started() {
+        assert_eq!(encode(1, None, &[]), "");
+    }
+}
@@ -0,0 +1,275 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::Duration,
+};
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_network::features::{
+    dht_kv::{self, Key, Map, MapControl, MapEvent},
+    FeaturesControl, FeaturesEvent,
+};
+
+/// Interval for refreshing the ttl of registered instances
+pub const REGISTRY_HEARTBEAT_MS: u64 = 2000;
+/// Instance which is not refreshed in this duration is expired, which survives two lost heartbeats
+pub const REGISTRY_TTL_MS: u64 = 3 * REGISTRY_HEARTBEAT_MS;
+/// Namespace of the dht_kv maps which store the instances, see [`Map::derive`]
+pub const REGISTRY_NAMESPACE: &str = "service_registry";
+
+/// Map which stores the instances of a service name
+pub fn registry_map(name: &str) -> Map {
+    Map::derive(REGISTRY_NAMESPACE, name)
+}
+
+/// Each instance is a key of the map, which is built from its node and port
+pub fn instance_key(node: NodeId, port: u16) -> Key {
+    Key(((node as u64) << 16) | port as u64)
+}
+
+fn instance_of(key: Key) -> (NodeId, u16) {
+    ((key.0 >> 16) as NodeId, key.0 as u16)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceInstance {
+    pub node: NodeId,
+    pub port: u16,
+    pub meta: Vec<u8>,
+}
+
+/// Membership change of a watched service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryEvent {
+    Joined(String, ServiceInstance),
+    /// Metadata of the instance is changed
+    Updated(String, ServiceInstance),
+    /// Instance is deregistered by its node
+    Left(String, NodeId, u16),
+    /// Heartbeats of the instance stopped
+    Expired(String, NodeId, u16),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryOutput {
+    Control(FeaturesControl),
+    Event(RegistryEvent),
+}
+
+struct Registration {
+    meta: Vec<u8>,
+    last_heartbeat: u64,
+}
+
+struct Watch {
+    name: String,
+    instances: BTreeMap<Key, ServiceInstance>,
+}
+
+/// Registry of named services over dht_kv.
+///
+/// Each service name is a map, and each instance is a key which is set with [`REGISTRY_TTL_MS`] and refreshed every
+/// [`REGISTRY_HEARTBEAT_MS`], so instances of a crashed or disconnected node are expired without deregistering.
+/// Watching a name subscribes its map and reports membership changes as [`RegistryEvent`].
+/// The registry is sans-io like [`crate::RoomTransaction`]: controls from [`ServiceRegistry::pop_output`] are sent with
+/// feature control of the node, and features events of the node are fed back with [`ServiceRegistry::on_event`].
+pub struct ServiceRegistry {
+    node_id: NodeId,
+    registered: HashMap<(String, u16), Registration>,
+    watches: HashMap<Map, Watch>,
+    queue: VecDeque<RegistryOutput>,
+}
+
+impl ServiceRegistry {
+    pub fn new(node_id: NodeId) -> Self {
+        Self {
+            node_id,
+            registered: HashMap::new(),
+            watches: HashMap::new(),
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Register an instance of this node, registering the same name and port again replaces the metadata
+    pub fn register(&mut self, now: u64, name: &str, port: u16, meta: Vec<u8>) {
+        log::info!("[ServiceRegistry] register {name} port {port}");
+        self.set(name, port, meta.clone());
+        self.registered.insert((name.to_string(), port), Registration { meta, last_heartbeat: now });
+    }
+
+    pub fn deregister(&mut self, name: &str, port: u16) {
+        if self.registered.remove(&(name.to_string(), port)).is_some() {
+            log::info!("[ServiceRegistry] deregister {name} port {port}");
+            self.control(dht_kv::Control::MapCmd(registry_map(name), MapControl::Del(instance_key(self.node_id, port))));
+        }
+    }
+
+    /// Subscribe membership changes of the service, current instances are reported as [`RegistryEvent::Joined`]
+    pub fn watch(&mut self, name: &str) {
+        let map = registry_map(name);
+        if self.watches.contains_key(&map) {
+            return;
+        }
+        log::info!("[ServiceRegistry] watch {name}");
+        self.watches.insert(
+            map,
+            Watch {
+                name: name.to_string(),
+                instances: BTreeMap::new(),
+            },
+        );
+        self.control(dht_kv::Control::MapCmd(map, MapControl::Sub));
+    }
+
+    /// Stop watching, no events are reported for the remaining instances
+    pub fn unwatch(&mut self, name: &str) {
+        let map = registry_map(name);
+        if self.watches.remove(&map).is_some() {
+            log::info!("[ServiceRegistry] unwatch {name}");
+            self.control(dht_kv::Control::MapCmd(map, MapControl::Unsub));
+        }
+    }
+
+    /// Known instances of a watched service
+    pub fn instances(&self, name: &str) -> Vec<ServiceInstance> {
+        self.watches.get(&registry_map(name)).map(|watch| watch.instances.values().cloned().collect()).unwrap_or_default()
+    }
+
+    pub fn on_tick(&mut self, now: u64) {
+        let mut refresh = vec![];
+        for ((name, port), registration) in self.registered.iter_mut() {
+            if now >= registration.last_heartbeat + REGISTRY_HEARTBEAT_MS {
+                registration.last_heartbeat = now;
+                refresh.push((name.clone(), *port, registration.meta.clone()));
+            }
+        }
+        for (name, port, meta) in refresh {
+            self.set(&name, port, meta);
+        }
+    }
+
+    /// Process a features event of the node, return true if it belongs to a registered or watched service
+    pub fn on_event(&mut self, _now: u64, event: &FeaturesEvent) -> bool {
+        let (map, event) = match event {
+            FeaturesEvent::DhtKv(dht_kv::Event::MapEvent(map, event)) => (*map, event),
+            _ => return false,
+        };
+        let watch = match self.watches.get_mut(&map) {
+            Some(watch) => watch,
+            None => return self.registered.keys().any(|(name, _)| registry_map(name) == map),
+        };
+        match event {
+            MapEvent::OnSet(key, _, meta) => {
+                let (node, port) = instance_of(*key);
+                let instance = ServiceInstance { node, port, meta: meta.clone() };
+                match watch.instances.insert(*key, instance.clone()) {
+                    None => self.queue.push_back(RegistryOutput::Event(RegistryEvent::Joined(watch.name.clone(), instance))),
+                    // heartbeats set the same metadata again
+                    Some(old) if old.meta != instance.meta => self.queue.push_back(RegistryOutput::Event(RegistryEvent::Updated(watch.name.clone(), instance))),
+                    Some(_) => {}
+                }
+            }
+            MapEvent::OnDel(key, _) => {
+                if watch.instances.remove(key).is_some() {
+                    let (node, port) = instance_of(*key);
+                    self.queue.push_back(RegistryOutput::Event(RegistryEvent::Left(watch.name.clone(), node, port)));
+                }
+            }
+            MapEvent::OnExpired(key, _) => {
+                if watch.instances.remove(key).is_some() {
+                    let (node, port) = instance_of(*key);
+                    log::warn!("[ServiceRegistry] instance {node}:{port} of {} expired", watch.name);
+                    self.queue.push_back(RegistryOutput::Event(RegistryEvent::Expired(watch.name.clone(), node, port)));
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+
+    pub fn pop_output(&mut self) -> Option<RegistryOutput> {
+        self.queue.pop_front()
+    }
+
+    fn set(&mut self, name: &str, port: u16, meta: Vec<u8>) {
+        let ttl = Duration::from_millis(REGISTRY_TTL_MS);
+        self.control(dht_kv::Control::MapCmd(registry_map(name), MapControl::SetWithTtl(instance_key(self.node_id, port), meta, ttl)));
+    }
+
+    fn control<C: Into<FeaturesControl>>(&mut self, control: C) {
+        self.queue.push_back(RegistryOutput::Control(control.into()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use atm0s_sdn_network::features::{
+        dht_kv::{self, MapControl, MapEvent},
+        FeaturesControl, FeaturesEvent,
+    };
+
+    use super::{instance_key, registry_map, RegistryEvent, RegistryOutput, ServiceInstance, ServiceRegistry, REGISTRY_HEARTBEAT_MS, REGISTRY_TTL_MS};
+
+    fn control<C: Into<FeaturesControl>>(control: C) -> Option<RegistryOutput> {
+        Some(RegistryOutput::Control(control.into()))
+    }
+
+    fn map_event(name: &str, event: MapEvent) -> FeaturesEvent {
+        FeaturesEvent::DhtKv(dht_kv::Event::MapEvent(registry_map(name), event))
+    }
+
+    #[test]
+    fn register_with_heartbeat() {
+        let mut registry = ServiceRegistry::new(1);
+        let set = control(dht_kv::Control::MapCmd(
+            registry_map("api"),
+            MapControl::SetWithTtl(instance_key(1, 80), vec![1], Duration::from_millis(REGISTRY_TTL_MS)),
+        ));
+
+        registry.register(0, "api", 80, vec![1]);
+        assert_eq!(registry.pop_output(), set);
+        registry.on_tick(REGISTRY_HEARTBEAT_MS - 1);
+        assert_eq!(registry.pop_output(), None);
+        registry.on_tick(REGISTRY_HEARTBEAT_MS);
+        assert_eq!(registry.pop_output(), set);
+
+        registry.deregister("api", 80);
+        assert_eq!(registry.pop_output(), control(dht_kv::Control::MapCmd(registry_map("api"), MapControl::Del(instance_key(1, 80)))));
+        registry.on_tick(2 * REGISTRY_HEARTBEAT_MS);
+        assert_eq!(registry.pop_output(), None);
+    }
+
+    #[test]
+    fn watch_membership() {
+        let mut registry = ServiceRegistry::new(1);
+        registry.watch("api");
+        assert_eq!(registry.pop_output(), control(dht_kv::Control::MapCmd(registry_map("api"), MapControl::Sub)));
+
+        let instance = ServiceInstance { node: 2, port: 80, meta: vec![1] };
+        assert!(registry.on_event(0, &map_event("api", MapEvent::OnSet(instance_key(2, 80), 2, vec![1]))));
+        assert_eq!(registry.pop_output(), Some(RegistryOutput::Event(RegistryEvent::Joined("api".to_string(), instance.clone()))));
+        // heartbeat doesn't change the membership
+        assert!(registry.on_event(10, &map_event("api", MapEvent::OnSet(instance_key(2, 80), 2, vec![1]))));
+        assert_eq!(registry.pop_output(), None);
+        assert!(registry.on_event(20, &map_event("api", MapEvent::OnSet(instance_key(2, 80), 2, vec![2]))));
+        let updated = ServiceInstance { meta: vec![2], ..instance };
+        assert_eq!(registry.pop_output(), Some(RegistryOutput::Event(RegistryEvent::Updated("api".to_string(), updated.clone()))));
+        assert!(registry.on_event(30, &map_event("api", MapEvent::OnSet(instance_key(3, 81), 3, vec![]))));
+        assert!(registry.pop_output().is_some());
+        assert_eq!(registry.instances("api").len(), 2);
+
+        assert!(registry.on_event(40, &map_event("api", MapEvent::OnDel(instance_key(2, 80), 2))));
+        assert_eq!(registry.pop_output(), Some(RegistryOutput::Event(RegistryEvent::Left("api".to_string(), 2, 80))));
+        assert!(registry.on_event(50, &map_event("api", MapEvent::OnExpired(instance_key(3, 81), 3))));
+        assert_eq!(registry.pop_output(), Some(RegistryOutput::Event(RegistryEvent::Expired("api".to_string(), 3, 81))));
+        assert_eq!(registry.instances("api"), vec![]);
+
+        // events of other maps are ignored
+        assert!(!registry.on_event(60, &map_event("other", MapEvent::OnDel(instance_key(2, 80), 2))));
+
+        registry.unwatch("api");
+        assert_eq!(registry.pop_output(), control(dht_kv::Control::MapCmd(registry_map("api"), MapControl::Unsub)));
+        assert!(!registry.on_event(70, &map_event("api", MapEvent::OnSet(instance_key(2, 80), 2, vec![1]))));
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::VecDeque;
+
+use atm0s_sdn_network::{
+    base::FeatureError,
+    features::{
+        alias::{self, FoundLocation},
+        dht_kv::{self, Key, Map, MapControl},
+        pubsub::{self, ChannelControl, ChannelId},
+        FeaturesControl, FeaturesEvent,
+    },
+};
+use atm0s_sdn_router::ServiceBroadcastLevel;
+
+/// Each step must be confirmed in this duration, otherwise the transaction is failed and rolled back
+pub const ROOM_STEP_TIMEOUT_MS: u64 = 5000;
+
+/// Registrations which bring a room online
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomSpec {
+    pub alias: u64,
+    pub service: u8,
+    pub level: ServiceBroadcastLevel,
+    pub map: Map,
+    pub key: Key,
+    pub value: Vec<u8>,
+    pub channel: ChannelId,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomStep {
+    Alias,
+    Record,
+    Channel,
+}
+
+/// Combined result of a room transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoomEvent {
+    Registered,
+    /// The step which is failed, all previous steps are rolled back
+    Failed(RoomStep, FeatureError),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoomOutput {
+    Control(FeaturesControl),
+    Event(RoomEvent),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    Alias { started_at: u64 },
+    Record { started_at: u64, querying: bool },
+    Registered,
+}
+
+/// Register alias, dht_kv record and pubsub channel of a room as one transaction.
+///
+/// Each registration is confirmed before the next one: alias is queried to be served locally, and the record is read back from the map.
+/// If a step is failed or timed out, done steps are rolled back and a single [`RoomEvent::Failed`] is emitted, otherwise [`RoomEvent::Registered`].
+/// The transaction is sans-io: controls from [`RoomTransaction::pop_output`] are sent with feature control of the node,
+/// and features events of the node are fed back with [`RoomTransaction::on_event`].
+pub struct RoomTransaction {
+    spec: RoomSpec,
+    state: State,
+    queue: VecDeque<RoomOutput>,
+}
+
+impl RoomTransaction {
+    pub fn new(spec: RoomSpec) -> Self {
+        Self {
+            spec,
+            state: State::Idle,
+            queue: VecDeque::new(),
+        }
+    }
+
+    pub fn is_registered(&self) -> bool {
+        self.state == State::Registered
+    }
+
+    pub fn start(&mut self, now: u64) {
+        if self.state != State::Idle {
+            log::warn!("[RoomTransaction] room {} is already started", self.spec.alias);
+            return;
+        }
+        log::info!("[RoomTransaction] start registering room {}", self.spec.alias);
+        self.state = State::Alias { started_at: now };
+        self.control(alias::Control::Register {
+            alias: self.spec.alias,
+            service: self.spec.service,
+            level: self.spec.level,
+        });
+        self.control(alias::Control::Query {
+            alias: self.spec.alias,
+            service: self.spec.service,
+            level: self.spec.level,
+        });
+    }
+
+    /// Remove all registrations of a registered room
+    pub fn teardown(&mut self) {
+        if self.state != State::Registered {
+            return;
+        }
+        log::info!("[RoomTransaction] teardown room {}", self.spec.alias);
+        self.control(pubsub::Control(self.spec.channel, ChannelControl::PubStop));
+        self.rollback(RoomStep::Channel);
+        self.state = State::Idle;
+    }
+
+    pub fn on_tick(&mut self, now: u64) {
+        match self.state {
+            State::Alias { started_at } if now >= started_at + ROOM_STEP_TIMEOUT_MS => self.fail(RoomStep::Alias, FeatureError::Timeout),
+            State::Record { started_at, .. } if now >= started_at + ROOM_STEP_TIMEOUT_MS => self.fail(RoomStep::Record, FeatureError::Timeout),
+            State::Record { started_at, querying: false } => {
+                // the record is read back on ticks until it is stored
+                self.state = State::Record { started_at, querying: true };
+                self.control(dht_kv::Control::MapGet(self.spec.map));
+            }
+            _ => {}
+        }
+    }
+
+    /// Process a features event of the node, return true if it belongs to this transaction
+    pub fn on_event(&mut self, now: u64, event: &FeaturesEvent) -> bool {
+        match (self.state, event) {
+            (State::Alias { .. }, FeaturesEvent::Alias(alias::Event::QueryResult(alias, location))) if *alias == self.spec.alias => {
+                match location {
+                    Some(FoundLocation::Local) => {
+                        log::info!("[RoomTransaction] alias of room {} is registered", self.spec.alias);
+                        self.state = State::Record { started_at: now, querying: false };
+                        self.control(dht_kv::Control::MapCmd(self.spec.map, MapControl::Set(self.spec.key, self.spec.value.clone())));
+                    }
+                    // alias is served by other node
+                    Some(_) => self.fail(RoomStep::Alias, FeatureError::Rejected),
+                    None => self.fail(RoomStep::Alias, FeatureError::Unreachable),
+                }
+                true
+            }
+            (State::Record { started_at, querying: true }, FeaturesEvent::DhtKv(dht_kv::Event::MapGetRes(map, res))) if *map == self.spec.map => {
+                match res {
+                    Ok(slots) => {
+                        if slots.iter().any(|(key, _, _, value)| *key == self.spec.key && *value == self.spec.value) {
+                            log::info!("[RoomTransaction] record of room {} is stored", self.spec.alias);
+                            self.control(pubsub::Control(self.spec.channel, ChannelControl::PubStart));
+                            self.state = State::Registered;
+                            self.queue.push_back(RoomOutput::Event(RoomEvent::Registered));
+                        } else {
+                            self.state = State::Record { started_at, querying: false };
+                        }
+                    }
+                    Err(err) => self.fail(RoomStep::Record, err.into()),
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn pop_output(&mut self) -> Option<RoomOutput> {
+        self.queue.pop_front()
+    }
+
+    fn control<C: Into<FeaturesControl>>(&mut self, control: C) {
+        self.queue.push_back(RoomOutput::Control(control.into()));
+    }
+
+    fn fail(&mut self, step: RoomStep, err: FeatureError) {
+        log::warn!("[RoomTransaction] register room {} failed at {:?} with {:?}, rollback", self.spec.alias, step, err);
+        self.rollback(step);
+        self.state = State::Idle;
+        self.queue.push_back(RoomOutput::Event(RoomEvent::Failed(step, err)));
+    }
+
+    /// Remove registrations which are made before the step, the failed step itself is also removed because it may be partially applied
+    fn rollback(&mut self, step: RoomStep) {
+        if matches!(step, RoomStep::Record | RoomStep::Channel) {
+            self.control(dht_kv::Control::MapCmd(self.spec.map, MapControl::Del(self.spec.key)));
+        }
+        self.control(alias::Control::Unregister { alias: self.spec.alias });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_network::{
+        base::FeatureError,
+        features::{
+            alias::{self, FoundLocation},
+            dht_kv::{self, GetError, MapControl, NodeSession, Version},
+            pubsub::{self, ChannelControl},
+            FeaturesControl, FeaturesEvent,
+        },
+    };
+    use atm0s_sdn_router::ServiceBroadcastLevel;
+
+    use super::{RoomEvent, RoomOutput, RoomSpec, RoomStep, RoomTransaction, ROOM_STEP_TIMEOUT_MS};
+
+    fn spec() -> RoomSpec {
+        RoomSpec {
+            alias: 1000,
+            service: 1,
+            level: ServiceBroadcastLevel::Global,
+            map: 2000.into(),
+            key: 3000.into(),
+            value: vec![1, 2, 3],
+            channel: 4000.into(),
+        }
+    }
+
+    fn control<C: Into<FeaturesControl>>(control: C) -> Option<RoomOutput> {
+        Some(RoomOutput::Control(control.into()))
+    }
+
+    fn start_alias(tx: &mut RoomTransaction) {
+        tx.start(0);
+        assert!(tx.pop_output().is_some());
+        assert!(tx.pop_output().is_some());
+        assert!(tx.on_event(10, &FeaturesEvent::Alias(alias::Event::QueryResult(1000, Some(FoundLocation::Local)))));
+        assert_eq!(tx.pop_output(), control(dht_kv::Control::MapCmd(2000.into(), MapControl::Set(3000.into(), vec![1, 2, 3]))));
+    }
+
+    #[test]
+    fn register_all_steps() {
+        let mut tx = RoomTransaction::new(spec());
+        start_alias(&mut tx);
+
+        tx.on_tick(20);
+        assert_eq!(tx.pop_output(), control(dht_kv::Control::MapGet(2000.into())));
+        // record is not stored yet, it is queried again on next tick
+        assert!(tx.on_event(30, &FeaturesEvent::DhtKv(dht_kv::Event::MapGetRes(2000.into(), Ok(vec![])))));
+        assert_eq!(tx.pop_output(), None);
+        tx.on_tick(40);
+        assert_eq!(tx.pop_output(), control(dht_kv::Control::MapGet(2000.into())));
+        let slots = vec![(3000.into(), NodeSession(1, 0), Version(0), vec![1, 2, 3])];
+        assert!(tx.on_event(50, &FeaturesEvent::DhtKv(dht_kv::Event::MapGetRes(2000.into(), Ok(slots)))));
+        assert_eq!(tx.pop_output(), control(pubsub::Control(4000.into(), ChannelControl::PubStart)));
+        assert_eq!(tx.pop_output(), Some(RoomOutput::Event(RoomEvent::Registered)));
+        assert!(tx.is_registered());
+
+        tx.teardown();
+        assert_eq!(tx.pop_output(), control(pubsub::Control(4000.into(), ChannelControl::PubStop)));
+        assert_eq!(tx.pop_output(), control(dht_kv::Control::MapCmd(2000.into(), MapControl::Del(3000.into()))));
+        assert_eq!(tx.pop_output(), control(alias::Control::Unregister { alias: 1000 }));
+        assert!(!tx.is_registered());
+    }
+
+    #[test]
+    fn rollback_alias_when_record_failed() {
+        let mut tx = RoomTransaction::new(spec());
+        start_alias(&mut tx);
+
+        tx.on_tick(20);
+        assert_eq!(tx.pop_output(), control(dht_kv::Control::MapGet(2000.into())));
+        assert!(tx.on_event(30, &FeaturesEvent::DhtKv(dht_kv::Event::MapGetRes(2000.into(), Err(GetError::Timeout)))));
+        assert_eq!(tx.pop_output(), control(dht_kv::Control::MapCmd(2000.into(), MapControl::Del(3000.into()))));
+        assert_eq!(tx.pop_output(), control(alias::Control::Unregister { alias: 1000 }));
+        assert_eq!(tx.pop_output(), Some(RoomOutput::Event(RoomEvent::Failed(RoomStep::Record, FeatureError::Timeout))));
+        assert_eq!(tx.pop_output(), None);
+    }
+
+    #[test]
+    fn rollback_alias_when_timeout() {
+        let mut tx = RoomTransaction::new(spec());
+        tx.start(0);
+        assert!(tx.pop_output().is_some());
+        assert!(tx.pop_output().is_some());
+        // events of other aliases are ignored
+        assert!(!tx.on_event(10, &FeaturesEvent::Alias(alias::Event::QueryResult(1001, Some(FoundLocation::Local)))));
+        tx.on_tick(ROOM_STEP_TIMEOUT_MS);
+        assert_eq!(tx.pop_output(), control(alias::Control::Unregister { alias: 1000 }));
+        assert_eq!(tx.pop_output(), Some(RoomOutput::Event(RoomEvent::Failed(RoomStep::Alias, FeatureError::Timeout))));
+    }
+}
@@ -0,0 +1,102 @@
+use atm0s_sdn_network::base::ServiceId;
+
+/// Aggregated enum of multiple services types, which is generated by [`sdn_services_enum!`](crate::sdn_services_enum).
+/// It allows routing a value to the service which it belongs to.
+pub trait SdnServiceEnum {
+    fn service_id(&self) -> ServiceId;
+}
+
+/// Generate an aggregated enum for services SC, SE, TC or TW types from a simple service list.
+///
+/// Each variant is declared with the wrapped type and the id of the service which it belongs to.
+/// The macro generates the enum, `From<Inner>` and `TryFrom<Enum>` for each inner type (which are required by services)
+/// and [`SdnServiceEnum`] for routing values to services.
+///
+/// ```
+/// use atm0s_sdn::{sdn_services_enum, services::visualization, SdnServiceEnum, ServiceId};
+///
+/// sdn_services_enum! {
+///     #[derive(Debug, Clone)]
+///     pub enum SC {
+///         Visualization(visualization::Control<u32>) = visualization::SERVICE_ID,
+///     }
+/// }
+///
+/// let sc: SC = visualization::Control::Subscribe.into();
+/// assert_eq!(sc.service_id(), ServiceId(visualization::SERVICE_ID));
+/// ```
+#[macro_export]
+macro_rules! sdn_services_enum {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident {
+            $($variant:ident($ty:ty) = $service_id:expr),+ $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $($variant($ty)),+
+        }
+
+        $(
+            impl From<$ty> for $name {
+                fn from(value: $ty) -> Self {
+                    Self::$variant(value)
+                }
+            }
+
+            impl TryFrom<$name> for $ty {
+                type Error = $name;
+
+                #[allow(unreachable_patterns)]
+                fn try_from(value: $name) -> Result<Self, Self::Error> {
+                    match value {
+                        $name::$variant(value) => Ok(value),
+                        _ => Err(value),
+                    }
+                }
+            }
+        )+
+
+        impl $crate::SdnServiceEnum for $name {
+            fn service_id(&self) -> $crate::ServiceId {
+                match self {
+                    $(Self::$variant(_) => $crate::ServiceId($service_id)),+
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_network::base::ServiceId;
+
+    use super::SdnServiceEnum;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct KvControl(u32);
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct ChatControl(String);
+
+    sdn_services_enum! {
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        enum SC {
+            Kv(KvControl) = 100,
+            Chat(ChatControl) = 101,
+        }
+    }
+
+    #[test]
+    fn convert_and_route() {
+        let sc: SC = KvControl(1).into();
+        assert_eq!(sc, SC::Kv(KvControl(1)));
+        assert_eq!(sc.service_id(), ServiceId(100));
+        assert_eq!(TryInto::<KvControl>::try_into(sc.clone()), Ok(KvControl(1)));
+        assert_eq!(TryInto::<ChatControl>::try_into(sc.clone()), Err(sc));
+
+        let sc: SC = ChatControl("hello".to_string()).into();
+        assert_eq!(sc.service_id(), ServiceId(101));
+    }
+}
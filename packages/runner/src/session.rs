@@ -0,0 +1,155 @@
+//! Persist the node session in a file, so a quick restart is recognized by peers as the same node instance.
+//!
+//! The session is the identity of a running node for features: dht_kv servers keep slots per (node, session) and pubsub relays
+//! are bound to it. With a new session after restart, peers keep the old state until it is timed out, while the restarted node
+//! builds everything again. The session is reused only when the file is refreshed recently (the node was stopped shortly),
+//! otherwise peers already dropped the state and a new session is generated.
+
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use atm0s_sdn_identity::NodeId;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+/// Default max time between the last refresh of the session file and the restart for reusing the session
+pub const SESSION_MAX_AGE_MS: u64 = 30_000;
+/// Interval for refreshing the session file while the node is running
+pub const SESSION_REFRESH_MS: u64 = 5_000;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionRecord {
+    node_id: NodeId,
+    session: u64,
+    saved_at_ms: u64,
+}
+
+pub struct SessionFile {
+    path: PathBuf,
+    node_id: NodeId,
+    session: u64,
+    restored: bool,
+}
+
+impl SessionFile {
+    /// Load the session of the previous run if it is saved for the same node less than `max_age` ago, otherwise generate a new one.
+    /// The file is written immediately, so a crash right after starting still keeps the session
+    pub fn open<P: AsRef<Path>>(path: P, node_id: NodeId, max_age: Duration) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let now_ms = unix_ms();
+        let previous = match File::open(&path) {
+            Ok(mut file) => {
+                let mut buf = vec![];
+                file.read_to_end(&mut buf)?;
+                match bincode::deserialize::<SessionRecord>(&buf) {
+                    Ok(record) => Some(record),
+                    Err(e) => {
+                        log::warn!("[SessionFile] ignore invalid session file {}: {e}", path.display());
+                        None
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        let restored = previous.filter(|r| r.node_id == node_id && now_ms.saturating_sub(r.saved_at_ms) <= max_age.as_millis() as u64);
+        let file = match restored {
+            Some(record) => {
+                log::info!(
+                    "[SessionFile] restored session {} of node {node_id}, saved {} ms ago",
+                    record.session,
+                    now_ms.saturating_sub(record.saved_at_ms)
+                );
+                Self {
+                    path,
+                    node_id,
+                    session: record.session,
+                    restored: true,
+                }
+            }
+            None => Self {
+                path,
+                node_id,
+                session: OsRng.next_u64(),
+                restored: false,
+            },
+        };
+        file.save(file.session)?;
+        Ok(file)
+    }
+
+    /// Delete the session file, then the next start runs with a clean identity
+    pub fn invalidate<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn session(&self) -> u64 {
+        self.session
+    }
+
+    /// The session is reused from the previous run
+    pub fn restored(&self) -> bool {
+        self.restored
+    }
+
+    /// Write the running session with current time, it is called periodically and after the session is changed
+    pub fn save(&self, session: u64) -> std::io::Result<()> {
+        let record = SessionRecord {
+            node_id: self.node_id,
+            session,
+            saved_at_ms: unix_ms(),
+        };
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&bincode::serialize(&record).expect("Should serialize"))?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &self.path)
+    }
+}
+
+fn unix_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::SessionFile;
+
+    #[test]
+    fn restore_and_invalidate_session() {
+        let path = std::env::temp_dir().join(format!("atm0s-sdn-session-test-{}.bin", std::process::id()));
+        SessionFile::invalidate(&path).expect("Should invalidate");
+
+        let first = SessionFile::open(&path, 1, Duration::from_secs(30)).expect("Should open");
+        assert!(!first.restored());
+
+        // quick restart reuses the session
+        let second = SessionFile::open(&path, 1, Duration::from_secs(30)).expect("Should open");
+        assert!(second.restored());
+        assert_eq!(second.session(), first.session());
+
+        // the session which is changed while running is saved
+        second.save(1000).expect("Should save");
+        let third = SessionFile::open(&path, 1, Duration::from_secs(30)).expect("Should open");
+        assert_eq!(third.session(), 1000);
+
+        // other node id or too old file is not reused
+        assert!(!SessionFile::open(&path, 2, Duration::from_secs(30)).expect("Should open").restored());
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(!SessionFile::open(&path, 2, Duration::ZERO).expect("Should open").restored());
+
+        SessionFile::invalidate(&path).expect("Should invalidate");
+        assert!(!SessionFile::open(&path, 2, Duration::from_secs(30)).expect("Should open").restored());
+        SessionFile::invalidate(&path).expect("Should invalidate");
+    }
+}
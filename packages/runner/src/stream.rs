@@ -0,0 +1,843 @@
+//! Reliable ordered streams over virtual sockets of the socket feature.
+//!
+//! Each datagram of the socket feature carries one segment:
+//!
+//! ```text
+//! | flags (1 byte) | seq (8 bytes BE) | ack (8 bytes BE) | window (4 bytes BE) | data |
+//! ```
+//!
+//! Sequences count bytes like TCP: the SYN is sequence 0, data starts at 1 and the FIN takes one sequence after the data.
+//! Segments are retransmitted until they are acknowledged, the receiver advertises the free space of its buffer and the
+//! sender never has more bytes in flight than that window. When the window is closed a single byte is sent as a probe so
+//! the sender learns when the receiver has room again.
+//!
+//! Like [`crate::RoomTransaction`] the streams are sans-io: controls from `pop_output` are sent with feature control of
+//! the node, and features events of the node are fed back with `on_event`.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_network::{
+    base::FeatureError,
+    features::{socket, FeaturesControl, FeaturesEvent},
+};
+
+/// Max data bytes of a segment, a segment with header fits in one udp packet of the underlay
+pub const STREAM_MSS: usize = 1100;
+/// Receive buffer of a stream, this is the max window which is advertised to the remote
+pub const STREAM_RECV_BUFFER: usize = 256 * 1024;
+/// Bytes which can be written but not yet sent
+pub const STREAM_SEND_BUFFER: usize = 256 * 1024;
+/// First retransmission timeout, it doubles after each timeout
+pub const STREAM_RTO_MS: u64 = 300;
+pub const STREAM_MAX_RTO_MS: u64 = 5000;
+/// Retransmissions of the oldest segment before the stream is failed with [`FeatureError::Timeout`]
+pub const STREAM_MAX_RETRIES: u8 = 8;
+
+const FLAG_SYN: u8 = 1;
+const FLAG_ACK: u8 = 2;
+const FLAG_FIN: u8 = 4;
+const FLAG_RST: u8 = 8;
+const HEADER_LEN: usize = 21;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Segment {
+    flags: u8,
+    seq: u64,
+    ack: u64,
+    window: u32,
+    data: Vec<u8>,
+}
+
+impl Segment {
+    fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.data.len());
+        buf.push(self.flags);
+        buf.extend_from_slice(&self.seq.to_be_bytes());
+        buf.extend_from_slice(&self.ack.to_be_bytes());
+        buf.extend_from_slice(&self.window.to_be_bytes());
+        buf.extend_from_slice(&self.data);
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() < HEADER_LEN {
+            return None;
+        }
+        Some(Self {
+            flags: buf[0],
+            seq: u64::from_be_bytes(buf[1..9].try_into().ok()?),
+            ack: u64::from_be_bytes(buf[9..17].try_into().ok()?),
+            window: u32::from_be_bytes(buf[17..21].try_into().ok()?),
+            data: buf[HEADER_LEN..].to_vec(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamState {
+    /// SYN is sent, waiting for the remote
+    Connecting,
+    /// SYN is received by a listener, waiting for the first ack
+    Accepting,
+    Established,
+    Closed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamEvent {
+    Connected,
+    /// New data can be read with [`VirtualTcpStream::read`]
+    Readable,
+    /// Send buffer has space again after a short write
+    Writable,
+    /// Remote finished sending, buffered data can still be read
+    Finished,
+    /// Both sides finished and all data is acknowledged
+    Closed,
+    Failed(FeatureError),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamOutput {
+    Control(FeaturesControl),
+    Event(StreamEvent),
+}
+
+/// Segment which is sent but not acknowledged yet, ack and window are refreshed on each transmission
+#[derive(Debug)]
+struct Inflight {
+    flags: u8,
+    seq: u64,
+    data: Vec<u8>,
+    sent_at: u64,
+}
+
+impl Inflight {
+    fn end(&self) -> u64 {
+        self.seq + self.data.len() as u64 + (self.flags & (FLAG_SYN | FLAG_FIN) != 0) as u64
+    }
+}
+
+/// Ordered, reliable and flow-controlled byte stream between two virtual sockets.
+///
+/// A stream is created with [`VirtualTcpStream::connect`], which binds its own local port, or is accepted by a [`VirtualTcpListener`].
+pub struct VirtualTcpStream {
+    port: u16,
+    remote: (NodeId, u16),
+    owns_socket: bool,
+    state: StreamState,
+    send_buf: VecDeque<u8>,
+    send_next: u64,
+    send_acked: u64,
+    remote_window: u64,
+    inflight: VecDeque<Inflight>,
+    rto: u64,
+    retries: u8,
+    write_blocked: bool,
+    closing: bool,
+    fin_sent: bool,
+    recv_next: u64,
+    recv_buf: VecDeque<u8>,
+    out_of_order: BTreeMap<u64, (Vec<u8>, bool)>,
+    finished: bool,
+    advertised_window: u64,
+    queue: VecDeque<StreamOutput>,
+}
+
+impl VirtualTcpStream {
+    fn new(port: u16, remote: (NodeId, u16), owns_socket: bool, state: StreamState) -> Self {
+        Self {
+            port,
+            remote,
+            owns_socket,
+            state,
+            send_buf: VecDeque::new(),
+            send_next: 0,
+            send_acked: 0,
+            remote_window: 0,
+            inflight: VecDeque::new(),
+            rto: STREAM_RTO_MS,
+            retries: 0,
+            write_blocked: false,
+            closing: false,
+            fin_sent: false,
+            recv_next: 0,
+            recv_buf: VecDeque::new(),
+            out_of_order: BTreeMap::new(),
+            finished: false,
+            advertised_window: STREAM_RECV_BUFFER as u64,
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Bind the local port and connect to a listener of the remote node
+    pub fn connect(now: u64, port: u16, node: NodeId, remote_port: u16) -> Self {
+        let mut stream = Self::new(port, (node, remote_port), true, StreamState::Connecting);
+        log::info!("[VirtualTcpStream] connect {port} => {node}:{remote_port}");
+        stream.control(socket::Control::Bind(port));
+        stream.send_segment(now, FLAG_SYN, vec![]);
+        stream
+    }
+
+    fn accept(now: u64, port: u16, remote: (NodeId, u16)) -> Self {
+        let mut stream = Self::new(port, remote, false, StreamState::Accepting);
+        stream.recv_next = 1;
+        stream.send_segment(now, FLAG_SYN | FLAG_ACK, vec![]);
+        stream
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn remote(&self) -> (NodeId, u16) {
+        self.remote
+    }
+
+    pub fn state(&self) -> StreamState {
+        self.state
+    }
+
+    /// Remote finished sending and all its data is read
+    pub fn is_eof(&self) -> bool {
+        self.finished && self.recv_buf.is_empty()
+    }
+
+    /// Buffer data for sending, return the number of accepted bytes. After a short write [`StreamEvent::Writable`] is emitted when there is space again
+    pub fn write(&mut self, now: u64, data: &[u8]) -> usize {
+        if self.closing || self.state == StreamState::Closed {
+            return 0;
+        }
+        let len = data.len().min(STREAM_SEND_BUFFER - self.send_buf.len());
+        self.send_buf.extend(&data[..len]);
+        self.write_blocked = len < data.len();
+        self.flush(now);
+        len
+    }
+
+    /// Read buffered data in order, return 0 when nothing is buffered
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(self.recv_buf.len());
+        for (dest, byte) in buf.iter_mut().zip(self.recv_buf.drain(..len)) {
+            *dest = byte;
+        }
+        // the remote stops sending when the window is nearly closed, so it must be told when the window opens again
+        if len > 0 && self.state == StreamState::Established && self.advertised_window < STREAM_MSS as u64 && self.recv_window() >= STREAM_MSS as u64 {
+            self.send_ack();
+        }
+        len
+    }
+
+    /// Finish sending after all buffered data, the stream is closed when the remote also finishes
+    pub fn close(&mut self, now: u64) {
+        if self.closing || self.state == StreamState::Closed {
+            return;
+        }
+        log::info!("[VirtualTcpStream] close {} => {:?}", self.port, self.remote);
+        self.closing = true;
+        self.flush(now);
+    }
+
+    /// Close immediately, the remote is failed with [`FeatureError::Rejected`]
+    pub fn abort(&mut self) {
+        if self.state == StreamState::Closed {
+            return;
+        }
+        self.transmit(FLAG_RST, self.send_next, vec![]);
+        self.terminate();
+        self.queue.push_back(StreamOutput::Event(StreamEvent::Closed));
+    }
+
+    pub fn on_tick(&mut self, now: u64) {
+        if self.state == StreamState::Closed {
+            return;
+        }
+        let expired = match self.inflight.front() {
+            Some(front) => now >= front.sent_at + self.rto,
+            None => false,
+        };
+        if !expired {
+            return;
+        }
+        self.retries += 1;
+        if self.retries > STREAM_MAX_RETRIES {
+            log::warn!("[VirtualTcpStream] {} => {:?} timeout after {} retries", self.port, self.remote, STREAM_MAX_RETRIES);
+            self.fail(FeatureError::Timeout);
+            return;
+        }
+        let rto = self.rto;
+        self.rto = (self.rto * 2).min(STREAM_MAX_RTO_MS);
+        let resend: Vec<(u8, u64, Vec<u8>)> = self
+            .inflight
+            .iter_mut()
+            .filter(|seg| now >= seg.sent_at + rto)
+            .map(|seg| {
+                seg.sent_at = now;
+                (seg.flags, seg.seq, seg.data.clone())
+            })
+            .collect();
+        for (flags, seq, data) in resend {
+            self.transmit(flags, seq, data);
+        }
+    }
+
+    /// Process a features event of the node, return true if it belongs to this stream
+    pub fn on_event(&mut self, now: u64, event: &FeaturesEvent) -> bool {
+        match event {
+            FeaturesEvent::Socket(socket::Event::RecvFrom(port, node, remote_port, data, _)) if *port == self.port && (*node, *remote_port) == self.remote => {
+                match Segment::decode(data) {
+                    Some(segment) => self.on_segment(now, segment),
+                    None => log::warn!("[VirtualTcpStream] invalid segment from {node}:{remote_port}"),
+                }
+                true
+            }
+            FeaturesEvent::Socket(socket::Event::Error(port, err)) if self.owns_socket && *port == self.port => {
+                self.fail(*err);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn pop_output(&mut self) -> Option<StreamOutput> {
+        self.queue.pop_front()
+    }
+
+    fn on_segment(&mut self, now: u64, segment: Segment) {
+        if segment.has(FLAG_RST) {
+            if self.state != StreamState::Closed {
+                log::warn!("[VirtualTcpStream] {} => {:?} reset by remote", self.port, self.remote);
+                self.terminate();
+                self.queue.push_back(StreamOutput::Event(StreamEvent::Failed(FeatureError::Rejected)));
+            }
+            return;
+        }
+        match self.state {
+            StreamState::Connecting => {
+                if !segment.has(FLAG_SYN) || !segment.has(FLAG_ACK) || segment.ack != 1 {
+                    return;
+                }
+                self.recv_next = 1;
+                self.on_ack(now, segment.ack, segment.window);
+                self.state = StreamState::Established;
+                log::info!("[VirtualTcpStream] {} => {:?} connected", self.port, self.remote);
+                self.queue.push_back(StreamOutput::Event(StreamEvent::Connected));
+                self.send_ack();
+                self.flush(now);
+                return;
+            }
+            StreamState::Accepting => {
+                if segment.has(FLAG_SYN) {
+                    // our SYN|ACK is lost, it is resent by the retransmission timer
+                    return;
+                }
+                if !segment.has(FLAG_ACK) || segment.ack < 1 {
+                    return;
+                }
+                self.state = StreamState::Established;
+                self.queue.push_back(StreamOutput::Event(StreamEvent::Connected));
+            }
+            StreamState::Established => {
+                if segment.has(FLAG_SYN) {
+                    // our ack of the SYN|ACK is lost
+                    self.send_ack();
+                    return;
+                }
+            }
+            StreamState::Closed => {
+                // the remote did not receive our last ack
+                if !segment.data.is_empty() || segment.has(FLAG_FIN) {
+                    self.send_ack();
+                }
+                return;
+            }
+        }
+
+        if segment.has(FLAG_ACK) {
+            self.on_ack(now, segment.ack, segment.window);
+        }
+        if !segment.data.is_empty() || segment.has(FLAG_FIN) {
+            let fin = segment.has(FLAG_FIN);
+            self.on_data(segment.seq, segment.data, fin);
+            self.send_ack();
+        }
+        self.check_closed();
+    }
+
+    fn on_ack(&mut self, now: u64, ack: u64, window: u32) {
+        if ack < self.send_acked || ack > self.send_next {
+            return;
+        }
+        if ack > self.send_acked {
+            while self.inflight.front().map(|seg| seg.end() <= ack).unwrap_or(false) {
+                self.inflight.pop_front();
+            }
+            self.send_acked = ack;
+            self.rto = STREAM_RTO_MS;
+        }
+        // the remote is alive even when it acks a probe of a closed window without progress
+        self.retries = 0;
+        self.remote_window = window as u64;
+        self.flush(now);
+        if self.write_blocked && self.send_buf.len() < STREAM_SEND_BUFFER {
+            self.write_blocked = false;
+            self.queue.push_back(StreamOutput::Event(StreamEvent::Writable));
+        }
+    }
+
+    fn on_data(&mut self, seq: u64, data: Vec<u8>, fin: bool) {
+        if self.finished {
+            return;
+        }
+        if seq > self.recv_next {
+            if seq - self.recv_next < STREAM_RECV_BUFFER as u64 {
+                self.out_of_order.insert(seq, (data, fin));
+            }
+            return;
+        }
+        let was_empty = self.recv_buf.is_empty();
+        self.append(seq, data, fin);
+        while let Some(entry) = self.out_of_order.first_entry() {
+            if *entry.key() > self.recv_next {
+                break;
+            }
+            let (seq, (data, fin)) = entry.remove_entry();
+            self.append(seq, data, fin);
+        }
+        if was_empty && !self.recv_buf.is_empty() {
+            self.queue.push_back(StreamOutput::Event(StreamEvent::Readable));
+        }
+    }
+
+    /// Append the part of an in order segment which is not received yet, limited by the free space of the receive buffer
+    fn append(&mut self, seq: u64, data: Vec<u8>, fin: bool) {
+        if self.finished {
+            return;
+        }
+        let end = seq + data.len() as u64;
+        if end < self.recv_next || (end == self.recv_next && !fin) {
+            return;
+        }
+        let offset = (self.recv_next - seq) as usize;
+        let len = (data.len() - offset).min(STREAM_RECV_BUFFER - self.recv_buf.len());
+        self.recv_buf.extend(&data[offset..offset + len]);
+        self.recv_next += len as u64;
+        if fin && self.recv_next == end {
+            self.recv_next += 1;
+            self.finished = true;
+            log::info!("[VirtualTcpStream] {} => {:?} finished by remote", self.port, self.remote);
+            self.queue.push_back(StreamOutput::Event(StreamEvent::Finished));
+        }
+    }
+
+    fn flush(&mut self, now: u64) {
+        if self.state != StreamState::Established {
+            return;
+        }
+        while !self.send_buf.is_empty() {
+            // a single byte probes a closed window, the remote answers with its current window
+            let window = if self.inflight.is_empty() {
+                self.remote_window.max(1)
+            } else {
+                self.remote_window
+            };
+            let available = (self.send_acked + window).saturating_sub(self.send_next) as usize;
+            let len = STREAM_MSS.min(self.send_buf.len()).min(available);
+            if len == 0 {
+                break;
+            }
+            let data: Vec<u8> = self.send_buf.drain(..len).collect();
+            self.send_segment(now, FLAG_ACK, data);
+        }
+        if self.closing && !self.fin_sent && self.send_buf.is_empty() {
+            self.fin_sent = true;
+            self.send_segment(now, FLAG_FIN | FLAG_ACK, vec![]);
+        }
+    }
+
+    /// Send a new segment which is retransmitted until it is acknowledged
+    fn send_segment(&mut self, now: u64, flags: u8, data: Vec<u8>) {
+        let segment = Inflight {
+            flags,
+            seq: self.send_next,
+            data,
+            sent_at: now,
+        };
+        self.send_next = segment.end();
+        self.transmit(segment.flags, segment.seq, segment.data.clone());
+        self.inflight.push_back(segment);
+    }
+
+    fn send_ack(&mut self) {
+        self.transmit(FLAG_ACK, self.send_next, vec![]);
+    }
+
+    fn recv_window(&self) -> u64 {
+        (STREAM_RECV_BUFFER - self.recv_buf.len()) as u64
+    }
+
+    fn transmit(&mut self, flags: u8, seq: u64, data: Vec<u8>) {
+        let window = self.recv_window();
+        self.advertised_window = window;
+        let segment = Segment {
+            flags,
+            seq,
+            ack: if flags & FLAG_ACK != 0 {
+                self.recv_next
+            } else {
+                0
+            },
+            window: window as u32,
+            data,
+        };
+        let (node, remote_port) = self.remote;
+        self.control(socket::Control::SendTo(self.port, node, remote_port, segment.encode().into(), 0));
+    }
+
+    fn check_closed(&mut self) {
+        if self.state == StreamState::Established && self.fin_sent && self.finished && self.inflight.is_empty() {
+            log::info!("[VirtualTcpStream] {} => {:?} closed", self.port, self.remote);
+            self.terminate();
+            self.queue.push_back(StreamOutput::Event(StreamEvent::Closed));
+        }
+    }
+
+    fn fail(&mut self, err: FeatureError) {
+        self.transmit(FLAG_RST, self.send_next, vec![]);
+        self.terminate();
+        self.queue.push_back(StreamOutput::Event(StreamEvent::Failed(err)));
+    }
+
+    fn terminate(&mut self) {
+        self.state = StreamState::Closed;
+        self.inflight.clear();
+        self.send_buf.clear();
+        if self.owns_socket {
+            self.control(socket::Control::Unbind(self.port));
+        }
+    }
+
+    fn control<C: Into<FeaturesControl>>(&mut self, control: C) {
+        self.queue.push_back(StreamOutput::Control(control.into()));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenerOutput {
+    Control(FeaturesControl),
+    /// A stream is established, it is available with [`VirtualTcpListener::stream`]
+    Accepted(NodeId, u16),
+    /// Event of an accepted stream, closed and failed streams are removed after their event
+    Stream(NodeId, u16, StreamEvent),
+}
+
+/// Accept streams on a local port, accepted streams share the port and are identified by the remote node and port
+pub struct VirtualTcpListener {
+    port: u16,
+    streams: HashMap<(NodeId, u16), VirtualTcpStream>,
+    queue: VecDeque<ListenerOutput>,
+}
+
+impl VirtualTcpListener {
+    pub fn bind(port: u16) -> Self {
+        log::info!("[VirtualTcpListener] bind {port}");
+        Self {
+            port,
+            streams: HashMap::new(),
+            queue: VecDeque::from([ListenerOutput::Control(socket::Control::Bind(port).into())]),
+        }
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn stream(&mut self, node: NodeId, port: u16) -> Option<&mut VirtualTcpStream> {
+        self.streams.get_mut(&(node, port))
+    }
+
+    /// Abort all streams and unbind the port
+    pub fn close(&mut self) {
+        for stream in self.streams.values_mut() {
+            stream.abort();
+        }
+        self.drain_streams();
+        self.streams.clear();
+        self.queue.push_back(ListenerOutput::Control(socket::Control::Unbind(self.port).into()));
+    }
+
+    pub fn on_tick(&mut self, now: u64) {
+        for stream in self.streams.values_mut() {
+            stream.on_tick(now);
+        }
+    }
+
+    /// Process a features event of the node, return true if it belongs to this listener
+    pub fn on_event(&mut self, now: u64, event: &FeaturesEvent) -> bool {
+        let (node, remote_port, data) = match event {
+            FeaturesEvent::Socket(socket::Event::RecvFrom(port, node, remote_port, data, _)) if *port == self.port => (*node, *remote_port, data),
+            _ => return false,
+        };
+        if let Some(stream) = self.streams.get_mut(&(node, remote_port)) {
+            stream.on_event(now, event);
+            return true;
+        }
+        match Segment::decode(data) {
+            Some(segment) if segment.has(FLAG_SYN) && !segment.has(FLAG_ACK) => {
+                log::info!("[VirtualTcpListener] {} accepting {node}:{remote_port}", self.port);
+                self.streams.insert((node, remote_port), VirtualTcpStream::accept(now, self.port, (node, remote_port)));
+            }
+            Some(segment) if !segment.has(FLAG_RST) => {
+                log::warn!("[VirtualTcpListener] {} reset unknown stream from {node}:{remote_port}", self.port);
+                let reset = Segment {
+                    flags: FLAG_RST,
+                    seq: segment.ack,
+                    ack: 0,
+                    window: 0,
+                    data: vec![],
+                };
+                let control = socket::Control::SendTo(self.port, node, remote_port, reset.encode().into(), 0);
+                self.queue.push_back(ListenerOutput::Control(control.into()));
+            }
+            _ => {}
+        }
+        true
+    }
+
+    pub fn pop_output(&mut self) -> Option<ListenerOutput> {
+        if self.queue.is_empty() {
+            self.drain_streams();
+        }
+        self.queue.pop_front()
+    }
+
+    fn drain_streams(&mut self) {
+        let mut closed = vec![];
+        for (remote, stream) in self.streams.iter_mut() {
+            while let Some(out) = stream.pop_output() {
+                let out = match out {
+                    StreamOutput::Control(control) => ListenerOutput::Control(control),
+                    StreamOutput::Event(StreamEvent::Connected) => ListenerOutput::Accepted(remote.0, remote.1),
+                    StreamOutput::Event(event) => {
+                        if matches!(event, StreamEvent::Closed | StreamEvent::Failed(_)) {
+                            closed.push(*remote);
+                        }
+                        ListenerOutput::Stream(remote.0, remote.1, event)
+                    }
+                };
+                self.queue.push_back(out);
+            }
+        }
+        for remote in closed {
+            self.streams.remove(&remote);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_network::{
+        base::FeatureError,
+        features::{socket, FeaturesControl, FeaturesEvent},
+    };
+
+    use super::{ListenerOutput, StreamEvent, StreamOutput, StreamState, VirtualTcpListener, VirtualTcpStream, STREAM_MAX_RETRIES, STREAM_MAX_RTO_MS, STREAM_RECV_BUFFER};
+
+    const CLIENT: u32 = 1;
+    const SERVER: u32 = 2;
+
+    /// Client stream and server listener which are connected by a lossy in-memory link
+    struct Pair {
+        client: VirtualTcpStream,
+        listener: VirtualTcpListener,
+        client_events: Vec<StreamEvent>,
+        server_events: Vec<ListenerOutput>,
+        /// Drop each n-th packet
+        drop_every: usize,
+        sent: usize,
+    }
+
+    impl Pair {
+        fn new(drop_every: usize) -> Self {
+            Self {
+                client: VirtualTcpStream::connect(0, 20000, SERVER, 10000),
+                listener: VirtualTcpListener::bind(10000),
+                client_events: vec![],
+                server_events: vec![],
+                drop_every,
+                sent: 0,
+            }
+        }
+
+        fn lost(&mut self) -> bool {
+            self.sent += 1;
+            self.drop_every > 0 && self.sent % self.drop_every == 0
+        }
+
+        fn exchange(&mut self, now: u64) {
+            loop {
+                let mut progress = false;
+                while let Some(out) = self.client.pop_output() {
+                    progress = true;
+                    match out {
+                        StreamOutput::Control(FeaturesControl::Socket(socket::Control::SendTo(port, _, dest_port, data, meta))) => {
+                            if !self.lost() {
+                                self.listener.on_event(now, &FeaturesEvent::Socket(socket::Event::RecvFrom(dest_port, CLIENT, port, data, meta)));
+                            }
+                        }
+                        StreamOutput::Control(_) => {}
+                        StreamOutput::Event(event) => self.client_events.push(event),
+                    }
+                }
+                while let Some(out) = self.listener.pop_output() {
+                    progress = true;
+                    match out {
+                        ListenerOutput::Control(FeaturesControl::Socket(socket::Control::SendTo(port, _, dest_port, data, meta))) => {
+                            if !self.lost() {
+                                self.client.on_event(now, &FeaturesEvent::Socket(socket::Event::RecvFrom(dest_port, SERVER, port, data, meta)));
+                            }
+                        }
+                        ListenerOutput::Control(_) => {}
+                        out => self.server_events.push(out),
+                    }
+                }
+                if !progress {
+                    break;
+                }
+            }
+        }
+
+        fn run(&mut self, from: u64, to: u64) {
+            for now in (from..to).step_by(50) {
+                self.client.on_tick(now);
+                self.listener.on_tick(now);
+                self.exchange(now);
+            }
+        }
+    }
+
+    fn read_all(stream: &mut VirtualTcpStream, out: &mut Vec<u8>) {
+        let mut buf = [0; 4096];
+        loop {
+            let len = stream.read(&mut buf);
+            if len == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..len]);
+        }
+    }
+
+    #[test]
+    fn transfer_both_directions_and_close() {
+        let mut pair = Pair::new(0);
+        pair.exchange(0);
+        assert_eq!(pair.client.state(), StreamState::Established);
+        assert_eq!(pair.client_events, vec![StreamEvent::Connected]);
+        assert_eq!(pair.server_events, vec![ListenerOutput::Accepted(CLIENT, 20000)]);
+
+        assert_eq!(pair.client.write(10, b"hello"), 5);
+        pair.exchange(10);
+        let server = pair.listener.stream(CLIENT, 20000).expect("Should have accepted stream");
+        let mut received = vec![];
+        read_all(server, &mut received);
+        assert_eq!(received, b"hello");
+        assert_eq!(server.write(20, b"world"), 5);
+        server.close(20);
+        pair.exchange(20);
+
+        let mut received = vec![];
+        read_all(&mut pair.client, &mut received);
+        assert_eq!(received, b"world");
+        assert!(pair.client.is_eof());
+        pair.client.close(30);
+        pair.exchange(30);
+        assert_eq!(pair.client.state(), StreamState::Closed);
+        assert_eq!(pair.client_events, vec![StreamEvent::Connected, StreamEvent::Readable, StreamEvent::Finished, StreamEvent::Closed]);
+        assert_eq!(
+            pair.server_events[1..],
+            [
+                ListenerOutput::Stream(CLIENT, 20000, StreamEvent::Readable),
+                ListenerOutput::Stream(CLIENT, 20000, StreamEvent::Finished),
+                ListenerOutput::Stream(CLIENT, 20000, StreamEvent::Closed),
+            ]
+        );
+        assert!(pair.listener.stream(CLIENT, 20000).is_none());
+    }
+
+    #[test]
+    fn ordered_delivery_over_lossy_link() {
+        let mut pair = Pair::new(3);
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        assert_eq!(pair.client.write(0, &data), data.len());
+        pair.client.close(0);
+
+        let mut received = vec![];
+        for step in 0..400 {
+            let now = step * 50;
+            pair.run(now, now + 50);
+            if let Some(server) = pair.listener.stream(CLIENT, 20000) {
+                read_all(server, &mut received);
+                if server.is_eof() {
+                    server.close(now);
+                }
+            }
+            if pair.client.state() == StreamState::Closed {
+                break;
+            }
+        }
+        assert_eq!(received.len(), data.len());
+        assert!(received == data);
+        assert_eq!(pair.client_events.last(), Some(&StreamEvent::Closed));
+    }
+
+    #[test]
+    fn sender_is_limited_by_receiver_window() {
+        let mut pair = Pair::new(0);
+        pair.exchange(0);
+        let data = vec![1; STREAM_RECV_BUFFER * 2];
+        let mut written = pair.client.write(0, &data);
+        pair.exchange(0);
+        // the receiver does not read, so only its buffer is filled and the rest waits in the sender
+        let server = pair.listener.stream(CLIENT, 20000).expect("Should have accepted stream");
+        let mut received = vec![];
+        read_all(server, &mut received);
+        assert_eq!(received.len(), STREAM_RECV_BUFFER);
+
+        // reading opens the window again
+        let mut now = 0;
+        while received.len() < data.len() && now < 100_000 {
+            now += 50;
+            if written < data.len() {
+                written += pair.client.write(now, &data[written..]);
+            }
+            pair.run(now, now + 50);
+            let server = pair.listener.stream(CLIENT, 20000).expect("Should have accepted stream");
+            read_all(server, &mut received);
+        }
+        assert_eq!(received.len(), data.len());
+    }
+
+    #[test]
+    fn connect_timeout_without_listener() {
+        let mut client = VirtualTcpStream::connect(0, 20000, SERVER, 10000);
+        let mut now = 0;
+        while client.state() != StreamState::Closed && now < STREAM_MAX_RTO_MS * (STREAM_MAX_RETRIES as u64 + 2) {
+            now += 50;
+            client.on_tick(now);
+        }
+        let mut events = vec![];
+        while let Some(out) = client.pop_output() {
+            match out {
+                StreamOutput::Control(FeaturesControl::Socket(socket::Control::Unbind(port))) => assert_eq!(port, 20000),
+                StreamOutput::Control(_) => {}
+                StreamOutput::Event(event) => events.push(event),
+            }
+        }
+        assert_eq!(events, vec![StreamEvent::Failed(FeatureError::Timeout)]);
+    }
+}
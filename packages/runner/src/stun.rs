@@ -0,0 +1,155 @@
+//! Minimal STUN client (RFC 5389) for discovering the public address of a udp socket.
+//!
+//! Only the Binding request without attributes is sent, and the mapped address is read from XOR-MAPPED-ADDRESS,
+//! or MAPPED-ADDRESS for old servers. The query is sent from the bind address of the node, so on 1:1 NAT the discovered
+//! port is the same one which neighbours will reach.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use rand::{thread_rng, RngCore};
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const HEADER_LEN: usize = 20;
+
+pub(crate) fn binding_request(transaction: &[u8; 12]) -> [u8; HEADER_LEN] {
+    let mut buf = [0u8; HEADER_LEN];
+    buf[0..2].copy_from_slice(&BINDING_REQUEST.to_be_bytes());
+    // length of attributes is zero
+    buf[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    buf[8..20].copy_from_slice(transaction);
+    buf
+}
+
+/// Parse the mapped address of a Binding success response, None if it is not the response of the transaction
+pub(crate) fn parse_binding_response(buf: &[u8], transaction: &[u8; 12]) -> Option<SocketAddr> {
+    if buf.len() < HEADER_LEN || u16::from_be_bytes([buf[0], buf[1]]) != BINDING_SUCCESS || buf[4..8] != MAGIC_COOKIE.to_be_bytes() || buf[8..20] != transaction[..] {
+        return None;
+    }
+    let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    let attrs = buf.get(HEADER_LEN..HEADER_LEN + len)?;
+    let mut mapped = None;
+    let mut offset = 0;
+    while offset + 4 <= attrs.len() {
+        let attr_type = u16::from_be_bytes([attrs[offset], attrs[offset + 1]]);
+        let attr_len = u16::from_be_bytes([attrs[offset + 2], attrs[offset + 3]]) as usize;
+        let value = attrs.get(offset + 4..offset + 4 + attr_len)?;
+        match attr_type {
+            ATTR_XOR_MAPPED_ADDRESS => return parse_address(value, Some(&buf[4..20])),
+            ATTR_MAPPED_ADDRESS => mapped = parse_address(value, None),
+            _ => {}
+        }
+        // attributes are padded to 4 bytes
+        offset += 4 + attr_len.div_ceil(4) * 4;
+    }
+    mapped
+}
+
+/// Parse an address attribute, xor is the magic cookie and transaction id for XOR-MAPPED-ADDRESS
+fn parse_address(value: &[u8], xor: Option<&[u8]>) -> Option<SocketAddr> {
+    let family = *value.get(1)?;
+    let mut port = u16::from_be_bytes([*value.get(2)?, *value.get(3)?]);
+    let mut ip = value.get(4..)?.to_vec();
+    if let Some(xor) = xor {
+        port ^= (MAGIC_COOKIE >> 16) as u16;
+        for (i, b) in ip.iter_mut().enumerate() {
+            *b ^= xor.get(i)?;
+        }
+    }
+    let ip = match (family, ip.len()) {
+        (0x01, 4) => IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3])),
+        (0x02, 16) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip.as_slice()).ok()?)),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Query public address of the bind address from a STUN server, the socket is closed before returning
+pub fn discover_public_addr(bind: SocketAddr, server: SocketAddr, timeout: Duration) -> io::Result<SocketAddr> {
+    let socket = UdpSocket::bind(bind)?;
+    let mut transaction = [0u8; 12];
+    thread_rng().fill_bytes(&mut transaction);
+    socket.send_to(&binding_request(&transaction), server)?;
+
+    let started = Instant::now();
+    let mut buf = [0u8; 1500];
+    loop {
+        let remain = timeout.saturating_sub(started.elapsed());
+        if remain.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, format!("stun server {server} timeout")));
+        }
+        socket.set_read_timeout(Some(remain))?;
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(res) => res,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e),
+        };
+        if from != server {
+            continue;
+        }
+        if let Some(addr) = parse_binding_response(&buf[..len], &transaction) {
+            return Ok(addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, thread, time::Duration};
+
+    use super::{binding_request, discover_public_addr, parse_binding_response, BINDING_SUCCESS, MAGIC_COOKIE};
+
+    /// Binding response with XOR-MAPPED-ADDRESS of `addr`, which is what a STUN server sees
+    fn xor_response(request: &[u8], addr: std::net::SocketAddr) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&BINDING_SUCCESS.to_be_bytes());
+        buf.extend_from_slice(&12u16.to_be_bytes());
+        buf.extend_from_slice(&request[4..20]);
+        buf.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01]);
+        buf.extend_from_slice(&(addr.port() ^ (MAGIC_COOKIE >> 16) as u16).to_be_bytes());
+        match addr.ip() {
+            std::net::IpAddr::V4(ip) => {
+                for (i, b) in ip.octets().iter().enumerate() {
+                    buf.push(b ^ MAGIC_COOKIE.to_be_bytes()[i]);
+                }
+            }
+            std::net::IpAddr::V6(_) => panic!("Should be ipv4"),
+        }
+        buf
+    }
+
+    #[test]
+    fn parse_rfc5769_ipv4_response() {
+        // response test vector from RFC 5769 section 2.2, without the optional attributes after the address
+        let transaction = [0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae];
+        let mut buf = vec![0x01, 0x01, 0x00, 0x0c, 0x21, 0x12, 0xa4, 0x42];
+        buf.extend_from_slice(&transaction);
+        buf.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43]);
+        assert_eq!(parse_binding_response(&buf, &transaction), Some("192.0.2.1:32853".parse().expect("Should parse")));
+        // other transaction is ignored
+        assert_eq!(parse_binding_response(&buf, &[0; 12]), None);
+        assert_eq!(parse_binding_response(&buf[..24], &transaction), None);
+    }
+
+    #[test]
+    fn discover_from_local_server() {
+        let server = UdpSocket::bind("127.0.0.1:0").expect("Should bind");
+        let server_addr = server.local_addr().expect("Should have addr");
+        thread::spawn(move || {
+            let mut buf = [0u8; 1500];
+            let (len, from) = server.recv_from(&mut buf).expect("Should recv");
+            assert_eq!(&buf[..len], &binding_request(buf[8..20].try_into().expect("Should have transaction"))[..]);
+            server.send_to(&xor_response(&buf[..len], from), from).expect("Should send");
+        });
+
+        let bind = UdpSocket::bind("127.0.0.1:0").expect("Should bind").local_addr().expect("Should have addr");
+        assert_eq!(discover_public_addr(bind, server_addr, Duration::from_secs(2)).expect("Should discover"), bind);
+    }
+}
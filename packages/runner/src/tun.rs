@@ -0,0 +1,64 @@
+//! Tun device of the vpn feature on each platform.
+//!
+//! Linux creates a multi-queue device so each worker reads its own queue. macOS only has utun devices with a single queue
+//! which is read by the first worker, and packets carry a 4 bytes packet information header, see the vpn feature.
+//! Windows needs a wintun backend in the io runtime, which is not available yet.
+
+use std::net::Ipv4Addr;
+
+use atm0s_sdn_identity::NodeId;
+use sans_io_runtime::backend::tun::{create_tun, TunDevice};
+
+#[cfg(target_os = "windows")]
+compile_error!("vpn feature is not supported on windows yet: the io runtime does not have a wintun backend");
+
+/// The io runtime only adds the route of the default subnet on macOS
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const RUNTIME_ROUTE: (Ipv4Addr, u8) = (Ipv4Addr::new(10, 33, 33, 0), 24);
+
+pub const TUN_MTU: u16 = 1400;
+
+/// macOS only accepts `utun{N}` names, Linux accepts any name so the same is used
+pub fn tun_name(node_id: NodeId) -> String {
+    format!("utun{}", node_id as u8)
+}
+
+pub fn create(node_id: NodeId, ip: Ipv4Addr, netmask: Ipv4Addr, queues: usize) -> TunDevice {
+    let device = create_tun(&tun_name(node_id), ip, netmask, TUN_MTU, queues);
+    add_route(ip, netmask);
+    device
+}
+
+fn network_of(ip: Ipv4Addr, netmask: Ipv4Addr) -> (Ipv4Addr, u8) {
+    let mask = u32::from(netmask);
+    (Ipv4Addr::from(u32::from(ip) & mask), mask.count_ones() as u8)
+}
+
+/// Linux adds the route of the interface network itself, macOS utun is point-to-point so the route must be added by us
+fn add_route(ip: Ipv4Addr, netmask: Ipv4Addr) {
+    let (network, prefix) = network_of(ip, netmask);
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    if (network, prefix) != RUNTIME_ROUTE {
+        let net = format!("{network}/{prefix}");
+        match std::process::Command::new("route").args(["-n", "add", "-net", &net, &ip.to_string()]).output() {
+            Ok(output) if output.status.success() => log::info!("[Tun] added route {net} via {ip}"),
+            Ok(output) => log::error!("[Tun] add route {net} via {ip} error {}", String::from_utf8_lossy(&output.stderr)),
+            Err(err) => log::error!("[Tun] add route {net} via {ip} error {err}"),
+        }
+        return;
+    }
+    log::info!("[Tun] interface {ip} network {network}/{prefix}");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::network_of;
+
+    #[test]
+    fn network_of_interface() {
+        assert_eq!(network_of(Ipv4Addr::new(10, 33, 33, 5), Ipv4Addr::new(255, 255, 255, 0)), (Ipv4Addr::new(10, 33, 33, 0), 24));
+        assert_eq!(network_of(Ipv4Addr::new(172, 16, 9, 5), Ipv4Addr::new(255, 255, 0, 0)), (Ipv4Addr::new(172, 16, 0, 0), 16));
+    }
+}
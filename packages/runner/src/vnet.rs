@@ -0,0 +1,616 @@
+//! Tokio adapters for virtual sockets of the socket feature.
+//!
+//! [`VirtualNetwork`] is the sans-io side which lives beside the [`crate::SdnController`] loop: controls from
+//! `pop_control` are sent with feature control of the node and features events are fed back with `on_event`, like
+//! [`crate::RoomTransaction`]. The [`VirtualNetworkHandle`] can be cloned into async tasks for creating
+//! [`VirtualUdpSocket`], [`VirtualStream`] and [`VirtualListener`], they only share buffers with the driver so the
+//! controller does not need to be `Send`.
+//!
+//! ```ignore
+//! let (mut vnet, handle) = VirtualNetwork::new();
+//! loop {
+//!     controller.process();
+//!     vnet.on_tick(now_ms);
+//!     while let Some(control) = vnet.pop_control() {
+//!         controller.feature_control((), control);
+//!     }
+//!     while let Some(SdnExtOut::FeaturesEvent(_, event)) = controller.pop_event() {
+//!         vnet.on_event(now_ms, &event);
+//!     }
+//! }
+//! // in an async task
+//! let sock = handle.virtual_udp(0).await?;
+//! ```
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::poll_fn,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_network::{
+    base::FeatureError,
+    features::{socket, FeaturesControl, FeaturesEvent},
+};
+use parking_lot::Mutex;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::stream::{ListenerOutput, StreamEvent, StreamOutput, StreamState, VirtualTcpListener, VirtualTcpStream, STREAM_RECV_BUFFER, STREAM_SEND_BUFFER};
+
+/// Ports which are allocated when binding port 0, same as the ephemeral range of the OS
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+/// Received datagrams which are buffered for a udp socket, newer datagrams are dropped like an OS socket
+const UDP_RECV_QUEUE: usize = 1024;
+const READ_CHUNK: usize = 4096;
+
+/// Local port, remote node and remote port
+type StreamKey = (u16, NodeId, u16);
+
+enum Command {
+    BindUdp(u16),
+    SendTo(u16, NodeId, u16, Vec<u8>),
+    Unbind(u16),
+    Connect(u16, NodeId, u16),
+    Listen(u16),
+    CloseListener(u16),
+}
+
+#[derive(Default)]
+struct UdpSlot {
+    queue: VecDeque<(NodeId, u16, Vec<u8>)>,
+    error: Option<FeatureError>,
+    waker: Option<Waker>,
+}
+
+#[derive(Default)]
+struct StreamSlot {
+    read_buf: VecDeque<u8>,
+    write_buf: VecDeque<u8>,
+    connected: bool,
+    eof: bool,
+    closed: bool,
+    error: Option<FeatureError>,
+    shutdown: bool,
+    dropped: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl StreamSlot {
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[derive(Default)]
+struct ListenerSlot {
+    accepted: VecDeque<StreamKey>,
+    waker: Option<Waker>,
+}
+
+#[derive(Default)]
+struct Shared {
+    ports: HashSet<u16>,
+    next_ephemeral: u16,
+    commands: VecDeque<Command>,
+    udp: HashMap<u16, UdpSlot>,
+    streams: HashMap<StreamKey, StreamSlot>,
+    listeners: HashMap<u16, ListenerSlot>,
+}
+
+impl Shared {
+    fn alloc_port(&mut self, port: u16) -> io::Result<u16> {
+        if port != 0 {
+            return match self.ports.insert(port) {
+                true => Ok(port),
+                false => Err(io::ErrorKind::AddrInUse.into()),
+            };
+        }
+        let range = EPHEMERAL_PORTS.end() - EPHEMERAL_PORTS.start() + 1;
+        for _ in 0..range {
+            let port = EPHEMERAL_PORTS.start() + self.next_ephemeral % range;
+            self.next_ephemeral = self.next_ephemeral.wrapping_add(1);
+            if self.ports.insert(port) {
+                return Ok(port);
+            }
+        }
+        Err(io::ErrorKind::AddrNotAvailable.into())
+    }
+}
+
+fn io_error(err: FeatureError) -> io::Error {
+    let kind = match err {
+        FeatureError::Unreachable => io::ErrorKind::ConnectionRefused,
+        FeatureError::Timeout => io::ErrorKind::TimedOut,
+        FeatureError::Rejected => io::ErrorKind::ConnectionReset,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("{err:?}"))
+}
+
+/// Driver of virtual sockets, see the module docs for wiring it into the controller loop
+pub struct VirtualNetwork {
+    shared: Arc<Mutex<Shared>>,
+    clients: HashMap<u16, VirtualTcpStream>,
+    listeners: HashMap<u16, VirtualTcpListener>,
+    controls: VecDeque<FeaturesControl>,
+}
+
+impl VirtualNetwork {
+    pub fn new() -> (Self, VirtualNetworkHandle) {
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let vnet = Self {
+            shared: shared.clone(),
+            clients: HashMap::new(),
+            listeners: HashMap::new(),
+            controls: VecDeque::new(),
+        };
+        (vnet, VirtualNetworkHandle { shared })
+    }
+
+    /// Apply requests from handles, move buffered data between handles and streams and retransmit streams
+    pub fn on_tick(&mut self, now: u64) {
+        let mut shared = self.shared.lock();
+        while let Some(command) = shared.commands.pop_front() {
+            match command {
+                Command::BindUdp(port) => self.controls.push_back(socket::Control::Bind(port).into()),
+                Command::SendTo(port, node, remote_port, data) => self.controls.push_back(socket::Control::SendTo(port, node, remote_port, data.into(), 0).into()),
+                Command::Unbind(port) => {
+                    shared.ports.remove(&port);
+                    self.controls.push_back(socket::Control::Unbind(port).into());
+                }
+                Command::Connect(port, node, remote_port) => {
+                    self.clients.insert(port, VirtualTcpStream::connect(now, port, node, remote_port));
+                }
+                Command::Listen(port) => {
+                    self.listeners.insert(port, VirtualTcpListener::bind(port));
+                }
+                Command::CloseListener(port) => {
+                    if let Some(mut listener) = self.listeners.remove(&port) {
+                        listener.close();
+                        Self::drain_listener(&mut shared, &mut self.controls, &mut listener);
+                    }
+                    shared.listeners.remove(&port);
+                    shared.ports.remove(&port);
+                }
+            }
+        }
+
+        for stream in self.clients.values_mut() {
+            let (node, remote_port) = stream.remote();
+            Self::sync_stream(now, &mut shared, (stream.local_port(), node, remote_port), stream);
+            stream.on_tick(now);
+        }
+        for listener in self.listeners.values_mut() {
+            let keys: Vec<StreamKey> = shared.streams.keys().filter(|key| key.0 == listener.local_port()).copied().collect();
+            for key in keys {
+                if let Some(stream) = listener.stream(key.1, key.2) {
+                    Self::sync_stream(now, &mut shared, key, stream);
+                }
+            }
+            listener.on_tick(now);
+        }
+        drop(shared);
+        self.drain();
+    }
+
+    /// Process a features event of the node, return true if it belongs to a virtual socket of this network
+    pub fn on_event(&mut self, now: u64, event: &FeaturesEvent) -> bool {
+        let handled = {
+            let mut shared = self.shared.lock();
+            match event {
+                FeaturesEvent::Socket(socket::Event::RecvFrom(port, node, remote_port, data, _)) => match shared.udp.get_mut(port) {
+                    Some(slot) => {
+                        if slot.queue.len() < UDP_RECV_QUEUE {
+                            slot.queue.push_back((*node, *remote_port, data.to_vec()));
+                        } else {
+                            log::warn!("[VirtualNetwork] udp {port} queue full, drop datagram from {node}:{remote_port}");
+                        }
+                        if let Some(waker) = slot.waker.take() {
+                            waker.wake();
+                        }
+                        true
+                    }
+                    None => false,
+                },
+                FeaturesEvent::Socket(socket::Event::Error(port, err)) => match shared.udp.get_mut(port) {
+                    Some(slot) => {
+                        slot.error = Some(*err);
+                        if let Some(waker) = slot.waker.take() {
+                            waker.wake();
+                        }
+                        true
+                    }
+                    None => false,
+                },
+                _ => false,
+            }
+        };
+        let handled = handled || self.listeners.values_mut().any(|listener| listener.on_event(now, event)) || self.clients.values_mut().any(|stream| stream.on_event(now, event));
+        if handled {
+            self.drain();
+        }
+        handled
+    }
+
+    pub fn pop_control(&mut self) -> Option<FeaturesControl> {
+        self.controls.pop_front()
+    }
+
+    fn sync_stream(now: u64, shared: &mut Shared, key: StreamKey, stream: &mut VirtualTcpStream) {
+        let slot = match shared.streams.get_mut(&key) {
+            Some(slot) => slot,
+            None => return,
+        };
+        if slot.dropped {
+            // a dropped handle without shutdown cannot read anymore, so the remote is reset instead of waiting for its data
+            if slot.shutdown {
+                stream.close(now);
+            } else {
+                stream.abort();
+            }
+            shared.streams.remove(&key);
+            return;
+        }
+        if stream.state() == StreamState::Established && !slot.connected {
+            slot.connected = true;
+            slot.wake();
+        }
+        if !slot.write_buf.is_empty() && stream.state() == StreamState::Established {
+            let (front, _) = slot.write_buf.as_slices();
+            let len = stream.write(now, front);
+            if len > 0 {
+                slot.write_buf.drain(..len);
+                slot.wake();
+            }
+        }
+        if slot.shutdown && slot.write_buf.is_empty() {
+            stream.close(now);
+        }
+        Self::pull_stream(slot, stream);
+    }
+
+    /// Move received data into the slot, everything is moved from a closed stream because it is removed after its event
+    fn pull_stream(slot: &mut StreamSlot, stream: &mut VirtualTcpStream) {
+        let mut buf = [0; READ_CHUNK];
+        while slot.read_buf.len() < STREAM_RECV_BUFFER || stream.state() == StreamState::Closed {
+            let len = stream.read(&mut buf);
+            if len == 0 {
+                break;
+            }
+            slot.read_buf.extend(&buf[..len]);
+            slot.wake();
+        }
+        if stream.is_eof() && !slot.eof {
+            slot.eof = true;
+            slot.wake();
+        }
+    }
+
+    fn pull_listener(shared: &mut Shared, listener: &mut VirtualTcpListener) {
+        let port = listener.local_port();
+        for (key, slot) in shared.streams.iter_mut().filter(|(key, _)| key.0 == port) {
+            if let Some(stream) = listener.stream(key.1, key.2) {
+                Self::pull_stream(slot, stream);
+            }
+        }
+    }
+
+    fn on_stream_event(shared: &mut Shared, key: StreamKey, event: StreamEvent) {
+        let slot = match shared.streams.get_mut(&key) {
+            Some(slot) => slot,
+            None => return,
+        };
+        match event {
+            StreamEvent::Connected => slot.connected = true,
+            // received data is pulled before the events, so nothing is left after the stream is closed
+            StreamEvent::Closed => {
+                slot.eof = true;
+                slot.closed = true;
+            }
+            StreamEvent::Failed(err) => slot.error = Some(err),
+            // eof is set when the data before the fin is pulled
+            StreamEvent::Finished | StreamEvent::Readable | StreamEvent::Writable => {}
+        }
+        slot.wake();
+    }
+
+    fn drain_listener(shared: &mut Shared, controls: &mut VecDeque<FeaturesControl>, listener: &mut VirtualTcpListener) {
+        let port = listener.local_port();
+        Self::pull_listener(shared, listener);
+        while let Some(out) = listener.pop_output() {
+            match out {
+                ListenerOutput::Control(control) => controls.push_back(control),
+                ListenerOutput::Accepted(node, remote_port) => {
+                    if let Some(slot) = shared.listeners.get_mut(&port) {
+                        let key = (port, node, remote_port);
+                        shared.streams.insert(
+                            key,
+                            StreamSlot {
+                                connected: true,
+                                ..Default::default()
+                            },
+                        );
+                        slot.accepted.push_back(key);
+                        if let Some(waker) = slot.waker.take() {
+                            waker.wake();
+                        }
+                    }
+                }
+                ListenerOutput::Stream(node, remote_port, event) => Self::on_stream_event(shared, (port, node, remote_port), event),
+            }
+        }
+    }
+
+    fn drain(&mut self) {
+        let mut shared = self.shared.lock();
+        let mut finished = vec![];
+        for (port, stream) in self.clients.iter_mut() {
+            let (node, remote_port) = stream.remote();
+            if let Some(slot) = shared.streams.get_mut(&(*port, node, remote_port)) {
+                Self::pull_stream(slot, stream);
+            }
+            while let Some(out) = stream.pop_output() {
+                match out {
+                    StreamOutput::Control(control) => self.controls.push_back(control),
+                    StreamOutput::Event(event) => Self::on_stream_event(&mut shared, (*port, node, remote_port), event),
+                }
+            }
+            if stream.state() == StreamState::Closed {
+                finished.push(*port);
+            }
+        }
+        for port in finished {
+            // the stream already unbound its port
+            self.clients.remove(&port);
+            shared.ports.remove(&port);
+        }
+        for listener in self.listeners.values_mut() {
+            Self::drain_listener(&mut shared, &mut self.controls, listener);
+        }
+        shared.streams.retain(|_, slot| !(slot.dropped && (slot.closed || slot.error.is_some())));
+    }
+}
+
+/// Cloneable handle for creating virtual sockets from async tasks
+#[derive(Clone)]
+pub struct VirtualNetworkHandle {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl VirtualNetworkHandle {
+    /// Bind a datagram socket, port 0 allocates an ephemeral port
+    pub async fn virtual_udp(&self, port: u16) -> io::Result<VirtualUdpSocket> {
+        let mut shared = self.shared.lock();
+        let port = shared.alloc_port(port)?;
+        shared.udp.insert(port, UdpSlot::default());
+        shared.commands.push_back(Command::BindUdp(port));
+        Ok(VirtualUdpSocket { port, shared: self.shared.clone() })
+    }
+
+    /// Connect a stream from an ephemeral port to a listener of the remote node, resolves when the stream is established
+    pub async fn connect(&self, node: NodeId, remote_port: u16) -> io::Result<VirtualStream> {
+        let stream = {
+            let mut shared = self.shared.lock();
+            let port = shared.alloc_port(0)?;
+            let key = (port, node, remote_port);
+            shared.streams.insert(key, StreamSlot::default());
+            shared.commands.push_back(Command::Connect(port, node, remote_port));
+            VirtualStream { key, shared: self.shared.clone() }
+        };
+        poll_fn(|cx| {
+            let mut shared = stream.shared.lock();
+            let slot = shared.streams.get_mut(&stream.key).expect("should have slot of a living stream");
+            if let Some(err) = slot.error {
+                Poll::Ready(Err(io_error(err)))
+            } else if slot.connected {
+                Poll::Ready(Ok(()))
+            } else {
+                slot.write_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await?;
+        Ok(stream)
+    }
+
+    /// Accept streams on the port, port 0 allocates an ephemeral port
+    pub async fn listen(&self, port: u16) -> io::Result<VirtualListener> {
+        let mut shared = self.shared.lock();
+        let port = shared.alloc_port(port)?;
+        shared.listeners.insert(port, ListenerSlot::default());
+        shared.commands.push_back(Command::Listen(port));
+        Ok(VirtualListener { port, shared: self.shared.clone() })
+    }
+}
+
+/// Datagram socket over the socket feature, the port is unbound on drop
+pub struct VirtualUdpSocket {
+    port: u16,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl VirtualUdpSocket {
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Datagrams are queued to the driver without waiting, so this is always ready
+    pub fn poll_send_to(&self, _cx: &mut Context<'_>, buf: &[u8], node: NodeId, port: u16) -> Poll<io::Result<usize>> {
+        self.shared.lock().commands.push_back(Command::SendTo(self.port, node, port, buf.to_vec()));
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    /// Receive a datagram into the buffer, a longer datagram is truncated
+    pub fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<(usize, NodeId, u16)>> {
+        let mut shared = self.shared.lock();
+        let slot = shared.udp.get_mut(&self.port).expect("should have slot of a living socket");
+        if let Some((node, port, data)) = slot.queue.pop_front() {
+            let len = data.len().min(buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            return Poll::Ready(Ok((len, node, port)));
+        }
+        if let Some(err) = slot.error.take() {
+            return Poll::Ready(Err(io_error(err)));
+        }
+        slot.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    pub async fn send_to(&self, buf: &[u8], node: NodeId, port: u16) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_send_to(cx, buf, node, port)).await
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, NodeId, u16)> {
+        poll_fn(|cx| self.poll_recv_from(cx, buf)).await
+    }
+}
+
+impl Drop for VirtualUdpSocket {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock();
+        shared.udp.remove(&self.port);
+        shared.commands.push_back(Command::Unbind(self.port));
+    }
+}
+
+/// Stream over a [`VirtualTcpStream`] of the driver. Shutdown finishes sending, dropping without shutdown resets the remote
+pub struct VirtualStream {
+    key: StreamKey,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl VirtualStream {
+    pub fn local_port(&self) -> u16 {
+        self.key.0
+    }
+
+    pub fn remote(&self) -> (NodeId, u16) {
+        (self.key.1, self.key.2)
+    }
+}
+
+impl AsyncRead for VirtualStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut shared = self.shared.lock();
+        let slot = shared.streams.get_mut(&self.key).expect("should have slot of a living stream");
+        if !slot.read_buf.is_empty() {
+            let len = buf.remaining().min(slot.read_buf.len());
+            let (front, back) = slot.read_buf.as_slices();
+            let front_len = len.min(front.len());
+            buf.put_slice(&front[..front_len]);
+            buf.put_slice(&back[..len - front_len]);
+            slot.read_buf.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(err) = slot.error {
+            return Poll::Ready(Err(io_error(err)));
+        }
+        if slot.eof {
+            return Poll::Ready(Ok(()));
+        }
+        slot.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for VirtualStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut shared = self.shared.lock();
+        let slot = shared.streams.get_mut(&self.key).expect("should have slot of a living stream");
+        if let Some(err) = slot.error {
+            return Poll::Ready(Err(io_error(err)));
+        }
+        if slot.shutdown || slot.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let len = buf.len().min(STREAM_SEND_BUFFER.saturating_sub(slot.write_buf.len()));
+        if len == 0 {
+            slot.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        slot.write_buf.extend(&buf[..len]);
+        Poll::Ready(Ok(len))
+    }
+
+    /// Ready when all written data is handed to the stream of the driver
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut shared = self.shared.lock();
+        let slot = shared.streams.get_mut(&self.key).expect("should have slot of a living stream");
+        if let Some(err) = slot.error {
+            return Poll::Ready(Err(io_error(err)));
+        }
+        if slot.write_buf.is_empty() || slot.closed {
+            return Poll::Ready(Ok(()));
+        }
+        slot.write_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shared.lock().streams.get_mut(&self.key).expect("should have slot of a living stream").shutdown = true;
+        self.poll_flush(cx)
+    }
+}
+
+impl Drop for VirtualStream {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock();
+        if let Some(slot) = shared.streams.get_mut(&self.key) {
+            if slot.closed || slot.error.is_some() {
+                shared.streams.remove(&self.key);
+            } else {
+                slot.dropped = true;
+            }
+        }
+    }
+}
+
+/// Accept streams on a local port, the port is unbound and pending streams are reset on drop
+pub struct VirtualListener {
+    port: u16,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl VirtualListener {
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<VirtualStream>> {
+        let mut shared = self.shared.lock();
+        let slot = shared.listeners.get_mut(&self.port).expect("should have slot of a living listener");
+        match slot.accepted.pop_front() {
+            Some(key) => Poll::Ready(Ok(VirtualStream { key, shared: self.shared.clone() })),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<VirtualStream> {
+        poll_fn(|cx| self.poll_accept(cx)).await
+    }
+}
+
+impl Drop for VirtualListener {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock();
+        if let Some(slot) = shared.listeners.remove(&self.port) {
+            for key in slot.accepted {
+                shared.streams.remove(&key);
+            }
+        }
+        shared.commands.push_back(Command::CloseListener(self.port));
+    }
+}
@@ -0,0 +1,219 @@
+//! Detect a stuck controller worker.
+//!
+//! The controller runs inside a worker thread, so a stall is detected when the thread comes back: either the next tick
+//! arrives too late (the thread was blocked), or outputs are still popped long after the last tick (livelock where the
+//! runtime never gets back to ticking). A thread which never returns cannot be detected from inside the worker.
+
+use std::time::Duration;
+
+/// Default time without processed ticks before the controller is considered stuck
+pub const WATCHDOG_STALL_MS: u64 = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchdogConfig {
+    /// Time without processed ticks before an alert is emitted
+    pub stall_ms: u64,
+    /// Rebuild the controller and data plane of the worker after an alert
+    pub restart: bool,
+}
+
+impl WatchdogConfig {
+    pub fn new(stall_timeout: Duration, restart: bool) -> Self {
+        Self {
+            stall_ms: stall_timeout.as_millis() as u64,
+            restart,
+        }
+    }
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            stall_ms: WATCHDOG_STALL_MS,
+            restart: false,
+        }
+    }
+}
+
+pub(crate) struct Watchdog {
+    cfg: WatchdogConfig,
+    last_tick_ms: Option<u64>,
+    last_event: &'static str,
+    /// Stall is already reported, avoid reporting it again when the late tick arrives
+    reported: bool,
+}
+
+impl Watchdog {
+    pub fn new(cfg: WatchdogConfig) -> Self {
+        Self {
+            cfg,
+            last_tick_ms: None,
+            last_event: "none",
+            reported: false,
+        }
+    }
+
+    pub fn last_event(&self) -> &'static str {
+        self.last_event
+    }
+
+    pub fn on_event(&mut self, kind: &'static str) {
+        self.last_event = kind;
+    }
+
+    /// Returns the stalled time if the previous tick is too old
+    pub fn on_tick(&mut self, now_ms: u64) -> Option<u64> {
+        let stalled = match self.last_tick_ms {
+            Some(last) if !self.reported && now_ms >= last + self.cfg.stall_ms => Some(now_ms - last),
+            _ => None,
+        };
+        self.last_tick_ms = Some(now_ms);
+        self.reported = false;
+        stalled
+    }
+
+    /// Called while outputs are popped, returns the stalled time once if ticks are not processed for too long
+    pub fn check(&mut self, now_ms: u64) -> Option<u64> {
+        let last = self.last_tick_ms?;
+        if self.reported || now_ms < last + self.cfg.stall_ms {
+            return None;
+        }
+        self.reported = true;
+        Some(now_ms - last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use atm0s_sdn_network::{
+        worker::{ReplicationCfg, SdnWorkerBusEvent},
+        ExtIn, ExtOut,
+    };
+    use sans_io_runtime::{BusChannelControl, BusControl, BusEvent, WorkerInner, WorkerInnerInput, WorkerInnerOutput};
+
+    use crate::worker_inner::{tests::inner_cfg, SdnChannel, SdnOwner, SdnWorkerInner};
+
+    use super::{Watchdog, WatchdogConfig};
+
+    fn watchdog() -> Watchdog {
+        Watchdog::new(WatchdogConfig { stall_ms: 1000, restart: false })
+    }
+
+    #[test]
+    fn detect_late_tick() {
+        let mut watchdog = watchdog();
+        assert_eq!(watchdog.on_tick(0), None);
+        assert_eq!(watchdog.on_tick(100), None);
+        assert_eq!(watchdog.on_tick(1500), Some(1400));
+        assert_eq!(watchdog.on_tick(1600), None);
+    }
+
+    #[test]
+    fn detect_livelock_once() {
+        let mut watchdog = watchdog();
+        assert_eq!(watchdog.check(5000), None);
+        watchdog.on_tick(0);
+        assert_eq!(watchdog.check(999), None);
+        assert_eq!(watchdog.check(1000), Some(1000));
+        assert_eq!(watchdog.check(2000), None);
+        // the late tick belongs to the reported stall
+        assert_eq!(watchdog.on_tick(2500), None);
+        assert_eq!(watchdog.check(3500), Some(1000));
+    }
+
+    #[test]
+    fn restart_stalled_controller_worker() {
+        let cfg = inner_cfg(None, false);
+        let mut worker = SdnWorkerInner::build(0, cfg);
+        let alerts = |worker: &mut SdnWorkerInner<(), (), (), (), ()>, now: Instant| {
+            let mut alerts = vec![];
+            while let Some(out) = worker.pop_output(now) {
+                if let WorkerInnerOutput::Ext(_, ExtOut::WatchdogAlert(alert)) = out {
+                    alerts.push(alert);
+                }
+            }
+            alerts
+        };
+
+        let started = Instant::now();
+        worker.on_tick(started);
+        assert_eq!(alerts(&mut worker, started), vec![]);
+        worker.on_tick(started + Duration::from_millis(100));
+        assert_eq!(alerts(&mut worker, started + Duration::from_millis(100)), vec![]);
+
+        // the thread was blocked for 2 seconds
+        let now = started + Duration::from_millis(2100);
+        worker.on_tick(now);
+        let alerts = alerts(&mut worker, now);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].stalled_ms, 2000);
+        assert!(alerts[0].restarted);
+    }
+
+    #[test]
+    fn standby_takes_over_stalled_controller() {
+        type Worker = SdnWorkerInner<(), (), (), (), ()>;
+        type Output = WorkerInnerOutput<SdnOwner, ExtOut<(), ()>, SdnChannel, SdnWorkerBusEvent<(), (), (), (), ()>, ()>;
+        let replication = ReplicationCfg { interval_ms: 100, takeover_ms: 1000 };
+        let mut active: Worker = SdnWorkerInner::build(0, inner_cfg(Some(replication), false));
+        let mut standby: Worker = SdnWorkerInner::build(1, inner_cfg(Some(replication), true));
+        let outputs = |worker: &mut Worker, now: Instant| {
+            let mut outputs: Vec<Output> = vec![];
+            while let Some(out) = worker.pop_output(now) {
+                outputs.push(out);
+            }
+            outputs
+        };
+
+        let started = Instant::now();
+        standby.on_tick(started);
+        outputs(&mut standby, started);
+        active.on_tick(started);
+        outputs(&mut active, started);
+
+        // the active controller replicates its snapshot to the standby channel
+        let now = started + Duration::from_millis(200);
+        active.on_tick(now);
+        let snapshot = outputs(&mut active, now)
+            .into_iter()
+            .find_map(|out| match out {
+                WorkerInnerOutput::Bus(BusControl::Channel(_, BusChannelControl::Publish(SdnChannel::Standby, _, event))) => Some(event),
+                _ => None,
+            })
+            .expect("Should replicate snapshot");
+        standby.on_event(now, WorkerInnerInput::Bus(BusEvent::Channel(SdnOwner, SdnChannel::Standby, snapshot)));
+        standby.on_tick(now);
+        assert!(!outputs(&mut standby, now).iter().any(|out| matches!(out, WorkerInnerOutput::Ext(_, ExtOut::ControllerTakeover(..)))));
+
+        // the active controller is stalled, so the standby takes over
+        let now = started + Duration::from_millis(1300);
+        standby.on_tick(now);
+        let mut takeover = None;
+        let mut subscribed = false;
+        let mut announced = None;
+        for out in outputs(&mut standby, now) {
+            match out {
+                WorkerInnerOutput::Ext(_, ExtOut::ControllerTakeover(event)) => takeover = Some(event),
+                WorkerInnerOutput::Bus(BusControl::Channel(_, BusChannelControl::Subscribe(SdnChannel::Controller))) => subscribed = true,
+                WorkerInnerOutput::Bus(BusControl::Broadcast(_, event @ SdnWorkerBusEvent::Takeover(1))) => announced = Some(event),
+                _ => {}
+            }
+        }
+        let takeover = takeover.expect("Should take over");
+        assert_eq!((takeover.worker, takeover.connections), (1, 0));
+        assert!(subscribed);
+
+        // the old controller steps down, then forwards external controls to the new one
+        active.on_event(now, WorkerInnerInput::Bus(BusEvent::Broadcast(1, announced.expect("Should announce takeover"))));
+        assert!(outputs(&mut active, now)
+            .iter()
+            .any(|out| matches!(out, WorkerInnerOutput::Bus(BusControl::Channel(_, BusChannelControl::Unsubscribe(SdnChannel::Controller))))));
+        active.on_event(now, WorkerInnerInput::Ext(ExtIn::DisconnectFrom(2)));
+        assert!(outputs(&mut active, now).iter().any(|out| matches!(
+            out,
+            WorkerInnerOutput::Bus(BusControl::Channel(_, BusChannelControl::Publish(SdnChannel::Controller, _, SdnWorkerBusEvent::Ext(..))))
+        )));
+    }
+}
@@ -2,28 +2,40 @@ use std::{
     collections::{HashMap, VecDeque},
     fmt::Debug,
     hash::Hash,
-    net::SocketAddr,
+    io,
+    net::{IpAddr, SocketAddr, UdpSocket},
     sync::Arc,
     time::Instant,
 };
 
-use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_identity::{NodeAddr, NodeId};
 use atm0s_sdn_network::{
-    base::{Authorization, HandshakeBuilder, ServiceBuilder},
-    controller_plane::ControllerPlaneCfg,
-    data_plane::{DataPlaneCfg, NetInput, NetOutput, NetPair},
-    features::{FeaturesControl, FeaturesEvent},
-    worker::{SdnWorker, SdnWorkerBusEvent, SdnWorkerCfg, SdnWorkerInput, SdnWorkerOutput},
-    ExtIn, ExtOut,
+    base::{Authorization, Capabilities, CompressionConfig, HandshakeBuilder, InterfaceEvent, LatencyProfile, LinkProfile, MsgPriority, ServiceBuilder, ServiceId},
+    controller_plane::{ControllerPlaneCfg, EventSink, NeighbourPolicy, StateCheckpoint},
+    data_plane::{fragment::FragmentConfig, multipath::MultipathPolicy, scheduler::SchedulerConfig, shaper::ShapingProfile, DataPlaneCfg, NetInput, NetOutput, NetPair},
+    features::{dht_kv::KvStorageBackend, pubsub::ChannelAuthorizer, FeaturesControl, FeaturesEvent},
+    worker::{ReplicationCfg, SdnWorker, SdnWorkerBusEvent, SdnWorkerCfg, SdnWorkerInput, SdnWorkerOutput},
+    ExtIn, ExtOut, WatchdogAlert,
 };
-use atm0s_sdn_router::shadow::ShadowRouterHistory;
-use rand::rngs::OsRng;
+use atm0s_sdn_router::{core::RoutingPolicy, shadow::ShadowRouterHistory};
+use rand::{rngs::OsRng, RngCore};
 use sans_io_runtime::{
     backend::{BackendIncoming, BackendOutgoing},
-    BusChannelControl, BusControl, BusEvent, Controller, WorkerInner, WorkerInnerInput, WorkerInnerOutput,
+    return_if_none, BusChannelControl, BusControl, BusEvent, Controller, WorkerInner, WorkerInnerInput, WorkerInnerOutput,
 };
 
-use crate::time::TimePivot;
+use crate::{
+    dscp::{self, DscpClasses},
+    metrics::{SdnMetrics, METRICS_UPDATE_INTERVAL_MS},
+    session::{SessionFile, SESSION_REFRESH_MS},
+    time::TimePivot,
+    watchdog::{Watchdog, WatchdogConfig},
+};
+
+/// Interval for retrying failed udp binds, which happen when the network interface is down or the address is changed
+const REBIND_INTERVAL_MS: u64 = 1000;
+/// Interval for checking that bound addresses are still assigned to the host, sockets of a removed address stay open but cannot send
+const ADDR_CHECK_INTERVAL_MS: u64 = 2000;
 
 pub type SdnController<UserData, SC, SE, TC, TW> = Controller<SdnExtIn<UserData, SC>, SdnExtOut<UserData, SE>, SdnSpawnCfg, SdnChannel, SdnEvent<UserData, SC, SE, TC, TW>, 1024>;
 
@@ -37,14 +49,31 @@ pub struct SdnOwner;
 pub enum SdnChannel {
     Controller,
     Worker(u16),
+    /// Snapshots of the active controller, which are received by the standby worker
+    Standby,
 }
 
 pub type SdnEvent<UserData, SC, SE, TC, TW> = SdnWorkerBusEvent<UserData, SC, SE, TC, TW>;
 
 pub struct ControllerCfg {
     pub session: u64,
+    /// File which the running session is saved to, for reusing it after a quick restart
+    pub session_file: Option<Arc<SessionFile>>,
     pub auth: Arc<dyn Authorization>,
     pub handshake: Arc<dyn HandshakeBuilder>,
+    pub profile: LatencyProfile,
+    pub link: LinkProfile,
+    pub dht_kv_storage: Option<Arc<dyn KvStorageBackend>>,
+    pub channel_authorizer: Option<Arc<dyn ChannelAuthorizer>>,
+    pub observer: bool,
+    pub capabilities: Capabilities,
+    pub compression: Option<CompressionConfig>,
+    pub rekey_interval_ms: Option<u64>,
+    pub neighbour_policy: NeighbourPolicy,
+    pub routing_policy: RoutingPolicy,
+    /// State of a previous run, it isn't restored again when the watchdog restarts the controller
+    pub checkpoint: Option<StateCheckpoint>,
+    pub event_sinks: Vec<Arc<dyn EventSink>>,
     #[cfg(feature = "vpn")]
     pub vpn_tun_device: Option<sans_io_runtime::backend::tun::TunDevice>,
 }
@@ -53,24 +82,149 @@ pub struct SdnInnerCfg<UserData, SC, SE, TC, TW> {
     pub node_id: NodeId,
     pub tick_ms: u64,
     pub bind_addrs: Vec<SocketAddr>,
+    /// Bind udp sockets with SO_REUSEPORT, which allows all workers to receive on same addresses
+    pub udp_reuse_port: bool,
+    /// Code points of traffic classes, each distinct one is bound as its own socket on every address
+    pub dscp: DscpClasses,
     pub controller: Option<ControllerCfg>,
     #[allow(clippy::type_complexity)]
     pub services: Vec<Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>,
     pub history: Arc<dyn ShadowRouterHistory>,
+    pub scheduler: Option<SchedulerConfig>,
+    pub bandwidth_limit_kbps: Option<u32>,
+    pub service_shaping: Vec<(ServiceId, ShapingProfile)>,
+    pub multipath: Option<MultipathPolicy>,
+    pub fragment: FragmentConfig,
+    /// Attach incoming route to meta of messages which are received from the network
+    pub incoming_route: bool,
+    /// Shared metrics, the controller worker fills controller metrics and every worker fills its data plane metrics
+    pub metrics: Arc<SdnMetrics>,
+    /// Detect stalls of the controller, only used by the worker which runs the controller
+    pub watchdog: Option<WatchdogConfig>,
+    /// Replicate the controller to a standby worker, used by both the active and the standby worker
+    pub replication: Option<ReplicationCfg>,
+    /// Keep the controller as a standby, which is started when the active controller stops replicating
+    pub standby: bool,
     #[cfg(feature = "vpn")]
     pub vpn_tun_fd: Option<sans_io_runtime::backend::tun::TunFd>,
 }
 
 pub type SdnSpawnCfg = ();
 
+/// Parts of the config for building the worker which runs the controller, it is kept for restarting after stalls
+struct ControllerWorkerCfg<UserData, SC, SE, TC, TW> {
+    node_id: NodeId,
+    tick_ms: u64,
+    bind_addrs: Vec<SocketAddr>,
+    #[allow(clippy::type_complexity)]
+    services: Vec<Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>,
+    history: Arc<dyn ShadowRouterHistory>,
+    scheduler: Option<SchedulerConfig>,
+    bandwidth_limit_kbps: Option<u32>,
+    service_shaping: Vec<(ServiceId, ShapingProfile)>,
+    multipath: Option<MultipathPolicy>,
+    fragment: FragmentConfig,
+    incoming_route: bool,
+    session: u64,
+    auth: Arc<dyn Authorization>,
+    handshake: Arc<dyn HandshakeBuilder>,
+    profile: LatencyProfile,
+    link: LinkProfile,
+    dht_kv_storage: Option<Arc<dyn KvStorageBackend>>,
+    channel_authorizer: Option<Arc<dyn ChannelAuthorizer>>,
+    observer: bool,
+    capabilities: Capabilities,
+    compression: Option<CompressionConfig>,
+    rekey_interval_ms: Option<u64>,
+    neighbour_policy: NeighbourPolicy,
+    routing_policy: RoutingPolicy,
+    event_sinks: Vec<Arc<dyn EventSink>>,
+    replication: Option<ReplicationCfg>,
+    standby: bool,
+}
+
+impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Debug, TW: Debug> ControllerWorkerCfg<UserData, SC, SE, TC, TW> {
+    fn build(&self, worker: u16, checkpoint: Option<StateCheckpoint>) -> SdnWorker<UserData, SC, SE, TC, TW> {
+        let (controller, standby) = if self.standby {
+            (None, Some(self.controller_cfg()))
+        } else {
+            (Some(ControllerPlaneCfg { checkpoint, ..self.controller_cfg() }), None)
+        };
+        SdnWorker::new(SdnWorkerCfg {
+            node_id: self.node_id,
+            tick_ms: self.tick_ms,
+            controller,
+            standby,
+            replication: self.replication,
+            data: DataPlaneCfg {
+                worker_id: worker,
+                services: self.services.clone(),
+                history: self.history.clone(),
+                scheduler: self.scheduler.clone(),
+                bandwidth_limit_kbps: self.bandwidth_limit_kbps,
+                service_shaping: self.service_shaping.clone(),
+                multipath: self.multipath,
+                incoming_route: self.incoming_route,
+                sticky_ext: None,
+                fragment: self.fragment,
+            },
+        })
+    }
+
+    fn controller_cfg(&self) -> ControllerPlaneCfg<UserData, SC, SE, TC, TW> {
+        ControllerPlaneCfg {
+            bind_addrs: self.bind_addrs.clone(),
+            authorization: self.auth.clone(),
+            handshake_builder: self.handshake.clone(),
+            session: self.session,
+            random: Box::new(OsRng),
+            services: self.services.clone(),
+            history: self.history.clone(),
+            profile: self.profile,
+            link: self.link,
+            dht_kv_storage: self.dht_kv_storage.clone(),
+            channel_authorizer: self.channel_authorizer.clone(),
+            observer: self.observer,
+            capabilities: self.capabilities,
+            compression: self.compression.clone(),
+            rekey_interval_ms: self.rekey_interval_ms,
+            neighbour_policy: self.neighbour_policy.clone(),
+            sticky_ext: None,
+            routing_policy: self.routing_policy,
+            checkpoint: None,
+            event_sinks: self.event_sinks.clone(),
+        }
+    }
+}
+
 pub struct SdnWorkerInner<UserData, SC, SE, TC, TW> {
     worker: u16,
     worker_inner: SdnWorker<UserData, SC, SE, TC, TW>,
     timer: TimePivot,
     #[cfg(feature = "vpn")]
     _vpn_tun_device: Option<sans_io_runtime::backend::tun::TunDevice>,
-    bind_addrs: HashMap<SocketAddr, usize>,
+    udp_reuse_port: bool,
+    dscp: DscpClasses,
+    /// Sockets of bound addresses by DSCP code point, the socket of the normal class is always bound first
+    bind_addrs: HashMap<SocketAddr, HashMap<u8, usize>>,
     bind_slots: HashMap<usize, SocketAddr>,
+    /// Code points of udp listens which are waiting for results, by requested address
+    binding: HashMap<SocketAddr, VecDeque<u8>>,
+    rebind_addrs: Vec<SocketAddr>,
+    last_rebind_ms: u64,
+    last_addr_check_ms: u64,
+    metrics: Arc<SdnMetrics>,
+    last_metrics_ms: Option<u64>,
+    watchdog: Option<Watchdog>,
+    /// Only kept when the watchdog is allowed to restart the controller
+    rebuild: Option<ControllerWorkerCfg<UserData, SC, SE, TC, TW>>,
+    /// Running session and the file which it is refreshed to, only used by the worker which runs the controller
+    session_file: Option<(Arc<SessionFile>, u64)>,
+    last_session_save_ms: u64,
+    /// Addresses which are asked to connect, they are connected again after restart
+    connect_history: Vec<NodeAddr>,
+    /// Subscribed to the controller channel, it follows the controller after a takeover
+    controller_channel: bool,
     #[cfg(feature = "vpn")]
     tun_backend_slot: Option<usize>,
     #[allow(clippy::type_complexity)]
@@ -92,13 +246,13 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
             }
             SdnWorkerOutput::Net(net) => {
                 let out = match net {
-                    NetOutput::UdpPacket(pair, data) => BackendOutgoing::UdpPacket {
-                        slot: *self.bind_addrs.get(&pair.local)?,
+                    NetOutput::UdpPacket(pair, data, priority) => BackendOutgoing::UdpPacket {
+                        slot: self.slot(&pair.local, priority)?,
                         to: pair.remote,
                         data,
                     },
-                    NetOutput::UdpPackets(pairs, data) => {
-                        let to = pairs.into_iter().filter_map(|p| self.bind_addrs.get(&p.local).map(|s| (*s, p.remote))).collect::<Vec<_>>();
+                    NetOutput::UdpPackets(pairs, data, priority) => {
+                        let to = pairs.into_iter().filter_map(|p| self.slot(&p.local, priority).map(|s| (s, p.remote))).collect::<Vec<_>>();
                         BackendOutgoing::UdpPackets2 { to, data }
                     }
                     #[cfg(feature = "vpn")]
@@ -116,6 +270,12 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
                     SdnOwner,
                     BusChannelControl::Publish(SdnChannel::Worker(*worker), true, event),
                 ))),
+                SdnWorkerBusEvent::Ext(..) => Some(WorkerInnerOutput::Bus(BusControl::Channel(SdnOwner, BusChannelControl::Publish(SdnChannel::Controller, true, event)))),
+                SdnWorkerBusEvent::Replicate(..) => Some(WorkerInnerOutput::Bus(BusControl::Channel(SdnOwner, BusChannelControl::Publish(SdnChannel::Standby, true, event)))),
+                SdnWorkerBusEvent::Takeover(..) => {
+                    self.sync_controller_channel();
+                    Some(WorkerInnerOutput::Bus(BusControl::Broadcast(true, event)))
+                }
             },
             SdnWorkerOutput::Continue => {
                 //we need to continue pop for continue gather output
@@ -125,6 +285,100 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
             SdnWorkerOutput::OnResourceEmpty => Some(WorkerInnerOutput::Continue),
         }
     }
+
+    /// Socket of the traffic class on a local address, classes whose socket isn't bound are sent from the normal class socket
+    fn slot(&self, local: &SocketAddr, priority: MsgPriority) -> Option<usize> {
+        let slots = self.bind_addrs.get(local)?;
+        slots.get(&self.dscp.get(priority)).or_else(|| slots.get(&self.dscp.normal)).copied()
+    }
+
+    /// Bind a udp socket which is marked with the code point by the backend
+    fn listen(&mut self, addr: SocketAddr, dscp: u8) {
+        self.binding.entry(addr).or_default().push_back(dscp);
+        dscp::request(addr, dscp);
+        self.queue.push_back(WorkerInnerOutput::Net(SdnOwner, BackendOutgoing::UdpListen { addr, reuse: self.udp_reuse_port }));
+    }
+
+    /// Release sockets of addresses which are removed from the host, they are bound again when the address is back
+    fn check_addrs(&mut self, now_ms: u64) {
+        let lost: Vec<_> = self.bind_addrs.keys().filter(|addr| !is_host_ip(addr.ip())).copied().collect();
+        for addr in lost {
+            log::warn!("Worker {} addr {addr} is removed from the host, will rebind after {REBIND_INTERVAL_MS} ms", self.worker);
+            for slot in self.bind_addrs.remove(&addr).unwrap_or_default().into_values() {
+                self.bind_slots.remove(&slot);
+                self.queue.push_back(WorkerInnerOutput::Net(SdnOwner, BackendOutgoing::UdpUnlisten { slot }));
+            }
+            self.last_rebind_ms = now_ms;
+            self.rebind_addrs.push(addr);
+            self.worker_inner.on_event(now_ms, SdnWorkerInput::Net(NetInput::Interface(InterfaceEvent::Down(addr))));
+        }
+    }
+
+    /// Code point of the oldest udp listen to the address, listens of an address are answered in order
+    fn take_binding(&mut self, bind: SocketAddr) -> u8 {
+        let queue = match self.binding.get_mut(&bind) {
+            Some(queue) => queue,
+            None => return self.dscp.normal,
+        };
+        let dscp = queue.pop_front().unwrap_or(self.dscp.normal);
+        if queue.is_empty() {
+            self.binding.remove(&bind);
+        }
+        dscp
+    }
+
+    /// Follow the controller after a takeover: the new controller worker subscribes to controls and the old one unsubscribes
+    fn sync_controller_channel(&mut self) {
+        let has_controller = self.worker_inner.has_controller();
+        if self.controller_channel == has_controller {
+            return;
+        }
+        self.controller_channel = has_controller;
+        let control = if has_controller {
+            BusChannelControl::Subscribe(SdnChannel::Controller)
+        } else {
+            BusChannelControl::Unsubscribe(SdnChannel::Controller)
+        };
+        log::info!("[SdnWorkerInner] worker {} controller channel {:?}", self.worker, control);
+        self.queue.push_back(WorkerInnerOutput::Bus(BusControl::Channel(SdnOwner, control)));
+    }
+
+    fn on_stall(&mut self, now_ms: u64, stalled_ms: u64) {
+        let watchdog = return_if_none!(self.watchdog.as_ref());
+        let alert = WatchdogAlert {
+            stalled_ms,
+            last_event: watchdog.last_event(),
+            worker_queue: self.queue.len(),
+            controller_queue: self.worker_inner.controller_queue_len().unwrap_or(0),
+            restarted: self.rebuild.is_some() && !self.shutdown,
+        };
+        log::error!("[SdnWorkerInner] worker {} controller stalled: {:?}", self.worker, alert);
+        if alert.restarted {
+            self.restart_controller(now_ms);
+        }
+        self.queue.push_back(WorkerInnerOutput::Ext(true, ExtOut::WatchdogAlert(alert)));
+    }
+
+    /// Replace the stuck worker with a new one, which runs with a new session so neighbours treat it as a new node instance
+    fn restart_controller(&mut self, now_ms: u64) {
+        let rebuild = return_if_none!(self.rebuild.as_mut());
+        rebuild.session = OsRng.next_u64();
+        log::warn!("[SdnWorkerInner] worker {} restart controller with session {}", self.worker, rebuild.session);
+        if let Some((file, session)) = &mut self.session_file {
+            *session = rebuild.session;
+            self.last_session_save_ms = now_ms;
+            if let Err(e) = file.save(*session) {
+                log::error!("[SdnWorkerInner] save session file error {e}");
+            }
+        }
+        self.worker_inner = rebuild.build(self.worker, None);
+        for addr in self.bind_addrs.keys() {
+            self.worker_inner.on_event(now_ms, SdnWorkerInput::Net(NetInput::Interface(InterfaceEvent::Up(*addr))));
+        }
+        for addr in &self.connect_history {
+            self.worker_inner.on_event(now_ms, SdnWorkerInput::Ext(ExtIn::ConnectTo(addr.clone())));
+        }
+    }
 }
 
 impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Debug, TW: Debug>
@@ -133,45 +387,75 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
 {
     fn build(worker: u16, cfg: SdnInnerCfg<UserData, SC, SE, TC, TW>) -> Self {
         let mut queue = VecDeque::from([WorkerInnerOutput::Bus(BusControl::Channel(SdnOwner, BusChannelControl::Subscribe(SdnChannel::Worker(worker))))]);
-
-        for addr in &cfg.bind_addrs {
-            queue.push_back(WorkerInnerOutput::Net(SdnOwner, BackendOutgoing::UdpListen { addr: *addr, reuse: true }));
-        }
+        let bind_addrs = cfg.bind_addrs.clone();
 
         #[cfg(feature = "vpn")]
         if let Some(fd) = cfg.vpn_tun_fd {
             queue.push_back(WorkerInnerOutput::Net(SdnOwner, BackendOutgoing::TunBind { fd }));
         }
-        if let Some(controller) = cfg.controller {
-            queue.push_back(WorkerInnerOutput::Bus(BusControl::Channel(SdnOwner, BusChannelControl::Subscribe(SdnChannel::Controller))));
-            log::info!("Create controller worker");
+        let mut inner = if let Some(controller) = cfg.controller {
+            if cfg.standby {
+                log::info!("Create standby controller worker");
+                queue.push_back(WorkerInnerOutput::Bus(BusControl::Channel(SdnOwner, BusChannelControl::Subscribe(SdnChannel::Standby))));
+            } else {
+                log::info!("Create controller worker");
+                queue.push_back(WorkerInnerOutput::Bus(BusControl::Channel(SdnOwner, BusChannelControl::Subscribe(SdnChannel::Controller))));
+            }
+            let controller_cfg = ControllerWorkerCfg {
+                node_id: cfg.node_id,
+                tick_ms: cfg.tick_ms,
+                bind_addrs: cfg.bind_addrs,
+                services: cfg.services,
+                history: cfg.history,
+                scheduler: cfg.scheduler,
+                bandwidth_limit_kbps: cfg.bandwidth_limit_kbps,
+                service_shaping: cfg.service_shaping,
+                multipath: cfg.multipath,
+                fragment: cfg.fragment,
+                incoming_route: cfg.incoming_route,
+                session: controller.session,
+                auth: controller.auth,
+                handshake: controller.handshake,
+                profile: controller.profile,
+                link: controller.link,
+                dht_kv_storage: controller.dht_kv_storage,
+                channel_authorizer: controller.channel_authorizer,
+                observer: controller.observer,
+                capabilities: controller.capabilities,
+                compression: controller.compression,
+                rekey_interval_ms: controller.rekey_interval_ms,
+                neighbour_policy: controller.neighbour_policy,
+                routing_policy: controller.routing_policy,
+                event_sinks: controller.event_sinks,
+                replication: cfg.replication,
+                standby: cfg.standby,
+            };
+            // the standby takes over instead of restarting, and a restart would compete with it
+            let watchdog = cfg.watchdog.filter(|_| !cfg.standby);
             Self {
                 worker,
-                worker_inner: SdnWorker::new(SdnWorkerCfg {
-                    node_id: cfg.node_id,
-                    tick_ms: cfg.tick_ms,
-                    controller: Some(ControllerPlaneCfg {
-                        bind_addrs: cfg.bind_addrs,
-                        authorization: controller.auth,
-                        handshake_builder: controller.handshake,
-                        session: controller.session,
-                        random: Box::new(OsRng),
-                        services: cfg.services.clone(),
-                        history: cfg.history.clone(),
-                    }),
-                    data: DataPlaneCfg {
-                        worker_id: worker,
-                        services: cfg.services,
-                        history: cfg.history,
-                    },
-                }),
+                worker_inner: controller_cfg.build(worker, controller.checkpoint),
                 timer: TimePivot::build(),
                 #[cfg(feature = "vpn")]
                 _vpn_tun_device: controller.vpn_tun_device,
                 queue,
                 shutdown: false,
+                udp_reuse_port: cfg.udp_reuse_port,
+                dscp: cfg.dscp,
                 bind_addrs: Default::default(),
                 bind_slots: Default::default(),
+                binding: Default::default(),
+                rebind_addrs: Default::default(),
+                last_rebind_ms: 0,
+                last_addr_check_ms: 0,
+                metrics: cfg.metrics,
+                last_metrics_ms: None,
+                watchdog: watchdog.map(Watchdog::new),
+                rebuild: watchdog.filter(|w| w.restart && cfg.replication.is_none()).map(|_| controller_cfg),
+                session_file: controller.session_file.map(|file| (file, controller.session)),
+                last_session_save_ms: 0,
+                connect_history: vec![],
+                controller_channel: !cfg.standby,
                 #[cfg(feature = "vpn")]
                 tun_backend_slot: None,
             }
@@ -183,10 +467,19 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
                     node_id: cfg.node_id,
                     tick_ms: cfg.tick_ms,
                     controller: None,
+                    standby: None,
+                    replication: None,
                     data: DataPlaneCfg {
                         worker_id: worker,
                         services: cfg.services,
                         history: cfg.history,
+                        scheduler: cfg.scheduler,
+                        bandwidth_limit_kbps: cfg.bandwidth_limit_kbps,
+                        service_shaping: cfg.service_shaping,
+                        multipath: cfg.multipath,
+                        incoming_route: cfg.incoming_route,
+                        sticky_ext: None,
+                        fragment: cfg.fragment,
                     },
                 }),
                 timer: TimePivot::build(),
@@ -194,12 +487,30 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
                 _vpn_tun_device: None,
                 queue,
                 shutdown: false,
+                udp_reuse_port: cfg.udp_reuse_port,
+                dscp: cfg.dscp,
                 bind_addrs: Default::default(),
                 bind_slots: Default::default(),
+                binding: Default::default(),
+                rebind_addrs: Default::default(),
+                last_rebind_ms: 0,
+                last_addr_check_ms: 0,
+                metrics: cfg.metrics,
+                last_metrics_ms: None,
+                watchdog: None,
+                rebuild: None,
+                session_file: None,
+                last_session_save_ms: 0,
+                connect_history: vec![],
+                controller_channel: false,
                 #[cfg(feature = "vpn")]
                 tun_backend_slot: None,
             }
+        };
+        for addr in bind_addrs {
+            inner.listen(addr, inner.dscp.normal);
         }
+        inner
     }
 
     fn worker_index(&self) -> u16 {
@@ -220,22 +531,88 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
 
     fn on_tick(&mut self, now: Instant) {
         let now_ms = self.timer.timestamp_ms(now);
+        if !self.shutdown && now_ms >= self.last_addr_check_ms + ADDR_CHECK_INTERVAL_MS {
+            self.last_addr_check_ms = now_ms;
+            self.check_addrs(now_ms);
+        }
+        if !self.shutdown && !self.rebind_addrs.is_empty() && now_ms >= self.last_rebind_ms + REBIND_INTERVAL_MS {
+            self.last_rebind_ms = now_ms;
+            for addr in std::mem::take(&mut self.rebind_addrs) {
+                log::info!("Worker {} retry bind addr {addr}", self.worker);
+                self.listen(addr, self.dscp.normal);
+            }
+        }
+        if let Some(stalled_ms) = self.watchdog.as_mut().and_then(|w| w.on_tick(now_ms)) {
+            self.on_stall(now_ms, stalled_ms);
+        }
         self.worker_inner.on_tick(now_ms);
+        if self.last_metrics_ms.map_or(true, |last| now_ms >= last + METRICS_UPDATE_INTERVAL_MS) {
+            self.last_metrics_ms = Some(now_ms);
+            if let Some(latest) = self.worker_inner.controller_metrics() {
+                self.metrics.update(latest);
+            }
+            self.metrics.update_data(self.worker, self.worker_inner.data_metrics());
+        }
+        if let Some((file, session)) = &self.session_file {
+            if !self.shutdown && now_ms >= self.last_session_save_ms + SESSION_REFRESH_MS {
+                self.last_session_save_ms = now_ms;
+                if let Err(e) = file.save(*session) {
+                    log::error!("[SdnWorkerInner] save session file error {e}");
+                }
+            }
+        }
     }
 
     fn on_event(&mut self, now: Instant, event: WorkerInnerInput<SdnOwner, SdnExtIn<UserData, SC>, SdnChannel, SdnEvent<UserData, SC, SE, TC, TW>>) {
         let now_ms = self.timer.timestamp_ms(now);
+        if let Some(watchdog) = &mut self.watchdog {
+            watchdog.on_event(match &event {
+                WorkerInnerInput::Net(_, BackendIncoming::UdpPacket { .. }) => "net.udp_packet",
+                WorkerInnerInput::Net(..) => "net.other",
+                WorkerInnerInput::Bus(..) => "bus",
+                WorkerInnerInput::Ext(..) => "ext",
+            });
+        }
+        if self.rebuild.is_some() {
+            match &event {
+                WorkerInnerInput::Ext(ExtIn::ConnectTo(addr)) => {
+                    self.connect_history.retain(|a| a.node_id() != addr.node_id());
+                    self.connect_history.push(addr.clone());
+                }
+                WorkerInnerInput::Ext(ExtIn::DisconnectFrom(node)) => self.connect_history.retain(|a| a.node_id() != *node),
+                _ => {}
+            }
+        }
         match event {
             WorkerInnerInput::Net(_, event) => match event {
-                BackendIncoming::UdpListenResult { bind: _, result } => {
-                    if let Ok((addr, slot)) = result {
-                        log::info!("Worker {} bind addr {addr} to slot {slot}", self.worker);
-                        self.bind_addrs.insert(addr, slot);
+                BackendIncoming::UdpListenResult { bind, result } => match (self.take_binding(bind), result) {
+                    (dscp, Ok((addr, slot))) => {
+                        log::info!("Worker {} bind addr {addr} with dscp {dscp} to slot {slot}", self.worker);
+                        self.bind_addrs.entry(addr).or_default().insert(dscp, slot);
                         self.bind_slots.insert(slot, addr);
+                        if dscp == self.dscp.normal {
+                            // other classes bind the resolved address, which has the port when binding port 0
+                            if !self.shutdown {
+                                for point in self.dscp.code_points().into_iter().skip(1) {
+                                    self.listen(addr, point);
+                                }
+                            }
+                            self.worker_inner.on_event(now_ms, SdnWorkerInput::Net(NetInput::Interface(InterfaceEvent::Up(addr))));
+                        }
                     }
-                }
+                    (dscp, Err(err)) if dscp != self.dscp.normal => {
+                        log::warn!("Worker {} bind addr {bind} with dscp {dscp} error {err}, the class is sent with dscp {}", self.worker, self.dscp.normal);
+                    }
+                    (_, Err(err)) => {
+                        log::warn!("Worker {} bind addr {bind} error {err}, will retry after {REBIND_INTERVAL_MS} ms", self.worker);
+                        self.last_rebind_ms = now_ms;
+                        self.rebind_addrs.push(bind);
+                        self.worker_inner.on_event(now_ms, SdnWorkerInput::Net(NetInput::Interface(InterfaceEvent::Down(bind))));
+                    }
+                },
                 BackendIncoming::UdpPacket { slot, from, data } => {
-                    let local = *self.bind_slots.get(&slot).expect("Should have local addr");
+                    // packets which are received before the socket of a removed address is released are dropped
+                    let local = *return_if_none!(self.bind_slots.get(&slot));
                     let pair = NetPair::new(local, from);
                     self.worker_inner.on_event(now_ms, SdnWorkerInput::Net(NetInput::UdpPacket(pair, data)))
                 }
@@ -246,10 +623,13 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
                 #[cfg(feature = "vpn")]
                 BackendIncoming::TunPacket { slot: _, data } => self.worker_inner.on_event(now_ms, SdnWorkerInput::Net(NetInput::TunPacket(data))),
             },
-            WorkerInnerInput::Bus(event) => match event {
-                BusEvent::Broadcast(_from_worker, msg) => self.worker_inner.on_event(now_ms, SdnWorkerInput::Bus(msg)),
-                BusEvent::Channel(_, _, msg) => self.worker_inner.on_event(now_ms, SdnWorkerInput::Bus(msg)),
-            },
+            WorkerInnerInput::Bus(event) => {
+                match event {
+                    BusEvent::Broadcast(_from_worker, msg) => self.worker_inner.on_event(now_ms, SdnWorkerInput::Bus(msg)),
+                    BusEvent::Channel(_, _, msg) => self.worker_inner.on_event(now_ms, SdnWorkerInput::Bus(msg)),
+                }
+                self.sync_controller_channel();
+            }
             WorkerInnerInput::Ext(ext) => self.worker_inner.on_event(now_ms, SdnWorkerInput::Ext(ext)),
         };
     }
@@ -259,6 +639,10 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
             return Some(e);
         }
         let now_ms = self.timer.timestamp_ms(now);
+        if let Some(stalled_ms) = self.watchdog.as_mut().and_then(|w| w.check(now_ms)) {
+            self.on_stall(now_ms, stalled_ms);
+            return self.queue.pop_front();
+        }
         let out = self.worker_inner.pop_output2(now_ms)?;
         self.convert_output(now_ms, out)
     }
@@ -269,9 +653,205 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
         }
         let now_ms = self.timer.timestamp_ms(now);
         self.worker_inner.on_shutdown(now_ms);
-        for slot in self.bind_addrs.values() {
+        for slot in self.bind_addrs.values().flat_map(|slots| slots.values()) {
             self.queue.push_back(WorkerInnerOutput::Net(SdnOwner, BackendOutgoing::UdpUnlisten { slot: *slot }));
         }
         self.shutdown = true;
     }
 }
+
+/// An address is removed from the host if a probe socket cannot be bound to its ip anymore
+fn is_host_ip(ip: IpAddr) -> bool {
+    if ip.is_unspecified() {
+        return true;
+    }
+    match UdpSocket::bind(SocketAddr::new(ip, 0)) {
+        Ok(_) => true,
+        Err(e) => e.kind() != io::ErrorKind::AddrNotAvailable,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use atm0s_sdn_identity::{NodeAddrBuilder, Protocol};
+    use atm0s_sdn_network::{
+        base::{Capabilities, InterfaceEvent, MsgPriority},
+        secure::{HandshakeBuilderXDA, StaticKeyAuthorization},
+        worker::{ReplicationCfg, SdnWorkerBusEvent},
+        ExtIn, LogicControl,
+    };
+    use sans_io_runtime::{
+        backend::{BackendIncoming, BackendOutgoing},
+        BusChannelControl, BusControl, WorkerInner, WorkerInnerInput, WorkerInnerOutput,
+    };
+
+    use crate::{
+        dscp::{self, DscpClasses, DSCP_EF},
+        history::DataWorkerHistory,
+        metrics::SdnMetrics,
+        watchdog::WatchdogConfig,
+    };
+
+    use super::{ControllerCfg, SdnInnerCfg, SdnOwner, SdnWorkerInner};
+
+    type Worker = SdnWorkerInner<(), (), (), (), ()>;
+
+    pub(crate) fn inner_cfg(replication: Option<ReplicationCfg>, standby: bool) -> SdnInnerCfg<(), (), (), (), ()> {
+        SdnInnerCfg::<(), (), (), (), ()> {
+            node_id: 1,
+            tick_ms: 100,
+            bind_addrs: vec![],
+            udp_reuse_port: false,
+            dscp: Default::default(),
+            controller: Some(ControllerCfg {
+                session: 0,
+                session_file: None,
+                auth: Arc::new(StaticKeyAuthorization::new("password")),
+                handshake: Arc::new(HandshakeBuilderXDA),
+                profile: Default::default(),
+                link: Default::default(),
+                dht_kv_storage: None,
+                channel_authorizer: None,
+                observer: false,
+                capabilities: Capabilities::SUPPORTED,
+                compression: None,
+                rekey_interval_ms: None,
+                neighbour_policy: Default::default(),
+                routing_policy: Default::default(),
+                checkpoint: None,
+                event_sinks: vec![],
+                #[cfg(feature = "vpn")]
+                vpn_tun_device: None,
+            }),
+            services: vec![],
+            history: Arc::new(DataWorkerHistory::default()),
+            scheduler: None,
+            bandwidth_limit_kbps: None,
+            service_shaping: vec![],
+            multipath: None,
+            fragment: Default::default(),
+            incoming_route: false,
+            metrics: Arc::new(SdnMetrics::new(1)),
+            watchdog: Some(WatchdogConfig::new(Duration::from_secs(1), true)),
+            replication,
+            standby,
+            #[cfg(feature = "vpn")]
+            vpn_tun_fd: None,
+        }
+    }
+
+    /// Net actions and interface events which are sent to the controller through the bus
+    fn outputs(worker: &mut Worker, now: Instant) -> (Vec<BackendOutgoing>, Vec<(u16, InterfaceEvent)>) {
+        let mut net = vec![];
+        let mut interfaces = vec![];
+        while let Some(out) = worker.pop_output(now) {
+            match out {
+                WorkerInnerOutput::Net(_, out) => net.push(out),
+                WorkerInnerOutput::Bus(BusControl::Channel(_, BusChannelControl::Publish(_, _, SdnWorkerBusEvent::Control(LogicControl::NetInterface(worker, event))))) => {
+                    interfaces.push((worker, event))
+                }
+                _ => {}
+            }
+        }
+        (net, interfaces)
+    }
+
+    fn net_outputs(worker: &mut Worker, now: Instant) -> Vec<BackendOutgoing> {
+        outputs(worker, now).0
+    }
+
+    fn listen_result(worker: &mut Worker, now: Instant, bind: SocketAddr, local: SocketAddr, slot: usize) {
+        worker.on_event(now, WorkerInnerInput::Net(SdnOwner, BackendIncoming::UdpListenResult { bind, result: Ok((local, slot)) }));
+    }
+
+    #[test]
+    fn classes_are_sent_from_sockets_of_their_dscp() {
+        let local = SocketAddr::from(([127, 0, 0, 1], 10000));
+        let mut cfg = inner_cfg(None, false);
+        cfg.bind_addrs = vec![local];
+        cfg.udp_reuse_port = true;
+        cfg.dscp = DscpClasses {
+            control: DSCP_EF,
+            ..Default::default()
+        };
+        let mut worker = Worker::build(0, cfg);
+        let now = Instant::now();
+
+        assert_eq!(net_outputs(&mut worker, now), vec![BackendOutgoing::UdpListen { addr: local, reuse: true }]);
+        listen_result(&mut worker, now, local, local, 1);
+        // the control class socket is bound after the normal class socket, on the same address
+        assert_eq!(net_outputs(&mut worker, now), vec![BackendOutgoing::UdpListen { addr: local, reuse: true }]);
+        listen_result(&mut worker, now, local, local, 2);
+        // code points are taken by the backend, which is not running in this test
+        assert_eq!(dscp::take(local), Some(0));
+        assert_eq!(dscp::take(local), Some(DSCP_EF));
+
+        assert_eq!(worker.slot(&local, MsgPriority::Control), Some(2));
+        assert_eq!(worker.slot(&local, MsgPriority::Normal), Some(1));
+        assert_eq!(worker.slot(&local, MsgPriority::Bulk), Some(1));
+
+        let remote = SocketAddr::from(([127, 0, 0, 1], 20000));
+        let mut builder = NodeAddrBuilder::new(2);
+        builder.add_protocol(Protocol::Ip4([127, 0, 0, 1].into()));
+        builder.add_protocol(Protocol::Udp(remote.port()));
+        worker.on_event(now, WorkerInnerInput::Ext(ExtIn::ConnectTo(builder.addr())));
+        worker.on_tick(now + Duration::from_millis(100));
+        let requests: Vec<_> = net_outputs(&mut worker, now + Duration::from_millis(100))
+            .into_iter()
+            .filter_map(|out| match out {
+                BackendOutgoing::UdpPacket { slot, to, .. } if to == remote => Some(slot),
+                _ => None,
+            })
+            .collect();
+        assert!(!requests.is_empty());
+        assert!(requests.iter().all(|slot| *slot == 2));
+    }
+
+    #[test]
+    fn removed_address_is_released_and_rebound() {
+        let local = SocketAddr::from(([127, 0, 0, 1], 10000));
+        // documentation address, which is never assigned to the host
+        let removed = SocketAddr::from(([192, 0, 2, 1], 10000));
+        let mut cfg = inner_cfg(None, false);
+        // a data worker, which reports interface events to the controller of another worker
+        cfg.controller = None;
+        cfg.bind_addrs = vec![local, removed];
+        let mut worker = Worker::build(3, cfg);
+        let started = Instant::now();
+
+        assert_eq!(net_outputs(&mut worker, started).len(), 2);
+        listen_result(&mut worker, started, local, local, 1);
+        listen_result(&mut worker, started, removed, removed, 2);
+        let (_, interfaces) = outputs(&mut worker, started);
+        assert_eq!(interfaces, vec![(3, InterfaceEvent::Up(local)), (3, InterfaceEvent::Up(removed))]);
+
+        let now = started + Duration::from_millis(2000);
+        worker.on_tick(now);
+        let (net, interfaces) = outputs(&mut worker, now);
+        assert_eq!(net, vec![BackendOutgoing::UdpUnlisten { slot: 2 }]);
+        assert_eq!(interfaces, vec![(3, InterfaceEvent::Down(removed))]);
+        assert_eq!(worker.slot(&removed, MsgPriority::Normal), None);
+        // a packet which was already received by the released socket is dropped
+        worker.on_event(
+            now,
+            WorkerInnerInput::Net(
+                SdnOwner,
+                BackendIncoming::UdpPacket {
+                    slot: 2,
+                    from: SocketAddr::from(([192, 0, 2, 2], 10000)),
+                    data: vec![1, 2, 3].into(),
+                },
+            ),
+        );
+
+        let now = started + Duration::from_millis(3000);
+        worker.on_tick(now);
+        assert_eq!(net_outputs(&mut worker, now), vec![BackendOutgoing::UdpListen { addr: removed, reuse: false }]);
+    }
+}
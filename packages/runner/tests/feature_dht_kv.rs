@@ -34,7 +34,12 @@ fn process(nodes: &mut [&mut SdnController<(), SC, SE, TC, TW>], timeout_ms: u64
 }
 
 fn expect_event(node: &mut SdnController<(), SC, SE, TC, TW>, expected: dht_kv::Event) {
-    match node.pop_event() {
+    let mut event = node.pop_event();
+    // interface events are emitted when the node bind its udp sockets
+    while let Some(SdnExtOut::InterfaceEvent(_)) = event {
+        event = node.pop_event();
+    }
+    match event {
         Some(SdnExtOut::FeaturesEvent((), FeaturesEvent::DhtKv(event))) => {
             assert_eq!(event, expected)
         }
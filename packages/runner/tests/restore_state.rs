@@ -0,0 +1,57 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+    time::Duration,
+};
+
+use atm0s_sdn::{secure::StaticKeyAuthorization, services::visualization, CheckpointError, NodeAddr, NodeId, SdnBuilder, SdnController, SdnControllerUtils, SdnMetrics, SdnOwner};
+use sans_io_runtime::backend::PollingBackend;
+
+type UserInfo = u32;
+type SC = visualization::Control<UserInfo>;
+type SE = visualization::Event<UserInfo>;
+type TC = ();
+type TW = ();
+
+fn process(nodes: &mut [&mut SdnController<(), SC, SE, TC, TW>], timeout_ms: u64) {
+    for _ in 0..timeout_ms / 10 {
+        std::thread::sleep(Duration::from_millis(10));
+        for node in nodes.iter_mut() {
+            node.process();
+            while node.pop_event().is_some() {}
+        }
+    }
+}
+
+fn builder(node_id: NodeId, udp_port: u16) -> SdnBuilder<(), SC, SE, TC, TW, UserInfo> {
+    let addrs = [SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, udp_port))];
+    let mut builder = SdnBuilder::<(), SC, SE, TC, TW, UserInfo>::new(node_id, &addrs, vec![]);
+    builder.set_authorization(StaticKeyAuthorization::new("password-here"));
+    builder
+}
+
+fn build_node(builder: SdnBuilder<(), SC, SE, TC, TW, UserInfo>, node_id: NodeId) -> (SdnController<(), SC, SE, TC, TW>, NodeAddr, Arc<SdnMetrics>) {
+    let node_addr = builder.node_addr();
+    let metrics = builder.metrics();
+    (builder.build::<PollingBackend<SdnOwner, 16, 16>>(1, node_id), node_addr, metrics)
+}
+
+#[test]
+fn restart_with_restored_state() {
+    let (mut node1, node_addr1, _) = build_node(builder(1, 14000), 1);
+    let (mut node2, _, _) = build_node(builder(2, 14001), 2);
+    node2.connect_to(node_addr1);
+    process(&mut [&mut node1, &mut node2], 1000);
+
+    let state = node1.snapshot_state();
+    assert!(!state.is_empty());
+    assert_eq!(builder(3, 14002).with_restored_state(&state).err(), Some(CheckpointError::NodeMismatch(1)));
+
+    // node 1 restarts on another port and connects to its last neighbour without seeds
+    node1.shutdown();
+    let mut restarted = builder(1, 14003);
+    restarted.with_restored_state(&state).expect("Should restore state");
+    let (mut node1, _, metrics) = build_node(restarted, 1);
+    process(&mut [&mut node1, &mut node2], 2000);
+    assert_eq!(metrics.latest().map(|m| m.connections), Some(1));
+}
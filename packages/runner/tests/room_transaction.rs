@@ -0,0 +1,65 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use atm0s_sdn::{
+    secure::StaticKeyAuthorization, services::visualization, NodeId, RoomEvent, RoomOutput, RoomSpec, RoomTransaction, SdnBuilder, SdnController, SdnControllerUtils, SdnExtOut, SdnOwner,
+    ServiceBroadcastLevel,
+};
+use sans_io_runtime::backend::PollingBackend;
+
+type UserInfo = u32;
+type SC = visualization::Control<UserInfo>;
+type SE = visualization::Event<UserInfo>;
+type TC = ();
+type TW = ();
+
+fn build_node(node_id: NodeId, udp_port: u16) -> SdnController<(), SC, SE, TC, TW> {
+    let addrs = [SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, udp_port))];
+    let mut builder = SdnBuilder::<(), SC, SE, TC, TW, UserInfo>::new(node_id, &addrs, vec![]);
+    builder.set_authorization(StaticKeyAuthorization::new("password-here"));
+    builder.build::<PollingBackend<SdnOwner, 16, 16>>(2, node_id)
+}
+
+/// Drive the transaction with the node until it emits the combined result
+fn run(node: &mut SdnController<(), SC, SE, TC, TW>, tx: &mut RoomTransaction, timeout_ms: u64) -> Option<RoomEvent> {
+    let started_at = Instant::now();
+    tx.start(0);
+    while (started_at.elapsed().as_millis() as u64) < timeout_ms {
+        std::thread::sleep(Duration::from_millis(10));
+        let now = started_at.elapsed().as_millis() as u64;
+        if node.process().is_none() {
+            panic!("Node is shutdown");
+        }
+        while let Some(event) = node.pop_event() {
+            if let SdnExtOut::FeaturesEvent((), event) = event {
+                tx.on_event(now, &event);
+            }
+        }
+        tx.on_tick(now);
+        while let Some(out) = tx.pop_output() {
+            match out {
+                RoomOutput::Control(control) => node.feature_control((), control),
+                RoomOutput::Event(event) => return Some(event),
+            }
+        }
+    }
+    None
+}
+
+#[test]
+fn room_transaction_single_node() {
+    let mut node = build_node(1, 13000);
+    let mut tx = RoomTransaction::new(RoomSpec {
+        alias: 1000,
+        service: 1,
+        level: ServiceBroadcastLevel::Global,
+        map: 2000.into(),
+        key: 3000.into(),
+        value: vec![1, 2, 3],
+        channel: 4000.into(),
+    });
+    assert_eq!(run(&mut node, &mut tx, 3000), Some(RoomEvent::Registered));
+    assert!(tx.is_registered());
+}
@@ -0,0 +1,88 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use atm0s_sdn::{
+    secure::StaticKeyAuthorization, services::visualization, NodeAddr, NodeId, RegistryEvent, RegistryOutput, SdnBuilder, SdnController, SdnControllerUtils, SdnExtOut, SdnOwner, ServiceInstance,
+    ServiceRegistry, REGISTRY_TTL_MS,
+};
+use sans_io_runtime::backend::PollBackend;
+
+type UserInfo = u32;
+type SC = visualization::Control<UserInfo>;
+type SE = visualization::Event<UserInfo>;
+type TC = ();
+type TW = ();
+type Node = SdnController<(), SC, SE, TC, TW>;
+
+fn build_node(node_id: NodeId, udp_port: u16) -> (Node, NodeAddr) {
+    let addrs = [SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, udp_port))];
+    let mut builder = SdnBuilder::<(), SC, SE, TC, TW, UserInfo>::new(node_id, &addrs, vec![]);
+    builder.set_authorization(StaticKeyAuthorization::new("password-here"));
+    let node_addr = builder.node_addr();
+    (builder.build::<PollBackend<SdnOwner, 16, 16>>(2, node_id), node_addr)
+}
+
+/// Drive nodes with their registries, a registry which is None is stopped like a crashed application
+fn run(nodes: &mut [(&mut Node, Option<&mut ServiceRegistry>)], started_at: Instant, duration_ms: u64) -> Vec<RegistryEvent> {
+    let mut events = vec![];
+    let end = started_at.elapsed().as_millis() as u64 + duration_ms;
+    while (started_at.elapsed().as_millis() as u64) < end {
+        std::thread::sleep(Duration::from_millis(10));
+        let now = started_at.elapsed().as_millis() as u64;
+        for (node, registry) in nodes.iter_mut() {
+            if node.process().is_none() {
+                panic!("Node is shutdown");
+            }
+            while let Some(event) = node.pop_event() {
+                if let (SdnExtOut::FeaturesEvent((), event), Some(registry)) = (event, registry.as_mut()) {
+                    registry.on_event(now, &event);
+                }
+            }
+            if let Some(registry) = registry {
+                registry.on_tick(now);
+                while let Some(out) = registry.pop_output() {
+                    match out {
+                        RegistryOutput::Control(control) => node.feature_control((), control),
+                        RegistryOutput::Event(event) => events.push(event),
+                    }
+                }
+            }
+        }
+    }
+    events
+}
+
+#[test]
+fn service_registry_membership() {
+    let (mut node1, node_addr1) = build_node(1, 13300);
+    let (mut node2, _) = build_node(2, 13301);
+    node2.connect_to(node_addr1);
+    let started_at = Instant::now();
+    run(&mut [(&mut node1, None), (&mut node2, None)], started_at, 500);
+
+    let mut watcher = ServiceRegistry::new(1);
+    let mut provider = ServiceRegistry::new(2);
+    watcher.watch("api");
+    provider.register(0, "api", 80, vec![1]);
+    provider.register(0, "api", 81, vec![2]);
+    let mut events = run(&mut [(&mut node1, Some(&mut watcher)), (&mut node2, Some(&mut provider))], started_at, 1000);
+    events.sort_by_key(|event| format!("{event:?}"));
+    assert_eq!(
+        events,
+        vec![
+            RegistryEvent::Joined("api".to_string(), ServiceInstance { node: 2, port: 80, meta: vec![1] }),
+            RegistryEvent::Joined("api".to_string(), ServiceInstance { node: 2, port: 81, meta: vec![2] }),
+        ]
+    );
+
+    provider.deregister("api", 80);
+    let events = run(&mut [(&mut node1, Some(&mut watcher)), (&mut node2, Some(&mut provider))], started_at, 1000);
+    assert_eq!(events, vec![RegistryEvent::Left("api".to_string(), 2, 80)]);
+
+    // provider stops heartbeats without deregistering
+    let events = run(&mut [(&mut node1, Some(&mut watcher)), (&mut node2, None)], started_at, REGISTRY_TTL_MS + 2000);
+    assert_eq!(events, vec![RegistryEvent::Expired("api".to_string(), 2, 81)]);
+    assert_eq!(watcher.instances("api"), vec![]);
+}
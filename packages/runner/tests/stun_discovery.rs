@@ -0,0 +1,63 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    thread,
+    time::Duration,
+};
+
+use atm0s_sdn::{services::visualization, NodeAddrBuilder, Protocol, SdnBuilder};
+
+type UserInfo = u32;
+type SC = visualization::Control<UserInfo>;
+type SE = visualization::Event<UserInfo>;
+type TC = ();
+type TW = ();
+
+const MAGIC_COOKIE: [u8; 4] = [0x21, 0x12, 0xa4, 0x42];
+
+/// STUN server behind which every client is seen from the public ip, with the same port like 1:1 NAT
+fn spawn_stun_server(public_ip: Ipv4Addr, requests: usize) -> SocketAddr {
+    let server = UdpSocket::bind("127.0.0.1:0").expect("Should bind");
+    let addr = server.local_addr().expect("Should have addr");
+    thread::spawn(move || {
+        for _ in 0..requests {
+            let mut buf = [0u8; 1500];
+            let (len, from) = server.recv_from(&mut buf).expect("Should recv");
+            assert_eq!(len, 20);
+            let mut res = vec![0x01, 0x01, 0x00, 0x0c];
+            res.extend_from_slice(&buf[4..20]);
+            res.extend_from_slice(&[0x00, 0x20, 0x00, 0x08, 0x00, 0x01]);
+            let port = from.port().to_be_bytes();
+            res.extend_from_slice(&[port[0] ^ MAGIC_COOKIE[0], port[1] ^ MAGIC_COOKIE[1]]);
+            for (i, b) in public_ip.octets().iter().enumerate() {
+                res.push(b ^ MAGIC_COOKIE[i]);
+            }
+            server.send_to(&res, from).expect("Should send");
+        }
+    });
+    addr
+}
+
+#[test]
+fn append_discovered_public_addr() {
+    let public_ip = Ipv4Addr::new(203, 0, 113, 5);
+    let server = spawn_stun_server(public_ip, 2);
+    let addrs = [SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 13100))];
+    let mut builder = SdnBuilder::<(), SC, SE, TC, TW, UserInfo>::new(1, &addrs, vec![]);
+
+    // unreachable server is skipped, ipv6 server is not used for ipv4 bind addr
+    let dead = UdpSocket::bind("127.0.0.1:0").expect("Should bind").local_addr().expect("Should have addr");
+    let servers = [dead, "[::1]:3478".parse().expect("Should parse"), server];
+    let public = SocketAddr::V4(SocketAddrV4::new(public_ip, 13100));
+    assert_eq!(builder.discover_public_addrs(&servers, Duration::from_millis(200)), vec![public]);
+
+    let mut expected = NodeAddrBuilder::new(1);
+    expected.add_protocol(Protocol::Ip4(Ipv4Addr::LOCALHOST));
+    expected.add_protocol(Protocol::Udp(13100));
+    expected.add_protocol(Protocol::Ip4(public_ip));
+    expected.add_protocol(Protocol::Udp(13100));
+    assert_eq!(builder.node_addr(), expected.addr());
+
+    // already advertised address is not appended again
+    assert_eq!(builder.discover_public_addrs(&[server], Duration::from_millis(200)), vec![]);
+    assert_eq!(builder.node_addr(), expected.addr());
+}
@@ -0,0 +1,97 @@
+#![cfg(feature = "tokio")]
+
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use atm0s_sdn::{secure::StaticKeyAuthorization, services::visualization, NodeId, SdnBuilder, SdnControllerUtils, SdnExtOut, SdnOwner, VirtualNetwork, VirtualNetworkHandle};
+use sans_io_runtime::backend::PollingBackend;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+type UserInfo = u32;
+type SC = visualization::Control<UserInfo>;
+type SE = visualization::Event<UserInfo>;
+type TC = ();
+type TW = ();
+
+/// Run a node with the virtual network driver in a thread, like the controller loop of an application
+fn spawn_node(node_id: NodeId, udp_port: u16, stop: Arc<AtomicBool>) -> (VirtualNetworkHandle, std::thread::JoinHandle<()>) {
+    let (mut vnet, handle) = VirtualNetwork::new();
+    let join = std::thread::spawn(move || {
+        let addrs = [SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, udp_port))];
+        let mut builder = SdnBuilder::<(), SC, SE, TC, TW, UserInfo>::new(node_id, &addrs, vec![]);
+        builder.set_authorization(StaticKeyAuthorization::new("password-here"));
+        let mut node = builder.build::<PollingBackend<SdnOwner, 16, 16>>(2, node_id);
+        let started_at = Instant::now();
+        while !stop.load(Ordering::Relaxed) {
+            std::thread::sleep(Duration::from_millis(1));
+            let now = started_at.elapsed().as_millis() as u64;
+            if node.process().is_none() {
+                panic!("Node is shutdown");
+            }
+            vnet.on_tick(now);
+            while let Some(control) = vnet.pop_control() {
+                node.feature_control((), control);
+            }
+            while let Some(event) = node.pop_event() {
+                if let SdnExtOut::FeaturesEvent((), event) = event {
+                    vnet.on_event(now, &event);
+                }
+            }
+        }
+    });
+    (handle, join)
+}
+
+#[tokio::test]
+async fn virtual_udp_and_stream() {
+    let stop = Arc::new(AtomicBool::new(false));
+    let (handle, join) = spawn_node(1, 13200, stop.clone());
+
+    let sock1 = handle.virtual_udp(1000).await.expect("should bind");
+    let sock2 = handle.virtual_udp(0).await.expect("should bind ephemeral");
+    assert!(handle.virtual_udp(1000).await.is_err());
+    // wait both binds are applied by the driver
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    sock2.send_to(b"hello", 1, 1000).await.expect("should send");
+    let mut buf = [0; 100];
+    let (len, node, port) = tokio::time::timeout(Duration::from_secs(2), sock1.recv_from(&mut buf))
+        .await
+        .expect("should receive")
+        .expect("should not error");
+    assert_eq!((&buf[..len], node, port), (b"hello".as_slice(), 1, sock2.local_port()));
+
+    let listener = handle.listen(2000).await.expect("should listen");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+    let sent = data.clone();
+    let client = tokio::spawn(async move {
+        let mut stream = handle.connect(1, 2000).await.expect("should connect");
+        stream.write_all(&sent).await.expect("should write");
+        stream.shutdown().await.expect("should shutdown");
+        let mut echo = vec![];
+        stream.read_to_end(&mut echo).await.expect("should read");
+        echo
+    });
+    let mut server = tokio::time::timeout(Duration::from_secs(2), listener.accept()).await.expect("should accept").expect("should not error");
+    let mut received = vec![];
+    tokio::time::timeout(Duration::from_secs(10), server.read_to_end(&mut received))
+        .await
+        .expect("should finish")
+        .expect("should read");
+    assert_eq!(received.len(), data.len());
+    assert!(received == data);
+    server.write_all(b"done").await.expect("should write");
+    server.shutdown().await.expect("should shutdown");
+    let echo = tokio::time::timeout(Duration::from_secs(10), client).await.expect("should finish").expect("should join");
+    assert_eq!(echo, b"done");
+
+    stop.store(true, Ordering::Relaxed);
+    join.join().expect("should stop node");
+}
@@ -0,0 +1,87 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use atm0s_sdn::{
+    secure::StaticKeyAuthorization, services::visualization, ListenerOutput, NodeId, SdnBuilder, SdnController, SdnControllerUtils, SdnExtOut, SdnOwner, StreamEvent, StreamOutput, StreamState,
+    VirtualTcpListener, VirtualTcpStream,
+};
+use sans_io_runtime::backend::PollingBackend;
+
+type UserInfo = u32;
+type SC = visualization::Control<UserInfo>;
+type SE = visualization::Event<UserInfo>;
+type TC = ();
+type TW = ();
+
+fn build_node(node_id: NodeId, udp_port: u16) -> SdnController<(), SC, SE, TC, TW> {
+    let addrs = [SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, udp_port))];
+    let mut builder = SdnBuilder::<(), SC, SE, TC, TW, UserInfo>::new(node_id, &addrs, vec![]);
+    builder.set_authorization(StaticKeyAuthorization::new("password-here"));
+    builder.build::<PollingBackend<SdnOwner, 16, 16>>(2, node_id)
+}
+
+#[test]
+fn virtual_stream_single_node() {
+    let mut node = build_node(1, 13100);
+    let mut listener = VirtualTcpListener::bind(10000);
+    let mut client = VirtualTcpStream::connect(0, 20000, 1, 10000);
+    let data: Vec<u8> = (0..50_000).map(|i| (i % 251) as u8).collect();
+    let mut written = 0;
+    let mut received = vec![];
+    let mut buf = [0; 4096];
+
+    let started_at = Instant::now();
+    while client.state() != StreamState::Closed && started_at.elapsed() < Duration::from_secs(10) {
+        std::thread::sleep(Duration::from_millis(1));
+        let now = started_at.elapsed().as_millis() as u64;
+        if node.process().is_none() {
+            panic!("Node is shutdown");
+        }
+        while let Some(event) = node.pop_event() {
+            if let SdnExtOut::FeaturesEvent((), event) = event {
+                let _ = listener.on_event(now, &event) || client.on_event(now, &event);
+            }
+        }
+        listener.on_tick(now);
+        client.on_tick(now);
+
+        if client.state() == StreamState::Established && written < data.len() {
+            written += client.write(now, &data[written..]);
+            if written == data.len() {
+                client.close(now);
+            }
+        }
+        if let Some(stream) = listener.stream(1, 20000) {
+            loop {
+                let len = stream.read(&mut buf);
+                if len == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buf[..len]);
+            }
+            if stream.is_eof() {
+                stream.close(now);
+            }
+        }
+
+        // the listener is bound before the SYN arrives, a local SYN to an unbound port fails the stream as unreachable
+        while let Some(out) = listener.pop_output() {
+            match out {
+                ListenerOutput::Control(control) => node.feature_control((), control),
+                ListenerOutput::Stream(_, _, event) => assert!(!matches!(event, StreamEvent::Failed(_)), "server stream failed {event:?}"),
+                ListenerOutput::Accepted(node, port) => assert_eq!((node, port), (1, 20000)),
+            }
+        }
+        while let Some(out) = client.pop_output() {
+            match out {
+                StreamOutput::Control(control) => node.feature_control((), control),
+                StreamOutput::Event(event) => assert!(!matches!(event, StreamEvent::Failed(_)), "client failed {event:?}"),
+            }
+        }
+    }
+
+    assert_eq!(client.state(), StreamState::Closed);
+    assert!(received == data);
+}
@@ -0,0 +1,260 @@
+//! Each connection runs in its own thread, which polls outgoing packets between reads with a short read timeout.
+//! When both nodes dial each other at the same time, the connection dialed by the node with the smaller address is kept.
+
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender, SyncSender, TryRecvError, TrySendError},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use parking_lot::{Mutex, RwLock};
+use tungstenite::{
+    client::IntoClientRequest,
+    handshake::server::{Request, Response},
+    http::HeaderValue,
+    protocol::WebSocketConfig,
+    Message, WebSocket,
+};
+
+use crate::{proxy::HttpProxy, stream::StreamWrapper, ADDR_HEADER, MAX_PACKET_SIZE};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Max delay of outgoing packets while the connection is waiting for incoming data
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(10);
+/// Packets which are queued for a connection, newer packets are dropped when it's full like udp
+const QUEUE_SIZE: usize = 1024;
+
+pub(crate) struct ConnSlot {
+    id: u64,
+    tx: SyncSender<Vec<u8>>,
+}
+
+pub(crate) struct Shared<W> {
+    pub local: SocketAddr,
+    pub running: AtomicBool,
+    pub conns: Mutex<HashMap<SocketAddr, ConnSlot>>,
+    /// Dialed connections are tunneled through the proxy if it is set
+    pub proxy: RwLock<Option<HttpProxy>>,
+    wrapper: W,
+    incoming: Sender<(SocketAddr, Vec<u8>)>,
+    next_id: AtomicU64,
+}
+
+impl<W: StreamWrapper> Shared<W> {
+    pub fn new(local: SocketAddr, wrapper: W, incoming: Sender<(SocketAddr, Vec<u8>)>) -> Self {
+        Self {
+            local,
+            running: AtomicBool::new(true),
+            conns: Mutex::new(HashMap::new()),
+            proxy: RwLock::new(None),
+            wrapper,
+            incoming,
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    fn new_slot(&self) -> (ConnSlot, Receiver<Vec<u8>>) {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        (ConnSlot { id, tx }, rx)
+    }
+
+    fn unregister(&self, remote: SocketAddr, id: u64) {
+        let mut conns = self.conns.lock();
+        if conns.get(&remote).map(|slot| slot.id) == Some(id) {
+            conns.remove(&remote);
+        }
+    }
+}
+
+fn ws_config() -> WebSocketConfig {
+    WebSocketConfig::default().max_message_size(Some(MAX_PACKET_SIZE)).max_frame_size(Some(MAX_PACKET_SIZE))
+}
+
+pub(crate) fn spawn_listener<W: StreamWrapper>(shared: Arc<Shared<W>>, listener: TcpListener) -> io::Result<()> {
+    thread::Builder::new().name("ws-listener".to_string()).spawn(move || {
+        while shared.running.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((tcp, peer)) => {
+                    let shared = shared.clone();
+                    if let Err(e) = thread::Builder::new().name("ws-conn".to_string()).spawn(move || accept(shared, tcp, peer)) {
+                        log::error!("[WsTransport] spawn thread for incoming {peer} error {e}");
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_INTERVAL),
+                Err(e) => {
+                    log::error!("[WsTransport] accept error {e}");
+                    thread::sleep(ACCEPT_INTERVAL);
+                }
+            }
+        }
+        log::info!("[WsTransport] listener {} stopped", shared.local);
+    })?;
+    Ok(())
+}
+
+pub(crate) fn send<W: StreamWrapper>(shared: &Arc<Shared<W>>, to: SocketAddr, data: Vec<u8>) {
+    let mut conns = shared.conns.lock();
+    let tx = if let Some(slot) = conns.get(&to) {
+        slot.tx.clone()
+    } else {
+        let (slot, rx) = shared.new_slot();
+        let (id, tx) = (slot.id, slot.tx.clone());
+        let shared_c = shared.clone();
+        match thread::Builder::new().name("ws-conn".to_string()).spawn(move || dial(shared_c, id, to, rx)) {
+            Ok(_) => {
+                conns.insert(to, slot);
+                tx
+            }
+            Err(e) => {
+                log::error!("[WsTransport] spawn thread for dialing {to} error {e}");
+                return;
+            }
+        }
+    };
+    drop(conns);
+
+    match tx.try_send(data) {
+        Ok(_) => {}
+        Err(TrySendError::Full(_)) => log::warn!("[WsTransport] queue to {to} is full, drop packet"),
+        Err(TrySendError::Disconnected(_)) => log::debug!("[WsTransport] connection to {to} is closed, drop packet"),
+    }
+}
+
+fn dial<W: StreamWrapper>(shared: Arc<Shared<W>>, id: u64, remote: SocketAddr, rx: Receiver<Vec<u8>>) {
+    match dial_handshake(&shared, remote) {
+        Ok(ws) => {
+            log::info!("[WsTransport] connected to {}://{remote}", W::SCHEME);
+            run(&shared, id, remote, ws, rx);
+        }
+        Err(e) => {
+            log::warn!("[WsTransport] connect to {}://{remote} error {e}", W::SCHEME);
+            shared.unregister(remote, id);
+        }
+    }
+}
+
+fn dial_handshake<W: StreamWrapper>(shared: &Shared<W>, remote: SocketAddr) -> io::Result<WebSocket<W::Stream>> {
+    let proxy = shared.proxy.read().clone();
+    let tcp = match proxy {
+        Some(proxy) => proxy.connect(remote, CONNECT_TIMEOUT)?,
+        None => TcpStream::connect_timeout(&remote, CONNECT_TIMEOUT)?,
+    };
+    tcp.set_nodelay(true)?;
+    tcp.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let stream = shared.wrapper.connect(remote, tcp)?;
+    let mut request = format!("{}://{remote}/", W::SCHEME).into_client_request().map_err(io::Error::other)?;
+    let local = HeaderValue::from_str(&shared.local.to_string()).map_err(io::Error::other)?;
+    request.headers_mut().insert(ADDR_HEADER, local);
+    let (ws, _) = tungstenite::client::client_with_config(request, stream, Some(ws_config())).map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(ws)
+}
+
+fn accept<W: StreamWrapper>(shared: Arc<Shared<W>>, tcp: TcpStream, peer: SocketAddr) {
+    let ws = match accept_handshake(&shared, tcp, peer) {
+        Ok(res) => res,
+        Err(e) => {
+            log::warn!("[WsTransport] accept from {peer} error {e}");
+            return;
+        }
+    };
+    let (mut ws, remote) = ws;
+
+    let mut conns = shared.conns.lock();
+    if conns.contains_key(&remote) && shared.local < remote {
+        drop(conns);
+        log::info!("[WsTransport] keep dialed connection to {remote}, close accepted one");
+        let _ = ws.close(None);
+        let _ = ws.flush();
+        return;
+    }
+    let (slot, rx) = shared.new_slot();
+    let id = slot.id;
+    // replacing a dialing connection closes it, because its sender is dropped
+    conns.insert(remote, slot);
+    drop(conns);
+
+    log::info!("[WsTransport] accepted {}://{remote} from {peer}", W::SCHEME);
+    run(&shared, id, remote, ws, rx);
+}
+
+fn accept_handshake<W: StreamWrapper>(shared: &Shared<W>, tcp: TcpStream, peer: SocketAddr) -> io::Result<(WebSocket<W::Stream>, SocketAddr)> {
+    tcp.set_nonblocking(false)?;
+    tcp.set_nodelay(true)?;
+    tcp.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let stream = shared.wrapper.accept(tcp)?;
+    let mut announced = None;
+    let callback = |req: &Request, res: Response| {
+        announced = req.headers().get(ADDR_HEADER).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<SocketAddr>().ok());
+        Ok(res)
+    };
+    let ws = tungstenite::accept_hdr_with_config(stream, callback, Some(ws_config())).map_err(|e| io::Error::other(e.to_string()))?;
+    // browsers and other clients don't announce an address, they are identified by the tcp address
+    let remote = match announced {
+        Some(addr) if addr.ip().is_unspecified() => SocketAddr::new(peer.ip(), addr.port()),
+        Some(addr) => addr,
+        None => peer,
+    };
+    Ok((ws, remote))
+}
+
+fn run<W: StreamWrapper>(shared: &Shared<W>, id: u64, remote: SocketAddr, mut ws: WebSocket<W::Stream>, rx: Receiver<Vec<u8>>) {
+    if let Err(e) = W::tcp(ws.get_ref()).set_read_timeout(Some(POLL_INTERVAL)) {
+        log::error!("[WsTransport] set read timeout for {remote} error {e}");
+        shared.unregister(remote, id);
+        return;
+    }
+
+    'main: while shared.running.load(Ordering::Relaxed) {
+        loop {
+            match rx.try_recv() {
+                Ok(data) => {
+                    if let Err(e) = ws.write(Message::Binary(data.into())) {
+                        log::warn!("[WsTransport] write to {remote} error {e}");
+                        break 'main;
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    log::info!("[WsTransport] connection to {remote} is replaced, close it");
+                    break 'main;
+                }
+            }
+        }
+        if let Err(e) = ws.flush() {
+            log::warn!("[WsTransport] flush to {remote} error {e}");
+            break;
+        }
+
+        match ws.read() {
+            Ok(Message::Binary(data)) => {
+                if shared.incoming.send((remote, data.as_slice().to_vec())).is_err() {
+                    break;
+                }
+            }
+            Ok(Message::Close(_)) => {
+                log::info!("[WsTransport] connection to {remote} closed by remote");
+                break;
+            }
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => {
+                log::warn!("[WsTransport] read from {remote} error {e}");
+                break;
+            }
+        }
+    }
+
+    let _ = ws.close(None);
+    let _ = ws.flush();
+    shared.unregister(remote, id);
+}
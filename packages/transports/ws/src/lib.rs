@@ -0,0 +1,161 @@
+//! WebSocket transport for atm0s-sdn.
+//!
+//! Nodes can interconnect through HTTP-friendly infrastructure like proxies and load balancers, where udp is blocked.
+//! The transport carries the same udp-like packets as `NetOutput` and `NetInput`: each packet is one binary
+//! websocket message, and the sdn handshake and secure layer run on top of it unchanged.
+//!
+//! Remote nodes are identified by their listening address, which is sent in the [`ADDR_HEADER`] of the upgrade request,
+//! so `NetPair` of a connection is the same regardless of which side dialed. A connection is dialed when the first
+//! packet is sent to an unknown address. Like udp, packets to unreachable addresses are dropped.
+//!
+//! The embedder pumps packets between the transport and a `SdnWorker`: `NetOutput::UdpPacket(pair, data, _)` is sent
+//! with [`WebSocketTransport::send_to`] to `pair.remote`, and received packets are fed back as `NetInput::UdpPacket`.
+//! [`WssTransport`] adds TLS with rustls, which requires the `tls` feature.
+//!
+//! In networks which only allow egress over HTTP proxies, dialed connections are tunneled with CONNECT through the
+//! proxy which is set by [`WebSocketTransport::set_proxy`]. The dialing node still announces its listening address,
+//! so remote nodes answer over the same connection even when they cannot dial back.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr, TcpListener},
+    sync::{
+        atomic::Ordering,
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    time::Duration,
+};
+
+use atm0s_sdn_identity::{NodeAddr, NodeAddrBuilder, NodeId, Protocol};
+
+use self::conn::Shared;
+pub use self::proxy::{HttpProxy, ProxyAuth};
+pub use self::stream::{Plain, StreamWrapper};
+#[cfg(feature = "tls")]
+pub use self::tls::{Tls, TlsConfig, TlsStream};
+
+mod conn;
+mod proxy;
+mod stream;
+#[cfg(feature = "tls")]
+mod tls;
+
+/// Max size of a packet, same as udp
+pub const MAX_PACKET_SIZE: usize = 65535;
+/// Header of the upgrade request which contains listening address of the dialing node
+pub const ADDR_HEADER: &str = "x-atm0s-sdn-addr";
+
+/// WebSocket transport with a stream wrapper, see the module docs
+pub struct WebSocketTransport<W: StreamWrapper> {
+    shared: Arc<Shared<W>>,
+    incoming: Receiver<(SocketAddr, Vec<u8>)>,
+}
+
+/// Plain WebSocket transport
+pub type WsTransport = WebSocketTransport<Plain>;
+
+/// WebSocket transport over TLS
+#[cfg(feature = "tls")]
+pub type WssTransport = WebSocketTransport<Tls>;
+
+impl WebSocketTransport<Plain> {
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Self::bind_with(addr, Plain)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl WebSocketTransport<Tls> {
+    pub fn bind(addr: SocketAddr, tls: TlsConfig) -> io::Result<Self> {
+        Self::bind_with(addr, Tls(tls))
+    }
+}
+
+impl<W: StreamWrapper> WebSocketTransport<W> {
+    /// Bind with a custom stream wrapper
+    pub fn bind_with(addr: SocketAddr, wrapper: W) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let (tx, incoming) = mpsc::channel();
+        let shared = Arc::new(Shared::new(listener.local_addr()?, wrapper, tx));
+        conn::spawn_listener(shared.clone(), listener)?;
+        log::info!("[WsTransport] listening on {}://{}", W::SCHEME, shared.local);
+        Ok(Self { shared, incoming })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.shared.local
+    }
+
+    /// Address of this node for connecting over the transport, like `/ip4/1.2.3.4/tcp/8080/ws`.
+    /// An unspecified bind ip must be replaced by the reachable ip.
+    pub fn node_addr(&self, node_id: NodeId, ip: Option<IpAddr>) -> NodeAddr {
+        let mut builder = NodeAddrBuilder::new(node_id);
+        match ip.unwrap_or(self.shared.local.ip()) {
+            IpAddr::V4(ip) => builder.add_protocol(Protocol::Ip4(ip)),
+            IpAddr::V6(ip) => builder.add_protocol(Protocol::Ip6(ip)),
+        }
+        builder.add_protocol(Protocol::Tcp(self.shared.local.port()));
+        if W::SCHEME == "wss" {
+            builder.add_protocol(Protocol::Wss("/".into()));
+        } else {
+            builder.add_protocol(Protocol::Ws("/".into()));
+        }
+        builder.addr()
+    }
+
+    /// Tunnel connections which are dialed after this through an HTTP proxy, or dial directly with None
+    pub fn set_proxy(&self, proxy: Option<HttpProxy>) {
+        *self.shared.proxy.write() = proxy;
+    }
+
+    /// Remote addresses in a node address which can be dialed by this transport, which are tcp ports followed by
+    /// the websocket protocol of the same scheme, like `/ip4/1.2.3.4/tcp/8080/ws`
+    pub fn remote_addrs(addr: &NodeAddr) -> Vec<SocketAddr> {
+        let mut addrs = Vec::new();
+        let mut ip = None;
+        let mut tcp = None;
+        for part in addr.multiaddr().iter() {
+            match part {
+                Protocol::Ip4(i) => ip = Some(IpAddr::V4(i)),
+                Protocol::Ip6(i) => ip = Some(IpAddr::V6(i)),
+                Protocol::Tcp(port) => tcp = ip.map(|ip| SocketAddr::new(ip, port)),
+                Protocol::Ws(_) if W::SCHEME == "ws" => addrs.extend(tcp.take()),
+                Protocol::Wss(_) if W::SCHEME == "wss" => addrs.extend(tcp.take()),
+                _ => tcp = None,
+            }
+        }
+        addrs
+    }
+
+    /// Number of established and dialing connections
+    pub fn connections(&self) -> usize {
+        self.shared.conns.lock().len()
+    }
+
+    /// Send a packet to the node which is listening on `to`, the connection is dialed if needed.
+    /// Packets are dropped if the node is unreachable or the connection queue is full.
+    pub fn send_to(&self, to: SocketAddr, data: &[u8]) -> io::Result<()> {
+        if data.len() > MAX_PACKET_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "packet is larger than max packet size"));
+        }
+        conn::send(&self.shared, to, data.to_vec());
+        Ok(())
+    }
+
+    /// Pop a received packet with the listening address of the sender
+    pub fn try_recv(&self) -> Option<(SocketAddr, Vec<u8>)> {
+        self.incoming.try_recv().ok()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<(SocketAddr, Vec<u8>)> {
+        self.incoming.recv_timeout(timeout).ok()
+    }
+}
+
+impl<W: StreamWrapper> Drop for WebSocketTransport<W> {
+    fn drop(&mut self) {
+        self.shared.running.store(false, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,36 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+};
+
+/// Wraps tcp streams before the websocket handshake, which is where TLS is added for wss
+pub trait StreamWrapper: Send + Sync + 'static {
+    type Stream: Read + Write + Send + 'static;
+    /// Scheme of the websocket url, `ws` or `wss`
+    const SCHEME: &'static str;
+
+    fn accept(&self, tcp: TcpStream) -> io::Result<Self::Stream>;
+    fn connect(&self, remote: SocketAddr, tcp: TcpStream) -> io::Result<Self::Stream>;
+    /// Underlying tcp stream, for setting read timeout
+    fn tcp(stream: &Self::Stream) -> &TcpStream;
+}
+
+/// Plain websocket without encryption. Packets are still encrypted by the sdn secure layer after the handshake
+pub struct Plain;
+
+impl StreamWrapper for Plain {
+    type Stream = TcpStream;
+    const SCHEME: &'static str = "ws";
+
+    fn accept(&self, tcp: TcpStream) -> io::Result<Self::Stream> {
+        Ok(tcp)
+    }
+
+    fn connect(&self, _remote: SocketAddr, tcp: TcpStream) -> io::Result<Self::Stream> {
+        Ok(tcp)
+    }
+
+    fn tcp(stream: &Self::Stream) -> &TcpStream {
+        stream
+    }
+}
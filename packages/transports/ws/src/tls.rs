@@ -0,0 +1,75 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+};
+
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, ServerConfig, ServerConnection, StreamOwned};
+
+use crate::stream::StreamWrapper;
+
+#[derive(Clone)]
+pub struct TlsConfig {
+    /// Used for accepted connections
+    pub server: Arc<ServerConfig>,
+    /// Used for dialed connections
+    pub client: Arc<ClientConfig>,
+    /// Name which is verified in certificates of remote nodes, the remote ip is used if None
+    pub server_name: Option<ServerName<'static>>,
+}
+
+/// Websocket over TLS
+pub struct Tls(pub TlsConfig);
+
+pub enum TlsStream {
+    Server(StreamOwned<ServerConnection, TcpStream>),
+    Client(StreamOwned<ClientConnection, TcpStream>),
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            TlsStream::Server(s) => s.read(buf),
+            TlsStream::Client(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            TlsStream::Server(s) => s.write(buf),
+            TlsStream::Client(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TlsStream::Server(s) => s.flush(),
+            TlsStream::Client(s) => s.flush(),
+        }
+    }
+}
+
+impl StreamWrapper for Tls {
+    type Stream = TlsStream;
+    const SCHEME: &'static str = "wss";
+
+    fn accept(&self, tcp: TcpStream) -> io::Result<Self::Stream> {
+        let conn = ServerConnection::new(self.0.server.clone()).map_err(io::Error::other)?;
+        Ok(TlsStream::Server(StreamOwned::new(conn, tcp)))
+    }
+
+    fn connect(&self, remote: SocketAddr, tcp: TcpStream) -> io::Result<Self::Stream> {
+        let name = self.0.server_name.clone().unwrap_or_else(|| ServerName::IpAddress(remote.ip().into()));
+        let conn = ClientConnection::new(self.0.client.clone(), name).map_err(io::Error::other)?;
+        Ok(TlsStream::Client(StreamOwned::new(conn, tcp)))
+    }
+
+    fn tcp(stream: &Self::Stream) -> &TcpStream {
+        match stream {
+            TlsStream::Server(s) => &s.sock,
+            TlsStream::Client(s) => &s.sock,
+        }
+    }
+}
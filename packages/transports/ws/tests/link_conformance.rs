@@ -0,0 +1,136 @@
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use atm0s_sdn_network::link_tests::{run_all, LinkError, LinkUnderTest, Side};
+use atm0s_sdn_transport_ws::{StreamWrapper, TlsConfig, WebSocketTransport, WsTransport, WssTransport, MAX_PACKET_SIZE};
+use rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    ClientConfig, RootCertStore, ServerConfig,
+};
+
+/// Simulated time of the suite runs faster than real time, but slow enough for tcp and tls handshakes
+const SPEEDUP: u64 = 5;
+
+struct WsLink<W: StreamWrapper> {
+    sides: [WebSocketTransport<W>; 2],
+    started: Instant,
+    start_ms: Option<u64>,
+}
+
+impl<W: StreamWrapper> WsLink<W> {
+    fn new(a: WebSocketTransport<W>, b: WebSocketTransport<W>) -> Self {
+        Self {
+            sides: [a, b],
+            started: Instant::now(),
+            start_ms: None,
+        }
+    }
+
+    fn side(&self, side: Side) -> &WebSocketTransport<W> {
+        match side {
+            Side::A => &self.sides[0],
+            Side::B => &self.sides[1],
+        }
+    }
+}
+
+impl<W: StreamWrapper> LinkUnderTest for WsLink<W> {
+    fn addr(&self, side: Side) -> SocketAddr {
+        self.side(side).local_addr()
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        Some(MAX_PACKET_SIZE)
+    }
+
+    fn send(&mut self, _now_ms: u64, side: Side, to: SocketAddr, data: &[u8]) -> Result<(), LinkError> {
+        self.side(side).send_to(to, data).map_err(|_| LinkError::TooLarge)
+    }
+
+    fn recv(&mut self, now_ms: u64, side: Side) -> Option<(SocketAddr, Vec<u8>)> {
+        if let Some(pkt) = self.side(side).try_recv() {
+            return Some(pkt);
+        }
+        let start_ms = *self.start_ms.get_or_insert(now_ms);
+        let target = self.started + Duration::from_millis((now_ms - start_ms) / SPEEDUP);
+        let wait = target.saturating_duration_since(Instant::now());
+        if wait.is_zero() {
+            None
+        } else {
+            self.side(side).recv_timeout(wait)
+        }
+    }
+}
+
+fn local() -> SocketAddr {
+    "127.0.0.1:0".parse().expect("Should parse")
+}
+
+fn tls_config(trusted: bool) -> TlsConfig {
+    let provider = Arc::new(ring::default_provider());
+    // self-signed for 127.0.0.1, valid for 100 years
+    let cert = CertificateDer::from(include_bytes!("certs/cert.der").to_vec());
+    let key = PrivateKeyDer::Pkcs8(include_bytes!("certs/key.der").to_vec().into());
+
+    let server = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .expect("Should have protocol versions")
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.clone()], key)
+        .expect("Should create server config");
+
+    let mut roots = RootCertStore::empty();
+    if trusted {
+        roots.add(cert).expect("Should add root cert");
+    }
+    let client = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .expect("Should have protocol versions")
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    TlsConfig {
+        server: Arc::new(server),
+        client: Arc::new(client),
+        server_name: None,
+    }
+}
+
+#[test]
+fn ws_link_conformance() {
+    let a = WsTransport::bind(local()).expect("Should bind");
+    let b = WsTransport::bind(local()).expect("Should bind");
+    assert_eq!(run_all(&mut WsLink::new(a, b), 0), Ok(()));
+}
+
+#[test]
+fn wss_link_conformance() {
+    let tls = tls_config(true);
+    let a = WssTransport::bind(local(), tls.clone()).expect("Should bind");
+    let b = WssTransport::bind(local(), tls).expect("Should bind");
+    assert_eq!(run_all(&mut WsLink::new(a, b), 0), Ok(()));
+}
+
+#[test]
+fn wss_reject_untrusted_cert() {
+    let a = WssTransport::bind(local(), tls_config(false)).expect("Should bind");
+    let b = WssTransport::bind(local(), tls_config(false)).expect("Should bind");
+    a.send_to(b.local_addr(), &[1, 2, 3]).expect("Should queue packet");
+    assert_eq!(b.recv_timeout(Duration::from_millis(500)), None);
+    assert_eq!(a.connections(), 0);
+}
+
+#[test]
+fn ws_node_addr() {
+    let ws = WsTransport::bind(local()).expect("Should bind");
+    let port = ws.local_addr().port();
+    assert_eq!(ws.node_addr(1, None).to_string(), format!("1@/ip4/127.0.0.1/tcp/{port}/ws"));
+
+    let wss = WssTransport::bind(local(), tls_config(true)).expect("Should bind");
+    let port = wss.local_addr().port();
+    assert_eq!(wss.node_addr(2, Some([10, 0, 0, 1].into())).to_string(), format!("2@/ip4/10.0.0.1/tcp/{port}/wss"));
+}
@@ -0,0 +1,116 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use atm0s_sdn_identity::{NodeAddrBuilder, Protocol};
+use atm0s_sdn_transport_ws::{HttpProxy, WsTransport};
+
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Minimal CONNECT proxy which requires `Proxy-Authorization` if `auth` is set, returns the proxy address and count of tunnels
+fn spawn_proxy(auth: Option<&'static str>) -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Should bind proxy");
+    let addr = listener.local_addr().expect("Should have addr");
+    let tunnels = Arc::new(AtomicUsize::new(0));
+    let tunnels_c = tunnels.clone();
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            let client = client.expect("Should accept");
+            let tunnels = tunnels_c.clone();
+            thread::spawn(move || {
+                let _ = tunnel(client, auth, &tunnels);
+            });
+        }
+    });
+    (addr, tunnels)
+}
+
+fn tunnel(mut client: TcpStream, auth: Option<&str>, tunnels: &AtomicUsize) -> io::Result<()> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        client.read_exact(&mut byte)?;
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8_lossy(&head).to_string();
+    let target = head.split_whitespace().nth(1).expect("Should have target").to_string();
+    if let Some(auth) = auth {
+        if !head.contains(&format!("Proxy-Authorization: Basic {auth}\r\n")) {
+            return client.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n");
+        }
+    }
+    let server = TcpStream::connect(target)?;
+    client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+    tunnels.fetch_add(1, Ordering::Relaxed);
+
+    let (mut client_r, mut server_w) = (client.try_clone()?, server.try_clone()?);
+    thread::spawn(move || io::copy(&mut client_r, &mut server_w));
+    let (mut server_r, mut client_w) = (server, client);
+    io::copy(&mut server_r, &mut client_w)?;
+    Ok(())
+}
+
+fn pair() -> (WsTransport, WsTransport) {
+    let a = WsTransport::bind("127.0.0.1:0".parse().expect("Should parse")).expect("Should bind");
+    let b = WsTransport::bind("127.0.0.1:0".parse().expect("Should parse")).expect("Should bind");
+    (a, b)
+}
+
+#[test]
+fn dial_through_proxy_with_auth() {
+    // user:pass
+    let (proxy, tunnels) = spawn_proxy(Some("dXNlcjpwYXNz"));
+    let (a, b) = pair();
+    a.set_proxy(Some(HttpProxy::new(proxy).with_auth("user", "pass")));
+
+    a.send_to(b.local_addr(), &[1, 2, 3]).expect("Should send");
+    assert_eq!(b.recv_timeout(RECV_TIMEOUT), Some((a.local_addr(), vec![1, 2, 3])));
+    // the answer is sent over the tunneled connection
+    b.send_to(a.local_addr(), &[4, 5, 6]).expect("Should send");
+    assert_eq!(a.recv_timeout(RECV_TIMEOUT), Some((b.local_addr(), vec![4, 5, 6])));
+    assert_eq!(tunnels.load(Ordering::Relaxed), 1);
+    assert_eq!(a.connections(), 1);
+    assert_eq!(b.connections(), 1);
+}
+
+#[test]
+fn drop_packets_when_proxy_rejects_auth() {
+    let (proxy, tunnels) = spawn_proxy(Some("dXNlcjpwYXNz"));
+    let (a, b) = pair();
+    a.set_proxy(Some(HttpProxy::new(proxy).with_auth("user", "wrong")));
+
+    a.send_to(b.local_addr(), &[1, 2, 3]).expect("Should send");
+    assert_eq!(b.recv_timeout(Duration::from_millis(500)), None);
+    assert_eq!(tunnels.load(Ordering::Relaxed), 0);
+    assert_eq!(a.connections(), 0);
+
+    // dial directly again after the proxy is removed
+    a.set_proxy(None);
+    a.send_to(b.local_addr(), &[1, 2, 3]).expect("Should send");
+    assert_eq!(b.recv_timeout(RECV_TIMEOUT), Some((a.local_addr(), vec![1, 2, 3])));
+}
+
+#[test]
+fn remote_addrs_of_node_addr() {
+    let (a, _b) = pair();
+    let addr = a.node_addr(1, None);
+    assert_eq!(WsTransport::remote_addrs(&addr), vec![a.local_addr()]);
+
+    // udp and wss endpoints are not dialed by plain ws transport
+    let mut builder = NodeAddrBuilder::new(2);
+    builder.add_protocol(Protocol::Ip4([10, 0, 0, 1].into()));
+    builder.add_protocol(Protocol::Udp(10000));
+    builder.add_protocol(Protocol::Ip4([10, 0, 0, 2].into()));
+    builder.add_protocol(Protocol::Tcp(8443));
+    builder.add_protocol(Protocol::Wss("/".into()));
+    builder.add_protocol(Protocol::Tcp(8080));
+    builder.add_protocol(Protocol::Ws("/".into()));
+    assert_eq!(WsTransport::remote_addrs(&builder.addr()), vec!["10.0.0.2:8080".parse().expect("Should parse")]);
+}